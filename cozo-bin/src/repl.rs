@@ -11,13 +11,15 @@
 use std::collections::BTreeMap;
 use std::error::Error;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{stdout, BufRead, BufReader, Read, Write};
+use std::time::Instant;
 
 use clap::Args;
+use itertools::Itertools;
 use miette::{bail, miette, IntoDiagnostic};
 use serde_json::{json, Value};
 
-use cozo::{DataValue, DbInstance};
+use cozo::{DataValue, DbInstance, NamedRows};
 
 struct Indented;
 
@@ -46,18 +48,61 @@ impl rustyline::validate::Validator for Indented {
         &self,
         ctx: &mut rustyline::validate::ValidationContext<'_>,
     ) -> rustyline::Result<rustyline::validate::ValidationResult> {
-        Ok(if ctx.input().starts_with(' ') {
-            if ctx.input().ends_with('\n') {
-                rustyline::validate::ValidationResult::Valid(None)
-            } else {
-                rustyline::validate::ValidationResult::Incomplete
-            }
-        } else {
+        Ok(if is_complete(ctx.input()) {
             rustyline::validate::ValidationResult::Valid(None)
+        } else {
+            rustyline::validate::ValidationResult::Incomplete
         })
     }
 }
 
+/// Whether the input so far is a whole statement: a meta-command, input ending with a blank
+/// line, or input with balanced brackets. Input starting with a space always continues
+/// until a blank line.
+fn is_complete(input: &str) -> bool {
+    let trimmed = input.trim_start();
+    if trimmed.starts_with('\\') || trimmed.starts_with('%') {
+        return true;
+    }
+    if input.ends_with('\n') || trimmed.is_empty() {
+        return true;
+    }
+    if input.starts_with(' ') {
+        return false;
+    }
+    bracket_depth(input) <= 0
+}
+
+/// The number of brackets left open, ignoring those in strings and comments
+fn bracket_depth(input: &str) -> i32 {
+    let mut depth = 0;
+    let mut in_string = None;
+    let mut in_comment = false;
+    let mut escaped = false;
+    for c in input.chars() {
+        if in_comment {
+            in_comment = c != '\n';
+        } else if let Some(quote) = in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == quote {
+                in_string = None;
+            }
+        } else {
+            match c {
+                '"' | '\'' => in_string = Some(c),
+                '#' => in_comment = true,
+                '{' | '[' | '(' => depth += 1,
+                '}' | ']' | ')' => depth -= 1,
+                _ => {}
+            }
+        }
+    }
+    depth
+}
+
 #[derive(Args, Debug)]
 pub(crate) struct ReplArgs {
     /// Database engine, can be `mem`, `sqlite`, `rocksdb` and others.
@@ -79,25 +124,22 @@ pub(crate) fn repl_main(args: ReplArgs) -> Result<(), Box<dyn Error>> {
     let db_copy = db.clone();
     ctrlc::set_handler(move || {
         let running = db_copy
-            .run_script("::running", Default::default())
+            .list_running()
             .expect("Cannot determine running queries");
-        for row in running.rows {
-            let id = row.into_iter().next().unwrap();
-            eprintln!("Killing running query {id}");
-            db_copy
-                .run_script("::kill $id", BTreeMap::from([("id".to_string(), id)]))
-                .expect("Cannot kill process");
+        for query in running {
+            eprintln!("Killing running query {}", query.id);
+            db_copy.kill_query(query.id);
         }
     })
     .expect("Error setting Ctrl-C handler");
 
     println!("Welcome to the Cozo REPL.");
-    println!("Type a space followed by newline to enter multiline mode.");
+    println!("Statements with open brackets continue on the next line, a blank line ends them.");
+    println!("Type \\? for the list of meta-commands.");
 
     let mut exit = false;
     let mut rl = rustyline::Editor::<Indented>::new()?;
-    let mut params = BTreeMap::new();
-    let mut save_next: Option<String> = None;
+    let mut repl = Repl::new(db);
     rl.set_helper(Some(Indented));
 
     let history_file = ".cozo_repl_history";
@@ -109,7 +151,7 @@ pub(crate) fn repl_main(args: ReplArgs) -> Result<(), Box<dyn Error>> {
         let readline = rl.readline("=> ");
         match readline {
             Ok(line) => {
-                if let Err(err) = process_line(&line, &db, &mut params, &mut save_next) {
+                if let Err(err) = repl.process(&line) {
                     eprintln!("{err:?}");
                 }
                 rl.add_history_entry(line);
@@ -134,21 +176,152 @@ pub(crate) fn repl_main(args: ReplArgs) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn process_line(
-    line: &str,
-    db: &DbInstance,
-    params: &mut BTreeMap<String, DataValue>,
-    save_next: &mut Option<String>,
-) -> miette::Result<()> {
-    let line = line.trim();
-    if line.is_empty() {
-        return Ok(());
+const META_HELP: &str = r"\d              list relations
+\d <relation>   show the columns, indices and triggers of a relation
+\timing on|off  show the time taken by each query
+\format <fmt>   output format of results: table, json, csv or ndjson
+\o [<file>]     write results to a file, or back to the terminal
+\i <file>       run the statements in a file
+%set <key> <value>, %unset <key>, %clear, %params
+                manage the parameters passed to queries
+%save [<file>]  save the next result to a JSON file
+%backup <file>, %restore <file>, %import <file or url>";
+
+/// Cells of tables are cut at this many characters.
+const MAX_CELL_WIDTH: usize = 60;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+    NdJson,
+}
+
+struct Repl {
+    db: DbInstance,
+    params: BTreeMap<String, DataValue>,
+    save_next: Option<String>,
+    timing: bool,
+    format: OutputFormat,
+    out: Box<dyn Write>,
+}
+
+impl Repl {
+    fn new(db: DbInstance) -> Self {
+        Self {
+            db,
+            params: Default::default(),
+            save_next: None,
+            timing: false,
+            format: OutputFormat::Table,
+            out: Box::new(stdout()),
+        }
+    }
+
+    /// Runs the statements read from `input`, stopping at the first error.
+    fn run_input(&mut self, input: impl BufRead) -> miette::Result<()> {
+        let mut pending = String::new();
+        for line in input.lines() {
+            let line = line.into_diagnostic()?;
+            pending.push_str(&line);
+            if is_complete(&pending) {
+                self.process(&pending)?;
+                pending.clear();
+            } else {
+                pending.push('\n');
+            }
+        }
+        self.process(&pending)
+    }
+
+    /// Runs a complete statement or meta-command.
+    fn process(&mut self, line: &str) -> miette::Result<()> {
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(());
+        }
+        if let Some(remaining) = line.strip_prefix('\\') {
+            let (op, payload) = remaining
+                .split_once(|c: char| c.is_whitespace())
+                .unwrap_or((remaining, ""));
+            self.process_meta(op, payload.trim())
+        } else if let Some(remaining) = line.strip_prefix('%') {
+            let remaining = remaining.trim();
+            let (op, payload) = remaining
+                .split_once(|c: char| c.is_whitespace())
+                .unwrap_or((remaining, ""));
+            self.process_op(op, payload)
+        } else {
+            let start = Instant::now();
+            let out = self.db.run_script(line, self.params.clone())?;
+            if let Some(path) = self.save_next.take() {
+                writeln!(
+                    self.out,
+                    "Query has returned {} rows, saving to file {}",
+                    out.rows.len(),
+                    path
+                )
+                .into_diagnostic()?;
+                save_rows(&out, &path)?;
+            } else {
+                self.write_rows(&out)?;
+            }
+            if self.timing {
+                let elapsed = start.elapsed();
+                writeln!(self.out, "Time: {:.3} ms", elapsed.as_secs_f64() * 1000.)
+                    .into_diagnostic()?;
+            }
+            Ok(())
+        }
+    }
+
+    fn process_meta(&mut self, op: &str, payload: &str) -> miette::Result<()> {
+        match op {
+            "?" => writeln!(self.out, "{META_HELP}").into_diagnostic()?,
+            "d" => self.describe(payload)?,
+            "timing" => {
+                self.timing = match payload {
+                    "on" => true,
+                    "off" => false,
+                    "" => !self.timing,
+                    _ => bail!("Bad timing syntax. Should be '\\timing on|off'."),
+                };
+                let state = if self.timing { "on" } else { "off" };
+                writeln!(self.out, "Timing is {state}").into_diagnostic()?;
+            }
+            "format" => {
+                self.format = match payload {
+                    "table" => OutputFormat::Table,
+                    "json" => OutputFormat::Json,
+                    "csv" => OutputFormat::Csv,
+                    "ndjson" => OutputFormat::NdJson,
+                    f => bail!(
+                        "Unknown format: '{}'. Should be table, json, csv or ndjson.",
+                        f
+                    ),
+                };
+            }
+            "o" => {
+                self.out = if payload.is_empty() {
+                    Box::new(stdout())
+                } else {
+                    Box::new(File::create(payload).into_diagnostic()?)
+                };
+            }
+            "i" => {
+                if payload.is_empty() {
+                    bail!("\\i requires a path");
+                }
+                let file = File::open(payload).into_diagnostic()?;
+                self.run_input(BufReader::new(file))?;
+            }
+            op => bail!("Unknown meta-command: \\{}. Type \\? for help.", op),
+        }
+        Ok(())
     }
-    if let Some(remaining) = line.strip_prefix('%') {
-        let remaining = remaining.trim();
-        let (op, payload) = remaining
-            .split_once(|c: char| c.is_whitespace())
-            .unwrap_or((remaining, ""));
+
+    fn process_op(&mut self, op: &str, payload: &str) -> miette::Result<()> {
         match op {
             "set" => {
                 let (key, v_str) = payload
@@ -156,44 +329,48 @@ fn process_line(
                     .split_once(|c: char| c.is_whitespace())
                     .ok_or_else(|| miette!("Bad set syntax. Should be '%set <KEY> <VALUE>'."))?;
                 let val = serde_json::from_str(v_str).into_diagnostic()?;
-                params.insert(key.to_string(), val);
+                self.params.insert(key.to_string(), val);
             }
             "unset" => {
                 let key = payload.trim();
-                if params.remove(key).is_none() {
+                if self.params.remove(key).is_none() {
                     bail!("Key not found: '{}'", key)
                 }
             }
             "clear" => {
-                params.clear();
+                self.params.clear();
             }
             "params" => {
-                let display = serde_json::to_string_pretty(&json!(&params)).into_diagnostic()?;
-                println!("{display}");
+                let display =
+                    serde_json::to_string_pretty(&json!(&self.params)).into_diagnostic()?;
+                writeln!(self.out, "{display}").into_diagnostic()?;
             }
             "backup" => {
                 let path = payload.trim();
                 if path.is_empty() {
                     bail!("Backup requires a path");
                 };
-                db.backup_db(path)?;
-                println!("Backup written successfully to {path}")
+                self.db.backup_db(path)?;
+                writeln!(self.out, "Backup written successfully to {path}").into_diagnostic()?;
             }
             "restore" => {
                 let path = payload.trim();
                 if path.is_empty() {
                     bail!("Restore requires a path");
                 };
-                db.restore_backup(path)?;
-                println!("Backup successfully loaded from {path}")
+                self.db.restore_backup(path)?;
+                writeln!(self.out, "Backup successfully loaded from {path}").into_diagnostic()?;
             }
             "save" => {
                 let next_path = payload.trim();
                 if next_path.is_empty() {
-                    println!("Next result will NOT be saved to file");
+                    writeln!(self.out, "Next result will NOT be saved to file")
+                        .into_diagnostic()?;
+                    self.save_next = None;
                 } else {
-                    println!("Next result will be saved to file: {next_path}");
-                    *save_next = Some(next_path.to_string())
+                    writeln!(self.out, "Next result will be saved to file: {next_path}")
+                        .into_diagnostic()?;
+                    self.save_next = Some(next_path.to_string())
                 }
             }
             "import" => {
@@ -201,68 +378,325 @@ fn process_line(
                 if url.starts_with("http://") || url.starts_with("https://") {
                     let data = minreq::get(url).send().into_diagnostic()?;
                     let data = data.as_str().into_diagnostic()?;
-                    db.import_relations_str_with_err(data)?;
-                    println!("Imported data from {url}")
+                    self.db.import_relations_str_with_err(data)?;
                 } else {
                     let file_path = url.strip_prefix("file://").unwrap_or(url);
                     let mut file = File::open(file_path).into_diagnostic()?;
                     let mut content = String::new();
                     file.read_to_string(&mut content).into_diagnostic()?;
-                    db.import_relations_str_with_err(&content)?;
-                    println!("Imported data from {url}");
+                    self.db.import_relations_str_with_err(&content)?;
                 }
+                writeln!(self.out, "Imported data from {url}").into_diagnostic()?;
             }
             op => bail!("Unknown op: {}", op),
         }
-    } else {
-        let out = db.run_script(line, params.clone())?;
-        if let Some(path) = save_next.as_ref() {
-            println!(
-                "Query has returned {} rows, saving to file {}",
-                out.rows.len(),
-                path
-            );
-
-            let to_save = out
+        Ok(())
+    }
+
+    /// Lists the relations, or shows the columns, indices and triggers of one relation.
+    fn describe(&mut self, name: &str) -> miette::Result<()> {
+        let relations = self.db.run_script("::relations", Default::default())?;
+        if name.is_empty() {
+            return self.write_rows(&relations);
+        }
+
+        let columns = self
+            .db
+            .run_script(&format!("::columns {name}"), Default::default())?;
+        self.write_section(&format!("Columns of {name}"), &columns)?;
+
+        let prefix = format!("{name}:");
+        let mut indices = vec![];
+        for row in &relations.rows {
+            let index = match &row[0] {
+                DataValue::Str(s) if s.starts_with(&prefix) => s.to_string(),
+                _ => continue,
+            };
+            let index_columns = self
+                .db
+                .run_script(&format!("::columns {index}"), Default::default())?;
+            let column_names = index_columns
                 .rows
                 .iter()
-                .map(|row| -> Value {
-                    row.iter()
-                        .zip(out.headers.iter())
-                        .map(|(v, k)| (k.to_string(), v.clone()))
-                        .collect()
-                })
-                .collect();
-
-            let j_payload = Value::Array(to_save);
-
-            let mut file = File::create(path).into_diagnostic()?;
-            file.write_all(j_payload.to_string().as_bytes())
-                .into_diagnostic()?;
-            *save_next = None;
-        } else {
+                .map(|col| col[0].get_str().unwrap_or_default().to_string())
+                .join(", ");
+            indices.push(vec![
+                DataValue::from(&index[prefix.len()..]),
+                DataValue::from(column_names),
+            ]);
+        }
+        let indices = NamedRows::new(vec!["index".to_string(), "columns".to_string()], indices);
+        self.write_section(&format!("Indices of {name}"), &indices)?;
+
+        let triggers = self
+            .db
+            .run_script(&format!("::show_triggers {name}"), Default::default())?;
+        self.write_section(&format!("Triggers of {name}"), &triggers)
+    }
+
+    /// Writes rows with a title, which is only shown for tables.
+    fn write_section(&mut self, title: &str, rows: &NamedRows) -> miette::Result<()> {
+        if self.format == OutputFormat::Table {
+            writeln!(self.out, "{title}:").into_diagnostic()?;
+        }
+        self.write_rows(rows)
+    }
+
+    fn write_rows(&mut self, rows: &NamedRows) -> miette::Result<()> {
+        write_rows(&mut self.out, self.format, rows).into_diagnostic()
+    }
+}
+
+fn write_rows(out: &mut dyn Write, format: OutputFormat, rows: &NamedRows) -> std::io::Result<()> {
+    match format {
+        OutputFormat::Table => {
             use prettytable::format;
             let mut table = prettytable::Table::new();
-            let headers = out
+            let headers = rows
                 .headers
                 .iter()
                 .map(prettytable::Cell::from)
                 .collect::<Vec<_>>();
             table.set_titles(prettytable::Row::new(headers));
-            let rows = out
-                .rows
-                .iter()
-                .map(|r| r.iter().map(|c| format!("{c}")).collect::<Vec<_>>())
-                .collect::<Vec<_>>();
-            let rows = rows
-                .iter()
-                .map(|r| r.iter().map(prettytable::Cell::from).collect::<Vec<_>>());
-            for row in rows {
-                table.add_row(prettytable::Row::new(row));
+            for row in &rows.rows {
+                let cells = row
+                    .iter()
+                    .map(|c| prettytable::Cell::new(&table_cell(c)))
+                    .collect::<Vec<_>>();
+                table.add_row(prettytable::Row::new(cells));
             }
             table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
-            table.printstd();
+            table.print(out)?;
+        }
+        OutputFormat::Json => {
+            writeln!(out, "{}", rows.clone().into_json())?;
+        }
+        OutputFormat::Csv => {
+            writeln!(
+                out,
+                "{}",
+                rows.headers.iter().map(|h| csv_field(h.as_str())).join(",")
+            )?;
+            for row in &rows.rows {
+                let mut fields = row.iter().map(|v| match v {
+                    DataValue::Null => String::new(),
+                    DataValue::Str(s) => csv_field(s),
+                    v => csv_field(&Value::from(v.clone()).to_string()),
+                });
+                writeln!(out, "{}", fields.join(","))?;
+            }
+        }
+        OutputFormat::NdJson => {
+            for row in &rows.rows {
+                let obj: Value = rows
+                    .headers
+                    .iter()
+                    .zip(row.iter())
+                    .map(|(k, v)| (k.to_string(), v.clone()))
+                    .collect();
+                writeln!(out, "{obj}")?;
+            }
         }
     }
     Ok(())
 }
+
+/// The content of a table cell, cut if too wide
+fn table_cell(val: &DataValue) -> String {
+    let s = val.to_string();
+    if s.chars().count() > MAX_CELL_WIDTH {
+        let mut cut: String = s.chars().take(MAX_CELL_WIDTH - 1).collect();
+        cut.push('…');
+        cut
+    } else {
+        s
+    }
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains(|c| matches!(c, ',' | '"' | '\n' | '\r')) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn save_rows(out: &NamedRows, path: &str) -> miette::Result<()> {
+    let to_save = out
+        .rows
+        .iter()
+        .map(|row| -> Value {
+            row.iter()
+                .zip(out.headers.iter())
+                .map(|(v, k)| (k.to_string(), v.clone()))
+                .collect()
+        })
+        .collect();
+
+    let j_payload = Value::Array(to_save);
+
+    let mut file = File::create(path).into_diagnostic()?;
+    file.write_all(j_payload.to_string().as_bytes())
+        .into_diagnostic()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuf {
+        fn take(&self) -> String {
+            String::from_utf8(std::mem::take(&mut *self.0.lock().unwrap())).unwrap()
+        }
+    }
+
+    fn test_repl() -> (Repl, SharedBuf) {
+        let db = DbInstance::new("mem", "", "").unwrap();
+        let buf = SharedBuf::default();
+        let mut repl = Repl::new(db);
+        repl.out = Box::new(buf.clone());
+        (repl, buf)
+    }
+
+    fn run(repl: &mut Repl, input: &str) {
+        repl.run_input(input.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn multi_line_input() {
+        assert!(is_complete("?[a] := a = 1"));
+        assert!(!is_complete("?[a] := a in [1,"));
+        assert!(!is_complete(":create r {"));
+        assert!(is_complete(":create r {\na\n}"));
+        assert!(is_complete("?[a] := a = '{' # ["));
+        assert!(is_complete("?[a] := a in [1,\n"));
+        assert!(!is_complete(" ?[a] := a = 1"));
+        assert!(is_complete(" ?[a] := a = 1\n"));
+        assert!(is_complete("\\d rel"));
+
+        let (mut repl, buf) = test_repl();
+        run(
+            &mut repl,
+            "\\format csv\n?[a, b] := a in [1,\n  2], b = 'x,y'\n ?[c] :=\n  c = 3\n\n",
+        );
+        assert_eq!(buf.take(), "a,b\n1,\"x,y\"\n2,\"x,y\"\nc\n3\n");
+    }
+
+    #[test]
+    fn describe_relations() {
+        let (mut repl, buf) = test_repl();
+        run(
+            &mut repl,
+            r"
+:create rel {
+    k: Int
+    =>
+    v: String?
+}
+::index create rel:by_v {v}
+:create log {k}
+::set_triggers rel on put { ?[k] := _new[k, v] :put log {k} }
+",
+        );
+        buf.take();
+
+        run(&mut repl, "\\format csv\n\\d");
+        let listed = buf.take();
+        assert!(listed.starts_with("name,arity,access_level,"));
+        assert!(listed.contains("\nlog,1,normal,"));
+        assert!(listed.contains("\nrel,2,normal,"));
+        assert!(listed.contains("\nrel:by_v,2,index,"));
+
+        run(&mut repl, "\\d rel");
+        let described = buf.take();
+        assert!(described.contains("\nk,true,0,Int,false\n"));
+        assert!(described.contains("\nv,false,1,String?,false\n"));
+        assert!(described.contains("index,columns\nby_v,\"v, k\"\n"));
+        assert!(described.contains("put,0,"));
+
+        run(&mut repl, "\\format table\n\\d rel");
+        let described = buf.take();
+        assert!(described.contains("Columns of rel:"));
+        assert!(described.contains("Indices of rel:"));
+        assert!(described.contains("Triggers of rel:"));
+
+        assert!(repl.process("\\d nothing").is_err());
+    }
+
+    #[test]
+    fn output_formats() {
+        let (mut repl, buf) = test_repl();
+        let query = "?[a, b] <- [[1, null], [2, 'a\"b']]";
+
+        run(&mut repl, &format!("\\format json\n{query}"));
+        let out: Value = serde_json::from_str(&buf.take()).unwrap();
+        assert_eq!(out["headers"], json!(["a", "b"]));
+        assert_eq!(out["rows"], json!([[1, null], [2, "a\"b"]]));
+
+        run(&mut repl, &format!("\\format ndjson\n{query}"));
+        assert_eq!(
+            buf.take(),
+            "{\"a\":1,\"b\":null}\n{\"a\":2,\"b\":\"a\\\"b\"}\n"
+        );
+
+        run(&mut repl, &format!("\\format csv\n{query}"));
+        assert_eq!(buf.take(), "a,b\n1,\n2,\"a\"\"b\"\n");
+
+        let wide = "x".repeat(100);
+        run(
+            &mut repl,
+            &format!("\\format table\n?[a, b] <- [[null, '{wide}']]"),
+        );
+        let table = buf.take();
+        assert!(table.contains("null"));
+        assert!(table.contains(&format!("{}…", "x".repeat(MAX_CELL_WIDTH - 2))));
+        assert!(!table.contains(&wide));
+
+        assert!(repl.process("\\format xml").is_err());
+    }
+
+    #[test]
+    fn timing_redirect_and_include() {
+        let (mut repl, buf) = test_repl();
+        run(&mut repl, "\\timing on\n\\format csv\n?[a] := a = 1");
+        let out = buf.take();
+        assert!(out.starts_with("Timing is on\na\n1\nTime: "));
+        assert!(out.ends_with(" ms\n"));
+        run(&mut repl, "\\timing off\n?[a] := a = 1");
+        assert_eq!(buf.take(), "Timing is off\na\n1\n");
+
+        let dir = std::env::temp_dir();
+        let id = rand::random::<u64>();
+        let out_path = dir.join(format!("cozo-repl-out-{id}.csv"));
+        let script_path = dir.join(format!("cozo-repl-script-{id}.cozo"));
+        std::fs::write(
+            &script_path,
+            "?[a] := a in [\n    1, 2\n]\n\n\\o\n?[b] := b = 3\n",
+        )
+        .unwrap();
+
+        run(&mut repl, &format!("\\o {}", out_path.display()));
+        run(&mut repl, &format!("\\i {}", script_path.display()));
+        // the script redirected the output back to stdout
+        assert_eq!(std::fs::read_to_string(&out_path).unwrap(), "a\n1\n2\n");
+        assert_eq!(buf.take(), "");
+
+        assert!(repl.process("\\i /nonexistent/script.cozo").is_err());
+        std::fs::remove_file(out_path).unwrap();
+        std::fs::remove_file(script_path).unwrap();
+    }
+}
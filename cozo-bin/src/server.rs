@@ -8,17 +8,18 @@
 
 use std::collections::BTreeMap;
 use std::convert::Infallible;
-use std::net::{Ipv6Addr, SocketAddr};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 use std::str::FromStr;
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use axum::body::{Body, BoxBody};
-use axum::extract::{Path, Query, State};
-use axum::http::{Method, Request, Response, StatusCode};
+use axum::extract::{ConnectInfo, Path, Query, State};
+use axum::http::{header, Method, Request, Response, StatusCode};
 use axum::response::sse::{Event, KeepAlive};
-use axum::response::{Html, Sse};
+use axum::response::{Html, IntoResponse, Sse};
 use axum::routing::{get, post, put};
 use axum::{Json, Router};
 use clap::Args;
@@ -34,7 +35,8 @@ use tower_http::compression::CompressionLayer;
 use tower_http::cors::{Any, CorsLayer};
 
 use cozo::{
-    format_error_as_json, DataValue, DbInstance, MultiTransaction, NamedRows, SimpleFixedRule,
+    format_error_as_json, json_to_string, DataValue, DbInstance, FloatFormat, MultiTransaction,
+    NamedRows, OutputOptions, SimpleFixedRule,
 };
 
 #[derive(Args, Debug)]
@@ -65,6 +67,14 @@ pub(crate) struct ServerArgs {
     /// Port to use
     #[clap(short = 'P', long, default_value_t = 9070)]
     port: u16,
+
+    /// Seconds a cursor over paginated results is kept after its last use
+    #[clap(long, default_value_t = 300)]
+    cursor_ttl: u64,
+
+    /// Maximal number of cursors over paginated results open at once for each client address
+    #[clap(long, default_value_t = 16)]
+    max_cursors_per_client: usize,
 }
 
 #[derive(Clone)]
@@ -74,6 +84,167 @@ struct DbState {
     rule_counter: Arc<AtomicU32>,
     tx_counter: Arc<AtomicU32>,
     txs: Arc<Mutex<BTreeMap<u32, Arc<MultiTransaction>>>>,
+    cursors: Arc<Cursors>,
+}
+
+/// Query results kept server-side, to be fetched page by page with the tokens handed out
+struct Cursors {
+    ttl: Duration,
+    max_per_client: usize,
+    state: Mutex<CursorsState>,
+}
+
+#[derive(Default)]
+struct CursorsState {
+    open: BTreeMap<String, Cursor>,
+    /// tokens of the cursors dropped for being idle, remembered for another TTL
+    /// so that clients can tell expiry from a wrong token
+    expired: BTreeMap<String, Instant>,
+}
+
+struct Cursor {
+    client: IpAddr,
+    headers: Vec<String>,
+    output_options: Option<OutputOptions>,
+    rows: std::vec::IntoIter<Vec<DataValue>>,
+    page_size: usize,
+    last_used: Instant,
+}
+
+enum CursorError {
+    NotFound,
+    Expired,
+    TooMany(usize),
+}
+
+impl CursorError {
+    fn into_response<B: From<serde_json::Value>>(self) -> (StatusCode, B) {
+        let (status, code, message) = match self {
+            CursorError::NotFound => (
+                StatusCode::NOT_FOUND,
+                "cursor::not_found",
+                "no cursor exists for the token".to_string(),
+            ),
+            CursorError::Expired => (
+                StatusCode::GONE,
+                "cursor::expired",
+                "the cursor expired after being idle".to_string(),
+            ),
+            CursorError::TooMany(max) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "cursor::too_many",
+                format!("at most {max} cursors can be open at once for each client"),
+            ),
+        };
+        (
+            status,
+            json!({"ok": false, "code": code, "message": message}).into(),
+        )
+    }
+}
+
+impl Cursor {
+    fn next_page(&mut self, token: Option<&str>) -> FormattedJson {
+        let rows = self.rows.by_ref().take(self.page_size).collect_vec();
+        let mut page = NamedRows::new(self.headers.clone(), rows);
+        page.output_options = self.output_options;
+        let float_format = page.float_format();
+        let mut page = page.into_json();
+        let next_token = if self.rows.as_slice().is_empty() {
+            None
+        } else {
+            token
+        };
+        let map = page.as_object_mut().unwrap();
+        map.insert("ok".to_string(), json!(true));
+        map.insert("next_token".to_string(), json!(next_token));
+        FormattedJson(page, float_format)
+    }
+}
+
+impl Cursors {
+    fn new(ttl: Duration, max_per_client: usize) -> Self {
+        Self {
+            ttl,
+            max_per_client,
+            state: Default::default(),
+        }
+    }
+    /// Returns the first page of the rows, with a token for fetching the rest
+    /// if they do not fit into it.
+    fn open(
+        &self,
+        client: IpAddr,
+        rows: NamedRows,
+        page_size: usize,
+    ) -> Result<FormattedJson, CursorError> {
+        let page_size = page_size.max(1);
+        let mut cursor = Cursor {
+            client,
+            headers: rows.headers,
+            output_options: rows.output_options,
+            rows: rows.rows.into_iter(),
+            page_size,
+            last_used: Instant::now(),
+        };
+        if cursor.rows.len() <= page_size {
+            return Ok(cursor.next_page(None));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        self.reap_locked(&mut state, Instant::now());
+        let n_open = state.open.values().filter(|c| c.client == client).count();
+        if n_open >= self.max_per_client {
+            return Err(CursorError::TooMany(self.max_per_client));
+        }
+        let token: String = rand::thread_rng()
+            .sample_iter(&rand::distributions::Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+        let page = cursor.next_page(Some(&token));
+        state.open.insert(token, cursor);
+        Ok(page)
+    }
+    fn next_page(&self, token: &str) -> Result<FormattedJson, CursorError> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        self.reap_locked(state, Instant::now());
+        let cursor = match state.open.get_mut(token) {
+            Some(cursor) => cursor,
+            None if state.expired.contains_key(token) => return Err(CursorError::Expired),
+            None => return Err(CursorError::NotFound),
+        };
+        cursor.last_used = Instant::now();
+        let page = cursor.next_page(Some(token));
+        if cursor.rows.as_slice().is_empty() {
+            state.open.remove(token);
+        }
+        Ok(page)
+    }
+    fn release(&self, token: &str) -> bool {
+        self.state.lock().unwrap().open.remove(token).is_some()
+    }
+    /// Drops the cursors idle for longer than the TTL, returning the number still open
+    fn reap(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        self.reap_locked(&mut state, Instant::now());
+        state.open.len()
+    }
+    fn reap_locked(&self, state: &mut CursorsState, now: Instant) {
+        let ttl = self.ttl;
+        state.expired.retain(|_, at| now.duration_since(*at) <= ttl);
+        let idle = state
+            .open
+            .iter()
+            .filter(|(_, c)| now.duration_since(c.last_used) > ttl)
+            .map(|(token, _)| token.clone())
+            .collect_vec();
+        for token in idle {
+            state.open.remove(&token);
+            state.expired.insert(token, now);
+        }
+    }
 }
 
 pub(crate) async fn server_main(args: ServerArgs) {
@@ -112,7 +283,19 @@ pub(crate) async fn server_main(args: ServerArgs) {
         rule_counter: Default::default(),
         tx_counter: Default::default(),
         txs: Default::default(),
+        cursors: Arc::new(Cursors::new(
+            Duration::from_secs(args.cursor_ttl),
+            args.max_cursors_per_client,
+        )),
     };
+    let cursors = state.cursors.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            cursors.reap();
+        }
+    });
     let cors = CorsLayer::new()
         .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
         .allow_origin(Any);
@@ -131,6 +314,9 @@ pub(crate) async fn server_main(args: ServerArgs) {
         ) // +keep alive
         .route("/transact", post(start_transact))
         .route("/transact/:id", post(transact_query).put(finish_query))
+        .route("/cursor", post(open_cursor))
+        .route("/cursor/:token", get(cursor_page).delete(release_cursor))
+        .route("/metrics", get(metrics))
         .with_state(state)
         .layer(RequireAuthorizationLayer::custom(
             move |request: &mut Request<Body>| {
@@ -195,7 +381,7 @@ pub(crate) async fn server_main(args: ServerArgs) {
     );
 
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 }
@@ -219,7 +405,7 @@ async fn transact_query(
     State(st): State<DbState>,
     Path(id): Path<u32>,
     Json(payload): Json<QueryPayload>,
-) -> (StatusCode, Json<serde_json::Value>) {
+) -> (StatusCode, FormattedJson) {
     let tx = match st.txs.lock().unwrap().get(&id) {
         None => return (StatusCode::NOT_FOUND, json!({"ok": false}).into()),
         Some(tx) => tx.clone(),
@@ -236,7 +422,10 @@ async fn transact_query(
     })
     .await;
     match result {
-        Ok(Ok(res)) => (StatusCode::OK, res.into_json().into()),
+        Ok(Ok(res)) => {
+            let float_format = res.float_format();
+            (StatusCode::OK, FormattedJson(res.into_json(), float_format))
+        }
         Ok(Err(err)) => (
             StatusCode::BAD_REQUEST,
             format_error_as_json(err, Some(&src)).into(),
@@ -282,19 +471,79 @@ struct QueryPayload {
 async fn text_query(
     State(st): State<DbState>,
     Json(payload): Json<QueryPayload>,
-) -> (StatusCode, Json<serde_json::Value>) {
+) -> (StatusCode, FormattedJson) {
     let params = payload
         .params
         .into_iter()
         .map(|(k, v)| (k, DataValue::from(v)))
         .collect();
-    let result = spawn_blocking(move || st.db.run_script_fold_err(&payload.script, params)).await;
+    let result = spawn_blocking(move || {
+        st.db
+            .run_script_fold_err_with_format(&payload.script, params)
+    })
+    .await;
     match result {
-        Ok(res) => wrap_json(res),
+        Ok((res, float_format)) => {
+            let (code, Json(res)) = wrap_json(res);
+            (code, FormattedJson(res, float_format))
+        }
         Err(err) => internal_error(err),
     }
 }
 
+#[derive(serde_derive::Deserialize)]
+struct CursorOptions {
+    page_size: usize,
+}
+
+async fn open_cursor(
+    State(st): State<DbState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(opts): Query<CursorOptions>,
+    Json(payload): Json<QueryPayload>,
+) -> (StatusCode, FormattedJson) {
+    let params = payload
+        .params
+        .into_iter()
+        .map(|(k, v)| (k, DataValue::from(v)))
+        .collect();
+    let src = payload.script.clone();
+    let db = st.db.clone();
+    let result = spawn_blocking(move || db.run_script(&payload.script, params)).await;
+    match result {
+        Ok(Ok(rows)) => match st.cursors.open(addr.ip(), rows, opts.page_size) {
+            Ok(page) => (StatusCode::OK, page),
+            Err(err) => err.into_response(),
+        },
+        Ok(Err(err)) => (
+            StatusCode::BAD_REQUEST,
+            format_error_as_json(err, Some(&src)).into(),
+        ),
+        Err(err) => internal_error(err),
+    }
+}
+
+async fn cursor_page(
+    State(st): State<DbState>,
+    Path(token): Path<String>,
+) -> (StatusCode, FormattedJson) {
+    match st.cursors.next_page(&token) {
+        Ok(page) => (StatusCode::OK, page),
+        Err(err) => err.into_response(),
+    }
+}
+
+async fn release_cursor(
+    State(st): State<DbState>,
+    Path(token): Path<String>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if st.cursors.release(&token) {
+        (StatusCode::OK, json!({"ok": true}).into())
+    } else {
+        CursorError::NotFound.into_response()
+    }
+}
+
 async fn export_relations(
     State(st): State<DbState>,
     Path(relations): Path<String>,
@@ -558,13 +807,47 @@ async fn observe_changes(
     Sse::new(stream).keep_alive(KeepAlive::default())
 }
 
+/// The numeric entries of `::storage_info` as gauges in the text format of Prometheus,
+/// named e.g. `cozo_storage_page_count` and labelled with the storage kind
+async fn metrics(State(st): State<DbState>) -> (StatusCode, String) {
+    let result =
+        spawn_blocking(move || st.db.run_script("::storage_info", Default::default())).await;
+    match result {
+        Ok(Ok(info)) => (StatusCode::OK, format_metrics(&info)),
+        Ok(Err(err)) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+    }
+}
+
+fn format_metrics(info: &NamedRows) -> String {
+    let entries = info
+        .rows
+        .iter()
+        .filter_map(|row| Some((row[0].get_str()?, &row[1])))
+        .collect_vec();
+    let kind = entries
+        .iter()
+        .find(|(key, _)| *key == "storage_kind")
+        .and_then(|(_, val)| val.get_str())
+        .unwrap_or("unknown");
+    let mut ret = String::new();
+    for (key, val) in entries {
+        if let Some(val) = val.get_float() {
+            let name = format!("cozo_storage_{key}");
+            ret += &format!("# TYPE {name} gauge\n{name}{{engine=\"{kind}\"}} {val}\n");
+        }
+    }
+    ret
+}
+
 async fn root() -> Html<&'static str> {
     Html(include_str!("./index.html"))
 }
 
-fn internal_error<E>(err: E) -> (StatusCode, Json<serde_json::Value>)
+fn internal_error<E, B>(err: E) -> (StatusCode, B)
 where
     E: std::error::Error,
+    B: From<serde_json::Value>,
 {
     (
         StatusCode::INTERNAL_SERVER_ERROR,
@@ -572,6 +855,25 @@ where
     )
 }
 
+/// A JSON body in which floats are written in the format required by the query
+struct FormattedJson(serde_json::Value, FloatFormat);
+
+impl From<serde_json::Value> for FormattedJson {
+    fn from(value: serde_json::Value) -> Self {
+        Self(value, FloatFormat::Shortest)
+    }
+}
+
+impl IntoResponse for FormattedJson {
+    fn into_response(self) -> axum::response::Response {
+        (
+            [(header::CONTENT_TYPE, "application/json")],
+            json_to_string(&self.0, self.1),
+        )
+            .into_response()
+    }
+}
+
 fn wrap_json(json: serde_json::Value) -> (StatusCode, Json<serde_json::Value>) {
     let code = if let Some(serde_json::Value::Bool(true)) = json.get("ok") {
         StatusCode::OK
@@ -587,3 +889,134 @@ pub async fn not_found(uri: axum::http::Uri) -> (StatusCode, Json<serde_json::Va
         json!({"ok": false, "message": format!("No route {}", uri)}).into(),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_state(n_rows: usize, ttl: Duration, max_per_client: usize) -> DbState {
+        let db = DbInstance::new("mem", "", "").unwrap();
+        db.run_script(
+            &format!("?[i] := i in int_range({n_rows}) :create nums {{i}}"),
+            Default::default(),
+        )
+        .unwrap();
+        DbState {
+            db,
+            rule_senders: Default::default(),
+            rule_counter: Default::default(),
+            tx_counter: Default::default(),
+            txs: Default::default(),
+            cursors: Arc::new(Cursors::new(ttl, max_per_client)),
+        }
+    }
+
+    async fn open(st: &DbState, client: u8, page_size: usize) -> (StatusCode, serde_json::Value) {
+        let (status, FormattedJson(page, _)) = open_cursor(
+            State(st.clone()),
+            ConnectInfo(SocketAddr::from(([127, 0, 0, client], 4000))),
+            Query(CursorOptions { page_size }),
+            Json(QueryPayload {
+                script: "?[i] := *nums{i}".to_string(),
+                params: Default::default(),
+            }),
+        )
+        .await;
+        (status, page)
+    }
+
+    async fn fetch(st: &DbState, token: &str) -> (StatusCode, serde_json::Value) {
+        let (status, FormattedJson(page, _)) =
+            cursor_page(State(st.clone()), Path(token.to_string())).await;
+        (status, page)
+    }
+
+    #[tokio::test]
+    async fn paginate() {
+        let st = test_state(10000, Duration::from_secs(60), 4);
+        let (status, mut page) = open(&st, 1, 1000).await;
+        assert_eq!(status, StatusCode::OK);
+        let mut seen = vec![];
+        let mut n_pages = 1;
+        loop {
+            assert_eq!(page["headers"], json!(["i"]));
+            let rows = page["rows"].as_array().unwrap();
+            assert_eq!(rows.len(), 1000);
+            seen.extend(rows.iter().map(|row| row[0].as_i64().unwrap()));
+            let token = match page["next_token"].as_str() {
+                None => break,
+                Some(token) => token.to_string(),
+            };
+            assert_eq!(st.cursors.reap(), 1);
+            let (status, next) = fetch(&st, &token).await;
+            assert_eq!(status, StatusCode::OK);
+            page = next;
+            n_pages += 1;
+        }
+        assert_eq!(n_pages, 10);
+        assert_eq!(seen, (0..10000).collect_vec());
+        assert_eq!(st.cursors.reap(), 0);
+
+        let (status, page) = open(&st, 1, 20000).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(page["rows"].as_array().unwrap().len(), 10000);
+        assert_eq!(page["next_token"], json!(null));
+        assert_eq!(st.cursors.reap(), 0);
+    }
+
+    #[tokio::test]
+    async fn storage_metrics() {
+        let st = test_state(10, Duration::from_secs(60), 4);
+        let (status, body) = metrics(State(st)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("# TYPE cozo_storage_n_keys gauge\n"));
+        let n_keys = body
+            .lines()
+            .find_map(|line| line.strip_prefix("cozo_storage_n_keys{engine=\"mem\"} "))
+            .unwrap();
+        assert!(n_keys.parse::<f64>().unwrap() > 0.);
+        // only numbers are reported
+        assert!(!body.contains("storage_kind"));
+    }
+
+    #[tokio::test]
+    async fn expiry_and_release() {
+        let st = test_state(3000, Duration::from_millis(500), 4);
+        let (_, page) = open(&st, 1, 1000).await;
+        let expiring = page["next_token"].as_str().unwrap().to_string();
+        let (_, page) = open(&st, 1, 1000).await;
+        let released = page["next_token"].as_str().unwrap().to_string();
+
+        let (status, _) = release_cursor(State(st.clone()), Path(released.clone())).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = fetch(&st, &released).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["code"], json!("cursor::not_found"));
+
+        tokio::time::sleep(Duration::from_millis(700)).await;
+        let (status, body) = fetch(&st, &expiring).await;
+        assert_eq!(status, StatusCode::GONE);
+        assert_eq!(body["code"], json!("cursor::expired"));
+        let (status, body) = fetch(&st, "no-such-token").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["code"], json!("cursor::not_found"));
+    }
+
+    #[tokio::test]
+    async fn cleanup_after_clients_disappear() {
+        let st = test_state(3000, Duration::from_millis(500), 2);
+        for client in [1, 1, 2] {
+            let (status, _) = open(&st, client, 1000).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        let (status, body) = open(&st, 1, 1000).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(body["code"], json!("cursor::too_many"));
+        assert_eq!(st.cursors.reap(), 3);
+
+        tokio::time::sleep(Duration::from_millis(700)).await;
+        assert_eq!(st.cursors.reap(), 0);
+        let (status, _) = open(&st, 1, 1000).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
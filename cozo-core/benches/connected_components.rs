@@ -0,0 +1,66 @@
+/*
+ *  Copyright 2023, The Cozo Project Authors.
+ *
+ *  This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ *  If a copy of the MPL was not distributed with this file,
+ *  You can obtain one at https://mozilla.org/MPL/2.0/.
+ *
+ */
+#![feature(test)]
+
+extern crate test;
+
+use cozo::{new_cozo_mem, Db, MemStorage};
+use lazy_static::lazy_static;
+use test::Bencher;
+
+// each node is labelled by the smallest node it is connected to
+const RECURSIVE_QUERY: &str = "
+    cc[n, min(c)] := *edge{fr: n}, c = n
+    cc[n, min(c)] := *edge{to: n}, c = n
+    cc[n, min(c)] := *edge{fr: n, to: m}, cc[m, c]
+    cc[n, min(c)] := *edge{fr: m, to: n}, cc[m, c]
+    ?[count_unique(c)] := cc[_, c]
+";
+
+const FIXED_RULE_QUERY: &str = "
+    cc[n, c] <~ ConnectedComponents(*edge[])
+    ?[count_unique(c)] := cc[_, c]
+";
+
+lazy_static! {
+    // one million edges between half a million nodes
+    static ref TEST_DB: Db<MemStorage> = {
+        let db = new_cozo_mem().unwrap();
+        db.run_script(
+            r#"
+            ?[fr, to] := i in int_range(1000000), fr = (i * 7919) % 500000,
+                         to = (i * 104729 + 13) % 500000
+            :create edge {fr, to}
+            "#,
+            Default::default(),
+        )
+        .unwrap();
+        db
+    };
+}
+
+#[bench]
+fn recursive_rules(b: &mut Bencher) {
+    lazy_static::initialize(&TEST_DB);
+    b.iter(|| {
+        TEST_DB
+            .run_script(RECURSIVE_QUERY, Default::default())
+            .unwrap()
+    })
+}
+
+#[bench]
+fn union_find(b: &mut Bencher) {
+    lazy_static::initialize(&TEST_DB);
+    b.iter(|| {
+        TEST_DB
+            .run_script(FIXED_RULE_QUERY, Default::default())
+            .unwrap()
+    })
+}
@@ -0,0 +1,55 @@
+/*
+ *  Copyright 2023, The Cozo Project Authors.
+ *
+ *  This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ *  If a copy of the MPL was not distributed with this file,
+ *  You can obtain one at https://mozilla.org/MPL/2.0/.
+ *
+ */
+#![feature(test)]
+
+extern crate test;
+
+use cozo::{new_cozo_mem, Db, MemStorage};
+use lazy_static::lazy_static;
+use test::Bencher;
+
+// joins on `k`, which neither relation is ordered by
+const QUERY: &str = "?[count(v)] := *left_rel{k, v}, *right_rel{k}";
+
+fn make_db(hash_join_max_rows: usize) -> Db<MemStorage> {
+    let mut db = new_cozo_mem().unwrap();
+    db.set_hash_join_max_rows(hash_join_max_rows);
+    db.run_script(
+        r#"
+        {
+            ?[id, k, v] := id in int_range(100000), k = (id * 7919) % 50000, v = id
+            :create left_rel {id => k, v}
+        }
+        {
+            ?[id, k] := id in int_range(100000), k = (id * 104729) % 50000
+            :create right_rel {id => k}
+        }
+        "#,
+        Default::default(),
+    )
+    .unwrap();
+    db
+}
+
+lazy_static! {
+    static ref HASHED_DB: Db<MemStorage> = make_db(usize::MAX);
+    static ref SORTED_DB: Db<MemStorage> = make_db(0);
+}
+
+#[bench]
+fn hash_join(b: &mut Bencher) {
+    lazy_static::initialize(&HASHED_DB);
+    b.iter(|| HASHED_DB.run_script(QUERY, Default::default()).unwrap())
+}
+
+#[bench]
+fn materialized_join(b: &mut Bencher) {
+    lazy_static::initialize(&SORTED_DB);
+    b.iter(|| SORTED_DB.run_script(QUERY, Default::default()).unwrap())
+}
@@ -0,0 +1,88 @@
+/*
+ *  Copyright 2023, The Cozo Project Authors.
+ *
+ *  This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ *  If a copy of the MPL was not distributed with this file,
+ *  You can obtain one at https://mozilla.org/MPL/2.0/.
+ *
+ */
+#![feature(test)]
+
+extern crate test;
+
+use std::env;
+
+use cozo::{DataValue, DbInstance, ImportRowsOptions};
+use itertools::Itertools;
+use lazy_static::lazy_static;
+use test::Bencher;
+
+const N_ROWS: usize = 100_000;
+// rows in each generated `:put` script
+const SCRIPT_ROWS: usize = 1_000;
+
+lazy_static! {
+    // run with `COZO_TEST_DB_ENGINE=rocksdb` to compare on the rocksdb engine
+    static ref TEST_DB: DbInstance = {
+        let db_kind = env::var("COZO_TEST_DB_ENGINE").unwrap_or("mem".to_string());
+        let mut db_path = env::temp_dir();
+        db_path.push(format!("cozo-bench-import-rows-{}.db", db_kind));
+        let _ = std::fs::remove_file(&db_path);
+        let _ = std::fs::remove_dir_all(&db_path);
+        let db = DbInstance::new(&db_kind, db_path.to_str().unwrap(), "").unwrap();
+        db.run_script(
+            ":create bulk {k: Int => v: String, n: Float}",
+            Default::default(),
+        )
+        .unwrap();
+        db
+    };
+}
+
+fn rows() -> impl Iterator<Item = Vec<DataValue>> {
+    (0..N_ROWS as i64).map(|i| {
+        vec![
+            DataValue::from(i),
+            DataValue::from(format!("row {i}")),
+            DataValue::from(i as f64 / 3.),
+        ]
+    })
+}
+
+#[bench]
+fn put_scripts(b: &mut Bencher) {
+    lazy_static::initialize(&TEST_DB);
+    b.iter(|| {
+        for chunk in &(0..N_ROWS).chunks(SCRIPT_ROWS) {
+            let data = chunk
+                .map(|i| format!("[{i}, 'row {i}', {}]", i as f64 / 3.))
+                .join(", ");
+            let script = format!("?[k, v, n] <- [{data}] :put bulk {{k => v, n}}");
+            TEST_DB.run_script(&script, Default::default()).unwrap();
+        }
+    })
+}
+
+#[bench]
+fn import_rows(b: &mut Bencher) {
+    lazy_static::initialize(&TEST_DB);
+    b.iter(|| {
+        TEST_DB
+            .import_rows("bulk", &["k", "v", "n"], rows(), Default::default())
+            .unwrap()
+    })
+}
+
+#[bench]
+fn import_rows_unchecked(b: &mut Bencher) {
+    lazy_static::initialize(&TEST_DB);
+    let options = ImportRowsOptions {
+        unchecked: true,
+        ..Default::default()
+    };
+    b.iter(|| {
+        TEST_DB
+            .import_rows("bulk", &["k", "v", "n"], rows(), options.clone())
+            .unwrap()
+    })
+}
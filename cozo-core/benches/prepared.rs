@@ -0,0 +1,75 @@
+/*
+ *  Copyright 2023, The Cozo Project Authors.
+ *
+ *  This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ *  If a copy of the MPL was not distributed with this file,
+ *  You can obtain one at https://mozilla.org/MPL/2.0/.
+ *
+ */
+#![feature(test)]
+
+extern crate test;
+
+use cozo::{DataValue, DbInstance, PreparedQuery};
+use lazy_static::lazy_static;
+use rand::Rng;
+use std::collections::BTreeMap;
+use test::Bencher;
+
+const QUERY: &str = r#"
+    ?[name, friend_name, age] := *person{id: $id, name},
+                                 *friend{from: $id, to},
+                                 *person{id: to, name: friend_name, age},
+                                 age >= $min_age
+    :order -age
+"#;
+
+lazy_static! {
+    static ref TEST_DB: DbInstance = {
+        let db = DbInstance::new("mem", "", "").unwrap();
+        db.run_script(
+            r#"
+            {
+                ?[id, name, age] := id in int_range(10000), name = concat('p', to_string(id)),
+                                    age = id % 90
+                :create person {id: Int => name: String, age: Int}
+            }
+            {
+                ?[from, to] := from in int_range(10000), d in int_range(1, 6),
+                               to = (from * 7 + d * 13) % 10000
+                :create friend {from: Int, to: Int}
+            }
+            "#,
+            Default::default(),
+        )
+        .unwrap();
+        db
+    };
+    static ref PREPARED: PreparedQuery = TEST_DB.prepare(QUERY).unwrap();
+}
+
+fn random_params() -> BTreeMap<String, DataValue> {
+    let mut rng = rand::thread_rng();
+    BTreeMap::from([
+        (
+            "id".to_string(),
+            DataValue::from(rng.gen_range(0..10000i64)),
+        ),
+        (
+            "min_age".to_string(),
+            DataValue::from(rng.gen_range(0..90i64)),
+        ),
+    ])
+}
+
+#[bench]
+fn script_each_time(b: &mut Bencher) {
+    lazy_static::initialize(&TEST_DB);
+    b.iter(|| TEST_DB.run_script(QUERY, random_params()).unwrap())
+}
+
+#[bench]
+fn prepared_once(b: &mut Bencher) {
+    lazy_static::initialize(&PREPARED);
+    b.iter(|| TEST_DB.run_prepared(&PREPARED, random_params()).unwrap())
+}
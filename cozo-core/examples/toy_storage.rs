@@ -0,0 +1,230 @@
+/*
+ *  Copyright 2023, The Cozo Project Authors.
+ *
+ *  This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ *  If a copy of the MPL was not distributed with this file,
+ *  You can obtain one at https://mozilla.org/MPL/2.0/.
+ *
+ */
+
+//! A storage engine implemented outside of Cozo, keeping the data in a `BTreeMap`.
+//! It is checked with the conformance suite, then used for a database.
+//!
+//! Run with `cargo run --example toy_storage --features storage-dev`.
+
+use std::collections::BTreeMap;
+use std::mem;
+use std::ops::RangeBounds;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+
+use cozo::format::{check_key_for_validity, extend_tuple_from_v, Tuple};
+use cozo::{storage_test_suite, Db, Storage, StoreTx, ValidityTs};
+use miette::{bail, Result};
+
+type Map = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// Transactions read from a snapshot of the data, taken when they start.
+/// Writers also hold the writer lock, so that there is only one at a time.
+#[derive(Clone, Default)]
+struct ToyStorage {
+    data: Arc<RwLock<Arc<Map>>>,
+    writer: Arc<Mutex<()>>,
+}
+
+struct ToyTx<'s> {
+    storage: &'s ToyStorage,
+    snapshot: Arc<Map>,
+    /// the writes of the transaction, `None` for deletions
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    /// held by write transactions
+    writer: Option<MutexGuard<'s, ()>>,
+}
+
+impl<'s> Storage<'s> for ToyStorage {
+    type Tx = ToyTx<'s>;
+
+    fn storage_kind(&self) -> &'static str {
+        "toy"
+    }
+
+    fn transact(&'s self, write: bool) -> Result<Self::Tx> {
+        // the writer lock is taken first, so that writers see the data committed before them
+        let writer = write.then(|| self.writer.lock().unwrap());
+        Ok(ToyTx {
+            storage: self,
+            snapshot: self.data.read().unwrap().clone(),
+            writes: Default::default(),
+            writer,
+        })
+    }
+
+    fn del_range(&'s self, lower: &[u8], upper: &[u8]) -> Result<()> {
+        // this may be called while a write transaction is held, so the writer lock is not
+        // taken: as the range is never written to again, the writer cannot undo the deletion
+        let mut data = self.data.write().unwrap();
+        let mut updated = (**data).clone();
+        updated.retain(|k, _| !(lower <= k.as_slice() && k.as_slice() < upper));
+        *data = Arc::new(updated);
+        Ok(())
+    }
+
+    fn range_compact(&'s self, _lower: &[u8], _upper: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    fn batch_put<'a>(
+        &'a self,
+        data: Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>,
+    ) -> Result<()> {
+        let _writer = self.writer.lock().unwrap();
+        let mut updated = (**self.data.read().unwrap()).clone();
+        for pair in data {
+            let (k, v) = pair?;
+            updated.insert(k, v);
+        }
+        *self.data.write().unwrap() = Arc::new(updated);
+        Ok(())
+    }
+}
+
+impl ToyTx<'_> {
+    /// The data in the range as seen by the transaction, with its own writes
+    fn merged(&self, range: impl RangeBounds<Vec<u8>> + Clone) -> Map {
+        let mut ret: Map = self
+            .snapshot
+            .range(range.clone())
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        for (k, v) in self.writes.range(range) {
+            match v {
+                Some(v) => ret.insert(k.clone(), v.clone()),
+                None => ret.remove(k),
+            };
+        }
+        ret
+    }
+    fn merged_between(&self, lower: &[u8], upper: &[u8]) -> Map {
+        if lower >= upper {
+            return Map::new();
+        }
+        self.merged(lower.to_vec()..upper.to_vec())
+    }
+    fn ensure_writer(&self) -> Result<()> {
+        if self.writer.is_none() {
+            bail!("write in read transaction")
+        }
+        Ok(())
+    }
+}
+
+impl<'s> StoreTx<'s> for ToyTx<'s> {
+    fn get(&self, key: &[u8], _for_update: bool) -> Result<Option<Vec<u8>>> {
+        // writers are not concurrent, so no write can conflict with this one
+        Ok(match self.writes.get(key) {
+            Some(v) => v.clone(),
+            None => self.snapshot.get(key).cloned(),
+        })
+    }
+
+    fn put(&mut self, key: &[u8], val: &[u8]) -> Result<()> {
+        self.ensure_writer()?;
+        self.writes.insert(key.to_vec(), Some(val.to_vec()));
+        Ok(())
+    }
+
+    fn supports_par_put(&self) -> bool {
+        false
+    }
+
+    fn par_put(&self, _key: &[u8], _val: &[u8]) -> Result<()> {
+        bail!("the toy storage does not support parallel puts")
+    }
+
+    fn del(&mut self, key: &[u8]) -> Result<()> {
+        self.ensure_writer()?;
+        self.writes.insert(key.to_vec(), None);
+        Ok(())
+    }
+
+    fn exists(&self, key: &[u8], for_update: bool) -> Result<bool> {
+        Ok(self.get(key, for_update)?.is_some())
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        if self.writer.is_none() {
+            return Ok(());
+        }
+        // the writes are applied to the current data rather than the snapshot,
+        // which may have had ranges deleted since
+        let mut data = self.storage.data.write().unwrap();
+        let mut updated = (**data).clone();
+        for (k, v) in mem::take(&mut self.writes) {
+            match v {
+                Some(v) => updated.insert(k, v),
+                None => updated.remove(&k),
+            };
+        }
+        *data = Arc::new(updated);
+        Ok(())
+    }
+
+    fn range_skip_scan_tuple<'a>(
+        &'a self,
+        lower: &[u8],
+        upper: &[u8],
+        valid_at: ValidityTs,
+    ) -> Box<dyn Iterator<Item = Result<Tuple>> + 'a> {
+        let data = self.merged_between(lower, upper);
+        let mut ret = vec![];
+        // seek to the version valid at `valid_at` of each key in turn
+        let mut seek = lower.to_vec();
+        while let Some((k, v)) = data.range(seek.clone()..).next() {
+            let (found, next) = match check_key_for_validity(k, valid_at) {
+                Ok(res) => res,
+                Err(err) => {
+                    ret.push(Err(err));
+                    break;
+                }
+            };
+            if let Some(mut tup) = found {
+                ret.push(extend_tuple_from_v(&mut tup, v).map(|_| tup));
+            }
+            seek = next;
+        }
+        Box::new(ret.into_iter())
+    }
+
+    fn range_scan<'a>(
+        &'a self,
+        lower: &[u8],
+        upper: &[u8],
+    ) -> Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>
+    where
+        's: 'a,
+    {
+        Box::new(self.merged_between(lower, upper).into_iter().map(Ok))
+    }
+
+    fn total_scan<'a>(&'a self) -> Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>
+    where
+        's: 'a,
+    {
+        Box::new(self.merged(..).into_iter().map(Ok))
+    }
+}
+
+fn main() -> Result<()> {
+    let storage = ToyStorage::default();
+    storage_test_suite(|| storage.clone());
+    println!("the toy storage passes the conformance suite");
+
+    let db = Db::new(ToyStorage::default())?;
+    db.initialize()?;
+    db.run_script(
+        "?[name, legs] <- [['cat', 4], ['bird', 2]] :create animals {name => legs}",
+        Default::default(),
+    )?;
+    let rows = db.run_script("?[name] := *animals{name, legs: 4}", Default::default())?;
+    println!("{}", rows.into_json());
+    Ok(())
+}
@@ -11,6 +11,7 @@ use std::fmt::{Debug, Formatter};
 
 use miette::{bail, ensure, miette, Result};
 use rand::prelude::*;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use crate::data::value::DataValue;
 
@@ -32,9 +33,47 @@ impl Clone for Aggregation {
     }
 }
 
+/// Aggregations are serialized by name, the operators are never part of the serialized form
+/// as they are only initialized during evaluation.
+impl Serialize for Aggregation {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        (self.name, self.is_meet).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Aggregation {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let (name, is_meet) = <(String, bool)>::deserialize(deserializer)?;
+        let short_name = name
+            .strip_prefix("AGGR_")
+            .unwrap_or(&name)
+            .to_ascii_lowercase();
+        let found = parse_aggr(&short_name).ok_or_else(|| {
+            serde::de::Error::custom(format!("aggregation not found in serialized data: {name}"))
+        })?;
+        Ok(Self {
+            name: found.name,
+            is_meet,
+            meet_op: None,
+            normal_op: None,
+        })
+    }
+}
+
 pub(crate) trait NormalAggrObj: Send + Sync {
     fn set(&mut self, value: &DataValue) -> Result<()>;
     fn get(&self) -> Result<DataValue>;
+    /// If this returns true, no further input can change the result,
+    /// and evaluation of the rule may stop early.
+    fn is_determined(&self) -> bool {
+        false
+    }
 }
 
 pub(crate) trait MeetAggrObj: Send + Sync {
@@ -150,6 +189,128 @@ impl MeetAggrObj for MeetAggrOr {
     }
 }
 
+define_aggr!(AGGR_ANY, false);
+
+/// The `bind_witness` argument of `any` and `all`: if true, the aggregated values are
+/// lists `[cond, witness]`, and the result is `[answer, witness]` where `witness` is
+/// that of the row deciding the answer, or null if no row did
+fn bind_witness_arg(name: &str, args: &[DataValue]) -> Result<bool> {
+    match args {
+        [] => Ok(false),
+        [DataValue::Bool(b)] => Ok(*b),
+        args => bail!(
+            "the argument 'bind_witness' to '{}' must be a boolean, got {:?}",
+            name,
+            args
+        ),
+    }
+}
+
+/// The condition and the witness of a value aggregated by `any` or `all`
+fn cond_and_witness<'a>(
+    name: &str,
+    bind_witness: bool,
+    value: &'a DataValue,
+) -> Result<(bool, Option<&'a DataValue>)> {
+    match (bind_witness, value) {
+        (false, DataValue::Bool(v)) => Ok((*v, None)),
+        (true, DataValue::List(l)) if l.len() == 2 => match &l[0] {
+            DataValue::Bool(v) => Ok((*v, Some(&l[1]))),
+            v => bail!("cannot compute '{}' for {:?}", name, v),
+        },
+        (true, v) => bail!(
+            "'{}' with 'bind_witness' requires a list [cond, witness] as argument, got {:?}",
+            name,
+            v
+        ),
+        (false, v) => bail!("cannot compute '{}' for {:?}", name, v),
+    }
+}
+
+fn with_witness(answer: bool, bind_witness: bool, witness: &DataValue) -> DataValue {
+    if bind_witness {
+        DataValue::List(vec![DataValue::from(answer), witness.clone()])
+    } else {
+        DataValue::from(answer)
+    }
+}
+
+pub(crate) struct AggrAny {
+    accum: bool,
+    bind_witness: bool,
+    witness: DataValue,
+}
+
+impl AggrAny {
+    fn new(bind_witness: bool) -> Self {
+        Self {
+            accum: false,
+            bind_witness,
+            witness: DataValue::Null,
+        }
+    }
+}
+
+impl NormalAggrObj for AggrAny {
+    fn set(&mut self, value: &DataValue) -> Result<()> {
+        let (v, witness) = cond_and_witness("any", self.bind_witness, value)?;
+        if v && !self.accum {
+            self.accum = true;
+            if let Some(witness) = witness {
+                self.witness = witness.clone();
+            }
+        }
+        Ok(())
+    }
+
+    fn get(&self) -> Result<DataValue> {
+        Ok(with_witness(self.accum, self.bind_witness, &self.witness))
+    }
+
+    fn is_determined(&self) -> bool {
+        self.accum
+    }
+}
+
+define_aggr!(AGGR_ALL, false);
+
+pub(crate) struct AggrAll {
+    accum: bool,
+    bind_witness: bool,
+    witness: DataValue,
+}
+
+impl AggrAll {
+    fn new(bind_witness: bool) -> Self {
+        Self {
+            accum: true,
+            bind_witness,
+            witness: DataValue::Null,
+        }
+    }
+}
+
+impl NormalAggrObj for AggrAll {
+    fn set(&mut self, value: &DataValue) -> Result<()> {
+        let (v, witness) = cond_and_witness("all", self.bind_witness, value)?;
+        if !v && self.accum {
+            self.accum = false;
+            if let Some(witness) = witness {
+                self.witness = witness.clone();
+            }
+        }
+        Ok(())
+    }
+
+    fn get(&self) -> Result<DataValue> {
+        Ok(with_witness(self.accum, self.bind_witness, &self.witness))
+    }
+
+    fn is_determined(&self) -> bool {
+        !self.accum
+    }
+}
+
 define_aggr!(AGGR_UNIQUE, false);
 
 #[derive(Default)]
@@ -1159,6 +1320,8 @@ pub(crate) fn parse_aggr(name: &str) -> Option<&'static Aggregation> {
     Some(match name {
         "and" => &AGGR_AND,
         "or" => &AGGR_OR,
+        "any" => &AGGR_ANY,
+        "all" => &AGGR_ALL,
         "unique" => &AGGR_UNIQUE,
         "group_count" => &AGGR_GROUP_COUNT,
         "union" => &AGGR_UNION,
@@ -1209,6 +1372,8 @@ impl Aggregation {
         self.normal_op.replace(match self.name {
             name if name == AGGR_AND.name => Box::new(AggrAnd::default()),
             name if name == AGGR_OR.name => Box::new(AggrOr::default()),
+            name if name == AGGR_ANY.name => Box::new(AggrAny::new(bind_witness_arg("any", args)?)),
+            name if name == AGGR_ALL.name => Box::new(AggrAll::new(bind_witness_arg("all", args)?)),
             name if name == AGGR_COUNT.name => Box::new(AggrCount::default()),
             name if name == AGGR_GROUP_COUNT.name => Box::new(AggrGroupCount::default()),
             name if name == AGGR_COUNT_UNIQUE.name => Box::new(AggrCountUnique::default()),
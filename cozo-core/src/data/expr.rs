@@ -6,21 +6,27 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+use std::cell::RefCell;
 use std::cmp::{max, min};
 use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{Debug, Display, Formatter};
 use std::mem;
+use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
 use itertools::Itertools;
-use miette::{bail, Diagnostic, Result};
+use log::warn;
+use miette::{bail, ensure, Diagnostic, Result};
 use serde::de::{Error, Visitor};
 use serde::{Deserializer, Serializer};
-use smartstring::SmartString;
+use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
 use crate::data::functions::*;
+use crate::data::json_path::JsonPath;
 use crate::data::symb::Symbol;
-use crate::data::value::{DataValue, LARGEST_UTF_CHAR};
+use crate::data::value::{DataValue, RegexWrapper, LARGEST_UTF_CHAR};
 use crate::parse::expr::expr2bytecode;
 use crate::parse::SourceSpan;
 
@@ -56,6 +62,231 @@ pub enum Bytecode {
         #[serde(skip)]
         span: SourceSpan,
     },
+    /// unchanged, registers an error handler jumping to `catch_to`
+    TryStart {
+        catch_to: usize,
+        warn: bool,
+        #[serde(skip)]
+        span: SourceSpan,
+    },
+    /// unchanged, discards the innermost error handler
+    TryEnd {
+        jump_to: usize,
+        #[serde(skip)]
+        span: SourceSpan,
+    },
+    /// push 1, replaced by a constant before evaluation
+    Param {
+        name: SmartString<LazyCompact>,
+        #[serde(skip)]
+        span: SourceSpan,
+    },
+    /// pop 1, push 1, compiles a pattern into a regex, reusing the last one compiled
+    Regex {
+        #[serde(skip)]
+        cache: RegexCache,
+        #[serde(skip)]
+        span: SourceSpan,
+    },
+    /// pop n, push 1, applies a function registered with the database
+    UserFn {
+        name: SmartString<LazyCompact>,
+        #[serde(serialize_with = "serialize_user_fn")]
+        #[serde(deserialize_with = "deserialize_user_fn")]
+        func: Option<Arc<UserFunction>>,
+        arity: usize,
+        #[serde(skip)]
+        span: SourceSpan,
+    },
+}
+
+/// A function registered with [crate::Db::register_function], found by its name when
+/// the queries calling it are compiled
+pub struct UserFunction {
+    pub(crate) name: String,
+    pub(crate) arity: usize,
+    /// whether applications to constants can be evaluated when the query is compiled
+    pub(crate) pure: bool,
+    pub(crate) inner: Box<dyn Fn(&[DataValue]) -> Result<DataValue> + Send + Sync>,
+}
+
+impl PartialEq for UserFunction {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Eq for UserFunction {}
+
+impl Debug for UserFunction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.name, self.arity)
+    }
+}
+
+/// Functions registered with the database hold closures, so plans calling them cannot be
+/// serialized, and are not cached
+fn serialize_user_fn<S>(
+    func: &Option<Arc<UserFunction>>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match func {
+        None => serializer.serialize_none(),
+        Some(func) => Err(serde::ser::Error::custom(format!(
+            "the registered function '{}' cannot be serialized",
+            func.name
+        ))),
+    }
+}
+
+fn deserialize_user_fn<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Option<Arc<UserFunction>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    <Option<()> as serde::Deserialize>::deserialize(deserializer)?;
+    Ok(None)
+}
+
+#[derive(Error, Diagnostic, Debug)]
+#[error("Named function '{0}' not found")]
+#[diagnostic(code(parser::func_not_function))]
+pub(crate) struct FuncNotFoundError(pub(crate) String, #[label] pub(crate) SourceSpan);
+
+fn apply_user_fn(
+    name: &str,
+    func: &Option<Arc<UserFunction>>,
+    args: &[DataValue],
+    span: SourceSpan,
+) -> Result<DataValue> {
+    match func {
+        None => bail!(FuncNotFoundError(name.to_string(), span)),
+        Some(func) => Ok((func.inner)(args).map_err(|err| EvalRaisedError(span, err.to_string()))?),
+    }
+}
+
+/// The last regex compiled by a [Bytecode::Regex] instruction, shared by the clones of the
+/// program, so that patterns are compiled once and not for every row.
+/// Programs are equal regardless of what is cached.
+#[derive(Clone, Default)]
+pub struct RegexCache(Arc<RegexCacheInner>);
+
+#[derive(Default)]
+struct RegexCacheInner {
+    regex: Mutex<Option<RegexWrapper>>,
+    compilations: AtomicUsize,
+}
+
+impl PartialEq for RegexCache {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for RegexCache {}
+
+impl Debug for RegexCache {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RegexCache")
+            .field("compilations", &self.compilations())
+            .finish()
+    }
+}
+
+impl RegexCache {
+    fn get_or_compile(&self, pattern: &DataValue) -> Result<DataValue> {
+        let s = match pattern {
+            DataValue::Str(s) => s,
+            _ => return op_regex(slice::from_ref(pattern)),
+        };
+        let mut cached = self.0.regex.lock().unwrap();
+        if let Some(r) = &*cached {
+            if r.0.as_str() == s {
+                return Ok(DataValue::Regex(r.clone()));
+            }
+        }
+        let compiled = op_regex(slice::from_ref(pattern))?;
+        self.0.compilations.fetch_add(1, Ordering::Relaxed);
+        if let DataValue::Regex(r) = &compiled {
+            *cached = Some(r.clone());
+        }
+        Ok(compiled)
+    }
+    /// The number of times patterns have been compiled
+    pub(crate) fn compilations(&self) -> usize {
+        self.0.compilations.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Error, Diagnostic, Debug)]
+#[error("Required parameter {0} not found")]
+#[diagnostic(code(parser::param_not_found))]
+pub(crate) struct ParamNotFoundError(pub(crate) String, #[label] pub(crate) SourceSpan);
+
+/// Replaces the parameters in the bytecodes by their values, see [Expr::bind_params]
+pub(crate) fn bind_params_in_bytecodes(
+    bytecodes: &mut [Bytecode],
+    params: &BTreeMap<String, DataValue>,
+) -> Result<()> {
+    for bytecode in bytecodes {
+        if let Bytecode::Param { name, span } = bytecode {
+            let span = *span;
+            let val = params
+                .get(name.as_str())
+                .ok_or_else(|| ParamNotFoundError(name.to_string(), span))?
+                .clone();
+            *bytecode = Bytecode::Const { val, span };
+        }
+    }
+    Ok(())
+}
+
+/// Collects the warnings of `try` expressions recovering from errors while a query is
+/// evaluated, to be returned with its result. Warnings raised on threads without a collector
+/// installed are logged instead.
+#[derive(Clone, Default)]
+pub(crate) struct TryWarnings(Arc<Mutex<Vec<String>>>);
+
+thread_local! {
+    static TRY_WARNINGS: RefCell<Option<TryWarnings>> = const { RefCell::new(None) };
+}
+
+/// Puts back the collector installed before [TryWarnings::scoped], also on panics
+struct RestoreTryWarnings(Option<TryWarnings>);
+
+impl Drop for RestoreTryWarnings {
+    fn drop(&mut self) {
+        let prev = self.0.take();
+        TRY_WARNINGS.with(|cur| *cur.borrow_mut() = prev);
+    }
+}
+
+impl TryWarnings {
+    /// The collector installed on the current thread, to be installed on the other threads
+    /// evaluating parts of the same query
+    pub(crate) fn current() -> Option<Self> {
+        TRY_WARNINGS.with(|cur| cur.borrow().clone())
+    }
+    /// Runs `f` with the warnings raised on the current thread going to `warnings`
+    pub(crate) fn scoped<T>(warnings: Option<&Self>, f: impl FnOnce() -> T) -> T {
+        let prev = TRY_WARNINGS.with(|cur| cur.replace(warnings.cloned()));
+        let _restore = RestoreTryWarnings(prev);
+        f()
+    }
+    /// The warnings collected so far, leaving none
+    pub(crate) fn take(&self) -> Vec<String> {
+        mem::take(&mut *self.0.lock().unwrap())
+    }
+    fn report(msg: String) {
+        TRY_WARNINGS.with(|cur| match &*cur.borrow() {
+            Some(warnings) => warnings.0.lock().unwrap().push(msg),
+            None => warn!("{}", msg),
+        })
+    }
 }
 
 #[derive(Error, Diagnostic, Debug)]
@@ -87,72 +318,114 @@ pub fn eval_bytecode(
     stack: &mut Vec<DataValue>,
 ) -> Result<DataValue> {
     stack.clear();
+    let bindings = bindings.as_ref();
     let mut pointer = 0;
-    // for (i, c) in bytecodes.iter().enumerate() {
-    //     println!("{i}  {c:?}");
-    // }
-    // println!();
+    // error handlers registered by `try`: (jump target, stack height, whether to warn)
+    let mut handlers: Vec<(usize, usize, bool)> = vec![];
     loop {
-        // println!("{pointer}  {stack:?}");
         if pointer == bytecodes.len() {
             break;
         }
-        let current_instruction = &bytecodes[pointer];
-        // println!("{current_instruction:?}");
-        match current_instruction {
-            Bytecode::Binding { var, tuple_pos, .. } => match tuple_pos {
-                None => {
-                    bail!(UnboundVariableError(var.name.to_string(), var.span))
-                }
-                Some(i) => {
-                    let val = bindings
-                        .as_ref()
-                        .get(*i)
-                        .ok_or_else(|| {
-                            TupleTooShortError(
-                                var.name.to_string(),
-                                *i,
-                                bindings.as_ref().len(),
-                                var.span,
-                            )
-                        })?
-                        .clone();
-                    stack.push(val);
-                    pointer += 1;
+        match eval_bytecode_step(bytecodes, pointer, bindings, stack, &mut handlers) {
+            Ok(next) => pointer = next,
+            Err(err) => match handlers.pop() {
+                None => return Err(err),
+                Some((catch_to, height, warn)) => {
+                    if warn {
+                        TryWarnings::report(format!(
+                            "error recovered by 'try' for row {bindings:?}: {err}"
+                        ));
+                    }
+                    stack.truncate(height);
+                    pointer = catch_to;
                 }
             },
-            Bytecode::Const { val, .. } => {
-                stack.push(val.clone());
-                pointer += 1;
-            }
-            Bytecode::Apply { op, arity, span } => {
-                let frame_start = stack.len() - *arity;
-                let args_frame = &stack[frame_start..];
-                let result = (op.inner)(args_frame)
-                    .map_err(|err| EvalRaisedError(*span, err.to_string()))?;
-                stack.truncate(frame_start);
-                stack.push(result);
-                pointer += 1;
-            }
-            Bytecode::JumpIfFalse { jump_to, span } => {
-                let val = stack.pop().unwrap();
-                let cond = val
-                    .get_bool()
-                    .ok_or_else(|| PredicateTypeError(*span, val))?;
-                if cond {
-                    pointer += 1;
-                } else {
-                    pointer = *jump_to;
-                }
-            }
-            Bytecode::Goto { jump_to, .. } => {
-                pointer = *jump_to;
-            }
         }
     }
     Ok(stack.pop().unwrap())
 }
 
+/// Execute a single instruction, returning the position of the next instruction
+fn eval_bytecode_step(
+    bytecodes: &[Bytecode],
+    pointer: usize,
+    bindings: &[DataValue],
+    stack: &mut Vec<DataValue>,
+    handlers: &mut Vec<(usize, usize, bool)>,
+) -> Result<usize> {
+    Ok(match &bytecodes[pointer] {
+        Bytecode::Binding { var, tuple_pos, .. } => match tuple_pos {
+            None => {
+                bail!(UnboundVariableError(var.name.to_string(), var.span))
+            }
+            Some(i) => {
+                let val = bindings
+                    .get(*i)
+                    .ok_or_else(|| {
+                        TupleTooShortError(var.name.to_string(), *i, bindings.len(), var.span)
+                    })?
+                    .clone();
+                stack.push(val);
+                pointer + 1
+            }
+        },
+        Bytecode::Const { val, .. } => {
+            stack.push(val.clone());
+            pointer + 1
+        }
+        Bytecode::Apply { op, arity, span } => {
+            let frame_start = stack.len() - *arity;
+            let args_frame = &stack[frame_start..];
+            let result =
+                (op.inner)(args_frame).map_err(|err| EvalRaisedError(*span, err.to_string()))?;
+            stack.truncate(frame_start);
+            stack.push(result);
+            pointer + 1
+        }
+        Bytecode::JumpIfFalse { jump_to, span } => {
+            let val = stack.pop().unwrap();
+            let cond = val
+                .get_bool()
+                .ok_or_else(|| PredicateTypeError(*span, val))?;
+            if cond {
+                pointer + 1
+            } else {
+                *jump_to
+            }
+        }
+        Bytecode::Goto { jump_to, .. } => *jump_to,
+        Bytecode::TryStart { catch_to, warn, .. } => {
+            handlers.push((*catch_to, stack.len(), *warn));
+            pointer + 1
+        }
+        Bytecode::TryEnd { jump_to, .. } => {
+            handlers.pop();
+            *jump_to
+        }
+        Bytecode::Param { name, span } => bail!(ParamNotFoundError(name.to_string(), *span)),
+        Bytecode::Regex { cache, span } => {
+            let pattern = stack.pop().unwrap();
+            let regex = cache
+                .get_or_compile(&pattern)
+                .map_err(|err| EvalRaisedError(*span, err.to_string()))?;
+            stack.push(regex);
+            pointer + 1
+        }
+        Bytecode::UserFn {
+            name,
+            func,
+            arity,
+            span,
+        } => {
+            let frame_start = stack.len() - *arity;
+            let result = apply_user_fn(name, func, &stack[frame_start..], *span)?;
+            stack.truncate(frame_start);
+            stack.push(result);
+            pointer + 1
+        }
+    })
+}
+
 /// Expression can be evaluated to yield a DataValue
 #[derive(Clone, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize)]
 pub enum Expr {
@@ -189,6 +462,41 @@ pub enum Expr {
         #[serde(skip)]
         span: SourceSpan,
     },
+    /// Error recovery: evaluates to `fallback` if the evaluation of `expr` raises an error
+    Try {
+        /// The expression that may fail
+        expr: Box<Expr>,
+        /// The expression to evaluate on failure, errors raised by it are not recovered
+        fallback: Box<Expr>,
+        /// Whether to report a warning containing the current row when recovering,
+        /// see [TryWarnings]
+        warn: bool,
+        /// Source span
+        #[serde(skip)]
+        span: SourceSpan,
+    },
+    /// Parameter of a prepared query, replaced by its value before evaluation
+    Param {
+        /// The name of the parameter, without the `$`
+        name: SmartString<LazyCompact>,
+        /// Source span
+        #[serde(skip)]
+        span: SourceSpan,
+    },
+    /// Application of a function registered with the database
+    UserFn {
+        /// The name of the function
+        name: SmartString<LazyCompact>,
+        /// The function, found when the query is compiled
+        #[serde(serialize_with = "serialize_user_fn")]
+        #[serde(deserialize_with = "deserialize_user_fn")]
+        func: Option<Arc<UserFunction>>,
+        /// Arguments to the application
+        args: Box<[Expr]>,
+        /// Source span
+        #[serde(skip)]
+        span: SourceSpan,
+    },
 }
 
 impl Debug for Expr {
@@ -207,8 +515,12 @@ impl Display for Expr {
                 write!(f, "{val}")
             }
             Expr::Apply { op, args, .. } => {
-                let mut writer =
-                    f.debug_tuple(op.name.strip_prefix("OP_").unwrap().to_lowercase().as_str());
+                let name = op.name.strip_prefix("OP_").unwrap().to_lowercase();
+                if args.is_empty() {
+                    // a tuple without fields would be written without the parentheses
+                    return write!(f, "{name}()");
+                }
+                let mut writer = f.debug_tuple(&name);
                 for arg in args.iter() {
                     writer.field(arg);
                 }
@@ -222,6 +534,25 @@ impl Display for Expr {
                 }
                 writer.finish()
             }
+            Expr::Try { expr, fallback, .. } => {
+                let mut writer = f.debug_tuple("try");
+                writer.field(expr);
+                writer.field(fallback);
+                writer.finish()
+            }
+            Expr::Param { name, .. } => {
+                write!(f, "${name}")
+            }
+            Expr::UserFn { name, args, .. } => {
+                if args.is_empty() {
+                    return write!(f, "{name}()");
+                }
+                let mut writer = f.debug_tuple(name);
+                for arg in args.iter() {
+                    writer.field(arg);
+                }
+                writer.finish()
+            }
         }
     }
 }
@@ -251,7 +582,12 @@ impl Expr {
     pub(crate) fn span(&self) -> SourceSpan {
         match self {
             Expr::Binding { var, .. } => var.span,
-            Expr::Const { span, .. } | Expr::Apply { span, .. } | Expr::Cond { span, .. } => *span,
+            Expr::Const { span, .. }
+            | Expr::Apply { span, .. }
+            | Expr::Cond { span, .. }
+            | Expr::Try { span, .. }
+            | Expr::Param { span, .. }
+            | Expr::UserFn { span, .. } => *span,
         }
     }
     pub(crate) fn get_binding(&self) -> Option<&Symbol> {
@@ -312,8 +648,8 @@ impl Expr {
                     .ok_or_else(|| BadBindingError(var.to_string(), var.span))?;
                 *tuple_pos = Some(found_idx)
             }
-            Expr::Const { .. } => {}
-            Expr::Apply { args, .. } => {
+            Expr::Const { .. } | Expr::Param { .. } => {}
+            Expr::Apply { args, .. } | Expr::UserFn { args, .. } => {
                 for arg in args.iter_mut() {
                     arg.fill_binding_indices(binding_map)?;
                 }
@@ -324,9 +660,85 @@ impl Expr {
                     val.fill_binding_indices(binding_map)?;
                 }
             }
+            Expr::Try { expr, fallback, .. } => {
+                expr.fill_binding_indices(binding_map)?;
+                fallback.fill_binding_indices(binding_map)?;
+            }
         }
         Ok(())
     }
+    /// Renames the variables found in `renames`, leaving the others unchanged
+    pub(crate) fn rename_bindings(&mut self, renames: &BTreeMap<Symbol, Symbol>) {
+        match self {
+            Expr::Binding { var, .. } => {
+                if let Some(renamed) = renames.get(var) {
+                    *var = renamed.clone();
+                }
+            }
+            Expr::Const { .. } | Expr::Param { .. } => {}
+            Expr::Apply { args, .. } | Expr::UserFn { args, .. } => {
+                for arg in args.iter_mut() {
+                    arg.rename_bindings(renames);
+                }
+            }
+            Expr::Cond { clauses, .. } => {
+                for (cond, val) in clauses {
+                    cond.rename_bindings(renames);
+                    val.rename_bindings(renames);
+                }
+            }
+            Expr::Try { expr, fallback, .. } => {
+                expr.rename_bindings(renames);
+                fallback.rename_bindings(renames);
+            }
+        }
+    }
+    /// Replaces the parameters of a prepared query by their values
+    pub(crate) fn bind_params(&mut self, params: &BTreeMap<String, DataValue>) -> Result<()> {
+        match self {
+            Expr::Param { name, span } => {
+                let span = *span;
+                let val = params
+                    .get(name.as_str())
+                    .ok_or_else(|| ParamNotFoundError(name.to_string(), span))?
+                    .clone();
+                *self = Expr::Const { val, span };
+            }
+            Expr::Binding { .. } | Expr::Const { .. } => {}
+            Expr::Apply { args, .. } | Expr::UserFn { args, .. } => {
+                for arg in args.iter_mut() {
+                    arg.bind_params(params)?;
+                }
+            }
+            Expr::Cond { clauses, .. } => {
+                for (cond, val) in clauses {
+                    cond.bind_params(params)?;
+                    val.bind_params(params)?;
+                }
+            }
+            Expr::Try { expr, fallback, .. } => {
+                expr.bind_params(params)?;
+                fallback.bind_params(params)?;
+            }
+        }
+        Ok(())
+    }
+    /// The first parameter left unbound in the expression, if any
+    fn first_param(&self) -> Option<(&str, SourceSpan)> {
+        match self {
+            Expr::Param { name, span } => Some((name.as_str(), *span)),
+            Expr::Binding { .. } | Expr::Const { .. } => None,
+            Expr::Apply { args, .. } | Expr::UserFn { args, .. } => {
+                args.iter().find_map(|arg| arg.first_param())
+            }
+            Expr::Cond { clauses, .. } => clauses
+                .iter()
+                .find_map(|(cond, val)| cond.first_param().or_else(|| val.first_param())),
+            Expr::Try { expr, fallback, .. } => {
+                expr.first_param().or_else(|| fallback.first_param())
+            }
+        }
+    }
     #[allow(dead_code)]
     pub(crate) fn binding_indices(&self) -> BTreeSet<usize> {
         let mut ret = BTreeSet::default();
@@ -341,8 +753,8 @@ impl Expr {
                     coll.insert(*idx);
                 }
             }
-            Expr::Const { .. } => {}
-            Expr::Apply { args, .. } => {
+            Expr::Const { .. } | Expr::Param { .. } => {}
+            Expr::Apply { args, .. } | Expr::UserFn { args, .. } => {
                 for arg in args.iter() {
                     arg.do_binding_indices(coll);
                 }
@@ -352,11 +764,76 @@ impl Expr {
                     cond.do_binding_indices(coll);
                     val.do_binding_indices(coll)
                 }
-            } // Expr::Try { clauses, .. } => {
-              //     for clause in clauses {
-              //         clause.do_binding_indices(coll)
-              //     }
-              // }
+            }
+            Expr::Try { expr, fallback, .. } => {
+                expr.do_binding_indices(coll);
+                fallback.do_binding_indices(coll)
+            }
+        }
+    }
+    /// Finds the functions registered with the database that the expression applies
+    pub(crate) fn resolve_user_fns(
+        &mut self,
+        registry: &BTreeMap<String, Arc<UserFunction>>,
+    ) -> Result<()> {
+        match self {
+            Expr::UserFn {
+                name,
+                func,
+                args,
+                span,
+            } => {
+                #[derive(Error, Diagnostic, Debug)]
+                #[error("Wrong number of arguments for function '{0}'")]
+                #[diagnostic(code(parser::func_wrong_num_args))]
+                #[diagnostic(help("Need exactly {1} argument(s)"))]
+                struct WrongNumArgsError(String, usize, #[label] SourceSpan);
+
+                for arg in args.iter_mut() {
+                    arg.resolve_user_fns(registry)?;
+                }
+                let found = registry
+                    .get(name.as_str())
+                    .ok_or_else(|| FuncNotFoundError(name.to_string(), *span))?;
+                ensure!(
+                    found.arity == args.len(),
+                    WrongNumArgsError(name.to_string(), found.arity, *span)
+                );
+                *func = Some(found.clone());
+            }
+            Expr::Binding { .. } | Expr::Const { .. } | Expr::Param { .. } => {}
+            Expr::Apply { args, .. } => {
+                for arg in args.iter_mut() {
+                    arg.resolve_user_fns(registry)?;
+                }
+            }
+            Expr::Cond { clauses, .. } => {
+                for (cond, val) in clauses {
+                    cond.resolve_user_fns(registry)?;
+                    val.resolve_user_fns(registry)?;
+                }
+            }
+            Expr::Try { expr, fallback, .. } => {
+                expr.resolve_user_fns(registry)?;
+                fallback.resolve_user_fns(registry)?;
+            }
+        }
+        Ok(())
+    }
+    /// Whether the plans of queries containing the expression cannot be reused,
+    /// see [Op::is_uncacheable]. Functions registered with the database may be
+    /// registered anew, so their applications are never reused either.
+    pub(crate) fn is_uncacheable(&self) -> bool {
+        match self {
+            Expr::Binding { .. } | Expr::Const { .. } | Expr::Param { .. } => false,
+            Expr::UserFn { .. } => true,
+            Expr::Apply { op, args, .. } => {
+                op.is_uncacheable() || args.iter().any(|arg| arg.is_uncacheable())
+            }
+            Expr::Cond { clauses, .. } => clauses
+                .iter()
+                .any(|(cond, val)| cond.is_uncacheable() || val.is_uncacheable()),
+            Expr::Try { expr, fallback, .. } => expr.is_uncacheable() || fallback.is_uncacheable(),
         }
     }
     pub(crate) fn eval_to_const(mut self) -> Result<DataValue> {
@@ -365,13 +842,40 @@ impl Expr {
         #[diagnostic(code(eval::not_constant))]
         struct NotConstError;
 
+        #[derive(Error, Diagnostic, Debug)]
+        #[error("The value of parameter ${0} is required when the query is prepared")]
+        #[diagnostic(code(eval::param_needed_to_prepare))]
+        #[diagnostic(help(
+            "Parameters left unbound in prepared queries can only be used in rule bodies"
+        ))]
+        struct ParamNeededToPrepareError(String, #[label] SourceSpan);
+
         self.partial_eval()?;
         match self {
             Expr::Const { val, .. } => Ok(val),
-            _ => bail!(NotConstError),
+            _ => match self.first_param() {
+                Some((name, span)) => bail!(ParamNeededToPrepareError(name.to_string(), span)),
+                None => bail!(NotConstError),
+            },
         }
     }
     pub(crate) fn partial_eval(&mut self) -> Result<()> {
+        if let Expr::UserFn {
+            func, args, span, ..
+        } = self
+        {
+            let span = *span;
+            let mut all_evaluated = true;
+            for arg in args.iter_mut() {
+                arg.partial_eval()?;
+                all_evaluated = all_evaluated && matches!(arg, Expr::Const { .. });
+            }
+            if all_evaluated && func.as_ref().is_some_and(|func| func.pure) {
+                let result = self.eval(&[])?;
+                *self = Expr::Const { val: result, span };
+            }
+            return Ok(());
+        }
         if let Expr::Apply { args, span, .. } = self {
             let span = *span;
             let mut all_evaluated = true;
@@ -417,8 +921,8 @@ impl Expr {
             Expr::Binding { var, .. } => {
                 coll.insert(var.clone());
             }
-            Expr::Const { .. } => {}
-            Expr::Apply { args, .. } => {
+            Expr::Const { .. } | Expr::Param { .. } => {}
+            Expr::Apply { args, .. } | Expr::UserFn { args, .. } => {
                 for arg in args.iter() {
                     arg.collect_bindings(coll)
                 }
@@ -429,6 +933,10 @@ impl Expr {
                     val.collect_bindings(coll)
                 }
             }
+            Expr::Try { expr, fallback, .. } => {
+                expr.collect_bindings(coll);
+                fallback.collect_bindings(coll)
+            }
         }
     }
     pub(crate) fn eval(&self, bindings: impl AsRef<[DataValue]>) -> Result<DataValue> {
@@ -451,6 +959,7 @@ impl Expr {
                     .clone()),
             },
             Expr::Const { val, .. } => Ok(val.clone()),
+            Expr::Param { name, span } => bail!(ParamNotFoundError(name.to_string(), *span)),
             Expr::Apply { op, args, .. } => {
                 let args: Box<[DataValue]> = args
                     .iter()
@@ -459,6 +968,18 @@ impl Expr {
                 Ok((op.inner)(&args)
                     .map_err(|err| EvalRaisedError(self.span(), err.to_string()))?)
             }
+            Expr::UserFn {
+                name,
+                func,
+                args,
+                span,
+            } => {
+                let args: Box<[DataValue]> = args
+                    .iter()
+                    .map(|v| v.eval(bindings.as_ref()))
+                    .try_collect()?;
+                apply_user_fn(name, func, &args, *span)
+            }
             Expr::Cond { clauses, .. } => {
                 for (cond, val) in clauses {
                     let cond_val = cond.eval(bindings.as_ref())?;
@@ -472,11 +993,34 @@ impl Expr {
                 }
                 Ok(DataValue::Null)
             }
+            Expr::Try {
+                expr,
+                fallback,
+                warn,
+                ..
+            } => match expr.eval(bindings.as_ref()) {
+                Ok(val) => Ok(val),
+                Err(err) => {
+                    if *warn {
+                        warn!(
+                            "error recovered by 'try' for row {:?}: {}",
+                            bindings.as_ref(),
+                            err
+                        );
+                    }
+                    fallback.eval(bindings.as_ref())
+                }
+            },
         }
     }
     pub(crate) fn extract_bound(&self, target: &Symbol) -> Result<ValueRange> {
         Ok(match self {
-            Expr::Binding { .. } | Expr::Const { .. } | Expr::Cond { .. } => ValueRange::default(),
+            Expr::Binding { .. }
+            | Expr::Const { .. }
+            | Expr::Cond { .. }
+            | Expr::Try { .. }
+            | Expr::Param { .. }
+            | Expr::UserFn { .. } => ValueRange::default(),
             Expr::Apply { op, args, .. } => match op.name {
                 n if n == OP_GE.name || n == OP_GT.name => {
                     if let Some(symb) = args[0].get_binding() {
@@ -501,6 +1045,16 @@ impl Expr {
                             }
                         }
                     }
+                    if let Some(val) = args[1].get_const() {
+                        if let Some(range) = args[0].extract_time_fn_bound(target, val, true) {
+                            return Ok(range);
+                        }
+                    }
+                    if let Some(val) = args[0].get_const() {
+                        if let Some(range) = args[1].extract_time_fn_bound(target, val, false) {
+                            return Ok(range);
+                        }
+                    }
                     ValueRange::default()
                 }
                 n if n == OP_LE.name || n == OP_LT.name => {
@@ -528,6 +1082,16 @@ impl Expr {
                             }
                         }
                     }
+                    if let Some(val) = args[1].get_const() {
+                        if let Some(range) = args[0].extract_time_fn_bound(target, val, false) {
+                            return Ok(range);
+                        }
+                    }
+                    if let Some(val) = args[0].get_const() {
+                        if let Some(range) = args[1].extract_time_fn_bound(target, val, true) {
+                            return Ok(range);
+                        }
+                    }
                     ValueRange::default()
                 }
                 n if n == OP_STARTS_WITH.name => {
@@ -559,6 +1123,56 @@ impl Expr {
             },
         })
     }
+    /// The bounds of `target` implied by `val` bounding a monotonic time function of it, either
+    /// its truncation `date_trunc(unit, target, tz?)` or its shift `ts_add(target, n, unit)` by
+    /// a fixed length of time. `is_lower` tells whether `val` bounds the function from below.
+    fn extract_time_fn_bound(
+        &self,
+        target: &Symbol,
+        val: &DataValue,
+        is_lower: bool,
+    ) -> Option<ValueRange> {
+        let (op, args) = match self {
+            Expr::Apply { op, args, .. } => (op, args),
+            _ => return None,
+        };
+        let bound = val.get_float()?;
+        if op.name == OP_DATE_TRUNC.name {
+            if args[1].get_binding()? != target {
+                return None;
+            }
+            let unit = args[0].get_const()?;
+            let tz = match args.get(2) {
+                Some(tz) => Some(tz.get_const()?.clone()),
+                None => None,
+            };
+            if is_lower {
+                // the truncation of a time is never after it
+                return Some(ValueRange::lower_bound(DataValue::from(bound)));
+            }
+            // the time is before the end of the unit of time the bound falls in
+            let mut trunc_args = vec![unit.clone(), DataValue::from(bound)];
+            trunc_args.extend(tz.clone());
+            let start = op_date_trunc(&trunc_args).ok()?;
+            let mut add_args = vec![start, DataValue::from(1), unit.clone()];
+            add_args.extend(tz);
+            Some(ValueRange::upper_bound(op_ts_add(&add_args).ok()?))
+        } else if op.name == OP_TS_ADD.name {
+            if args[0].get_binding()? != target {
+                return None;
+            }
+            let n = args[1].get_const()?.get_float()?;
+            let secs = get_time_unit(args[2].get_const()?)?.fixed_secs(args.len() == 3)?;
+            let shifted = DataValue::from(bound - n * secs);
+            Some(if is_lower {
+                ValueRange::lower_bound(shifted)
+            } else {
+                ValueRange::upper_bound(shifted)
+            })
+        } else {
+            None
+        }
+    }
 }
 
 pub(crate) fn compute_bounds(
@@ -773,11 +1387,18 @@ pub(crate) fn get_op(name: &str) -> Option<&'static Op> {
         "chars" => &OP_CHARS,
         "from_substrings" => &OP_FROM_SUBSTRINGS,
         "slice" => &OP_SLICE,
+        "regex" => &OP_REGEX,
         "regex_matches" => &OP_REGEX_MATCHES,
         "regex_replace" => &OP_REGEX_REPLACE,
         "regex_replace_all" => &OP_REGEX_REPLACE_ALL,
         "regex_extract" => &OP_REGEX_EXTRACT,
         "regex_extract_first" => &OP_REGEX_EXTRACT_FIRST,
+        "regex_extract_groups" => &OP_REGEX_EXTRACT_GROUPS,
+        "regex_extract_all" => &OP_REGEX_EXTRACT_ALL,
+        "json_path" => &OP_JSON_PATH,
+        "json_path_strict" => &OP_JSON_PATH_STRICT,
+        "json_set" => &OP_JSON_SET,
+        "json_merge" => &OP_JSON_MERGE,
         "encode_base64" => &OP_ENCODE_BASE64,
         "decode_base64" => &OP_DECODE_BASE64,
         "first" => &OP_FIRST,
@@ -785,6 +1406,7 @@ pub(crate) fn get_op(name: &str) -> Option<&'static Op> {
         "chunks" => &OP_CHUNKS,
         "chunks_exact" => &OP_CHUNKS_EXACT,
         "windows" => &OP_WINDOWS,
+        "int_range" => &OP_INT_RANGE,
         "to_int" => &OP_TO_INT,
         "to_float" => &OP_TO_FLOAT,
         "to_string" => &OP_TO_STRING,
@@ -799,17 +1421,30 @@ pub(crate) fn get_op(name: &str) -> Option<&'static Op> {
         "to_uuid" => &OP_TO_UUID,
         "to_bool" => &OP_TO_BOOL,
         "to_unity" => &OP_TO_UNITY,
+        "validity" => &OP_VALIDITY,
         "rand_uuid_v1" => &OP_RAND_UUID_V1,
         "rand_uuid_v4" => &OP_RAND_UUID_V4,
+        "rand_uuid_v7" => &OP_RAND_UUID_V7,
         "uuid_timestamp" => &OP_UUID_TIMESTAMP,
         "now" => &OP_NOW,
         "format_timestamp" => &OP_FORMAT_TIMESTAMP,
         "parse_timestamp" => &OP_PARSE_TIMESTAMP,
+        "parse_ts" => &OP_PARSE_TS,
+        "format_ts" => &OP_FORMAT_TS,
+        "date_trunc" => &OP_DATE_TRUNC,
+        "date_part" => &OP_DATE_PART,
+        "ts_add" => &OP_TS_ADD,
         _ => return None,
     })
 }
 
 impl Op {
+    /// Whether the plans of queries applying the op cannot be reused: random and
+    /// time-dependent functions with constant arguments are folded into values when
+    /// queries are compiled, and regexes cannot be serialized
+    pub(crate) fn is_uncacheable(&self) -> bool {
+        self.name == OP_NOW.name || self.name == OP_REGEX.name || self.name.starts_with("OP_RAND_")
+    }
     pub(crate) fn post_process_args(&self, args: &mut [Expr]) {
         if self.name.starts_with("OP_REGEX_") {
             args[1] = Expr::Apply {
@@ -819,4 +1454,19 @@ impl Op {
             }
         }
     }
+    /// Checks the constant arguments that can be checked before evaluation,
+    /// so that errors in them are reported at where they are
+    pub(crate) fn check_const_args(&self, args: &[Expr]) -> Result<()> {
+        if self.name == OP_JSON_PATH.name
+            || self.name == OP_JSON_PATH_STRICT.name
+            || self.name == OP_JSON_SET.name
+        {
+            if let Some(path) = args[1].get_const().and_then(|v| v.get_str()) {
+                if let Err(err) = JsonPath::parse(path) {
+                    bail!(err.in_literal(args[1].span()))
+                }
+            }
+        }
+        Ok(())
+    }
 }
@@ -8,13 +8,18 @@
 
 use std::cmp::Reverse;
 use std::collections::BTreeSet;
+use std::fmt::Write;
 use std::ops::{Div, Rem};
 use std::str::FromStr;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
-use chrono::{DateTime, TimeZone, Utc};
+use chrono::format::{parse, Item, Parsed, StrftimeItems};
+use chrono::{
+    DateTime, Datelike, Duration, LocalResult, Months, NaiveDate, NaiveDateTime, NaiveTime, Offset,
+    SecondsFormat, TimeZone, Timelike, Utc,
+};
 use itertools::Itertools;
 #[cfg(target_arch = "wasm32")]
 use js_sys::Date;
@@ -27,7 +32,9 @@ use uuid::v1::Timestamp;
 
 use crate::data::expr::Op;
 use crate::data::json::JsonValue;
+use crate::data::json_path::{merge_patch, JsonPath};
 use crate::data::value::{DataValue, Num, RegexWrapper, UuidWrapper, Validity, ValidityTs};
+use crate::runtime::clock::Clock;
 
 macro_rules! define_op {
     ($name:ident, $min_arity:expr, $vararg:expr) => {
@@ -842,7 +849,7 @@ pub(crate) fn op_regex_replace_all(args: &[DataValue]) -> Result<DataValue> {
         (DataValue::Str(s), DataValue::Regex(r), DataValue::Str(rp)) => {
             Ok(DataValue::Str(r.0.replace_all(s, rp as &str).into()))
         }
-        _ => bail!("'regex_replace' requires strings"),
+        _ => bail!("'regex_replace_all' requires strings"),
     }
 }
 
@@ -871,6 +878,48 @@ pub(crate) fn op_regex_extract_first(args: &[DataValue]) -> Result<DataValue> {
     }
 }
 
+/// The groups captured by a match, the whole match for regexes without groups,
+/// groups not taking part in the match giving nulls.
+fn regex_captured_groups(captures: &regex::Captures<'_>) -> DataValue {
+    if captures.len() == 1 {
+        return DataValue::List(vec![DataValue::from(&captures[0])]);
+    }
+    DataValue::List(
+        captures
+            .iter()
+            .skip(1)
+            .map(|group| match group {
+                None => DataValue::Null,
+                Some(m) => DataValue::from(m.as_str()),
+            })
+            .collect_vec(),
+    )
+}
+
+define_op!(OP_REGEX_EXTRACT_GROUPS, 2, false);
+pub(crate) fn op_regex_extract_groups(args: &[DataValue]) -> Result<DataValue> {
+    match (&args[0], &args[1]) {
+        (DataValue::Str(s), DataValue::Regex(r)) => Ok(r
+            .0
+            .captures(s)
+            .map(|captures| regex_captured_groups(&captures))
+            .unwrap_or(DataValue::Null)),
+        _ => bail!("'regex_extract_groups' requires strings"),
+    }
+}
+
+define_op!(OP_REGEX_EXTRACT_ALL, 2, false);
+pub(crate) fn op_regex_extract_all(args: &[DataValue]) -> Result<DataValue> {
+    match (&args[0], &args[1]) {
+        (DataValue::Str(s), DataValue::Regex(r)) => Ok(DataValue::List(
+            r.0.captures_iter(s)
+                .map(|captures| regex_captured_groups(&captures))
+                .collect_vec(),
+        )),
+        _ => bail!("'regex_extract_all' requires strings"),
+    }
+}
+
 define_op!(OP_IS_NULL, 1, false);
 pub(crate) fn op_is_null(args: &[DataValue]) -> Result<DataValue> {
     Ok(DataValue::from(matches!(args[0], DataValue::Null)))
@@ -1136,6 +1185,36 @@ pub(crate) fn op_windows(args: &[DataValue]) -> Result<DataValue> {
     Ok(DataValue::List(res))
 }
 
+define_op!(OP_INT_RANGE, 1, true);
+pub(crate) fn op_int_range(args: &[DataValue]) -> Result<DataValue> {
+    ensure!(args.len() <= 3, "'int_range' takes at most three arguments");
+    let mut ints = vec![];
+    for (i, arg) in args.iter().enumerate() {
+        ints.push(
+            arg.get_int()
+                .ok_or_else(|| miette!("argument {} of 'int_range' must be an integer", i + 1))?,
+        );
+    }
+    // `int_range(end)`, `int_range(start, end)` or `int_range(start, end, step)`
+    let (start, end, step) = match ints[..] {
+        [end] => (0, end, 1),
+        [start, end] => (start, end, 1),
+        [start, end, step] => (start, end, step),
+        _ => unreachable!(),
+    };
+    ensure!(step != 0, "the step of 'int_range' must not be zero");
+    let mut res = vec![];
+    let mut i = start;
+    while (step > 0 && i < end) || (step < 0 && i > end) {
+        res.push(DataValue::from(i));
+        i = match i.checked_add(step) {
+            Some(i) => i,
+            None => break,
+        };
+    }
+    Ok(DataValue::List(res))
+}
+
 fn get_index(mut i: i64, total: usize) -> Result<usize> {
     if i < 0 {
         i += total as i64;
@@ -1164,6 +1243,49 @@ pub(crate) fn op_get(args: &[DataValue]) -> Result<DataValue> {
     Ok(l[idx].clone())
 }
 
+fn select_json_path(args: &[DataValue], name: &str, strict: bool) -> Result<DataValue> {
+    let path_str = args[1]
+        .get_str()
+        .ok_or_else(|| miette!("'{}' requires the path to be a string", name))?;
+    let path = JsonPath::parse(path_str)?;
+    let found = path.select(&args[0]);
+    if strict && found.is_empty() {
+        bail!("'{}' found no value at the path '{}'", name, path_str)
+    }
+    Ok(if path.is_definite() {
+        found.first().map_or(DataValue::Null, |v| (*v).clone())
+    } else {
+        DataValue::List(found.into_iter().cloned().collect())
+    })
+}
+
+define_op!(OP_JSON_PATH, 2, false);
+pub(crate) fn op_json_path(args: &[DataValue]) -> Result<DataValue> {
+    select_json_path(args, "json_path", false)
+}
+
+define_op!(OP_JSON_PATH_STRICT, 2, false);
+pub(crate) fn op_json_path_strict(args: &[DataValue]) -> Result<DataValue> {
+    select_json_path(args, "json_path_strict", true)
+}
+
+define_op!(OP_JSON_SET, 3, false);
+pub(crate) fn op_json_set(args: &[DataValue]) -> Result<DataValue> {
+    let path_str = args[1]
+        .get_str()
+        .ok_or_else(|| miette!("'json_set' requires the path to be a string"))?;
+    let path = JsonPath::parse(path_str)?;
+    let mut doc = args[0].clone();
+    path.set(&mut doc, args[2].clone())
+        .map_err(|err| miette!("'json_set' cannot set the path '{}': {}", path_str, err))?;
+    Ok(doc)
+}
+
+define_op!(OP_JSON_MERGE, 2, false);
+pub(crate) fn op_json_merge(args: &[DataValue]) -> Result<DataValue> {
+    Ok(merge_patch(&args[0], &args[1]))
+}
+
 define_op!(OP_MAYBE_GET, 2, false);
 pub(crate) fn op_maybe_get(args: &[DataValue]) -> Result<DataValue> {
     let l = args[0]
@@ -1487,19 +1609,26 @@ pub(crate) fn op_to_uuid(args: &[DataValue]) -> Result<DataValue> {
     }
 }
 
-define_op!(OP_NOW, 0, false);
-#[cfg(target_arch = "wasm32")]
-pub(crate) fn op_now(_args: &[DataValue]) -> Result<DataValue> {
-    let d: f64 = Date::now() / 1000.;
-    Ok(DataValue::from(d))
+define_op!(OP_VALIDITY, 2, false);
+pub(crate) fn op_validity(args: &[DataValue]) -> Result<DataValue> {
+    let ts = match &args[0] {
+        DataValue::Str(s) => str2vld(s)?.0 .0,
+        v => v.get_int().ok_or_else(|| {
+            miette!("'validity' requires an integer timestamp or an RFC 3339 string")
+        })?,
+    };
+    let is_assert = args[1]
+        .get_bool()
+        .ok_or_else(|| miette!("'validity' requires a boolean for assertion"))?;
+    Ok(DataValue::Validity(Validity {
+        timestamp: ValidityTs(Reverse(ts)),
+        is_assert: Reverse(is_assert),
+    }))
 }
 
-#[cfg(not(target_arch = "wasm32"))]
+define_op!(OP_NOW, 0, false);
 pub(crate) fn op_now(_args: &[DataValue]) -> Result<DataValue> {
-    let now = SystemTime::now();
-    Ok(DataValue::from(
-        now.duration_since(UNIX_EPOCH).unwrap().as_secs_f64(),
-    ))
+    Ok(DataValue::from(Clock::current_micros() as f64 / 1000000.))
 }
 
 pub(crate) fn current_validity() -> ValidityTs {
@@ -1566,30 +1695,349 @@ pub(crate) fn op_parse_timestamp(args: &[DataValue]) -> Result<DataValue> {
     ))
 }
 
+/// Gets the timezone given as the argument at `idx`, UTC if absent.
+fn get_tz_arg(args: &[DataValue], idx: usize, name: &str) -> Result<chrono_tz::Tz> {
+    match args.get(idx) {
+        None => Ok(chrono_tz::Tz::UTC),
+        Some(v) => {
+            let s = v
+                .get_str()
+                .ok_or_else(|| miette!("'{}' requires the timezone to be a string", name))?;
+            chrono_tz::Tz::from_str(s).map_err(|_| {
+                miette!(
+                    "'{}' got the unknown timezone '{}', IANA names such as 'Europe/Berlin' are required",
+                    name,
+                    s
+                )
+            })
+        }
+    }
+}
+
+/// Gets the `strftime` format string given as the argument at `idx`, rejecting bad
+/// specifications before they are used, as `chrono` panics when formatting with them.
+fn get_format_arg<'a>(args: &'a [DataValue], idx: usize, name: &str) -> Result<&'a str> {
+    let fmt = args[idx]
+        .get_str()
+        .ok_or_else(|| miette!("'{}' requires the format to be a string", name))?;
+    if StrftimeItems::new(fmt).any(|item| matches!(item, Item::Error)) {
+        bail!("'{}' got the invalid format string '{}'", name, fmt)
+    }
+    Ok(fmt)
+}
+
+/// Gets the timestamp in seconds since the epoch given as the argument at `idx`,
+/// validities giving their timestamps.
+fn get_ts_arg(args: &[DataValue], idx: usize, name: &str) -> Result<DateTime<Utc>> {
+    let micros = match &args[idx] {
+        DataValue::Validity(vld) => vld.timestamp.0 .0,
+        v => {
+            let f = v
+                .get_float()
+                .ok_or_else(|| miette!("'{}' requires the timestamp to be a number", name))?;
+            let micros = (f * 1_000_000.).round();
+            ensure!(
+                micros.is_finite() && micros.abs() < i64::MAX as f64,
+                "'{}' got the timestamp {} out of range",
+                name,
+                v
+            );
+            micros as i64
+        }
+    };
+    Utc.timestamp_opt(
+        micros.div_euclid(1_000_000),
+        (micros.rem_euclid(1_000_000) * 1000) as u32,
+    )
+    .single()
+    .ok_or_else(|| miette!("'{}' got the timestamp {} out of range", name, args[idx]))
+}
+
+fn datetime2ts<Z: TimeZone>(dt: &DateTime<Z>) -> f64 {
+    dt.timestamp() as f64 + dt.timestamp_subsec_micros() as f64 / 1_000_000.
+}
+
+/// Resolves a local time in a timezone: times repeated when clocks go back are taken at their
+/// first occurrence, and times skipped when clocks go forward are moved forward by the gap,
+/// using the offset in effect before it.
+fn resolve_local_time(
+    tz: &chrono_tz::Tz,
+    local: &NaiveDateTime,
+) -> Option<DateTime<chrono_tz::Tz>> {
+    match tz.from_local_datetime(local) {
+        LocalResult::Single(dt) => Some(dt),
+        LocalResult::Ambiguous(earliest, _) => Some(earliest),
+        LocalResult::None => {
+            let before = tz
+                .offset_from_utc_datetime(&local.checked_sub_signed(Duration::days(1))?)
+                .fix();
+            let utc =
+                local.checked_sub_signed(Duration::seconds(before.local_minus_utc() as i64))?;
+            Some(tz.from_utc_datetime(&utc))
+        }
+    }
+}
+
+/// The units of time taken by `date_trunc` and `ts_add`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum TimeUnit {
+    Second,
+    Minute,
+    Hour,
+    Day,
+    Week,
+    Month,
+    Quarter,
+    Year,
+}
+
+impl TimeUnit {
+    fn from_arg(v: &DataValue, name: &str) -> Result<Self> {
+        Ok(
+            match v
+                .get_str()
+                .ok_or_else(|| miette!("'{}' requires the unit of time to be a string", name))?
+            {
+                "second" => TimeUnit::Second,
+                "minute" => TimeUnit::Minute,
+                "hour" => TimeUnit::Hour,
+                "day" => TimeUnit::Day,
+                "week" => TimeUnit::Week,
+                "month" => TimeUnit::Month,
+                "quarter" => TimeUnit::Quarter,
+                "year" => TimeUnit::Year,
+                s => bail!(
+                    "'{}' got the unknown unit of time '{}', one of 'second', 'minute', 'hour', 'day', 'week', 'month', 'quarter' or 'year' is required",
+                    name,
+                    s
+                ),
+            },
+        )
+    }
+    /// The length of the unit in seconds if it is the same everywhere, days and weeks having
+    /// fixed lengths only in timezones without daylight saving time, such as UTC.
+    pub(crate) fn fixed_secs(self, in_utc: bool) -> Option<f64> {
+        match self {
+            TimeUnit::Second => Some(1.),
+            TimeUnit::Minute => Some(60.),
+            TimeUnit::Hour => Some(3600.),
+            TimeUnit::Day if in_utc => Some(86400.),
+            TimeUnit::Week if in_utc => Some(7. * 86400.),
+            _ => None,
+        }
+    }
+}
+
+/// Gets the unit of time given as a constant argument, for the planner.
+pub(crate) fn get_time_unit(v: &DataValue) -> Option<TimeUnit> {
+    TimeUnit::from_arg(v, "").ok()
+}
+
+define_op!(OP_PARSE_TS, 2, true);
+pub(crate) fn op_parse_ts(args: &[DataValue]) -> Result<DataValue> {
+    let s = args[0]
+        .get_str()
+        .ok_or_else(|| miette!("'parse_ts' requires the time to be a string"))?;
+    let fmt = get_format_arg(args, 1, "parse_ts")?;
+    let tz = get_tz_arg(args, 2, "parse_ts")?;
+    let mut parsed = Parsed::new();
+    parse(&mut parsed, s, StrftimeItems::new(fmt))
+        .map_err(|err| miette!("'parse_ts' cannot parse '{}' with '{}': {}", s, fmt, err))?;
+    if parsed.offset.is_some() {
+        let dt = parsed
+            .to_datetime()
+            .map_err(|err| miette!("'parse_ts' cannot parse '{}' with '{}': {}", s, fmt, err))?;
+        return Ok(DataValue::from(datetime2ts(&dt)));
+    }
+    if parsed.timestamp.is_some() {
+        let local = parsed
+            .to_naive_datetime_with_offset(0)
+            .map_err(|err| miette!("'parse_ts' cannot parse '{}' with '{}': {}", s, fmt, err))?;
+        return Ok(DataValue::from(datetime2ts(&Utc.from_utc_datetime(&local))));
+    }
+    let date = parsed
+        .to_naive_date()
+        .map_err(|err| miette!("'parse_ts' cannot parse '{}' with '{}': {}", s, fmt, err))?;
+    // formats with dates only give midnight
+    let time = if parsed.hour_div_12.is_none() && parsed.hour_mod_12.is_none() {
+        NaiveTime::from_hms_opt(0, 0, 0).unwrap()
+    } else {
+        parsed
+            .to_naive_time()
+            .map_err(|err| miette!("'parse_ts' cannot parse '{}' with '{}': {}", s, fmt, err))?
+    };
+    let dt = resolve_local_time(&tz, &date.and_time(time))
+        .ok_or_else(|| miette!("'parse_ts' got the time '{}' out of range", s))?;
+    Ok(DataValue::from(datetime2ts(&dt)))
+}
+
+define_op!(OP_FORMAT_TS, 2, true);
+pub(crate) fn op_format_ts(args: &[DataValue]) -> Result<DataValue> {
+    let dt = get_ts_arg(args, 0, "format_ts")?;
+    let fmt = get_format_arg(args, 1, "format_ts")?;
+    let tz = get_tz_arg(args, 2, "format_ts")?;
+    let mut s = String::new();
+    write!(s, "{}", dt.with_timezone(&tz).format(fmt))
+        .map_err(|_| miette!("'format_ts' cannot format with '{}'", fmt))?;
+    Ok(DataValue::from(s))
+}
+
+define_op!(OP_DATE_TRUNC, 2, true);
+pub(crate) fn op_date_trunc(args: &[DataValue]) -> Result<DataValue> {
+    let unit = TimeUnit::from_arg(&args[0], "date_trunc")?;
+    let dt = get_ts_arg(args, 1, "date_trunc")?;
+    let tz = get_tz_arg(args, 2, "date_trunc")?;
+    let local = dt.with_timezone(&tz);
+    // units shorter than days are truncated keeping the offset, so that the hours repeated
+    // when clocks go back are told apart
+    let into_unit = match unit {
+        TimeUnit::Second => Some(0),
+        TimeUnit::Minute => Some(local.second() as i64),
+        TimeUnit::Hour => Some(local.minute() as i64 * 60 + local.second() as i64),
+        _ => None,
+    };
+    if let Some(secs) = into_unit {
+        let truncated = dt
+            .with_nanosecond(0)
+            .and_then(|dt| dt.checked_sub_signed(Duration::seconds(secs)))
+            .ok_or_else(|| miette!("'date_trunc' got the timestamp {} out of range", args[1]))?;
+        return Ok(DataValue::from(datetime2ts(&truncated)));
+    }
+    let date = local.naive_local().date();
+    let start = match unit {
+        TimeUnit::Day => Some(date),
+        TimeUnit::Week => {
+            date.checked_sub_signed(Duration::days(date.weekday().num_days_from_monday() as i64))
+        }
+        TimeUnit::Month => date.with_day(1),
+        TimeUnit::Quarter => NaiveDate::from_ymd_opt(date.year(), date.month0() / 3 * 3 + 1, 1),
+        TimeUnit::Year => NaiveDate::from_ymd_opt(date.year(), 1, 1),
+        TimeUnit::Second | TimeUnit::Minute | TimeUnit::Hour => unreachable!(),
+    };
+    let truncated = start
+        .and_then(|start| resolve_local_time(&tz, &start.and_hms_opt(0, 0, 0)?))
+        .ok_or_else(|| miette!("'date_trunc' got the timestamp {} out of range", args[1]))?;
+    Ok(DataValue::from(datetime2ts(&truncated)))
+}
+
+define_op!(OP_DATE_PART, 2, true);
+pub(crate) fn op_date_part(args: &[DataValue]) -> Result<DataValue> {
+    let part = args[0]
+        .get_str()
+        .ok_or_else(|| miette!("'date_part' requires the part to be a string"))?;
+    let dt = get_ts_arg(args, 1, "date_part")?;
+    let tz = get_tz_arg(args, 2, "date_part")?;
+    let local = dt.with_timezone(&tz);
+    let val = match part {
+        "year" => local.year() as i64,
+        "quarter" => local.month0() as i64 / 3 + 1,
+        "month" => local.month() as i64,
+        "week" => local.iso_week().week() as i64,
+        "day" => local.day() as i64,
+        "doy" => local.ordinal() as i64,
+        "dow" => local.weekday().num_days_from_sunday() as i64,
+        "isodow" => local.weekday().number_from_monday() as i64,
+        "hour" => local.hour() as i64,
+        "minute" => local.minute() as i64,
+        "second" => {
+            return Ok(DataValue::from(
+                local.second() as f64 + local.timestamp_subsec_micros() as f64 / 1_000_000.,
+            ))
+        }
+        "offset" => local.offset().fix().local_minus_utc() as i64,
+        s => bail!(
+            "'date_part' got the unknown part '{}', one of 'year', 'quarter', 'month', 'week', 'day', 'doy', 'dow', 'isodow', 'hour', 'minute', 'second' or 'offset' is required",
+            s
+        ),
+    };
+    Ok(DataValue::from(val))
+}
+
+define_op!(OP_TS_ADD, 3, true);
+pub(crate) fn op_ts_add(args: &[DataValue]) -> Result<DataValue> {
+    let dt = get_ts_arg(args, 0, "ts_add")?;
+    let n = args[1]
+        .get_float()
+        .ok_or_else(|| miette!("'ts_add' requires the number of units to be a number"))?;
+    let unit = TimeUnit::from_arg(&args[2], "ts_add")?;
+    let tz = get_tz_arg(args, 3, "ts_add")?;
+    if let Some(secs) = unit.fixed_secs(false) {
+        return Ok(DataValue::from(datetime2ts(&dt) + n * secs));
+    }
+    let n = args[1]
+        .get_int()
+        .filter(|n| n.unsigned_abs() < 1_000_000_000)
+        .ok_or_else(|| {
+            miette!("'ts_add' requires a whole number of days, weeks, months, quarters or years")
+        })?;
+    if let Some(secs) = unit.fixed_secs(args.get(3).is_none()) {
+        return Ok(DataValue::from(datetime2ts(&dt) + n as f64 * secs));
+    }
+    // days and longer units are added to the local date, keeping the local time
+    let local = dt.with_timezone(&tz).naive_local();
+    let months = |m: i64| -> Option<NaiveDateTime> {
+        let m = u32::try_from(m.unsigned_abs()).ok()?;
+        if n >= 0 {
+            local.checked_add_months(Months::new(m))
+        } else {
+            local.checked_sub_months(Months::new(m))
+        }
+    };
+    let shifted = match unit {
+        TimeUnit::Day => local.checked_add_signed(Duration::days(n)),
+        TimeUnit::Week => local.checked_add_signed(Duration::weeks(n)),
+        TimeUnit::Month => months(n),
+        TimeUnit::Quarter => n.checked_mul(3).and_then(months),
+        TimeUnit::Year => n.checked_mul(12).and_then(months),
+        TimeUnit::Second | TimeUnit::Minute | TimeUnit::Hour => unreachable!(),
+    };
+    let dt = shifted
+        .and_then(|local| resolve_local_time(&tz, &local))
+        .ok_or_else(|| miette!("'ts_add' gives a timestamp out of range"))?;
+    Ok(DataValue::from(datetime2ts(&dt)))
+}
+
+/// Parses an RFC 3339 string into a validity timestamp, keeping microsecond precision.
+/// Times before the epoch are accepted.
 pub(crate) fn str2vld(s: &str) -> Result<ValidityTs> {
     let dt = DateTime::parse_from_rfc3339(s).map_err(|_| miette!("bad datetime: {}", s))?;
-    let st: SystemTime = dt.into();
-    let microseconds = st.duration_since(UNIX_EPOCH).unwrap().as_micros();
-    Ok(ValidityTs(Reverse(microseconds as i64)))
+    let microseconds = dt
+        .timestamp()
+        .checked_mul(1_000_000)
+        .and_then(|micros| micros.checked_add(dt.timestamp_subsec_micros() as i64))
+        .ok_or_else(|| miette!("datetime out of range: {}", s))?;
+    Ok(ValidityTs(Reverse(microseconds)))
+}
+
+/// Formats a validity as an RFC 3339 string in UTC with microsecond precision,
+/// prefixed by `~` for retractions, so that it can be read back by coercion.
+/// Returns `None` for timestamps not representable as a date.
+pub(crate) fn vld2str(vld: &Validity) -> Option<String> {
+    let micros = vld.timestamp.0 .0;
+    let dt = Utc
+        .timestamp_opt(
+            micros.div_euclid(1_000_000),
+            (micros.rem_euclid(1_000_000) * 1000) as u32,
+        )
+        .single()?;
+    let formatted = dt.to_rfc3339_opts(SecondsFormat::Micros, true);
+    Some(if vld.is_assert.0 {
+        formatted
+    } else {
+        format!("~{formatted}")
+    })
 }
 
 define_op!(OP_RAND_UUID_V1, 0, false);
 pub(crate) fn op_rand_uuid_v1(_args: &[DataValue]) -> Result<DataValue> {
     let mut rng = rand::thread_rng();
     let uuid_ctx = uuid::v1::Context::new(rng.gen());
-    #[cfg(target_arch = "wasm32")]
-    let ts = {
-        let since_epoch: f64 = Date::now();
-        let seconds = since_epoch.floor();
-        let fractional = (since_epoch - seconds) * 1.0e9;
-        Timestamp::from_unix(uuid_ctx, seconds as u64, fractional as u32)
-    };
-    #[cfg(not(target_arch = "wasm32"))]
-    let ts = {
-        let now = SystemTime::now();
-        let since_epoch = now.duration_since(UNIX_EPOCH).unwrap();
-        Timestamp::from_unix(uuid_ctx, since_epoch.as_secs(), since_epoch.subsec_nanos())
-    };
+    let micros = Clock::current_micros();
+    let ts = Timestamp::from_unix(
+        uuid_ctx,
+        micros.div_euclid(1000000) as u64,
+        (micros.rem_euclid(1000000) * 1000) as u32,
+    );
     let mut rand_vals = [0u8; 6];
     rng.fill(&mut rand_vals);
     let id = uuid::Uuid::new_v1(ts, &rand_vals);
@@ -1602,9 +2050,28 @@ pub(crate) fn op_rand_uuid_v4(_args: &[DataValue]) -> Result<DataValue> {
     Ok(DataValue::uuid(id))
 }
 
+define_op!(OP_RAND_UUID_V7, 0, false);
+pub(crate) fn op_rand_uuid_v7(_args: &[DataValue]) -> Result<DataValue> {
+    let time = Clock::next_uuid_v7_time();
+    // 48 bits of milliseconds, the version, 12 bits of the counter, the variant, random bits
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill(&mut bytes[8..]);
+    bytes[0..6].copy_from_slice(&(time >> 12).to_be_bytes()[2..]);
+    bytes[6] = 0x70 | ((time >> 8) & 0x0f) as u8;
+    bytes[7] = time as u8;
+    bytes[8] = 0x80 | (bytes[8] & 0x3f);
+    Ok(DataValue::uuid(uuid::Uuid::from_bytes(bytes)))
+}
+
 define_op!(OP_UUID_TIMESTAMP, 1, false);
 pub(crate) fn op_uuid_timestamp(args: &[DataValue]) -> Result<DataValue> {
     Ok(match &args[0] {
+        // in seconds, as for version 1 UUIDs, with the precision of milliseconds
+        DataValue::Uuid(UuidWrapper(id)) if id.get_version_num() == 7 => {
+            let mut millis = [0u8; 8];
+            millis[2..].copy_from_slice(&id.as_bytes()[..6]);
+            (u64::from_be_bytes(millis) as f64 / 1000.).into()
+        }
         DataValue::Uuid(UuidWrapper(id)) => match id.get_timestamp() {
             None => DataValue::Null,
             Some(t) => {
@@ -6,9 +6,15 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::str::FromStr;
+
 use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
+use serde::Serialize;
 use serde_json::json;
+use serde_json::ser::{CompactFormatter, Formatter as JsonFormatter, Serializer};
 pub(crate) use serde_json::Value as JsonValue;
 
 use crate::data::value::{DataValue, Num};
@@ -103,3 +109,151 @@ impl From<DataValue> for JsonValue {
         }
     }
 }
+
+/// How floats in query results are written as JSON text
+#[derive(
+    Copy, Clone, Debug, Default, Eq, PartialEq, serde_derive::Serialize, serde_derive::Deserialize,
+)]
+#[serde(try_from = "String", into = "String")]
+pub enum FloatFormat {
+    /// The shortest decimal representation reading back to the same float, e.g. `0.30000000000000004`
+    #[default]
+    Shortest,
+    /// Rounded to the given number of digits after the decimal point, e.g. `0.30` for `fixed(2)`
+    Fixed(usize),
+    /// The shortest representation in scientific notation, e.g. `3.0000000000000004e-1`
+    Scientific,
+}
+
+impl FromStr for FloatFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "shortest" => Ok(FloatFormat::Shortest),
+            "scientific" => Ok(FloatFormat::Scientific),
+            s => s
+                .strip_prefix("fixed(")
+                .and_then(|rest| rest.strip_suffix(')'))
+                .and_then(|n| n.trim().parse::<usize>().ok())
+                .map(FloatFormat::Fixed)
+                .ok_or_else(|| {
+                    format!(
+                        "unknown float format '{s}', expected 'shortest', 'fixed(<digits>)' or 'scientific'"
+                    )
+                }),
+        }
+    }
+}
+
+impl TryFrom<String> for FloatFormat {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<FloatFormat> for String {
+    fn from(value: FloatFormat) -> Self {
+        value.to_string()
+    }
+}
+
+impl Display for FloatFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FloatFormat::Shortest => write!(f, "shortest"),
+            FloatFormat::Fixed(n) => write!(f, "fixed({n})"),
+            FloatFormat::Scientific => write!(f, "scientific"),
+        }
+    }
+}
+
+/// Options controlling how query results are converted to JSON. They only affect the output:
+/// values are stored and computed as usual.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, serde_derive::Deserialize)]
+pub struct OutputOptions {
+    /// How floats are written
+    #[serde(default)]
+    pub float_format: FloatFormat,
+    /// Write integers that cannot be represented exactly as 64-bit floats, i.e. those larger than
+    /// 2^53 - 1 in absolute value, as strings, since many JSON parsers read numbers as such floats.
+    /// Their positions are listed in the `types` field of the result.
+    #[serde(default)]
+    pub big_int_as_string: bool,
+}
+
+/// The largest integer `n` such that all integers with absolute values up to `n` are exact
+/// as 64-bit floats
+const MAX_SAFE_INT: u64 = (1 << 53) - 1;
+
+impl OutputOptions {
+    /// Converts the value to JSON. The paths of integers written as strings, i.e. the indices
+    /// leading to them starting from `path`, are collected in `big_ints`.
+    pub(crate) fn to_json(
+        self,
+        v: DataValue,
+        path: &mut Vec<usize>,
+        big_ints: &mut Vec<Vec<usize>>,
+    ) -> JsonValue {
+        match v {
+            DataValue::Num(Num::Int(i))
+                if self.big_int_as_string && i.unsigned_abs() > MAX_SAFE_INT =>
+            {
+                big_ints.push(path.clone());
+                JsonValue::String(i.to_string())
+            }
+            DataValue::Num(Num::Float(f)) if f.is_finite() => match self.float_format {
+                // so that the value matches the text written by `json_to_string`
+                FloatFormat::Fixed(n) => json!(format!("{f:.n$}").parse::<f64>().unwrap()),
+                _ => json!(f),
+            },
+            DataValue::List(l) => self.list_to_json(l, path, big_ints),
+            DataValue::Set(s) => self.list_to_json(s, path, big_ints),
+            v => JsonValue::from(v),
+        }
+    }
+
+    fn list_to_json(
+        self,
+        vals: impl IntoIterator<Item = DataValue>,
+        path: &mut Vec<usize>,
+        big_ints: &mut Vec<Vec<usize>>,
+    ) -> JsonValue {
+        vals.into_iter()
+            .enumerate()
+            .map(|(i, v)| {
+                path.push(i);
+                let ret = self.to_json(v, path, big_ints);
+                path.pop();
+                ret
+            })
+            .collect()
+    }
+}
+
+struct FloatFormatter(FloatFormat);
+
+impl JsonFormatter for FloatFormatter {
+    fn write_f64<W: ?Sized + io::Write>(&mut self, writer: &mut W, value: f64) -> io::Result<()> {
+        match self.0 {
+            FloatFormat::Shortest => CompactFormatter.write_f64(writer, value),
+            FloatFormat::Fixed(n) => write!(writer, "{value:.n$}"),
+            FloatFormat::Scientific => write!(writer, "{value:e}"),
+        }
+    }
+}
+
+/// Writes the JSON value as compact text, with floats in the given format.
+pub fn json_to_string(value: &JsonValue, float_format: FloatFormat) -> String {
+    if float_format == FloatFormat::Shortest {
+        return value.to_string();
+    }
+    let mut out = vec![];
+    let mut ser = Serializer::with_formatter(&mut out, FloatFormatter(float_format));
+    value
+        .serialize(&mut ser)
+        .expect("writing JSON to memory cannot fail");
+    String::from_utf8(out).unwrap()
+}
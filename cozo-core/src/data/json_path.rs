@@ -0,0 +1,492 @@
+/*
+ * Copyright 2022, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Paths into JSON documents, as used by `json_path` and `json_set`.
+//!
+//! Documents use the representation JSON is converted to: arrays are lists, and objects are
+//! lists of `[key, value]` pairs with string keys. Non-empty lists of such pairs are objects,
+//! empty lists are arrays.
+
+use miette::{bail, Diagnostic, Result};
+use thiserror::Error;
+
+use crate::data::value::DataValue;
+use crate::parse::SourceSpan;
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Bad JSON path '{path}': {message}")]
+#[diagnostic(code(parser::bad_json_path))]
+#[diagnostic(help(
+    "Paths start with '$', followed by '.key', '[\"key\"]', '[index]', '.*' or '[*]', \
+    with '..' instead of '.' for recursive descent"
+))]
+pub(crate) struct BadJsonPath {
+    path: String,
+    message: String,
+    #[label]
+    span: SourceSpan,
+}
+
+impl BadJsonPath {
+    /// Points the error at the path within the string literal at `literal_span` giving it,
+    /// or at the whole literal if its escapes or quotes make the position uncertain.
+    pub(crate) fn in_literal(mut self, literal_span: SourceSpan) -> Self {
+        self.span = if literal_span.1 == self.path.len() + 2 {
+            SourceSpan(literal_span.0 + 1 + self.span.0, self.span.1)
+        } else {
+            literal_span
+        };
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Selector {
+    Key(String),
+    Index(i64),
+    Wildcard,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Step {
+    /// Whether the selector applies to all descendants, not only to the children
+    descend: bool,
+    selector: Selector,
+}
+
+/// A parsed JSON path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct JsonPath {
+    steps: Vec<Step>,
+}
+
+struct PathParser<'a> {
+    path: &'a str,
+    pos: usize,
+}
+
+impl<'a> PathParser<'a> {
+    fn error(&self, message: impl Into<String>, len: usize) -> BadJsonPath {
+        BadJsonPath {
+            path: self.path.to_string(),
+            message: message.into(),
+            span: SourceSpan(self.pos, len),
+        }
+    }
+    fn rest(&self) -> &'a str {
+        &self.path[self.pos..]
+    }
+    fn eat(&mut self, prefix: &str) -> bool {
+        if self.rest().starts_with(prefix) {
+            self.pos += prefix.len();
+            true
+        } else {
+            false
+        }
+    }
+    fn key(&mut self) -> Result<Selector, BadJsonPath> {
+        if self.eat("*") {
+            return Ok(Selector::Wildcard);
+        }
+        let len = self.rest().find(['.', '[']).unwrap_or(self.rest().len());
+        if len == 0 {
+            return Err(self.error("a key is required", 1));
+        }
+        let key = self.rest()[..len].to_string();
+        self.pos += len;
+        Ok(Selector::Key(key))
+    }
+    fn bracketed(&mut self) -> Result<Selector, BadJsonPath> {
+        let start = self.pos;
+        let selector = if self.eat("*") {
+            Selector::Wildcard
+        } else if let Some(quote) = self
+            .rest()
+            .chars()
+            .next()
+            .filter(|c| *c == '\'' || *c == '"')
+        {
+            self.pos += 1;
+            let mut key = String::new();
+            let mut chars = self.rest().char_indices();
+            loop {
+                match chars.next() {
+                    None => {
+                        self.pos = start;
+                        return Err(self.error("the quoted key is not closed", 1));
+                    }
+                    Some((i, '\\')) => match chars.next() {
+                        Some((_, c)) => key.push(c),
+                        None => {
+                            self.pos += i;
+                            return Err(self.error("nothing to escape", 1));
+                        }
+                    },
+                    Some((i, c)) if c == quote => {
+                        self.pos += i + 1;
+                        break;
+                    }
+                    Some((_, c)) => key.push(c),
+                }
+            }
+            Selector::Key(key)
+        } else {
+            let len = self
+                .rest()
+                .find(']')
+                .ok_or_else(|| self.error("the bracket is not closed", 1))?;
+            let idx = self.rest()[..len]
+                .trim()
+                .parse::<i64>()
+                .map_err(|_| self.error("an index, a quoted key or '*' is required", len))?;
+            self.pos += len;
+            Selector::Index(idx)
+        };
+        if !self.eat("]") {
+            return Err(self.error("']' is required", 1));
+        }
+        Ok(selector)
+    }
+}
+
+impl JsonPath {
+    pub(crate) fn parse(path: &str) -> Result<Self, BadJsonPath> {
+        let mut parser = PathParser { path, pos: 0 };
+        if !parser.eat("$") {
+            return Err(parser.error("paths start with '$'", 1));
+        }
+        let mut steps = vec![];
+        while parser.pos < path.len() {
+            let step = if parser.eat("..") {
+                let selector = if parser.eat("[") {
+                    parser.bracketed()?
+                } else {
+                    parser.key()?
+                };
+                Step {
+                    descend: true,
+                    selector,
+                }
+            } else if parser.eat(".") {
+                Step {
+                    descend: false,
+                    selector: parser.key()?,
+                }
+            } else if parser.eat("[") {
+                Step {
+                    descend: false,
+                    selector: parser.bracketed()?,
+                }
+            } else {
+                return Err(parser.error("'.' or '[' is required", 1));
+            };
+            steps.push(step);
+        }
+        Ok(JsonPath { steps })
+    }
+
+    /// Whether the path selects at most one value, i.e. has no wildcards or recursive descents
+    pub(crate) fn is_definite(&self) -> bool {
+        self.steps
+            .iter()
+            .all(|step| !step.descend && step.selector != Selector::Wildcard)
+    }
+
+    /// The values selected by the path, in document order
+    pub(crate) fn select<'v>(&self, root: &'v DataValue) -> Vec<&'v DataValue> {
+        let mut current = vec![root];
+        for step in &self.steps {
+            let mut candidates = vec![];
+            if step.descend {
+                for node in current {
+                    collect_descendants(node, &mut candidates);
+                }
+            } else {
+                candidates = current;
+            }
+            current = vec![];
+            for node in candidates {
+                step.selector.select_children(node, &mut current);
+            }
+        }
+        current
+    }
+
+    /// Sets the value at the path, creating the missing keys of objects, and the missing
+    /// elements of arrays just past their ends
+    pub(crate) fn set(&self, root: &mut DataValue, new: DataValue) -> Result<()> {
+        if !self.is_definite() {
+            bail!("only paths without wildcards or recursive descents can be set")
+        }
+        set_at(root, &self.steps, new)
+    }
+}
+
+fn is_key_value_pair(v: &DataValue) -> bool {
+    match v {
+        DataValue::List(kv) => kv.len() == 2 && matches!(kv[0], DataValue::Str(_)),
+        _ => false,
+    }
+}
+
+/// The pairs of a value if it is an object
+pub(crate) fn as_object(v: &DataValue) -> Option<&[DataValue]> {
+    match v {
+        DataValue::List(l) if !l.is_empty() && l.iter().all(is_key_value_pair) => Some(l),
+        _ => None,
+    }
+}
+
+fn children(v: &DataValue) -> Vec<&DataValue> {
+    match as_object(v) {
+        Some(pairs) => pairs
+            .iter()
+            .map(|pair| &pair.get_slice().unwrap()[1])
+            .collect(),
+        None => match v {
+            DataValue::List(l) => l.iter().collect(),
+            _ => vec![],
+        },
+    }
+}
+
+fn collect_descendants<'v>(v: &'v DataValue, collector: &mut Vec<&'v DataValue>) {
+    collector.push(v);
+    for child in children(v) {
+        collect_descendants(child, collector);
+    }
+}
+
+impl Selector {
+    fn select_children<'v>(&self, v: &'v DataValue, collector: &mut Vec<&'v DataValue>) {
+        match self {
+            Selector::Key(key) => {
+                if let Some(pairs) = as_object(v) {
+                    collector.extend(
+                        pairs
+                            .iter()
+                            .map(|pair| pair.get_slice().unwrap())
+                            .filter(|kv| kv[0].get_str() == Some(key.as_str()))
+                            .map(|kv| &kv[1]),
+                    )
+                }
+            }
+            Selector::Index(i) => {
+                if as_object(v).is_none() {
+                    if let DataValue::List(l) = v {
+                        if let Some(idx) = array_index(*i, l.len()) {
+                            collector.push(&l[idx])
+                        }
+                    }
+                }
+            }
+            Selector::Wildcard => collector.extend(children(v)),
+        }
+    }
+}
+
+fn array_index(i: i64, len: usize) -> Option<usize> {
+    let idx = if i < 0 { len as i64 + i } else { i };
+    if idx >= 0 && (idx as usize) < len {
+        Some(idx as usize)
+    } else {
+        None
+    }
+}
+
+fn set_at(v: &mut DataValue, steps: &[Step], new: DataValue) -> Result<()> {
+    let (step, rest) = match steps.split_first() {
+        None => {
+            *v = new;
+            return Ok(());
+        }
+        Some(split) => split,
+    };
+    let built = |new: DataValue| -> Result<DataValue> {
+        let mut created = DataValue::Null;
+        set_at(&mut created, rest, new)?;
+        Ok(created)
+    };
+    match &step.selector {
+        Selector::Key(key) => {
+            let is_object = as_object(v).is_some();
+            match v {
+                DataValue::List(pairs) if is_object => {
+                    for pair in pairs.iter_mut() {
+                        if let DataValue::List(kv) = pair {
+                            if kv[0].get_str() == Some(key.as_str()) {
+                                return set_at(&mut kv[1], rest, new);
+                            }
+                        }
+                    }
+                    let val = built(new)?;
+                    pairs.push(DataValue::List(vec![DataValue::from(key.as_str()), val]));
+                }
+                DataValue::Null => {
+                    let val = built(new)?;
+                    *v = DataValue::List(vec![DataValue::List(vec![
+                        DataValue::from(key.as_str()),
+                        val,
+                    ])]);
+                }
+                DataValue::List(l) if l.is_empty() => {
+                    let val = built(new)?;
+                    l.push(DataValue::List(vec![DataValue::from(key.as_str()), val]));
+                }
+                v => bail!(
+                    "cannot set the key '{}' of {:?}, which is not an object",
+                    key,
+                    v
+                ),
+            }
+        }
+        Selector::Index(i) => {
+            let is_object = as_object(v).is_some();
+            match v {
+                DataValue::List(l) if !is_object => match array_index(*i, l.len()) {
+                    Some(idx) => return set_at(&mut l[idx], rest, new),
+                    None if *i == l.len() as i64 => {
+                        let val = built(new)?;
+                        l.push(val)
+                    }
+                    None => bail!(
+                        "the index {} is out of bounds for a list of length {}",
+                        i,
+                        l.len()
+                    ),
+                },
+                DataValue::Null if *i == 0 => {
+                    let val = built(new)?;
+                    *v = DataValue::List(vec![val]);
+                }
+                v => bail!("cannot set the index {} of {:?}", i, v),
+            }
+        }
+        Selector::Wildcard => unreachable!(),
+    }
+    Ok(())
+}
+
+/// Merges `patch` into `target` as a JSON merge patch (RFC 7396): objects are merged key by key,
+/// null values in the patch removing the keys, and other patches replace the targets.
+pub(crate) fn merge_patch(target: &DataValue, patch: &DataValue) -> DataValue {
+    let patch_pairs = match as_object(patch) {
+        None => return patch.clone(),
+        Some(pairs) => pairs,
+    };
+    let mut merged: Vec<(DataValue, DataValue)> = match as_object(target) {
+        None => vec![],
+        Some(pairs) => pairs
+            .iter()
+            .map(|pair| {
+                let kv = pair.get_slice().unwrap();
+                (kv[0].clone(), kv[1].clone())
+            })
+            .collect(),
+    };
+    for pair in patch_pairs {
+        let kv = pair.get_slice().unwrap();
+        let found = merged.iter().position(|(k, _)| *k == kv[0]);
+        match (found, &kv[1]) {
+            (Some(pos), DataValue::Null) => {
+                merged.remove(pos);
+            }
+            (None, DataValue::Null) => {}
+            (Some(pos), v) => merged[pos].1 = merge_patch(&merged[pos].1, v),
+            (None, v) => merged.push((kv[0].clone(), merge_patch(&DataValue::Null, v))),
+        }
+    }
+    DataValue::List(
+        merged
+            .into_iter()
+            .map(|(k, v)| DataValue::List(vec![k, v]))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde_json::json;
+
+    use crate::data::value::DataValue;
+    use crate::new_cozo_mem;
+
+    #[test]
+    fn test_json_path_functions() {
+        let db = new_cozo_mem().unwrap();
+        db.run_script(
+            ":create events {id: Int => payload: Any, region: String?}",
+            Default::default(),
+        )
+        .unwrap();
+        db.run_script(
+        r#"
+        ?[id, payload, region] := id in [1, 2, 3], p = get($payloads, id - 1),
+            payload = json_set(p, '$.seen', true), region = json_path(p, '$.user.address.region')
+        :put events {id => payload, region}
+        "#,
+        BTreeMap::from([(
+            "payloads".to_string(),
+            DataValue::from(json!([
+                {"user": {"name": "a", "address": {"region": "eu"}}, "items": [{"sku": 1}, {"sku": 2}]},
+                {"user": {"name": "b"}, "items": []},
+                {"user": {"name": "c", "address": {"region": "us"}}, "items": [{"sku": 3}]},
+            ])),
+        )]),
+    )
+    .unwrap();
+        let res = db
+            .run_script(
+                r#"
+            ?[id, name, region, skus, seen] := *events{id, payload, region},
+                json_path(payload, '$.items[0].sku') != null,
+                name = json_path(payload, '$.user.name'),
+                skus = json_path(payload, '$..sku'),
+                seen = json_path_strict(payload, '$.seen')
+            "#,
+                Default::default(),
+            )
+            .unwrap();
+        assert_eq!(
+            res.into_json()["rows"],
+            json!([[1, "a", "eu", [1, 2], true], [3, "c", "us", [3], true]])
+        );
+        let res = db
+            .run_script(
+                r#"
+            ?[merged] := *events{id, payload}, id == 2,
+                merged = json_path(json_merge(payload, $patch), '$.user')
+            "#,
+                BTreeMap::from([(
+                    "patch".to_string(),
+                    DataValue::from(json!({"user": {"name": null, "age": 3}})),
+                )]),
+            )
+            .unwrap();
+        assert_eq!(res.into_json()["rows"], json!([[[["age", 3]]]]));
+
+        // the strict variant fails on missing values
+        assert!(db
+            .run_script(
+                "?[x] := *events{id, payload}, x = json_path_strict(payload, '$.user.address')",
+                Default::default(),
+            )
+            .is_err());
+
+        // bad paths are reported at the error in them
+        let script = "?[x] := *events{payload}, x = json_path(payload, '$.user[name]')";
+        let err = db.run_script(script, Default::default()).unwrap_err();
+        let json = crate::format_error_as_json(err, Some(script));
+        assert_eq!(
+            json["labels"][0]["span"]["offset"],
+            json!(script.find("name]").unwrap())
+        );
+    }
+}
@@ -55,11 +55,7 @@ pub(crate) trait MemCmpEncoder: Write {
             }
             DataValue::Uuid(u) => {
                 self.write_u8(UUID_TAG).unwrap();
-                let (s_l, s_m, s_h, s_rest) = u.0.as_fields();
-                self.write_u16::<BigEndian>(s_h).unwrap();
-                self.write_u16::<BigEndian>(s_m).unwrap();
-                self.write_u32::<BigEndian>(s_l).unwrap();
-                self.write_all(s_rest.as_ref()).unwrap();
+                self.write_all(u.0.as_bytes()).unwrap();
             }
             DataValue::Regex(rx) => {
                 self.write_u8(REGEX_TAG).unwrap();
@@ -130,34 +126,40 @@ pub(crate) trait MemCmpEncoder: Write {
     }
 }
 
-pub fn decode_bytes(data: &[u8]) -> (Vec<u8>, &[u8]) {
+/// Decodes a byte sequence written by [MemCmpEncoder::encode_bytes],
+/// returning `None` if the data is malformed.
+pub fn decode_bytes(data: &[u8]) -> Option<(Vec<u8>, &[u8])> {
     let mut key = Vec::with_capacity(data.len() / (ENC_GROUP_SIZE + 1) * ENC_GROUP_SIZE);
-    let mut offset = 0;
-    let chunk_len = ENC_GROUP_SIZE + 1;
+    let mut remaining = data;
     loop {
-        let next_offset = offset + chunk_len;
-        debug_assert!(next_offset <= data.len());
-        let chunk = &data[offset..next_offset];
-        offset = next_offset;
+        let (chunk, next) = try_split_at(remaining, ENC_GROUP_SIZE + 1)?;
+        remaining = next;
 
-        let (&marker, bytes) = chunk.split_last().unwrap();
-        let pad_size = (ENC_MARKER - marker) as usize;
+        let (&marker, bytes) = chunk.split_last()?;
+        let pad_size = ENC_MARKER.checked_sub(marker)? as usize;
 
         if pad_size == 0 {
-            key.write_all(bytes).unwrap();
+            key.extend_from_slice(bytes);
             continue;
         }
-        debug_assert!(pad_size <= ENC_GROUP_SIZE);
+        if pad_size > ENC_GROUP_SIZE {
+            return None;
+        }
 
         let (bytes, padding) = bytes.split_at(ENC_GROUP_SIZE - pad_size);
-        key.write_all(bytes).unwrap();
-
-        debug_assert!(!padding.iter().any(|x| *x != 0));
+        if padding.iter().any(|x| *x != 0) {
+            return None;
+        }
+        key.extend_from_slice(bytes);
 
-        return (key, &data[offset..]);
+        return Some((key, remaining));
     }
 }
 
+fn try_split_at(bs: &[u8], mid: usize) -> Option<(&[u8], &[u8])> {
+    (bs.len() >= mid).then(|| bs.split_at(mid))
+}
+
 const SIGN_MARK: u64 = 0x8000000000000000;
 
 fn order_encode_i64(v: i64) -> u64 {
@@ -191,79 +193,65 @@ const ENC_MARKER: u8 = b'\xff';
 const ENC_ASC_PADDING: [u8; ENC_GROUP_SIZE] = [0; ENC_GROUP_SIZE];
 
 impl Num {
-    pub(crate) fn decode_from_key(bs: &[u8]) -> (Self, &[u8]) {
-        let (float_part, remaining) = bs.split_at(8);
+    pub(crate) fn decode_from_key(bs: &[u8]) -> Option<(Self, &[u8])> {
+        let (float_part, remaining) = try_split_at(bs, 8)?;
         let fu = BigEndian::read_u64(float_part);
         let f = order_decode_f64(fu);
-        let (tag, remaining) = remaining.split_first().unwrap();
-        match *tag {
+        let (tag, remaining) = remaining.split_first()?;
+        Some(match *tag {
             IS_FLOAT => (Num::Float(f), remaining),
             IS_EXACT_INT => (Num::Int(f as i64), remaining),
             IS_APPROX_INT => {
-                let (int_part, remaining) = remaining.split_at(8);
+                let (int_part, remaining) = try_split_at(remaining, 8)?;
                 let iu = BigEndian::read_u64(int_part);
                 let i = order_decode_i64(iu);
                 (Num::Int(i), remaining)
             }
-            _ => unreachable!(),
-        }
-        // if *tag == 0x80 {
-        //     return (Num::F(f), remaining);
-        // }
-        // let (subtag, remaining) = remaining.split_first().unwrap();
-        // let n = f as i64;
-        // let mut n_bytes = n.to_be_bytes();
-        // n_bytes[6] &= 0x80;
-        // n_bytes[6] |= tag;
-        // n_bytes[7] = *subtag;
-        // let n = BigEndian::read_i64(&n_bytes);
-        // (Num::I(n), remaining)
+            _ => return None,
+        })
     }
 }
 
 impl DataValue {
-    pub(crate) fn decode_from_key(bs: &[u8]) -> (Self, &[u8]) {
-        let (tag, remaining) = bs.split_first().unwrap();
-        match *tag {
+    /// Decodes the first value of the data, returning it with the rest of the data,
+    /// or `None` if the data is malformed.
+    pub(crate) fn decode_from_key(bs: &[u8]) -> Option<(Self, &[u8])> {
+        let (tag, remaining) = bs.split_first()?;
+        Some(match *tag {
             NULL_TAG => (DataValue::Null, remaining),
             FALSE_TAG => (DataValue::from(false), remaining),
             TRUE_TAG => (DataValue::from(true), remaining),
             NUM_TAG => {
-                let (n, remaining) = Num::decode_from_key(remaining);
+                let (n, remaining) = Num::decode_from_key(remaining)?;
                 (DataValue::Num(n), remaining)
             }
             STR_TAG => {
-                let (bytes, remaining) = decode_bytes(remaining);
-                let s = unsafe { String::from_utf8_unchecked(bytes) };
+                let (bytes, remaining) = decode_bytes(remaining)?;
+                let s = String::from_utf8(bytes).ok()?;
                 (DataValue::Str(s.into()), remaining)
             }
             BYTES_TAG => {
-                let (bytes, remaining) = decode_bytes(remaining);
+                let (bytes, remaining) = decode_bytes(remaining)?;
                 (DataValue::Bytes(bytes), remaining)
             }
             UUID_TAG => {
-                let (uuid_data, remaining) = remaining.split_at(16);
-                let s_h = BigEndian::read_u16(&uuid_data[0..2]);
-                let s_m = BigEndian::read_u16(&uuid_data[2..4]);
-                let s_l = BigEndian::read_u32(&uuid_data[4..8]);
-                let mut s_rest = [0u8; 8];
-                s_rest.copy_from_slice(&uuid_data[8..]);
-                let uuid = uuid::Uuid::from_fields(s_l, s_m, s_h, &s_rest);
+                let (uuid_data, remaining) = try_split_at(remaining, 16)?;
+                let uuid = uuid::Uuid::from_slice(uuid_data).ok()?;
                 (DataValue::Uuid(UuidWrapper(uuid)), remaining)
             }
             REGEX_TAG => {
-                let (bytes, remaining) = decode_bytes(remaining);
-                let s = unsafe { String::from_utf8_unchecked(bytes) };
+                let (bytes, remaining) = decode_bytes(remaining)?;
+                let s = String::from_utf8(bytes).ok()?;
                 (
-                    DataValue::Regex(RegexWrapper(Regex::from_str(&s).unwrap())),
+                    DataValue::Regex(RegexWrapper(Regex::from_str(&s).ok()?)),
                     remaining,
                 )
             }
             LIST_TAG => {
                 let mut collected = vec![];
                 let mut remaining = remaining;
-                while remaining[0] != INIT_TAG {
-                    let (val, next_chunk) = DataValue::decode_from_key(remaining);
+                while *remaining.first()? != INIT_TAG {
+                    let (val, next_chunk) = DataValue::decode_from_key(remaining)?;
                     remaining = next_chunk;
                     collected.push(val);
                 }
@@ -272,19 +260,19 @@ impl DataValue {
             SET_TAG => {
                 let mut collected = BTreeSet::default();
                 let mut remaining = remaining;
-                while remaining[0] != INIT_TAG {
-                    let (val, next_chunk) = DataValue::decode_from_key(remaining);
+                while *remaining.first()? != INIT_TAG {
+                    let (val, next_chunk) = DataValue::decode_from_key(remaining)?;
                     remaining = next_chunk;
                     collected.insert(val);
                 }
                 (DataValue::Set(collected), &remaining[1..])
             }
             VLD_TAG => {
-                let (ts_flipped_bytes, rest) = remaining.split_at(8);
+                let (ts_flipped_bytes, rest) = try_split_at(remaining, 8)?;
                 let ts_flipped = BigEndian::read_u64(ts_flipped_bytes);
                 let ts_u64 = !ts_flipped;
                 let ts = order_decode_i64(ts_u64);
-                let (is_assert_byte, rest) = rest.split_first().unwrap();
+                let (is_assert_byte, rest) = rest.split_first()?;
                 let is_assert = *is_assert_byte == 0;
                 (
                     DataValue::Validity(Validity {
@@ -295,8 +283,8 @@ impl DataValue {
                 )
             }
             BOT_TAG => (DataValue::Bot, remaining),
-            _ => unreachable!("{:?}", bs),
-        }
+            _ => return None,
+        })
     }
 }
 
@@ -10,6 +10,7 @@ pub(crate) mod aggr;
 pub(crate) mod expr;
 pub(crate) mod functions;
 pub(crate) mod json;
+pub(crate) mod json_path;
 pub(crate) mod memcmp;
 pub(crate) mod program;
 pub(crate) mod relation;
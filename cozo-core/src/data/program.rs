@@ -11,29 +11,33 @@ use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{Debug, Display, Formatter};
 use std::sync::Arc;
 
-use miette::{ensure, Diagnostic, Result};
+use itertools::Itertools;
+use miette::{bail, ensure, Diagnostic, Result};
 use smallvec::SmallVec;
 use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
 use crate::data::aggr::Aggregation;
-use crate::data::expr::Expr;
+use crate::data::expr::{Expr, UserFunction};
+use crate::data::json::FloatFormat;
 use crate::data::relation::StoredRelationMetadata;
 use crate::data::symb::{Symbol, PROG_ENTRY};
 use crate::data::value::{DataValue, ValidityTs};
+use crate::fixed_rule::utilities::constant::Constant;
 use crate::fixed_rule::{FixedRule, FixedRuleHandle};
 use crate::parse::SourceSpan;
+use crate::runtime::graph::GraphRelation;
 use crate::runtime::relation::InputRelationHandle;
 use crate::runtime::temp_store::EpochStore;
 use crate::runtime::transact::SessionTx;
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
 pub(crate) enum QueryAssertion {
     AssertNone(SourceSpan),
     AssertSome(SourceSpan),
 }
 
-#[derive(Clone, PartialEq, Default)]
+#[derive(Clone, PartialEq, Default, serde_derive::Serialize, serde_derive::Deserialize)]
 pub(crate) struct QueryOutOptions {
     pub(crate) limit: Option<usize>,
     pub(crate) offset: Option<usize>,
@@ -42,6 +46,87 @@ pub(crate) struct QueryOutOptions {
     pub(crate) sorters: Vec<(Symbol, SortDir)>,
     pub(crate) store_relation: Option<(InputRelationHandle, RelationOp)>,
     pub(crate) assertion: Option<QueryAssertion>,
+    /// output validity values as RFC 3339 strings
+    pub(crate) validity_as_string: bool,
+    /// how floats are written in JSON results
+    pub(crate) float_format: Option<FloatFormat>,
+    /// write integers not exact as floats as strings in JSON results
+    pub(crate) big_int_as_string: bool,
+    /// report the resources used by the query with the results
+    pub(crate) report_usage: bool,
+    /// columns computed over the sorted output by `:window`, following those of the head
+    #[serde(default)]
+    pub(crate) windows: Vec<WindowSpec>,
+}
+
+/// A function computed by `:window` for each row of the sorted output, from the rows before it
+/// in its partition, or the rows just after it for `lead`
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) enum WindowFn {
+    RowNumber,
+    Rank,
+    DenseRank,
+    Lag {
+        col: Symbol,
+        offset: usize,
+        default: DataValue,
+    },
+    Lead {
+        col: Symbol,
+        offset: usize,
+        default: DataValue,
+    },
+    Sum(Symbol),
+    Min(Symbol),
+    Max(Symbol),
+}
+
+impl Display for WindowFn {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WindowFn::RowNumber => write!(f, "row_number()"),
+            WindowFn::Rank => write!(f, "rank()"),
+            WindowFn::DenseRank => write!(f, "dense_rank()"),
+            WindowFn::Lag {
+                col,
+                offset,
+                default,
+            } => write!(f, "lag({col}, {offset}, {default})"),
+            WindowFn::Lead {
+                col,
+                offset,
+                default,
+            } => write!(f, "lead({col}, {offset}, {default})"),
+            WindowFn::Sum(col) => write!(f, "sum({col})"),
+            WindowFn::Min(col) => write!(f, "min({col})"),
+            WindowFn::Max(col) => write!(f, "max({col})"),
+        }
+    }
+}
+
+impl WindowFn {
+    /// The column the function reads, if any
+    pub(crate) fn col(&self) -> Option<&Symbol> {
+        match self {
+            WindowFn::RowNumber | WindowFn::Rank | WindowFn::DenseRank => None,
+            WindowFn::Lag { col, .. }
+            | WindowFn::Lead { col, .. }
+            | WindowFn::Sum(col)
+            | WindowFn::Min(col)
+            | WindowFn::Max(col) => Some(col),
+        }
+    }
+}
+
+/// A column computed by `:window`
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) struct WindowSpec {
+    pub(crate) func: WindowFn,
+    pub(crate) name: Symbol,
+    /// the function starts over whenever the values of these columns change
+    pub(crate) partition_by: Vec<Symbol>,
+    /// rows with the same values of these columns are peers with the same rank
+    pub(crate) order_by: Vec<(Symbol, SortDir)>,
 }
 
 impl Debug for QueryOutOptions {
@@ -79,26 +164,7 @@ impl Display for QueryOutOptions {
             op,
         )) = &self.store_relation
         {
-            match op {
-                RelationOp::Create => {
-                    write!(f, ":create ")?;
-                }
-                RelationOp::Replace => {
-                    write!(f, ":replace ")?;
-                }
-                RelationOp::Put => {
-                    write!(f, ":put ")?;
-                }
-                RelationOp::Rm => {
-                    write!(f, ":rm ")?;
-                }
-                RelationOp::Ensure => {
-                    write!(f, ":ensure ")?;
-                }
-                RelationOp::EnsureNot => {
-                    write!(f, ":ensure_not ")?;
-                }
-            }
+            write!(f, ":{} ", op.as_str())?;
             write!(f, "{name} {{")?;
             let mut is_first = true;
             for (col, bind) in keys.iter().zip(key_bindings) {
@@ -128,6 +194,12 @@ impl Display for QueryOutOptions {
                 } else {
                     write!(f, " = {bind}")?;
                 }
+                if let Some(au) = &col.auto_update {
+                    write!(f, " auto_update {}", au.expr)?;
+                    if au.allow_override {
+                        write!(f, " allow_override")?;
+                    }
+                }
             }
             writeln!(f, "}};")?;
         }
@@ -143,27 +215,76 @@ impl Display for QueryOutOptions {
             }
         }
 
+        if self.validity_as_string {
+            writeln!(f, ":validity_as_string;")?;
+        }
+
+        if let Some(ff) = &self.float_format {
+            writeln!(f, ":float_format '{ff}';")?;
+        }
+
+        if self.big_int_as_string {
+            writeln!(f, ":big_int_as_string;")?;
+        }
+
+        if self.report_usage {
+            writeln!(f, ":report_usage;")?;
+        }
+
+        for window in &self.windows {
+            write!(f, ":window {} as {}", window.func, window.name)?;
+            if !window.partition_by.is_empty() {
+                write!(f, " partition by {}", window.partition_by.iter().join(", "))?;
+            }
+            if !window.order_by.is_empty() {
+                write!(f, " order by ")?;
+                for (i, (symb, dir)) in window.order_by.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    if *dir == SortDir::Dsc {
+                        write!(f, "-")?;
+                    }
+                    write!(f, "{symb}")?;
+                }
+            }
+            writeln!(f, ";")?;
+        }
+
         Ok(())
     }
 }
 
 impl QueryOutOptions {
+    /// The number of rows of the sorted output needed, all of them for computing windows
     pub(crate) fn num_to_take(&self) -> Option<usize> {
+        if !self.windows.is_empty() {
+            return None;
+        }
         match (self.limit, self.offset) {
             (None, _) => None,
             (Some(i), None) => Some(i),
             (Some(i), Some(j)) => Some(i + j),
         }
     }
+    /// The numbers of rows to take and to skip by the evaluation of the entry rule, which
+    /// stops as soon as enough rows are taken. Only possible when the rows are not sorted.
+    pub(crate) fn early_stop(&self) -> (Option<usize>, Option<usize>) {
+        if self.sorters.is_empty() && self.windows.is_empty() {
+            (self.num_to_take(), self.offset)
+        } else {
+            (None, None)
+        }
+    }
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
 pub(crate) enum SortDir {
     Asc,
     Dsc,
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
 pub(crate) enum RelationOp {
     Create,
     Replace,
@@ -171,6 +292,26 @@ pub(crate) enum RelationOp {
     Rm,
     Ensure,
     EnsureNot,
+    /// writes the given columns of existing rows, the other columns keep their values
+    Update,
+    /// like [RelationOp::Update], also inserting the missing rows with default values
+    Upsert,
+}
+
+impl RelationOp {
+    /// The name of the op, as written in scripts without the colon
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            RelationOp::Create => "create",
+            RelationOp::Replace => "replace",
+            RelationOp::Put => "put",
+            RelationOp::Rm => "rm",
+            RelationOp::Ensure => "ensure",
+            RelationOp::EnsureNot => "ensure_not",
+            RelationOp::Update => "update",
+            RelationOp::Upsert => "upsert",
+        }
+    }
 }
 
 #[derive(Default)]
@@ -248,15 +389,65 @@ impl Debug for FixedRuleApply {
     }
 }
 
+#[derive(serde_derive::Serialize, serde_derive::Deserialize)]
 pub(crate) struct MagicFixedRuleApply {
     pub(crate) fixed_handle: FixedRuleHandle,
     pub(crate) rule_args: Vec<MagicFixedRuleRuleArg>,
+    #[serde(
+        serialize_with = "serialize_fixed_options",
+        deserialize_with = "deserialize_fixed_options"
+    )]
     pub(crate) options: Arc<BTreeMap<SmartString<LazyCompact>, Expr>>,
     pub(crate) span: SourceSpan,
     pub(crate) arity: usize,
+    /// Not serialized: must be looked up again by name with [Self::rebind_fixed_impl]
+    /// after deserialization.
+    #[serde(skip, default = "unbound_fixed_impl")]
     pub(crate) fixed_impl: Arc<Box<dyn FixedRule>>,
 }
 
+impl MagicFixedRuleApply {
+    /// Attaches the implementation registered under the name of the rule,
+    /// returns false if no such implementation exists.
+    pub(crate) fn rebind_fixed_impl(
+        &mut self,
+        fixed_rules: &BTreeMap<String, Arc<Box<dyn FixedRule>>>,
+    ) -> bool {
+        match fixed_rules.get(&self.fixed_handle.name.name as &str) {
+            None => false,
+            Some(fixed_impl) => {
+                self.fixed_impl = fixed_impl.clone();
+                true
+            }
+        }
+    }
+}
+
+fn serialize_fixed_options<S>(
+    options: &Arc<BTreeMap<SmartString<LazyCompact>, Expr>>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serde::Serialize::serialize(options.as_ref(), serializer)
+}
+
+fn deserialize_fixed_options<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Arc<BTreeMap<SmartString<LazyCompact>, Expr>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let options: BTreeMap<SmartString<LazyCompact>, Expr> =
+        serde::Deserialize::deserialize(deserializer)?;
+    Ok(Arc::new(options))
+}
+
+fn unbound_fixed_impl() -> Arc<Box<dyn FixedRule>> {
+    Arc::new(Box::new(Constant))
+}
+
 #[derive(Error, Diagnostic, Debug)]
 #[error("Cannot find a required named option '{name}' for '{rule_name}'")]
 #[diagnostic(code(fixed_rule::arg_not_found))]
@@ -344,12 +535,14 @@ pub(crate) enum FixedRuleArg {
     Stored {
         name: Symbol,
         bindings: Vec<Symbol>,
+        filters: Vec<Expr>,
         valid_at: Option<ValidityTs>,
         span: SourceSpan,
     },
     NamedStored {
         name: Symbol,
         bindings: BTreeMap<SmartString<LazyCompact>, Symbol>,
+        filters: Vec<Expr>,
         valid_at: Option<ValidityTs>,
         span: SourceSpan,
     },
@@ -385,7 +578,44 @@ impl Display for FixedRuleArg {
     }
 }
 
-#[derive(Debug)]
+fn graph_relation_rule(
+    rel: &GraphRelation,
+    bindings: &[Symbol],
+    span: SourceSpan,
+) -> InputInlineRulesOrFixed {
+    let args = rel
+        .columns
+        .iter()
+        .zip(bindings)
+        .map(|(col, binding)| {
+            (
+                col.clone(),
+                Expr::Binding {
+                    var: binding.clone(),
+                    tuple_pos: None,
+                },
+            )
+        })
+        .collect();
+    InputInlineRulesOrFixed::Rules {
+        rules: vec![InputInlineRule {
+            head: bindings.to_vec(),
+            aggr: bindings.iter().map(|_| None).collect(),
+            body: vec![InputAtom::NamedFieldRelation {
+                inner: InputNamedFieldRelationApplyAtom {
+                    name: Symbol::new(rel.relation.clone(), span),
+                    args,
+                    valid_at: None,
+                    valid_until: None,
+                    span,
+                },
+            }],
+            span,
+        }],
+    }
+}
+
+#[derive(Debug, serde_derive::Serialize, serde_derive::Deserialize)]
 pub(crate) enum MagicFixedRuleRuleArg {
     InMem {
         name: MagicSymbol,
@@ -395,6 +625,8 @@ pub(crate) enum MagicFixedRuleRuleArg {
     Stored {
         name: Symbol,
         bindings: Vec<Symbol>,
+        /// filters with binding indices referring to the columns of the relation
+        filters: Vec<Expr>,
         valid_at: Option<ValidityTs>,
         span: SourceSpan,
     },
@@ -593,10 +825,150 @@ impl InputProgram {
 
         Err(NoEntryError.into())
     }
+    /// Whether the plan of the program must not be reused, see [Expr::is_uncacheable].
+    /// Validity specifications are taken when the program is parsed, and may be `NOW`.
+    pub(crate) fn is_uncacheable(&self) -> bool {
+        for rules_or_fixed in self.prog.values() {
+            match rules_or_fixed {
+                InputInlineRulesOrFixed::Rules { rules } => {
+                    for rule in rules {
+                        if rule.body.iter().any(|atom| atom.is_uncacheable()) {
+                            return true;
+                        }
+                    }
+                }
+                InputInlineRulesOrFixed::Fixed { fixed } => {
+                    for arg in &fixed.rule_args {
+                        if let FixedRuleArg::Stored {
+                            filters, valid_at, ..
+                        }
+                        | FixedRuleArg::NamedStored {
+                            filters, valid_at, ..
+                        } = arg
+                        {
+                            if valid_at.is_some() || filters.iter().any(|f| f.is_uncacheable()) {
+                                return true;
+                            }
+                        }
+                    }
+                    if fixed.options.values().any(|option| option.is_uncacheable()) {
+                        return true;
+                    }
+                }
+            }
+        }
+        false
+    }
+    /// Finds the functions registered with the database that the program applies
+    fn resolve_user_fns(&mut self, registry: &BTreeMap<String, Arc<UserFunction>>) -> Result<()> {
+        for rules_or_fixed in self.prog.values_mut() {
+            match rules_or_fixed {
+                InputInlineRulesOrFixed::Rules { rules } => {
+                    for rule in rules {
+                        for atom in rule.body.iter_mut() {
+                            atom.resolve_user_fns(registry)?;
+                        }
+                    }
+                }
+                InputInlineRulesOrFixed::Fixed { fixed } => {
+                    for arg in fixed.rule_args.iter_mut() {
+                        if let FixedRuleArg::Stored { filters, .. }
+                        | FixedRuleArg::NamedStored { filters, .. } = arg
+                        {
+                            for filter in filters {
+                                filter.resolve_user_fns(registry)?;
+                            }
+                        }
+                    }
+                    for option in Arc::make_mut(&mut fixed.options).values_mut() {
+                        option.resolve_user_fns(registry)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Replaces the `graph` option of fixed rules by the edge (and node) relations
+    /// of the named graph, read through generated rules and passed as the leading
+    /// positional arguments.
+    fn resolve_graphs(&mut self, tx: &SessionTx<'_>) -> Result<()> {
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("The 'graph' option requires the name of a graph as a string")]
+        #[diagnostic(code(eval::bad_graph_option))]
+        struct BadGraphOption(#[label] SourceSpan);
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("Fixed rule '{0}' requires node data, but graph '{1}' has no node relation")]
+        #[diagnostic(code(eval::graph_without_nodes))]
+        struct GraphWithoutNodes(String, String, #[label] SourceSpan);
+
+        let mut generated = BTreeMap::new();
+        for rules_or_fixed in self.prog.values_mut() {
+            let fixed = match rules_or_fixed {
+                InputInlineRulesOrFixed::Fixed { fixed } => fixed,
+                InputInlineRulesOrFixed::Rules { .. } => continue,
+            };
+            let graph_name = match fixed.options.get("graph") {
+                None => continue,
+                Some(ex) => ex.clone(),
+            };
+            let span = graph_name.span();
+            let graph_name = match graph_name.eval_to_const()? {
+                DataValue::Str(s) => s,
+                _ => bail!(BadGraphOption(span)),
+            };
+            let def = tx.get_graph(&graph_name, span)?;
+
+            let mut graph_args = vec![];
+            let mut graph_rels = vec![(&def.edges, "edges")];
+            if fixed.fixed_impl.takes_graph_nodes() {
+                let nodes = def.nodes.as_ref().ok_or_else(|| {
+                    GraphWithoutNodes(
+                        fixed.fixed_handle.name.to_string(),
+                        def.name.to_string(),
+                        span,
+                    )
+                })?;
+                graph_rels.push((nodes, "nodes"));
+            }
+            for (rel, kind) in graph_rels {
+                let name = Symbol::new(format!("*{}.{}", def.name, kind), span);
+                let bindings: Vec<_> = rel
+                    .columns
+                    .iter()
+                    .map(|col| Symbol::new(col.clone(), span))
+                    .collect();
+                generated
+                    .entry(name.clone())
+                    .or_insert_with(|| graph_relation_rule(rel, &bindings, span));
+                graph_args.push(FixedRuleArg::InMem {
+                    name,
+                    bindings,
+                    span,
+                });
+            }
+            fixed.rule_args.splice(0..0, graph_args);
+
+            let options = Arc::make_mut(&mut fixed.options);
+            options.remove("graph");
+            if def.undirected {
+                options
+                    .entry(SmartString::from("undirected"))
+                    .or_insert(Expr::Const {
+                        val: DataValue::from(true),
+                        span,
+                    });
+            }
+        }
+        self.prog.extend(generated);
+        Ok(())
+    }
     pub(crate) fn into_normalized_program(
-        self,
+        mut self,
         tx: &SessionTx<'_>,
     ) -> Result<(NormalFormProgram, QueryOutOptions)> {
+        self.resolve_user_fns(&tx.user_functions.read().unwrap())?;
+        self.resolve_graphs(tx)?;
         let mut prog: BTreeMap<Symbol, _> = Default::default();
         for (k, rules_or_fixed) in self.prog {
             match rules_or_fixed {
@@ -725,7 +1097,9 @@ pub(crate) struct MagicProgram {
     pub(crate) prog: BTreeMap<MagicSymbol, MagicRulesOrFixed>,
 }
 
-#[derive(Clone, Ord, PartialOrd, Eq, PartialEq)]
+#[derive(
+    Clone, Ord, PartialOrd, Eq, PartialEq, serde_derive::Serialize, serde_derive::Deserialize,
+)]
 pub(crate) enum MagicSymbol {
     Muggle {
         inner: Symbol,
@@ -904,6 +1278,9 @@ pub(crate) enum InputAtom {
     Unification {
         inner: Unification,
     },
+    Search {
+        inner: InputSearchAtom,
+    },
 }
 
 impl Debug for InputAtom {
@@ -976,6 +1353,23 @@ impl Display for InputAtom {
                 }
                 write!(f, "{expr}")?;
             }
+            InputAtom::Search {
+                inner:
+                    InputSearchAtom {
+                        relation,
+                        index,
+                        bindings,
+                        parameters,
+                        ..
+                    },
+            } => {
+                write!(f, "~{relation}:{index}")?;
+                let mut sf = f.debug_struct("");
+                for (k, v) in bindings.iter().chain(parameters) {
+                    sf.field(k, v);
+                }
+                sf.finish()?;
+            }
         }
         Ok(())
     }
@@ -1002,8 +1396,68 @@ impl InputAtom {
             InputAtom::Relation { inner, .. } => inner.span,
             InputAtom::Predicate { inner, .. } => inner.span(),
             InputAtom::Unification { inner, .. } => inner.span,
+            InputAtom::Search { inner, .. } => inner.span,
+        }
+    }
+    fn is_uncacheable(&self) -> bool {
+        match self {
+            InputAtom::Rule { inner } => inner.args.iter().any(|arg| arg.is_uncacheable()),
+            InputAtom::NamedFieldRelation { inner } => {
+                inner.valid_at.is_some() || inner.args.values().any(|arg| arg.is_uncacheable())
+            }
+            InputAtom::Relation { inner } => {
+                inner.valid_at.is_some() || inner.args.iter().any(|arg| arg.is_uncacheable())
+            }
+            InputAtom::Predicate { inner } => inner.is_uncacheable(),
+            InputAtom::Negation { inner, .. } => inner.is_uncacheable(),
+            InputAtom::Conjunction { inner, .. } | InputAtom::Disjunction { inner, .. } => {
+                inner.iter().any(|atom| atom.is_uncacheable())
+            }
+            InputAtom::Unification { inner } => inner.expr.is_uncacheable(),
+            InputAtom::Search { inner } => inner
+                .bindings
+                .values()
+                .chain(inner.parameters.values())
+                .any(|arg| arg.is_uncacheable()),
         }
     }
+    fn resolve_user_fns(&mut self, registry: &BTreeMap<String, Arc<UserFunction>>) -> Result<()> {
+        match self {
+            InputAtom::Rule { inner } => {
+                for arg in inner.args.iter_mut() {
+                    arg.resolve_user_fns(registry)?;
+                }
+            }
+            InputAtom::NamedFieldRelation { inner } => {
+                for arg in inner.args.values_mut() {
+                    arg.resolve_user_fns(registry)?;
+                }
+            }
+            InputAtom::Relation { inner } => {
+                for arg in inner.args.iter_mut() {
+                    arg.resolve_user_fns(registry)?;
+                }
+            }
+            InputAtom::Predicate { inner } => inner.resolve_user_fns(registry)?,
+            InputAtom::Negation { inner, .. } => inner.resolve_user_fns(registry)?,
+            InputAtom::Conjunction { inner, .. } | InputAtom::Disjunction { inner, .. } => {
+                for atom in inner {
+                    atom.resolve_user_fns(registry)?;
+                }
+            }
+            InputAtom::Unification { inner } => inner.expr.resolve_user_fns(registry)?,
+            InputAtom::Search { inner } => {
+                for arg in inner
+                    .bindings
+                    .values_mut()
+                    .chain(inner.parameters.values_mut())
+                {
+                    arg.resolve_user_fns(registry)?;
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -1014,6 +1468,9 @@ pub(crate) enum NormalFormAtom {
     NegatedRelation(NormalFormRelationApplyAtom),
     Predicate(Expr),
     Unification(Unification),
+    HnswSearch(HnswSearch),
+    FtsSearch(FtsSearch),
+    LshSearch(LshSearch),
 }
 
 #[derive(Debug, Clone)]
@@ -1024,6 +1481,9 @@ pub(crate) enum MagicAtom {
     NegatedRule(MagicRuleApplyAtom),
     NegatedRelation(MagicRelationApplyAtom),
     Unification(Unification),
+    HnswSearch(HnswSearch),
+    FtsSearch(FtsSearch),
+    LshSearch(LshSearch),
 }
 
 #[derive(Clone, Debug)]
@@ -1038,6 +1498,19 @@ pub(crate) struct InputNamedFieldRelationApplyAtom {
     pub(crate) name: Symbol,
     pub(crate) args: BTreeMap<SmartString<LazyCompact>, Expr>,
     pub(crate) valid_at: Option<ValidityTs>,
+    pub(crate) valid_until: Option<ValidityTs>,
+    pub(crate) span: SourceSpan,
+}
+
+/// A search of an index, `~rel:idx{col: var | query: $q, k: 10}`, the parameters taken
+/// depending on the kind of the index
+#[derive(Clone, Debug)]
+pub(crate) struct InputSearchAtom {
+    pub(crate) relation: Symbol,
+    pub(crate) index: Symbol,
+    /// the columns of the rows found, bound by name as in `*rel{..}`
+    pub(crate) bindings: BTreeMap<SmartString<LazyCompact>, Expr>,
+    pub(crate) parameters: BTreeMap<SmartString<LazyCompact>, Expr>,
     pub(crate) span: SourceSpan,
 }
 
@@ -1046,6 +1519,8 @@ pub(crate) struct InputRelationApplyAtom {
     pub(crate) name: Symbol,
     pub(crate) args: Vec<Expr>,
     pub(crate) valid_at: Option<ValidityTs>,
+    /// the end of the window if every version valid from `valid_at` until then is scanned
+    pub(crate) valid_until: Option<ValidityTs>,
     pub(crate) span: SourceSpan,
 }
 
@@ -1061,6 +1536,7 @@ pub(crate) struct NormalFormRelationApplyAtom {
     pub(crate) name: Symbol,
     pub(crate) args: Vec<Symbol>,
     pub(crate) valid_at: Option<ValidityTs>,
+    pub(crate) valid_until: Option<ValidityTs>,
     pub(crate) span: SourceSpan,
 }
 
@@ -1076,9 +1552,69 @@ pub(crate) struct MagicRelationApplyAtom {
     pub(crate) name: Symbol,
     pub(crate) args: Vec<Symbol>,
     pub(crate) valid_at: Option<ValidityTs>,
+    pub(crate) valid_until: Option<ValidityTs>,
     pub(crate) span: SourceSpan,
 }
 
+/// A search of an HNSW index with a variable for every column of the relation
+#[derive(Clone, Debug)]
+pub(crate) struct HnswSearch {
+    pub(crate) relation: Symbol,
+    pub(crate) index: Symbol,
+    pub(crate) args: Vec<Symbol>,
+    pub(crate) query: Expr,
+    pub(crate) k: usize,
+    pub(crate) ef: Option<usize>,
+    pub(crate) bind_distance: Option<Symbol>,
+    pub(crate) span: SourceSpan,
+}
+
+impl HnswSearch {
+    /// The variables bound by the search: the columns, then the distance if asked for
+    pub(crate) fn all_bindings(&self) -> impl Iterator<Item = &Symbol> {
+        self.args.iter().chain(self.bind_distance.iter())
+    }
+}
+
+/// A search of a full-text index with a variable for every column of the relation
+#[derive(Clone, Debug)]
+pub(crate) struct FtsSearch {
+    pub(crate) relation: Symbol,
+    pub(crate) index: Symbol,
+    pub(crate) args: Vec<Symbol>,
+    pub(crate) query: Expr,
+    pub(crate) k: usize,
+    pub(crate) bind_score: Option<Symbol>,
+    pub(crate) span: SourceSpan,
+}
+
+impl FtsSearch {
+    /// The variables bound by the search: the columns, then the score if asked for
+    pub(crate) fn all_bindings(&self) -> impl Iterator<Item = &Symbol> {
+        self.args.iter().chain(self.bind_score.iter())
+    }
+}
+
+/// A search of an LSH index with a variable for every column of the relation
+#[derive(Clone, Debug)]
+pub(crate) struct LshSearch {
+    pub(crate) relation: Symbol,
+    pub(crate) index: Symbol,
+    pub(crate) args: Vec<Symbol>,
+    pub(crate) query: Expr,
+    /// the similarity of the rows found, the target threshold of the index if not given
+    pub(crate) min_similarity: Option<f64>,
+    pub(crate) bind_similarity: Option<Symbol>,
+    pub(crate) span: SourceSpan,
+}
+
+impl LshSearch {
+    /// The variables bound by the search: the columns, then the similarity if asked for
+    pub(crate) fn all_bindings(&self) -> impl Iterator<Item = &Symbol> {
+        self.args.iter().chain(self.bind_similarity.iter())
+    }
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct Unification {
     pub(crate) binding: Symbol,
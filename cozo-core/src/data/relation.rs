@@ -8,18 +8,17 @@
 
 use std::cmp::Reverse;
 use std::fmt::{Display, Formatter};
-use std::time::{SystemTime, UNIX_EPOCH};
 
 use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
-use chrono::DateTime;
 use itertools::Itertools;
 use miette::{bail, ensure, Diagnostic, Result};
 use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
 use crate::data::expr::Expr;
-use crate::data::value::{DataValue, UuidWrapper, Validity, ValidityTs};
+use crate::data::functions::str2vld;
+use crate::data::value::{DataValue, Num, UuidWrapper, Validity, ValidityTs};
 
 #[derive(Debug, Clone, Eq, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
 pub(crate) struct NullableColType {
@@ -27,6 +26,59 @@ pub(crate) struct NullableColType {
     pub(crate) nullable: bool,
 }
 
+impl NullableColType {
+    /// The narrowest type holding all the values given: nullable if one of them is null,
+    /// a float if they are integers and floats, and `Any` if they are of other different types.
+    /// The type of the elements of lists is inferred from all their elements.
+    pub(crate) fn infer<'a>(values: impl Iterator<Item = &'a DataValue>) -> Self {
+        let mut coltype = None;
+        let mut nullable = false;
+        let mut elements = vec![];
+        for val in values {
+            let val_type = match val {
+                DataValue::Null => {
+                    nullable = true;
+                    continue;
+                }
+                DataValue::Bool(_) => ColType::Bool,
+                DataValue::Num(Num::Int(_)) => ColType::Int,
+                DataValue::Num(Num::Float(_)) => ColType::Float,
+                DataValue::Str(_) => ColType::String,
+                DataValue::Bytes(_) => ColType::Bytes,
+                DataValue::Uuid(_) => ColType::Uuid,
+                DataValue::Validity(_) => ColType::Validity,
+                DataValue::List(l) => {
+                    elements.extend(l.iter());
+                    // the type of the elements is filled in below
+                    ColType::List {
+                        eltype: Box::new(NullableColType {
+                            coltype: ColType::Any,
+                            nullable: true,
+                        }),
+                        len: None,
+                    }
+                }
+                _ => ColType::Any,
+            };
+            coltype = Some(match (coltype, val_type) {
+                (None, t) => t,
+                (Some(t), val_type) if t == val_type => t,
+                (Some(ColType::Int), ColType::Float) | (Some(ColType::Float), ColType::Int) => {
+                    ColType::Float
+                }
+                _ => ColType::Any,
+            });
+        }
+        if let Some(ColType::List { eltype, .. }) = &mut coltype {
+            **eltype = Self::infer(elements.into_iter());
+        }
+        Self {
+            coltype: coltype.unwrap_or(ColType::Any),
+            nullable,
+        }
+    }
+}
+
 impl Display for NullableColType {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match &self.coltype {
@@ -87,6 +139,21 @@ pub(crate) struct ColumnDef {
     pub(crate) name: SmartString<LazyCompact>,
     pub(crate) typing: NullableColType,
     pub(crate) default_gen: Option<Expr>,
+    #[serde(default)]
+    pub(crate) auto_update: Option<AutoUpdate>,
+    /// Whether no two rows may have the same non-null value in the column,
+    /// enforced by a hidden index of the relation
+    #[serde(default)]
+    pub(crate) unique: bool,
+}
+
+/// A value computed anew whenever a row is put, e.g. an `updated_at` timestamp.
+#[derive(Debug, Clone, Eq, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub(crate) struct AutoUpdate {
+    /// The source of the expression, which is parsed with the parameters of each writing query
+    pub(crate) expr: String,
+    /// Whether a value given explicitly by the writing query is used instead
+    pub(crate) allow_override: bool,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
@@ -96,6 +163,17 @@ pub(crate) struct StoredRelationMetadata {
 }
 
 impl StoredRelationMetadata {
+    /// Whether the key and non-key columns have the same names and types, in the same order,
+    /// so that the rows of one relation are rows of the other
+    pub(crate) fn same_columns_as(&self, other: &Self) -> bool {
+        let same = |a: &[ColumnDef], b: &[ColumnDef]| {
+            a.len() == b.len()
+                && a.iter()
+                    .zip(b)
+                    .all(|(x, y)| x.name == y.name && x.typing == y.typing)
+        };
+        same(&self.keys, &other.keys) && same(&self.non_keys, &other.non_keys)
+    }
     pub(crate) fn satisfied_by_required_col(&self, col: &ColumnDef, is_key: bool) -> Result<()> {
         let targets = if is_key { &self.keys } else { &self.non_keys };
         for target in targets {
@@ -103,7 +181,7 @@ impl StoredRelationMetadata {
                 return Ok(());
             }
         }
-        if col.default_gen.is_none() {
+        if col.default_gen.is_none() && col.auto_update.is_none() {
             #[derive(Debug, Error, Diagnostic)]
             #[error("required column {0} not provided by input")]
             #[diagnostic(code(eval::required_col_not_provided))]
@@ -258,11 +336,10 @@ impl NullableColType {
                                 None => (true, s),
                                 Some(remaining) => (false, remaining),
                             };
-                            let dt = DateTime::parse_from_rfc3339(ts_str)
-                                .map_err(|_| InvalidValidity(DataValue::Str(s.into())))?;
-                            let st: SystemTime = dt.into();
-                            let microseconds =
-                                st.duration_since(UNIX_EPOCH).unwrap().as_micros() as i64;
+                            let microseconds = str2vld(ts_str)
+                                .map_err(|_| InvalidValidity(DataValue::Str(s.into())))?
+                                .0
+                                 .0;
 
                             if microseconds == i64::MAX || microseconds == i64::MIN {
                                 bail!(InvalidValidity(DataValue::Str(s.into())))
@@ -276,7 +353,10 @@ impl NullableColType {
                     },
                     DataValue::List(l) => {
                         if l.len() == 2 {
-                            let o_ts = l[0].get_int();
+                            let o_ts = match &l[0] {
+                                DataValue::Str(s) => str2vld(s).ok().map(|ts| ts.0 .0),
+                                v => v.get_int(),
+                            };
                             let o_is_assert = l[1].get_bool();
                             if let (Some(ts), Some(is_assert)) = (o_ts, o_is_assert) {
                                 if ts == i64::MAX || ts == i64::MIN {
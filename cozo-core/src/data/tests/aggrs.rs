@@ -71,6 +71,64 @@ fn test_or() {
     assert_eq!(v, DataValue::from(true));
 }
 
+#[test]
+fn test_any_all() {
+    let mut aggr = parse_aggr("any").unwrap().clone();
+    aggr.normal_init(&[]).unwrap();
+    let mut any_aggr = aggr.normal_op.unwrap();
+    assert_eq!(any_aggr.get().unwrap(), DataValue::from(false));
+    any_aggr.set(&DataValue::from(false)).unwrap();
+    assert!(!any_aggr.is_determined());
+    any_aggr.set(&DataValue::from(true)).unwrap();
+    assert!(any_aggr.is_determined());
+    assert_eq!(any_aggr.get().unwrap(), DataValue::from(true));
+
+    let mut aggr = parse_aggr("all").unwrap().clone();
+    aggr.normal_init(&[]).unwrap();
+    let mut all_aggr = aggr.normal_op.unwrap();
+    assert_eq!(all_aggr.get().unwrap(), DataValue::from(true));
+    all_aggr.set(&DataValue::from(true)).unwrap();
+    assert!(!all_aggr.is_determined());
+    all_aggr.set(&DataValue::from(false)).unwrap();
+    assert!(all_aggr.is_determined());
+    assert_eq!(all_aggr.get().unwrap(), DataValue::from(false));
+    assert!(all_aggr.set(&DataValue::from(1)).is_err());
+
+    let pair = |cond: bool, witness: i64| {
+        DataValue::List(vec![DataValue::from(cond), DataValue::from(witness)])
+    };
+    let mut aggr = parse_aggr("any").unwrap().clone();
+    aggr.normal_init(&[DataValue::from(true)]).unwrap();
+    let mut any_aggr = aggr.normal_op.unwrap();
+    assert_eq!(
+        any_aggr.get().unwrap(),
+        DataValue::List(vec![DataValue::from(false), DataValue::Null])
+    );
+    any_aggr.set(&pair(false, 1)).unwrap();
+    any_aggr.set(&pair(true, 2)).unwrap();
+    any_aggr.set(&pair(true, 3)).unwrap();
+    assert_eq!(
+        any_aggr.get().unwrap(),
+        DataValue::List(vec![DataValue::from(true), DataValue::from(2)])
+    );
+    assert!(any_aggr.set(&DataValue::from(true)).is_err());
+
+    let mut aggr = parse_aggr("all").unwrap().clone();
+    aggr.normal_init(&[DataValue::from(true)]).unwrap();
+    let mut all_aggr = aggr.normal_op.unwrap();
+    all_aggr.set(&pair(true, 1)).unwrap();
+    all_aggr.set(&pair(false, 2)).unwrap();
+    assert_eq!(
+        all_aggr.get().unwrap(),
+        DataValue::List(vec![DataValue::from(false), DataValue::from(2)])
+    );
+    assert!(parse_aggr("all")
+        .unwrap()
+        .clone()
+        .normal_init(&[DataValue::from(1)])
+        .is_err());
+}
+
 #[test]
 fn test_unique() {
     let mut aggr = parse_aggr("unique").unwrap().clone();
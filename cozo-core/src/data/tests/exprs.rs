@@ -6,6 +6,9 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+use crate::data::expr::{eval_bytecode, Bytecode, Expr};
+use crate::data::functions::OP_REGEX_MATCHES;
+use crate::data::symb::Symbol;
 use crate::{new_cozo_mem, DataValue};
 
 #[test]
@@ -32,3 +35,55 @@ fn expression_eval() {
         .unwrap();
     assert_eq!(res.rows[0][0].get_bool().unwrap(), true);
 }
+
+#[test]
+fn regex_compiled_once() {
+    let binding = |name: &str, pos: usize| Expr::Binding {
+        var: Symbol::new(name, Default::default()),
+        tuple_pos: Some(pos),
+    };
+    let mut args = [binding("s", 0), binding("p", 1)];
+    OP_REGEX_MATCHES.post_process_args(&mut args);
+    let expr = Expr::Apply {
+        op: &OP_REGEX_MATCHES,
+        args: args.into(),
+        span: Default::default(),
+    };
+    let bytecodes = expr.compile();
+    let cache = bytecodes
+        .iter()
+        .find_map(|code| match code {
+            Bytecode::Regex { cache, .. } => Some(cache.clone()),
+            _ => None,
+        })
+        .unwrap();
+
+    let mut stack = vec![];
+    let mut eval = |s: &str, p: &str| {
+        eval_bytecode(
+            &bytecodes,
+            [DataValue::from(s), DataValue::from(p)],
+            &mut stack,
+        )
+        .unwrap()
+    };
+    for i in 0..1000 {
+        assert_eq!(
+            eval(&format!("row {i}"), r"^row \d+$"),
+            DataValue::from(true)
+        );
+    }
+    assert_eq!(cache.compilations(), 1);
+    assert_eq!(eval("row 1", "^r.w"), DataValue::from(true));
+    assert_eq!(eval("row 1", "^w"), DataValue::from(false));
+    assert_eq!(cache.compilations(), 3);
+    // clones of the program share the cache
+    let cloned = bytecodes.clone();
+    eval_bytecode(
+        &cloned,
+        [DataValue::from("x"), DataValue::from("^w")],
+        &mut vec![],
+    )
+    .unwrap();
+    assert_eq!(cache.compilations(), 3);
+}
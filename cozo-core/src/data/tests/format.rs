@@ -0,0 +1,276 @@
+/*
+ *  Copyright 2023, The Cozo Project Authors.
+ *
+ *  This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ *  If a copy of the MPL was not distributed with this file,
+ *  You can obtain one at https://mozilla.org/MPL/2.0/.
+ *
+ */
+
+use std::cmp::Reverse;
+use std::collections::BTreeSet;
+
+use regex::Regex;
+use uuid::Uuid;
+
+use crate::data::value::{DataValue, Num, RegexWrapper, UuidWrapper, Validity, ValidityTs};
+use crate::format::*;
+use crate::new_cozo_mem;
+
+fn vld(ts: i64, is_assert: bool) -> DataValue {
+    DataValue::Validity(Validity {
+        timestamp: ValidityTs(Reverse(ts)),
+        is_assert: Reverse(is_assert),
+    })
+}
+
+#[test]
+fn known_answer_values() {
+    let uuid = Uuid::parse_str("00112233-4455-6677-8899-aabbccddeeff").unwrap();
+    let cases: Vec<(DataValue, Vec<u8>)> = vec![
+        (DataValue::Null, vec![0x01]),
+        (DataValue::from(false), vec![0x02]),
+        (DataValue::from(true), vec![0x03]),
+        (
+            DataValue::from(0),
+            vec![0x05, 0x80, 0, 0, 0, 0, 0, 0, 0, 0x00],
+        ),
+        (
+            DataValue::from(1),
+            vec![0x05, 0xbf, 0xf0, 0, 0, 0, 0, 0, 0, 0x00],
+        ),
+        (
+            DataValue::from(-1),
+            vec![0x05, 0x40, 0x0f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00],
+        ),
+        // integers not exactly representable as floats are followed by the exact value
+        (
+            DataValue::from(i64::MAX),
+            vec![
+                0x05, 0xc3, 0xe0, 0, 0, 0, 0, 0, 0, 0x04, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+                0xff,
+            ],
+        ),
+        (
+            DataValue::from(1.5),
+            vec![0x05, 0xbf, 0xf8, 0, 0, 0, 0, 0, 0, 0x10],
+        ),
+        (
+            DataValue::from(2.0),
+            vec![0x05, 0xc0, 0, 0, 0, 0, 0, 0, 0, 0x10],
+        ),
+        (
+            DataValue::from(-0.0),
+            vec![0x05, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x10],
+        ),
+        (
+            DataValue::from(""),
+            vec![0x06, 0, 0, 0, 0, 0, 0, 0, 0, 0xf7],
+        ),
+        (
+            DataValue::from("abc"),
+            vec![0x06, b'a', b'b', b'c', 0, 0, 0, 0, 0, 0xfa],
+        ),
+        // a full group is always followed by another one
+        (
+            DataValue::from("abcdefgh"),
+            vec![
+                0x06, b'a', b'b', b'c', b'd', b'e', b'f', b'g', b'h', 0xff, 0, 0, 0, 0, 0, 0, 0, 0,
+                0xf7,
+            ],
+        ),
+        (
+            DataValue::Bytes(vec![1, 2, 3]),
+            vec![0x07, 1, 2, 3, 0, 0, 0, 0, 0, 0xfa],
+        ),
+        // the bytes of the UUID in order
+        (
+            DataValue::Uuid(UuidWrapper(uuid)),
+            vec![
+                0x08, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc,
+                0xdd, 0xee, 0xff,
+            ],
+        ),
+        (
+            DataValue::Regex(RegexWrapper(Regex::new("a+").unwrap())),
+            vec![0x09, b'a', b'+', 0, 0, 0, 0, 0, 0, 0xf9],
+        ),
+        (
+            DataValue::List(vec![DataValue::from(1), DataValue::Null]),
+            vec![0x0a, 0x05, 0xbf, 0xf0, 0, 0, 0, 0, 0, 0, 0x00, 0x01, 0x00],
+        ),
+        (
+            DataValue::Set(BTreeSet::from([DataValue::from(true), DataValue::Null])),
+            vec![0x0b, 0x01, 0x03, 0x00],
+        ),
+        (
+            vld(1, true),
+            vec![0x0c, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe, 0x00],
+        ),
+        (
+            vld(1, false),
+            vec![0x0c, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe, 0x01],
+        ),
+        (DataValue::Bot, vec![0xff]),
+    ];
+    for (val, expected) in cases {
+        assert_eq!(encode_value(&val), expected, "encoding of {:?}", val);
+        let mut key = relation_prefix(0).to_vec();
+        key.extend(&expected);
+        assert_eq!(decode_tuple_from_key(&key).unwrap(), vec![val]);
+        assert!(validity_of_key(&key).is_ok(), "decoding of {:?}", expected);
+    }
+}
+
+#[test]
+fn known_answer_tuples() {
+    let key = encode_tuple_key(0x0102, &[DataValue::Null, DataValue::from(true)]);
+    assert_eq!(key, vec![0, 0, 0, 0, 0, 0, 0x01, 0x02, 0x01, 0x03]);
+    assert_eq!(relation_id_of(&key), Some(0x0102));
+    assert_eq!(relation_id_of(&key[..7]), None);
+    assert_eq!(relation_prefix(0x0102), [0, 0, 0, 0, 0, 0, 0x01, 0x02]);
+
+    // the non-key columns are a MessagePack array
+    let val = encode_tuple_value(0x0102, &[]);
+    assert_eq!(val, vec![0, 0, 0, 0, 0, 0, 0x01, 0x02, 0x90]);
+    assert_eq!(relation_id_of(&val), Some(0x0102));
+    assert!(decode_tuple_from_value(&val).unwrap().is_empty());
+    assert!(decode_tuple_from_value(&[]).unwrap().is_empty());
+    assert!(decode_tuple_from_value(&val[..7]).is_err());
+    assert!(decode_tuple_from_value(&val[..8]).is_err());
+
+    let non_key = vec![DataValue::from("x"), DataValue::from(1.5), vld(3, true)];
+    let val = encode_tuple_value(0x0102, &non_key);
+    assert_eq!(decode_tuple_from_value(&val).unwrap(), non_key);
+    assert_eq!(
+        decode_tuple_from_kv(&key, &val).unwrap(),
+        vec![
+            DataValue::Null,
+            DataValue::from(true),
+            DataValue::from("x"),
+            DataValue::from(1.5),
+            vld(3, true)
+        ]
+    );
+}
+
+#[test]
+fn malformed_tuples() {
+    let key = encode_tuple_key(0x0102, &[DataValue::from("abc"), DataValue::from(1)]);
+    let val = encode_tuple_value(0x0102, &[DataValue::from(true)]);
+    assert!(decode_tuple_from_key(&key[..4]).is_err());
+    assert!(decode_tuple_from_key(&key[..key.len() - 1]).is_err());
+    // strings must be UTF-8
+    let mut bad_str = relation_prefix(0x0102).to_vec();
+    bad_str.extend([0x06, 0xff, 0, 0, 0, 0, 0, 0, 0, 0xf8]);
+    assert!(decode_tuple_from_key(&bad_str).is_err());
+    // lists must be terminated
+    let open_list = [&relation_prefix(0x0102)[..], &[0x0a, 0x01]].concat();
+    assert!(decode_tuple_from_key(&open_list).is_err());
+    assert!(decode_tuple_from_kv(&key, &val[..val.len() - 1]).is_err());
+    assert!(decode_tuple_from_kv(&key[..9], &val).is_err());
+    let mut tuple = vec![];
+    assert!(extend_tuple_from_v(&mut tuple, &val[..4]).is_err());
+    extend_tuple_from_v(&mut tuple, &val).unwrap();
+    assert_eq!(tuple, vec![DataValue::from(true)]);
+}
+
+#[test]
+fn encoding_preserves_order() {
+    let ascending = vec![
+        DataValue::Null,
+        DataValue::from(false),
+        DataValue::from(true),
+        DataValue::from(f64::NEG_INFINITY),
+        DataValue::from(i64::MIN),
+        DataValue::from(-1.5),
+        DataValue::from(-1),
+        DataValue::from(-0.0),
+        DataValue::from(0),
+        DataValue::from(0.5),
+        DataValue::from(1),
+        DataValue::from(i64::MAX),
+        DataValue::from(f64::INFINITY),
+        DataValue::from(""),
+        DataValue::from("a"),
+        DataValue::from("abcdefgh"),
+        DataValue::from("abcdefgha"),
+        DataValue::from("b"),
+        DataValue::Bytes(vec![]),
+        DataValue::Bytes(vec![0]),
+        DataValue::Bytes(vec![0, 0]),
+        DataValue::Bytes(vec![1]),
+        DataValue::List(vec![]),
+        DataValue::List(vec![DataValue::Null]),
+        DataValue::List(vec![DataValue::from(1)]),
+        DataValue::Bot,
+    ];
+    let encoded = ascending.iter().map(encode_value).collect::<Vec<_>>();
+    for pair in encoded.windows(2) {
+        assert!(pair[0] < pair[1], "{:?} < {:?}", pair[0], pair[1]);
+    }
+}
+
+#[test]
+fn validity_keys() {
+    // later timestamps and assertions come first
+    assert!(encode_value(&vld(2, true)) < encode_value(&vld(1, true)));
+    assert!(encode_value(&vld(1, true)) < encode_value(&vld(1, false)));
+    assert!(encode_value(&vld(i64::MAX, true)) < encode_value(&vld(i64::MIN, true)));
+
+    let row = [DataValue::from("k")];
+    let mut key = encode_tuple_key(7, &row);
+    key.extend(encode_value(&vld(5, true)));
+    // `Validity` is not `Debug`, so its fields are compared
+    let fields = |vld: Option<Validity>| vld.map(|v| (v.timestamp, v.is_assert));
+    assert_eq!(
+        fields(validity_of_key(&key).unwrap()),
+        Some((ValidityTs(Reverse(5)), Reverse(true)))
+    );
+    assert_eq!(
+        fields(validity_of_key(&encode_tuple_key(7, &row)).unwrap()),
+        None
+    );
+    // truncated keys, or with unknown tags
+    assert!(validity_of_key(&key[..key.len() - 1]).is_err());
+    assert!(validity_of_key(&key[..4]).is_err());
+    let mut bad = relation_prefix(7).to_vec();
+    bad.push(0x0d);
+    assert!(validity_of_key(&bad).is_err());
+
+    // the row asserted at 5 is seen at 6, but not at 4
+    let seek = validity_seek_key(7, &row, ValidityTs(Reverse(6)));
+    assert!(seek <= key);
+    assert!(validity_seek_key(7, &row, ValidityTs(Reverse(4))) > key);
+    let (found, _) = check_key_for_validity(&key, ValidityTs(Reverse(6))).unwrap();
+    assert_eq!(found, Some(vec![DataValue::from("k"), vld(5, true)]));
+    let (found, _) = check_key_for_validity(&key, ValidityTs(Reverse(4))).unwrap();
+    assert_eq!(found, None);
+    // keys without a validity at the end
+    assert!(check_key_for_validity(&encode_tuple_key(7, &row), ValidityTs(Reverse(6))).is_err());
+    assert!(check_key_for_validity(&bad, ValidityTs(Reverse(6))).is_err());
+}
+
+#[test]
+fn format_matches_storage() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        "?[k, v] <- [['a', 1.5]] :create rel {k => v}",
+        Default::default(),
+    )
+    .unwrap();
+    let tx = db.transact().unwrap();
+    let id = tx.get_relation("rel", false).unwrap().id.0;
+    let kvs = tx
+        .store_tx
+        .range_scan(&relation_prefix(id), &relation_prefix(id + 1))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(
+        kvs,
+        vec![(
+            encode_tuple_key(id, &[DataValue::from("a")]),
+            encode_tuple_value(id, &[DataValue::Num(Num::Float(1.5))])
+        )]
+    );
+}
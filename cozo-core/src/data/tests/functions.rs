@@ -6,12 +6,14 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+use std::cmp::Reverse;
+
 use approx::AbsDiffEq;
 use num_traits::FloatConst;
 use regex::Regex;
 
 use crate::data::functions::*;
-use crate::data::value::{DataValue, RegexWrapper};
+use crate::data::value::{DataValue, RegexWrapper, Validity, ValidityTs};
 use crate::new_cozo_mem;
 
 #[test]
@@ -791,6 +793,76 @@ fn test_regex() {
     );
 }
 
+#[test]
+fn test_regex_groups() {
+    let s = |v: &str| DataValue::Str(v.into());
+    let r = |v: &str| DataValue::Regex(RegexWrapper(Regex::new(v).unwrap()));
+    let list = |vs: &[&str]| DataValue::List(vs.iter().map(|v| s(v)).collect());
+
+    assert_eq!(
+        op_regex_extract_groups(&[s("key=value; k2=v2"), r(r"(\w+)=(\w+)")]).unwrap(),
+        list(&["key", "value"])
+    );
+    assert_eq!(
+        op_regex_extract_all(&[s("key=value; k2=v2"), r(r"(\w+)=(\w+)")]).unwrap(),
+        DataValue::List(vec![list(&["key", "value"]), list(&["k2", "v2"])])
+    );
+    // the whole match for regexes without groups
+    assert_eq!(
+        op_regex_extract_groups(&[s("abcdef"), r("c.e")]).unwrap(),
+        list(&["cde"])
+    );
+    // groups not taking part in the match
+    assert_eq!(
+        op_regex_extract_groups(&[s("ac"), r("(a)(b)?(c)")]).unwrap(),
+        DataValue::List(vec![s("a"), DataValue::Null, s("c")])
+    );
+    assert_eq!(
+        op_regex_extract_groups(&[s("abcdef"), r("(x)")]).unwrap(),
+        DataValue::Null
+    );
+    assert_eq!(
+        op_regex_extract_all(&[s("abcdef"), r("(x)")]).unwrap(),
+        DataValue::List(vec![])
+    );
+
+    // unicode
+    assert_eq!(
+        op_regex_extract_groups(&[s("東京2024年"), r(r"(\p{Han}+)(\d+)")]).unwrap(),
+        list(&["東京", "2024"])
+    );
+    assert_eq!(
+        op_regex_extract_all(&[s("naïve café"), r(r"(\w)(\w*)")]).unwrap(),
+        DataValue::List(vec![list(&["n", "aïve"]), list(&["c", "afé"])])
+    );
+
+    // empty matches
+    assert_eq!(
+        op_regex_extract_all(&[s("abc"), r("x*")]).unwrap(),
+        DataValue::List(vec![list(&[""]), list(&[""]), list(&[""]), list(&[""])])
+    );
+    assert_eq!(
+        op_regex_replace_all(&[s("abc"), r("x*"), s("-")]).unwrap(),
+        s("-a-b-c-")
+    );
+
+    // backreferences
+    assert_eq!(
+        op_regex_replace_all(&[
+            s("2024-02-29, 2023-03-12"),
+            r(r"(\d+)-(\d+)-(\d+)"),
+            s("$3/$2/$1")
+        ])
+        .unwrap(),
+        s("29/02/2024, 12/03/2023")
+    );
+    assert_eq!(
+        op_regex_replace_all(&[s("ab"), r("(?P<first>a)"), s("${first}${first}")]).unwrap(),
+        s("aab")
+    );
+    assert!(op_regex_extract_all(&[s("abc"), s("b")]).is_err());
+}
+
 #[test]
 fn test_predicates() {
     assert_eq!(
@@ -1062,6 +1134,21 @@ fn test_first_last() {
     );
 }
 
+#[test]
+fn test_int_range() {
+    let ints = |args: &[i64]| {
+        op_int_range(&args.iter().map(|i| DataValue::from(*i)).collect::<Vec<_>>()).unwrap()
+    };
+    let list = |ints: &[i64]| DataValue::List(ints.iter().map(|i| DataValue::from(*i)).collect());
+    assert_eq!(ints(&[3]), list(&[0, 1, 2]));
+    assert_eq!(ints(&[2, 5]), list(&[2, 3, 4]));
+    assert_eq!(ints(&[0, 10, 4]), list(&[0, 4, 8]));
+    assert_eq!(ints(&[3, 0, -1]), list(&[3, 2, 1]));
+    assert_eq!(ints(&[5, 2]), list(&[]));
+    assert!(op_int_range(&[DataValue::from(0), DataValue::from(1), DataValue::from(0)]).is_err());
+    assert!(op_int_range(&[DataValue::from(1.5)]).is_err());
+}
+
 #[test]
 fn test_chunks() {
     assert_eq!(
@@ -1383,6 +1470,47 @@ fn test_uuid() {
     assert!(op_uuid_timestamp(&[v1]).unwrap().get_float().is_some());
     assert!(op_to_uuid(&[DataValue::from("")]).is_err());
     assert!(op_to_uuid(&[DataValue::from("f3b4958c-52a1-11e7-802a-010203040506")]).is_ok());
+
+    let before = op_now(&[]).unwrap().get_float().unwrap();
+    let v7s: Vec<_> = (0..1000).map(|_| op_rand_uuid_v7(&[]).unwrap()).collect();
+    let after = op_now(&[]).unwrap().get_float().unwrap();
+    for pair in v7s.windows(2) {
+        assert!(pair[0] < pair[1]);
+    }
+    let id = v7s[0].get_uuid().unwrap();
+    assert_eq!(id.get_version_num(), 7);
+    let ts = op_uuid_timestamp(&[v7s[0].clone()])
+        .unwrap()
+        .get_float()
+        .unwrap();
+    assert!(before - 0.001 <= ts && ts <= after, "{before} {ts} {after}");
+}
+
+#[test]
+fn test_validity() {
+    let vld = op_validity(&[DataValue::from(1000), DataValue::from(false)]).unwrap();
+    assert_eq!(
+        vld,
+        DataValue::Validity(Validity {
+            timestamp: ValidityTs(Reverse(1000)),
+            is_assert: Reverse(false),
+        })
+    );
+    let vld = op_validity(&[
+        DataValue::from("1970-01-01T01:00:00.001+01:00"),
+        DataValue::from(true),
+    ])
+    .unwrap();
+    assert_eq!(
+        vld,
+        DataValue::Validity(Validity {
+            timestamp: ValidityTs(Reverse(1000)),
+            is_assert: Reverse(true),
+        })
+    );
+    assert!(op_validity(&[DataValue::from("1970-13-01"), DataValue::from(true)]).is_err());
+    assert!(op_validity(&[DataValue::from(1.5), DataValue::from(true)]).is_err());
+    assert!(op_validity(&[DataValue::from(1000), DataValue::Null]).is_err());
 }
 
 #[test]
@@ -1456,3 +1584,157 @@ fn test_coalesce() {
         .rows;
     assert_eq!(res[0][0], DataValue::from(2));
 }
+
+#[test]
+fn test_parse_format_ts() {
+    let s = |x: &str| DataValue::from(x);
+    assert_eq!(
+        op_parse_ts(&[s("2024-02-29"), s("%Y-%m-%d")]).unwrap(),
+        DataValue::from(1709164800.)
+    );
+    assert_eq!(
+        op_parse_ts(&[s("2024-02-29 01:00 +0100"), s("%Y-%m-%d %H:%M %z")]).unwrap(),
+        DataValue::from(1709164800.)
+    );
+    assert!(op_parse_ts(&[s("2023-02-29"), s("%Y-%m-%d")]).is_err());
+    // skipped when clocks go forward, moved forward by an hour
+    assert_eq!(
+        op_parse_ts(&[
+            s("2023-03-12 02:30"),
+            s("%Y-%m-%d %H:%M"),
+            s("America/New_York")
+        ])
+        .unwrap(),
+        DataValue::from(1678606200.)
+    );
+    // repeated when clocks go back, the first occurrence is taken
+    assert_eq!(
+        op_parse_ts(&[
+            s("2023-11-05 01:30"),
+            s("%Y-%m-%d %H:%M"),
+            s("America/New_York")
+        ])
+        .unwrap(),
+        DataValue::from(1699162200.)
+    );
+    assert!(op_parse_ts(&[s("2024-02-29"), s("%Y-%m-%d"), s("Mars/Olympus_Mons")]).is_err());
+    assert!(op_parse_ts(&[s("2024-02-29"), s("%Y-%m-%Q")]).is_err());
+
+    assert_eq!(
+        op_format_ts(&[DataValue::from(1709164800.5), s("%Y-%m-%dT%H:%M:%S%.3f")]).unwrap(),
+        s("2024-02-29T00:00:00.500")
+    );
+    assert_eq!(
+        op_format_ts(&[
+            DataValue::from(1699162200),
+            s("%H:%M %z"),
+            s("America/New_York")
+        ])
+        .unwrap(),
+        s("01:30 -0400")
+    );
+    assert_eq!(
+        op_format_ts(&[
+            DataValue::from(1699165800),
+            s("%H:%M %z"),
+            s("America/New_York")
+        ])
+        .unwrap(),
+        s("01:30 -0500")
+    );
+    assert!(op_format_ts(&[DataValue::from(0), s("%Q")]).is_err());
+    assert!(op_format_ts(&[DataValue::from(0), s("%Y"), s("Nowhere")]).is_err());
+    assert!(op_format_ts(&[DataValue::from(f64::INFINITY), s("%Y")]).is_err());
+}
+
+#[test]
+fn test_date_trunc_part() {
+    let s = |x: &str| DataValue::from(x);
+    let leap_day = DataValue::from(1709164800 + 13 * 3600);
+    let trunc = |unit: &str| op_date_trunc(&[s(unit), leap_day.clone()]).unwrap();
+    assert_eq!(trunc("hour"), DataValue::from(1709164800. + 13. * 3600.));
+    assert_eq!(trunc("day"), DataValue::from(1709164800.));
+    assert_eq!(trunc("week"), DataValue::from(1708905600.));
+    assert_eq!(trunc("month"), DataValue::from(1706745600.));
+    assert_eq!(trunc("quarter"), DataValue::from(1704067200.));
+    assert_eq!(trunc("year"), DataValue::from(1704067200.));
+    assert!(op_date_trunc(&[s("fortnight"), leap_day.clone()]).is_err());
+
+    // the day clocks go forward starts before they do
+    let new_york = s("America/New_York");
+    assert_eq!(
+        op_date_trunc(&[s("day"), DataValue::from(1678636800), new_york.clone()]).unwrap(),
+        DataValue::from(1678597200.)
+    );
+    // the second of the hours repeated when clocks go back
+    assert_eq!(
+        op_date_trunc(&[s("hour"), DataValue::from(1699165800), new_york.clone()]).unwrap(),
+        DataValue::from(1699164000.)
+    );
+
+    let part = |p: &str, ts: &DataValue| op_date_part(&[s(p), ts.clone()]).unwrap();
+    assert_eq!(part("year", &leap_day), DataValue::from(2024));
+    assert_eq!(part("month", &leap_day), DataValue::from(2));
+    assert_eq!(part("day", &leap_day), DataValue::from(29));
+    assert_eq!(part("doy", &leap_day), DataValue::from(60));
+    assert_eq!(part("dow", &leap_day), DataValue::from(4));
+    assert_eq!(part("isodow", &leap_day), DataValue::from(4));
+    assert_eq!(part("week", &leap_day), DataValue::from(9));
+    assert_eq!(part("hour", &leap_day), DataValue::from(13));
+    assert_eq!(
+        part("second", &DataValue::from(1.25)),
+        DataValue::from(1.25)
+    );
+    assert_eq!(
+        part("doy", &DataValue::from(1735603200)),
+        DataValue::from(366)
+    );
+    assert_eq!(
+        op_date_part(&[s("offset"), DataValue::from(1678636800), new_york.clone()]).unwrap(),
+        DataValue::from(-4 * 3600)
+    );
+    assert_eq!(
+        op_date_part(&[s("hour"), DataValue::from(1678636800), new_york]).unwrap(),
+        DataValue::from(12)
+    );
+    assert!(op_date_part(&[s("century"), leap_day]).is_err());
+}
+
+#[test]
+fn test_ts_add() {
+    let s = |x: &str| DataValue::from(x);
+    let add = |ts: i64, n: i64, unit: &str| {
+        op_ts_add(&[DataValue::from(ts), DataValue::from(n), s(unit)]).unwrap()
+    };
+    assert_eq!(add(0, 90, "minute"), DataValue::from(5400.));
+    assert_eq!(add(1706659200, 1, "month"), DataValue::from(1709164800.));
+    assert_eq!(add(1709164800, 1, "year"), DataValue::from(1740700800.));
+    assert_eq!(add(1709164800, -1, "year"), DataValue::from(1677542400.));
+    assert_eq!(add(1709164800, -4, "quarter"), DataValue::from(1677542400.));
+    assert_eq!(add(1709164800, 1, "day"), DataValue::from(1709251200.));
+
+    // a day is 23 hours long when clocks go forward
+    let new_york = s("America/New_York");
+    assert_eq!(
+        op_ts_add(&[
+            DataValue::from(1678554000),
+            DataValue::from(1),
+            s("day"),
+            new_york.clone()
+        ])
+        .unwrap(),
+        DataValue::from(1678636800.)
+    );
+    assert_eq!(
+        op_ts_add(&[
+            DataValue::from(1678554000),
+            DataValue::from(24),
+            s("hour"),
+            new_york
+        ])
+        .unwrap(),
+        DataValue::from(1678640400.)
+    );
+    assert!(op_ts_add(&[DataValue::from(0), DataValue::from(1.5), s("day")]).is_err());
+    assert!(op_ts_add(&[DataValue::from(0), DataValue::from(1), s("eon")]).is_err());
+}
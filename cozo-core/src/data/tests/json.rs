@@ -7,10 +7,14 @@
  *
  */
 
+use miette::Diagnostic;
 use serde_json::json;
 
+use crate::data::functions::{op_json_merge, op_json_path, op_json_path_strict, op_json_set};
 use crate::data::json::JsonValue;
+use crate::data::json_path::JsonPath;
 use crate::data::value::DataValue;
+use crate::parse::SourceSpan;
 
 #[test]
 fn bad_values() {
@@ -19,3 +23,148 @@ fn bad_values() {
     println!("{}", JsonValue::from(DataValue::from(f64::NEG_INFINITY)));
     println!("{}", JsonValue::from(DataValue::from(f64::NAN)));
 }
+
+fn nested_doc() -> DataValue {
+    DataValue::from(json!({
+        "store": {
+            "books": [
+                {"title": "Sayings", "price": 8.95, "tags": ["old", "wise"]},
+                {"title": "Sword", "price": 12.99, "meta": {"isbn": {"code": "0-553"}}},
+            ],
+            "bicycle": {"colour": "red", "price": 19.95},
+        },
+        "a": {"b": {"c": {"d": {"e": {"f": [[1, 2], [3, {"g": "deep"}]]}}}}},
+        "key with.dots": 1,
+    }))
+}
+
+#[test]
+fn json_path_select() {
+    let doc = nested_doc();
+    let select = |path: &str| op_json_path(&[doc.clone(), DataValue::from(path)]).unwrap();
+    assert_eq!(select("$.store.books[0].title"), DataValue::from("Sayings"));
+    assert_eq!(
+        select("$.store.books[-1].meta.isbn.code"),
+        DataValue::from("0-553")
+    );
+    assert_eq!(
+        select("$['store'][\"bicycle\"].colour"),
+        DataValue::from("red")
+    );
+    assert_eq!(select("$['key with.dots']"), DataValue::from(1));
+    assert_eq!(select("$.a.b.c.d.e.f[1][1].g"), DataValue::from("deep"));
+    assert_eq!(select("$.a.b.c.d.e.f[0]"), DataValue::from(json!([1, 2])));
+    assert_eq!(select("$"), doc);
+
+    // missing paths give nulls
+    assert_eq!(select("$.store.books[2].title"), DataValue::Null);
+    assert_eq!(select("$.store.bicycle.colour.shade"), DataValue::Null);
+    assert_eq!(select("$.store.books.title"), DataValue::Null);
+    assert_eq!(select("$.store[0]"), DataValue::Null);
+
+    // wildcards and recursive descents give all values found
+    assert_eq!(
+        select("$.store.books[*].title"),
+        DataValue::from(json!(["Sayings", "Sword"]))
+    );
+    // keys of objects converted from JSON are sorted
+    assert_eq!(
+        select("$.store..price"),
+        DataValue::from(json!([19.95, 8.95, 12.99]))
+    );
+    assert_eq!(select("$..g"), DataValue::from(json!(["deep"])));
+    assert_eq!(select("$..code"), DataValue::from(json!(["0-553"])));
+    assert_eq!(
+        select("$.store.bicycle.*"),
+        DataValue::from(json!(["red", 19.95]))
+    );
+    assert_eq!(select("$..nothing"), DataValue::List(vec![]));
+
+    assert!(op_json_path_strict(&[doc.clone(), DataValue::from("$.store.books[2]")]).is_err());
+    assert!(op_json_path_strict(&[doc.clone(), DataValue::from("$..nothing")]).is_err());
+    assert_eq!(
+        op_json_path_strict(&[doc.clone(), DataValue::from("$.a.b.c.d.e.f[1][0]")]).unwrap(),
+        DataValue::from(3)
+    );
+}
+
+#[test]
+fn json_path_parse_errors() {
+    for (path, pos) in [
+        ("store", 0),
+        ("$.", 2),
+        ("$.a[", 4),
+        ("$.a[x]", 4),
+        ("$['a]", 2),
+        ("$.a[0", 4),
+        ("$a", 1),
+    ] {
+        let err = JsonPath::parse(path).unwrap_err();
+        let label = err.labels().unwrap().next().unwrap();
+        assert_eq!(label.offset(), pos, "{path}");
+    }
+    // errors in literals are pointed at
+    let err = JsonPath::parse("$.a[x]")
+        .unwrap_err()
+        .in_literal(SourceSpan(10, 8));
+    assert_eq!(err.labels().unwrap().next().unwrap().offset(), 15);
+    assert!(op_json_path(&[DataValue::Null, DataValue::from("$.a[")]).is_err());
+}
+
+#[test]
+fn json_path_set() {
+    let doc = nested_doc();
+    let set = |doc: &DataValue, path: &str, new: JsonValue| {
+        op_json_set(&[doc.clone(), DataValue::from(path), DataValue::from(new)])
+    };
+    let get =
+        |doc: &DataValue, path: &str| op_json_path(&[doc.clone(), DataValue::from(path)]).unwrap();
+
+    let updated = set(&doc, "$.a.b.c.d.e.f[1][1].g", json!("deeper")).unwrap();
+    assert_eq!(
+        get(&updated, "$.a.b.c.d.e.f[1][1].g"),
+        DataValue::from("deeper")
+    );
+    assert_eq!(get(&updated, "$.store"), get(&doc, "$.store"));
+
+    // missing keys are created, and lists extended at their ends
+    let updated = set(&doc, "$.store.books[2].title", json!("Third")).unwrap();
+    assert_eq!(
+        get(&updated, "$.store.books[2].title"),
+        DataValue::from("Third")
+    );
+    let updated = set(&updated, "$.x.y[0]", json!(true)).unwrap();
+    assert_eq!(get(&updated, "$.x"), DataValue::from(json!({"y": [true]})));
+    assert_eq!(
+        set(&DataValue::Null, "$.a", json!(1)).unwrap(),
+        DataValue::from(json!({"a": 1}))
+    );
+
+    assert!(set(&doc, "$.store.books[5]", json!(1)).is_err());
+    assert!(set(&doc, "$.store.bicycle.colour.shade", json!(1)).is_err());
+    assert!(set(&doc, "$..price", json!(1)).is_err());
+    assert!(set(&doc, "$.store.books[*].title", json!(1)).is_err());
+}
+
+#[test]
+fn json_merge() {
+    let merge = |a: JsonValue, b: JsonValue| {
+        op_json_merge(&[DataValue::from(a), DataValue::from(b)]).unwrap()
+    };
+    assert_eq!(
+        merge(
+            json!({"a": "b", "c": {"d": "e", "f": "g"}, "h": [1]}),
+            json!({"a": "z", "c": {"f": null, "x": {"y": 1}}, "h": [2, 3]})
+        ),
+        DataValue::from(json!({"a": "z", "c": {"d": "e", "x": {"y": 1}}, "h": [2, 3]}))
+    );
+    assert_eq!(merge(json!({"a": 1}), json!("s")), DataValue::from("s"));
+    assert_eq!(
+        merge(json!([1, 2]), json!({"a": 1})),
+        DataValue::from(json!({"a": 1}))
+    );
+    assert_eq!(
+        merge(json!({"a": 1}), json!({"b": null})),
+        DataValue::from(json!({"a": 1}))
+    );
+}
@@ -22,7 +22,7 @@ fn encode_decode_num() {
     let mut test_num = |n: Num| {
         let mut encoder = vec![];
         encoder.encode_num(n);
-        let (decoded, rest) = Num::decode_from_key(&encoder);
+        let (decoded, rest) = Num::decode_from_key(&encoder).unwrap();
         assert_eq!(decoded, n);
         assert!(rest.is_empty());
         collected.push(encoder);
@@ -45,7 +45,7 @@ fn encode_decode_num() {
     }
     let mut collected_copy = collected.clone();
     collected.sort();
-    collected_copy.sort_by_key(|c| Num::decode_from_key(c).0);
+    collected_copy.sort_by_key(|c| Num::decode_from_key(c).unwrap().0);
     assert_eq!(collected, collected_copy);
 }
 
@@ -56,11 +56,36 @@ fn test_encode_decode_uuid() {
     ));
     let mut encoder = vec![];
     encoder.encode_datavalue(&uuid);
-    let (decoded, remaining) = DataValue::decode_from_key(&encoder);
+    let (decoded, remaining) = DataValue::decode_from_key(&encoder).unwrap();
     assert_eq!(decoded, uuid);
     assert!(remaining.is_empty());
 }
 
+#[test]
+fn test_uuid_order() {
+    let uuids = [
+        "00000000-0000-7000-8000-000000000000",
+        "018b0000-0000-7000-8000-000000000000",
+        "018b0000-0001-7000-8000-000000000000",
+        "018b0001-0000-7000-8000-000000000000",
+        "dd85b19a-5fde-11ed-a88e-1774a7698039",
+        "dd85b19a-5fdf-11ec-a88e-1774a7698039",
+    ]
+    .map(|s| DataValue::Uuid(UuidWrapper(Uuid::parse_str(s).unwrap())));
+    let encoded: Vec<_> = uuids
+        .iter()
+        .map(|u| {
+            let mut encoder = vec![];
+            encoder.encode_datavalue(u);
+            encoder
+        })
+        .collect();
+    for i in 1..uuids.len() {
+        assert!(uuids[i - 1] < uuids[i]);
+        assert!(encoded[i - 1] < encoded[i]);
+    }
+}
+
 #[test]
 fn encode_decode_bytes() {
     let target = b"Lorem ipsum dolor sit amet, consectetur adipiscing elit...";
@@ -68,7 +93,7 @@ fn encode_decode_bytes() {
         let bs = &target[i..];
         let mut encoder: Vec<u8> = vec![];
         encoder.encode_bytes(bs);
-        let (decoded, remaining) = decode_bytes(&encoder);
+        let (decoded, remaining) = decode_bytes(&encoder).unwrap();
         assert!(remaining.is_empty());
         assert_eq!(bs, decoded);
 
@@ -78,16 +103,16 @@ fn encode_decode_bytes() {
         encoder.encode_bytes(bs);
         encoder.encode_bytes(target);
 
-        let (decoded, remaining) = decode_bytes(&encoder);
+        let (decoded, remaining) = decode_bytes(&encoder).unwrap();
         assert_eq!(&target[..], decoded);
 
-        let (decoded, remaining) = decode_bytes(remaining);
+        let (decoded, remaining) = decode_bytes(remaining).unwrap();
         assert_eq!(bs, decoded);
 
-        let (decoded, remaining) = decode_bytes(remaining);
+        let (decoded, remaining) = decode_bytes(remaining).unwrap();
         assert_eq!(bs, decoded);
 
-        let (decoded, remaining) = decode_bytes(remaining);
+        let (decoded, remaining) = decode_bytes(remaining).unwrap();
         assert_eq!(&target[..], decoded);
         assert!(remaining.is_empty());
     }
@@ -100,9 +125,9 @@ fn specific_encode() {
     // println!("e1 {:?}", encoder);
     encoder.encode_datavalue(&DataValue::from("MSS"));
     // println!("e2 {:?}", encoder);
-    let (a, remaining) = DataValue::decode_from_key(&encoder);
+    let (a, remaining) = DataValue::decode_from_key(&encoder).unwrap();
     // println!("r  {:?}", remaining);
-    let (b, remaining) = DataValue::decode_from_key(remaining);
+    let (b, remaining) = DataValue::decode_from_key(remaining).unwrap();
     assert!(remaining.is_empty());
     assert_eq!(a, DataValue::from(2095));
     assert_eq!(b, DataValue::from("MSS"));
@@ -131,7 +156,7 @@ fn encode_decode_datavalues() {
     let mut encoded = vec![];
     let v = DataValue::List(dv);
     encoded.encode_datavalue(&v);
-    let (decoded, remaining) = DataValue::decode_from_key(&encoded);
+    let (decoded, remaining) = DataValue::decode_from_key(&encoded).unwrap();
     assert!(remaining.is_empty());
     assert_eq!(decoded, v);
 }
@@ -8,6 +8,7 @@
 
 mod aggrs;
 mod exprs;
+mod format;
 mod functions;
 mod json;
 mod memcmp;
@@ -8,8 +8,9 @@
  */
 
 use crate::data::value::DataValue;
-use crate::DbInstance;
+use crate::{DbInstance, VirtualClock};
 use serde_json::json;
+use std::collections::BTreeMap;
 use std::env;
 
 #[test]
@@ -20,6 +21,8 @@ fn test_validity() {
     let db_kind = env::var("COZO_TEST_DB_ENGINE").unwrap_or("mem".to_string());
     println!("Using {} engine", db_kind);
     let db = DbInstance::new(&db_kind, path, Default::default()).unwrap();
+    let clock = VirtualClock::new(1_700_000_000_000_000);
+    db.set_clock(clock.as_clock_fn());
 
     db.run_script(":create vld {a, v: Validity => d}", Default::default())
         .unwrap();
@@ -106,6 +109,7 @@ fn test_validity() {
         .rows;
     assert_eq!(res.len(), 2);
 
+    clock.advance(1_000_000);
     db.run_script(
         r#"
     ?[a, v, d] <- [[1, "ASSERT", 2]]
@@ -138,6 +142,7 @@ fn test_validity() {
         .rows;
     assert_eq!(res.len(), 3);
 
+    clock.advance(1_000_000);
     db.run_script(
         r#"
     ?[a, v, d] <- [[1, "RETRACT", 3]]
@@ -213,3 +218,125 @@ fn test_validity() {
 
     println!("{}", json!(res));
 }
+
+#[test]
+fn test_validity_strings() {
+    let db = DbInstance::new("mem", "", Default::default()).unwrap();
+    db.run_script(":create vld {a, v: Validity => d}", Default::default())
+        .unwrap();
+
+    // strings in rows, in validity pairs and in parameters
+    db.run_script(
+        r#"
+    ?[a, v, d] <- [[1, "2024-03-01T00:00:00Z", 0],
+                   [1, ["2024-03-02T00:00:00+01:00", false], 1],
+                   [2, $ts, 2]]
+    :put vld {a, v => d}
+    "#,
+        BTreeMap::from([(
+            "ts".to_string(),
+            DataValue::from("1969-12-31T23:59:59.123456Z"),
+        )]),
+    )
+    .unwrap();
+
+    let res = db
+        .run_script(
+            "?[a, d] := *vld{a, d @ '2024-03-01T12:00:00Z'}",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[1, 0], [2, 2]]));
+    let res = db
+        .run_script(
+            "?[a, d] := *vld{a, d @ $at}",
+            BTreeMap::from([(
+                "at".to_string(),
+                DataValue::from("2024-03-02T00:30:00+01:00"),
+            )]),
+        )
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[2, 2]]));
+    let res = db
+        .run_script(
+            "?[a, d] := *vld{a, d @ validity('2024-03-01T13:00:00+01:00', true)}",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[1, 0], [2, 2]]));
+    let res = db
+        .run_script(
+            "?[a, d] := *vld{a, d @ '1969-12-31T23:59:59.123455Z'}",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([]));
+
+    // output as strings, at microsecond precision and in the same order
+    let raw = db
+        .run_script("?[a, v] := *vld{a, v}", Default::default())
+        .unwrap();
+    assert_eq!(
+        raw.clone().into_json()["rows"],
+        json!([
+            [1, [1709334000000000i64, false]],
+            [1, [1709251200000000i64, true]],
+            [2, [-876544i64, true]]
+        ])
+    );
+    let formatted = db
+        .run_script(
+            "?[a, v] := *vld{a, v} :validity_as_string",
+            Default::default(),
+        )
+        .unwrap();
+    let json = formatted.into_json();
+    assert_eq!(
+        json["rows"],
+        json!([
+            [1, "~2024-03-01T23:00:00.000000Z"],
+            [1, "2024-03-01T00:00:00.000000Z"],
+            [2, "1969-12-31T23:59:59.123456Z"]
+        ])
+    );
+
+    // and read back to the same values
+    let rows = DataValue::from(json["rows"].clone());
+    db.run_script(":create vld2 {a, v: Validity}", Default::default())
+        .unwrap();
+    db.run_script(
+        "?[a, v] <- $rows :put vld2 {a, v}",
+        BTreeMap::from([("rows".to_string(), rows)]),
+    )
+    .unwrap();
+    let copied = db
+        .run_script("?[a, v] := *vld2{a, v}", Default::default())
+        .unwrap();
+    assert_eq!(copied.into_json()["rows"], raw.into_json()["rows"]);
+
+    // as the default for all queries
+    let db = DbInstance::new("mem", "", r#"{"validity_as_string": true}"#).unwrap();
+    db.run_script(":create vld {v: Validity}", Default::default())
+        .unwrap();
+    db.run_script(
+        "?[v] <- [['2024-03-01T00:00:00.000001+02:00']] :put vld {v}",
+        Default::default(),
+    )
+    .unwrap();
+    let res = db
+        .run_script("?[v] := *vld{v}", Default::default())
+        .unwrap();
+    assert_eq!(
+        res.into_json()["rows"],
+        json!([["2024-02-29T22:00:00.000001Z"]])
+    );
+
+    let err = db
+        .run_script(
+            "?[v] := *vld{v @ '2024-13-01T00:00:00Z'}",
+            Default::default(),
+        )
+        .unwrap_err();
+    assert_eq!(err.code().unwrap().to_string(), "parser::bad_validity_spec");
+    assert!(err.labels().unwrap().next().is_some());
+}
@@ -7,13 +7,15 @@
  */
 
 use crate::data::functions::TERMINAL_VALIDITY;
-use miette::Result;
+use miette::{bail, Diagnostic, Result};
 use std::cmp::Reverse;
+use thiserror::Error;
 
 use crate::data::memcmp::MemCmpEncoder;
 use crate::data::value::{DataValue, Validity, ValidityTs};
 use crate::runtime::relation::RelationId;
 
+/// A row of a relation: the key columns followed by the non-key ones
 pub type Tuple = Vec<DataValue>;
 
 pub(crate) type TupleIter<'a> = Box<dyn Iterator<Item = Result<Tuple>> + 'a>;
@@ -38,15 +40,30 @@ where
     }
 }
 
-pub fn decode_tuple_from_key(key: &[u8]) -> Tuple {
-    let mut remaining = &key[ENCODED_KEY_MIN_LEN..];
+#[derive(Debug, Error, Diagnostic)]
+#[error("Malformed {0}: {1:x?}")]
+#[diagnostic(code(format::malformed))]
+pub(crate) struct MalformedData(pub(crate) &'static str, pub(crate) Vec<u8>);
+
+/// Decode the key columns of a tuple from a key, skipping the relation prefix.
+pub fn decode_tuple_from_key(key: &[u8]) -> Result<Tuple> {
+    let malformed = || MalformedData("key", key.to_vec());
+    let mut remaining = key.get(ENCODED_KEY_MIN_LEN..).ok_or_else(malformed)?;
     let mut ret = vec![];
     while !remaining.is_empty() {
-        let (val, next) = DataValue::decode_from_key(remaining);
+        let (val, next) = DataValue::decode_from_key(remaining).ok_or_else(malformed)?;
         ret.push(val);
         remaining = next;
     }
-    ret
+    Ok(ret)
+}
+
+/// Splits the validity, the last column, from the key columns of a key
+fn pop_validity(decoded: &mut Tuple, key: &[u8]) -> Result<Validity> {
+    match decoded.pop() {
+        Some(DataValue::Validity(vld)) => Ok(vld),
+        _ => bail!(MalformedData("key with validity", key.to_vec())),
+    }
 }
 
 /// Check if the tuple key passed in should be a valid return for a validity query.
@@ -55,29 +72,118 @@ pub fn decode_tuple_from_key(key: &[u8]) -> Tuple {
 /// in the return set and `None` otherwise,
 /// the second element gives the next binary key for the seek to be used as an inclusive
 /// lower bound.
-pub fn check_key_for_validity(key: &[u8], valid_at: ValidityTs) -> (Option<Tuple>, Vec<u8>) {
-    let mut decoded = decode_tuple_from_key(key);
+pub fn check_key_for_validity(
+    key: &[u8],
+    valid_at: ValidityTs,
+) -> Result<(Option<Tuple>, Vec<u8>)> {
+    let mut decoded = decode_tuple_from_key(key)?;
     let rel_id = RelationId::raw_decode(key);
-    let vld = match decoded.last().unwrap() {
-        DataValue::Validity(vld) => vld,
-        _ => unreachable!(),
-    };
-    if vld.timestamp < valid_at {
-        *decoded.last_mut().unwrap() = DataValue::Validity(Validity {
+    let vld = pop_validity(&mut decoded, key)?;
+    Ok(if vld.timestamp < valid_at {
+        decoded.push(DataValue::Validity(Validity {
             timestamp: valid_at,
             is_assert: Reverse(true),
-        });
+        }));
         let nxt_seek = decoded.encode_as_key(rel_id);
         (None, nxt_seek)
     } else if !vld.is_assert.0 {
-        *decoded.last_mut().unwrap() = DataValue::Validity(TERMINAL_VALIDITY);
+        decoded.push(DataValue::Validity(TERMINAL_VALIDITY));
         let nxt_seek = decoded.encode_as_key(rel_id);
         (None, nxt_seek)
     } else {
-        let ret = decoded.clone();
-        *decoded.last_mut().unwrap() = DataValue::Validity(TERMINAL_VALIDITY);
+        let mut ret = decoded.clone();
+        ret.push(DataValue::Validity(vld));
+        decoded.push(DataValue::Validity(TERMINAL_VALIDITY));
         let nxt_seek = decoded.encode_as_key(rel_id);
         (Some(ret), nxt_seek)
+    })
+}
+
+/// The state of a scan of keys with validity returning every version valid within a window,
+/// see [`StoreTx::range_skip_scan_tuple_window`](crate::StoreTx::range_skip_scan_tuple_window).
+pub(crate) struct ValidityWindow {
+    valid_from: i64,
+    valid_to: i64,
+    current: Option<WindowedKey>,
+}
+
+/// The versions of a key are stored newest first, so the end of the validity of a version
+/// is the timestamp of the version seen before it.
+struct WindowedKey {
+    key: Tuple,
+    newer: Option<i64>,
+    exhausted: bool,
+}
+
+impl ValidityWindow {
+    pub(crate) fn new(valid_from: ValidityTs, valid_to: ValidityTs) -> Self {
+        Self {
+            valid_from: valid_from.0 .0,
+            valid_to: valid_to.0 .0,
+            current: None,
+        }
+    }
+
+    /// Check if the tuple key passed in should be a valid return for the window.
+    ///
+    /// Returns two elements, the first element contains `Some((tuple, [valid_from, valid_to]))`
+    /// if the key should be included in the return set and `None` otherwise, `valid_to` being
+    /// null for a version that is still valid. The second element gives the next binary key
+    /// for the seek to be used as an inclusive lower bound.
+    ///
+    /// Keys skipped by the seeks may still be passed in, and are then ignored.
+    pub(crate) fn check_key(
+        &mut self,
+        key: &[u8],
+    ) -> Result<(Option<(Tuple, [DataValue; 2])>, Vec<u8>)> {
+        let mut decoded = decode_tuple_from_key(key)?;
+        let rel_id = RelationId::raw_decode(key);
+        let vld = pop_validity(&mut decoded, key)?;
+        if !matches!(&self.current, Some(cur) if cur.key == decoded) {
+            self.current = Some(WindowedKey {
+                key: decoded.clone(),
+                newer: None,
+                exhausted: false,
+            });
+        }
+        let current = self.current.as_mut().unwrap();
+        let mut next_key = decoded.clone();
+        next_key.push(DataValue::Validity(TERMINAL_VALIDITY));
+        let next_key = next_key.encode_as_key(rel_id);
+        if current.exhausted {
+            return Ok((None, next_key));
+        }
+
+        let ts = vld.timestamp.0 .0;
+        let newer = current.newer.replace(ts);
+        let nxt_seek = if ts > self.valid_to {
+            // newer than the window, but its timestamp may end a version within it
+            let mut successor = key.to_vec();
+            successor.push(0);
+            return Ok((None, successor));
+        } else if ts < self.valid_from {
+            // the version valid at the start of the window, everything older is not needed
+            current.exhausted = true;
+            next_key
+        } else {
+            let mut successor = key.to_vec();
+            successor.push(0);
+            successor
+        };
+        let overlaps = match newer {
+            None => true,
+            Some(n) => n > ts && n > self.valid_from,
+        };
+        if vld.is_assert.0 && overlaps {
+            decoded.push(DataValue::Validity(vld));
+            let interval = [
+                DataValue::from(ts),
+                newer.map(DataValue::from).unwrap_or(DataValue::Null),
+            ];
+            Ok((Some((decoded, interval)), nxt_seek))
+        } else {
+            Ok((None, nxt_seek))
+        }
     }
 }
 
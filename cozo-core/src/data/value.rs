@@ -29,14 +29,11 @@ impl PartialOrd<Self> for UuidWrapper {
     }
 }
 
+/// UUIDs are ordered by their bytes, so that time-ordered UUIDs such as version 7 ones
+/// are ordered by time, in memory as well as in storage
 impl Ord for UuidWrapper {
     fn cmp(&self, other: &Self) -> Ordering {
-        let (s_l, s_m, s_h, s_rest) = self.0.as_fields();
-        let (o_l, o_m, o_h, o_rest) = other.0.as_fields();
-        s_h.cmp(&o_h)
-            .then_with(|| s_m.cmp(&o_m))
-            .then_with(|| s_l.cmp(&o_l))
-            .then_with(|| s_rest.cmp(o_rest))
+        self.0.as_bytes().cmp(other.0.as_bytes())
     }
 }
 
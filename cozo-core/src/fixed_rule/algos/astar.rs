@@ -28,6 +28,10 @@ use crate::runtime::temp_store::RegularTempStore;
 pub(crate) struct ShortestPathAStar;
 
 impl FixedRule for ShortestPathAStar {
+    fn takes_graph_nodes(&self) -> bool {
+        true
+    }
+
     fn run(
         &self,
         payload: FixedRulePayload<'_, '_>,
@@ -0,0 +1,318 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use miette::{bail, Diagnostic, Result};
+use smartstring::{LazyCompact, SmartString};
+use thiserror::Error;
+
+use crate::data::expr::Expr;
+use crate::data::symb::Symbol;
+use crate::data::tuple::Tuple;
+use crate::data::value::DataValue;
+use crate::fixed_rule::{FixedRule, FixedRuleOptions, FixedRulePayload, NotAnEdgeError};
+use crate::parse::SourceSpan;
+use crate::runtime::db::Poison;
+use crate::runtime::temp_store::RegularTempStore;
+
+/// Maximum matching of a bipartite graph by the Hopcroft-Karp algorithm, with a row
+/// `(left, right)` for each matched pair, or the single row `(size)` if `size_only` is set.
+///
+/// The two sides are given as the second and third inputs. Otherwise the nodes at the
+/// start of the edges are on the left, and those at the end on the right.
+pub(crate) struct MaximumBipartiteMatching;
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("The edge from {0:?} to {1:?} does not join the two sides of the bipartite graph")]
+#[diagnostic(code(algo::not_bipartite))]
+#[diagnostic(help("Each edge must join a node of the left side and a node of the right side"))]
+struct NotBipartiteError(DataValue, DataValue, #[label] SourceSpan);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("The node {0:?} is on both sides of the bipartite graph")]
+#[diagnostic(code(algo::not_bipartite))]
+#[diagnostic(help(
+    "Without relations of the nodes on each side, the nodes at the start of the edges \
+    are on the left and those at the end on the right"
+))]
+struct NodeOnBothSidesError(DataValue, #[label] SourceSpan);
+
+impl FixedRule for MaximumBipartiteMatching {
+    // nodes are only mutable keys to clippy for the regex a `DataValue` may hold
+    #[allow(clippy::mutable_key_type)]
+    fn run(
+        &self,
+        payload: FixedRulePayload<'_, '_>,
+        out: &mut RegularTempStore,
+        poison: Poison,
+    ) -> Result<()> {
+        let edges = payload.get_input(0)?;
+        let size_only = payload.bool_option("size_only", Some(false))?;
+
+        let mut pairs = vec![];
+        for tuple in edges.iter()? {
+            let mut tuple = tuple?.into_iter();
+            match (tuple.next(), tuple.next()) {
+                (Some(from), Some(to)) => pairs.push((from, to)),
+                _ => bail!(NotAnEdgeError(edges.span())),
+            }
+        }
+
+        let collect_nodes = |tuples: &mut dyn Iterator<Item = Result<Tuple>>| {
+            let mut nodes = BTreeSet::new();
+            for tuple in tuples {
+                nodes.insert(tuple?.into_iter().next().unwrap());
+            }
+            Ok::<_, miette::Report>(nodes)
+        };
+        let sides = match (payload.get_input(1), payload.get_input(2)) {
+            (Err(_), _) => None,
+            (Ok(left), right) => {
+                let right = right?;
+                Some((
+                    collect_nodes(&mut left.iter()?)?,
+                    collect_nodes(&mut right.iter()?)?,
+                ))
+            }
+        };
+        let (left, right) = match sides {
+            Some(sides) => sides,
+            None => (
+                pairs.iter().map(|(from, _)| from.clone()).collect(),
+                pairs.iter().map(|(_, to)| to.clone()).collect(),
+            ),
+        };
+        if let Some(node) = left.intersection(&right).next() {
+            bail!(NodeOnBothSidesError(node.clone(), payload.span()))
+        }
+
+        let mut left_indices: BTreeMap<DataValue, u32> = BTreeMap::new();
+        let mut right_indices: BTreeMap<DataValue, u32> = BTreeMap::new();
+        let mut left_nodes = vec![];
+        let mut right_nodes = vec![];
+        let mut adjacency: Vec<Vec<u32>> = vec![];
+        for (from, to) in pairs {
+            let (l, r) = if left.contains(&from) && right.contains(&to) {
+                (from, to)
+            } else if right.contains(&from) && left.contains(&to) {
+                (to, from)
+            } else {
+                bail!(NotBipartiteError(from, to, edges.span()))
+            };
+            let l_idx = *left_indices.entry(l).or_insert_with_key(|l| {
+                left_nodes.push(l.clone());
+                adjacency.push(vec![]);
+                left_nodes.len() as u32 - 1
+            });
+            let r_idx = *right_indices.entry(r).or_insert_with_key(|r| {
+                right_nodes.push(r.clone());
+                right_nodes.len() as u32 - 1
+            });
+            adjacency[l_idx as usize].push(r_idx);
+        }
+        for targets in adjacency.iter_mut() {
+            targets.sort_unstable();
+            targets.dedup();
+        }
+
+        let matching = HopcroftKarp::new(&adjacency, right_nodes.len()).run(poison)?;
+        if size_only {
+            let size = matching.iter().filter(|r| r.is_some()).count();
+            out.put(vec![DataValue::from(size as i64)]);
+        } else {
+            for (l_idx, r_idx) in matching.into_iter().enumerate() {
+                if let Some(r_idx) = r_idx {
+                    out.put(vec![
+                        left_nodes[l_idx].clone(),
+                        right_nodes[r_idx as usize].clone(),
+                    ]);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn arity(
+        &self,
+        options: &BTreeMap<SmartString<LazyCompact>, Expr>,
+        _rule_head: &[Symbol],
+        span: SourceSpan,
+    ) -> Result<usize> {
+        let options = FixedRuleOptions::new(options, "MaximumBipartiteMatching", span);
+        Ok(if options.bool_option("size_only", Some(false))? {
+            1
+        } else {
+            2
+        })
+    }
+}
+
+struct HopcroftKarp<'a> {
+    /// The right nodes joined to each left node
+    adjacency: &'a [Vec<u32>],
+    left_matches: Vec<Option<u32>>,
+    right_matches: Vec<Option<u32>>,
+    /// The layer of each left node in the current search, `u32::MAX` if not in any
+    layers: Vec<u32>,
+    /// The next edge to try from each left node in the current search
+    next_edge: Vec<usize>,
+}
+
+impl<'a> HopcroftKarp<'a> {
+    fn new(adjacency: &'a [Vec<u32>], n_right: usize) -> Self {
+        Self {
+            adjacency,
+            left_matches: vec![None; adjacency.len()],
+            right_matches: vec![None; n_right],
+            layers: vec![u32::MAX; adjacency.len()],
+            next_edge: vec![0; adjacency.len()],
+        }
+    }
+    /// The right node matched to each left node
+    fn run(mut self, poison: Poison) -> Result<Vec<Option<u32>>> {
+        while self.layer() {
+            self.next_edge.fill(0);
+            for l in 0..self.adjacency.len() as u32 {
+                if self.left_matches[l as usize].is_none() {
+                    self.augment(l);
+                }
+            }
+            poison.check()?;
+        }
+        Ok(self.left_matches)
+    }
+    /// Layers the left nodes by their distances from the unmatched left nodes,
+    /// returning whether an unmatched right node can be reached
+    fn layer(&mut self) -> bool {
+        let mut queue = VecDeque::new();
+        for (l, matched) in self.left_matches.iter().enumerate() {
+            if matched.is_none() {
+                self.layers[l] = 0;
+                queue.push_back(l as u32);
+            } else {
+                self.layers[l] = u32::MAX;
+            }
+        }
+        let mut found = false;
+        while let Some(l) = queue.pop_front() {
+            for r in &self.adjacency[l as usize] {
+                match self.right_matches[*r as usize] {
+                    None => found = true,
+                    Some(next) => {
+                        if self.layers[next as usize] == u32::MAX {
+                            self.layers[next as usize] = self.layers[l as usize] + 1;
+                            queue.push_back(next);
+                        }
+                    }
+                }
+            }
+        }
+        found
+    }
+    /// Looks for an augmenting path from the unmatched `start` along the layers, with an
+    /// explicit stack, and flips the matching along the path if found
+    fn augment(&mut self, start: u32) -> bool {
+        // the left nodes of the path, and the right nodes taken from each of them
+        let mut lefts = vec![start];
+        let mut rights = vec![];
+        while let Some(&l) = lefts.last() {
+            let edges = &self.adjacency[l as usize];
+            if self.next_edge[l as usize] == edges.len() {
+                // no augmenting path through `l` in this round
+                self.layers[l as usize] = u32::MAX;
+                lefts.pop();
+                rights.pop();
+                continue;
+            }
+            let r = edges[self.next_edge[l as usize]];
+            self.next_edge[l as usize] += 1;
+            match self.right_matches[r as usize] {
+                None => {
+                    rights.push(r);
+                    for (l, r) in lefts.iter().zip(rights.iter()) {
+                        self.left_matches[*l as usize] = Some(*r);
+                        self.right_matches[*r as usize] = Some(*l);
+                    }
+                    return true;
+                }
+                Some(next) => {
+                    if self.layers[next as usize] == self.layers[l as usize] + 1 {
+                        rights.push(r);
+                        lefts.push(next);
+                    }
+                }
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::value::DataValue;
+    use crate::new_cozo_mem;
+
+    fn pairs(rows: Vec<Vec<DataValue>>) -> Vec<String> {
+        rows.into_iter()
+            .map(|row| format!("{}{}", row[0].get_str().unwrap(), row[1].get_str().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_bipartite_matching() {
+        let db = new_cozo_mem().unwrap();
+        let run = |script: &str| {
+            db.run_script(script, Default::default())
+                .map(|res| res.rows)
+        };
+
+        // taking `ax` first leaves `b` unmatched, the matching must be undone;
+        // `ax` is given twice
+        let edges = "['a', 'x', 1], ['a', 'x', 2], ['a', 'y', 1], ['b', 'x', 1],
+                     ['c', 'y', 1], ['c', 'z', 1]";
+        let res = run(&format!(
+            "edges[f, t, n] <- [{edges}]\n?[l, r] <~ MaximumBipartiteMatching(edges[])"
+        ))
+        .unwrap();
+        assert_eq!(pairs(res), ["ay", "bx", "cz"]);
+
+        // `d` can only be matched to `x`, which `b` needs
+        let with_d = format!("edges[f, t, n] <- [{edges}, ['d', 'x', 1]]");
+        let res = run(&format!(
+            "{with_d}\n?[l, r] <~ MaximumBipartiteMatching(edges[])"
+        ))
+        .unwrap();
+        assert_eq!(res.len(), 3);
+        assert!(pairs(res).contains(&"cz".to_string()));
+        let res = run(&format!(
+            "{with_d}\n?[n] <~ MaximumBipartiteMatching(edges[], size_only: true)"
+        ))
+        .unwrap();
+        assert_eq!(res, [[DataValue::from(3)]]);
+
+        // with the sides given, edges may go either way
+        let res = run("edges[f, t] <- [['x', 'a'], ['b', 'y']]
+                       left[n] <- [['a'], ['b']]
+                       right[n] <- [['x'], ['y']]
+                       ?[l, r] <~ MaximumBipartiteMatching(edges[], left[], right[])")
+        .unwrap();
+        assert_eq!(pairs(res), ["ax", "by"]);
+
+        let err = run("edges[f, t] <- [['a', 'x'], ['a', 'b']]
+                       left[n] <- [['a'], ['b']]
+                       right[n] <- [['x'], ['y']]
+                       ?[l, r] <~ MaximumBipartiteMatching(edges[], left[], right[])")
+        .unwrap_err();
+        assert!(err.to_string().contains("does not join"), "{err}");
+        // `x` would be on both sides
+        assert!(run("edges[f, t] <- [['a', 'x'], ['x', 'y']]
+                     ?[l, r] <~ MaximumBipartiteMatching(edges[])")
+        .is_err());
+    }
+}
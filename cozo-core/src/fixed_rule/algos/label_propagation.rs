@@ -22,6 +22,11 @@ use crate::parse::SourceSpan;
 use crate::runtime::db::Poison;
 use crate::runtime::temp_store::RegularTempStore;
 
+/// Label propagation, with a row `(label, node)` for each node.
+///
+/// Each node takes the label with the most weight among its neighbours, breaking ties at
+/// random, drawn from `seed` if given. The labels are updated in place unless `synchronous`
+/// is set, in which case all nodes take their labels from those of the last iteration.
 pub(crate) struct LabelPropagation;
 
 impl FixedRule for LabelPropagation {
@@ -34,8 +39,14 @@ impl FixedRule for LabelPropagation {
         let edges = payload.get_input(0)?;
         let undirected = payload.bool_option("undirected", Some(false))?;
         let max_iter = payload.pos_integer_option("max_iter", Some(10))?;
+        let synchronous = payload.bool_option("synchronous", Some(false))?;
+        let rng = if payload.options().contains("seed") {
+            StdRng::seed_from_u64(payload.integer_option("seed", None)? as u64)
+        } else {
+            StdRng::from_entropy()
+        };
         let (graph, indices, _inv_indices) = edges.as_directed_weighted_graph(undirected, true)?;
-        let labels = label_propagation(&graph, max_iter, poison)?;
+        let labels = label_propagation(&graph, max_iter, synchronous, rng, poison)?;
         for (idx, label) in labels.into_iter().enumerate() {
             let node = indices[idx].clone();
             out.put(vec![DataValue::from(label as i64), node]);
@@ -56,19 +67,26 @@ impl FixedRule for LabelPropagation {
 fn label_propagation(
     graph: &DirectedCsrGraph<u32, (), f32>,
     max_iter: usize,
+    synchronous: bool,
+    mut rng: StdRng,
     poison: Poison,
 ) -> Result<Vec<u32>> {
     let n_nodes = graph.node_count();
     let mut labels = (0..n_nodes).collect_vec();
-    let mut rng = thread_rng();
     let mut iter_order = (0..n_nodes).collect_vec();
     for _ in 0..max_iter {
         iter_order.shuffle(&mut rng);
+        let last_labels = if synchronous {
+            Some(labels.clone())
+        } else {
+            None
+        };
         let mut changed = false;
         for node in &iter_order {
+            let neighbor_labels = last_labels.as_ref().unwrap_or(&labels);
             let mut labels_for_node: BTreeMap<u32, f32> = BTreeMap::new();
             for edge in graph.out_neighbors_with_values(*node) {
-                let label = labels[edge.target as usize];
+                let label = neighbor_labels[edge.target as usize];
                 *labels_for_node.entry(label).or_default() += edge.value;
             }
             if labels_for_node.is_empty() {
@@ -95,3 +113,54 @@ fn label_propagation(
     }
     Ok(labels)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use crate::new_cozo_mem;
+
+    // two cliques joined by a light bridge between 4 and 5
+    const EDGES: &str = "
+        edges[f, t, w] <- [[1, 2, 1], [1, 3, 1], [1, 4, 1], [2, 3, 1], [2, 4, 1], [3, 4, 1],
+                           [5, 6, 1], [5, 7, 1], [5, 8, 1], [6, 7, 1], [6, 8, 1], [7, 8, 1],
+                           [4, 5, 0.1]]
+    ";
+
+    #[test]
+    fn test_label_propagation() {
+        let db = new_cozo_mem().unwrap();
+        let labels = |options: &str| {
+            let rows = db
+                .run_script(
+                    &format!(
+                        "{EDGES}?[l, n] <~ LabelPropagation(edges[], undirected: true{options})"
+                    ),
+                    Default::default(),
+                )
+                .unwrap()
+                .rows;
+            let mut clusters = (BTreeSet::new(), BTreeSet::new());
+            for row in rows {
+                let label = row[0].get_int().unwrap();
+                if row[1].get_int().unwrap() <= 4 {
+                    clusters.0.insert(label);
+                } else {
+                    clusters.1.insert(label);
+                }
+            }
+            clusters
+        };
+
+        for seed in 0..5 {
+            let (left, right) = labels(&format!(", max_iter: 100, seed: {seed}"));
+            assert_eq!(left.len(), 1);
+            assert_eq!(right.len(), 1);
+            assert_ne!(left, right);
+
+            let res = labels(&format!(", synchronous: true, seed: {seed}"));
+            assert!(res.0.is_disjoint(&res.1));
+            assert_eq!(res, labels(&format!(", synchronous: true, seed: {seed}")));
+        }
+    }
+}
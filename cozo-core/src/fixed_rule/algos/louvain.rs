@@ -13,10 +13,12 @@ use graph::prelude::{
 };
 use itertools::Itertools;
 use log::debug;
-use miette::Result;
+use miette::{bail, Result};
+use rand::prelude::*;
 use smartstring::{LazyCompact, SmartString};
 
 use crate::data::expr::Expr;
+use crate::data::program::WrongFixedRuleOptionError;
 use crate::data::symb::Symbol;
 use crate::data::value::DataValue;
 use crate::fixed_rule::{FixedRule, FixedRulePayload};
@@ -24,6 +26,11 @@ use crate::parse::SourceSpan;
 use crate::runtime::db::Poison;
 use crate::runtime::temp_store::RegularTempStore;
 
+/// Louvain community detection, with a row `(communities, node)` for each node, where
+/// `communities` lists the community of the node at each level, the coarsest first.
+///
+/// The nodes are visited in a random order drawn from `seed` if given, in the order of
+/// their values otherwise. Self-loops in the input are ignored if `self_loops` is false.
 pub(crate) struct CommunityDetectionLouvain;
 
 impl FixedRule for CommunityDetectionLouvain {
@@ -37,10 +44,33 @@ impl FixedRule for CommunityDetectionLouvain {
         let undirected = payload.bool_option("undirected", Some(false))?;
         let max_iter = payload.pos_integer_option("max_iter", Some(10))?;
         let delta = payload.unit_interval_option("delta", Some(0.0001))? as f32;
-        let keep_depth = payload.non_neg_integer_option("keep_depth", None).ok();
+        let keep_depth = if payload.options().contains("keep_depth") {
+            Some(payload.non_neg_integer_option("keep_depth", None)?)
+        } else {
+            None
+        };
+        let resolution = payload.float_option("resolution", Some(1.))?;
+        if resolution.is_nan() || resolution < 0. {
+            bail!(WrongFixedRuleOptionError {
+                name: "resolution".to_string(),
+                span: payload.option_span("resolution")?,
+                rule_name: payload.name().to_string(),
+                help: "a non-negative number is required".to_string()
+            })
+        }
+        let seed = if payload.options().contains("seed") {
+            Some(payload.integer_option("seed", None)? as u64)
+        } else {
+            None
+        };
+        let self_loops = payload.bool_option("self_loops", Some(true))?;
 
-        let (graph, indices, _inv_indices) = edges.as_directed_weighted_graph(undirected, false)?;
-        let result = louvain(&graph, delta, max_iter, poison)?;
+        let (mut graph, indices, _inv_indices) =
+            edges.as_directed_weighted_graph(undirected, false)?;
+        if !self_loops {
+            graph = without_self_loops(&graph);
+        }
+        let result = louvain(&graph, delta, max_iter, resolution as f32, seed, poison)?;
         for (idx, node) in indices.into_iter().enumerate() {
             let mut labels = vec![];
             let mut cur_idx = idx as u32;
@@ -69,16 +99,43 @@ impl FixedRule for CommunityDetectionLouvain {
     }
 }
 
+/// The same graph with the weights of the self-loops set to zero, keeping all nodes
+fn without_self_loops(graph: &DirectedCsrGraph<u32, (), f32>) -> DirectedCsrGraph<u32, (), f32> {
+    GraphBuilder::new()
+        .csr_layout(CsrLayout::Sorted)
+        .edges_with_values((0..graph.node_count()).flat_map(|from| {
+            graph.out_neighbors_with_values(from).map(move |target| {
+                let weight = if target.target == from {
+                    0.
+                } else {
+                    target.value
+                };
+                (from, target.target, weight)
+            })
+        }))
+        .build()
+}
+
 fn louvain(
     graph: &DirectedCsrGraph<u32, (), f32>,
     delta: f32,
     max_iter: usize,
+    resolution: f32,
+    seed: Option<u64>,
     poison: Poison,
 ) -> Result<Vec<Vec<u32>>> {
+    let mut rng = seed.map(StdRng::seed_from_u64);
     let mut current = graph;
     let mut collected = vec![];
     while current.node_count() > 2 {
-        let (node2comm, new_graph) = louvain_step(current, delta, max_iter, poison.clone())?;
+        let (node2comm, new_graph) = louvain_step(
+            current,
+            delta,
+            max_iter,
+            resolution,
+            &mut rng,
+            poison.clone(),
+        )?;
         debug!(
             "before size: {}, after size: {}",
             current.node_count(),
@@ -101,6 +158,7 @@ fn calculate_delta(
     out_weights: &[f32],
     in_weights: &[f32],
     total_weight: f32,
+    resolution: f32,
 ) -> f32 {
     let mut sigma_out_total = 0.;
     let mut sigma_in_total = 0.;
@@ -126,15 +184,45 @@ fn calculate_delta(
         }
     }
     d2comm
-        - (sigma_out_total * in_weights[node as usize]
-            + sigma_in_total * out_weights[node as usize])
+        - resolution
+            * (sigma_out_total * in_weights[node as usize]
+                + sigma_in_total * out_weights[node as usize])
             / total_weight
 }
 
+/// The modularity of the division of the nodes of `graph` into the communities `node2comm`
+fn modularity(graph: &DirectedCsrGraph<u32, (), f32>, node2comm: &[u32], resolution: f32) -> f32 {
+    let n_comms = node2comm.iter().max().map_or(0, |comm| *comm as usize + 1);
+    let mut comm_out_weights = vec![0.; n_comms];
+    let mut comm_in_weights = vec![0.; n_comms];
+    let mut total_weight = 0.;
+    let mut internal_weight = 0.;
+    for from in 0..graph.node_count() {
+        let from_comm = node2comm[from as usize];
+        for target in graph.out_neighbors_with_values(from) {
+            let to_comm = node2comm[target.target as usize];
+            total_weight += target.value;
+            comm_out_weights[from_comm as usize] += target.value;
+            comm_in_weights[to_comm as usize] += target.value;
+            if from_comm == to_comm {
+                internal_weight += target.value;
+            }
+        }
+    }
+    let expected_weight: f32 = comm_out_weights
+        .iter()
+        .zip(comm_in_weights.iter())
+        .map(|(out_weight, in_weight)| out_weight * in_weight)
+        .sum();
+    (internal_weight - resolution * expected_weight / total_weight) / total_weight
+}
+
 fn louvain_step(
     graph: &DirectedCsrGraph<u32, (), f32>,
     delta: f32,
     max_iter: usize,
+    resolution: f32,
+    rng: &mut Option<StdRng>,
     poison: Poison,
 ) -> Result<(Vec<u32>, DirectedCsrGraph<u32, (), f32>)> {
     let n_nodes = graph.node_count();
@@ -157,33 +245,22 @@ fn louvain_step(
     let mut comm2nodes = (0..n_nodes).map(|i| BTreeSet::from([i])).collect_vec();
 
     let mut last_modurality = f32::NEG_INFINITY;
+    let mut visit_order = (0..n_nodes).collect_vec();
 
     for _ in 0..max_iter {
-        let modularity = {
-            let mut modularity = 0.;
-            for from in 0..n_nodes {
-                for to in &comm2nodes[node2comm[from as usize] as usize] {
-                    for target in graph.out_neighbors_with_values(from) {
-                        if target.target == *to {
-                            modularity += target.value;
-                        }
-                    }
-                    modularity -=
-                        in_weights[from as usize] * out_weights[*to as usize] / total_weight;
-                }
-            }
-            modularity /= total_weight;
-            debug!("modurality {}", modularity);
-            modularity
-        };
+        let modularity = modularity(graph, &node2comm, resolution);
+        debug!("modurality {}", modularity);
         if modularity <= last_modurality + delta {
             break;
         } else {
             last_modurality = modularity;
         }
 
+        if let Some(rng) = rng {
+            visit_order.shuffle(rng);
+        }
         let mut moved = false;
-        for node in 0..n_nodes {
+        for &node in &visit_order {
             let community_for_node = node2comm[node as usize];
 
             let original_delta_q = calculate_delta(
@@ -194,6 +271,7 @@ fn louvain_step(
                 &out_weights,
                 &in_weights,
                 total_weight,
+                resolution,
             );
             let mut candidate_community = community_for_node;
             let mut best_improvement = 0.;
@@ -218,6 +296,7 @@ fn louvain_step(
                     &out_weights,
                     &in_weights,
                     total_weight,
+                    resolution,
                 );
                 if delta_q - original_delta_q > best_improvement {
                     best_improvement = delta_q - original_delta_q;
@@ -279,11 +358,123 @@ fn louvain_step(
 
 #[cfg(test)]
 mod tests {
-    use graph::prelude::{CsrLayout, GraphBuilder};
+    use std::collections::BTreeMap;
+
+    use graph::prelude::{CsrLayout, DirectedCsrGraph, GraphBuilder};
+    use itertools::Itertools;
 
-    use crate::fixed_rule::algos::louvain::louvain;
+    use crate::data::value::DataValue;
+    use crate::fixed_rule::algos::louvain::{louvain, modularity};
+    use crate::new_cozo_mem;
     use crate::runtime::db::Poison;
 
+    /// Zachary's karate club, with the members numbered from 1
+    const KARATE_CLUB: &[(u32, &[u32])] = &[
+        (1, &[2, 3, 4, 5, 6, 7, 8, 9, 11, 12, 13, 14, 18, 20, 22, 32]),
+        (2, &[3, 4, 8, 14, 18, 20, 22, 31]),
+        (3, &[4, 8, 9, 10, 14, 28, 29, 33]),
+        (4, &[8, 13, 14]),
+        (5, &[7, 11]),
+        (6, &[7, 11, 17]),
+        (7, &[17]),
+        (9, &[31, 33, 34]),
+        (10, &[34]),
+        (14, &[34]),
+        (15, &[33, 34]),
+        (16, &[33, 34]),
+        (19, &[33, 34]),
+        (20, &[34]),
+        (21, &[33, 34]),
+        (23, &[33, 34]),
+        (24, &[26, 28, 30, 33, 34]),
+        (25, &[26, 28, 32]),
+        (26, &[32]),
+        (27, &[30, 34]),
+        (28, &[34]),
+        (29, &[32, 34]),
+        (30, &[33, 34]),
+        (31, &[33, 34]),
+        (32, &[33, 34]),
+        (33, &[34]),
+    ];
+
+    fn karate_club() -> DirectedCsrGraph<u32, (), f32> {
+        GraphBuilder::new()
+            .csr_layout(CsrLayout::Sorted)
+            .edges_with_values(KARATE_CLUB.iter().flat_map(|(fr, tos)| {
+                tos.iter()
+                    .flat_map(move |to| [(fr - 1, to - 1, 1.), (to - 1, fr - 1, 1.)])
+            }))
+            .build()
+    }
+
+    #[test]
+    fn karate_club_modularity() {
+        let graph = karate_club();
+        for seed in [None, Some(0), Some(42)] {
+            let levels = louvain(&graph, 0., 100, 1., seed, Poison::default()).unwrap();
+            assert!(!levels.is_empty());
+            let mut node2comm = (0..34).collect_vec();
+            let mut last_modularity = modularity(&graph, &node2comm, 1.);
+            for level in &levels {
+                for comm in node2comm.iter_mut() {
+                    *comm = level[*comm as usize];
+                }
+                let current_modularity = modularity(&graph, &node2comm, 1.);
+                assert!(current_modularity > last_modularity);
+                last_modularity = current_modularity;
+            }
+            assert!(last_modularity > 0.35, "{last_modularity}");
+            assert_eq!(
+                levels,
+                louvain(&graph, 0., 100, 1., seed, Poison::default()).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn louvain_options() {
+        let db = new_cozo_mem().unwrap();
+        // two triangles not connected to each other, one with a heavy self-loop on 4
+        let communities = |options: &str| {
+            db.run_script(
+                &format!(
+                    "edges[f, t, w] <- [[1, 2, 1], [2, 3, 1], [3, 1, 1],
+                                        [4, 5, 1], [5, 6, 1], [6, 4, 1], [4, 4, 100]]
+                    ?[c, n] <~ CommunityDetectionLouvain(edges[], undirected: true{options})"
+                ),
+                Default::default(),
+            )
+            .map(|res| {
+                res.rows
+                    .into_iter()
+                    .map(|row| {
+                        let top = row[0].get_slice().unwrap()[0].clone();
+                        (row[1].get_int().unwrap(), top)
+                    })
+                    .collect::<BTreeMap<i64, DataValue>>()
+            })
+        };
+
+        let res = communities("").unwrap();
+        assert_eq!(res[&1], res[&2]);
+        assert_eq!(res[&1], res[&3]);
+        assert_eq!(res[&5], res[&6]);
+        assert_ne!(res[&4], res[&5]);
+        assert_ne!(res[&1], res[&5]);
+
+        let res = communities(", self_loops: false").unwrap();
+        assert_eq!(res[&4], res[&5]);
+        assert_eq!(res[&4], res[&6]);
+        assert_ne!(res[&1], res[&4]);
+
+        assert_eq!(
+            communities(", seed: 7").unwrap(),
+            communities(", seed: 7").unwrap()
+        );
+        assert!(communities(", resolution: -1").is_err());
+    }
+
     #[test]
     fn sample() {
         let graph: Vec<Vec<u32>> = vec![
@@ -313,6 +504,6 @@ mod tests {
                     .flat_map(|(fr, tos)| tos.into_iter().map(move |to| (fr as u32, to, 1.))),
             )
             .build();
-        louvain(&graph, 0., 100, Poison::default()).unwrap();
+        louvain(&graph, 0., 100, 1., None, Poison::default()).unwrap();
     }
 }
@@ -0,0 +1,360 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use graph::prelude::{DirectedCsrGraph, DirectedNeighborsWithValues, Graph};
+use miette::{bail, ensure, Diagnostic, Result};
+use smartstring::{LazyCompact, SmartString};
+use thiserror::Error;
+
+use crate::data::expr::Expr;
+use crate::data::symb::Symbol;
+use crate::data::value::DataValue;
+use crate::fixed_rule::{FixedRule, FixedRuleOptions, FixedRulePayload};
+use crate::parse::SourceSpan;
+use crate::runtime::db::Poison;
+use crate::runtime::temp_store::RegularTempStore;
+
+/// Maximum flow from `source` to `sink` by Dinic's algorithm, over edges `(from, to, capacity)`.
+///
+/// The source and the sink are given by the options `source` and `sink`, or as the single
+/// row of the second input. There is a row `(from, to, flow)` for each pair of nodes with
+/// some flow between them, and a row `(null, null, total)` with the total flow.
+/// With `min_cut` set, the rows have a fourth column telling whether the edges from `from`
+/// to `to` are in a minimum cut, null for the total.
+pub(crate) struct MaxFlow;
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("The source and the sink of the flow are both {0:?}")]
+#[diagnostic(code(algo::source_is_sink))]
+struct SourceIsSinkError(DataValue, #[label] SourceSpan);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("The source and the sink of the flow must be given")]
+#[diagnostic(code(algo::bad_flow_terminals))]
+#[diagnostic(help(
+    "Use the options `source` and `sink`, or a relation with the single row [source, sink]"
+))]
+struct BadTerminalsError(#[label] SourceSpan);
+
+impl FixedRule for MaxFlow {
+    fn run(
+        &self,
+        payload: FixedRulePayload<'_, '_>,
+        out: &mut RegularTempStore,
+        poison: Poison,
+    ) -> Result<()> {
+        let edges = payload.get_input(0)?;
+        let undirected = payload.bool_option("undirected", Some(false))?;
+        let min_cut = payload.bool_option("min_cut", Some(false))?;
+
+        let options = payload.options();
+        let (source, sink) = if options.contains("source") || options.contains("sink") {
+            (
+                payload.value_option("source", None)?,
+                payload.value_option("sink", None)?,
+            )
+        } else {
+            let terminals = payload
+                .get_input(1)
+                .map_err(|_| BadTerminalsError(payload.span()))?;
+            let mut rows = terminals.iter()?;
+            let (row, rest) = (rows.next(), rows.next());
+            match (row, rest) {
+                (Some(row), None) => {
+                    let mut row = row?.into_iter();
+                    match (row.next(), row.next()) {
+                        (Some(source), Some(sink)) => (source, sink),
+                        _ => bail!(BadTerminalsError(terminals.span())),
+                    }
+                }
+                _ => bail!(BadTerminalsError(terminals.span())),
+            }
+        };
+        ensure!(source != sink, SourceIsSinkError(source, payload.span()));
+
+        // capacities are checked to be non-negative numbers here
+        let (graph, indices, inv_indices) = edges.as_directed_weighted_graph(undirected, false)?;
+        let (source_idx, sink_idx) = match (inv_indices.get(&source), inv_indices.get(&sink)) {
+            (Some(source_idx), Some(sink_idx)) => (*source_idx, *sink_idx),
+            _ => {
+                let mut row = vec![DataValue::Null, DataValue::Null, DataValue::from(0.)];
+                if min_cut {
+                    row.push(DataValue::Null);
+                }
+                out.put(row);
+                return Ok(());
+            }
+        };
+
+        let mut network = FlowNetwork::new(&graph);
+        let total = network.max_flow(source_idx, sink_idx, poison)?;
+        let source_side = if min_cut {
+            network.reachable(source_idx)
+        } else {
+            BTreeSet::new()
+        };
+
+        let mut row = vec![DataValue::Null, DataValue::Null, DataValue::from(total)];
+        if min_cut {
+            row.push(DataValue::Null);
+        }
+        out.put(row);
+        for ((from, to), flow) in network.edge_flows() {
+            if flow <= 0. {
+                continue;
+            }
+            let mut row = vec![
+                indices[from as usize].clone(),
+                indices[to as usize].clone(),
+                DataValue::from(flow),
+            ];
+            if min_cut {
+                let in_cut = source_side.contains(&from) && !source_side.contains(&to);
+                row.push(DataValue::from(in_cut));
+            }
+            out.put(row);
+        }
+        Ok(())
+    }
+
+    fn arity(
+        &self,
+        options: &BTreeMap<SmartString<LazyCompact>, Expr>,
+        _rule_head: &[Symbol],
+        span: SourceSpan,
+    ) -> Result<usize> {
+        let options = FixedRuleOptions::new(options, "MaxFlow", span);
+        Ok(if options.bool_option("min_cut", Some(false))? {
+            4
+        } else {
+            3
+        })
+    }
+}
+
+/// The residual network, where the edge `e ^ 1` is the reverse of the edge `e`
+struct FlowNetwork {
+    /// The edges leaving each node
+    adjacency: Vec<Vec<usize>>,
+    targets: Vec<u32>,
+    capacities: Vec<f64>,
+    /// The capacities of the edges of the graph, zero for the reverse edges
+    original: Vec<f64>,
+}
+
+impl FlowNetwork {
+    fn new(graph: &DirectedCsrGraph<u32, (), f32>) -> Self {
+        let n = graph.node_count() as usize;
+        let mut network = Self {
+            adjacency: vec![vec![]; n],
+            targets: vec![],
+            capacities: vec![],
+            original: vec![],
+        };
+        for from in 0..n as u32 {
+            for target in graph.out_neighbors_with_values(from) {
+                let capacity = target.value as f64;
+                network.add_edge(from, target.target, capacity);
+                network.add_edge(target.target, from, 0.);
+            }
+        }
+        network
+    }
+    fn add_edge(&mut self, from: u32, to: u32, capacity: f64) {
+        self.adjacency[from as usize].push(self.targets.len());
+        self.targets.push(to);
+        self.capacities.push(capacity);
+        self.original.push(capacity);
+    }
+    fn source_of(&self, edge: usize) -> u32 {
+        self.targets[edge ^ 1]
+    }
+    /// The distances from `source` through edges with capacity left, `None` if unreachable
+    fn levels(&self, source: u32) -> Vec<Option<usize>> {
+        let mut levels = vec![None; self.adjacency.len()];
+        levels[source as usize] = Some(0);
+        let mut queue = VecDeque::from([source]);
+        while let Some(node) = queue.pop_front() {
+            let level = levels[node as usize].unwrap();
+            for &edge in &self.adjacency[node as usize] {
+                let to = self.targets[edge] as usize;
+                if self.capacities[edge] > 0. && levels[to].is_none() {
+                    levels[to] = Some(level + 1);
+                    queue.push_back(self.targets[edge]);
+                }
+            }
+        }
+        levels
+    }
+    fn reachable(&self, source: u32) -> BTreeSet<u32> {
+        self.levels(source)
+            .into_iter()
+            .enumerate()
+            .filter(|(_, level)| level.is_some())
+            .map(|(node, _)| node as u32)
+            .collect()
+    }
+    fn max_flow(&mut self, source: u32, sink: u32, poison: Poison) -> Result<f64> {
+        let mut total = 0.;
+        loop {
+            let levels = self.levels(source);
+            if levels[sink as usize].is_none() {
+                return Ok(total);
+            }
+            total += self.blocking_flow(source, sink, &levels);
+            poison.check()?;
+        }
+    }
+    /// Saturates all shortest paths from `source` to `sink`, searching with an explicit stack
+    fn blocking_flow(&mut self, source: u32, sink: u32, levels: &[Option<usize>]) -> f64 {
+        // the next edge to try from each node, the edges before it leading nowhere
+        let mut next_edge = vec![0; self.adjacency.len()];
+        let mut path: Vec<usize> = vec![];
+        let mut node = source;
+        let mut total = 0.;
+        loop {
+            if node == sink {
+                let bottleneck = path
+                    .iter()
+                    .map(|edge| self.capacities[*edge])
+                    .fold(f64::INFINITY, f64::min);
+                for edge in &path {
+                    self.capacities[*edge] -= bottleneck;
+                    self.capacities[*edge ^ 1] += bottleneck;
+                }
+                total += bottleneck;
+                // go back to the start of the first saturated edge
+                let saturated = path
+                    .iter()
+                    .position(|edge| self.capacities[*edge] <= 0.)
+                    .unwrap_or(0);
+                path.truncate(saturated);
+                node = path.last().map_or(source, |edge| self.targets[*edge]);
+                continue;
+            }
+            let edges = &self.adjacency[node as usize];
+            let advance = edges[next_edge[node as usize]..].iter().position(|edge| {
+                let to = self.targets[*edge] as usize;
+                self.capacities[*edge] > 0.
+                    && levels[to] == levels[node as usize].map(|level| level + 1)
+            });
+            match advance {
+                Some(offset) => {
+                    next_edge[node as usize] += offset;
+                    let edge = edges[next_edge[node as usize]];
+                    path.push(edge);
+                    node = self.targets[edge];
+                }
+                None => {
+                    next_edge[node as usize] = edges.len();
+                    match path.pop() {
+                        None => return total,
+                        Some(edge) => {
+                            node = self.source_of(edge);
+                            next_edge[node as usize] += 1;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    /// The flow through the edges of the graph, summed over parallel edges
+    fn edge_flows(&self) -> BTreeMap<(u32, u32), f64> {
+        let mut flows = BTreeMap::new();
+        for edge in (0..self.targets.len()).step_by(2) {
+            let flow = self.original[edge] - self.capacities[edge];
+            *flows
+                .entry((self.source_of(edge), self.targets[edge]))
+                .or_default() += flow;
+        }
+        flows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+
+    use crate::data::value::DataValue;
+    use crate::new_cozo_mem;
+
+    // the network of CLRS figure 26.1, with a maximum flow of 23
+    const NETWORK: &str = "
+        edges[f, t, c] <- [['s', 'v1', 16], ['s', 'v2', 13], ['v1', 'v3', 12], ['v2', 'v1', 4],
+                           ['v2', 'v4', 14], ['v3', 'v2', 9], ['v3', 't', 20], ['v4', 'v3', 7],
+                           ['v4', 't', 4]]
+    ";
+
+    #[test]
+    fn test_max_flow() {
+        let db = new_cozo_mem().unwrap();
+        let run = |query: &str| {
+            db.run_script(&format!("{NETWORK}{query}"), Default::default())
+                .map(|res| res.rows)
+        };
+
+        let rows = run("?[f, t, v] <~ MaxFlow(edges[], source: 's', sink: 't')").unwrap();
+        assert_eq!(rows[0][..2], [DataValue::Null, DataValue::Null]);
+        assert_eq!(rows[0][2], DataValue::from(23.));
+        let flow_through = |node: &str, from: bool| -> f64 {
+            let col = if from { 0 } else { 1 };
+            rows[1..]
+                .iter()
+                .filter(|row| row[col].get_str() == Some(node))
+                .map(|row| row[2].get_float().unwrap())
+                .sum()
+        };
+        assert_eq!(flow_through("s", true), 23.);
+        assert_eq!(flow_through("t", false), 23.);
+        for node in ["v1", "v2", "v3", "v4"] {
+            assert_eq!(flow_through(node, true), flow_through(node, false));
+        }
+
+        let terminals = run("terminals[] <- [['s', 't']]
+            ?[f, t, v] <~ MaxFlow(edges[], terminals[])")
+        .unwrap();
+        assert_eq!(terminals, rows);
+
+        // the minimum cut separates {s, v1, v2, v4} from {v3, t}
+        let rows = run("?[f, t, v, c] <~ MaxFlow(edges[], source: 's', sink: 't', min_cut: true)")
+            .unwrap();
+        let cut = rows
+            .iter()
+            .filter(|row| row[3] == DataValue::from(true))
+            .map(|row| {
+                format!(
+                    "{}-{}:{}",
+                    row[0].get_str().unwrap(),
+                    row[1].get_str().unwrap(),
+                    row[2].get_float().unwrap()
+                )
+            })
+            .collect_vec();
+        assert_eq!(cut, ["v1-v3:12", "v4-t:4", "v4-v3:7"]);
+
+        // no path to the sink
+        let rows = run("?[f, t, v] <~ MaxFlow(edges[], source: 't', sink: 's')").unwrap();
+        assert_eq!(
+            rows,
+            [vec![DataValue::Null, DataValue::Null, DataValue::from(0.)]]
+        );
+
+        assert!(run("?[f, t, v] <~ MaxFlow(edges[], source: 's', sink: 's')").is_err());
+        assert!(run("?[f, t, v] <~ MaxFlow(edges[])").is_err());
+        let err = db
+            .run_script(
+                "edges[f, t, c] <- [['s', 't', -1]]
+                ?[f, t, v] <~ MaxFlow(edges[], source: 's', sink: 't')",
+                Default::default(),
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("edge weight"), "{err}");
+    }
+}
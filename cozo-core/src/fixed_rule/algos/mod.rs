@@ -9,17 +9,24 @@
 pub(crate) mod all_pairs_shortest_path;
 pub(crate) mod astar;
 pub(crate) mod bfs;
+pub(crate) mod bipartite_matching;
 pub(crate) mod degree_centrality;
 pub(crate) mod dfs;
 pub(crate) mod kruskal;
 pub(crate) mod label_propagation;
 pub(crate) mod louvain;
+pub(crate) mod max_flow;
+pub(crate) mod node_similarity;
 pub(crate) mod pagerank;
 pub(crate) mod prim;
 pub(crate) mod random_walk;
+pub(crate) mod random_walk_restart;
+pub(crate) mod shortest_path_bellman_ford;
 pub(crate) mod shortest_path_bfs;
 pub(crate) mod shortest_path_dijkstra;
+pub(crate) mod shortest_path_floyd_warshall;
 pub(crate) mod strongly_connected_components;
+pub(crate) mod subgraph;
 pub(crate) mod top_sort;
 pub(crate) mod triangles;
 pub(crate) mod yen;
@@ -27,17 +34,24 @@ pub(crate) mod yen;
 pub(crate) use all_pairs_shortest_path::{BetweennessCentrality, ClosenessCentrality};
 pub(crate) use astar::ShortestPathAStar;
 pub(crate) use bfs::Bfs;
+pub(crate) use bipartite_matching::MaximumBipartiteMatching;
 pub(crate) use degree_centrality::DegreeCentrality;
 pub(crate) use dfs::Dfs;
 pub(crate) use kruskal::MinimumSpanningForestKruskal;
 pub(crate) use label_propagation::LabelPropagation;
 pub(crate) use louvain::CommunityDetectionLouvain;
+pub(crate) use max_flow::MaxFlow;
+pub(crate) use node_similarity::NodeSimilarity;
 pub(crate) use pagerank::PageRank;
 pub(crate) use prim::MinimumSpanningTreePrim;
 pub(crate) use random_walk::RandomWalk;
+pub(crate) use random_walk_restart::RandomWalkWithRestart;
+pub(crate) use shortest_path_bellman_ford::ShortestPathBellmanFord;
 pub(crate) use shortest_path_bfs::ShortestPathBFS;
 pub(crate) use shortest_path_dijkstra::ShortestPathDijkstra;
+pub(crate) use shortest_path_floyd_warshall::ShortestPathFloydWarshall;
 pub(crate) use strongly_connected_components::StronglyConnectedComponent;
+pub(crate) use subgraph::Subgraph;
 pub(crate) use top_sort::TopSort;
 pub(crate) use triangles::ClusteringCoefficients;
 pub(crate) use yen::KShortestPathYen;
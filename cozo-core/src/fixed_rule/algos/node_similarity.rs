@@ -0,0 +1,226 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::BTreeMap;
+
+use graph::prelude::{DirectedNeighbors, Graph};
+use itertools::Itertools;
+use miette::{bail, Result};
+use smartstring::{LazyCompact, SmartString};
+
+use crate::data::expr::Expr;
+use crate::data::program::WrongFixedRuleOptionError;
+use crate::data::symb::Symbol;
+use crate::data::value::DataValue;
+use crate::fixed_rule::{FixedRule, FixedRuleOptions, FixedRulePayload};
+use crate::parse::SourceSpan;
+use crate::runtime::db::Poison;
+use crate::runtime::temp_store::RegularTempStore;
+
+/// The similarity of nodes by the targets of their edges, with a row `(a, b, score)` for each
+/// pair of nodes with `a < b` and some targets in common.
+///
+/// With `top_k` set, a pair is only included if one of its nodes is among the `top_k` nodes
+/// most similar to the other. Pairs with a score below `min_similarity` are left out.
+pub(crate) struct NodeSimilarity;
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Metric {
+    /// the targets in common over all targets of either node
+    Jaccard,
+    /// the targets in common over the geometric mean of the numbers of targets of each node
+    Cosine,
+}
+
+impl Metric {
+    fn score(self, common: usize, a_len: usize, b_len: usize) -> f64 {
+        match self {
+            Metric::Jaccard => common as f64 / (a_len + b_len - common) as f64,
+            Metric::Cosine => common as f64 / ((a_len * b_len) as f64).sqrt(),
+        }
+    }
+}
+
+impl NodeSimilarity {
+    fn metric(options: FixedRuleOptions<'_>) -> Result<Metric> {
+        let metric = options.string_option("metric", Some("jaccard"))?;
+        Ok(match metric.as_str() {
+            "jaccard" => Metric::Jaccard,
+            "cosine" => Metric::Cosine,
+            _ => bail!(WrongFixedRuleOptionError {
+                name: "metric".to_string(),
+                span: options.option_span("metric")?,
+                rule_name: "NodeSimilarity".to_string(),
+                help: "'metric' must be 'jaccard' or 'cosine'".to_string(),
+            }),
+        })
+    }
+}
+
+impl FixedRule for NodeSimilarity {
+    fn init_options(
+        &self,
+        options: &mut BTreeMap<SmartString<LazyCompact>, Expr>,
+        span: SourceSpan,
+    ) -> Result<()> {
+        Self::metric(FixedRuleOptions::new(options, "NodeSimilarity", span))?;
+        Ok(())
+    }
+
+    fn run(
+        &self,
+        payload: FixedRulePayload<'_, '_>,
+        out: &mut RegularTempStore,
+        poison: Poison,
+    ) -> Result<()> {
+        let edges = payload.get_input(0)?;
+        let undirected = payload.bool_option("undirected", Some(false))?;
+        let metric = Self::metric(payload.options())?;
+        let min_similarity = payload.unit_interval_option("min_similarity", Some(0.))?;
+        let top_k = if payload.options().contains("top_k") {
+            Some(payload.pos_integer_option("top_k", None)?)
+        } else {
+            None
+        };
+
+        let (graph, indices, _) = edges.as_directed_graph(undirected)?;
+        let n = graph.node_count();
+
+        let targets = (0..n)
+            .map(|node| graph.out_neighbors(node).copied().dedup().collect_vec())
+            .collect_vec();
+        // the nodes with an edge to each node, so that only the pairs of nodes with
+        // targets in common are visited
+        let mut sources = vec![vec![]; n as usize];
+        for (node, node_targets) in targets.iter().enumerate() {
+            for target in node_targets {
+                sources[*target as usize].push(node as u32);
+            }
+        }
+
+        let mut pairs: BTreeMap<(u32, u32), f64> = BTreeMap::new();
+        for node in 0..n {
+            let mut common: BTreeMap<u32, usize> = BTreeMap::new();
+            for target in &targets[node as usize] {
+                for other in &sources[*target as usize] {
+                    if *other != node {
+                        *common.entry(*other).or_default() += 1;
+                    }
+                }
+            }
+            let node_len = targets[node as usize].len();
+            let mut similar = common
+                .into_iter()
+                .map(|(other, common)| {
+                    let other_len = targets[other as usize].len();
+                    (other, metric.score(common, node_len, other_len))
+                })
+                .filter(|(_, score)| *score >= min_similarity)
+                .collect_vec();
+            if let Some(k) = top_k {
+                similar.sort_by(|(a, a_score), (b, b_score)| {
+                    b_score
+                        .total_cmp(a_score)
+                        .then_with(|| indices[*a as usize].cmp(&indices[*b as usize]))
+                });
+                similar.truncate(k);
+            }
+            for (other, score) in similar {
+                let pair = if indices[node as usize] < indices[other as usize] {
+                    (node, other)
+                } else {
+                    (other, node)
+                };
+                pairs.insert(pair, score);
+            }
+            poison.check()?;
+        }
+
+        for ((a, b), score) in pairs {
+            out.put(vec![
+                indices[a as usize].clone(),
+                indices[b as usize].clone(),
+                DataValue::from(score),
+            ]);
+        }
+        Ok(())
+    }
+
+    fn arity(
+        &self,
+        _options: &BTreeMap<SmartString<LazyCompact>, Expr>,
+        _rule_head: &[Symbol],
+        _span: SourceSpan,
+    ) -> Result<usize> {
+        Ok(3)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::new_cozo_mem;
+
+    fn similarity(options: &str) -> Vec<(String, f64)> {
+        let db = new_cozo_mem().unwrap();
+        db.run_script(
+            &format!(
+                "edges[f, t] <- [['a', 'x'], ['a', 'y'], ['a', 'z'], ['b', 'y'], ['b', 'z'],
+                                 ['c', 'z'], ['c', 'w'], ['d', 'q']]
+                ?[a, b, s] <~ NodeSimilarity(edges[]{options})"
+            ),
+            Default::default(),
+        )
+        .unwrap()
+        .rows
+        .into_iter()
+        .map(|row| {
+            (
+                format!("{}{}", row[0].get_str().unwrap(), row[1].get_str().unwrap()),
+                row[2].get_float().unwrap(),
+            )
+        })
+        .collect()
+    }
+
+    fn assert_scores(actual: Vec<(String, f64)>, expected: &[(&str, f64)]) {
+        assert_eq!(actual.len(), expected.len(), "{actual:?}");
+        for ((pair, score), (expected_pair, expected_score)) in actual.iter().zip(expected) {
+            assert_eq!(pair, expected_pair);
+            assert!((score - expected_score).abs() < 1e-9, "{pair}: {score}");
+        }
+    }
+
+    #[test]
+    fn test_node_similarity() {
+        assert_scores(
+            similarity(""),
+            &[("ab", 2. / 3.), ("ac", 1. / 4.), ("bc", 1. / 3.)],
+        );
+        assert_scores(
+            similarity(", metric: 'cosine'"),
+            &[
+                ("ab", 2. / 6f64.sqrt()),
+                ("ac", 1. / 6f64.sqrt()),
+                ("bc", 1. / 2.),
+            ],
+        );
+        // `c` is more similar to `b` than to `a`
+        assert_scores(
+            similarity(", top_k: 1"),
+            &[("ab", 2. / 3.), ("bc", 1. / 3.)],
+        );
+        assert_scores(
+            similarity(", min_similarity: 0.3"),
+            &[("ab", 2. / 3.), ("bc", 1. / 3.)],
+        );
+        assert_scores(
+            similarity(", undirected: true, min_similarity: 0.5"),
+            &[("ab", 2. / 3.), ("xy", 1. / 2.), ("yz", 2. / 3.)],
+        );
+    }
+}
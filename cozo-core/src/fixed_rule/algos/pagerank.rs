@@ -8,11 +8,11 @@
 
 use std::collections::BTreeMap;
 
-#[cfg(not(feature = "rayon"))]
-use approx::AbsDiffEq;
-use graph::prelude::{page_rank, PageRankConfig};
-use miette::Result;
+use graph::prelude::{DirectedCsrGraph, DirectedNeighbors, Graph};
+use itertools::Itertools;
+use miette::{ensure, Diagnostic, Result};
 use smartstring::{LazyCompact, SmartString};
+use thiserror::Error;
 
 use crate::data::expr::Expr;
 use crate::data::symb::Symbol;
@@ -22,10 +22,19 @@ use crate::parse::SourceSpan;
 use crate::runtime::db::Poison;
 use crate::runtime::temp_store::RegularTempStore;
 
+/// PageRank, with a row `(node, rank)` for each node, the ranks summing to one.
+///
+/// The random surfer jumps to a node drawn from the personalization weights given as the
+/// optional second input `(node, weight)`, or to any node if there are none, with the
+/// probability `1 - damping` at each step, and always from nodes without out-edges.
 pub(crate) struct PageRank;
 
+#[derive(Debug, Error, Diagnostic)]
+#[error("The personalization weights must be non-negative numbers with a positive sum")]
+#[diagnostic(code(algo::bad_personalization))]
+struct BadPersonalizationError(#[label] SourceSpan);
+
 impl FixedRule for PageRank {
-    #[allow(unused_variables)]
     fn run(
         &self,
         payload: FixedRulePayload<'_, '_>,
@@ -34,23 +43,62 @@ impl FixedRule for PageRank {
     ) -> Result<()> {
         let edges = payload.get_input(0)?;
         let undirected = payload.bool_option("undirected", Some(false))?;
-        let theta = payload.unit_interval_option("theta", Some(0.85))? as f32;
-        let epsilon = payload.unit_interval_option("epsilon", Some(0.0001))? as f32;
-        let iterations = payload.pos_integer_option("iterations", Some(10))?;
+        // `theta` and `iterations` are the former names of `damping` and `max_iter`
+        let damping = if payload.options().contains("theta") {
+            payload.unit_interval_option("theta", None)?
+        } else {
+            payload.unit_interval_option("damping", Some(0.85))?
+        };
+        let epsilon = payload.unit_interval_option("epsilon", Some(0.0001))?;
+        let max_iter = if payload.options().contains("iterations") {
+            payload.pos_integer_option("iterations", None)?
+        } else {
+            payload.pos_integer_option("max_iter", Some(100))?
+        };
 
-        let (graph, indices, _) = edges.as_directed_graph(undirected)?;
+        let (graph, indices, inv_indices) = edges.as_directed_graph(undirected)?;
 
         if indices.is_empty() {
             return Ok(());
         }
 
-        let (ranks, _n_run, _) = page_rank(
+        let personalization = match payload.get_input(1) {
+            Err(_) => None,
+            Ok(rel) => {
+                let mut weights = vec![0.; indices.len()];
+                for tuple in rel.iter()? {
+                    let tuple = tuple?;
+                    let weight = match tuple.get(1) {
+                        None => 1.,
+                        Some(val) => val
+                            .get_float()
+                            .ok_or_else(|| BadPersonalizationError(rel.span()))?,
+                    };
+                    ensure!(weight >= 0., BadPersonalizationError(rel.span()));
+                    if let Some(idx) = inv_indices.get(&tuple[0]) {
+                        weights[*idx as usize] += weight;
+                    }
+                }
+                let total: f64 = weights.iter().sum();
+                ensure!(total > 0., BadPersonalizationError(rel.span()));
+                for weight in weights.iter_mut() {
+                    *weight /= total;
+                }
+                Some(weights)
+            }
+        };
+
+        let ranks = pagerank(
             &graph,
-            PageRankConfig::new(iterations, epsilon as f64, theta),
-        );
+            damping,
+            epsilon,
+            max_iter,
+            personalization.as_deref(),
+            poison,
+        )?;
 
-        for (idx, score) in ranks.iter().enumerate() {
-            out.put(vec![indices[idx].clone(), DataValue::from(*score as f64)]);
+        for (idx, score) in ranks.into_iter().enumerate() {
+            out.put(vec![indices[idx].clone(), DataValue::from(score)]);
         }
         Ok(())
     }
@@ -65,45 +113,129 @@ impl FixedRule for PageRank {
     }
 }
 
-#[cfg(not(feature = "rayon"))]
+/// Iterates the ranks until their total change is below `epsilon`, or for `max_iter` rounds.
+/// `personalization` sums to one if given.
 fn pagerank(
-    edges: &[Vec<usize>],
-    theta: f32,
-    epsilon: f32,
-    iterations: usize,
+    graph: &DirectedCsrGraph<u32>,
+    damping: f64,
+    epsilon: f64,
+    max_iter: usize,
+    personalization: Option<&[f64]>,
     poison: Poison,
-) -> Result<OMatrix<f32, Dynamic, U1>> {
-    let init_val = (1. - theta) / edges.len() as f32;
-    let mut g_mat = OMatrix::<f32, Dynamic, Dynamic>::repeat(edges.len(), edges.len(), init_val);
-    let n = edges.len();
-    let empty_score = theta / n as f32;
-    for (node, to_nodes) in edges.iter().enumerate() {
-        let l = to_nodes.len();
-        if l == 0 {
-            for to_node in 0..n {
-                g_mat[(node, to_node)] = empty_score;
+) -> Result<Vec<f64>> {
+    let n = graph.node_count() as usize;
+    let uniform = vec![1. / n as f64; n];
+    let jump = personalization.unwrap_or(uniform.as_slice());
+    let out_degrees = (0..n as u32)
+        .map(|node| graph.out_neighbors(node).count())
+        .collect_vec();
+
+    let mut ranks = uniform.clone();
+    let mut next_ranks = vec![0.; n];
+    for _ in 0..max_iter {
+        // the surfer at a node without out-edges always jumps
+        let dangling: f64 = (0..n)
+            .filter(|node| out_degrees[*node] == 0)
+            .map(|node| ranks[node])
+            .sum();
+        let jumping = 1. - damping + damping * dangling;
+        for (next_rank, jump_weight) in next_ranks.iter_mut().zip(jump) {
+            *next_rank = jumping * jump_weight;
+        }
+        for node in 0..n {
+            if out_degrees[node] == 0 {
+                continue;
             }
-        } else {
-            let score = theta / n as f32;
-            for to_node in to_nodes {
-                g_mat[(node, *to_node)] = score;
+            let share = damping * ranks[node] / out_degrees[node] as f64;
+            for target in graph.out_neighbors(node as u32) {
+                next_ranks[*target as usize] += share;
             }
         }
-    }
-    let mut pi_vec = OMatrix::<f32, Dynamic, U1>::repeat(edges.len(), 1.);
-    let scale_target = (n as f32).sqrt();
-    let mut last_pi_vec = pi_vec.clone();
-    for _ in 0..iterations {
-        std::mem::swap(&mut pi_vec, &mut last_pi_vec);
-        pi_vec = g_mat.tr_mul(&last_pi_vec);
-        pi_vec.normalize_mut();
-        let f = pi_vec.norm() / scale_target;
-        pi_vec.unscale_mut(f);
-
-        if pi_vec.abs_diff_eq(&last_pi_vec, epsilon) {
+        // the ranks only stray from summing to one by rounding errors
+        let total: f64 = next_ranks.iter().sum();
+        for rank in next_ranks.iter_mut() {
+            *rank /= total;
+        }
+        let change: f64 = ranks
+            .iter()
+            .zip(next_ranks.iter())
+            .map(|(rank, next_rank)| (rank - next_rank).abs())
+            .sum();
+        std::mem::swap(&mut ranks, &mut next_ranks);
+        if change < epsilon {
             break;
         }
         poison.check()?;
     }
-    Ok(pi_vec)
+    Ok(ranks)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::new_cozo_mem;
+
+    fn ranks(edges: &str, query: &str) -> Vec<(i64, f64)> {
+        let db = new_cozo_mem().unwrap();
+        db.run_script(&format!("{edges}\n{query}"), Default::default())
+            .unwrap()
+            .rows
+            .into_iter()
+            .map(|row| (row[0].get_int().unwrap(), row[1].get_float().unwrap()))
+            .collect()
+    }
+
+    fn assert_sums_to_one(ranks: &[(i64, f64)]) {
+        let total: f64 = ranks.iter().map(|(_, rank)| rank).sum();
+        assert!((total - 1.).abs() < 1e-9, "{total}");
+    }
+
+    #[test]
+    fn cycle_is_uniform() {
+        let ranks = ranks(
+            "edges[f, t] <- [[1, 2], [2, 3], [3, 4], [4, 5], [5, 1]]",
+            "?[n, r] <~ PageRank(edges[])",
+        );
+        assert_eq!(ranks.len(), 5);
+        for (_, rank) in &ranks {
+            assert!((rank - 0.2).abs() < 1e-9, "{rank}");
+        }
+    }
+
+    #[test]
+    fn hub_and_spokes() {
+        // the spokes point to the hub 1, which points back to 2 only
+        let edges = "edges[f, t] <- [[2, 1], [3, 1], [4, 1], [5, 1], [1, 2]]";
+        let res = ranks(
+            edges,
+            "?[n, r] <~ PageRank(edges[], damping: 0.85, epsilon: 0.000001)",
+        );
+        assert_sums_to_one(&res);
+        assert!(res[0].1 > res[1].1);
+        assert!(res[1].1 > res[2].1);
+        assert!((res[2].1 - res[3].1).abs() < 1e-9);
+        assert!((res[2].1 - res[4].1).abs() < 1e-9);
+
+        // only 3 is jumped to, so 4 and 5 are never visited
+        let res = ranks(
+            &format!("{edges}\npersonal[n, w] <- [[3, 1]]"),
+            "?[n, r] <~ PageRank(edges[], personal[])",
+        );
+        assert_sums_to_one(&res);
+        assert!(res[0].1 > res[2].1);
+        assert!(res[2].1 > 0.);
+        assert_eq!(res[3].1, 0.);
+        assert_eq!(res[4].1, 0.);
+    }
+
+    #[test]
+    fn dangling_nodes() {
+        // 3 has no out-edges
+        let res = ranks(
+            "edges[f, t] <- [[1, 2], [2, 3], [1, 3]]",
+            "?[n, r] <~ PageRank(edges[], max_iter: 1000)",
+        );
+        assert_sums_to_one(&res);
+        assert!(res[2].1 > res[1].1);
+        assert!(res[1].1 > res[0].1);
+    }
 }
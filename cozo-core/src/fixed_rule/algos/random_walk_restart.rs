@@ -0,0 +1,217 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::BTreeMap;
+
+use graph::prelude::{DirectedCsrGraph, DirectedNeighborsWithValues};
+use miette::Result;
+use smartstring::{LazyCompact, SmartString};
+
+use crate::data::expr::Expr;
+use crate::data::symb::Symbol;
+use crate::data::value::DataValue;
+use crate::fixed_rule::{FixedRule, FixedRulePayload};
+use crate::parse::SourceSpan;
+use crate::runtime::db::Poison;
+use crate::runtime::temp_store::RegularTempStore;
+
+/// Random walk with restart from the seed nodes, with a row `(node, score)` for each node
+/// reached, the scores summing to one.
+///
+/// At each step the walker goes back to a seed with the probability `restart_prob`, and
+/// otherwise follows an out-edge, chosen in proportion to the weights if `weighted` is set.
+/// The scores are found by power iteration, only visiting the nodes reached so far.
+pub(crate) struct RandomWalkWithRestart;
+
+impl FixedRule for RandomWalkWithRestart {
+    fn run(
+        &self,
+        payload: FixedRulePayload<'_, '_>,
+        out: &mut RegularTempStore,
+        poison: Poison,
+    ) -> Result<()> {
+        let edges = payload.get_input(0)?;
+        let seeds = payload.get_input(1)?;
+        let undirected = payload.bool_option("undirected", Some(false))?;
+        let weighted = payload.bool_option("weighted", Some(false))?;
+        let restart_prob = payload.unit_interval_option("restart_prob", Some(0.15))?;
+        let iterations = payload.pos_integer_option("iterations", Some(100))?;
+        let epsilon = payload.unit_interval_option("epsilon", Some(0.000001))?;
+
+        let (graph, indices, inv_indices) = edges.as_directed_weighted_graph(undirected, false)?;
+
+        let mut restarts = BTreeMap::new();
+        for tuple in seeds.iter()? {
+            let tuple = tuple?;
+            if let Some(idx) = inv_indices.get(&tuple[0]) {
+                restarts.insert(*idx, 1.);
+            }
+        }
+        if restarts.is_empty() {
+            return Ok(());
+        }
+        let n_seeds = restarts.len() as f64;
+        for prob in restarts.values_mut() {
+            *prob /= n_seeds;
+        }
+
+        let scores = random_walk_with_restart(
+            &graph,
+            &restarts,
+            restart_prob,
+            weighted,
+            iterations,
+            epsilon,
+            poison,
+        )?;
+        let total: f64 = scores.values().sum();
+        for (idx, score) in scores {
+            if score > 0. {
+                out.put(vec![
+                    indices[idx as usize].clone(),
+                    DataValue::from(score / total),
+                ]);
+            }
+        }
+        Ok(())
+    }
+
+    fn arity(
+        &self,
+        _options: &BTreeMap<SmartString<LazyCompact>, Expr>,
+        _rule_head: &[Symbol],
+        _span: SourceSpan,
+    ) -> Result<usize> {
+        Ok(2)
+    }
+}
+
+/// Iterates the scores from `restarts` until their total change is below `epsilon`,
+/// or `iterations` times. The walker also restarts from nodes without out-edges.
+fn random_walk_with_restart(
+    graph: &DirectedCsrGraph<u32, (), f32>,
+    restarts: &BTreeMap<u32, f64>,
+    restart_prob: f64,
+    weighted: bool,
+    iterations: usize,
+    epsilon: f64,
+    poison: Poison,
+) -> Result<BTreeMap<u32, f64>> {
+    let edge_weight = |weight: f32| if weighted { weight as f64 } else { 1. };
+    let mut scores = restarts.clone();
+    for _ in 0..iterations {
+        let mut next_scores: BTreeMap<u32, f64> = BTreeMap::new();
+        let mut restarting = restart_prob;
+        for (node, score) in &scores {
+            let out_weight: f64 = graph
+                .out_neighbors_with_values(*node)
+                .map(|target| edge_weight(target.value))
+                .sum();
+            if out_weight <= 0. {
+                restarting += (1. - restart_prob) * score;
+                continue;
+            }
+            let moving = (1. - restart_prob) * score / out_weight;
+            for target in graph.out_neighbors_with_values(*node) {
+                *next_scores.entry(target.target).or_default() +=
+                    moving * edge_weight(target.value);
+            }
+        }
+        for (node, prob) in restarts {
+            *next_scores.entry(*node).or_default() += restarting * prob;
+        }
+
+        let mut change: f64 = next_scores
+            .iter()
+            .map(|(node, score)| (score - scores.get(node).unwrap_or(&0.)).abs())
+            .sum();
+        change += scores
+            .iter()
+            .filter(|(node, _)| !next_scores.contains_key(node))
+            .map(|(_, score)| score)
+            .sum::<f64>();
+        scores = next_scores;
+        if change < epsilon {
+            break;
+        }
+        poison.check()?;
+    }
+    Ok(scores)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::new_cozo_mem;
+
+    fn scores(edges: &str, options: &str) -> BTreeMap<String, f64> {
+        let db = new_cozo_mem().unwrap();
+        db.run_script(
+            &format!(
+                "{edges}
+                seeds[] <- [['a']]
+                ?[n, s] <~ RandomWalkWithRestart(edges[], seeds[]{options})"
+            ),
+            Default::default(),
+        )
+        .unwrap()
+        .rows
+        .into_iter()
+        .map(|row| {
+            (
+                row[0].get_str().unwrap().to_string(),
+                row[1].get_float().unwrap(),
+            )
+        })
+        .collect()
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-6, "{a} != {b}");
+    }
+
+    #[test]
+    fn converges() {
+        // `d` cannot be reached from the seed
+        let res = scores(
+            "edges[f, t] <- [['a', 'b'], ['b', 'a'], ['b', 'c'], ['c', 'a'], ['d', 'a']]",
+            ", iterations: 1000, epsilon: 0.",
+        );
+        assert_eq!(res.len(), 3);
+        // solving the equations of the stationary scores
+        let a = 0.15 / (1. - 0.85 * (0.85 / 2. + 0.85 * 0.85 / 2.));
+        assert_close(res["a"], a);
+        assert_close(res["b"], 0.85 * a);
+        assert_close(res["c"], 0.85 * 0.85 / 2. * a);
+
+        // stopping early changes the scores only a little
+        let early = scores(
+            "edges[f, t] <- [['a', 'b'], ['b', 'a'], ['b', 'c'], ['c', 'a'], ['d', 'a']]",
+            ", epsilon: 0.001",
+        );
+        for (node, score) in &res {
+            assert!((early[node] - score).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn weights_and_dangling_nodes() {
+        // `d` has no out-edges
+        let edges = "edges[f, t, w] <- [['a', 'b', 3], ['a', 'c', 1], ['b', 'a', 1],
+                                         ['c', 'd', 1]]";
+        let res = scores(edges, "");
+        assert_close(res["b"], res["c"]);
+        assert_close(res.values().sum(), 1.);
+
+        let res = scores(edges, ", weighted: true, restart_prob: 0.5, epsilon: 0.");
+        assert_close(res["b"], 3. * res["c"]);
+        assert_close(res["d"], 0.5 * res["c"]);
+        assert_close(res.values().sum(), 1.);
+    }
+}
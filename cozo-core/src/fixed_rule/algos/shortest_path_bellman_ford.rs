@@ -0,0 +1,344 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use graph::prelude::{DirectedCsrGraph, DirectedNeighborsWithValues, Graph};
+use itertools::Itertools;
+use miette::{bail, Diagnostic, Result};
+use smartstring::{LazyCompact, SmartString};
+use thiserror::Error;
+
+use crate::data::expr::Expr;
+use crate::data::symb::Symbol;
+use crate::data::value::DataValue;
+use crate::fixed_rule::algos::shortest_path_dijkstra::Goal;
+use crate::fixed_rule::{FixedRule, FixedRulePayload};
+use crate::parse::SourceSpan;
+use crate::runtime::db::Poison;
+use crate::runtime::temp_store::RegularTempStore;
+
+/// Single-source shortest paths allowing negative edge weights, with the same input and
+/// output as [ShortestPathDijkstra](super::ShortestPathDijkstra).
+///
+/// A negative cycle reachable from a starting node is an error, unless `report_cycles`
+/// is set: then the rows for that starting node are the nodes of the cycle instead,
+/// with the total weight of the cycle and the cycle from the node back to itself.
+pub(crate) struct ShortestPathBellmanFord;
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("The graph has a negative cycle reachable from the starting node {0:?}")]
+#[diagnostic(code(algo::negative_cycle))]
+#[diagnostic(help("Use the option `report_cycles: true` to return the cycle instead"))]
+struct NegativeCycleError(DataValue, #[label] SourceSpan);
+
+impl FixedRule for ShortestPathBellmanFord {
+    fn run(
+        &self,
+        payload: FixedRulePayload<'_, '_>,
+        out: &mut RegularTempStore,
+        poison: Poison,
+    ) -> Result<()> {
+        let edges = payload.get_input(0)?;
+        let starting = payload.get_input(1)?;
+        let termination = payload.get_input(2);
+        let undirected = payload.bool_option("undirected", Some(false))?;
+        let report_cycles = payload.bool_option("report_cycles", Some(false))?;
+
+        let (graph, indices, inv_indices) = edges.as_directed_weighted_graph(undirected, true)?;
+
+        let mut starting_nodes = BTreeSet::new();
+        for tuple in starting.iter()? {
+            let tuple = tuple?;
+            let node = &tuple[0];
+            if let Some(idx) = inv_indices.get(node) {
+                starting_nodes.insert(*idx);
+            }
+        }
+        let termination_nodes = match termination {
+            Err(_) => None,
+            Ok(t) => {
+                let mut tn = BTreeSet::new();
+                for tuple in t.iter()? {
+                    let tuple = tuple?;
+                    let node = &tuple[0];
+                    if let Some(idx) = inv_indices.get(node) {
+                        tn.insert(*idx);
+                    }
+                }
+                Some(tn)
+            }
+        };
+
+        let to_path = |path: Vec<u32>| {
+            DataValue::List(
+                path.into_iter()
+                    .map(|u| indices[u as usize].clone())
+                    .collect_vec(),
+            )
+        };
+        for start in starting_nodes {
+            let res = match &termination_nodes {
+                None => bellman_ford(&graph, start, &(), poison.clone())?,
+                Some(tn) => bellman_ford(&graph, start, tn, poison.clone())?,
+            };
+            match res {
+                ShortestPaths::Paths(paths) => {
+                    for (target, cost, path) in paths {
+                        out.put(vec![
+                            indices[start as usize].clone(),
+                            indices[target as usize].clone(),
+                            DataValue::from(cost as f64),
+                            to_path(path),
+                        ])
+                    }
+                }
+                ShortestPaths::NegativeCycle(cycle) => {
+                    if !report_cycles {
+                        bail!(NegativeCycleError(
+                            indices[start as usize].clone(),
+                            payload.span()
+                        ))
+                    }
+                    let cost: f32 = cycle
+                        .iter()
+                        .zip(cycle.iter().cycle().skip(1))
+                        .map(|(from, to)| edge_weight(&graph, *from, *to))
+                        .sum();
+                    for i in 0..cycle.len() {
+                        let mut path = cycle[i..].to_vec();
+                        path.extend_from_slice(&cycle[..=i]);
+                        out.put(vec![
+                            indices[start as usize].clone(),
+                            indices[cycle[i] as usize].clone(),
+                            DataValue::from(cost as f64),
+                            to_path(path),
+                        ])
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn arity(
+        &self,
+        _options: &BTreeMap<SmartString<LazyCompact>, Expr>,
+        _rule_head: &[Symbol],
+        _span: SourceSpan,
+    ) -> Result<usize> {
+        Ok(4)
+    }
+}
+
+enum ShortestPaths {
+    /// The targets with their costs and paths, unreachable ones with infinite costs
+    Paths(Vec<(u32, f32, Vec<u32>)>),
+    /// The nodes of a negative cycle, in the order of its edges
+    NegativeCycle(Vec<u32>),
+}
+
+/// The weight of the cheapest of the edges from `from` to `to`
+fn edge_weight(edges: &DirectedCsrGraph<u32, (), f32>, from: u32, to: u32) -> f32 {
+    edges
+        .out_neighbors_with_values(from)
+        .filter(|target| target.target == to)
+        .map(|target| target.value)
+        .fold(f32::INFINITY, f32::min)
+}
+
+fn bellman_ford<G: Goal>(
+    edges: &DirectedCsrGraph<u32, (), f32>,
+    start: u32,
+    goals: &G,
+    poison: Poison,
+) -> Result<ShortestPaths> {
+    let graph_size = edges.node_count() as usize;
+    let mut distance = vec![f32::INFINITY; graph_size];
+    let mut back_pointers = vec![u32::MAX; graph_size];
+    distance[start as usize] = 0.;
+
+    // shortest paths have fewer than `graph_size` edges, so edges can still be relaxed
+    // in the last round only if there is a negative cycle
+    let mut last_relaxed = None;
+    for _ in 0..graph_size {
+        last_relaxed = None;
+        for node in 0..graph_size as u32 {
+            let cost = distance[node as usize];
+            if !cost.is_finite() {
+                continue;
+            }
+            for target in edges.out_neighbors_with_values(node) {
+                let nxt_node = target.target;
+                let nxt_cost = cost + target.value;
+                if nxt_cost < distance[nxt_node as usize] {
+                    distance[nxt_node as usize] = nxt_cost;
+                    back_pointers[nxt_node as usize] = node;
+                    last_relaxed = Some(nxt_node);
+                }
+            }
+        }
+        if last_relaxed.is_none() {
+            break;
+        }
+        poison.check()?;
+    }
+
+    if let Some(relaxed) = last_relaxed {
+        // going back as many steps as there are nodes surely ends up on the cycle
+        let mut on_cycle = relaxed;
+        for _ in 0..graph_size {
+            on_cycle = back_pointers[on_cycle as usize];
+        }
+        let mut cycle = vec![on_cycle];
+        let mut current = back_pointers[on_cycle as usize];
+        while current != on_cycle {
+            cycle.push(current);
+            current = back_pointers[current as usize];
+        }
+        cycle.reverse();
+        return Ok(ShortestPaths::NegativeCycle(cycle));
+    }
+
+    let paths = goals
+        .iter(graph_size as u32)
+        .map(|target| {
+            let cost = distance[target as usize];
+            if !cost.is_finite() {
+                (target, cost, vec![])
+            } else {
+                let mut path = vec![];
+                let mut current = target;
+                while current != start {
+                    path.push(current);
+                    current = back_pointers[current as usize];
+                }
+                path.push(start);
+                path.reverse();
+                (target, cost, path)
+            }
+        })
+        .collect_vec();
+    Ok(ShortestPaths::Paths(paths))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::value::DataValue;
+    use crate::new_cozo_mem;
+
+    fn row(target: &str, cost: f64, path: &str) -> (String, f64, String) {
+        (target.to_string(), cost, path.to_string())
+    }
+
+    const EDGES: &str = "edges[f, t, c] <- [['a', 'b', 4], ['a', 'c', 2], ['c', 'b', -3],
+                                                ['b', 'd', 2], ['e', 'a', 1]]";
+
+    #[test]
+    fn test_bellman_ford() {
+        let db = new_cozo_mem().unwrap();
+        let run = |edges: &str, query: &str| {
+            db.run_script(&format!("{edges}\n{query}"), Default::default())
+                .map(|res| {
+                    res.rows
+                        .into_iter()
+                        .map(|row| {
+                            let path = row[3]
+                                .get_slice()
+                                .unwrap()
+                                .iter()
+                                .map(|node| node.get_str().unwrap())
+                                .collect::<String>();
+                            (
+                                row[1].get_str().unwrap().to_string(),
+                                row[2].get_float().unwrap(),
+                                path,
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                })
+        };
+
+        let res = run(
+            EDGES,
+            "start[] <- [['a']]
+            ?[s, t, c, p] <~ ShortestPathBellmanFord(edges[], start[])",
+        )
+        .unwrap();
+        assert_eq!(res.len(), 5);
+        assert_eq!(res[0], row("a", 0., "a"));
+        assert_eq!(res[1], row("b", -1., "acb"));
+        assert_eq!(res[2], row("c", 2., "ac"));
+        assert_eq!(res[3], row("d", 1., "acbd"));
+        // `e` cannot be reached
+        assert_eq!(res[4].0, "e");
+        assert!(res[4].1.is_infinite());
+        assert_eq!(res[4].2, "");
+
+        let res = run(
+            EDGES,
+            "start[] <- [['a']]
+            goal[] <- [['d']]
+            ?[s, t, c, p] <~ ShortestPathBellmanFord(edges[], start[], goal[])",
+        )
+        .unwrap();
+        assert_eq!(res, [row("d", 1., "acbd")]);
+
+        // the cycle `b -> c -> b` has the weight -2
+        let with_cycle = "edges[f, t, c] <- [['a', 'b', 4], ['a', 'c', 2], ['c', 'b', -3],
+                                              ['b', 'c', 1], ['b', 'd', 2]]";
+        let err = run(
+            with_cycle,
+            "start[] <- [['a']]
+            ?[s, t, c, p] <~ ShortestPathBellmanFord(edges[], start[])",
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("negative cycle"));
+        let res = run(
+            with_cycle,
+            "start[] <- [['a']]
+            ?[s, t, c, p] <~ ShortestPathBellmanFord(edges[], start[], report_cycles: true)",
+        )
+        .unwrap();
+        assert_eq!(res, [row("b", -2., "bcb"), row("c", -2., "cbc")]);
+        // the cycle cannot be reached from `d`
+        let res = run(
+            with_cycle,
+            "start[] <- [['d']]
+            goal[] <- [['d']]
+            ?[s, t, c, p] <~ ShortestPathBellmanFord(edges[], start[], goal[])",
+        )
+        .unwrap();
+        assert_eq!(res, [row("d", 0., "d")]);
+    }
+
+    #[test]
+    fn test_bellman_ford_matches_dijkstra() {
+        let db = new_cozo_mem().unwrap();
+        let run = |rule: &str| {
+            db.run_script(
+                &format!(
+                    "edges[f, t, c] <- [['a', 'b', 4], ['a', 'c', 2], ['c', 'b', 1],
+                                        ['b', 'd', 2], ['e', 'a', 1], ['d', 'a', 3]]
+                    start[] <- [['a'], ['d']]
+                    ?[s, t, c, p] <~ {rule}(edges[], start[], undirected: true)"
+                ),
+                Default::default(),
+            )
+            .unwrap()
+            .rows
+        };
+        let rows = run("ShortestPathBellmanFord");
+        assert_eq!(rows.len(), 10);
+        assert_eq!(rows, run("ShortestPathDijkstra"));
+        assert!(rows
+            .iter()
+            .all(|row| row[2] != DataValue::from(f64::INFINITY)));
+    }
+}
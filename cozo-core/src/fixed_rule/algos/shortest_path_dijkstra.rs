@@ -41,6 +41,15 @@ impl FixedRule for ShortestPathDijkstra {
         let termination = payload.get_input(2);
         let undirected = payload.bool_option("undirected", Some(false))?;
         let keep_ties = payload.bool_option("keep_ties", Some(false))?;
+        // only the `k` cheapest paths from each starting node are returned, 0 for all of them
+        let k = payload.non_neg_integer_option("k", Some(0))?;
+        let cheapest = |mut res: Vec<(u32, f32, Vec<u32>)>| {
+            if k > 0 {
+                res.sort_by(|a, b| a.1.total_cmp(&b.1));
+                res.truncate(k);
+            }
+            res
+        };
 
         let (graph, indices, inv_indices) = edges.as_directed_weighted_graph(undirected, false)?;
 
@@ -85,7 +94,7 @@ impl FixedRule for ShortestPathDijkstra {
                 } else {
                     dijkstra(&graph, start, &(), &(), &())
                 };
-                for (target, cost, path) in res {
+                for (target, cost, path) in cheapest(res) {
                     let t = vec![
                         indices[start as usize].clone(),
                         indices[target as usize].clone(),
@@ -133,7 +142,7 @@ impl FixedRule for ShortestPathDijkstra {
                 })
                 .collect::<Result<_>>()?;
             for (start, res) in all_res {
-                for (target, cost, path) in res {
+                for (target, cost, path) in cheapest(res) {
                     let t = vec![
                         indices[start as usize].clone(),
                         indices[target as usize].clone(),
@@ -0,0 +1,202 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::BTreeMap;
+
+use graph::prelude::{DirectedNeighborsWithValues, Graph};
+use miette::{ensure, Diagnostic, Result};
+use smartstring::{LazyCompact, SmartString};
+use thiserror::Error;
+
+use crate::data::expr::Expr;
+use crate::data::symb::Symbol;
+use crate::data::value::DataValue;
+use crate::fixed_rule::{FixedRule, FixedRuleOptions, FixedRulePayload};
+use crate::parse::SourceSpan;
+use crate::runtime::db::Poison;
+use crate::runtime::temp_store::RegularTempStore;
+
+/// The default of the `node_limit` option
+const DEFAULT_NODE_LIMIT: usize = 5000;
+
+/// All-pairs shortest paths, with a row `(from, to, cost)` for each pair of nodes with a
+/// path between them, also with the `path` when `with_path` is set.
+///
+/// The time taken grows as the cube of the number of nodes and the memory used as the square,
+/// so graphs with more nodes than `node_limit` are refused.
+pub(crate) struct ShortestPathFloydWarshall;
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("The graph has {0} nodes, more than the limit of {1} for all-pairs shortest paths")]
+#[diagnostic(code(algo::too_many_nodes))]
+#[diagnostic(help(
+    "Raise the limit with the option `node_limit`, or use ShortestPathDijkstra from the nodes needed"
+))]
+struct TooManyNodesError(usize, usize, #[label] SourceSpan);
+
+impl FixedRule for ShortestPathFloydWarshall {
+    fn run(
+        &self,
+        payload: FixedRulePayload<'_, '_>,
+        out: &mut RegularTempStore,
+        poison: Poison,
+    ) -> Result<()> {
+        let edges = payload.get_input(0)?;
+        let undirected = payload.bool_option("undirected", Some(false))?;
+        let with_path = payload.bool_option("with_path", Some(false))?;
+        let node_limit = payload.pos_integer_option("node_limit", Some(DEFAULT_NODE_LIMIT))?;
+
+        let (graph, indices, _inv_indices) = edges.as_directed_weighted_graph(undirected, false)?;
+        let n = graph.node_count() as usize;
+        ensure!(
+            n <= node_limit,
+            TooManyNodesError(n, node_limit, payload.span())
+        );
+
+        // `next[i * n + j]` is the node after `i` on the shortest path from `i` to `j`
+        let mut distance = vec![f32::INFINITY; n * n];
+        let mut next = if with_path {
+            vec![u32::MAX; n * n]
+        } else {
+            vec![]
+        };
+        for from in 0..n {
+            distance[from * n + from] = 0.;
+            if with_path {
+                next[from * n + from] = from as u32;
+            }
+            for target in graph.out_neighbors_with_values(from as u32) {
+                let to = target.target as usize;
+                if target.value < distance[from * n + to] {
+                    distance[from * n + to] = target.value;
+                    if with_path {
+                        next[from * n + to] = to as u32;
+                    }
+                }
+            }
+        }
+        for via in 0..n {
+            poison.check()?;
+            for from in 0..n {
+                let to_via = distance[from * n + via];
+                if !to_via.is_finite() {
+                    continue;
+                }
+                for to in 0..n {
+                    let cost = to_via + distance[via * n + to];
+                    if cost < distance[from * n + to] {
+                        distance[from * n + to] = cost;
+                        if with_path {
+                            next[from * n + to] = next[from * n + via];
+                        }
+                    }
+                }
+            }
+        }
+
+        for from in 0..n {
+            for to in 0..n {
+                let cost = distance[from * n + to];
+                if !cost.is_finite() {
+                    continue;
+                }
+                let mut row = vec![
+                    indices[from].clone(),
+                    indices[to].clone(),
+                    DataValue::from(cost as f64),
+                ];
+                if with_path {
+                    let mut path = vec![indices[from].clone()];
+                    let mut current = from;
+                    while current != to {
+                        current = next[current * n + to] as usize;
+                        path.push(indices[current].clone());
+                    }
+                    row.push(DataValue::List(path));
+                }
+                out.put(row);
+            }
+        }
+        Ok(())
+    }
+
+    fn arity(
+        &self,
+        options: &BTreeMap<SmartString<LazyCompact>, Expr>,
+        _rule_head: &[Symbol],
+        span: SourceSpan,
+    ) -> Result<usize> {
+        let options = FixedRuleOptions::new(options, "ShortestPathFloydWarshall", span);
+        Ok(if options.bool_option("with_path", Some(false))? {
+            4
+        } else {
+            3
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+
+    use crate::data::value::DataValue;
+    use crate::new_cozo_mem;
+
+    // the weights are distinct powers of two, so that no two paths cost the same
+    const GRAPH: &str = "
+        edges[f, t, c] <- [[1, 2, 1], [2, 3, 2], [1, 3, 8], [3, 4, 4], [4, 1, 16], [2, 5, 32],
+                           [5, 6, 64], [6, 2, 128], [4, 6, 256], [7, 8, 512], [8, 7, 1024]]
+        nodes[n] := edges[n, _, _]
+        nodes[n] := edges[_, n, _]
+    ";
+
+    #[test]
+    fn test_floyd_warshall() {
+        let db = new_cozo_mem().unwrap();
+        let run = |query: &str| {
+            db.run_script(&format!("{GRAPH}{query}"), Default::default())
+                .map(|res| res.rows)
+        };
+
+        let dijkstra = run("?[s, t, c, p] <~ ShortestPathDijkstra(edges[], nodes[])")
+            .unwrap()
+            .into_iter()
+            .filter(|row| row[2].get_float().unwrap().is_finite())
+            .collect_vec();
+        // 6 nodes reaching each other, and 2 others reaching each other
+        assert_eq!(dijkstra.len(), 6 * 6 + 2 * 2);
+        let floyd_warshall =
+            run("?[s, t, c, p] <~ ShortestPathFloydWarshall(edges[], with_path: true)").unwrap();
+        assert_eq!(floyd_warshall, dijkstra);
+        let costs = run("?[s, t, c] <~ ShortestPathFloydWarshall(edges[], node_limit: 8)").unwrap();
+        assert_eq!(
+            costs,
+            dijkstra.iter().map(|row| row[..3].to_vec()).collect_vec()
+        );
+
+        let undirected = "<~ ShortestPathDijkstra(edges[], nodes[], undirected: true)";
+        let dijkstra = run(&format!("?[s, t, c, p] {undirected}"))
+            .unwrap()
+            .into_iter()
+            .filter(|row| row[2].get_float().unwrap().is_finite())
+            .collect_vec();
+        let floyd_warshall = run(
+            "?[s, t, c, p] <~ ShortestPathFloydWarshall(edges[], with_path: true, undirected: true)",
+        )
+        .unwrap();
+        assert_eq!(floyd_warshall, dijkstra);
+        assert_eq!(
+            floyd_warshall[0][3],
+            DataValue::List(vec![DataValue::from(1)])
+        );
+
+        let err =
+            run("?[s, t, c] <~ ShortestPathFloydWarshall(edges[], node_limit: 7)").unwrap_err();
+        assert!(err.to_string().contains("8 nodes"));
+    }
+}
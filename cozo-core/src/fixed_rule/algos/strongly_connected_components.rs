@@ -12,7 +12,7 @@ use std::cmp::min;
 use std::collections::BTreeMap;
 
 use itertools::Itertools;
-use miette::Result;
+use miette::{bail, Result};
 use smartstring::{LazyCompact, SmartString};
 
 use crate::data::expr::Expr;
@@ -20,7 +20,7 @@ use crate::data::program::{MagicFixedRuleApply, MagicSymbol};
 use crate::data::symb::Symbol;
 use crate::data::tuple::Tuple;
 use crate::data::value::DataValue;
-use crate::fixed_rule::{FixedRule, FixedRulePayload};
+use crate::fixed_rule::{FixedRule, FixedRuleInputRelation, FixedRulePayload, NotAnEdgeError};
 use crate::parse::SourceSpan;
 use crate::runtime::db::Poison;
 use crate::runtime::temp_store::{EpochStore, RegularTempStore};
@@ -46,19 +46,23 @@ impl FixedRule for StronglyConnectedComponent {
         poison: Poison,
     ) -> Result<()> {
         let edges = payload.get_input(0)?;
+        let min_size = payload.pos_integer_option("min_size", Some(1))?;
 
-        let (graph, indices, mut inv_indices) = edges.as_directed_graph(!self.strong)?;
-
-        let tarjan = TarjanSccG::new(graph).run(poison)?;
-        for (grp_id, cc) in tarjan.iter().enumerate() {
-            for idx in cc {
-                let val = indices.get(*idx as usize).unwrap();
-                let tuple = vec![val.clone(), DataValue::from(grp_id as i64)];
-                out.put(tuple);
-            }
-        }
-
-        let mut counter = tarjan.len() as i64;
+        let (mut components, mut inv_indices) = if self.strong {
+            let (graph, indices, inv_indices) = edges.as_directed_graph(false)?;
+            let components = TarjanSccG::new(graph)
+                .run(poison)?
+                .into_iter()
+                .map(|cc| {
+                    cc.into_iter()
+                        .map(|idx| indices[idx as usize].clone())
+                        .collect_vec()
+                })
+                .collect_vec();
+            (components, inv_indices)
+        } else {
+            weak_components(&edges, poison)?
+        };
 
         if let Ok(nodes) = payload.get_input(1) {
             for tuple in nodes.iter()? {
@@ -66,13 +70,23 @@ impl FixedRule for StronglyConnectedComponent {
                 let node = tuple.into_iter().next().unwrap();
                 if !inv_indices.contains_key(&node) {
                     inv_indices.insert(node.clone(), u32::MAX);
-                    let tuple = vec![node, DataValue::from(counter)];
-                    out.put(tuple);
-                    counter += 1;
+                    components.push(vec![node]);
                 }
             }
         }
 
+        // components are numbered in the order of their smallest nodes, so the numbers
+        // do not depend on the order the graph is traversed in, nor on `min_size`
+        components.sort_by_cached_key(|cc| cc.iter().min().cloned());
+        for (grp_id, cc) in components.into_iter().enumerate() {
+            if cc.len() < min_size {
+                continue;
+            }
+            for node in cc {
+                out.put(vec![node, DataValue::from(grp_id as i64)]);
+            }
+        }
+
         Ok(())
     }
 
@@ -86,6 +100,83 @@ impl FixedRule for StronglyConnectedComponent {
     }
 }
 
+/// The connected components of the graph ignoring the directions of the edges, joining
+/// the nodes as the edges are read
+fn weak_components(
+    edges: &FixedRuleInputRelation<'_, '_>,
+    poison: Poison,
+) -> Result<(Vec<Vec<DataValue>>, BTreeMap<DataValue, u32>)> {
+    let mut sets = UnionFind::default();
+    for tuple in edges.iter()? {
+        let mut tuple = tuple?.into_iter();
+        let (from, to) = match (tuple.next(), tuple.next()) {
+            (Some(from), Some(to)) => (from, to),
+            _ => bail!(NotAnEdgeError(edges.span())),
+        };
+        let from = sets.index(from);
+        let to = sets.index(to);
+        sets.union(from, to);
+        poison.check()?;
+    }
+    Ok(sets.into_components())
+}
+
+/// Disjoint sets of nodes, the smaller set joining the larger one, with path halving
+#[derive(Default)]
+struct UnionFind {
+    nodes: Vec<DataValue>,
+    indices: BTreeMap<DataValue, u32>,
+    parents: Vec<u32>,
+    sizes: Vec<u32>,
+}
+
+impl UnionFind {
+    /// The index of `node`, in a new set of its own if not seen before
+    fn index(&mut self, node: DataValue) -> u32 {
+        if let Some(idx) = self.indices.get(&node) {
+            return *idx;
+        }
+        let idx = self.nodes.len() as u32;
+        self.indices.insert(node.clone(), idx);
+        self.nodes.push(node);
+        self.parents.push(idx);
+        self.sizes.push(1);
+        idx
+    }
+    fn find(&mut self, mut idx: u32) -> u32 {
+        while self.parents[idx as usize] != idx {
+            let grandparent = self.parents[self.parents[idx as usize] as usize];
+            self.parents[idx as usize] = grandparent;
+            idx = grandparent;
+        }
+        idx
+    }
+    fn union(&mut self, a: u32, b: u32) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return;
+        }
+        let (larger, smaller) = if self.sizes[a as usize] >= self.sizes[b as usize] {
+            (a, b)
+        } else {
+            (b, a)
+        };
+        self.parents[smaller as usize] = larger;
+        self.sizes[larger as usize] += self.sizes[smaller as usize];
+    }
+    fn into_components(mut self) -> (Vec<Vec<DataValue>>, BTreeMap<DataValue, u32>) {
+        let mut components: BTreeMap<u32, Vec<DataValue>> = BTreeMap::new();
+        for idx in 0..self.nodes.len() as u32 {
+            let root = self.find(idx);
+            components
+                .entry(root)
+                .or_default()
+                .push(self.nodes[idx as usize].clone());
+        }
+        (components.into_values().collect_vec(), self.indices)
+    }
+}
+
 pub(crate) struct TarjanSccG {
     graph: DirectedCsrGraph<u32>,
     id: u32,
@@ -122,28 +213,123 @@ impl TarjanSccG {
 
         Ok(low_map.into_values().collect_vec())
     }
-    fn dfs(&mut self, at: u32) {
-        self.stack.push(at);
-        self.on_stack[at as usize] = true;
-        self.id += 1;
-        self.ids[at as usize] = Some(self.id);
-        self.low[at as usize] = self.id;
-        for to in self.graph.out_neighbors(at).cloned().collect_vec() {
-            if self.ids[to as usize].is_none() {
-                self.dfs(to);
+    /// Visits the nodes reachable from `root` with an explicit stack instead of recursion,
+    /// so that long paths cannot overflow the call stack
+    fn dfs(&mut self, root: u32) {
+        let mut frames = vec![self.visit(root)];
+        while let Some((at, neighbors)) = frames.last_mut() {
+            let at = *at;
+            if let Some(to) = neighbors.next() {
+                if self.ids[to as usize].is_none() {
+                    frames.push(self.visit(to));
+                } else if self.on_stack[to as usize] {
+                    self.low[at as usize] = min(self.low[at as usize], self.low[to as usize]);
+                }
+                continue;
             }
-            if self.on_stack[to as usize] {
-                self.low[at as usize] = min(self.low[at as usize], self.low[to as usize]);
+            frames.pop();
+            if self.ids[at as usize].unwrap() == self.low[at as usize] {
+                while let Some(node) = self.stack.pop() {
+                    self.on_stack[node as usize] = false;
+                    self.low[node as usize] = self.ids[at as usize].unwrap();
+                    if node == at {
+                        break;
+                    }
+                }
             }
-        }
-        if self.ids[at as usize].unwrap() == self.low[at as usize] {
-            while let Some(node) = self.stack.pop() {
-                self.on_stack[node as usize] = false;
-                self.low[node as usize] = self.ids[at as usize].unwrap();
-                if node == at {
-                    break;
+            if let Some((parent, _)) = frames.last() {
+                if self.on_stack[at as usize] {
+                    let parent = *parent as usize;
+                    self.low[parent] = min(self.low[parent], self.low[at as usize]);
                 }
             }
         }
     }
+    /// Numbers `at` and puts it on the stack, returning it with the neighbours to visit
+    fn visit(&mut self, at: u32) -> (u32, std::vec::IntoIter<u32>) {
+        self.stack.push(at);
+        self.on_stack[at as usize] = true;
+        self.id += 1;
+        self.ids[at as usize] = Some(self.id);
+        self.low[at as usize] = self.id;
+        (
+            at,
+            self.graph
+                .out_neighbors(at)
+                .cloned()
+                .collect_vec()
+                .into_iter(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::new_cozo_mem;
+
+    // `a` has a self-loop, the cycle `c -> e -> c` is nested in `b -> c -> d -> b`,
+    // `h` is only reached and `z` is only in the nodes
+    const GRAPH: &str = "
+        edges[f, t] <- [['a', 'a'], ['a', 'b'], ['b', 'c'], ['c', 'd'], ['d', 'b'], ['c', 'e'],
+                        ['e', 'c'], ['d', 'f'], ['f', 'g'], ['g', 'f'], ['g', 'h']]
+        nodes[n] <- [['a'], ['h'], ['z']]
+    ";
+
+    #[test]
+    fn test_scc() {
+        let db = new_cozo_mem().unwrap();
+        let run = |query: &str| {
+            db.run_script(&format!("{GRAPH}{query}"), Default::default())
+                .unwrap()
+                .rows
+                .into_iter()
+                .map(|row| {
+                    (
+                        row[0].get_str().unwrap().to_string(),
+                        row[1].get_int().unwrap(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let expected = |groups: &[(&str, i64)]| {
+            groups
+                .iter()
+                .flat_map(|(nodes, grp)| nodes.chars().map(|node| (node.to_string(), *grp)))
+                .collect::<Vec<_>>()
+        };
+
+        let res = run("?[n, c] <~ StronglyConnectedComponents(edges[], nodes[])");
+        assert_eq!(
+            res,
+            expected(&[("a", 0), ("bcde", 1), ("fg", 2), ("h", 3), ("z", 4)])
+        );
+        let res = run("?[n, c] <~ SCC(edges[], nodes[], min_size: 2)");
+        assert_eq!(res, expected(&[("bcde", 1), ("fg", 2)]));
+        let res = run("?[n, c] <~ SCC(edges[])");
+        assert_eq!(res, expected(&[("a", 0), ("bcde", 1), ("fg", 2), ("h", 3)]));
+        let res = run("?[n, c] <~ ConnectedComponents(edges[], nodes[])");
+        assert_eq!(res, expected(&[("abcdefgh", 0), ("z", 1)]));
+        let res = run("?[n, c] <~ ConnectedComponents(edges[], nodes[], min_size: 2)");
+        assert_eq!(res, expected(&[("abcdefgh", 0)]));
+    }
+
+    #[test]
+    fn test_connected_components_of_long_chains() {
+        let db = new_cozo_mem().unwrap();
+        // two chains of 5000 nodes, one going up through the even numbers and one going
+        // down through the odd numbers
+        let res = db
+            .run_script(
+                "edges[f, t] := i in int_range(4999), f = 2 * i, t = f + 2
+                edges[f, t] := i in int_range(4999), t = 2 * i + 1, f = t + 2
+                cc[n, c] <~ ConnectedComponents(edges[])
+                ?[c, count(n)] := cc[n, c]",
+                Default::default(),
+            )
+            .unwrap();
+        assert_eq!(
+            res.into_json()["rows"],
+            serde_json::json!([[0, 5000], [1, 5000]])
+        );
+    }
 }
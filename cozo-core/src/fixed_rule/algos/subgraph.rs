@@ -0,0 +1,380 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use miette::{bail, Diagnostic, Result};
+use rand::prelude::*;
+use smartstring::{LazyCompact, SmartString};
+use thiserror::Error;
+
+use crate::data::expr::Expr;
+use crate::data::program::WrongFixedRuleOptionError;
+use crate::data::symb::Symbol;
+use crate::data::value::DataValue;
+use crate::fixed_rule::{FixedRule, FixedRuleInputRelation, FixedRuleOptions, FixedRulePayload};
+use crate::parse::SourceSpan;
+use crate::runtime::db::Poison;
+use crate::runtime::temp_store::RegularTempStore;
+
+/// Samples a bounded subgraph around the seed nodes.
+///
+/// Nodes are sampled first, starting from the seeds and following the edges for at most `hops`
+/// steps, until `max_nodes` nodes are reached. Then at most `max_edges` edges between the
+/// sampled nodes are taken, those by which nodes were reached first. With `output: 'nodes'`
+/// (the default) the rows are the sampled nodes with the number of hops at which they were
+/// reached, and with `output: 'edges'` the sampled edges. Both are determined by the inputs
+/// and the options, so two invocations differing only in `output` describe the same sample.
+pub(crate) struct Subgraph;
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Strategy {
+    /// all nodes closest to the seeds
+    Bfs,
+    /// from each reached node, follow a random number of its edges
+    ForestFire,
+    /// repeatedly follow a random edge out of the reached nodes
+    RandomEdge,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Output {
+    Nodes,
+    Edges,
+}
+
+impl Subgraph {
+    fn strategy(options: FixedRuleOptions<'_>) -> Result<Strategy> {
+        let strategy = options.string_option("strategy", Some("bfs"))?;
+        Ok(match strategy.as_str() {
+            "bfs" => Strategy::Bfs,
+            "forest_fire" => Strategy::ForestFire,
+            "random_edge" => Strategy::RandomEdge,
+            _ => bail!(WrongFixedRuleOptionError {
+                name: "strategy".to_string(),
+                span: options.option_span("strategy")?,
+                rule_name: "Subgraph".to_string(),
+                help: "'strategy' must be one of 'bfs', 'forest_fire' or 'random_edge'".to_string(),
+            }),
+        })
+    }
+    fn output(options: FixedRuleOptions<'_>) -> Result<Output> {
+        let output = options.string_option("output", Some("nodes"))?;
+        Ok(match output.as_str() {
+            "nodes" => Output::Nodes,
+            "edges" => Output::Edges,
+            _ => bail!(WrongFixedRuleOptionError {
+                name: "output".to_string(),
+                span: options.option_span("output")?,
+                rule_name: "Subgraph".to_string(),
+                help: "'output' must be 'nodes' or 'edges'".to_string(),
+            }),
+        })
+    }
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("There are {0} seed nodes, more than 'max_nodes' of {1}")]
+#[diagnostic(code(algo::too_many_seeds))]
+#[diagnostic(help("All seed nodes are always included in the sample"))]
+struct TooManySeedsError(usize, usize, #[label] SourceSpan);
+
+/// The nodes sampled so far, with the numbers of hops at which they were reached
+struct Sample {
+    max_nodes: usize,
+    hops: BTreeMap<DataValue, usize>,
+    nodes: Vec<DataValue>,
+    /// the edges by which the nodes were reached
+    edges: Vec<(DataValue, DataValue)>,
+}
+
+impl Sample {
+    fn is_full(&self) -> bool {
+        self.nodes.len() >= self.max_nodes
+    }
+    fn contains(&self, node: &DataValue) -> bool {
+        self.hops.contains_key(node)
+    }
+    /// Adds the node reached from `from`, returning the number of hops to it
+    fn reach(&mut self, from: &DataValue, to: DataValue) -> usize {
+        let hops = self.hops[from] + 1;
+        self.hops.insert(to.clone(), hops);
+        self.edges.push((from.clone(), to.clone()));
+        self.nodes.push(to);
+        hops
+    }
+}
+
+fn targets(edges: FixedRuleInputRelation<'_, '_>, node: &DataValue) -> Result<Vec<DataValue>> {
+    let mut ret = vec![];
+    for edge in edges.prefix_iter(node)? {
+        let mut edge = edge?;
+        ret.push(edge.swap_remove(1));
+    }
+    Ok(ret)
+}
+
+impl FixedRule for Subgraph {
+    fn init_options(
+        &self,
+        options: &mut BTreeMap<SmartString<LazyCompact>, Expr>,
+        span: SourceSpan,
+    ) -> Result<()> {
+        let options = FixedRuleOptions::new(options, "Subgraph", span);
+        Self::strategy(options)?;
+        Self::output(options)?;
+        Ok(())
+    }
+
+    // nodes are only mutable keys to clippy for the regex a `DataValue` may hold
+    #[allow(clippy::mutable_key_type)]
+    fn run(
+        &self,
+        payload: FixedRulePayload<'_, '_>,
+        out: &mut RegularTempStore,
+        poison: Poison,
+    ) -> Result<()> {
+        let edges = payload.get_input(0)?.ensure_min_len(2)?;
+        let seeds = payload.get_input(1)?;
+        let strategy = Self::strategy(payload.options())?;
+        let output = Self::output(payload.options())?;
+        let max_nodes = payload.pos_integer_option("max_nodes", None)?;
+        let max_edges = payload.non_neg_integer_option("max_edges", Some(i64::MAX as usize))?;
+        let max_hops = payload.non_neg_integer_option("hops", Some(i64::MAX as usize))?;
+        let burn_probability = payload.unit_interval_option("burn_probability", Some(0.7))?;
+        let mut rng = StdRng::seed_from_u64(payload.integer_option("seed", Some(0))? as u64);
+
+        let mut sample = Sample {
+            max_nodes,
+            hops: Default::default(),
+            nodes: vec![],
+            edges: vec![],
+        };
+        for tuple in seeds.iter()? {
+            let node = tuple?.swap_remove(0);
+            if !sample.contains(&node) {
+                sample.hops.insert(node.clone(), 0);
+                sample.nodes.push(node);
+            }
+        }
+        if sample.nodes.len() > max_nodes {
+            bail!(TooManySeedsError(
+                sample.nodes.len(),
+                max_nodes,
+                seeds.span()
+            ))
+        }
+
+        match strategy {
+            Strategy::Bfs => {
+                let mut queue: VecDeque<DataValue> = sample.nodes.iter().cloned().collect();
+                'outer: while let Some(node) = queue.pop_front() {
+                    if sample.hops[&node] >= max_hops {
+                        continue;
+                    }
+                    for to in targets(edges, &node)? {
+                        if sample.contains(&to) {
+                            continue;
+                        }
+                        if sample.is_full() {
+                            break 'outer;
+                        }
+                        sample.reach(&node, to.clone());
+                        queue.push_back(to);
+                    }
+                    poison.check()?;
+                }
+            }
+            Strategy::ForestFire => {
+                let mut queue: VecDeque<DataValue> = sample.nodes.iter().cloned().collect();
+                while let Some(node) = queue.pop_front() {
+                    if sample.is_full() {
+                        break;
+                    }
+                    if sample.hops[&node] >= max_hops {
+                        continue;
+                    }
+                    let mut candidates = targets(edges, &node)?;
+                    let mut seen = BTreeSet::new();
+                    candidates.retain(|to| !sample.contains(to) && seen.insert(to.clone()));
+                    let mut n_burning = 0;
+                    while n_burning < candidates.len() && rng.gen_bool(burn_probability) {
+                        n_burning += 1;
+                    }
+                    let (burning, _) = candidates.partial_shuffle(&mut rng, n_burning);
+                    for to in burning.iter() {
+                        if sample.is_full() {
+                            break;
+                        }
+                        sample.reach(&node, to.clone());
+                        queue.push_back(to.clone());
+                    }
+                    poison.check()?;
+                }
+            }
+            Strategy::RandomEdge => {
+                let mut frontier = vec![];
+                if max_hops > 0 {
+                    for node in &sample.nodes {
+                        for to in targets(edges, node)? {
+                            frontier.push((node.clone(), to));
+                        }
+                    }
+                }
+                while !sample.is_full() && !frontier.is_empty() {
+                    let (from, to) = frontier.swap_remove(rng.gen_range(0..frontier.len()));
+                    if sample.contains(&to) {
+                        continue;
+                    }
+                    if sample.reach(&from, to.clone()) < max_hops {
+                        for next in targets(edges, &to)? {
+                            frontier.push((to.clone(), next));
+                        }
+                    }
+                    poison.check()?;
+                }
+            }
+        }
+
+        match output {
+            Output::Nodes => {
+                for node in sample.nodes {
+                    let hops = sample.hops[&node];
+                    out.put(vec![node, DataValue::from(hops as i64)]);
+                }
+            }
+            Output::Edges => {
+                let mut taken = BTreeSet::new();
+                for edge in &sample.edges {
+                    if taken.len() >= max_edges {
+                        break;
+                    }
+                    taken.insert(edge.clone());
+                }
+                'outer: for from in &sample.nodes {
+                    for to in targets(edges, from)? {
+                        if taken.len() >= max_edges {
+                            break 'outer;
+                        }
+                        if sample.contains(&to) {
+                            taken.insert((from.clone(), to));
+                        }
+                    }
+                    poison.check()?;
+                }
+                for (from, to) in taken {
+                    out.put(vec![from, to]);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn arity(
+        &self,
+        _options: &BTreeMap<SmartString<LazyCompact>, Expr>,
+        _rule_head: &[Symbol],
+        _span: SourceSpan,
+    ) -> Result<usize> {
+        Ok(2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use serde_json::json;
+
+    use crate::data::value::DataValue;
+    use crate::new_cozo_mem;
+
+    #[cfg(feature = "graph-algo")]
+    #[test]
+    fn test_subgraph_sampling() {
+        let db = new_cozo_mem().unwrap();
+        let known = r"edges[f, t] <- [['a', 'b'], ['a', 'c'], ['b', 'd'], ['c', 'd'], ['d', 'e'],
+                                  ['e', 'f'], ['x', 'y']]";
+        let run =
+            |seeds: &str, opts: &str| {
+                db.run_script(
+            &format!("{known}\nseeds[n] <- {seeds}\n?[a, b] <~ Subgraph(edges[], seeds[], {opts})"),
+            Default::default(),
+        )
+        .map(|res| res.into_json()["rows"].clone())
+            };
+
+        let res = run("[['a']]", "max_nodes: 100").unwrap();
+        assert_eq!(
+            res,
+            json!([["a", 0], ["b", 1], ["c", 1], ["d", 2], ["e", 3], ["f", 4]])
+        );
+        let res = run("[['a']]", "max_nodes: 100, hops: 2").unwrap();
+        assert_eq!(res, json!([["a", 0], ["b", 1], ["c", 1], ["d", 2]]));
+        let res = run("[['a']]", "max_nodes: 100, hops: 2, output: 'edges'").unwrap();
+        assert_eq!(res, json!([["a", "b"], ["a", "c"], ["b", "d"], ["c", "d"]]));
+        let res = run(
+            "[['a']]",
+            "max_nodes: 100, hops: 2, max_edges: 3, output: 'edges'",
+        )
+        .unwrap();
+        assert_eq!(res, json!([["a", "b"], ["a", "c"], ["b", "d"]]));
+        // seeds are expanded in order
+        let res = run("[['x'], ['a']]", "max_nodes: 3").unwrap();
+        assert_eq!(res, json!([["a", 0], ["b", 1], ["x", 0]]));
+        let res = run("[['x'], ['a']]", "max_nodes: 2").unwrap();
+        assert_eq!(res, json!([["a", 0], ["x", 0]]));
+        assert!(run("[['x'], ['a'], ['f']]", "max_nodes: 2").is_err());
+        assert!(run("[['a']]", "max_nodes: 2, strategy: 'dfs'").is_err());
+
+        // a larger graph for the random strategies
+        db.run_script(
+            r"?[f, t] := f in int_range(500), k in int_range(1, 6), t = (f * 7 + k * k) % 500
+          :create big_edges {f, t}",
+            Default::default(),
+        )
+        .unwrap();
+        let sample = |strategy: &str, seed: i64, output: &str| {
+            db.run_script(
+                &format!(
+                    r"seeds[n] <- [[0], [250], [499]]
+                  ?[a, b] <~ Subgraph(*big_edges[], seeds[], max_nodes: 60, max_edges: 80,
+                                      strategy: '{strategy}', seed: {seed}, output: '{output}')"
+                ),
+                Default::default(),
+            )
+            .unwrap()
+            .rows
+        };
+        for strategy in ["bfs", "forest_fire", "random_edge"] {
+            let nodes = sample(strategy, 1, "nodes");
+            let edges = sample(strategy, 1, "edges");
+            assert_eq!(nodes, sample(strategy, 1, "nodes"), "{strategy}");
+            assert_eq!(edges, sample(strategy, 1, "edges"), "{strategy}");
+            assert!(nodes.len() <= 60, "{strategy}");
+            assert!(edges.len() <= 80, "{strategy}");
+            let node_set: BTreeSet<_> = nodes.iter().map(|row| row[0].clone()).collect();
+            for seed in [0, 250, 499] {
+                assert!(
+                    nodes.contains(&vec![DataValue::from(seed), DataValue::from(0)]),
+                    "{strategy}"
+                );
+            }
+            for edge in &edges {
+                assert!(node_set.contains(&edge[0]), "{strategy}");
+                assert!(node_set.contains(&edge[1]), "{strategy}");
+            }
+        }
+        assert_eq!(sample("bfs", 1, "nodes").len(), 60);
+        assert!(sample("bfs", 1, "edges").len() >= 57);
+        assert_ne!(
+            sample("random_edge", 1, "nodes"),
+            sample("random_edge", 2, "nodes")
+        );
+    }
+}
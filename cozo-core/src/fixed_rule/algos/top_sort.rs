@@ -6,23 +6,79 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use graph::prelude::{DirectedCsrGraph, DirectedNeighbors, Graph};
-use std::collections::BTreeMap;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
 
-use miette::Result;
+use graph::prelude::{DirectedCsrGraph, DirectedNeighbors, Graph};
+use itertools::Itertools;
+use miette::{bail, Diagnostic, Result};
 use smartstring::{LazyCompact, SmartString};
+use thiserror::Error;
 
 use crate::data::expr::Expr;
+use crate::data::program::WrongFixedRuleOptionError;
 use crate::data::symb::Symbol;
 use crate::data::value::DataValue;
-use crate::fixed_rule::{FixedRule, FixedRulePayload};
+use crate::fixed_rule::algos::strongly_connected_components::TarjanSccG;
+use crate::fixed_rule::{FixedRule, FixedRuleOptions, FixedRulePayload};
 use crate::parse::SourceSpan;
 use crate::runtime::db::Poison;
 use crate::runtime::temp_store::RegularTempStore;
 
+/// Topological sort, with a row `(index, node)` for each node, the nodes with no order
+/// between them sorted by their values.
+///
+/// A cycle in the graph is an error, unless `on_cycle` is `'partial'`. Then the rows are
+/// `(index, node, on_cycle)`, where the nodes that cannot be sorted have a null index and
+/// `on_cycle` tells whether they are on a cycle or only after one.
 pub(crate) struct TopSort;
 
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum OnCycle {
+    Error,
+    Partial,
+}
+
+impl TopSort {
+    fn on_cycle(options: FixedRuleOptions<'_>) -> Result<OnCycle> {
+        let on_cycle = options.string_option("on_cycle", Some("error"))?;
+        Ok(match on_cycle.as_str() {
+            "error" => OnCycle::Error,
+            "partial" => OnCycle::Partial,
+            _ => bail!(WrongFixedRuleOptionError {
+                name: "on_cycle".to_string(),
+                span: options.option_span("on_cycle")?,
+                rule_name: "TopSort".to_string(),
+                help: "'on_cycle' must be 'error' or 'partial'".to_string(),
+            }),
+        })
+    }
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("The graph cannot be sorted as it has the cycle {}", display_cycle(.0))]
+#[diagnostic(code(algo::cycle_in_graph))]
+#[diagnostic(help("Use the option `on_cycle: 'partial'` to sort the nodes not after a cycle"))]
+struct CycleError(Vec<DataValue>, #[label] SourceSpan);
+
+fn display_cycle(cycle: &[DataValue]) -> String {
+    cycle
+        .iter()
+        .chain(cycle.first())
+        .map(|node| format!("{node:?}"))
+        .join(" -> ")
+}
+
 impl FixedRule for TopSort {
+    fn init_options(
+        &self,
+        options: &mut BTreeMap<SmartString<LazyCompact>, Expr>,
+        span: SourceSpan,
+    ) -> Result<()> {
+        Self::on_cycle(FixedRuleOptions::new(options, "TopSort", span))?;
+        Ok(())
+    }
+
     fn run(
         &self,
         payload: FixedRulePayload<'_, '_>,
@@ -30,31 +86,85 @@ impl FixedRule for TopSort {
         poison: Poison,
     ) -> Result<()> {
         let edges = payload.get_input(0)?;
+        let on_cycle = Self::on_cycle(payload.options())?;
 
         let (graph, indices, _) = edges.as_directed_graph(false)?;
 
-        let sorted = kahn_g(&graph, poison)?;
+        let sorted = kahn_g(&graph, &indices, poison.clone())?;
+
+        if sorted.len() < indices.len() && on_cycle == OnCycle::Error {
+            bail!(CycleError(
+                find_cycle(&graph, &sorted)
+                    .into_iter()
+                    .map(|idx| indices[idx as usize].clone())
+                    .collect(),
+                payload.span()
+            ))
+        }
 
         for (idx, val_id) in sorted.iter().enumerate() {
             let val = indices.get(*val_id as usize).unwrap();
-            let tuple = vec![DataValue::from(idx as i64), val.clone()];
+            let mut tuple = vec![DataValue::from(idx as i64), val.clone()];
+            if on_cycle == OnCycle::Partial {
+                tuple.push(DataValue::from(false));
+            }
             out.put(tuple);
         }
 
+        if sorted.len() < indices.len() {
+            let mut is_sorted = vec![false; indices.len()];
+            for idx in &sorted {
+                is_sorted[*idx as usize] = true;
+            }
+            let mut on_cycle = vec![false; indices.len()];
+            for idx in 0..graph.node_count() {
+                if graph.out_neighbors(idx).any(|to| *to == idx) {
+                    on_cycle[idx as usize] = true;
+                }
+            }
+            let (graph, _, _) = edges.as_directed_graph(false)?;
+            for component in TarjanSccG::new(graph).run(poison)? {
+                if component.len() > 1 {
+                    for idx in component {
+                        on_cycle[idx as usize] = true;
+                    }
+                }
+            }
+            for (idx, val) in indices.iter().enumerate() {
+                if !is_sorted[idx] {
+                    out.put(vec![
+                        DataValue::Null,
+                        val.clone(),
+                        DataValue::from(on_cycle[idx]),
+                    ]);
+                }
+            }
+        }
+
         Ok(())
     }
 
     fn arity(
         &self,
-        _options: &BTreeMap<SmartString<LazyCompact>, Expr>,
+        options: &BTreeMap<SmartString<LazyCompact>, Expr>,
         _rule_head: &[Symbol],
-        _span: SourceSpan,
+        span: SourceSpan,
     ) -> Result<usize> {
-        Ok(2)
+        let options = FixedRuleOptions::new(options, "TopSort", span);
+        Ok(match Self::on_cycle(options)? {
+            OnCycle::Error => 2,
+            OnCycle::Partial => 3,
+        })
     }
 }
 
-pub(crate) fn kahn_g(graph: &DirectedCsrGraph<u32>, poison: Poison) -> Result<Vec<u32>> {
+/// Kahn's algorithm, taking the nodes ready to be sorted in the order of their values.
+/// The nodes on or after cycles are left out.
+pub(crate) fn kahn_g(
+    graph: &DirectedCsrGraph<u32>,
+    indices: &[DataValue],
+    poison: Poison,
+) -> Result<Vec<u32>> {
     let graph_size = graph.node_count();
     let mut in_degree = vec![0; graph_size as usize];
     for tos in 0..graph_size {
@@ -63,21 +173,20 @@ pub(crate) fn kahn_g(graph: &DirectedCsrGraph<u32>, poison: Poison) -> Result<Ve
         }
     }
     let mut sorted = Vec::with_capacity(graph_size as usize);
-    let mut pending = vec![];
+    let mut pending = BinaryHeap::new();
 
     for (node, degree) in in_degree.iter().enumerate() {
         if *degree == 0 {
-            pending.push(node as u32);
+            pending.push(Reverse((&indices[node], node as u32)));
         }
     }
 
-    while !pending.is_empty() {
-        let removed = pending.pop().unwrap();
+    while let Some(Reverse((_, removed))) = pending.pop() {
         sorted.push(removed);
         for nxt in graph.out_neighbors(removed) {
             in_degree[*nxt as usize] -= 1;
             if in_degree[*nxt as usize] == 0 {
-                pending.push(*nxt);
+                pending.push(Reverse((&indices[*nxt as usize], *nxt)));
             }
         }
         poison.check()?;
@@ -85,3 +194,130 @@ pub(crate) fn kahn_g(graph: &DirectedCsrGraph<u32>, poison: Poison) -> Result<Ve
 
     Ok(sorted)
 }
+
+/// A cycle among the nodes left out by [kahn_g], each of which has an edge from another
+/// of them, in the order of the edges
+fn find_cycle(graph: &DirectedCsrGraph<u32>, sorted: &[u32]) -> Vec<u32> {
+    let n = graph.node_count() as usize;
+    let mut is_sorted = vec![false; n];
+    for idx in sorted {
+        is_sorted[*idx as usize] = true;
+    }
+    let mut predecessors = vec![None; n];
+    for from in 0..n as u32 {
+        if is_sorted[from as usize] {
+            continue;
+        }
+        for to in graph.out_neighbors(from) {
+            predecessors[*to as usize].get_or_insert(from);
+        }
+    }
+    // going back from any node left out ends up going round a cycle
+    let mut seen = vec![false; n];
+    let mut current = (0..n as u32).find(|idx| !is_sorted[*idx as usize]).unwrap();
+    while !seen[current as usize] {
+        seen[current as usize] = true;
+        current = predecessors[current as usize].unwrap();
+    }
+    let mut cycle = vec![current];
+    let mut node = predecessors[current as usize].unwrap();
+    while node != current {
+        cycle.push(node);
+        node = predecessors[node as usize].unwrap();
+    }
+    cycle.reverse();
+    cycle
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::value::DataValue;
+    use crate::new_cozo_mem;
+
+    fn run(edges: &str, options: &str) -> miette::Result<Vec<Vec<DataValue>>> {
+        let db = new_cozo_mem().unwrap();
+        db.run_script(
+            &format!(
+                "edges[f, t] <- {edges}
+                ?[i, n, c] <~ TopSort(edges[], {options})"
+            ),
+            Default::default(),
+        )
+        .map(|res| res.rows)
+    }
+
+    fn order(edges: &str) -> String {
+        let db = new_cozo_mem().unwrap();
+        db.run_script(
+            &format!(
+                "edges[f, t] <- {edges}
+                ?[i, n] <~ TopSort(edges[])"
+            ),
+            Default::default(),
+        )
+        .unwrap()
+        .rows
+        .into_iter()
+        .map(|row| row[1].get_str().unwrap().to_string())
+        .collect()
+    }
+
+    #[test]
+    fn test_top_sort() {
+        // `b` and `c` are both ready after `a`
+        assert_eq!(
+            order("[['a', 'c'], ['a', 'b'], ['b', 'd'], ['c', 'd'], ['d', 'e']]"),
+            "abcde"
+        );
+        // disjoint components are interleaved by node value
+        assert_eq!(order("[['x', 'y'], ['c', 'd'], ['a', 'z']]"), "acdxyz");
+    }
+
+    #[test]
+    fn test_top_sort_cycles() {
+        let db = new_cozo_mem().unwrap();
+        let err = db
+            .run_script(
+                "edges[f, t] <- [['a', 'b'], ['b', 'c'], ['c', 'd'], ['d', 'b'], ['d', 'e']]
+                ?[i, n] <~ TopSort(edges[])",
+                Default::default(),
+            )
+            .unwrap_err();
+        let msg = err.to_string();
+        assert!(
+            msg.contains(r#""b" -> "c" -> "d" -> "b""#)
+                || msg.contains(r#""c" -> "d" -> "b" -> "c""#)
+                || msg.contains(r#""d" -> "b" -> "c" -> "d""#),
+            "{msg}"
+        );
+
+        // `a` is sorted, `b`, `c` and `d` are on the cycle and `e` after it,
+        // `x` has a self-loop
+        let rows = run(
+            "[['a', 'b'], ['b', 'c'], ['c', 'd'], ['d', 'b'], ['d', 'e'], ['x', 'x']]",
+            "on_cycle: 'partial'",
+        )
+        .unwrap();
+        let expected = [
+            (None, "b", true),
+            (None, "c", true),
+            (None, "d", true),
+            (None, "e", false),
+            (None, "x", true),
+            (Some(0), "a", false),
+        ];
+        let actual: Vec<_> = rows
+            .iter()
+            .map(|row| {
+                (
+                    row[0].get_int(),
+                    row[1].get_str().unwrap(),
+                    row[2].get_bool().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(actual, expected);
+
+        assert!(run("[['a', 'b']]", "on_cycle: 'ignore'").is_err());
+    }
+}
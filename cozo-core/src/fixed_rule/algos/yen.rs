@@ -135,12 +135,13 @@ fn k_shortest_path_yen(
     let mut k_shortest: Vec<(f32, Vec<u32>)> = Vec::with_capacity(k);
     let mut candidates: Vec<(f32, Vec<u32>)> = vec![];
 
+    // the path is empty if the goal cannot be reached
     match dijkstra(edges, start, &Some(goal), &(), &())
         .into_iter()
         .next()
     {
-        None => return Ok(k_shortest),
-        Some((_, cost, path)) => k_shortest.push((cost, path)),
+        Some((_, cost, path)) if !path.is_empty() => k_shortest.push((cost, path)),
+        _ => return Ok(k_shortest),
     }
 
     for _ in 1..k {
@@ -162,6 +163,7 @@ fn k_shortest_path_yen(
             for node in &prev_path[0..i] {
                 forbidden_nodes.insert(*node);
             }
+            poison.check()?;
             if let Some((_, spur_cost, spur_path)) = dijkstra(
                 edges,
                 spur_node,
@@ -171,35 +173,100 @@ fn k_shortest_path_yen(
             )
             .into_iter()
             .next()
+            .filter(|(_, _, spur_path)| !spur_path.is_empty())
             {
                 let mut total_cost = spur_cost;
-                for i in 0..root_path.len() - 1 {
-                    let s = root_path[i];
-                    let d = root_path[i + 1];
-                    for target in edges.out_neighbors_with_values(s) {
-                        let e = target.target;
-                        let c = target.value;
-                        if e == d {
-                            total_cost += c;
-                            break;
-                        }
-                    }
+                for (s, d) in root_path.iter().tuple_windows() {
+                    // the cheapest of parallel edges, as taken by the search
+                    total_cost += edges
+                        .out_neighbors_with_values(*s)
+                        .filter(|target| target.target == *d)
+                        .map(|target| target.value)
+                        .fold(f32::INFINITY, f32::min);
                 }
                 let mut total_path = root_path.to_vec();
                 total_path.pop();
                 total_path.extend(spur_path);
-                if candidates.iter().all(|(_, v)| *v != total_path) {
+                if candidates
+                    .iter()
+                    .chain(k_shortest.iter())
+                    .all(|(_, v)| *v != total_path)
+                {
                     candidates.push((total_cost, total_path));
                 }
-                poison.check()?;
             }
         }
         if candidates.is_empty() {
             break;
         }
-        candidates.sort_by(|(a_cost, _), (b_cost, _)| b_cost.total_cmp(a_cost));
+        // of paths with the same cost, those with fewer hops come first
+        candidates.sort_by(|(a_cost, a_path), (b_cost, b_path)| {
+            b_cost
+                .total_cmp(a_cost)
+                .then_with(|| b_path.len().cmp(&a_path.len()))
+                .then_with(|| b_path.cmp(a_path))
+        });
         let shortest = candidates.pop().unwrap();
         k_shortest.push(shortest);
     }
     Ok(k_shortest)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::data::value::DataValue;
+    use crate::new_cozo_mem;
+
+    /// The costs and paths of the rows, as in `3:abc`
+    fn paths(rows: &[Vec<DataValue>]) -> Vec<String> {
+        rows.iter()
+            .map(|row| {
+                let path = row[3]
+                    .get_slice()
+                    .unwrap()
+                    .iter()
+                    .map(|node| node.get_str().unwrap())
+                    .collect::<String>();
+                format!("{}:{}", row[2].get_float().unwrap(), path)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_k_shortest_paths() {
+        let db = new_cozo_mem().unwrap();
+        let run = |start: &str, goals: &str, options: &str| {
+            let script = format!(
+                r#"
+                edges[f, t, c] <- [['a', 'b', 1], ['b', 'd', 1], ['a', 'c', 1], ['c', 'd', 1],
+                                   ['a', 'd', 3], ['b', 'c', 1], ['d', 'b', 1], ['z', 'a', 1]]
+                start[n] <- [['{start}']]
+                goal[n] <- [{goals}]
+                ?[s, g, c, p] <~ KShortestPathYen(edges[], start[], goal[], {options})
+                "#
+            );
+            db.run_script(&script, Default::default()).unwrap().rows
+        };
+
+        // ties in cost are broken by the number of hops, and paths do not go around the
+        // loop between `b` and `d`
+        assert_eq!(
+            paths(&run("a", "['d']", "k: 3")),
+            ["2:abd", "2:acd", "3:ad"]
+        );
+        assert_eq!(
+            paths(&run("a", "['d']", "k: 10")),
+            ["2:abd", "2:acd", "3:abcd", "3:ad"]
+        );
+        // `z` cannot be reached
+        let rows = run("a", "['d'], ['z']", "k: 2");
+        assert_eq!(paths(&rows), ["2:abd", "2:acd"]);
+        assert!(rows.iter().all(|row| row[1] == DataValue::from("d")));
+
+        assert!(run("d", "['a']", "k: 3").is_empty());
+        assert_eq!(
+            paths(&run("d", "['a']", "k: 3, undirected: true")),
+            ["2:dba", "2:dca", "3:da"]
+        );
+    }
+}
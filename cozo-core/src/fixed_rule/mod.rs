@@ -7,6 +7,8 @@
  */
 
 use std::collections::BTreeMap;
+#[cfg(test)]
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 use crossbeam::channel::{bounded, Receiver, Sender};
@@ -34,6 +36,7 @@ use crate::data::value::DataValue;
 use crate::fixed_rule::algos::*;
 use crate::fixed_rule::utilities::*;
 use crate::parse::SourceSpan;
+use crate::query::ra::filter_iter;
 use crate::runtime::db::Poison;
 use crate::runtime::temp_store::{EpochStore, RegularTempStore};
 use crate::runtime::transact::SessionTx;
@@ -84,26 +87,35 @@ impl<'a, 'b> FixedRuleInputRelation<'a, 'b> {
     }
     /// Iterate the input relation
     pub fn iter(&self) -> Result<TupleIter<'a>> {
-        Ok(match &self.arg_manifest {
+        let it: TupleIter<'a> = match &self.arg_manifest {
             MagicFixedRuleRuleArg::InMem { name, .. } => {
                 let store = self.stores.get(name).ok_or_else(|| {
                     RuleNotFoundError(name.symbol().to_string(), name.symbol().span)
                 })?;
                 Box::new(store.all_iter().map(|t| Ok(t.into_tuple())))
             }
-            MagicFixedRuleRuleArg::Stored { name, valid_at, .. } => {
+            MagicFixedRuleRuleArg::Stored {
+                name,
+                filters,
+                valid_at,
+                ..
+            } => {
                 let relation = self.tx.get_relation(name, false)?;
-                if let Some(valid_at) = valid_at {
+                let expiry = relation.expiry(self.tx);
+                let it: TupleIter<'a> = if let Some(valid_at) = valid_at {
                     Box::new(relation.skip_scan_all(self.tx, *valid_at))
                 } else {
                     Box::new(relation.scan_all(self.tx))
-                }
+                };
+                let it = Box::new(it.filter_ok(move |row| expiry.is_live(row)));
+                filter_stored_arg(filters, it)
             }
-        })
+        };
+        Ok(self.count_rows(it))
     }
     /// Iterate the relation with the given single-value prefix
     pub fn prefix_iter(&self, prefix: &DataValue) -> Result<TupleIter<'_>> {
-        Ok(match self.arg_manifest {
+        let it: TupleIter<'_> = match self.arg_manifest {
             MagicFixedRuleRuleArg::InMem { name, .. } => {
                 let store = self.stores.get(name).ok_or_else(|| {
                     RuleNotFoundError(name.symbol().to_string(), name.symbol().span)
@@ -111,16 +123,41 @@ impl<'a, 'b> FixedRuleInputRelation<'a, 'b> {
                 let t = vec![prefix.clone()];
                 Box::new(store.prefix_iter(&t).map(|t| Ok(t.into_tuple())))
             }
-            MagicFixedRuleRuleArg::Stored { name, valid_at, .. } => {
+            MagicFixedRuleRuleArg::Stored {
+                name,
+                filters,
+                valid_at,
+                ..
+            } => {
                 let relation = self.tx.get_relation(name, false)?;
+                let expiry = relation.expiry(self.tx);
                 let t = vec![prefix.clone()];
-                if let Some(valid_at) = valid_at {
+                let it: TupleIter<'_> = if let Some(valid_at) = valid_at {
                     Box::new(relation.skip_scan_prefix(self.tx, &t, *valid_at))
                 } else {
                     Box::new(relation.scan_prefix(self.tx, &t))
-                }
+                };
+                let it = Box::new(it.filter_ok(move |row| expiry.is_live(row)));
+                filter_stored_arg(filters, it)
             }
-        })
+        };
+        Ok(self.count_rows(it))
+    }
+    /// Counts the rows handed to the fixed rule, only in tests
+    fn count_rows<'c>(&self, it: TupleIter<'c>) -> TupleIter<'c>
+    where
+        'a: 'c,
+    {
+        #[cfg(test)]
+        let it: TupleIter<'c> = {
+            let tx = self.tx;
+            Box::new(it.inspect(move |r| {
+                if r.is_ok() {
+                    tx.fixed_rule_input_rows.fetch_add(1, Ordering::Relaxed);
+                }
+            }))
+        };
+        it
     }
     /// Get the source span of the input relation. Useful for generating informative error messages.
     pub fn span(&self) -> SourceSpan {
@@ -346,187 +383,220 @@ impl<'a, 'b> FixedRulePayload<'a, 'b> {
     pub fn span(&self) -> SourceSpan {
         self.manifest.span
     }
+    /// Typed access to the options of the current fixed rule
+    pub fn options(&self) -> FixedRuleOptions<'_> {
+        FixedRuleOptions::new(
+            &self.manifest.options,
+            &self.manifest.fixed_handle.name,
+            self.manifest.span,
+        )
+    }
     /// Extract an expression option
     pub fn expr_option(&self, name: &str, default: Option<Expr>) -> Result<Expr> {
-        match self.manifest.options.get(name) {
-            Some(ex) => Ok(ex.clone()),
-            None => match default {
-                Some(ex) => Ok(ex),
-                None => Err(FixedRuleOptionNotFoundError {
-                    name: name.to_string(),
-                    span: self.manifest.span,
-                    rule_name: self.manifest.fixed_handle.name.to_string(),
-                }
-                .into()),
-            },
-        }
+        self.options().expr_option(name, default)
+    }
+    /// Extract the constant value of an option
+    pub fn value_option(&self, name: &str, default: Option<DataValue>) -> Result<DataValue> {
+        self.options().value_option(name, default)
     }
-
     /// Extract a string option
     pub fn string_option(
         &self,
         name: &str,
         default: Option<&str>,
     ) -> Result<SmartString<LazyCompact>> {
-        match self.manifest.options.get(name) {
-            Some(ex) => match ex.clone().eval_to_const()? {
-                DataValue::Str(s) => Ok(s),
-                _ => Err(WrongFixedRuleOptionError {
-                    name: name.to_string(),
-                    span: ex.span(),
-                    rule_name: self.manifest.fixed_handle.name.to_string(),
-                    help: "a string is required".to_string(),
-                }
-                .into()),
-            },
-            None => match default {
-                None => Err(FixedRuleOptionNotFoundError {
-                    name: name.to_string(),
-                    span: self.manifest.span,
-                    rule_name: self.manifest.fixed_handle.name.to_string(),
-                }
-                .into()),
-                Some(s) => Ok(SmartString::from(s)),
-            },
-        }
+        self.options().string_option(name, default)
+    }
+    /// Get the source span of the named option. Useful for generating informative error messages.
+    pub fn option_span(&self, name: &str) -> Result<SourceSpan> {
+        self.options().option_span(name)
+    }
+    /// Extract an integer option
+    pub fn integer_option(&self, name: &str, default: Option<i64>) -> Result<i64> {
+        self.options().integer_option(name, default)
+    }
+    /// Extract a positive integer option
+    pub fn pos_integer_option(&self, name: &str, default: Option<usize>) -> Result<usize> {
+        self.options().pos_integer_option(name, default)
+    }
+    /// Extract a non-negative integer option
+    pub fn non_neg_integer_option(&self, name: &str, default: Option<usize>) -> Result<usize> {
+        self.options().non_neg_integer_option(name, default)
+    }
+    /// Extract a floating point option
+    pub fn float_option(&self, name: &str, default: Option<f64>) -> Result<f64> {
+        self.options().float_option(name, default)
+    }
+    /// Extract a floating point option between 0. and 1.
+    pub fn unit_interval_option(&self, name: &str, default: Option<f64>) -> Result<f64> {
+        self.options().unit_interval_option(name, default)
+    }
+    /// Extract a boolean option
+    pub fn bool_option(&self, name: &str, default: Option<bool>) -> Result<bool> {
+        self.options().bool_option(name, default)
     }
+}
 
+/// Typed access to the options of a fixed rule, for use both in [FixedRule::run]
+/// (through [FixedRulePayload::options]) and in [FixedRule::arity].
+///
+/// Options not referring to any binding are evaluated once when the query is parsed,
+/// with parameters substituted, so `max_iter: $n * 2` is seen here as a constant.
+#[derive(Copy, Clone)]
+pub struct FixedRuleOptions<'a> {
+    options: &'a BTreeMap<SmartString<LazyCompact>, Expr>,
+    rule_name: &'a str,
+    span: SourceSpan,
+}
+
+impl<'a> FixedRuleOptions<'a> {
+    /// Wrap the options of a fixed rule. The name and span are used in error messages.
+    pub fn new(
+        options: &'a BTreeMap<SmartString<LazyCompact>, Expr>,
+        rule_name: &'a str,
+        span: SourceSpan,
+    ) -> Self {
+        Self {
+            options,
+            rule_name,
+            span,
+        }
+    }
+    /// Whether the option is given
+    pub fn contains(&self, name: &str) -> bool {
+        self.options.contains_key(name)
+    }
+    fn not_found(&self, name: &str) -> Report {
+        FixedRuleOptionNotFoundError {
+            name: name.to_string(),
+            span: self.span,
+            rule_name: self.rule_name.to_string(),
+        }
+        .into()
+    }
+    fn wrong(&self, name: &str, span: SourceSpan, help: &str) -> Report {
+        WrongFixedRuleOptionError {
+            name: name.to_string(),
+            span,
+            rule_name: self.rule_name.to_string(),
+            help: help.to_string(),
+        }
+        .into()
+    }
+    /// Extract an expression option
+    pub fn expr_option(&self, name: &str, default: Option<Expr>) -> Result<Expr> {
+        match self.options.get(name) {
+            Some(ex) => Ok(ex.clone()),
+            None => default.ok_or_else(|| self.not_found(name)),
+        }
+    }
     /// Get the source span of the named option. Useful for generating informative error messages.
     pub fn option_span(&self, name: &str) -> Result<SourceSpan> {
-        match self.manifest.options.get(name) {
-            None => Err(FixedRuleOptionNotFoundError {
-                name: name.to_string(),
-                span: self.manifest.span,
-                rule_name: self.manifest.fixed_handle.name.to_string(),
-            }
-            .into()),
+        match self.options.get(name) {
+            None => Err(self.not_found(name)),
             Some(v) => Ok(v.span()),
         }
     }
-    /// Extract an integer option
-    pub fn integer_option(&self, name: &str, default: Option<i64>) -> Result<i64> {
-        match self.manifest.options.get(name) {
-            Some(v) => match v.clone().eval_to_const() {
-                Ok(DataValue::Num(n)) => match n.get_int() {
-                    Some(i) => Ok(i),
-                    None => Err(FixedRuleOptionNotFoundError {
-                        name: name.to_string(),
-                        span: self.manifest.span,
-                        rule_name: self.manifest.fixed_handle.name.to_string(),
-                    }
-                    .into()),
-                },
-                _ => Err(WrongFixedRuleOptionError {
-                    name: name.to_string(),
-                    span: v.span(),
-                    rule_name: self.manifest.fixed_handle.name.to_string(),
-                    help: "an integer is required".to_string(),
-                }
-                .into()),
-            },
-            None => match default {
-                Some(v) => Ok(v),
-                None => Err(FixedRuleOptionNotFoundError {
-                    name: name.to_string(),
-                    span: self.manifest.span,
-                    rule_name: self.manifest.fixed_handle.name.to_string(),
+    /// Extract the constant value of an option together with its span,
+    /// or `None` if the option is not given
+    pub fn const_option(&self, name: &str) -> Result<Option<(DataValue, SourceSpan)>> {
+        match self.options.get(name) {
+            None => Ok(None),
+            Some(ex) => {
+                let span = ex.span();
+                match ex.clone().eval_to_const() {
+                    Ok(val) => Ok(Some((val, span))),
+                    Err(_) => Err(self.wrong(name, span, "a constant value is required")),
                 }
-                .into()),
-            },
+            }
+        }
+    }
+    /// Extract the constant value of an option
+    pub fn value_option(&self, name: &str, default: Option<DataValue>) -> Result<DataValue> {
+        match self.const_option(name)? {
+            Some((val, _)) => Ok(val),
+            None => default.ok_or_else(|| self.not_found(name)),
         }
     }
+    fn typed_option<T>(
+        &self,
+        name: &str,
+        default: Option<T>,
+        help: &str,
+        extract: impl FnOnce(DataValue) -> Option<T>,
+    ) -> Result<T> {
+        match self.const_option(name)? {
+            Some((val, span)) => extract(val).ok_or_else(|| self.wrong(name, span, help)),
+            None => default.ok_or_else(|| self.not_found(name)),
+        }
+    }
+    /// Extract a string option
+    pub fn string_option(
+        &self,
+        name: &str,
+        default: Option<&str>,
+    ) -> Result<SmartString<LazyCompact>> {
+        self.typed_option(
+            name,
+            default.map(SmartString::from),
+            "a string is required",
+            |v| match v {
+                DataValue::Str(s) => Some(s),
+                _ => None,
+            },
+        )
+    }
+    /// Extract an integer option
+    pub fn integer_option(&self, name: &str, default: Option<i64>) -> Result<i64> {
+        self.typed_option(name, default, "an integer is required", |v| v.get_int())
+    }
     /// Extract a positive integer option
     pub fn pos_integer_option(&self, name: &str, default: Option<usize>) -> Result<usize> {
         let i = self.integer_option(name, default.map(|i| i as i64))?;
-        ensure!(
-            i > 0,
-            WrongFixedRuleOptionError {
-                name: name.to_string(),
-                span: self.option_span(name)?,
-                rule_name: self.manifest.fixed_handle.name.to_string(),
-                help: "a positive integer is required".to_string(),
-            }
-        );
+        if i <= 0 {
+            return Err(self.wrong(
+                name,
+                self.option_span(name)?,
+                "a positive integer is required",
+            ));
+        }
         Ok(i as usize)
     }
     /// Extract a non-negative integer option
     pub fn non_neg_integer_option(&self, name: &str, default: Option<usize>) -> Result<usize> {
         let i = self.integer_option(name, default.map(|i| i as i64))?;
-        ensure!(
-            i >= 0,
-            WrongFixedRuleOptionError {
-                name: name.to_string(),
-                span: self.option_span(name)?,
-                rule_name: self.manifest.fixed_handle.name.to_string(),
-                help: "a non-negative integer is required".to_string(),
-            }
-        );
+        if i < 0 {
+            return Err(self.wrong(
+                name,
+                self.option_span(name)?,
+                "a non-negative integer is required",
+            ));
+        }
         Ok(i as usize)
     }
     /// Extract a floating point option
     pub fn float_option(&self, name: &str, default: Option<f64>) -> Result<f64> {
-        match self.manifest.options.get(name) {
-            Some(v) => match v.clone().eval_to_const() {
-                Ok(DataValue::Num(n)) => {
-                    let f = n.get_float();
-                    Ok(f)
-                }
-                _ => Err(WrongFixedRuleOptionError {
-                    name: name.to_string(),
-                    span: v.span(),
-                    rule_name: self.manifest.fixed_handle.name.to_string(),
-                    help: "a floating number is required".to_string(),
-                }
-                .into()),
-            },
-            None => match default {
-                Some(v) => Ok(v),
-                None => Err(FixedRuleOptionNotFoundError {
-                    name: name.to_string(),
-                    span: self.manifest.span,
-                    rule_name: self.manifest.fixed_handle.name.to_string(),
-                }
-                .into()),
-            },
-        }
+        self.typed_option(name, default, "a floating number is required", |v| {
+            v.get_float()
+        })
     }
     /// Extract a floating point option between 0. and 1.
     pub fn unit_interval_option(&self, name: &str, default: Option<f64>) -> Result<f64> {
         let f = self.float_option(name, default)?;
-        ensure!(
-            (0. ..=1.).contains(&f),
-            WrongFixedRuleOptionError {
-                name: name.to_string(),
-                span: self.option_span(name)?,
-                rule_name: self.manifest.fixed_handle.name.to_string(),
-                help: "a number between 0. and 1. is required".to_string(),
-            }
-        );
+        if !(0. ..=1.).contains(&f) {
+            return Err(self.wrong(
+                name,
+                self.option_span(name)?,
+                "a number between 0. and 1. is required",
+            ));
+        }
         Ok(f)
     }
     /// Extract a boolean option
     pub fn bool_option(&self, name: &str, default: Option<bool>) -> Result<bool> {
-        match self.manifest.options.get(name) {
-            Some(v) => match v.clone().eval_to_const() {
-                Ok(DataValue::Bool(b)) => Ok(b),
-                _ => Err(WrongFixedRuleOptionError {
-                    name: name.to_string(),
-                    span: v.span(),
-                    rule_name: self.manifest.fixed_handle.name.to_string(),
-                    help: "a boolean value is required".to_string(),
-                }
-                .into()),
-            },
-            None => match default {
-                Some(v) => Ok(v),
-                None => Err(FixedRuleOptionNotFoundError {
-                    name: name.to_string(),
-                    span: self.manifest.span,
-                    rule_name: self.manifest.fixed_handle.name.to_string(),
-                }
-                .into()),
-            },
-        }
+        self.typed_option(name, default, "a boolean value is required", |v| {
+            v.get_bool()
+        })
     }
 }
 
@@ -543,6 +613,12 @@ pub trait FixedRule: Send + Sync {
     ) -> Result<()> {
         Ok(())
     }
+    /// Whether the rule expects a relation of nodes right after the relation of edges.
+    /// When the rule is invoked on a named graph, the node relation of the graph is then
+    /// passed in that position. The default implementation returns `false`.
+    fn takes_graph_nodes(&self) -> bool {
+        false
+    }
     /// You must return the row width of the returned relation and it must be accurate.
     /// This function may be called multiple times.
     fn arity(
@@ -693,7 +769,7 @@ pub(crate) struct CannotDetermineArity(
     #[label] pub(crate) SourceSpan,
 );
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, serde_derive::Serialize, serde_derive::Deserialize)]
 pub(crate) struct FixedRuleHandle {
     pub(crate) name: Symbol,
 }
@@ -752,6 +828,16 @@ lazy_static! {
                 Arc::<Box<dyn FixedRule>>::new(Box::new(ShortestPathDijkstra)),
             ),
             #[cfg(feature = "graph-algo")]
+            (
+                "ShortestPathBellmanFord".to_string(),
+                Arc::<Box<dyn FixedRule>>::new(Box::new(ShortestPathBellmanFord)),
+            ),
+            #[cfg(feature = "graph-algo")]
+            (
+                "ShortestPathFloydWarshall".to_string(),
+                Arc::<Box<dyn FixedRule>>::new(Box::new(ShortestPathFloydWarshall)),
+            ),
+            #[cfg(feature = "graph-algo")]
             (
                 "ShortestPathAStar".to_string(),
                 Arc::<Box<dyn FixedRule>>::new(Box::new(ShortestPathAStar)),
@@ -772,6 +858,16 @@ lazy_static! {
                 Arc::<Box<dyn FixedRule>>::new(Box::new(MinimumSpanningForestKruskal)),
             ),
             #[cfg(feature = "graph-algo")]
+            (
+                "MaximumBipartiteMatching".to_string(),
+                Arc::<Box<dyn FixedRule>>::new(Box::new(MaximumBipartiteMatching)),
+            ),
+            #[cfg(feature = "graph-algo")]
+            (
+                "MaxFlow".to_string(),
+                Arc::<Box<dyn FixedRule>>::new(Box::new(MaxFlow)),
+            ),
+            #[cfg(feature = "graph-algo")]
             (
                 "TopSort".to_string(),
                 Arc::<Box<dyn FixedRule>>::new(Box::new(TopSort)),
@@ -807,10 +903,25 @@ lazy_static! {
                 Arc::<Box<dyn FixedRule>>::new(Box::new(LabelPropagation)),
             ),
             #[cfg(feature = "graph-algo")]
+            (
+                "NodeSimilarity".to_string(),
+                Arc::<Box<dyn FixedRule>>::new(Box::new(NodeSimilarity)),
+            ),
+            #[cfg(feature = "graph-algo")]
             (
                 "RandomWalk".to_string(),
                 Arc::<Box<dyn FixedRule>>::new(Box::new(RandomWalk)),
             ),
+            #[cfg(feature = "graph-algo")]
+            (
+                "RandomWalkWithRestart".to_string(),
+                Arc::<Box<dyn FixedRule>>::new(Box::new(RandomWalkWithRestart)),
+            ),
+            #[cfg(feature = "graph-algo")]
+            (
+                "Subgraph".to_string(),
+                Arc::<Box<dyn FixedRule>>::new(Box::new(Subgraph)),
+            ),
             (
                 "ReorderSort".to_string(),
                 Arc::<Box<dyn FixedRule>>::new(Box::new(ReorderSort)),
@@ -827,6 +938,10 @@ lazy_static! {
                 "Constant".to_string(),
                 Arc::<Box<dyn FixedRule>>::new(Box::new(Constant)),
             ),
+            (
+                "VectorTopK".to_string(),
+                Arc::<Box<dyn FixedRule>>::new(Box::new(VectorTopK)),
+            ),
         ])
     };
 }
@@ -843,7 +958,7 @@ impl FixedRuleHandle {
 #[error("The relation cannot be interpreted as an edge")]
 #[diagnostic(code(algo::not_an_edge))]
 #[diagnostic(help("Edge relation requires tuples of length at least two"))]
-struct NotAnEdgeError(#[label] SourceSpan);
+pub(crate) struct NotAnEdgeError(#[label] pub(crate) SourceSpan);
 
 #[derive(Error, Diagnostic, Debug)]
 #[error(
@@ -894,6 +1009,15 @@ pub(crate) struct BadExprValueError(
 #[diagnostic(code(parser::fixed_rule_not_found))]
 pub(crate) struct FixedRuleNotFoundError(pub(crate) String, #[label] pub(crate) SourceSpan);
 
+/// Applies the filters given with a stored relation argument, e.g. `*roads{f, t, w | w < 100}`.
+fn filter_stored_arg<'a>(filters: &[Expr], it: TupleIter<'a>) -> TupleIter<'a> {
+    if filters.is_empty() {
+        return it;
+    }
+    let filters_bytecodes = filters.iter().map(|f| (f.compile(), f.span())).collect();
+    Box::new(filter_iter(filters_bytecodes, it))
+}
+
 impl MagicFixedRuleRuleArg {
     pub(crate) fn arity(
         &self,
@@ -16,7 +16,7 @@ use crate::data::expr::Expr;
 use crate::data::program::WrongFixedRuleOptionError;
 use crate::data::symb::Symbol;
 use crate::data::value::DataValue;
-use crate::fixed_rule::{FixedRule, FixedRulePayload};
+use crate::fixed_rule::{FixedRule, FixedRuleOptions, FixedRulePayload};
 use crate::parse::SourceSpan;
 use crate::runtime::db::Poison;
 use crate::runtime::temp_store::RegularTempStore;
@@ -76,16 +76,8 @@ impl FixedRule for Constant {
         options: &mut BTreeMap<SmartString<LazyCompact>, Expr>,
         span: SourceSpan,
     ) -> Result<()> {
-        let data = options
-            .get("data")
-            .ok_or_else(|| WrongFixedRuleOptionError {
-                name: "data".to_string(),
-                span: Default::default(),
-                rule_name: "Constant".to_string(),
-                help: "a list of lists is required".to_string(),
-            })?;
-        let data = match data.clone().eval_to_const()? {
-            DataValue::List(l) => l,
+        let data = match FixedRuleOptions::new(options, "Constant", span).const_option("data")? {
+            Some((DataValue::List(l), _)) => l,
             _ => bail!(WrongFixedRuleOptionError {
                 name: "data".to_string(),
                 span: Default::default(),
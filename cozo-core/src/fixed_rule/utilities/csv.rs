@@ -20,7 +20,7 @@ use crate::data::symb::Symbol;
 use crate::data::value::DataValue;
 #[cfg(feature = "requests")]
 use crate::fixed_rule::utilities::jlines::get_file_content_from_url;
-use crate::fixed_rule::{CannotDetermineArity, FixedRule, FixedRulePayload};
+use crate::fixed_rule::{CannotDetermineArity, FixedRule, FixedRuleOptions, FixedRulePayload};
 use crate::parse::{parse_type, SourceSpan};
 use crate::runtime::db::Poison;
 use crate::runtime::temp_store::RegularTempStore;
@@ -48,7 +48,7 @@ impl FixedRule for CsvReader {
         let delimiter = delimiter[0];
         let prepend_index = payload.bool_option("prepend_index", Some(false))?;
         let has_headers = payload.bool_option("has_headers", Some(true))?;
-        let types_opts = payload.expr_option("types", None)?.eval_to_const()?;
+        let types_opts = payload.value_option("types", None)?;
         let typing = NullableColType {
             coltype: ColType::List {
                 eltype: Box::new(NullableColType {
@@ -179,30 +179,16 @@ impl FixedRule for CsvReader {
         _rule_head: &[Symbol],
         span: SourceSpan,
     ) -> Result<usize> {
-        let with_row_num = match options.get("prepend_index") {
-            None => 0,
-            Some(Expr::Const {
-                val: DataValue::Bool(true),
-                ..
-            }) => 1,
-            Some(Expr::Const {
-                val: DataValue::Bool(false),
-                ..
-            }) => 0,
-            _ => bail!(CannotDetermineArity(
-                "CsvReader".to_string(),
-                "invalid option 'prepend_index' given, expect a boolean".to_string(),
-                span
-            )),
-        };
+        let options = FixedRuleOptions::new(options, "CsvReader", span);
+        let with_row_num = usize::from(options.bool_option("prepend_index", Some(false))?);
         let columns = options
-            .get("types")
+            .const_option("types")?
             .ok_or_else(|| FixedRuleOptionNotFoundError {
                 name: "types".to_string(),
                 span,
                 rule_name: "CsvReader".to_string(),
-            })?;
-        let columns = columns.clone().eval_to_const()?;
+            })?
+            .0;
         if let Some(l) = columns.get_slice() {
             return Ok(l.len() + with_row_num);
         }
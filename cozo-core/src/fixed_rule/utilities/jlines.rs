@@ -24,7 +24,7 @@ use crate::data::expr::Expr;
 use crate::data::json::JsonValue;
 use crate::data::symb::Symbol;
 use crate::data::value::DataValue;
-use crate::fixed_rule::{CannotDetermineArity, FixedRule, FixedRulePayload};
+use crate::fixed_rule::{CannotDetermineArity, FixedRule, FixedRuleOptions, FixedRulePayload};
 use crate::parse::SourceSpan;
 use crate::runtime::db::Poison;
 use crate::runtime::temp_store::RegularTempStore;
@@ -48,9 +48,8 @@ impl FixedRule for JsonReader {
         #[diagnostic(code(eval::algo_bad_fields))]
         struct BadFields(#[label] SourceSpan);
 
-        let fields_expr = payload.expr_option("fields", None)?;
-        let fields_span = fields_expr.span();
-        let fields: Vec<_> = match fields_expr.eval_to_const()? {
+        let fields_span = payload.option_span("fields")?;
+        let fields: Vec<_> = match payload.value_option("fields", None)? {
             DataValue::List(l) => l
                 .into_iter()
                 .map(|d| match d {
@@ -140,31 +139,15 @@ impl FixedRule for JsonReader {
         _rule_head: &[Symbol],
         span: SourceSpan,
     ) -> Result<usize> {
-        let with_row_num = match opts.get("prepend_index") {
-            None => 0,
-            Some(Expr::Const {
-                val: DataValue::Bool(true),
-                ..
-            }) => 1,
-            Some(Expr::Const {
-                val: DataValue::Bool(false),
-                ..
-            }) => 0,
-            _ => bail!(CannotDetermineArity(
-                "JsonReader".to_string(),
-                "invalid option 'prepend_index' given, expect a boolean".to_string(),
-                span
-            )),
-        };
-        let fields = opts.get("fields").ok_or_else(|| {
-            CannotDetermineArity(
+        let opts = FixedRuleOptions::new(opts, "JsonReader", span);
+        let with_row_num = usize::from(opts.bool_option("prepend_index", Some(false))?);
+        Ok(match opts.const_option("fields")? {
+            Some((DataValue::List(l), _)) => l.len() + with_row_num,
+            None => bail!(CannotDetermineArity(
                 "JsonReader".to_string(),
                 "option 'fields' not provided".to_string(),
                 span,
-            )
-        })?;
-        Ok(match fields.clone().eval_to_const()? {
-            DataValue::List(l) => l.len() + with_row_num,
+            )),
             _ => bail!(CannotDetermineArity(
                 "JsonReader".to_string(),
                 "invalid option 'fields' given, expect a list".to_string(),
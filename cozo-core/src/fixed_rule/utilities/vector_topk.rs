@@ -0,0 +1,282 @@
+/*
+ * Copyright 2022, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::{BTreeMap, BinaryHeap};
+
+use miette::{bail, ensure, Diagnostic, Result};
+use ordered_float::OrderedFloat;
+use smartstring::{LazyCompact, SmartString};
+use thiserror::Error;
+
+use crate::data::expr::Expr;
+use crate::data::program::WrongFixedRuleOptionError;
+use crate::data::symb::Symbol;
+use crate::data::tuple::Tuple;
+use crate::data::value::DataValue;
+use crate::fixed_rule::{CannotDetermineArity, FixedRule, FixedRulePayload};
+use crate::parse::SourceSpan;
+use crate::runtime::db::Poison;
+use crate::runtime::temp_store::RegularTempStore;
+
+/// Exact nearest neighbours: the input rows are keys followed by a vector, a list of numbers,
+/// and the `k` rows whose vectors are nearest to the `query` vector are output as their keys
+/// followed by their distances.
+pub(crate) struct VectorTopK;
+
+/// Number of accumulators the products of the components are summed into, so that
+/// the loops can be vectorized
+const LANES: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Metric {
+    /// one minus the cosine of the angle, one for zero vectors
+    Cosine,
+    /// euclidean distance
+    L2,
+    /// the inner product negated, so that smaller distances are nearer
+    InnerProduct,
+}
+
+pub(crate) fn dot(a: &[f64], b: &[f64]) -> f64 {
+    let mut lanes = [0.; LANES];
+    let a_chunks = a.chunks_exact(LANES);
+    let b_chunks = b.chunks_exact(LANES);
+    let rest: f64 = a_chunks
+        .remainder()
+        .iter()
+        .zip(b_chunks.remainder())
+        .map(|(x, y)| x * y)
+        .sum();
+    for (x, y) in a_chunks.zip(b_chunks) {
+        for ((acc, x), y) in lanes.iter_mut().zip(x).zip(y) {
+            *acc += x * y;
+        }
+    }
+    lanes.iter().sum::<f64>() + rest
+}
+
+pub(crate) fn squared_l2(a: &[f64], b: &[f64]) -> f64 {
+    let mut lanes = [0.; LANES];
+    let a_chunks = a.chunks_exact(LANES);
+    let b_chunks = b.chunks_exact(LANES);
+    let rest: f64 = a_chunks
+        .remainder()
+        .iter()
+        .zip(b_chunks.remainder())
+        .map(|(x, y)| (x - y) * (x - y))
+        .sum();
+    for (x, y) in a_chunks.zip(b_chunks) {
+        for ((acc, x), y) in lanes.iter_mut().zip(x).zip(y) {
+            *acc += (x - y) * (x - y);
+        }
+    }
+    lanes.iter().sum::<f64>() + rest
+}
+
+impl Metric {
+    /// `query_norm` is the euclidean norm of `query`
+    fn distance(self, query: &[f64], query_norm: f64, v: &[f64]) -> f64 {
+        match self {
+            Metric::Cosine => {
+                let norm = dot(v, v).sqrt();
+                if norm == 0. || query_norm == 0. {
+                    1.
+                } else {
+                    1. - dot(query, v) / (query_norm * norm)
+                }
+            }
+            Metric::L2 => squared_l2(query, v).sqrt(),
+            Metric::InnerProduct => -dot(query, v),
+        }
+    }
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("The vector of the row with key {0:?} has {1} dimensions, but the query vector has {2}")]
+#[diagnostic(code(algo::vector_dim_mismatch))]
+struct VectorDimMismatch(Tuple, usize, usize, #[label] SourceSpan);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("The row with key {0:?} has {1:?} in place of a vector")]
+#[diagnostic(code(algo::not_a_vector))]
+#[diagnostic(help("Vectors are lists of numbers, in the last column of the rows"))]
+struct NotAVector(Tuple, DataValue, #[label] SourceSpan);
+
+/// The components of `v` as floats, if it is a list of numbers
+fn fill_vector(v: &DataValue, buffer: &mut Vec<f64>) -> bool {
+    buffer.clear();
+    match v {
+        DataValue::List(l) => {
+            for x in l {
+                match x.get_float() {
+                    Some(f) => buffer.push(f),
+                    None => return false,
+                }
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+impl FixedRule for VectorTopK {
+    fn run(
+        &self,
+        payload: FixedRulePayload<'_, '_>,
+        out: &mut RegularTempStore,
+        poison: Poison,
+    ) -> Result<()> {
+        let in_rel = payload.get_input(0)?.ensure_min_len(2)?;
+        let wrong_option = |name: &str, help: &str| -> Result<WrongFixedRuleOptionError> {
+            Ok(WrongFixedRuleOptionError {
+                name: name.to_string(),
+                span: payload.option_span(name)?,
+                rule_name: payload.name().to_string(),
+                help: help.to_string(),
+            })
+        };
+
+        let mut query = vec![];
+        let query_val = payload.value_option("query", None)?;
+        if !fill_vector(&query_val, &mut query) || query.is_empty() {
+            bail!(wrong_option(
+                "query",
+                "a non-empty list of numbers is required"
+            )?)
+        }
+        let query_norm = dot(&query, &query).sqrt();
+        let k = payload.pos_integer_option("k", None)?;
+        let metric = match payload.string_option("metric", Some("l2"))?.as_str() {
+            "cosine" => Metric::Cosine,
+            "l2" => Metric::L2,
+            "ip" => Metric::InnerProduct,
+            _ => bail!(wrong_option(
+                "metric",
+                "the metric is one of 'cosine', 'l2' and 'ip'"
+            )?),
+        };
+
+        // the nearest rows seen so far, the farthest of them on top
+        let mut nearest: BinaryHeap<(OrderedFloat<f64>, Tuple)> = BinaryHeap::with_capacity(k + 1);
+        let mut vector = vec![];
+        for tuple in in_rel.iter()? {
+            let mut key = tuple?;
+            let v = key.pop().unwrap();
+            if !fill_vector(&v, &mut vector) {
+                bail!(NotAVector(key, v, in_rel.span()))
+            }
+            ensure!(
+                vector.len() == query.len(),
+                VectorDimMismatch(key, vector.len(), query.len(), in_rel.span())
+            );
+            let candidate = (
+                OrderedFloat(metric.distance(&query, query_norm, &vector)),
+                key,
+            );
+            // among rows at the same distance, those with the smallest keys are kept
+            if nearest.len() < k {
+                nearest.push(candidate);
+            } else if candidate < *nearest.peek().unwrap() {
+                nearest.pop();
+                nearest.push(candidate);
+            }
+            poison.check()?;
+        }
+        for (dist, mut key) in nearest {
+            key.push(DataValue::from(dist.0));
+            out.put(key);
+        }
+        Ok(())
+    }
+
+    fn arity(
+        &self,
+        _options: &BTreeMap<SmartString<LazyCompact>, Expr>,
+        rule_head: &[Symbol],
+        span: SourceSpan,
+    ) -> Result<usize> {
+        if rule_head.len() < 2 {
+            bail!(CannotDetermineArity(
+                "VectorTopK".to_string(),
+                "the rule head must name the key columns followed by the distance".to_string(),
+                span
+            ))
+        }
+        Ok(rule_head.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::new_cozo_mem;
+
+    fn topk(rows: &str, options: &str) -> miette::Result<serde_json::Value> {
+        let db = new_cozo_mem().unwrap();
+        db.run_script(
+            &format!(
+                "vecs[id, v] <- {rows}
+                ?[id, dist] <~ VectorTopK(vecs[], {options})"
+            ),
+            Default::default(),
+        )
+        .map(|res| res.into_json()["rows"].clone())
+    }
+
+    #[test]
+    fn test_vector_topk() {
+        let rows = "[[1, [0, 0]], [2, [3, 4]], [3, [1, 0]], [4, [0, 2]]]";
+        assert_eq!(
+            topk(rows, "query: [0, 0], k: 2").unwrap(),
+            json!([[1, 0.0], [3, 1.0]])
+        );
+        assert_eq!(
+            topk(rows, "query: [1, 0], k: 1, metric: 'cosine'").unwrap(),
+            json!([[3, 0.0]])
+        );
+        assert_eq!(
+            topk(rows, "query: [1, 1], k: 1, metric: 'ip'").unwrap(),
+            json!([[2, -7.0]])
+        );
+        // vectors longer than the number of lanes
+        let long = "[[1, [1, 1, 1, 1, 1, 1, 1, 1, 1, 1]], [2, [0, 0, 0, 0, 0, 0, 0, 0, 0, 3]]]";
+        assert_eq!(
+            topk(long, "query: [0, 0, 0, 0, 0, 0, 0, 0, 0, 0], k: 1").unwrap(),
+            json!([[2, 3.0]])
+        );
+    }
+
+    #[test]
+    fn test_vector_topk_ties_and_large_k() {
+        // rows at the same distance are kept by the order of their keys
+        let rows = "[[4, [1, 0]], [2, [0, 1]], [3, [-1, 0]], [1, [0, -1]], [5, [2, 0]]]";
+        assert_eq!(
+            topk(rows, "query: [0, 0], k: 2").unwrap(),
+            json!([[1, 1.0], [2, 1.0]])
+        );
+        // all rows are output when there are fewer than k
+        assert_eq!(
+            topk(rows, "query: [0, 0], k: 100").unwrap(),
+            json!([[1, 1.0], [2, 1.0], [3, 1.0], [4, 1.0], [5, 2.0]])
+        );
+    }
+
+    #[test]
+    fn test_vector_topk_errors() {
+        let err = topk("[[1, [0, 0]], ['b', [1, 2, 3]]]", "query: [0, 0], k: 1").unwrap_err();
+        assert_eq!(err.code().unwrap().to_string(), "algo::vector_dim_mismatch");
+        assert!(err.to_string().contains("\"b\""), "{err}");
+
+        let err = topk("[[1, 'abc']]", "query: [0, 0], k: 1").unwrap_err();
+        assert_eq!(err.code().unwrap().to_string(), "algo::not_a_vector");
+
+        let err = topk("[[1, [0, 0]]]", "query: [0, 0], k: 1, metric: 'hamming'").unwrap_err();
+        assert_eq!(err.code().unwrap().to_string(), "fixed_rule::arg_wrong");
+    }
+}
@@ -0,0 +1,128 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! The binary format of the key-value pairs written to the storage engines, for tools
+//! reading them directly, e.g. from a RocksDB SST file or the `cozo` table of SQLite.
+//!
+//! The encodings here are part of the stable API: changing them is a breaking change.
+//!
+//! Each row of a stored relation is one key-value pair:
+//!
+//! * the key is the relation prefix followed by the memcomparable encoding of each key column,
+//!   so that the lexicographic order of the bytes agrees with the order of the values;
+//! * the value is the relation prefix followed by the non-key columns as a MessagePack array.
+//!
+//! Entries of indices have all their columns in the key, and an empty value.
+//!
+//! The relation prefix is the ID of the relation as a big-endian `u64`. ID 0 is reserved for
+//! the system catalog, whose keys are the relation names.
+//!
+//! Each value in a key starts with a tag byte, in the order of [DataValue]:
+//!
+//! | Value       | Encoding                                                                    |
+//! |-------------|-----------------------------------------------------------------------------|
+//! | null        | `0x01`                                                                      |
+//! | `false`     | `0x02`                                                                      |
+//! | `true`      | `0x03`                                                                      |
+//! | number      | `0x05`, the value as a float (8 bytes), then a byte: `0x00` for integers exactly representable as floats (absolute value below 2^53), `0x10` for floats, or `0x04` followed by the integer (8 bytes) for other integers |
+//! | string      | `0x06`, then the UTF-8 bytes in groups (see below)                          |
+//! | bytes       | `0x07`, then the bytes in groups                                            |
+//! | UUID        | `0x08`, then the 16 bytes of the UUID as they are                           |
+//! | regex       | `0x09`, then the pattern in groups                                          |
+//! | list        | `0x0A`, the elements, then `0x00`                                           |
+//! | set         | `0x0B`, the elements in ascending order, then `0x00`                        |
+//! | validity    | `0x0C`, the bitwise negation of the timestamp as ordered integer (8 bytes), then `0x00` for assertions and `0x01` for retractions |
+//! | bottom      | `0xFF`                                                                      |
+//!
+//! All multi-byte numbers are big-endian. Floats are written as their IEEE 754 bits with the
+//! sign bit set if positive, and with all bits flipped if negative (so `-0.0` sorts before
+//! `0.0`). Ordered integers are written with the sign bit flipped. Byte sequences are split
+//! into groups of 8 bytes, each followed by a marker byte: `0xFF` for a full group followed by
+//! another one, or `0xFF` minus the number of zero bytes padding the last group, which is
+//! always present even if empty. UUIDs sort by their bytes, so version 7 UUIDs sort by time,
+//! and validities are negated so that the latest ones, and assertions, sort first.
+//!
+//! Storages written before UUIDs were encoded as raw bytes, with the time-high, time-mid and
+//! time-low fields first, have storage version `[0x00]`. The current storage version is
+//! `[0x01]`: older storages are rewritten to it in batches when opened for writing, or with
+//! `Db::upgrade_storage`, and cannot be opened read-only before that. Backups of older storages
+//! are only read, and upgraded as they are restored.
+
+use std::cmp::Reverse;
+
+use miette::Result;
+
+use crate::data::memcmp::MemCmpEncoder;
+use crate::data::tuple::{TupleT, ENCODED_KEY_MIN_LEN};
+use crate::data::value::{DataValue, Validity, ValidityTs};
+use crate::runtime::relation::RelationId;
+
+pub use crate::data::tuple::{check_key_for_validity, decode_tuple_from_key, Tuple};
+pub use crate::runtime::relation::{decode_tuple_from_kv, extend_tuple_from_v};
+
+/// Length of the relation prefix of keys and values
+pub const RELATION_PREFIX_LEN: usize = ENCODED_KEY_MIN_LEN;
+
+/// The prefix of all keys and values of the relation with the given ID.
+pub fn relation_prefix(relation_id: u64) -> [u8; RELATION_PREFIX_LEN] {
+    relation_id.to_be_bytes()
+}
+
+/// The ID of the relation a key or non-empty value belongs to,
+/// or `None` if the data is shorter than the prefix.
+pub fn relation_id_of(data: &[u8]) -> Option<u64> {
+    let prefix = data.get(..RELATION_PREFIX_LEN)?;
+    Some(u64::from_be_bytes(prefix.try_into().ok()?))
+}
+
+/// Encodes a single value as it appears in keys.
+pub fn encode_value(val: &DataValue) -> Vec<u8> {
+    let mut ret = vec![];
+    ret.encode_datavalue(val);
+    ret
+}
+
+/// Encodes the key columns of a row of the relation with the given ID.
+pub fn encode_tuple_key(relation_id: u64, key: &[DataValue]) -> Vec<u8> {
+    key.encode_as_key(RelationId(relation_id))
+}
+
+/// Encodes the non-key columns of a row of the relation with the given ID.
+pub fn encode_tuple_value(relation_id: u64, non_key: &[DataValue]) -> Vec<u8> {
+    let mut ret = relation_prefix(relation_id).to_vec();
+    rmp_serde::encode::write(&mut ret, non_key).unwrap();
+    ret
+}
+
+/// The validity of a key of a relation with time travel, which is its last column.
+/// `None` if the last column is not a validity.
+pub fn validity_of_key(key: &[u8]) -> Result<Option<Validity>> {
+    Ok(match decode_tuple_from_key(key)?.pop() {
+        Some(DataValue::Validity(vld)) => Some(vld),
+        _ => None,
+    })
+}
+
+/// The key to seek to for the row with the given key columns, excluding the validity,
+/// that is valid at the given timestamp: the first key at or after it with the same
+/// key columns is the row, if it is an assertion. See also [check_key_for_validity].
+pub fn validity_seek_key(relation_id: u64, key: &[DataValue], valid_at: ValidityTs) -> Vec<u8> {
+    let mut ret = encode_tuple_key(relation_id, key);
+    ret.encode_datavalue(&DataValue::Validity(Validity {
+        timestamp: valid_at,
+        is_assert: Reverse(true),
+    }));
+    ret
+}
+
+/// Decodes the non-key columns from a value. An empty value decodes to no columns.
+pub fn decode_tuple_from_value(val: &[u8]) -> Result<Tuple> {
+    let mut tuple = vec![];
+    extend_tuple_from_v(&mut tuple, val)?;
+    Ok(tuple)
+}
@@ -32,12 +32,19 @@
 #![allow(clippy::too_many_arguments)]
 
 use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+use std::ops::ControlFlow;
 use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 #[allow(unused_imports)]
 use std::time::Instant;
 
 use crossbeam::channel::{bounded, Receiver, Sender};
+#[cfg(feature = "async")]
+use futures::{future::BoxFuture, stream::BoxStream, FutureExt, StreamExt};
 use lazy_static::lazy_static;
 pub use miette::Error;
 use miette::Report;
@@ -46,24 +53,52 @@ use miette::{
     bail, miette, GraphicalReportHandler, GraphicalTheme, IntoDiagnostic, JSONReportHandler,
     Result, ThemeCharacters, ThemeStyles,
 };
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use serde_json::json;
 
+#[cfg(feature = "arrow")]
+pub use arrow;
+pub use data::json::{json_to_string, FloatFormat, OutputOptions};
 pub use data::value::{DataValue, Num, RegexWrapper, UuidWrapper, Validity, ValidityTs};
-pub use fixed_rule::{FixedRule, FixedRuleInputRelation, FixedRulePayload};
+pub use fixed_rule::{FixedRule, FixedRuleInputRelation, FixedRuleOptions, FixedRulePayload};
+#[cfg(feature = "async")]
+pub use runtime::asynchronous::CallbackEvent;
+pub use runtime::backup::BackupProgress;
+pub use runtime::bulk_import::ImportRowsOptions;
+pub use runtime::clock::VirtualClock;
 pub use runtime::db::Db;
 pub use runtime::db::NamedRows;
+pub use runtime::db::{ImportOptions, ImportReport, OnConflict, RunningQuery};
+pub use runtime::error::CozoError;
+pub use runtime::incremental::{BackupManifest, RelationVersion};
+pub use runtime::prepared::PreparedQuery;
 pub use runtime::relation::decode_tuple_from_kv;
+#[cfg(not(target_arch = "wasm32"))]
+pub use runtime::retry::RetryPolicy;
+#[cfg(not(target_arch = "wasm32"))]
+pub use runtime::subscription::{QueryDiff, SubscriptionHandle, SubscriptionOptions};
 pub use runtime::temp_store::RegularTempStore;
+pub use runtime::typed_rows::PutRowsOptions;
+pub use runtime::usage::QueryUsage;
+pub use runtime::verify::VerifyBackupOptions;
 pub use storage::mem::{new_cozo_mem, MemStorage};
 #[cfg(feature = "storage-rocksdb")]
-pub use storage::rocks::{new_cozo_rocksdb, RocksDbStorage};
+pub use storage::rocks::{
+    new_cozo_rocksdb, new_cozo_rocksdb_with_options, RocksDbOptions, RocksDbStorage,
+};
 #[cfg(feature = "storage-sled")]
 pub use storage::sled::{new_cozo_sled, SledStorage};
 #[cfg(feature = "storage-sqlite")]
-pub use storage::sqlite::{new_cozo_sqlite, SqliteStorage};
+pub use storage::sqlite::{
+    new_cozo_sqlite, new_cozo_sqlite_read_only, new_cozo_sqlite_with_options, SqliteOptions,
+    SqliteStorage,
+};
+#[cfg(feature = "storage-dev")]
+pub use storage::test_suite::storage_test_suite;
 #[cfg(feature = "storage-tikv")]
 pub use storage::tikv::{new_cozo_tikv, TiKvStorage};
-pub use storage::{Storage, StoreTx};
+pub use storage::{Storage, StoreTx, TransientStorageError, WriteConflict};
 
 pub use crate::data::expr::Expr;
 use crate::data::json::JsonValue;
@@ -73,12 +108,15 @@ pub use crate::parse::SourceSpan;
 pub use crate::runtime::callback::CallbackOp;
 pub use crate::runtime::db::Poison;
 pub use crate::runtime::db::TransactionPayload;
+use crate::runtime::jsonl::{create_jsonl, open_jsonl};
 
 pub(crate) mod data;
 pub(crate) mod fixed_rule;
+pub mod format;
 pub(crate) mod parse;
 pub(crate) mod query;
 pub(crate) mod runtime;
+pub mod script;
 pub(crate) mod storage;
 pub(crate) mod utils;
 
@@ -123,16 +161,41 @@ impl DbInstance {
     /// some of the engines are available. The `mem` engine is always available.
     ///
     /// `path` is ignored for `mem` and `tikv` engines.
-    /// `options` is ignored for every engine except `tikv`.
+    /// `options` is a JSON object. For every engine it may contain `plan_cache_path`,
+    /// see [crate::Db::set_plan_cache_path], `validity_as_string`,
+    /// see [crate::Db::set_validity_as_string], and `float_format` and `big_int_as_string`,
+    /// see [crate::Db::set_output_options], and `read_only`, see [crate::Db::set_read_only].
+    /// With `read_only`, the `sqlite` engine also opens the file with read-only flags,
+    /// see [crate::new_cozo_sqlite_read_only]. The `sqlite` engine also takes the fields of
+    /// [crate::SqliteOptions], e.g. `max_connections`, and the `rocksdb` engine those of
+    /// [crate::RocksDbOptions], e.g. `statistics`. Other options are only used by `tikv`.
     #[allow(unused_variables)]
     pub fn new(engine: &str, path: impl AsRef<Path>, options: &str) -> Result<Self> {
         let options = if options.is_empty() { "{}" } else { options };
-        Ok(match engine {
+        #[derive(serde_derive::Deserialize)]
+        struct CommonOpts {
+            #[serde(default)]
+            plan_cache_path: Option<String>,
+            #[serde(default)]
+            validity_as_string: bool,
+            #[serde(default)]
+            read_only: bool,
+            #[serde(flatten)]
+            output_options: OutputOptions,
+        }
+        let common_opts: CommonOpts = serde_json::from_str(options).into_diagnostic()?;
+        let mut ret = match engine {
             "mem" => Self::Mem(new_cozo_mem()?),
             #[cfg(feature = "storage-sqlite")]
-            "sqlite" => Self::Sqlite(new_cozo_sqlite(path)?),
+            "sqlite" => {
+                let opts: SqliteOptions = serde_json::from_str(options).into_diagnostic()?;
+                Self::Sqlite(new_cozo_sqlite_with_options(path, opts)?)
+            }
             #[cfg(feature = "storage-rocksdb")]
-            "rocksdb" => Self::RocksDb(new_cozo_rocksdb(path)?),
+            "rocksdb" => {
+                let opts: RocksDbOptions = serde_json::from_str(options).into_diagnostic()?;
+                Self::RocksDb(new_cozo_rocksdb_with_options(path, opts)?)
+            }
             #[cfg(feature = "storage-sled")]
             "sled" => Self::Sled(new_cozo_sled(path)?),
             #[cfg(feature = "storage-tikv")]
@@ -150,7 +213,61 @@ impl DbInstance {
                 "database engine '{}' not supported (maybe not compiled in)",
                 k
             ),
-        })
+        };
+        if let Some(plan_cache_path) = common_opts.plan_cache_path {
+            match &mut ret {
+                DbInstance::Mem(db) => db.set_plan_cache_path(plan_cache_path),
+                #[cfg(feature = "storage-sqlite")]
+                DbInstance::Sqlite(db) => db.set_plan_cache_path(plan_cache_path),
+                #[cfg(feature = "storage-rocksdb")]
+                DbInstance::RocksDb(db) => db.set_plan_cache_path(plan_cache_path),
+                #[cfg(feature = "storage-sled")]
+                DbInstance::Sled(db) => db.set_plan_cache_path(plan_cache_path),
+                #[cfg(feature = "storage-tikv")]
+                DbInstance::TiKv(db) => db.set_plan_cache_path(plan_cache_path),
+            }
+        }
+        if common_opts.validity_as_string {
+            match &mut ret {
+                DbInstance::Mem(db) => db.set_validity_as_string(true),
+                #[cfg(feature = "storage-sqlite")]
+                DbInstance::Sqlite(db) => db.set_validity_as_string(true),
+                #[cfg(feature = "storage-rocksdb")]
+                DbInstance::RocksDb(db) => db.set_validity_as_string(true),
+                #[cfg(feature = "storage-sled")]
+                DbInstance::Sled(db) => db.set_validity_as_string(true),
+                #[cfg(feature = "storage-tikv")]
+                DbInstance::TiKv(db) => db.set_validity_as_string(true),
+            }
+        }
+        if common_opts.output_options != OutputOptions::default() {
+            let opts = common_opts.output_options;
+            match &mut ret {
+                DbInstance::Mem(db) => db.set_output_options(opts),
+                #[cfg(feature = "storage-sqlite")]
+                DbInstance::Sqlite(db) => db.set_output_options(opts),
+                #[cfg(feature = "storage-rocksdb")]
+                DbInstance::RocksDb(db) => db.set_output_options(opts),
+                #[cfg(feature = "storage-sled")]
+                DbInstance::Sled(db) => db.set_output_options(opts),
+                #[cfg(feature = "storage-tikv")]
+                DbInstance::TiKv(db) => db.set_output_options(opts),
+            }
+        }
+        if common_opts.read_only {
+            match &mut ret {
+                DbInstance::Mem(db) => db.set_read_only(true),
+                #[cfg(feature = "storage-sqlite")]
+                DbInstance::Sqlite(db) => db.set_read_only(true),
+                #[cfg(feature = "storage-rocksdb")]
+                DbInstance::RocksDb(db) => db.set_read_only(true),
+                #[cfg(feature = "storage-sled")]
+                DbInstance::Sled(db) => db.set_read_only(true),
+                #[cfg(feature = "storage-tikv")]
+                DbInstance::TiKv(db) => db.set_read_only(true),
+            }
+        }
+        Ok(ret)
     }
     /// Same as [Self::new], but inputs and error messages are all in strings
     pub fn new_with_str(
@@ -178,6 +295,286 @@ impl DbInstance {
             DbInstance::TiKv(db) => db.run_script(payload, params),
         }
     }
+    /// Dispatcher method. See [crate::Db::run_script_with_retry].
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn run_script_with_retry(
+        &self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+        policy: RetryPolicy,
+    ) -> Result<NamedRows> {
+        match self {
+            DbInstance::Mem(db) => db.run_script_with_retry(payload, params, policy),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.run_script_with_retry(payload, params, policy),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.run_script_with_retry(payload, params, policy),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.run_script_with_retry(payload, params, policy),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.run_script_with_retry(payload, params, policy),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::run_script_with_timeout].
+    pub fn run_script_with_timeout(
+        &self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+        timeout: Duration,
+    ) -> Result<NamedRows> {
+        match self {
+            DbInstance::Mem(db) => db.run_script_with_timeout(payload, params, timeout),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.run_script_with_timeout(payload, params, timeout),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.run_script_with_timeout(payload, params, timeout),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.run_script_with_timeout(payload, params, timeout),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.run_script_with_timeout(payload, params, timeout),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::run_script_with_relations].
+    pub fn run_script_with_relations(
+        &self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+        relations: BTreeMap<String, NamedRows>,
+    ) -> Result<NamedRows> {
+        match self {
+            DbInstance::Mem(db) => db.run_script_with_relations(payload, params, relations),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.run_script_with_relations(payload, params, relations),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.run_script_with_relations(payload, params, relations),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.run_script_with_relations(payload, params, relations),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.run_script_with_relations(payload, params, relations),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::run_script_arrow].
+    #[cfg(feature = "arrow")]
+    pub fn run_script_arrow(
+        &self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+    ) -> Result<arrow::record_batch::RecordBatch> {
+        match self {
+            DbInstance::Mem(db) => db.run_script_arrow(payload, params),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.run_script_arrow(payload, params),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.run_script_arrow(payload, params),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.run_script_arrow(payload, params),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.run_script_arrow(payload, params),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::run_typed].
+    pub fn run_typed<T: DeserializeOwned>(
+        &self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+    ) -> Result<Vec<T>> {
+        match self {
+            DbInstance::Mem(db) => db.run_typed(payload, params),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.run_typed(payload, params),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.run_typed(payload, params),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.run_typed(payload, params),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.run_typed(payload, params),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::put_rows].
+    pub fn put_rows<T: Serialize>(
+        &self,
+        relation: &str,
+        rows: impl IntoIterator<Item = T>,
+    ) -> Result<()> {
+        match self {
+            DbInstance::Mem(db) => db.put_rows(relation, rows),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.put_rows(relation, rows),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.put_rows(relation, rows),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.put_rows(relation, rows),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.put_rows(relation, rows),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::put_rows_with_options].
+    pub fn put_rows_with_options<T: Serialize>(
+        &self,
+        relation: &str,
+        rows: impl IntoIterator<Item = T>,
+        options: PutRowsOptions,
+    ) -> Result<()> {
+        match self {
+            DbInstance::Mem(db) => db.put_rows_with_options(relation, rows, options),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.put_rows_with_options(relation, rows, options),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.put_rows_with_options(relation, rows, options),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.put_rows_with_options(relation, rows, options),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.put_rows_with_options(relation, rows, options),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::import_rows].
+    pub fn import_rows(
+        &self,
+        relation: &str,
+        headers: &[&str],
+        rows: impl Iterator<Item = Vec<DataValue>>,
+        options: ImportRowsOptions,
+    ) -> Result<usize> {
+        match self {
+            DbInstance::Mem(db) => db.import_rows(relation, headers, rows, options),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.import_rows(relation, headers, rows, options),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.import_rows(relation, headers, rows, options),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.import_rows(relation, headers, rows, options),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.import_rows(relation, headers, rows, options),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::import_rows_with_progress].
+    pub fn import_rows_with_progress(
+        &self,
+        relation: &str,
+        headers: &[&str],
+        rows: impl Iterator<Item = Vec<DataValue>>,
+        options: ImportRowsOptions,
+        on_progress: impl FnMut(usize) -> bool,
+    ) -> Result<usize> {
+        match self {
+            DbInstance::Mem(db) => {
+                db.import_rows_with_progress(relation, headers, rows, options, on_progress)
+            }
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => {
+                db.import_rows_with_progress(relation, headers, rows, options, on_progress)
+            }
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => {
+                db.import_rows_with_progress(relation, headers, rows, options, on_progress)
+            }
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => {
+                db.import_rows_with_progress(relation, headers, rows, options, on_progress)
+            }
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => {
+                db.import_rows_with_progress(relation, headers, rows, options, on_progress)
+            }
+        }
+    }
+    /// Dispatcher method. See [crate::Db::run_script_as].
+    pub fn run_script_as(
+        &self,
+        identity: &str,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+    ) -> Result<NamedRows> {
+        match self {
+            DbInstance::Mem(db) => db.run_script_as(identity, payload, params),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.run_script_as(identity, payload, params),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.run_script_as(identity, payload, params),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.run_script_as(identity, payload, params),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.run_script_as(identity, payload, params),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::explain_script].
+    pub fn explain_script(
+        &self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+    ) -> Result<NamedRows> {
+        match self {
+            DbInstance::Mem(db) => db.explain_script(payload, params),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.explain_script(payload, params),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.explain_script(payload, params),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.explain_script(payload, params),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.explain_script(payload, params),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::prepare].
+    pub fn prepare(&self, script: &str) -> Result<PreparedQuery> {
+        match self {
+            DbInstance::Mem(db) => db.prepare(script),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.prepare(script),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.prepare(script),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.prepare(script),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.prepare(script),
+        }
+    }
+    /// Dispatcher method. See [crate::PreparedQuery::run].
+    pub fn run_prepared(
+        &self,
+        query: &PreparedQuery,
+        params: BTreeMap<String, DataValue>,
+    ) -> Result<NamedRows> {
+        match self {
+            DbInstance::Mem(db) => query.run(db, params),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => query.run(db, params),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => query.run(db, params),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => query.run(db, params),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => query.run(db, params),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::set_clock].
+    pub fn set_clock(&self, clock: Box<dyn Fn() -> i64 + Send + Sync>) {
+        match self {
+            DbInstance::Mem(db) => db.set_clock(clock),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.set_clock(clock),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.set_clock(clock),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.set_clock(clock),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.set_clock(clock),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::use_system_clock].
+    pub fn use_system_clock(&self) {
+        match self {
+            DbInstance::Mem(db) => db.use_system_clock(),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.use_system_clock(),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.use_system_clock(),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.use_system_clock(),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.use_system_clock(),
+        }
+    }
     /// Run the CozoScript passed in. The `params` argument is a map of parameters.
     /// Fold any error into the return JSON itself.
     /// See [crate::Db::run_script].
@@ -186,11 +583,46 @@ impl DbInstance {
         payload: &str,
         params: BTreeMap<String, DataValue>,
     ) -> JsonValue {
+        self.run_script_fold_err_with_format(payload, params).0
+    }
+    /// Same as [Self::run_script_fold_err], but returns the JSON as text, in which floats are
+    /// written as required by the `:float_format` option of the query, or the default
+    /// set by [crate::Db::set_output_options].
+    pub fn run_script_fold_err_str(
+        &self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+    ) -> String {
+        let (j_val, float_format) = self.run_script_fold_err_with_format(payload, params);
+        json_to_string(&j_val, float_format)
+    }
+    /// Same as [Self::run_script_fold_err], also returning how floats should be written
+    /// when the JSON is turned into text by [json_to_string].
+    pub fn run_script_fold_err_with_format(
+        &self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+    ) -> (JsonValue, FloatFormat) {
+        self.fold_err_with_format(payload, params, Default::default(), None)
+    }
+    fn fold_err_with_format(
+        &self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+        relations: BTreeMap<String, NamedRows>,
+        timeout: Option<Duration>,
+    ) -> (JsonValue, FloatFormat) {
         #[cfg(not(target_arch = "wasm32"))]
         let start = Instant::now();
 
-        match self.run_script(payload, params) {
+        let res = match timeout {
+            None if relations.is_empty() => self.run_script(payload, params),
+            None => self.run_script_with_relations(payload, params, relations),
+            Some(timeout) => self.run_script_with_timeout(payload, params, timeout),
+        };
+        match res {
             Ok(named_rows) => {
+                let float_format = named_rows.float_format();
                 let mut j_val = named_rows.into_json();
                 #[cfg(not(target_arch = "wasm32"))]
                 let took = start.elapsed().as_secs_f64();
@@ -199,29 +631,131 @@ impl DbInstance {
                 #[cfg(not(target_arch = "wasm32"))]
                 map.insert("took".to_string(), json!(took));
 
-                j_val
+                (j_val, float_format)
             }
-            Err(err) => format_error_as_json(err, Some(payload)),
+            Err(err) => (
+                format_error_as_json(err, Some(payload)),
+                FloatFormat::Shortest,
+            ),
         }
     }
     /// Run the CozoScript passed in. The `params` argument is a map of parameters formatted as JSON.
+    /// The key `"timeout"` is not a parameter: it is the number of seconds the whole script
+    /// may run for, see [crate::Db::run_script_with_timeout].
+    /// Keys starting with `_` whose values are objects with `headers` and `rows`, as returned
+    /// for query results, are not parameters either: they are relations given with the script,
+    /// see [crate::Db::run_script_with_relations]. They cannot be given with a timeout.
     /// See [crate::Db::run_script].
     pub fn run_script_str(&self, payload: &str, params: &str) -> String {
-        let params_json = if params.is_empty() {
-            BTreeMap::default()
-        } else {
-            match serde_json::from_str::<BTreeMap<String, JsonValue>>(params) {
-                Ok(map) => map
-                    .into_iter()
-                    .map(|(k, v)| (k, DataValue::from(v)))
-                    .collect(),
-                Err(_) => {
-                    return json!({"ok": false, "message": "params argument is not a JSON map"})
-                        .to_string()
+        let (mut params_json, relations) = match params_and_relations_from_str(params) {
+            Ok(res) => res,
+            Err(message) => return json!({"ok": false, "message": message}).to_string(),
+        };
+        let timeout = match params_json.remove("timeout") {
+            None => None,
+            Some(secs) => match secs.get_float() {
+                Some(secs) if secs.is_finite() && secs > 0. => Some(Duration::from_secs_f64(secs)),
+                _ => {
+                    let message = "timeout in params is not a positive number of seconds";
+                    return json!({"ok": false, "message": message}).to_string();
                 }
+            },
+        };
+        if timeout.is_some() && !relations.is_empty() {
+            let message = "timeout in params cannot be given with relations";
+            return json!({"ok": false, "message": message}).to_string();
+        }
+        let (j_val, float_format) =
+            self.fold_err_with_format(payload, params_json, relations, timeout);
+        json_to_string(&j_val, float_format)
+    }
+    /// Dispatcher method. See [crate::Db::list_running].
+    pub fn list_running(&self) -> Result<Vec<RunningQuery>> {
+        match self {
+            DbInstance::Mem(db) => db.list_running(),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.list_running(),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.list_running(),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.list_running(),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.list_running(),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::kill_query].
+    pub fn kill_query(&self, id: u64) -> bool {
+        match self {
+            DbInstance::Mem(db) => db.kill_query(id),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.kill_query(id),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.kill_query(id),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.kill_query(id),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.kill_query(id),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::run_script_streaming].
+    pub fn run_script_streaming(
+        &self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+        on_row: impl FnMut(Vec<DataValue>) -> Result<ControlFlow<()>>,
+    ) -> Result<Vec<String>> {
+        match self {
+            DbInstance::Mem(db) => db.run_script_streaming(payload, params, on_row),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.run_script_streaming(payload, params, on_row),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.run_script_streaming(payload, params, on_row),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.run_script_streaming(payload, params, on_row),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.run_script_streaming(payload, params, on_row),
+        }
+    }
+    /// Run the read-only query passed in, passing each row of the result to `on_row` as a JSON
+    /// array, which returns `false` to stop the query. The `params` argument is a map of
+    /// parameters formatted as JSON. Returns the JSON of the result, with the headers only.
+    /// See [crate::Db::run_script_streaming].
+    pub fn run_script_streaming_str(
+        &self,
+        payload: &str,
+        params: &str,
+        mut on_row: impl FnMut(&str) -> bool,
+    ) -> String {
+        let params_json = match params_from_str(params) {
+            Some(params) => params,
+            None => {
+                return json!({"ok": false, "message": "params argument is not a JSON map"})
+                    .to_string()
             }
         };
-        self.run_script_fold_err(payload, params_json).to_string()
+        #[cfg(not(target_arch = "wasm32"))]
+        let start = Instant::now();
+
+        let res = self.run_script_streaming(payload, params_json, |row| {
+            let row = JsonValue::Array(row.into_iter().map(JsonValue::from).collect());
+            Ok(if on_row(&row.to_string()) {
+                ControlFlow::Continue(())
+            } else {
+                ControlFlow::Break(())
+            })
+        });
+        match res {
+            Ok(headers) => {
+                let mut j_val = json!({"ok": true, "headers": headers});
+                #[cfg(not(target_arch = "wasm32"))]
+                j_val
+                    .as_object_mut()
+                    .unwrap()
+                    .insert("took".to_string(), json!(start.elapsed().as_secs_f64()));
+                j_val.to_string()
+            }
+            Err(err) => format_error_as_json(err, Some(payload)).to_string(),
+        }
     }
     /// Dispatcher method. See [crate::Db::export_relations].
     pub fn export_relations<'a, I, T>(&self, relations: I) -> Result<BTreeMap<String, NamedRows>>
@@ -281,6 +815,24 @@ impl DbInstance {
             DbInstance::TiKv(db) => db.import_relations(data),
         }
     }
+    /// Dispatcher method. See [crate::Db::import_relations_with_options].
+    pub fn import_relations_with_options(
+        &self,
+        data: BTreeMap<String, NamedRows>,
+        options: ImportOptions,
+    ) -> Result<ImportReport> {
+        match self {
+            DbInstance::Mem(db) => db.import_relations_with_options(data, options),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.import_relations_with_options(data, options),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.import_relations_with_options(data, options),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.import_relations_with_options(data, options),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.import_relations_with_options(data, options),
+        }
+    }
     /// Import a relation, the data is given as a JSON string, and the returned result is converted into a string.
     /// See [crate::Db::import_relations].
     pub fn import_relations_str(&self, data: &str) -> String {
@@ -299,6 +851,86 @@ impl DbInstance {
         let j_obj: BTreeMap<String, NamedRows> = serde_json::from_str(data).into_diagnostic()?;
         self.import_relations(j_obj)
     }
+    /// Import relations with options, both given as JSON strings, and the returned result,
+    /// including the report of what happened to the rows, is converted into a string.
+    /// See [crate::Db::import_relations_with_options].
+    pub fn import_relations_with_options_str(&self, data: &str, options: &str) -> String {
+        match self.import_relations_with_options_str_with_err(data, options) {
+            Ok(report) => json!({"ok": true, "report": report}).to_string(),
+            Err(err) => json!({"ok": false, "message": err.to_string()}).to_string(),
+        }
+    }
+    /// Import relations with options, both given as JSON strings.
+    /// See [crate::Db::import_relations_with_options].
+    pub fn import_relations_with_options_str_with_err(
+        &self,
+        data: &str,
+        options: &str,
+    ) -> Result<ImportReport> {
+        let j_obj: BTreeMap<String, JsonValue> = serde_json::from_str(data).into_diagnostic()?;
+        let mut relations = BTreeMap::new();
+        for (name, rows) in j_obj {
+            let rows = NamedRows::from_json(&rows)?;
+            relations.insert(name, rows);
+        }
+        let options = if options.is_empty() { "{}" } else { options };
+        let options: ImportOptions = serde_json::from_str(options).into_diagnostic()?;
+        self.import_relations_with_options(relations, options)
+    }
+    /// Dispatcher method. See [crate::Db::export_relations_jsonl].
+    pub fn export_relations_jsonl(&self, relation: &str, out: impl Write) -> Result<usize> {
+        match self {
+            DbInstance::Mem(db) => db.export_relations_jsonl(relation, out),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.export_relations_jsonl(relation, out),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.export_relations_jsonl(relation, out),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.export_relations_jsonl(relation, out),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.export_relations_jsonl(relation, out),
+        }
+    }
+    /// Export a relation into a JSON Lines file, with JSON string return value.
+    /// The payload is `{"relation": ..., "path": ...}`.
+    /// See [crate::Db::export_relations_jsonl].
+    pub fn export_relations_jsonl_str(&self, payload: &str) -> String {
+        match self.export_relations_jsonl_str_inner(payload) {
+            Ok(n) => json!({"ok": true, "exported": n}).to_string(),
+            Err(err) => json!({"ok": false, "message": err.to_string()}).to_string(),
+        }
+    }
+    fn export_relations_jsonl_str_inner(&self, payload: &str) -> Result<usize> {
+        let payload: JsonlPayload = serde_json::from_str(payload).into_diagnostic()?;
+        self.export_relations_jsonl(&payload.relation, create_jsonl(&payload.path)?)
+    }
+    /// Dispatcher method. See [crate::Db::import_relations_jsonl].
+    pub fn import_relations_jsonl(&self, relation: &str, input: impl BufRead) -> Result<usize> {
+        match self {
+            DbInstance::Mem(db) => db.import_relations_jsonl(relation, input),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.import_relations_jsonl(relation, input),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.import_relations_jsonl(relation, input),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.import_relations_jsonl(relation, input),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.import_relations_jsonl(relation, input),
+        }
+    }
+    /// Import a relation from a JSON Lines file, with JSON string return value.
+    /// The payload is `{"relation": ..., "path": ...}`.
+    /// See [crate::Db::import_relations_jsonl].
+    pub fn import_relations_jsonl_str(&self, payload: &str) -> String {
+        match self.import_relations_jsonl_str_inner(payload) {
+            Ok(n) => json!({"ok": true, "imported": n}).to_string(),
+            Err(err) => json!({"ok": false, "message": err.to_string()}).to_string(),
+        }
+    }
+    fn import_relations_jsonl_str_inner(&self, payload: &str) -> Result<usize> {
+        let payload: JsonlPayload = serde_json::from_str(payload).into_diagnostic()?;
+        self.import_relations_jsonl(&payload.relation, open_jsonl(&payload.path)?)
+    }
     /// Dispatcher method. See [crate::Db::backup_db].
     pub fn backup_db(&self, out_file: impl AsRef<Path>) -> Result<()> {
         match self {
@@ -321,6 +953,65 @@ impl DbInstance {
             Err(err) => json!({"ok": false, "message": err.to_string()}).to_string(),
         }
     }
+    /// Dispatcher method. See [crate::Db::backup_db_with_progress].
+    pub fn backup_db_with_progress(
+        &self,
+        out_file: impl AsRef<Path>,
+        on_progress: impl FnMut(BackupProgress) -> bool,
+    ) -> Result<bool> {
+        match self {
+            DbInstance::Mem(db) => db.backup_db_with_progress(out_file, on_progress),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.backup_db_with_progress(out_file, on_progress),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.backup_db_with_progress(out_file, on_progress),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.backup_db_with_progress(out_file, on_progress),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.backup_db_with_progress(out_file, on_progress),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::backup_db_resume].
+    pub fn backup_db_resume(
+        &self,
+        out_file: impl AsRef<Path>,
+        on_progress: impl FnMut(BackupProgress) -> bool,
+    ) -> Result<bool> {
+        match self {
+            DbInstance::Mem(db) => db.backup_db_resume(out_file, on_progress),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.backup_db_resume(out_file, on_progress),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.backup_db_resume(out_file, on_progress),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.backup_db_resume(out_file, on_progress),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.backup_db_resume(out_file, on_progress),
+        }
+    }
+    /// Backup the running database into an Sqlite file in chunks, with JSON string return
+    /// value. `on_progress` is called with each [BackupProgress] formatted as JSON, and
+    /// cancels the backup by returning `false`. If `resume` is true, a cancelled or
+    /// interrupted backup into the file is continued.
+    /// See [crate::Db::backup_db_with_progress] and [crate::Db::backup_db_resume].
+    pub fn backup_db_with_progress_str(
+        &self,
+        out_file: impl AsRef<Path>,
+        resume: bool,
+        mut on_progress: impl FnMut(&str) -> bool,
+    ) -> String {
+        let on_progress =
+            |progress: BackupProgress| on_progress(&serde_json::to_string(&progress).unwrap());
+        let res = if resume {
+            self.backup_db_resume(out_file, on_progress)
+        } else {
+            self.backup_db_with_progress(out_file, on_progress)
+        };
+        match res {
+            Ok(complete) => json!({"ok": true, "complete": complete}).to_string(),
+            Err(err) => json!({"ok": false, "message": err.to_string()}).to_string(),
+        }
+    }
     /// Dispatcher method. See [crate::Db::restore_backup].
     pub fn restore_backup(&self, in_file: impl AsRef<Path>) -> Result<()> {
         match self {
@@ -343,6 +1034,70 @@ impl DbInstance {
             Err(err) => json!({"ok": false, "message": err.to_string()}).to_string(),
         }
     }
+    /// Dispatcher method. See [crate::Db::restore_backup_resume].
+    pub fn restore_backup_resume(
+        &self,
+        in_file: impl AsRef<Path>,
+        on_progress: impl FnMut(BackupProgress) -> bool,
+    ) -> Result<bool> {
+        match self {
+            DbInstance::Mem(db) => db.restore_backup_resume(in_file, on_progress),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.restore_backup_resume(in_file, on_progress),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.restore_backup_resume(in_file, on_progress),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.restore_backup_resume(in_file, on_progress),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.restore_backup_resume(in_file, on_progress),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::backup_manifest].
+    pub fn backup_manifest(&self, backup_file: impl AsRef<Path>) -> Result<BackupManifest> {
+        match self {
+            DbInstance::Mem(db) => db.backup_manifest(backup_file),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.backup_manifest(backup_file),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.backup_manifest(backup_file),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.backup_manifest(backup_file),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.backup_manifest(backup_file),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::backup_incremental].
+    pub fn backup_incremental(
+        &self,
+        base: &BackupManifest,
+        out_file: impl AsRef<Path>,
+    ) -> Result<BackupManifest> {
+        match self {
+            DbInstance::Mem(db) => db.backup_incremental(base, out_file),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.backup_incremental(base, out_file),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.backup_incremental(base, out_file),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.backup_incremental(base, out_file),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.backup_incremental(base, out_file),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::restore_chain].
+    pub fn restore_chain(&self, backup_files: &[impl AsRef<Path>]) -> Result<()> {
+        match self {
+            DbInstance::Mem(db) => db.restore_chain(backup_files),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.restore_chain(backup_files),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.restore_chain(backup_files),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.restore_chain(backup_files),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.restore_chain(backup_files),
+        }
+    }
     /// Dispatcher method. See [crate::Db::import_from_backup].
     pub fn import_from_backup(
         &self,
@@ -361,8 +1116,51 @@ impl DbInstance {
             DbInstance::TiKv(db) => db.import_from_backup(in_file, relations),
         }
     }
-    /// Import relations from an Sqlite backup, with JSON string return value.
-    /// See [crate::Db::import_from_backup].
+    /// Dispatcher method. See [crate::Db::import_from_backup_with_options].
+    pub fn import_from_backup_with_options(
+        &self,
+        in_file: impl AsRef<Path>,
+        relations: &[String],
+        options: ImportOptions,
+    ) -> Result<()> {
+        match self {
+            DbInstance::Mem(db) => db.import_from_backup_with_options(in_file, relations, options),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => {
+                db.import_from_backup_with_options(in_file, relations, options)
+            }
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => {
+                db.import_from_backup_with_options(in_file, relations, options)
+            }
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.import_from_backup_with_options(in_file, relations, options),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.import_from_backup_with_options(in_file, relations, options),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::import_from_backup_renamed].
+    pub fn import_from_backup_renamed(
+        &self,
+        in_file: impl AsRef<Path>,
+        mappings: &[(String, String)],
+    ) -> Result<()> {
+        match self {
+            DbInstance::Mem(db) => db.import_from_backup_renamed(in_file, mappings),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.import_from_backup_renamed(in_file, mappings),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.import_from_backup_renamed(in_file, mappings),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.import_from_backup_renamed(in_file, mappings),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.import_from_backup_renamed(in_file, mappings),
+        }
+    }
+    /// Import relations from an Sqlite backup, with JSON string return value. The payload is
+    /// `{"path": ..., "relations": [...], "skip_constraint_checks": ...}`, where the last
+    /// is optional.
+    /// See [crate::Db::import_from_backup_with_options].
     pub fn import_from_backup_str(&self, payload: &str) -> String {
         match self.import_from_backup_str_inner(payload) {
             Ok(_) => json!({"ok": true}).to_string(),
@@ -374,10 +1172,60 @@ impl DbInstance {
         struct Payload {
             path: String,
             relations: Vec<String>,
+            #[serde(default)]
+            skip_constraint_checks: bool,
+        }
+        let json_payload: Payload = serde_json::from_str(payload).into_diagnostic()?;
+        let options = ImportOptions {
+            skip_constraint_checks: json_payload.skip_constraint_checks,
+            ..Default::default()
+        };
+
+        self.import_from_backup_with_options(&json_payload.path, &json_payload.relations, options)
+    }
+
+    /// Dispatcher method. See [crate::Db::verify_backup].
+    pub fn verify_backup(
+        &self,
+        in_file: impl AsRef<Path>,
+        options: VerifyBackupOptions,
+    ) -> Result<NamedRows> {
+        match self {
+            DbInstance::Mem(db) => db.verify_backup(in_file, options),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.verify_backup(in_file, options),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.verify_backup(in_file, options),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.verify_backup(in_file, options),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.verify_backup(in_file, options),
+        }
+    }
+    /// Verify a backup, with JSON string return value. The payload is
+    /// `{"path": ..., "sample": ..., "valid_at": ...}`, where only the path is required.
+    /// See [crate::Db::verify_backup].
+    pub fn verify_backup_str(&self, payload: &str) -> String {
+        match self.verify_backup_str_inner(payload) {
+            Ok(rows) => {
+                let mut j_val = rows.into_json();
+                let map = j_val.as_object_mut().unwrap();
+                map.insert("ok".to_string(), json!(true));
+                j_val.to_string()
+            }
+            Err(err) => json!({"ok": false, "message": err.to_string()}).to_string(),
+        }
+    }
+    fn verify_backup_str_inner(&self, payload: &str) -> Result<NamedRows> {
+        #[derive(serde_derive::Deserialize)]
+        struct Payload {
+            path: String,
+            #[serde(flatten)]
+            options: VerifyBackupOptions,
         }
         let json_payload: Payload = serde_json::from_str(payload).into_diagnostic()?;
 
-        self.import_from_backup(&json_payload.path, &json_payload.relations)
+        self.verify_backup(&json_payload.path, json_payload.options)
     }
 
     /// Dispatcher method. See [crate::Db::register_callback].
@@ -400,6 +1248,38 @@ impl DbInstance {
         }
     }
 
+    /// Dispatcher method. See [crate::Db::register_callback_filtered].
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn register_callback_filtered(
+        &self,
+        relation: &str,
+        filter: &str,
+        deliver_snapshot: bool,
+        capacity: Option<usize>,
+    ) -> Result<(u32, Receiver<(CallbackOp, NamedRows, NamedRows)>)> {
+        match self {
+            DbInstance::Mem(db) => {
+                db.register_callback_filtered(relation, filter, deliver_snapshot, capacity)
+            }
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => {
+                db.register_callback_filtered(relation, filter, deliver_snapshot, capacity)
+            }
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => {
+                db.register_callback_filtered(relation, filter, deliver_snapshot, capacity)
+            }
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => {
+                db.register_callback_filtered(relation, filter, deliver_snapshot, capacity)
+            }
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => {
+                db.register_callback_filtered(relation, filter, deliver_snapshot, capacity)
+            }
+        }
+    }
+
     /// Dispatcher method. See [crate::Db::unregister_callback].
     #[cfg(not(target_arch = "wasm32"))]
     pub fn unregister_callback(&self, id: u32) -> bool {
@@ -415,6 +1295,76 @@ impl DbInstance {
             DbInstance::TiKv(db) => db.unregister_callback(id),
         }
     }
+    /// Dispatcher method. See [crate::Db::subscribe_query].
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn subscribe_query(
+        &self,
+        script: &str,
+        params: BTreeMap<String, DataValue>,
+        options: SubscriptionOptions,
+    ) -> Result<SubscriptionHandle> {
+        match self {
+            DbInstance::Mem(db) => db.subscribe_query(script, params, options),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.subscribe_query(script, params, options),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.subscribe_query(script, params, options),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.subscribe_query(script, params, options),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.subscribe_query(script, params, options),
+        }
+    }
+
+    /// Dispatcher method. See [crate::Db::unsubscribe_query].
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn unsubscribe_query(&self, id: u32) -> bool {
+        match self {
+            DbInstance::Mem(db) => db.unsubscribe_query(id),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.unsubscribe_query(id),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.unsubscribe_query(id),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.unsubscribe_query(id),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.unsubscribe_query(id),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::run_script_async].
+    #[cfg(feature = "async")]
+    pub fn run_script_async(
+        &self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+    ) -> BoxFuture<'static, Result<NamedRows>> {
+        match self {
+            DbInstance::Mem(db) => db.run_script_async(payload, params).boxed(),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.run_script_async(payload, params).boxed(),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.run_script_async(payload, params).boxed(),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.run_script_async(payload, params).boxed(),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.run_script_async(payload, params).boxed(),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::subscribe].
+    #[cfg(feature = "async")]
+    pub fn subscribe(&self, relation: &str) -> BoxStream<'static, CallbackEvent> {
+        match self {
+            DbInstance::Mem(db) => db.subscribe(relation).boxed(),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.subscribe(relation).boxed(),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.subscribe(relation).boxed(),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.subscribe(relation).boxed(),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.subscribe(relation).boxed(),
+        }
+    }
     /// Dispatcher method. See [crate::Db::register_fixed_rule].
     pub fn register_fixed_rule<R>(&self, name: String, rule_impl: R) -> Result<()>
     where
@@ -446,6 +1396,54 @@ impl DbInstance {
             DbInstance::TiKv(db) => db.unregister_fixed_rule(name),
         }
     }
+    /// Dispatcher method. See [crate::Db::register_function].
+    pub fn register_function<F>(&self, name: String, arity: usize, func: F) -> Result<()>
+    where
+        F: Fn(&[DataValue]) -> Result<DataValue> + Send + Sync + 'static,
+    {
+        match self {
+            DbInstance::Mem(db) => db.register_function(name, arity, func),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.register_function(name, arity, func),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.register_function(name, arity, func),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.register_function(name, arity, func),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.register_function(name, arity, func),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::register_pure_function].
+    pub fn register_pure_function<F>(&self, name: String, arity: usize, func: F) -> Result<()>
+    where
+        F: Fn(&[DataValue]) -> Result<DataValue> + Send + Sync + 'static,
+    {
+        match self {
+            DbInstance::Mem(db) => db.register_pure_function(name, arity, func),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.register_pure_function(name, arity, func),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.register_pure_function(name, arity, func),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.register_pure_function(name, arity, func),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.register_pure_function(name, arity, func),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::unregister_function].
+    pub fn unregister_function(&self, name: &str) -> bool {
+        match self {
+            DbInstance::Mem(db) => db.unregister_function(name),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.unregister_function(name),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.unregister_function(name),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.unregister_function(name),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.unregister_function(name),
+        }
+    }
 
     /// Dispatcher method. See [crate::Db::run_multi_transaction]
     pub fn run_multi_transaction(
@@ -478,10 +1476,112 @@ impl DbInstance {
             receiver: db2app_recv,
         }
     }
+    /// The transactions begun by [Self::begin_transaction_str] on this database
+    fn str_transactions(&self) -> &Arc<StrTransactions> {
+        match self {
+            DbInstance::Mem(db) => &db.str_transactions,
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => &db.str_transactions,
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => &db.str_transactions,
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => &db.str_transactions,
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => &db.str_transactions,
+        }
+    }
+    /// Begin a multi-transaction, with JSON string return value `{"ok": true, "id": ...}`.
+    /// The transaction is then used through its id with [Self::run_script_in_transaction_str],
+    /// and ended with [Self::commit_transaction_str] or [Self::abort_transaction_str].
+    /// The ids are only valid for this database. Transactions that are never ended are
+    /// rolled back once every handle of the database is dropped. See [Self::multi_transaction].
+    pub fn begin_transaction_str(&self, write: bool) -> String {
+        // the thread running the transaction gets a handle without the transactions,
+        // which would otherwise keep themselves alive
+        let mut detached = self.clone();
+        match &mut detached {
+            DbInstance::Mem(db) => db.str_transactions = Default::default(),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.str_transactions = Default::default(),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.str_transactions = Default::default(),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.str_transactions = Default::default(),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.str_transactions = Default::default(),
+        }
+        let tx = detached.multi_transaction(write);
+        let id = self.str_transactions().begin(tx);
+        json!({"ok": true, "id": id}).to_string()
+    }
+    /// Run a single script in the multi-transaction with the id given by
+    /// [Self::begin_transaction_str], with JSON string return value as for
+    /// [Self::run_script_str]. If `read_only` is true, the script fails if it writes to
+    /// a stored relation. See [MultiTransaction::run_script].
+    pub fn run_script_in_transaction_str(
+        &self,
+        id: u32,
+        payload: &str,
+        params: &str,
+        read_only: bool,
+    ) -> String {
+        let params_json = match params_from_str(params) {
+            Some(params) => params,
+            None => {
+                return json!({"ok": false, "message": "params argument is not a JSON map"})
+                    .to_string()
+            }
+        };
+        let tx = match self.str_transactions().get(id) {
+            Some(tx) => tx,
+            None => return json!({"ok": false, "message": "transaction not found"}).to_string(),
+        };
+        // one script at a time, so that each call receives its own results
+        let tx = tx.lock().unwrap();
+        let res = if read_only {
+            tx.run_script_read_only(payload, params_json)
+        } else {
+            tx.run_script(payload, params_json)
+        };
+        match res {
+            Ok(named_rows) => {
+                let float_format = named_rows.float_format();
+                let mut j_val = named_rows.into_json();
+                let map = j_val.as_object_mut().unwrap();
+                map.insert("ok".to_string(), json!(true));
+                json_to_string(&j_val, float_format)
+            }
+            Err(err) => format_error_as_json(err, Some(payload)).to_string(),
+        }
+    }
+    /// Commit the multi-transaction with the id given by [Self::begin_transaction_str],
+    /// with JSON string return value. See [MultiTransaction::commit].
+    pub fn commit_transaction_str(&self, id: u32) -> String {
+        self.end_transaction_str(id, MultiTransaction::commit)
+    }
+    /// Abort the multi-transaction with the id given by [Self::begin_transaction_str],
+    /// with JSON string return value. See [MultiTransaction::abort].
+    pub fn abort_transaction_str(&self, id: u32) -> String {
+        self.end_transaction_str(id, MultiTransaction::abort)
+    }
+    fn end_transaction_str(&self, id: u32, end: fn(&MultiTransaction) -> Result<()>) -> String {
+        let tx = match self.str_transactions().remove(id) {
+            Some(tx) => tx,
+            None => return json!({"ok": false, "message": "transaction not found"}).to_string(),
+        };
+        // waits for the script running in the transaction, if any
+        let tx = tx.lock().unwrap();
+        match end(&tx) {
+            Ok(()) => json!({"ok": true}).to_string(),
+            Err(err) => format_error_as_json(err, None).to_string(),
+        }
+    }
 }
 
 /// A multi-transaction handle.
 /// You should use either the fields directly, or the associated functions.
+///
+/// Dropping the handle of a transaction that has not been committed rolls it back.
 pub struct MultiTransaction {
     /// Commands can be sent into the transaction through this channel
     pub sender: Sender<TransactionPayload>,
@@ -496,14 +1596,25 @@ impl MultiTransaction {
         payload: &str,
         params: BTreeMap<String, DataValue>,
     ) -> Result<NamedRows> {
-        if let Err(err) = self
-            .sender
-            .send(TransactionPayload::Query((payload.to_string(), params)))
-        {
+        self.send_query(TransactionPayload::Query((payload.to_string(), params)))
+    }
+    /// Runs a single script in the transaction, failing if it writes to a stored relation.
+    pub fn run_script_read_only(
+        &self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+    ) -> Result<NamedRows> {
+        self.send_query(TransactionPayload::QueryReadOnly((
+            payload.to_string(),
+            params,
+        )))
+    }
+    fn send_query(&self, payload: TransactionPayload) -> Result<NamedRows> {
+        if let Err(err) = self.sender.send(payload) {
             bail!(err);
         }
         match self.receiver.recv() {
-            Ok(r) => r,
+            Ok(r) => r.map_err(CozoError::wrap),
             Err(err) => bail!(err),
         }
     }
@@ -513,7 +1624,7 @@ impl MultiTransaction {
             bail!(err);
         }
         match self.receiver.recv() {
-            Ok(_) => Ok(()),
+            Ok(r) => r.map(|_| ()).map_err(CozoError::wrap),
             Err(err) => bail!(err),
         }
     }
@@ -529,8 +1640,95 @@ impl MultiTransaction {
     }
 }
 
+impl Drop for MultiTransaction {
+    fn drop(&mut self) {
+        // waits for the rollback, so that the locks of the transaction are released on return
+        if self.sender.send(TransactionPayload::Abort).is_ok() {
+            let _ = self.receiver.recv();
+        }
+    }
+}
+
+/// The transactions begun by [DbInstance::begin_transaction_str] on a database, by their ids
+#[derive(Default)]
+pub(crate) struct StrTransactions {
+    count: AtomicU32,
+    txs: Mutex<BTreeMap<u32, Arc<Mutex<MultiTransaction>>>>,
+}
+
+impl StrTransactions {
+    fn begin(&self, tx: MultiTransaction) -> u32 {
+        let id = self.count.fetch_add(1, Ordering::AcqRel);
+        self.txs
+            .lock()
+            .unwrap()
+            .insert(id, Arc::new(Mutex::new(tx)));
+        id
+    }
+    fn get(&self, id: u32) -> Option<Arc<Mutex<MultiTransaction>>> {
+        self.txs.lock().unwrap().get(&id).cloned()
+    }
+    fn remove(&self, id: u32) -> Option<Arc<Mutex<MultiTransaction>>> {
+        self.txs.lock().unwrap().remove(&id)
+    }
+}
+
+/// Parameters given as a JSON map, an empty string meaning none
+/// The payload of the string APIs for JSON Lines files
+#[derive(serde_derive::Deserialize)]
+struct JsonlPayload {
+    relation: String,
+    path: String,
+}
+
+fn params_from_str(params: &str) -> Option<BTreeMap<String, DataValue>> {
+    if params.is_empty() {
+        return Some(BTreeMap::default());
+    }
+    let map = serde_json::from_str::<BTreeMap<String, JsonValue>>(params).ok()?;
+    Some(
+        map.into_iter()
+            .map(|(k, v)| (k, DataValue::from(v)))
+            .collect(),
+    )
+}
+
+/// Splits the JSON map of parameters, taking the values shaped as [NamedRows] under keys
+/// starting with `_` as the relations given with the script.
+fn params_and_relations_from_str(
+    params: &str,
+) -> std::result::Result<(BTreeMap<String, DataValue>, BTreeMap<String, NamedRows>), String> {
+    const NOT_A_MAP: &str = "params argument is not a JSON map";
+    if params.is_empty() {
+        return Ok(Default::default());
+    }
+    let map = serde_json::from_str::<BTreeMap<String, JsonValue>>(params).map_err(|_| NOT_A_MAP)?;
+    let mut params = BTreeMap::new();
+    let mut relations = BTreeMap::new();
+    for (k, v) in map {
+        let is_relation = k.starts_with('_')
+            && v.get("headers").is_some_and(|h| h.is_array())
+            && v.get("rows").is_some_and(|r| r.is_array());
+        if is_relation {
+            let rows = NamedRows::from_json(&v)
+                .map_err(|err| format!("relation '{k}' in params is invalid: {err}"))?;
+            relations.insert(k, rows);
+        } else {
+            params.insert(k, DataValue::from(v));
+        }
+    }
+    Ok((params, relations))
+}
+
 /// Convert error raised by the database into friendly JSON format
 pub fn format_error_as_json(mut err: Report, source: Option<&str>) -> JsonValue {
+    let kind = match err.downcast_ref::<CozoError>() {
+        Some(cozo_err) => cozo_err.kind(),
+        None => {
+            err = CozoError::wrap(err);
+            err.downcast_ref::<CozoError>().unwrap().kind()
+        }
+    };
     if err.source_code().is_none() {
         if let Some(src) = source {
             err = err.with_source_code(src.to_string());
@@ -548,6 +1746,7 @@ pub fn format_error_as_json(mut err: Report, source: Option<&str>) -> JsonValue
         serde_json::from_str(&json_err).expect("parse rendered json error failed");
     let map = json.as_object_mut().unwrap();
     map.insert("ok".to_string(), json!(false));
+    map.insert("kind".to_string(), json!(kind));
     map.insert("display".to_string(), json!(text_err));
     json
 }
@@ -18,7 +18,7 @@ use thiserror::Error;
 use crate::data::expr::{get_op, Bytecode, Expr};
 use crate::data::functions::{
     OP_ADD, OP_AND, OP_COALESCE, OP_CONCAT, OP_DIV, OP_EQ, OP_GE, OP_GT, OP_LE, OP_LIST, OP_LT,
-    OP_MINUS, OP_MOD, OP_MUL, OP_NEGATE, OP_NEQ, OP_OR, OP_POW, OP_SUB,
+    OP_MINUS, OP_MOD, OP_MUL, OP_NEGATE, OP_NEQ, OP_OR, OP_POW, OP_REGEX, OP_SUB,
 };
 use crate::data::symb::Symbol;
 use crate::data::value::DataValue;
@@ -63,17 +63,44 @@ pub(crate) fn expr2bytecode(expr: &Expr, collector: &mut Vec<Bytecode>) {
             val: val.clone(),
             span: *span,
         }),
+        Expr::Param { name, span } => collector.push(Bytecode::Param {
+            name: name.clone(),
+            span: *span,
+        }),
         Expr::Apply { op, args, span } => {
             let arity = args.len();
             for arg in args.iter() {
                 expr2bytecode(arg, collector);
             }
+            if op.name == OP_REGEX.name {
+                collector.push(Bytecode::Regex {
+                    cache: Default::default(),
+                    span: *span,
+                });
+                return;
+            }
             collector.push(Bytecode::Apply {
                 op,
                 arity,
                 span: *span,
             })
         }
+        Expr::UserFn {
+            name,
+            func,
+            args,
+            span,
+        } => {
+            for arg in args.iter() {
+                expr2bytecode(arg, collector);
+            }
+            collector.push(Bytecode::UserFn {
+                name: name.clone(),
+                func: func.clone(),
+                arity: args.len(),
+                span: *span,
+            })
+        }
         Expr::Cond { clauses, span } => {
             let mut return_jump_pos = vec![];
             for (cond, val) in clauses {
@@ -105,12 +132,43 @@ pub(crate) fn expr2bytecode(expr: &Expr, collector: &mut Vec<Bytecode>) {
                 }
             }
         }
+        Expr::Try {
+            expr,
+            fallback,
+            warn,
+            span,
+        } => {
+            collector.push(Bytecode::TryStart {
+                catch_to: 0,
+                warn: *warn,
+                span: *span,
+            });
+            let try_start_amend_pos = collector.len() - 1;
+            // +1
+            expr2bytecode(expr, collector);
+            collector.push(Bytecode::TryEnd {
+                jump_to: 0,
+                span: *span,
+            });
+            let try_end_amend_pos = collector.len() - 1;
+            collector[try_start_amend_pos] = Bytecode::TryStart {
+                catch_to: collector.len(),
+                warn: *warn,
+                span: *span,
+            };
+            // +1, only reached when `expr` raised an error
+            expr2bytecode(fallback, collector);
+            collector[try_end_amend_pos] = Bytecode::TryEnd {
+                jump_to: collector.len(),
+                span: *span,
+            };
+        }
     }
 }
 
 pub(crate) fn build_expr(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Result<Expr> {
     ensure!(
-        pair.as_rule() == Rule::expr,
+        matches!(pair.as_rule(), Rule::expr | Rule::merge_expr),
         InvalidExpression(pair.extract_span())
     );
 
@@ -168,27 +226,26 @@ fn build_expr_infix(lhs: Result<Expr>, op: Pair<'_>, rhs: Result<Expr>) -> Resul
     })
 }
 
-fn build_term(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Result<Expr> {
+pub(crate) fn build_term(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Result<Expr> {
     let span = pair.extract_span();
     let op = pair.as_rule();
     Ok(match op {
-        Rule::var => Expr::Binding {
+        Rule::var | Rule::qualified_var => Expr::Binding {
             var: Symbol::new(pair.as_str(), pair.extract_span()),
             tuple_pos: None,
         },
         Rule::param => {
-            #[derive(Error, Diagnostic, Debug)]
-            #[error("Required parameter {0} not found")]
-            #[diagnostic(code(parser::param_not_found))]
-            struct ParamNotFoundError(String, #[label] SourceSpan);
-
             let param_str = pair.as_str().strip_prefix('$').unwrap();
-            Expr::Const {
-                val: param_pool
-                    .get(param_str)
-                    .ok_or_else(|| ParamNotFoundError(param_str.to_string(), span))?
-                    .clone(),
-                span,
+            match param_pool.get(param_str) {
+                Some(val) => Expr::Const {
+                    val: val.clone(),
+                    span,
+                },
+                // missing parameters are rejected before building, except in prepared queries
+                None => Expr::Param {
+                    name: SmartString::from(param_str),
+                    span,
+                },
             }
         }
         Rule::pos_int => {
@@ -259,7 +316,7 @@ fn build_term(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Resul
                 span,
             }
         }
-        Rule::list => {
+        Rule::list | Rule::merge_list => {
             let mut collected = vec![];
             for p in pair.into_inner() {
                 collected.push(build_expr(p, param_pool)?)
@@ -270,7 +327,7 @@ fn build_term(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Resul
                 span,
             }
         }
-        Rule::apply => {
+        Rule::apply | Rule::merge_apply => {
             let mut p = pair.into_inner();
             let ident_p = p.next().unwrap();
             let ident = ident_p.as_str();
@@ -280,11 +337,6 @@ fn build_term(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Resul
                 .into_inner()
                 .map(|v| build_expr(v, param_pool))
                 .try_collect()?;
-            #[derive(Error, Diagnostic, Debug)]
-            #[error("Named function '{0}' not found")]
-            #[diagnostic(code(parser::func_not_function))]
-            struct FuncNotFoundError(String, #[label] SourceSpan);
-
             match ident {
                 "cond" => {
                     if args.is_empty() {
@@ -354,10 +406,51 @@ fn build_term(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Resul
                     ));
                     Expr::Cond { clauses, span }
                 }
+                "try" => {
+                    #[derive(Debug, Error, Diagnostic)]
+                    #[error("wrong number of arguments to try: 2 or 3 required")]
+                    #[diagnostic(code(parser::bad_try))]
+                    struct WrongArgsToTry(#[label] SourceSpan);
+
+                    #[derive(Debug, Error, Diagnostic)]
+                    #[error("the third argument to try must be a constant boolean")]
+                    #[diagnostic(code(parser::bad_try_warn))]
+                    struct BadWarnFlagToTry(#[label] SourceSpan);
+
+                    ensure!(args.len() == 2 || args.len() == 3, WrongArgsToTry(span));
+
+                    let mut args = args.into_iter();
+                    let expr = args.next().unwrap();
+                    let fallback = args.next().unwrap();
+                    let warn = match args.next() {
+                        None => false,
+                        Some(flag) => {
+                            let flag_span = flag.span();
+                            flag.eval_to_const()?
+                                .get_bool()
+                                .ok_or(BadWarnFlagToTry(flag_span))?
+                        }
+                    };
+                    Expr::Try {
+                        expr: Box::new(expr),
+                        fallback: Box::new(fallback),
+                        warn,
+                        span,
+                    }
+                }
                 _ => {
-                    let op = get_op(ident).ok_or_else(|| {
-                        FuncNotFoundError(ident.to_string(), ident_p.extract_span())
-                    })?;
+                    let op = match get_op(ident) {
+                        Some(op) => op,
+                        // functions registered with the database are found at compile time
+                        None => {
+                            return Ok(Expr::UserFn {
+                                name: SmartString::from(ident),
+                                func: None,
+                                args: args.into(),
+                                span,
+                            })
+                        }
+                    };
                     op.post_process_args(&mut args);
 
                     #[derive(Error, Diagnostic, Debug)]
@@ -384,6 +477,7 @@ fn build_term(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Resul
                             )
                         );
                     }
+                    op.check_const_args(&args)?;
                     Expr::Apply {
                         op,
                         args: args.into(),
@@ -392,7 +486,9 @@ fn build_term(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Resul
                 }
             }
         }
-        Rule::grouping => build_expr(pair.into_inner().next().unwrap(), param_pool)?,
+        Rule::grouping | Rule::merge_grouping => {
+            build_expr(pair.into_inner().next().unwrap(), param_pool)?
+        }
         r => unreachable!("Encountered unknown op {:?}", r),
     })
 }
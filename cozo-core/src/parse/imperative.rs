@@ -12,12 +12,17 @@ use std::sync::Arc;
 
 use either::{Left, Right};
 use itertools::Itertools;
-use miette::{Diagnostic, Result};
+use miette::{ensure, Diagnostic, Result};
 use smartstring::SmartString;
 use thiserror::Error;
 
+use crate::data::symb::Symbol;
+use crate::parse::expr::{build_expr, build_term};
 use crate::parse::query::parse_query;
-use crate::parse::{ExtractSpan, ImperativeProgram, ImperativeStmt, Pair, Rule, SourceSpan};
+use crate::parse::{
+    ExtractSpan, ImperativeCondition, ImperativeProgram, ImperativeStmt, LoopGuard, Pair,
+    ReplaceManyEntry, Rule, SourceSpan,
+};
 use crate::{DataValue, FixedRule, ValidityTs};
 
 pub(crate) fn parse_imperative_block(
@@ -53,6 +58,45 @@ struct CannotManipulatePermRel(#[label] SourceSpan);
 #[diagnostic(code(parser::dup_marker))]
 struct DuplicateMarker(#[label] SourceSpan);
 
+#[derive(Debug, Error, Diagnostic)]
+#[error("{0} must be a non-negative integer")]
+#[diagnostic(code(parser::bad_imperative_int))]
+#[diagnostic(help("Give an integer, or a parameter holding one"))]
+struct BadImperativeInt(&'static str, #[label] SourceSpan);
+
+/// Parses an integer of a statement, given literally or as a parameter
+fn parse_imperative_int(
+    pair: Pair<'_>,
+    param_pool: &BTreeMap<String, DataValue>,
+    what: &'static str,
+) -> Result<u64> {
+    let span = pair.extract_span();
+    let val = build_term(pair.into_inner().next().unwrap(), param_pool)?
+        .eval_to_const()
+        .map_err(|_| BadImperativeInt(what, span))?
+        .get_non_neg_int()
+        .ok_or(BadImperativeInt(what, span))?;
+    Ok(val)
+}
+
+fn parse_imperative_condition(
+    pair: Pair<'_>,
+    param_pool: &BTreeMap<String, DataValue>,
+    fixed_rules: &BTreeMap<String, Arc<Box<dyn FixedRule>>>,
+    cur_vld: ValidityTs,
+) -> Result<ImperativeCondition> {
+    Ok(match pair.as_rule() {
+        Rule::underscore_ident => Left(SmartString::from(pair.as_str())),
+        Rule::query_script_inner => Right(parse_query(
+            pair.into_inner(),
+            param_pool,
+            fixed_rules,
+            cur_vld,
+        )?),
+        _ => unreachable!(),
+    })
+}
+
 fn parse_imperative_stmt(
     pair: Pair<'_>,
     param_pool: &BTreeMap<String, DataValue>,
@@ -98,17 +142,12 @@ fn parse_imperative_stmt(
             let negated = pair.as_rule() == Rule::if_not_chain;
             let span = pair.extract_span();
             let mut inner = pair.into_inner();
-            let condition = inner.next().unwrap();
-            let cond = match condition.as_rule() {
-                Rule::underscore_ident => Left(SmartString::from(condition.as_str())),
-                Rule::query_script_inner => Right(parse_query(
-                    condition.into_inner(),
-                    param_pool,
-                    fixed_rules,
-                    cur_vld,
-                )?),
-                _ => unreachable!(),
-            };
+            let cond = parse_imperative_condition(
+                inner.next().unwrap(),
+                param_pool,
+                fixed_rules,
+                cur_vld,
+            )?;
             let body = inner
                 .next()
                 .unwrap()
@@ -138,8 +177,93 @@ fn parse_imperative_stmt(
                 mark = Some(SmartString::from(nxt.as_str()));
                 nxt = inner.next().unwrap();
             }
+            let mut guard = None;
+            if nxt.as_rule() == Rule::imperative_int {
+                let count = parse_imperative_int(nxt, param_pool, "the number of iterations")?;
+                guard = Some(LoopGuard::Count(count as usize));
+                nxt = inner.next().unwrap();
+            }
+            let body = parse_imperative_block(nxt, param_pool, fixed_rules, cur_vld)?;
+            ImperativeStmt::Loop {
+                label: mark,
+                body,
+                guard,
+            }
+        }
+        Rule::while_block | Rule::until_block => {
+            let negated = pair.as_rule() == Rule::until_block;
+            let span = pair.extract_span();
+            let mut inner = pair.into_inner();
+            let mut mark = None;
+            let mut nxt = inner.next().unwrap();
+            if nxt.as_rule() == Rule::ident {
+                mark = Some(SmartString::from(nxt.as_str()));
+                nxt = inner.next().unwrap();
+            }
+            let condition = parse_imperative_condition(nxt, param_pool, fixed_rules, cur_vld)?;
+            let body =
+                parse_imperative_block(inner.next().unwrap(), param_pool, fixed_rules, cur_vld)?;
+            ImperativeStmt::Loop {
+                label: mark,
+                body,
+                guard: Some(LoopGuard::Condition {
+                    condition: Box::new(condition),
+                    negated,
+                    span,
+                }),
+            }
+        }
+        Rule::retry_block => {
+            #[derive(Debug, Error, Diagnostic)]
+            #[error("the backoff factor of '%retry' must be a number not less than 1")]
+            #[diagnostic(code(parser::bad_backoff_factor))]
+            struct BadBackoffFactor(#[label] SourceSpan);
+
+            let mut inner = pair.into_inner();
+            let retries =
+                parse_imperative_int(inner.next().unwrap(), param_pool, "the number of retries")?;
+            let mut backoff = 0;
+            let mut factor = 2.;
+            let mut nxt = inner.next().unwrap();
+            if nxt.as_rule() == Rule::retry_backoff {
+                backoff = parse_imperative_int(
+                    nxt.into_inner().next().unwrap(),
+                    param_pool,
+                    "the backoff of '%retry'",
+                )?;
+                nxt = inner.next().unwrap();
+            }
+            if nxt.as_rule() == Rule::retry_factor {
+                let factor_span = nxt.extract_span();
+                factor = build_term(nxt.into_inner().next().unwrap(), param_pool)?
+                    .eval_to_const()
+                    .map_err(|_| BadBackoffFactor(factor_span))?
+                    .get_float()
+                    .ok_or(BadBackoffFactor(factor_span))?;
+                ensure!(factor >= 1., BadBackoffFactor(factor_span));
+                nxt = inner.next().unwrap();
+            }
             let body = parse_imperative_block(nxt, param_pool, fixed_rules, cur_vld)?;
-            ImperativeStmt::Loop { label: mark, body }
+            ImperativeStmt::Retry {
+                retries: retries as usize,
+                backoff,
+                factor,
+                body,
+            }
+        }
+        Rule::sleep_stmt => {
+            #[cfg(target_arch = "wasm32")]
+            miette::bail!("%sleep is not supported under WASM");
+
+            #[cfg(not(target_arch = "wasm32"))]
+            {
+                let millis = parse_imperative_int(
+                    pair.into_inner().next().unwrap(),
+                    param_pool,
+                    "the duration of '%sleep'",
+                )?;
+                ImperativeStmt::Sleep { millis }
+            }
         }
         Rule::temp_swap => {
             // let span = pair.extract_span();
@@ -163,14 +287,75 @@ fn parse_imperative_stmt(
                 temp: SmartString::from(name),
             }
         }
+        Rule::savepoint_stmt => {
+            let name = pair.into_inner().next().unwrap().as_str();
+            ImperativeStmt::Savepoint {
+                name: SmartString::from(name),
+            }
+        }
+        Rule::rollback_to_stmt => {
+            let span = pair.extract_span();
+            let name = pair.into_inner().next().unwrap().as_str();
+            ImperativeStmt::RollbackTo {
+                name: SmartString::from(name),
+                span,
+            }
+        }
         Rule::query_script_inner => {
             let prog = parse_query(pair.into_inner(), param_pool, fixed_rules, cur_vld)?;
             ImperativeStmt::Program { prog }
         }
         Rule::ignore_error_script => {
-            let pair = pair.into_inner().next().unwrap();
-            let prog = parse_query(pair.into_inner(), param_pool, fixed_rules, cur_vld)?;
-            ImperativeStmt::IgnoreErrorProgram { prog }
+            let mut inner = pair.into_inner();
+            let mut binding = None;
+            let mut nxt = inner.next().unwrap();
+            if nxt.as_rule() == Rule::underscore_ident {
+                binding = Some(Symbol::new(nxt.as_str(), nxt.extract_span()));
+                nxt = inner.next().unwrap();
+            }
+            let prog = parse_query(nxt.into_inner(), param_pool, fixed_rules, cur_vld)?;
+            ImperativeStmt::IgnoreErrorProgram { prog, binding }
+        }
+        Rule::replace_many_stmt => {
+            #[derive(Debug, Error, Diagnostic)]
+            #[error("relation '{0}' is replaced more than once")]
+            #[diagnostic(code(parser::dup_replaced_relation))]
+            struct DuplicateReplacedRelation(String, #[label] SourceSpan);
+
+            #[derive(Debug, Error, Diagnostic)]
+            #[error("query giving the new contents of '{0}' cannot have its own relation option")]
+            #[diagnostic(code(parser::store_in_replace_many))]
+            struct StoreInReplaceMany(String, #[label] SourceSpan);
+
+            let mut entries: Vec<ReplaceManyEntry> = vec![];
+            for entry in pair.into_inner() {
+                let entry_span = entry.extract_span();
+                let mut inner = entry.into_inner();
+                let name_p = inner.next().unwrap();
+                let name = Symbol::new(name_p.as_str(), name_p.extract_span());
+                ensure!(
+                    entries.iter().all(|e| e.name != name),
+                    DuplicateReplacedRelation(name.to_string(), entry_span)
+                );
+                let src = inner.next().unwrap();
+                let source = match src.as_rule() {
+                    Rule::query_script_inner => {
+                        let prog = parse_query(src.into_inner(), param_pool, fixed_rules, cur_vld)?;
+                        ensure!(
+                            prog.out_opts.store_relation.is_none(),
+                            StoreInReplaceMany(name.to_string(), entry_span)
+                        );
+                        Left(prog)
+                    }
+                    _ => Right(build_expr(src, param_pool)?),
+                };
+                entries.push(ReplaceManyEntry {
+                    name,
+                    source,
+                    span: entry_span,
+                });
+            }
+            ImperativeStmt::ReplaceMany { entries }
         }
         r => unreachable!("{r:?}"),
     })
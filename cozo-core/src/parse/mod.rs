@@ -18,9 +18,12 @@ use pest::Parser;
 use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
+use crate::data::expr::{Expr, ParamNotFoundError};
 use crate::data::program::InputProgram;
 use crate::data::relation::NullableColType;
+use crate::data::symb::Symbol;
 use crate::data::value::{DataValue, ValidityTs};
+use crate::parse::expr::build_expr;
 use crate::parse::imperative::parse_imperative_block;
 use crate::parse::query::parse_query;
 use crate::parse::schema::parse_nullable_type;
@@ -64,6 +67,8 @@ pub(crate) enum ImperativeStmt {
     },
     IgnoreErrorProgram {
         prog: InputProgram,
+        /// The temp relation the outcome is stored in
+        binding: Option<Symbol>,
     },
     If {
         condition: ImperativeCondition,
@@ -75,6 +80,18 @@ pub(crate) enum ImperativeStmt {
     Loop {
         label: Option<SmartString<LazyCompact>>,
         body: ImperativeProgram,
+        guard: Option<LoopGuard>,
+    },
+    /// Runs the body again if it fails, at most `retries` times, waiting `backoff` milliseconds
+    /// before the first retry and `factor` times longer before each next one
+    Retry {
+        retries: usize,
+        backoff: u64,
+        factor: f64,
+        body: ImperativeProgram,
+    },
+    Sleep {
+        millis: u64,
     },
     TempSwap {
         left: SmartString<LazyCompact>,
@@ -84,6 +101,38 @@ pub(crate) enum ImperativeStmt {
     TempDebug {
         temp: SmartString<LazyCompact>,
     },
+    ReplaceMany {
+        entries: Vec<ReplaceManyEntry>,
+    },
+    Savepoint {
+        name: SmartString<LazyCompact>,
+    },
+    RollbackTo {
+        name: SmartString<LazyCompact>,
+        span: SourceSpan,
+    },
+}
+
+/// One relation of a `:replace_many` statement, with the query or the rows giving its
+/// new contents.
+#[derive(Debug)]
+pub(crate) struct ReplaceManyEntry {
+    pub(crate) name: Symbol,
+    pub(crate) source: Either<InputProgram, Expr>,
+    pub(crate) span: SourceSpan,
+}
+
+/// What ends a loop besides `%break` and `%return`, checked before each iteration
+#[derive(Debug)]
+pub(crate) enum LoopGuard {
+    /// `%loop n`
+    Count(usize),
+    /// `%while` and `%until`, the latter being negated
+    Condition {
+        condition: Box<ImperativeCondition>,
+        negated: bool,
+        span: SourceSpan,
+    },
 }
 
 pub(crate) type ImperativeCondition = Either<SmartString<LazyCompact>, InputProgram>;
@@ -123,15 +172,35 @@ impl ImperativeStmt {
                     prog.needs_write_locks(collector);
                 }
             }
-            ImperativeStmt::Loop { body, .. } => {
+            ImperativeStmt::Loop { body, guard, .. } => {
+                if let Some(LoopGuard::Condition { condition, .. }) = guard {
+                    if let ImperativeCondition::Right(prog) = &**condition {
+                        if let Some(name) = prog.needs_write_lock() {
+                            collector.insert(name);
+                        }
+                    }
+                }
+                for prog in body {
+                    prog.needs_write_locks(collector);
+                }
+            }
+            ImperativeStmt::Retry { body, .. } => {
                 for prog in body {
                     prog.needs_write_locks(collector);
                 }
             }
+            ImperativeStmt::ReplaceMany { entries, .. } => {
+                for entry in entries {
+                    collector.insert(entry.name.name.clone());
+                }
+            }
             ImperativeStmt::TempDebug { .. }
             | ImperativeStmt::Break { .. }
             | ImperativeStmt::Continue { .. }
-            | ImperativeStmt::TempSwap { .. } => {}
+            | ImperativeStmt::TempSwap { .. }
+            | ImperativeStmt::Sleep { .. }
+            | ImperativeStmt::Savepoint { .. }
+            | ImperativeStmt::RollbackTo { .. } => {}
         }
     }
 }
@@ -195,6 +264,151 @@ pub(crate) struct ParseError {
     pub(crate) span: SourceSpan,
 }
 
+#[derive(thiserror::Error, Diagnostic, Debug)]
+#[error("The query parser has encountered {} errors", .errors.len())]
+#[diagnostic(code(parser::pest))]
+#[diagnostic(help(
+    "Parsing resumed at the next statement after each error, later errors may be caused by earlier ones"
+))]
+pub(crate) struct ParseErrors {
+    #[related]
+    pub(crate) errors: Vec<ParseError>,
+}
+
+/// At most this many syntax errors are reported for a script
+const MAX_PARSE_ERRORS: usize = 20;
+
+fn pest_error_span(err: &pest::error::Error<Rule>) -> SourceSpan {
+    match err.location {
+        InputLocation::Pos(p) => SourceSpan(p, 0),
+        InputLocation::Span((start, end)) => SourceSpan(start, end - start),
+    }
+}
+
+/// Parses a script, reporting all syntax errors found by [collect_parse_errors] on failure
+fn parse_script_pair(src: &str) -> Result<Pair<'_>> {
+    match CozoScriptParser::parse(Rule::script, src) {
+        Ok(mut pairs) => Ok(pairs.next().unwrap()),
+        Err(err) => {
+            let mut errors = collect_parse_errors(src, pest_error_span(&err));
+            if errors.len() == 1 {
+                bail!(errors.pop().unwrap())
+            }
+            bail!(ParseErrors { errors })
+        }
+    }
+}
+
+/// Collects the syntax errors of a script, the first one being at `first`. The statement
+/// containing each error is blanked out and the script parsed again, until it parses or the
+/// next error is not further into the script, as errors caused by blanking out are not
+/// reported.
+fn collect_parse_errors(src: &str, first: SourceSpan) -> Vec<ParseError> {
+    let boundaries = statement_boundaries(src);
+    let end_of_input = src.trim_end().len();
+    let mut errors = vec![ParseError { span: first }];
+    let mut blanked = src.as_bytes().to_vec();
+    let mut last_pos = first.0;
+    while errors.len() < MAX_PARSE_ERRORS {
+        // an error at the start of a statement is in the previous one, which is unterminated
+        let start = boundaries
+            .iter()
+            .rev()
+            .find(|b| **b < last_pos)
+            .copied()
+            .unwrap_or(0);
+        let end = boundaries
+            .iter()
+            .find(|b| **b > start)
+            .copied()
+            .unwrap_or(src.len());
+        for b in &mut blanked[start..end] {
+            if *b != b'\n' {
+                *b = b' ';
+            }
+        }
+        // boundaries are at the starts of characters, so this is still valid UTF-8
+        let blanked_src = std::str::from_utf8(&blanked).unwrap();
+        match CozoScriptParser::parse(Rule::script, blanked_src) {
+            Ok(_) => break,
+            Err(err) => {
+                let span = pest_error_span(&err);
+                if span.0 <= last_pos || span.0 >= end_of_input {
+                    break;
+                }
+                errors.push(ParseError { span });
+                last_pos = span.0;
+            }
+        }
+    }
+    errors
+}
+
+/// Positions in the script where statements are likely to start: after `;` outside of
+/// brackets, and at the starts of lines beginning with `?`, `%`, `:`, `{`, `}` or a rule head.
+fn statement_boundaries(src: &str) -> Vec<usize> {
+    let mut ret = vec![];
+    let mut depth = 0;
+    let mut at_line_start = true;
+    let mut chars = src.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if at_line_start && !c.is_whitespace() {
+            at_line_start = false;
+            if i > 0 && starts_statement(&src[i..]) {
+                ret.push(i);
+            }
+        }
+        match c {
+            '\n' => at_line_start = true,
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            ';' if depth <= 0 => ret.push(i + 1),
+            '#' => while chars.next_if(|(_, c)| *c != '\n').is_some() {},
+            '/' if chars.next_if(|(_, c)| *c == '*').is_some() => {
+                let mut prev = ' ';
+                for (_, c) in chars.by_ref() {
+                    if prev == '*' && c == '/' {
+                        break;
+                    }
+                    prev = c;
+                }
+            }
+            '"' | '\'' => {
+                let mut escaped = false;
+                for (_, cur) in chars.by_ref() {
+                    if escaped {
+                        escaped = false;
+                    } else if cur == '\\' {
+                        escaped = true;
+                    } else if cur == c {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    ret
+}
+
+fn starts_statement(rest: &str) -> bool {
+    if rest.starts_with(":=") {
+        return false;
+    }
+    if matches!(rest.chars().next(), Some('?' | '%' | ':' | '{' | '}')) {
+        return true;
+    }
+    // rule heads: `name[...] :=`, `name[...] <-` or `name[...] <~`, on a single line
+    let line = rest.lines().next().unwrap_or_default();
+    let ident_len = line
+        .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .unwrap_or(line.len());
+    let after = line[ident_len..].trim_start();
+    ident_len > 0
+        && after.starts_with('[')
+        && [":=", "<-", "<~"].iter().any(|op| after.contains(op))
+}
+
 pub(crate) fn parse_type(src: &str) -> Result<NullableColType> {
     let parsed = CozoScriptParser::parse(Rule::col_type_with_term, src)
         .into_diagnostic()?
@@ -203,13 +417,29 @@ pub(crate) fn parse_type(src: &str) -> Result<NullableColType> {
     parse_nullable_type(parsed.into_inner().next().unwrap())
 }
 
-pub(crate) fn parse_script(
+/// Parses a standalone expression, outside of any script.
+pub(crate) fn parse_expression(
     src: &str,
     param_pool: &BTreeMap<String, DataValue>,
-    fixed_rules: &BTreeMap<String, Arc<Box<dyn FixedRule>>>,
-    cur_vld: ValidityTs,
-) -> Result<CozoScript> {
-    let parsed = CozoScriptParser::parse(Rule::script, src)
+) -> Result<Expr> {
+    parse_standalone_expression(Rule::expression_script, src, param_pool)
+}
+
+/// Parses an expression merging an imported row into a stored one, which can refer to
+/// the columns of both as `old.<col>` and `new.<col>`.
+pub(crate) fn parse_merge_expression(
+    src: &str,
+    param_pool: &BTreeMap<String, DataValue>,
+) -> Result<Expr> {
+    parse_standalone_expression(Rule::merge_expression_script, src, param_pool)
+}
+
+fn parse_standalone_expression(
+    entry: Rule,
+    src: &str,
+    param_pool: &BTreeMap<String, DataValue>,
+) -> Result<Expr> {
+    let parsed = CozoScriptParser::parse(entry, src)
         .map_err(|err| {
             let span = match err.location {
                 InputLocation::Pos(p) => SourceSpan(p, 0),
@@ -219,6 +449,83 @@ pub(crate) fn parse_script(
         })?
         .next()
         .unwrap();
+    check_params(parsed.clone(), param_pool)?;
+    build_expr(parsed.into_inner().next().unwrap(), param_pool)
+}
+
+/// Rejects parameters missing from the pool, which are otherwise left unbound in the
+/// expressions built. Parameters in column updates and triggers are only bound when
+/// these run, with the parameters of the query writing to the relation.
+fn check_params(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Result<()> {
+    match pair.as_rule() {
+        Rule::param => {
+            let name = pair.as_str().strip_prefix('$').unwrap();
+            if !param_pool.contains_key(name) {
+                bail!(ParamNotFoundError(name.to_string(), pair.extract_span()))
+            }
+        }
+        Rule::auto_update | Rule::trigger_clause => {}
+        _ => {
+            for inner in pair.into_inner() {
+                check_params(inner, param_pool)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Collects the parameters used in the query, with the spans of their first uses
+fn collect_params(pair: Pair<'_>, coll: &mut BTreeMap<String, SourceSpan>) {
+    match pair.as_rule() {
+        Rule::param => {
+            let name = pair.as_str().strip_prefix('$').unwrap().to_string();
+            coll.entry(name).or_insert_with(|| pair.extract_span());
+        }
+        Rule::auto_update | Rule::trigger_clause => {}
+        _ => {
+            for inner in pair.into_inner() {
+                collect_params(inner, coll);
+            }
+        }
+    }
+}
+
+/// Parses a single query leaving its parameters unbound, for [crate::PreparedQuery].
+/// Returns the parameters used together with the program.
+pub(crate) fn parse_prepared_query(
+    src: &str,
+    fixed_rules: &BTreeMap<String, Arc<Box<dyn FixedRule>>>,
+    cur_vld: ValidityTs,
+) -> Result<(InputProgram, BTreeMap<String, SourceSpan>)> {
+    #[derive(Debug, Error, Diagnostic)]
+    #[error("Only single queries can be prepared")]
+    #[diagnostic(code(parser::not_single_query))]
+    #[diagnostic(help("Imperative scripts and system ops must be run as scripts"))]
+    struct NotSingleQueryError;
+
+    let parsed = parse_script_pair(src)?;
+    if parsed.as_rule() != Rule::query_script {
+        bail!(NotSingleQueryError)
+    }
+    let mut params = BTreeMap::new();
+    collect_params(parsed.clone(), &mut params);
+    let prog = parse_query(
+        parsed.into_inner(),
+        &Default::default(),
+        fixed_rules,
+        cur_vld,
+    )?;
+    Ok((prog, params))
+}
+
+pub(crate) fn parse_script(
+    src: &str,
+    param_pool: &BTreeMap<String, DataValue>,
+    fixed_rules: &BTreeMap<String, Arc<Box<dyn FixedRule>>>,
+    cur_vld: ValidityTs,
+) -> Result<CozoScript> {
+    let parsed = parse_script_pair(src)?;
+    check_params(parsed.clone(), param_pool)?;
     Ok(match parsed.as_rule() {
         Rule::query_script => {
             let q = parse_query(parsed.into_inner(), param_pool, fixed_rules, cur_vld)?;
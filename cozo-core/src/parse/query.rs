@@ -22,10 +22,12 @@ use thiserror::Error;
 use crate::data::aggr::{parse_aggr, Aggregation};
 use crate::data::expr::Expr;
 use crate::data::functions::{str2vld, MAX_VALIDITY_TS};
+use crate::data::json::FloatFormat;
 use crate::data::program::{
     FixedRuleApply, FixedRuleArg, InputAtom, InputInlineRule, InputInlineRulesOrFixed,
     InputNamedFieldRelationApplyAtom, InputProgram, InputRelationApplyAtom, InputRuleApplyAtom,
-    QueryAssertion, QueryOutOptions, RelationOp, SortDir, Unification,
+    InputSearchAtom, QueryAssertion, QueryOutOptions, RelationOp, SortDir, Unification, WindowFn,
+    WindowSpec, WrongFixedRuleOptionError,
 };
 use crate::data::relation::{ColType, ColumnDef, NullableColType, StoredRelationMetadata};
 use crate::data::symb::{Symbol, PROG_ENTRY};
@@ -283,23 +285,10 @@ pub(crate) fn parse_query(
             }
             Rule::sort_option => {
                 for part in pair.into_inner() {
-                    let mut var = "";
-                    let mut dir = SortDir::Asc;
-                    let mut span = part.extract_span();
-                    for a in part.into_inner() {
-                        match a.as_rule() {
-                            Rule::out_arg => {
-                                var = a.as_str();
-                                span = a.extract_span();
-                            }
-                            Rule::sort_asc => dir = SortDir::Asc,
-                            Rule::sort_desc => dir = SortDir::Dsc,
-                            _ => unreachable!(),
-                        }
-                    }
-                    out_opts.sorters.push((Symbol::new(var, span), dir));
+                    out_opts.sorters.push(parse_sort_arg(part));
                 }
             }
+            Rule::window_option => out_opts.windows.push(parse_window(pair, param_pool)?),
             Rule::relation_option => {
                 let span = pair.extract_span();
                 let mut args = pair.into_inner();
@@ -310,6 +299,8 @@ pub(crate) fn parse_query(
                     Rule::relation_rm => RelationOp::Rm,
                     Rule::relation_ensure => RelationOp::Ensure,
                     Rule::relation_ensure_not => RelationOp::EnsureNot,
+                    Rule::relation_update => RelationOp::Update,
+                    Rule::relation_upsert => RelationOp::Upsert,
                     _ => unreachable!(),
                 };
 
@@ -326,6 +317,7 @@ pub(crate) fn parse_query(
                                 key_bindings,
                                 dep_bindings,
                                 span,
+                                params: param_pool.clone(),
                             },
                             op,
                         )))
@@ -346,6 +338,32 @@ pub(crate) fn parse_query(
                 );
                 out_opts.assertion = Some(QueryAssertion::AssertSome(pair.extract_span()))
             }
+            Rule::validity_as_string_option => out_opts.validity_as_string = true,
+            Rule::float_format_option => {
+                #[derive(Error, Diagnostic, Debug)]
+                #[error("Invalid float format")]
+                #[diagnostic(code(parser::bad_float_format))]
+                #[diagnostic(help("{0}"))]
+                struct BadFloatFormatError(String, #[label] SourceSpan);
+
+                let pair = pair.into_inner().next().unwrap();
+                let span = pair.extract_span();
+                let format = build_expr(pair, param_pool)?
+                    .eval_to_const()
+                    .map_err(|err| OptionNotConstantError("float_format", span, [err]))?;
+                let format = match format.get_str() {
+                    Some(s) => s
+                        .parse::<FloatFormat>()
+                        .map_err(|msg| BadFloatFormatError(msg, span))?,
+                    None => bail!(BadFloatFormatError(
+                        "the format must be given as a string".to_string(),
+                        span
+                    )),
+                };
+                out_opts.float_format = Some(format);
+            }
+            Rule::big_int_as_string_option => out_opts.big_int_as_string = true,
+            Rule::report_usage_option => out_opts.report_usage = true,
             Rule::EOI => break,
             r => unreachable!("{:?}", r),
         }
@@ -392,6 +410,8 @@ pub(crate) fn parse_query(
                             nullable: true,
                         },
                         default_gen: None,
+                        auto_update: None,
+                        unique: false,
                     })
                     .collect(),
                 non_keys: vec![],
@@ -403,6 +423,7 @@ pub(crate) fn parse_query(
                 key_bindings: head,
                 dep_bindings: vec![],
                 span,
+                params: param_pool.clone(),
             };
             prog.out_opts.store_relation = Some((handle, op))
         }
@@ -417,6 +438,10 @@ pub(crate) fn parse_query(
         }
     }
 
+    if !prog.out_opts.windows.is_empty() {
+        check_windows(&mut prog)?;
+    }
+
     if !prog.out_opts.sorters.is_empty() {
         #[derive(Debug, Error, Diagnostic)]
         #[error("Sort key '{0}' not found")]
@@ -436,6 +461,255 @@ pub(crate) fn parse_query(
     Ok(prog)
 }
 
+fn parse_sort_arg(part: Pair<'_>) -> (Symbol, SortDir) {
+    let mut var = "";
+    let mut dir = SortDir::Asc;
+    let mut span = part.extract_span();
+    for a in part.into_inner() {
+        match a.as_rule() {
+            Rule::out_arg => {
+                var = a.as_str();
+                span = a.extract_span();
+            }
+            Rule::sort_asc => dir = SortDir::Asc,
+            Rule::sort_desc => dir = SortDir::Dsc,
+            _ => unreachable!(),
+        }
+    }
+    (Symbol::new(var, span), dir)
+}
+
+fn parse_window(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Result<WindowSpec> {
+    #[derive(Debug, Error, Diagnostic)]
+    #[error("Unknown window function '{0}'")]
+    #[diagnostic(code(parser::unknown_window_fn))]
+    #[diagnostic(help(
+        "The window functions are 'row_number', 'rank', 'dense_rank', 'lag', 'lead', \
+        'sum', 'min' and 'max'"
+    ))]
+    struct UnknownWindowFn(String, #[label] SourceSpan);
+
+    #[derive(Debug, Error, Diagnostic)]
+    #[error("Bad arguments for the window function '{0}'")]
+    #[diagnostic(code(parser::bad_window_args))]
+    struct BadWindowArgs(String, #[label] SourceSpan, #[help] &'static str);
+
+    let mut src = pair.into_inner();
+    let apply = src.next().unwrap();
+    let apply_span = apply.extract_span();
+    let mut apply_src = apply.into_inner();
+    let fn_name = apply_src.next().unwrap().as_str();
+    let args: Vec<Expr> = apply_src
+        .next()
+        .unwrap()
+        .into_inner()
+        .map(|p| build_expr(p, param_pool))
+        .try_collect()?;
+    let bad_args = |span, help| BadWindowArgs(fn_name.to_string(), span, help);
+    let col_arg = |args: &[Expr]| match args.first() {
+        Some(Expr::Binding { var, .. }) => Ok(var.clone()),
+        _ => Err(bad_args(
+            apply_span,
+            "The first argument must be a column of the output",
+        )),
+    };
+
+    let func = match fn_name {
+        "row_number" | "rank" | "dense_rank" => {
+            ensure!(
+                args.is_empty(),
+                bad_args(apply_span, "The function takes no arguments")
+            );
+            match fn_name {
+                "row_number" => WindowFn::RowNumber,
+                "rank" => WindowFn::Rank,
+                _ => WindowFn::DenseRank,
+            }
+        }
+        "sum" | "min" | "max" => {
+            ensure!(
+                args.len() == 1,
+                bad_args(apply_span, "The function takes exactly one column")
+            );
+            let col = col_arg(&args)?;
+            match fn_name {
+                "sum" => WindowFn::Sum(col),
+                "min" => WindowFn::Min(col),
+                _ => WindowFn::Max(col),
+            }
+        }
+        "lag" | "lead" => {
+            ensure!(
+                (1..=3).contains(&args.len()),
+                bad_args(
+                    apply_span,
+                    "The function takes a column, optionally followed by \
+                    a constant offset and a constant default value"
+                )
+            );
+            let col = col_arg(&args)?;
+            let offset = match args.get(1) {
+                None => 1,
+                Some(expr) => {
+                    let span = expr.span();
+                    expr.clone()
+                        .eval_to_const()
+                        .map_err(|err| OptionNotConstantError("window", span, [err]))?
+                        .get_non_neg_int()
+                        .ok_or_else(|| {
+                            bad_args(span, "The offset must be a non-negative integer")
+                        })? as usize
+                }
+            };
+            let default = match args.get(2) {
+                None => DataValue::Null,
+                Some(expr) => {
+                    let span = expr.span();
+                    expr.clone()
+                        .eval_to_const()
+                        .map_err(|err| OptionNotConstantError("window", span, [err]))?
+                }
+            };
+            if fn_name == "lag" {
+                WindowFn::Lag {
+                    col,
+                    offset,
+                    default,
+                }
+            } else {
+                WindowFn::Lead {
+                    col,
+                    offset,
+                    default,
+                }
+            }
+        }
+        name => bail!(UnknownWindowFn(name.to_string(), apply_span)),
+    };
+
+    let name = src.next().unwrap();
+    let name = Symbol::new(name.as_str(), name.extract_span());
+    let mut partition_by = vec![];
+    let mut order_by = vec![];
+    for part in src {
+        match part.as_rule() {
+            Rule::window_partition => {
+                partition_by = part
+                    .into_inner()
+                    .map(|v| Symbol::new(v.as_str(), v.extract_span()))
+                    .collect()
+            }
+            Rule::window_order => order_by = part.into_inner().map(parse_sort_arg).collect(),
+            r => unreachable!("{:?}", r),
+        }
+    }
+    Ok(WindowSpec {
+        func,
+        name,
+        partition_by,
+        order_by,
+    })
+}
+
+/// Checks the columns of the windows, and that the output is sorted the way they need, sorting it
+/// that way if no `:order` is given
+fn check_windows(prog: &mut InputProgram) -> Result<()> {
+    #[derive(Debug, Error, Diagnostic)]
+    #[error("Windows cannot be computed for queries storing their results")]
+    #[diagnostic(code(parser::window_with_relation_op))]
+    struct WindowWithRelationOp(#[label] SourceSpan);
+
+    #[derive(Debug, Error, Diagnostic)]
+    #[error("Window column '{0}' not found")]
+    #[diagnostic(code(parser::window_col_not_found))]
+    struct WindowColNotFound(String, #[label] SourceSpan);
+
+    #[derive(Debug, Error, Diagnostic)]
+    #[error("The window name '{0}' is already used for another column")]
+    #[diagnostic(code(parser::duplicate_window_name))]
+    struct DuplicateWindowName(String, #[label] SourceSpan);
+
+    #[derive(Debug, Error, Diagnostic)]
+    #[error("The output is not sorted the way the window '{0}' needs")]
+    #[diagnostic(code(parser::window_order_mismatch))]
+    #[diagnostic(help(
+        "The window needs the output sorted by its partition columns in any order, \
+        followed by its order columns, e.g. with ':order {1}'"
+    ))]
+    struct WindowOrderMismatch(String, String, #[label] SourceSpan);
+
+    let head = prog.get_entry_out_head()?;
+    let out_opts = &mut prog.out_opts;
+    let mut names: Vec<&Symbol> = head.iter().collect();
+    for window in &out_opts.windows {
+        if out_opts.store_relation.is_some() {
+            bail!(WindowWithRelationOp(window.name.span));
+        }
+        let cols = window
+            .func
+            .col()
+            .into_iter()
+            .chain(window.partition_by.iter())
+            .chain(window.order_by.iter().map(|(col, _)| col));
+        for col in cols {
+            ensure!(
+                head.contains(col),
+                WindowColNotFound(col.to_string(), col.span)
+            );
+        }
+        ensure!(
+            !names.contains(&&window.name),
+            DuplicateWindowName(window.name.to_string(), window.name.span)
+        );
+        names.push(&window.name);
+    }
+
+    if out_opts.sorters.is_empty() {
+        let first = &out_opts.windows[0];
+        let mut sorters = first
+            .partition_by
+            .iter()
+            .map(|col| (col.clone(), SortDir::Asc))
+            .collect_vec();
+        sorters.extend(first.order_by.iter().cloned());
+        if sorters.is_empty() {
+            sorters = head.iter().map(|col| (col.clone(), SortDir::Asc)).collect();
+        }
+        out_opts.sorters = sorters;
+    }
+
+    for window in &out_opts.windows {
+        let n_part = window.partition_by.len();
+        let n_sorted = n_part + window.order_by.len();
+        let sorted_as_needed = out_opts.sorters.len() >= n_sorted
+            && out_opts.sorters[..n_part]
+                .iter()
+                .all(|(col, _)| window.partition_by.contains(col))
+            && window
+                .partition_by
+                .iter()
+                .all(|col| out_opts.sorters[..n_part].iter().any(|(c, _)| c == col))
+            && out_opts.sorters[n_part..n_sorted] == window.order_by[..];
+        if !sorted_as_needed {
+            let needed = window
+                .partition_by
+                .iter()
+                .map(|col| col.to_string())
+                .chain(window.order_by.iter().map(|(col, dir)| match dir {
+                    SortDir::Asc => col.to_string(),
+                    SortDir::Dsc => format!("-{col}"),
+                }))
+                .join(", ");
+            bail!(WindowOrderMismatch(
+                window.name.to_string(),
+                needed,
+                window.name.span
+            ))
+        }
+    }
+    Ok(())
+}
+
 fn parse_rule(
     src: Pair<'_>,
     param_pool: &BTreeMap<String, DataValue>,
@@ -596,11 +870,12 @@ fn parse_atom(
                 .into_inner()
                 .map(|v| build_expr(v, param_pool))
                 .try_collect()?;
-            let valid_at = match src.next() {
-                None => None,
+            let (valid_at, valid_until) = match src.next() {
+                None => (None, None),
                 Some(vld_clause) => {
                     let vld_expr = build_expr(vld_clause.into_inner().next().unwrap(), param_pool)?;
-                    Some(expr2vld_spec(vld_expr, cur_vld)?)
+                    let (valid_at, valid_until) = expr2vld_window(vld_expr, cur_vld)?;
+                    (Some(valid_at), valid_until)
                 }
             };
             InputAtom::Relation {
@@ -608,6 +883,7 @@ fn parse_atom(
                     name: Symbol::new(&name.as_str()[1..], name.extract_span()),
                     args,
                     valid_at,
+                    valid_until,
                     span,
                 },
             }
@@ -617,29 +893,13 @@ fn parse_atom(
             let mut src = src.into_inner();
             let name_p = src.next().unwrap();
             let name = Symbol::new(&name_p.as_str()[1..], name_p.extract_span());
-            let args = src
-                .next()
-                .unwrap()
-                .into_inner()
-                .map(|pair| -> Result<(SmartString<LazyCompact>, Expr)> {
-                    let mut inner = pair.into_inner();
-                    let name_p = inner.next().unwrap();
-                    let name = SmartString::from(name_p.as_str());
-                    let arg = match inner.next() {
-                        Some(a) => build_expr(a, param_pool)?,
-                        None => Expr::Binding {
-                            var: Symbol::new(name.clone(), name_p.extract_span()),
-                            tuple_pos: None,
-                        },
-                    };
-                    Ok((name, arg))
-                })
-                .try_collect()?;
-            let valid_at = match src.next() {
-                None => None,
+            let args = parse_named_apply_args(src.next().unwrap(), param_pool)?;
+            let (valid_at, valid_until) = match src.next() {
+                None => (None, None),
                 Some(vld_clause) => {
                     let vld_expr = build_expr(vld_clause.into_inner().next().unwrap(), param_pool)?;
-                    Some(expr2vld_spec(vld_expr, cur_vld)?)
+                    let (valid_at, valid_until) = expr2vld_window(vld_expr, cur_vld)?;
+                    (Some(valid_at), valid_until)
                 }
             };
             InputAtom::NamedFieldRelation {
@@ -648,6 +908,36 @@ fn parse_atom(
                     args,
                     span,
                     valid_at,
+                    valid_until,
+                },
+            }
+        }
+        Rule::search_apply => {
+            let span = src.extract_span();
+            let mut src = src.into_inner();
+            let name_p = src.next().unwrap();
+            let name_span = name_p.extract_span();
+            let (relation, index) = name_p.as_str()[1..].rsplit_once(':').unwrap();
+            let mut options = src.next().unwrap();
+            let mut bindings = BTreeMap::new();
+            if options.as_rule() == Rule::named_apply_args {
+                bindings = parse_named_apply_args(options, param_pool)?;
+                options = src.next().unwrap();
+            }
+            let mut parameters = BTreeMap::new();
+            for opt in options.into_inner() {
+                let mut opt = opt.into_inner();
+                let name = SmartString::from(opt.next().unwrap().as_str());
+                let expr = build_expr(opt.next().unwrap(), param_pool)?;
+                parameters.insert(name, expr);
+            }
+            InputAtom::Search {
+                inner: InputSearchAtom {
+                    relation: Symbol::new(relation, name_span),
+                    index: Symbol::new(index, name_span),
+                    bindings,
+                    parameters,
+                    span,
                 },
             }
         }
@@ -655,6 +945,29 @@ fn parse_atom(
     })
 }
 
+/// The columns bound by `*rel{..}` atoms, a column given without a value being bound
+/// to the variable of the same name
+fn parse_named_apply_args(
+    src: Pair<'_>,
+    param_pool: &BTreeMap<String, DataValue>,
+) -> Result<BTreeMap<SmartString<LazyCompact>, Expr>> {
+    src.into_inner()
+        .map(|pair| -> Result<(SmartString<LazyCompact>, Expr)> {
+            let mut inner = pair.into_inner();
+            let name_p = inner.next().unwrap();
+            let name = SmartString::from(name_p.as_str());
+            let arg = match inner.next() {
+                Some(a) => build_expr(a, param_pool)?,
+                None => Expr::Binding {
+                    var: Symbol::new(name.clone(), name_p.extract_span()),
+                    tuple_pos: None,
+                },
+            };
+            Ok((name, arg))
+        })
+        .try_collect()
+}
+
 fn parse_rule_head(
     src: Pair<'_>,
     param_pool: &BTreeMap<String, DataValue>,
@@ -712,6 +1025,9 @@ fn parse_rule_head_arg(
 #[derive(Debug, Error, Diagnostic)]
 #[error("bad specification of validity")]
 #[diagnostic(code(parser::bad_validity_spec))]
+#[diagnostic(help(
+    "Expected microseconds since the epoch, an RFC 3339 string such as '2024-03-01T00:00:00Z', 'NOW' or 'END'"
+))]
 struct BadValiditySpecification(#[label] SourceSpan);
 
 fn parse_fixed_rule(
@@ -782,6 +1098,7 @@ fn parse_fixed_rule(
                         let mut els = inner.into_inner();
                         let name = els.next().unwrap();
                         let mut bindings = vec![];
+                        let mut filters = vec![];
                         let mut valid_at = None;
                         for v in els {
                             match v.as_rule() {
@@ -801,6 +1118,9 @@ fn parse_fixed_rule(
                                         bindings.push(Symbol::new(v.as_str(), v.extract_span()))
                                     }
                                 }
+                                Rule::fixed_rel_filter => {
+                                    filters = parse_fixed_rel_filter(v, param_pool)?;
+                                }
                                 Rule::validity_clause => {
                                     let vld_inner = v.into_inner().next().unwrap();
                                     let vld_expr = build_expr(vld_inner, param_pool)?;
@@ -809,12 +1129,14 @@ fn parse_fixed_rule(
                                 _ => unreachable!(),
                             }
                         }
+                        check_fixed_rel_filter(&filters, bindings.iter())?;
                         rule_args.push(FixedRuleArg::Stored {
                             name: Symbol::new(
                                 name.as_str().strip_prefix('*').unwrap(),
                                 name.extract_span(),
                             ),
                             bindings,
+                            filters,
                             valid_at,
                             span,
                         })
@@ -823,6 +1145,7 @@ fn parse_fixed_rule(
                         let mut els = inner.into_inner();
                         let name = els.next().unwrap();
                         let mut bindings = BTreeMap::new();
+                        let mut filters = vec![];
                         let mut valid_at = None;
                         for p in els {
                             match p.as_rule() {
@@ -846,6 +1169,9 @@ fn parse_fixed_rule(
                                     };
                                     bindings.insert(k, v);
                                 }
+                                Rule::fixed_rel_filter => {
+                                    filters = parse_fixed_rel_filter(p, param_pool)?;
+                                }
                                 Rule::validity_clause => {
                                     let vld_inner = p.into_inner().next().unwrap();
                                     let vld_expr = build_expr(vld_inner, param_pool)?;
@@ -854,13 +1180,15 @@ fn parse_fixed_rule(
                                 _ => unreachable!(),
                             }
                         }
+                        check_fixed_rel_filter(&filters, bindings.values())?;
 
                         rule_args.push(FixedRuleArg::NamedStored {
                             name: Symbol::new(
-                                name.as_str().strip_prefix(':').unwrap(),
+                                name.as_str().strip_prefix('*').unwrap(),
                                 name.extract_span(),
                             ),
                             bindings,
+                            filters,
                             valid_at,
                             span,
                         })
@@ -881,6 +1209,25 @@ fn parse_fixed_rule(
 
     let fixed = FixedRuleHandle::new(fixed_name, name_pair.extract_span());
 
+    // options not depending on bindings are evaluated once here, so that rules
+    // see parameters and constant expressions as plain constants
+    for (name, val) in options.iter_mut() {
+        if !val.bindings().is_empty() {
+            continue;
+        }
+        let span = val.span();
+        let folded = val
+            .clone()
+            .eval_to_const()
+            .map_err(|err| WrongFixedRuleOptionError {
+                name: name.to_string(),
+                span,
+                rule_name: fixed.name.to_string(),
+                help: format!("the value cannot be evaluated: {err}"),
+            })?;
+        *val = Expr::Const { val: folded, span };
+    }
+
     let fixed_impl = fixed_rules
         .get(&fixed.name as &str)
         .ok_or_else(|| FixedRuleNotFoundError(fixed.name.to_string(), name_pair.extract_span()))?;
@@ -906,6 +1253,39 @@ fn parse_fixed_rule(
     ))
 }
 
+fn parse_fixed_rel_filter(
+    src: Pair<'_>,
+    param_pool: &BTreeMap<String, DataValue>,
+) -> Result<Vec<Expr>> {
+    src.into_inner()
+        .map(|p| build_expr(p, param_pool))
+        .try_collect()
+}
+
+fn check_fixed_rel_filter<'a>(
+    filters: &[Expr],
+    bindings: impl Iterator<Item = &'a Symbol>,
+) -> Result<()> {
+    #[derive(Debug, Error, Diagnostic)]
+    #[error("Filter on relation argument uses unbound variable '{0}'")]
+    #[diagnostic(code(parser::fixed_rel_filter_unbound))]
+    #[diagnostic(help(
+        "A filter on a relation passed to a fixed rule can only use variables bound by that relation"
+    ))]
+    struct FixedRelFilterUnboundError(String, #[label] SourceSpan);
+
+    let bound: BTreeSet<_> = bindings.collect();
+    for filter in filters {
+        for var in filter.bindings() {
+            ensure!(
+                bound.contains(&var),
+                FixedRelFilterUnboundError(var.to_string(), var.span)
+            );
+        }
+    }
+    Ok(())
+}
+
 #[derive(Debug, Error, Diagnostic)]
 #[error("Fixed rule head arity mismatch")]
 #[diagnostic(code(parser::fixed_rule_head_arity_mismatch))]
@@ -945,9 +1325,39 @@ fn make_empty_const_rule(prog: &mut InputProgram, bindings: &[Symbol]) {
     );
 }
 
-fn expr2vld_spec(expr: Expr, cur_vld: ValidityTs) -> Result<ValidityTs> {
+#[derive(Debug, Error, Diagnostic)]
+#[error("bad window of validity")]
+#[diagnostic(code(parser::bad_validity_window))]
+#[diagnostic(help("A window is given as a list `[from, to]` with `from` not later than `to`"))]
+struct BadValidityWindow(#[label] SourceSpan);
+
+/// The validity of the application of a stored relation, either a time or a window
+/// `[from, to]` of times, in which case the end of the window is returned as well
+fn expr2vld_window(expr: Expr, cur_vld: ValidityTs) -> Result<(ValidityTs, Option<ValidityTs>)> {
     let vld_span = expr.span();
     match expr.eval_to_const()? {
+        DataValue::List(l) => {
+            ensure!(l.len() == 2, BadValidityWindow(vld_span));
+            let valid_from = value2vld_spec(l[0].clone(), vld_span, cur_vld)?;
+            let valid_to = value2vld_spec(l[1].clone(), vld_span, cur_vld)?;
+            ensure!(
+                valid_from.0 .0 <= valid_to.0 .0,
+                BadValidityWindow(vld_span)
+            );
+            Ok((valid_from, Some(valid_to)))
+        }
+        v => Ok((value2vld_spec(v, vld_span, cur_vld)?, None)),
+    }
+}
+
+fn expr2vld_spec(expr: Expr, cur_vld: ValidityTs) -> Result<ValidityTs> {
+    let vld_span = expr.span();
+    value2vld_spec(expr.eval_to_const()?, vld_span, cur_vld)
+}
+
+fn value2vld_spec(v: DataValue, vld_span: SourceSpan, cur_vld: ValidityTs) -> Result<ValidityTs> {
+    match v {
+        DataValue::Validity(vld) => Ok(vld.timestamp),
         DataValue::Num(n) => {
             let microseconds = n.get_int().ok_or(BadValiditySpecification(vld_span))?;
             Ok(ValidityTs(Reverse(microseconds)))
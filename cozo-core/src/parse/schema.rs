@@ -13,7 +13,9 @@ use miette::{bail, ensure, Diagnostic, Result};
 use smartstring::SmartString;
 use thiserror::Error;
 
-use crate::data::relation::{ColType, ColumnDef, NullableColType, StoredRelationMetadata};
+use crate::data::relation::{
+    AutoUpdate, ColType, ColumnDef, NullableColType, StoredRelationMetadata,
+};
 use crate::data::symb::Symbol;
 use crate::data::value::DataValue;
 use crate::parse::expr::build_expr;
@@ -36,12 +38,30 @@ pub(crate) fn parse_schema(
     #[error("Column {0} is defined multiple times")]
     #[diagnostic(code(parser::dup_name_in_cols))]
     struct DuplicateNameInCols(String, #[label] SourceSpan);
+
+    #[derive(Debug, Error, Diagnostic)]
+    #[error("Key column {0} cannot be updated automatically")]
+    #[diagnostic(code(parser::auto_update_key))]
+    #[diagnostic(help("Updating a key column would write a different row"))]
+    struct AutoUpdateKey(String, #[label] SourceSpan);
+
+    #[derive(Debug, Error, Diagnostic)]
+    #[error("Key column {0} cannot be declared unique")]
+    #[diagnostic(code(parser::unique_key))]
+    #[diagnostic(help("Only non-key columns can be declared unique"))]
+    struct UniqueKey(String, #[label] SourceSpan);
     for p in src.next().unwrap().into_inner() {
         let span = p.extract_span();
         let (col, ident) = parse_col(p)?;
         if !seen_names.insert(col.name.clone()) {
             bail!(DuplicateNameInCols(col.name.to_string(), span));
         }
+        if col.auto_update.is_some() {
+            bail!(AutoUpdateKey(col.name.to_string(), span));
+        }
+        if col.unique {
+            bail!(UniqueKey(col.name.to_string(), span));
+        }
         keys.push(col);
         key_bindings.push(ident)
     }
@@ -75,7 +95,7 @@ pub(crate) fn parse_schema(
     ))
 }
 
-fn parse_col(pair: Pair<'_>) -> Result<(ColumnDef, Symbol)> {
+pub(crate) fn parse_col(pair: Pair<'_>) -> Result<(ColumnDef, Symbol)> {
     let mut src = pair.into_inner();
     let name_p = src.next().unwrap();
     let name = SmartString::from(name_p.as_str());
@@ -85,6 +105,8 @@ fn parse_col(pair: Pair<'_>) -> Result<(ColumnDef, Symbol)> {
     };
     let mut default_gen = None;
     let mut binding_candidate = None;
+    let mut auto_update = None;
+    let mut unique = false;
     for nxt in src {
         match nxt.as_rule() {
             Rule::col_type => typing = parse_nullable_type(nxt)?,
@@ -92,6 +114,16 @@ fn parse_col(pair: Pair<'_>) -> Result<(ColumnDef, Symbol)> {
             Rule::out_arg => {
                 binding_candidate = Some(Symbol::new(nxt.as_str(), nxt.extract_span()))
             }
+            Rule::auto_update => {
+                let mut inner = nxt.into_inner();
+                // parameters are only known when writing, so the expression is kept as source
+                let expr = inner.next().unwrap().as_str().to_string();
+                auto_update = Some(AutoUpdate {
+                    expr,
+                    allow_override: inner.next().is_some(),
+                })
+            }
+            Rule::unique_col => unique = true,
             r => unreachable!("{:?}", r),
         }
     }
@@ -102,6 +134,8 @@ fn parse_col(pair: Pair<'_>) -> Result<(ColumnDef, Symbol)> {
             name,
             typing,
             default_gen,
+            auto_update,
+            unique,
         },
         binding,
     ))
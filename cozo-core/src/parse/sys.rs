@@ -11,32 +11,76 @@ use std::sync::Arc;
 
 use itertools::Itertools;
 use miette::{ensure, miette, Diagnostic, Result};
+use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
+use crate::data::expr::Expr;
 use crate::data::program::InputProgram;
 use crate::data::symb::Symbol;
 use crate::data::value::{DataValue, ValidityTs};
 use crate::parse::expr::build_expr;
 use crate::parse::query::parse_query;
-use crate::parse::{ExtractSpan, Pairs, Rule, SourceSpan};
+use crate::parse::schema::parse_col;
+use crate::parse::{ExtractSpan, Pair, Pairs, Rule, SourceSpan};
+use crate::runtime::alter::AlterOp;
+use crate::runtime::audit::AuditFlags;
+use crate::runtime::constraint::ForeignKey;
+use crate::runtime::csv_io::{CsvOptions, CsvSource};
+use crate::runtime::fts::FtsOptions;
+use crate::runtime::graph::{GraphDef, GraphRelation};
+use crate::runtime::hnsw::HnswOptions;
+use crate::runtime::lsh::LshOptions;
 use crate::runtime::relation::AccessLevel;
+use crate::runtime::retention::HistoryRetention;
 use crate::FixedRule;
 
 pub(crate) enum SysOp {
     Compact,
+    CheckIntegrity,
+    StorageInfo,
     ListRelation(Symbol),
     ListRelations,
     ListRunning,
     ListFixedRules,
     KillRunning(u64),
     Explain(Box<InputProgram>),
+    Profile(Box<InputProgram>),
+    Estimate(Box<InputProgram>, Option<usize>),
     RemoveRelation(Vec<Symbol>),
     RenameRelation(Vec<(Symbol, Symbol)>),
+    RebuildRelation(Symbol),
+    AlterRelation(Symbol, AlterOp),
+    SetTtl(Symbol, Option<Symbol>),
+    SweepExpired(Symbol),
+    SetHistoryRetention(Symbol, Option<HistoryRetention>),
+    ImportCsv(Symbol, String, CsvOptions),
+    ExportCsv(CsvSource, String, CsvOptions),
+    ImportJsonl(Symbol, String),
+    ExportJsonl(Symbol, String),
+    RestoreRelations(String, Vec<(Symbol, Symbol)>),
     ShowTrigger(Symbol),
     SetTriggers(Symbol, Vec<String>, Vec<String>, Vec<String>),
     SetAccessLevel(Vec<Symbol>, AccessLevel),
-    CreateIndex(Symbol, Symbol, Vec<Symbol>),
+    SetAudit(Symbol, AuditFlags),
+    ShowAuditLog,
+    CreateIndex(Symbol, Symbol, Vec<Symbol>, Option<Expr>),
     RemoveIndex(Symbol, Symbol),
+    ListIndices(Symbol),
+    CreateHnswIndex(Symbol, Symbol, HnswOptions),
+    RemoveHnswIndex(Symbol, Symbol),
+    RebuildHnswIndex(Symbol, Symbol),
+    CreateFtsIndex(Symbol, Symbol, FtsOptions),
+    RemoveFtsIndex(Symbol, Symbol),
+    RebuildFtsIndex(Symbol, Symbol),
+    CreateLshIndex(Symbol, Symbol, LshOptions),
+    RemoveLshIndex(Symbol, Symbol),
+    RebuildLshIndex(Symbol, Symbol),
+    CreateConstraint(ForeignKey),
+    RemoveConstraint(Symbol, SmartString<LazyCompact>),
+    ListConstraints,
+    CreateGraph(GraphDef),
+    RemoveGraph(Symbol),
+    ListGraphs,
 }
 
 #[derive(Debug, Diagnostic, Error)]
@@ -53,6 +97,8 @@ pub(crate) fn parse_sys(
     let inner = src.next().unwrap();
     Ok(match inner.as_rule() {
         Rule::compact_op => SysOp::Compact,
+        Rule::check_integrity_op => SysOp::CheckIntegrity,
+        Rule::storage_info_op => SysOp::StorageInfo,
         Rule::running_op => SysOp::ListRunning,
         Rule::kill_op => {
             let i_expr = inner.into_inner().next().unwrap();
@@ -72,6 +118,43 @@ pub(crate) fn parse_sys(
             )?;
             SysOp::Explain(Box::new(prog))
         }
+        Rule::profile_op => {
+            let prog = parse_query(
+                inner.into_inner().next().unwrap().into_inner(),
+                param_pool,
+                algorithms,
+                cur_vld,
+            )?;
+            SysOp::Profile(Box::new(prog))
+        }
+        Rule::estimate_op => {
+            let mut inner = inner.into_inner();
+            let prog = parse_query(
+                inner.next().unwrap().into_inner(),
+                param_pool,
+                algorithms,
+                cur_vld,
+            )?;
+            let sample = match inner.next() {
+                None => None,
+                Some(sample_p) => {
+                    let expr_p = sample_p.into_inner().next().unwrap();
+                    let span = expr_p.extract_span();
+                    let n = build_expr(expr_p, param_pool)?.eval_to_const()?;
+
+                    #[derive(Debug, Diagnostic, Error)]
+                    #[error("Sample size must be a positive integer")]
+                    #[diagnostic(code(parser::bad_estimate_sample))]
+                    struct BadSampleSize(#[label] SourceSpan);
+
+                    match n.get_int() {
+                        Some(n) if n > 0 => Some(n as usize),
+                        _ => return Err(BadSampleSize(span).into()),
+                    }
+                }
+            };
+            SysOp::Estimate(Box::new(prog), sample)
+        }
         Rule::list_relations_op => SysOp::ListRelations,
         Rule::remove_relations_op => {
             let rel = inner
@@ -86,6 +169,119 @@ pub(crate) fn parse_sys(
             let rel = Symbol::new(rels_p.as_str(), rels_p.extract_span());
             SysOp::ListRelation(rel)
         }
+        Rule::rebuild_relation_op => {
+            let rels_p = inner.into_inner().next().unwrap();
+            let rel = Symbol::new(rels_p.as_str(), rels_p.extract_span());
+            SysOp::RebuildRelation(rel)
+        }
+        Rule::alter_op => {
+            let mut src = inner.into_inner();
+            let rels_p = src.next().unwrap();
+            let rel = Symbol::new(rels_p.as_str(), rels_p.extract_span());
+            let op_p = src.next().unwrap();
+            let op = match op_p.as_rule() {
+                Rule::alter_add => {
+                    let (col, _) = parse_col(op_p.into_inner().next().unwrap())?;
+                    AlterOp::Add(col, cur_vld)
+                }
+                Rule::alter_drop => {
+                    let col_p = op_p.into_inner().next().unwrap();
+                    AlterOp::Drop(Symbol::new(col_p.as_str(), col_p.extract_span()))
+                }
+                Rule::alter_rename => {
+                    let mut cols = op_p
+                        .into_inner()
+                        .map(|col_p| Symbol::new(col_p.as_str(), col_p.extract_span()));
+                    AlterOp::Rename(cols.next().unwrap(), cols.next().unwrap())
+                }
+                _ => unreachable!(),
+            };
+            SysOp::AlterRelation(rel, op)
+        }
+        Rule::set_ttl_op => {
+            let mut src = inner.into_inner();
+            let rels_p = src.next().unwrap();
+            let rel = Symbol::new(rels_p.as_str(), rels_p.extract_span());
+            let col = src
+                .next()
+                .map(|p| Symbol::new(p.as_str(), p.extract_span()));
+            SysOp::SetTtl(rel, col)
+        }
+        Rule::ttl_sweep_op => {
+            let rels_p = inner.into_inner().next().unwrap();
+            let rel = Symbol::new(rels_p.as_str(), rels_p.extract_span());
+            SysOp::SweepExpired(rel)
+        }
+        Rule::set_history_retention_op => {
+            let span = inner.extract_span();
+            let mut src = inner.into_inner();
+            let rels_p = src.next().unwrap();
+            let rel = Symbol::new(rels_p.as_str(), rels_p.extract_span());
+            let retention = match src.peek() {
+                Some(p) if p.as_rule() == Rule::null => None,
+                _ => {
+                    let (_, given) = parse_search_index_options(src, param_pool)?;
+                    Some(HistoryRetention::new(given, span)?)
+                }
+            };
+            SysOp::SetHistoryRetention(rel, retention)
+        }
+        Rule::import_csv_op => {
+            let mut src = inner.into_inner();
+            let rels_p = src.next().unwrap();
+            let rel = Symbol::new(rels_p.as_str(), rels_p.extract_span());
+            let path = parse_file_path(src.next().unwrap(), param_pool)?;
+            let options = parse_csv_options(src.next(), param_pool, true)?;
+            SysOp::ImportCsv(rel, path, options)
+        }
+        Rule::export_csv_op => {
+            let mut src = inner.into_inner();
+            let source_p = src.next().unwrap();
+            let source = match source_p.as_rule() {
+                Rule::export_csv_query => {
+                    let prog = parse_query(
+                        source_p.into_inner().next().unwrap().into_inner(),
+                        param_pool,
+                        algorithms,
+                        cur_vld,
+                    )?;
+                    CsvSource::Query(Box::new(prog))
+                }
+                _ => CsvSource::Relation(Symbol::new(source_p.as_str(), source_p.extract_span())),
+            };
+            let path = parse_file_path(src.next().unwrap(), param_pool)?;
+            let options = parse_csv_options(src.next(), param_pool, false)?;
+            SysOp::ExportCsv(source, path, options)
+        }
+        Rule::import_jsonl_op | Rule::export_jsonl_op => {
+            let is_import = inner.as_rule() == Rule::import_jsonl_op;
+            let mut src = inner.into_inner();
+            let rels_p = src.next().unwrap();
+            let rel = Symbol::new(rels_p.as_str(), rels_p.extract_span());
+            let path = parse_file_path(src.next().unwrap(), param_pool)?;
+            if is_import {
+                SysOp::ImportJsonl(rel, path)
+            } else {
+                SysOp::ExportJsonl(rel, path)
+            }
+        }
+        Rule::restore_relation_op => {
+            let mut src = inner.into_inner();
+            let path = parse_file_path(src.next().unwrap(), param_pool)?;
+            let mappings = src
+                .map(|pair| {
+                    let mut src = pair.into_inner();
+                    let rels_p = src.next().unwrap();
+                    let rel = Symbol::new(rels_p.as_str(), rels_p.extract_span());
+                    let new_rel = match src.next() {
+                        Some(rels_p) => Symbol::new(rels_p.as_str(), rels_p.extract_span()),
+                        None => rel.clone(),
+                    };
+                    (rel, new_rel)
+                })
+                .collect_vec();
+            SysOp::RestoreRelations(path, mappings)
+        }
         Rule::rename_relations_op => {
             let rename_pairs = inner
                 .into_inner()
@@ -156,9 +352,16 @@ pub(crate) fn parse_sys(
                     let mut inner = inner.into_inner();
                     let rel = inner.next().unwrap();
                     let name = inner.next().unwrap();
-                    let cols = inner
-                        .map(|p| Symbol::new(p.as_str(), p.extract_span()))
-                        .collect_vec();
+                    let mut cols = vec![];
+                    let mut predicate = None;
+                    for p in inner {
+                        if p.as_rule() == Rule::index_predicate {
+                            let expr = build_expr(p.into_inner().next().unwrap(), param_pool)?;
+                            predicate = Some(expr);
+                        } else {
+                            cols.push(Symbol::new(p.as_str(), p.extract_span()));
+                        }
+                    }
 
                     #[derive(Debug, Diagnostic, Error)]
                     #[error("index must have at least one column specified")]
@@ -170,6 +373,7 @@ pub(crate) fn parse_sys(
                         Symbol::new(rel.as_str(), rel.extract_span()),
                         Symbol::new(name.as_str(), name.extract_span()),
                         cols,
+                        predicate,
                     )
                 }
                 Rule::index_drop => {
@@ -184,7 +388,224 @@ pub(crate) fn parse_sys(
                 _ => unreachable!(),
             }
         }
+        Rule::list_indices_op => {
+            let rel_p = inner.into_inner().next().unwrap();
+            SysOp::ListIndices(Symbol::new(rel_p.as_str(), rel_p.extract_span()))
+        }
+        Rule::constraint_op => {
+            let inner = inner.into_inner().next().unwrap();
+            match inner.as_rule() {
+                Rule::constraint_create => {
+                    let mut inner = inner.into_inner();
+                    let (relation, column) = parse_relation_column(inner.next().unwrap())?;
+                    let (target, target_column) = parse_relation_column(inner.next().unwrap())?;
+                    let cascade = match inner.next() {
+                        None => false,
+                        Some(on_delete) => on_delete.into_inner().next().is_some(),
+                    };
+                    SysOp::CreateConstraint(ForeignKey {
+                        relation: relation.name,
+                        column,
+                        target: target.name,
+                        target_column,
+                        cascade,
+                    })
+                }
+                Rule::constraint_drop => {
+                    let (relation, column) =
+                        parse_relation_column(inner.into_inner().next().unwrap())?;
+                    SysOp::RemoveConstraint(relation, column)
+                }
+                _ => unreachable!(),
+            }
+        }
+        Rule::list_constraints_op => SysOp::ListConstraints,
+        Rule::hnsw_op | Rule::fts_op | Rule::lsh_op => {
+            let inner = inner.into_inner().next().unwrap();
+            let op_rule = inner.as_rule();
+            let span = inner.extract_span();
+            let mut inner = inner.into_inner();
+            let rel = inner.next().unwrap();
+            let rel = Symbol::new(rel.as_str(), rel.extract_span());
+            let name = inner.next().unwrap();
+            let name = Symbol::new(name.as_str(), name.extract_span());
+            match op_rule {
+                Rule::hnsw_create => {
+                    let (fields, given) = parse_search_index_options(inner, param_pool)?;
+                    SysOp::CreateHnswIndex(rel, name, HnswOptions::new(given, fields, span)?)
+                }
+                Rule::hnsw_drop => SysOp::RemoveHnswIndex(rel, name),
+                Rule::hnsw_rebuild => SysOp::RebuildHnswIndex(rel, name),
+                Rule::fts_create => {
+                    let (fields, given) = parse_search_index_options(inner, param_pool)?;
+                    SysOp::CreateFtsIndex(rel, name, FtsOptions::new(given, fields, span)?)
+                }
+                Rule::fts_drop => SysOp::RemoveFtsIndex(rel, name),
+                Rule::fts_rebuild => SysOp::RebuildFtsIndex(rel, name),
+                Rule::lsh_create => {
+                    let (fields, given) = parse_search_index_options(inner, param_pool)?;
+                    SysOp::CreateLshIndex(rel, name, LshOptions::new(given, fields, span)?)
+                }
+                Rule::lsh_drop => SysOp::RemoveLshIndex(rel, name),
+                Rule::lsh_rebuild => SysOp::RebuildLshIndex(rel, name),
+                _ => unreachable!(),
+            }
+        }
+        Rule::graph_op => {
+            let inner = inner.into_inner().next().unwrap();
+            match inner.as_rule() {
+                Rule::graph_create => {
+                    let span = inner.extract_span();
+                    let mut inner = inner.into_inner();
+                    let name = inner.next().unwrap();
+                    let mut edges = None;
+                    let mut nodes = None;
+                    let mut undirected = false;
+                    for opt in inner {
+                        match opt.as_rule() {
+                            Rule::graph_edges => {
+                                edges = Some(parse_graph_relation(opt.into_inner()));
+                            }
+                            Rule::graph_nodes => {
+                                nodes = Some(parse_graph_relation(opt.into_inner()));
+                            }
+                            Rule::graph_undirected => {
+                                undirected = opt.into_inner().next().unwrap().as_str() == "true";
+                            }
+                            _ => unreachable!(),
+                        }
+                    }
+
+                    #[derive(Debug, Diagnostic, Error)]
+                    #[error("graph definition requires an edge relation")]
+                    #[diagnostic(code(parser::graph_without_edges))]
+                    #[diagnostic(help("Specify it as `edges: rel[from, to]`"))]
+                    struct GraphWithoutEdges(#[label] SourceSpan);
+
+                    let edges = edges.ok_or(GraphWithoutEdges(span))?;
+                    SysOp::CreateGraph(GraphDef {
+                        name: name.as_str().into(),
+                        edges,
+                        nodes,
+                        undirected,
+                    })
+                }
+                Rule::graph_drop => {
+                    let name = inner.into_inner().next().unwrap();
+                    SysOp::RemoveGraph(Symbol::new(name.as_str(), name.extract_span()))
+                }
+                Rule::graph_list => SysOp::ListGraphs,
+                _ => unreachable!(),
+            }
+        }
         Rule::list_fixed_rules => SysOp::ListFixedRules,
+        Rule::audit_op => {
+            let inner = inner.into_inner().next().unwrap();
+            match inner.as_rule() {
+                Rule::audit_enable => {
+                    let mut ps = inner.into_inner();
+                    let rel_p = ps.next().unwrap();
+                    let rel = Symbol::new(rel_p.as_str(), rel_p.extract_span());
+                    let mut flags = AuditFlags::default();
+                    for kind in ps {
+                        match kind.as_str() {
+                            "reads" => flags.reads = true,
+                            "writes" => flags.writes = true,
+                            _ => unreachable!(),
+                        }
+                    }
+                    SysOp::SetAudit(rel, flags)
+                }
+                Rule::audit_disable => {
+                    let rel_p = inner.into_inner().next().unwrap();
+                    let rel = Symbol::new(rel_p.as_str(), rel_p.extract_span());
+                    SysOp::SetAudit(rel, AuditFlags::default())
+                }
+                Rule::audit_log => SysOp::ShowAuditLog,
+                _ => unreachable!(),
+            }
+        }
         rule => unreachable!("{:?}", rule),
     })
 }
+
+/// Splits `rel.col` into the relation and the column, the relation name may have dots itself
+fn parse_relation_column(pair: Pair<'_>) -> Result<(Symbol, SmartString<LazyCompact>)> {
+    #[derive(Debug, Diagnostic, Error)]
+    #[error("Expected a column given as `relation.column`, got {0}")]
+    #[diagnostic(code(parser::constraint_column))]
+    struct ConstraintColumnError(String, #[label] SourceSpan);
+
+    let span = pair.extract_span();
+    let (relation, column) = pair
+        .as_str()
+        .rsplit_once('.')
+        .ok_or_else(|| ConstraintColumnError(pair.as_str().to_string(), span))?;
+    Ok((Symbol::new(relation, span), column.into()))
+}
+
+fn parse_file_path(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Result<String> {
+    #[derive(Debug, Diagnostic, Error)]
+    #[error("The path of a file must be a string")]
+    #[diagnostic(code(parser::bad_file_path))]
+    struct BadFilePath(#[label] SourceSpan);
+
+    let span = pair.extract_span();
+    let path = build_expr(pair, param_pool)?.eval_to_const()?;
+    Ok(path.get_str().ok_or(BadFilePath(span))?.to_string())
+}
+
+fn parse_csv_options(
+    pair: Option<Pair<'_>>,
+    param_pool: &BTreeMap<String, DataValue>,
+    for_import: bool,
+) -> Result<CsvOptions> {
+    let mut given = vec![];
+    if let Some(pair) = pair {
+        for opt in pair.into_inner() {
+            let mut opt = opt.into_inner();
+            let name = opt.next().unwrap().as_str().to_string();
+            let expr_p = opt.next().unwrap();
+            let span = expr_p.extract_span();
+            let value = build_expr(expr_p, param_pool)?.eval_to_const()?;
+            given.push((name, value, span));
+        }
+    }
+    CsvOptions::new(given, for_import)
+}
+
+/// The indexed columns and the other options of `::hnsw create`, `::fts create` and `::lsh create`
+fn parse_search_index_options(
+    src: Pairs<'_>,
+    param_pool: &BTreeMap<String, DataValue>,
+) -> Result<(Vec<Symbol>, Vec<(String, DataValue, SourceSpan)>)> {
+    let mut fields = vec![];
+    let mut given = vec![];
+    for opt in src {
+        match opt.as_rule() {
+            Rule::search_index_fields => {
+                fields.extend(
+                    opt.into_inner()
+                        .map(|p| Symbol::new(p.as_str(), p.extract_span())),
+                );
+            }
+            Rule::search_index_option => {
+                let mut opt = opt.into_inner();
+                let name = opt.next().unwrap().as_str().to_string();
+                let expr_p = opt.next().unwrap();
+                let span = expr_p.extract_span();
+                let value = build_expr(expr_p, param_pool)?.eval_to_const()?;
+                given.push((name, value, span));
+            }
+            _ => unreachable!(),
+        }
+    }
+    Ok((fields, given))
+}
+
+fn parse_graph_relation(mut src: Pairs<'_>) -> GraphRelation {
+    let mut inner = src.next().unwrap().into_inner();
+    let relation = inner.next().unwrap().as_str().into();
+    let columns = inner.map(|p| p.as_str().into()).collect_vec();
+    GraphRelation { relation, columns }
+}
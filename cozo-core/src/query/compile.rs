@@ -7,6 +7,7 @@
  */
 
 use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
 
 use itertools::Itertools;
 use miette::{bail, ensure, Context, Diagnostic, Result};
@@ -15,19 +16,24 @@ use thiserror::Error;
 use crate::data::aggr::Aggregation;
 use crate::data::expr::Expr;
 use crate::data::program::{
-    MagicAtom, MagicFixedRuleApply, MagicInlineRule, MagicRulesOrFixed, MagicSymbol,
-    StratifiedMagicProgram,
+    MagicAtom, MagicFixedRuleApply, MagicFixedRuleRuleArg, MagicInlineRule, MagicRelationApplyAtom,
+    MagicRulesOrFixed, MagicSymbol, StratifiedMagicProgram,
 };
 use crate::data::symb::Symbol;
 use crate::data::value::DataValue;
 use crate::parse::SourceSpan;
-use crate::query::ra::RelAlgebra;
-use crate::runtime::relation::{AccessLevel, InsufficientAccessLevel};
+use crate::query::ra::{RelAlgebra, SharedScan};
+use crate::runtime::fts::FtsIndexNotFound;
+use crate::runtime::hnsw::{HnswIndexNotFound, DEFAULT_HNSW_EF};
+use crate::runtime::lsh::LshIndexNotFound;
+use crate::runtime::relation::{
+    index_filter_key, AccessLevel, InsufficientAccessLevel, RelationHandle,
+};
 use crate::runtime::transact::SessionTx;
 
 pub(crate) type CompiledProgram = BTreeMap<MagicSymbol, CompiledRuleSet>;
 
-#[derive(Debug)]
+#[derive(Debug, serde_derive::Serialize, serde_derive::Deserialize)]
 pub(crate) enum CompiledRuleSet {
     Rules(Vec<CompiledRule>),
     Fixed(MagicFixedRuleApply),
@@ -77,7 +83,7 @@ impl CompiledRuleSet {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, serde_derive::Serialize, serde_derive::Deserialize)]
 pub(crate) struct CompiledRule {
     pub(crate) aggr: Vec<Option<(Aggregation, Vec<DataValue>)>>,
     pub(crate) relation: RelAlgebra,
@@ -115,7 +121,7 @@ impl<'a> SessionTx<'a> {
             }
         }
 
-        let compiled: Vec<_> = prog
+        let mut compiled: Vec<_> = prog
             .0
             .into_iter()
             .rev()
@@ -153,6 +159,7 @@ impl<'a> SessionTx<'a> {
                     .try_collect()
             })
             .try_collect()?;
+        share_common_scans(&mut compiled)?;
         Ok(compiled)
     }
     pub(crate) fn compile_magic_rule_body(
@@ -170,6 +177,25 @@ impl<'a> SessionTx<'a> {
             serial_id += 1;
             ret
         };
+        // filters of the body, which may allow partial indices to be used
+        let body_filters = rule
+            .body
+            .iter()
+            .flat_map(|atom| match atom {
+                MagicAtom::Predicate(p) => p.to_conjunction(),
+                MagicAtom::Unification(u) if !u.one_many_unif => vec![Expr::build_equate(
+                    vec![
+                        Expr::Binding {
+                            var: u.binding.clone(),
+                            tuple_pos: None,
+                        },
+                        u.expr.clone(),
+                    ],
+                    u.span,
+                )],
+                _ => vec![],
+            })
+            .collect_vec();
         for atom in &rule.body {
             match atom {
                 MagicAtom::Rule(rule_app) => {
@@ -219,11 +245,12 @@ impl<'a> SessionTx<'a> {
                             store.access_level
                         ));
                     }
+                    let arity = stored_scan_arity(&store, rel_app);
                     ensure!(
-                        store.arity() == rel_app.args.len(),
+                        arity == rel_app.args.len(),
                         ArityMismatch(
                             rel_app.name.to_string(),
-                            store.arity(),
+                            arity,
                             rel_app.args.len(),
                             rel_app.span
                         )
@@ -258,18 +285,22 @@ impl<'a> SessionTx<'a> {
                         }
                     }
 
-                    let chosen_index =
-                        store.choose_index(&join_indices, rel_app.valid_at.is_some());
+                    // indices are not used for scans of windows of validity
+                    let chosen_index = if rel_app.valid_until.is_some() {
+                        None
+                    } else {
+                        store.choose_index(
+                            &join_indices,
+                            rel_app.valid_at.is_some(),
+                            &filters_on_relation(&body_filters, &rel_app.args, &store),
+                        )
+                    };
+                    let audited = store.audit.reads.then(|| store.name.clone());
 
                     match chosen_index {
                         None => {
                             // scan original relation
-                            let right = RelAlgebra::relation(
-                                right_vars,
-                                store,
-                                rel_app.span,
-                                rel_app.valid_at,
-                            )?;
+                            let right = stored_scan(right_vars, store, rel_app)?.audited(audited);
                             debug_assert_eq!(prev_joiner_vars.len(), right_joiner_vars.len());
                             ret =
                                 ret.join(right, prev_joiner_vars, right_joiner_vars, rel_app.span);
@@ -285,7 +316,8 @@ impl<'a> SessionTx<'a> {
                                 chosen_index,
                                 rel_app.span,
                                 rel_app.valid_at,
-                            )?;
+                            )?
+                            .audited(audited);
                             debug_assert_eq!(prev_joiner_vars.len(), right_joiner_vars.len());
                             ret =
                                 ret.join(right, prev_joiner_vars, right_joiner_vars, rel_app.span);
@@ -316,10 +348,12 @@ impl<'a> SessionTx<'a> {
                                 })
                                 .collect_vec();
 
-                            let mut final_joiner_vars = vec![];
-                            for idx in mapper.iter() {
-                                final_joiner_vars.push(right_vars[*idx].clone());
-                            }
+                            // joined by the keys, as are the rows of the index above
+                            let final_joiner_vars = mapper
+                                .iter()
+                                .filter(|idx| **idx < store.metadata.keys.len())
+                                .map(|idx| right_vars[*idx].clone())
+                                .collect_vec();
 
                             let middle = RelAlgebra::relation(
                                 middle_vars,
@@ -333,12 +367,14 @@ impl<'a> SessionTx<'a> {
                                 middle_joiner_left_vars,
                                 rel_app.span,
                             );
+                            // the rows are counted once, as read from the relation
                             let final_alg = RelAlgebra::relation(
                                 right_vars,
                                 store,
                                 rel_app.span,
                                 rel_app.valid_at,
-                            )?;
+                            )?
+                            .audited(audited);
                             ret = ret.join(
                                 final_alg,
                                 middle_joiner_right_vars,
@@ -387,11 +423,12 @@ impl<'a> SessionTx<'a> {
                 }
                 MagicAtom::NegatedRelation(rel_app) => {
                     let store = self.get_relation(&rel_app.name, false)?;
+                    let arity = stored_scan_arity(&store, rel_app);
                     ensure!(
-                        store.arity() == rel_app.args.len(),
+                        arity == rel_app.args.len(),
                         ArityMismatch(
                             rel_app.name.to_string(),
-                            store.arity(),
+                            arity,
                             rel_app.args.len(),
                             rel_app.span
                         )
@@ -427,17 +464,20 @@ impl<'a> SessionTx<'a> {
                         }
                     }
 
-                    let chosen_index =
-                        store.choose_index(&join_indices, rel_app.valid_at.is_some());
+                    // indices are not used for scans of windows of validity
+                    let chosen_index = if rel_app.valid_until.is_some() {
+                        None
+                    } else {
+                        store.choose_index(
+                            &join_indices,
+                            rel_app.valid_at.is_some(),
+                            &filters_on_relation(&body_filters, &rel_app.args, &store),
+                        )
+                    };
 
                     match chosen_index {
                         None | Some((_, _, true)) => {
-                            let right = RelAlgebra::relation(
-                                right_vars,
-                                store,
-                                rel_app.span,
-                                rel_app.valid_at,
-                            )?;
+                            let right = stored_scan(right_vars, store, rel_app)?;
                             debug_assert_eq!(prev_joiner_vars.len(), right_joiner_vars.len());
                             ret = ret.neg_join(
                                 right,
@@ -502,6 +542,121 @@ impl<'a> SessionTx<'a> {
                         ret = ret.unify(u.binding.clone(), u.expr.clone(), u.one_many_unif, u.span);
                     }
                 }
+                MagicAtom::HnswSearch(search) => {
+                    let store = self.get_relation(&search.relation, false)?;
+                    if store.access_level < AccessLevel::ReadOnly {
+                        bail!(InsufficientAccessLevel(
+                            store.name.to_string(),
+                            "reading rows".to_string(),
+                            store.access_level
+                        ));
+                    }
+                    let (idx, manifest) = store
+                        .hnsw_indices
+                        .get(&search.index.name)
+                        .ok_or_else(|| {
+                            HnswIndexNotFound(
+                                search.relation.to_string(),
+                                search.index.to_string(),
+                                search.index.span,
+                            )
+                        })?
+                        .clone();
+                    let (mut bindings, joiners) =
+                        bind_search_vars(search.all_bindings(), &mut seen_variables, &mut gen_symb);
+                    let bind_distance = search.bind_distance.as_ref().and_then(|_| bindings.pop());
+                    let ef = search.ef.unwrap_or(DEFAULT_HNSW_EF).max(search.k);
+                    ret = ret.hnsw_search(
+                        store,
+                        idx,
+                        manifest,
+                        bindings,
+                        search.query.clone(),
+                        search.k,
+                        ef,
+                        bind_distance,
+                        search.span,
+                    );
+                    for joiner in joiners {
+                        ret = ret.filter(joiner);
+                    }
+                }
+                MagicAtom::FtsSearch(search) => {
+                    let store = self.get_relation(&search.relation, false)?;
+                    if store.access_level < AccessLevel::ReadOnly {
+                        bail!(InsufficientAccessLevel(
+                            store.name.to_string(),
+                            "reading rows".to_string(),
+                            store.access_level
+                        ));
+                    }
+                    let (idx, manifest) = store
+                        .fts_indices
+                        .get(&search.index.name)
+                        .ok_or_else(|| {
+                            FtsIndexNotFound(
+                                search.relation.to_string(),
+                                search.index.to_string(),
+                                search.index.span,
+                            )
+                        })?
+                        .clone();
+                    let (mut bindings, joiners) =
+                        bind_search_vars(search.all_bindings(), &mut seen_variables, &mut gen_symb);
+                    let bind_score = search.bind_score.as_ref().and_then(|_| bindings.pop());
+                    ret = ret.fts_search(
+                        store,
+                        idx,
+                        manifest,
+                        bindings,
+                        search.query.clone(),
+                        search.k,
+                        bind_score,
+                        search.span,
+                    );
+                    for joiner in joiners {
+                        ret = ret.filter(joiner);
+                    }
+                }
+                MagicAtom::LshSearch(search) => {
+                    let store = self.get_relation(&search.relation, false)?;
+                    if store.access_level < AccessLevel::ReadOnly {
+                        bail!(InsufficientAccessLevel(
+                            store.name.to_string(),
+                            "reading rows".to_string(),
+                            store.access_level
+                        ));
+                    }
+                    let (idx, manifest) = store
+                        .lsh_indices
+                        .get(&search.index.name)
+                        .ok_or_else(|| {
+                            LshIndexNotFound(
+                                search.relation.to_string(),
+                                search.index.to_string(),
+                                search.index.span,
+                            )
+                        })?
+                        .clone();
+                    let (mut bindings, joiners) =
+                        bind_search_vars(search.all_bindings(), &mut seen_variables, &mut gen_symb);
+                    let bind_similarity =
+                        search.bind_similarity.as_ref().and_then(|_| bindings.pop());
+                    let min_similarity = search.min_similarity.unwrap_or(manifest.threshold);
+                    ret = ret.lsh_search(
+                        store,
+                        idx,
+                        manifest,
+                        bindings,
+                        search.query.clone(),
+                        min_similarity,
+                        bind_similarity,
+                        search.span,
+                    );
+                    for joiner in joiners {
+                        ret = ret.filter(joiner);
+                    }
+                }
             }
         }
 
@@ -535,3 +690,145 @@ impl<'a> SessionTx<'a> {
         Ok(ret)
     }
 }
+
+/// Lets identical full scans of stored relations appearing in more than one place
+/// of the program (across rules and strata) share a single materialized copy.
+pub(crate) fn share_common_scans(strata: &mut [CompiledProgram]) -> Result<()> {
+    let mut collected = BTreeMap::new();
+    for stratum in strata.iter_mut() {
+        for ruleset in stratum.values_mut() {
+            if let CompiledRuleSet::Rules(rules) = ruleset {
+                for rule in rules.iter_mut() {
+                    rule.relation.collect_full_scans(&mut collected)?;
+                }
+            }
+        }
+    }
+    let mut scan_id = 0;
+    for (_, consumers) in collected {
+        if consumers.len() < 2 {
+            continue;
+        }
+        let shared = Arc::new(SharedScan::new(scan_id, consumers.len()));
+        scan_id += 1;
+        for consumer in consumers {
+            consumer.shared = Some(shared.clone());
+        }
+    }
+    Ok(())
+}
+
+/// Names of the stored relations read anywhere in the program.
+pub(crate) fn stored_relations_read(strata: &[CompiledProgram]) -> BTreeSet<String> {
+    let mut collected = BTreeSet::new();
+    for ruleset in strata.iter().flat_map(|stratum| stratum.values()) {
+        match ruleset {
+            CompiledRuleSet::Rules(rules) => {
+                for rule in rules {
+                    rule.relation.collect_stored_relations(&mut collected);
+                }
+            }
+            CompiledRuleSet::Fixed(fixed) => {
+                for arg in &fixed.rule_args {
+                    if let MagicFixedRuleRuleArg::Stored { name, .. } = arg {
+                        collected.insert(name.name.to_string());
+                    }
+                }
+            }
+        }
+    }
+    collected
+}
+
+/// The variables bound by a search, those already bound being renamed, with the filters
+/// joining the renamed variables on the bound ones
+fn bind_search_vars<'v>(
+    vars: impl Iterator<Item = &'v Symbol>,
+    seen_variables: &mut BTreeSet<Symbol>,
+    gen_symb: &mut impl FnMut(SourceSpan) -> Symbol,
+) -> (Vec<Symbol>, Vec<Expr>) {
+    let mut bindings = vec![];
+    let mut joiners = vec![];
+    for var in vars {
+        if seen_variables.insert(var.clone()) {
+            bindings.push(var.clone());
+        } else {
+            let renamed = gen_symb(var.span);
+            joiners.push(Expr::build_equate(
+                vec![
+                    Expr::Binding {
+                        var: var.clone(),
+                        tuple_pos: None,
+                    },
+                    Expr::Binding {
+                        var: renamed.clone(),
+                        tuple_pos: None,
+                    },
+                ],
+                var.span,
+            ));
+            bindings.push(renamed);
+        }
+    }
+    (bindings, joiners)
+}
+
+/// The number of arguments of an application of a stored relation, which for scans of
+/// windows of validity include when the rows start and stop to be valid
+fn stored_scan_arity(store: &RelationHandle, rel_app: &MagicRelationApplyAtom) -> usize {
+    if rel_app.valid_until.is_some() {
+        store.arity() + 2
+    } else {
+        store.arity()
+    }
+}
+
+fn stored_scan(
+    bindings: Vec<Symbol>,
+    store: RelationHandle,
+    rel_app: &MagicRelationApplyAtom,
+) -> Result<RelAlgebra> {
+    match (rel_app.valid_at, rel_app.valid_until) {
+        (Some(valid_from), Some(valid_to)) => {
+            RelAlgebra::relation_in_window(bindings, store, rel_app.span, valid_from, valid_to)
+        }
+        (valid_at, _) => RelAlgebra::relation(bindings, store, rel_app.span, valid_at),
+    }
+}
+
+/// The filters that only refer to the arguments of the stored relation, keyed by
+/// [index_filter_key] after renaming the arguments to the columns they are bound to.
+fn filters_on_relation(
+    filters: &[Expr],
+    args: &[Symbol],
+    store: &RelationHandle,
+) -> BTreeSet<String> {
+    if store.index_predicates.is_empty() {
+        return Default::default();
+    }
+    let renames: BTreeMap<_, _> = args
+        .iter()
+        .zip(
+            store
+                .metadata
+                .keys
+                .iter()
+                .chain(store.metadata.non_keys.iter()),
+        )
+        .map(|(arg, col)| {
+            (
+                arg.clone(),
+                Symbol::new(col.name.clone(), Default::default()),
+            )
+        })
+        .collect();
+    filters
+        .iter()
+        .filter(|filter| filter.bindings().iter().all(|b| renames.contains_key(b)))
+        .map(|filter| {
+            let mut filter = filter.clone();
+            filter.rename_bindings(&renames);
+            index_filter_key(&filter)
+        })
+        .collect()
+}
@@ -0,0 +1,551 @@
+/*
+ * Copyright 2022, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use miette::Result;
+use smartstring::{LazyCompact, SmartString};
+
+use crate::data::expr::Expr;
+use crate::data::program::{MagicFixedRuleRuleArg, MagicSymbol};
+use crate::data::symb::{Symbol, PROG_ENTRY};
+use crate::data::tuple::{decode_tuple_from_key, Tuple, TupleT};
+use crate::data::value::DataValue;
+use crate::parse::SourceSpan;
+use crate::query::compile::{AggrKind, CompiledProgram, CompiledRuleSet};
+use crate::query::ra::{join_is_prefix, RelAlgebra};
+use crate::runtime::relation::{RelationHandle, RelationId};
+use crate::runtime::transact::SessionTx;
+use crate::{NamedRows, StoreTx};
+
+/// Fraction of rows assumed to pass a single filter.
+const FILTER_SELECTIVITY: f64 = 0.33;
+/// Fraction of rows assumed to survive a negation.
+const NEGATION_SELECTIVITY: f64 = 0.5;
+/// Rows assumed for inputs whose size cannot be known before execution.
+const UNKNOWN_ROWS: f64 = 1000.;
+/// Rows assumed for each element of a spread unification with a non-constant list.
+const UNKNOWN_SPREAD: f64 = 10.;
+/// Stored relations with more rows than this are not counted exhaustively.
+const STATS_SCAN_LIMIT: usize = 100_000;
+/// Assumed encoded size of a single value when no relation has been looked at.
+const DEFAULT_VALUE_BYTES: f64 = 16.;
+/// Seconds after which the statistics of a stored relation are gathered again.
+const STATS_MAX_AGE_SECS: f64 = 60.;
+
+#[derive(Clone, Debug)]
+struct RelationStats {
+    rows: usize,
+    bytes: usize,
+    arity: usize,
+    /// `prefix_distinct[i]` is the number of distinct key prefixes of length `i + 1`
+    prefix_distinct: Vec<usize>,
+    complete: bool,
+}
+
+/// The statistics of stored relations gathered by estimates, kept for later estimates so that
+/// the relations are not scanned every time. An entry is dropped when its relation is written
+/// by a query, and gathered again once older than [STATS_MAX_AGE_SECS] in any case, as the
+/// relation may have been written otherwise, e.g. by imports.
+#[derive(Default)]
+pub(crate) struct RelationStatsCache(Mutex<BTreeMap<SmartString<LazyCompact>, CachedStats>>);
+
+struct CachedStats {
+    /// a relation created again under the same name has another id
+    id: RelationId,
+    gathered_at: f64,
+    stats: RelationStats,
+}
+
+impl RelationStatsCache {
+    fn get(&self, handle: &RelationHandle, now: f64) -> Option<RelationStats> {
+        let cache = self.0.lock().unwrap();
+        let found = cache.get(&handle.name)?;
+        if found.id != handle.id || now - found.gathered_at > STATS_MAX_AGE_SECS {
+            return None;
+        }
+        Some(found.stats.clone())
+    }
+    fn put(&self, handle: &RelationHandle, now: f64, stats: RelationStats) {
+        let cached = CachedStats {
+            id: handle.id,
+            gathered_at: now,
+            stats,
+        };
+        self.0.lock().unwrap().insert(handle.name.clone(), cached);
+    }
+    pub(crate) fn invalidate(&self, name: &str) {
+        self.0.lock().unwrap().remove(name);
+    }
+}
+
+/// Estimated size of the result of a query, obtained without executing it.
+#[derive(Debug)]
+pub(crate) struct Estimate {
+    pub(crate) rows: f64,
+    pub(crate) bytes: f64,
+    pub(crate) secs: Option<f64>,
+    pub(crate) dominant: String,
+    pub(crate) confident: bool,
+    /// the number of rows of the largest stored relation that drives a rule,
+    /// used to scale up the results of sampled executions
+    pub(crate) driving_rows: Option<usize>,
+}
+
+impl Estimate {
+    pub(crate) fn into_named_rows(self) -> NamedRows {
+        NamedRows::new(
+            vec![
+                "estimated_rows".to_string(),
+                "estimated_bytes".to_string(),
+                "estimated_secs".to_string(),
+                "dominant_node".to_string(),
+                "confident".to_string(),
+            ],
+            vec![vec![
+                DataValue::from(self.rows.round() as i64),
+                DataValue::from(self.bytes.round() as i64),
+                match self.secs {
+                    None => DataValue::Null,
+                    Some(secs) => DataValue::from(secs),
+                },
+                DataValue::from(self.dominant),
+                DataValue::from(self.confident),
+            ]],
+        )
+    }
+}
+
+struct Estimator<'a, 'b> {
+    tx: &'a SessionTx<'b>,
+    cache: &'a RelationStatsCache,
+    now: f64,
+    stats: BTreeMap<SmartString<LazyCompact>, RelationStats>,
+    rule_rows: BTreeMap<MagicSymbol, f64>,
+    confident: bool,
+    dominant: Option<(f64, String)>,
+    driving_rows: Option<usize>,
+}
+
+impl<'a> SessionTx<'a> {
+    /// Estimates the number of rows returned by the compiled program, using statistics
+    /// of the stored relations involved, taken from `cache` if gathered recently,
+    /// and fixed selectivity heuristics. `now` is in seconds since the epoch.
+    pub(crate) fn estimate_compiled(
+        &self,
+        strata: &[CompiledProgram],
+        limit: Option<usize>,
+        cache: &RelationStatsCache,
+        now: f64,
+    ) -> Result<Estimate> {
+        let mut estimator = Estimator {
+            tx: self,
+            cache,
+            now,
+            stats: Default::default(),
+            rule_rows: Default::default(),
+            confident: true,
+            dominant: None,
+            driving_rows: None,
+        };
+        let mut entry_arity = 0;
+        for stratum in strata {
+            for (name, ruleset) in stratum {
+                let rows = estimator.ruleset_rows(name, ruleset)?;
+                estimator.rule_rows.insert(name.clone(), rows);
+                if name.is_prog_entry() {
+                    entry_arity = ruleset.arity();
+                }
+            }
+        }
+        let entry = MagicSymbol::Muggle {
+            inner: Symbol::new(PROG_ENTRY, SourceSpan(0, 0)),
+        };
+        let mut rows = estimator.rule_rows.get(&entry).cloned().unwrap_or(0.);
+        if let Some(limit) = limit {
+            rows = rows.min(limit as f64);
+        }
+        let bytes = rows * entry_arity as f64 * estimator.bytes_per_value();
+        Ok(Estimate {
+            rows,
+            bytes,
+            secs: None,
+            dominant: estimator
+                .dominant
+                .map(|(_, desc)| desc)
+                .unwrap_or_else(|| "none".to_string()),
+            confident: estimator.confident,
+            driving_rows: estimator.driving_rows,
+        })
+    }
+}
+
+impl<'a, 'b> Estimator<'a, 'b> {
+    fn bytes_per_value(&self) -> f64 {
+        let (bytes, values) = self.stats.values().fold((0, 0), |(b, v), s| {
+            (b + s.bytes, v + s.rows * s.arity.max(1))
+        });
+        if values == 0 {
+            DEFAULT_VALUE_BYTES
+        } else {
+            bytes as f64 / values as f64
+        }
+    }
+
+    fn relation_stats(&mut self, handle: &RelationHandle) -> Result<RelationStats> {
+        if let Some(found) = self.stats.get(&handle.name) {
+            return Ok(found.clone());
+        }
+        let stats = match self.cache.get(handle, self.now) {
+            Some(stats) => stats,
+            None => {
+                let stats = self.gather_stats(handle)?;
+                if !handle.is_temp {
+                    self.cache.put(handle, self.now, stats.clone());
+                }
+                stats
+            }
+        };
+        if !stats.complete {
+            self.confident = false;
+        }
+        self.stats.insert(handle.name.clone(), stats.clone());
+        Ok(stats)
+    }
+
+    fn gather_stats(&self, handle: &RelationHandle) -> Result<RelationStats> {
+        let n_keys = handle.metadata.keys.len();
+        let lower = Tuple::default().encode_as_key(handle.id);
+        let upper = Tuple::default().encode_as_key(handle.id.next());
+        let it = if handle.is_temp {
+            self.tx.temp_store_tx.range_scan(&lower, &upper)
+        } else {
+            self.tx.store_tx.range_scan(&lower, &upper)
+        };
+        let mut stats = RelationStats {
+            rows: 0,
+            bytes: 0,
+            arity: handle.arity(),
+            prefix_distinct: vec![0; n_keys],
+            complete: true,
+        };
+        let mut prev: Option<Tuple> = None;
+        for kv in it {
+            if stats.rows == STATS_SCAN_LIMIT {
+                stats.complete = false;
+                break;
+            }
+            let (k, v) = kv?;
+            stats.rows += 1;
+            stats.bytes += k.len() + v.len();
+            let key = decode_tuple_from_key(&k)?;
+            let first_diff = match &prev {
+                None => 0,
+                Some(prev) => prev
+                    .iter()
+                    .zip(key.iter())
+                    .position(|(a, b)| a != b)
+                    .unwrap_or(n_keys),
+            };
+            for distinct in stats.prefix_distinct.iter_mut().skip(first_diff) {
+                *distinct += 1;
+            }
+            prev = Some(key);
+        }
+        Ok(stats)
+    }
+
+    fn record(&mut self, rows: f64, desc: impl FnOnce() -> String) {
+        let larger = match &self.dominant {
+            None => true,
+            Some((max, _)) => rows > *max,
+        };
+        if larger {
+            self.dominant = Some((rows, desc()));
+        }
+    }
+
+    fn ruleset_rows(&mut self, name: &MagicSymbol, ruleset: &CompiledRuleSet) -> Result<f64> {
+        match ruleset {
+            CompiledRuleSet::Rules(rules) => {
+                let mut total = 0.;
+                for (i, rule) in rules.iter().enumerate() {
+                    let rule_desc = format!("{}[{}]", name.symbol(), i);
+                    let mut rows = self.rel_rows(&rule.relation, &rule_desc, true)?;
+                    if ruleset.aggr_kind() != AggrKind::None
+                        && rule.aggr.iter().all(|aggr| aggr.is_some())
+                    {
+                        rows = rows.min(1.);
+                    }
+                    total += rows;
+                }
+                Ok(total)
+            }
+            CompiledRuleSet::Fixed(fixed) => {
+                if &*fixed.fixed_handle.name == "Constant" {
+                    if let Some(Expr::Const {
+                        val: DataValue::List(data),
+                        ..
+                    }) = fixed.options.get("data")
+                    {
+                        return Ok(data.len() as f64);
+                    }
+                }
+                self.confident = false;
+                let mut rows = 0.;
+                for arg in fixed.rule_args.iter() {
+                    rows += match arg {
+                        MagicFixedRuleRuleArg::InMem { name, .. } => {
+                            self.rule_rows.get(name).cloned().unwrap_or(UNKNOWN_ROWS)
+                        }
+                        MagicFixedRuleRuleArg::Stored { name, .. } => {
+                            let handle = self.tx.get_relation(name, false)?;
+                            self.relation_stats(&handle)?.rows as f64
+                        }
+                    };
+                }
+                let rows = if fixed.rule_args.is_empty() {
+                    UNKNOWN_ROWS
+                } else {
+                    rows
+                };
+                self.record(rows, || {
+                    format!("{}: {}", name.symbol(), fixed.fixed_handle.name)
+                });
+                Ok(rows)
+            }
+        }
+    }
+
+    fn rel_rows(&mut self, rel: &RelAlgebra, rule: &str, driving: bool) -> Result<f64> {
+        Ok(match rel {
+            RelAlgebra::Fixed(f) => f.data.len() as f64,
+            RelAlgebra::TempStore(r) => {
+                let rows = match self.rule_rows.get(&r.storage_key) {
+                    Some(rows) => *rows,
+                    None => {
+                        // recursive rule, size only known after evaluation
+                        self.confident = false;
+                        UNKNOWN_ROWS
+                    }
+                };
+                rows * FILTER_SELECTIVITY.powi(r.filters.len() as i32)
+            }
+            RelAlgebra::Stored(s) => {
+                let stats = self.relation_stats(&s.storage)?;
+                if driving {
+                    self.driving_rows = Some(self.driving_rows.unwrap_or(0).max(stats.rows));
+                }
+                let rows = stats.rows as f64 * FILTER_SELECTIVITY.powi(s.filters.len() as i32);
+                self.record(rows, || format!("{rule}: load_stored :{}", s.storage.name));
+                rows
+            }
+            RelAlgebra::StoredWithValidity(s) => {
+                let stats = self.relation_stats(&s.storage)?;
+                let rows = stats.rows as f64 * FILTER_SELECTIVITY.powi(s.filters.len() as i32);
+                self.record(rows, || {
+                    format!("{rule}: load_stored_with_validity :{}", s.storage.name)
+                });
+                rows
+            }
+            RelAlgebra::Join(j) => {
+                if j.left.is_unit() {
+                    return self.rel_rows(&j.right, rule, driving);
+                }
+                let left = self.rel_rows(&j.left, rule, driving)?;
+                let (_, right_indices) = j
+                    .joiner
+                    .join_indices(
+                        &j.left.bindings_after_eliminate(),
+                        &j.right.bindings_after_eliminate(),
+                    )
+                    .unwrap();
+                let per_left = self.matches_per_key(&j.right, &right_indices, rule)?;
+                let rows = left * per_left;
+                self.record(rows, || format!("{rule}: {}", j.join_type()));
+                rows
+            }
+            RelAlgebra::NegJoin(j) => self.rel_rows(&j.left, rule, driving)? * NEGATION_SELECTIVITY,
+            RelAlgebra::Reorder(r) => self.rel_rows(&r.relation, rule, driving)?,
+            RelAlgebra::Filter(f) => {
+                self.rel_rows(&f.parent, rule, driving)?
+                    * FILTER_SELECTIVITY.powi(f.filters.len() as i32)
+            }
+            RelAlgebra::Unification(u) => {
+                let parent = self.rel_rows(&u.parent, rule, driving)?;
+                if u.is_multi {
+                    match &u.expr {
+                        Expr::Const {
+                            val: DataValue::List(l),
+                            ..
+                        } => parent * l.len() as f64,
+                        _ => {
+                            self.confident = false;
+                            parent * UNKNOWN_SPREAD
+                        }
+                    }
+                } else {
+                    parent
+                }
+            }
+            RelAlgebra::HnswSearch(s) => {
+                let rows = self.rel_rows(&s.parent, rule, driving)? * s.k as f64;
+                self.record(rows, || format!("{rule}: hnsw_search ~{}", s.idx.name));
+                rows
+            }
+            RelAlgebra::FtsSearch(s) => {
+                let rows = self.rel_rows(&s.parent, rule, driving)? * s.k as f64;
+                self.record(rows, || format!("{rule}: fts_search ~{}", s.idx.name));
+                rows
+            }
+            RelAlgebra::LshSearch(s) => {
+                // near duplicates are expected to be few
+                self.confident = false;
+                let rows = self.rel_rows(&s.parent, rule, driving)?;
+                self.record(rows, || format!("{rule}: lsh_search ~{}", s.idx.name));
+                rows
+            }
+        })
+    }
+
+    /// The number of rows of `rel` expected to match a single row joined on `indices`.
+    fn matches_per_key(&mut self, rel: &RelAlgebra, indices: &[usize], rule: &str) -> Result<f64> {
+        let rows = self.rel_rows(rel, rule, false)?;
+        if indices.is_empty() {
+            return Ok(rows);
+        }
+        if let RelAlgebra::Stored(s) = rel {
+            if join_is_prefix(indices) {
+                let stats = self.relation_stats(&s.storage)?;
+                let k = indices.len();
+                return Ok(if k >= stats.prefix_distinct.len() {
+                    rows.min(1.)
+                } else {
+                    rows / stats.prefix_distinct[k - 1].max(1) as f64
+                });
+            }
+        }
+        let arity = rel.bindings_after_eliminate().len().max(1) as f64;
+        let k = indices.len() as f64;
+        Ok(rows.powf(1. - (k / arity).min(1.)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use itertools::Itertools;
+
+    use crate::data::value::DataValue;
+    use crate::new_cozo_mem;
+
+    #[test]
+    fn test_estimate() {
+        let db = new_cozo_mem().unwrap();
+        let create = |name: &str, cols: &str, rows: Vec<String>| {
+            db.run_script(
+                &format!(
+                    "?[{cols}] <- [{}] :create {name} {{{cols}}}",
+                    rows.join(", ")
+                ),
+                Default::default(),
+            )
+            .unwrap();
+        };
+        create("a", "k", (0..100).map(|k| format!("[{k}]")).collect());
+        create(
+            "b",
+            "k, i",
+            (0..100)
+                .cartesian_product(0..10)
+                .map(|(k, i)| format!("[{k}, {i}]"))
+                .collect(),
+        );
+        // only every tenth key of `a` has matches in `c`, but those have many
+        create(
+            "c",
+            "k, i",
+            (0..10)
+                .cartesian_product(0..100)
+                .map(|(j, i)| format!("[{}, {i}]", j * 10))
+                .collect(),
+        );
+
+        let estimate = |script: &str| -> (f64, BTreeMap<String, DataValue>) {
+            let res = db.run_script(script, Default::default()).unwrap();
+            let row: BTreeMap<_, _> = res
+                .headers
+                .iter()
+                .cloned()
+                .zip(res.rows[0].iter().cloned())
+                .collect();
+            (row["estimated_rows"].get_float().unwrap(), row)
+        };
+        let off_by = |est: f64, actual: f64| (est / actual).max(actual / est);
+
+        let uniform = "?[x, i] := *a{k: x}, *b{k: x, i}";
+        let actual = db
+            .run_script(uniform, Default::default())
+            .unwrap()
+            .rows
+            .len() as f64;
+        assert_eq!(actual, 1000.);
+        let (est, row) = estimate(&format!("::estimate {{ {uniform} }}"));
+        assert!(off_by(est, actual) < 10., "{est}");
+        assert!(row["estimated_bytes"].get_int().unwrap() > 0);
+        assert_eq!(row["estimated_secs"], DataValue::Null);
+        assert_eq!(row["confident"], DataValue::from(true));
+
+        let (est, _) = estimate(&format!("::estimate {{ {uniform} :limit 10 }}"));
+        assert_eq!(est, 10.);
+
+        let skewed = "?[x, i] := *a{k: x}, *c{k: x, i}";
+        let actual = db
+            .run_script(skewed, Default::default())
+            .unwrap()
+            .rows
+            .len() as f64;
+        assert_eq!(actual, 1000.);
+        let (heuristic, row) = estimate(&format!("::estimate {{ {skewed} }}"));
+        assert!(row["dominant_node"].get_str().unwrap().contains("join"));
+        let (sampled, row) = estimate(&format!("::estimate {{ {skewed} }} sample 50"));
+        assert!(row["estimated_secs"].get_float().is_some());
+        assert!(off_by(sampled, actual) < off_by(heuristic, actual));
+
+        // only the scan driving the rule is sampled, not the other side of the join
+        create(
+            "d",
+            "w, v",
+            (0..1000).map(|w| format!("[{w}, {}]", 999 - w)).collect(),
+        );
+        let (sampled, _) = estimate("::estimate { ?[x, w] := *a{k: x}, *d{w, v: x} } sample 10");
+        assert_eq!(sampled, 100.);
+
+        // the statistics kept are dropped when the relation is written
+        let (before, _) = estimate("::estimate { ?[k] := *a{k} }");
+        db.run_script(
+            "?[k] := k in int_range(100, 200) :put a {k}",
+            Default::default(),
+        )
+        .unwrap();
+        let (after, _) = estimate("::estimate { ?[k] := *a{k} }");
+        assert_eq!((before, after), (100., 200.));
+
+        assert!(db
+            .run_script(
+                "::estimate { ?[k] <- [[1000]] :put a {k} }",
+                Default::default()
+            )
+            .is_err());
+        assert!(db
+            .run_script("::estimate { ?[k] := *a{k} } sample 0", Default::default())
+            .is_err());
+    }
+}
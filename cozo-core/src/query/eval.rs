@@ -17,6 +17,7 @@ use miette::Result;
 use rayon::prelude::*;
 
 use crate::data::aggr::Aggregation;
+use crate::data::expr::TryWarnings;
 use crate::data::program::{MagicSymbol, NoEntryError};
 use crate::data::symb::{Symbol, PROG_ENTRY};
 use crate::data::tuple::Tuple;
@@ -167,13 +168,14 @@ impl<'a> SessionTx<'a> {
                         },
                         CompiledRuleSet::Fixed(fixed) => {
                             let fixed_impl = fixed.fixed_impl.as_ref();
-                            let mut out = RegularTempStore::default();
+                            let mut out = self.rule_out_store(k);
                             let payload = FixedRulePayload {
                                 manifest: &fixed,
                                 stores: borrowed_stores,
                                 tx: self,
                             };
                             fixed_impl.run(payload, &mut out, poison.clone())?;
+                            out.check_sink()?;
                             out.wrap()
                         }
                     };
@@ -194,10 +196,16 @@ impl<'a> SessionTx<'a> {
                         }
                     }
 
+                    // the warnings raised on the threads of the pool go to the same query
+                    let try_warnings = TryWarnings::current();
                     let execs = prog
                         .par_iter()
                         .filter(|(symb, _)| !(limiter_enabled && symb.is_prog_entry()))
-                        .map(execution);
+                        .map(|rs| {
+                            TryWarnings::scoped(try_warnings.as_ref(), || {
+                                self.clock.scoped(|| execution(rs))
+                            })
+                        });
 
                     for res in execs.collect::<Vec<_>>() {
                         let (k, new_store) = res?;
@@ -268,10 +276,16 @@ impl<'a> SessionTx<'a> {
                         }
                     }
 
+                    // the warnings raised on the threads of the pool go to the same query
+                    let try_warnings = TryWarnings::current();
                     let execs = prog
                         .par_iter()
                         .filter(|(symb, _)| !(limiter_enabled && symb.is_prog_entry()))
-                        .map(execution);
+                        .map(|rs| {
+                            TryWarnings::scoped(try_warnings.as_ref(), || {
+                                self.clock.scoped(|| execution(rs))
+                            })
+                        });
                     for res in execs.collect::<Vec<_>>() {
                         let (k, new_store) = res?;
                         to_merge.insert(k, new_store);
@@ -298,6 +312,14 @@ impl<'a> SessionTx<'a> {
         }
         Ok(used_limiter.load(Ordering::Acquire))
     }
+    /// The store receiving the rows derived for a rule, which passes them on to the entry sink
+    /// if the rule is the entry and the sink is set
+    fn rule_out_store(&self, rule_symb: &MagicSymbol) -> RegularTempStore {
+        match self.entry_sink.lock().unwrap().as_ref() {
+            Some(sink) if rule_symb.is_prog_entry() => RegularTempStore::streaming(sink.clone()),
+            _ => RegularTempStore::default(),
+        }
+    }
     /// returns true is early return is activated
     fn initial_rule_non_aggr_eval(
         &self,
@@ -307,7 +329,7 @@ impl<'a> SessionTx<'a> {
         limiter: &QueryLimiter,
         poison: Poison,
     ) -> Result<(bool, RegularTempStore)> {
-        let mut out_store = RegularTempStore::default();
+        let mut out_store = self.rule_out_store(rule_symb);
         let should_check_limit = limiter.total.is_some() && rule_symb.is_prog_entry();
 
         for (rule_n, rule) in ruleset.iter().enumerate() {
@@ -333,6 +355,7 @@ impl<'a> SessionTx<'a> {
             }
             poison.check()?;
         }
+        out_store.check_sink()?;
 
         Ok((should_check_limit, out_store))
     }
@@ -387,7 +410,7 @@ impl<'a> SessionTx<'a> {
         let should_check_limit = limiter.total.is_some() && rule_symb.is_prog_entry();
         let mut aggr_work: BTreeMap<Vec<DataValue>, Vec<Aggregation>> = BTreeMap::new();
 
-        for (rule_n, rule) in ruleset.iter().enumerate() {
+        'rules: for (rule_n, rule) in ruleset.iter().enumerate() {
             debug!(
                 "Calculation for normal aggr rule {:?}.{}",
                 rule_symb, rule_n
@@ -439,6 +462,19 @@ impl<'a> SessionTx<'a> {
                         ent.insert(aggr_ops);
                     }
                 }
+
+                // without grouping keys, there is a single group, and once every aggregation
+                // in it is determined, the rest of the input need not be consumed
+                if keys_indices.is_empty()
+                    && aggr_work.values().all(|aggr_ops| {
+                        aggr_ops
+                            .iter()
+                            .all(|op| op.normal_op.as_ref().unwrap().is_determined())
+                    })
+                {
+                    debug!("short-circuiting aggregation for rule {:?}", rule_symb);
+                    break 'rules;
+                }
             }
             poison.check()?;
         }
@@ -511,7 +547,7 @@ impl<'a> SessionTx<'a> {
         poison: Poison,
     ) -> Result<(bool, RegularTempStore)> {
         let prev_store = stores.get(rule_symb).unwrap();
-        let mut out_store = RegularTempStore::default();
+        let mut out_store = self.rule_out_store(rule_symb);
         let should_check_limit = limiter.total.is_some() && rule_symb.is_prog_entry();
         for (rule_n, rule) in ruleset.iter().enumerate() {
             let dependencies_changed = rule
@@ -564,6 +600,7 @@ impl<'a> SessionTx<'a> {
                 poison.check()?;
             }
         }
+        out_store.check_sink()?;
         Ok((should_check_limit, out_store))
     }
     fn incremental_rule_meet_eval(
@@ -6,21 +6,27 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 
 use itertools::Itertools;
 use miette::{bail, ensure, Diagnostic, Result};
+use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
 use crate::data::expr::Expr;
 use crate::data::program::{
-    InputAtom, InputNamedFieldRelationApplyAtom, InputRelationApplyAtom, InputRuleApplyAtom,
-    NormalFormAtom, NormalFormRelationApplyAtom, NormalFormRuleApplyAtom, TempSymbGen, Unification,
+    FtsSearch, HnswSearch, InputAtom, InputNamedFieldRelationApplyAtom, InputRelationApplyAtom,
+    InputRuleApplyAtom, InputSearchAtom, LshSearch, NormalFormAtom, NormalFormRelationApplyAtom,
+    NormalFormRuleApplyAtom, TempSymbGen, Unification,
 };
+use crate::data::symb::Symbol;
 use crate::parse::SourceSpan;
 use crate::query::reorder::UnsafeNegation;
 use crate::runtime::transact::SessionTx;
 
+/// The columns bound after those of the relation by scans of a window of validity
+const WINDOW_FIELDS: [&str; 2] = ["valid_from", "valid_to"];
+
 #[derive(Debug)]
 pub(crate) struct Disjunction {
     pub(crate) inner: Vec<Conjunction>,
@@ -63,7 +69,8 @@ impl InputAtom {
             a @ (InputAtom::Rule { .. }
             | InputAtom::NamedFieldRelation { .. }
             | InputAtom::Predicate { .. }
-            | InputAtom::Relation { .. }) => a,
+            | InputAtom::Relation { .. }
+            | InputAtom::Search { .. }) => a,
             InputAtom::Conjunction { inner: args, span } => InputAtom::Conjunction {
                 inner: args
                     .into_iter()
@@ -121,6 +128,9 @@ impl InputAtom {
                 InputAtom::Unification { inner } => {
                     bail!(UnsafeNegation(inner.span))
                 }
+                InputAtom::Search { inner } => {
+                    bail!(UnsafeNegation(inner.span))
+                }
             },
         })
     }
@@ -136,6 +146,7 @@ impl InputAtom {
             name,
             mut args,
             valid_at,
+            valid_until,
             span,
         }: InputNamedFieldRelationApplyAtom,
         gen: &mut TempSymbGen,
@@ -149,9 +160,15 @@ impl InputAtom {
             .chain(stored.metadata.non_keys.iter())
             .map(|col| &col.name)
             .collect();
+        // scans of a window also bind when the versions start and stop to be valid
+        let window_fields: &[&str] = if valid_until.is_some() {
+            &WINDOW_FIELDS
+        } else {
+            &[]
+        };
         for k in args.keys() {
             ensure!(
-                fields.contains(k),
+                fields.contains(k) || window_fields.contains(&k.as_str()),
                 NamedFieldNotFound(name.to_string(), k.to_string(), span)
             );
         }
@@ -168,14 +185,108 @@ impl InputAtom {
             });
             new_args.push(arg)
         }
+        for field in window_fields {
+            let arg = args.remove(*field).unwrap_or_else(|| Expr::Binding {
+                var: gen.next_ignored(span),
+                tuple_pos: None,
+            });
+            new_args.push(arg)
+        }
         Ok(InputRelationApplyAtom {
             name,
             args: new_args,
             span,
             valid_at,
+            valid_until,
         })
     }
 
+    fn convert_search(
+        InputSearchAtom {
+            relation,
+            index,
+            bindings,
+            parameters,
+            span,
+        }: InputSearchAtom,
+        gen: &mut TempSymbGen,
+        tx: &SessionTx<'_>,
+    ) -> Result<Disjunction> {
+        let stored = tx.get_relation(&relation, false)?;
+        let is_hnsw = stored.hnsw_indices.contains_key(&index.name);
+        let is_fts = stored.fts_indices.contains_key(&index.name);
+        ensure!(
+            is_hnsw || is_fts || stored.lsh_indices.contains_key(&index.name),
+            SearchIndexNotFound(relation.to_string(), index.to_string(), index.span)
+        );
+        let r = Self::convert_named_field_relation(
+            InputNamedFieldRelationApplyAtom {
+                name: relation.clone(),
+                args: bindings,
+                valid_at: None,
+                valid_until: None,
+                span,
+            },
+            gen,
+            tx,
+        )?;
+        // the search takes the place of the relation application, keeping the
+        // unifications generated for the bound columns
+        let mut ret = r.normalize(false, gen);
+        let conj = &mut ret.inner[0].0;
+        let args = match conj.pop() {
+            Some(NormalFormAtom::Relation(r)) => r.args,
+            _ => unreachable!(),
+        };
+        let mut parameters = SearchParameters { parameters, span };
+        conj.push(if is_hnsw {
+            let query = parameters.required_expr("query")?;
+            let k = parameters.count("k")?;
+            let ef = parameters.optional_count("ef")?;
+            let bind_distance = parameters.binding("bind_distance")?;
+            parameters.finish("'query', 'k', 'ef' and 'bind_distance'")?;
+            NormalFormAtom::HnswSearch(HnswSearch {
+                relation,
+                index,
+                args,
+                query,
+                k,
+                ef,
+                bind_distance,
+                span,
+            })
+        } else if is_fts {
+            let query = parameters.required_expr("query")?;
+            let k = parameters.count("k")?;
+            let bind_score = parameters.binding("bind_score")?;
+            parameters.finish("'query', 'k' and 'bind_score'")?;
+            NormalFormAtom::FtsSearch(FtsSearch {
+                relation,
+                index,
+                args,
+                query,
+                k,
+                bind_score,
+                span,
+            })
+        } else {
+            let query = parameters.required_expr("query")?;
+            let min_similarity = parameters.optional_fraction("min_similarity")?;
+            let bind_similarity = parameters.binding("bind_similarity")?;
+            parameters.finish("'query', 'min_similarity' and 'bind_similarity'")?;
+            NormalFormAtom::LshSearch(LshSearch {
+                relation,
+                index,
+                args,
+                query,
+                min_similarity,
+                bind_similarity,
+                span,
+            })
+        });
+        Ok(ret)
+    }
+
     fn do_disjunctive_normal_form(
         self,
         gen: &mut TempSymbGen,
@@ -225,6 +336,7 @@ impl InputAtom {
             InputAtom::Unification { inner: u } => {
                 Disjunction::singlet(NormalFormAtom::Unification(u))
             }
+            InputAtom::Search { inner } => Self::convert_search(inner, gen, tx)?,
         })
     }
 }
@@ -337,6 +449,7 @@ impl InputRelationApplyAtom {
                 name: self.name,
                 args,
                 valid_at: self.valid_at,
+                valid_until: self.valid_until,
                 span: self.span,
             })
         } else {
@@ -344,6 +457,7 @@ impl InputRelationApplyAtom {
                 name: self.name,
                 args,
                 valid_at: self.valid_at,
+                valid_until: self.valid_until,
                 span: self.span,
             })
         });
@@ -359,3 +473,97 @@ pub(crate) struct NamedFieldNotFound(
     pub(crate) String,
     #[label] pub(crate) SourceSpan,
 );
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Index {1} for relation {0} not found")]
+#[diagnostic(code(query::search_index_not_found))]
+#[diagnostic(help("Searches are made on HNSW, full-text and LSH indices"))]
+struct SearchIndexNotFound(String, String, #[label] SourceSpan);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Bad parameter '{0}' for the search")]
+#[diagnostic(code(parser::bad_search_parameter))]
+#[diagnostic(help("{1}"))]
+struct BadSearchParameter(String, String, #[label] SourceSpan);
+
+/// The parameters of a search, taken out one by one as they are checked
+struct SearchParameters {
+    parameters: BTreeMap<SmartString<LazyCompact>, Expr>,
+    span: SourceSpan,
+}
+
+impl SearchParameters {
+    fn required_expr(&mut self, name: &str) -> Result<Expr> {
+        match self.parameters.remove(name) {
+            Some(expr) => Ok(expr),
+            None => bail!(BadSearchParameter(
+                name.to_string(),
+                "The parameter is required".to_string(),
+                self.span
+            )),
+        }
+    }
+    fn optional_count(&mut self, name: &str) -> Result<Option<usize>> {
+        let expr = match self.parameters.remove(name) {
+            None => return Ok(None),
+            Some(expr) => expr,
+        };
+        let span = expr.span();
+        match expr.eval_to_const()?.get_int() {
+            Some(i) if i > 0 => Ok(Some(i as usize)),
+            _ => bail!(BadSearchParameter(
+                name.to_string(),
+                "A positive integer is required".to_string(),
+                span
+            )),
+        }
+    }
+    fn optional_fraction(&mut self, name: &str) -> Result<Option<f64>> {
+        let expr = match self.parameters.remove(name) {
+            None => return Ok(None),
+            Some(expr) => expr,
+        };
+        let span = expr.span();
+        match expr.eval_to_const()?.get_float() {
+            Some(f) if (0. ..=1.).contains(&f) => Ok(Some(f)),
+            _ => bail!(BadSearchParameter(
+                name.to_string(),
+                "A number between 0 and 1 is required".to_string(),
+                span
+            )),
+        }
+    }
+    fn count(&mut self, name: &str) -> Result<usize> {
+        let span = self.span;
+        self.optional_count(name)?.ok_or_else(|| {
+            BadSearchParameter(
+                name.to_string(),
+                "The parameter is required".to_string(),
+                span,
+            )
+            .into()
+        })
+    }
+    fn binding(&mut self, name: &str) -> Result<Option<Symbol>> {
+        match self.parameters.remove(name) {
+            None => Ok(None),
+            Some(Expr::Binding { var, .. }) => Ok(Some(var)),
+            Some(expr) => bail!(BadSearchParameter(
+                name.to_string(),
+                "A variable is required".to_string(),
+                expr.span()
+            )),
+        }
+    }
+    /// Fails if any parameter is left, not being known to the index
+    fn finish(self, known: &str) -> Result<()> {
+        if let Some((name, expr)) = self.parameters.into_iter().next() {
+            bail!(BadSearchParameter(
+                name.to_string(),
+                format!("The parameters of the search are {known}"),
+                expr.span()
+            ))
+        }
+        Ok(())
+    }
+}
@@ -14,6 +14,7 @@ use miette::{bail, ensure, Result};
 use smallvec::SmallVec;
 use smartstring::SmartString;
 
+use crate::data::expr::Expr;
 use crate::data::program::{
     FixedRuleArg, MagicAtom, MagicFixedRuleApply, MagicFixedRuleRuleArg, MagicInlineRule,
     MagicProgram, MagicRelationApplyAtom, MagicRuleApplyAtom, MagicRulesOrFixed, MagicSymbol,
@@ -172,6 +173,18 @@ fn magic_rewrite_ruleset(
                     seen_bindings.insert(u.binding.clone());
                     collected_atoms.push(MagicAtom::Unification(u));
                 }
+                MagicAtom::HnswSearch(s) => {
+                    seen_bindings.extend(s.all_bindings().cloned());
+                    collected_atoms.push(MagicAtom::HnswSearch(s));
+                }
+                MagicAtom::FtsSearch(s) => {
+                    seen_bindings.extend(s.all_bindings().cloned());
+                    collected_atoms.push(MagicAtom::FtsSearch(s));
+                }
+                MagicAtom::LshSearch(s) => {
+                    seen_bindings.extend(s.all_bindings().cloned());
+                    collected_atoms.push(MagicAtom::LshSearch(s));
+                }
                 MagicAtom::Rule(r_app) => {
                     if r_app.name.has_bound_adornment() {
                         // we are guaranteed to have a magic rule application
@@ -333,6 +346,7 @@ impl NormalFormProgram {
                                             FixedRuleArg::Stored {
                                                 name,
                                                 bindings,
+                                                filters,
                                                 span,
                                                 valid_at,
                                             } => {
@@ -360,6 +374,9 @@ impl NormalFormProgram {
                                                 MagicFixedRuleRuleArg::Stored {
                                                     name: name.clone(),
                                                     bindings: bindings.clone(),
+                                                    filters: fill_filter_indices(
+                                                        filters, bindings,
+                                                    )?,
                                                     valid_at: *valid_at,
                                                     span: *span,
                                                 }
@@ -367,6 +384,7 @@ impl NormalFormProgram {
                                             FixedRuleArg::NamedStored {
                                                 name,
                                                 bindings,
+                                                filters,
                                                 valid_at,
                                                 span,
                                             } => {
@@ -423,6 +441,10 @@ impl NormalFormProgram {
                                                     .collect_vec();
                                                 MagicFixedRuleRuleArg::Stored {
                                                     name: name.clone(),
+                                                    filters: fill_filter_indices(
+                                                        filters,
+                                                        &new_bindings,
+                                                    )?,
                                                     bindings: new_bindings,
                                                     valid_at: *valid_at,
                                                     span: *span,
@@ -493,6 +515,22 @@ impl NormalFormProgram {
     }
 }
 
+fn fill_filter_indices(filters: &[Expr], bindings: &[Symbol]) -> Result<Vec<Expr>> {
+    let binding_map = bindings
+        .iter()
+        .enumerate()
+        .map(|(i, symb)| (symb.clone(), i))
+        .collect();
+    filters
+        .iter()
+        .map(|filter| -> Result<Expr> {
+            let mut filter = filter.clone();
+            filter.fill_binding_indices(&binding_map)?;
+            Ok(filter)
+        })
+        .try_collect()
+}
+
 impl NormalFormAtom {
     fn adorn(
         &self,
@@ -506,6 +544,7 @@ impl NormalFormAtom {
                     name: v.name.clone(),
                     args: v.args.clone(),
                     valid_at: v.valid_at,
+                    valid_until: v.valid_until,
                     span: v.span,
                 };
                 for arg in v.args.iter() {
@@ -561,6 +600,7 @@ impl NormalFormAtom {
                     name: nv.name.clone(),
                     args: nv.args.clone(),
                     valid_at: nv.valid_at,
+                    valid_until: nv.valid_until,
                     span: nv.span,
                 })
             }
@@ -568,6 +608,18 @@ impl NormalFormAtom {
                 seen_bindings.insert(u.binding.clone());
                 MagicAtom::Unification(u.clone())
             }
+            NormalFormAtom::HnswSearch(s) => {
+                seen_bindings.extend(s.all_bindings().cloned());
+                MagicAtom::HnswSearch(s.clone())
+            }
+            NormalFormAtom::FtsSearch(s) => {
+                seen_bindings.extend(s.all_bindings().cloned());
+                MagicAtom::FtsSearch(s.clone())
+            }
+            NormalFormAtom::LshSearch(s) => {
+                seen_bindings.extend(s.all_bindings().cloned());
+                MagicAtom::LshSearch(s.clone())
+            }
         }
     }
 }
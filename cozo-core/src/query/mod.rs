@@ -7,6 +7,7 @@
  */
 
 pub(crate) mod compile;
+pub(crate) mod estimate;
 pub(crate) mod eval;
 pub(crate) mod graph;
 pub(crate) mod logical;
@@ -16,3 +17,4 @@ pub(crate) mod reorder;
 pub(crate) mod sort;
 pub(crate) mod stored;
 pub(crate) mod stratify;
+pub(crate) mod window;
@@ -6,28 +6,44 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt::{Debug, Formatter};
 use std::iter;
+#[cfg(test)]
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
 
-use either::{Left, Right};
+use either::{Either, Left, Right};
 use itertools::Itertools;
-use log::{debug, error};
+use log::debug;
 use miette::{bail, Diagnostic, Result};
+use serde_json::json;
+use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
-use crate::data::expr::{compute_bounds, eval_bytecode, eval_bytecode_pred, Bytecode, Expr};
+use crate::data::expr::{
+    bind_params_in_bytecodes, compute_bounds, eval_bytecode, eval_bytecode_pred, Bytecode, Expr,
+};
+use crate::data::json::JsonValue;
 use crate::data::program::MagicSymbol;
 use crate::data::relation::{ColType, NullableColType};
 use crate::data::symb::Symbol;
 use crate::data::tuple::{Tuple, TupleIter};
 use crate::data::value::{DataValue, ValidityTs};
 use crate::parse::SourceSpan;
+use crate::runtime::fts::{fts_query, FtsIndexManifest};
+use crate::runtime::hnsw::{hnsw_query_vector, HnswIndexManifest};
+use crate::runtime::lsh::{lsh_query_signature, LshIndexManifest};
 use crate::runtime::relation::RelationHandle;
 use crate::runtime::temp_store::EpochStore;
 use crate::runtime::transact::SessionTx;
 use crate::utils::swap_option_result;
 
+/// Joins whose right side has more rows than this do not use a hash table by default,
+/// see [crate::Db::set_hash_join_max_rows]
+pub(crate) const DEFAULT_HASH_JOIN_MAX_ROWS: usize = 1_000_000;
+
+#[derive(serde_derive::Serialize, serde_derive::Deserialize)]
 pub(crate) enum RelAlgebra {
     Fixed(InlineFixedRA),
     TempStore(TempStoreRA),
@@ -38,6 +54,9 @@ pub(crate) enum RelAlgebra {
     Reorder(ReorderRA),
     Filter(FilteredRA),
     Unification(UnificationRA),
+    HnswSearch(HnswSearchRA),
+    FtsSearch(FtsSearchRA),
+    LshSearch(LshSearchRA),
 }
 
 impl RelAlgebra {
@@ -52,10 +71,198 @@ impl RelAlgebra {
             RelAlgebra::Filter(i) => i.span,
             RelAlgebra::Unification(i) => i.span,
             RelAlgebra::StoredWithValidity(i) => i.span,
+            RelAlgebra::HnswSearch(i) => i.span,
+            RelAlgebra::FtsSearch(i) => i.span,
+            RelAlgebra::LshSearch(i) => i.span,
+        }
+    }
+    /// The plan as nested JSON objects, for tools displaying it, see [crate::Db::explain_script].
+    /// Every node has a `kind`, its output `bindings` and its `span` in the script,
+    /// `[offset, length]`, and nodes with inputs have them under `input`, or `left` and `right`.
+    pub(crate) fn to_json(&self) -> JsonValue {
+        fn names(bindings: &[Symbol]) -> Vec<String> {
+            bindings.iter().map(|b| b.name.to_string()).collect()
+        }
+        fn texts(exprs: &[Expr]) -> Vec<String> {
+            exprs.iter().map(|e| e.to_string()).collect()
+        }
+        let bindings = names(&self.bindings_after_eliminate());
+        let SourceSpan(offset, length) = self.span();
+        let span = json!([offset, length]);
+        match self {
+            RelAlgebra::Fixed(r) => json!({
+                "kind": if self.is_unit() { "unit" } else { "fixed" },
+                "bindings": bindings,
+                "rows": r.data.len(),
+                "span": span,
+            }),
+            RelAlgebra::TempStore(r) => json!({
+                "kind": "temp_store",
+                "bindings": bindings,
+                "relation": r.storage_key.to_string(),
+                "filters": texts(&r.filters),
+                "span": span,
+            }),
+            RelAlgebra::Stored(r) => json!({
+                "kind": "stored",
+                "bindings": bindings,
+                "relation": r.storage.name.to_string(),
+                "filters": texts(&r.filters),
+                "shared_scan": r.shared.is_some(),
+                "span": span,
+            }),
+            RelAlgebra::StoredWithValidity(r) => json!({
+                "kind": "stored_with_validity",
+                "bindings": bindings,
+                "relation": r.storage.name.to_string(),
+                "filters": texts(&r.filters),
+                "valid_at": r.valid_at.0 .0,
+                "valid_until": r.valid_until.map(|vld| vld.0 .0),
+                "span": span,
+            }),
+            RelAlgebra::Join(r) => {
+                if r.left.is_unit() {
+                    return r.right.to_json();
+                }
+                json!({
+                    "kind": "join",
+                    "bindings": bindings,
+                    "join_type": r.join_type(),
+                    "join_keys": r.joiner.key_pairs(),
+                    "left": r.left.to_json(),
+                    "right": r.right.to_json(),
+                    "span": span,
+                })
+            }
+            RelAlgebra::NegJoin(r) => json!({
+                "kind": "neg_join",
+                "bindings": bindings,
+                "join_type": r.join_type(),
+                "join_keys": r.joiner.key_pairs(),
+                "left": r.left.to_json(),
+                "right": r.right.to_json(),
+                "span": span,
+            }),
+            RelAlgebra::Reorder(r) => json!({
+                "kind": "reorder",
+                "bindings": bindings,
+                "input": r.relation.to_json(),
+                "span": span,
+            }),
+            RelAlgebra::Filter(r) => json!({
+                "kind": "filter",
+                "bindings": bindings,
+                "filters": texts(&r.filters),
+                "input": r.parent.to_json(),
+                "span": span,
+            }),
+            RelAlgebra::Unification(r) => json!({
+                "kind": if r.is_multi { "multi_unify" } else { "unify" },
+                "bindings": bindings,
+                "binding": r.binding.name.to_string(),
+                "expr": r.expr.to_string(),
+                "input": r.parent.to_json(),
+                "span": span,
+            }),
+            RelAlgebra::HnswSearch(r) => json!({
+                "kind": "hnsw_search",
+                "bindings": bindings,
+                "relation": r.base.name.to_string(),
+                "index": r.idx.name.to_string(),
+                "query": r.query.to_string(),
+                "k": r.k,
+                "ef": r.ef,
+                "input": r.parent.to_json(),
+                "span": span,
+            }),
+            RelAlgebra::FtsSearch(r) => json!({
+                "kind": "fts_search",
+                "bindings": bindings,
+                "relation": r.base.name.to_string(),
+                "index": r.idx.name.to_string(),
+                "query": r.query.to_string(),
+                "k": r.k,
+                "input": r.parent.to_json(),
+                "span": span,
+            }),
+            RelAlgebra::LshSearch(r) => json!({
+                "kind": "lsh_search",
+                "bindings": bindings,
+                "relation": r.base.name.to_string(),
+                "index": r.idx.name.to_string(),
+                "query": r.query.to_string(),
+                "min_similarity": r.min_similarity,
+                "input": r.parent.to_json(),
+                "span": span,
+            }),
+        }
+    }
+    /// Replaces the parameters of a prepared query by their values
+    pub(crate) fn bind_params(&mut self, params: &BTreeMap<String, DataValue>) -> Result<()> {
+        match self {
+            RelAlgebra::Fixed(_) => {}
+            RelAlgebra::TempStore(r) => {
+                bind_params_in_filters(&mut r.filters, &mut r.filters_bytecodes, params)?
+            }
+            RelAlgebra::Stored(r) => {
+                bind_params_in_filters(&mut r.filters, &mut r.filters_bytecodes, params)?
+            }
+            RelAlgebra::StoredWithValidity(r) => {
+                bind_params_in_filters(&mut r.filters, &mut r.filters_bytecodes, params)?
+            }
+            RelAlgebra::Join(r) => {
+                r.left.bind_params(params)?;
+                r.right.bind_params(params)?;
+            }
+            RelAlgebra::NegJoin(r) => {
+                r.left.bind_params(params)?;
+                r.right.bind_params(params)?;
+            }
+            RelAlgebra::Reorder(r) => r.relation.bind_params(params)?,
+            RelAlgebra::Filter(r) => {
+                r.parent.bind_params(params)?;
+                bind_params_in_filters(&mut r.filters, &mut r.filters_bytecodes, params)?
+            }
+            RelAlgebra::Unification(r) => {
+                r.parent.bind_params(params)?;
+                r.expr.bind_params(params)?;
+                bind_params_in_bytecodes(&mut r.expr_bytecode, params)?
+            }
+            RelAlgebra::HnswSearch(r) => {
+                r.parent.bind_params(params)?;
+                r.query.bind_params(params)?;
+                bind_params_in_bytecodes(&mut r.query_bytecode, params)?
+            }
+            RelAlgebra::FtsSearch(r) => {
+                r.parent.bind_params(params)?;
+                r.query.bind_params(params)?;
+                bind_params_in_bytecodes(&mut r.query_bytecode, params)?
+            }
+            RelAlgebra::LshSearch(r) => {
+                r.parent.bind_params(params)?;
+                r.query.bind_params(params)?;
+                bind_params_in_bytecodes(&mut r.query_bytecode, params)?
+            }
         }
+        Ok(())
+    }
+}
+
+fn bind_params_in_filters(
+    filters: &mut [Expr],
+    filters_bytecodes: &mut [(Vec<Bytecode>, SourceSpan)],
+    params: &BTreeMap<String, DataValue>,
+) -> Result<()> {
+    for filter in filters {
+        filter.bind_params(params)?;
+    }
+    for (bytecodes, _) in filters_bytecodes {
+        bind_params_in_bytecodes(bytecodes, params)?;
     }
+    Ok(())
 }
 
+#[derive(serde_derive::Serialize, serde_derive::Deserialize)]
 pub(crate) struct UnificationRA {
     pub(crate) parent: Box<RelAlgebra>,
     pub(crate) binding: Symbol,
@@ -169,6 +376,293 @@ impl UnificationRA {
     }
 }
 
+/// Searches an HNSW index for the rows nearest to a vector computed from every row
+/// of the parent, each found row being joined onto the row it was found for
+#[derive(serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) struct HnswSearchRA {
+    pub(crate) parent: Box<RelAlgebra>,
+    pub(crate) base: RelationHandle,
+    pub(crate) idx: RelationHandle,
+    pub(crate) manifest: HnswIndexManifest,
+    /// bound to the columns of the rows found
+    pub(crate) bindings: Vec<Symbol>,
+    pub(crate) query: Expr,
+    pub(crate) query_bytecode: Vec<Bytecode>,
+    pub(crate) k: usize,
+    pub(crate) ef: usize,
+    pub(crate) bind_distance: Option<Symbol>,
+    /// the name of the relation whose reads are audited, see [RelAlgebra::audited]
+    pub(crate) audit: Option<SmartString<LazyCompact>>,
+    pub(crate) to_eliminate: BTreeSet<Symbol>,
+    pub(crate) span: SourceSpan,
+}
+
+impl HnswSearchRA {
+    fn own_bindings(&self) -> impl Iterator<Item = &Symbol> {
+        self.bindings.iter().chain(self.bind_distance.iter())
+    }
+    fn fill_binding_indices_and_compile(&mut self) -> Result<()> {
+        let parent_bindings: BTreeMap<_, _> = self
+            .parent
+            .bindings_after_eliminate()
+            .into_iter()
+            .enumerate()
+            .map(|(a, b)| (b, a))
+            .collect();
+        self.query.fill_binding_indices(&parent_bindings)?;
+        self.query_bytecode = self.query.compile();
+        Ok(())
+    }
+    pub(crate) fn do_eliminate_temp_vars(&mut self, used: &BTreeSet<Symbol>) -> Result<()> {
+        let own: Vec<_> = self.own_bindings().cloned().collect();
+        for binding in self
+            .parent
+            .bindings_before_eliminate()
+            .into_iter()
+            .chain(own)
+        {
+            if !used.contains(&binding) {
+                self.to_eliminate.insert(binding);
+            }
+        }
+        let mut nxt = used.clone();
+        nxt.extend(self.query.bindings());
+        self.parent.eliminate_temp_vars(&nxt)?;
+        Ok(())
+    }
+
+    fn iter<'a>(
+        &'a self,
+        tx: &'a SessionTx<'_>,
+        delta_rule: Option<&MagicSymbol>,
+        stores: &'a BTreeMap<MagicSymbol, EpochStore>,
+    ) -> Result<TupleIter<'a>> {
+        let mut bindings = self.parent.bindings_after_eliminate();
+        bindings.extend(self.own_bindings().cloned());
+        let eliminate_indices = get_eliminate_indices(&bindings, &self.to_eliminate);
+        let mut stack = vec![];
+        let it = self
+            .parent
+            .iter(tx, delta_rule, stores)?
+            .map_ok(move |tuple| -> Result<Vec<Tuple>> {
+                let query = eval_bytecode(&self.query_bytecode, &tuple, &mut stack)?;
+                let query = hnsw_query_vector(query, &self.manifest, self.span)?;
+                let found = tx.hnsw_search(
+                    &self.base,
+                    &self.idx,
+                    &self.manifest,
+                    &query,
+                    self.k,
+                    self.ef,
+                )?;
+                let mut coll = Vec::with_capacity(found.len());
+                for (dist, row) in found {
+                    let mut ret = tuple.clone();
+                    ret.extend(row);
+                    if self.bind_distance.is_some() {
+                        ret.push(DataValue::from(dist));
+                    }
+                    coll.push(eliminate_from_tuple(ret, &eliminate_indices));
+                }
+                Ok(coll)
+            })
+            .map(flatten_err)
+            .flatten_ok();
+        Ok(tx.audit_reads(self.audit.as_deref(), Box::new(it)))
+    }
+}
+
+/// Searches a full-text index for the rows best matching a query computed from every row
+/// of the parent, each found row being joined onto the row it was found for
+#[derive(serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) struct FtsSearchRA {
+    pub(crate) parent: Box<RelAlgebra>,
+    pub(crate) base: RelationHandle,
+    pub(crate) idx: RelationHandle,
+    pub(crate) manifest: FtsIndexManifest,
+    /// bound to the columns of the rows found
+    pub(crate) bindings: Vec<Symbol>,
+    pub(crate) query: Expr,
+    pub(crate) query_bytecode: Vec<Bytecode>,
+    pub(crate) k: usize,
+    pub(crate) bind_score: Option<Symbol>,
+    /// the name of the relation whose reads are audited, see [RelAlgebra::audited]
+    pub(crate) audit: Option<SmartString<LazyCompact>>,
+    pub(crate) to_eliminate: BTreeSet<Symbol>,
+    pub(crate) span: SourceSpan,
+}
+
+impl FtsSearchRA {
+    fn own_bindings(&self) -> impl Iterator<Item = &Symbol> {
+        self.bindings.iter().chain(self.bind_score.iter())
+    }
+    fn fill_binding_indices_and_compile(&mut self) -> Result<()> {
+        let parent_bindings: BTreeMap<_, _> = self
+            .parent
+            .bindings_after_eliminate()
+            .into_iter()
+            .enumerate()
+            .map(|(a, b)| (b, a))
+            .collect();
+        self.query.fill_binding_indices(&parent_bindings)?;
+        self.query_bytecode = self.query.compile();
+        Ok(())
+    }
+    pub(crate) fn do_eliminate_temp_vars(&mut self, used: &BTreeSet<Symbol>) -> Result<()> {
+        let own: Vec<_> = self.own_bindings().cloned().collect();
+        for binding in self
+            .parent
+            .bindings_before_eliminate()
+            .into_iter()
+            .chain(own)
+        {
+            if !used.contains(&binding) {
+                self.to_eliminate.insert(binding);
+            }
+        }
+        let mut nxt = used.clone();
+        nxt.extend(self.query.bindings());
+        self.parent.eliminate_temp_vars(&nxt)?;
+        Ok(())
+    }
+
+    fn iter<'a>(
+        &'a self,
+        tx: &'a SessionTx<'_>,
+        delta_rule: Option<&MagicSymbol>,
+        stores: &'a BTreeMap<MagicSymbol, EpochStore>,
+    ) -> Result<TupleIter<'a>> {
+        let mut bindings = self.parent.bindings_after_eliminate();
+        bindings.extend(self.own_bindings().cloned());
+        let eliminate_indices = get_eliminate_indices(&bindings, &self.to_eliminate);
+        let mut stack = vec![];
+        let it = self
+            .parent
+            .iter(tx, delta_rule, stores)?
+            .map_ok(move |tuple| -> Result<Vec<Tuple>> {
+                let query = eval_bytecode(&self.query_bytecode, &tuple, &mut stack)?;
+                // queries without terms match nothing
+                let query = match fts_query(query, &self.manifest, self.span)? {
+                    None => return Ok(vec![]),
+                    Some(query) => query,
+                };
+                let found = tx.fts_search(&self.base, &self.idx, &query, self.k)?;
+                let mut coll = Vec::with_capacity(found.len());
+                for (score, row) in found {
+                    let mut ret = tuple.clone();
+                    ret.extend(row);
+                    if self.bind_score.is_some() {
+                        ret.push(DataValue::from(score));
+                    }
+                    coll.push(eliminate_from_tuple(ret, &eliminate_indices));
+                }
+                Ok(coll)
+            })
+            .map(flatten_err)
+            .flatten_ok();
+        Ok(tx.audit_reads(self.audit.as_deref(), Box::new(it)))
+    }
+}
+
+/// Searches an LSH index for the rows similar to a text computed from every row of the
+/// parent, each found row being joined onto the row it was found for
+#[derive(serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) struct LshSearchRA {
+    pub(crate) parent: Box<RelAlgebra>,
+    pub(crate) base: RelationHandle,
+    pub(crate) idx: RelationHandle,
+    pub(crate) manifest: LshIndexManifest,
+    /// bound to the columns of the rows found
+    pub(crate) bindings: Vec<Symbol>,
+    pub(crate) query: Expr,
+    pub(crate) query_bytecode: Vec<Bytecode>,
+    pub(crate) min_similarity: f64,
+    pub(crate) bind_similarity: Option<Symbol>,
+    /// the name of the relation whose reads are audited, see [RelAlgebra::audited]
+    pub(crate) audit: Option<SmartString<LazyCompact>>,
+    pub(crate) to_eliminate: BTreeSet<Symbol>,
+    pub(crate) span: SourceSpan,
+}
+
+impl LshSearchRA {
+    fn own_bindings(&self) -> impl Iterator<Item = &Symbol> {
+        self.bindings.iter().chain(self.bind_similarity.iter())
+    }
+    fn fill_binding_indices_and_compile(&mut self) -> Result<()> {
+        let parent_bindings: BTreeMap<_, _> = self
+            .parent
+            .bindings_after_eliminate()
+            .into_iter()
+            .enumerate()
+            .map(|(a, b)| (b, a))
+            .collect();
+        self.query.fill_binding_indices(&parent_bindings)?;
+        self.query_bytecode = self.query.compile();
+        Ok(())
+    }
+    pub(crate) fn do_eliminate_temp_vars(&mut self, used: &BTreeSet<Symbol>) -> Result<()> {
+        let own: Vec<_> = self.own_bindings().cloned().collect();
+        for binding in self
+            .parent
+            .bindings_before_eliminate()
+            .into_iter()
+            .chain(own)
+        {
+            if !used.contains(&binding) {
+                self.to_eliminate.insert(binding);
+            }
+        }
+        let mut nxt = used.clone();
+        nxt.extend(self.query.bindings());
+        self.parent.eliminate_temp_vars(&nxt)?;
+        Ok(())
+    }
+
+    fn iter<'a>(
+        &'a self,
+        tx: &'a SessionTx<'_>,
+        delta_rule: Option<&MagicSymbol>,
+        stores: &'a BTreeMap<MagicSymbol, EpochStore>,
+    ) -> Result<TupleIter<'a>> {
+        let mut bindings = self.parent.bindings_after_eliminate();
+        bindings.extend(self.own_bindings().cloned());
+        let eliminate_indices = get_eliminate_indices(&bindings, &self.to_eliminate);
+        let mut stack = vec![];
+        let it = self
+            .parent
+            .iter(tx, delta_rule, stores)?
+            .map_ok(move |tuple| -> Result<Vec<Tuple>> {
+                let query = eval_bytecode(&self.query_bytecode, &tuple, &mut stack)?;
+                // texts too short to be shingled are similar to nothing
+                let signature = match lsh_query_signature(query, &self.manifest, self.span)? {
+                    None => return Ok(vec![]),
+                    Some(signature) => signature,
+                };
+                let found = tx.lsh_search(
+                    &self.base,
+                    &self.idx,
+                    &self.manifest,
+                    &signature,
+                    self.min_similarity,
+                )?;
+                let mut coll = Vec::with_capacity(found.len());
+                for (similarity, row) in found {
+                    let mut ret = tuple.clone();
+                    ret.extend(row);
+                    if self.bind_similarity.is_some() {
+                        ret.push(DataValue::from(similarity));
+                    }
+                    coll.push(eliminate_from_tuple(ret, &eliminate_indices));
+                }
+                Ok(coll)
+            })
+            .map(flatten_err)
+            .flatten_ok();
+        Ok(tx.audit_reads(self.audit.as_deref(), Box::new(it)))
+    }
+}
+
+#[derive(serde_derive::Serialize, serde_derive::Deserialize)]
 pub(crate) struct FilteredRA {
     pub(crate) parent: Box<RelAlgebra>,
     pub(crate) filters: Vec<Expr>,
@@ -282,6 +776,7 @@ impl Debug for RelAlgebra {
                 .field(&r.storage.name)
                 .field(&r.filters)
                 .field(&r.valid_at)
+                .field(&r.valid_until)
                 .finish(),
             RelAlgebra::Join(r) => {
                 if r.left.is_unit() {
@@ -320,6 +815,27 @@ impl Debug for RelAlgebra {
                 .field(&r.binding)
                 .field(&r.expr)
                 .finish(),
+            RelAlgebra::HnswSearch(r) => f
+                .debug_tuple("HnswSearch")
+                .field(&bindings)
+                .field(&r.parent)
+                .field(&r.idx.name)
+                .field(&r.query)
+                .finish(),
+            RelAlgebra::FtsSearch(r) => f
+                .debug_tuple("FtsSearch")
+                .field(&bindings)
+                .field(&r.parent)
+                .field(&r.idx.name)
+                .field(&r.query)
+                .finish(),
+            RelAlgebra::LshSearch(r) => f
+                .debug_tuple("LshSearch")
+                .field(&bindings)
+                .field(&r.parent)
+                .field(&r.idx.name)
+                .field(&r.query)
+                .finish(),
         }
     }
 }
@@ -359,6 +875,18 @@ impl RelAlgebra {
                 u.parent.fill_binding_indices_and_compile()?;
                 u.fill_binding_indices_and_compile()?
             }
+            RelAlgebra::HnswSearch(s) => {
+                s.parent.fill_binding_indices_and_compile()?;
+                s.fill_binding_indices_and_compile()?
+            }
+            RelAlgebra::FtsSearch(s) => {
+                s.parent.fill_binding_indices_and_compile()?;
+                s.fill_binding_indices_and_compile()?
+            }
+            RelAlgebra::LshSearch(s) => {
+                s.parent.fill_binding_indices_and_compile()?;
+                s.fill_binding_indices_and_compile()?
+            }
             RelAlgebra::Join(r) => {
                 r.left.fill_binding_indices_and_compile()?;
                 r.right.fill_binding_indices_and_compile()?;
@@ -404,27 +932,59 @@ impl RelAlgebra {
                 storage,
                 filters: vec![],
                 filters_bytecodes: vec![],
+                shared: None,
+                audit: None,
                 span,
             })),
-            Some(vld) => {
-                if storage.metadata.keys.last().unwrap().typing
-                    != (NullableColType {
-                        coltype: ColType::Validity,
-                        nullable: false,
-                    })
-                {
-                    bail!(InvalidTimeTravelScanning(storage.name.to_string(), span));
-                };
-                Ok(Self::StoredWithValidity(StoredWithValidityRA {
-                    bindings,
-                    storage,
-                    filters: vec![],
-                    filters_bytecodes: vec![],
-                    valid_at: vld,
-                    span,
-                }))
-            }
+            Some(vld) => Self::relation_with_validity(bindings, storage, span, vld, None),
+        }
+    }
+    /// Scans every version of the rows of the relation valid within the window,
+    /// binding the timestamps at which they start and stop to be valid after the columns
+    pub(crate) fn relation_in_window(
+        bindings: Vec<Symbol>,
+        storage: RelationHandle,
+        span: SourceSpan,
+        valid_from: ValidityTs,
+        valid_to: ValidityTs,
+    ) -> Result<Self> {
+        Self::relation_with_validity(bindings, storage, span, valid_from, Some(valid_to))
+    }
+    fn relation_with_validity(
+        bindings: Vec<Symbol>,
+        storage: RelationHandle,
+        span: SourceSpan,
+        valid_at: ValidityTs,
+        valid_until: Option<ValidityTs>,
+    ) -> Result<Self> {
+        if storage.metadata.keys.last().unwrap().typing
+            != (NullableColType {
+                coltype: ColType::Validity,
+                nullable: false,
+            })
+        {
+            bail!(InvalidTimeTravelScanning(storage.name.to_string(), span));
+        };
+        Ok(Self::StoredWithValidity(StoredWithValidityRA {
+            bindings,
+            storage,
+            filters: vec![],
+            filters_bytecodes: vec![],
+            valid_at,
+            valid_until,
+            audit: None,
+            span,
+        }))
+    }
+    /// Counts the rows read from the stored relation as reads of `relation` in the audit log,
+    /// if given. Scans of indices are counted as reads of the relation they index.
+    pub(crate) fn audited(mut self, relation: Option<SmartString<LazyCompact>>) -> Self {
+        match &mut self {
+            RelAlgebra::Stored(r) => r.audit = relation,
+            RelAlgebra::StoredWithValidity(r) => r.audit = relation,
+            _ => unreachable!(),
         }
+        self
     }
     pub(crate) fn reorder(self, new_order: Vec<Symbol>) -> Self {
         Self::Reorder(ReorderRA {
@@ -437,7 +997,10 @@ impl RelAlgebra {
             s @ (RelAlgebra::Fixed(_)
             | RelAlgebra::Reorder(_)
             | RelAlgebra::NegJoin(_)
-            | RelAlgebra::Unification(_)) => {
+            | RelAlgebra::Unification(_)
+            | RelAlgebra::HnswSearch(_)
+            | RelAlgebra::FtsSearch(_)
+            | RelAlgebra::LshSearch(_)) => {
                 let span = filter.span();
                 RelAlgebra::Filter(FilteredRA {
                     parent: Box::new(s),
@@ -484,6 +1047,8 @@ impl RelAlgebra {
                 storage,
                 mut filters,
                 filters_bytecodes,
+                shared,
+                audit,
                 span,
             }) => {
                 filters.push(filter);
@@ -492,6 +1057,8 @@ impl RelAlgebra {
                     storage,
                     filters,
                     filters_bytecodes,
+                    shared,
+                    audit,
                     span,
                 })
             }
@@ -502,6 +1069,8 @@ impl RelAlgebra {
                 filters_bytecodes: filter_bytecodes,
                 span,
                 valid_at,
+                valid_until,
+                audit,
             }) => {
                 filters.push(filter);
                 RelAlgebra::StoredWithValidity(StoredWithValidityRA {
@@ -510,7 +1079,9 @@ impl RelAlgebra {
                     filters,
                     span,
                     valid_at,
+                    valid_until,
                     filters_bytecodes: filter_bytecodes,
+                    audit,
                 })
             }
             RelAlgebra::Join(inner) => {
@@ -578,6 +1149,95 @@ impl RelAlgebra {
             span,
         })
     }
+    /// Joins the rows found by searching the HNSW index `idx` of `base` onto every row
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn hnsw_search(
+        self,
+        base: RelationHandle,
+        idx: RelationHandle,
+        manifest: HnswIndexManifest,
+        bindings: Vec<Symbol>,
+        query: Expr,
+        k: usize,
+        ef: usize,
+        bind_distance: Option<Symbol>,
+        span: SourceSpan,
+    ) -> Self {
+        let audit = base.audit.reads.then(|| base.name.clone());
+        RelAlgebra::HnswSearch(HnswSearchRA {
+            parent: Box::new(self),
+            base,
+            idx,
+            manifest,
+            bindings,
+            query,
+            query_bytecode: vec![],
+            k,
+            ef,
+            bind_distance,
+            audit,
+            to_eliminate: Default::default(),
+            span,
+        })
+    }
+    /// Joins the rows found by searching the full-text index `idx` of `base` onto every row
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn fts_search(
+        self,
+        base: RelationHandle,
+        idx: RelationHandle,
+        manifest: FtsIndexManifest,
+        bindings: Vec<Symbol>,
+        query: Expr,
+        k: usize,
+        bind_score: Option<Symbol>,
+        span: SourceSpan,
+    ) -> Self {
+        let audit = base.audit.reads.then(|| base.name.clone());
+        RelAlgebra::FtsSearch(FtsSearchRA {
+            parent: Box::new(self),
+            base,
+            idx,
+            manifest,
+            bindings,
+            query,
+            query_bytecode: vec![],
+            k,
+            bind_score,
+            audit,
+            to_eliminate: Default::default(),
+            span,
+        })
+    }
+    /// Joins the rows found by searching the LSH index `idx` of `base` onto every row
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn lsh_search(
+        self,
+        base: RelationHandle,
+        idx: RelationHandle,
+        manifest: LshIndexManifest,
+        bindings: Vec<Symbol>,
+        query: Expr,
+        min_similarity: f64,
+        bind_similarity: Option<Symbol>,
+        span: SourceSpan,
+    ) -> Self {
+        let audit = base.audit.reads.then(|| base.name.clone());
+        RelAlgebra::LshSearch(LshSearchRA {
+            parent: Box::new(self),
+            base,
+            idx,
+            manifest,
+            bindings,
+            query,
+            query_bytecode: vec![],
+            min_similarity,
+            bind_similarity,
+            audit,
+            to_eliminate: Default::default(),
+            span,
+        })
+    }
     pub(crate) fn join(
         self,
         right: RelAlgebra,
@@ -616,7 +1276,7 @@ impl RelAlgebra {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, serde_derive::Serialize, serde_derive::Deserialize)]
 pub(crate) struct ReorderRA {
     pub(crate) relation: Box<RelAlgebra>,
     pub(crate) new_order: Vec<Symbol>,
@@ -662,7 +1322,7 @@ impl ReorderRA {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, serde_derive::Serialize, serde_derive::Deserialize)]
 pub(crate) struct InlineFixedRA {
     pub(crate) bindings: Vec<Symbol>,
     pub(crate) data: Vec<Vec<DataValue>>,
@@ -777,7 +1437,7 @@ fn invert_option_err<T>(v: Result<Option<T>>) -> Option<Result<T>> {
     }
 }
 
-fn filter_iter(
+pub(crate) fn filter_iter(
     filters_bytecodes: Vec<(Vec<Bytecode>, SourceSpan)>,
     it: impl Iterator<Item = Result<Tuple>>,
 ) -> impl Iterator<Item = Result<Tuple>> {
@@ -812,22 +1472,93 @@ fn get_eliminate_indices(bindings: &[Symbol], eliminate: &BTreeSet<Symbol>) -> B
         .collect::<BTreeSet<_>>()
 }
 
-#[derive(Debug)]
+#[derive(Debug, serde_derive::Serialize, serde_derive::Deserialize)]
 pub(crate) struct StoredRA {
     pub(crate) bindings: Vec<Symbol>,
     pub(crate) storage: RelationHandle,
     pub(crate) filters: Vec<Expr>,
     pub(crate) filters_bytecodes: Vec<(Vec<Bytecode>, SourceSpan)>,
+    #[serde(skip)]
+    pub(crate) shared: Option<Arc<SharedScan>>,
+    /// the name of the relation whose reads are audited, see [Self::audited]
+    pub(crate) audit: Option<SmartString<LazyCompact>>,
     pub(crate) span: SourceSpan,
 }
 
+/// Scans larger than this are not materialized for sharing: the first consumer reads on
+/// past the rows it has buffered, and each other consumer scans the stored relation by itself.
+pub(crate) const SHARED_SCAN_MAX_ROWS: usize = 100_000;
+
+/// A full scan of a stored relation that is performed identically by several rules
+/// of the same program. The first consumer to run materializes the filtered rows,
+/// the other consumers read them back. Stored relations are not mutated while a
+/// program is being evaluated, and the scan is dropped together with the compiled program,
+/// before any mutation of the program is applied.
+#[derive(Debug)]
+pub(crate) struct SharedScan {
+    pub(crate) id: usize,
+    pub(crate) consumers: usize,
+    state: Mutex<SharedScanState>,
+}
+
+pub(crate) type SharedScanKey = (String, Vec<String>, Vec<String>);
+
 #[derive(Debug)]
+enum SharedScanState {
+    Pending,
+    Materialized(Arc<Vec<Tuple>>),
+    TooLarge,
+}
+
+impl SharedScan {
+    pub(crate) fn new(id: usize, consumers: usize) -> Self {
+        Self {
+            id,
+            consumers,
+            state: Mutex::new(SharedScanState::Pending),
+        }
+    }
+    /// The materialized rows, or else the rows of a scan of this consumer's own.
+    /// The consumer finding the scan too large to share goes on from the rows it has buffered.
+    fn rows<'a>(
+        &self,
+        scan: impl FnOnce() -> Result<TupleIter<'a>>,
+    ) -> Result<Either<Arc<Vec<Tuple>>, TupleIter<'a>>> {
+        let mut state = self.state.lock().unwrap();
+        match &*state {
+            SharedScanState::Materialized(rows) => return Ok(Left(rows.clone())),
+            SharedScanState::TooLarge => return Ok(Right(scan()?)),
+            SharedScanState::Pending => {}
+        }
+        let mut it = scan()?;
+        let mut rows = vec![];
+        while rows.len() < SHARED_SCAN_MAX_ROWS {
+            match it.next() {
+                Some(tuple) => rows.push(tuple?),
+                None => {
+                    let rows = Arc::new(rows);
+                    *state = SharedScanState::Materialized(rows.clone());
+                    return Ok(Left(rows));
+                }
+            }
+        }
+        debug!("shared scan #{} exceeds the size cutoff", self.id);
+        *state = SharedScanState::TooLarge;
+        Ok(Right(Box::new(rows.into_iter().map(Ok).chain(it))))
+    }
+}
+
+#[derive(Debug, serde_derive::Serialize, serde_derive::Deserialize)]
 pub(crate) struct StoredWithValidityRA {
     pub(crate) bindings: Vec<Symbol>,
     pub(crate) storage: RelationHandle,
     pub(crate) filters: Vec<Expr>,
     pub(crate) filters_bytecodes: Vec<(Vec<Bytecode>, SourceSpan)>,
+    /// the time of the scan, or the start of the window if `valid_until` is given
     pub(crate) valid_at: ValidityTs,
+    /// the end of the window of a scan of every version valid within it
+    pub(crate) valid_until: Option<ValidityTs>,
+    pub(crate) audit: Option<SmartString<LazyCompact>>,
     pub(crate) span: SourceSpan,
 }
 
@@ -847,14 +1578,34 @@ impl StoredWithValidityRA {
         Ok(())
     }
     fn iter<'a>(&'a self, tx: &'a SessionTx<'_>) -> Result<TupleIter<'a>> {
-        let it = self.storage.skip_scan_all(tx, self.valid_at);
-        Ok(if self.filters.is_empty() {
+        let expiry = self.storage.expiry(tx);
+        let it: TupleIter<'a> = match self.valid_until {
+            None => Box::new(self.storage.skip_scan_all(tx, self.valid_at)),
+            Some(valid_until) => Box::new(self.storage.skip_scan_window_all(
+                tx,
+                self.valid_at,
+                valid_until,
+            )),
+        };
+        let it = it.filter_ok(move |row| expiry.is_live(row));
+        let it: TupleIter<'a> = if self.filters.is_empty() {
             Box::new(it)
         } else {
             Box::new(filter_iter(self.filters_bytecodes.clone(), it))
-        })
+        };
+        Ok(tx.audit_reads(self.audit.as_deref(), it))
     }
     fn prefix_join<'a>(
+        &'a self,
+        tx: &'a SessionTx<'_>,
+        left_iter: TupleIter<'a>,
+        join_indices: (Vec<usize>, Vec<usize>),
+        eliminate_indices: BTreeSet<usize>,
+    ) -> Result<TupleIter<'a>> {
+        let it = self.do_prefix_join(tx, left_iter, join_indices, eliminate_indices)?;
+        Ok(tx.audit_reads(self.audit.as_deref(), it))
+    }
+    fn do_prefix_join<'a>(
         &'a self,
         tx: &'a SessionTx<'_>,
         left_iter: TupleIter<'a>,
@@ -868,6 +1619,7 @@ impl StoredWithValidityRA {
             .map(|(a, _)| left_join_indices[a])
             .collect_vec();
 
+        let expiry = self.storage.expiry(tx);
         let mut skip_range_check = false;
 
         let it = left_iter
@@ -877,7 +1629,7 @@ impl StoredWithValidityRA {
                     .map(|i| tuple[*i].clone())
                     .collect_vec();
 
-                if !skip_range_check && !self.filters.is_empty() {
+                if !skip_range_check && !self.filters.is_empty() && self.valid_until.is_none() {
                     let other_bindings = &self.bindings[right_join_indices.len()..];
                     let (l_bound, u_bound) = match compute_bounds(&self.filters, other_bindings) {
                         Ok(b) => b,
@@ -898,6 +1650,9 @@ impl StoredWithValidityRA {
                                 )
                                 .map(move |res_found| -> Result<Option<Tuple>> {
                                     let found = res_found?;
+                                    if !expiry.is_live(&found) {
+                                        return Ok(None);
+                                    }
                                     for (p, span) in self.filters_bytecodes.iter() {
                                         if !eval_bytecode_pred(p, &found, &mut stack, *span)? {
                                             return Ok(None);
@@ -913,11 +1668,22 @@ impl StoredWithValidityRA {
                 }
                 skip_range_check = true;
                 let mut stack = vec![];
+                let found_iter: TupleIter<'a> = match self.valid_until {
+                    None => Box::new(self.storage.skip_scan_prefix(tx, &prefix, self.valid_at)),
+                    Some(valid_until) => Box::new(self.storage.skip_scan_window_prefix(
+                        tx,
+                        &prefix,
+                        self.valid_at,
+                        valid_until,
+                    )),
+                };
                 Right(
-                    self.storage
-                        .skip_scan_prefix(tx, &prefix, self.valid_at)
+                    found_iter
                         .map(move |res_found| -> Result<Option<Tuple>> {
                             let found = res_found?;
+                            if !expiry.is_live(&found) {
+                                return Ok(None);
+                            }
                             for (p, span) in self.filters_bytecodes.iter() {
                                 if !eval_bytecode_pred(p, &found, &mut stack, *span)? {
                                     return Ok(None);
@@ -969,7 +1735,12 @@ impl StoredRA {
         let all_right_val_indices: BTreeSet<usize> =
             (0..val_len).map(|i| left_tuple_len + key_len + i).collect();
         let mut stack = vec![];
-        if self.filters.is_empty() && eliminate_indices.is_superset(&all_right_val_indices) {
+        let expiry = self.storage.expiry(tx);
+        // whether rows have expired is only known from their values
+        if self.filters.is_empty()
+            && !expiry.has_ttl()
+            && eliminate_indices.is_superset(&all_right_val_indices)
+        {
             let it = left_iter
                 .map_ok(move |tuple| -> Result<Option<Tuple>> {
                     let prefix = left_to_prefix_indices
@@ -982,7 +1753,7 @@ impl StoredRA {
                             return Ok(None);
                         }
                     }
-                    if self.storage.exists(tx, key)? {
+                    if tx.retry_lookup(|| self.storage.exists(tx, key))? {
                         let mut ret = tuple;
                         ret.extend_from_slice(key);
                         for _ in 0..val_len {
@@ -993,7 +1764,7 @@ impl StoredRA {
                         Ok(None)
                     }
                 })
-                .flatten_ok()
+                .map(flatten_err)
                 .filter_map(invert_option_err);
             Ok(if eliminate_indices.is_empty() {
                 Box::new(it)
@@ -1008,8 +1779,9 @@ impl StoredRA {
                         .map(|i| tuple[*i].clone())
                         .collect_vec();
                     let key = &prefix[0..key_len];
-                    match self.storage.get(tx, key)? {
+                    match tx.retry_lookup(|| self.storage.get(tx, key))? {
                         None => Ok(None),
+                        Some(found) if !expiry.is_live(&found) => Ok(None),
                         Some(found) => {
                             for (p, span) in self.filters_bytecodes.iter() {
                                 if !eval_bytecode_pred(p, &found, &mut stack, *span)? {
@@ -1022,7 +1794,7 @@ impl StoredRA {
                         }
                     }
                 })
-                .flatten_ok()
+                .map(flatten_err)
                 .filter_map(invert_option_err);
             Ok(if eliminate_indices.is_empty() {
                 Box::new(it)
@@ -1033,6 +1805,23 @@ impl StoredRA {
     }
 
     fn prefix_join<'a>(
+        &'a self,
+        tx: &'a SessionTx<'_>,
+        left_iter: TupleIter<'a>,
+        join_indices: (Vec<usize>, Vec<usize>),
+        eliminate_indices: BTreeSet<usize>,
+        left_tuple_len: usize,
+    ) -> Result<TupleIter<'a>> {
+        let it = self.do_prefix_join(
+            tx,
+            left_iter,
+            join_indices,
+            eliminate_indices,
+            left_tuple_len,
+        )?;
+        Ok(tx.audit_reads(self.audit.as_deref(), it))
+    }
+    fn do_prefix_join<'a>(
         &'a self,
         tx: &'a SessionTx<'_>,
         left_iter: TupleIter<'a>,
@@ -1059,6 +1848,7 @@ impl StoredRA {
             );
         }
 
+        let expiry = self.storage.expiry(tx);
         let mut skip_range_check = false;
         // In some cases, maybe we can stop as soon as we get one result?
         let it = left_iter
@@ -1068,6 +1858,13 @@ impl StoredRA {
                     .map(|i| tuple[*i].clone())
                     .collect_vec();
                 let mut stack = vec![];
+                // only the scan driving the rule is sampled, joined onto the unit relation,
+                // whose rows have no columns; scans for other rows on the left are complete
+                let scan_limit = if left_tuple_len == 0 {
+                    tx.scan_sample.unwrap_or(usize::MAX)
+                } else {
+                    usize::MAX
+                };
 
                 if !skip_range_check && !self.filters.is_empty() {
                     let other_bindings = &self.bindings[right_join_indices.len()..];
@@ -1081,8 +1878,12 @@ impl StoredRA {
                         return Left(
                             self.storage
                                 .scan_bounded_prefix(tx, &prefix, &l_bound, &u_bound)
+                                .take(scan_limit)
                                 .map(move |res_found| -> Result<Option<Tuple>> {
                                     let found = res_found?;
+                                    if !expiry.is_live(&found) {
+                                        return Ok(None);
+                                    }
                                     for (p, span) in self.filters_bytecodes.iter() {
                                         if !eval_bytecode_pred(p, &found, &mut stack, *span)? {
                                             return Ok(None);
@@ -1100,8 +1901,12 @@ impl StoredRA {
                 Right(
                     self.storage
                         .scan_prefix(tx, &prefix)
+                        .take(scan_limit)
                         .map(move |res_found| -> Result<Option<Tuple>> {
                             let found = res_found?;
+                            if !expiry.is_live(&found) {
+                                return Ok(None);
+                            }
                             for (p, span) in self.filters_bytecodes.iter() {
                                 if !eval_bytecode_pred(p, &found, &mut stack, *span)? {
                                     return Ok(None);
@@ -1131,6 +1936,7 @@ impl StoredRA {
         eliminate_indices: BTreeSet<usize>,
     ) -> Result<TupleIter<'a>> {
         debug_assert!(!right_join_indices.is_empty());
+        let expiry = self.storage.expiry(tx);
         let mut right_invert_indices = right_join_indices.iter().enumerate().collect_vec();
         right_invert_indices.sort_by_key(|(_, b)| **b);
         let mut left_to_prefix_indices = vec![];
@@ -1152,6 +1958,9 @@ impl StoredRA {
 
                         'outer: for found in self.storage.scan_prefix(tx, &prefix) {
                             let found = found?;
+                            if !expiry.is_live(&found) {
+                                continue;
+                            }
                             for (left_idx, right_idx) in
                                 left_join_indices.iter().zip(right_join_indices.iter())
                             {
@@ -1186,6 +1995,9 @@ impl StoredRA {
 
             for tuple in self.storage.scan_all(tx) {
                 let tuple = tuple?;
+                if !expiry.is_live(&tuple) {
+                    continue;
+                }
                 let to_join: Box<[DataValue]> = right_join_indices
                     .iter()
                     .map(|i| tuple[*i].clone())
@@ -1226,7 +2038,24 @@ impl StoredRA {
     }
 
     fn iter<'a>(&'a self, tx: &'a SessionTx<'_>) -> Result<TupleIter<'a>> {
-        let it = self.storage.scan_all(tx);
+        if let Some(shared) = &self.shared {
+            let it: TupleIter<'a> = match shared.rows(|| self.scan(tx))? {
+                Left(rows) => Box::new((0..rows.len()).map(move |i| Ok(rows[i].clone()))),
+                Right(it) => it,
+            };
+            return Ok(tx.audit_reads(self.audit.as_deref(), it));
+        }
+        Ok(tx.audit_reads(self.audit.as_deref(), self.scan(tx)?))
+    }
+
+    fn scan<'a>(&'a self, tx: &'a SessionTx<'_>) -> Result<TupleIter<'a>> {
+        #[cfg(test)]
+        tx.stored_scans.fetch_add(1, Ordering::Relaxed);
+        let expiry = self.storage.expiry(tx);
+        let it = self
+            .storage
+            .scan_all(tx)
+            .filter_ok(move |row| expiry.is_live(row));
         Ok(if self.filters.is_empty() {
             Box::new(it)
         } else {
@@ -1235,14 +2064,14 @@ impl StoredRA {
     }
 }
 
-fn join_is_prefix(right_join_indices: &[usize]) -> bool {
+pub(crate) fn join_is_prefix(right_join_indices: &[usize]) -> bool {
     let mut indices = right_join_indices.to_vec();
     indices.sort();
     let l = indices.len();
     indices.into_iter().eq(0..l)
 }
 
-#[derive(Debug)]
+#[derive(Debug, serde_derive::Serialize, serde_derive::Deserialize)]
 pub(crate) struct TempStoreRA {
     pub(crate) bindings: Vec<Symbol>,
     pub(crate) storage_key: MagicSymbol,
@@ -1495,6 +2324,7 @@ impl TempStoreRA {
     }
 }
 
+#[derive(serde_derive::Serialize, serde_derive::Deserialize)]
 pub(crate) struct Joiner {
     // invariant: these are of the same lengths
     pub(crate) left_keys: Vec<Symbol>,
@@ -1510,6 +2340,14 @@ impl Debug for Joiner {
 }
 
 impl Joiner {
+    /// The pairs of the bindings joined on, left then right
+    fn key_pairs(&self) -> Vec<(&str, &str)> {
+        self.left_keys
+            .iter()
+            .zip(self.right_keys.iter())
+            .map(|(l, r)| (&l.name as &str, &r.name as &str))
+            .collect()
+    }
     pub(crate) fn as_map(&self) -> BTreeMap<&str, &str> {
         self.left_keys
             .iter()
@@ -1556,6 +2394,9 @@ impl RelAlgebra {
             RelAlgebra::Filter(r) => r.do_eliminate_temp_vars(used),
             RelAlgebra::NegJoin(r) => r.do_eliminate_temp_vars(used),
             RelAlgebra::Unification(r) => r.do_eliminate_temp_vars(used),
+            RelAlgebra::HnswSearch(r) => r.do_eliminate_temp_vars(used),
+            RelAlgebra::FtsSearch(r) => r.do_eliminate_temp_vars(used),
+            RelAlgebra::LshSearch(r) => r.do_eliminate_temp_vars(used),
         }
     }
 
@@ -1570,6 +2411,9 @@ impl RelAlgebra {
             RelAlgebra::Filter(r) => Some(&r.to_eliminate),
             RelAlgebra::NegJoin(r) => Some(&r.to_eliminate),
             RelAlgebra::Unification(u) => Some(&u.to_eliminate),
+            RelAlgebra::HnswSearch(s) => Some(&s.to_eliminate),
+            RelAlgebra::FtsSearch(s) => Some(&s.to_eliminate),
+            RelAlgebra::LshSearch(s) => Some(&s.to_eliminate),
         }
     }
 
@@ -1584,6 +2428,23 @@ impl RelAlgebra {
         }
     }
 
+    /// The number of leading columns by which the tuples are produced in ascending order.
+    /// Only full scans of stored relations, possibly joined onto the unit relation,
+    /// are known to be ordered, by their key columns.
+    fn sorted_prefix_len(&self) -> usize {
+        match self {
+            RelAlgebra::Stored(r) => r.storage.metadata.keys.len().min(r.bindings.len()),
+            RelAlgebra::Join(j) if j.left.is_unit() => {
+                let n = j.right.sorted_prefix_len();
+                j.right.bindings_after_eliminate()[..n]
+                    .iter()
+                    .take_while(|b| !j.to_eliminate.contains(*b))
+                    .count()
+            }
+            _ => 0,
+        }
+    }
+
     fn bindings_before_eliminate(&self) -> Vec<Symbol> {
         match self {
             RelAlgebra::Fixed(f) => f.bindings.clone(),
@@ -1599,6 +2460,108 @@ impl RelAlgebra {
                 bindings.push(u.binding.clone());
                 bindings
             }
+            RelAlgebra::HnswSearch(s) => {
+                let mut bindings = s.parent.bindings_after_eliminate();
+                bindings.extend(s.own_bindings().cloned());
+                bindings
+            }
+            RelAlgebra::FtsSearch(s) => {
+                let mut bindings = s.parent.bindings_after_eliminate();
+                bindings.extend(s.own_bindings().cloned());
+                bindings
+            }
+            RelAlgebra::LshSearch(s) => {
+                let mut bindings = s.parent.bindings_after_eliminate();
+                bindings.extend(s.own_bindings().cloned());
+                bindings
+            }
+        }
+    }
+    /// Collects the stored relations that are scanned in full (as opposed to looked up by prefix),
+    /// keyed by relation, bindings and filters. A stored relation joined onto the unit relation
+    /// counts as a full scan.
+    pub(crate) fn collect_full_scans<'a>(
+        &'a mut self,
+        collected: &mut BTreeMap<SharedScanKey, Vec<&'a mut StoredRA>>,
+    ) -> Result<()> {
+        match self {
+            RelAlgebra::Stored(s) => {
+                let key = (
+                    s.storage.name.to_string(),
+                    s.bindings.iter().map(|b| b.name.to_string()).collect_vec(),
+                    s.filters.iter().map(|f| f.to_string()).collect_vec(),
+                );
+                collected.entry(key).or_default().push(s);
+            }
+            RelAlgebra::Fixed(_) | RelAlgebra::TempStore(_) | RelAlgebra::StoredWithValidity(_) => {
+            }
+            RelAlgebra::Reorder(r) => r.relation.collect_full_scans(collected)?,
+            RelAlgebra::Filter(r) => r.parent.collect_full_scans(collected)?,
+            RelAlgebra::Unification(r) => r.parent.collect_full_scans(collected)?,
+            RelAlgebra::HnswSearch(r) => r.parent.collect_full_scans(collected)?,
+            RelAlgebra::FtsSearch(r) => r.parent.collect_full_scans(collected)?,
+            RelAlgebra::LshSearch(r) => r.parent.collect_full_scans(collected)?,
+            RelAlgebra::NegJoin(r) => r.left.collect_full_scans(collected)?,
+            RelAlgebra::Join(r) => {
+                let right_is_scanned = match &r.right {
+                    RelAlgebra::Stored(_) if r.left.is_unit() => true,
+                    RelAlgebra::Stored(_) => {
+                        let (_, right_join_indices) = r.joiner.join_indices(
+                            &r.left.bindings_after_eliminate(),
+                            &r.right.bindings_after_eliminate(),
+                        )?;
+                        !join_is_prefix(&right_join_indices)
+                    }
+                    RelAlgebra::Join(_)
+                    | RelAlgebra::Filter(_)
+                    | RelAlgebra::Unification(_)
+                    | RelAlgebra::HnswSearch(_)
+                    | RelAlgebra::FtsSearch(_)
+                    | RelAlgebra::LshSearch(_) => true,
+                    _ => false,
+                };
+                let InnerJoin { left, right, .. } = r.as_mut();
+                left.collect_full_scans(collected)?;
+                if right_is_scanned {
+                    right.collect_full_scans(collected)?;
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Collects the names of all stored relations read by this relation.
+    pub(crate) fn collect_stored_relations(&self, collected: &mut BTreeSet<String>) {
+        match self {
+            RelAlgebra::Stored(s) => {
+                collected.insert(s.storage.name.to_string());
+            }
+            RelAlgebra::StoredWithValidity(s) => {
+                collected.insert(s.storage.name.to_string());
+            }
+            RelAlgebra::Fixed(_) | RelAlgebra::TempStore(_) => {}
+            RelAlgebra::Reorder(r) => r.relation.collect_stored_relations(collected),
+            RelAlgebra::Filter(r) => r.parent.collect_stored_relations(collected),
+            RelAlgebra::Unification(r) => r.parent.collect_stored_relations(collected),
+            RelAlgebra::HnswSearch(r) => {
+                collected.insert(r.base.name.to_string());
+                r.parent.collect_stored_relations(collected);
+            }
+            RelAlgebra::FtsSearch(r) => {
+                collected.insert(r.base.name.to_string());
+                r.parent.collect_stored_relations(collected);
+            }
+            RelAlgebra::LshSearch(r) => {
+                collected.insert(r.base.name.to_string());
+                r.parent.collect_stored_relations(collected);
+            }
+            RelAlgebra::NegJoin(r) => {
+                r.left.collect_stored_relations(collected);
+                r.right.collect_stored_relations(collected);
+            }
+            RelAlgebra::Join(r) => {
+                r.left.collect_stored_relations(collected);
+                r.right.collect_stored_relations(collected);
+            }
         }
     }
     pub(crate) fn iter<'a>(
@@ -1606,6 +2569,14 @@ impl RelAlgebra {
         tx: &'a SessionTx<'_>,
         delta_rule: Option<&MagicSymbol>,
         stores: &'a BTreeMap<MagicSymbol, EpochStore>,
+    ) -> Result<TupleIter<'a>> {
+        tx.profile_iter(self, || self.do_iter(tx, delta_rule, stores))
+    }
+    fn do_iter<'a>(
+        &'a self,
+        tx: &'a SessionTx<'_>,
+        delta_rule: Option<&MagicSymbol>,
+        stores: &'a BTreeMap<MagicSymbol, EpochStore>,
     ) -> Result<TupleIter<'a>> {
         match self {
             RelAlgebra::Fixed(f) => Ok(Box::new(f.data.iter().map(|t| Ok(t.clone())))),
@@ -1617,11 +2588,14 @@ impl RelAlgebra {
             RelAlgebra::Filter(r) => r.iter(tx, delta_rule, stores),
             RelAlgebra::NegJoin(r) => r.iter(tx, delta_rule, stores),
             RelAlgebra::Unification(r) => r.iter(tx, delta_rule, stores),
+            RelAlgebra::HnswSearch(r) => r.iter(tx, delta_rule, stores),
+            RelAlgebra::FtsSearch(r) => r.iter(tx, delta_rule, stores),
+            RelAlgebra::LshSearch(r) => r.iter(tx, delta_rule, stores),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, serde_derive::Serialize, serde_derive::Deserialize)]
 pub(crate) struct NegJoin {
     pub(crate) left: RelAlgebra,
     pub(crate) right: RelAlgebra,
@@ -1726,7 +2700,7 @@ impl NegJoin {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, serde_derive::Serialize, serde_derive::Deserialize)]
 pub(crate) struct InnerJoin {
     pub(crate) left: RelAlgebra,
     pub(crate) right: RelAlgebra,
@@ -1779,7 +2753,7 @@ impl InnerJoin {
                 if join_is_prefix(&join_indices.1) {
                     "mem_prefix_join"
                 } else {
-                    "mem_mat_join"
+                    "mem_hash_join"
                 }
             }
             RelAlgebra::Stored(_) => {
@@ -1790,10 +2764,12 @@ impl InnerJoin {
                         &self.right.bindings_after_eliminate(),
                     )
                     .unwrap();
-                if join_is_prefix(&join_indices.1) {
+                if self.can_merge_join(&join_indices) {
+                    "merge_join"
+                } else if join_is_prefix(&join_indices.1) {
                     "stored_prefix_join"
                 } else {
-                    "stored_mat_join"
+                    "stored_hash_join"
                 }
             }
             RelAlgebra::StoredWithValidity(_) => {
@@ -1807,12 +2783,15 @@ impl InnerJoin {
                 if join_is_prefix(&join_indices.1) {
                     "stored_prefix_join"
                 } else {
-                    "stored_mat_join"
+                    "stored_hash_join"
                 }
             }
-            RelAlgebra::Join(_) | RelAlgebra::Filter(_) | RelAlgebra::Unification(_) => {
-                "generic_mat_join"
-            }
+            RelAlgebra::Join(_)
+            | RelAlgebra::Filter(_)
+            | RelAlgebra::Unification(_)
+            | RelAlgebra::HnswSearch(_)
+            | RelAlgebra::FtsSearch(_)
+            | RelAlgebra::LshSearch(_) => "generic_mat_join",
             RelAlgebra::Reorder(_) => {
                 panic!("joining on reordered")
             }
@@ -1861,10 +2840,19 @@ impl InnerJoin {
                         stores,
                     )
                 } else {
-                    self.materialized_join(tx, eliminate_indices, delta_rule, stores)
+                    self.hash_join(tx, eliminate_indices, delta_rule, stores)
                 }
             }
             RelAlgebra::Stored(r) => {
+                // sampled scans are taken below, before their rows are filtered
+                if r.shared.is_some() && self.left.is_unit() && tx.scan_sample.is_none() {
+                    let it = r.iter(tx)?;
+                    return Ok(if eliminate_indices.is_empty() {
+                        it
+                    } else {
+                        Box::new(it.map_ok(move |t| eliminate_from_tuple(t, &eliminate_indices)))
+                    });
+                }
                 let join_indices = self
                     .joiner
                     .join_indices(
@@ -1872,7 +2860,15 @@ impl InnerJoin {
                         &self.right.bindings_after_eliminate(),
                     )
                     .unwrap();
-                if join_is_prefix(&join_indices.1) {
+                if self.can_merge_join(&join_indices) {
+                    self.merge_join(
+                        tx,
+                        join_indices.0.len(),
+                        eliminate_indices,
+                        delta_rule,
+                        stores,
+                    )
+                } else if join_is_prefix(&join_indices.1) {
                     let left_len = self.left.bindings_after_eliminate().len();
                     r.prefix_join(
                         tx,
@@ -1882,7 +2878,7 @@ impl InnerJoin {
                         left_len,
                     )
                 } else {
-                    self.materialized_join(tx, eliminate_indices, delta_rule, stores)
+                    self.hash_join(tx, eliminate_indices, delta_rule, stores)
                 }
             }
             RelAlgebra::StoredWithValidity(r) => {
@@ -1901,10 +2897,15 @@ impl InnerJoin {
                         eliminate_indices,
                     )
                 } else {
-                    self.materialized_join(tx, eliminate_indices, delta_rule, stores)
+                    self.hash_join(tx, eliminate_indices, delta_rule, stores)
                 }
             }
-            RelAlgebra::Join(_) | RelAlgebra::Filter(_) | RelAlgebra::Unification(_) => {
+            RelAlgebra::Join(_)
+            | RelAlgebra::Filter(_)
+            | RelAlgebra::Unification(_)
+            | RelAlgebra::HnswSearch(_)
+            | RelAlgebra::FtsSearch(_)
+            | RelAlgebra::LshSearch(_) => {
                 self.materialized_join(tx, eliminate_indices, delta_rule, stores)
             }
             RelAlgebra::Reorder(_) => {
@@ -1915,6 +2916,99 @@ impl InnerJoin {
             }
         }
     }
+    /// Whether both sides are produced in the order of the join keys, which must then be
+    /// the same leading columns of both
+    fn can_merge_join(
+        &self,
+        (left_join_indices, right_join_indices): &(Vec<usize>, Vec<usize>),
+    ) -> bool {
+        let n = right_join_indices.len();
+        n > 0
+            && left_join_indices == right_join_indices
+            && join_is_prefix(right_join_indices)
+            && self.left.sorted_prefix_len() >= n
+            && self.right.sorted_prefix_len() >= n
+    }
+    /// Joins the sides by merging them, as both are ordered by their first `key_len` columns,
+    /// which are the join keys. Only the rows of the right side with the current join key
+    /// are held in memory.
+    fn merge_join<'a>(
+        &'a self,
+        tx: &'a SessionTx<'_>,
+        key_len: usize,
+        eliminate_indices: BTreeSet<usize>,
+        delta_rule: Option<&MagicSymbol>,
+        stores: &'a BTreeMap<MagicSymbol, EpochStore>,
+    ) -> Result<TupleIter<'a>> {
+        debug!("using merge join");
+        let mut left = self.left.iter(tx, delta_rule, stores)?.peekable();
+        if left.peek().is_none() {
+            return Ok(Box::new(iter::empty()));
+        }
+        let mut right = self.right.iter(tx, delta_rule, stores)?.peekable();
+        if right.peek().is_none() {
+            return Ok(Box::new(iter::empty()));
+        }
+        Ok(Box::new(MergeJoinIterator {
+            left: Box::new(left),
+            right: Box::new(right),
+            key_len,
+            eliminate_indices,
+            left_cache: None,
+            group: vec![],
+            group_idx: 0,
+            right_cache: None,
+            right_exhausted: false,
+        }))
+    }
+    /// Joins by holding the rows of the right side in a hash table keyed by the join columns.
+    /// If the right side has more than `tx.hash_join_max_rows` rows, falls back to
+    /// [Self::materialized_join] to bound the memory used.
+    fn hash_join<'a>(
+        &'a self,
+        tx: &'a SessionTx<'_>,
+        eliminate_indices: BTreeSet<usize>,
+        delta_rule: Option<&MagicSymbol>,
+        stores: &'a BTreeMap<MagicSymbol, EpochStore>,
+    ) -> Result<TupleIter<'a>> {
+        let (left_join_indices, right_join_indices) = self
+            .joiner
+            .join_indices(
+                &self.left.bindings_after_eliminate(),
+                &self.right.bindings_after_eliminate(),
+            )
+            .unwrap();
+        let mut left = self.left.iter(tx, delta_rule, stores)?.peekable();
+        if left.peek().is_none() {
+            return Ok(Box::new(iter::empty()));
+        }
+
+        // keys are only mutable to clippy for the regex a `DataValue` may hold
+        #[allow(clippy::mutable_key_type)]
+        let mut table: HashMap<Box<[DataValue]>, Vec<Tuple>> = HashMap::new();
+        let mut n_rows = 0;
+        for tuple in self.right.iter(tx, delta_rule, stores)? {
+            let tuple = tuple?;
+            n_rows += 1;
+            if n_rows > tx.hash_join_max_rows {
+                drop(table);
+                return self.materialized_join(tx, eliminate_indices, delta_rule, stores);
+            }
+            let key = right_join_indices
+                .iter()
+                .map(|i| tuple[*i].clone())
+                .collect();
+            table.entry(key).or_default().push(tuple);
+        }
+        debug!("using hash join");
+        Ok(Box::new(HashJoinIterator {
+            left: Box::new(left),
+            left_join_indices,
+            table,
+            eliminate_indices,
+            pending: vec![].into_iter(),
+        }))
+    }
     fn materialized_join<'a>(
         &'a self,
         tx: &'a SessionTx<'_>,
@@ -2045,6 +3139,125 @@ impl<'a> CachedMaterializedIterator<'a> {
     }
 }
 
+struct HashJoinIterator<'a> {
+    left: TupleIter<'a>,
+    left_join_indices: Vec<usize>,
+    /// the rows of the right side by their join columns
+    table: HashMap<Box<[DataValue]>, Vec<Tuple>>,
+    eliminate_indices: BTreeSet<usize>,
+    /// the joined rows of the current row of the left side not yet returned
+    pending: std::vec::IntoIter<Tuple>,
+}
+
+impl<'a> HashJoinIterator<'a> {
+    fn next_inner(&mut self) -> Result<Option<Tuple>> {
+        loop {
+            if let Some(tuple) = self.pending.next() {
+                return Ok(Some(tuple));
+            }
+            let left_tuple = match self.left.next() {
+                None => return Ok(None),
+                Some(t) => t?,
+            };
+            let key = self
+                .left_join_indices
+                .iter()
+                .map(|i| left_tuple[*i].clone())
+                .collect_vec();
+            if let Some(matches) = self.table.get(&key[..]) {
+                self.pending = matches
+                    .iter()
+                    .map(|right_tuple| {
+                        let mut ret = left_tuple.clone();
+                        ret.extend_from_slice(right_tuple);
+                        eliminate_from_tuple(ret, &self.eliminate_indices)
+                    })
+                    .collect_vec()
+                    .into_iter();
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for HashJoinIterator<'a> {
+    type Item = Result<Tuple>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        swap_option_result(self.next_inner())
+    }
+}
+
+struct MergeJoinIterator<'a> {
+    left: TupleIter<'a>,
+    right: TupleIter<'a>,
+    key_len: usize,
+    eliminate_indices: BTreeSet<usize>,
+    left_cache: Option<Tuple>,
+    /// the rows of the right side with the join key of `left_cache`
+    group: Vec<Tuple>,
+    group_idx: usize,
+    /// the first row of the right side after `group`
+    right_cache: Option<Tuple>,
+    right_exhausted: bool,
+}
+
+impl<'a> MergeJoinIterator<'a> {
+    fn next_inner(&mut self) -> Result<Option<Tuple>> {
+        loop {
+            if let Some(left_tuple) = &self.left_cache {
+                if let Some(right_tuple) = self.group.get(self.group_idx) {
+                    self.group_idx += 1;
+                    let mut ret = left_tuple.clone();
+                    ret.extend_from_slice(right_tuple);
+                    return Ok(Some(eliminate_from_tuple(ret, &self.eliminate_indices)));
+                }
+            }
+            let left_tuple = match self.left.next() {
+                None => return Ok(None),
+                Some(t) => t?,
+            };
+            self.group_idx = 0;
+            let key = &left_tuple[..self.key_len];
+            let same_group = matches!(self.group.first(), Some(t) if &t[..self.key_len] == key);
+            if !same_group {
+                self.group.clear();
+                loop {
+                    let right_tuple = match self.right_cache.take() {
+                        Some(t) => t,
+                        None => match self.right.next() {
+                            None => {
+                                self.right_exhausted = true;
+                                break;
+                            }
+                            Some(t) => t?,
+                        },
+                    };
+                    let right_key = &right_tuple[..self.key_len];
+                    if right_key == key {
+                        self.group.push(right_tuple);
+                    } else if right_key > key {
+                        self.right_cache = Some(right_tuple);
+                        break;
+                    }
+                }
+                if self.group.is_empty() && self.right_exhausted {
+                    // later rows of the left side have larger keys
+                    return Ok(None);
+                }
+            }
+            self.left_cache = Some(left_tuple);
+        }
+    }
+}
+
+impl<'a> Iterator for MergeJoinIterator<'a> {
+    type Item = Result<Tuple>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        swap_option_result(self.next_inner())
+    }
+}
+
 fn build_mat_range_iter(
     mat: &[Tuple],
     left_join_indices: &[usize],
@@ -2073,6 +3286,7 @@ impl<'a> Iterator for CachedMaterializedIterator<'a> {
 mod tests {
     use crate::data::value::DataValue;
     use crate::new_cozo_mem;
+    use crate::query::ra::DEFAULT_HASH_JOIN_MAX_ROWS;
 
     #[test]
     fn test_mat_join() {
@@ -2092,4 +3306,123 @@ mod tests {
             vec![vec![DataValue::from(1)], vec![DataValue::from(2)]]
         )
     }
+
+    #[test]
+    fn test_merge_join() {
+        let db = new_cozo_mem().unwrap();
+        db.run_script(
+            r#"
+        {
+            ?[k, j, v] := k in int_range(20), j in int_range(3), v = k * 10 + j
+            :create a {k, j => v}
+        }
+        {
+            ?[k, j, w] := k in int_range(10, 30), j in int_range(2), w = -k - j
+            :create b {k, j => w}
+        }
+        {:create c {k, j}}
+        "#,
+            Default::default(),
+        )
+        .unwrap();
+
+        let merged = "?[v, w] := *a{k, v}, *b{k, w}";
+        let explained = db
+            .run_script(&format!("::explain {{ {merged} }}"), Default::default())
+            .unwrap();
+        let op_idx = explained.headers.iter().position(|h| h == "op").unwrap();
+        assert!(explained
+            .rows
+            .iter()
+            .any(|row| row[op_idx] == DataValue::from("merge_join")));
+        let merged = db.run_script(merged, Default::default()).unwrap().rows;
+        // duplicate keys on both sides give all pairs
+        assert_eq!(merged.len(), 10 * 3 * 2);
+
+        // the same join, hashed as the join key of the right side is not its prefix
+        let materialized = db
+            .run_script(
+                r#"
+        r[j, k, w] := *b{k, j, w}
+        ?[v, w] := *a{k, v}, r[_, k, w]
+        "#,
+                Default::default(),
+            )
+            .unwrap();
+        let explained = db
+            .run_script(
+                "::explain { r[j, k, w] := *b{k, j, w} ?[v, w] := *a{k, v}, r[_, k, w] }",
+                Default::default(),
+            )
+            .unwrap();
+        assert!(explained
+            .rows
+            .iter()
+            .any(|row| row[op_idx] == DataValue::from("mem_hash_join")));
+        assert_eq!(merged, materialized.rows);
+
+        let empty = db
+            .run_script("?[v, j] := *a{k, v}, *c{k, j}", Default::default())
+            .unwrap();
+        assert!(empty.rows.is_empty());
+        let empty = db
+            .run_script("?[v, j] := *c{k, j}, *a{k, v}", Default::default())
+            .unwrap();
+        assert!(empty.rows.is_empty());
+    }
+
+    #[test]
+    fn test_hash_join() {
+        let setup = |hash_join_max_rows: usize| {
+            let mut db = new_cozo_mem().unwrap();
+            db.set_hash_join_max_rows(hash_join_max_rows);
+            db.run_script(
+                r#"
+            {
+                ?[id, k, v] <- [[1, 1, 'a1'], [2, 1, 'a2'], [3, null, 'a3'], [4, 2, 'a4'],
+                                [5, [null, 1], 'a5']]
+                :create a {id => k, v}
+            }
+            {
+                ?[id, k, w] <- [[1, 1, 'b1'], [2, 1, 'b2'], [3, null, 'b3'], [4, 3, 'b4'],
+                                [5, [null, 1], 'b5']]
+                :create b {id => k, w}
+            }
+            "#,
+                Default::default(),
+            )
+            .unwrap();
+            db
+        };
+        let query = "?[v, w] := *a{k, v}, *b{k, w}";
+
+        let db = setup(DEFAULT_HASH_JOIN_MAX_ROWS);
+        let explained = db
+            .run_script(&format!("::explain {{ {query} }}"), Default::default())
+            .unwrap();
+        let op_idx = explained.headers.iter().position(|h| h == "op").unwrap();
+        assert!(explained
+            .rows
+            .iter()
+            .any(|row| row[op_idx] == DataValue::from("stored_hash_join")));
+        let hashed = db.run_script(query, Default::default()).unwrap().rows;
+        // duplicate keys give all pairs, and null keys are equal to each other
+        assert_eq!(
+            hashed,
+            [
+                ["a1", "b1"],
+                ["a1", "b2"],
+                ["a2", "b1"],
+                ["a2", "b2"],
+                ["a3", "b3"],
+                ["a5", "b5"]
+            ]
+            .map(|row| row.map(DataValue::from).to_vec())
+        );
+
+        // too many rows on the right for a hash table
+        let db = setup(2);
+        let materialized = db.run_script(query, Default::default()).unwrap().rows;
+        assert_eq!(hashed, materialized);
+    }
 }
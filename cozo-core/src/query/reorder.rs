@@ -71,6 +71,30 @@ impl NormalFormInlineRule {
                 NormalFormAtom::Predicate(p) => {
                     pending.push(NormalFormAtom::Predicate(p));
                 }
+                NormalFormAtom::HnswSearch(s) => {
+                    if s.query.bindings().is_subset(&seen_variables) {
+                        seen_variables.extend(s.all_bindings().cloned());
+                        round_1_collected.push(NormalFormAtom::HnswSearch(s));
+                    } else {
+                        pending.push(NormalFormAtom::HnswSearch(s));
+                    }
+                }
+                NormalFormAtom::FtsSearch(s) => {
+                    if s.query.bindings().is_subset(&seen_variables) {
+                        seen_variables.extend(s.all_bindings().cloned());
+                        round_1_collected.push(NormalFormAtom::FtsSearch(s));
+                    } else {
+                        pending.push(NormalFormAtom::FtsSearch(s));
+                    }
+                }
+                NormalFormAtom::LshSearch(s) => {
+                    if s.query.bindings().is_subset(&seen_variables) {
+                        seen_variables.extend(s.all_bindings().cloned());
+                        round_1_collected.push(NormalFormAtom::LshSearch(s));
+                    } else {
+                        pending.push(NormalFormAtom::LshSearch(s));
+                    }
+                }
             }
         }
 
@@ -98,6 +122,18 @@ impl NormalFormInlineRule {
                     seen_variables.insert(u.binding.clone());
                     collected.push(NormalFormAtom::Unification(u));
                 }
+                NormalFormAtom::HnswSearch(s) => {
+                    seen_variables.extend(s.all_bindings().cloned());
+                    collected.push(NormalFormAtom::HnswSearch(s));
+                }
+                NormalFormAtom::FtsSearch(s) => {
+                    seen_variables.extend(s.all_bindings().cloned());
+                    collected.push(NormalFormAtom::FtsSearch(s));
+                }
+                NormalFormAtom::LshSearch(s) => {
+                    seen_variables.extend(s.all_bindings().cloned());
+                    collected.push(NormalFormAtom::LshSearch(s));
+                }
             }
             for atom in last_pending.iter() {
                 match atom {
@@ -130,6 +166,30 @@ impl NormalFormInlineRule {
                             pending.push(NormalFormAtom::Unification(u.clone()));
                         }
                     }
+                    NormalFormAtom::HnswSearch(s) => {
+                        if s.query.bindings().is_subset(&seen_variables) {
+                            seen_variables.extend(s.all_bindings().cloned());
+                            collected.push(NormalFormAtom::HnswSearch(s.clone()));
+                        } else {
+                            pending.push(NormalFormAtom::HnswSearch(s.clone()));
+                        }
+                    }
+                    NormalFormAtom::FtsSearch(s) => {
+                        if s.query.bindings().is_subset(&seen_variables) {
+                            seen_variables.extend(s.all_bindings().cloned());
+                            collected.push(NormalFormAtom::FtsSearch(s.clone()));
+                        } else {
+                            pending.push(NormalFormAtom::FtsSearch(s.clone()));
+                        }
+                    }
+                    NormalFormAtom::LshSearch(s) => {
+                        if s.query.bindings().is_subset(&seen_variables) {
+                            seen_variables.extend(s.all_bindings().cloned());
+                            collected.push(NormalFormAtom::LshSearch(s.clone()));
+                        } else {
+                            pending.push(NormalFormAtom::LshSearch(s.clone()));
+                        }
+                    }
                 }
             }
         }
@@ -158,6 +218,15 @@ impl NormalFormInlineRule {
                     NormalFormAtom::Unification(u) => {
                         bail!(UnboundVariable(u.span))
                     }
+                    NormalFormAtom::HnswSearch(s) => {
+                        bail!(UnboundVariable(s.span))
+                    }
+                    NormalFormAtom::FtsSearch(s) => {
+                        bail!(UnboundVariable(s.span))
+                    }
+                    NormalFormAtom::LshSearch(s) => {
+                        bail!(UnboundVariable(s.span))
+                    }
                 }
             }
         }
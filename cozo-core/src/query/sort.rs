@@ -8,45 +8,434 @@
 
 use std::cmp::Ordering;
 use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use std::sync::atomic;
 
 use itertools::Itertools;
-use miette::Result;
+use miette::{IntoDiagnostic, Result};
 
 use crate::data::program::SortDir;
 use crate::data::symb::Symbol;
 use crate::data::tuple::Tuple;
+use crate::data::value::DataValue;
 use crate::runtime::temp_store::EpochStore;
 use crate::runtime::transact::SessionTx;
 
+/// Sorted rows, possibly merged from runs spilled to disk.
+pub(crate) type SortedTuples = Box<dyn Iterator<Item = Result<Tuple>>>;
+
+/// Controls when sorting the output spills to disk.
+#[derive(Clone, Debug)]
+pub(crate) struct SortOptions {
+    /// approximate number of bytes of rows held in memory before a sorted run is spilled
+    pub(crate) memory_budget: usize,
+    /// directory receiving the spilled runs
+    pub(crate) spill_dir: PathBuf,
+}
+
+impl Default for SortOptions {
+    fn default() -> Self {
+        Self {
+            memory_budget: 256 * 1024 * 1024,
+            spill_dir: std::env::temp_dir(),
+        }
+    }
+}
+
+/// With a limit, rows beyond the first `limit` are dropped whenever the buffer
+/// holds this many times as many rows.
+const TOP_K_SLACK: usize = 2;
+
 impl<'a> SessionTx<'a> {
+    /// Sorts the rows of `original`, see [ExternalSorter]. The files of the spilled runs
+    /// are removed when the returned iterator is dropped.
     pub(crate) fn sort_and_collect(
-        &mut self,
+        &self,
         original: EpochStore,
         sorters: &[(Symbol, SortDir)],
         head: &[Symbol],
-    ) -> Result<Vec<Tuple>> {
+        num_to_take: Option<usize>,
+        options: &SortOptions,
+    ) -> Result<SortedTuples> {
+        let mut sorter = ExternalSorter::new(sorters, head, num_to_take, options);
+        for tuple in original.all_iter() {
+            sorter.push(self, tuple.into_tuple())?;
+        }
+        sorter.finish(self)
+    }
+}
+
+/// Sorts rows as they are pushed, dropping duplicates. If only the first `num_to_take` rows
+/// are needed, only the best rows seen so far are kept. Otherwise, when the rows take more
+/// memory than allowed, sorted runs are written to disk and merged when iterating.
+pub(crate) struct ExternalSorter {
+    comparator: TupleComparator,
+    num_to_take: Option<usize>,
+    options: SortOptions,
+    runs: Vec<SpilledRun>,
+    buffer: Vec<Tuple>,
+    buffered_bytes: usize,
+}
+
+impl ExternalSorter {
+    pub(crate) fn new(
+        sorters: &[(Symbol, SortDir)],
+        head: &[Symbol],
+        num_to_take: Option<usize>,
+        options: &SortOptions,
+    ) -> Self {
         let head_indices: BTreeMap<_, _> = head.iter().enumerate().map(|(i, k)| (k, i)).collect();
-        let idx_sorters = sorters
-            .iter()
-            .map(|(k, dir)| (head_indices[k], *dir))
-            .collect_vec();
-
-        let mut all_data: Vec<_> = original.all_iter().map(|v| v.into_tuple()).collect_vec();
-        all_data.sort_by(|a, b| {
-            for (idx, dir) in &idx_sorters {
-                match a[*idx].cmp(&b[*idx]) {
-                    Ordering::Equal => {}
-                    o => {
-                        return match dir {
-                            SortDir::Asc => o,
-                            SortDir::Dsc => o.reverse(),
-                        }
+        let comparator = TupleComparator(
+            sorters
+                .iter()
+                .map(|(k, dir)| (head_indices[k], *dir))
+                .collect_vec(),
+        );
+        Self {
+            comparator,
+            num_to_take,
+            options: options.clone(),
+            runs: vec![],
+            buffer: vec![],
+            buffered_bytes: 0,
+        }
+    }
+    pub(crate) fn push(&mut self, tx: &SessionTx<'_>, tuple: Tuple) -> Result<()> {
+        if let Some(k) = self.num_to_take {
+            if k > 0 {
+                self.buffer.push(tuple);
+                if self.buffer.len() >= k.saturating_mul(TOP_K_SLACK) {
+                    self.sort_buffer();
+                    self.buffer.truncate(k);
+                }
+            }
+            return Ok(());
+        }
+
+        self.buffered_bytes += approx_tuple_size(&tuple);
+        self.buffer.push(tuple);
+        if cfg!(not(target_arch = "wasm32")) && self.buffered_bytes > self.options.memory_budget {
+            tx.track_memory(self.buffered_bytes);
+            self.sort_buffer();
+            self.runs
+                .push(SpilledRun::write(&self.options.spill_dir, &self.buffer)?);
+            tx.spilled_sort_runs.fetch_add(1, atomic::Ordering::Relaxed);
+            self.buffer.clear();
+            self.buffered_bytes = 0;
+        }
+        Ok(())
+    }
+    /// The rows pushed, in order and without duplicates.
+    pub(crate) fn finish(mut self, tx: &SessionTx<'_>) -> Result<SortedTuples> {
+        tx.track_memory(self.buffered_bytes);
+        self.sort_buffer();
+        if let Some(k) = self.num_to_take {
+            self.buffer.truncate(k);
+        }
+        if self.runs.is_empty() {
+            return Ok(Box::new(self.buffer.into_iter().map(Ok)));
+        }
+
+        let mut sources: Vec<SortedTuples> = Vec::with_capacity(self.runs.len() + 1);
+        for run in self.runs {
+            sources.push(Box::new(run.into_reader()?));
+        }
+        sources.push(Box::new(self.buffer.into_iter().map(Ok)));
+        let comparator = self.comparator;
+        let merged = sources
+            .into_iter()
+            .kmerge_by(move |a: &Result<Tuple>, b: &Result<Tuple>| match (a, b) {
+                (Ok(a), Ok(b)) => comparator.compare(a, b) == Ordering::Less,
+                // errors surface as early as possible
+                (Err(_), _) => true,
+                (Ok(_), Err(_)) => false,
+            });
+        // the same row may be in several runs
+        Ok(Box::new(merged.dedup_by(|a, b| match (a, b) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => false,
+        })))
+    }
+    fn sort_buffer(&mut self) {
+        let comparator = &self.comparator;
+        self.buffer.sort_by(|a, b| comparator.compare(a, b));
+        self.buffer.dedup();
+    }
+}
+
+/// Orders tuples by the sort keys, then by the whole tuple so that the order is total
+/// and does not depend on how the rows were split into runs.
+struct TupleComparator(Vec<(usize, SortDir)>);
+
+impl TupleComparator {
+    fn compare(&self, a: &Tuple, b: &Tuple) -> Ordering {
+        for (idx, dir) in &self.0 {
+            match a[*idx].cmp(&b[*idx]) {
+                Ordering::Equal => {}
+                o => {
+                    return match dir {
+                        SortDir::Asc => o,
+                        SortDir::Dsc => o.reverse(),
                     }
                 }
             }
-            Ordering::Equal
-        });
+        }
+        a.cmp(b)
+    }
+}
+
+pub(crate) fn approx_tuple_size(tuple: &Tuple) -> usize {
+    size_of::<Tuple>() + tuple.iter().map(approx_value_size).sum::<usize>()
+}
+
+fn approx_value_size(val: &DataValue) -> usize {
+    size_of::<DataValue>()
+        + match val {
+            DataValue::Str(s) => s.len(),
+            DataValue::Bytes(b) => b.len(),
+            DataValue::List(l) => l.iter().map(approx_value_size).sum(),
+            DataValue::Set(s) => s.iter().map(approx_value_size).sum(),
+            _ => 0,
+        }
+}
+
+/// A sorted run written to a file, which is removed when this is dropped.
+struct SpilledRun {
+    path: PathBuf,
+    len: usize,
+}
+
+impl SpilledRun {
+    fn write(dir: &Path, sorted: &[Tuple]) -> Result<Self> {
+        let run = SpilledRun {
+            path: dir.join(format!("cozo-sort-{:016x}.run", rand::random::<u64>())),
+            len: sorted.len(),
+        };
+        let mut writer = BufWriter::new(File::create(&run.path).into_diagnostic()?);
+        for tuple in sorted {
+            rmp_serde::encode::write(&mut writer, tuple).into_diagnostic()?;
+        }
+        writer.flush().into_diagnostic()?;
+        Ok(run)
+    }
+    fn into_reader(self) -> Result<SpilledRunReader> {
+        let reader = BufReader::new(File::open(&self.path).into_diagnostic()?);
+        Ok(SpilledRunReader {
+            remaining: self.len,
+            reader,
+            _run: self,
+        })
+    }
+}
+
+impl Drop for SpilledRun {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+struct SpilledRunReader {
+    remaining: usize,
+    reader: BufReader<File>,
+    _run: SpilledRun,
+}
+
+impl Iterator for SpilledRunReader {
+    type Item = Result<Tuple>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let decoded = rmp_serde::decode::from_read(&mut self.reader).into_diagnostic();
+        if decoded.is_err() {
+            self.remaining = 0;
+        }
+        Some(decoded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::ControlFlow;
+    use std::sync::atomic::Ordering;
+
+    use crate::data::functions::current_validity;
+    use crate::data::tuple::Tuple;
+    use crate::parse::parse_script;
+    use crate::{Db, MemStorage, NamedRows};
+
+    #[test]
+    fn test_external_sort() {
+        let spill_dir = std::env::temp_dir().join(format!("cozo_sort_{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&spill_dir).unwrap();
+        let storage = MemStorage::default();
+        let reference = Db::new(storage.clone()).unwrap();
+        reference.initialize().unwrap();
+        let mut db = Db::new(storage).unwrap();
+        db.set_sort_memory_budget(4096);
+        db.set_sort_spill_dir(&spill_dir);
+        db.initialize().unwrap();
+        db.run_script(
+            r"?[i, k, s] := i in int_range(3000), k = i % 7, s = to_string(i % 13)
+          :create nums {i => k, s}",
+            Default::default(),
+        )
+        .unwrap();
+
+        let run_counting_spills = |script: &str| -> (miette::Result<NamedRows>, usize) {
+            let cur_vld = current_validity();
+            let program = parse_script(
+                script,
+                &Default::default(),
+                &db.fixed_rules.read().unwrap(),
+                cur_vld,
+            )
+            .unwrap()
+            .get_single_program()
+            .unwrap();
+            let mut tx = db.transact_write().unwrap();
+            let res = db
+                .run_query(
+                    &mut tx,
+                    program,
+                    cur_vld,
+                    &Default::default(),
+                    &mut Default::default(),
+                    true,
+                )
+                .map(|(rows, _)| rows);
+            let spilled = tx.spilled_sort_runs.load(Ordering::Relaxed);
+            if res.is_ok() {
+                tx.commit_tx().unwrap();
+            }
+            (res, spilled)
+        };
+
+        for opts in [":order k, -s, i", ":order -s, k", ":order k, s :offset 100"] {
+            let script = format!("?[i, k, s] := *nums[i, k, s] {opts}");
+            let (res, spilled) = run_counting_spills(&script);
+            assert!(spilled > 10, "{opts}");
+            let expected = reference.run_script(&script, Default::default()).unwrap();
+            assert_eq!(res.unwrap().rows, expected.rows, "{opts}");
+        }
+
+        // with a limit, only the top rows are kept
+        let script = "?[i, k, s] := *nums[i, k, s] :order -k, s :limit 20 :offset 5";
+        let (res, spilled) = run_counting_spills(script);
+        assert_eq!(spilled, 0);
+        let res = res.unwrap();
+        assert_eq!(res.rows.len(), 20);
+        let expected = reference.run_script(script, Default::default()).unwrap();
+        assert_eq!(res.rows, expected.rows);
+
+        // sorted rows stored into a relation
+        let (res, spilled) =
+            run_counting_spills("?[i, k] := *nums[i, k, _] :order k :replace sorted {i => k}");
+        res.unwrap();
+        assert!(spilled > 0);
+        let stored = db
+            .run_script("?[i, k] := *sorted[i, k]", Default::default())
+            .unwrap();
+        assert_eq!(stored.rows.len(), 3000);
+
+        // failing in the middle of consuming the merged runs
+        let (res, spilled) =
+            run_counting_spills("?[i, s] := *nums[i, _, s] :order s :replace bad {i => s: Int}");
+        assert!(res.is_err());
+        assert!(spilled > 0);
+
+        assert_eq!(std::fs::read_dir(&spill_dir).unwrap().count(), 0);
+        std::fs::remove_dir(&spill_dir).unwrap();
+    }
+
+    #[test]
+    fn test_streaming_external_sort() {
+        let spill_dir = std::env::temp_dir().join(format!("cozo_sort_{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&spill_dir).unwrap();
+        let storage = MemStorage::default();
+        let reference = Db::new(storage.clone()).unwrap();
+        reference.initialize().unwrap();
+        let mut db = Db::new(storage).unwrap();
+        db.set_sort_memory_budget(4096);
+        db.set_sort_spill_dir(&spill_dir);
+        db.initialize().unwrap();
+        db.run_script(
+            r"?[i, k, s] := i in int_range(3000), k = i % 7, s = to_string(i % 13)
+          :create nums {i => k, s}",
+            Default::default(),
+        )
+        .unwrap();
+
+        // also counts the rows pushed into the sorter as they were derived
+        let stream = |script: &str| -> (Vec<Tuple>, usize, usize) {
+            let cur_vld = current_validity();
+            let program = parse_script(
+                script,
+                &Default::default(),
+                &db.fixed_rules.read().unwrap(),
+                cur_vld,
+            )
+            .unwrap()
+            .get_single_program()
+            .unwrap();
+            let mut tx = db.transact().unwrap();
+            let prepared = db.prepare_query(&mut tx, program).unwrap();
+            let mut rows = vec![];
+            db.evaluate_prepared_query(
+                &mut tx,
+                prepared,
+                cur_vld,
+                &Default::default(),
+                &mut Default::default(),
+                true,
+                Some(&mut |row| {
+                    rows.push(row);
+                    Ok(ControlFlow::Continue(()))
+                }),
+            )
+            .unwrap();
+            let streamed = tx.streamed_rows.load(Ordering::Relaxed);
+            let spilled = tx.spilled_sort_runs.load(Ordering::Relaxed);
+            tx.commit_tx().unwrap();
+            (rows, streamed, spilled)
+        };
+
+        for script in [
+            "?[i, k, s] := *nums[i, k, s]",
+            "?[i, k, s] := *nums[i, k, s] :order k, -s, i",
+            "?[i, s] := *nums[i, _, s] :order -s :offset 100",
+            "?[k] := *nums[_, k, _]",
+            "?[i, s] := *nums[i, _, s] :order s, i :window row_number() as n partition by s",
+            "r[i] := *nums[i, 0, _]
+             r[j] := r[i], j = i + 1, j < 3000, j % 7 != 0
+             ?[i, s] := r[i], *nums[i, _, s] :order s",
+        ] {
+            let (rows, streamed, spilled) = stream(script);
+            let expected = reference.run_script(script, Default::default()).unwrap();
+            assert_eq!(rows, expected.rows, "{script}");
+            assert!(streamed >= 3000 && spilled > 10, "{script}");
+        }
+
+        // with a limit, only the top rows are kept
+        let script = "?[i, k, s] := *nums[i, k, s] :order -k, s :limit 20 :offset 5";
+        let (rows, streamed, spilled) = stream(script);
+        let expected = reference.run_script(script, Default::default()).unwrap();
+        assert_eq!(rows, expected.rows);
+        assert_eq!((streamed, spilled), (3000, 0));
+
+        // aggregations are collected first
+        let script = "?[k, count(i)] := *nums[i, k, _] :order -k";
+        let (rows, streamed, _) = stream(script);
+        let expected = reference.run_script(script, Default::default()).unwrap();
+        assert_eq!(rows, expected.rows);
+        assert_eq!(streamed, 0);
 
-        Ok(all_data)
+        assert_eq!(std::fs::read_dir(&spill_dir).unwrap().count(), 0);
+        std::fs::remove_dir(&spill_dir).unwrap();
     }
 }
@@ -9,6 +9,7 @@
 use std::collections::{BTreeMap, BTreeSet};
 use std::sync::Arc;
 
+use crossbeam::channel::Receiver;
 use itertools::Itertools;
 use miette::{bail, Diagnostic, Result, WrapErr};
 use smartstring::{LazyCompact, SmartString};
@@ -22,10 +23,11 @@ use crate::data::tuple::Tuple;
 use crate::data::value::{DataValue, ValidityTs};
 use crate::fixed_rule::utilities::constant::Constant;
 use crate::fixed_rule::FixedRuleHandle;
-use crate::parse::parse_script;
+use crate::parse::{parse_expression, parse_script, SourceSpan};
 use crate::runtime::callback::{CallbackCollector, CallbackOp};
 use crate::runtime::relation::{
-    extend_tuple_from_v, AccessLevel, InputRelationHandle, InsufficientAccessLevel,
+    decode_tuple_from_kv, extend_tuple_from_v, AccessLevel, InputRelationHandle,
+    InsufficientAccessLevel, RelationHandle,
 };
 use crate::runtime::transact::SessionTx;
 use crate::storage::Storage;
@@ -36,6 +38,42 @@ use crate::{Db, NamedRows, StoreTx};
 #[diagnostic(code(eval::relation_arity_mismatch))]
 struct RelationArityMismatch(String, usize, usize);
 
+/// How many triggers may run within each other, as the writes of a trigger set off
+/// the triggers of the relations written to
+pub(crate) const MAX_TRIGGER_DEPTH: usize = 32;
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("triggers of relation {0} nested too deeply")]
+#[diagnostic(code(eval::trigger_depth_exceeded))]
+#[diagnostic(help(
+    "Triggers writing to relations with triggers of their own may set off each other without end"
+))]
+struct TriggerDepthExceeded(String);
+
+/// The numbers of rows changed by writing into a stored relation
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct MutationCounts {
+    /// rows written whose keys were not in the relation
+    pub(crate) inserted: usize,
+    /// rows written over the rows with the same keys
+    pub(crate) overwritten: usize,
+    /// rows removed, not counting keys not found
+    pub(crate) removed: usize,
+}
+
+impl MutationCounts {
+    pub(crate) fn rows_affected(&self) -> usize {
+        self.inserted + self.overwritten + self.removed
+    }
+    fn count_write(&mut self, existed: bool) {
+        if existed {
+            self.overwritten += 1;
+        } else {
+            self.inserted += 1;
+        }
+    }
+}
+
 impl<'a> SessionTx<'a> {
     pub(crate) fn execute_relation<'s, S: Storage<'s>>(
         &mut self,
@@ -47,12 +85,15 @@ impl<'a> SessionTx<'a> {
         cur_vld: ValidityTs,
         callback_targets: &BTreeSet<SmartString<LazyCompact>>,
         callback_collector: &mut CallbackCollector,
-        propagate_triggers: bool,
-    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        top_level: bool,
+    ) -> Result<(Vec<(Vec<u8>, Vec<u8>)>, MutationCounts)> {
+        // the statistics kept for estimates no longer hold
+        db.estimate_stats.invalidate(&meta.name.name);
         let mut to_clear = vec![];
+        let mut counts = MutationCounts::default();
         let mut replaced_old_triggers = None;
         if op == RelationOp::Replace {
-            if !propagate_triggers {
+            if !top_level {
                 #[derive(Debug, Error, Diagnostic)]
                 #[error("replace op in trigger is not allowed: {0}")]
                 #[diagnostic(code(eval::replace_in_trigger))]
@@ -60,7 +101,7 @@ impl<'a> SessionTx<'a> {
                 bail!(ReplaceInTrigger(meta.name.to_string()))
             }
             if let Ok(old_handle) = self.get_relation(&meta.name, true) {
-                if !old_handle.indices.is_empty() {
+                if old_handle.has_user_indices() {
                     #[derive(Debug, Error, Diagnostic)]
                     #[error("cannot replace relation {0} since it has indices")]
                     #[diagnostic(code(eval::replace_rel_with_indices))]
@@ -105,7 +146,13 @@ impl<'a> SessionTx<'a> {
                     to_clear.extend(cleanups);
                 }
 
-                to_clear.push(self.destroy_relation(&meta.name)?);
+                if old_handle.is_temp {
+                    // temp relations are gone with the transaction, but may be replaced
+                    // many times by loops before then
+                    self.clear_temp_relation(old_handle.id)?;
+                } else {
+                    to_clear.extend(self.destroy_relation(&meta.name)?);
+                }
             }
         }
         let mut relation_store = if op == RelationOp::Replace || op == RelationOp::Create {
@@ -122,10 +169,19 @@ impl<'a> SessionTx<'a> {
             key_bindings,
             dep_bindings,
             span,
+            params,
             ..
         } = meta;
 
         let is_callback_target = callback_targets.contains(&relation_store.name);
+        if is_callback_target && op == RelationOp::Replace {
+            // replacing changes the relation even if no row is put
+            callback_collector
+                .entry(relation_store.name.clone())
+                .or_default();
+        }
+
+        let foreign_keys = self.foreign_keys(&relation_store)?;
 
         match op {
             RelationOp::Rm => {
@@ -141,144 +197,61 @@ impl<'a> SessionTx<'a> {
                     &metadata.keys,
                     key_bindings,
                     headers,
+                    None,
                 )?;
 
                 let need_to_collect = !relation_store.is_temp
-                    && (is_callback_target
-                        || (propagate_triggers && !relation_store.rm_triggers.is_empty()));
-                let has_indices = !relation_store.indices.is_empty();
-                let mut new_tuples: Vec<DataValue> = vec![];
-                let mut old_tuples: Vec<DataValue> = vec![];
+                    && (is_callback_target || !relation_store.rm_triggers.is_empty());
+                let has_indices = relation_store.has_indices();
 
-                for tuple in res_iter {
-                    let extracted = key_extractors
-                        .iter()
-                        .map(|ex| ex.extract_data(&tuple, cur_vld))
-                        .try_collect()?;
-                    let key = relation_store.encode_key_for_store(&extracted, *span)?;
-                    if need_to_collect || has_indices {
-                        if let Some(existing) = self.store_tx.get(&key, false)? {
+                for batch in &res_iter.chunks(db.mutation_batch_size) {
+                    let rows =
+                        extract_batch(&relation_store, batch, &key_extractors, cur_vld, *span)?;
+                    if relation_store.audit.writes {
+                        self.audit_writes(&relation_store.name, rows.len());
+                    }
+                    let existing = self.fetch_old_images(&relation_store, &rows)?;
+                    let mut new_tuples = vec![];
+                    let mut old_tuples = vec![];
+
+                    for ((key, extracted), existing) in rows.into_iter().zip(existing) {
+                        let removed = existing.is_some();
+                        if let Some(existing) = existing {
+                            counts.removed += 1;
                             let mut tup = extracted.clone();
-                            extend_tuple_from_v(&mut tup, &existing);
+                            extend_tuple_from_v(&mut tup, &existing)?;
                             if has_indices {
-                                for (idx_rel, extractor) in relation_store.indices.values() {
-                                    let idx_tup =
-                                        extractor.iter().map(|i| tup[*i].clone()).collect_vec();
-                                    let encoded = idx_rel
-                                        .encode_key_for_store(&idx_tup, Default::default())?;
-                                    self.store_tx.del(&encoded)?;
-                                }
+                                self.delete_from_indices(&relation_store, &tup)?;
                             }
                             if need_to_collect {
-                                old_tuples.push(DataValue::List(tup));
+                                old_tuples.push(tup);
                             }
                         }
-                        if need_to_collect {
-                            new_tuples.push(DataValue::List(extracted.clone()));
+                        if relation_store.is_temp {
+                            self.temp_store_tx.del(&key)?;
+                        } else {
+                            self.store_tx.del(&key)?;
                         }
-                    }
-                    if relation_store.is_temp {
-                        self.temp_store_tx.del(&key)?;
-                    } else {
-                        self.store_tx.del(&key)?;
-                    }
-                }
-
-                // triggers and callbacks
-                if need_to_collect && !new_tuples.is_empty() {
-                    let k_bindings = relation_store
-                        .metadata
-                        .keys
-                        .iter()
-                        .map(|k| Symbol::new(k.name.clone(), Default::default()))
-                        .collect_vec();
-
-                    let v_bindings = relation_store
-                        .metadata
-                        .non_keys
-                        .iter()
-                        .map(|k| Symbol::new(k.name.clone(), Default::default()));
-                    let mut kv_bindings = k_bindings.clone();
-                    kv_bindings.extend(v_bindings);
-                    let kv_bindings = kv_bindings;
-
-                    if propagate_triggers {
-                        for trigger in &relation_store.rm_triggers {
-                            let mut program = parse_script(
-                                trigger,
-                                &Default::default(),
-                                &db.fixed_rules.read().unwrap(),
-                                cur_vld,
-                            )?
-                            .get_single_program()?;
-
-                            make_const_rule(
-                                &mut program,
-                                "_new",
-                                k_bindings.clone(),
-                                new_tuples.clone(),
-                            );
-
-                            make_const_rule(
-                                &mut program,
-                                "_old",
-                                kv_bindings.clone(),
-                                old_tuples.clone(),
-                            );
-
-                            let (_, cleanups) = db
-                                .run_query(
-                                    self,
-                                    program,
-                                    cur_vld,
-                                    callback_targets,
-                                    callback_collector,
-                                    false,
-                                )
-                                .map_err(|err| {
-                                    if err.source_code().is_some() {
-                                        err
-                                    } else {
-                                        err.with_source_code(trigger.to_string())
-                                    }
-                                })?;
-                            to_clear.extend(cleanups);
+                        if removed && foreign_keys.has_incoming() {
+                            self.remove_references(&foreign_keys, &extracted)?;
+                        }
+                        if need_to_collect {
+                            new_tuples.push(extracted);
                         }
                     }
 
-                    if is_callback_target {
-                        let target_collector = callback_collector
-                            .entry(relation_store.name.clone())
-                            .or_default();
-                        target_collector.push((
+                    if need_to_collect && !new_tuples.is_empty() {
+                        to_clear.extend(self.propagate_batch(
+                            db,
+                            &relation_store,
                             CallbackOp::Rm,
-                            NamedRows::new(
-                                k_bindings
-                                    .into_iter()
-                                    .map(|k| k.name.to_string())
-                                    .collect_vec(),
-                                new_tuples
-                                    .into_iter()
-                                    .map(|v| match v {
-                                        DataValue::List(l) => l,
-                                        _ => unreachable!(),
-                                    })
-                                    .collect_vec(),
-                            ),
-                            NamedRows::new(
-                                kv_bindings
-                                    .into_iter()
-                                    .map(|k| k.name.to_string())
-                                    .collect_vec(),
-                                old_tuples
-                                    .into_iter()
-                                    .map(|v| match v {
-                                        DataValue::List(l) => l,
-                                        _ => unreachable!(),
-                                    })
-                                    .collect_vec(),
-                            ),
-                        ))
+                            params,
+                            new_tuples,
+                            old_tuples,
+                            cur_vld,
+                            callback_targets,
+                            callback_collector,
+                        )?);
                     }
                 }
             }
@@ -296,6 +269,7 @@ impl<'a> SessionTx<'a> {
                     &metadata.keys,
                     key_bindings,
                     headers,
+                    None,
                 )?;
 
                 let val_extractors = make_extractors(
@@ -303,6 +277,7 @@ impl<'a> SessionTx<'a> {
                     &metadata.non_keys,
                     dep_bindings,
                     headers,
+                    None,
                 )?;
                 key_extractors.extend(val_extractors);
 
@@ -355,6 +330,7 @@ impl<'a> SessionTx<'a> {
                     &metadata.keys,
                     key_bindings,
                     headers,
+                    None,
                 )?;
 
                 for tuple in res_iter {
@@ -386,184 +362,595 @@ impl<'a> SessionTx<'a> {
                     ));
                 }
 
+                // values given when creating or replacing a relation are kept, e.g. when restoring
+                let put_auto_update = Some(AutoUpdateCtx {
+                    params,
+                    reject_override: op == RelationOp::Put,
+                });
                 let mut key_extractors = make_extractors(
                     &relation_store.metadata.keys,
                     &metadata.keys,
                     key_bindings,
                     headers,
+                    put_auto_update,
                 )?;
 
                 let need_to_collect = !relation_store.is_temp
-                    && (is_callback_target
-                        || (propagate_triggers && !relation_store.put_triggers.is_empty()));
-                let has_indices = !relation_store.indices.is_empty();
-                let mut new_tuples: Vec<DataValue> = vec![];
-                let mut old_tuples: Vec<DataValue> = vec![];
+                    && (is_callback_target || !relation_store.put_triggers.is_empty());
+                let has_indices = relation_store.has_indices();
+                let trims_history = relation_store.history_retention.is_some();
+                let n_keys = relation_store.metadata.keys.len();
 
                 let val_extractors = make_extractors(
                     &relation_store.metadata.non_keys,
                     &metadata.non_keys,
                     dep_bindings,
                     headers,
+                    put_auto_update,
                 )?;
                 key_extractors.extend(val_extractors);
 
-                for tuple in res_iter {
-                    let extracted = key_extractors
-                        .iter()
-                        .map(|ex| ex.extract_data(&tuple, cur_vld))
-                        .try_collect()?;
-
-                    let key = relation_store.encode_key_for_store(&extracted, *span)?;
-                    let val = relation_store.encode_val_for_store(&extracted, *span)?;
+                for batch in &res_iter.chunks(db.mutation_batch_size) {
+                    let rows =
+                        extract_batch(&relation_store, batch, &key_extractors, cur_vld, *span)?;
+                    if relation_store.audit.writes {
+                        self.audit_writes(&relation_store.name, rows.len());
+                    }
+                    let existing = self.fetch_old_images(&relation_store, &rows)?;
+                    let mut new_tuples = vec![];
+                    let mut old_tuples = vec![];
+                    // keys are only mutable to clippy for the regex a `DataValue` may hold
+                    #[allow(clippy::mutable_key_type)]
+                    let mut written_keys = BTreeSet::new();
 
-                    if need_to_collect || has_indices {
-                        if let Some(existing) = self.store_tx.get(&key, false)? {
-                            let mut tup = extracted[0..relation_store.metadata.keys.len()].to_vec();
-                            extend_tuple_from_v(&mut tup, &existing);
-                            if has_indices && extracted != tup {
-                                for (idx_rel, extractor) in relation_store.indices.values() {
-                                    let idx_tup_old =
-                                        extractor.iter().map(|i| tup[*i].clone()).collect_vec();
-                                    let encoded_old = idx_rel
-                                        .encode_key_for_store(&idx_tup_old, Default::default())?;
-                                    self.store_tx.del(&encoded_old)?;
-
-                                    let idx_tup_new = extractor
-                                        .iter()
-                                        .map(|i| extracted[*i].clone())
-                                        .collect_vec();
-                                    let encoded_new = idx_rel
-                                        .encode_key_for_store(&idx_tup_new, Default::default())?;
-                                    self.store_tx.put(&encoded_new, &[])?;
-                                }
-                            }
+                    for ((key, extracted), existing) in rows.into_iter().zip(existing) {
+                        let val = relation_store.encode_val_for_store(&extracted, *span)?;
+                        counts.count_write(existing.is_some());
 
-                            if need_to_collect {
-                                old_tuples.push(DataValue::List(tup));
-                            }
-                        } else if has_indices {
-                            for (idx_rel, extractor) in relation_store.indices.values() {
-                                let idx_tup_new = extractor
-                                    .iter()
-                                    .map(|i| extracted[*i].clone())
-                                    .collect_vec();
-                                let encoded_new = idx_rel
-                                    .encode_key_for_store(&idx_tup_new, Default::default())?;
-                                self.store_tx.put(&encoded_new, &[])?;
-                            }
+                        let old = existing
+                            .map(|existing| -> Result<Tuple> {
+                                let mut tup = extracted[0..n_keys].to_vec();
+                                extend_tuple_from_v(&mut tup, &existing)?;
+                                Ok(tup)
+                            })
+                            .transpose()?;
+                        self.check_references(&foreign_keys, &extracted)?;
+                        if has_indices {
+                            self.reindex_row(&relation_store, old.as_ref(), &extracted)?;
+                        }
+                        if need_to_collect {
+                            old_tuples.extend(old);
+                        }
+                        if trims_history {
+                            written_keys.insert(extracted[0..n_keys - 1].to_vec());
                         }
 
                         if need_to_collect {
-                            new_tuples.push(DataValue::List(extracted));
+                            new_tuples.push(extracted);
+                        }
+
+                        if relation_store.is_temp {
+                            self.temp_store_tx.put(&key, &val)?;
+                        } else {
+                            self.store_tx.put(&key, &val)?;
                         }
                     }
+                    self.trim_history(&relation_store, &written_keys, cur_vld)?;
 
-                    if relation_store.is_temp {
-                        self.temp_store_tx.put(&key, &val)?;
-                    } else {
-                        self.store_tx.put(&key, &val)?;
+                    if need_to_collect && !new_tuples.is_empty() {
+                        to_clear.extend(self.propagate_batch(
+                            db,
+                            &relation_store,
+                            CallbackOp::Put,
+                            params,
+                            new_tuples,
+                            old_tuples,
+                            cur_vld,
+                            callback_targets,
+                            callback_collector,
+                        )?);
                     }
                 }
+            }
+            RelationOp::Update | RelationOp::Upsert => {
+                if relation_store.access_level < AccessLevel::Protected {
+                    bail!(InsufficientAccessLevel(
+                        relation_store.name.to_string(),
+                        "row update".to_string(),
+                        relation_store.access_level
+                    ));
+                }
 
-                if need_to_collect && !new_tuples.is_empty() {
-                    let mut bindings = relation_store
-                        .metadata
-                        .keys
-                        .iter()
-                        .map(|k| Symbol::new(k.name.clone(), Default::default()))
-                        .collect_vec();
-                    let v_bindings = relation_store
-                        .metadata
-                        .non_keys
-                        .iter()
-                        .map(|k| Symbol::new(k.name.clone(), Default::default()));
-                    bindings.extend(v_bindings);
-
-                    let kv_bindings = bindings;
-                    if propagate_triggers {
-                        for trigger in &relation_store.put_triggers {
-                            let mut program = parse_script(
-                                trigger,
-                                &Default::default(),
-                                &db.fixed_rules.read().unwrap(),
-                                cur_vld,
-                            )?
-                            .get_single_program()?;
-
-                            make_const_rule(
-                                &mut program,
-                                "_new",
-                                kv_bindings.clone(),
-                                new_tuples.clone(),
-                            );
-                            make_const_rule(
-                                &mut program,
-                                "_old",
-                                kv_bindings.clone(),
-                                old_tuples.clone(),
-                            );
-
-                            let (_, cleanups) = db
-                                .run_query(
-                                    self,
-                                    program,
-                                    cur_vld,
-                                    callback_targets,
-                                    callback_collector,
-                                    false,
-                                )
-                                .map_err(|err| {
-                                    if err.source_code().is_some() {
-                                        err
-                                    } else {
-                                        err.with_source_code(trigger.to_string())
+                let auto_update = Some(AutoUpdateCtx {
+                    params,
+                    reject_override: true,
+                });
+                let key_extractors = make_extractors(
+                    &relation_store.metadata.keys,
+                    &metadata.keys,
+                    key_bindings,
+                    headers,
+                    auto_update,
+                )?;
+                // `None` for the columns keeping the values they have
+                let val_extractors: Vec<Option<DataExtractor>> = relation_store
+                    .metadata
+                    .non_keys
+                    .iter()
+                    .map(|col| {
+                        if col.auto_update.is_none()
+                            && !metadata.non_keys.iter().any(|given| given.name == col.name)
+                        {
+                            return Ok(None);
+                        }
+                        make_extractor(col, &metadata.non_keys, dep_bindings, headers, auto_update)
+                            .map(Some)
+                    })
+                    .try_collect()?;
+
+                let need_to_collect = !relation_store.is_temp
+                    && (is_callback_target || !relation_store.put_triggers.is_empty());
+                let has_indices = relation_store.has_indices();
+                let trims_history = relation_store.history_retention.is_some();
+                let n_keys = relation_store.metadata.keys.len();
+
+                for batch in &res_iter.chunks(db.mutation_batch_size) {
+                    let mut new_tuples = vec![];
+                    let mut old_tuples = vec![];
+                    // keys are only mutable to clippy for the regex a `DataValue` may hold
+                    #[allow(clippy::mutable_key_type)]
+                    let mut written_keys = BTreeSet::new();
+                    let mut n_rows = 0;
+
+                    for tuple in batch {
+                        let mut merged: Tuple = key_extractors
+                            .iter()
+                            .map(|ex| ex.extract_data(&tuple, cur_vld))
+                            .try_collect()?;
+                        let key = relation_store.encode_key_for_store(&merged, *span)?;
+                        // rows with the same key earlier in the batch are seen here
+                        let existing = if relation_store.is_temp {
+                            self.temp_store_tx.get(&key, true)?
+                        } else {
+                            self.store_tx.get(&key, true)?
+                        };
+                        let old = match existing {
+                            Some(existing) => {
+                                let mut old = merged.clone();
+                                extend_tuple_from_v(&mut old, &existing)?;
+                                Some(old)
+                            }
+                            None if op == RelationOp::Update => {
+                                bail!(UpdateMissingRow(relation_store.name.to_string(), merged))
+                            }
+                            None => None,
+                        };
+                        for (i, (col, extractor)) in relation_store
+                            .metadata
+                            .non_keys
+                            .iter()
+                            .zip(&val_extractors)
+                            .enumerate()
+                        {
+                            let val = match (extractor, &old) {
+                                (Some(extractor), _) => extractor.extract_data(&tuple, cur_vld)?,
+                                (None, Some(old)) => old[n_keys + i].clone(),
+                                (None, None) => match &col.default_gen {
+                                    Some(expr) => {
+                                        col.typing.coerce(expr.clone().eval_to_const()?, cur_vld)?
                                     }
-                                })?;
-                            to_clear.extend(cleanups);
+                                    None => bail!(UpsertWithoutDefault(
+                                        col.name.to_string(),
+                                        relation_store.name.to_string(),
+                                        merged
+                                    )),
+                                },
+                            };
+                            merged.push(val);
                         }
+
+                        self.check_references(&foreign_keys, &merged)?;
+                        if has_indices {
+                            self.reindex_row(&relation_store, old.as_ref(), &merged)?;
+                        }
+                        let val = relation_store.encode_val_for_store(&merged, *span)?;
+                        if relation_store.is_temp {
+                            self.temp_store_tx.put(&key, &val)?;
+                        } else {
+                            self.store_tx.put(&key, &val)?;
+                        }
+                        n_rows += 1;
+                        counts.count_write(old.is_some());
+                        if trims_history {
+                            written_keys.insert(merged[0..n_keys - 1].to_vec());
+                        }
+
+                        if need_to_collect {
+                            new_tuples.push(merged);
+                            old_tuples.extend(old);
+                        }
+                    }
+                    if relation_store.audit.writes {
+                        self.audit_writes(&relation_store.name, n_rows);
                     }
+                    self.trim_history(&relation_store, &written_keys, cur_vld)?;
 
-                    if is_callback_target {
-                        let target_collector = callback_collector
-                            .entry(relation_store.name.clone())
-                            .or_default();
-                        let headers = kv_bindings
-                            .into_iter()
-                            .map(|k| k.name.to_string())
-                            .collect_vec();
-                        target_collector.push((
+                    if need_to_collect && !new_tuples.is_empty() {
+                        to_clear.extend(self.propagate_batch(
+                            db,
+                            &relation_store,
                             CallbackOp::Put,
-                            NamedRows::new(
-                                headers.clone(),
-                                new_tuples
-                                    .into_iter()
-                                    .map(|v| match v {
-                                        DataValue::List(l) => l,
-                                        _ => unreachable!(),
-                                    })
-                                    .collect_vec(),
-                            ),
-                            NamedRows::new(
-                                headers,
-                                old_tuples
-                                    .into_iter()
-                                    .map(|v| match v {
-                                        DataValue::List(l) => l,
-                                        _ => unreachable!(),
-                                    })
-                                    .collect_vec(),
-                            ),
-                        ))
+                            params,
+                            new_tuples,
+                            old_tuples,
+                            cur_vld,
+                            callback_targets,
+                            callback_collector,
+                        )?);
                     }
                 }
             }
         };
 
+        Ok((to_clear, counts))
+    }
+    /// Moves the entries of the indices of a stored relation from the old image of a row,
+    /// if there is one, to its new image, checking the unique columns of the new image
+    fn reindex_row(
+        &mut self,
+        relation_store: &RelationHandle,
+        old: Option<&Tuple>,
+        new: &Tuple,
+    ) -> Result<()> {
+        if old == Some(new) {
+            return Ok(());
+        }
+        self.check_unique(relation_store, new)?;
+        for (idx_name, (idx_rel, extractor)) in relation_store.indices.iter() {
+            // with partial indices, the row may enter or leave the index
+            if let Some(old) = old {
+                if relation_store.index_includes(idx_name, old)? {
+                    let idx_tup_old = extractor.iter().map(|i| old[*i].clone()).collect_vec();
+                    let encoded_old =
+                        idx_rel.encode_key_for_store(&idx_tup_old, Default::default())?;
+                    self.store_tx.del(&encoded_old)?;
+                }
+            }
+            if relation_store.index_includes(idx_name, new)? {
+                let idx_tup_new = extractor.iter().map(|i| new[*i].clone()).collect_vec();
+                let encoded_new = idx_rel.encode_key_for_store(&idx_tup_new, Default::default())?;
+                self.store_tx.put(&encoded_new, &[])?;
+            }
+        }
+        self.reindex_hnsw_row(relation_store, old, new)?;
+        self.reindex_fts_row(relation_store, old, new)?;
+        self.reindex_lsh_row(relation_store, old, new)
+    }
+    /// The values currently stored under the keys of a batch of rows
+    fn fetch_old_images(
+        &self,
+        relation_store: &RelationHandle,
+        rows: &[(Vec<u8>, Tuple)],
+    ) -> Result<Vec<Option<Vec<u8>>>> {
+        if relation_store.is_temp {
+            return rows
+                .iter()
+                .map(|(key, _)| self.temp_store_tx.get(key, false))
+                .collect();
+        }
+        let keys = rows.iter().map(|(key, _)| &key[..]).collect_vec();
+        self.store_tx.multi_get(&keys, false)
+    }
+    /// Runs the triggers for a batch of rows put into or removed from a stored relation,
+    /// and records the batch for callbacks. For removals, `new_rows` only have the keys.
+    fn propagate_batch<'s, S: Storage<'s>>(
+        &mut self,
+        db: &Db<S>,
+        relation_store: &RelationHandle,
+        op: CallbackOp,
+        params: &BTreeMap<String, DataValue>,
+        new_rows: Vec<Tuple>,
+        old_rows: Vec<Tuple>,
+        cur_vld: ValidityTs,
+        callback_targets: &BTreeSet<SmartString<LazyCompact>>,
+        callback_collector: &mut CallbackCollector,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut to_clear = vec![];
+        let k_bindings = relation_store
+            .metadata
+            .keys
+            .iter()
+            .map(|k| Symbol::new(k.name.clone(), Default::default()))
+            .collect_vec();
+        let mut kv_bindings = k_bindings.clone();
+        kv_bindings.extend(
+            relation_store
+                .metadata
+                .non_keys
+                .iter()
+                .map(|k| Symbol::new(k.name.clone(), Default::default())),
+        );
+        let (new_bindings, triggers) = match op {
+            CallbackOp::Put => (kv_bindings.clone(), &relation_store.put_triggers),
+            CallbackOp::Rm => (k_bindings, &relation_store.rm_triggers),
+        };
+
+        if !triggers.is_empty() {
+            if self.trigger_depth >= MAX_TRIGGER_DEPTH {
+                bail!(TriggerDepthExceeded(relation_store.name.to_string()))
+            }
+            let new_data = new_rows
+                .iter()
+                .map(|row| DataValue::List(row.clone()))
+                .collect_vec();
+            let old_data = old_rows
+                .iter()
+                .map(|row| DataValue::List(row.clone()))
+                .collect_vec();
+            for trigger in triggers {
+                let mut program = parse_script(
+                    trigger,
+                    &Default::default(),
+                    &db.fixed_rules.read().unwrap(),
+                    cur_vld,
+                )?
+                .get_single_program()?;
+
+                make_const_rule(&mut program, "_new", new_bindings.clone(), new_data.clone());
+                make_const_rule(&mut program, "_old", kv_bindings.clone(), old_data.clone());
+                // columns updated automatically by the trigger's writes see the parameters
+                // of the query setting it off, e.g. the client making the change
+                if let Some((handle, _)) = &mut program.out_opts.store_relation {
+                    handle.params = params.clone();
+                }
+
+                // the writes of the trigger may set off the triggers of other relations in turn
+                self.trigger_depth += 1;
+                let res = db.run_query(
+                    self,
+                    program,
+                    cur_vld,
+                    callback_targets,
+                    callback_collector,
+                    false,
+                );
+                self.trigger_depth -= 1;
+                let (_, cleanups) = res
+                    .map_err(|err| {
+                        if err.source_code().is_some() {
+                            err
+                        } else {
+                            err.with_source_code(trigger.to_string())
+                        }
+                    })
+                    .wrap_err_with(|| {
+                        format!(
+                            "when running {} trigger of relation '{}'",
+                            op.as_str().to_lowercase(),
+                            relation_store.name
+                        )
+                    })?;
+                to_clear.extend(cleanups);
+            }
+        }
+
+        if callback_targets.contains(&relation_store.name) {
+            let names = |bindings: Vec<Symbol>| {
+                bindings
+                    .into_iter()
+                    .map(|k| k.name.to_string())
+                    .collect_vec()
+            };
+            callback_collector
+                .entry(relation_store.name.clone())
+                .or_default()
+                .push((
+                    op,
+                    NamedRows::new(names(new_bindings), new_rows),
+                    NamedRows::new(names(kv_bindings), old_rows),
+                ));
+        }
         Ok(to_clear)
     }
+    /// Whether the output of the query can be written into the stored relation while it is
+    /// being produced, bypassing [Self::execute_relation]. This requires that nothing needs to
+    /// look at the old or new rows (triggers, indices, callbacks), and that the storage engine
+    /// accepts writes through a shared reference.
+    pub(crate) fn can_store_directly(
+        &self,
+        meta: &InputRelationHandle,
+        op: RelationOp,
+        callback_targets: &BTreeSet<SmartString<LazyCompact>>,
+    ) -> Result<bool> {
+        if !self.store_tx.supports_par_put()
+            || meta.name.is_temp_store_name()
+            || callback_targets.contains(&meta.name.name)
+            || meta.metadata.non_keys.iter().any(|col| col.unique)
+        {
+            return Ok(false);
+        }
+        Ok(match op {
+            RelationOp::Put => {
+                let handle = self.get_relation(&meta.name, false)?;
+                handle.put_triggers.is_empty()
+                    && !handle.has_indices()
+                    && handle.history_retention.is_none()
+                    && handle.access_level >= AccessLevel::Protected
+            }
+            RelationOp::Replace => match self.get_relation(&meta.name, false) {
+                Err(_) => true,
+                Ok(handle) => {
+                    !handle.has_triggers()
+                        && handle.replace_triggers.is_empty()
+                        && !handle.has_indices()
+                        && handle.access_level >= AccessLevel::Normal
+                }
+            },
+            _ => false,
+        })
+    }
+    /// Sets up the target relation for [Self::write_direct_store], replacing it if required.
+    /// Returns the ranges to be cleaned up at the end of the transaction.
+    pub(crate) fn prepare_direct_store(
+        &mut self,
+        meta: &InputRelationHandle,
+        op: RelationOp,
+        headers: &[Symbol],
+    ) -> Result<(DirectStore, Vec<(Vec<u8>, Vec<u8>)>)> {
+        let mut to_clear = vec![];
+        let handle = if op == RelationOp::Replace {
+            if self.relation_exists(&meta.name)? {
+                to_clear.extend(self.destroy_relation(&meta.name)?);
+            }
+            self.create_relation(meta.clone())?
+        } else {
+            self.get_relation(&meta.name, false)?
+        };
+        let auto_update = Some(AutoUpdateCtx {
+            params: &meta.params,
+            reject_override: op == RelationOp::Put,
+        });
+        let mut extractors = make_extractors(
+            &handle.metadata.keys,
+            &meta.metadata.keys,
+            &meta.key_bindings,
+            headers,
+            auto_update,
+        )?;
+        extractors.extend(make_extractors(
+            &handle.metadata.non_keys,
+            &meta.metadata.non_keys,
+            &meta.dep_bindings,
+            headers,
+            auto_update,
+        )?);
+        let order = extractors
+            .iter()
+            .enumerate()
+            .filter_map(|(pos, ex)| match ex {
+                DataExtractor::IndexExtractor(i, _) => Some((*i, pos)),
+                DataExtractor::DefaultExtractor(..) => None,
+            })
+            .sorted()
+            .map(|(_, pos)| pos)
+            .collect();
+        Ok((
+            DirectStore {
+                handle,
+                extractors,
+                order,
+                replaced: op == RelationOp::Replace,
+                span: meta.span,
+            },
+            to_clear,
+        ))
+    }
+    /// Writes the rows received into the target relation until the sending side is closed,
+    /// in sorted chunks. Returns the numbers of rows written.
+    ///
+    /// Of several rows with the same key, the greatest one is kept, as it would be when the
+    /// rows are first collected into a sorted store and then written by [extract_batch].
+    /// With `:put`, the rows already stored cannot be told from those written before by this
+    /// statement without remembering every key, so only the keys of the previous chunk are
+    /// remembered: a row whose key last came more than a chunk before overwrites the row
+    /// written then, and is counted as an update.
+    pub(crate) fn write_direct_store(
+        &self,
+        target: &DirectStore,
+        rows: Receiver<Tuple>,
+        cur_vld: ValidityTs,
+    ) -> Result<MutationCounts> {
+        let mut counts = MutationCounts::default();
+        let mut chunk: Vec<(Vec<u8>, Tuple)> = Vec::with_capacity(DIRECT_STORE_CHUNK_SIZE);
+        // sorted, the rows stored with them were written by this statement
+        let mut prev_keys: Vec<Vec<u8>> = vec![];
+        loop {
+            for tuple in rows.iter().take(DIRECT_STORE_CHUNK_SIZE) {
+                let extracted: Vec<_> = target
+                    .extractors
+                    .iter()
+                    .map(|ex| ex.extract_data(&tuple, cur_vld))
+                    .try_collect()?;
+                let key = target
+                    .handle
+                    .encode_key_for_store(&extracted, target.span)?;
+                chunk.push((key, extracted));
+            }
+            if chunk.is_empty() {
+                return Ok(counts);
+            }
+            chunk.sort_by(|a, b| {
+                a.0.cmp(&b.0)
+                    .then_with(|| target.order_key(&a.1).cmp(&target.order_key(&b.1)))
+            });
+            chunk.dedup_by(|later, earlier| {
+                if later.0 == earlier.0 {
+                    std::mem::swap(later, earlier);
+                    true
+                } else {
+                    false
+                }
+            });
+            let mut keys = Vec::with_capacity(chunk.len());
+            for (key, extracted) in chunk.drain(..) {
+                // a replaced relation only has the rows written by this statement
+                let maybe_written = target.replaced || prev_keys.binary_search(&key).is_ok();
+                if maybe_written {
+                    if let Some(existing) = self.store_tx.get(&key, false)? {
+                        let existing = decode_tuple_from_kv(&key, &existing)?;
+                        if target.order_key(&existing) >= target.order_key(&extracted) {
+                            keys.push(key);
+                            continue;
+                        }
+                    } else {
+                        counts.count_write(false);
+                    }
+                } else {
+                    counts.count_write(self.store_tx.exists(&key, false)?);
+                }
+                let val = target
+                    .handle
+                    .encode_val_for_store(&extracted, target.span)?;
+                self.store_tx.par_put(&key, &val)?;
+                keys.push(key);
+            }
+            prev_keys = keys;
+        }
+    }
+}
+
+/// Rows streamed into a stored relation are written in sorted chunks of this size.
+pub(crate) const DIRECT_STORE_CHUNK_SIZE: usize = 4096;
+
+/// A stored relation that rows are written into directly, see [SessionTx::write_direct_store].
+pub(crate) struct DirectStore {
+    handle: RelationHandle,
+    extractors: Vec<DataExtractor>,
+    /// the positions of the extractors taking columns of the rows, in the order of the columns
+    order: Vec<usize>,
+    /// whether the relation was created afresh for the rows
+    replaced: bool,
+    span: SourceSpan,
 }
 
+impl DirectStore {
+    /// Extracted values in the order of the rows they came from, to tell which row is greater
+    fn order_key<'a>(&self, extracted: &'a [DataValue]) -> Vec<&'a DataValue> {
+        self.order.iter().map(|pos| &extracted[*pos]).collect()
+    }
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Cannot update the row of {0} with the key {1:?} as it does not exist")]
+#[diagnostic(code(eval::update_missing_row))]
+#[diagnostic(help("Use `:upsert` to insert the rows that do not exist"))]
+struct UpdateMissingRow(String, Vec<DataValue>);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Column {0} of {1} has no default, so the missing row {2:?} cannot be inserted")]
+#[diagnostic(code(eval::required_col_not_provided))]
+struct UpsertWithoutDefault(String, String, Vec<DataValue>);
+
 #[derive(Debug, Error, Diagnostic)]
 #[error("Assertion failure for {key:?} of {relation}: {notice}")]
 struct TransactAssertionFailure {
@@ -590,33 +977,97 @@ impl DataExtractor {
     }
 }
 
+/// Extracts the rows of a batch and encodes their keys. The rows are sorted by key so that
+/// the storage is accessed in order, and of rows with the same key only the last one,
+/// which is the one finally written, is kept.
+fn extract_batch(
+    relation_store: &RelationHandle,
+    batch: impl Iterator<Item = Tuple>,
+    extractors: &[DataExtractor],
+    cur_vld: ValidityTs,
+    span: SourceSpan,
+) -> Result<Vec<(Vec<u8>, Tuple)>> {
+    let mut rows: Vec<(Vec<u8>, Tuple)> = batch
+        .map(|tuple| -> Result<(Vec<u8>, Tuple)> {
+            let extracted: Tuple = extractors
+                .iter()
+                .map(|ex| ex.extract_data(&tuple, cur_vld))
+                .try_collect()?;
+            let key = relation_store.encode_key_for_store(&extracted, span)?;
+            Ok((key, extracted))
+        })
+        .try_collect()?;
+    // stable, so rows with the same key stay in the order they came in
+    rows.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut deduped: Vec<(Vec<u8>, Tuple)> = Vec::with_capacity(rows.len());
+    for row in rows {
+        match deduped.last_mut() {
+            Some(last) if last.0 == row.0 => *last = row,
+            _ => deduped.push(row),
+        }
+    }
+    Ok(deduped)
+}
+
 fn make_extractors(
     stored: &[ColumnDef],
     input: &[ColumnDef],
     bindings: &[Symbol],
     tuple_headers: &[Symbol],
+    auto_update: Option<AutoUpdateCtx<'_>>,
 ) -> Result<Vec<DataExtractor>> {
     stored
         .iter()
-        .map(|s| make_extractor(s, input, bindings, tuple_headers))
+        .map(|s| make_extractor(s, input, bindings, tuple_headers, auto_update))
         .try_collect()
 }
 
+/// How auto-updated columns are written. Without it, they are written like any other column.
+#[derive(Copy, Clone)]
+struct AutoUpdateCtx<'a> {
+    /// the parameters of the writing query
+    params: &'a BTreeMap<String, DataValue>,
+    /// whether values given for columns not allowing overrides are an error,
+    /// otherwise they are written as given
+    reject_override: bool,
+}
+
 fn make_extractor(
     stored: &ColumnDef,
     input: &[ColumnDef],
     bindings: &[Symbol],
     tuple_headers: &[Symbol],
+    auto_update: Option<AutoUpdateCtx<'_>>,
 ) -> Result<DataExtractor> {
     for (inp_col, inp_binding) in input.iter().zip(bindings.iter()) {
         if inp_col.name == stored.name {
             for (idx, tuple_head) in tuple_headers.iter().enumerate() {
                 if tuple_head == inp_binding {
+                    if let (Some(au), Some(ctx)) = (&stored.auto_update, auto_update) {
+                        if ctx.reject_override && !au.allow_override {
+                            #[derive(Debug, Error, Diagnostic)]
+                            #[error("Column {0} is updated automatically and cannot be given")]
+                            #[diagnostic(code(eval::auto_update_override))]
+                            #[diagnostic(help(
+                                "Declare the column with `allow_override` to accept given values"
+                            ))]
+                            struct AutoUpdateOverride(String, #[label] SourceSpan);
+                            bail!(AutoUpdateOverride(
+                                stored.name.to_string(),
+                                inp_binding.span
+                            ))
+                        }
+                    }
                     return Ok(DataExtractor::IndexExtractor(idx, stored.typing.clone()));
                 }
             }
         }
     }
+    if let (Some(au), Some(ctx)) = (&stored.auto_update, auto_update) {
+        let expr = parse_expression(&au.expr, ctx.params)
+            .wrap_err_with(|| format!("when updating column {}", stored.name))?;
+        return Ok(DataExtractor::DefaultExtractor(expr, stored.typing.clone()));
+    }
     if let Some(expr) = &stored.default_gen {
         Ok(DataExtractor::DefaultExtractor(
             expr.clone(),
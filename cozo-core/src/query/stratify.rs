@@ -29,7 +29,10 @@ impl NormalFormAtom {
             NormalFormAtom::Relation(_)
             | NormalFormAtom::NegatedRelation(_)
             | NormalFormAtom::Predicate(_)
-            | NormalFormAtom::Unification(_) => Default::default(),
+            | NormalFormAtom::Unification(_)
+            | NormalFormAtom::HnswSearch(_)
+            | NormalFormAtom::FtsSearch(_)
+            | NormalFormAtom::LshSearch(_) => Default::default(),
             NormalFormAtom::Rule(r) => BTreeMap::from([(&r.name, false)]),
             NormalFormAtom::NegatedRule(r) => BTreeMap::from([(&r.name, true)]),
         }
@@ -0,0 +1,295 @@
+/*
+ * Copyright 2022, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::VecDeque;
+
+use miette::Result;
+
+use crate::data::functions::op_add;
+use crate::data::program::{WindowFn, WindowSpec};
+use crate::data::symb::Symbol;
+use crate::data::tuple::Tuple;
+use crate::data::value::DataValue;
+use crate::query::sort::SortedTuples;
+
+/// Appends the columns of `windows` to the rows of `sorted`, which must be sorted by the
+/// partition columns of each window followed by its order columns. The rows are streamed,
+/// only the rows needed by `lag` and `lead` are held.
+pub(crate) fn compute_windows(
+    sorted: SortedTuples,
+    windows: &[WindowSpec],
+    head: &[Symbol],
+) -> SortedTuples {
+    let col_idx = |col: &Symbol| head.iter().position(|h| h == col).unwrap();
+    let windows = windows
+        .iter()
+        .map(|spec| WindowState {
+            col: spec.func.col().map(col_idx),
+            partition: spec.partition_by.iter().map(col_idx).collect(),
+            order: spec.order_by.iter().map(|(col, _)| col_idx(col)).collect(),
+            func: spec.func.clone(),
+            row_number: 0,
+            rank: 0,
+            dense_rank: 0,
+            acc: DataValue::Null,
+        })
+        .collect::<Vec<_>>();
+    let max_offset = |is_lag: bool| {
+        windows
+            .iter()
+            .filter_map(|w| match &w.func {
+                WindowFn::Lag { offset, .. } if is_lag => Some(*offset),
+                WindowFn::Lead { offset, .. } if !is_lag => Some(*offset),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
+    };
+    Box::new(WindowedTuples {
+        max_lag: max_offset(true),
+        max_lead: max_offset(false),
+        source: sorted,
+        windows,
+        ahead: VecDeque::new(),
+        behind: VecDeque::new(),
+    })
+}
+
+struct WindowState {
+    func: WindowFn,
+    col: Option<usize>,
+    partition: Vec<usize>,
+    order: Vec<usize>,
+    row_number: i64,
+    rank: i64,
+    dense_rank: i64,
+    /// the running sum, min or max of the non-null values of the partition so far
+    acc: DataValue,
+}
+
+fn same_at(cols: &[usize], a: &Tuple, b: &Tuple) -> bool {
+    cols.iter().all(|i| a[*i] == b[*i])
+}
+
+impl WindowState {
+    fn compute(
+        &mut self,
+        row: &Tuple,
+        behind: &VecDeque<Tuple>,
+        ahead: &VecDeque<Tuple>,
+    ) -> Result<DataValue> {
+        let prev = behind.back();
+        let new_partition = match prev {
+            None => true,
+            Some(prev) => !same_at(&self.partition, prev, row),
+        };
+        if new_partition {
+            self.row_number = 0;
+            self.rank = 0;
+            self.dense_rank = 0;
+            self.acc = DataValue::Null;
+        }
+        self.row_number += 1;
+        if new_partition || !same_at(&self.order, prev.unwrap(), row) {
+            self.rank = self.row_number;
+            self.dense_rank += 1;
+        }
+        let val = self.col.map(|i| &row[i]);
+        Ok(match &self.func {
+            WindowFn::RowNumber => DataValue::from(self.row_number),
+            WindowFn::Rank => DataValue::from(self.rank),
+            WindowFn::DenseRank => DataValue::from(self.dense_rank),
+            WindowFn::Lag {
+                offset, default, ..
+            } => {
+                let found = match *offset {
+                    0 => Some(row),
+                    offset => behind
+                        .len()
+                        .checked_sub(offset)
+                        .map(|i| &behind[i])
+                        .filter(|other| same_at(&self.partition, other, row)),
+                };
+                match found {
+                    Some(other) => other[self.col.unwrap()].clone(),
+                    None => default.clone(),
+                }
+            }
+            WindowFn::Lead {
+                offset, default, ..
+            } => {
+                let found = match *offset {
+                    0 => Some(row),
+                    offset => ahead
+                        .get(offset - 1)
+                        .filter(|other| same_at(&self.partition, other, row)),
+                };
+                match found {
+                    Some(other) => other[self.col.unwrap()].clone(),
+                    None => default.clone(),
+                }
+            }
+            WindowFn::Sum(_) => {
+                let val = val.unwrap();
+                if *val != DataValue::Null {
+                    self.acc = if self.acc == DataValue::Null {
+                        val.clone()
+                    } else {
+                        op_add(&[self.acc.clone(), val.clone()])?
+                    };
+                }
+                self.acc.clone()
+            }
+            WindowFn::Min(_) | WindowFn::Max(_) => {
+                let val = val.unwrap();
+                let replaces = match self.func {
+                    WindowFn::Min(_) => *val < self.acc,
+                    _ => *val > self.acc,
+                };
+                if *val != DataValue::Null && (self.acc == DataValue::Null || replaces) {
+                    self.acc = val.clone();
+                }
+                self.acc.clone()
+            }
+        })
+    }
+}
+
+struct WindowedTuples {
+    source: SortedTuples,
+    windows: Vec<WindowState>,
+    /// rows read from the source but not yet output, for `lead`
+    ahead: VecDeque<Tuple>,
+    /// the rows last output, the latest at the back, for `lag`
+    behind: VecDeque<Tuple>,
+    max_lag: usize,
+    max_lead: usize,
+}
+
+impl WindowedTuples {
+    fn next_row(&mut self) -> Result<Option<Tuple>> {
+        while self.ahead.len() <= self.max_lead {
+            match self.source.next() {
+                None => break,
+                Some(row) => self.ahead.push_back(row?),
+            }
+        }
+        let row = match self.ahead.pop_front() {
+            None => return Ok(None),
+            Some(row) => row,
+        };
+        let mut out = row.clone();
+        for window in self.windows.iter_mut() {
+            out.push(window.compute(&row, &self.behind, &self.ahead)?);
+        }
+        self.behind.push_back(row);
+        // the previous row is always kept for detecting the boundaries of partitions
+        while self.behind.len() > self.max_lag.max(1) {
+            self.behind.pop_front();
+        }
+        Ok(Some(out))
+    }
+}
+
+impl Iterator for WindowedTuples {
+    type Item = Result<Tuple>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_row().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::new_cozo_mem;
+
+    #[test]
+    fn test_window_options() {
+        let db = new_cozo_mem().unwrap();
+        let res = db
+            .run_script(
+                r#"
+            ?[region, t, v] <- [['a', 1, 10], ['a', 2, 5], ['a', 2, 7], ['b', 1, 3], ['b', 3, 4]]
+            :order region, t, v
+            :window rank() as r partition by region order by t
+            :window dense_rank() as dr partition by region order by t
+            :window lag(v) as prev partition by region order by t
+            :window sum(v) as cum partition by region order by t
+            "#,
+                Default::default(),
+            )
+            .unwrap()
+            .into_json();
+        assert_eq!(
+            res["headers"],
+            json!(["region", "t", "v", "r", "dr", "prev", "cum"])
+        );
+        // lag is the default at the first row of each partition
+        assert_eq!(
+            res["rows"],
+            json!([
+                ["a", 1, 10, 1, 1, null, 10],
+                ["a", 2, 5, 2, 2, 10, 15],
+                ["a", 2, 7, 2, 2, 5, 22],
+                ["b", 1, 3, 1, 1, null, 3],
+                ["b", 3, 4, 2, 2, 3, 7]
+            ])
+        );
+
+        // the output is sorted for the window without `:order`, and limited afterwards
+        let res = db
+            .run_script(
+                r#"
+            ?[t, v] <- [[1, 4], [2, null], [3, 1], [4, 6]]
+            :window row_number() as n order by -t
+            :window lead(v, 2, 0) as next2 order by -t
+            :window min(v) as lo order by -t
+            :window max(v) as hi order by -t
+            :limit 3
+            "#,
+                Default::default(),
+            )
+            .unwrap();
+        assert_eq!(
+            res.into_json()["rows"],
+            json!([
+                [4, 6, 1, null, 6, 6],
+                [3, 1, 2, 4, 1, 6],
+                [2, null, 3, 0, 1, 6]
+            ])
+        );
+
+        for (script, code) in [
+            (
+                "?[t] <- [[1]] :window median(t) as m",
+                "parser::unknown_window_fn",
+            ),
+            (
+                "?[t] <- [[1]] :window lag(t, -1) as m",
+                "parser::bad_window_args",
+            ),
+            (
+                "?[t] <- [[1]] :window sum(v) as m",
+                "parser::window_col_not_found",
+            ),
+            (
+                "?[t] <- [[1]] :window rank() as t",
+                "parser::duplicate_window_name",
+            ),
+            (
+                "?[t, v] <- [[1, 2]] :order t :window rank() as r order by v",
+                "parser::window_order_mismatch",
+            ),
+        ] {
+            let err = db.run_script(script, Default::default()).unwrap_err();
+            assert_eq!(err.code().unwrap().to_string(), code, "{script}");
+        }
+    }
+}
@@ -0,0 +1,592 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::BTreeMap;
+
+use itertools::Itertools;
+use log::info;
+use miette::{bail, Diagnostic, IntoDiagnostic, Result};
+use smartstring::{LazyCompact, SmartString};
+use thiserror::Error;
+
+use crate::data::relation::ColumnDef;
+use crate::data::symb::Symbol;
+use crate::data::tuple::{Tuple, TupleT, ENCODED_KEY_MIN_LEN};
+use crate::data::value::{DataValue, ValidityTs};
+use crate::runtime::relation::{
+    unique_index_col, unique_index_name, AccessLevel, InsufficientAccessLevel, RelationHandle,
+    RelationId,
+};
+use crate::runtime::transact::SessionTx;
+
+/// How often the progress of rewriting the rows of a relation is logged
+const PROGRESS_INTERVAL: usize = 100_000;
+
+/// A change to the columns of a stored relation made by `::alter`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum AlterOp {
+    /// Adds a value column, filled in the existing rows by its default evaluated
+    /// at the given validity, or by null
+    Add(ColumnDef, ValidityTs),
+    Drop(Symbol),
+    Rename(Symbol, Symbol),
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Relation '{0}' already has a column named '{1}'")]
+#[diagnostic(code(eval::alter_column_conflict))]
+struct AlterColumnConflict(String, String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Column '{1}' not found in relation '{0}'")]
+#[diagnostic(code(eval::alter_column_not_found))]
+struct AlterColumnNotFound(String, String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Cannot drop the key column '{1}' of relation '{0}'")]
+#[diagnostic(code(eval::alter_key_column))]
+#[diagnostic(help("Rows are stored by their keys, so use `:replace` to change them"))]
+struct AlterKeyColumn(String, String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Cannot drop column '{1}' of relation '{0}' as the index '{2}' uses it")]
+#[diagnostic(code(eval::alter_indexed_column))]
+#[diagnostic(help("Drop the index with `::index drop` first"))]
+struct AlterIndexedColumn(String, String, String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Cannot alter column '{1}' of relation '{0}' as it is involved in constraints")]
+#[diagnostic(code(eval::alter_constrained_column))]
+#[diagnostic(help("Drop the constraints with `::constraint drop` first"))]
+struct AlterConstrainedColumn(String, String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Cannot drop column '{1}' of relation '{0}' as it holds the expiry of the rows")]
+#[diagnostic(code(eval::alter_ttl_column))]
+#[diagnostic(help("Unset the TTL column with `::set_ttl` first"))]
+struct AlterTtlColumn(String, String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Cannot add the unique column '{1}' to relation '{0}'")]
+#[diagnostic(code(eval::alter_unique_column))]
+#[diagnostic(help("Unique columns can only be declared when the relation is created"))]
+struct AlterUniqueColumn(String, String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Cannot add column '{1}' to relation '{0}' as it has rows and the column has no default")]
+#[diagnostic(code(eval::alter_missing_default))]
+#[diagnostic(help("Give the column a default, or make it nullable"))]
+struct AlterMissingDefault(String, String);
+
+fn column_position(handle: &RelationHandle, column: &str) -> Option<usize> {
+    handle
+        .metadata
+        .keys
+        .iter()
+        .chain(handle.metadata.non_keys.iter())
+        .position(|col| col.name == column)
+}
+
+impl<'a> SessionTx<'a> {
+    /// Changes the columns of the relation, rewriting its rows if needed. Returns the ranges
+    /// of the entries of the indices removed, to be cleared at the end of the transaction.
+    pub(crate) fn alter_relation(
+        &mut self,
+        name: &Symbol,
+        op: AlterOp,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        if name.name.starts_with('_') {
+            bail!("Cannot alter temp relation");
+        }
+        let handle = self.get_relation(name, true)?;
+        if handle.access_level < AccessLevel::Normal {
+            bail!(InsufficientAccessLevel(
+                handle.name.to_string(),
+                "altering relation".to_string(),
+                handle.access_level
+            ));
+        }
+        let ret = match op {
+            AlterOp::Add(col, cur_vld) => {
+                self.add_column(handle, col, cur_vld)?;
+                vec![]
+            }
+            AlterOp::Drop(col) => self.drop_column(handle, &col.name)?,
+            AlterOp::Rename(old, new) => {
+                self.rename_column(handle, &old.name, &new.name)?;
+                vec![]
+            }
+        };
+        self.bump_schema_generation()?;
+        Ok(ret)
+    }
+    fn add_column(
+        &mut self,
+        mut handle: RelationHandle,
+        col: ColumnDef,
+        cur_vld: ValidityTs,
+    ) -> Result<()> {
+        if column_position(&handle, &col.name).is_some() {
+            bail!(AlterColumnConflict(
+                handle.name.to_string(),
+                col.name.to_string()
+            ))
+        }
+        if col.unique {
+            bail!(AlterUniqueColumn(
+                handle.name.to_string(),
+                col.name.to_string()
+            ))
+        }
+        // the new column comes last, so the positions used by the indices stay the same
+        self.rewrite_values(&handle, |vals| {
+            let val = match &col.default_gen {
+                Some(expr) => col.typing.coerce(expr.clone().eval_to_const()?, cur_vld)?,
+                None if col.typing.nullable => DataValue::Null,
+                None => bail!(AlterMissingDefault(
+                    handle.name.to_string(),
+                    col.name.to_string()
+                )),
+            };
+            vals.push(val);
+            Ok(())
+        })?;
+        handle.metadata.non_keys.push(col);
+        self.put_relation_meta(&handle)
+    }
+    fn drop_column(
+        &mut self,
+        handle: RelationHandle,
+        column: &str,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let pos = column_position(&handle, column)
+            .ok_or_else(|| AlterColumnNotFound(handle.name.to_string(), column.to_string()))?;
+        let n_keys = handle.metadata.keys.len();
+        if pos < n_keys {
+            bail!(AlterKeyColumn(handle.name.to_string(), column.to_string()))
+        }
+        if handle
+            .foreign_keys
+            .iter()
+            .any(|fk| fk.relation == handle.name && fk.column == column)
+        {
+            bail!(AlterConstrainedColumn(
+                handle.name.to_string(),
+                column.to_string()
+            ))
+        }
+        if handle.ttl.as_deref() == Some(column) {
+            bail!(AlterTtlColumn(handle.name.to_string(), column.to_string()))
+        }
+        let binding = Symbol::new(column, Default::default());
+        for (idx_name, (_, extractor)) in handle.indices.iter() {
+            if unique_index_col(idx_name) == Some(column) {
+                continue;
+            }
+            let in_predicate = handle
+                .index_predicates
+                .get(idx_name)
+                .is_some_and(|pred| pred.bindings().contains(&binding));
+            if extractor.contains(&pos) || in_predicate {
+                bail!(AlterIndexedColumn(
+                    handle.name.to_string(),
+                    column.to_string(),
+                    idx_name.to_string()
+                ))
+            }
+        }
+        for (idx_name, (_, manifest)) in handle.hnsw_indices.iter() {
+            if manifest.field_positions.contains(&pos) {
+                bail!(AlterIndexedColumn(
+                    handle.name.to_string(),
+                    column.to_string(),
+                    idx_name.to_string()
+                ))
+            }
+        }
+        for (idx_name, (_, manifest)) in handle.fts_indices.iter() {
+            if manifest.field_positions.contains(&pos) {
+                bail!(AlterIndexedColumn(
+                    handle.name.to_string(),
+                    column.to_string(),
+                    idx_name.to_string()
+                ))
+            }
+        }
+        for (idx_name, (_, manifest)) in handle.lsh_indices.iter() {
+            if manifest.field_positions.contains(&pos) {
+                bail!(AlterIndexedColumn(
+                    handle.name.to_string(),
+                    column.to_string(),
+                    idx_name.to_string()
+                ))
+            }
+        }
+
+        // the uniqueness of the column goes with it
+        let mut cleared = vec![];
+        let unique_idx = unique_index_name(column);
+        let mut handle = if handle.indices.contains_key(&unique_idx) {
+            let rel_name = Symbol::new(handle.name.clone(), Default::default());
+            let idx_name = Symbol::new(unique_idx, Default::default());
+            cleared = self.remove_index(&rel_name, &idx_name)?;
+            self.get_relation(&handle.name, true)?
+        } else {
+            handle
+        };
+
+        self.rewrite_values(&handle, |vals| {
+            vals.remove(pos - n_keys);
+            Ok(())
+        })?;
+        handle.metadata.non_keys.remove(pos - n_keys);
+        for (_, extractor) in handle.indices.values_mut() {
+            for i in extractor.iter_mut() {
+                if *i > pos {
+                    *i -= 1;
+                }
+            }
+        }
+        for (_, manifest) in handle.hnsw_indices.values_mut() {
+            for i in manifest.field_positions.iter_mut() {
+                if *i > pos {
+                    *i -= 1;
+                }
+            }
+        }
+        for (_, manifest) in handle.fts_indices.values_mut() {
+            for i in manifest.field_positions.iter_mut() {
+                if *i > pos {
+                    *i -= 1;
+                }
+            }
+        }
+        for (_, manifest) in handle.lsh_indices.values_mut() {
+            for i in manifest.field_positions.iter_mut() {
+                if *i > pos {
+                    *i -= 1;
+                }
+            }
+        }
+        let col_positions: BTreeMap<_, _> = handle
+            .metadata
+            .keys
+            .iter()
+            .chain(handle.metadata.non_keys.iter())
+            .enumerate()
+            .map(|(i, col)| (Symbol::new(col.name.clone(), Default::default()), i))
+            .collect();
+        for pred in handle.index_predicates.values_mut() {
+            pred.fill_binding_indices(&col_positions)?;
+        }
+        self.put_relation_meta(&handle)?;
+        Ok(cleared)
+    }
+    fn rename_column(
+        &mut self,
+        mut handle: RelationHandle,
+        old: &SmartString<LazyCompact>,
+        new: &SmartString<LazyCompact>,
+    ) -> Result<()> {
+        let pos = column_position(&handle, old)
+            .ok_or_else(|| AlterColumnNotFound(handle.name.to_string(), old.to_string()))?;
+        if column_position(&handle, new).is_some() {
+            bail!(AlterColumnConflict(
+                handle.name.to_string(),
+                new.to_string()
+            ))
+        }
+        if handle.foreign_keys.iter().any(|fk| {
+            (fk.relation == handle.name && fk.column == *old)
+                || (fk.target == handle.name && fk.target_column == *old)
+        }) {
+            bail!(AlterConstrainedColumn(
+                handle.name.to_string(),
+                old.to_string()
+            ))
+        }
+        if handle.ttl.as_ref() == Some(old) {
+            handle.ttl = Some(new.clone());
+        }
+        let n_keys = handle.metadata.keys.len();
+        if pos < n_keys {
+            handle.metadata.keys[pos].name = new.clone();
+        } else {
+            handle.metadata.non_keys[pos - n_keys].name = new.clone();
+        }
+
+        // the indices have the columns of the relation by name
+        let renames = BTreeMap::from([(
+            Symbol::new(old.clone(), Default::default()),
+            Symbol::new(new.clone(), Default::default()),
+        )]);
+        for pred in handle.index_predicates.values_mut() {
+            pred.rename_bindings(&renames);
+        }
+        let old_unique = unique_index_name(old);
+        let mut indices = std::mem::take(&mut handle.indices);
+        if let Some(mut unique_idx) = indices.remove(&old_unique) {
+            let new_unique = unique_index_name(new);
+            let old_key =
+                vec![DataValue::Str(unique_idx.0.name.clone())].encode_as_key(RelationId::SYSTEM);
+            self.store_tx.del(&old_key)?;
+            unique_idx.0.name = SmartString::from(format!("{}:{}", handle.name, new_unique));
+            indices.insert(new_unique, unique_idx);
+        }
+        for (idx_rel, _) in indices.values_mut() {
+            for col in idx_rel.metadata.keys.iter_mut() {
+                if col.name == *old {
+                    col.name = new.clone();
+                }
+            }
+            self.put_relation_meta(idx_rel)?;
+        }
+        handle.indices = indices;
+        for (_, manifest) in handle.hnsw_indices.values_mut() {
+            for field in manifest.fields.iter_mut() {
+                if field == old {
+                    *field = new.clone();
+                }
+            }
+        }
+        for (_, manifest) in handle.fts_indices.values_mut() {
+            for field in manifest.fields.iter_mut() {
+                if field == old {
+                    *field = new.clone();
+                }
+            }
+        }
+        for (_, manifest) in handle.lsh_indices.values_mut() {
+            for field in manifest.fields.iter_mut() {
+                if field == old {
+                    *field = new.clone();
+                }
+            }
+        }
+        self.put_relation_meta(&handle)
+    }
+    /// Rewrites the values of all the rows of the relation, the keys staying the same.
+    fn rewrite_values(
+        &mut self,
+        handle: &RelationHandle,
+        mut rewrite: impl FnMut(&mut Vec<DataValue>) -> Result<()>,
+    ) -> Result<()> {
+        let lower = Tuple::default().encode_as_key(handle.id);
+        let upper = Tuple::default().encode_as_key(handle.id.next());
+        let mut rewritten = |v: &[u8]| -> Result<Vec<u8>> {
+            let mut vals: Vec<DataValue> = if v.is_empty() {
+                vec![]
+            } else {
+                rmp_serde::from_slice(&v[ENCODED_KEY_MIN_LEN..]).into_diagnostic()?
+            };
+            rewrite(&mut vals)?;
+            handle.encode_val_only_for_store(&vals, Default::default())
+        };
+        let mut count = 0;
+        let mut log_progress = || {
+            count += 1;
+            if count % PROGRESS_INTERVAL == 0 {
+                info!(
+                    "altering relation {}: {} rows rewritten",
+                    handle.name, count
+                );
+            }
+        };
+        if self.store_tx.supports_par_put() {
+            for kv in self.store_tx.range_scan(&lower, &upper) {
+                let (k, v) = kv?;
+                self.store_tx.par_put(&k, &rewritten(&v)?)?;
+                log_progress();
+            }
+        } else {
+            for kv in self.store_tx.range_scan(&lower, &upper).collect_vec() {
+                let (k, v) = kv?;
+                self.store_tx.put(&k, &rewritten(&v)?)?;
+                log_progress();
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+    use serde_json::json;
+
+    use crate::{new_cozo_mem, Db, MemStorage};
+
+    #[test]
+    fn test_alter_columns() {
+        let db = new_cozo_mem().unwrap();
+        db.run_script(
+        r"
+        {:create users {id: Int => name: String, email: String unique, age: Int, status: String}}
+        {?[id, name, email, age, status] <- [[1, 'a', 'a@x', 30, 'active'], [2, 'b', 'b@x', 12, 'active'],
+                                             [3, 'c', 'c@x', 40, 'gone']]
+         :put users {id => name, email, age, status}}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+        db.run_script(
+            "::index create users:active {status} where age > 18",
+            Default::default(),
+        )
+        .unwrap();
+        let run = |script: &str| db.run_script(script, Default::default());
+        let rows = |script: &str| run(script).unwrap().into_json()["rows"].clone();
+        let err_code = |script: &str| run(script).unwrap_err().code().unwrap().to_string();
+
+        // existing rows get the default of the column added, evaluated for each row
+        run("::alter users add column score: Int default 0").unwrap();
+        run("::alter users add column nick: String?").unwrap();
+        assert_eq!(
+            rows("?[id, score, nick] := *users{id, score, nick}"),
+            json!([[1, 0, null], [2, 0, null], [3, 0, null]])
+        );
+        assert_eq!(
+            err_code("::alter users add column title: String"),
+            "eval::alter_missing_default"
+        );
+        assert_eq!(
+            err_code("::alter users add column name: String?"),
+            "eval::alter_column_conflict"
+        );
+        assert_eq!(
+            err_code("::alter users add column handle: String? unique"),
+            "eval::alter_unique_column"
+        );
+
+        assert_eq!(
+            err_code("::alter users drop column id"),
+            "eval::alter_key_column"
+        );
+        assert_eq!(
+            err_code("::alter users drop column age"),
+            "eval::alter_indexed_column"
+        );
+        assert_eq!(
+            err_code("::alter users drop column missing"),
+            "eval::alter_column_not_found"
+        );
+        // the indices follow the columns moving after the one dropped
+        run("::alter users drop column name").unwrap();
+        assert_eq!(
+            rows("?[id, email, age, status, score] := *users{id, email, age, status, score}"),
+            json!([
+                [1, "a@x", 30, "active", 0],
+                [2, "b@x", 12, "active", 0],
+                [3, "c@x", 40, "gone", 0]
+            ])
+        );
+        assert!(run("?[name] := *users{name}").is_err());
+        run("?[id, email, age, status, nick] <- [[4, 'd@x', 50, 'active', null]] :put users {id => email, age, status, nick}")
+        .unwrap();
+        assert_eq!(
+            rows("?[status, id] := *users:active{status, id}"),
+            json!([["active", 1], ["active", 4], ["gone", 3]])
+        );
+
+        // renamed columns keep their uniqueness and their indices
+        run("::alter users rename column email to mail").unwrap();
+        run("::alter users rename column id to uid").unwrap();
+        run("::alter users rename column age to years").unwrap();
+        assert_eq!(
+            err_code("::alter users rename column mail to status"),
+            "eval::alter_column_conflict"
+        );
+        assert_eq!(
+        err_code("?[uid, mail, years, status, nick] <- [[5, 'a@x', 1, 'x', null]] :put users {uid => mail, years, status, nick}"),
+        "eval::unique_violation"
+    );
+        assert_eq!(
+            rows("?[status, uid] := *users:active{status, uid}"),
+            json!([["active", 1], ["active", 4], ["gone", 3]])
+        );
+        assert_eq!(
+            rows("?[uid] := *users{uid, years}, years > 35"),
+            json!([[3], [4]])
+        );
+        let columns = rows("::columns users");
+        assert_eq!(
+            columns
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|col| col[0].clone())
+                .collect_vec(),
+            ["uid", "mail", "years", "status", "score", "nick"]
+        );
+
+        // the uniqueness of a column goes with it
+        run("::alter users drop column mail").unwrap();
+        let integrity = rows("::check_integrity");
+        assert_eq!(integrity, json!([["users", "active", 3, 0, 0, true]]));
+    }
+
+    #[cfg(feature = "storage-sqlite")]
+    #[test]
+    fn test_alter_columns_backup() {
+        let path = std::env::temp_dir().join(format!("cozo-test-{}.db", rand::random::<u64>()));
+        let db = new_cozo_mem().unwrap();
+        db.run_script(
+            r"
+        {:create items {id: Int => label: String, price: Float, code: String unique}}
+        {?[id, label, price, code] <- [[1, 'x', 1.5, 'a'], [2, 'y', 2.5, 'b']]
+         :put items {id => label, price, code}}
+        ",
+            Default::default(),
+        )
+        .unwrap();
+        for alter in [
+            "::alter items add column stock: Int default 10",
+            "::alter items drop column label",
+            "::alter items rename column code to sku",
+        ] {
+            db.run_script(alter, Default::default()).unwrap();
+        }
+        db.backup_db(&path).unwrap();
+
+        let restored = new_cozo_mem().unwrap();
+        restored.restore_backup(&path).unwrap();
+        let query = "?[id, price, sku, stock] := *items{id, price, sku, stock}";
+        let rows = |db: &Db<MemStorage>| {
+            db.run_script(query, Default::default())
+                .unwrap()
+                .into_json()["rows"]
+                .clone()
+        };
+        assert_eq!(
+            rows(&restored),
+            json!([[1, 1.5, "a", 10], [2, 2.5, "b", 10]])
+        );
+        assert_eq!(rows(&restored), rows(&db));
+        let err = restored
+        .run_script(
+            "?[id, price, sku, stock] <- [[3, 0.5, 'a', 1]] :put items {id => price, sku, stock}",
+            Default::default(),
+        )
+        .unwrap_err();
+        assert_eq!(err.code().unwrap().to_string(), "eval::unique_violation");
+
+        // a relation altered in the same way takes the rows of the backup
+        let other = new_cozo_mem().unwrap();
+        other
+        .run_script(
+            ":create items {id: Int => price: Float, sku: String unique, stock: Int default 10}",
+            Default::default(),
+        )
+        .unwrap();
+        other
+            .import_from_backup(&path, &["items".to_string()])
+            .unwrap();
+        assert_eq!(rows(&other), rows(&db));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
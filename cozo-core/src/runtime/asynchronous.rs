@@ -0,0 +1,149 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::num::NonZeroUsize;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::thread;
+
+use crossbeam::channel::{unbounded, Sender};
+use futures::channel::mpsc;
+use futures::channel::oneshot;
+use futures::Stream;
+use miette::{miette, Result};
+
+use crate::runtime::callback::{CallbackOp, CallbackSender};
+use crate::runtime::db::RunningScript;
+use crate::runtime::error::CozoError;
+use crate::{DataValue, Db, NamedRows, Poison, Storage};
+
+/// A change to a relation, as received from [Db::subscribe]
+#[derive(Clone, Debug)]
+pub struct CallbackEvent {
+    /// Whether rows were put or removed
+    pub op: CallbackOp,
+    /// The rows put, or the keys of the rows removed
+    pub new_rows: NamedRows,
+    /// The rows overwritten or removed
+    pub old_rows: NamedRows,
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The threads running the scripts of [Db::run_script_async], started on first use
+#[derive(Clone)]
+pub(crate) struct AsyncPool {
+    threads: usize,
+    jobs: Arc<Mutex<Option<Sender<Job>>>>,
+}
+
+impl Default for AsyncPool {
+    fn default() -> Self {
+        Self::new(thread::available_parallelism().map_or(4, NonZeroUsize::get))
+    }
+}
+
+impl AsyncPool {
+    fn new(threads: usize) -> Self {
+        Self {
+            threads: threads.max(1),
+            jobs: Default::default(),
+        }
+    }
+    fn spawn(&self, job: Job) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let sender = jobs.get_or_insert_with(|| {
+            let (sender, receiver) = unbounded::<Job>();
+            for _ in 0..self.threads {
+                let receiver = receiver.clone();
+                thread::spawn(move || {
+                    for job in receiver {
+                        job()
+                    }
+                });
+            }
+            sender
+        });
+        // the threads only stop once the sender is dropped
+        sender.send(job).unwrap();
+    }
+}
+
+/// The result of a script run by [Db::run_script_async], killing the script if dropped early
+struct ScriptFuture {
+    result: oneshot::Receiver<Result<NamedRows>>,
+    poison: Poison,
+}
+
+impl Future for ScriptFuture {
+    type Output = Result<NamedRows>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match Pin::new(&mut self.result).poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(res)) => Poll::Ready(res),
+            Poll::Ready(Err(_)) => Poll::Ready(Err(miette!("the script stopped without a result"))),
+        }
+    }
+}
+
+impl Drop for ScriptFuture {
+    fn drop(&mut self) {
+        // does nothing if the script is already done
+        self.poison.kill();
+    }
+}
+
+impl<S> Db<S>
+where
+    S: for<'s> Storage<'s> + 'static,
+{
+    /// Run the CozoScript passed in on a pool of threads of its own, so that async code
+    /// can await the result instead of blocking. Dropping the returned future before it
+    /// completes kills the script, as [Db::kill_query] would.
+    pub fn run_script_async(
+        &self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+    ) -> impl Future<Output = Result<NamedRows>> + Send {
+        let (sender, result) = oneshot::channel();
+        let poison = Poison::default();
+        let script = RunningScript::new(payload, poison.clone());
+        let payload = payload.to_string();
+        let db = self.clone();
+        self.async_pool.spawn(Box::new(move || {
+            let cur_vld = db.clock.current_validity();
+            let res = db
+                .run_script_poisoned(&payload, &params, cur_vld, None, &script)
+                .map(|mut ret| {
+                    ret.fill_output_options(db.output_options);
+                    ret
+                })
+                .map_err(CozoError::wrap);
+            // the future may have been dropped
+            let _ = sender.send(res);
+        }));
+        ScriptFuture { result, poison }
+    }
+    /// The number of threads running the scripts of [Db::run_script_async], for this
+    /// database object and the clones made of it afterwards. Defaults to the number of
+    /// CPUs. The threads are started when the first script is run.
+    pub fn set_async_threads(&mut self, threads: usize) {
+        self.async_pool = AsyncPool::new(threads);
+    }
+    /// The changes to the relation committed from now on, as a stream. The stream is
+    /// unregistered once it is dropped and the relation changes again.
+    pub fn subscribe(&self, relation: &str) -> impl Stream<Item = CallbackEvent> + Send {
+        let (sender, receiver) = mpsc::unbounded();
+        self.add_callback(relation, CallbackSender::Stream(sender));
+        receiver
+    }
+}
@@ -0,0 +1,286 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use miette::{bail, Diagnostic, Result};
+use rmp_serde::Serializer;
+use serde::Serialize;
+use smartstring::{LazyCompact, SmartString};
+use thiserror::Error;
+
+use crate::data::symb::Symbol;
+use crate::data::tuple::{decode_tuple_from_key, Tuple, TupleIter, TupleT};
+use crate::data::value::DataValue;
+use crate::parse::SourceSpan;
+use crate::runtime::relation::RelationId;
+use crate::runtime::transact::SessionTx;
+use crate::NamedRows;
+
+/// Which accesses to a stored relation are recorded in the audit log, set by `::audit`
+#[derive(
+    Copy, Clone, Debug, Default, Eq, PartialEq, serde_derive::Serialize, serde_derive::Deserialize,
+)]
+pub(crate) struct AuditFlags {
+    pub(crate) reads: bool,
+    pub(crate) writes: bool,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub(crate) enum AuditOp {
+    Read,
+    Write,
+}
+
+impl AuditOp {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AuditOp::Read => "read",
+            AuditOp::Write => "write",
+        }
+    }
+}
+
+/// The numbers of rows of audited relations read or written by a transaction,
+/// not yet recorded in the audit log
+#[derive(Default)]
+pub(crate) struct AuditCounts(Mutex<BTreeMap<(SmartString<LazyCompact>, AuditOp), usize>>);
+
+impl AuditCounts {
+    fn add(&self, relation: &str, op: AuditOp, n_rows: usize) {
+        *self
+            .0
+            .lock()
+            .unwrap()
+            .entry((relation.into(), op))
+            .or_default() += n_rows;
+    }
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
+}
+
+#[derive(serde_derive::Serialize, serde_derive::Deserialize)]
+struct AuditEntry {
+    identity: Option<String>,
+    relation: String,
+    op: String,
+    rows: usize,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Cannot audit the temp relation '{0}'")]
+#[diagnostic(code(eval::audit_temp_relation))]
+struct AuditTempRelationError(String, #[label] SourceSpan);
+
+fn audit_key_prefix() -> Tuple {
+    vec![DataValue::Null, DataValue::from("AUDIT")]
+}
+
+/// Counts the rows read from an audited relation, adding them to the counts when done
+struct AuditedRows<'a> {
+    inner: TupleIter<'a>,
+    relation: &'a str,
+    n_rows: usize,
+    counts: &'a AuditCounts,
+}
+
+impl Iterator for AuditedRows<'_> {
+    type Item = Result<Tuple>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let ret = self.inner.next();
+        if let Some(Ok(_)) = ret {
+            self.n_rows += 1;
+        }
+        ret
+    }
+}
+
+impl Drop for AuditedRows<'_> {
+    fn drop(&mut self) {
+        if self.n_rows > 0 {
+            self.counts.add(self.relation, AuditOp::Read, self.n_rows);
+        }
+    }
+}
+
+impl<'a> SessionTx<'a> {
+    pub(crate) fn set_audit(&mut self, rel: &Symbol, flags: AuditFlags) -> Result<()> {
+        if rel.name.starts_with('_') {
+            bail!(AuditTempRelationError(rel.name.to_string(), rel.span))
+        }
+        let mut handle = self.get_relation(rel, true)?;
+        handle.audit = flags;
+
+        let name_key = vec![DataValue::Str(handle.name.clone())].encode_as_key(RelationId::SYSTEM);
+        let mut handle_val = vec![];
+        handle
+            .serialize(&mut Serializer::new(&mut handle_val).with_struct_map())
+            .unwrap();
+        self.store_tx.put(&name_key, &handle_val)?;
+        // plans read the flags
+        self.bump_schema_generation()
+    }
+    /// Counts the rows of `rows` as read from `relation`, if it is audited
+    pub(crate) fn audit_reads<'r>(
+        &'r self,
+        relation: Option<&'r str>,
+        rows: TupleIter<'r>,
+    ) -> TupleIter<'r> {
+        match relation {
+            None => rows,
+            Some(relation) => Box::new(AuditedRows {
+                inner: rows,
+                relation,
+                n_rows: 0,
+                counts: &self.audit_counts,
+            }),
+        }
+    }
+    pub(crate) fn audit_writes(&self, relation: &str, n_rows: usize) {
+        if n_rows > 0 {
+            self.audit_counts.add(relation, AuditOp::Write, n_rows);
+        }
+    }
+    /// Takes the rows of audited relations read or written and not yet recorded
+    pub(crate) fn take_audit_counts(&self) -> AuditCounts {
+        AuditCounts(Mutex::new(std::mem::take(
+            &mut *self.audit_counts.0.lock().unwrap(),
+        )))
+    }
+    /// Records the rows of audited relations in `counts` in the audit log,
+    /// as at `at` seconds since the epoch
+    pub(crate) fn record_audit_entries(
+        &mut self,
+        counts: AuditCounts,
+        identity: Option<&str>,
+        at: f64,
+    ) -> Result<()> {
+        let counts = counts.0.into_inner().unwrap();
+        for ((relation, op), rows) in counts {
+            let mut key = audit_key_prefix();
+            key.push(DataValue::from(at));
+            // entries recorded at the same time are kept apart
+            key.push(DataValue::from(rand::random::<i64>()));
+            let entry = AuditEntry {
+                identity: identity.map(|s| s.to_string()),
+                relation: relation.to_string(),
+                op: op.as_str().to_string(),
+                rows,
+            };
+            let mut val = vec![];
+            entry
+                .serialize(&mut Serializer::new(&mut val).with_struct_map())
+                .unwrap();
+            self.store_tx
+                .put(&key.encode_as_key(RelationId::SYSTEM), &val)?;
+        }
+        Ok(())
+    }
+    pub(crate) fn audit_log(&self) -> Result<NamedRows> {
+        let lower = audit_key_prefix().encode_as_key(RelationId::SYSTEM);
+        let mut upper = audit_key_prefix();
+        upper.push(DataValue::Bot);
+        let upper = upper.encode_as_key(RelationId::SYSTEM);
+        let mut rows = vec![];
+        for kv in self.store_tx.range_scan(&lower, &upper) {
+            let (k, v) = kv?;
+            let key = decode_tuple_from_key(&k)?;
+            let entry: AuditEntry = rmp_serde::from_slice(&v)
+                .map_err(|e| miette::miette!("Cannot deserialize audit log entry: {}", e))?;
+            rows.push(vec![
+                key[2].clone(),
+                entry.identity.map_or(DataValue::Null, DataValue::from),
+                DataValue::from(entry.relation),
+                DataValue::from(entry.op),
+                DataValue::from(entry.rows as i64),
+            ]);
+        }
+        Ok(NamedRows::new(
+            vec![
+                "at".to_string(),
+                "identity".to_string(),
+                "relation".to_string(),
+                "op".to_string(),
+                "rows".to_string(),
+            ],
+            rows,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::value::DataValue;
+    use crate::new_cozo_mem;
+
+    #[test]
+    fn test_audit() {
+        let db = new_cozo_mem().unwrap();
+        db.run_script(
+            r"
+        {?[id, name] <- [[1, 'a'], [2, 'b'], [3, 'c']] :create patients {id => name}}
+        {?[id, ward] <- [[1, 'x'], [2, 'y'], [4, 'z']] :create stays {id => ward}}
+        ",
+            Default::default(),
+        )
+        .unwrap();
+        db.run_script("::audit enable patients reads writes", Default::default())
+            .unwrap();
+
+        let query = "?[name, ward] := *patients{id, name}, *stays{id, ward}";
+        let res = db
+            .run_script_as("alice", query, Default::default())
+            .unwrap();
+        assert_eq!(res.rows.len(), 2);
+        // relations not audited are not logged
+        db.run_script_as("alice", "?[ward] := *stays{ward}", Default::default())
+            .unwrap();
+        let log = db.run_script("::audit log", Default::default()).unwrap();
+        assert_eq!(log.rows.len(), 1);
+        // all rows of patients are scanned by the join
+        assert_eq!(
+            log.rows[0][1..],
+            [
+                DataValue::from("alice"),
+                DataValue::from("patients"),
+                DataValue::from("read"),
+                DataValue::from(3)
+            ]
+        );
+
+        db.run_script_as(
+            "bob",
+            "?[id, name] <- [[4, 'd'], [5, 'e']] :put patients {id => name}",
+            Default::default(),
+        )
+        .unwrap();
+        let log = db.run_script("::audit log", Default::default()).unwrap();
+        assert_eq!(log.rows.len(), 2);
+        assert!(log.rows.iter().any(|row| row[1..]
+            == [
+                DataValue::from("bob"),
+                DataValue::from("patients"),
+                DataValue::from("write"),
+                DataValue::from(2)
+            ]));
+
+        db.run_script("::audit disable patients", Default::default())
+            .unwrap();
+        db.run_script_as("alice", query, Default::default())
+            .unwrap();
+        let log = db.run_script("::audit log", Default::default()).unwrap();
+        assert_eq!(log.rows.len(), 2);
+
+        assert!(db
+            .run_script("::audit enable _temp reads", Default::default())
+            .is_err());
+    }
+}
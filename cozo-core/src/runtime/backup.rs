@@ -0,0 +1,494 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// backups are only written to Sqlite files
+#![cfg_attr(not(feature = "storage-sqlite"), allow(dead_code, unused_imports))]
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use miette::{bail, Diagnostic, Result};
+use thiserror::Error;
+
+use crate::data::tuple::{Tuple, TupleT};
+use crate::runtime::db::Db;
+use crate::runtime::relation::RelationId;
+use crate::runtime::transact::SessionTx;
+use crate::storage::Storage;
+
+/// The number of keys written by each transaction of a chunked backup or restore.
+/// A chunk never spans two relations.
+pub(crate) const BACKUP_CHUNK_KEYS: usize = 10_000;
+
+/// Progress of a backup or restore, reported after each chunk of keys is committed
+#[derive(Clone, Debug, Default, serde_derive::Serialize)]
+pub struct BackupProgress {
+    /// The relation or index the chunk belongs to, or `None` for the catalog
+    /// and other metadata of the database
+    pub relation: Option<String>,
+    /// The keys of the relation written so far, by this call
+    pub relation_keys: usize,
+    /// The bytes of keys and values of the relation written so far, by this call
+    pub relation_bytes: usize,
+    /// The keys written so far, by this call
+    pub total_keys: usize,
+    /// The bytes of keys and values written so far, by this call
+    pub total_bytes: usize,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Cannot resume: relation '{0}' is not the same in the backup and the database")]
+#[diagnostic(code(eval::backup_resume_mismatch))]
+#[diagnostic(help(
+    "Only a backup or restore interrupted with no writes to the database since can be resumed"
+))]
+struct BackupResumeMismatch(String);
+
+/// Fails unless every relation in `partial`, a database written by an interrupted backup or
+/// restore, is the same as in `full`, the database it is copied from
+fn check_resumable(full: &SessionTx<'_>, partial: &SessionTx<'_>) -> Result<()> {
+    let full = full.relation_catalog()?;
+    for (name, handle) in partial.relation_catalog()? {
+        if full.get(&name) != Some(&handle) {
+            bail!(BackupResumeMismatch(name.to_string()))
+        }
+    }
+    Ok(())
+}
+
+/// The largest key of the relations written to the database, looked for from the last relation
+/// of its catalog. The catalog itself, which also holds the keys written when the database
+/// is created, is small and copied again when resuming.
+fn last_written_key(tx: &SessionTx<'_>) -> Result<Option<Vec<u8>>> {
+    let mut ids = tx
+        .relation_catalog()?
+        .values()
+        .map(|handle| handle.id)
+        .collect::<Vec<_>>();
+    ids.sort_by_key(|id| Reverse(id.0));
+    for id in ids {
+        let lower = Tuple::default().encode_as_key(id);
+        let upper = Tuple::default().encode_as_key(id.next());
+        let mut last = None;
+        for kv in tx.store_tx.range_scan(&lower, &upper) {
+            last = Some(kv?.0);
+        }
+        if last.is_some() {
+            return Ok(last);
+        }
+    }
+    Ok(None)
+}
+
+/// Copies the key-value pairs, given in ascending order, in chunks written by `put_chunk`,
+/// each reported to `on_progress` after it is written. Returns `false` if `on_progress`
+/// cancelled the copy by returning `false`.
+fn copy_in_chunks(
+    pairs: impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>,
+    names: &BTreeMap<RelationId, String>,
+    mut put_chunk: impl FnMut(Vec<(Vec<u8>, Vec<u8>)>) -> Result<()>,
+    on_progress: &mut impl FnMut(BackupProgress) -> bool,
+) -> Result<bool> {
+    let mut progress = BackupProgress::default();
+    let mut current = None;
+    let mut chunk = vec![];
+    let mut chunk_bytes = 0;
+    let mut flush = |chunk: &mut Vec<(Vec<u8>, Vec<u8>)>,
+                     chunk_bytes: &mut usize,
+                     progress: &mut BackupProgress|
+     -> Result<bool> {
+        let n = chunk.len();
+        put_chunk(std::mem::take(chunk))?;
+        progress.relation_keys += n;
+        progress.relation_bytes += *chunk_bytes;
+        progress.total_keys += n;
+        progress.total_bytes += *chunk_bytes;
+        *chunk_bytes = 0;
+        Ok(on_progress(progress.clone()))
+    };
+    for pair in pairs {
+        let (k, v) = pair?;
+        let id = RelationId::raw_decode(&k);
+        if current != Some(id) {
+            if !chunk.is_empty() && !flush(&mut chunk, &mut chunk_bytes, &mut progress)? {
+                return Ok(false);
+            }
+            current = Some(id);
+            progress.relation = names.get(&id).cloned();
+            progress.relation_keys = 0;
+            progress.relation_bytes = 0;
+        }
+        chunk_bytes += k.len() + v.len();
+        chunk.push((k, v));
+        if chunk.len() == BACKUP_CHUNK_KEYS && !flush(&mut chunk, &mut chunk_bytes, &mut progress)?
+        {
+            return Ok(false);
+        }
+    }
+    if !chunk.is_empty() {
+        return flush(&mut chunk, &mut chunk_bytes, &mut progress);
+    }
+    Ok(true)
+}
+
+/// The names of the relations and indices of the database, by their ids
+fn relation_names(tx: &SessionTx<'_>) -> Result<BTreeMap<RelationId, String>> {
+    Ok(tx
+        .relation_catalog()?
+        .into_iter()
+        .map(|(name, handle)| (handle.id, name.to_string()))
+        .collect())
+}
+
+/// The key-value pairs to copy: all of them, or when resuming after `last_written`,
+/// those after it together with the catalog
+fn pairs_to_copy<'a>(
+    tx: &'a SessionTx<'_>,
+    last_written: Option<Vec<u8>>,
+) -> Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a> {
+    match last_written {
+        None => tx.store_tx.range_scan(&[], &[0xFF]),
+        Some(mut key) => {
+            // the key right after the last one written
+            key.push(0);
+            let catalog_upper = Tuple::default().encode_as_key(RelationId::SYSTEM.next());
+            Box::new(
+                tx.store_tx
+                    .range_scan(&[], &catalog_upper)
+                    .chain(tx.store_tx.range_scan(&key, &[0xFF])),
+            )
+        }
+    }
+}
+
+impl<'s, S: Storage<'s>> Db<S> {
+    /// Backup the running database into an Sqlite file as [Self::backup_db] does, writing
+    /// the keys in chunks of fixed size, each in its own transaction of the backup.
+    /// `on_progress` is called after each chunk is written, and cancels the backup by
+    /// returning `false`. Returns whether the backup is complete: a cancelled or otherwise
+    /// interrupted backup can be continued with [Self::backup_db_resume].
+    #[allow(unused_variables, unused_mut)]
+    pub fn backup_db_with_progress(
+        &'s self,
+        out_file: impl AsRef<Path>,
+        mut on_progress: impl FnMut(BackupProgress) -> bool,
+    ) -> Result<bool> {
+        #[cfg(feature = "storage-sqlite")]
+        {
+            let sqlite_db = crate::new_cozo_sqlite(out_file)?;
+            if sqlite_db.relation_store_id.load(Ordering::SeqCst) != 0 {
+                bail!("Cannot create backup: data exists in the target database.");
+            }
+            self.backup_db_chunked(&sqlite_db, None, &mut on_progress)
+        }
+        #[cfg(not(feature = "storage-sqlite"))]
+        bail!("backup requires the 'storage-sqlite' feature to be enabled")
+    }
+    /// Continue a backup into an Sqlite file that was cancelled or interrupted, after the
+    /// last chunk it wrote, as [Self::backup_db_with_progress] does. The database must not
+    /// have been written to since the backup started, otherwise the backup may be
+    /// inconsistent: it is checked that the relations already in the backup are unchanged.
+    #[allow(unused_variables, unused_mut)]
+    pub fn backup_db_resume(
+        &'s self,
+        out_file: impl AsRef<Path>,
+        mut on_progress: impl FnMut(BackupProgress) -> bool,
+    ) -> Result<bool> {
+        #[cfg(feature = "storage-sqlite")]
+        {
+            let sqlite_db = crate::new_cozo_sqlite(out_file)?;
+            let last_written = {
+                let tx = self.transact()?;
+                let backup_tx = sqlite_db.transact()?;
+                check_resumable(&tx, &backup_tx)?;
+                last_written_key(&backup_tx)?
+            };
+            self.backup_db_chunked(&sqlite_db, last_written, &mut on_progress)
+        }
+        #[cfg(not(feature = "storage-sqlite"))]
+        bail!("backup requires the 'storage-sqlite' feature to be enabled")
+    }
+    #[cfg(feature = "storage-sqlite")]
+    fn backup_db_chunked(
+        &'s self,
+        sqlite_db: &Db<crate::SqliteStorage>,
+        last_written: Option<Vec<u8>>,
+        on_progress: &mut impl FnMut(BackupProgress) -> bool,
+    ) -> Result<bool> {
+        // the keys of removed relations may still be deleted in the background
+        self.db.wait_for_deletions()?;
+        let mut tx = self.transact()?;
+        let names = relation_names(&tx)?;
+        let complete = copy_in_chunks(
+            pairs_to_copy(&tx, last_written),
+            &names,
+            |chunk| sqlite_db.db.batch_put(Box::new(chunk.into_iter().map(Ok))),
+            on_progress,
+        )?;
+        tx.commit_tx()?;
+        Ok(complete)
+    }
+    /// Restore from an Sqlite backup as [Self::restore_backup] does, but writing the keys
+    /// in chunks of fixed size, each in its own transaction. `on_progress` is called after
+    /// each chunk is written, and cancels the restore by returning `false`. If the database
+    /// holds the data of a cancelled or interrupted restore from the same backup, the restore
+    /// continues after the last chunk written. Returns whether the restore is complete.
+    #[allow(unused_variables, unused_mut)]
+    pub fn restore_backup_resume(
+        &'s self,
+        in_file: impl AsRef<Path>,
+        mut on_progress: impl FnMut(BackupProgress) -> bool,
+    ) -> Result<bool> {
+        self.ensure_writable()?;
+        #[cfg(feature = "storage-sqlite")]
+        {
+            let sqlite_db = crate::new_cozo_sqlite_read_only(in_file)?;
+            let mut s_tx = sqlite_db.transact()?;
+            let last_written = {
+                let tx = self.transact()?;
+                check_resumable(&s_tx, &tx)?;
+                last_written_key(&tx)?
+            };
+            let names = relation_names(&s_tx)?;
+            let complete = copy_in_chunks(
+                pairs_to_copy(&s_tx, last_written),
+                &names,
+                |chunk| self.db.batch_put(Box::new(chunk.into_iter().map(Ok))),
+                &mut on_progress,
+            )?;
+            s_tx.commit_tx()?;
+            if complete {
+                // new relations must not take the ids of the restored ones
+                self.load_last_ids()?;
+            }
+            Ok(complete)
+        }
+        #[cfg(not(feature = "storage-sqlite"))]
+        bail!("backup requires the 'storage-sqlite' feature to be enabled")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use itertools::Itertools;
+    use serde_json::json;
+
+    use crate::data::value::DataValue;
+    use crate::{new_cozo_mem, Db, Storage};
+
+    #[cfg(feature = "storage-sqlite")]
+    #[test]
+    fn test_backup_with_progress_resume() {
+        use crate::runtime::backup::BACKUP_CHUNK_KEYS;
+        use crate::BackupProgress;
+
+        fn all_pairs<'s, S: Storage<'s>>(db: &'s Db<S>) -> Vec<(Vec<u8>, Vec<u8>)> {
+            let tx = db.transact().unwrap();
+            let pairs = tx.store_tx.range_scan(&[], &[0xFF]).try_collect().unwrap();
+            pairs
+        }
+
+        fn temp_path() -> std::path::PathBuf {
+            std::env::temp_dir().join(format!("cozo-test-{}.db", rand::random::<u64>()))
+        }
+
+        let db = new_cozo_mem().unwrap();
+        db.run_script(
+            r"
+        {?[k, v] := k in int_range(25000), v = k * 2 :create nums {k => v}}
+        {?[k, v] <- [[1, 'a'], [2, 'b']] :create strs {k => v}}
+        ",
+            Default::default(),
+        )
+        .unwrap();
+        db.run_script("::index create nums:by_v {v}", Default::default())
+            .unwrap();
+
+        let full = temp_path();
+        let mut reports = vec![];
+        assert!(db
+            .backup_db_with_progress(&full, |progress| {
+                reports.push(progress);
+                true
+            })
+            .unwrap());
+        // chunks never exceed the chunk size, nor span relations
+        assert!(reports
+            .iter()
+            .tuple_windows()
+            .all(|(a, b)| b.total_keys - a.total_keys <= BACKUP_CHUNK_KEYS));
+        assert_eq!(
+            reports
+                .iter()
+                .filter(|p| p.relation.as_deref() == Some("nums"))
+                .count(),
+            3
+        );
+        let last_of = |name: &str| -> BackupProgress {
+            reports
+                .iter()
+                .rfind(|p| p.relation.as_deref() == Some(name))
+                .unwrap()
+                .clone()
+        };
+        assert_eq!(last_of("nums").relation_keys, 25000);
+        assert_eq!(last_of("nums:by_v").relation_keys, 25000);
+        assert_eq!(last_of("strs").relation_keys, 2);
+        let last = reports.last().unwrap();
+        assert_eq!(last.total_keys, all_pairs(&db).len());
+        assert!(last.total_bytes > last.total_keys);
+
+        // cancelled after two chunks, and resumed
+        let partial = temp_path();
+        let mut n_chunks = 0;
+        assert!(!db
+            .backup_db_with_progress(&partial, |_| {
+                n_chunks += 1;
+                n_chunks < 2
+            })
+            .unwrap());
+        assert!(db.backup_db_with_progress(&partial, |_| true).is_err());
+        let mut resumed_keys = 0;
+        assert!(db
+            .backup_db_resume(&partial, |progress| {
+                resumed_keys = progress.total_keys;
+                true
+            })
+            .unwrap());
+        assert!(resumed_keys > 0 && resumed_keys < last.total_keys);
+        assert_eq!(
+            all_pairs(&crate::new_cozo_sqlite(&partial).unwrap()),
+            all_pairs(&crate::new_cozo_sqlite(&full).unwrap())
+        );
+
+        // the same for restores
+        let restored = new_cozo_mem().unwrap();
+        let mut n_chunks = 0;
+        assert!(!restored
+            .restore_backup_resume(&partial, |_| {
+                n_chunks += 1;
+                n_chunks < 3
+            })
+            .unwrap());
+        assert!(restored.restore_backup_resume(&partial, |_| true).unwrap());
+        assert_eq!(all_pairs(&restored), all_pairs(&db));
+        assert_eq!(
+            restored
+                .run_script("?[v] := *strs{k: 2, v}", Default::default())
+                .unwrap()
+                .into_json()["rows"],
+            json!([["b"]])
+        );
+        restored
+            .run_script(":create more {k}", Default::default())
+            .unwrap();
+        assert_eq!(
+            restored
+                .run_script("?[count(k)] := *nums{k}", Default::default())
+                .unwrap()
+                .into_json()["rows"],
+            json!([[25000]])
+        );
+
+        // the database is not resumed into if it holds other data
+        let other = new_cozo_mem().unwrap();
+        other
+            .run_script(":create nums {k: Int => v: Int}", Default::default())
+            .unwrap();
+        assert!(other.restore_backup_resume(&full, |_| true).is_err());
+    }
+
+    #[cfg(feature = "storage-sqlite")]
+    #[test]
+    fn test_restore_relation_renamed() {
+        let path = std::env::temp_dir().join(format!("cozo-test-{}.db", rand::random::<u64>()));
+        let backup = new_cozo_mem().unwrap();
+        backup
+            .run_script(
+                r"
+            {?[id, email] <- [[1, 'a'], [2, 'b']] :create users {id: Int => email: String unique}}
+            {?[k] <- [[1]] :create other {k}}
+            ",
+                Default::default(),
+            )
+            .unwrap();
+        backup.backup_db(&path).unwrap();
+
+        let db = new_cozo_mem().unwrap();
+        db.run_script(
+            r"
+        {?[id, email] <- [[9, 'z']] :create users {id: Int => email: String unique}}
+        {:create log {id: Int}}
+        {:create wrong {id: Int => email: Int}}
+        ",
+            Default::default(),
+        )
+        .unwrap();
+        db.run_script(
+            "::set_triggers users on put { ?[id] := _new[id, _] :put log {id} }",
+            Default::default(),
+        )
+        .unwrap();
+        let rows = |query: &str| {
+            db.run_script(query, Default::default())
+                .unwrap()
+                .into_json()["rows"]
+                .clone()
+        };
+
+        // restored under a new name, the relation is created with the columns of the backup
+        let params =
+            BTreeMap::from([("path".to_string(), DataValue::from(path.to_str().unwrap()))]);
+        db.run_script(
+            "::restore_relation $path users as users_old",
+            params.clone(),
+        )
+        .unwrap();
+        assert_eq!(
+            rows("?[id, email] := *users_old{id, email}"),
+            json!([[1, "a"], [2, "b"]])
+        );
+        assert_eq!(rows("?[id, email] := *users{id, email}"), json!([[9, "z"]]));
+        // with its unique columns
+        assert!(db
+            .run_script(
+                "?[id, email] <- [[3, 'a']] :put users_old {id => email}",
+                Default::default()
+            )
+            .is_err());
+
+        // into an existing relation, without running its triggers
+        db.import_from_backup_renamed(&path, &[("users".to_string(), "users".to_string())])
+            .unwrap();
+        assert_eq!(
+            rows("?[id, email] := *users{id, email}"),
+            json!([[1, "a"], [2, "b"], [9, "z"]])
+        );
+        assert_eq!(rows("?[id] := *log{id}"), json!([]));
+
+        // columns must match, and nothing is imported if any relation fails
+        let err = db
+            .run_script(
+                "::restore_relation $path other as fresh, users as wrong",
+                params,
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.code().unwrap().to_string(),
+            "eval::backup_schema_mismatch"
+        );
+        assert!(db
+            .run_script("?[k] := *fresh{k}", Default::default())
+            .is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,410 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::iter;
+
+use itertools::Itertools;
+use miette::{bail, Diagnostic, Result, WrapErr};
+use smartstring::SmartString;
+use thiserror::Error;
+
+use crate::data::program::RelationOp;
+use crate::data::relation::{ColumnDef, StoredRelationMetadata};
+use crate::data::symb::Symbol;
+use crate::data::tuple::Tuple;
+use crate::data::value::{DataValue, ValidityTs};
+use crate::runtime::callback::CallbackCollector;
+use crate::runtime::db::{Db, ImportIntoIndex};
+use crate::runtime::relation::{
+    AccessLevel, InputRelationHandle, InsufficientAccessLevel, RelationHandle,
+};
+use crate::storage::Storage;
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Relation '{0}' has no column '{1}' to import into")]
+#[diagnostic(code(import::unknown_column))]
+struct UnknownImportColumn(String, String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Column '{1}' of relation '{0}' has no default and must be given")]
+#[diagnostic(code(import::missing_column))]
+struct MissingImportColumn(String, String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Row {0} has {1} values, but {2} headers are given")]
+#[diagnostic(code(import::bad_row_arity))]
+struct ImportRowArity(usize, usize, usize);
+
+/// Options for [Db::import_rows]
+#[derive(Clone, Debug)]
+pub struct ImportRowsOptions {
+    /// The number of rows written in each transaction, or each storage batch if unchecked
+    pub chunk_size: usize,
+    /// Write the encoded rows straight into the storage, without running triggers or callbacks,
+    /// maintaining indices, checking constraints or trimming history. Stored rows with the same
+    /// keys are overwritten. Indices of the relation, including the hidden indices of unique
+    /// columns, are left stale and must be dropped and created again afterwards. Each chunk
+    /// is written as a storage batch, not in a transaction.
+    pub unchecked: bool,
+}
+
+impl Default for ImportRowsOptions {
+    fn default() -> Self {
+        Self {
+            chunk_size: 100_000,
+            unchecked: false,
+        }
+    }
+}
+
+impl<'s, S: Storage<'s>> Db<S> {
+    /// Put the `rows` into the stored `relation`, as `:put` does but without parsing a script.
+    /// Each row holds the values of the columns named by `headers`, in that order, and the
+    /// columns not given take their defaults. The rows are validated against the relation
+    /// as they are read and written in chunks, each in its own transaction, so inputs of any size
+    /// can be imported. An error stops the import, leaving the chunks before it written.
+    /// Returns the number of rows written.
+    pub fn import_rows(
+        &'s self,
+        relation: &str,
+        headers: &[&str],
+        rows: impl Iterator<Item = Vec<DataValue>>,
+        options: ImportRowsOptions,
+    ) -> Result<usize> {
+        self.import_rows_with_progress(relation, headers, rows, options, |_| true)
+    }
+    /// Put the `rows` into the stored `relation` as [Self::import_rows] does, calling
+    /// `on_progress` with the number of rows written so far after each chunk.
+    /// The import stops if it returns `false`.
+    pub fn import_rows_with_progress(
+        &'s self,
+        relation: &str,
+        headers: &[&str],
+        rows: impl Iterator<Item = Vec<DataValue>>,
+        options: ImportRowsOptions,
+        mut on_progress: impl FnMut(usize) -> bool,
+    ) -> Result<usize> {
+        self.ensure_writable()?;
+        if relation.contains(':') {
+            bail!(ImportIntoIndex(relation.to_string()))
+        }
+        let rel_name = SmartString::from(relation);
+        let lock = self
+            .obtain_relation_locks(iter::once(&rel_name))
+            .pop()
+            .unwrap();
+        let _guard = lock.read().unwrap();
+        let cur_vld = self.clock.current_validity();
+        let handle = self.transact()?.get_relation(relation, false)?;
+        if handle.access_level < AccessLevel::Protected {
+            bail!(InsufficientAccessLevel(
+                handle.name.to_string(),
+                "data import".to_string(),
+                handle.access_level
+            ));
+        }
+        let columns = handle
+            .metadata
+            .keys
+            .iter()
+            .chain(handle.metadata.non_keys.iter())
+            .collect_vec();
+        // for each header, its column
+        let given: Vec<&ColumnDef> = headers
+            .iter()
+            .map(|h| {
+                columns
+                    .iter()
+                    .find(|col| col.name.as_str() == *h)
+                    .copied()
+                    .ok_or_else(|| UnknownImportColumn(relation.to_string(), h.to_string()))
+            })
+            .try_collect()?;
+        for col in &columns {
+            if col.default_gen.is_none() && !headers.contains(&col.name.as_str()) {
+                bail!(MissingImportColumn(
+                    relation.to_string(),
+                    col.name.to_string()
+                ))
+            }
+        }
+        let target = if options.unchecked {
+            ImportTarget::Unchecked(UncheckedImport::new(&handle, headers)?)
+        } else {
+            let (meta, bindings) = checked_import_handle(&handle, &given);
+            ImportTarget::Checked(meta, bindings)
+        };
+
+        let mut n = 0;
+        for chunk in &rows.enumerate().chunks(options.chunk_size.max(1)) {
+            let mut tuples = vec![];
+            for (i, row) in chunk {
+                if row.len() != headers.len() {
+                    bail!(ImportRowArity(i, row.len(), headers.len()))
+                }
+                let row: Tuple = row
+                    .into_iter()
+                    .zip(given.iter())
+                    .map(|(val, col)| {
+                        col.typing.coerce(val, cur_vld).wrap_err_with(|| {
+                            format!("when importing the column '{}' of row {i}", col.name)
+                        })
+                    })
+                    .try_collect()?;
+                tuples.push(row);
+            }
+            n += tuples.len();
+            match &target {
+                ImportTarget::Checked(meta, bindings) => {
+                    self.put_chunk(tuples, meta, bindings, cur_vld)?
+                }
+                ImportTarget::Unchecked(unchecked) => unchecked.put_chunk(self, tuples)?,
+            }
+            if !on_progress(n) {
+                break;
+            }
+        }
+        Ok(n)
+    }
+    /// Puts the rows in a transaction of their own, as `:put` does
+    fn put_chunk(
+        &'s self,
+        tuples: Vec<Tuple>,
+        meta: &InputRelationHandle,
+        bindings: &[Symbol],
+        cur_vld: ValidityTs,
+    ) -> Result<()> {
+        let callback_targets = self.current_callback_targets();
+        let mut callback_collector = CallbackCollector::default();
+        let mut tx = self.transact_write()?;
+        let clock = tx.clock.clone();
+        let (cleanups, _) = clock.scoped(|| {
+            tx.execute_relation(
+                self,
+                tuples.into_iter(),
+                RelationOp::Put,
+                meta,
+                bindings,
+                cur_vld,
+                &callback_targets,
+                &mut callback_collector,
+                true,
+            )
+        })?;
+        self.commit_audited(tx, None)?;
+        #[cfg(not(target_arch = "wasm32"))]
+        if !callback_collector.is_empty() {
+            self.send_callbacks(callback_collector)
+        }
+        for (lower, upper) in cleanups {
+            self.db.del_range(&lower, &upper)?;
+        }
+        Ok(())
+    }
+}
+
+enum ImportTarget {
+    /// the handle to write with and the headers of the rows
+    Checked(InputRelationHandle, Vec<Symbol>),
+    Unchecked(UncheckedImport),
+}
+
+/// The handle for writing the `given` columns of the relation, as parsed from
+/// `:put <relation> {<given columns>}`, together with the headers of the rows
+fn checked_import_handle(
+    handle: &RelationHandle,
+    given: &[&ColumnDef],
+) -> (InputRelationHandle, Vec<Symbol>) {
+    let (keys, non_keys): (Vec<&ColumnDef>, Vec<&ColumnDef>) = given
+        .iter()
+        .copied()
+        .partition(|col| handle.metadata.keys.iter().any(|k| k.name == col.name));
+    let meta = InputRelationHandle {
+        name: Symbol::new(handle.name.as_str(), Default::default()),
+        key_bindings: col_symbols(&keys),
+        dep_bindings: col_symbols(&non_keys),
+        metadata: StoredRelationMetadata {
+            keys: keys.into_iter().cloned().collect(),
+            non_keys: non_keys.into_iter().cloned().collect(),
+        },
+        span: Default::default(),
+        params: Default::default(),
+    };
+    (meta, col_symbols(given))
+}
+
+fn col_symbols(cols: &[&ColumnDef]) -> Vec<Symbol> {
+    cols.iter()
+        .map(|col| Symbol::new(col.name.as_str(), Default::default()))
+        .collect()
+}
+
+/// Writes rows straight into the storage for [ImportRowsOptions::unchecked]
+struct UncheckedImport {
+    handle: RelationHandle,
+    /// where the value of each column of the relation comes from
+    sources: Vec<ColumnSource>,
+}
+
+enum ColumnSource {
+    /// the position of the value in the given rows
+    Given(usize),
+    Default(DataValue),
+}
+
+impl UncheckedImport {
+    fn new(handle: &RelationHandle, headers: &[&str]) -> Result<Self> {
+        let sources = handle
+            .metadata
+            .keys
+            .iter()
+            .chain(handle.metadata.non_keys.iter())
+            .map(|col| -> Result<ColumnSource> {
+                Ok(match headers.iter().position(|h| col.name.as_str() == *h) {
+                    Some(idx) => ColumnSource::Given(idx),
+                    // only columns with defaults can be left out
+                    None => {
+                        ColumnSource::Default(col.default_gen.clone().unwrap().eval_to_const()?)
+                    }
+                })
+            })
+            .try_collect()?;
+        Ok(Self {
+            handle: handle.clone(),
+            sources,
+        })
+    }
+    fn put_chunk<'s, S: Storage<'s>>(&self, db: &'s Db<S>, tuples: Vec<Tuple>) -> Result<()> {
+        let mut pairs = Vec::with_capacity(tuples.len());
+        for given in tuples {
+            let row: Tuple = self
+                .sources
+                .iter()
+                .map(|src| match src {
+                    ColumnSource::Given(idx) => given[*idx].clone(),
+                    ColumnSource::Default(default) => default.clone(),
+                })
+                .collect();
+            let key = self.handle.encode_key_for_store(&row, Default::default())?;
+            let val = self.handle.encode_val_for_store(&row, Default::default())?;
+            pairs.push((key, val));
+        }
+        // the storage wants the keys in order without duplicates, and of the rows with
+        // the same key the last one is kept, as when putting them one by one
+        pairs.reverse();
+        pairs.sort_by(|(a, _), (b, _)| a.cmp(b));
+        pairs.dedup_by(|(a, _), (b, _)| a == b);
+        db.db.batch_put(Box::new(pairs.into_iter().map(Ok)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::value::DataValue;
+    use crate::new_cozo_mem;
+
+    #[test]
+    fn test_import_rows() {
+        use crate::ImportRowsOptions;
+
+        let db = new_cozo_mem().unwrap();
+        for rel in ["checked", "unchecked", "cancelled", "failing"] {
+            db.run_script(
+                &format!(
+                    "{{:create {rel} {{k: Int => v: String, n: Int default 0}}}}
+                {{:create {rel}_log {{k: Int}}}}"
+                ),
+                Default::default(),
+            )
+            .unwrap();
+            db.run_script(
+                &format!(
+                    "::set_triggers {rel} on put {{ ?[k] := _new[k, _, _] :put {rel}_log {{k}} }}"
+                ),
+                Default::default(),
+            )
+            .unwrap();
+        }
+        let rows =
+            || (0..10).map(|i| vec![DataValue::from(i % 7), DataValue::from(format!("v{i}"))]);
+        let read = |rel: &str| {
+            db.run_script(
+                &format!("?[k, v, n] := *{rel}{{k, v, n}}"),
+                Default::default(),
+            )
+            .unwrap()
+            .rows
+        };
+        let logged = |rel: &str| {
+            db.run_script(&format!("?[k] := *{rel}_log{{k}}"), Default::default())
+                .unwrap()
+                .rows
+                .len()
+        };
+        let options = |chunk_size, unchecked| ImportRowsOptions {
+            chunk_size,
+            unchecked,
+        };
+
+        let mut progress = vec![];
+        let reversed = rows().map(|row| row.into_iter().rev().collect());
+        let n = db
+            .import_rows_with_progress("checked", &["v", "k"], reversed, options(4, false), |n| {
+                progress.push(n);
+                true
+            })
+            .unwrap();
+        assert_eq!(n, 10);
+        assert_eq!(progress, vec![4, 8, 10]);
+        let n = db
+            .import_rows("unchecked", &["k", "v"], rows(), options(4, true))
+            .unwrap();
+        assert_eq!(n, 10);
+        // in both modes later rows replace earlier ones with the same keys,
+        // and defaults are filled in, but only checked imports run triggers
+        assert_eq!(read("checked"), read("unchecked"));
+        assert_eq!(read("unchecked").len(), 7);
+        assert_eq!(
+            read("unchecked")[0],
+            vec![
+                DataValue::from(0),
+                DataValue::from("v7"),
+                DataValue::from(0)
+            ]
+        );
+        assert_eq!(logged("checked"), 7);
+        assert_eq!(logged("unchecked"), 0);
+
+        // the import stops after the chunk for which the progress callback returns false
+        let n = db
+            .import_rows_with_progress("cancelled", &["k", "v"], rows(), options(3, true), |_| {
+                false
+            })
+            .unwrap();
+        assert_eq!(n, 3);
+        assert_eq!(read("cancelled").len(), 3);
+
+        // errors stop the import, keeping the chunks written before
+        let bad = rows().enumerate().map(|(i, mut row)| {
+            if i == 5 {
+                row.pop();
+            }
+            row
+        });
+        assert!(db
+            .import_rows("failing", &["k", "v"], bad, options(2, false))
+            .is_err());
+        assert_eq!(read("failing").len(), 4);
+        assert!(db
+            .import_rows("failing", &["k", "w"], rows(), options(2, false))
+            .is_err());
+        assert!(db
+            .import_rows("failing", &["v"], rows(), options(2, false))
+            .is_err());
+    }
+}
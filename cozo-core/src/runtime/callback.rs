@@ -10,8 +10,12 @@ use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{Display, Formatter};
 
 use crossbeam::channel::Sender;
+#[cfg(feature = "async")]
+use futures::channel::mpsc::UnboundedSender;
 use smartstring::{LazyCompact, SmartString};
 
+#[cfg(feature = "async")]
+use crate::runtime::asynchronous::CallbackEvent;
 use crate::{Db, NamedRows, Storage};
 
 /// Represents the kind of operation that triggered the callback
@@ -45,7 +49,32 @@ impl CallbackOp {
 #[allow(dead_code)]
 pub struct CallbackDeclaration {
     pub(crate) dependent: SmartString<LazyCompact>,
-    pub(crate) sender: Sender<(CallbackOp, NamedRows, NamedRows)>,
+    pub(crate) sender: CallbackSender,
+}
+
+/// Where the changes to a relation are sent
+pub(crate) enum CallbackSender {
+    Channel(Sender<(CallbackOp, NamedRows, NamedRows)>),
+    #[cfg(feature = "async")]
+    Stream(UnboundedSender<CallbackEvent>),
+}
+
+impl CallbackSender {
+    /// Returns `false` if the receiving end is gone
+    #[cfg(not(target_arch = "wasm32"))]
+    fn send(&self, op: CallbackOp, new: NamedRows, old: NamedRows) -> bool {
+        match self {
+            CallbackSender::Channel(sender) => sender.send((op, new, old)).is_ok(),
+            #[cfg(feature = "async")]
+            CallbackSender::Stream(sender) => sender
+                .unbounded_send(CallbackEvent {
+                    op,
+                    new_rows: new,
+                    old_rows: old,
+                })
+                .is_ok(),
+        }
+    }
 }
 
 pub(crate) type CallbackCollector =
@@ -61,13 +90,17 @@ impl<'s, S: Storage<'s>> Db<S> {
     pub(crate) fn current_callback_targets(&self) -> BTreeSet<SmartString<LazyCompact>> {
         #[cfg(not(target_arch = "wasm32"))]
         {
-            self.event_callbacks
+            let mut targets: BTreeSet<_> = self
+                .event_callbacks
                 .read()
                 .unwrap()
                 .1
                 .keys()
                 .cloned()
-                .collect()
+                .collect();
+            // the changes collected for callbacks also wake up subscriptions to queries
+            targets.extend(self.subscriptions.read().unwrap().relations_read().cloned());
+            targets
         }
 
         #[cfg(target_arch = "wasm32")]
@@ -77,6 +110,7 @@ impl<'s, S: Storage<'s>> Db<S> {
     }
     #[cfg(not(target_arch = "wasm32"))]
     pub(crate) fn send_callbacks(&'s self, collector: CallbackCollector) {
+        self.notify_subscriptions(collector.keys());
         let mut to_remove = vec![];
 
         for (table, vals) in collector {
@@ -87,14 +121,14 @@ impl<'s, S: Storage<'s>> Db<S> {
                     if let Some(fst) = it.next() {
                         for cb_id in it {
                             if let Some(cb) = cbs.get(cb_id) {
-                                if cb.sender.send((op, new.clone(), old.clone())).is_err() {
+                                if !cb.sender.send(op, new.clone(), old.clone()) {
                                     to_remove.push(*cb_id)
                                 }
                             }
                         }
 
                         if let Some(cb) = cbs.get(fst) {
-                            if cb.sender.send((op, new, old)).is_err() {
+                            if !cb.sender.send(op, new, old) {
                                 to_remove.push(*fst)
                             }
                         }
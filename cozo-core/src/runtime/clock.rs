@@ -0,0 +1,237 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crossbeam::sync::ShardedLock;
+
+use crate::data::functions::current_validity;
+use crate::data::value::ValidityTs;
+use crate::runtime::db::seconds_since_the_epoch;
+
+/// A function returning the current time in microseconds since the epoch
+pub(crate) type ClockFn = Arc<dyn Fn() -> i64 + Send + Sync>;
+
+/// The source of the current time of a database, see [crate::Db::set_clock].
+/// Shared by all clones of the database. Reads the system time unless replaced.
+#[derive(Clone, Default)]
+pub(crate) struct Clock {
+    func: Arc<ShardedLock<Option<ClockFn>>>,
+    /// the time of the last version 7 UUID generated from the installed clock since it was
+    /// installed, see [Clock::next_uuid_v7_time]
+    last_uuid_v7_time: Arc<AtomicU64>,
+}
+
+/// The time of the last version 7 UUID generated from the system time, in milliseconds
+/// shifted left by 12 bits, plus a counter making the UUIDs generated in the same millisecond
+/// ordered
+static LAST_UUID_V7_TIME: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    static CURRENT_CLOCK: RefCell<Option<Clock>> = const { RefCell::new(None) };
+}
+
+/// Puts back the clock installed before [Clock::scoped], also on panics
+struct RestoreClock(Option<Clock>);
+
+impl Drop for RestoreClock {
+    fn drop(&mut self) {
+        let prev = self.0.take();
+        CURRENT_CLOCK.with(|cur| *cur.borrow_mut() = prev);
+    }
+}
+
+impl Clock {
+    /// Runs `f` with the functions of expressions reading the time on the current thread,
+    /// such as `now()`, reading it from this clock
+    pub(crate) fn scoped<T>(&self, f: impl FnOnce() -> T) -> T {
+        let prev = CURRENT_CLOCK.with(|cur| cur.replace(Some(self.clone())));
+        let _restore = RestoreClock(prev);
+        f()
+    }
+    /// The current time in microseconds since the epoch, read from the clock installed on
+    /// the current thread by [Clock::scoped], or from the system time if there is none
+    pub(crate) fn current_micros() -> i64 {
+        let installed = CURRENT_CLOCK.with(|cur| cur.borrow().as_ref().and_then(Clock::virtual_fn));
+        match installed {
+            None => current_validity().0 .0,
+            Some(f) => f(),
+        }
+    }
+    /// The time for a new version 7 UUID, read as by [Clock::current_micros], in milliseconds
+    /// shifted left by 12 bits, plus a counter keeping the UUIDs generated from the same clock
+    /// ordered within a millisecond and when the clock goes back
+    pub(crate) fn next_uuid_v7_time() -> u64 {
+        let installed = CURRENT_CLOCK.with(|cur| {
+            let cur = cur.borrow();
+            let clock = cur.as_ref()?;
+            Some((clock.virtual_fn()?, clock.last_uuid_v7_time.clone()))
+        });
+        let (micros, last) = match &installed {
+            None => (current_validity().0 .0, &LAST_UUID_V7_TIME),
+            Some((f, last)) => (f(), &**last),
+        };
+        let now = ((micros / 1000) as u64) << 12;
+        let prev = last
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |last| {
+                Some(now.max(last + 1))
+            })
+            .unwrap();
+        now.max(prev + 1)
+    }
+    pub(crate) fn set(&self, f: Option<ClockFn>) {
+        *self.func.write().unwrap() = f;
+        self.last_uuid_v7_time.store(0, Ordering::Release);
+    }
+    /// The installed clock, `None` for the system clock
+    pub(crate) fn virtual_fn(&self) -> Option<ClockFn> {
+        self.func.read().unwrap().clone()
+    }
+    pub(crate) fn current_validity(&self) -> ValidityTs {
+        match &*self.func.read().unwrap() {
+            None => current_validity(),
+            Some(f) => ValidityTs(Reverse(f())),
+        }
+    }
+    pub(crate) fn seconds_since_the_epoch(&self) -> miette::Result<f64> {
+        match &*self.func.read().unwrap() {
+            None => seconds_since_the_epoch(),
+            Some(f) => Ok(f() as f64 / 1000000.),
+        }
+    }
+}
+
+/// A clock that only moves when told to, for tests that must not depend on the time
+/// they run at or sleep. Install it with [crate::Db::set_clock]:
+///
+/// ```
+/// use cozo::{new_cozo_mem, VirtualClock};
+///
+/// let db = new_cozo_mem().unwrap();
+/// let clock = VirtualClock::new(1_000_000);
+/// db.set_clock(clock.as_clock_fn());
+/// clock.advance(60_000_000);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct VirtualClock(Arc<AtomicI64>);
+
+impl VirtualClock {
+    /// A clock stopped at `micros` microseconds since the epoch
+    pub fn new(micros: i64) -> Self {
+        Self(Arc::new(AtomicI64::new(micros)))
+    }
+    /// The time of the clock, in microseconds since the epoch
+    pub fn now(&self) -> i64 {
+        self.0.load(Ordering::Acquire)
+    }
+    /// Moves the clock forward by `micros` microseconds
+    pub fn advance(&self, micros: i64) {
+        self.0.fetch_add(micros, Ordering::AcqRel);
+    }
+    /// Sets the clock to `micros` microseconds since the epoch
+    pub fn set(&self, micros: i64) {
+        self.0.store(micros, Ordering::Release);
+    }
+    /// The function to pass to [crate::Db::set_clock]
+    pub fn as_clock_fn(&self) -> Box<dyn Fn() -> i64 + Send + Sync> {
+        let clock = self.clone();
+        Box::new(move || clock.now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::data::value::DataValue;
+    use crate::{new_cozo_mem, VirtualClock};
+
+    #[test]
+    fn test_virtual_clock() {
+        let db = new_cozo_mem().unwrap();
+        let clock = VirtualClock::new(1_000_000_000);
+        db.set_clock(clock.as_clock_fn());
+        db.run_script(
+            r"
+        {:create vld {k, at: Validity => v}}
+        {?[k, at, v] <- [[1, 'ASSERT', 'a'], [2, [1010000000, true], 'b']] :put vld {k, at => v}}
+        ",
+            Default::default(),
+        )
+        .unwrap();
+        let query = "?[k, v] := *vld{k, v @ 'NOW'}";
+        let res = db.run_script(query, Default::default()).unwrap();
+        assert_eq!(res.into_json()["rows"], json!([[1, "a"]]));
+
+        // rows asserted in the future become visible once the clock gets there
+        clock.advance(10_000_000);
+        let res = db.run_script(query, Default::default()).unwrap();
+        assert_eq!(res.into_json()["rows"], json!([[1, "a"], [2, "b"]]));
+
+        db.run_script("::audit enable vld writes", Default::default())
+            .unwrap();
+        db.run_script(
+            "?[k, at, v] <- [[1, 'RETRACT', null]] :put vld {k, at => v}",
+            Default::default(),
+        )
+        .unwrap();
+        let res = db.run_script(query, Default::default()).unwrap();
+        assert_eq!(res.into_json()["rows"], json!([[2, "b"]]));
+        let res = db
+            .run_script("?[k, at] := *vld{k, at}", Default::default())
+            .unwrap();
+        assert_eq!(
+            res.into_json()["rows"],
+            json!([
+                [1, [1010000000, false]],
+                [1, [1000000000, true]],
+                [2, [1010000000, true]]
+            ])
+        );
+        let log = db.run_script("::audit log", Default::default()).unwrap();
+        assert_eq!(log.rows[0][0], DataValue::from(1010.));
+
+        db.use_system_clock();
+        let res = db.run_script(query, Default::default()).unwrap();
+        assert_eq!(res.into_json()["rows"], json!([[2, "b"]]));
+    }
+
+    #[test]
+    fn test_virtual_clock_in_expressions() {
+        let db = new_cozo_mem().unwrap();
+        let clock = VirtualClock::new(1_000_000_000);
+        db.set_clock(clock.as_clock_fn());
+        let query = r"
+        ?[t, u, v] := t = now(), u = uuid_timestamp(rand_uuid_v1()),
+                      v = uuid_timestamp(rand_uuid_v7())
+        ";
+        let res = db.run_script(query, Default::default()).unwrap();
+        assert_eq!(res.into_json()["rows"], json!([[1000., 1000., 1000.]]));
+
+        db.run_script(
+            ":create docs {id => body, updated_at: Float auto_update now()}",
+            Default::default(),
+        )
+        .unwrap();
+        let put = "?[id, body] <- [[1, 'a']] :put docs {id => body}";
+        let get = "?[updated_at] := *docs{id: 1, updated_at}";
+        db.run_script(put, Default::default()).unwrap();
+        let res = db.run_script(get, Default::default()).unwrap();
+        assert_eq!(res.into_json()["rows"], json!([[1000.]]));
+
+        clock.advance(60_000_000);
+        let res = db.run_script(query, Default::default()).unwrap();
+        assert_eq!(res.into_json()["rows"], json!([[1060., 1060., 1060.]]));
+        db.run_script(put, Default::default()).unwrap();
+        let res = db.run_script(get, Default::default()).unwrap();
+        assert_eq!(res.into_json()["rows"], json!([[1060.]]));
+    }
+}
@@ -0,0 +1,500 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::fmt::{Display, Formatter};
+
+use itertools::Itertools;
+use miette::{bail, Diagnostic, Result};
+use smartstring::{LazyCompact, SmartString};
+use thiserror::Error;
+
+use crate::data::symb::Symbol;
+use crate::data::tuple::{Tuple, TupleT};
+use crate::data::value::{DataValue, LARGEST_UTF_CHAR};
+use crate::runtime::relation::{
+    extend_tuple_from_v, foreign_key_index_name, RelationHandle, RelationId,
+};
+use crate::runtime::transact::SessionTx;
+use crate::NamedRows;
+
+/// A column of a stored relation whose non-null values must be keys of another relation,
+/// declared by `::constraint create`. It is kept by both relations.
+#[derive(Debug, Clone, Eq, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) struct ForeignKey {
+    /// the referencing relation
+    pub(crate) relation: SmartString<LazyCompact>,
+    pub(crate) column: SmartString<LazyCompact>,
+    /// the referenced relation, whose only key column is `target_column`
+    pub(crate) target: SmartString<LazyCompact>,
+    pub(crate) target_column: SmartString<LazyCompact>,
+    /// whether removing a referenced row removes the rows referencing it instead of failing
+    pub(crate) cascade: bool,
+}
+
+impl Display for ForeignKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}.{} references {}.{}",
+            self.relation, self.column, self.target, self.target_column
+        )
+    }
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Constraint `{0}` violated: no row of '{1}' has the key {2:?}")]
+#[diagnostic(code(eval::foreign_key_violation))]
+struct MissingReferencedRow(String, String, DataValue);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Cannot remove the row with key {2:?} from '{1}' as rows refer to it by constraint `{0}`")]
+#[diagnostic(code(eval::foreign_key_violation))]
+#[diagnostic(help(
+    "Remove the referring rows first, or create the constraint with `on delete cascade`"
+))]
+struct ReferencedRowRemoval(String, String, DataValue);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Column '{1}' of relation '{0}' already has a constraint")]
+#[diagnostic(code(eval::constraint_conflict))]
+struct ConstraintConflict(String, String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("No constraint on column '{1}' of relation '{0}'")]
+#[diagnostic(code(eval::constraint_not_found))]
+struct ConstraintNotFound(String, String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Column '{1}' not found in relation '{0}'")]
+#[diagnostic(code(eval::constraint_column_not_found))]
+struct ConstraintColumnNotFound(String, String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Column '{1}' is not the only key column of relation '{0}'")]
+#[diagnostic(code(eval::constraint_bad_target))]
+#[diagnostic(help("Constraints can only refer to relations with a single key column"))]
+struct BadConstraintTarget(String, String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Constraints cannot involve the temp relation '{0}'")]
+#[diagnostic(code(eval::constraint_temp_relation))]
+struct ConstraintTempRelation(String);
+
+fn column_position(handle: &RelationHandle, column: &str) -> Result<usize> {
+    handle
+        .metadata
+        .keys
+        .iter()
+        .chain(handle.metadata.non_keys.iter())
+        .position(|col| col.name == column)
+        .ok_or_else(|| ConstraintColumnNotFound(handle.name.to_string(), column.to_string()).into())
+}
+
+/// The constraints involving a relation being written, with the handles of the other
+/// relations, fetched once for all the rows written
+#[derive(Default)]
+pub(crate) struct ForeignKeys {
+    /// the constraints of the relation, with the positions of their columns
+    /// and the referenced relations
+    outgoing: Vec<(ForeignKey, usize, RelationHandle)>,
+    /// the constraints referring to the relation, with the referring relations
+    incoming: Vec<(ForeignKey, RelationHandle)>,
+}
+
+impl ForeignKeys {
+    pub(crate) fn has_outgoing(&self) -> bool {
+        !self.outgoing.is_empty()
+    }
+    pub(crate) fn has_incoming(&self) -> bool {
+        !self.incoming.is_empty()
+    }
+}
+
+impl<'a> SessionTx<'a> {
+    pub(crate) fn create_foreign_key(&mut self, fk: ForeignKey) -> Result<()> {
+        for name in [&fk.relation, &fk.target] {
+            if name.starts_with('_') {
+                bail!(ConstraintTempRelation(name.to_string()))
+            }
+        }
+        let handle = self.get_relation(&fk.relation, true)?;
+        if handle
+            .foreign_keys
+            .iter()
+            .any(|other| other.relation == fk.relation && other.column == fk.column)
+        {
+            bail!(ConstraintConflict(
+                fk.relation.to_string(),
+                fk.column.to_string()
+            ))
+        }
+        let col_idx = column_position(&handle, &fk.column)?;
+        let target = self.get_relation(&fk.target, true)?;
+        if target.metadata.keys.len() != 1 || target.metadata.keys[0].name != fk.target_column {
+            bail!(BadConstraintTarget(
+                fk.target.to_string(),
+                fk.target_column.to_string()
+            ))
+        }
+        for row in handle.scan_all(self) {
+            self.check_reference(&fk, &target, &row?[col_idx])?;
+        }
+
+        // the index finds the referring rows when referenced rows are removed
+        let rel_name = Symbol::new(fk.relation.clone(), Default::default());
+        let idx_name = Symbol::new(foreign_key_index_name(&fk.column), Default::default());
+        let column = Symbol::new(fk.column.clone(), Default::default());
+        self.create_index(&rel_name, &idx_name, vec![column], None)?;
+
+        let mut handle = self.get_relation(&fk.relation, true)?;
+        handle.foreign_keys.push(fk.clone());
+        self.put_relation_meta(&handle)?;
+        if fk.target != fk.relation {
+            let mut target = target;
+            target.foreign_keys.push(fk);
+            self.put_relation_meta(&target)?;
+        }
+        self.bump_schema_generation()
+    }
+    /// Removes the constraint on the column of the relation, returning the ranges
+    /// of the entries of its index, to be cleared at the end of the transaction
+    pub(crate) fn remove_foreign_key(
+        &mut self,
+        rel: &Symbol,
+        column: &str,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut handle = self.get_relation(rel, true)?;
+        let pos = handle
+            .foreign_keys
+            .iter()
+            .position(|fk| fk.relation == handle.name && fk.column == column)
+            .ok_or_else(|| ConstraintNotFound(rel.name.to_string(), column.to_string()))?;
+        let fk = handle.foreign_keys.remove(pos);
+        self.put_relation_meta(&handle)?;
+        if fk.target != fk.relation {
+            let mut target = self.get_relation(&fk.target, true)?;
+            target.foreign_keys.retain(|other| *other != fk);
+            self.put_relation_meta(&target)?;
+        }
+        let idx_name = Symbol::new(foreign_key_index_name(column), Default::default());
+        self.remove_index(rel, &idx_name)
+    }
+    /// All the constraints, listed by the relations declaring them
+    pub(crate) fn list_foreign_keys(&self) -> Result<NamedRows> {
+        let lower = vec![DataValue::from("")].encode_as_key(RelationId::SYSTEM);
+        let upper =
+            vec![DataValue::from(String::from(LARGEST_UTF_CHAR))].encode_as_key(RelationId::SYSTEM);
+        let mut rows = vec![];
+        for kv in self.store_tx.range_scan(&lower, &upper) {
+            let (_, v) = kv?;
+            let handle = RelationHandle::decode(&v)?;
+            for fk in handle.foreign_keys {
+                if fk.relation != handle.name {
+                    continue;
+                }
+                rows.push(vec![
+                    DataValue::from(&fk.relation as &str),
+                    DataValue::from(&fk.column as &str),
+                    DataValue::from(&fk.target as &str),
+                    DataValue::from(&fk.target_column as &str),
+                    DataValue::from(if fk.cascade { "cascade" } else { "restrict" }),
+                ]);
+            }
+        }
+        Ok(NamedRows::new(
+            vec![
+                "relation".to_string(),
+                "column".to_string(),
+                "target".to_string(),
+                "target_column".to_string(),
+                "on_delete".to_string(),
+            ],
+            rows,
+        ))
+    }
+    /// The constraints involving the relation, to be enforced when writing to it
+    pub(crate) fn foreign_keys(&self, handle: &RelationHandle) -> Result<ForeignKeys> {
+        let mut ret = ForeignKeys::default();
+        for fk in &handle.foreign_keys {
+            if fk.relation == handle.name {
+                let col_idx = column_position(handle, &fk.column)?;
+                let target = self.get_relation(&fk.target, false)?;
+                ret.outgoing.push((fk.clone(), col_idx, target));
+            }
+            if fk.target == handle.name {
+                let referring = self.get_relation(&fk.relation, false)?;
+                ret.incoming.push((fk.clone(), referring));
+            }
+        }
+        Ok(ret)
+    }
+    fn check_reference(
+        &self,
+        fk: &ForeignKey,
+        target: &RelationHandle,
+        value: &DataValue,
+    ) -> Result<()> {
+        if *value == DataValue::Null {
+            return Ok(());
+        }
+        let key = target.encode_key_for_store(&vec![value.clone()], Default::default())?;
+        // locking the referenced row makes its concurrent removal conflict with this transaction
+        if !self.store_tx.exists(&key, true)? {
+            bail!(MissingReferencedRow(
+                fk.to_string(),
+                fk.target.to_string(),
+                value.clone()
+            ))
+        }
+        Ok(())
+    }
+    /// Fails if the row, given with keys and values, refers to missing rows
+    pub(crate) fn check_references(&self, fks: &ForeignKeys, row: &[DataValue]) -> Result<()> {
+        for (fk, col_idx, target) in &fks.outgoing {
+            self.check_reference(fk, target, &row[*col_idx])?;
+        }
+        Ok(())
+    }
+    /// Deals with the rows referring to the row with the given keys, which has been removed:
+    /// fails if a constraint restricts removals, otherwise removes the referring rows too.
+    /// The triggers and callbacks of the relations are not run for the rows removed.
+    pub(crate) fn remove_references(&mut self, fks: &ForeignKeys, key: &[DataValue]) -> Result<()> {
+        for (fk, referring) in &fks.incoming {
+            let (idx_rel, extractor) = &referring.indices[&foreign_key_index_name(&fk.column)];
+            let n_keys = referring.metadata.keys.len();
+            let row_keys: Vec<Tuple> = idx_rel
+                .scan_prefix(self, &vec![key[0].clone()])
+                .map_ok(|entry| {
+                    let mut row_key = vec![DataValue::Null; n_keys];
+                    for (val, i) in entry.into_iter().zip(extractor) {
+                        if *i < n_keys {
+                            row_key[*i] = val;
+                        }
+                    }
+                    row_key
+                })
+                .try_collect()?;
+            if row_keys.is_empty() {
+                continue;
+            }
+            if !fk.cascade {
+                bail!(ReferencedRowRemoval(
+                    fk.to_string(),
+                    fk.target.to_string(),
+                    key[0].clone()
+                ))
+            }
+            let nested = self.foreign_keys(referring)?;
+            for row_key in row_keys {
+                let encoded = referring.encode_key_for_store(&row_key, Default::default())?;
+                if let Some(val) = self.store_tx.get(&encoded, true)? {
+                    let mut row = row_key.clone();
+                    extend_tuple_from_v(&mut row, &val)?;
+                    self.delete_from_indices(referring, &row)?;
+                    self.store_tx.del(&encoded)?;
+                    if nested.has_incoming() {
+                        self.remove_references(&nested, &row_key)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::data::value::DataValue;
+    use crate::{new_cozo_mem, ImportOptions, NamedRows};
+
+    #[test]
+    fn test_foreign_keys() {
+        let db = new_cozo_mem().unwrap();
+        db.run_script(
+            r#"
+        {?[id, name] <- [[1, 'a'], [2, 'b'], [3, 'c']] :create users {id: Int => name: String}}
+        {?[id, user_id] <- [[10, 1], [11, 1], [12, 2], [13, null]]
+         :create orders {id: Int => user_id: Int?}}
+        {?[id, order_id] <- [[100, 10], [101, 12]] :create items {id: Int => order_id: Int}}
+        "#,
+            Default::default(),
+        )
+        .unwrap();
+        let run = |script: &str| db.run_script(script, Default::default());
+        let rows = |script: &str| run(script).unwrap().into_json()["rows"].clone();
+        let code = |res: miette::Result<NamedRows>| res.unwrap_err().code().unwrap().to_string();
+
+        run("::constraint create orders.user_id references users.id").unwrap();
+        run("::constraint create items.order_id references orders.id on delete cascade").unwrap();
+        assert_eq!(
+            rows("::constraints"),
+            json!([
+                ["items", "order_id", "orders", "id", "cascade"],
+                ["orders", "user_id", "users", "id", "restrict"]
+            ])
+        );
+        // existing rows must satisfy new constraints, and only single keys can be referred to
+        assert_eq!(
+            code(run("::constraint create users.id references items.id")),
+            "eval::foreign_key_violation"
+        );
+        assert_eq!(
+            code(run(
+                "::constraint create users.name references orders.user_id"
+            )),
+            "eval::constraint_bad_target"
+        );
+
+        // writes referring to missing rows fail with the whole mutation
+        let err =
+            run("?[id, user_id] <- [[14, 3], [15, 4]] :put orders {id => user_id}").unwrap_err();
+        assert_eq!(
+            err.code().unwrap().to_string(),
+            "eval::foreign_key_violation"
+        );
+        assert!(err
+            .root_cause()
+            .to_string()
+            .contains("orders.user_id references users.id"));
+        assert_eq!(rows("?[count(id)] := *orders{id}"), json!([[4]]));
+        assert_eq!(
+            code(run(
+                "?[id, user_id] <- [[10, 5]] :update orders {id => user_id}"
+            )),
+            "eval::foreign_key_violation"
+        );
+
+        // restricted removals fail, unreferenced rows can be removed
+        assert_eq!(
+            code(run("?[id] <- [[3], [2]] :rm users {id}")),
+            "eval::foreign_key_violation"
+        );
+        run("?[id] <- [[3]] :rm users {id}").unwrap();
+        assert_eq!(rows("?[id] := *users{id}"), json!([[1], [2]]));
+
+        // cascading removals go as far as the constraints allow
+        run("?[id] <- [[12]] :rm orders {id}").unwrap();
+        assert_eq!(rows("?[id] := *items{id}"), json!([[100]]));
+        run("?[id, user_id] <- [[12, null]] :put orders {id => user_id}").unwrap();
+        run("?[id] <- [[2]] :rm users {id}").unwrap();
+
+        // relations with constraints cannot be removed until these are dropped
+        assert_eq!(
+            code(run("::remove orders")),
+            "eval::relation_with_constraints"
+        );
+        assert_eq!(
+            code(run("::constraint drop orders.name")),
+            "eval::constraint_not_found"
+        );
+        run("::constraint drop items.order_id").unwrap();
+        run("?[id] <- [[10]] :rm orders {id}").unwrap();
+        assert_eq!(
+            rows("?[id, order_id] := *items{id, order_id}"),
+            json!([[100, 10]])
+        );
+        run("::remove items").unwrap();
+        assert_eq!(
+            rows("::constraints"),
+            json!([["orders", "user_id", "users", "id", "restrict"]])
+        );
+    }
+
+    #[test]
+    fn test_foreign_keys_in_imports() {
+        let db = new_cozo_mem().unwrap();
+        db.run_script(
+            r#"
+        {:create users {id: Int}}
+        {:create orders {id: Int => user_id: Int}}
+        {:create nodes {id: Int => parent: Int?}}
+        "#,
+            Default::default(),
+        )
+        .unwrap();
+        db.run_script(
+            "::constraint create orders.user_id references users.id on delete cascade",
+            Default::default(),
+        )
+        .unwrap();
+        db.run_script(
+            "::constraint create nodes.parent references nodes.id on delete cascade",
+            Default::default(),
+        )
+        .unwrap();
+        let named_rows = |headers: &[&str], rows: Vec<Vec<i64>>| {
+            NamedRows::new(
+                headers.iter().map(|h| h.to_string()).collect(),
+                rows.into_iter()
+                    .map(|row| row.into_iter().map(DataValue::from).collect())
+                    .collect(),
+            )
+        };
+        let import = |data: Vec<(&str, NamedRows)>, options: ImportOptions| {
+            let data = data
+                .into_iter()
+                .map(|(name, rows)| (name.to_string(), rows))
+                .collect();
+            db.import_relations_with_options(data, options)
+        };
+        let count = |rel: &str| {
+            db.run_script(&format!("?[count(id)] := *{rel}{{id}}"), Default::default())
+                .unwrap()
+                .rows[0][0]
+                .clone()
+        };
+
+        // rows may refer to rows imported with them, in any order
+        import(
+            vec![
+                ("orders", named_rows(&["id", "user_id"], vec![vec![10, 1]])),
+                ("users", named_rows(&["id"], vec![vec![1], vec![2]])),
+            ],
+            Default::default(),
+        )
+        .unwrap();
+        let err = import(
+            vec![("orders", named_rows(&["id", "user_id"], vec![vec![11, 3]]))],
+            Default::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.code().unwrap().to_string(),
+            "eval::foreign_key_violation"
+        );
+        import(
+            vec![("orders", named_rows(&["id", "user_id"], vec![vec![11, 3]]))],
+            ImportOptions {
+                skip_constraint_checks: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(count("orders"), DataValue::from(2));
+        // removals cascade also in imports
+        import(
+            vec![("-users", named_rows(&["id"], vec![vec![1]]))],
+            Default::default(),
+        )
+        .unwrap();
+        assert_eq!(count("orders"), DataValue::from(1));
+
+        // a tree referring to itself is removed from its root
+        db.run_script(
+            "?[id, parent] <- [[1, null], [2, 1], [3, 2], [4, 2], [5, null]]
+         :put nodes {id => parent}",
+            Default::default(),
+        )
+        .unwrap();
+        db.run_script("?[id] <- [[1]] :rm nodes {id}", Default::default())
+            .unwrap();
+        assert_eq!(count("nodes"), DataValue::from(1));
+    }
+}
@@ -0,0 +1,656 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::cell::RefCell;
+use std::iter;
+use std::ops::ControlFlow;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use itertools::Itertools;
+use miette::{bail, miette, Diagnostic, Result};
+use thiserror::Error;
+
+use crate::data::json::JsonValue;
+use crate::data::program::InputProgram;
+use crate::data::relation::{ColType, ColumnDef, NullableColType};
+use crate::data::symb::Symbol;
+use crate::data::tuple::Tuple;
+use crate::data::value::{DataValue, Num, ValidityTs};
+use crate::parse::SourceSpan;
+use crate::runtime::db::{Db, ImportIntoIndex};
+use crate::runtime::relation::{AccessLevel, InsufficientAccessLevel};
+use crate::storage::Storage;
+use crate::NamedRows;
+
+/// The number of rows skipped by `::import_csv` whose errors are listed in its result
+const MAX_REPORTED_ERRORS: usize = 100;
+
+/// Options of `::import_csv` and `::export_csv`, given as `{headers: false, delimiter: ';'}`
+#[derive(Debug, Clone)]
+pub(crate) struct CsvOptions {
+    /// whether the first line of the file holds the names of the columns
+    pub(crate) headers: bool,
+    pub(crate) delimiter: u8,
+    /// the field standing for null
+    pub(crate) null_token: String,
+    /// whether rows with values that cannot be imported are skipped instead of failing the import
+    pub(crate) skip_bad_rows: bool,
+    /// the columns in the file: a subset of the columns exported, or for imports
+    /// without headers, the columns the fields are for
+    pub(crate) columns: Option<Vec<String>>,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            headers: true,
+            delimiter: b',',
+            null_token: String::new(),
+            skip_bad_rows: false,
+            columns: None,
+        }
+    }
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Bad option '{0}' for CSV {1}")]
+#[diagnostic(code(parser::bad_csv_option))]
+#[diagnostic(help("{2}"))]
+struct BadCsvOption(String, &'static str, String, #[label] SourceSpan);
+
+impl CsvOptions {
+    /// The options with the given values, for imports or exports
+    pub(crate) fn new(
+        given: Vec<(String, DataValue, SourceSpan)>,
+        for_import: bool,
+    ) -> Result<Self> {
+        let kind = if for_import { "import" } else { "export" };
+        let mut ret = Self::default();
+        for (name, value, span) in given {
+            let bad = |help: &str| BadCsvOption(name.clone(), kind, help.to_string(), span);
+            match &name as &str {
+                "headers" => {
+                    ret.headers = value
+                        .get_bool()
+                        .ok_or_else(|| bad("'headers' must be a boolean"))?
+                }
+                "delimiter" => match value.get_str().map(|s| s.as_bytes()) {
+                    Some([b]) => ret.delimiter = *b,
+                    _ => bail!(bad("'delimiter' must be a single-byte string")),
+                },
+                "null_token" => {
+                    ret.null_token = value
+                        .get_str()
+                        .ok_or_else(|| bad("'null_token' must be a string"))?
+                        .to_string()
+                }
+                "on_error" if for_import => match value.get_str() {
+                    Some("abort") => ret.skip_bad_rows = false,
+                    Some("skip") => ret.skip_bad_rows = true,
+                    _ => bail!(bad("'on_error' must be 'abort' or 'skip'")),
+                },
+                "columns" => {
+                    let columns = value
+                        .get_slice()
+                        .and_then(|cols| {
+                            cols.iter()
+                                .map(|col| col.get_str())
+                                .collect::<Option<Vec<_>>>()
+                        })
+                        .ok_or_else(|| bad("'columns' must be a list of column names"))?;
+                    ret.columns = Some(columns.into_iter().map(|s| s.to_string()).collect());
+                }
+                _ => bail!(bad(if for_import {
+                    "The options are 'headers', 'delimiter', 'null_token', 'on_error' and 'columns'"
+                } else {
+                    "The options are 'headers', 'delimiter', 'null_token' and 'columns'"
+                })),
+            }
+        }
+        Ok(ret)
+    }
+}
+
+/// What `::export_csv` writes
+pub(crate) enum CsvSource {
+    Relation(Symbol),
+    Query(Box<InputProgram>),
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Cannot {0} CSV file '{1}': {2}")]
+#[diagnostic(code(eval::csv_io))]
+struct CsvFileError(&'static str, String, String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Column '{1}' not found in {0}")]
+#[diagnostic(code(eval::csv_column_not_found))]
+struct CsvColumnNotFound(String, String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Column '{1}' of relation '{0}' is missing from the CSV file and has no default")]
+#[diagnostic(code(import::missing_csv_column))]
+struct MissingCsvColumn(String, String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Cannot import row {0} of the CSV file: the value {2:?} of column '{1}' is invalid: {3}")]
+#[diagnostic(code(import::bad_csv_value))]
+#[diagnostic(help("Use the option `on_error: 'skip'` to skip the rows that cannot be imported"))]
+struct BadCsvValue(usize, String, String, String);
+
+/// Reads a field of a CSV file as a value of the type of a column. Numbers and booleans
+/// are read as such, lists as JSON text, and other values from their text as by `:put`.
+fn parse_field(
+    field: &str,
+    typing: &NullableColType,
+    null_token: &str,
+    cur_vld: ValidityTs,
+) -> Result<DataValue> {
+    let value = if field == null_token {
+        DataValue::Null
+    } else {
+        match &typing.coltype {
+            ColType::Int => match field.trim().parse::<i64>() {
+                Ok(i) => DataValue::from(i),
+                // integral floats are coerced to integers
+                Err(_) => DataValue::from(
+                    field
+                        .trim()
+                        .parse::<f64>()
+                        .map_err(|_| miette!("expected {}", typing))?,
+                ),
+            },
+            ColType::Float => DataValue::from(
+                field
+                    .trim()
+                    .parse::<f64>()
+                    .map_err(|_| miette!("expected {}", typing))?,
+            ),
+            ColType::Bool => match field.trim() {
+                "true" => DataValue::from(true),
+                "false" => DataValue::from(false),
+                _ => bail!("expected {}", typing),
+            },
+            ColType::List { .. } | ColType::Tuple(_) => {
+                let json: JsonValue = serde_json::from_str(field)
+                    .map_err(|err| miette!("expected {} as JSON: {}", typing, err))?;
+                DataValue::from(json)
+            }
+            // given as `[timestamp, is_assert]` or as a time string
+            ColType::Validity => match serde_json::from_str(field) {
+                Ok(json @ JsonValue::Array(_)) => DataValue::from(json),
+                _ => DataValue::from(field),
+            },
+            ColType::Any | ColType::String | ColType::Bytes | ColType::Uuid => {
+                DataValue::from(field)
+            }
+        }
+    };
+    typing.coerce(value, cur_vld)
+}
+
+/// Writes a value as a CSV field, in the way [parse_field] reads it
+fn format_field(value: DataValue, null_token: &str) -> String {
+    match value {
+        DataValue::Null => null_token.to_string(),
+        DataValue::Str(s) => s.to_string(),
+        DataValue::Bool(b) => b.to_string(),
+        DataValue::Num(Num::Int(i)) => i.to_string(),
+        // the shortest text reading back to the same float
+        DataValue::Num(Num::Float(f)) => f.to_string(),
+        DataValue::Uuid(u) => u.0.to_string(),
+        DataValue::Bytes(b) => STANDARD.encode(b),
+        v => JsonValue::from(v).to_string(),
+    }
+}
+
+impl<'s, S: Storage<'s>> Db<S> {
+    /// Writes the rows of the CSV file at `path` into the stored relation, coercing the fields
+    /// to the types of the columns, in one transaction. The rows are read one at a time and
+    /// replace stored rows with the same keys, without running triggers, as
+    /// [import_relations](Self::import_relations) does.
+    pub(crate) fn import_csv(
+        &'s self,
+        rel: &Symbol,
+        path: &str,
+        options: &CsvOptions,
+    ) -> Result<NamedRows> {
+        if rel.name.contains(':') {
+            bail!(ImportIntoIndex(rel.name.to_string()))
+        }
+        let lock = self
+            .obtain_relation_locks(iter::once(&rel.name))
+            .pop()
+            .unwrap();
+        let _guard = lock.read().unwrap();
+        let cur_vld = self.clock.current_validity();
+        let mut tx = self.transact_write()?;
+        let handle = tx.get_relation(rel, false)?;
+        if handle.access_level < AccessLevel::Protected {
+            bail!(InsufficientAccessLevel(
+                handle.name.to_string(),
+                "CSV import".to_string(),
+                handle.access_level
+            ));
+        }
+        let foreign_keys = tx.foreign_keys(&handle)?;
+
+        let mut rdr = csv::ReaderBuilder::new()
+            .delimiter(options.delimiter)
+            .has_headers(options.headers)
+            .flexible(true)
+            .from_path(path)
+            .map_err(|err| CsvFileError("read", path.to_string(), err.to_string()))?;
+        let columns: Vec<&ColumnDef> = handle
+            .metadata
+            .keys
+            .iter()
+            .chain(handle.metadata.non_keys.iter())
+            .collect_vec();
+        let fields: Vec<String> = if options.headers {
+            rdr.headers()
+                .map_err(|err| CsvFileError("read", path.to_string(), err.to_string()))?
+                .iter()
+                .map(|s| s.to_string())
+                .collect_vec()
+        } else if let Some(cols) = &options.columns {
+            cols.clone()
+        } else {
+            columns.iter().map(|col| col.name.to_string()).collect_vec()
+        };
+        // for each column, the position of its field in the rows of the file
+        let mut positions = vec![None; columns.len()];
+        for (i, field) in fields.iter().enumerate() {
+            let pos = columns
+                .iter()
+                .position(|col| col.name == *field)
+                .ok_or_else(|| {
+                    CsvColumnNotFound(format!("relation '{}'", handle.name), field.to_string())
+                })?;
+            positions[pos] = Some(i);
+        }
+        for (col, pos) in columns.iter().zip(&positions) {
+            if pos.is_none() && col.default_gen.is_none() && !col.typing.nullable {
+                bail!(MissingCsvColumn(
+                    handle.name.to_string(),
+                    col.name.to_string()
+                ))
+            }
+        }
+
+        let mut imported = 0;
+        let mut errors = vec![];
+        let mut n_skipped = 0;
+        for (i, record) in rdr.records().enumerate() {
+            let row_no = i + 1;
+            let record = match record {
+                Ok(record) => record,
+                Err(err) => {
+                    if !options.skip_bad_rows {
+                        bail!(CsvFileError("read", path.to_string(), err.to_string()))
+                    }
+                    n_skipped += 1;
+                    if errors.len() < MAX_REPORTED_ERRORS {
+                        errors.push(DataValue::List(vec![
+                            DataValue::from(row_no as i64),
+                            DataValue::Null,
+                            DataValue::Null,
+                            DataValue::from(err.to_string()),
+                        ]));
+                    }
+                    continue;
+                }
+            };
+            let parsed: Result<Tuple, BadCsvValue> = columns
+                .iter()
+                .zip(&positions)
+                .map(|(col, pos)| {
+                    let bad = |value: &str, err: String| {
+                        BadCsvValue(row_no, col.name.to_string(), value.to_string(), err)
+                    };
+                    match pos {
+                        Some(pos) => {
+                            let field = record
+                                .get(*pos)
+                                .ok_or_else(|| bad("", "the row is too short".to_string()))?;
+                            parse_field(field, &col.typing, &options.null_token, cur_vld)
+                                .map_err(|err| bad(field, err.to_string()))
+                        }
+                        None => match &col.default_gen {
+                            Some(expr) => expr
+                                .clone()
+                                .eval_to_const()
+                                .and_then(|v| col.typing.coerce(v, cur_vld))
+                                .map_err(|err| bad("", err.to_string())),
+                            None => Ok(DataValue::Null),
+                        },
+                    }
+                })
+                .try_collect();
+            let row = match parsed {
+                Ok(row) => row,
+                Err(err) => {
+                    if !options.skip_bad_rows {
+                        bail!(err)
+                    }
+                    n_skipped += 1;
+                    if errors.len() < MAX_REPORTED_ERRORS {
+                        let BadCsvValue(row_no, column, value, msg) = err;
+                        errors.push(DataValue::List(vec![
+                            DataValue::from(row_no as i64),
+                            DataValue::from(column),
+                            DataValue::from(value),
+                            DataValue::from(msg),
+                        ]));
+                    }
+                    continue;
+                }
+            };
+
+            tx.put_imported_row(&handle, &foreign_keys, &row)?;
+            imported += 1;
+        }
+        tx.commit_tx()?;
+        Ok(NamedRows::new(
+            vec![
+                "imported".to_string(),
+                "skipped".to_string(),
+                "errors".to_string(),
+            ],
+            vec![vec![
+                DataValue::from(imported as i64),
+                DataValue::from(n_skipped as i64),
+                DataValue::List(errors),
+            ]],
+        ))
+    }
+    /// Writes the rows of a stored relation, or of a query, into the CSV file at `path`,
+    /// one at a time. The file is replaced if it exists.
+    pub(crate) fn export_csv(
+        &'s self,
+        source: CsvSource,
+        path: &str,
+        options: &CsvOptions,
+    ) -> Result<NamedRows> {
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(options.delimiter)
+            .from_path(path)
+            .map_err(|err| CsvFileError("write", path.to_string(), err.to_string()))?;
+        let write_err = |err: csv::Error| CsvFileError("write", path.to_string(), err.to_string());
+        // the positions of the columns written among those of the rows
+        let select = |headers: &[String], what: String| -> Result<Vec<usize>> {
+            match &options.columns {
+                None => Ok((0..headers.len()).collect_vec()),
+                Some(cols) => {
+                    cols.iter()
+                        .map(|col| {
+                            headers.iter().position(|h| h == col).ok_or_else(|| {
+                                CsvColumnNotFound(what.clone(), col.to_string()).into()
+                            })
+                        })
+                        .try_collect()
+                }
+            }
+        };
+        let mut exported = 0;
+        match source {
+            CsvSource::Relation(rel) => {
+                let tx = self.transact()?;
+                let handle = tx.get_relation(&rel, false)?;
+                if handle.access_level < AccessLevel::ReadOnly {
+                    bail!(InsufficientAccessLevel(
+                        handle.name.to_string(),
+                        "CSV export".to_string(),
+                        handle.access_level
+                    ));
+                }
+                let headers = handle
+                    .metadata
+                    .keys
+                    .iter()
+                    .chain(handle.metadata.non_keys.iter())
+                    .map(|col| col.name.to_string())
+                    .collect_vec();
+                let positions = select(&headers, format!("relation '{}'", handle.name))?;
+                if options.headers {
+                    wtr.write_record(positions.iter().map(|i| &headers[*i]))
+                        .map_err(write_err)?;
+                }
+                let expiry = handle.expiry(&tx);
+                for row in handle.scan_all(&tx) {
+                    let row = row?;
+                    if !expiry.is_live(&row) {
+                        continue;
+                    }
+                    wtr.write_record(
+                        positions
+                            .iter()
+                            .map(|i| format_field(row[*i].clone(), &options.null_token)),
+                    )
+                    .map_err(write_err)?;
+                    exported += 1;
+                }
+            }
+            CsvSource::Query(program) => {
+                // the headers are only known once the query is compiled
+                let positions = RefCell::new(vec![]);
+                let wtr = RefCell::new(&mut wtr);
+                self.stream_program(
+                    *program,
+                    "::export_csv",
+                    self.clock.current_validity(),
+                    &mut |headers| {
+                        let selected = select(headers, "the query".to_string())?;
+                        if options.headers {
+                            wtr.borrow_mut()
+                                .write_record(selected.iter().map(|i| &headers[*i]))
+                                .map_err(write_err)?;
+                        }
+                        *positions.borrow_mut() = selected;
+                        Ok(())
+                    },
+                    &mut |row| {
+                        wtr.borrow_mut()
+                            .write_record(
+                                positions
+                                    .borrow()
+                                    .iter()
+                                    .map(|i| format_field(row[*i].clone(), &options.null_token)),
+                            )
+                            .map_err(write_err)?;
+                        exported += 1;
+                        Ok(ControlFlow::Continue(()))
+                    },
+                )?;
+            }
+        }
+        wtr.flush()
+            .map_err(|err| CsvFileError("write", path.to_string(), err.to_string()))?;
+        Ok(NamedRows::new(
+            vec!["exported".to_string()],
+            vec![vec![DataValue::from(exported as i64)]],
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::data::value::DataValue;
+    use crate::new_cozo_mem;
+
+    #[test]
+    fn test_csv_import_export() {
+        let db = new_cozo_mem().unwrap();
+        let dir = std::env::temp_dir();
+        let path = |name: &str| {
+            dir.join(format!("cozo-test-{}-{name}.csv", rand::random::<u64>()))
+                .to_string_lossy()
+                .to_string()
+        };
+        db.run_script(
+        r#"
+        {:create items {id: Int => name: String?, price: Float?, tags: [String]?, ok: Bool default true}}
+        {
+            ?[id, name, price, tags, ok] <- [
+                [1, 'plain', 0.1, ['a', 'b'], true],
+                [2, 'unicode: héllo wörld, 日本語 🌍', 1e-300, [], false],
+                [3, 'line one\nline two, "quoted"', -2.5e300, null, true],
+                [4, null, null, ['x'], false],
+                [5, 'tiny', 0.30000000000000004, null, true]
+            ]
+            :put items {id => name, price, tags, ok}
+        }
+        {:create copy {id: Int => name: String?, price: Float?, tags: [String]?, ok: Bool default true}}
+        "#,
+        Default::default(),
+    )
+    .unwrap();
+        let all = "?[id, name, price, tags, ok] := *{rel}{id, name, price, tags, ok}";
+        let read = |rel: &str| {
+            db.run_script(&all.replace("{rel}", rel), Default::default())
+                .unwrap()
+                .rows
+        };
+
+        // the default options write headers and use the empty field for nulls
+        let file = path("items");
+        let res = db
+            .run_script(
+                &format!("::export_csv items to '{file}'"),
+                Default::default(),
+            )
+            .unwrap();
+        assert_eq!(res.rows, vec![vec![DataValue::from(5)]]);
+        let res = db
+            .run_script(
+                &format!("::import_csv copy from '{file}'"),
+                Default::default(),
+            )
+            .unwrap();
+        assert_eq!(res.rows[0][0], DataValue::from(5));
+        assert_eq!(res.rows[0][1], DataValue::from(0));
+        assert_eq!(read("copy"), read("items"));
+        let _ = std::fs::remove_file(&file);
+
+        // a query, a subset of the columns, no headers and other delimiter and null token
+        let file = path("query");
+        db.run_script(
+            &format!(
+                "::export_csv {{ ?[price, id] := *items{{id, price}}, id > 2 }} to '{file}' \
+             {{headers: false, delimiter: ';', null_token: 'NULL', columns: ['id', 'price']}}"
+            ),
+            Default::default(),
+        )
+        .unwrap();
+        // rows come sorted by the first column, nulls first
+        assert_eq!(
+            std::fs::read_to_string(&file).unwrap(),
+            format!("4;NULL\n3;{}\n5;0.30000000000000004\n", -2.5e300f64)
+        );
+        db.run_script("?[id] <- [[3], [4], [5]] :rm copy {id}", Default::default())
+            .unwrap();
+        db.run_script(
+            &format!(
+                "::import_csv copy from '{file}' {{headers: false, delimiter: ';', \
+             null_token: 'NULL', columns: ['id', 'price']}}"
+            ),
+            Default::default(),
+        )
+        .unwrap();
+        let res = db
+            .run_script(
+                "?[id, name, price, ok] := *copy{id, name, price, ok}, id > 2",
+                Default::default(),
+            )
+            .unwrap();
+        assert_eq!(
+            res.into_json()["rows"],
+            json!([
+                [3, null, -2.5e300, true],
+                [4, null, null, true],
+                [5, null, 0.30000000000000004, true]
+            ])
+        );
+        let _ = std::fs::remove_file(&file);
+
+        // bad values are reported with their rows, and either fail the import or are skipped
+        let file = path("bad");
+        std::fs::write(
+            &file,
+            "id,price,ok\n10,1.5,true\n11,cheap,true\n12,2,maybe\n13,3,false\n",
+        )
+        .unwrap();
+        let err = db
+            .run_script(
+                &format!("::import_csv copy from '{file}'"),
+                Default::default(),
+            )
+            .unwrap_err();
+        let msg = err.to_string();
+        assert!(
+            msg.contains("row 2") && msg.contains("'price'") && msg.contains("\"cheap\""),
+            "{msg}"
+        );
+        let count = "?[count(id)] := *copy{id}, id >= 10";
+        let res = db.run_script(count, Default::default()).unwrap();
+        assert_eq!(res.rows, vec![vec![DataValue::from(0)]]);
+        let res = db
+            .run_script(
+                &format!("::import_csv copy from '{file}' {{on_error: 'skip'}}"),
+                Default::default(),
+            )
+            .unwrap();
+        assert_eq!(
+            res.into_json()["rows"][0],
+            json!([
+                2,
+                2,
+                [
+                    [2, "price", "cheap", "expected Float?"],
+                    [3, "ok", "maybe", "expected Bool"]
+                ]
+            ])
+        );
+        let res = db.run_script(count, Default::default()).unwrap();
+        assert_eq!(res.rows, vec![vec![DataValue::from(2)]]);
+        let _ = std::fs::remove_file(&file);
+
+        // columns must exist, and those missing from the file need defaults or to be nullable
+        let file = path("missing");
+        std::fs::write(&file, "name\nx\n").unwrap();
+        assert!(db
+            .run_script(
+                &format!("::import_csv copy from '{file}'"),
+                Default::default()
+            )
+            .is_err());
+        std::fs::write(&file, "id,color\n1,red\n").unwrap();
+        assert!(db
+            .run_script(
+                &format!("::import_csv copy from '{file}'"),
+                Default::default()
+            )
+            .is_err());
+        assert!(db
+            .run_script(
+                &format!("::export_csv items to '{file}' {{columns: ['color']}}"),
+                Default::default()
+            )
+            .is_err());
+        assert!(db
+            .run_script(
+                &format!("::export_csv items to '{file}' {{on_error: 'skip'}}"),
+                Default::default()
+            )
+            .is_err());
+        let _ = std::fs::remove_file(&file);
+    }
+}
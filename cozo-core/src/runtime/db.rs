@@ -11,14 +11,15 @@ use std::collections::btree_map::Entry;
 use std::default::Default;
 use std::fmt::{Debug, Formatter};
 use std::iter;
+use std::ops::ControlFlow;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
 #[allow(unused_imports)]
-use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering};
 #[allow(unused_imports)]
 use std::thread;
 #[allow(unused_imports)]
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 #[allow(unused_imports)]
 use crossbeam::channel::{bounded, Receiver, Sender, unbounded};
@@ -32,35 +33,109 @@ use serde_json::json;
 use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
-use crate::{decode_tuple_from_kv, FixedRule};
-use crate::data::functions::current_validity;
-use crate::data::json::JsonValue;
-use crate::data::program::{InputProgram, QueryAssertion, RelationOp};
-use crate::data::relation::ColumnDef;
+use crate::{decode_tuple_from_kv, FixedRule, StrTransactions};
+use crate::data::expr::{get_op, Expr, TryWarnings, UserFunction};
+use crate::data::functions::vld2str;
+use crate::data::json::{FloatFormat, JsonValue, OutputOptions};
+use crate::data::program::{
+    InputProgram, MagicSymbol, QueryAssertion, QueryOutOptions, RelationOp,
+};
+use crate::data::relation::{ColumnDef, NullableColType, StoredRelationMetadata};
+use crate::data::symb::Symbol;
 use crate::data::tuple::{Tuple, TupleT};
 use crate::data::value::{DataValue, LARGEST_UTF_CHAR, ValidityTs};
 use crate::fixed_rule::DEFAULT_FIXED_RULES;
-use crate::parse::{CozoScript, parse_script, SourceSpan};
+use crate::parse::{CozoScript, parse_merge_expression, parse_script, SourceSpan};
 use crate::parse::sys::SysOp;
-use crate::query::compile::{CompiledProgram, CompiledRule, CompiledRuleSet};
+use crate::query::compile::{
+    stored_relations_read, AggrKind, CompiledProgram, CompiledRule, CompiledRuleSet,
+};
+use crate::query::estimate::RelationStatsCache;
 use crate::query::ra::{
-    FilteredRA, InnerJoin, NegJoin, RelAlgebra, ReorderRA, StoredRA, StoredWithValidityRA,
-    TempStoreRA, UnificationRA,
+    FilteredRA, FtsSearchRA, HnswSearchRA, InnerJoin, LshSearchRA, NegJoin, RelAlgebra, ReorderRA,
+    StoredRA, StoredWithValidityRA, TempStoreRA, UnificationRA, DEFAULT_HASH_JOIN_MAX_ROWS,
 };
+use crate::query::sort::{approx_tuple_size, ExternalSorter, SortOptions, SortedTuples};
+use crate::query::stored::{MutationCounts, DIRECT_STORE_CHUNK_SIZE};
+use crate::query::window::compute_windows;
+#[cfg(feature = "async")]
+use crate::runtime::asynchronous::AsyncPool;
 #[allow(unused_imports)]
+use crate::runtime::audit::AuditCounts;
 use crate::runtime::callback::{
-    CallbackCollector, CallbackDeclaration, CallbackOp, EventCallbackRegistry,
+    CallbackCollector, CallbackDeclaration, CallbackOp, CallbackSender, EventCallbackRegistry,
 };
+use crate::runtime::clock::{Clock, ClockFn};
+use crate::runtime::error::CozoError;
+use crate::runtime::incremental::ChangeTrackingTx;
+use crate::runtime::jsonl::{create_jsonl, open_jsonl};
+use crate::runtime::plan_cache::{CompiledQuery, PlanCache, PlanKey};
+use crate::runtime::profile::{node_key, PlanStats};
 use crate::runtime::relation::{
-    AccessLevel, extend_tuple_from_v, InsufficientAccessLevel, RelationHandle, RelationId,
+    AccessLevel, extend_tuple_from_v, foreign_key_index_col, InputRelationHandle,
+    InsufficientAccessLevel, RelationHandle, RelationId, unique_index_col,
+};
+use crate::runtime::savepoint::{Savepoints, UndoLogTx};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::runtime::subscription::SubscriptionRegistry;
+#[allow(unused_imports)]
+use crate::runtime::transact::{
+    has_uuid_field_order, upgrade_uuid_keys, upgraded_uuid_pairs, SessionTx,
 };
-use crate::runtime::transact::SessionTx;
+use crate::runtime::ttl::SWEEP_BATCH_ROWS;
+use crate::runtime::usage::QueryUsage;
+use crate::runtime::verify::VerifyBackupOptions;
 use crate::storage::{Storage, StoreTx};
 use crate::storage::temp::TempStorage;
 
+/// Receives the rows of a query streamed by [Db::run_script_streaming]
+pub(crate) type RowSink<'f> = dyn FnMut(Tuple) -> Result<ControlFlow<()>> + 'f;
+
+/// The number of characters of the script of a running query listed by `::running`
+const SCRIPT_SNIPPET_CHARS: usize = 200;
+
 pub(crate) struct RunningQueryHandle {
     pub(crate) started_at: f64,
     pub(crate) poison: Poison,
+    /// the start of the script the query is in
+    pub(crate) script: String,
+    pub(crate) is_write: bool,
+}
+
+/// The script whose queries a transaction runs
+#[derive(Clone, Default)]
+pub(crate) struct RunningScript {
+    /// poisoned when the whole script is, the poisons of its queries are nested in it
+    pub(crate) poison: Poison,
+    /// the start of the text of the script
+    pub(crate) snippet: String,
+    /// the relations given with the script, loaded as temp relations into its transactions
+    pub(crate) relations: Arc<BTreeMap<String, NamedRows>>,
+}
+
+impl RunningScript {
+    pub(crate) fn new(script: &str, poison: Poison) -> Self {
+        Self {
+            poison,
+            snippet: script.trim().chars().take(SCRIPT_SNIPPET_CHARS).collect(),
+            relations: Default::default(),
+        }
+    }
+}
+
+/// A query being run, as listed by [Db::list_running]
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunningQuery {
+    /// The ID to pass to [Db::kill_query]
+    pub id: u64,
+    /// When the query started, in seconds since the epoch
+    pub started_at: f64,
+    /// The number of seconds since the query started
+    pub elapsed: f64,
+    /// The first 200 characters of the script the query is in
+    pub script: String,
+    /// Whether the query writes to stored relations
+    pub is_write: bool,
 }
 
 pub(crate) struct RunningQueryCleanup {
@@ -72,7 +147,7 @@ impl Drop for RunningQueryCleanup {
     fn drop(&mut self) {
         let mut map = self.running_queries.lock().unwrap();
         if let Some(handle) = map.remove(&self.id) {
-            handle.poison.0.store(true, Ordering::Relaxed);
+            handle.poison.kill();
         }
     }
 }
@@ -87,15 +162,39 @@ pub struct DbManifest {
 pub struct Db<S> {
     pub(crate) db: S,
     temp_db: TempStorage,
-    relation_store_id: Arc<AtomicU64>,
+    pub(crate) relation_store_id: Arc<AtomicU64>,
     pub(crate) queries_count: Arc<AtomicU64>,
     pub(crate) running_queries: Arc<Mutex<BTreeMap<u64, RunningQueryHandle>>>,
     pub(crate) fixed_rules: Arc<ShardedLock<BTreeMap<String, Arc<Box<dyn FixedRule>>>>>,
+    user_functions: Arc<ShardedLock<BTreeMap<String, Arc<UserFunction>>>>,
     #[cfg(not(target_arch = "wasm32"))]
     callback_count: Arc<AtomicU32>,
     #[cfg(not(target_arch = "wasm32"))]
     pub(crate) event_callbacks: Arc<ShardedLock<EventCallbackRegistry>>,
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) subscriptions: Arc<ShardedLock<SubscriptionRegistry<S>>>,
     relation_locks: Arc<ShardedLock<BTreeMap<SmartString<LazyCompact>, Arc<ShardedLock<()>>>>>,
+    /// see [crate::DbInstance::begin_transaction_str]
+    pub(crate) str_transactions: Arc<StrTransactions>,
+    plan_cache: Option<Arc<PlanCache>>,
+    /// number of queries that went through planning
+    pub(crate) plans_count: Arc<AtomicU64>,
+    /// statistics of stored relations kept by `::estimate`
+    pub(crate) estimate_stats: Arc<RelationStatsCache>,
+    validity_as_string: bool,
+    pub(crate) output_options: OutputOptions,
+    sort_options: SortOptions,
+    lookup_retries: usize,
+    hash_join_max_rows: usize,
+    /// maximum number of rows written by a statement for which triggers and callbacks run at once
+    pub(crate) mutation_batch_size: usize,
+    /// where the current time is read from, see [Db::set_clock]
+    pub(crate) clock: Clock,
+    /// see [Db::set_read_only]
+    read_only: bool,
+    /// see [Db::set_async_threads]
+    #[cfg(feature = "async")]
+    pub(crate) async_pool: AsyncPool,
 }
 
 impl<S> Debug for Db<S> {
@@ -114,6 +213,29 @@ pub(crate) struct BadDbInit(#[help] pub(crate) String);
 #[diagnostic(code(tx::import_into_index))]
 pub(crate) struct ImportIntoIndex(pub(crate) String);
 
+#[derive(Debug, Error, Diagnostic)]
+#[error("Cannot write to the database as it is read-only")]
+#[diagnostic(code(tx::read_only_database))]
+#[diagnostic(help("The database was opened with the `read_only` option"))]
+pub(crate) struct ReadOnlyDatabase;
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Relation '{0}' in the backup does not have the same columns as relation '{1}'")]
+#[diagnostic(code(eval::backup_schema_mismatch))]
+#[diagnostic(help("The columns must have the same names and types, in the same order"))]
+struct BackupSchemaMismatch(String, String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Relation '{0}' given with the script is not named as a temp relation")]
+#[diagnostic(code(eval::input_relation_not_temp))]
+#[diagnostic(help("The relations given become temp relations, whose names start with '_'"))]
+struct InputRelationNotTemp(String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Row {1} of relation '{0}' given with the script has {2} columns, not {3}")]
+#[diagnostic(code(eval::input_relation_arity_mismatch))]
+struct InputRelationArityMismatch(String, usize, usize, usize);
+
 #[derive(serde_derive::Serialize, serde_derive::Deserialize, Debug, Clone, Default)]
 /// Rows in a relation, together with headers for the fields.
 pub struct NamedRows {
@@ -123,6 +245,19 @@ pub struct NamedRows {
     pub rows: Vec<Tuple>,
     /// Contains the next named rows, if exists
     pub next: Option<Box<NamedRows>>,
+    /// How the rows are converted to JSON, set for the results of queries.
+    /// The defaults are used if absent.
+    #[serde(skip)]
+    pub output_options: Option<OutputOptions>,
+    /// The resources used by the query, set for queries with the `:report_usage` option
+    #[serde(skip)]
+    pub(crate) usage: Option<QueryUsage>,
+    /// The number of times the script was run, set by [Db::run_script_with_retry]
+    #[serde(skip)]
+    pub(crate) attempts: Option<usize>,
+    /// The warnings of the `try` expressions of the query recovering from errors
+    #[serde(skip)]
+    pub(crate) warnings: Vec<String>,
 }
 
 impl NamedRows {
@@ -132,6 +267,10 @@ impl NamedRows {
             headers,
             rows,
             next: None,
+            output_options: None,
+            usage: None,
+            attempts: None,
+            warnings: vec![],
         }
     }
 
@@ -156,22 +295,92 @@ impl NamedRows {
         collected
     }
 
-    /// Convert to a JSON object
+    /// Convert to a JSON object.
+    ///
+    /// With [OutputOptions::big_int_as_string], the object has a `types` field of the form
+    /// `{"int": [[0, 1], [2, 0, 3]]}`, listing the positions of the integers written as strings:
+    /// the row, the column, and then the indices into nested lists, if any.
     pub fn into_json(self) -> JsonValue {
         let nxt = match self.next {
             None => json!(null),
             Some(more) => more.into_json(),
         };
+        let options = self.output_options.unwrap_or_default();
+        let mut path = vec![];
+        let mut big_ints = vec![];
         let rows = self
             .rows
             .into_iter()
-            .map(|row| row.into_iter().map(JsonValue::from).collect::<JsonValue>())
+            .enumerate()
+            .map(|(i, row)| {
+                path.push(i);
+                let row = row
+                    .into_iter()
+                    .enumerate()
+                    .map(|(j, v)| {
+                        path.push(j);
+                        let v = options.to_json(v, &mut path, &mut big_ints);
+                        path.pop();
+                        v
+                    })
+                    .collect::<JsonValue>();
+                path.pop();
+                row
+            })
             .collect::<JsonValue>();
-        json!({
+        let mut ret = json!({
             "headers": self.headers,
             "rows": rows,
             "next": nxt,
-        })
+        });
+        if options.big_int_as_string {
+            ret.as_object_mut()
+                .unwrap()
+                .insert("types".to_string(), json!({ "int": big_ints }));
+        }
+        if let Some(usage) = self.usage {
+            ret.as_object_mut()
+                .unwrap()
+                .insert("usage".to_string(), json!(usage));
+        }
+        if let Some(attempts) = self.attempts {
+            ret.as_object_mut()
+                .unwrap()
+                .insert("attempts".to_string(), json!(attempts));
+        }
+        if !self.warnings.is_empty() {
+            ret.as_object_mut()
+                .unwrap()
+                .insert("warnings".to_string(), json!(self.warnings));
+        }
+        ret
+    }
+    /// The resources used by the query, if it has the `:report_usage` option
+    pub fn usage(&self) -> Option<&QueryUsage> {
+        self.usage.as_ref()
+    }
+    /// The number of times the script was run before it succeeded,
+    /// if it was run by [Db::run_script_with_retry]
+    pub fn attempts(&self) -> Option<usize> {
+        self.attempts
+    }
+    /// The warnings of the `try` expressions of the query that recovered from errors,
+    /// one for each row
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+    /// How floats should be written when the JSON object is turned into text,
+    /// see [crate::json_to_string]
+    pub fn float_format(&self) -> FloatFormat {
+        self.output_options.unwrap_or_default().float_format
+    }
+    /// Use the given output options for these and the following rows that have none
+    pub(crate) fn fill_output_options(&mut self, options: OutputOptions) {
+        let mut cur = Some(self);
+        while let Some(rows) = cur {
+            rows.output_options.get_or_insert(options);
+            cur = rows.next.as_deref_mut();
+        }
     }
     /// Make named rows from JSON
     pub fn from_json(value: &JsonValue) -> Result<Self> {
@@ -204,6 +413,10 @@ impl NamedRows {
             headers,
             rows,
             next: None,
+            output_options: None,
+            usage: None,
+            attempts: None,
+            warnings: vec![],
         })
     }
 }
@@ -220,6 +433,142 @@ pub enum TransactionPayload {
     Abort,
     /// Run a query inside the transaction
     Query((String, BTreeMap<String, DataValue>)),
+    /// Run a query inside the transaction, failing if it writes to a stored relation
+    QueryReadOnly((String, BTreeMap<String, DataValue>)),
+}
+
+/// The state of a transaction run by [Db::run_multi_transaction]
+struct MultiTransactionState<'a> {
+    tx: SessionTx<'a>,
+    cleanups: Vec<(Vec<u8>, Vec<u8>)>,
+    cur_vld: ValidityTs,
+    callback_targets: BTreeSet<SmartString<LazyCompact>>,
+    callback_collector: CallbackCollector,
+    /// the relations written, whose locks are held until the transaction ends
+    write_locked: BTreeSet<SmartString<LazyCompact>>,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Cannot run a query writing to a stored relation as read-only")]
+#[diagnostic(code(eval::read_only_mutation))]
+struct ReadOnlyQueryMutation;
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Relation '{0}' is locked by a change to its schema")]
+#[diagnostic(code(tx::relation_locked))]
+#[diagnostic(help("Retry the query once the change is done"))]
+struct RelationLocked(String);
+
+/// What to do when an imported row has the same key as a row already stored,
+/// see [Db::import_relations_with_options].
+///
+/// In JSON, this is one of `"overwrite"`, `"keep"`, `"error"` or `{"merge": {...}}`.
+#[derive(Clone, Debug, Default, Eq, PartialEq, serde_derive::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnConflict {
+    /// Replace the stored row
+    #[default]
+    Overwrite,
+    /// Keep the stored row and skip the imported one
+    Keep,
+    /// Abort the whole import
+    Error,
+    /// Compute the values of the given non-key columns by expressions, in which the
+    /// columns of the stored row are available as `old.<column>` and those of the imported
+    /// row as `new.<column>`. The other columns take the imported values.
+    Merge(BTreeMap<String, String>),
+}
+
+/// Options for [Db::import_relations_with_options]
+#[derive(Clone, Debug, Default, serde_derive::Deserialize)]
+pub struct ImportOptions {
+    /// Policy for rows with keys that are already stored
+    #[serde(default)]
+    pub on_conflict: OnConflict,
+    /// Write rows without checking that their unique columns have values not in other rows
+    /// and that the rows they refer to by constraints exist, for bulk restores of data
+    /// known to be consistent. The indices are still maintained, and removals of rows
+    /// referred to still fail or cascade.
+    #[serde(default)]
+    pub skip_constraint_checks: bool,
+}
+
+/// The numbers of rows imported by [Db::import_relations_with_options], by what happened to them.
+#[derive(Clone, Debug, Default, Eq, PartialEq, serde_derive::Serialize)]
+pub struct ImportReport {
+    /// rows whose keys were not stored before
+    pub inserted: usize,
+    /// rows that replaced stored rows
+    pub overwritten: usize,
+    /// rows skipped in favour of stored rows
+    pub kept: usize,
+    /// rows merged with stored rows
+    pub merged: usize,
+    /// rows deleted, for relations prefixed by `-`
+    pub deleted: usize,
+}
+
+/// Compiles the merge expressions of [OnConflict::Merge] against the columns of the
+/// relation, returning for each the index among the non-key columns.
+fn compile_import_mergers<'a>(
+    handle: &'a RelationHandle,
+    exprs: &BTreeMap<String, String>,
+) -> Result<Vec<(usize, Expr, &'a ColumnDef)>> {
+    let columns = handle
+        .metadata
+        .keys
+        .iter()
+        .chain(handle.metadata.non_keys.iter())
+        .collect_vec();
+    let binding_map: BTreeMap<_, _> = columns
+        .iter()
+        .enumerate()
+        .flat_map(|(i, col)| {
+            [
+                (
+                    Symbol::new(format!("old.{}", col.name), Default::default()),
+                    i,
+                ),
+                (
+                    Symbol::new(format!("new.{}", col.name), Default::default()),
+                    columns.len() + i,
+                ),
+            ]
+        })
+        .collect();
+    exprs
+        .iter()
+        .map(|(col_name, src)| {
+            let val_idx = handle
+                .metadata
+                .non_keys
+                .iter()
+                .position(|col| col.name == *col_name)
+                .ok_or_else(|| {
+                    miette!(
+                        "cannot merge '{}': not a non-key column of relation '{}'",
+                        col_name,
+                        handle.name
+                    )
+                })?;
+            let mut expr = parse_merge_expression(src, &Default::default())
+                .map_err(|err| err.with_source_code(src.to_string()))?;
+            if let Some(unknown) = expr
+                .bindings()
+                .into_iter()
+                .find(|b| !binding_map.contains_key(b))
+            {
+                bail!(
+                    "unknown column '{}' in merge expression for '{}', columns are available as \
+                     'old.<column>' and 'new.<column>'",
+                    unknown,
+                    col_name
+                )
+            }
+            expr.fill_binding_indices(&binding_map)?;
+            Ok((val_idx, expr, &handle.metadata.non_keys[val_idx]))
+        })
+        .try_collect()
 }
 
 impl<'s, S: Storage<'s>> Db<S> {
@@ -234,26 +583,158 @@ impl<'s, S: Storage<'s>> Db<S> {
             queries_count: Default::default(),
             running_queries: Default::default(),
             fixed_rules: Arc::new(ShardedLock::new(DEFAULT_FIXED_RULES.clone())),
+            user_functions: Default::default(),
             #[cfg(not(target_arch = "wasm32"))]
             callback_count: Default::default(),
             // callback_receiver: Arc::new(receiver),
             #[cfg(not(target_arch = "wasm32"))]
             event_callbacks: Default::default(),
+            #[cfg(not(target_arch = "wasm32"))]
+            subscriptions: Default::default(),
             relation_locks: Default::default(),
+            str_transactions: Default::default(),
+            plan_cache: None,
+            validity_as_string: false,
+            output_options: Default::default(),
+            sort_options: Default::default(),
+            lookup_retries: 3,
+            hash_join_max_rows: DEFAULT_HASH_JOIN_MAX_ROWS,
+            mutation_batch_size: usize::MAX,
+            plans_count: Default::default(),
+            estimate_stats: Default::default(),
+            clock: Default::default(),
+            read_only: false,
+            #[cfg(feature = "async")]
+            async_pool: Default::default(),
         };
         Ok(ret)
     }
 
+    /// Keep the plans of read-only queries in a cache persisted to the file at `path`.
+    /// Running the same script with the same parameters again, even after the database
+    /// is closed and opened again, then skips parsing and planning entirely.
+    /// Plans are discarded whenever the schema of stored relations changes.
+    ///
+    /// The file is written when the last copy of this database object is dropped,
+    /// or when [`save_plan_cache`](Self::save_plan_cache) is called.
+    pub fn set_plan_cache_path(&mut self, path: impl AsRef<Path>) {
+        self.plan_cache = Some(Arc::new(PlanCache::open(path.as_ref().to_path_buf())));
+    }
+
+    /// Output validity values as RFC 3339 strings in query results by default,
+    /// as if every query had the `:validity_as_string` option.
+    pub fn set_validity_as_string(&mut self, validity_as_string: bool) {
+        self.validity_as_string = validity_as_string;
+    }
+
+    /// Reject every write to the database: queries and system ops that write fail with
+    /// an error before taking any lock, as do restores and imports. Reading, backups and
+    /// registering callbacks are still allowed.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// How query results are converted to JSON by default, as if every query had the
+    /// `:float_format` and `:big_int_as_string` options set accordingly.
+    pub fn set_output_options(&mut self, options: OutputOptions) {
+        self.output_options = options;
+    }
+
+    /// When the rows of a query with `:order` take more than about `bytes` of memory,
+    /// sorted runs of them are written to disk and merged afterwards. Defaults to 256 MiB.
+    pub fn set_sort_memory_budget(&mut self, bytes: usize) {
+        self.sort_options.memory_budget = bytes;
+    }
+
+    /// The directory that sorted runs are written to, see
+    /// [`set_sort_memory_budget`](Self::set_sort_memory_budget).
+    /// Defaults to the temporary directory of the system.
+    pub fn set_sort_spill_dir(&mut self, dir: impl AsRef<Path>) {
+        self.sort_options.spill_dir = dir.as_ref().to_path_buf();
+    }
+
+    /// How many times a point lookup in a join is retried immediately when the storage
+    /// fails with a transient error, such as a lock held by another process. Defaults to 3.
+    /// Other errors are never retried.
+    pub fn set_lookup_retries(&mut self, retries: usize) {
+        self.lookup_retries = retries;
+    }
+
+    /// Joins on columns that the relation on the right is not ordered by hold the rows of
+    /// that relation in a hash table, unless it has more than `rows` rows, in which case
+    /// they are sorted instead to save memory. Defaults to 1,000,000.
+    pub fn set_hash_join_max_rows(&mut self, rows: usize) {
+        self.hash_join_max_rows = rows;
+    }
+
+    /// Rows put into or removed from a stored relation by a single statement are processed
+    /// in batches of at most `rows` rows: triggers run, and callbacks receive the rows, once
+    /// per batch. By default, all rows of a statement form a single batch.
+    pub fn set_mutation_batch_size(&mut self, rows: usize) {
+        self.mutation_batch_size = rows.max(1);
+    }
+
+    /// Read the current time from `clock`, in microseconds since the epoch, instead of the
+    /// system time, e.g. a [VirtualClock](crate::VirtualClock) in tests. The clock gives the
+    /// validity of `'NOW'` and `'ASSERT'`, the start times of running queries, the deadlines
+    /// of `:timeout`, the times of audit log entries, the expiry of rows by TTL columns,
+    /// and the times given by `now()`, `rand_uuid_v1()` and `rand_uuid_v7()`, also in
+    /// `auto_update` columns, for this database and all its clones.
+    pub fn set_clock(&self, clock: Box<dyn Fn() -> i64 + Send + Sync>) {
+        self.clock.set(Some(clock.into()));
+    }
+
+    /// Read the current time from the system again, undoing [`set_clock`](Self::set_clock).
+    pub fn use_system_clock(&self) {
+        self.clock.set(None);
+    }
+
+    /// Write the plan cache to its file now. Does nothing if no plan cache path is set.
+    pub fn save_plan_cache(&self) -> Result<()> {
+        match &self.plan_cache {
+            None => Ok(()),
+            Some(cache) => cache.save(),
+        }
+    }
+
     /// Must be called after creation of the database to initialize the runtime state.
+    /// A storage written by an older version of Cozo is upgraded first, see [Self::upgrade_storage].
     pub fn initialize(&'s self) -> Result<()> {
         self.load_last_ids()?;
         Ok(())
     }
 
+    /// Upgrade a storage written by an older version of Cozo, whose keys have UUIDs encoded
+    /// in the order of their fields, to UUIDs in the order of their bytes. The keys are
+    /// rewritten in batches, each committed on its own: an upgrade that is interrupted
+    /// continues where it stopped when called again. Does nothing if the storage is
+    /// up to date.
+    ///
+    /// This is done by [Self::initialize], unless the database is read-only: a storage that
+    /// is not upgraded yet cannot be opened read-only. Backups taken by older versions can
+    /// still be restored with [Self::restore_backup], which upgrades the keys as it copies them.
+    pub fn upgrade_storage(&'s self) -> Result<()> {
+        self.ensure_writable()?;
+        loop {
+            // written directly, as the keys of relations are only moved
+            let mut tx = self.db.transact(true)?;
+            let done = upgrade_uuid_keys(&mut tx)?;
+            tx.commit()?;
+            if done {
+                return Ok(());
+            }
+        }
+    }
+
     /// Run a multi-transaction. A command should be sent to `payloads`, and the result should be
     /// retrieved from `results`. A transaction ends when it receives a `Commit` or `Abort`,
-    /// or when a query is not successful. After a transaction ends, sending / receiving from
-    /// the channels will fail.
+    /// or when `payloads` is disconnected, in which case it is rolled back. After a transaction
+    /// ends, sending / receiving from the channels will fail.
+    ///
+    /// Each relation written is locked against schema changes, such as `::remove`, from the
+    /// first query writing it until the transaction ends. A query fails with a transient error
+    /// instead of waiting if the schema of the relation is being changed. Callbacks are sent
+    /// only once the transaction is committed, for the changes of all its queries.
     ///
     /// Write transactions _may_ block other reads, but we guarantee that this does not happen
     /// for the RocksDB backend.
@@ -268,8 +749,7 @@ impl<'s, S: Storage<'s>> Db<S> {
         } else {
             self.transact()
         };
-        let mut cleanups: Vec<(Vec<u8>, Vec<u8>)> = vec![];
-        let mut tx = match tx {
+        let tx = match tx {
             Ok(tx) => tx,
             Err(err) => {
                 let _ = results.send(Err(err));
@@ -277,93 +757,384 @@ impl<'s, S: Storage<'s>> Db<S> {
             }
         };
 
-        let ts = current_validity();
-        let callback_targets = self.current_callback_targets();
-        let mut callback_collector = BTreeMap::new();
-        let mut write_locks = BTreeMap::new();
-
-        for payload in payloads {
-            match payload {
+        let mut state = MultiTransactionState {
+            tx,
+            cleanups: vec![],
+            cur_vld: self.clock.current_validity(),
+            callback_targets: self.current_callback_targets(),
+            callback_collector: BTreeMap::new(),
+            write_locked: BTreeSet::new(),
+        };
+        if let Some(res) = self.serve_multi_transaction(&mut state, &payloads, &results) {
+            // the audit log is written once the transaction is dropped
+            drop(state);
+            let res = res.and_then(|counts| self.record_audit_counts(counts, None));
+            let _ = results.send(res.map(|_| NamedRows::default()));
+        }
+    }
+    /// Serves the payloads of [Self::run_multi_transaction] until the transaction ends.
+    /// The lock of a relation written is held by a recursive call serving the payloads
+    /// after the first write to it. Returns the outcome of committing, if it was committed.
+    fn serve_multi_transaction(
+        &'s self,
+        state: &mut MultiTransactionState<'_>,
+        payloads: &Receiver<TransactionPayload>,
+        results: &Sender<Result<NamedRows>>,
+    ) -> Option<Result<AuditCounts>> {
+        while let Ok(payload) = payloads.recv() {
+            let (script, params, read_only) = match payload {
                 TransactionPayload::Commit => {
-                    let _ = results.send(tx.commit_tx().map(|_| NamedRows::default()));
-                    #[cfg(not(target_arch = "wasm32"))]
-                    if !callback_collector.is_empty() {
-                        self.send_callbacks(callback_collector)
-                    }
+                    let res = state.tx.commit_tx();
+                    if res.is_ok() {
+                        #[cfg(not(target_arch = "wasm32"))]
+                        if !state.callback_collector.is_empty() {
+                            self.send_callbacks(std::mem::take(&mut state.callback_collector))
+                        }
 
-                    for (lower, upper) in cleanups {
-                        if let Err(err) = self.db.del_range(&lower, &upper) {
-                            eprintln!("{err:?}")
+                        for (lower, upper) in std::mem::take(&mut state.cleanups) {
+                            if let Err(err) = self.db.del_range(&lower, &upper) {
+                                eprintln!("{err:?}")
+                            }
                         }
                     }
-
-                    break;
+                    return Some(res.map(|_| state.tx.take_audit_counts()));
                 }
                 TransactionPayload::Abort => {
                     let _ = results.send(Ok(NamedRows::default()));
-                    break;
+                    return None;
                 }
-                TransactionPayload::Query((script, params)) => {
-                    let p =
-                        match parse_script(&script, &params, &self.fixed_rules.read().unwrap(), ts)
-                        {
-                            Ok(p) => p,
-                            Err(err) => {
-                                if results.send(Err(err)).is_err() {
-                                    break;
-                                } else {
-                                    continue;
-                                }
-                            }
-                        };
+                TransactionPayload::Query((script, params)) => (script, params, false),
+                TransactionPayload::QueryReadOnly((script, params)) => (script, params, true),
+            };
+            let p = parse_script(
+                &script,
+                &params,
+                &self.fixed_rules.read().unwrap(),
+                state.cur_vld,
+            )
+            .and_then(|p| p.get_single_program());
+            let p = match p {
+                Ok(p) => p,
+                Err(err) => {
+                    if results.send(Err(err)).is_err() {
+                        return None;
+                    } else {
+                        continue;
+                    }
+                }
+            };
 
-                    let p = match p.get_single_program() {
-                        Ok(p) => p,
-                        Err(err) => {
+            let mut newly_locked = None;
+            if let Some(write_lock_name) = p.needs_write_lock() {
+                if read_only {
+                    if results.send(Err(ReadOnlyQueryMutation.into())).is_err() {
+                        return None;
+                    }
+                    continue;
+                }
+                if !state.write_locked.contains(&write_lock_name) {
+                    newly_locked = Some(write_lock_name);
+                }
+            }
+            match newly_locked {
+                None => {
+                    let res = self.execute_in_multi_transaction(p, state);
+                    if results.send(res).is_err() {
+                        return None;
+                    }
+                }
+                Some(name) => {
+                    let lock = self.obtain_relation_locks(iter::once(&name)).pop().unwrap();
+                    // waiting here could deadlock with a schema change waiting for this
+                    // transaction to end
+                    let _guard = match lock.try_read() {
+                        Ok(guard) => guard,
+                        Err(_) => {
+                            let err = RelationLocked(name.to_string()).into();
                             if results.send(Err(err)).is_err() {
-                                break;
-                            } else {
-                                continue;
+                                return None;
                             }
+                            continue;
                         }
                     };
-                    if let Some(write_lock_name) = p.needs_write_lock() {
-                        match write_locks.entry(write_lock_name) {
-                            Entry::Vacant(e) => {
-                                let lock = self
-                                    .obtain_relation_locks(iter::once(e.key()))
-                                    .pop()
-                                    .unwrap();
-                                e.insert(lock);
-                            }
-                            Entry::Occupied(_) => {}
-                        }
-                    }
-
-                    let res = self.execute_single_program(
-                        p,
-                        &mut tx,
-                        &mut cleanups,
-                        ts,
-                        &callback_targets,
-                        &mut callback_collector,
-                    );
+                    state.write_locked.insert(name);
+                    let res = self.execute_in_multi_transaction(p, state);
                     if results.send(res).is_err() {
-                        break;
+                        return None;
                     }
+                    return self.serve_multi_transaction(state, payloads, results);
                 }
             }
         }
+        None
+    }
+    fn execute_in_multi_transaction(
+        &'s self,
+        p: InputProgram,
+        state: &mut MultiTransactionState<'_>,
+    ) -> Result<NamedRows> {
+        self.execute_single_program(
+            p,
+            &mut state.tx,
+            &mut state.cleanups,
+            state.cur_vld,
+            &state.callback_targets,
+            &mut state.callback_collector,
+        )
     }
 
     /// Run the CozoScript passed in. The `params` argument is a map of parameters.
+    /// Errors wrap a [CozoError] classifying them.
     pub fn run_script(
         &'s self,
         payload: &str,
         params: BTreeMap<String, DataValue>,
     ) -> Result<NamedRows> {
-        let cur_vld = current_validity();
-        self.do_run_script(payload, &params, cur_vld)
+        let cur_vld = self.clock.current_validity();
+        let mut ret = self
+            .do_run_script(payload, &params, Default::default(), cur_vld, None, None)
+            .map_err(CozoError::wrap)?;
+        ret.fill_output_options(self.output_options);
+        Ok(ret)
+    }
+    /// Run the CozoScript passed in, with each of the `relations` given available in it as
+    /// a temp relation of the same name, which must start with `_`. The types of the columns
+    /// are inferred from the rows, and every column is a key, as in `{:create _rel {a, b}}`.
+    /// This is much faster than passing many rows as a literal list in the script.
+    pub fn run_script_with_relations(
+        &'s self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+        relations: BTreeMap<String, NamedRows>,
+    ) -> Result<NamedRows> {
+        let cur_vld = self.clock.current_validity();
+        let mut ret = self
+            .check_script_relations(&relations)
+            .and_then(|_| self.do_run_script(payload, &params, relations, cur_vld, None, None))
+            .map_err(CozoError::wrap)?;
+        ret.fill_output_options(self.output_options);
+        Ok(ret)
+    }
+    /// Run the CozoScript passed in, stopping it with a timeout error telling the time elapsed
+    /// once it has run for longer than `timeout`. The time is for the whole script, including
+    /// all the queries of an imperative script, and the `:timeout` of each query still applies.
+    /// The changes of a script stopped this way are rolled back.
+    pub fn run_script_with_timeout(
+        &'s self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+        timeout: Duration,
+    ) -> Result<NamedRows> {
+        let cur_vld = self.clock.current_validity();
+        let mut ret = self
+            .do_run_script(
+                payload,
+                &params,
+                Default::default(),
+                cur_vld,
+                None,
+                Some(timeout),
+            )
+            .map_err(CozoError::wrap)?;
+        ret.fill_output_options(self.output_options);
+        Ok(ret)
+    }
+    /// Run the CozoScript passed in on behalf of `identity`, which is recorded as the caller
+    /// in the audit log for the audited relations the script reads or writes, see `::audit`.
+    pub fn run_script_as(
+        &'s self,
+        identity: &str,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+    ) -> Result<NamedRows> {
+        let cur_vld = self.clock.current_validity();
+        let mut ret = self
+            .do_run_script(
+                payload,
+                &params,
+                Default::default(),
+                cur_vld,
+                Some(identity),
+                None,
+            )
+            .map_err(CozoError::wrap)?;
+        ret.fill_output_options(self.output_options);
+        Ok(ret)
+    }
+    /// Run the read-only query in `payload`, passing each row of the result to `on_row` as it
+    /// is read from the evaluated query, instead of collecting the rows into [NamedRows].
+    /// The rows are in the order given by `:order`, and `:limit` and `:offset` are respected.
+    /// Returns the headers of the result.
+    ///
+    /// The rows of the entry rule are sorted as they are derived, spilling to disk past the
+    /// memory budget of sorting, so the result is never held in memory as a whole. As rows
+    /// are ordered and deduplicated, the first row is passed on once the query is evaluated.
+    /// The result is collected first if the entry rule aggregates, if `:assert` is given,
+    /// if `:limit` or `:offset` is given without `:order` (the evaluation then stops early),
+    /// or on WebAssembly.
+    ///
+    /// The read transaction stays open while `on_row` is called. The query stops as soon as
+    /// `on_row` returns [ControlFlow::Break] or an error, which is returned, and the
+    /// transaction is released, also when `on_row` panics.
+    pub fn run_script_streaming(
+        &'s self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+        mut on_row: impl FnMut(Vec<DataValue>) -> Result<ControlFlow<()>>,
+    ) -> Result<Vec<String>> {
+        self.do_run_script_streaming(payload, &params, &mut on_row)
+            .map_err(CozoError::wrap)
+    }
+    /// The queries being run, ordered by their IDs, which is the order they started in.
+    /// The queries of an imperative script are listed along with the script.
+    pub fn list_running(&self) -> Result<Vec<RunningQuery>> {
+        let now = self.clock.seconds_since_the_epoch()?;
+        Ok(self
+            .running_queries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, handle)| RunningQuery {
+                id: *id,
+                started_at: handle.started_at,
+                elapsed: (now - handle.started_at).max(0.),
+                script: handle.script.clone(),
+                is_write: handle.is_write,
+            })
+            .collect_vec())
+    }
+    /// Kills the running query with the ID given by [Self::list_running], returns whether
+    /// there is such a query. A killed query fails, and its changes are rolled back.
+    pub fn kill_query(&self, id: u64) -> bool {
+        match self.running_queries.lock().unwrap().get(&id) {
+            None => false,
+            Some(handle) => {
+                handle.poison.kill();
+                true
+            }
+        }
+    }
+    fn do_run_script_streaming(
+        &'s self,
+        payload: &str,
+        params: &BTreeMap<String, DataValue>,
+        on_row: &mut RowSink<'_>,
+    ) -> Result<Vec<String>> {
+        let cur_vld = self.clock.current_validity();
+        let program = parse_script(payload, params, &self.fixed_rules.read().unwrap(), cur_vld)?
+            .get_single_program()?;
+        self.stream_program(program, payload, cur_vld, &mut |_| Ok(()), on_row)
+    }
+    /// Runs the read-only query of `script` in a read transaction, giving its headers
+    /// to `on_headers` before its rows are given to `on_row`
+    pub(crate) fn stream_program(
+        &'s self,
+        program: InputProgram,
+        script: &str,
+        cur_vld: ValidityTs,
+        on_headers: &mut dyn FnMut(&[String]) -> Result<()>,
+        on_row: &mut RowSink<'_>,
+    ) -> Result<Vec<String>> {
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("Cannot stream the results of a query that mutates stored relations")]
+        #[diagnostic(code(eval::streaming_mutation))]
+        struct StreamingMutationError;
+
+        ensure!(
+            program.out_opts.store_relation.is_none(),
+            StreamingMutationError
+        );
+        let mut tx = self.transact()?;
+        tx.script = RunningScript::new(script, Default::default());
+        let prepared = self.prepare_query(&mut tx, program)?;
+        let headers = prepared.out_headers();
+        on_headers(&headers)?;
+        let clock = tx.clock.clone();
+        let (_, cleanups) = clock.scoped(|| {
+            self.evaluate_prepared_query(
+                &mut tx,
+                prepared,
+                cur_vld,
+                &Default::default(),
+                &mut Default::default(),
+                true,
+                Some(on_row),
+            )
+        })?;
+        self.commit_audited(tx, None)?;
+        assert!(cleanups.is_empty(), "non-empty cleanups on read-only tx");
+        Ok(headers)
+    }
+    /// Plan the query in `payload` without running it, and return the plan as structured data.
+    /// There is a row for each rule of the query, with the columns `stratum`, `rule`,
+    /// `rule_idx` and `plan`. The last holds JSON text: an object with the `kind` `rule`,
+    /// the `aggregations` of the head and the plan of the `body` as nested objects,
+    /// or with the `kind` `fixed_rule` and its `name`.
+    pub fn explain_script(
+        &'s self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+    ) -> Result<NamedRows> {
+        self.do_explain_script(payload, &params)
+            .map_err(CozoError::wrap)
+    }
+    fn do_explain_script(
+        &'s self,
+        payload: &str,
+        params: &BTreeMap<String, DataValue>,
+    ) -> Result<NamedRows> {
+        let program = parse_script(
+            payload,
+            params,
+            &self.fixed_rules.read().unwrap(),
+            self.clock.current_validity(),
+        )?
+        .get_single_program()?;
+        let mut tx = self.transact()?;
+        let (compiled, _) = compile_for_explain(&mut tx, program)?;
+        tx.commit_tx()?;
+
+        let mut rows = vec![];
+        for (stratum, p) in compiled.iter().enumerate() {
+            for (rule_name, ruleset) in p {
+                let plans = match ruleset {
+                    CompiledRuleSet::Rules(rules) => rules
+                        .iter()
+                        .map(|CompiledRule { aggr, relation, .. }| {
+                            json!({
+                                "kind": "rule",
+                                "aggregations": aggr
+                                    .iter()
+                                    .map(|a| a.as_ref().map(|(aggr, _)| aggr.name))
+                                    .collect_vec(),
+                                "body": relation.to_json(),
+                            })
+                        })
+                        .collect_vec(),
+                    CompiledRuleSet::Fixed(fixed) => vec![json!({
+                        "kind": "fixed_rule",
+                        "name": fixed.fixed_handle.name.name.to_string(),
+                    })],
+                };
+                for (rule_idx, plan) in plans.into_iter().enumerate() {
+                    rows.push(vec![
+                        DataValue::from(stratum as i64),
+                        DataValue::from(rule_name.to_string()),
+                        DataValue::from(rule_idx as i64),
+                        DataValue::from(plan.to_string()),
+                    ]);
+                }
+            }
+        }
+        Ok(NamedRows::new(
+            vec![
+                "stratum".to_string(),
+                "rule".to_string(),
+                "rule_idx".to_string(),
+                "plan".to_string(),
+            ],
+            rows,
+        ))
     }
     /// Export relations to JSON data.
     ///
@@ -407,7 +1178,7 @@ impl<'s, S: Storage<'s>> Db<S> {
             let mut rows = vec![];
             for data in tx.store_tx.range_scan(&start, &end) {
                 let (k, v) = data?;
-                let tuple = decode_tuple_from_kv(&k, &v);
+                let tuple = decode_tuple_from_kv(&k, &v)?;
                 rows.push(tuple);
             }
             let headers = cols.iter().map(|col| col.to_string()).collect_vec();
@@ -419,22 +1190,44 @@ impl<'s, S: Storage<'s>> Db<S> {
     /// what was returned by [Self::export_relations].
     /// The target stored relations must already exist in the database.
     /// Any associated indices will be updated.
+    /// Rows with the same keys as existing rows overwrite them,
+    /// see [Self::import_relations_with_options] for other behaviours.
     ///
     /// Note that triggers and callbacks are _not_ run for the relations, if any exists.
     /// If you need to activate triggers or callbacks, use queries with parameters.
     pub fn import_relations(&'s self, data: BTreeMap<String, NamedRows>) -> Result<()> {
+        self.import_relations_with_options(data, Default::default())
+            .map(|_| ())
+    }
+    /// Import relations as [Self::import_relations] does, dealing with rows whose keys
+    /// are already present as specified by `options`. All data is imported in a single
+    /// transaction: if any row cannot be imported, nothing is.
+    pub fn import_relations_with_options(
+        &'s self,
+        data: BTreeMap<String, NamedRows>,
+        options: ImportOptions,
+    ) -> Result<ImportReport> {
         #[derive(Debug, Diagnostic, Error)]
         #[error("cannot import data for relation '{0}': {1}")]
         #[diagnostic(code(import::bad_data))]
         struct BadDataForRelation(String, JsonValue);
 
+        #[derive(Debug, Diagnostic, Error)]
+        #[error("row with key {1:?} already exists in relation '{0}'")]
+        #[diagnostic(code(import::conflict))]
+        #[diagnostic(help("Use a different `on_conflict` option to keep or update the row"))]
+        struct ImportConflict(String, Vec<DataValue>);
+
         let rel_names = data.keys().map(SmartString::from).collect_vec();
         let locks = self.obtain_relation_locks(rel_names.iter());
         let _guards = locks.iter().map(|l| l.read().unwrap()).collect_vec();
 
-        let cur_vld = current_validity();
+        let cur_vld = self.clock.current_validity();
 
         let mut tx = self.transact_write()?;
+        let mut report = ImportReport::default();
+        // rows may refer to rows imported after them, so references are checked at the end
+        let mut to_check = vec![];
 
         for (relation_op, in_data) in data {
             let is_delete;
@@ -452,7 +1245,13 @@ impl<'s, S: Storage<'s>> Db<S> {
                 bail!(ImportIntoIndex(relation.to_string()))
             }
             let handle = tx.get_relation(relation, false)?;
-            let has_indices = !handle.indices.is_empty();
+            let has_indices = handle.has_indices();
+            let foreign_keys = tx.foreign_keys(&handle)?;
+            let mut written = vec![];
+            let trims_history = handle.history_retention.is_some();
+            // keys are only mutable to clippy for the regex a `DataValue` may hold
+            #[allow(clippy::mutable_key_type)]
+            let mut written_keys = BTreeSet::new();
 
             if handle.access_level < AccessLevel::Protected {
                 bail!(InsufficientAccessLevel(
@@ -462,6 +1261,11 @@ impl<'s, S: Storage<'s>> Db<S> {
                 ));
             }
 
+            let mergers = match &options.on_conflict {
+                OnConflict::Merge(exprs) if !is_delete => compile_import_mergers(&handle, exprs)?,
+                _ => vec![],
+            };
+
             let header2idx: BTreeMap<_, _> = in_data
                 .headers
                 .iter()
@@ -505,6 +1309,8 @@ impl<'s, S: Storage<'s>> Db<S> {
                     .try_collect()?
             };
 
+            let needs_existing =
+                has_indices || !matches!(options.on_conflict, OnConflict::Overwrite);
             for row in in_data.rows {
                 let keys: Vec<_> = key_indices
                     .iter()
@@ -516,76 +1322,120 @@ impl<'s, S: Storage<'s>> Db<S> {
                     })
                     .try_collect()?;
                 let k_store = handle.encode_key_for_store(&keys, Default::default())?;
-                if has_indices {
-                    if let Some(existing) = tx.store_tx.get(&k_store, false)? {
-                        let mut old = keys.clone();
-                        extend_tuple_from_v(&mut old, &existing);
-                        if is_delete || old != row {
-                            for (idx_rel, extractor) in handle.indices.values() {
-                                let idx_tup =
-                                    extractor.iter().map(|i| old[*i].clone()).collect_vec();
-                                let encoded =
-                                    idx_rel.encode_key_for_store(&idx_tup, Default::default())?;
-                                tx.store_tx.del(&encoded)?;
-                            }
-                        }
-                    }
-                }
+                let existing = if needs_existing {
+                    tx.store_tx
+                        .get(&k_store, false)?
+                        .map(|existing| -> Result<Tuple> {
+                            let mut old = keys.clone();
+                            extend_tuple_from_v(&mut old, &existing)?;
+                            Ok(old)
+                        })
+                        .transpose()?
+                } else {
+                    None
+                };
                 if is_delete {
+                    if let Some(old) = &existing {
+                        tx.delete_from_indices(&handle, old)?;
+                    }
                     tx.store_tx.del(&k_store)?;
-                } else {
-                    let vals: Vec<_> = val_indices
-                        .iter()
-                        .map(|(i, col)| -> Result<DataValue> {
-                            let v = row
-                                .get(*i)
-                                .ok_or_else(|| miette!("row too short: {:?}", row))?;
-                            col.typing.coerce(v.clone(), cur_vld)
-                        })
-                        .try_collect()?;
-                    let v_store = handle.encode_val_only_for_store(&vals, Default::default())?;
-                    tx.store_tx.put(&k_store, &v_store)?;
-                    if has_indices {
-                        let mut kv = keys;
-                        kv.extend(vals);
-                        for (idx_rel, extractor) in handle.indices.values() {
-                            let idx_tup = extractor.iter().map(|i| kv[*i].clone()).collect_vec();
-                            let encoded =
-                                idx_rel.encode_key_for_store(&idx_tup, Default::default())?;
-                            tx.store_tx.put(&encoded, &[])?;
+                    if foreign_keys.has_incoming() {
+                        tx.remove_references(&foreign_keys, &keys)?;
+                    }
+                    report.deleted += 1;
+                    continue;
+                }
+                let mut vals: Vec<_> = val_indices
+                    .iter()
+                    .map(|(i, col)| -> Result<DataValue> {
+                        let v = row
+                            .get(*i)
+                            .ok_or_else(|| miette!("row too short: {:?}", row))?;
+                        col.typing.coerce(v.clone(), cur_vld)
+                    })
+                    .try_collect()?;
+                match (&existing, &options.on_conflict) {
+                    (None, _) => report.inserted += 1,
+                    (Some(_), OnConflict::Overwrite) => report.overwritten += 1,
+                    (Some(_), OnConflict::Keep) => {
+                        report.kept += 1;
+                        continue;
+                    }
+                    (Some(_), OnConflict::Error) => {
+                        bail!(ImportConflict(relation.to_string(), keys))
+                    }
+                    (Some(old), OnConflict::Merge(_)) => {
+                        let mut old_and_new = old.clone();
+                        old_and_new.extend(keys.iter().cloned());
+                        old_and_new.extend(vals.iter().cloned());
+                        for (val_idx, expr, col) in &mergers {
+                            let merged = expr.eval(&old_and_new).wrap_err_with(|| {
+                                format!(
+                                    "when merging column '{}' of relation '{}'",
+                                    col.name, relation
+                                )
+                            })?;
+                            vals[*val_idx] = col.typing.coerce(merged, cur_vld)?;
                         }
+                        report.merged += 1;
+                    }
+                }
+                if let Some(old) = &existing {
+                    tx.delete_from_indices(&handle, old)?;
+                }
+                let v_store = handle.encode_val_only_for_store(&vals, Default::default())?;
+                tx.store_tx.put(&k_store, &v_store)?;
+                if trims_history {
+                    written_keys.insert(keys[..keys.len() - 1].to_vec());
+                }
+                if has_indices {
+                    let mut kv = keys;
+                    kv.extend(vals);
+                    if !options.skip_constraint_checks {
+                        tx.check_unique(&handle, &kv)?;
+                    }
+                    tx.put_into_indices(&handle, &kv)?;
+                    // relations with constraints always have indices
+                    if foreign_keys.has_outgoing() && !options.skip_constraint_checks {
+                        written.push(kv);
                     }
                 }
             }
+            tx.trim_history(&handle, &written_keys, cur_vld)?;
+            if !written.is_empty() {
+                to_check.push((foreign_keys, written));
+            }
+        }
+        for (foreign_keys, rows) in to_check {
+            for row in rows {
+                tx.check_references(&foreign_keys, &row)?;
+            }
         }
         tx.commit_tx()?;
+        Ok(report)
+    }
+    /// Backup the running database into an Sqlite file.
+    /// See [Self::backup_db_with_progress] for backups that report progress and can be resumed.
+    pub fn backup_db(&'s self, out_file: impl AsRef<Path>) -> Result<()> {
+        self.backup_db_with_progress(out_file, |_| true)?;
         Ok(())
     }
-    /// Backup the running database into an Sqlite file
+    /// Restore from an Sqlite backup. The backup is only read, and may have been taken
+    /// by an older version of Cozo, in which case its keys are upgraded as they are copied,
+    /// see [Self::upgrade_storage].
     #[allow(unused_variables)]
-    pub fn backup_db(&'s self, out_file: impl AsRef<Path>) -> Result<()> {
+    pub fn restore_backup(&'s self, in_file: impl AsRef<Path>) -> Result<()> {
+        self.ensure_writable()?;
         #[cfg(feature = "storage-sqlite")]
         {
-            let sqlite_db = crate::new_cozo_sqlite(out_file)?;
-            if sqlite_db.relation_store_id.load(Ordering::SeqCst) != 0 {
-                bail!("Cannot create backup: data exists in the target database.");
-            }
-            let mut tx = self.transact()?;
-            let iter = tx.store_tx.range_scan(&[], &[0xFF]);
-            sqlite_db.db.batch_put(iter)?;
-            tx.commit_tx()?;
-            Ok(())
-        }
-        #[cfg(not(feature = "storage-sqlite"))]
-        bail!("backup requires the 'storage-sqlite' feature to be enabled")
-    }
-    /// Restore from an Sqlite backup
-    #[allow(unused_variables)]
-    pub fn restore_backup(&'s self, in_file: impl AsRef<Path>) -> Result<()> {
-        #[cfg(feature = "storage-sqlite")]
-        {
-            let sqlite_db = crate::new_cozo_sqlite(in_file)?;
-            let mut s_tx = sqlite_db.transact()?;
+            let options = crate::SqliteOptions {
+                read_only: true,
+                ..Default::default()
+            };
+            // opened without being initialized, as backups of older versions are upgraded
+            // as they are copied
+            let backup = crate::storage::sqlite::open_sqlite_storage(in_file, options)?;
+            let mut s_tx = backup.transact(false)?;
             {
                 let mut tx = self.transact()?;
                 let store_id = tx.relation_store_id.load(Ordering::SeqCst);
@@ -598,9 +1448,12 @@ impl<'s, S: Storage<'s>> Db<S> {
                 }
                 tx.commit_tx()?;
             }
-            let iter = s_tx.store_tx.total_scan();
-            self.db.batch_put(iter)?;
-            s_tx.commit_tx()?;
+            if has_uuid_field_order(&s_tx)? {
+                self.db.batch_put(Box::new(upgraded_uuid_pairs(&s_tx)?))?;
+            } else {
+                self.db.batch_put(s_tx.total_scan())?;
+            }
+            s_tx.commit()?;
             Ok(())
         }
         #[cfg(not(feature = "storage-sqlite"))]
@@ -608,38 +1461,100 @@ impl<'s, S: Storage<'s>> Db<S> {
     }
     /// Import data from relations in a backup file.
     /// The target stored relations must already exist in the database, and it must not
-    /// have any associated indices. If you want to import into relations with indices,
-    /// use [Db::import_relations].
+    /// have any associated indices, apart from the hidden ones of unique columns.
+    /// If you want to import into relations with indices, use [Db::import_relations].
     ///
     /// Note that triggers and callbacks are _not_ run for the relations, if any exists.
     /// If you need to activate triggers or callbacks, use queries with parameters.
-    #[allow(unused_variables)]
     pub fn import_from_backup(
         &'s self,
         in_file: impl AsRef<Path>,
         relations: &[String],
+    ) -> Result<()> {
+        self.import_from_backup_with_options(in_file, relations, Default::default())
+    }
+    /// Import data from relations in a backup file as [Self::import_from_backup] does.
+    /// Rows from the backup always overwrite stored rows with the same keys, so of the
+    /// options only [ImportOptions::skip_constraint_checks] applies.
+    pub fn import_from_backup_with_options(
+        &'s self,
+        in_file: impl AsRef<Path>,
+        relations: &[String],
+        options: ImportOptions,
+    ) -> Result<()> {
+        let mappings = relations
+            .iter()
+            .map(|rel| (rel.clone(), rel.clone()))
+            .collect_vec();
+        self.import_from_backup_mapped(in_file, &mappings, options, false)
+    }
+    /// Import data from relations in a backup file as [Self::import_from_backup] does, each
+    /// pair of `mappings` naming a relation in the backup and the relation to import it into.
+    /// A target relation that does not exist is created with the columns of the relation
+    /// in the backup, otherwise it must have the same columns, with the same types.
+    /// All relations are imported in a single transaction.
+    ///
+    /// As for [Self::import_from_backup], triggers and callbacks are _not_ run.
+    pub fn import_from_backup_renamed(
+        &'s self,
+        in_file: impl AsRef<Path>,
+        mappings: &[(String, String)],
+    ) -> Result<()> {
+        self.import_from_backup_mapped(in_file, mappings, Default::default(), true)
+    }
+    #[allow(unused_variables)]
+    fn import_from_backup_mapped(
+        &'s self,
+        in_file: impl AsRef<Path>,
+        mappings: &[(String, String)],
+        options: ImportOptions,
+        create_absent: bool,
     ) -> Result<()> {
         #[cfg(not(feature = "storage-sqlite"))]
         bail!("backup requires the 'storage-sqlite' feature to be enabled");
 
         #[cfg(feature = "storage-sqlite")]
         {
-            let rel_names = relations.iter().map(SmartString::from).collect_vec();
+            let rel_names = mappings
+                .iter()
+                .map(|(_, to)| SmartString::from(to))
+                .collect_vec();
             let locks = self.obtain_relation_locks(rel_names.iter());
             let _guards = locks.iter().map(|l| l.read().unwrap()).collect_vec();
 
-            let source_db = crate::new_cozo_sqlite(in_file)?;
+            let source_db = crate::new_cozo_sqlite_read_only(in_file)?;
             let mut src_tx = source_db.transact()?;
             let mut dst_tx = self.transact_write()?;
+            // rows may refer to rows imported after them, so references are checked at the end
+            let mut to_check = vec![];
 
-            for relation in relations {
-                if relation.contains(':') {
-                    bail!(ImportIntoIndex(relation.to_string()))
+            for (from, to) in mappings {
+                for relation in [from, to] {
+                    if relation.contains(':') {
+                        bail!(ImportIntoIndex(relation.to_string()))
+                    }
+                }
+                let src_handle = src_tx.get_relation(from, false)?;
+                let dst_handle = if create_absent && !dst_tx.relation_exists(to)? {
+                    dst_tx.create_relation(InputRelationHandle {
+                        name: Symbol::new(to.clone(), Default::default()),
+                        metadata: src_handle.metadata.clone(),
+                        key_bindings: vec![],
+                        dep_bindings: vec![],
+                        span: Default::default(),
+                        params: Default::default(),
+                    })?
+                } else {
+                    dst_tx.get_relation(to, false)?
+                };
+                if !src_handle.metadata.same_columns_as(&dst_handle.metadata) {
+                    bail!(BackupSchemaMismatch(
+                        src_handle.name.to_string(),
+                        dst_handle.name.to_string()
+                    ))
                 }
-                let src_handle = src_tx.get_relation(relation, false)?;
-                let dst_handle = dst_tx.get_relation(relation, false)?;
 
-                if !dst_handle.indices.is_empty() {
+                if dst_handle.has_user_indices() {
                     #[derive(Debug, Error, Diagnostic)]
                     #[error("Cannot import data into relation {0} from backup as the relation has indices")]
                     #[diagnostic(code(tx::bare_import_with_indices))]
@@ -668,31 +1583,109 @@ impl<'s, S: Storage<'s>> Db<S> {
                         Ok((src_k, src_v))
                     },
                 );
+                let has_indices = dst_handle.has_indices();
+                let foreign_keys = dst_tx.foreign_keys(&dst_handle)?;
+                let mut written = vec![];
                 for result in data_it {
                     let (key, val) = result?;
+                    if has_indices {
+                        if let Some(existing) = dst_tx.store_tx.get(&key, false)? {
+                            let old = decode_tuple_from_kv(&key, &existing)?;
+                            dst_tx.delete_from_indices(&dst_handle, &old)?;
+                        }
+                        let row = decode_tuple_from_kv(&key, &val)?;
+                        if !options.skip_constraint_checks {
+                            dst_tx.check_unique(&dst_handle, &row)?;
+                        }
+                        dst_tx.put_into_indices(&dst_handle, &row)?;
+                        if foreign_keys.has_outgoing() && !options.skip_constraint_checks {
+                            written.push(row);
+                        }
+                    }
                     dst_tx.store_tx.put(&key, &val)?;
                 }
+                if !written.is_empty() {
+                    to_check.push((foreign_keys, written));
+                }
+            }
+            for (foreign_keys, rows) in to_check {
+                for row in rows {
+                    dst_tx.check_references(&foreign_keys, &row)?;
+                }
             }
 
             src_tx.commit_tx()?;
             dst_tx.commit_tx()
         }
     }
-    /// Register a custom fixed rule implementation.
+    /// Checks that the backup in the Sqlite file has the same relations as the running
+    /// database, with the same columns and rows. The rows are compared by their counts and
+    /// digests, without holding the relations in memory, and optionally by looking up a
+    /// sample of them in the backup. Returns a row for each relation in either database,
+    /// telling whether it matches.
+    #[allow(unused_variables)]
+    pub fn verify_backup(
+        &'s self,
+        in_file: impl AsRef<Path>,
+        options: VerifyBackupOptions,
+    ) -> Result<NamedRows> {
+        #[cfg(feature = "storage-sqlite")]
+        {
+            if !in_file.as_ref().is_file() {
+                bail!(
+                    "Cannot verify backup: {} does not exist",
+                    in_file.as_ref().display()
+                );
+            }
+            let backup_db = crate::new_cozo_sqlite_read_only(in_file)?;
+            let mut backup_tx = backup_db.transact()?;
+            let mut tx = self.transact()?;
+            let ret = crate::runtime::verify::compare_with_backup(&tx, &backup_tx, &options)?;
+            backup_tx.commit_tx()?;
+            tx.commit_tx()?;
+            Ok(ret)
+        }
+        #[cfg(not(feature = "storage-sqlite"))]
+        bail!("backup requires the 'storage-sqlite' feature to be enabled")
+    }
+    /// Register a custom fixed rule implementation, invoked in scripts as `?[..] <~ Name(..)`.
+    /// The name must be usable in scripts, and must not be taken by a builtin or another
+    /// registered rule.
     pub fn register_fixed_rule<R>(&self, name: String, rule_impl: R) -> Result<()>
     where
         R: FixedRule + 'static,
     {
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("'{0}' cannot be used as the name of a fixed rule")]
+        #[diagnostic(code(fixed_rule::bad_name))]
+        #[diagnostic(help(
+            "Names of fixed rules start with a letter, followed by letters, digits and underscores"
+        ))]
+        struct BadFixedRuleName(String);
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("A fixed rule with the name {0} is already registered")]
+        #[diagnostic(code(fixed_rule::name_conflict))]
+        struct FixedRuleNameConflict(String, #[help] &'static str);
+
+        let mut chars = name.chars();
+        ensure!(
+            chars.next().is_some_and(char::is_alphabetic)
+                && chars.all(|c| c.is_alphanumeric() || c == '_'),
+            BadFixedRuleName(name)
+        );
         match self.fixed_rules.write().unwrap().entry(name) {
             Entry::Vacant(ent) => {
                 ent.insert(Arc::new(Box::new(rule_impl)));
                 Ok(())
             }
             Entry::Occupied(ent) => {
-                bail!(
-                    "A fixed rule with the name {} is already registered",
-                    ent.key()
-                )
+                let help = if DEFAULT_FIXED_RULES.contains_key(ent.key()) {
+                    "Builtin fixed rules cannot be replaced"
+                } else {
+                    "Unregister the rule first to replace it"
+                };
+                bail!(FixedRuleNameConflict(ent.key().clone(), help))
             }
         }
     }
@@ -705,6 +1698,81 @@ impl<'s, S: Storage<'s>> Db<S> {
         Ok(self.fixed_rules.write().unwrap().remove(name).is_some())
     }
 
+    /// Register a function callable in expressions as `name(arg, ..)`, with exactly `arity`
+    /// arguments. Errors returned by `func` are reported at the application. Calls are found
+    /// when queries are compiled, and are made each time they are evaluated, see
+    /// [Db::register_pure_function] for functions that can be evaluated beforehand.
+    pub fn register_function<F>(&self, name: String, arity: usize, func: F) -> Result<()>
+    where
+        F: Fn(&[DataValue]) -> Result<DataValue> + Send + Sync + 'static,
+    {
+        self.do_register_function(name, arity, false, Box::new(func))
+    }
+
+    /// Register a function as by [Db::register_function], which must always return the same
+    /// value for the same arguments: applications to constants are evaluated when queries
+    /// are compiled, and their results used instead.
+    pub fn register_pure_function<F>(&self, name: String, arity: usize, func: F) -> Result<()>
+    where
+        F: Fn(&[DataValue]) -> Result<DataValue> + Send + Sync + 'static,
+    {
+        self.do_register_function(name, arity, true, Box::new(func))
+    }
+
+    fn do_register_function(
+        &self,
+        name: String,
+        arity: usize,
+        pure: bool,
+        inner: Box<dyn Fn(&[DataValue]) -> Result<DataValue> + Send + Sync>,
+    ) -> Result<()> {
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("'{0}' cannot be used as the name of a function")]
+        #[diagnostic(code(eval::bad_function_name))]
+        #[diagnostic(help(
+            "Names of functions start with a letter, followed by letters, digits and underscores"
+        ))]
+        struct BadFunctionName(String);
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("A function with the name {0} already exists")]
+        #[diagnostic(code(eval::function_name_conflict))]
+        struct FunctionNameConflict(String, #[help] &'static str);
+
+        let mut chars = name.chars();
+        ensure!(
+            chars.next().is_some_and(char::is_alphabetic)
+                && chars.all(|c| c.is_alphanumeric() || c == '_'),
+            BadFunctionName(name)
+        );
+        ensure!(
+            get_op(&name).is_none() && !matches!(name.as_str(), "cond" | "if" | "try"),
+            FunctionNameConflict(name, "Builtin functions cannot be replaced")
+        );
+        match self.user_functions.write().unwrap().entry(name) {
+            Entry::Vacant(ent) => {
+                let func = UserFunction {
+                    name: ent.key().clone(),
+                    arity,
+                    pure,
+                    inner,
+                };
+                ent.insert(Arc::new(func));
+                Ok(())
+            }
+            Entry::Occupied(ent) => bail!(FunctionNameConflict(
+                ent.key().clone(),
+                "Unregister the function first to replace it"
+            )),
+        }
+    }
+
+    /// Unregister a function registered by [Db::register_function], returning whether
+    /// it was found. Queries calling it can no longer be compiled.
+    pub fn unregister_function(&self, name: &str) -> bool {
+        self.user_functions.write().unwrap().remove(name).is_some()
+    }
+
     /// Register callback channel to receive changes when the requested relation are successfully committed.
     /// The returned ID can be used to unregister the callback channel.
     #[cfg(not(target_arch = "wasm32"))]
@@ -718,9 +1786,15 @@ impl<'s, S: Storage<'s>> Db<S> {
         } else {
             unbounded()
         };
+        let new_id = self.add_callback(relation, CallbackSender::Channel(sender));
+        (new_id, receiver)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn add_callback(&self, relation: &str, sender: CallbackSender) -> u32 {
         let cb = CallbackDeclaration {
             dependent: SmartString::from(relation),
-            sender: sender,
+            sender,
         };
 
         let mut guard = self.event_callbacks.write().unwrap();
@@ -732,7 +1806,7 @@ impl<'s, S: Storage<'s>> Db<S> {
             .insert(new_id);
 
         guard.0.insert(new_id, cb);
-        (new_id, receiver)
+        new_id
     }
 
     /// Unregister callbacks/channels to run when changes to relations are committed.
@@ -784,7 +1858,16 @@ impl<'s, S: Storage<'s>> Db<S> {
         Ok(())
     }
 
-    fn load_last_ids(&'s self) -> Result<()> {
+    pub(crate) fn load_last_ids(&'s self) -> Result<()> {
+        if self.read_only {
+            let id = self.transact()?.last_relation_id()?;
+            let id = id.ok_or_else(|| {
+                BadDbInit("a read-only database must be initialized already".to_string())
+            })?;
+            self.relation_store_id.store(id.0, Ordering::Release);
+            return Ok(());
+        }
+        self.upgrade_storage()?;
         let mut tx = self.transact_write()?;
         self.relation_store_id
             .store(tx.init_storage()?.0, Ordering::Release);
@@ -797,15 +1880,69 @@ impl<'s, S: Storage<'s>> Db<S> {
             temp_store_tx: self.temp_db.transact(true)?,
             relation_store_id: self.relation_store_id.clone(),
             temp_store_id: Default::default(),
+            #[cfg(test)]
+            stored_scans: Default::default(),
+            scan_sample: None,
+            entry_sink: Default::default(),
+            #[cfg(test)]
+            streamed_rows: Default::default(),
+            spilled_sort_runs: Default::default(),
+            #[cfg(test)]
+            fixed_rule_input_rows: Default::default(),
+            lookup_retries: self.lookup_retries,
+            hash_join_max_rows: self.hash_join_max_rows,
+            lookup_retry_count: Default::default(),
+            storage_counters: None,
+            peak_memory_bytes: Default::default(),
+            audit_counts: Default::default(),
+            plan_stats: None,
+            script: Default::default(),
+            now: self.clock.seconds_since_the_epoch()?,
+            clock: self.clock.clone(),
+            savepoints: Default::default(),
+            user_functions: self.user_functions.clone(),
+            trigger_depth: 0,
         };
         Ok(ret)
     }
+    /// Fails if the database is read-only, see [Db::set_read_only]
+    pub(crate) fn ensure_writable(&self) -> Result<()> {
+        if self.read_only {
+            bail!(ReadOnlyDatabase)
+        }
+        Ok(())
+    }
     pub(crate) fn transact_write(&'s self) -> Result<SessionTx<'_>> {
+        self.ensure_writable()?;
+        let savepoints = Savepoints::default();
+        let store_tx = ChangeTrackingTx::new(Box::new(self.db.transact(true)?));
         let ret = SessionTx {
-            store_tx: Box::new(self.db.transact(true)?),
+            store_tx: Box::new(UndoLogTx::new(Box::new(store_tx), savepoints.undo_log())),
             temp_store_tx: self.temp_db.transact(true)?,
             relation_store_id: self.relation_store_id.clone(),
             temp_store_id: Default::default(),
+            #[cfg(test)]
+            stored_scans: Default::default(),
+            scan_sample: None,
+            entry_sink: Default::default(),
+            #[cfg(test)]
+            streamed_rows: Default::default(),
+            spilled_sort_runs: Default::default(),
+            #[cfg(test)]
+            fixed_rule_input_rows: Default::default(),
+            lookup_retries: self.lookup_retries,
+            hash_join_max_rows: self.hash_join_max_rows,
+            lookup_retry_count: Default::default(),
+            storage_counters: None,
+            peak_memory_bytes: Default::default(),
+            audit_counts: Default::default(),
+            plan_stats: None,
+            script: Default::default(),
+            now: self.clock.seconds_since_the_epoch()?,
+            clock: self.clock.clone(),
+            savepoints,
+            user_functions: self.user_functions.clone(),
+            trigger_depth: 0,
         };
         Ok(ret)
     }
@@ -832,27 +1969,155 @@ impl<'s, S: Storage<'s>> Db<S> {
     }
 
     fn do_run_script(
+        &'s self,
+        payload: &str,
+        param_pool: &BTreeMap<String, DataValue>,
+        relations: BTreeMap<String, NamedRows>,
+        cur_vld: ValidityTs,
+        identity: Option<&str>,
+        timeout: Option<Duration>,
+    ) -> Result<NamedRows> {
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("The script timed out after running for {0:.3} seconds, longer than its timeout of {1} seconds")]
+        #[diagnostic(code(eval::timeout))]
+        #[diagnostic(help("The time allowed for the whole script is the timeout it is run with"))]
+        struct QueryTimeout(f64, f64);
+
+        let poison = Poison::default();
+        let relations = Arc::new(relations);
+        let timeout = match timeout {
+            None => {
+                let mut script = RunningScript::new(payload, poison);
+                script.relations = relations;
+                return self.run_script_poisoned(payload, param_pool, cur_vld, identity, &script);
+            }
+            Some(timeout) => timeout.as_secs_f64(),
+        };
+        let started = self.clock.seconds_since_the_epoch()?;
+        poison.set_timeout(timeout, self.clock.virtual_fn())?;
+        let mut script = RunningScript::new(payload, poison.clone());
+        script.relations = relations;
+        let res = self.run_script_poisoned(payload, param_pool, cur_vld, identity, &script);
+        if res.is_err() && poison.timed_out() {
+            let elapsed = self.clock.seconds_since_the_epoch()? - started;
+            bail!(QueryTimeout(elapsed, timeout))
+        }
+        res
+    }
+
+    /// Runs the script, all queries in it being killed when its poison is
+    pub(crate) fn run_script_poisoned(
         &'s self,
         payload: &str,
         param_pool: &BTreeMap<String, DataValue>,
         cur_vld: ValidityTs,
+        identity: Option<&str>,
+        script: &RunningScript,
     ) -> Result<NamedRows> {
+        let plan_key = match &self.plan_cache {
+            // the plans of queries using the relations given would not be found in the cache
+            Some(_) if !script.relations.is_empty() => None,
+            None => None,
+            Some(cache) => match PlanCache::key(payload, param_pool) {
+                None => None,
+                Some(key) => {
+                    if let Some(res) =
+                        self.execute_cached_plan(cache, &key, cur_vld, identity, script)?
+                    {
+                        return Ok(res);
+                    }
+                    Some(key)
+                }
+            },
+        };
         match parse_script(
             payload,
             param_pool,
             &self.fixed_rules.read().unwrap(),
             cur_vld,
         )? {
-            CozoScript::Single(p) => self.execute_single(cur_vld, p),
-            CozoScript::Imperative(ps) => self.execute_imperative(cur_vld, &ps),
+            CozoScript::Single(p) => match (&self.plan_cache, plan_key) {
+                (Some(cache), Some(key))
+                    if p.out_opts.store_relation.is_none() && p.out_opts.sleep.is_none() =>
+                {
+                    self.execute_and_cache_plan(cache, key, cur_vld, p, identity, script)
+                }
+                _ => self.execute_single(cur_vld, p, identity, script),
+            },
+            CozoScript::Imperative(ps) => self.execute_imperative(cur_vld, &ps, identity, script),
             CozoScript::Sys(op) => self.run_sys_op(op),
         }
     }
 
-    fn execute_single(&'s self, cur_vld: ValidityTs, p: InputProgram) -> Result<NamedRows, Report> {
+    /// Runs a read-only query from the plan cache, returns `None` if no usable plan is cached.
+    fn execute_cached_plan(
+        &'s self,
+        cache: &PlanCache,
+        key: &PlanKey,
+        cur_vld: ValidityTs,
+        identity: Option<&str>,
+        script: &RunningScript,
+    ) -> Result<Option<NamedRows>> {
+        let mut tx = self.transact()?;
+        tx.script = script.clone();
+        let generation = tx.schema_generation()?;
+        let prepared = match cache.get(key, generation, &self.fixed_rules.read().unwrap()) {
+            None => return Ok(None),
+            Some(prepared) => prepared,
+        };
+        let (res, cleanups) = self.run_prepared_query(
+            &mut tx,
+            prepared,
+            cur_vld,
+            &Default::default(),
+            &mut Default::default(),
+            true,
+        )?;
+        self.commit_audited(tx, identity)?;
+        assert!(cleanups.is_empty(), "non-empty cleanups on read-only tx");
+        Ok(Some(res))
+    }
+
+    fn execute_and_cache_plan(
+        &'s self,
+        cache: &PlanCache,
+        key: PlanKey,
+        cur_vld: ValidityTs,
+        p: InputProgram,
+        identity: Option<&str>,
+        script: &RunningScript,
+    ) -> Result<NamedRows> {
+        let mut tx = self.transact()?;
+        tx.script = script.clone();
+        let generation = tx.schema_generation()?;
+        let prepared = self.prepare_query(&mut tx, p)?;
+        cache.insert(key, generation, &prepared);
+        let (res, cleanups) = self.run_prepared_query(
+            &mut tx,
+            prepared,
+            cur_vld,
+            &Default::default(),
+            &mut Default::default(),
+            true,
+        )?;
+        self.commit_audited(tx, identity)?;
+        assert!(cleanups.is_empty(), "non-empty cleanups on read-only tx");
+        Ok(res)
+    }
+
+    pub(crate) fn execute_single(
+        &'s self,
+        cur_vld: ValidityTs,
+        p: InputProgram,
+        identity: Option<&str>,
+        script: &RunningScript,
+    ) -> Result<NamedRows, Report> {
         let mut callback_collector = BTreeMap::new();
         let write_lock_names = p.needs_write_lock();
         let is_write = write_lock_names.is_some();
+        if is_write {
+            self.ensure_writable()?;
+        }
         let write_lock = self.obtain_relation_locks(write_lock_names.iter());
         let _write_lock_guards = if is_write {
             Some(write_lock[0].read().unwrap())
@@ -872,6 +2137,8 @@ impl<'s, S: Storage<'s>> Db<S> {
             } else {
                 self.transact()?
             };
+            tx.script = script.clone();
+            self.load_script_relations(&mut tx, cur_vld)?;
 
             res = self.execute_single_program(
                 p,
@@ -882,10 +2149,8 @@ impl<'s, S: Storage<'s>> Db<S> {
                 &mut callback_collector,
             )?;
 
-            if is_write {
-                tx.commit_tx()?;
-            } else {
-                tx.commit_tx()?;
+            self.commit_audited(tx, identity)?;
+            if !is_write {
                 assert!(cleanups.is_empty(), "non-empty cleanups on read-only tx");
             }
         }
@@ -899,7 +2164,107 @@ impl<'s, S: Storage<'s>> Db<S> {
         }
         Ok(res)
     }
-    fn explain_compiled(&self, strata: &[CompiledProgram]) -> Result<NamedRows> {
+    /// Fails if a relation to be given with a script cannot be loaded as a temp relation
+    fn check_script_relations(&self, relations: &BTreeMap<String, NamedRows>) -> Result<()> {
+        for (name, rows) in relations {
+            ensure!(
+                name.starts_with('_'),
+                InputRelationNotTemp(name.to_string())
+            );
+            for (i, row) in rows.rows.iter().enumerate() {
+                ensure!(
+                    row.len() == rows.headers.len(),
+                    InputRelationArityMismatch(name.to_string(), i, row.len(), rows.headers.len())
+                );
+            }
+        }
+        Ok(())
+    }
+    /// Creates the temp relations holding the relations given with the script of the transaction
+    pub(crate) fn load_script_relations(
+        &'s self,
+        tx: &mut SessionTx<'_>,
+        cur_vld: ValidityTs,
+    ) -> Result<()> {
+        let relations = tx.script.relations.clone();
+        for (name, rows) in relations.iter() {
+            let bindings = rows
+                .headers
+                .iter()
+                .map(|h| Symbol::new(h.as_str(), Default::default()))
+                .collect_vec();
+            let keys = rows
+                .headers
+                .iter()
+                .enumerate()
+                .map(|(i, h)| ColumnDef {
+                    name: SmartString::from(h.as_str()),
+                    typing: NullableColType::infer(rows.rows.iter().map(|row| &row[i])),
+                    default_gen: None,
+                    auto_update: None,
+                    unique: false,
+                })
+                .collect_vec();
+            let handle = InputRelationHandle {
+                name: Symbol::new(name.as_str(), Default::default()),
+                metadata: StoredRelationMetadata {
+                    keys,
+                    non_keys: vec![],
+                },
+                key_bindings: bindings.clone(),
+                dep_bindings: vec![],
+                span: Default::default(),
+                params: Default::default(),
+            };
+            let clock = tx.clock.clone();
+            clock.scoped(|| {
+                tx.execute_relation(
+                    self,
+                    rows.rows.iter().cloned(),
+                    RelationOp::Create,
+                    &handle,
+                    &bindings,
+                    cur_vld,
+                    &Default::default(),
+                    &mut Default::default(),
+                    true,
+                )
+            })?;
+        }
+        Ok(())
+    }
+    /// Commits the transaction, then records the rows of audited relations it read or wrote
+    /// in the audit log, attributed to `identity`. The transaction is dropped first, as
+    /// engines may hold locks until then.
+    pub(crate) fn commit_audited(
+        &'s self,
+        mut tx: SessionTx<'_>,
+        identity: Option<&str>,
+    ) -> Result<()> {
+        tx.commit_tx()?;
+        let counts = tx.take_audit_counts();
+        drop(tx);
+        self.record_audit_counts(counts, identity)
+    }
+    fn record_audit_counts(&'s self, counts: AuditCounts, identity: Option<&str>) -> Result<()> {
+        // a read-only database cannot record its reads of audited relations
+        if counts.is_empty() || self.read_only {
+            return Ok(());
+        }
+        let mut audit_tx = self.transact_write()?;
+        let at = self.clock.seconds_since_the_epoch()?;
+        audit_tx.record_audit_entries(counts, identity, at)?;
+        audit_tx.commit_tx()
+    }
+    /// `early_stop` gives the numbers of rows to take and skip by the entry rule,
+    /// see [QueryOutOptions::early_stop]. With `stats` from `::profile`, the rows produced
+    /// by each node and the time taken are given too.
+    fn explain_compiled(
+        &self,
+        strata: &[CompiledProgram],
+        (num_to_take, num_to_skip): (Option<usize>, Option<usize>),
+        stats: Option<&PlanStats>,
+    ) -> Result<NamedRows> {
         let mut ret: Vec<JsonValue> = vec![];
         const STRATUM: &str = "stratum";
         const ATOM_IDX: &str = "atom_idx";
@@ -910,8 +2275,10 @@ impl<'s, S: Storage<'s>> Db<S> {
         const OUT_BINDINGS: &str = "out_relation";
         const JOINS_ON: &str = "joins_on";
         const FILTERS: &str = "filters/expr";
+        const ROWS: &str = "rows";
+        const TIME_MS: &str = "time_ms";
 
-        let headers = vec![
+        let mut headers = vec![
             STRATUM.to_string(),
             RULE_IDX.to_string(),
             RULE_NAME.to_string(),
@@ -922,6 +2289,16 @@ impl<'s, S: Storage<'s>> Db<S> {
             FILTERS.to_string(),
             OUT_BINDINGS.to_string(),
         ];
+        if stats.is_some() {
+            headers.push(ROWS.to_string());
+            headers.push(TIME_MS.to_string());
+        }
+        // nodes that never produced rows by themselves, e.g. those only looked up by joins,
+        // have no statistics
+        let node_stats = |node: &RelAlgebra| match stats.and_then(|s| s.get(&node_key(node))) {
+            None => (json!(null), json!(null)),
+            Some(s) => (json!(s.rows), json!(s.time.as_secs_f64() * 1000.)),
+        };
 
         for (stratum, p) in strata.iter().enumerate() {
             let mut clause_idx = -1;
@@ -931,7 +2308,8 @@ impl<'s, S: Storage<'s>> Db<S> {
                         for CompiledRule { aggr, relation, .. } in rules.iter() {
                             clause_idx += 1;
                             let mut ret_for_relation = vec![];
-                            let mut rel_stack = vec![relation];
+                            // the nodes to show, with the nodes whose statistics they show
+                            let mut rel_stack = vec![(relation, relation)];
                             let mut idx = 0;
                             let mut atom_type = "out";
                             for (a, _) in aggr.iter().flatten() {
@@ -944,6 +2322,26 @@ impl<'s, S: Storage<'s>> Db<S> {
                                 }
                             }
 
+                            if let (Some(n), true, "out") =
+                                (num_to_take, rule_name.is_prog_entry(), atom_type)
+                            {
+                                // the entry rule stops pulling rows once it has enough of them,
+                                // which aggregations cannot do as they need all the rows first
+                                let limit = match num_to_skip {
+                                    None => format!("take {n}"),
+                                    Some(skip) => format!("skip {skip}, take {}", n - skip),
+                                };
+                                ret_for_relation.push(json!({
+                                    STRATUM: stratum,
+                                    ATOM_IDX: idx,
+                                    OP: "limit",
+                                    RULE_IDX: clause_idx,
+                                    RULE_NAME: rule_name.to_string(),
+                                    FILTERS: limit,
+                                    OUT_BINDINGS: relation.bindings_after_eliminate().into_iter().map(|v| v.to_string()).collect_vec()
+                                }));
+                                idx += 1;
+                            }
                             ret_for_relation.push(json!({
                                 STRATUM: stratum,
                                 ATOM_IDX: idx,
@@ -954,7 +2352,7 @@ impl<'s, S: Storage<'s>> Db<S> {
                             }));
                             idx += 1;
 
-                            while let Some(rel) = rel_stack.pop() {
+                            while let Some((rel, stats_rel)) = rel_stack.pop() {
                                 let (atom_type, ref_name, joins_on, filters) = match rel {
                                     r @ RelAlgebra::Fixed(..) => {
                                         if r.is_unit() {
@@ -973,26 +2371,44 @@ impl<'s, S: Storage<'s>> Db<S> {
                                         json!(filters.iter().map(|f| f.to_string()).collect_vec()),
                                     ),
                                     RelAlgebra::Stored(StoredRA {
-                                        storage, filters, ..
+                                        storage,
+                                        filters,
+                                        shared,
+                                        ..
                                     }) => (
-                                        "load_stored",
-                                        json!(format!(":{}", storage.name)),
+                                        if shared.is_some() {
+                                            "load_stored_shared"
+                                        } else {
+                                            "load_stored"
+                                        },
+                                        match shared {
+                                            None => json!(format!(":{}", storage.name)),
+                                            Some(shared) => json!(format!(
+                                                ":{} (shared scan #{}, {} consumers)",
+                                                storage.name, shared.id, shared.consumers
+                                            )),
+                                        },
                                         json!(null),
                                         json!(filters.iter().map(|f| f.to_string()).collect_vec()),
                                     ),
                                     RelAlgebra::StoredWithValidity(StoredWithValidityRA {
                                         storage,
                                         filters,
+                                        valid_until,
                                         ..
                                     }) => (
-                                        "load_stored_with_validity",
+                                        if valid_until.is_some() {
+                                            "load_stored_in_window"
+                                        } else {
+                                            "load_stored_with_validity"
+                                        },
                                         json!(format!(":{}", storage.name)),
                                         json!(null),
                                         json!(filters.iter().map(|f| f.to_string()).collect_vec()),
                                     ),
                                     RelAlgebra::Join(inner) => {
                                         if inner.left.is_unit() {
-                                            rel_stack.push(&inner.right);
+                                            rel_stack.push((&inner.right, rel));
                                             continue;
                                         }
                                         let t = inner.join_type();
@@ -1002,8 +2418,8 @@ impl<'s, S: Storage<'s>> Db<S> {
                                             joiner,
                                             ..
                                         } = inner.as_ref();
-                                        rel_stack.push(left);
-                                        rel_stack.push(right);
+                                        rel_stack.push((left, left));
+                                        rel_stack.push((right, right));
                                         (t, json!(null), json!(joiner.as_map()), json!(null))
                                     }
                                     RelAlgebra::NegJoin(inner) => {
@@ -1014,12 +2430,12 @@ impl<'s, S: Storage<'s>> Db<S> {
                                             joiner,
                                             ..
                                         } = inner.as_ref();
-                                        rel_stack.push(left);
-                                        rel_stack.push(right);
+                                        rel_stack.push((left, left));
+                                        rel_stack.push((right, right));
                                         (t, json!(null), json!(joiner.as_map()), json!(null))
                                     }
                                     RelAlgebra::Reorder(ReorderRA { relation, .. }) => {
-                                        rel_stack.push(relation);
+                                        rel_stack.push((relation.as_ref(), relation.as_ref()));
                                         ("reorder", json!(null), json!(null), json!(null))
                                     }
                                     RelAlgebra::Filter(FilteredRA {
@@ -1027,7 +2443,7 @@ impl<'s, S: Storage<'s>> Db<S> {
                                         filters: pred,
                                         ..
                                     }) => {
-                                        rel_stack.push(parent);
+                                        rel_stack.push((parent.as_ref(), parent.as_ref()));
                                         (
                                             "filter",
                                             json!(null),
@@ -1042,7 +2458,7 @@ impl<'s, S: Storage<'s>> Db<S> {
                                         is_multi,
                                         ..
                                     }) => {
-                                        rel_stack.push(parent);
+                                        rel_stack.push((parent.as_ref(), parent.as_ref()));
                                         (
                                             if *is_multi { "multi-unify" } else { "unify" },
                                             json!(binding.name),
@@ -1050,7 +2466,50 @@ impl<'s, S: Storage<'s>> Db<S> {
                                             json!(expr.to_string()),
                                         )
                                     }
+                                    RelAlgebra::HnswSearch(HnswSearchRA {
+                                        parent,
+                                        idx,
+                                        query,
+                                        ..
+                                    }) => {
+                                        rel_stack.push((parent.as_ref(), parent.as_ref()));
+                                        (
+                                            "hnsw_search",
+                                            json!(format!("~{}", idx.name)),
+                                            json!(null),
+                                            json!(query.to_string()),
+                                        )
+                                    }
+                                    RelAlgebra::FtsSearch(FtsSearchRA {
+                                        parent,
+                                        idx,
+                                        query,
+                                        ..
+                                    }) => {
+                                        rel_stack.push((parent.as_ref(), parent.as_ref()));
+                                        (
+                                            "fts_search",
+                                            json!(format!("~{}", idx.name)),
+                                            json!(null),
+                                            json!(query.to_string()),
+                                        )
+                                    }
+                                    RelAlgebra::LshSearch(LshSearchRA {
+                                        parent,
+                                        idx,
+                                        query,
+                                        ..
+                                    }) => {
+                                        rel_stack.push((parent.as_ref(), parent.as_ref()));
+                                        (
+                                            "lsh_search",
+                                            json!(format!("~{}", idx.name)),
+                                            json!(null),
+                                            json!(query.to_string()),
+                                        )
+                                    }
                                 };
+                                let (rows, time_ms) = node_stats(stats_rel);
                                 ret_for_relation.push(json!({
                                     STRATUM: stratum,
                                     ATOM_IDX: idx,
@@ -1061,6 +2520,8 @@ impl<'s, S: Storage<'s>> Db<S> {
                                     OUT_BINDINGS: rel.bindings_after_eliminate().into_iter().map(|v| v.to_string()).collect_vec(),
                                     JOINS_ON: joins_on,
                                     FILTERS: filters,
+                                    ROWS: rows,
+                                    TIME_MS: time_ms,
                                 }));
                                 idx += 1;
                             }
@@ -1095,12 +2556,93 @@ impl<'s, S: Storage<'s>> Db<S> {
         match op {
             SysOp::Explain(prog) => {
                 let mut tx = self.transact()?;
-                let (normalized_program, _) = prog.into_normalized_program(&tx)?;
-                let (stratified_program, _) = normalized_program.into_stratified_program()?;
+                let (compiled, out_opts) = compile_for_explain(&mut tx, *prog)?;
+                tx.commit_tx()?;
+                self.explain_compiled(&compiled, out_opts.early_stop(), None)
+            }
+            SysOp::Profile(prog) => {
+                #[derive(Debug, Error, Diagnostic)]
+                #[error("Cannot profile a query that mutates stored relations")]
+                #[diagnostic(code(eval::profile_mutation))]
+                struct ProfileMutationError;
+
+                ensure!(prog.out_opts.store_relation.is_none(), ProfileMutationError);
+                let mut tx = self.transact()?;
+                let (normalized_program, out_opts) = prog.into_normalized_program(&tx)?;
+                let (stratified_program, store_lifetimes) =
+                    normalized_program.into_stratified_program()?;
+                let program = stratified_program.magic_sets_rewrite(&tx)?;
+                let compiled = tx.stratified_magic_compile(program)?;
+                let (num_to_take, num_to_skip) = out_opts.early_stop();
+                tx.plan_stats = Some(Default::default());
+                tx.stratified_magic_evaluate(
+                    &compiled,
+                    store_lifetimes,
+                    num_to_take,
+                    num_to_skip,
+                    Poison::default(),
+                )?;
+                let stats = tx.plan_stats.take().unwrap().into_inner().unwrap();
+                tx.commit_tx()?;
+                self.explain_compiled(&compiled, (num_to_take, num_to_skip), Some(&stats))
+            }
+            SysOp::Estimate(prog, sample) => {
+                #[derive(Debug, Error, Diagnostic)]
+                #[error("Cannot estimate a query that mutates stored relations")]
+                #[diagnostic(code(eval::estimate_mutation))]
+                struct EstimateMutationError;
+
+                ensure!(
+                    prog.out_opts.store_relation.is_none(),
+                    EstimateMutationError
+                );
+                let mut tx = self.transact()?;
+                let (normalized_program, out_opts) = prog.into_normalized_program(&tx)?;
+                let (stratified_program, store_lifetimes) =
+                    normalized_program.into_stratified_program()?;
                 let program = stratified_program.magic_sets_rewrite(&tx)?;
                 let compiled = tx.stratified_magic_compile(program)?;
+                let now = self.clock.seconds_since_the_epoch()?;
+                let mut estimate =
+                    tx.estimate_compiled(&compiled, out_opts.limit, &self.estimate_stats, now)?;
+                if let Some(n) = sample {
+                    // run the query on the first `n` rows of the scans driving the rules,
+                    // and scale up
+                    tx.scan_sample = Some(n);
+                    let started = self.clock.seconds_since_the_epoch()?;
+                    let (result, _) = tx.stratified_magic_evaluate(
+                        &compiled,
+                        store_lifetimes,
+                        None,
+                        None,
+                        Poison::default(),
+                    )?;
+                    let elapsed = self.clock.seconds_since_the_epoch()? - started;
+                    let (rows, bytes) =
+                        result
+                            .all_iter()
+                            .fold((0usize, 0usize), |(rows, bytes), t| {
+                                let encoded = t.into_tuple().encode_as_key(RelationId::SYSTEM);
+                                (rows + 1, bytes + encoded.len())
+                            });
+                    let scale = match estimate.driving_rows {
+                        Some(total) if total > n => total as f64 / n as f64,
+                        _ => 1.,
+                    };
+                    let mut est_rows = rows as f64 * scale;
+                    if let Some(limit) = out_opts.limit {
+                        est_rows = est_rows.min(limit as f64);
+                    }
+                    estimate.bytes = if rows == 0 {
+                        0.
+                    } else {
+                        bytes as f64 / rows as f64 * est_rows
+                    };
+                    estimate.rows = est_rows;
+                    estimate.secs = Some(elapsed * scale);
+                }
                 tx.commit_tx()?;
-                self.explain_compiled(&compiled)
+                Ok(estimate.into_named_rows())
             }
             SysOp::Compact => {
                 self.compact_relation()?;
@@ -1109,6 +2651,8 @@ impl<'s, S: Storage<'s>> Db<S> {
                     vec![vec![DataValue::from(OK_STR)]],
                 ))
             }
+            SysOp::CheckIntegrity => self.check_integrity(),
+            SysOp::StorageInfo => self.db.storage_info(),
             SysOp::ListRelations => self.list_relations(),
             SysOp::ListFixedRules => {
                 let rules = self.fixed_rules.read().unwrap();
@@ -1128,8 +2672,7 @@ impl<'s, S: Storage<'s>> Db<S> {
                 {
                     let mut tx = self.transact_write()?;
                     for rs in rel_names {
-                        let bound = tx.destroy_relation(&rs)?;
-                        bounds.push(bound);
+                        bounds.extend(tx.destroy_relation(&rs)?);
                     }
                     tx.commit_tx()?;
                 }
@@ -1141,14 +2684,14 @@ impl<'s, S: Storage<'s>> Db<S> {
                     vec![vec![DataValue::from(OK_STR)]],
                 ))
             }
-            SysOp::CreateIndex(rel_name, idx_name, cols) => {
+            SysOp::CreateIndex(rel_name, idx_name, cols, predicate) => {
                 let lock = self
                     .obtain_relation_locks(iter::once(&rel_name.name))
                     .pop()
                     .unwrap();
                 let _guard = lock.write().unwrap();
                 let mut tx = self.transact_write()?;
-                tx.create_index(&rel_name, &idx_name, cols)?;
+                tx.create_index(&rel_name, &idx_name, cols, predicate)?;
                 tx.commit_tx()?;
                 Ok(NamedRows::new(
                     vec![STATUS_STR.to_string()],
@@ -1162,53 +2705,472 @@ impl<'s, S: Storage<'s>> Db<S> {
                     .unwrap();
                 let _guard = lock.read().unwrap();
                 let mut tx = self.transact_write()?;
-                tx.remove_index(&rel_name, &idx_name)?;
+                let bounds = tx.remove_index(&rel_name, &idx_name)?;
                 tx.commit_tx()?;
+                for (lower, upper) in bounds {
+                    self.db.del_range(&lower, &upper)?;
+                }
                 Ok(NamedRows::new(
                     vec![STATUS_STR.to_string()],
                     vec![vec![DataValue::from(OK_STR)]],
                 ))
             }
-            SysOp::ListRelation(rs) => self.list_relation(&rs),
-            SysOp::RenameRelation(rename_pairs) => {
-                let rel_names = rename_pairs.iter().flat_map(|(f, t)| [&f.name, &t.name]);
-                let locks = self.obtain_relation_locks(rel_names);
-                let _guards = locks.iter().map(|l| l.read().unwrap()).collect_vec();
+            SysOp::CreateHnswIndex(rel_name, idx_name, options) => {
+                let lock = self
+                    .obtain_relation_locks(iter::once(&rel_name.name))
+                    .pop()
+                    .unwrap();
+                let _guard = lock.write().unwrap();
                 let mut tx = self.transact_write()?;
-                for (old, new) in rename_pairs {
-                    tx.rename_relation(old, new)?;
-                }
+                tx.create_hnsw_index(&rel_name, &idx_name, options)?;
                 tx.commit_tx()?;
                 Ok(NamedRows::new(
                     vec![STATUS_STR.to_string()],
                     vec![vec![DataValue::from(OK_STR)]],
                 ))
             }
-            SysOp::ListRunning => self.list_running(),
-            SysOp::KillRunning(id) => {
-                let queries = self.running_queries.lock().unwrap();
-                Ok(match queries.get(&id) {
-                    None => NamedRows::new(
-                        vec![STATUS_STR.to_string()],
-                        vec![vec![DataValue::from("NOT_FOUND")]],
-                    ),
-                    Some(handle) => {
-                        handle.poison.0.store(true, Ordering::Relaxed);
-                        NamedRows::new(
-                            vec![STATUS_STR.to_string()],
-                            vec![vec![DataValue::from("KILLING")]],
-                        )
-                    }
-                })
-            }
-            SysOp::ShowTrigger(name) => {
-                let mut tx = self.transact()?;
-                let rel = tx.get_relation(&name, false)?;
-                let mut rows: Vec<Vec<JsonValue>> = vec![];
-                for (i, trigger) in rel.put_triggers.iter().enumerate() {
-                    rows.push(vec![json!("put"), json!(i), json!(trigger)])
+            SysOp::RemoveHnswIndex(rel_name, idx_name) => {
+                let lock = self
+                    .obtain_relation_locks(iter::once(&rel_name.name))
+                    .pop()
+                    .unwrap();
+                let _guard = lock.write().unwrap();
+                let mut tx = self.transact_write()?;
+                let bounds = tx.remove_hnsw_index(&rel_name, &idx_name)?;
+                tx.commit_tx()?;
+                for (lower, upper) in bounds {
+                    self.db.del_range(&lower, &upper)?;
                 }
-                for (i, trigger) in rel.rm_triggers.iter().enumerate() {
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::RebuildHnswIndex(rel_name, idx_name) => {
+                let lock = self
+                    .obtain_relation_locks(iter::once(&rel_name.name))
+                    .pop()
+                    .unwrap();
+                let _guard = lock.write().unwrap();
+                let mut tx = self.transact_write()?;
+                tx.rebuild_hnsw_index(&rel_name, &idx_name)?;
+                tx.commit_tx()?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::CreateFtsIndex(rel_name, idx_name, options) => {
+                let lock = self
+                    .obtain_relation_locks(iter::once(&rel_name.name))
+                    .pop()
+                    .unwrap();
+                let _guard = lock.write().unwrap();
+                let mut tx = self.transact_write()?;
+                tx.create_fts_index(&rel_name, &idx_name, options)?;
+                tx.commit_tx()?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::RemoveFtsIndex(rel_name, idx_name) => {
+                let lock = self
+                    .obtain_relation_locks(iter::once(&rel_name.name))
+                    .pop()
+                    .unwrap();
+                let _guard = lock.write().unwrap();
+                let mut tx = self.transact_write()?;
+                let bounds = tx.remove_fts_index(&rel_name, &idx_name)?;
+                tx.commit_tx()?;
+                for (lower, upper) in bounds {
+                    self.db.del_range(&lower, &upper)?;
+                }
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::RebuildFtsIndex(rel_name, idx_name) => {
+                let lock = self
+                    .obtain_relation_locks(iter::once(&rel_name.name))
+                    .pop()
+                    .unwrap();
+                let _guard = lock.write().unwrap();
+                let mut tx = self.transact_write()?;
+                tx.rebuild_fts_index(&rel_name, &idx_name)?;
+                tx.commit_tx()?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::CreateLshIndex(rel_name, idx_name, options) => {
+                let lock = self
+                    .obtain_relation_locks(iter::once(&rel_name.name))
+                    .pop()
+                    .unwrap();
+                let _guard = lock.write().unwrap();
+                let mut tx = self.transact_write()?;
+                tx.create_lsh_index(&rel_name, &idx_name, options)?;
+                tx.commit_tx()?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::RemoveLshIndex(rel_name, idx_name) => {
+                let lock = self
+                    .obtain_relation_locks(iter::once(&rel_name.name))
+                    .pop()
+                    .unwrap();
+                let _guard = lock.write().unwrap();
+                let mut tx = self.transact_write()?;
+                let bounds = tx.remove_lsh_index(&rel_name, &idx_name)?;
+                tx.commit_tx()?;
+                for (lower, upper) in bounds {
+                    self.db.del_range(&lower, &upper)?;
+                }
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::RebuildLshIndex(rel_name, idx_name) => {
+                let lock = self
+                    .obtain_relation_locks(iter::once(&rel_name.name))
+                    .pop()
+                    .unwrap();
+                let _guard = lock.write().unwrap();
+                let mut tx = self.transact_write()?;
+                tx.rebuild_lsh_index(&rel_name, &idx_name)?;
+                tx.commit_tx()?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::CreateConstraint(fk) => {
+                // in order and only once, also for relations referring to themselves
+                let rel_names = BTreeSet::from([&fk.relation, &fk.target]);
+                let locks = self.obtain_relation_locks(rel_names.into_iter());
+                let _guards = locks.iter().map(|l| l.write().unwrap()).collect_vec();
+                let mut tx = self.transact_write()?;
+                tx.create_foreign_key(fk)?;
+                tx.commit_tx()?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::RemoveConstraint(rel_name, column) => {
+                let lock = self
+                    .obtain_relation_locks(iter::once(&rel_name.name))
+                    .pop()
+                    .unwrap();
+                let _guard = lock.write().unwrap();
+                let mut tx = self.transact_write()?;
+                let bounds = tx.remove_foreign_key(&rel_name, &column)?;
+                tx.commit_tx()?;
+                for (lower, upper) in bounds {
+                    self.db.del_range(&lower, &upper)?;
+                }
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::ListConstraints => {
+                let tx = self.transact()?;
+                tx.list_foreign_keys()
+            }
+            SysOp::ListIndices(rel_name) => {
+                let tx = self.transact()?;
+                let handle = tx.get_relation(&rel_name, false)?;
+                // the hidden indices of unique columns and constraints are not listed
+                let mut rows = handle
+                    .indices
+                    .iter()
+                    .filter(|(name, _)| {
+                        unique_index_col(name).is_none() && foreign_key_index_col(name).is_none()
+                    })
+                    .map(|(name, (idx_handle, _))| {
+                        let columns = idx_handle
+                            .metadata
+                            .keys
+                            .iter()
+                            .map(|col| DataValue::from(&col.name as &str))
+                            .collect_vec();
+                        vec![
+                            DataValue::from(name as &str),
+                            DataValue::List(columns),
+                            match handle.index_predicates.get(name) {
+                                None => DataValue::Null,
+                                Some(pred) => DataValue::from(pred.to_string()),
+                            },
+                        ]
+                    })
+                    .collect_vec();
+                // HNSW, full-text and LSH indices are listed with the columns they index
+                let searched = handle
+                    .hnsw_indices
+                    .iter()
+                    .map(|(name, (_, manifest))| (name, &manifest.fields))
+                    .chain(
+                        handle
+                            .fts_indices
+                            .iter()
+                            .map(|(name, (_, manifest))| (name, &manifest.fields)),
+                    )
+                    .chain(
+                        handle
+                            .lsh_indices
+                            .iter()
+                            .map(|(name, (_, manifest))| (name, &manifest.fields)),
+                    );
+                for (name, fields) in searched {
+                    let columns = fields
+                        .iter()
+                        .map(|col| DataValue::from(col as &str))
+                        .collect_vec();
+                    rows.push(vec![
+                        DataValue::from(name as &str),
+                        DataValue::List(columns),
+                        DataValue::Null,
+                    ]);
+                }
+                Ok(NamedRows::new(
+                    vec![
+                        "name".to_string(),
+                        "columns".to_string(),
+                        "predicate".to_string(),
+                    ],
+                    rows,
+                ))
+            }
+            SysOp::CreateGraph(def) => {
+                let mut tx = self.transact_write()?;
+                tx.create_graph(def)?;
+                tx.commit_tx()?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::RemoveGraph(name) => {
+                let mut tx = self.transact_write()?;
+                tx.remove_graph(&name, name.span)?;
+                tx.commit_tx()?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::ListGraphs => {
+                let tx = self.transact()?;
+                let mut rows = vec![];
+                for def in tx.list_graphs()? {
+                    rows.push(vec![
+                        DataValue::from(&def.name as &str),
+                        DataValue::from(def.edges.to_string()),
+                        match &def.nodes {
+                            None => DataValue::Null,
+                            Some(nodes) => DataValue::from(nodes.to_string()),
+                        },
+                        DataValue::from(def.undirected),
+                    ]);
+                }
+                Ok(NamedRows::new(
+                    vec![
+                        "name".to_string(),
+                        "edges".to_string(),
+                        "nodes".to_string(),
+                        "undirected".to_string(),
+                    ],
+                    rows,
+                ))
+            }
+            SysOp::ListRelation(rs) => self.list_relation(&rs),
+            SysOp::RenameRelation(rename_pairs) => {
+                let rel_names = rename_pairs.iter().flat_map(|(f, t)| [&f.name, &t.name]);
+                let locks = self.obtain_relation_locks(rel_names);
+                let _guards = locks.iter().map(|l| l.read().unwrap()).collect_vec();
+                let mut tx = self.transact_write()?;
+                for (old, new) in rename_pairs {
+                    tx.rename_relation(old, new)?;
+                }
+                tx.commit_tx()?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::RebuildRelation(rel_name) => {
+                let lock = self
+                    .obtain_relation_locks(iter::once(&rel_name.name))
+                    .pop()
+                    .unwrap();
+                let _guard = lock.write().unwrap();
+                let old_ranges = {
+                    let mut tx = self.transact_write()?;
+                    let old_ranges = tx.rebuild_relation(&rel_name)?;
+                    tx.commit_tx()?;
+                    old_ranges
+                };
+                for (lower, upper) in old_ranges {
+                    self.db.del_range(&lower, &upper)?;
+                }
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::AlterRelation(rel_name, op) => {
+                let lock = self
+                    .obtain_relation_locks(iter::once(&rel_name.name))
+                    .pop()
+                    .unwrap();
+                let _guard = lock.write().unwrap();
+                let mut tx = self.transact_write()?;
+                let cleared = tx.alter_relation(&rel_name, op)?;
+                tx.commit_tx()?;
+                for (lower, upper) in cleared {
+                    self.db.del_range(&lower, &upper)?;
+                }
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::SetTtl(rel_name, column) => {
+                let lock = self
+                    .obtain_relation_locks(iter::once(&rel_name.name))
+                    .pop()
+                    .unwrap();
+                let _guard = lock.write().unwrap();
+                let mut tx = self.transact_write()?;
+                tx.set_ttl(&rel_name, column)?;
+                tx.commit_tx()?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::SetHistoryRetention(rel_name, retention) => {
+                let lock = self
+                    .obtain_relation_locks(iter::once(&rel_name.name))
+                    .pop()
+                    .unwrap();
+                let _guard = lock.write().unwrap();
+                let mut tx = self.transact_write()?;
+                tx.set_history_retention(&rel_name, retention)?;
+                tx.commit_tx()?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::SweepExpired(rel_name) => {
+                let lock = self
+                    .obtain_relation_locks(iter::once(&rel_name.name))
+                    .pop()
+                    .unwrap();
+                let _guard = lock.write().unwrap();
+                // in batches, so that large relations do not make huge transactions
+                let mut removed = 0;
+                let mut from = None;
+                loop {
+                    let mut tx = self.transact_write()?;
+                    let batch = tx.sweep_expired(&rel_name, from, SWEEP_BATCH_ROWS)?;
+                    tx.commit_tx()?;
+                    for (lower, upper) in &batch.ranges {
+                        self.db.del_range(lower, upper)?;
+                    }
+                    removed += batch.removed;
+                    match batch.resume {
+                        None => break,
+                        Some(key) => from = Some(key),
+                    }
+                }
+                // the keys of the rows removed may be written again once the lock is released
+                self.db.wait_for_deletions()?;
+                let handle = self.transact()?.get_relation(&rel_name, false)?;
+                let lower = Tuple::default().encode_as_key(handle.id);
+                let upper = Tuple::default().encode_as_key(handle.id.next());
+                self.db.range_compact(&lower, &upper)?;
+                Ok(NamedRows::new(
+                    vec!["removed".to_string()],
+                    vec![vec![DataValue::from(removed as i64)]],
+                ))
+            }
+            SysOp::ImportCsv(rel, path, options) => self.import_csv(&rel, &path, &options),
+            SysOp::ExportCsv(source, path, options) => self.export_csv(source, &path, &options),
+            SysOp::ImportJsonl(rel, path) => {
+                let n = self.import_relations_jsonl(&rel.name, open_jsonl(&path)?)?;
+                Ok(NamedRows::new(
+                    vec!["imported".to_string()],
+                    vec![vec![DataValue::from(n as i64)]],
+                ))
+            }
+            SysOp::ExportJsonl(rel, path) => {
+                let n = self.export_relations_jsonl(&rel.name, create_jsonl(&path)?)?;
+                Ok(NamedRows::new(
+                    vec!["exported".to_string()],
+                    vec![vec![DataValue::from(n as i64)]],
+                ))
+            }
+            SysOp::RestoreRelations(path, mappings) => {
+                let mappings = mappings
+                    .into_iter()
+                    .map(|(from, to)| (from.name.to_string(), to.name.to_string()))
+                    .collect_vec();
+                self.import_from_backup_renamed(&path, &mappings)?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::ListRunning => {
+                let rows = self
+                    .list_running()?
+                    .into_iter()
+                    .map(|query| {
+                        vec![
+                            DataValue::from(query.id as i64),
+                            DataValue::from(query.started_at),
+                            DataValue::from(query.elapsed),
+                            DataValue::from(query.script),
+                            DataValue::from(query.is_write),
+                        ]
+                    })
+                    .collect_vec();
+                Ok(NamedRows::new(
+                    vec![
+                        "id".to_string(),
+                        "started_at".to_string(),
+                        "elapsed".to_string(),
+                        "script".to_string(),
+                        "is_write".to_string(),
+                    ],
+                    rows,
+                ))
+            }
+            SysOp::KillRunning(id) => {
+                let found = self.kill_query(id);
+                let status = if found { "KILLING" } else { "NOT_FOUND" };
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string(), "found".to_string()],
+                    vec![vec![DataValue::from(status), DataValue::from(found)]],
+                ))
+            }
+            SysOp::ShowTrigger(name) => {
+                let mut tx = self.transact()?;
+                let rel = tx.get_relation(&name, false)?;
+                let mut rows: Vec<Vec<JsonValue>> = vec![];
+                for (i, trigger) in rel.put_triggers.iter().enumerate() {
+                    rows.push(vec![json!("put"), json!(i), json!(trigger)])
+                }
+                for (i, trigger) in rel.rm_triggers.iter().enumerate() {
                     rows.push(vec![json!("rm"), json!(i), json!(trigger)])
                 }
                 for (i, trigger) in rel.replace_triggers.iter().enumerate() {
@@ -1244,6 +3206,24 @@ impl<'s, S: Storage<'s>> Db<S> {
                     vec![vec![DataValue::from(OK_STR)]],
                 ))
             }
+            SysOp::SetAudit(name, flags) => {
+                let lock = self
+                    .obtain_relation_locks(iter::once(&name.name))
+                    .pop()
+                    .unwrap();
+                let _guard = lock.write().unwrap();
+                let mut tx = self.transact_write()?;
+                tx.set_audit(&name, flags)?;
+                tx.commit_tx()?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::ShowAuditLog => {
+                let tx = self.transact()?;
+                tx.audit_log()
+            }
         }
     }
     /// This is the entry to query evaluation
@@ -1256,9 +3236,6 @@ impl<'s, S: Storage<'s>> Db<S> {
         callback_collector: &mut CallbackCollector,
         top_level: bool,
     ) -> Result<(NamedRows, Vec<(Vec<u8>, Vec<u8>)>)> {
-        // cleanups contain stored relations that should be deleted at the end of query
-        let mut clean_ups = vec![];
-
         // Some checks in case the query specifies mutation
         if let Some((meta, op)) = &input_program.out_opts.store_relation {
             if *op == RelationOp::Create {
@@ -1284,31 +3261,130 @@ impl<'s, S: Storage<'s>> Db<S> {
                     StoreRelationNotFoundError(meta.name.to_string())
                 );
 
-                existing.ensure_compatible(meta, *op == RelationOp::Rm)?;
+                existing.ensure_compatible(
+                    meta,
+                    matches!(op, RelationOp::Rm | RelationOp::Update | RelationOp::Upsert),
+                )?;
             }
         };
 
-        // query compilation
-        let entry_head_or_default = input_program.get_entry_out_head_or_default()?;
-        let (normalized_program, out_opts) = input_program.into_normalized_program(tx)?;
+        let prepared = self.prepare_query(tx, input_program)?;
+        self.run_prepared_query(
+            tx,
+            prepared,
+            cur_vld,
+            callback_targets,
+            callback_collector,
+            top_level,
+        )
+    }
+    /// Query compilation: takes the program through all the planning stages
+    pub(crate) fn prepare_query(
+        &self,
+        tx: &mut SessionTx<'_>,
+        input_program: InputProgram,
+    ) -> Result<CompiledQuery> {
+        let entry_head = input_program.get_entry_out_head_or_default()?;
+        let uncacheable = input_program.is_uncacheable();
+        // applications of `now()` are folded into values when normalizing
+        let clock = tx.clock.clone();
+        let (normalized_program, out_opts) =
+            clock.scoped(|| input_program.into_normalized_program(tx))?;
         let (stratified_program, store_lifetimes) = normalized_program.into_stratified_program()?;
         let program = stratified_program.magic_sets_rewrite(tx)?;
-        let compiled = tx.stratified_magic_compile(program)?;
+        let strata = tx.stratified_magic_compile(program)?;
+        self.plans_count.fetch_add(1, Ordering::AcqRel);
+        Ok(CompiledQuery {
+            entry_head,
+            out_opts,
+            store_lifetimes,
+            strata,
+            uncacheable,
+        })
+    }
+    /// Evaluates a query that has already been compiled
+    pub(crate) fn run_prepared_query(
+        &self,
+        tx: &mut SessionTx<'_>,
+        prepared: CompiledQuery,
+        cur_vld: ValidityTs,
+        callback_targets: &BTreeSet<SmartString<LazyCompact>>,
+        callback_collector: &mut CallbackCollector,
+        top_level: bool,
+    ) -> Result<(NamedRows, Vec<(Vec<u8>, Vec<u8>)>)> {
+        let start = if prepared.out_opts.report_usage {
+            Some(tx.start_usage_report()?)
+        } else {
+            None
+        };
+        let try_warnings = TryWarnings::default();
+        let clock = tx.clock.clone();
+        let (mut ret, clean_ups) = TryWarnings::scoped(Some(&try_warnings), || {
+            clock.scoped(|| {
+                self.evaluate_prepared_query(
+                    tx,
+                    prepared,
+                    cur_vld,
+                    callback_targets,
+                    callback_collector,
+                    top_level,
+                    None,
+                )
+            })
+        })?;
+        ret.warnings = try_warnings.take();
+        if let Some(start) = start {
+            ret.usage = Some(tx.finish_usage_report(start)?);
+        }
+        Ok((ret, clean_ups))
+    }
+    /// With `sink`, the rows of a read-only query are passed to it instead of being returned
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn evaluate_prepared_query(
+        &self,
+        tx: &mut SessionTx<'_>,
+        prepared: CompiledQuery,
+        cur_vld: ValidityTs,
+        callback_targets: &BTreeSet<SmartString<LazyCompact>>,
+        callback_collector: &mut CallbackCollector,
+        top_level: bool,
+        sink: Option<&mut RowSink<'_>>,
+    ) -> Result<(NamedRows, Vec<(Vec<u8>, Vec<u8>)>)> {
+        // cleanups contain stored relations that should be deleted at the end of query
+        let mut clean_ups = vec![];
+        let out_headers = prepared.out_headers();
+        let CompiledQuery {
+            entry_head: entry_head_or_default,
+            out_opts,
+            store_lifetimes,
+            strata: compiled,
+            ..
+        } = prepared;
+
+        let validity_as_string = out_opts.validity_as_string || self.validity_as_string;
+        let output_options = OutputOptions {
+            float_format: out_opts
+                .float_format
+                .unwrap_or(self.output_options.float_format),
+            big_int_as_string: out_opts.big_int_as_string || self.output_options.big_int_as_string,
+        };
 
         // poison is used to terminate queries early
-        let poison = Poison::default();
+        let poison = tx.script.poison.nested();
         if let Some(secs) = out_opts.timeout {
-            poison.set_timeout(secs)?;
+            poison.set_timeout(secs, self.clock.virtual_fn())?;
         }
         // give the query an ID and store it so that it can be queried and cancelled
         let id = self.queries_count.fetch_add(1, Ordering::AcqRel);
 
         // time the query
-        let since_the_epoch = seconds_since_the_epoch()?;
+        let since_the_epoch = self.clock.seconds_since_the_epoch()?;
 
         let handle = RunningQueryHandle {
             started_at: since_the_epoch,
             poison: poison.clone(),
+            script: tx.script.snippet.clone(),
+            is_write: out_opts.store_relation.is_some(),
         };
         self.running_queries.lock().unwrap().insert(id, handle);
 
@@ -1318,18 +3394,64 @@ impl<'s, S: Storage<'s>> Db<S> {
             running_queries: self.running_queries.clone(),
         };
 
-        let total_num_to_take = if out_opts.sorters.is_empty() {
-            out_opts.num_to_take()
-        } else {
-            None
-        };
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some((meta, relation_op)) = &out_opts.store_relation {
+            if out_opts.sorters.is_empty()
+                && out_opts.limit.is_none()
+                && out_opts.offset.is_none()
+                && out_opts.assertion.is_none()
+                && entry_is_fixed_rule(&compiled)
+                && !stored_relations_read(&compiled).contains(meta.name.name.as_str())
+                && tx.can_store_directly(meta, *relation_op, callback_targets)?
+            {
+                let (to_clear, counts) = Self::store_fixed_rule_directly(
+                    tx,
+                    &compiled,
+                    store_lifetimes,
+                    meta,
+                    *relation_op,
+                    &entry_head_or_default,
+                    cur_vld,
+                    poison,
+                )
+                .wrap_err_with(|| format!("when executing against relation '{}'", meta.name))?;
+                clean_ups.extend(to_clear);
+                return Ok((mutation_result(*relation_op, counts), clean_ups));
+            }
+        }
 
-        let num_to_skip = if out_opts.sorters.is_empty() {
-            out_opts.offset
-        } else {
-            None
+        // rows for a sink are sorted as they are derived instead of being collected first
+        #[cfg(not(target_arch = "wasm32"))]
+        let sink = match sink {
+            Some(sink)
+                if out_opts.store_relation.is_none()
+                    && out_opts.early_stop() == (None, None)
+                    && out_opts.assertion.is_none()
+                    && entry_streams_rows(&compiled) =>
+            {
+                let sorter = ExternalSorter::new(
+                    &out_opts.sorters,
+                    &entry_head_or_default,
+                    out_opts.num_to_take(),
+                    &self.sort_options,
+                );
+                let sorted = Self::evaluate_sorted(tx, &compiled, store_lifetimes, sorter, poison)?;
+                let sorted = if out_opts.windows.is_empty() {
+                    sorted
+                } else {
+                    compute_windows(sorted, &out_opts.windows, &entry_head_or_default)
+                };
+                let sorted = sorted
+                    .skip(out_opts.offset.unwrap_or(0))
+                    .take(out_opts.limit.unwrap_or(usize::MAX));
+                feed_rows(sorted, sink, validity_as_string)?;
+                return Ok((NamedRows::default(), clean_ups));
+            }
+            sink => sink,
         };
 
+        let (total_num_to_take, num_to_skip) = out_opts.early_stop();
+
         // the real evaluation
         let (result_store, early_return) = tx.stratified_magic_evaluate(
             &compiled,
@@ -1367,12 +3489,22 @@ impl<'s, S: Storage<'s>> Db<S> {
 
         if !out_opts.sorters.is_empty() {
             // sort outputs if required
-            let sorted_result =
-                tx.sort_and_collect(result_store, &out_opts.sorters, &entry_head_or_default)?;
+            let sorted_result = tx.sort_and_collect(
+                result_store,
+                &out_opts.sorters,
+                &entry_head_or_default,
+                out_opts.num_to_take(),
+                &self.sort_options,
+            )?;
+            let sorted_result = if out_opts.windows.is_empty() {
+                sorted_result
+            } else {
+                compute_windows(sorted_result, &out_opts.windows, &entry_head_or_default)
+            };
             let sorted_iter = if let Some(offset) = out_opts.offset {
-                Left(sorted_result.into_iter().skip(offset))
+                Left(sorted_result.skip(offset))
             } else {
-                Right(sorted_result.into_iter())
+                Right(sorted_result)
             };
             let sorted_iter = if let Some(limit) = out_opts.limit {
                 Left(sorted_iter.take(limit))
@@ -1380,7 +3512,11 @@ impl<'s, S: Storage<'s>> Db<S> {
                 Right(sorted_iter)
             };
             if let Some((meta, relation_op)) = &out_opts.store_relation {
-                let to_clear = tx
+                // rows merged from disk may fail to be read
+                let mut read_err = None;
+                let sorted_iter =
+                    sorted_iter.map_while(|row| row.map_err(|err| read_err = Some(err)).ok());
+                let (to_clear, counts) = tx
                     .execute_relation(
                         self,
                         sorted_iter,
@@ -1393,27 +3529,26 @@ impl<'s, S: Storage<'s>> Db<S> {
                         top_level,
                     )
                     .wrap_err_with(|| format!("when executing against relation '{}'", meta.name))?;
+                if let Some(err) = read_err {
+                    return Err(err);
+                }
                 clean_ups.extend(to_clear);
-                Ok((
-                    NamedRows::new(
-                        vec![STATUS_STR.to_string()],
-                        vec![vec![DataValue::from(OK_STR)]],
-                    ),
-                    clean_ups,
-                ))
+                Ok((mutation_result(*relation_op, counts), clean_ups))
+            } else if let Some(sink) = sink {
+                feed_rows(sorted_iter, sink, validity_as_string)?;
+                Ok((NamedRows::default(), clean_ups))
             } else {
                 // not sorting outputs
-                let rows: Vec<Tuple> = sorted_iter.collect_vec();
-                Ok((
-                    NamedRows::new(
-                        entry_head_or_default
-                            .iter()
-                            .map(|s| s.to_string())
-                            .collect_vec(),
-                        rows,
-                    ),
-                    clean_ups,
-                ))
+                let mut rows: Vec<Tuple> = sorted_iter.try_collect()?;
+                if out_opts.report_usage {
+                    tx.track_memory(rows.iter().map(approx_tuple_size).sum());
+                }
+                if validity_as_string {
+                    validity_to_string(&mut rows);
+                }
+                let mut ret = NamedRows::new(out_headers, rows);
+                ret.output_options = Some(output_options);
+                Ok((ret, clean_ups))
             }
         } else {
             let scan = if early_return {
@@ -1435,7 +3570,7 @@ impl<'s, S: Storage<'s>> Db<S> {
             };
 
             if let Some((meta, relation_op)) = &out_opts.store_relation {
-                let to_clear = tx
+                let (to_clear, counts) = tx
                     .execute_relation(
                         self,
                         scan,
@@ -1449,46 +3584,104 @@ impl<'s, S: Storage<'s>> Db<S> {
                     )
                     .wrap_err_with(|| format!("when executing against relation '{}'", meta.name))?;
                 clean_ups.extend(to_clear);
-                Ok((
-                    NamedRows::new(
-                        vec![STATUS_STR.to_string()],
-                        vec![vec![DataValue::from(OK_STR)]],
-                    ),
-                    clean_ups,
-                ))
+                Ok((mutation_result(*relation_op, counts), clean_ups))
+            } else if let Some(sink) = sink {
+                feed_rows(scan.map(Ok), sink, validity_as_string)?;
+                Ok((NamedRows::default(), clean_ups))
             } else {
-                let rows: Vec<Tuple> = scan.collect_vec();
-
-                Ok((
-                    NamedRows::new(
-                        entry_head_or_default
-                            .iter()
-                            .map(|s| s.to_string())
-                            .collect_vec(),
-                        rows,
-                    ),
-                    clean_ups,
-                ))
+                let mut rows: Vec<Tuple> = scan.collect_vec();
+                if out_opts.report_usage {
+                    tx.track_memory(rows.iter().map(approx_tuple_size).sum());
+                }
+                if validity_as_string {
+                    validity_to_string(&mut rows);
+                }
+                let mut ret = NamedRows::new(out_headers, rows);
+                ret.output_options = Some(output_options);
+                Ok((ret, clean_ups))
             }
         }
     }
-    pub(crate) fn list_running(&self) -> Result<NamedRows> {
-        let rows = self
-            .running_queries
-            .lock()
-            .unwrap()
-            .iter()
-            .map(|(k, v)| {
-                vec![
-                    DataValue::from(*k as i64),
-                    DataValue::from(format!("{:?}", v.started_at)),
-                ]
-            })
-            .collect_vec();
-        Ok(NamedRows::new(
-            vec!["id".to_string(), "started_at".to_string()],
-            rows,
-        ))
+    /// Evaluates a program whose entry is a fixed rule, writing the rows produced into the
+    /// stored relation from another thread as they come, instead of materializing them first.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[allow(clippy::too_many_arguments)]
+    fn store_fixed_rule_directly(
+        tx: &mut SessionTx<'_>,
+        compiled: &[CompiledProgram],
+        store_lifetimes: BTreeMap<MagicSymbol, usize>,
+        meta: &InputRelationHandle,
+        op: RelationOp,
+        headers: &[Symbol],
+        cur_vld: ValidityTs,
+        poison: Poison,
+    ) -> Result<(Vec<(Vec<u8>, Vec<u8>)>, MutationCounts)> {
+        let (target, to_clear) = tx.prepare_direct_store(meta, op, headers)?;
+        let (sender, receiver) = bounded(DIRECT_STORE_CHUNK_SIZE);
+        *tx.entry_sink.lock().unwrap() = Some(sender);
+        let tx = &*tx;
+        let counts = thread::scope(|s| -> Result<MutationCounts> {
+            let writer = s.spawn(move || {
+                tx.clock
+                    .scoped(|| tx.write_direct_store(&target, receiver, cur_vld))
+            });
+            let evaluated =
+                tx.stratified_magic_evaluate(compiled, store_lifetimes, None, None, poison);
+            // the sink must be gone before joining, otherwise the writer waits forever
+            tx.entry_sink.lock().unwrap().take();
+            let evaluated = evaluated.map(|(result_store, _)| {
+                debug_assert!(result_store.all_iter().next().is_none());
+            });
+            let written = writer
+                .join()
+                .unwrap_or_else(|err| std::panic::resume_unwind(err));
+            // a failing writer makes the evaluation fail as well, report the cause
+            let counts = written?;
+            evaluated?;
+            Ok(counts)
+        })?;
+        #[cfg(test)]
+        tx.streamed_rows
+            .fetch_add(counts.rows_affected(), Ordering::Relaxed);
+        Ok((to_clear, counts))
+    }
+    /// Evaluates a program whose entry rule does not aggregate, pushing the rows derived for
+    /// the entry into `sorter` from another thread as they come, instead of storing them first.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn evaluate_sorted(
+        tx: &mut SessionTx<'_>,
+        compiled: &[CompiledProgram],
+        store_lifetimes: BTreeMap<MagicSymbol, usize>,
+        mut sorter: ExternalSorter,
+        poison: Poison,
+    ) -> Result<SortedTuples> {
+        let (sender, receiver) = bounded(DIRECT_STORE_CHUNK_SIZE);
+        *tx.entry_sink.lock().unwrap() = Some(sender);
+        let tx = &*tx;
+        thread::scope(|s| -> Result<()> {
+            let sorting = s.spawn(|| -> Result<()> {
+                for tuple in receiver {
+                    sorter.push(tx, tuple)?;
+                    #[cfg(test)]
+                    tx.streamed_rows.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(())
+            });
+            let evaluated =
+                tx.stratified_magic_evaluate(compiled, store_lifetimes, None, None, poison);
+            // the sink must be gone before joining, otherwise the sorting waits forever
+            tx.entry_sink.lock().unwrap().take();
+            let evaluated = evaluated.map(|(result_store, _)| {
+                debug_assert!(result_store.all_iter().next().is_none());
+            });
+            let sorted = sorting
+                .join()
+                .unwrap_or_else(|err| std::panic::resume_unwind(err));
+            // a failing sorter makes the evaluation fail as well, report the cause
+            sorted?;
+            evaluated
+        })?;
+        sorter.finish(tx)
     }
     fn list_relation(&'s self, name: &str) -> Result<NamedRows> {
         let mut tx = self.transact()?;
@@ -1502,6 +3695,9 @@ impl<'s, S: Storage<'s>> Db<S> {
                 json!(idx),
                 json!(col.typing.to_string()),
                 json!(col.default_gen.is_some()),
+                json!(col.auto_update.as_ref().map(|au| &au.expr)),
+                json!(col.auto_update.as_ref().map(|au| au.allow_override)),
+                json!(col.default_gen.as_ref().map(|expr| expr.to_string())),
             ]);
             idx += 1;
         }
@@ -1512,6 +3708,9 @@ impl<'s, S: Storage<'s>> Db<S> {
                 json!(idx),
                 json!(col.typing.to_string()),
                 json!(col.default_gen.is_some()),
+                json!(col.auto_update.as_ref().map(|au| &au.expr)),
+                json!(col.auto_update.as_ref().map(|au| au.allow_override)),
+                json!(col.default_gen.as_ref().map(|expr| expr.to_string())),
             ]);
             idx += 1;
         }
@@ -1527,6 +3726,64 @@ impl<'s, S: Storage<'s>> Db<S> {
                 "index".to_string(),
                 "type".to_string(),
                 "has_default".to_string(),
+                "auto_update".to_string(),
+                "allow_override".to_string(),
+                "default".to_string(),
+            ],
+            rows,
+        ))
+    }
+    fn check_integrity(&'s self) -> Result<NamedRows> {
+        let lower = vec![DataValue::from("")].encode_as_key(RelationId::SYSTEM);
+        let upper =
+            vec![DataValue::from(String::from(LARGEST_UTF_CHAR))].encode_as_key(RelationId::SYSTEM);
+        let mut tx = self.transact()?;
+        let mut handles = vec![];
+        for kv_res in tx.store_tx.range_scan(&lower, &upper) {
+            let (k_slice, v_slice) = kv_res?;
+            if upper <= k_slice {
+                break;
+            }
+            let handle = RelationHandle::decode(&v_slice)?;
+            if !handle.indices.is_empty() {
+                handles.push(handle);
+            }
+        }
+        let mut rows = vec![];
+        for handle in handles {
+            for checked in tx.check_index_integrity(&handle)? {
+                rows.push(vec![
+                    DataValue::from(&handle.name as &str),
+                    DataValue::from(&checked.index as &str),
+                    DataValue::from(checked.entries as i64),
+                    DataValue::from(checked.missing as i64),
+                    DataValue::from(checked.extra as i64),
+                    DataValue::from(checked.missing == 0 && checked.extra == 0),
+                ]);
+            }
+        }
+        // graphs referring to relations that no longer fit them are flagged under their name
+        for def in tx.list_graphs()? {
+            for relation in tx.broken_graph_relations(&def)? {
+                rows.push(vec![
+                    DataValue::from(relation),
+                    DataValue::from(&def.name as &str),
+                    DataValue::Null,
+                    DataValue::Null,
+                    DataValue::Null,
+                    DataValue::from(false),
+                ]);
+            }
+        }
+        tx.commit_tx()?;
+        Ok(NamedRows::new(
+            vec![
+                "relation".to_string(),
+                "index".to_string(),
+                "n_entries".to_string(),
+                "n_missing".to_string(),
+                "n_extra".to_string(),
+                "ok".to_string(),
             ],
             rows,
         ))
@@ -1552,6 +3809,7 @@ impl<'s, S: Storage<'s>> Db<S> {
             } else {
                 meta.access_level.to_string()
             };
+            let retention = meta.history_retention.as_ref();
             rows.push(vec![
                 json!(name),
                 json!(arity),
@@ -1561,6 +3819,8 @@ impl<'s, S: Storage<'s>> Db<S> {
                 json!(meta.put_triggers.len()),
                 json!(meta.rm_triggers.len()),
                 json!(meta.replace_triggers.len()),
+                json!(retention.and_then(|r| r.max_versions)),
+                json!(retention.and_then(|r| r.max_age_seconds)),
             ]);
         }
         let rows = rows
@@ -1577,6 +3837,8 @@ impl<'s, S: Storage<'s>> Db<S> {
                 "n_put_triggers".to_string(),
                 "n_rm_triggers".to_string(),
                 "n_replace_triggers".to_string(),
+                "history_max_versions".to_string(),
+                "history_max_age_seconds".to_string(),
             ],
             rows,
         ))
@@ -1585,7 +3847,10 @@ impl<'s, S: Storage<'s>> Db<S> {
 
 /// Used for user-initiated termination of running queries
 #[derive(Clone, Default)]
-pub struct Poison(pub(crate) Arc<AtomicBool>);
+pub struct Poison(pub(crate) Arc<AtomicU8>, Option<Arc<Poison>>);
+
+const POISON_KILLED: u8 = 1;
+const POISON_TIMED_OUT: u8 = 2;
 
 impl Poison {
     /// Will return `Err` if user has initiated termination.
@@ -1597,26 +3862,166 @@ impl Poison {
         #[diagnostic(help("A query may be killed by timeout, or explicit command"))]
         struct ProcessKilled;
 
-        if self.0.load(Ordering::Relaxed) {
-            bail!(ProcessKilled)
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("Running query timed out before completion")]
+        #[diagnostic(code(eval::timeout))]
+        #[diagnostic(help("The time allowed is set by the `:timeout` option"))]
+        struct ProcessTimedOut;
+
+        let mut poison = self;
+        loop {
+            match poison.0.load(Ordering::Relaxed) {
+                0 => {}
+                POISON_TIMED_OUT => bail!(ProcessTimedOut),
+                _ => bail!(ProcessKilled),
+            }
+            match &poison.1 {
+                None => return Ok(()),
+                Some(outer) => poison = outer,
+            }
+        }
+    }
+    /// A poison for a part of the work guarded by this one: it can be killed or time out
+    /// on its own, and is also poisoned when this one is
+    pub(crate) fn nested(&self) -> Self {
+        Self(Default::default(), Some(Arc::new(self.clone())))
+    }
+    /// Whether this poison, not counting the ones it is nested in, has timed out
+    pub(crate) fn timed_out(&self) -> bool {
+        self.0.load(Ordering::Relaxed) == POISON_TIMED_OUT
+    }
+    pub(crate) fn kill(&self) {
+        self.0.store(POISON_KILLED, Ordering::Relaxed);
+    }
+    /// Sleeps for the duration given, returning `Err` as soon as termination is initiated
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn sleep(&self, duration: Duration) -> Result<()> {
+        let deadline = Instant::now() + duration;
+        loop {
+            self.check()?;
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(());
+            }
+            thread::sleep((deadline - now).min(Duration::from_millis(10)));
         }
-        Ok(())
     }
     #[cfg(target_arch = "wasm32")]
-    pub(crate) fn set_timeout(&self, _secs: f64) -> Result<()> {
+    pub(crate) fn set_timeout(&self, _secs: f64, _clock: Option<ClockFn>) -> Result<()> {
         bail!("Cannot set timeout when threading is disallowed");
     }
+    /// Times out after `secs` seconds of the system time, or of `clock` if given
     #[cfg(not(target_arch = "wasm32"))]
-    pub(crate) fn set_timeout(&self, secs: f64) -> Result<()> {
+    pub(crate) fn set_timeout(&self, secs: f64, clock: Option<ClockFn>) -> Result<()> {
         let pill = self.clone();
         thread::spawn(move || {
-            thread::sleep(Duration::from_micros((secs * 1000000.) as u64));
-            pill.0.store(true, Ordering::Relaxed);
+            match clock {
+                None => thread::sleep(Duration::from_micros((secs * 1000000.) as u64)),
+                Some(clock) => {
+                    let deadline = clock() + (secs * 1000000.) as i64;
+                    while clock() < deadline {
+                        // the query is done when no one else holds the pill
+                        if Arc::strong_count(&pill.0) == 1 {
+                            return;
+                        }
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                }
+            }
+            let _ =
+                pill.0
+                    .compare_exchange(0, POISON_TIMED_OUT, Ordering::Relaxed, Ordering::Relaxed);
         });
         Ok(())
     }
 }
 
+/// The result of a query writing into a stored relation, with the numbers of rows it changed
+fn mutation_result(op: RelationOp, counts: MutationCounts) -> NamedRows {
+    NamedRows::new(
+        vec![
+            STATUS_STR.to_string(),
+            "op".to_string(),
+            "rows_affected".to_string(),
+            "inserted".to_string(),
+            "overwritten".to_string(),
+        ],
+        vec![vec![
+            DataValue::from(OK_STR),
+            DataValue::from(op.as_str()),
+            DataValue::from(counts.rows_affected() as i64),
+            DataValue::from(counts.inserted as i64),
+            DataValue::from(counts.overwritten as i64),
+        ]],
+    )
+}
+
+/// Replaces validity values in the rows by their RFC 3339 string form.
+/// Passes the rows to `sink` until it asks to stop
+fn feed_rows(
+    rows: impl Iterator<Item = Result<Tuple>>,
+    sink: &mut RowSink<'_>,
+    validity_as_string: bool,
+) -> Result<()> {
+    for row in rows {
+        let mut row = row?;
+        if validity_as_string {
+            validity_to_string(std::slice::from_mut(&mut row));
+        }
+        if sink(row)?.is_break() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn validity_to_string(rows: &mut [Tuple]) {
+    for val in rows.iter_mut().flat_map(|row| row.iter_mut()) {
+        if let DataValue::Validity(vld) = val {
+            if let Some(formatted) = vld2str(vld) {
+                *val = DataValue::from(formatted);
+            }
+        }
+    }
+}
+
+/// Whether the rows derived for the entry of the program can be passed on as they come,
+/// which is the case unless the entry aggregates.
+#[cfg(not(target_arch = "wasm32"))]
+fn entry_streams_rows(strata: &[CompiledProgram]) -> bool {
+    strata
+        .iter()
+        .flat_map(|stratum| stratum.iter())
+        .any(|(name, ruleset)| {
+            name.is_prog_entry()
+                && match ruleset {
+                    CompiledRuleSet::Fixed(_) => true,
+                    CompiledRuleSet::Rules(_) => ruleset.aggr_kind() == AggrKind::None,
+                }
+        })
+}
+
+/// Whether the entry of the program is a fixed rule.
+#[cfg(not(target_arch = "wasm32"))]
+fn entry_is_fixed_rule(strata: &[CompiledProgram]) -> bool {
+    strata
+        .iter()
+        .flat_map(|stratum| stratum.iter())
+        .any(|(name, ruleset)| name.is_prog_entry() && matches!(ruleset, CompiledRuleSet::Fixed(_)))
+}
+
+/// Compiles the query as it would be run, for explaining its plan
+fn compile_for_explain(
+    tx: &mut SessionTx<'_>,
+    program: InputProgram,
+) -> Result<(Vec<CompiledProgram>, QueryOutOptions)> {
+    let (normalized_program, out_opts) = program.into_normalized_program(tx)?;
+    let (stratified_program, _) = normalized_program.into_stratified_program()?;
+    let program = stratified_program.magic_sets_rewrite(tx)?;
+    let compiled = tx.stratified_magic_compile(program)?;
+    Ok((compiled, out_opts))
+}
+
 pub(crate) fn seconds_since_the_epoch() -> Result<f64> {
     #[cfg(not(target_arch = "wasm32"))]
         let now = SystemTime::now();
@@ -1628,4 +4033,4 @@ pub(crate) fn seconds_since_the_epoch() -> Result<f64> {
 
     #[cfg(target_arch = "wasm32")]
         Ok(js_sys::Date::now())
-}
\ No newline at end of file
+}
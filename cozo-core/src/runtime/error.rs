@@ -0,0 +1,397 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::error::Error as StdError;
+use std::fmt::{Debug, Display, Formatter};
+
+use miette::{Diagnostic, LabeledSpan, Report, Severity, SourceCode};
+
+/// Errors returned by the database, classified for programmatic handling.
+///
+/// Each variant carries the original diagnostic, which is also what is displayed.
+/// Errors returned by [crate::Db::run_script] and its dispatchers are reports wrapping
+/// this type, so it can be obtained with `report.downcast_ref::<CozoError>()`,
+/// or with [CozoError::from_report] for reports coming from elsewhere.
+///
+/// The class is decided by the code of the diagnostic:
+///
+/// | Variant               | Diagnostic codes                                                                |
+/// |-----------------------|---------------------------------------------------------------------------------|
+/// | `Parse`               | `parser::*` other than those below, `fixed_rule::arg_*`, `fixed_rule::not_enough_args` |
+/// | `Plan`                | `eval::unbound_symb_in_head`, `eval::unbound_variable`, `eval::unsafe_negation`, `eval::unstratifiable`, `eval::rule_arity_mismatch`, `eval::invalid_time_travel`, `eval::estimate_mutation`, `eval::profile_mutation`, `eval::streaming_mutation`, `eval::read_only_mutation`, `eval::dangling_ctrl_flow`, `eval::replace_in_trigger`, `eval::unable_to_make_extractor`, `eval::bad_standing_query`, `eval::input_relation_not_temp` |
+/// | `ConstraintViolation` | `eval::assert_*`, `eval::coercion_*`, `eval::required_col_not_provided`, `eval::relation_arity_mismatch`, `eval::stored_rel_arity_mismatch`, `eval::replace_many_arity_mismatch`, `eval::input_relation_arity_mismatch`, `eval::rel_name_conflict`, `eval::stored_relation_conflict`, `eval::graph_conflict`, `eval::replace_rel_with_indices`, `eval::update_missing_row`, `eval::unique_violation`, `eval::unique_in_temp_relation`, `eval::foreign_key_violation`, `eval::constraint_conflict`, `eval::constraint_bad_target`, `eval::constraint_temp_relation`, `eval::relation_with_constraints`, `eval::alter_column_conflict`, `eval::alter_key_column`, `eval::alter_indexed_column`, `eval::alter_constrained_column`, `eval::alter_unique_column`, `eval::alter_missing_default`, `eval::alter_ttl_column`, `eval::bad_ttl_column`, `eval::no_ttl_column`, `eval::backup_schema_mismatch`, `tx::insufficient_access_level`, `tx::read_only_database`, `tx::index_already_exists`, `tx::import_into_index`, `tx::bare_import_with_indices`, `import::*` |
+/// | `NotFound`            | `eval::stored_relation_not_found`, `eval::rule_not_found`, `eval::named_field_not_found`, `eval::required_col_not_found`, `eval::graph_not_found`, `eval::graph_column_not_found`, `eval::constraint_not_found`, `eval::constraint_column_not_found`, `eval::alter_column_not_found`, `eval::ttl_column_not_found`, `eval::csv_column_not_found`, `eval::savepoint_not_found`, `query::relation_not_found`, `tx::idx_not_found`, `tx::col_in_idx_not_found`, `parser::fixed_rule_not_found` |
+/// | `Killed`              | `eval::killed`                                                                  |
+/// | `Timeout`             | `eval::timeout`                                                                 |
+/// | `StorageConflict`     | `sqlite::busy`, `sqlite::locked`, `rocksdb::kBusy::*`, `rocksdb::kTryAgain::*`, `rocksdb::kTimedOut::*`, `storage::transient`, `storage::write_conflict`, `tx::relation_locked` |
+/// | `QuotaExceeded`       | `sqlite::full`, `rocksdb::kIOError::kNoSpace`                                   |
+/// | `Corruption`          | `sqlite::corrupt`, `sqlite::notadb`, `rocksdb::kCorruption::*`, `deser::*`      |
+/// | `StorageIo`           | other `sqlite::*` and `rocksdb::*`, `db::init`, `tx::lookup_retries_exhausted`  |
+/// | `Eval`                | other `eval::*`, `algo::*`, `fixed_rule::*`, `tx::*`                            |
+/// | `Other`               | errors without a code, or with codes not listed                                 |
+pub enum CozoError {
+    /// The script cannot be parsed, or its options are invalid
+    Parse(Report),
+    /// The script parsed, but cannot be turned into an executable plan
+    Plan(Report),
+    /// An error was raised while evaluating the query
+    Eval(Report),
+    /// The data violates a constraint of the database, e.g. types, assertions or access levels
+    ConstraintViolation(Report),
+    /// The storage refused the transaction because of concurrent access
+    StorageConflict(Report),
+    /// The storage failed to read or write
+    StorageIo(Report),
+    /// The stored data cannot be decoded
+    Corruption(Report),
+    /// The query was killed before completion
+    Killed(Report),
+    /// The query ran out of the time allowed by its `:timeout`
+    Timeout(Report),
+    /// A relation, index, rule or column referred to does not exist
+    NotFound(Report),
+    /// The storage is out of space
+    QuotaExceeded(Report),
+    /// Any other error
+    Other(Report),
+}
+
+impl CozoError {
+    /// Classifies the error. If the report already wraps a `CozoError`, it is returned as is.
+    pub fn from_report(report: Report) -> Self {
+        let report = match report.downcast::<CozoError>() {
+            Ok(err) => return err,
+            Err(report) => report,
+        };
+        let code = report.code().map(|c| c.to_string());
+        let code = match &code {
+            None => return CozoError::Other(report),
+            Some(code) => code.as_str(),
+        };
+        let (namespace, name) = code.split_once("::").unwrap_or((code, ""));
+        match (namespace, name) {
+            (namespace, name) if is_transient_code(namespace, name) => {
+                CozoError::StorageConflict(report)
+            }
+            ("parser", "fixed_rule_not_found") => CozoError::NotFound(report),
+            ("parser", _) => CozoError::Parse(report),
+            ("fixed_rule", "arg_not_found" | "arg_wrong" | "not_enough_args") => {
+                CozoError::Parse(report)
+            }
+            (
+                "eval",
+                "unbound_symb_in_head"
+                | "unbound_variable"
+                | "unsafe_negation"
+                | "unstratifiable"
+                | "rule_arity_mismatch"
+                | "invalid_time_travel"
+                | "estimate_mutation"
+                | "profile_mutation"
+                | "streaming_mutation"
+                | "read_only_mutation"
+                | "dangling_ctrl_flow"
+                | "replace_in_trigger"
+                | "unable_to_make_extractor"
+                | "bad_standing_query"
+                | "input_relation_not_temp",
+            ) => CozoError::Plan(report),
+            (
+                "eval",
+                "required_col_not_provided"
+                | "relation_arity_mismatch"
+                | "stored_rel_arity_mismatch"
+                | "replace_many_arity_mismatch"
+                | "input_relation_arity_mismatch"
+                | "rel_name_conflict"
+                | "stored_relation_conflict"
+                | "graph_conflict"
+                | "replace_rel_with_indices"
+                | "update_missing_row"
+                | "unique_violation"
+                | "unique_in_temp_relation"
+                | "foreign_key_violation"
+                | "constraint_conflict"
+                | "constraint_bad_target"
+                | "constraint_temp_relation"
+                | "relation_with_constraints"
+                | "alter_column_conflict"
+                | "alter_key_column"
+                | "alter_indexed_column"
+                | "alter_constrained_column"
+                | "alter_unique_column"
+                | "alter_missing_default"
+                | "alter_ttl_column"
+                | "bad_ttl_column"
+                | "no_ttl_column"
+                | "backup_schema_mismatch",
+            )
+            | (
+                "tx",
+                "insufficient_access_level"
+                | "read_only_database"
+                | "index_already_exists"
+                | "import_into_index"
+                | "bare_import_with_indices",
+            )
+            | ("import", _) => CozoError::ConstraintViolation(report),
+            ("eval", n) if n.starts_with("assert_") || n.starts_with("coercion_") => {
+                CozoError::ConstraintViolation(report)
+            }
+            (
+                "eval",
+                "stored_relation_not_found"
+                | "rule_not_found"
+                | "named_field_not_found"
+                | "required_col_not_found"
+                | "graph_not_found"
+                | "graph_column_not_found"
+                | "constraint_not_found"
+                | "constraint_column_not_found"
+                | "alter_column_not_found"
+                | "ttl_column_not_found"
+                | "csv_column_not_found"
+                | "savepoint_not_found",
+            )
+            | ("query", "relation_not_found")
+            | ("tx", "idx_not_found" | "col_in_idx_not_found") => CozoError::NotFound(report),
+            ("tx", "lookup_retries_exhausted") => CozoError::StorageIo(report),
+            ("eval", "killed") => CozoError::Killed(report),
+            ("eval", "timeout") => CozoError::Timeout(report),
+            ("eval" | "algo" | "fixed_rule" | "tx", _) => CozoError::Eval(report),
+            ("sqlite", "full") => CozoError::QuotaExceeded(report),
+            ("sqlite", "corrupt" | "notadb") => CozoError::Corruption(report),
+            ("rocksdb", n) => {
+                if n == "kIOError::kNoSpace" {
+                    CozoError::QuotaExceeded(report)
+                } else if n.starts_with("kCorruption::") {
+                    CozoError::Corruption(report)
+                } else {
+                    CozoError::StorageIo(report)
+                }
+            }
+            ("deser", _) | ("format", "malformed") => CozoError::Corruption(report),
+            ("sqlite", _) | ("db", "init") => CozoError::StorageIo(report),
+            _ => CozoError::Other(report),
+        }
+    }
+    /// Whether the error may go away if the operation is retried, i.e. it is a storage conflict.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, CozoError::StorageConflict(_))
+    }
+    /// Whether the report is a transient error, without classifying it fully.
+    pub(crate) fn is_transient_report(report: &Report) -> bool {
+        if let Some(err) = report.downcast_ref::<CozoError>() {
+            return err.is_transient();
+        }
+        match report.code() {
+            None => false,
+            Some(code) => {
+                let code = code.to_string();
+                let (namespace, name) = code.split_once("::").unwrap_or((&code, ""));
+                is_transient_code(namespace, name)
+            }
+        }
+    }
+    /// Classifies the error and wraps it back into a report, from which it can be downcast.
+    pub(crate) fn wrap(report: Report) -> Report {
+        Report::new(CozoError::from_report(report))
+    }
+    /// The class of the error as a string in snake case, e.g. `"constraint_violation"`.
+    /// This is the `kind` field of errors formatted as JSON.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CozoError::Parse(_) => "parse",
+            CozoError::Plan(_) => "plan",
+            CozoError::Eval(_) => "eval",
+            CozoError::ConstraintViolation(_) => "constraint_violation",
+            CozoError::StorageConflict(_) => "storage_conflict",
+            CozoError::StorageIo(_) => "storage_io",
+            CozoError::Corruption(_) => "corruption",
+            CozoError::Killed(_) => "killed",
+            CozoError::Timeout(_) => "timeout",
+            CozoError::NotFound(_) => "not_found",
+            CozoError::QuotaExceeded(_) => "quota_exceeded",
+            CozoError::Other(_) => "other",
+        }
+    }
+    /// The underlying diagnostic
+    pub fn report(&self) -> &Report {
+        match self {
+            CozoError::Parse(r)
+            | CozoError::Plan(r)
+            | CozoError::Eval(r)
+            | CozoError::ConstraintViolation(r)
+            | CozoError::StorageConflict(r)
+            | CozoError::StorageIo(r)
+            | CozoError::Corruption(r)
+            | CozoError::Killed(r)
+            | CozoError::Timeout(r)
+            | CozoError::NotFound(r)
+            | CozoError::QuotaExceeded(r)
+            | CozoError::Other(r) => r,
+        }
+    }
+    /// Unwraps the underlying diagnostic
+    pub fn into_report(self) -> Report {
+        match self {
+            CozoError::Parse(r)
+            | CozoError::Plan(r)
+            | CozoError::Eval(r)
+            | CozoError::ConstraintViolation(r)
+            | CozoError::StorageConflict(r)
+            | CozoError::StorageIo(r)
+            | CozoError::Corruption(r)
+            | CozoError::Killed(r)
+            | CozoError::Timeout(r)
+            | CozoError::NotFound(r)
+            | CozoError::QuotaExceeded(r)
+            | CozoError::Other(r) => r,
+        }
+    }
+}
+
+fn is_transient_code(namespace: &str, name: &str) -> bool {
+    match namespace {
+        "sqlite" => matches!(name, "busy" | "locked"),
+        "rocksdb" => {
+            name.starts_with("kBusy::")
+                || name.starts_with("kTryAgain::")
+                || name.starts_with("kTimedOut::")
+        }
+        "storage" => matches!(name, "transient" | "write_conflict"),
+        "tx" => name == "relation_locked",
+        _ => false,
+    }
+}
+
+impl Debug for CozoError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}({:?})", self.kind(), self.report())
+    }
+}
+
+impl Display for CozoError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self.report(), f)
+    }
+}
+
+impl StdError for CozoError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        self.report().source()
+    }
+}
+
+impl Diagnostic for CozoError {
+    fn code<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        self.report().code()
+    }
+    fn severity(&self) -> Option<Severity> {
+        self.report().severity()
+    }
+    fn help<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        self.report().help()
+    }
+    fn url<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        self.report().url()
+    }
+    fn source_code(&self) -> Option<&dyn SourceCode> {
+        self.report().source_code()
+    }
+    fn labels(&self) -> Option<Box<dyn Iterator<Item = LabeledSpan> + '_>> {
+        self.report().labels()
+    }
+    fn related<'a>(&'a self) -> Option<Box<dyn Iterator<Item = &'a dyn Diagnostic> + 'a>> {
+        self.report().related()
+    }
+    fn diagnostic_source(&self) -> Option<&dyn Diagnostic> {
+        self.report().diagnostic_source()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use crate::{CozoError, DbInstance};
+
+    #[test]
+    fn test_error_kinds() {
+        let mut dbs = vec![DbInstance::new("mem", "", "").unwrap()];
+        #[cfg(feature = "storage-sqlite")]
+        {
+            let path = std::env::temp_dir().join(format!("cozo-test-{}.db", rand::random::<u64>()));
+            dbs.push(DbInstance::new("sqlite", path, "").unwrap());
+        }
+        let endless = "r[x] := x = 0
+                   r[y] := r[x], y = x + 1
+                   ?[x] := r[x]";
+        for db in dbs {
+            db.run_script(
+                ":create stations {name: String => capacity: Int}",
+                Default::default(),
+            )
+            .unwrap();
+            let kind = |script: &str| {
+                let err = db.run_script(script, Default::default()).unwrap_err();
+                err.downcast_ref::<CozoError>().unwrap().kind()
+            };
+            assert_eq!(kind("?[x] <- [[1]"), "parse");
+            assert_eq!(kind("?[x, y] := x = 1"), "plan");
+            assert_eq!(kind("?[x] := x = 1 / 'a'"), "eval");
+            assert_eq!(
+                kind("?[name, capacity] <- [['a', 'many']] :put stations {name => capacity}"),
+                "constraint_violation"
+            );
+            assert_eq!(kind("?[x] := *nowhere[x]"), "not_found");
+            assert_eq!(kind(&format!("{endless}\n:timeout 0.05")), "timeout");
+
+            let killed = std::thread::scope(|s| {
+                let running = s.spawn(|| kind(endless));
+                loop {
+                    let listed = db.run_script("::running", Default::default()).unwrap();
+                    if let Some(row) = listed.rows.first() {
+                        let id = row[0].clone();
+                        db.run_script("::kill $id", BTreeMap::from([("id".to_string(), id)]))
+                            .unwrap();
+                        break;
+                    }
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                running.join().unwrap()
+            });
+            assert_eq!(killed, "killed");
+
+            let res: serde_json::Value =
+                serde_json::from_str(&db.run_script_str("?[x] := *nowhere[x]", "")).unwrap();
+            assert_eq!(res["ok"], json!(false));
+            assert_eq!(res["kind"], json!("not_found"));
+        }
+
+        #[cfg(feature = "storage-sqlite")]
+        {
+            use crate::storage::sqlite::SqliteError;
+
+            let kind_of_code = |code: isize| {
+                let err = SqliteError(sqlite::Error {
+                    code: Some(code),
+                    message: None,
+                });
+                CozoError::from_report(err.into()).kind()
+            };
+            assert_eq!(kind_of_code(5), "storage_conflict");
+            assert_eq!(kind_of_code(11), "corruption");
+            assert_eq!(kind_of_code(13), "quota_exceeded");
+            assert_eq!(kind_of_code(14), "storage_io");
+        }
+    }
+}
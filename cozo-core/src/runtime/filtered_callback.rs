@@ -0,0 +1,270 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::{iter, thread};
+
+use crossbeam::channel::{bounded, unbounded, Receiver};
+use itertools::Itertools;
+use log::error;
+use miette::{bail, Result};
+use smartstring::SmartString;
+
+use crate::data::expr::{eval_bytecode_pred, Bytecode, UserFunction};
+use crate::data::symb::Symbol;
+use crate::data::tuple::Tuple;
+use crate::parse::{parse_expression, SourceSpan};
+use crate::runtime::callback::CallbackOp;
+use crate::runtime::relation::RelationHandle;
+use crate::{DataValue, Db, NamedRows, Storage};
+
+/// A predicate on the rows of a relation, deciding which of its changes are sent to a callback
+struct CallbackFilter {
+    bytecode: Vec<Bytecode>,
+    span: SourceSpan,
+    n_keys: usize,
+}
+
+impl CallbackFilter {
+    /// The filter evaluating `src` with the columns of the relation bound by their names
+    fn new(
+        handle: &RelationHandle,
+        src: &str,
+        user_functions: &BTreeMap<String, Arc<UserFunction>>,
+    ) -> Result<Self> {
+        let binding_map: BTreeMap<_, _> = handle
+            .metadata
+            .keys
+            .iter()
+            .chain(handle.metadata.non_keys.iter())
+            .enumerate()
+            .map(|(i, col)| (Symbol::new(col.name.clone(), Default::default()), i))
+            .collect();
+        let mut expr = parse_expression(src, &Default::default())
+            .map_err(|err| err.with_source_code(src.to_string()))?;
+        if let Some(unknown) = expr
+            .bindings()
+            .into_iter()
+            .find(|b| !binding_map.contains_key(b))
+        {
+            bail!(
+                "unknown column '{}' in callback filter for relation '{}'",
+                unknown,
+                handle.name
+            )
+        }
+        expr.fill_binding_indices(&binding_map)?;
+        expr.resolve_user_fns(user_functions)?;
+        Ok(Self {
+            bytecode: expr.compile(),
+            span: expr.span(),
+            n_keys: handle.metadata.keys.len(),
+        })
+    }
+    /// Rows for which the filter fails to evaluate are not matched
+    fn matches(&self, row: &[DataValue], stack: &mut Vec<DataValue>) -> bool {
+        match eval_bytecode_pred(&self.bytecode, row, stack, self.span) {
+            Ok(matched) => matched,
+            Err(err) => {
+                error!("callback filter failed for row {:?}: {}", row, err);
+                false
+            }
+        }
+    }
+    /// Keeps the matching rows of a change, or nothing if none of them match.
+    /// The rows removed only have their keys, so they are kept if their old rows match.
+    fn apply(
+        &self,
+        op: CallbackOp,
+        mut new: NamedRows,
+        mut old: NamedRows,
+    ) -> Option<(CallbackOp, NamedRows, NamedRows)> {
+        let mut stack = vec![];
+        old.rows.retain(|row| self.matches(row, &mut stack));
+        match op {
+            CallbackOp::Put => new.rows.retain(|row| self.matches(row, &mut stack)),
+            CallbackOp::Rm => {
+                // keys are only mutable to clippy for the regex a `DataValue` may hold
+                #[allow(clippy::mutable_key_type)]
+                let removed: BTreeSet<_> = old.rows.iter().map(|row| &row[..self.n_keys]).collect();
+                new.rows.retain(|row| removed.contains(&row[..]))
+            }
+        }
+        if new.rows.is_empty() && old.rows.is_empty() {
+            None
+        } else {
+            Some((op, new, old))
+        }
+    }
+}
+
+impl<'s, S: Storage<'s>> Db<S> {
+    /// Register callback channel to receive the changes to the rows of a relation for which
+    /// the expression `filter` holds, with the columns bound by their names,
+    /// e.g. `status == 'open' && priority > 2`.
+    /// The filter is evaluated in a thread of its own, after the changes are committed.
+    ///
+    /// For puts, the rows written that match are sent with the matching rows they overwrote;
+    /// for removals, the keys of the rows removed that matched are sent with these rows.
+    /// Applying the events in order to the matching rows therefore keeps them up to date.
+    /// With `deliver_snapshot`, the first event is a put of all rows matching at the time
+    /// of registration, read while writes to the relation wait.
+    ///
+    /// The returned ID can be used to unregister the callback channel.
+    pub fn register_callback_filtered(
+        &'s self,
+        relation: &str,
+        filter: &str,
+        deliver_snapshot: bool,
+        capacity: Option<usize>,
+    ) -> Result<(u32, Receiver<(CallbackOp, NamedRows, NamedRows)>)> {
+        let name = SmartString::from(relation);
+        let lock = self.obtain_relation_locks(iter::once(&name)).pop().unwrap();
+        // the snapshot must see exactly the writes not sent to the callback
+        let _guard = lock.write().unwrap();
+        let tx = self.transact()?;
+        let handle = tx.get_relation(relation, false)?;
+        let filter = CallbackFilter::new(&handle, filter, &tx.user_functions.read().unwrap())?;
+        let snapshot = if deliver_snapshot {
+            let mut stack = vec![];
+            let mut rows = vec![];
+            for row in handle.scan_all(&tx) {
+                let row: Tuple = row?;
+                if filter.matches(&row, &mut stack) {
+                    rows.push(row);
+                }
+            }
+            let headers = handle
+                .metadata
+                .keys
+                .iter()
+                .chain(handle.metadata.non_keys.iter())
+                .map(|col| col.name.to_string())
+                .collect_vec();
+            let old = NamedRows::new(headers.clone(), vec![]);
+            Some((CallbackOp::Put, NamedRows::new(headers, rows), old))
+        } else {
+            None
+        };
+        let (id, changes) = self.register_callback(relation, None);
+
+        let (sender, receiver) = if let Some(c) = capacity {
+            bounded(c)
+        } else {
+            unbounded()
+        };
+        thread::spawn(move || {
+            if let Some(snapshot) = snapshot {
+                if sender.send(snapshot).is_err() {
+                    return;
+                }
+            }
+            // dropping the receiver of the changes unregisters the callback
+            for (op, new, old) in changes {
+                if let Some(event) = filter.apply(op, new, old) {
+                    if sender.send(event).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Ok((id, receiver))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use itertools::Itertools;
+
+    use crate::new_cozo_mem;
+    use crate::runtime::callback::CallbackOp;
+
+    #[test]
+    fn test_filtered_callback() {
+        let db = new_cozo_mem().unwrap();
+        db.run_script(
+            r"
+        {:create tickets {id => status, pri}}
+        {?[id, status, pri] <- [[1, 'open', 1], [2, 'closed', 2], [3, 'open', 3]]
+            :put tickets {id => status, pri}}
+        ",
+            Default::default(),
+        )
+        .unwrap();
+        let (_id, receiver) = db
+            .register_callback_filtered("tickets", "status == 'open' && pri > 1", true, None)
+            .unwrap();
+        let next = || receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // the matching rows as they are kept by a subscriber
+        let mut state = BTreeMap::new();
+        let (op, snapshot, _) = next();
+        assert_eq!(op, CallbackOp::Put);
+        assert_eq!(snapshot.headers, vec!["id", "status", "pri"]);
+        for row in snapshot.rows {
+            state.insert(row[0].clone(), row);
+        }
+        assert_eq!(state.len(), 1);
+
+        let writes = [
+        // not matching before or after: nothing is sent
+        "?[id, status, pri] <- [[4, 'closed', 5], [1, 'open', 0]] :put tickets {id => status, pri}",
+        "?[id] <- [[2]] :rm tickets {id}",
+        // starts matching
+        "?[id, status, pri] <- [[5, 'open', 2], [6, 'closed', 1]] :put tickets {id => status, pri}",
+        // stops matching
+        "?[id, status, pri] <- [[3, 'closed', 3]] :put tickets {id => status, pri}",
+        "?[id, status, pri] <- [[7, 'open', 9]] :put tickets {id => status, pri}",
+        "?[id] <- [[7], [4]] :rm tickets {id}",
+    ];
+        for write in writes {
+            db.run_script(write, Default::default()).unwrap();
+        }
+        let mut events = vec![];
+        for _ in 0..4 {
+            let (op, new, old) = next();
+            for row in &old.rows {
+                state.remove(&row[0]);
+            }
+            if op == CallbackOp::Put {
+                for row in new.rows {
+                    state.insert(row[0].clone(), row);
+                }
+            }
+            events.push(op);
+        }
+        assert_eq!(
+            events,
+            vec![
+                CallbackOp::Put,
+                CallbackOp::Put,
+                CallbackOp::Put,
+                CallbackOp::Rm
+            ]
+        );
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // the snapshot and the changes after it add up to the rows matching now
+        let res = db
+            .run_script(
+                "?[id, status, pri] := *tickets{id, status, pri}, status == 'open', pri > 1",
+                Default::default(),
+            )
+            .unwrap();
+        assert_eq!(state.into_values().collect_vec(), res.rows);
+        assert_eq!(res.rows.len(), 1);
+
+        assert!(db
+            .register_callback_filtered("tickets", "owner == 'me'", false, None)
+            .is_err());
+    }
+}
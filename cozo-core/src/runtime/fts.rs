@@ -0,0 +1,1297 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Full-text indices over the string columns of stored relations, made by `::fts create`
+//! and searched by `~rel:idx{..}` atoms, the rows found being scored by BM25.
+//!
+//! The postings of an index are kept in a hidden relation `rel:idx`, keyed by the term
+//! followed by the keys of the row it occurs in, with the positions of the term in the text
+//! of the row and the number of terms of that text. The row with a null term and null keys
+//! holds the number of rows indexed, as positions, and the total number of their terms.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use itertools::Itertools;
+use miette::{bail, Diagnostic, Result};
+use smartstring::{LazyCompact, SmartString};
+use thiserror::Error;
+
+use crate::data::relation::{ColType, ColumnDef, NullableColType, StoredRelationMetadata};
+use crate::data::symb::Symbol;
+use crate::data::tuple::{Tuple, TupleT};
+use crate::data::value::DataValue;
+use crate::parse::SourceSpan;
+use crate::runtime::relation::{InputRelationHandle, RelationHandle};
+use crate::runtime::transact::SessionTx;
+
+/// The saturation of the scores of terms repeated in a row
+const BM25_K1: f64 = 1.2;
+/// How much the scores of terms are lowered in rows longer than the average
+const BM25_B: f64 = 0.75;
+
+/// The positions skipped between the texts of the columns of a row, so that phrases
+/// do not match across columns
+const FIELD_GAP: i64 = 1;
+
+/// How texts are split into terms
+#[derive(Copy, Clone, Debug, Eq, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) enum FtsTokenizer {
+    /// the words of the text, runs of letters and digits of any script
+    Simple,
+    /// the runs of the given number of characters within the words, words shorter than that
+    /// being terms by themselves
+    NGram(usize),
+}
+
+/// Transformations applied to the words of texts, in the order given
+#[derive(Copy, Clone, Debug, Eq, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) enum FtsFilter {
+    Lowercase,
+    /// the Porter stemmer, for words of lowercase English letters
+    StemmerEn,
+}
+
+/// The parameters of a full-text index, kept with the relation it indexes
+#[derive(Clone, Debug, Eq, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) struct FtsIndexManifest {
+    pub(crate) tokenizer: FtsTokenizer,
+    pub(crate) filters: Vec<FtsFilter>,
+    /// the names of the indexed columns
+    pub(crate) fields: Vec<SmartString<LazyCompact>>,
+    /// the positions of the indexed columns in the rows, keys followed by values
+    pub(crate) field_positions: Vec<usize>,
+}
+
+/// Options of `::fts create`, given as `{fields: [body], tokenizer: 'simple', filters: ['lowercase']}`
+#[derive(Debug, Clone)]
+pub(crate) struct FtsOptions {
+    pub(crate) tokenizer: FtsTokenizer,
+    pub(crate) filters: Vec<FtsFilter>,
+    pub(crate) fields: Vec<Symbol>,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Bad option '{0}' for full-text index")]
+#[diagnostic(code(parser::bad_fts_option))]
+#[diagnostic(help("{1}"))]
+struct BadFtsOption(String, String, #[label] SourceSpan);
+
+impl FtsOptions {
+    /// The options with the given values, the fields being required. Texts are split into
+    /// words and lowercased by default.
+    pub(crate) fn new(
+        given: Vec<(String, DataValue, SourceSpan)>,
+        fields: Vec<Symbol>,
+        span: SourceSpan,
+    ) -> Result<Self> {
+        let mut ngram = None;
+        let mut is_ngram = false;
+        let mut ret = Self {
+            tokenizer: FtsTokenizer::Simple,
+            filters: vec![FtsFilter::Lowercase],
+            fields,
+        };
+        for (name, value, span) in given {
+            let bad = |help: &str| BadFtsOption(name.clone(), help.to_string(), span);
+            match &name as &str {
+                "tokenizer" => {
+                    is_ngram = match value.get_str() {
+                        Some("simple") => false,
+                        Some("ngram") => true,
+                        _ => bail!(bad("'tokenizer' must be 'simple' or 'ngram'")),
+                    }
+                }
+                "ngram" => match value.get_int() {
+                    Some(n) if n >= 1 => ngram = Some(n as usize),
+                    _ => bail!(bad("'ngram' must be a positive integer")),
+                },
+                "filters" => {
+                    let filters = match &value {
+                        DataValue::List(l) => l,
+                        _ => bail!(bad("'filters' must be a list")),
+                    };
+                    ret.filters = filters
+                        .iter()
+                        .map(|f| match f.get_str() {
+                            Some("lowercase") => Ok(FtsFilter::Lowercase),
+                            Some("stemmer_en") => Ok(FtsFilter::StemmerEn),
+                            _ => Err(bad("The filters are 'lowercase' and 'stemmer_en'")),
+                        })
+                        .try_collect()?;
+                }
+                _ => bail!(bad(
+                    "The options are 'tokenizer', 'ngram', 'filters' and 'fields'"
+                )),
+            }
+        }
+        if is_ngram {
+            ret.tokenizer = FtsTokenizer::NGram(ngram.unwrap_or(3));
+        } else if ngram.is_some() {
+            bail!(BadFtsOption(
+                "ngram".to_string(),
+                "'ngram' is only given with the 'ngram' tokenizer".to_string(),
+                span,
+            ))
+        }
+        if ret.fields.is_empty() {
+            bail!(BadFtsOption(
+                "fields".to_string(),
+                "The indexed columns must be given as 'fields: [..]'".to_string(),
+                span,
+            ))
+        }
+        Ok(ret)
+    }
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Full-text index {1} for relation {0} not found")]
+#[diagnostic(code(query::fts_index_not_found))]
+pub(crate) struct FtsIndexNotFound(
+    pub(crate) String,
+    pub(crate) String,
+    #[label] pub(crate) SourceSpan,
+);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error(
+    "Column '{column}' of the row with keys {keys:?} has {value:?}, \
+which cannot be indexed by full-text index '{relation}:{index}'"
+)]
+#[diagnostic(code(eval::fts_not_a_string))]
+#[diagnostic(help("The indexed columns hold strings, or null for rows not indexed"))]
+struct FtsNotAString {
+    relation: String,
+    index: String,
+    column: String,
+    keys: Tuple,
+    value: DataValue,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Bad full-text query {1:?}: {0}")]
+#[diagnostic(code(eval::fts_bad_query))]
+#[diagnostic(help(
+    "Queries are made of words, \"quoted phrases\" and prefixes ending with '*', \
+combined by AND, OR, NOT and parentheses, words next to each other being all required"
+))]
+pub(crate) struct FtsBadQuery(String, String, #[label] SourceSpan);
+
+impl FtsIndexManifest {
+    /// The words of a text, with the filters applied
+    fn words(&self, text: &str, stem: bool) -> Vec<String> {
+        text.split(|c: char| !c.is_alphanumeric())
+            .filter(|w| !w.is_empty())
+            .map(|w| {
+                let mut w = w.to_string();
+                for filter in &self.filters {
+                    match filter {
+                        FtsFilter::Lowercase => w = w.to_lowercase(),
+                        FtsFilter::StemmerEn => {
+                            if stem {
+                                w = porter_stem(&w)
+                            }
+                        }
+                    }
+                }
+                w
+            })
+            .collect()
+    }
+    /// The terms of a word
+    fn word_terms(&self, word: String, terms: &mut Vec<String>) {
+        match self.tokenizer {
+            FtsTokenizer::Simple => terms.push(word),
+            FtsTokenizer::NGram(n) => {
+                let chars = word.chars().collect_vec();
+                if chars.len() <= n {
+                    terms.push(word)
+                } else {
+                    terms.extend(chars.windows(n).map(|w| w.iter().collect()))
+                }
+            }
+        }
+    }
+    /// The terms of a text, in order
+    fn terms(&self, text: &str) -> Vec<String> {
+        let mut terms = vec![];
+        for word in self.words(text, true) {
+            self.word_terms(word, &mut terms);
+        }
+        terms
+    }
+}
+
+/// The terms of the indexed columns of a row, given with keys and values, with their positions
+fn row_terms(
+    handle: &RelationHandle,
+    idx_name: &str,
+    manifest: &FtsIndexManifest,
+    row: &[DataValue],
+) -> Result<BTreeMap<String, Vec<i64>>> {
+    let mut ret: BTreeMap<String, Vec<i64>> = BTreeMap::new();
+    let mut pos = 0;
+    for (field_pos, field) in manifest.field_positions.iter().zip(&manifest.fields) {
+        let text = match &row[*field_pos] {
+            DataValue::Null => continue,
+            DataValue::Str(s) => s,
+            value => bail!(FtsNotAString {
+                relation: handle.name.to_string(),
+                index: idx_name.to_string(),
+                column: field.to_string(),
+                keys: row[..handle.metadata.keys.len()].to_vec(),
+                value: value.clone(),
+            }),
+        };
+        for term in manifest.terms(text) {
+            ret.entry(term).or_default().push(pos);
+            pos += 1;
+        }
+        pos += FIELD_GAP;
+    }
+    Ok(ret)
+}
+
+/// A parsed full-text query
+#[derive(Debug, Clone)]
+pub(crate) enum FtsQuery {
+    Term(String),
+    Prefix(String),
+    /// terms next to each other, in order
+    Phrase(Vec<String>),
+    And(Vec<FtsQuery>),
+    Or(Vec<FtsQuery>),
+    /// the rows not matching, only found among the operands of `And`, with other operands
+    Not(Box<FtsQuery>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Lexeme {
+    Open,
+    Close,
+    And,
+    Or,
+    Not,
+    Quoted(String),
+    Word(String),
+}
+
+fn lex(text: &str) -> std::result::Result<Vec<Lexeme>, String> {
+    let mut ret = vec![];
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '(' => ret.push(Lexeme::Open),
+            ')' => ret.push(Lexeme::Close),
+            '"' => {
+                let mut phrase = String::new();
+                loop {
+                    match chars.next() {
+                        None => return Err("unterminated quote".to_string()),
+                        Some('"') => break,
+                        Some(c) => phrase.push(c),
+                    }
+                }
+                ret.push(Lexeme::Quoted(phrase))
+            }
+            c => {
+                let mut word = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || matches!(c, '(' | ')' | '"') {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                ret.push(match &word as &str {
+                    "AND" => Lexeme::And,
+                    "OR" => Lexeme::Or,
+                    "NOT" => Lexeme::Not,
+                    _ => Lexeme::Word(word),
+                })
+            }
+        }
+    }
+    Ok(ret)
+}
+
+struct QueryParser<'m> {
+    manifest: &'m FtsIndexManifest,
+    lexemes: Vec<Lexeme>,
+    pos: usize,
+}
+
+/// Parsing returns `None` for parts of queries without terms, such as punctuation
+impl QueryParser<'_> {
+    fn peek(&self) -> Option<&Lexeme> {
+        self.lexemes.get(self.pos)
+    }
+    fn parse_or(&mut self) -> std::result::Result<Option<FtsQuery>, String> {
+        let mut items = vec![];
+        items.extend(self.parse_and()?);
+        while self.peek() == Some(&Lexeme::Or) {
+            self.pos += 1;
+            items.extend(self.parse_and()?);
+        }
+        Ok(match items.len() {
+            0 => None,
+            1 => items.pop(),
+            _ => Some(FtsQuery::Or(items)),
+        })
+    }
+    fn parse_and(&mut self) -> std::result::Result<Option<FtsQuery>, String> {
+        let mut items = vec![];
+        items.extend(self.parse_unary()?);
+        loop {
+            match self.peek() {
+                Some(Lexeme::And) => {
+                    self.pos += 1;
+                    items.extend(self.parse_unary()?);
+                }
+                Some(Lexeme::Open | Lexeme::Not | Lexeme::Quoted(_) | Lexeme::Word(_)) => {
+                    items.extend(self.parse_unary()?);
+                }
+                _ => break,
+            }
+        }
+        if !items.is_empty() && items.iter().all(|q| matches!(q, FtsQuery::Not(_))) {
+            return Err("NOT must be combined with terms to match".to_string());
+        }
+        Ok(match items.len() {
+            0 => None,
+            1 => items.pop(),
+            _ => Some(FtsQuery::And(items)),
+        })
+    }
+    fn parse_unary(&mut self) -> std::result::Result<Option<FtsQuery>, String> {
+        if self.peek() == Some(&Lexeme::Not) {
+            self.pos += 1;
+            return Ok(self.parse_unary()?.map(|q| FtsQuery::Not(Box::new(q))));
+        }
+        let lexeme = match self.lexemes.get(self.pos) {
+            None => return Err("the query ends where a term is expected".to_string()),
+            Some(l) => l.clone(),
+        };
+        self.pos += 1;
+        match lexeme {
+            Lexeme::Open => {
+                let inner = self.parse_or()?;
+                if self.peek() != Some(&Lexeme::Close) {
+                    return Err("unbalanced parentheses".to_string());
+                }
+                self.pos += 1;
+                Ok(inner)
+            }
+            Lexeme::Quoted(phrase) => Ok(self.phrase(self.manifest.terms(&phrase))),
+            Lexeme::Word(word) => match word.strip_suffix('*') {
+                None => Ok(self.phrase(self.manifest.terms(&word))),
+                Some(prefix) => {
+                    // prefixes are not stemmed, as their ends are not those of words
+                    let mut words = self.manifest.words(prefix, false);
+                    match words.len() {
+                        0 => Ok(None),
+                        1 => {
+                            let word = words.pop().unwrap();
+                            Ok(match self.manifest.tokenizer {
+                                FtsTokenizer::NGram(n) if word.chars().count() >= n => {
+                                    let mut terms = vec![];
+                                    self.manifest.word_terms(word, &mut terms);
+                                    self.phrase(terms)
+                                }
+                                _ => Some(FtsQuery::Prefix(word)),
+                            })
+                        }
+                        _ => Err(format!("the prefix '{word}' is not a single word")),
+                    }
+                }
+            },
+            l => Err(format!("unexpected {l:?}")),
+        }
+    }
+    fn phrase(&self, mut terms: Vec<String>) -> Option<FtsQuery> {
+        match terms.len() {
+            0 => None,
+            1 => terms.pop().map(FtsQuery::Term),
+            _ => Some(FtsQuery::Phrase(terms)),
+        }
+    }
+}
+
+/// The query of a search, `None` if it has no terms
+pub(crate) fn fts_query(
+    v: DataValue,
+    manifest: &FtsIndexManifest,
+    span: SourceSpan,
+) -> Result<Option<FtsQuery>> {
+    let text = match v.get_str() {
+        Some(text) => text,
+        None => bail!(FtsBadQuery(
+            "a string is required".to_string(),
+            format!("{v:?}"),
+            span
+        )),
+    };
+    let bad = |msg: String| FtsBadQuery(msg, text.to_string(), span);
+    let mut parser = QueryParser {
+        manifest,
+        lexemes: lex(text).map_err(bad)?,
+        pos: 0,
+    };
+    let query = parser.parse_or().map_err(bad)?;
+    if let Some(l) = parser.peek() {
+        bail!(bad(format!("unexpected {l:?}")))
+    }
+    Ok(query)
+}
+
+/// The number of rows indexed, and the total number of their terms
+#[derive(Debug, Default, Copy, Clone)]
+struct FtsStats {
+    rows: i64,
+    terms: i64,
+}
+
+impl FtsStats {
+    fn score(&self, tf: usize, row_terms: i64, df: usize) -> f64 {
+        let n = self.rows as f64;
+        let df = df as f64;
+        let idf = (1. + (n - df + 0.5) / (df + 0.5)).ln();
+        let avg = self.terms as f64 / n;
+        let tf = tf as f64;
+        idf * tf * (BM25_K1 + 1.) / (tf + BM25_K1 * (1. - BM25_B + BM25_B * row_terms as f64 / avg))
+    }
+}
+
+/// The rows a term occurs in, by keys, with its positions and the number of terms of the rows
+type Postings = BTreeMap<Tuple, (Vec<i64>, i64)>;
+
+fn stats_key(idx_rel: &RelationHandle) -> Tuple {
+    vec![DataValue::Null; idx_rel.metadata.keys.len()]
+}
+
+impl<'a> SessionTx<'a> {
+    pub(crate) fn create_fts_index(
+        &mut self,
+        rel_name: &Symbol,
+        idx_name: &Symbol,
+        options: FtsOptions,
+    ) -> Result<()> {
+        let mut rel_handle = self.get_relation(rel_name, true)?;
+        if rel_handle.is_temp {
+            #[derive(Debug, Error, Diagnostic)]
+            #[error("Temp relation {0} cannot have full-text indices")]
+            #[diagnostic(code(eval::fts_in_temp_relation))]
+            struct FtsInTempRelation(String);
+
+            bail!(FtsInTempRelation(rel_handle.name.to_string()))
+        }
+        if rel_handle.has_index(&idx_name.name) {
+            #[derive(Debug, Error, Diagnostic)]
+            #[error("index {0} for relation {1} already exists")]
+            #[diagnostic(code(tx::index_already_exists))]
+            struct IndexAlreadyExists(String, String);
+
+            bail!(IndexAlreadyExists(
+                idx_name.name.to_string(),
+                rel_name.name.to_string()
+            ));
+        }
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("column {0} in full-text index {1} for relation {2} cannot hold strings")]
+        #[diagnostic(code(tx::bad_fts_column))]
+        #[diagnostic(help("Indexed columns must exist and be of type 'String' or 'Any'"))]
+        struct BadFtsColumn(String, String, String, #[label] SourceSpan);
+
+        let mut field_positions = vec![];
+        for field in options.fields.iter() {
+            let found = rel_handle
+                .metadata
+                .keys
+                .iter()
+                .chain(rel_handle.metadata.non_keys.iter())
+                .find_position(|col| col.name == field.name);
+            match found {
+                Some((pos, col))
+                    if matches!(col.typing.coltype, ColType::Any | ColType::String) =>
+                {
+                    field_positions.push(pos)
+                }
+                _ => bail!(BadFtsColumn(
+                    field.name.to_string(),
+                    idx_name.name.to_string(),
+                    rel_name.name.to_string(),
+                    field.span
+                )),
+            }
+        }
+        let manifest = FtsIndexManifest {
+            tokenizer: options.tokenizer,
+            filters: options.filters,
+            fields: options.fields.iter().map(|f| f.name.clone()).collect(),
+            field_positions,
+        };
+
+        let col = |name: String, coltype: ColType, nullable: bool| ColumnDef {
+            name: name.into(),
+            typing: NullableColType { coltype, nullable },
+            default_gen: None,
+            auto_update: None,
+            unique: false,
+        };
+        let mut keys = vec![col("term".to_string(), ColType::Any, true)];
+        for key in rel_handle.metadata.keys.iter() {
+            keys.push(col(format!("k_{}", key.name), ColType::Any, true));
+        }
+        let non_keys = vec![
+            col("positions".to_string(), ColType::Any, false),
+            col("len".to_string(), ColType::Int, false),
+        ];
+        let key_bindings = keys
+            .iter()
+            .map(|col| Symbol::new(col.name.clone(), Default::default()))
+            .collect_vec();
+        let dep_bindings = non_keys
+            .iter()
+            .map(|col| Symbol::new(col.name.clone(), Default::default()))
+            .collect_vec();
+        let idx_handle = self.create_relation(InputRelationHandle {
+            name: Symbol::new(
+                format!("{}:{}", rel_name.name, idx_name.name),
+                Default::default(),
+            ),
+            metadata: StoredRelationMetadata { keys, non_keys },
+            key_bindings,
+            dep_bindings,
+            span: Default::default(),
+            params: Default::default(),
+        })?;
+
+        for row in rel_handle.scan_all(self).collect_vec() {
+            let row = row?;
+            self.fts_put_row(&rel_handle, &idx_name.name, &idx_handle, &manifest, &row)?;
+        }
+
+        rel_handle
+            .fts_indices
+            .insert(idx_name.name.clone(), (idx_handle, manifest));
+        self.put_relation_meta(&rel_handle)?;
+        self.bump_schema_generation()?;
+        Ok(())
+    }
+
+    /// Removes the index, returning the range of its postings, to be cleared at the end
+    /// of the transaction.
+    pub(crate) fn remove_fts_index(
+        &mut self,
+        rel_name: &Symbol,
+        idx_name: &Symbol,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut rel = self.get_relation(rel_name, true)?;
+        if rel.fts_indices.remove(&idx_name.name).is_none() {
+            bail!(FtsIndexNotFound(
+                rel_name.name.to_string(),
+                idx_name.name.to_string(),
+                idx_name.span
+            ))
+        }
+        let cleared = self.destroy_relation(&format!("{}:{}", rel_name.name, idx_name.name))?;
+        self.put_relation_meta(&rel)?;
+        self.bump_schema_generation()?;
+        Ok(cleared)
+    }
+
+    /// Builds the postings of the index anew from the rows of the relation
+    pub(crate) fn rebuild_fts_index(&mut self, rel_name: &Symbol, idx_name: &Symbol) -> Result<()> {
+        let rel = self.get_relation(rel_name, true)?;
+        let (idx_rel, manifest) = rel.fts_indices.get(&idx_name.name).ok_or_else(|| {
+            FtsIndexNotFound(
+                rel_name.name.to_string(),
+                idx_name.name.to_string(),
+                idx_name.span,
+            )
+        })?;
+        let lower = Tuple::default().encode_as_key(idx_rel.id);
+        let upper = Tuple::default().encode_as_key(idx_rel.id.next());
+        for kv in self.store_tx.range_scan(&lower, &upper).collect_vec() {
+            let (k, _) = kv?;
+            self.store_tx.del(&k)?;
+        }
+        for row in rel.scan_all(self).collect_vec() {
+            let row = row?;
+            self.fts_put_row(&rel, &idx_name.name, idx_rel, manifest, &row)?;
+        }
+        Ok(())
+    }
+
+    /// Adds the terms of a row, given with keys and values, to the full-text indices
+    pub(crate) fn put_into_fts_indices(
+        &mut self,
+        handle: &RelationHandle,
+        row: &[DataValue],
+    ) -> Result<()> {
+        for (idx_name, (idx_rel, manifest)) in handle.fts_indices.iter() {
+            self.fts_put_row(handle, idx_name, idx_rel, manifest, row)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the terms of a row, given with keys and values, from the full-text indices
+    pub(crate) fn delete_from_fts_indices(
+        &mut self,
+        handle: &RelationHandle,
+        row: &[DataValue],
+    ) -> Result<()> {
+        for (idx_name, (idx_rel, manifest)) in handle.fts_indices.iter() {
+            self.fts_del_row(handle, idx_name, idx_rel, manifest, row)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the terms of a row from its old image, if there is one, to its new image,
+    /// leaving the indices whose columns did not change alone
+    pub(crate) fn reindex_fts_row(
+        &mut self,
+        handle: &RelationHandle,
+        old: Option<&Tuple>,
+        new: &Tuple,
+    ) -> Result<()> {
+        for (idx_name, (idx_rel, manifest)) in handle.fts_indices.iter() {
+            if let Some(old) = old {
+                if manifest.field_positions.iter().all(|p| old[*p] == new[*p]) {
+                    continue;
+                }
+                self.fts_del_row(handle, idx_name, idx_rel, manifest, old)?;
+            }
+            self.fts_put_row(handle, idx_name, idx_rel, manifest, new)?;
+        }
+        Ok(())
+    }
+
+    /// The `k` rows of the relation scoring highest for the query, with their scores,
+    /// highest first, rows with the same score being ordered by their keys
+    pub(crate) fn fts_search(
+        &self,
+        base: &RelationHandle,
+        idx_rel: &RelationHandle,
+        query: &FtsQuery,
+        k: usize,
+    ) -> Result<Vec<(f64, Tuple)>> {
+        let stats = self.fts_stats(idx_rel)?;
+        if stats.rows == 0 {
+            return Ok(vec![]);
+        }
+        let mut found = self
+            .fts_eval(idx_rel, &stats, query)?
+            .into_iter()
+            .collect_vec();
+        found.sort_by(|(a_keys, a), (b_keys, b)| b.total_cmp(a).then_with(|| a_keys.cmp(b_keys)));
+
+        let expiry = base.expiry(self);
+        let mut ret = vec![];
+        for (keys, score) in found {
+            if ret.len() >= k {
+                break;
+            }
+            if let Some(row) = base.get(self, &keys)? {
+                if expiry.is_live(&row) {
+                    ret.push((score, row));
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    /// The scores of the rows matching the query, by keys
+    // clippy sees tuples as mutable keys for the regex a `DataValue` may hold, ordered by value
+    #[allow(clippy::mutable_key_type)]
+    fn fts_eval(
+        &self,
+        idx_rel: &RelationHandle,
+        stats: &FtsStats,
+        query: &FtsQuery,
+    ) -> Result<BTreeMap<Tuple, f64>> {
+        Ok(match query {
+            FtsQuery::Term(term) => {
+                let postings = self.fts_postings(idx_rel, term)?;
+                let df = postings.len();
+                postings
+                    .into_iter()
+                    .map(|(keys, (positions, len))| (keys, stats.score(positions.len(), len, df)))
+                    .collect()
+            }
+            FtsQuery::Prefix(prefix) => {
+                let mut upper = prefix.clone();
+                upper.push(char::MAX);
+                let terms: BTreeSet<String> = idx_rel
+                    .scan_bounded_prefix(
+                        self,
+                        &vec![],
+                        &[DataValue::from(prefix as &str)],
+                        &[DataValue::from(upper)],
+                    )
+                    .map_ok(|row| row[0].get_str().unwrap_or_default().to_string())
+                    .filter_ok(|term| term.starts_with(prefix as &str))
+                    .try_collect()?;
+                let mut ret: BTreeMap<Tuple, f64> = BTreeMap::new();
+                for term in terms {
+                    for (keys, score) in self.fts_eval(idx_rel, stats, &FtsQuery::Term(term))? {
+                        *ret.entry(keys).or_default() += score;
+                    }
+                }
+                ret
+            }
+            FtsQuery::Phrase(terms) => {
+                let postings: Vec<Postings> = terms
+                    .iter()
+                    .map(|term| self.fts_postings(idx_rel, term))
+                    .try_collect()?;
+                let mut matched = vec![];
+                'rows: for (keys, (first, len)) in &postings[0] {
+                    let mut rest = vec![];
+                    for p in &postings[1..] {
+                        match p.get(keys) {
+                            None => continue 'rows,
+                            Some((positions, _)) => rest.push(positions),
+                        }
+                    }
+                    let tf = first
+                        .iter()
+                        .filter(|start| {
+                            rest.iter().enumerate().all(|(i, positions)| {
+                                positions.binary_search(&(**start + i as i64 + 1)).is_ok()
+                            })
+                        })
+                        .count();
+                    if tf > 0 {
+                        matched.push((keys.clone(), tf, *len));
+                    }
+                }
+                let df = matched.len();
+                matched
+                    .into_iter()
+                    .map(|(keys, tf, len)| (keys, stats.score(tf, len, df)))
+                    .collect()
+            }
+            FtsQuery::And(items) => {
+                let mut ret: Option<BTreeMap<Tuple, f64>> = None;
+                let mut excluded = BTreeSet::new();
+                for item in items {
+                    if let FtsQuery::Not(inner) = item {
+                        excluded.extend(self.fts_eval(idx_rel, stats, inner)?.into_keys());
+                        continue;
+                    }
+                    let found = self.fts_eval(idx_rel, stats, item)?;
+                    ret = Some(match ret {
+                        None => found,
+                        Some(prev) => prev
+                            .into_iter()
+                            .filter_map(|(keys, score)| {
+                                found.get(&keys).map(|other| (keys, score + other))
+                            })
+                            .collect(),
+                    });
+                }
+                let mut ret = ret.unwrap_or_default();
+                ret.retain(|keys, _| !excluded.contains(keys));
+                ret
+            }
+            FtsQuery::Or(items) => {
+                let mut ret: BTreeMap<Tuple, f64> = BTreeMap::new();
+                for item in items {
+                    for (keys, score) in self.fts_eval(idx_rel, stats, item)? {
+                        *ret.entry(keys).or_default() += score;
+                    }
+                }
+                ret
+            }
+            // the parser only puts negations among the operands of conjunctions
+            FtsQuery::Not(_) => unreachable!(),
+        })
+    }
+
+    fn fts_postings(&self, idx_rel: &RelationHandle, term: &str) -> Result<Postings> {
+        let n_keys = idx_rel.metadata.keys.len();
+        idx_rel
+            .scan_prefix(self, &vec![DataValue::from(term)])
+            .map_ok(|row| {
+                let positions: Vec<i64> = match &row[n_keys] {
+                    DataValue::List(l) => l.iter().filter_map(|p| p.get_int()).collect(),
+                    _ => vec![],
+                };
+                let len = row[n_keys + 1].get_int().unwrap_or_default();
+                (row[1..n_keys].to_vec(), (positions, len))
+            })
+            .try_collect()
+    }
+
+    fn fts_stats(&self, idx_rel: &RelationHandle) -> Result<FtsStats> {
+        let n_keys = idx_rel.metadata.keys.len();
+        Ok(match idx_rel.get(self, &stats_key(idx_rel))? {
+            None => FtsStats::default(),
+            Some(row) => FtsStats {
+                rows: row[n_keys].get_int().unwrap_or_default(),
+                terms: row[n_keys + 1].get_int().unwrap_or_default(),
+            },
+        })
+    }
+
+    fn fts_add_stats(&mut self, idx_rel: &RelationHandle, rows: i64, terms: i64) -> Result<()> {
+        let mut stats = self.fts_stats(idx_rel)?;
+        stats.rows += rows;
+        stats.terms += terms;
+        let mut row = stats_key(idx_rel);
+        row.push(DataValue::from(stats.rows));
+        row.push(DataValue::from(stats.terms));
+        self.fts_put(idx_rel, &row)
+    }
+
+    fn fts_put(&mut self, idx_rel: &RelationHandle, row: &Tuple) -> Result<()> {
+        let key = idx_rel.encode_key_for_store(row, Default::default())?;
+        let val = idx_rel.encode_val_for_store(row, Default::default())?;
+        self.store_tx.put(&key, &val)
+    }
+
+    fn fts_put_row(
+        &mut self,
+        handle: &RelationHandle,
+        idx_name: &str,
+        idx_rel: &RelationHandle,
+        manifest: &FtsIndexManifest,
+        row: &[DataValue],
+    ) -> Result<()> {
+        let terms = row_terms(handle, idx_name, manifest, row)?;
+        if terms.is_empty() {
+            return Ok(());
+        }
+        let keys = &row[..handle.metadata.keys.len()];
+        let len: i64 = terms.values().map(|p| p.len() as i64).sum();
+        for (term, positions) in terms {
+            let mut posting = vec![DataValue::from(term)];
+            posting.extend_from_slice(keys);
+            posting.push(DataValue::List(
+                positions.into_iter().map(DataValue::from).collect(),
+            ));
+            posting.push(DataValue::from(len));
+            self.fts_put(idx_rel, &posting)?;
+        }
+        self.fts_add_stats(idx_rel, 1, len)
+    }
+
+    fn fts_del_row(
+        &mut self,
+        handle: &RelationHandle,
+        idx_name: &str,
+        idx_rel: &RelationHandle,
+        manifest: &FtsIndexManifest,
+        row: &[DataValue],
+    ) -> Result<()> {
+        let terms = row_terms(handle, idx_name, manifest, row)?;
+        if terms.is_empty() {
+            return Ok(());
+        }
+        let keys = &row[..handle.metadata.keys.len()];
+        let len: i64 = terms.values().map(|p| p.len() as i64).sum();
+        for term in terms.into_keys() {
+            let mut posting = vec![DataValue::from(term)];
+            posting.extend_from_slice(keys);
+            let key = idx_rel.encode_key_for_store(&posting, Default::default())?;
+            self.store_tx.del(&key)?;
+        }
+        self.fts_add_stats(idx_rel, -1, -len)
+    }
+}
+
+/// The stem of an English word by the Porter algorithm, words that are not made of
+/// lowercase ASCII letters being returned as they are
+fn porter_stem(word: &str) -> String {
+    if word.len() <= 2 || !word.bytes().all(|b| b.is_ascii_lowercase()) {
+        return word.to_string();
+    }
+    let mut w = word.as_bytes().to_vec();
+    stem_step_1(&mut w);
+    stem_step_2(&mut w);
+    stem_step_3(&mut w);
+    stem_step_4(&mut w);
+    stem_step_5(&mut w);
+    String::from_utf8(w).unwrap()
+}
+
+fn is_consonant(w: &[u8], i: usize) -> bool {
+    match w[i] {
+        b'a' | b'e' | b'i' | b'o' | b'u' => false,
+        b'y' => i == 0 || !is_consonant(w, i - 1),
+        _ => true,
+    }
+}
+
+/// The number of vowel-consonant sequences of the stem
+fn measure(stem: &[u8]) -> usize {
+    let mut n = 0;
+    let mut prev_vowel = false;
+    for i in 0..stem.len() {
+        let consonant = is_consonant(stem, i);
+        if consonant && prev_vowel {
+            n += 1;
+        }
+        prev_vowel = !consonant;
+    }
+    n
+}
+
+fn has_vowel(stem: &[u8]) -> bool {
+    (0..stem.len()).any(|i| !is_consonant(stem, i))
+}
+
+fn ends_double_consonant(w: &[u8]) -> bool {
+    let n = w.len();
+    n >= 2 && w[n - 1] == w[n - 2] && is_consonant(w, n - 1)
+}
+
+/// Whether the word ends consonant-vowel-consonant, the last not being w, x or y
+fn ends_cvc(w: &[u8]) -> bool {
+    let n = w.len();
+    n >= 3
+        && is_consonant(w, n - 1)
+        && !is_consonant(w, n - 2)
+        && is_consonant(w, n - 3)
+        && !matches!(w[n - 1], b'w' | b'x' | b'y')
+}
+
+/// Replaces the first of the suffixes the word ends with by its replacement, if the
+/// measure of the stem is larger than `min_measure`. Returns whether a suffix matched.
+fn replace_suffix(w: &mut Vec<u8>, rules: &[(&str, &str)], min_measure: usize) -> bool {
+    for (suffix, replacement) in rules {
+        if w.ends_with(suffix.as_bytes()) {
+            let stem_len = w.len() - suffix.len();
+            if measure(&w[..stem_len]) > min_measure {
+                w.truncate(stem_len);
+                w.extend_from_slice(replacement.as_bytes());
+            }
+            return true;
+        }
+    }
+    false
+}
+
+fn stem_step_1(w: &mut Vec<u8>) {
+    if w.ends_with(b"sses") || w.ends_with(b"ies") {
+        w.truncate(w.len() - 2);
+    } else if w.ends_with(b"s") && !w.ends_with(b"ss") {
+        w.pop();
+    }
+
+    if w.ends_with(b"eed") {
+        if measure(&w[..w.len() - 3]) > 0 {
+            w.pop();
+        }
+    } else {
+        let suffix_len = if w.ends_with(b"ed") {
+            2
+        } else if w.ends_with(b"ing") {
+            3
+        } else {
+            0
+        };
+        if suffix_len > 0 && has_vowel(&w[..w.len() - suffix_len]) {
+            w.truncate(w.len() - suffix_len);
+            if w.ends_with(b"at") || w.ends_with(b"bl") || w.ends_with(b"iz") {
+                w.push(b'e');
+            } else if ends_double_consonant(w) && !matches!(w[w.len() - 1], b'l' | b's' | b'z') {
+                w.pop();
+            } else if measure(w) == 1 && ends_cvc(w) {
+                w.push(b'e');
+            }
+        }
+    }
+
+    if w.ends_with(b"y") && has_vowel(&w[..w.len() - 1]) {
+        let n = w.len();
+        w[n - 1] = b'i';
+    }
+}
+
+fn stem_step_2(w: &mut Vec<u8>) {
+    replace_suffix(
+        w,
+        &[
+            ("ational", "ate"),
+            ("tional", "tion"),
+            ("enci", "ence"),
+            ("anci", "ance"),
+            ("izer", "ize"),
+            ("abli", "able"),
+            ("alli", "al"),
+            ("entli", "ent"),
+            ("eli", "e"),
+            ("ousli", "ous"),
+            ("ization", "ize"),
+            ("ation", "ate"),
+            ("ator", "ate"),
+            ("alism", "al"),
+            ("iveness", "ive"),
+            ("fulness", "ful"),
+            ("ousness", "ous"),
+            ("aliti", "al"),
+            ("iviti", "ive"),
+            ("biliti", "ble"),
+        ],
+        0,
+    );
+}
+
+fn stem_step_3(w: &mut Vec<u8>) {
+    replace_suffix(
+        w,
+        &[
+            ("icate", "ic"),
+            ("ative", ""),
+            ("alize", "al"),
+            ("iciti", "ic"),
+            ("ical", "ic"),
+            ("ful", ""),
+            ("ness", ""),
+        ],
+        0,
+    );
+}
+
+fn stem_step_4(w: &mut Vec<u8>) {
+    if w.ends_with(b"ion") {
+        let stem = &w[..w.len() - 3];
+        if matches!(stem.last(), Some(b's' | b't')) && measure(stem) > 1 {
+            w.truncate(w.len() - 3);
+        }
+        return;
+    }
+    replace_suffix(
+        w,
+        &[
+            ("ement", ""),
+            ("ment", ""),
+            ("ent", ""),
+            ("ance", ""),
+            ("ence", ""),
+            ("able", ""),
+            ("ible", ""),
+            ("ant", ""),
+            ("ism", ""),
+            ("ate", ""),
+            ("iti", ""),
+            ("ous", ""),
+            ("ive", ""),
+            ("ize", ""),
+            ("al", ""),
+            ("er", ""),
+            ("ic", ""),
+            ("ou", ""),
+        ],
+        1,
+    );
+}
+
+fn stem_step_5(w: &mut Vec<u8>) {
+    if w.ends_with(b"e") {
+        let stem = &w[..w.len() - 1];
+        let m = measure(stem);
+        if m > 1 || (m == 1 && !ends_cvc(stem)) {
+            w.pop();
+        }
+    }
+    if measure(w) > 1 && ends_double_consonant(w) && w.ends_with(b"l") {
+        w.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use itertools::Itertools;
+    use serde_json::json;
+
+    use crate::data::value::DataValue;
+    use crate::new_cozo_mem;
+
+    #[test]
+    fn test_fts_index() {
+        let db = new_cozo_mem().unwrap();
+        db.run_script(
+            ":create docs {id: Int => title: String, body: String?}",
+            Default::default(),
+        )
+        .unwrap();
+        db.run_script(
+            r#"
+        ?[id, title, body] <- [
+            [1, "The Quick Fox", "A fox is running through the forest"],
+            [2, "Lazy dogs", "The dog sleeps all day, it runs nowhere"],
+            [3, "Straße Ünïcödé", "Words: naïve café"],
+            [4, "Fox and dog", "the quick brown fox jumps over the lazy dog"],
+            [5, "Forests", null],
+        ]
+        :put docs {id => title, body}
+        "#,
+            Default::default(),
+        )
+        .unwrap();
+        db.run_script(
+            "::fts create docs:text {fields: [title, body], filters: ['lowercase', 'stemmer_en']}",
+            Default::default(),
+        )
+        .unwrap();
+
+        let search = |q: &str| -> Vec<i64> {
+            db.run_script(
+                "?[id, s] := ~docs:text{id | query: $q, k: 10, bind_score: s} :order -s, id",
+                BTreeMap::from([("q".to_string(), DataValue::from(q))]),
+            )
+            .unwrap()
+            .rows
+            .into_iter()
+            .map(|row| row[0].get_int().unwrap())
+            .collect()
+        };
+
+        // shorter rows score higher for the same number of occurrences
+        assert_eq!(search("fox"), vec![1, 4]);
+        // words are lowercased and stemmed, in the rows and in queries
+        assert_eq!(search("RUNNING"), vec![1, 2]);
+        assert_eq!(search("ÜNÏCÖDÉ"), vec![3]);
+        assert_eq!(search("straße café"), vec![3]);
+        assert_eq!(search("fox dog"), vec![4]);
+        assert_eq!(search("fox AND dog"), vec![4]);
+        assert_eq!(
+            search("fox OR dog").into_iter().sorted().collect_vec(),
+            vec![1, 2, 4]
+        );
+        assert_eq!(search("fox NOT dog"), vec![1]);
+        assert_eq!(
+            search("(fox OR sleeps) AND lazy")
+                .into_iter()
+                .sorted()
+                .collect_vec(),
+            vec![2, 4]
+        );
+        assert_eq!(search("\"quick fox\""), vec![1]);
+        // phrases do not match across columns
+        assert_eq!(search("\"fox a\""), Vec::<i64>::new());
+        assert_eq!(
+            search("for*").into_iter().sorted().collect_vec(),
+            vec![1, 5]
+        );
+        assert_eq!(search("nothing"), Vec::<i64>::new());
+        assert_eq!(search("..."), Vec::<i64>::new());
+
+        for (script, code) in [
+            (
+                "?[id] := ~docs:text{id | query: '\"quick', k: 3}",
+                "eval::fts_bad_query",
+            ),
+            (
+                "?[id] := ~docs:text{id | query: 'NOT fox', k: 3}",
+                "eval::fts_bad_query",
+            ),
+            (
+                "?[id] := ~docs:text{id | query: 1, k: 3}",
+                "eval::fts_bad_query",
+            ),
+            (
+                "?[id] := ~docs:text{id | query: 'fox', k: 3, ef: 10}",
+                "parser::bad_search_parameter",
+            ),
+            (
+                "::fts create docs:other {fields: [id]}",
+                "tx::bad_fts_column",
+            ),
+            (
+                "::fts create docs:other {fields: [title], tokenizer: 'words'}",
+                "parser::bad_fts_option",
+            ),
+        ] {
+            let err = db.run_script(script, Default::default()).unwrap_err();
+            assert_eq!(err.code().unwrap().to_string(), code, "{script}");
+        }
+
+        // n-grams match within words
+        db.run_script(
+            "::fts create docs:grams {fields: [title], tokenizer: 'ngram', ngram: 3}",
+            Default::default(),
+        )
+        .unwrap();
+        let grams = |q: &str| -> Vec<i64> {
+            db.run_script(
+                "?[id] := ~docs:grams{id | query: $q, k: 10}",
+                BTreeMap::from([("q".to_string(), DataValue::from(q))]),
+            )
+            .unwrap()
+            .rows
+            .into_iter()
+            .map(|row| row[0].get_int().unwrap())
+            .sorted()
+            .collect()
+        };
+        assert_eq!(grams("ick"), vec![1]);
+        assert_eq!(grams("orest"), vec![5]);
+        assert_eq!(grams("fo*"), vec![1, 4, 5]);
+
+        // the indices follow the rows put into and removed from the relation
+        db.run_script(
+            "?[id, title, body] <- [[2, 'Lazy cats', 'They sleep']] :put docs {id => title, body}",
+            Default::default(),
+        )
+        .unwrap();
+        assert_eq!(search("runs"), vec![1]);
+        assert_eq!(search("cat"), vec![2]);
+        db.run_script("?[id] <- [[1]] :rm docs {id}", Default::default())
+            .unwrap();
+        assert_eq!(search("fox"), vec![4]);
+        assert_eq!(grams("ick"), Vec::<i64>::new());
+
+        // rows scoring the same are found in the order of their keys
+        db.run_script(
+        "?[id, title, body] <- [[11, 'zebra', null], [10, 'zebra', null]] :put docs {id => title, body}",
+        Default::default(),
+    )
+    .unwrap();
+        let res = db
+            .run_script(
+                "?[id] := ~docs:text{id | query: 'zebra', k: 1}",
+                Default::default(),
+            )
+            .unwrap();
+        assert_eq!(res.into_json()["rows"], json!([[10]]));
+
+        assert_eq!(
+            db.run_script("::indices docs", Default::default())
+                .unwrap()
+                .into_json()["rows"],
+            json!([
+                ["grams", ["title"], null],
+                ["text", ["title", "body"], null]
+            ])
+        );
+        db.run_script("::fts rebuild docs:text", Default::default())
+            .unwrap();
+        assert_eq!(search("fox"), vec![4]);
+        assert!(db.run_script("::remove docs", Default::default()).is_err());
+        db.run_script("::fts drop docs:text", Default::default())
+            .unwrap();
+        let err = db
+            .run_script(
+                "?[id] := ~docs:text{id | query: 'fox', k: 3}",
+                Default::default(),
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.code().unwrap().to_string(),
+            "query::search_index_not_found"
+        );
+    }
+}
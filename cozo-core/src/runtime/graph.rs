@@ -0,0 +1,351 @@
+/*
+ * Copyright 2022, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::fmt::{Display, Formatter};
+
+use miette::{bail, ensure, Diagnostic, Result};
+use rmp_serde::Serializer;
+use serde::Serialize;
+use smartstring::{LazyCompact, SmartString};
+use thiserror::Error;
+
+use crate::data::relation::ColType;
+use crate::data::tuple::TupleT;
+use crate::data::value::DataValue;
+use crate::parse::SourceSpan;
+use crate::runtime::relation::RelationId;
+use crate::runtime::transact::SessionTx;
+
+/// A named pair of edge and node relations, usable by fixed rules
+/// in place of positional relation arguments.
+#[derive(Debug, Clone, Eq, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) struct GraphDef {
+    pub(crate) name: SmartString<LazyCompact>,
+    pub(crate) edges: GraphRelation,
+    pub(crate) nodes: Option<GraphRelation>,
+    pub(crate) undirected: bool,
+}
+
+/// A stored relation together with the columns taken from it, in order.
+#[derive(Debug, Clone, Eq, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) struct GraphRelation {
+    pub(crate) relation: SmartString<LazyCompact>,
+    pub(crate) columns: Vec<SmartString<LazyCompact>>,
+}
+
+impl Display for GraphRelation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}[", self.relation)?;
+        for (i, col) in self.columns.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{col}")?;
+        }
+        write!(f, "]")
+    }
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Graph '{0}' not found")]
+#[diagnostic(code(eval::graph_not_found))]
+pub(crate) struct GraphNotFoundError(pub(crate) String, #[label] pub(crate) SourceSpan);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Graph '{0}' already exists")]
+#[diagnostic(code(eval::graph_conflict))]
+struct GraphConflictError(String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Column '{1}' not found in relation '{0}' used by graph")]
+#[diagnostic(code(eval::graph_column_not_found))]
+struct GraphColumnNotFound(String, String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Edge weight column '{1}' of relation '{0}' must be numeric, got {2}")]
+#[diagnostic(code(eval::graph_bad_weight_column))]
+struct GraphBadWeightColumn(String, String, String);
+
+fn graph_key(name: &str) -> Vec<u8> {
+    vec![
+        DataValue::Null,
+        DataValue::from("GRAPH"),
+        DataValue::from(name),
+    ]
+    .encode_as_key(RelationId::SYSTEM)
+}
+
+impl<'a> SessionTx<'a> {
+    pub(crate) fn create_graph(&mut self, def: GraphDef) -> Result<()> {
+        let key = graph_key(&def.name);
+        if self.store_tx.exists(&key, true)? {
+            bail!(GraphConflictError(def.name.to_string()))
+        }
+        ensure!(
+            def.edges.columns.len() >= 2,
+            "The edge relation of a graph requires at least the source and target columns"
+        );
+        self.validate_graph_relation(&def.edges, true)?;
+        if let Some(nodes) = &def.nodes {
+            ensure!(
+                !nodes.columns.is_empty(),
+                "The node relation of a graph requires at least the node ID column"
+            );
+            self.validate_graph_relation(nodes, false)?;
+        }
+
+        let mut val = vec![];
+        def.serialize(&mut Serializer::new(&mut val).with_struct_map())
+            .unwrap();
+        self.store_tx.put(&key, &val)?;
+        self.bump_schema_generation()
+    }
+    fn validate_graph_relation(&self, rel: &GraphRelation, is_edges: bool) -> Result<()> {
+        ensure!(
+            !rel.relation.starts_with('_'),
+            "Graphs cannot refer to the temp relation '{}'",
+            rel.relation
+        );
+        let handle = self.get_relation(&rel.relation, false)?;
+        for (i, col_name) in rel.columns.iter().enumerate() {
+            let col = handle
+                .metadata
+                .keys
+                .iter()
+                .chain(handle.metadata.non_keys.iter())
+                .find(|c| c.name == *col_name)
+                .ok_or_else(|| {
+                    GraphColumnNotFound(rel.relation.to_string(), col_name.to_string())
+                })?;
+            if is_edges && i == 2 {
+                match col.typing.coltype {
+                    ColType::Any | ColType::Int | ColType::Float => {}
+                    _ => bail!(GraphBadWeightColumn(
+                        rel.relation.to_string(),
+                        col_name.to_string(),
+                        col.typing.to_string()
+                    )),
+                }
+            }
+        }
+        Ok(())
+    }
+    pub(crate) fn get_graph(&self, name: &str, span: SourceSpan) -> Result<GraphDef> {
+        let found = self
+            .store_tx
+            .get(&graph_key(name), false)?
+            .ok_or_else(|| GraphNotFoundError(name.to_string(), span))?;
+        rmp_serde::from_slice(&found)
+            .map_err(|e| miette::miette!("Cannot deserialize graph definition '{}': {}", name, e))
+    }
+    pub(crate) fn remove_graph(&mut self, name: &str, span: SourceSpan) -> Result<()> {
+        let key = graph_key(name);
+        if !self.store_tx.exists(&key, true)? {
+            bail!(GraphNotFoundError(name.to_string(), span))
+        }
+        self.store_tx.del(&key)?;
+        self.bump_schema_generation()
+    }
+    pub(crate) fn list_graphs(&self) -> Result<Vec<GraphDef>> {
+        let lower =
+            vec![DataValue::Null, DataValue::from("GRAPH")].encode_as_key(RelationId::SYSTEM);
+        let upper = vec![DataValue::Null, DataValue::from("GRAPH"), DataValue::Bot]
+            .encode_as_key(RelationId::SYSTEM);
+        let mut ret = vec![];
+        for kv in self.store_tx.range_scan(&lower, &upper) {
+            let (_, v) = kv?;
+            let def: GraphDef = rmp_serde::from_slice(&v)
+                .map_err(|e| miette::miette!("Cannot deserialize graph definition: {}", e))?;
+            ret.push(def);
+        }
+        Ok(ret)
+    }
+    /// The relations of a graph that were dropped, or lost the columns the graph takes from them.
+    pub(crate) fn broken_graph_relations(&self, def: &GraphDef) -> Result<Vec<String>> {
+        let rels = [Some(&def.edges), def.nodes.as_ref()];
+        let mut ret = vec![];
+        for (i, rel) in rels.into_iter().flatten().enumerate() {
+            if !self.relation_exists(&rel.relation)?
+                || self.validate_graph_relation(rel, i == 0).is_err()
+            {
+                ret.push(rel.relation.to_string());
+            }
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::new_cozo_mem;
+
+    #[test]
+    fn test_named_graph() {
+        let db = new_cozo_mem().unwrap();
+        db.run_script(
+        r"
+        ?[src, dst, dist, lanes] <- [['a', 'b', 1.0, 2], ['b', 'c', 2.0, 1], ['a', 'c', 5.0, 1], ['c', 'd', 1.5, 3]]
+        :create roads {src, dst => dist, lanes}
+    ",
+        Default::default(),
+    )
+    .unwrap();
+        db.run_script(
+            r"
+        ?[id, x, y] <- [['a', 0, 0], ['b', 1, 0], ['c', 2, 1], ['d', 3, 1]]
+        :create intersections {id => x, y}
+    ",
+            Default::default(),
+        )
+        .unwrap();
+        db.run_script(
+            r"::graph create city {edges: roads[src, dst, dist], nodes: intersections[id, x, y]}",
+            Default::default(),
+        )
+        .unwrap();
+        db.run_script(
+            r"::graph create city_undirected {edges: roads[src, dst], undirected: true}",
+            Default::default(),
+        )
+        .unwrap();
+        assert!(db
+            .run_script(
+                r"::graph create bad {edges: roads[src, nothing]}",
+                Default::default(),
+            )
+            .is_err());
+        assert!(db
+        .run_script(
+            r"::graph create bad {edges: roads[src, dst, lanes, dist], nodes: intersections[id]}",
+            Default::default(),
+        )
+        .is_ok());
+        assert!(db
+            .run_script(
+                r"::graph create bad {edges: roads[src, dst]}",
+                Default::default()
+            )
+            .is_err());
+
+        let positional = db
+            .run_script(
+                r"
+            starting[] <- [['a']]
+            ?[s, t, c, p] <~ ShortestPathDijkstra(*roads[], starting[])
+        ",
+                Default::default(),
+            )
+            .unwrap();
+        let named = db
+            .run_script(
+                r"
+            starting[] <- [['a']]
+            ?[s, t, c, p] <~ ShortestPathDijkstra(starting[], graph: 'city')
+        ",
+                Default::default(),
+            )
+            .unwrap();
+        assert_eq!(positional.rows, named.rows);
+        assert!(!named.rows.is_empty());
+
+        let positional = db
+            .run_script(
+                r"?[n, r] <~ PageRank(*roads[], undirected: true)",
+                Default::default(),
+            )
+            .unwrap();
+        let named = db
+            .run_script(
+                r"?[n, r] <~ PageRank(graph: 'city_undirected')",
+                Default::default(),
+            )
+            .unwrap();
+        assert_eq!(positional.rows, named.rows);
+
+        let astar = db
+            .run_script(
+                r"
+            starting[] <- [['a']]
+            goal[] <- [['d']]
+            ?[s, g, c, p] <~ ShortestPathAStar(starting[], goal[], graph: 'city', heuristic: 0)
+        ",
+                Default::default(),
+            )
+            .unwrap();
+        assert_eq!(
+            astar.into_json()["rows"],
+            json!([["a", "d", 4.5, ["a", "b", "c", "d"]]])
+        );
+
+        assert!(db
+            .run_script(r"?[n, r] <~ PageRank(graph: 'nowhere')", Default::default())
+            .is_err());
+
+        let listed = db
+            .run_script("::graph list", Default::default())
+            .unwrap()
+            .into_json();
+        assert_eq!(
+            listed["rows"],
+            json!([
+                [
+                    "bad",
+                    "roads[src, dst, lanes, dist]",
+                    "intersections[id]",
+                    false
+                ],
+                [
+                    "city",
+                    "roads[src, dst, dist]",
+                    "intersections[id, x, y]",
+                    false
+                ],
+                ["city_undirected", "roads[src, dst]", null, true]
+            ])
+        );
+
+        db.run_script("::graph drop bad", Default::default())
+            .unwrap();
+        assert!(db
+            .run_script("::graph drop bad", Default::default())
+            .is_err());
+        let integrity = db
+            .run_script("::check_integrity", Default::default())
+            .unwrap()
+            .into_json();
+        assert_eq!(integrity["rows"], json!([]));
+        db.run_script("::remove intersections", Default::default())
+            .unwrap();
+        let integrity = db
+            .run_script("::check_integrity", Default::default())
+            .unwrap()
+            .into_json();
+        assert_eq!(
+            integrity["rows"],
+            json!([["intersections", "city", null, null, null, false]])
+        );
+        let listed = db
+            .run_script("::graph list", Default::default())
+            .unwrap()
+            .into_json();
+        assert_eq!(
+            listed["rows"],
+            json!([
+                [
+                    "city",
+                    "roads[src, dst, dist]",
+                    "intersections[id, x, y]",
+                    false
+                ],
+                ["city_undirected", "roads[src, dst]", null, true]
+            ])
+        );
+    }
+}
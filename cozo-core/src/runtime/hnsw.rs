@@ -0,0 +1,1083 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! HNSW indices over the vector columns of stored relations, made by `::hnsw create` and
+//! searched by `~rel:idx{..}` atoms.
+//!
+//! The graph of an index is kept in a hidden relation `rel:idx`, one row per edge, keyed by
+//! the layer, the node the edge is from and the node it is to. A node is a vector of a row,
+//! given as the keys of the row followed by the position of the column among the indexed ones.
+//! The row of the edge from a node to itself marks that the node is present in the layer,
+//! and at the bottom layer, holds the vector, so that searches need not read the relation.
+//! The row at layer -1 points at the node the searches start from, its distance being the
+//! top layer of the graph.
+
+use std::cmp::Reverse;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap};
+use std::fmt::{Display, Formatter};
+use std::hash::{Hash, Hasher};
+
+use itertools::Itertools;
+use miette::{bail, Diagnostic, Result};
+use ordered_float::OrderedFloat;
+use smartstring::{LazyCompact, SmartString};
+use thiserror::Error;
+
+use crate::data::relation::{ColType, ColumnDef, NullableColType, StoredRelationMetadata};
+use crate::data::symb::Symbol;
+use crate::data::tuple::{Tuple, TupleT};
+use crate::data::value::DataValue;
+use crate::fixed_rule::utilities::vector_topk::{dot, squared_l2};
+use crate::parse::SourceSpan;
+use crate::runtime::relation::{InputRelationHandle, RelationHandle};
+use crate::runtime::transact::SessionTx;
+
+/// Nodes are not put in layers above this one, however unlucky the draw
+const MAX_LAYER: usize = 16;
+
+/// The layer of the row pointing at the entry node of the graph
+const ENTRY_LAYER: i64 = -1;
+
+/// The number of candidates kept by searches not giving `ef`, or `k` if larger
+pub(crate) const DEFAULT_HNSW_EF: usize = 50;
+
+/// How the distances between vectors are measured
+#[derive(Copy, Clone, Debug, Eq, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) enum HnswDistance {
+    /// euclidean distance
+    L2,
+    /// one minus the cosine of the angle, one for zero vectors
+    Cosine,
+    /// the inner product negated, so that smaller distances are nearer
+    InnerProduct,
+}
+
+impl HnswDistance {
+    pub(crate) fn distance(self, a: &[f64], b: &[f64]) -> f64 {
+        match self {
+            HnswDistance::L2 => squared_l2(a, b).sqrt(),
+            HnswDistance::Cosine => {
+                let norms = (dot(a, a) * dot(b, b)).sqrt();
+                if norms == 0. {
+                    1.
+                } else {
+                    1. - dot(a, b) / norms
+                }
+            }
+            HnswDistance::InnerProduct => -dot(a, b),
+        }
+    }
+}
+
+impl Display for HnswDistance {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            HnswDistance::L2 => "l2",
+            HnswDistance::Cosine => "cosine",
+            HnswDistance::InnerProduct => "ip",
+        })
+    }
+}
+
+/// The parameters of an HNSW index, kept with the relation it indexes
+#[derive(Clone, Debug, Eq, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) struct HnswIndexManifest {
+    /// the number of components of the vectors
+    pub(crate) dim: usize,
+    /// the number of neighbours nodes are linked to when inserted, nodes having at most
+    /// twice as many at the bottom layer and as many above it
+    pub(crate) m: usize,
+    /// the number of candidates kept while looking for the neighbours of new nodes
+    pub(crate) ef_construction: usize,
+    pub(crate) distance: HnswDistance,
+    /// the names of the indexed columns
+    pub(crate) fields: Vec<SmartString<LazyCompact>>,
+    /// the positions of the indexed columns in the rows, keys followed by values
+    pub(crate) field_positions: Vec<usize>,
+}
+
+/// Options of `::hnsw create`, given as `{dim: 128, fields: [v], distance: 'cosine'}`
+#[derive(Debug, Clone)]
+pub(crate) struct HnswOptions {
+    pub(crate) dim: usize,
+    pub(crate) m: usize,
+    pub(crate) ef_construction: usize,
+    pub(crate) distance: HnswDistance,
+    pub(crate) fields: Vec<Symbol>,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Bad option '{0}' for HNSW index")]
+#[diagnostic(code(parser::bad_hnsw_option))]
+#[diagnostic(help("{1}"))]
+struct BadHnswOption(String, String, #[label] SourceSpan);
+
+impl HnswOptions {
+    /// The options with the given values, `dim` and the fields being required
+    pub(crate) fn new(
+        given: Vec<(String, DataValue, SourceSpan)>,
+        fields: Vec<Symbol>,
+        span: SourceSpan,
+    ) -> Result<Self> {
+        let mut dim = None;
+        let mut ret = Self {
+            dim: 0,
+            m: 16,
+            ef_construction: 200,
+            distance: HnswDistance::L2,
+            fields,
+        };
+        for (name, value, span) in given {
+            let bad = |help: &str| BadHnswOption(name.clone(), help.to_string(), span);
+            let positive = |min: i64| match value.get_int() {
+                Some(i) if i >= min => Ok(i as usize),
+                _ => Err(bad(&format!(
+                    "'{name}' must be an integer of at least {min}"
+                ))),
+            };
+            match &name as &str {
+                "dim" => dim = Some(positive(1)?),
+                "m" => ret.m = positive(2)?,
+                "ef_construction" => ret.ef_construction = positive(1)?,
+                "distance" => {
+                    ret.distance = match value.get_str() {
+                        Some("l2") => HnswDistance::L2,
+                        Some("cosine") => HnswDistance::Cosine,
+                        Some("ip") => HnswDistance::InnerProduct,
+                        _ => bail!(bad("'distance' must be 'l2', 'cosine' or 'ip'")),
+                    }
+                }
+                _ => bail!(bad(
+                    "The options are 'dim', 'm', 'ef_construction', 'distance' and 'fields'"
+                )),
+            }
+        }
+        ret.dim = dim.ok_or_else(|| {
+            BadHnswOption(
+                "dim".to_string(),
+                "The number of components of the vectors must be given as 'dim'".to_string(),
+                span,
+            )
+        })?;
+        if ret.fields.is_empty() {
+            bail!(BadHnswOption(
+                "fields".to_string(),
+                "The indexed columns must be given as 'fields: [..]'".to_string(),
+                span,
+            ))
+        }
+        Ok(ret)
+    }
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("HNSW index {1} for relation {0} not found")]
+#[diagnostic(code(query::hnsw_index_not_found))]
+pub(crate) struct HnswIndexNotFound(
+    pub(crate) String,
+    pub(crate) String,
+    #[label] pub(crate) SourceSpan,
+);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error(
+    "The vector in column '{column}' of the row with keys {keys:?} has {actual} dimensions, \
+but HNSW index '{relation}:{index}' requires {expected}"
+)]
+#[diagnostic(code(eval::hnsw_dim_mismatch))]
+struct HnswDimMismatch {
+    relation: String,
+    index: String,
+    column: String,
+    keys: Tuple,
+    actual: usize,
+    expected: usize,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error(
+    "Column '{column}' of the row with keys {keys:?} has {value:?}, \
+which cannot be indexed by HNSW index '{relation}:{index}'"
+)]
+#[diagnostic(code(eval::hnsw_not_a_vector))]
+#[diagnostic(help("The indexed columns hold lists of numbers, or null for rows not indexed"))]
+struct HnswNotAVector {
+    relation: String,
+    index: String,
+    column: String,
+    keys: Tuple,
+    value: DataValue,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("The query of the HNSW search must be a list of {1} numbers, but got {0:?}")]
+#[diagnostic(code(eval::hnsw_bad_query))]
+pub(crate) struct HnswBadQuery(DataValue, usize, #[label] SourceSpan);
+
+/// The components of `v`, if it is a list of numbers
+fn to_vector(v: &DataValue) -> Option<Vec<f64>> {
+    match v {
+        DataValue::List(l) => l.iter().map(|x| x.get_float()).collect(),
+        _ => None,
+    }
+}
+
+/// The vector searched for by a query, checked against the index
+pub(crate) fn hnsw_query_vector(
+    v: DataValue,
+    manifest: &HnswIndexManifest,
+    span: SourceSpan,
+) -> Result<Vec<f64>> {
+    match to_vector(&v) {
+        Some(q) if q.len() == manifest.dim => Ok(q),
+        _ => bail!(HnswBadQuery(v, manifest.dim, span)),
+    }
+}
+
+/// The nodes of a row, given with keys and values, with their vectors. Null columns
+/// have no nodes.
+fn row_nodes(
+    handle: &RelationHandle,
+    idx_name: &str,
+    manifest: &HnswIndexManifest,
+    row: &[DataValue],
+) -> Result<Vec<(Tuple, Vec<f64>)>> {
+    let keys = &row[..handle.metadata.keys.len()];
+    let mut ret = vec![];
+    for (i, (pos, field)) in manifest
+        .field_positions
+        .iter()
+        .zip(&manifest.fields)
+        .enumerate()
+    {
+        let value = &row[*pos];
+        if *value == DataValue::Null {
+            continue;
+        }
+        let v = to_vector(value).ok_or_else(|| HnswNotAVector {
+            relation: handle.name.to_string(),
+            index: idx_name.to_string(),
+            column: field.to_string(),
+            keys: keys.to_vec(),
+            value: value.clone(),
+        })?;
+        if v.len() != manifest.dim {
+            bail!(HnswDimMismatch {
+                relation: handle.name.to_string(),
+                index: idx_name.to_string(),
+                column: field.to_string(),
+                keys: keys.to_vec(),
+                actual: v.len(),
+                expected: manifest.dim,
+            })
+        }
+        ret.push((node_of(keys, i), v));
+    }
+    Ok(ret)
+}
+
+fn node_of(keys: &[DataValue], field: usize) -> Tuple {
+    let mut node = keys.to_vec();
+    node.push(DataValue::from(field as i64));
+    node
+}
+
+/// The layer a node is put in, and all the layers below it: the draws are made from
+/// the hash of the node, so that the graph does not depend on when it is built.
+fn node_level(node: &Tuple, m: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    node.hash(&mut hasher);
+    // uniform in (0, 1]
+    let u = ((hasher.finish() >> 11) + 1) as f64 / (1u64 << 53) as f64;
+    ((-u.ln() / (m as f64).ln()).floor() as usize).min(MAX_LAYER)
+}
+
+fn max_degree(manifest: &HnswIndexManifest, layer: usize) -> usize {
+    if layer == 0 {
+        2 * manifest.m
+    } else {
+        manifest.m
+    }
+}
+
+fn edge_key(layer: i64, fr: &[DataValue], to: &[DataValue]) -> Tuple {
+    let mut key = Vec::with_capacity(1 + fr.len() + to.len());
+    key.push(DataValue::from(layer));
+    key.extend_from_slice(fr);
+    key.extend_from_slice(to);
+    key
+}
+
+/// The vectors of the nodes read during an operation on the graph, `None` for nodes
+/// that have been removed, to which edges may still point.
+///
+/// Clippy takes tuples for mutable keys because of the compiled regex in `DataValue::Regex`,
+/// but they are ordered by value only, hence the `clippy::mutable_key_type` allowances below.
+type VectorCache = BTreeMap<Tuple, Option<Vec<f64>>>;
+
+impl<'a> SessionTx<'a> {
+    pub(crate) fn create_hnsw_index(
+        &mut self,
+        rel_name: &Symbol,
+        idx_name: &Symbol,
+        options: HnswOptions,
+    ) -> Result<()> {
+        let mut rel_handle = self.get_relation(rel_name, true)?;
+        if rel_handle.is_temp {
+            #[derive(Debug, Error, Diagnostic)]
+            #[error("Temp relation {0} cannot have HNSW indices")]
+            #[diagnostic(code(eval::hnsw_in_temp_relation))]
+            struct HnswInTempRelation(String);
+
+            bail!(HnswInTempRelation(rel_handle.name.to_string()))
+        }
+        if rel_handle.has_index(&idx_name.name) {
+            #[derive(Debug, Error, Diagnostic)]
+            #[error("index {0} for relation {1} already exists")]
+            #[diagnostic(code(tx::index_already_exists))]
+            struct IndexAlreadyExists(String, String);
+
+            bail!(IndexAlreadyExists(
+                idx_name.name.to_string(),
+                rel_name.name.to_string()
+            ));
+        }
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("column {0} in HNSW index {1} for relation {2} cannot hold vectors")]
+        #[diagnostic(code(tx::bad_hnsw_column))]
+        #[diagnostic(help("Indexed columns must exist and be of a list type, or of type 'Any'"))]
+        struct BadHnswColumn(String, String, String, #[label] SourceSpan);
+
+        let mut field_positions = vec![];
+        for field in options.fields.iter() {
+            let found = rel_handle
+                .metadata
+                .keys
+                .iter()
+                .chain(rel_handle.metadata.non_keys.iter())
+                .find_position(|col| col.name == field.name);
+            match found {
+                Some((pos, col))
+                    if matches!(col.typing.coltype, ColType::Any | ColType::List { .. }) =>
+                {
+                    field_positions.push(pos)
+                }
+                _ => bail!(BadHnswColumn(
+                    field.name.to_string(),
+                    idx_name.name.to_string(),
+                    rel_name.name.to_string(),
+                    field.span
+                )),
+            }
+        }
+        let manifest = HnswIndexManifest {
+            dim: options.dim,
+            m: options.m,
+            ef_construction: options.ef_construction,
+            distance: options.distance,
+            fields: options.fields.iter().map(|f| f.name.clone()).collect(),
+            field_positions,
+        };
+
+        let col = |name: String, coltype: ColType, nullable: bool| ColumnDef {
+            name: name.into(),
+            typing: NullableColType { coltype, nullable },
+            default_gen: None,
+            auto_update: None,
+            unique: false,
+        };
+        let mut keys = vec![col("layer".to_string(), ColType::Int, false)];
+        for side in ["fr", "to"] {
+            for key in rel_handle.metadata.keys.iter() {
+                keys.push(col(format!("{side}_{}", key.name), ColType::Any, false));
+            }
+            // column names of relations do not start with an underscore
+            keys.push(col(format!("{side}__field"), ColType::Int, false));
+        }
+        let non_keys = vec![
+            col("dist".to_string(), ColType::Float, false),
+            col("vec".to_string(), ColType::Any, true),
+        ];
+        let key_bindings = keys
+            .iter()
+            .map(|col| Symbol::new(col.name.clone(), Default::default()))
+            .collect_vec();
+        let dep_bindings = non_keys
+            .iter()
+            .map(|col| Symbol::new(col.name.clone(), Default::default()))
+            .collect_vec();
+        let idx_handle = self.create_relation(InputRelationHandle {
+            name: Symbol::new(
+                format!("{}:{}", rel_name.name, idx_name.name),
+                Default::default(),
+            ),
+            metadata: StoredRelationMetadata { keys, non_keys },
+            key_bindings,
+            dep_bindings,
+            span: Default::default(),
+            params: Default::default(),
+        })?;
+
+        for row in rel_handle.scan_all(self).collect_vec() {
+            let row = row?;
+            for (node, v) in row_nodes(&rel_handle, &idx_name.name, &manifest, &row)? {
+                self.hnsw_insert(&idx_handle, &manifest, node, v)?;
+            }
+        }
+
+        rel_handle
+            .hnsw_indices
+            .insert(idx_name.name.clone(), (idx_handle, manifest));
+        self.put_relation_meta(&rel_handle)?;
+        self.bump_schema_generation()?;
+        Ok(())
+    }
+
+    /// Removes the index, returning the range of its graph, to be cleared at the end
+    /// of the transaction.
+    pub(crate) fn remove_hnsw_index(
+        &mut self,
+        rel_name: &Symbol,
+        idx_name: &Symbol,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut rel = self.get_relation(rel_name, true)?;
+        if rel.hnsw_indices.remove(&idx_name.name).is_none() {
+            bail!(HnswIndexNotFound(
+                rel_name.name.to_string(),
+                idx_name.name.to_string(),
+                idx_name.span
+            ))
+        }
+        let cleared = self.destroy_relation(&format!("{}:{}", rel_name.name, idx_name.name))?;
+        self.put_relation_meta(&rel)?;
+        self.bump_schema_generation()?;
+        Ok(cleared)
+    }
+
+    /// Builds the graph of the index anew from the rows of the relation
+    pub(crate) fn rebuild_hnsw_index(
+        &mut self,
+        rel_name: &Symbol,
+        idx_name: &Symbol,
+    ) -> Result<()> {
+        let rel = self.get_relation(rel_name, true)?;
+        let (idx_rel, manifest) = rel.hnsw_indices.get(&idx_name.name).ok_or_else(|| {
+            HnswIndexNotFound(
+                rel_name.name.to_string(),
+                idx_name.name.to_string(),
+                idx_name.span,
+            )
+        })?;
+        let lower = Tuple::default().encode_as_key(idx_rel.id);
+        let upper = Tuple::default().encode_as_key(idx_rel.id.next());
+        for kv in self.store_tx.range_scan(&lower, &upper).collect_vec() {
+            let (k, _) = kv?;
+            self.store_tx.del(&k)?;
+        }
+        for row in rel.scan_all(self).collect_vec() {
+            let row = row?;
+            for (node, v) in row_nodes(&rel, &idx_name.name, manifest, &row)? {
+                self.hnsw_insert(idx_rel, manifest, node, v)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the vectors of a row, given with keys and values, to the HNSW indices
+    pub(crate) fn put_into_hnsw_indices(
+        &mut self,
+        handle: &RelationHandle,
+        row: &[DataValue],
+    ) -> Result<()> {
+        for (idx_name, (idx_rel, manifest)) in handle.hnsw_indices.iter() {
+            for (node, v) in row_nodes(handle, idx_name, manifest, row)? {
+                self.hnsw_insert(idx_rel, manifest, node, v)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the vectors of a row, given with keys and values, from the HNSW indices
+    pub(crate) fn delete_from_hnsw_indices(
+        &mut self,
+        handle: &RelationHandle,
+        row: &[DataValue],
+    ) -> Result<()> {
+        let keys = &row[..handle.metadata.keys.len()];
+        for (idx_rel, manifest) in handle.hnsw_indices.values() {
+            for field in 0..manifest.fields.len() {
+                self.hnsw_remove(idx_rel, manifest, &node_of(keys, field))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the vectors of a row from its old image, if there is one, to its new image,
+    /// leaving the vectors that did not change in place
+    pub(crate) fn reindex_hnsw_row(
+        &mut self,
+        handle: &RelationHandle,
+        old: Option<&Tuple>,
+        new: &Tuple,
+    ) -> Result<()> {
+        let keys = &new[..handle.metadata.keys.len()];
+        for (idx_name, (idx_rel, manifest)) in handle.hnsw_indices.iter() {
+            let new_nodes = row_nodes(handle, idx_name, manifest, new)?;
+            for (field, pos) in manifest.field_positions.iter().enumerate() {
+                if let Some(old) = old {
+                    if old[*pos] == new[*pos] {
+                        continue;
+                    }
+                    self.hnsw_remove(idx_rel, manifest, &node_of(keys, field))?;
+                }
+            }
+            for (node, v) in new_nodes {
+                let field = node.last().unwrap().get_int().unwrap() as usize;
+                let pos = manifest.field_positions[field];
+                if old.is_some_and(|old| old[pos] == new[pos]) {
+                    continue;
+                }
+                self.hnsw_insert(idx_rel, manifest, node, v)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The rows of the relation with the `k` vectors nearest to `query` that are found,
+    /// with their distances, nearest first. A row having several vectors among them is
+    /// returned once, at the smallest distance.
+    #[allow(clippy::mutable_key_type)]
+    pub(crate) fn hnsw_search(
+        &self,
+        base: &RelationHandle,
+        idx_rel: &RelationHandle,
+        manifest: &HnswIndexManifest,
+        query: &[f64],
+        k: usize,
+        ef: usize,
+    ) -> Result<Vec<(f64, Tuple)>> {
+        let (entry, top) = match self.hnsw_entry(idx_rel)? {
+            None => return Ok(vec![]),
+            Some(entry) => entry,
+        };
+        let mut cache = VectorCache::new();
+        let entry_dist = match self.hnsw_distance(idx_rel, manifest, query, &entry, &mut cache)? {
+            None => return Ok(vec![]),
+            Some(d) => d,
+        };
+        let mut nearest = vec![(entry_dist, entry)];
+        for layer in (1..=top).rev() {
+            nearest =
+                self.hnsw_search_layer(idx_rel, manifest, query, nearest, 1, layer, &mut cache)?;
+        }
+        let found =
+            self.hnsw_search_layer(idx_rel, manifest, query, nearest, ef.max(k), 0, &mut cache)?;
+
+        let n_keys = base.metadata.keys.len();
+        let expiry = base.expiry(self);
+        let mut seen = BTreeSet::new();
+        let mut ret = vec![];
+        for (dist, node) in found {
+            if ret.len() >= k {
+                break;
+            }
+            let keys = &node[..n_keys];
+            if !seen.insert(keys.to_vec()) {
+                continue;
+            }
+            if let Some(row) = base.get(self, keys)? {
+                if expiry.is_live(&row) {
+                    ret.push((dist, row));
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    fn hnsw_entry(&self, idx_rel: &RelationHandle) -> Result<Option<(Tuple, usize)>> {
+        let width = (idx_rel.metadata.keys.len() - 1) / 2;
+        match idx_rel
+            .scan_prefix(self, &vec![DataValue::from(ENTRY_LAYER)])
+            .next()
+        {
+            None => Ok(None),
+            Some(row) => {
+                let row = row?;
+                let top = row[1 + 2 * width].get_float().unwrap() as usize;
+                Ok(Some((row[1..1 + width].to_vec(), top)))
+            }
+        }
+    }
+
+    fn hnsw_set_entry(
+        &mut self,
+        idx_rel: &RelationHandle,
+        entry: Option<(&Tuple, usize)>,
+    ) -> Result<()> {
+        let old = idx_rel
+            .scan_prefix(self, &vec![DataValue::from(ENTRY_LAYER)])
+            .collect_vec();
+        for row in old {
+            let row = row?;
+            let key = idx_rel.encode_key_for_store(&row, Default::default())?;
+            self.store_tx.del(&key)?;
+        }
+        if let Some((node, top)) = entry {
+            let mut row = edge_key(ENTRY_LAYER, node, node);
+            row.push(DataValue::from(top as f64));
+            row.push(DataValue::Null);
+            self.hnsw_put_row(idx_rel, &row)?;
+        }
+        Ok(())
+    }
+
+    fn hnsw_put_row(&mut self, idx_rel: &RelationHandle, row: &Tuple) -> Result<()> {
+        let key = idx_rel.encode_key_for_store(row, Default::default())?;
+        let val = idx_rel.encode_val_for_store(row, Default::default())?;
+        self.store_tx.put(&key, &val)
+    }
+
+    fn hnsw_put_edge(
+        &mut self,
+        idx_rel: &RelationHandle,
+        layer: usize,
+        fr: &Tuple,
+        to: &Tuple,
+        dist: f64,
+    ) -> Result<()> {
+        let mut row = edge_key(layer as i64, fr, to);
+        row.push(DataValue::from(dist));
+        row.push(DataValue::Null);
+        self.hnsw_put_row(idx_rel, &row)
+    }
+
+    fn hnsw_del_edge(
+        &mut self,
+        idx_rel: &RelationHandle,
+        layer: usize,
+        fr: &Tuple,
+        to: &Tuple,
+    ) -> Result<()> {
+        let key = edge_key(layer as i64, fr, to);
+        let encoded = idx_rel.encode_key_for_store(&key, Default::default())?;
+        self.store_tx.del(&encoded)
+    }
+
+    fn hnsw_has_node(&self, idx_rel: &RelationHandle, layer: usize, node: &Tuple) -> Result<bool> {
+        idx_rel.exists(self, &edge_key(layer as i64, node, node))
+    }
+
+    /// The nodes the node has edges to at the layer, with the lengths of the edges
+    fn hnsw_neighbours(
+        &self,
+        idx_rel: &RelationHandle,
+        layer: usize,
+        node: &Tuple,
+    ) -> Result<Vec<(f64, Tuple)>> {
+        let width = node.len();
+        let mut prefix = vec![DataValue::from(layer as i64)];
+        prefix.extend_from_slice(node);
+        let mut ret = vec![];
+        for row in idx_rel.scan_prefix(self, &prefix) {
+            let row = row?;
+            let to = &row[1 + width..1 + 2 * width];
+            if to == &node[..] {
+                continue;
+            }
+            ret.push((row[1 + 2 * width].get_float().unwrap(), to.to_vec()));
+        }
+        Ok(ret)
+    }
+
+    fn hnsw_vector(&self, idx_rel: &RelationHandle, node: &Tuple) -> Result<Option<Vec<f64>>> {
+        Ok(idx_rel
+            .get(self, &edge_key(0, node, node))?
+            .and_then(|row| to_vector(row.last().unwrap())))
+    }
+
+    #[allow(clippy::mutable_key_type)]
+    fn hnsw_distance(
+        &self,
+        idx_rel: &RelationHandle,
+        manifest: &HnswIndexManifest,
+        query: &[f64],
+        node: &Tuple,
+        cache: &mut VectorCache,
+    ) -> Result<Option<f64>> {
+        if !cache.contains_key(node) {
+            let v = self.hnsw_vector(idx_rel, node)?;
+            cache.insert(node.clone(), v);
+        }
+        Ok(cache[node]
+            .as_ref()
+            .map(|v| manifest.distance.distance(query, v)))
+    }
+
+    /// The `ef` nodes nearest to `query` found at the layer by greedy search from the entry
+    /// nodes, nearest first
+    #[allow(clippy::too_many_arguments, clippy::mutable_key_type)]
+    fn hnsw_search_layer(
+        &self,
+        idx_rel: &RelationHandle,
+        manifest: &HnswIndexManifest,
+        query: &[f64],
+        entry: Vec<(f64, Tuple)>,
+        ef: usize,
+        layer: usize,
+        cache: &mut VectorCache,
+    ) -> Result<Vec<(f64, Tuple)>> {
+        let mut visited: BTreeSet<Tuple> = entry.iter().map(|(_, node)| node.clone()).collect();
+        let mut candidates = BinaryHeap::new();
+        // the nearest nodes found so far, the farthest of them on top
+        let mut found = BinaryHeap::new();
+        for (dist, node) in entry {
+            candidates.push(Reverse((OrderedFloat(dist), node.clone())));
+            found.push((OrderedFloat(dist), node));
+            if found.len() > ef {
+                found.pop();
+            }
+        }
+        while let Some(Reverse((dist, node))) = candidates.pop() {
+            if found.len() >= ef && dist > found.peek().unwrap().0 {
+                break;
+            }
+            for (_, nb) in self.hnsw_neighbours(idx_rel, layer, &node)? {
+                if !visited.insert(nb.clone()) {
+                    continue;
+                }
+                let nb_dist = match self.hnsw_distance(idx_rel, manifest, query, &nb, cache)? {
+                    None => continue,
+                    Some(d) => OrderedFloat(d),
+                };
+                if found.len() < ef || nb_dist < found.peek().unwrap().0 {
+                    candidates.push(Reverse((nb_dist, nb.clone())));
+                    found.push((nb_dist, nb));
+                    if found.len() > ef {
+                        found.pop();
+                    }
+                }
+            }
+        }
+        Ok(found
+            .into_sorted_vec()
+            .into_iter()
+            .map(|(dist, node)| (dist.0, node))
+            .collect())
+    }
+
+    /// Drops the longest edges of the node at the layer until it has at most `max_degree`
+    fn hnsw_shrink(
+        &mut self,
+        idx_rel: &RelationHandle,
+        layer: usize,
+        node: &Tuple,
+        max_degree: usize,
+    ) -> Result<()> {
+        let mut neighbours = self.hnsw_neighbours(idx_rel, layer, node)?;
+        if neighbours.len() <= max_degree {
+            return Ok(());
+        }
+        neighbours.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+        for (_, nb) in &neighbours[max_degree..] {
+            self.hnsw_del_edge(idx_rel, layer, node, nb)?;
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::mutable_key_type)]
+    fn hnsw_insert(
+        &mut self,
+        idx_rel: &RelationHandle,
+        manifest: &HnswIndexManifest,
+        node: Tuple,
+        v: Vec<f64>,
+    ) -> Result<()> {
+        if self.hnsw_has_node(idx_rel, 0, &node)? {
+            self.hnsw_remove(idx_rel, manifest, &node)?;
+        }
+        let level = node_level(&node, manifest.m);
+        let entry = self.hnsw_entry(idx_rel)?;
+
+        let mut bottom = edge_key(0, &node, &node);
+        bottom.push(DataValue::from(0.));
+        bottom.push(DataValue::List(
+            v.iter().map(|x| DataValue::from(*x)).collect(),
+        ));
+        self.hnsw_put_row(idx_rel, &bottom)?;
+        for layer in 1..=level {
+            self.hnsw_put_edge(idx_rel, layer, &node, &node, 0.)?;
+        }
+
+        let (entry, top) = match entry {
+            None => return self.hnsw_set_entry(idx_rel, Some((&node, level))),
+            Some(entry) => entry,
+        };
+        let mut cache = VectorCache::new();
+        let mut nearest = match self.hnsw_distance(idx_rel, manifest, &v, &entry, &mut cache)? {
+            None => vec![],
+            Some(d) => vec![(d, entry)],
+        };
+        for layer in (level + 1..=top).rev() {
+            nearest =
+                self.hnsw_search_layer(idx_rel, manifest, &v, nearest, 1, layer, &mut cache)?;
+        }
+        for layer in (0..=level.min(top)).rev() {
+            nearest = self.hnsw_search_layer(
+                idx_rel,
+                manifest,
+                &v,
+                nearest,
+                manifest.ef_construction,
+                layer,
+                &mut cache,
+            )?;
+            let max_degree = max_degree(manifest, layer);
+            for (dist, nb) in nearest
+                .iter()
+                .filter(|(_, nb)| *nb != node)
+                .take(manifest.m)
+            {
+                self.hnsw_put_edge(idx_rel, layer, &node, nb, *dist)?;
+                self.hnsw_put_edge(idx_rel, layer, nb, &node, *dist)?;
+                self.hnsw_shrink(idx_rel, layer, nb, max_degree)?;
+            }
+        }
+        if level > top {
+            self.hnsw_set_entry(idx_rel, Some((&node, level)))?;
+        }
+        Ok(())
+    }
+
+    /// Removes the node, linking its neighbours to one another in its place. Edges from
+    /// nodes that were not its neighbours may remain, and are skipped by searches.
+    #[allow(clippy::mutable_key_type)]
+    fn hnsw_remove(
+        &mut self,
+        idx_rel: &RelationHandle,
+        manifest: &HnswIndexManifest,
+        node: &Tuple,
+    ) -> Result<()> {
+        if !self.hnsw_has_node(idx_rel, 0, node)? {
+            return Ok(());
+        }
+        let mut cache = VectorCache::new();
+        let mut layer = 0;
+        while self.hnsw_has_node(idx_rel, layer, node)? {
+            let neighbours = self.hnsw_neighbours(idx_rel, layer, node)?;
+            self.hnsw_del_edge(idx_rel, layer, node, node)?;
+            for (_, nb) in &neighbours {
+                self.hnsw_del_edge(idx_rel, layer, node, nb)?;
+                self.hnsw_del_edge(idx_rel, layer, nb, node)?;
+            }
+            let max_degree = max_degree(manifest, layer);
+            for (_, nb) in &neighbours {
+                let nb_vec = match self.hnsw_vector(idx_rel, nb)? {
+                    None => continue,
+                    Some(v) => v,
+                };
+                let linked: BTreeSet<_> = self
+                    .hnsw_neighbours(idx_rel, layer, nb)?
+                    .into_iter()
+                    .map(|(_, n)| n)
+                    .collect();
+                if linked.len() >= max_degree {
+                    continue;
+                }
+                let mut candidates = vec![];
+                for (_, other) in &neighbours {
+                    if other == nb || linked.contains(other) {
+                        continue;
+                    }
+                    if let Some(d) =
+                        self.hnsw_distance(idx_rel, manifest, &nb_vec, other, &mut cache)?
+                    {
+                        candidates.push((d, other));
+                    }
+                }
+                candidates.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+                for (d, other) in candidates.into_iter().take(max_degree - linked.len()) {
+                    self.hnsw_put_edge(idx_rel, layer, nb, other, d)?;
+                    self.hnsw_put_edge(idx_rel, layer, other, nb, d)?;
+                    self.hnsw_shrink(idx_rel, layer, other, max_degree)?;
+                }
+            }
+            layer += 1;
+        }
+
+        if let Some((entry, top)) = self.hnsw_entry(idx_rel)? {
+            if entry == *node {
+                // any node at the highest layer left will do
+                let mut new_entry = None;
+                for layer in (0..=top).rev() {
+                    if let Some(row) = idx_rel
+                        .scan_prefix(self, &vec![DataValue::from(layer as i64)])
+                        .next()
+                    {
+                        let row = row?;
+                        new_entry = Some((row[1..1 + node.len()].to_vec(), layer));
+                        break;
+                    }
+                }
+                self.hnsw_set_entry(idx_rel, new_entry.as_ref().map(|(node, top)| (node, *top)))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, BTreeSet};
+
+    use itertools::Itertools;
+    use serde_json::json;
+
+    use crate::data::value::DataValue;
+    use crate::new_cozo_mem;
+
+    #[test]
+    fn test_hnsw_index() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        const DIM: usize = 8;
+        let db = new_cozo_mem().unwrap();
+        db.run_script(":create items {id: Int => v: [Float]?}", Default::default())
+            .unwrap();
+        db.run_script(
+            "::hnsw create items:vec_idx {dim: 8, ef_construction: 64, fields: [v]}",
+            Default::default(),
+        )
+        .unwrap();
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let vectors = (0..300)
+            .map(|_| (0..DIM).map(|_| rng.gen_range(-1.0..1.0)).collect_vec())
+            .collect_vec();
+        let to_value = |v: &[f64]| DataValue::List(v.iter().map(|x| DataValue::from(*x)).collect());
+        let rows = vectors
+            .iter()
+            .enumerate()
+            .map(|(i, v)| DataValue::List(vec![DataValue::from(i as i64), to_value(v)]))
+            .collect_vec();
+        db.run_script(
+            "?[id, v] <- $rows :put items {id => v}",
+            BTreeMap::from([("rows".to_string(), DataValue::List(rows))]),
+        )
+        .unwrap();
+
+        let search = |q: &[f64]| -> Vec<(i64, f64)> {
+            db.run_script(
+            "?[id, d] := ~items:vec_idx{id | query: $q, k: 10, ef: 64, bind_distance: d} :order d",
+            BTreeMap::from([("q".to_string(), to_value(q))]),
+        )
+        .unwrap()
+        .rows
+        .into_iter()
+        .map(|row| (row[0].get_int().unwrap(), row[1].get_float().unwrap()))
+        .collect()
+        };
+        let distance = |a: &[f64], b: &[f64]| -> f64 {
+            a.iter()
+                .zip(b)
+                .map(|(x, y)| (x - y) * (x - y))
+                .sum::<f64>()
+                .sqrt()
+        };
+
+        // the nearest rows are found for most queries, with their distances
+        let mut found = 0;
+        for _ in 0..20 {
+            let q = (0..DIM).map(|_| rng.gen_range(-1.0..1.0)).collect_vec();
+            let res = search(&q);
+            assert_eq!(res.len(), 10);
+            for (id, d) in &res {
+                assert!((distance(&q, &vectors[*id as usize]) - d).abs() < 1e-9);
+            }
+            let exact: BTreeSet<_> = (0..vectors.len() as i64)
+                .sorted_by(|a, b| {
+                    distance(&q, &vectors[*a as usize])
+                        .total_cmp(&distance(&q, &vectors[*b as usize]))
+                })
+                .take(10)
+                .collect();
+            found += res.iter().filter(|(id, _)| exact.contains(id)).count();
+        }
+        assert!(found as f64 / 200. >= 0.9, "recall {found}/200");
+
+        // the index follows the rows put into and removed from the relation
+        let far = [10.; DIM];
+        db.run_script(
+            "?[id, v] <- [[1000, $v]] :put items {id => v}",
+            BTreeMap::from([("v".to_string(), to_value(&far))]),
+        )
+        .unwrap();
+        assert_eq!(search(&far)[0].0, 1000);
+        db.run_script("?[id] <- [[1000]] :rm items {id}", Default::default())
+            .unwrap();
+        assert!(search(&far).iter().all(|(id, _)| *id != 1000));
+        db.run_script(
+            "?[id, v] <- [[0, null]] :put items {id => v}",
+            Default::default(),
+        )
+        .unwrap();
+        assert!(search(&vectors[0]).iter().all(|(id, _)| *id != 0));
+
+        // the columns of the rows found can be bound and joined on
+        let res = db
+            .run_script(
+                "?[id] := id = 5, ~items:vec_idx{id, v | query: $q, k: 3}, length(v) == 8",
+                BTreeMap::from([("q".to_string(), to_value(&vectors[5]))]),
+            )
+            .unwrap();
+        assert_eq!(res.into_json()["rows"], json!([[5]]));
+
+        // vectors not matching the index are rejected
+        for (script, code) in [
+            (
+                "?[id, v] <- [[1, [1.0, 2.0]]] :put items {id => v}",
+                "eval::hnsw_dim_mismatch",
+            ),
+            (
+                "?[id] := ~items:vec_idx{id | query: [1.0], k: 3}",
+                "eval::hnsw_bad_query",
+            ),
+            (
+                "?[id] := ~items:nowhere{id | query: [1.0], k: 3}",
+                "query::search_index_not_found",
+            ),
+            (
+                "?[id] := ~items:vec_idx{id | query: [1.0], k: 0}",
+                "parser::bad_search_parameter",
+            ),
+        ] {
+            let err = db.run_script(script, Default::default()).unwrap_err();
+            assert_eq!(err.code().unwrap().to_string(), code, "{script}");
+        }
+
+        assert_eq!(
+            db.run_script("::indices items", Default::default())
+                .unwrap()
+                .into_json()["rows"],
+            json!([["vec_idx", ["v"], null]])
+        );
+        db.run_script("::hnsw rebuild items:vec_idx", Default::default())
+            .unwrap();
+        assert_eq!(search(&vectors[7])[0].0, 7);
+        db.run_script("::hnsw drop items:vec_idx", Default::default())
+            .unwrap();
+        assert!(db
+            .run_script(
+                "?[id] := ~items:vec_idx{id | query: $q, k: 3}",
+                BTreeMap::from([("q".to_string(), to_value(&vectors[7]))]),
+            )
+            .is_err());
+    }
+}
@@ -8,21 +8,37 @@
 
 use std::collections::{BTreeMap, BTreeSet};
 use std::sync::atomic::Ordering;
+use std::sync::Arc;
+#[allow(unused_imports)]
+use std::time::Duration;
 
 use either::{Either, Left, Right};
 use itertools::Itertools;
-use miette::{bail, Diagnostic, Report, Result};
+use miette::{bail, ensure, Diagnostic, Report, Result};
 use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
-use crate::data::expr::PredicateTypeError;
+use crate::data::expr::{Expr, PredicateTypeError};
 use crate::data::functions::op_to_bool;
-use crate::data::symb::Symbol;
-use crate::parse::{ImperativeCondition, ImperativeProgram, ImperativeStmt, SourceSpan};
+use crate::data::program::{FixedRuleApply, InputInlineRulesOrFixed, InputProgram, RelationOp};
+use crate::data::relation::{ColType, ColumnDef, NullableColType, StoredRelationMetadata};
+use crate::data::symb::{Symbol, PROG_ENTRY};
+use crate::fixed_rule::utilities::constant::Constant;
+use crate::fixed_rule::{FixedRule, FixedRuleHandle};
+use crate::parse::{
+    ImperativeCondition, ImperativeProgram, ImperativeStmt, LoopGuard, ReplaceManyEntry, SourceSpan,
+};
 use crate::runtime::callback::CallbackCollector;
+use crate::runtime::db::{RunningQueryCleanup, RunningQueryHandle, RunningScript};
+use crate::runtime::relation::InputRelationHandle;
 use crate::runtime::transact::SessionTx;
 use crate::{DataValue, Db, NamedRows, Poison, Storage, ValidityTs};
-use crate::runtime::db::{RunningQueryCleanup, RunningQueryHandle, seconds_since_the_epoch};
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("savepoint '{0}' is not set")]
+#[diagnostic(code(eval::savepoint_not_found))]
+#[diagnostic(help("Set it with `%savepoint` before rolling back to it"))]
+struct SavepointNotFound(String, #[label] SourceSpan);
 
 enum ControlCode {
     Termination(NamedRows),
@@ -129,8 +145,10 @@ impl<'s, S: Storage<'s>> Db<S> {
                         callback_collector,
                     )?;
                 }
-                ImperativeStmt::IgnoreErrorProgram { prog, .. } => {
-                    match self.execute_single_program(
+                ImperativeStmt::IgnoreErrorProgram { prog, binding } => {
+                    // the partial writes of a failed program are rolled back
+                    tx.set_savepoint(None, cleanups, callback_collector);
+                    let outcome = match self.execute_single_program(
                         prog.clone(),
                         tx,
                         cleanups,
@@ -138,15 +156,50 @@ impl<'s, S: Storage<'s>> Db<S> {
                         callback_targets,
                         callback_collector,
                     ) {
-                        Ok(res) => ret = res,
-                        Err(_) => {
+                        Ok(res) => {
+                            ret = res;
+                            vec![
+                                DataValue::from("OK"),
+                                DataValue::Null,
+                                DataValue::Null,
+                                DataValue::Null,
+                            ]
+                        }
+                        Err(err) => {
+                            tx.rollback_to_savepoint(None, cleanups, callback_collector)?;
+                            let outcome = error_outcome(&err);
                             ret = NamedRows::new(
-                                vec!["status".to_string()],
-                                vec![vec![DataValue::from("FAILED")]],
-                            )
+                                ERROR_OUTCOME_HEADERS
+                                    .iter()
+                                    .map(|h| h.to_string())
+                                    .collect(),
+                                vec![outcome.clone()],
+                            );
+                            outcome
                         }
+                    };
+                    tx.release_last_savepoint();
+                    if let Some(binding) = binding {
+                        self.execute_single_program(
+                            outcome_program(binding, outcome)?,
+                            tx,
+                            cleanups,
+                            cur_vld,
+                            callback_targets,
+                            callback_collector,
+                        )?;
                     }
                 }
+                ImperativeStmt::Savepoint { name } => {
+                    tx.set_savepoint(Some(name.clone()), cleanups, callback_collector);
+                    ret = NamedRows::default();
+                }
+                ImperativeStmt::RollbackTo { name, span } => {
+                    if !tx.rollback_to_savepoint(Some(name), cleanups, callback_collector)? {
+                        bail!(SavepointNotFound(name.to_string(), *span))
+                    }
+                    ret = NamedRows::default();
+                }
                 ImperativeStmt::If {
                     condition,
                     then_branch,
@@ -180,11 +233,38 @@ impl<'s, S: Storage<'s>> Db<S> {
                         Right(ctrl) => return Ok(Right(ctrl)),
                     }
                 }
-                ImperativeStmt::Loop { label, body, .. } => {
+                ImperativeStmt::Loop {
+                    label, body, guard, ..
+                } => {
                     ret = Default::default();
+                    let mut iterations = 0;
                     loop {
                         poison.check()?;
 
+                        match guard {
+                            Some(LoopGuard::Count(n)) if iterations >= *n => break,
+                            None | Some(LoopGuard::Count(_)) => {}
+                            Some(LoopGuard::Condition {
+                                condition,
+                                negated,
+                                span,
+                            }) => {
+                                let cond_val = self.execute_imperative_condition(
+                                    condition,
+                                    tx,
+                                    cleanups,
+                                    cur_vld,
+                                    *span,
+                                    callback_targets,
+                                    callback_collector,
+                                )?;
+                                if cond_val == *negated {
+                                    break;
+                                }
+                            }
+                        }
+                        iterations += 1;
+
                         match self.execute_imperative_stmts(
                             body,
                             tx,
@@ -217,6 +297,84 @@ impl<'s, S: Storage<'s>> Db<S> {
                         }
                     }
                 }
+                ImperativeStmt::Retry {
+                    retries,
+                    backoff,
+                    factor,
+                    body,
+                } => {
+                    #[allow(unused_variables, unused_mut)]
+                    let mut wait = *backoff as f64;
+                    let mut attempt = 0;
+                    loop {
+                        poison.check()?;
+                        // the writes of a failed attempt are rolled back
+                        let savepoint = tx.set_savepoint(None, cleanups, callback_collector);
+                        let res = self.execute_imperative_stmts(
+                            body,
+                            tx,
+                            cleanups,
+                            cur_vld,
+                            callback_targets,
+                            callback_collector,
+                            poison,
+                        );
+                        match res {
+                            Ok(res) => {
+                                tx.release_savepoints(savepoint);
+                                match res {
+                                    Left(rows) => ret = rows,
+                                    Right(ctrl) => return Ok(Right(ctrl)),
+                                }
+                                break;
+                            }
+                            Err(err) => {
+                                // the savepoint is gone if the body set one with the same name
+                                // as one set before it, and then the whole transaction fails
+                                if !tx.rollback_to_savepoint_at(
+                                    savepoint,
+                                    cleanups,
+                                    callback_collector,
+                                )? {
+                                    return Err(err);
+                                }
+                                tx.release_savepoints(savepoint);
+                                // killed scripts are not retried
+                                poison.check()?;
+                                if attempt >= *retries {
+                                    return Err(err);
+                                }
+                                attempt += 1;
+                                #[cfg(not(target_arch = "wasm32"))]
+                                {
+                                    poison.sleep(Duration::from_micros((wait * 1000.) as u64))?;
+                                    wait *= *factor;
+                                }
+                            }
+                        }
+                    }
+                }
+                ImperativeStmt::Sleep { millis } => {
+                    #[cfg(not(target_arch = "wasm32"))]
+                    poison.sleep(Duration::from_millis(*millis))?;
+                    ret = NamedRows::default();
+                }
+                ImperativeStmt::ReplaceMany { entries, .. } => {
+                    // all entries are written in the current transaction, so a failure in any
+                    // of them leaves every relation as it was
+                    for entry in entries {
+                        poison.check()?;
+                        let prog = entry.make_program(tx)?;
+                        ret = self.execute_single_program(
+                            prog,
+                            tx,
+                            cleanups,
+                            cur_vld,
+                            callback_targets,
+                            callback_collector,
+                        )?;
+                    }
+                }
                 ImperativeStmt::TempSwap { left, right, .. } => {
                     tx.rename_temp_relation(
                         Symbol::new(left.clone(), Default::default()),
@@ -241,6 +399,8 @@ impl<'s, S: Storage<'s>> Db<S> {
         &'s self,
         cur_vld: ValidityTs,
         ps: &ImperativeProgram,
+        identity: Option<&str>,
+        script: &RunningScript,
     ) -> Result<NamedRows, Report> {
         let mut callback_collector = BTreeMap::new();
         let mut write_lock_names = BTreeSet::new();
@@ -248,6 +408,9 @@ impl<'s, S: Storage<'s>> Db<S> {
             p.needs_write_locks(&mut write_lock_names);
         }
         let is_write = !write_lock_names.is_empty();
+        if is_write {
+            self.ensure_writable()?;
+        }
         let write_lock = self.obtain_relation_locks(write_lock_names.iter());
         let _write_lock_guards = write_lock.iter().map(|l| l.read().unwrap()).collect_vec();
 
@@ -265,13 +428,22 @@ impl<'s, S: Storage<'s>> Db<S> {
                 self.transact()?
             };
 
-            let poison = Poison::default();
+            // the queries of the script are also killed with the script
+            let poison = script.poison.nested();
+            tx.script = RunningScript {
+                poison: poison.clone(),
+                snippet: script.snippet.clone(),
+                relations: script.relations.clone(),
+            };
+            self.load_script_relations(&mut tx, cur_vld)?;
             let qid = self.queries_count.fetch_add(1, Ordering::AcqRel);
-            let since_the_epoch = seconds_since_the_epoch()?;
+            let since_the_epoch = self.clock.seconds_since_the_epoch()?;
 
             let q_handle = RunningQueryHandle {
                 started_at: since_the_epoch,
                 poison: poison.clone(),
+                script: script.snippet.clone(),
+                is_write,
             };
             self.running_queries.lock().unwrap().insert(qid, q_handle);
             let _guard = RunningQueryCleanup {
@@ -304,10 +476,8 @@ impl<'s, S: Storage<'s>> Db<S> {
                 },
             }
 
-            if is_write {
-                tx.commit_tx()?;
-            } else {
-                tx.commit_tx()?;
+            self.commit_audited(tx, identity)?;
+            if !is_write {
                 assert!(cleanups.is_empty(), "non-empty cleanups on read-only tx");
             }
         }
@@ -322,3 +492,154 @@ impl<'s, S: Storage<'s>> Db<S> {
         Ok(ret)
     }
 }
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("rows given for relation '{0}' do not match its columns")]
+#[diagnostic(code(eval::replace_many_arity_mismatch))]
+#[diagnostic(help("The relation has {1} columns but the rows have {2}"))]
+struct ReplaceManyArityMismatch(String, usize, usize, #[label] SourceSpan);
+
+impl ReplaceManyEntry {
+    /// Makes the program replacing the contents of the relation, keeping its schema.
+    fn make_program(&self, tx: &SessionTx<'_>) -> Result<InputProgram> {
+        let relation = tx.get_relation(&self.name, false)?;
+        let metadata = relation.metadata.clone();
+        let to_bindings = |cols: &[ColumnDef]| {
+            cols.iter()
+                .map(|col| Symbol::new(col.name.clone(), self.span))
+                .collect_vec()
+        };
+        let key_bindings = to_bindings(&metadata.keys);
+        let dep_bindings = to_bindings(&metadata.non_keys);
+        let mut prog = match &self.source {
+            Left(prog) => prog.clone(),
+            Right(rows) => {
+                let head = key_bindings
+                    .iter()
+                    .chain(dep_bindings.iter())
+                    .cloned()
+                    .collect_vec();
+                let n_cols = head.len();
+                let (prog, arity) = constant_program(head, rows.clone(), self.span)?;
+                ensure!(
+                    arity == n_cols,
+                    ReplaceManyArityMismatch(relation.name.to_string(), n_cols, arity, self.span)
+                );
+                prog
+            }
+        };
+        prog.out_opts.store_relation = Some((
+            InputRelationHandle {
+                name: Symbol::new(relation.name.clone(), self.span),
+                metadata,
+                key_bindings,
+                dep_bindings,
+                span: self.span,
+                params: Default::default(),
+            },
+            RelationOp::Replace,
+        ));
+        Ok(prog)
+    }
+}
+
+/// Makes the program returning the rows given, with the number of columns they have.
+fn constant_program(
+    head: Vec<Symbol>,
+    rows: Expr,
+    span: SourceSpan,
+) -> Result<(InputProgram, usize)> {
+    let mut options = BTreeMap::new();
+    options.insert(SmartString::from("data"), rows);
+    let fixed_impl = Box::new(Constant);
+    fixed_impl.init_options(&mut options, span)?;
+    let arity = fixed_impl.arity(&options, &head, span)?;
+    let prog = InputProgram {
+        prog: BTreeMap::from([(
+            Symbol::new(PROG_ENTRY, span),
+            InputInlineRulesOrFixed::Fixed {
+                fixed: FixedRuleApply {
+                    fixed_handle: FixedRuleHandle {
+                        name: Symbol::new("Constant", span),
+                    },
+                    rule_args: vec![],
+                    options: Arc::new(options),
+                    head,
+                    arity,
+                    span,
+                    fixed_impl: Arc::new(fixed_impl),
+                },
+            },
+        )]),
+        out_opts: Default::default(),
+    };
+    Ok((prog, arity))
+}
+
+/// The columns of the outcome of an `%ignore_error` statement
+const ERROR_OUTCOME_HEADERS: [&str; 4] = ["status", "error_code", "error_message", "span"];
+
+/// The outcome of an `%ignore_error` statement whose program failed. The span is given as
+/// `[offset, length]` in the script, and is null if the error does not point into it.
+fn error_outcome(err: &Report) -> Vec<DataValue> {
+    let code = match err.code() {
+        None => DataValue::Null,
+        Some(code) => DataValue::from(code.to_string()),
+    };
+    let span = match err.labels().and_then(|mut labels| labels.next()) {
+        None => DataValue::Null,
+        Some(label) => DataValue::List(vec![
+            DataValue::from(label.offset() as i64),
+            DataValue::from(label.len() as i64),
+        ]),
+    };
+    vec![
+        DataValue::from("FAILED"),
+        code,
+        DataValue::from(err.to_string()),
+        span,
+    ]
+}
+
+/// Makes the program storing the outcome of an `%ignore_error` statement in its temp relation,
+/// replacing what it held.
+fn outcome_program(binding: &Symbol, outcome: Vec<DataValue>) -> Result<InputProgram> {
+    let span = binding.span;
+    let head = ERROR_OUTCOME_HEADERS
+        .iter()
+        .map(|h| Symbol::new(*h, span))
+        .collect_vec();
+    let metadata = StoredRelationMetadata {
+        keys: ERROR_OUTCOME_HEADERS
+            .iter()
+            .map(|h| ColumnDef {
+                name: SmartString::from(*h),
+                typing: NullableColType {
+                    coltype: ColType::Any,
+                    nullable: true,
+                },
+                default_gen: None,
+                auto_update: None,
+                unique: false,
+            })
+            .collect(),
+        non_keys: vec![],
+    };
+    let rows = Expr::Const {
+        val: DataValue::List(vec![DataValue::List(outcome)]),
+        span,
+    };
+    let (mut prog, _) = constant_program(head.clone(), rows, span)?;
+    prog.out_opts.store_relation = Some((
+        InputRelationHandle {
+            name: binding.clone(),
+            metadata,
+            key_bindings: head,
+            dep_bindings: vec![],
+            span,
+            params: Default::default(),
+        },
+        RelationOp::Replace,
+    ));
+    Ok(prog)
+}
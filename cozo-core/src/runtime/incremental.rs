@@ -0,0 +1,468 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// backups are only written to Sqlite files
+#![cfg_attr(not(feature = "storage-sqlite"), allow(dead_code, unused_imports))]
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::iter;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use itertools::Itertools;
+use miette::{bail, Diagnostic, IntoDiagnostic, Result};
+use rmp_serde::Serializer;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::data::tuple::{Tuple, TupleT};
+use crate::data::value::{DataValue, ValidityTs};
+use crate::runtime::db::Db;
+use crate::runtime::relation::RelationId;
+use crate::runtime::transact::SessionTx;
+use crate::storage::{Storage, StoreTx};
+
+/// The version of a stored relation or index when a backup was taken
+#[derive(Copy, Clone, Debug, Eq, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct RelationVersion {
+    /// The id of the relation, which changes when it is created again under the same name
+    pub id: u64,
+    /// The number of committed write transactions that touched the relation
+    pub changes: u64,
+}
+
+/// The stored relations and indices of a backup, by name, with their versions when it was
+/// taken. An incremental backup taken against this manifest only holds the relations whose
+/// versions changed since. See [crate::Db::backup_incremental].
+#[derive(
+    Clone, Debug, Default, Eq, PartialEq, serde_derive::Serialize, serde_derive::Deserialize,
+)]
+pub struct BackupManifest {
+    /// The versions of the stored relations and indices, by name
+    pub relations: BTreeMap<String, RelationVersion>,
+}
+
+/// Kept in the catalog of an incremental backup
+#[derive(serde_derive::Serialize, serde_derive::Deserialize)]
+struct IncrementRecord {
+    /// the manifest of the backup the increment was taken against
+    base: BackupManifest,
+    manifest: BackupManifest,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Backup '{0}' does not follow the backup before it in the chain")]
+#[diagnostic(code(eval::backup_chain_order))]
+#[diagnostic(help("Each increment must be restored right after the backup it was taken against"))]
+struct BackupChainOrder(String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Backup '{0}' is an increment, but a chain of backups must start with a full backup")]
+#[diagnostic(code(eval::backup_chain_base))]
+struct BackupChainBase(String);
+
+fn change_counter_key(id: u64) -> Vec<u8> {
+    let tuple = vec![
+        DataValue::Null,
+        DataValue::from("CHANGES"),
+        DataValue::from(id as i64),
+    ];
+    tuple.encode_as_key(RelationId::SYSTEM)
+}
+
+fn increment_record_key() -> Vec<u8> {
+    let tuple = vec![DataValue::Null, DataValue::from("BACKUP_INCREMENT")];
+    tuple.encode_as_key(RelationId::SYSTEM)
+}
+
+fn relation_range(id: RelationId) -> (Vec<u8>, Vec<u8>) {
+    (
+        Tuple::default().encode_as_key(id),
+        Tuple::default().encode_as_key(id.next()),
+    )
+}
+
+fn read_change_counter(tx: &dyn StoreTx<'_>, key: &[u8], for_update: bool) -> Result<u64> {
+    Ok(match tx.get(key, for_update)? {
+        Some(v) if v.len() == 8 => u64::from_be_bytes(v[..].try_into().unwrap()),
+        _ => 0,
+    })
+}
+
+/// Wraps the storage transactions that write, to bump the change counters of the relations
+/// they write to when they commit. The counters tell incremental backups what to dump.
+pub(crate) struct ChangeTrackingTx<'s> {
+    inner: Box<dyn StoreTx<'s> + 's>,
+    /// the ids of the relations written to
+    touched: Mutex<BTreeSet<u64>>,
+}
+
+impl<'s> ChangeTrackingTx<'s> {
+    pub(crate) fn new(inner: Box<dyn StoreTx<'s> + 's>) -> Self {
+        Self {
+            inner,
+            touched: Default::default(),
+        }
+    }
+    fn touch(touched: &mut BTreeSet<u64>, key: &[u8]) {
+        // keys start with the id of the relation, and those of the catalog are not counted
+        if let Some(prefix) = key.get(..8) {
+            let id = u64::from_be_bytes(prefix.try_into().unwrap());
+            if id != RelationId::SYSTEM.0 {
+                touched.insert(id);
+            }
+        }
+    }
+}
+
+impl<'s> StoreTx<'s> for ChangeTrackingTx<'s> {
+    fn get(&self, key: &[u8], for_update: bool) -> Result<Option<Vec<u8>>> {
+        self.inner.get(key, for_update)
+    }
+
+    fn multi_get(&self, keys: &[&[u8]], for_update: bool) -> Result<Vec<Option<Vec<u8>>>> {
+        self.inner.multi_get(keys, for_update)
+    }
+
+    fn put(&mut self, key: &[u8], val: &[u8]) -> Result<()> {
+        Self::touch(self.touched.get_mut().unwrap(), key);
+        self.inner.put(key, val)
+    }
+
+    fn supports_par_put(&self) -> bool {
+        self.inner.supports_par_put()
+    }
+
+    fn par_put(&self, key: &[u8], val: &[u8]) -> Result<()> {
+        Self::touch(&mut self.touched.lock().unwrap(), key);
+        self.inner.par_put(key, val)
+    }
+
+    fn del(&mut self, key: &[u8]) -> Result<()> {
+        Self::touch(self.touched.get_mut().unwrap(), key);
+        self.inner.del(key)
+    }
+
+    fn exists(&self, key: &[u8], for_update: bool) -> Result<bool> {
+        self.inner.exists(key, for_update)
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        for id in std::mem::take(self.touched.get_mut().unwrap()) {
+            let key = change_counter_key(id);
+            // read for update, so that concurrent writes to the relation conflict
+            let changes = read_change_counter(&*self.inner, &key, true)?;
+            self.inner.put(&key, &(changes + 1).to_be_bytes())?;
+        }
+        self.inner.commit()
+    }
+
+    fn range_scan_tuple<'a>(
+        &'a self,
+        lower: &[u8],
+        upper: &[u8],
+    ) -> Box<dyn Iterator<Item = Result<Tuple>> + 'a>
+    where
+        's: 'a,
+    {
+        self.inner.range_scan_tuple(lower, upper)
+    }
+
+    fn range_skip_scan_tuple<'a>(
+        &'a self,
+        lower: &[u8],
+        upper: &[u8],
+        valid_at: ValidityTs,
+    ) -> Box<dyn Iterator<Item = Result<Tuple>> + 'a> {
+        self.inner.range_skip_scan_tuple(lower, upper, valid_at)
+    }
+
+    fn range_skip_scan_tuple_window<'a>(
+        &'a self,
+        lower: &[u8],
+        upper: &[u8],
+        valid_from: ValidityTs,
+        valid_to: ValidityTs,
+    ) -> Box<dyn Iterator<Item = Result<Tuple>> + 'a>
+    where
+        's: 'a,
+    {
+        self.inner
+            .range_skip_scan_tuple_window(lower, upper, valid_from, valid_to)
+    }
+
+    fn range_scan<'a>(
+        &'a self,
+        lower: &[u8],
+        upper: &[u8],
+    ) -> Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>
+    where
+        's: 'a,
+    {
+        self.inner.range_scan(lower, upper)
+    }
+
+    fn total_scan<'a>(&'a self) -> Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>
+    where
+        's: 'a,
+    {
+        self.inner.total_scan()
+    }
+}
+
+impl<'a> SessionTx<'a> {
+    /// The versions of all stored relations and indices
+    pub(crate) fn backup_manifest(&self) -> Result<BackupManifest> {
+        let mut relations = BTreeMap::new();
+        for (name, handle) in self.relation_catalog()? {
+            let changes =
+                read_change_counter(&*self.store_tx, &change_counter_key(handle.id.0), false)?;
+            relations.insert(
+                name.to_string(),
+                RelationVersion {
+                    id: handle.id.0,
+                    changes,
+                },
+            );
+        }
+        Ok(BackupManifest { relations })
+    }
+    fn increment_record(&self) -> Result<Option<IncrementRecord>> {
+        match self.store_tx.get(&increment_record_key(), false)? {
+            None => Ok(None),
+            Some(v) => Ok(Some(rmp_serde::from_slice(&v).into_diagnostic()?)),
+        }
+    }
+}
+
+impl<'s, S: Storage<'s>> Db<S> {
+    /// The manifest of a backup in an Sqlite file, full or incremental, to take
+    /// the next increment against with [Self::backup_incremental]
+    #[allow(unused_variables)]
+    pub fn backup_manifest(&'s self, backup_file: impl AsRef<Path>) -> Result<BackupManifest> {
+        #[cfg(feature = "storage-sqlite")]
+        {
+            let sqlite_db = crate::new_cozo_sqlite_read_only(backup_file)?;
+            let tx = sqlite_db.transact()?;
+            match tx.increment_record()? {
+                Some(record) => Ok(record.manifest),
+                None => tx.backup_manifest(),
+            }
+        }
+        #[cfg(not(feature = "storage-sqlite"))]
+        bail!("backup requires the 'storage-sqlite' feature to be enabled")
+    }
+    /// Backup into an Sqlite file only the relations and indices created or written to since
+    /// the backup with the manifest `base` was taken, together with the catalog, which records
+    /// those dropped since. Returns the manifest of the new backup. The backups are restored
+    /// in order with [Self::restore_chain].
+    #[allow(unused_variables)]
+    pub fn backup_incremental(
+        &'s self,
+        base: &BackupManifest,
+        out_file: impl AsRef<Path>,
+    ) -> Result<BackupManifest> {
+        #[cfg(feature = "storage-sqlite")]
+        {
+            let sqlite_db = crate::new_cozo_sqlite(out_file)?;
+            if sqlite_db.relation_store_id.load(Ordering::SeqCst) != 0 {
+                bail!("Cannot create backup: data exists in the target database.");
+            }
+            let tx = self.transact()?;
+            let manifest = tx.backup_manifest()?;
+            let mut ranges = vec![relation_range(RelationId::SYSTEM)];
+            for (name, version) in &manifest.relations {
+                if base.relations.get(name) != Some(version) {
+                    ranges.push(relation_range(RelationId::new(version.id)));
+                }
+            }
+            let record = IncrementRecord {
+                base: base.clone(),
+                manifest: manifest.clone(),
+            };
+            let mut record_val = vec![];
+            record
+                .serialize(&mut Serializer::new(&mut record_val).with_struct_map())
+                .unwrap();
+            let pairs = ranges
+                .iter()
+                .flat_map(|(lower, upper)| tx.store_tx.range_scan(lower, upper))
+                .chain(iter::once(Ok((increment_record_key(), record_val))));
+            sqlite_db.db.batch_put(Box::new(pairs))?;
+            Ok(manifest)
+        }
+        #[cfg(not(feature = "storage-sqlite"))]
+        bail!("backup requires the 'storage-sqlite' feature to be enabled")
+    }
+    /// Restore a full backup followed by increments taken with [Self::backup_incremental],
+    /// each against the backup before it in `backup_files`. As for [Self::restore_backup],
+    /// the database must be empty. Each increment is applied in its own transaction.
+    #[allow(unused_variables)]
+    pub fn restore_chain(&'s self, backup_files: &[impl AsRef<Path>]) -> Result<()> {
+        self.ensure_writable()?;
+        #[cfg(feature = "storage-sqlite")]
+        {
+            let (base_file, increments) = match backup_files.split_first() {
+                Some(split) => split,
+                None => bail!("Cannot restore an empty chain of backups"),
+            };
+            let mut state = {
+                let base_db = crate::new_cozo_sqlite_read_only(base_file)?;
+                let tx = base_db.transact()?;
+                if tx.increment_record()?.is_some() {
+                    bail!(BackupChainBase(
+                        base_file.as_ref().to_string_lossy().to_string()
+                    ))
+                }
+                tx.backup_manifest()?
+            };
+            self.restore_backup(base_file)?;
+
+            let record_key = increment_record_key();
+            for file in increments {
+                let increment_db = crate::new_cozo_sqlite_read_only(file)?;
+                let src_tx = increment_db.transact()?;
+                let record = match src_tx.increment_record()? {
+                    Some(record) if record.base == state => record,
+                    _ => bail!(BackupChainOrder(
+                        file.as_ref().to_string_lossy().to_string()
+                    )),
+                };
+                // written directly, so that the change counters are those of the increment
+                let mut tx = self.db.transact(true)?;
+                let mut to_clear = vec![relation_range(RelationId::SYSTEM)];
+                for (name, version) in &state.relations {
+                    if record.manifest.relations.get(name) != Some(version) {
+                        to_clear.push(relation_range(RelationId::new(version.id)));
+                    }
+                }
+                for (lower, upper) in to_clear {
+                    let keys: Vec<Vec<u8>> = tx
+                        .range_scan(&lower, &upper)
+                        .map_ok(|(k, _)| k)
+                        .try_collect()?;
+                    for key in keys {
+                        tx.del(&key)?;
+                    }
+                }
+                for kv in src_tx.store_tx.total_scan() {
+                    let (k, v) = kv?;
+                    if k != record_key {
+                        tx.put(&k, &v)?;
+                    }
+                }
+                tx.commit()?;
+                state = record.manifest;
+            }
+            // new relations must not take the ids of the restored ones
+            self.load_last_ids()
+        }
+        #[cfg(not(feature = "storage-sqlite"))]
+        bail!("backup requires the 'storage-sqlite' feature to be enabled")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{new_cozo_mem, Db, Storage};
+
+    #[cfg(feature = "storage-sqlite")]
+    #[test]
+    fn test_incremental_backup() {
+        fn temp_path() -> std::path::PathBuf {
+            std::env::temp_dir().join(format!("cozo-test-{}.db", rand::random::<u64>()))
+        }
+        fn rows<'s, S: Storage<'s>>(db: &'s Db<S>, query: &str) -> serde_json::Value {
+            db.run_script(query, Default::default())
+                .unwrap()
+                .into_json()["rows"]
+                .clone()
+        }
+
+        let db_path = temp_path();
+        let db = crate::new_cozo_sqlite(&db_path).unwrap();
+        db.run_script(
+            r"
+        {?[k, v] <- [[1, 'a'], [2, 'b']] :create a {k => v}}
+        {?[k, v] <- [[1, 'x']] :create b {k => v}}
+        {?[k] <- [[1]] :create c {k}}
+        ",
+            Default::default(),
+        )
+        .unwrap();
+        let full = temp_path();
+        db.backup_db(&full).unwrap();
+        let m0 = db.backup_manifest(&full).unwrap();
+        assert_eq!(m0.relations.len(), 3);
+
+        db.run_script(
+            r"
+        {?[k, v] <- [[3, 'c']] :put a {k => v}}
+        {?[k] <- [[7]] :create d {k}}
+        ",
+            Default::default(),
+        )
+        .unwrap();
+        db.run_script("::remove c", Default::default()).unwrap();
+        let inc1 = temp_path();
+        let m1 = db.backup_incremental(&m0, &inc1).unwrap();
+        assert!(m1.relations["a"].changes > m0.relations["a"].changes);
+        assert_eq!(m1.relations["b"], m0.relations["b"]);
+        assert!(!m1.relations.contains_key("c"));
+        // only the relations changed are dumped
+        let increment = crate::new_cozo_sqlite(&inc1).unwrap();
+        assert_eq!(rows(&increment, "?[k] := *a{k}"), json!([[1], [2], [3]]));
+        assert_eq!(rows(&increment, "?[k] := *b{k}"), json!([]));
+        assert_eq!(db.backup_manifest(&inc1).unwrap(), m1);
+
+        db.run_script(
+            "?[k, v] <- [[5, 'y']] :replace b {k => v}",
+            Default::default(),
+        )
+        .unwrap();
+        let inc2 = temp_path();
+        let m2 = db.backup_incremental(&m1, &inc2).unwrap();
+        assert_ne!(m2.relations["b"].id, m1.relations["b"].id);
+
+        // the change counters survive restarts
+        drop(db);
+        let db = crate::new_cozo_sqlite(&db_path).unwrap();
+        assert_eq!(db.backup_incremental(&m2, temp_path()).unwrap(), m2);
+
+        let restored = new_cozo_mem().unwrap();
+        restored.restore_chain(&[&full, &inc1, &inc2]).unwrap();
+        for query in [
+            "?[k, v] := *a{k, v}",
+            "?[k, v] := *b{k, v}",
+            "?[k] := *d{k}",
+        ] {
+            assert_eq!(rows(&restored, query), rows(&db, query));
+        }
+        assert_eq!(rows(&restored, "?[k, v] := *b{k, v}"), json!([[5, "y"]]));
+        assert!(restored
+            .run_script("?[k] := *c{k}", Default::default())
+            .is_err());
+        restored
+            .run_script(":create e {k}", Default::default())
+            .unwrap();
+
+        let err = new_cozo_mem()
+            .unwrap()
+            .restore_chain(&[&full, &inc2, &inc1])
+            .unwrap_err();
+        assert_eq!(err.code().unwrap().to_string(), "eval::backup_chain_order");
+        let err = new_cozo_mem()
+            .unwrap()
+            .restore_chain(&[&inc1, &inc2])
+            .unwrap_err();
+        assert_eq!(err.code().unwrap().to_string(), "eval::backup_chain_base");
+    }
+}
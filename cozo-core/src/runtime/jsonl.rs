@@ -0,0 +1,271 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::iter;
+
+use itertools::Itertools;
+use miette::{bail, Diagnostic, IntoDiagnostic, Result};
+use smartstring::SmartString;
+use thiserror::Error;
+
+use crate::data::json::JsonValue;
+use crate::data::relation::ColumnDef;
+use crate::data::tuple::Tuple;
+use crate::data::value::DataValue;
+use crate::runtime::db::{Db, ImportIntoIndex};
+use crate::runtime::relation::{AccessLevel, InsufficientAccessLevel};
+use crate::storage::Storage;
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Cannot {0} JSON Lines file '{1}': {2}")]
+#[diagnostic(code(eval::jsonl_io))]
+struct JsonlFileError(&'static str, String, String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Cannot import line {0}: {1}")]
+#[diagnostic(code(import::bad_jsonl))]
+struct BadJsonLine(usize, String);
+
+/// Opens the JSON Lines file at `path` for [Db::import_relations_jsonl]
+pub(crate) fn open_jsonl(path: &str) -> Result<BufReader<File>> {
+    let file = File::open(path)
+        .map_err(|err| JsonlFileError("read", path.to_string(), err.to_string()))?;
+    Ok(BufReader::new(file))
+}
+
+/// Creates the JSON Lines file at `path` for [Db::export_relations_jsonl],
+/// replacing it if it exists
+pub(crate) fn create_jsonl(path: &str) -> Result<BufWriter<File>> {
+    let file = File::create(path)
+        .map_err(|err| JsonlFileError("write", path.to_string(), err.to_string()))?;
+    Ok(BufWriter::new(file))
+}
+
+impl<'s, S: Storage<'s>> Db<S> {
+    /// Export a stored relation as JSON Lines: each row is written to `out` as a JSON object
+    /// keyed by the names of the columns, on its own line. Rows are written as they are read,
+    /// so relations of any size can be exported. Returns the number of rows written.
+    pub fn export_relations_jsonl(&'s self, relation: &str, mut out: impl Write) -> Result<usize> {
+        let tx = self.transact()?;
+        let handle = tx.get_relation(relation, false)?;
+        if handle.access_level < AccessLevel::ReadOnly {
+            bail!(InsufficientAccessLevel(
+                handle.name.to_string(),
+                "data export".to_string(),
+                handle.access_level
+            ));
+        }
+        let names = handle
+            .metadata
+            .keys
+            .iter()
+            .chain(handle.metadata.non_keys.iter())
+            .map(|col| col.name.to_string())
+            .collect_vec();
+        let expiry = handle.expiry(&tx);
+        let mut n = 0;
+        for row in handle.scan_all(&tx) {
+            let row = row?;
+            if !expiry.is_live(&row) {
+                continue;
+            }
+            let obj: serde_json::Map<String, JsonValue> = names
+                .iter()
+                .cloned()
+                .zip(row.into_iter().map(JsonValue::from))
+                .collect();
+            serde_json::to_writer(&mut out, &obj).into_diagnostic()?;
+            out.write_all(b"\n").into_diagnostic()?;
+            n += 1;
+        }
+        out.flush().into_diagnostic()?;
+        Ok(n)
+    }
+    /// Import rows written by [Self::export_relations_jsonl] into a stored relation,
+    /// in a single transaction. Rows are read one line at a time, so inputs of any size
+    /// can be imported. The values are coerced to the types of the columns as JSON data
+    /// given to queries is, e.g. bytes are given in base64, and missing columns take their
+    /// defaults, or null. Rows replace stored rows with the same keys, and triggers are
+    /// not run. Returns the number of rows imported.
+    pub fn import_relations_jsonl(&'s self, relation: &str, input: impl BufRead) -> Result<usize> {
+        if relation.contains(':') {
+            bail!(ImportIntoIndex(relation.to_string()))
+        }
+        let rel_name = SmartString::from(relation);
+        let lock = self
+            .obtain_relation_locks(iter::once(&rel_name))
+            .pop()
+            .unwrap();
+        let _guard = lock.read().unwrap();
+        let cur_vld = self.clock.current_validity();
+        let mut tx = self.transact_write()?;
+        let handle = tx.get_relation(relation, false)?;
+        if handle.access_level < AccessLevel::Protected {
+            bail!(InsufficientAccessLevel(
+                handle.name.to_string(),
+                "data import".to_string(),
+                handle.access_level
+            ));
+        }
+        let foreign_keys = tx.foreign_keys(&handle)?;
+        let columns: Vec<&ColumnDef> = handle
+            .metadata
+            .keys
+            .iter()
+            .chain(handle.metadata.non_keys.iter())
+            .collect_vec();
+
+        let mut n = 0;
+        for (i, line) in input.lines().enumerate() {
+            let line_no = i + 1;
+            let line = line.map_err(|err| BadJsonLine(line_no, err.to_string()))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut obj: serde_json::Map<String, JsonValue> = serde_json::from_str(&line)
+                .map_err(|err| BadJsonLine(line_no, format!("expected a JSON object: {err}")))?;
+            let row: Tuple = columns
+                .iter()
+                .map(|col| -> Result<DataValue> {
+                    let value = match obj.remove(&col.name as &str) {
+                        Some(v) => DataValue::from(v),
+                        None => match &col.default_gen {
+                            Some(expr) => expr.clone().eval_to_const()?,
+                            None => DataValue::Null,
+                        },
+                    };
+                    col.typing.coerce(value, cur_vld).map_err(|err| {
+                        BadJsonLine(line_no, format!("column '{}': {}", col.name, err)).into()
+                    })
+                })
+                .try_collect()?;
+            if let Some(unknown) = obj.keys().next() {
+                bail!(BadJsonLine(
+                    line_no,
+                    format!("no column '{}' in relation '{}'", unknown, handle.name)
+                ))
+            }
+            tx.put_imported_row(&handle, &foreign_keys, &row)?;
+            n += 1;
+        }
+        tx.commit_tx()?;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+    use serde_json::json;
+
+    use crate::data::value::DataValue;
+    use crate::DbInstance;
+
+    #[test]
+    fn test_jsonl_import_export() {
+        let db = DbInstance::new("mem", "", "").unwrap();
+        db.run_script(
+            r"
+        {:create src {k: Int => f: Float, b: Bytes?, s: String?, l: [Int]}}
+        {
+            ?[k, f, b, s, l] <- [[1, 2, 'AAEC', 'héllo\nwörld', [1, 2]],
+                                 [2, 0.1, null, null, []]]
+            :put src {k => f, b, s, l}
+        }
+        {:create dst {k: Int => f: Float, b: Bytes?, s: String?, l: [Int], extra: Int default 7}}
+        ",
+            Default::default(),
+        )
+        .unwrap();
+
+        let mut out = vec![];
+        assert_eq!(db.export_relations_jsonl("src", &mut out).unwrap(), 2);
+        let text = String::from_utf8(out.clone()).unwrap();
+        let lines = text.lines().collect_vec();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(lines[0]).unwrap(),
+            json!({"k": 1, "f": 2.0, "b": "AAEC", "s": "héllo\nwörld", "l": [1, 2]})
+        );
+        assert_eq!(db.import_relations_jsonl("dst", &out[..]).unwrap(), 2);
+        let res = db
+            .run_script(
+                "?[k, f, b, s, l, extra] := *dst{k, f, b, s, l, extra}",
+                Default::default(),
+            )
+            .unwrap();
+        assert_eq!(
+            res.rows[0],
+            vec![
+                DataValue::from(1),
+                DataValue::from(2.),
+                DataValue::Bytes(vec![0, 1, 2]),
+                DataValue::from("héllo\nwörld"),
+                DataValue::List(vec![DataValue::from(1), DataValue::from(2)]),
+                DataValue::from(7)
+            ]
+        );
+        assert_eq!(res.rows[1][3], DataValue::Null);
+
+        // failures give the line, and nothing is imported
+        let bad = "{\"k\": 3, \"f\": 1, \"l\": []}\n\n{\"k\": 4, \"f\": \"x\", \"l\": []}\n";
+        let err = db
+            .import_relations_jsonl("dst", bad.as_bytes())
+            .unwrap_err();
+        assert!(err.to_string().contains("line 3"), "{err}");
+        let bad = "{\"k\": 3, \"f\": 1, \"l\": [], \"color\": 1}\n";
+        let err = db
+            .import_relations_jsonl("dst", bad.as_bytes())
+            .unwrap_err();
+        assert!(err.to_string().contains("'color'"), "{err}");
+        let bad = "[3, 1, []]\n";
+        assert!(db.import_relations_jsonl("dst", bad.as_bytes()).is_err());
+        let res = db
+            .run_script("?[count(k)] := *dst{k}", Default::default())
+            .unwrap();
+        assert_eq!(res.rows, vec![vec![DataValue::from(2)]]);
+
+        // the same through scripts and the string API, with files
+        let path = std::env::temp_dir().join(format!("cozo-test-{}.jsonl", rand::random::<u64>()));
+        let path = path.to_string_lossy().to_string();
+        let res = db
+            .run_script(
+                &format!("::export_jsonl src to '{path}'"),
+                Default::default(),
+            )
+            .unwrap();
+        assert_eq!(res.rows, vec![vec![DataValue::from(2)]]);
+        db.run_script("::remove dst", Default::default()).unwrap();
+        db.run_script(
+            "{:create dst {k: Int => f: Float, b: Bytes?, s: String?, l: [Int]}}",
+            Default::default(),
+        )
+        .unwrap();
+        let res = db
+            .run_script(
+                &format!("::import_jsonl dst from '{path}'"),
+                Default::default(),
+            )
+            .unwrap();
+        assert_eq!(res.rows, vec![vec![DataValue::from(2)]]);
+        let payload = json!({"relation": "dst", "path": path}).to_string();
+        let res: serde_json::Value =
+            serde_json::from_str(&db.import_relations_jsonl_str(&payload)).unwrap();
+        assert_eq!(res, json!({"ok": true, "imported": 2}));
+        let res: serde_json::Value =
+            serde_json::from_str(&db.export_relations_jsonl_str(&payload)).unwrap();
+        assert_eq!(res, json!({"ok": true, "exported": 2}));
+        assert_eq!(std::fs::read(&path).unwrap(), out);
+        let _ = std::fs::remove_file(&path);
+        let res: serde_json::Value =
+            serde_json::from_str(&db.import_relations_jsonl_str(&payload)).unwrap();
+        assert_eq!(res["ok"], json!(false));
+    }
+}
@@ -0,0 +1,800 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! MinHash LSH indices over the string columns of stored relations, made by `::lsh create`
+//! and searched by `~rel:idx{..}` atoms for the rows whose texts are near duplicates of
+//! a query text.
+//!
+//! The shingles of a text are its runs of `n_grams` characters, and the similarity of two
+//! texts is the Jaccard similarity of their shingles. The signature of a row holds the
+//! smallest hash of its shingles under each of `n_perm` hash functions, two signatures having
+//! the same value at a position with the probability of the similarity of their texts.
+//! Signatures are cut into bands, hashed into buckets, and rows sharing a bucket with the
+//! query are the candidates, kept if the similarity estimated from their signatures is high
+//! enough. The number of bands is chosen so that few rows less similar than the target
+//! threshold of the index are candidates, and few rows more similar are missed.
+//!
+//! The index is kept in a hidden relation `rel:idx`, keyed by the band and the bucket
+//! followed by the keys of the row, the signatures of the rows being kept under band -1.
+
+use std::collections::BTreeSet;
+
+use itertools::Itertools;
+use miette::{bail, Diagnostic, Result};
+use smartstring::{LazyCompact, SmartString};
+use thiserror::Error;
+
+use crate::data::relation::{ColType, ColumnDef, NullableColType, StoredRelationMetadata};
+use crate::data::symb::Symbol;
+use crate::data::tuple::{Tuple, TupleT};
+use crate::data::value::DataValue;
+use crate::parse::SourceSpan;
+use crate::runtime::relation::{InputRelationHandle, RelationHandle};
+use crate::runtime::transact::SessionTx;
+
+/// The modulus of the hash functions, the Mersenne prime 2^61 - 1
+const MERSENNE_PRIME: u64 = (1 << 61) - 1;
+
+/// The band under which the signatures of the rows are kept
+const SIGNATURE_BAND: i64 = -1;
+
+/// The parameters of an LSH index, kept with the relation it indexes
+#[derive(Clone, Debug, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) struct LshIndexManifest {
+    pub(crate) n_grams: usize,
+    pub(crate) n_perm: usize,
+    /// the number of bands the signatures are cut into
+    pub(crate) bands: usize,
+    /// the number of signature values in each band
+    pub(crate) rows: usize,
+    /// the similarity of the rows found by searches not giving one
+    pub(crate) threshold: f64,
+    /// the names of the indexed columns
+    pub(crate) fields: Vec<SmartString<LazyCompact>>,
+    /// the positions of the indexed columns in the rows, keys followed by values
+    pub(crate) field_positions: Vec<usize>,
+}
+
+impl Eq for LshIndexManifest {}
+
+/// Options of `::lsh create`, given as `{fields: [body], n_grams: 5, n_perm: 128, target_threshold: 0.7}`
+#[derive(Debug, Clone)]
+pub(crate) struct LshOptions {
+    pub(crate) n_grams: usize,
+    pub(crate) n_perm: usize,
+    pub(crate) target_threshold: f64,
+    pub(crate) fields: Vec<Symbol>,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Bad option '{0}' for LSH index")]
+#[diagnostic(code(parser::bad_lsh_option))]
+#[diagnostic(help("{1}"))]
+struct BadLshOption(String, String, #[label] SourceSpan);
+
+impl LshOptions {
+    /// The options with the given values, the fields being required
+    pub(crate) fn new(
+        given: Vec<(String, DataValue, SourceSpan)>,
+        fields: Vec<Symbol>,
+        span: SourceSpan,
+    ) -> Result<Self> {
+        let mut ret = Self {
+            n_grams: 5,
+            n_perm: 128,
+            target_threshold: 0.7,
+            fields,
+        };
+        for (name, value, span) in given {
+            let bad = |help: &str| BadLshOption(name.clone(), help.to_string(), span);
+            match &name as &str {
+                "n_grams" => match value.get_int() {
+                    Some(n) if n >= 1 => ret.n_grams = n as usize,
+                    _ => bail!(bad("'n_grams' must be a positive integer")),
+                },
+                "n_perm" => match value.get_int() {
+                    Some(n) if (1..=1024).contains(&n) => ret.n_perm = n as usize,
+                    _ => bail!(bad("'n_perm' must be an integer between 1 and 1024")),
+                },
+                "target_threshold" => match value.get_float() {
+                    Some(t) if t > 0. && t < 1. => ret.target_threshold = t,
+                    _ => bail!(bad("'target_threshold' must be a number between 0 and 1")),
+                },
+                _ => bail!(bad(
+                    "The options are 'n_grams', 'n_perm', 'target_threshold' and 'fields'"
+                )),
+            }
+        }
+        if ret.fields.is_empty() {
+            bail!(BadLshOption(
+                "fields".to_string(),
+                "The indexed columns must be given as 'fields: [..]'".to_string(),
+                span,
+            ))
+        }
+        Ok(ret)
+    }
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("LSH index {1} for relation {0} not found")]
+#[diagnostic(code(query::lsh_index_not_found))]
+pub(crate) struct LshIndexNotFound(
+    pub(crate) String,
+    pub(crate) String,
+    #[label] pub(crate) SourceSpan,
+);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error(
+    "Column '{column}' of the row with keys {keys:?} has {value:?}, \
+which cannot be indexed by LSH index '{relation}:{index}'"
+)]
+#[diagnostic(code(eval::lsh_not_a_string))]
+#[diagnostic(help("The indexed columns hold strings, or null for rows not indexed"))]
+struct LshNotAString {
+    relation: String,
+    index: String,
+    column: String,
+    keys: Tuple,
+    value: DataValue,
+}
+
+/// The probability that a row with the given similarity to the query shares a bucket with it
+fn candidate_probability(similarity: f64, bands: usize, rows: usize) -> f64 {
+    1. - (1. - similarity.powi(rows as i32)).powi(bands as i32)
+}
+
+/// The integral of the function over the interval, by the midpoint rule
+fn integrate(f: impl Fn(f64) -> f64, lower: f64, upper: f64) -> f64 {
+    const STEPS: usize = 100;
+    let step = (upper - lower) / STEPS as f64;
+    (0..STEPS)
+        .map(|i| f(lower + (i as f64 + 0.5) * step) * step)
+        .sum()
+}
+
+/// The numbers of bands and of rows in each band minimizing the chances of rows less
+/// similar than the threshold being candidates, and of rows more similar not being ones
+fn optimal_bands(n_perm: usize, threshold: f64) -> (usize, usize) {
+    let mut best = (1, n_perm);
+    let mut best_error = f64::INFINITY;
+    for bands in 1..=n_perm {
+        for rows in 1..=n_perm / bands {
+            let false_positives =
+                integrate(|s| candidate_probability(s, bands, rows), 0., threshold);
+            let false_negatives = integrate(
+                |s| 1. - candidate_probability(s, bands, rows),
+                threshold,
+                1.,
+            );
+            let error = (false_positives + false_negatives) / 2.;
+            if error < best_error {
+                best_error = error;
+                best = (bands, rows);
+            }
+        }
+    }
+    best
+}
+
+/// The FNV-1a hash of the bytes, which stays the same across builds, unlike the hashers
+/// of the standard library
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// The next value of the SplitMix64 generator, giving the coefficients of the hash functions
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// The coefficients `(a, b)` of the hash functions `x -> (a * x + b) mod p`
+fn permutations(n_perm: usize) -> Vec<(u64, u64)> {
+    let mut state = 0;
+    (0..n_perm)
+        .map(|_| {
+            let a = splitmix64(&mut state) % (MERSENNE_PRIME - 1) + 1;
+            let b = splitmix64(&mut state) % MERSENNE_PRIME;
+            (a, b)
+        })
+        .collect()
+}
+
+/// Adds the runs of `n` characters of the text to the shingles, texts shorter than that
+/// being a shingle by themselves
+fn add_shingles(text: &str, n: usize, shingles: &mut BTreeSet<String>) {
+    let chars = text.chars().collect_vec();
+    if chars.is_empty() {
+        return;
+    }
+    if chars.len() <= n {
+        shingles.insert(text.to_string());
+    } else {
+        shingles.extend(chars.windows(n).map(|w| w.iter().collect::<String>()));
+    }
+}
+
+impl LshIndexManifest {
+    /// The signature of the shingles, `None` if there are none
+    fn signature(&self, shingles: &BTreeSet<String>) -> Option<Vec<u64>> {
+        if shingles.is_empty() {
+            return None;
+        }
+        let hashes = shingles
+            .iter()
+            .map(|s| fnv1a(s.as_bytes()) % MERSENNE_PRIME)
+            .collect_vec();
+        Some(
+            permutations(self.n_perm)
+                .into_iter()
+                .map(|(a, b)| {
+                    hashes
+                        .iter()
+                        .map(|x| {
+                            ((a as u128 * *x as u128 + b as u128) % MERSENNE_PRIME as u128) as u64
+                        })
+                        .min()
+                        .unwrap()
+                })
+                .collect(),
+        )
+    }
+    /// The buckets of the signature, one for each band
+    fn buckets(&self, signature: &[u64]) -> Vec<i64> {
+        signature
+            .chunks(self.rows)
+            .take(self.bands)
+            .map(|band| {
+                let bytes = band.iter().flat_map(|v| v.to_be_bytes()).collect_vec();
+                fnv1a(&bytes) as i64
+            })
+            .collect()
+    }
+}
+
+/// The estimated similarity of the texts with the signatures
+fn similarity(a: &[u64], b: &[u64]) -> f64 {
+    a.iter().zip(b).filter(|(x, y)| x == y).count() as f64 / a.len() as f64
+}
+
+fn encode_signature(signature: &[u64]) -> DataValue {
+    DataValue::Bytes(signature.iter().flat_map(|v| v.to_be_bytes()).collect())
+}
+
+fn decode_signature(v: &DataValue) -> Vec<u64> {
+    match v {
+        DataValue::Bytes(b) => b
+            .chunks_exact(8)
+            .map(|c| u64::from_be_bytes(c.try_into().unwrap()))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// The signature of the indexed columns of a row, given with keys and values, `None` if
+/// they have no shingles
+fn row_signature(
+    handle: &RelationHandle,
+    idx_name: &str,
+    manifest: &LshIndexManifest,
+    row: &[DataValue],
+) -> Result<Option<Vec<u64>>> {
+    let mut shingles = BTreeSet::new();
+    for (field_pos, field) in manifest.field_positions.iter().zip(&manifest.fields) {
+        match &row[*field_pos] {
+            DataValue::Null => {}
+            DataValue::Str(s) => add_shingles(s, manifest.n_grams, &mut shingles),
+            value => bail!(LshNotAString {
+                relation: handle.name.to_string(),
+                index: idx_name.to_string(),
+                column: field.to_string(),
+                keys: row[..handle.metadata.keys.len()].to_vec(),
+                value: value.clone(),
+            }),
+        }
+    }
+    Ok(manifest.signature(&shingles))
+}
+
+/// The signature of the text of a search, `None` if it has no shingles
+pub(crate) fn lsh_query_signature(
+    v: DataValue,
+    manifest: &LshIndexManifest,
+    span: SourceSpan,
+) -> Result<Option<Vec<u64>>> {
+    #[derive(Debug, Error, Diagnostic)]
+    #[error("The query of an LSH search must be a string, got {0:?}")]
+    #[diagnostic(code(eval::lsh_bad_query))]
+    struct LshBadQuery(DataValue, #[label] SourceSpan);
+
+    let text = match v.get_str() {
+        Some(text) => text,
+        None => bail!(LshBadQuery(v, span)),
+    };
+    let mut shingles = BTreeSet::new();
+    add_shingles(text, manifest.n_grams, &mut shingles);
+    Ok(manifest.signature(&shingles))
+}
+
+impl<'a> SessionTx<'a> {
+    pub(crate) fn create_lsh_index(
+        &mut self,
+        rel_name: &Symbol,
+        idx_name: &Symbol,
+        options: LshOptions,
+    ) -> Result<()> {
+        let mut rel_handle = self.get_relation(rel_name, true)?;
+        if rel_handle.is_temp {
+            #[derive(Debug, Error, Diagnostic)]
+            #[error("Temp relation {0} cannot have LSH indices")]
+            #[diagnostic(code(eval::lsh_in_temp_relation))]
+            struct LshInTempRelation(String);
+
+            bail!(LshInTempRelation(rel_handle.name.to_string()))
+        }
+        if rel_handle.has_index(&idx_name.name) {
+            #[derive(Debug, Error, Diagnostic)]
+            #[error("index {0} for relation {1} already exists")]
+            #[diagnostic(code(tx::index_already_exists))]
+            struct IndexAlreadyExists(String, String);
+
+            bail!(IndexAlreadyExists(
+                idx_name.name.to_string(),
+                rel_name.name.to_string()
+            ));
+        }
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("column {0} in LSH index {1} for relation {2} cannot hold strings")]
+        #[diagnostic(code(tx::bad_lsh_column))]
+        #[diagnostic(help("Indexed columns must exist and be of type 'String' or 'Any'"))]
+        struct BadLshColumn(String, String, String, #[label] SourceSpan);
+
+        let mut field_positions = vec![];
+        for field in options.fields.iter() {
+            let found = rel_handle
+                .metadata
+                .keys
+                .iter()
+                .chain(rel_handle.metadata.non_keys.iter())
+                .find_position(|col| col.name == field.name);
+            match found {
+                Some((pos, col))
+                    if matches!(col.typing.coltype, ColType::Any | ColType::String) =>
+                {
+                    field_positions.push(pos)
+                }
+                _ => bail!(BadLshColumn(
+                    field.name.to_string(),
+                    idx_name.name.to_string(),
+                    rel_name.name.to_string(),
+                    field.span
+                )),
+            }
+        }
+        let (bands, rows) = optimal_bands(options.n_perm, options.target_threshold);
+        let manifest = LshIndexManifest {
+            n_grams: options.n_grams,
+            n_perm: options.n_perm,
+            bands,
+            rows,
+            threshold: options.target_threshold,
+            fields: options.fields.iter().map(|f| f.name.clone()).collect(),
+            field_positions,
+        };
+
+        let col = |name: String, coltype: ColType, nullable: bool| ColumnDef {
+            name: name.into(),
+            typing: NullableColType { coltype, nullable },
+            default_gen: None,
+            auto_update: None,
+            unique: false,
+        };
+        let mut keys = vec![
+            col("band".to_string(), ColType::Int, false),
+            col("bucket".to_string(), ColType::Int, false),
+        ];
+        for key in rel_handle.metadata.keys.iter() {
+            keys.push(col(format!("k_{}", key.name), ColType::Any, false));
+        }
+        let non_keys = vec![col("signature".to_string(), ColType::Bytes, true)];
+        let key_bindings = keys
+            .iter()
+            .map(|col| Symbol::new(col.name.clone(), Default::default()))
+            .collect_vec();
+        let dep_bindings = non_keys
+            .iter()
+            .map(|col| Symbol::new(col.name.clone(), Default::default()))
+            .collect_vec();
+        let idx_handle = self.create_relation(InputRelationHandle {
+            name: Symbol::new(
+                format!("{}:{}", rel_name.name, idx_name.name),
+                Default::default(),
+            ),
+            metadata: StoredRelationMetadata { keys, non_keys },
+            key_bindings,
+            dep_bindings,
+            span: Default::default(),
+            params: Default::default(),
+        })?;
+
+        for row in rel_handle.scan_all(self).collect_vec() {
+            let row = row?;
+            self.lsh_put_row(&rel_handle, &idx_name.name, &idx_handle, &manifest, &row)?;
+        }
+
+        rel_handle
+            .lsh_indices
+            .insert(idx_name.name.clone(), (idx_handle, manifest));
+        self.put_relation_meta(&rel_handle)?;
+        self.bump_schema_generation()?;
+        Ok(())
+    }
+
+    /// Removes the index, returning the range of its buckets, to be cleared at the end
+    /// of the transaction.
+    pub(crate) fn remove_lsh_index(
+        &mut self,
+        rel_name: &Symbol,
+        idx_name: &Symbol,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut rel = self.get_relation(rel_name, true)?;
+        if rel.lsh_indices.remove(&idx_name.name).is_none() {
+            bail!(LshIndexNotFound(
+                rel_name.name.to_string(),
+                idx_name.name.to_string(),
+                idx_name.span
+            ))
+        }
+        let cleared = self.destroy_relation(&format!("{}:{}", rel_name.name, idx_name.name))?;
+        self.put_relation_meta(&rel)?;
+        self.bump_schema_generation()?;
+        Ok(cleared)
+    }
+
+    /// Builds the buckets of the index anew from the rows of the relation
+    pub(crate) fn rebuild_lsh_index(&mut self, rel_name: &Symbol, idx_name: &Symbol) -> Result<()> {
+        let rel = self.get_relation(rel_name, true)?;
+        let (idx_rel, manifest) = rel.lsh_indices.get(&idx_name.name).ok_or_else(|| {
+            LshIndexNotFound(
+                rel_name.name.to_string(),
+                idx_name.name.to_string(),
+                idx_name.span,
+            )
+        })?;
+        let lower = Tuple::default().encode_as_key(idx_rel.id);
+        let upper = Tuple::default().encode_as_key(idx_rel.id.next());
+        for kv in self.store_tx.range_scan(&lower, &upper).collect_vec() {
+            let (k, _) = kv?;
+            self.store_tx.del(&k)?;
+        }
+        for row in rel.scan_all(self).collect_vec() {
+            let row = row?;
+            self.lsh_put_row(&rel, &idx_name.name, idx_rel, manifest, &row)?;
+        }
+        Ok(())
+    }
+
+    /// Adds the signature of a row, given with keys and values, to the LSH indices
+    pub(crate) fn put_into_lsh_indices(
+        &mut self,
+        handle: &RelationHandle,
+        row: &[DataValue],
+    ) -> Result<()> {
+        for (idx_name, (idx_rel, manifest)) in handle.lsh_indices.iter() {
+            self.lsh_put_row(handle, idx_name, idx_rel, manifest, row)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the signature of a row, given with keys and values, from the LSH indices
+    pub(crate) fn delete_from_lsh_indices(
+        &mut self,
+        handle: &RelationHandle,
+        row: &[DataValue],
+    ) -> Result<()> {
+        for (idx_name, (idx_rel, manifest)) in handle.lsh_indices.iter() {
+            self.lsh_del_row(handle, idx_name, idx_rel, manifest, row)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the signature of a row from its old image, if there is one, to its new image,
+    /// leaving the indices whose columns did not change alone
+    pub(crate) fn reindex_lsh_row(
+        &mut self,
+        handle: &RelationHandle,
+        old: Option<&Tuple>,
+        new: &Tuple,
+    ) -> Result<()> {
+        for (idx_name, (idx_rel, manifest)) in handle.lsh_indices.iter() {
+            if let Some(old) = old {
+                if manifest.field_positions.iter().all(|p| old[*p] == new[*p]) {
+                    continue;
+                }
+                self.lsh_del_row(handle, idx_name, idx_rel, manifest, old)?;
+            }
+            self.lsh_put_row(handle, idx_name, idx_rel, manifest, new)?;
+        }
+        Ok(())
+    }
+
+    /// The rows of the relation whose estimated similarity to the text with the signature
+    /// is at least `min_similarity`, with their similarities, highest first, rows with the
+    /// same similarity being ordered by their keys
+    pub(crate) fn lsh_search(
+        &self,
+        base: &RelationHandle,
+        idx_rel: &RelationHandle,
+        manifest: &LshIndexManifest,
+        signature: &[u64],
+        min_similarity: f64,
+    ) -> Result<Vec<(f64, Tuple)>> {
+        let n_keys = base.metadata.keys.len();
+        // keys are only mutable to clippy for the regex a `DataValue` may hold
+        #[allow(clippy::mutable_key_type)]
+        let mut candidates = BTreeSet::new();
+        for (band, bucket) in manifest.buckets(signature).into_iter().enumerate() {
+            let prefix = vec![DataValue::from(band as i64), DataValue::from(bucket)];
+            for row in idx_rel.scan_prefix(self, &prefix) {
+                candidates.insert(row?[2..2 + n_keys].to_vec());
+            }
+        }
+
+        let mut found = vec![];
+        for keys in candidates {
+            let mut sig_key = vec![DataValue::from(SIGNATURE_BAND), DataValue::from(0)];
+            sig_key.extend_from_slice(&keys);
+            let sim = match idx_rel.get(self, &sig_key)? {
+                None => continue,
+                Some(row) => similarity(signature, &decode_signature(&row[2 + n_keys])),
+            };
+            if sim >= min_similarity {
+                found.push((sim, keys));
+            }
+        }
+        found.sort_by(|(a, a_keys), (b, b_keys)| b.total_cmp(a).then_with(|| a_keys.cmp(b_keys)));
+
+        let expiry = base.expiry(self);
+        let mut ret = vec![];
+        for (sim, keys) in found {
+            if let Some(row) = base.get(self, &keys)? {
+                if expiry.is_live(&row) {
+                    ret.push((sim, row));
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    fn lsh_put(&mut self, idx_rel: &RelationHandle, row: &Tuple) -> Result<()> {
+        let key = idx_rel.encode_key_for_store(row, Default::default())?;
+        let val = idx_rel.encode_val_for_store(row, Default::default())?;
+        self.store_tx.put(&key, &val)
+    }
+
+    fn lsh_put_row(
+        &mut self,
+        handle: &RelationHandle,
+        idx_name: &str,
+        idx_rel: &RelationHandle,
+        manifest: &LshIndexManifest,
+        row: &[DataValue],
+    ) -> Result<()> {
+        let signature = match row_signature(handle, idx_name, manifest, row)? {
+            None => return Ok(()),
+            Some(signature) => signature,
+        };
+        let keys = &row[..handle.metadata.keys.len()];
+        for (band, bucket) in manifest.buckets(&signature).into_iter().enumerate() {
+            let mut entry = vec![DataValue::from(band as i64), DataValue::from(bucket)];
+            entry.extend_from_slice(keys);
+            entry.push(DataValue::Null);
+            self.lsh_put(idx_rel, &entry)?;
+        }
+        let mut entry = vec![DataValue::from(SIGNATURE_BAND), DataValue::from(0)];
+        entry.extend_from_slice(keys);
+        entry.push(encode_signature(&signature));
+        self.lsh_put(idx_rel, &entry)
+    }
+
+    fn lsh_del_row(
+        &mut self,
+        handle: &RelationHandle,
+        idx_name: &str,
+        idx_rel: &RelationHandle,
+        manifest: &LshIndexManifest,
+        row: &[DataValue],
+    ) -> Result<()> {
+        let signature = match row_signature(handle, idx_name, manifest, row)? {
+            None => return Ok(()),
+            Some(signature) => signature,
+        };
+        let keys = &row[..handle.metadata.keys.len()];
+        let bands = manifest
+            .buckets(&signature)
+            .into_iter()
+            .enumerate()
+            .map(|(band, bucket)| (band as i64, bucket))
+            .chain([(SIGNATURE_BAND, 0)]);
+        for (band, bucket) in bands {
+            let mut entry = vec![DataValue::from(band), DataValue::from(bucket)];
+            entry.extend_from_slice(keys);
+            let key = idx_rel.encode_key_for_store(&entry, Default::default())?;
+            self.store_tx.del(&key)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, BTreeSet};
+
+    use itertools::Itertools;
+    use serde_json::json;
+
+    use crate::data::value::DataValue;
+    use crate::new_cozo_mem;
+
+    #[test]
+    fn test_lsh_index() {
+        let db = new_cozo_mem().unwrap();
+        db.run_script(
+            ":create docs {id: Int => body: String?}",
+            Default::default(),
+        )
+        .unwrap();
+        db.run_script(
+        "::lsh create docs:dedup {fields: [body], n_grams: 5, n_perm: 128, target_threshold: 0.7}",
+        Default::default(),
+    )
+    .unwrap();
+
+        let base =
+            "Cozo is a general-purpose, transactional, relational database that uses Datalog \
+for query, is embeddable but can also handle huge amounts of data and concurrency, and focuses \
+on graph data and algorithms. It supports time travel and it is performant!";
+        // the text with `edits` characters replaced, keyed by the number of edits
+        let variant = |edits: usize| -> String {
+            let mut chars = base.chars().collect_vec();
+            let len = chars.len();
+            for i in 0..edits {
+                chars[(i * 97 + 13) % len] = '#';
+            }
+            chars.into_iter().collect()
+        };
+        let shingles = |text: &str| -> BTreeSet<String> {
+            let chars = text.chars().collect_vec();
+            chars.windows(5).map(|w| w.iter().collect()).collect()
+        };
+        let jaccard = |a: &str, b: &str| -> f64 {
+            let (a, b) = (shingles(a), shingles(b));
+            a.intersection(&b).count() as f64 / a.union(&b).count() as f64
+        };
+        let edit_counts = [0, 1, 2, 3, 5, 8, 12, 20, 30, 50, 80];
+        let rows = edit_counts
+            .iter()
+            .map(|e| {
+                DataValue::List(vec![
+                    DataValue::from(*e as i64),
+                    DataValue::from(variant(*e)),
+                ])
+            })
+            .collect_vec();
+        db.run_script(
+            "?[id, body] <- $rows :put docs {id => body}",
+            BTreeMap::from([("rows".to_string(), DataValue::List(rows))]),
+        )
+        .unwrap();
+
+        let search = |q: &str, extra: &str| -> Vec<(i64, f64)> {
+            db.run_script(
+                &format!(
+                "?[id, s] := ~docs:dedup{{id | query: $q, bind_similarity: s{extra}}} :order -s"
+            ),
+                BTreeMap::from([("q".to_string(), DataValue::from(q))]),
+            )
+            .unwrap()
+            .rows
+            .into_iter()
+            .map(|row| (row[0].get_int().unwrap(), row[1].get_float().unwrap()))
+            .collect()
+        };
+
+        // near duplicates are found and distant texts are not, the similarities being estimated
+        // closely enough
+        let found = search(base, "");
+        assert_eq!(found[0], (0, 1.0));
+        for edits in edit_counts {
+            let similarity = jaccard(base, &variant(edits));
+            let hit = found.iter().find(|(id, _)| *id == edits as i64);
+            if similarity >= 0.85 {
+                assert!(hit.is_some(), "{edits} edits, similarity {similarity}");
+            }
+            if similarity < 0.6 {
+                assert!(hit.is_none(), "{edits} edits, similarity {similarity}");
+            }
+            if let Some((_, estimated)) = hit {
+                assert!(estimated >= &0.7);
+                assert!((estimated - similarity).abs() < 0.1, "{edits} edits");
+            }
+        }
+        let strict = search(base, ", min_similarity: 0.9");
+        assert!(strict.iter().all(|(_, s)| *s >= 0.9));
+        assert!(strict.len() < found.len());
+        assert_eq!(
+            strict.iter().map(|(id, _)| *id).take(2).collect_vec(),
+            vec![0, 1]
+        );
+
+        // the index follows the rows put into and removed from the relation
+        db.run_script("?[id] <- [[1]] :rm docs {id}", Default::default())
+            .unwrap();
+        db.run_script(
+            "?[id, body] <- [[2, 'something else entirely'], [80, $base]] :put docs {id => body}",
+            BTreeMap::from([("base".to_string(), DataValue::from(base))]),
+        )
+        .unwrap();
+        let ids = search(base, "").into_iter().map(|(id, _)| id).collect_vec();
+        assert!(ids.contains(&80));
+        assert!(!ids.contains(&1));
+        assert!(!ids.contains(&2));
+        assert_eq!(
+            search("something else entirely", "")
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect_vec(),
+            vec![2]
+        );
+
+        for (script, code) in [
+            ("?[id] := ~docs:dedup{id | query: 1}", "eval::lsh_bad_query"),
+            (
+                "?[id] := ~docs:dedup{id | query: 'x', k: 3}",
+                "parser::bad_search_parameter",
+            ),
+            (
+                "::lsh create docs:other {fields: [id]}",
+                "tx::bad_lsh_column",
+            ),
+            (
+                "::lsh create docs:other {fields: [body], target_threshold: 2}",
+                "parser::bad_lsh_option",
+            ),
+        ] {
+            let err = db.run_script(script, Default::default()).unwrap_err();
+            assert_eq!(err.code().unwrap().to_string(), code, "{script}");
+        }
+
+        assert_eq!(
+            db.run_script("::indices docs", Default::default())
+                .unwrap()
+                .into_json()["rows"],
+            json!([["dedup", ["body"], null]])
+        );
+        db.run_script("::lsh rebuild docs:dedup", Default::default())
+            .unwrap();
+        assert_eq!(search(base, "")[0].1, 1.0);
+        db.run_script("::lsh drop docs:dedup", Default::default())
+            .unwrap();
+        assert!(db
+            .run_script("?[id] := ~docs:dedup{id | query: 'x'}", Default::default())
+            .is_err());
+    }
+}
@@ -6,11 +6,44 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+pub(crate) mod alter;
+#[cfg(feature = "async")]
+pub(crate) mod asynchronous;
+pub(crate) mod audit;
+pub(crate) mod backup;
+pub(crate) mod bulk_import;
 pub(crate) mod callback;
+pub(crate) mod clock;
+pub(crate) mod constraint;
+pub(crate) mod csv_io;
 pub(crate) mod db;
+pub(crate) mod error;
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) mod filtered_callback;
+pub(crate) mod fts;
+pub(crate) mod graph;
+pub(crate) mod hnsw;
 pub(crate) mod imperative;
+pub(crate) mod incremental;
+pub(crate) mod jsonl;
+pub(crate) mod lsh;
+pub(crate) mod plan_cache;
+pub(crate) mod prepared;
+pub(crate) mod profile;
+#[cfg(feature = "arrow")]
+pub(crate) mod record_batch;
 pub(crate) mod relation;
+pub(crate) mod retention;
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) mod retry;
+pub(crate) mod savepoint;
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) mod subscription;
 pub(crate) mod temp_store;
 #[cfg(test)]
 mod tests;
 pub(crate) mod transact;
+pub(crate) mod ttl;
+pub(crate) mod typed_rows;
+pub(crate) mod usage;
+pub(crate) mod verify;
@@ -0,0 +1,391 @@
+/*
+ * Copyright 2022, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use log::{debug, warn};
+use miette::{IntoDiagnostic, Result};
+
+use crate::data::program::{MagicSymbol, QueryOutOptions};
+use crate::data::symb::Symbol;
+use crate::data::value::DataValue;
+use crate::fixed_rule::FixedRule;
+use crate::query::compile::{share_common_scans, CompiledProgram, CompiledRuleSet};
+
+/// A query that has gone through all the planning stages and is ready for evaluation.
+#[derive(serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) struct CompiledQuery {
+    pub(crate) entry_head: Vec<Symbol>,
+    pub(crate) out_opts: QueryOutOptions,
+    pub(crate) store_lifetimes: BTreeMap<MagicSymbol, usize>,
+    pub(crate) strata: Vec<CompiledProgram>,
+    /// set when the plan must not be reused, see [crate::data::program::InputProgram::is_uncacheable]
+    #[serde(skip)]
+    pub(crate) uncacheable: bool,
+}
+
+impl CompiledQuery {
+    /// The headers of the output, the columns of the windows following those of the entry rule
+    pub(crate) fn out_headers(&self) -> Vec<String> {
+        self.entry_head
+            .iter()
+            .chain(self.out_opts.windows.iter().map(|w| &w.name))
+            .map(|s| s.to_string())
+            .collect()
+    }
+    /// Replaces the parameters left unbound when the query was prepared by their values
+    pub(crate) fn bind_params(&mut self, params: &BTreeMap<String, DataValue>) -> Result<()> {
+        for stratum in self.strata.iter_mut() {
+            for ruleset in stratum.values_mut() {
+                match ruleset {
+                    CompiledRuleSet::Rules(rules) => {
+                        for rule in rules {
+                            rule.relation.bind_params(params)?;
+                        }
+                    }
+                    CompiledRuleSet::Fixed(fixed) => {
+                        let mut options = fixed.options.as_ref().clone();
+                        for option in options.values_mut() {
+                            option.bind_params(params)?;
+                        }
+                        fixed.options = Arc::new(options);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The cache holds at most this many plans, the least recently used ones are evicted first.
+const PLAN_CACHE_MAX_ENTRIES: usize = 256;
+
+#[derive(serde_derive::Serialize, serde_derive::Deserialize)]
+struct PlanEntry {
+    script: String,
+    params: Vec<u8>,
+    schema_generation: u64,
+    plan: Vec<u8>,
+    #[serde(skip)]
+    last_used: u64,
+}
+
+#[derive(serde_derive::Serialize, serde_derive::Deserialize)]
+struct PlanCacheFile {
+    version: String,
+    entries: BTreeMap<u64, PlanEntry>,
+}
+
+/// Borrowed form of [PlanCacheFile] for writing.
+#[derive(serde_derive::Serialize)]
+struct PlanCacheFileRef<'a> {
+    version: &'a str,
+    entries: &'a BTreeMap<u64, PlanEntry>,
+}
+
+#[derive(Default)]
+struct PlanCacheState {
+    entries: BTreeMap<u64, PlanEntry>,
+    clock: u64,
+}
+
+/// Identifies a script together with its parameters in the plan cache.
+pub(crate) struct PlanKey {
+    hash: u64,
+    script: String,
+    params: Vec<u8>,
+}
+
+/// Plans of read-only queries, keyed by the hash of the script and parameters,
+/// persisted to a sidecar file so that they survive the database being closed.
+pub(crate) struct PlanCache {
+    path: PathBuf,
+    state: Mutex<PlanCacheState>,
+    dirty: AtomicBool,
+}
+
+impl PlanCache {
+    /// Loads the cache from `path`. A missing, unreadable or outdated file results in an
+    /// empty cache, which will overwrite the file when saved.
+    pub(crate) fn open(path: PathBuf) -> Self {
+        let mut state = PlanCacheState::default();
+        if let Ok(bytes) = fs::read(&path) {
+            match rmp_serde::from_slice::<PlanCacheFile>(&bytes) {
+                Ok(file) if file.version == env!("CARGO_PKG_VERSION") => {
+                    state.entries = file.entries;
+                }
+                Ok(_) => debug!("ignoring plan cache written by another version of Cozo"),
+                Err(err) => warn!("ignoring corrupt plan cache {}: {}", path.display(), err),
+            }
+        }
+        Self {
+            path,
+            state: Mutex::new(state),
+            dirty: AtomicBool::new(false),
+        }
+    }
+    /// Returns `None` if the parameters cannot be cached, as regexes cannot be serialized.
+    pub(crate) fn key(script: &str, params: &BTreeMap<String, DataValue>) -> Option<PlanKey> {
+        if params.values().any(contains_regex) {
+            return None;
+        }
+        let params = rmp_serde::to_vec(params).ok()?;
+        let hash = fnv1a(params.iter().copied(), fnv1a(script.bytes(), FNV_OFFSET));
+        Some(PlanKey {
+            hash,
+            script: script.to_string(),
+            params,
+        })
+    }
+    /// Retrieves the cached plan, if it was made against the current schema generation.
+    /// Stale and undecodable plans are dropped.
+    pub(crate) fn get(
+        &self,
+        key: &PlanKey,
+        schema_generation: u64,
+        fixed_rules: &BTreeMap<String, Arc<Box<dyn FixedRule>>>,
+    ) -> Option<CompiledQuery> {
+        let mut state = self.state.lock().unwrap();
+        let entry = state.entries.get(&key.hash)?;
+        if entry.script != key.script || entry.params != key.params {
+            return None;
+        }
+        let prepared = if entry.schema_generation == schema_generation {
+            rmp_serde::from_slice::<CompiledQuery>(&entry.plan)
+                .ok()
+                .and_then(|prepared| rebind(prepared, fixed_rules))
+        } else {
+            None
+        };
+        match prepared {
+            None => {
+                state.entries.remove(&key.hash);
+                self.dirty.store(true, Ordering::Release);
+                None
+            }
+            Some(prepared) => {
+                state.clock += 1;
+                let clock = state.clock;
+                state.entries.get_mut(&key.hash).unwrap().last_used = clock;
+                Some(prepared)
+            }
+        }
+    }
+    /// Caches the plan, unless it must not be reused
+    pub(crate) fn insert(&self, key: PlanKey, schema_generation: u64, prepared: &CompiledQuery) {
+        if prepared.uncacheable {
+            return;
+        }
+        let plan = match rmp_serde::to_vec(prepared) {
+            Ok(plan) => plan,
+            Err(err) => {
+                debug!("cannot cache plan: {}", err);
+                return;
+            }
+        };
+        let mut state = self.state.lock().unwrap();
+        if state.entries.len() >= PLAN_CACHE_MAX_ENTRIES && !state.entries.contains_key(&key.hash) {
+            if let Some(evicted) = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(hash, _)| *hash)
+            {
+                state.entries.remove(&evicted);
+            }
+        }
+        state.clock += 1;
+        let last_used = state.clock;
+        state.entries.insert(
+            key.hash,
+            PlanEntry {
+                script: key.script,
+                params: key.params,
+                schema_generation,
+                plan,
+                last_used,
+            },
+        );
+        self.dirty.store(true, Ordering::Release);
+    }
+    /// Writes the cache to its file, if anything changed since it was last written.
+    pub(crate) fn save(&self) -> Result<()> {
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let state = self.state.lock().unwrap();
+        let file = PlanCacheFileRef {
+            version: env!("CARGO_PKG_VERSION"),
+            entries: &state.entries,
+        };
+        let bytes = rmp_serde::to_vec(&file).into_diagnostic()?;
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, bytes).into_diagnostic()?;
+        fs::rename(&tmp_path, &self.path).into_diagnostic()?;
+        Ok(())
+    }
+}
+
+impl Drop for PlanCache {
+    fn drop(&mut self) {
+        if let Err(err) = self.save() {
+            warn!("failed to save plan cache {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+/// Attaches fixed rule implementations and shared scans, neither of which are serialized.
+pub(crate) fn rebind(
+    mut prepared: CompiledQuery,
+    fixed_rules: &BTreeMap<String, Arc<Box<dyn FixedRule>>>,
+) -> Option<CompiledQuery> {
+    for stratum in prepared.strata.iter_mut() {
+        for ruleset in stratum.values_mut() {
+            if let CompiledRuleSet::Fixed(fixed) = ruleset {
+                if !fixed.rebind_fixed_impl(fixed_rules) {
+                    return None;
+                }
+            }
+        }
+    }
+    // a plan whose scans cannot be shared is compiled afresh
+    share_common_scans(&mut prepared.strata).ok()?;
+    Some(prepared)
+}
+
+fn contains_regex(val: &DataValue) -> bool {
+    match val {
+        DataValue::Regex(_) => true,
+        DataValue::List(l) => l.iter().any(contains_regex),
+        DataValue::Set(s) => s.iter().any(contains_regex),
+        _ => false,
+    }
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// FNV-1a, chosen over the std hasher as the hash must be stable across builds.
+fn fnv1a(bytes: impl Iterator<Item = u8>, init: u64) -> u64 {
+    bytes.fold(init, |hash, b| (hash ^ b as u64).wrapping_mul(FNV_PRIME))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::atomic::Ordering;
+
+    use serde_json::json;
+
+    use crate::data::value::DataValue;
+    use crate::{Db, MemStorage};
+
+    #[test]
+    fn test_plan_cache_persistence() {
+        let path = std::env::temp_dir().join(format!("cozo_plan_cache_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let storage = MemStorage::default();
+        let script = r#"
+        start[x] <- [[$start]]
+        ?[a, count(c)] := start[a], *edge[a, b], *edge[b, c]
+    "#;
+        let params = BTreeMap::from([("start".to_string(), DataValue::from(1))]);
+        {
+            let mut db = Db::new(storage.clone()).unwrap();
+            db.set_plan_cache_path(&path);
+            db.initialize().unwrap();
+            db.run_script(":create edge {a: Int, b: Int}", Default::default())
+                .unwrap();
+            db.run_script(
+                "?[a, b] <- [[1, 2], [2, 3], [2, 4]] :put edge {a, b}",
+                Default::default(),
+            )
+            .unwrap();
+            let planned = db.plans_count.load(Ordering::Acquire);
+            let res = db.run_script(script, params.clone()).unwrap();
+            assert_eq!(res.into_json()["rows"], json!([[1, 2]]));
+            assert_eq!(db.plans_count.load(Ordering::Acquire), planned + 1);
+            let res = db.run_script(script, params.clone()).unwrap();
+            assert_eq!(res.into_json()["rows"], json!([[1, 2]]));
+            assert_eq!(db.plans_count.load(Ordering::Acquire), planned + 1);
+        }
+        assert!(path.exists());
+
+        // reopening: the plan is loaded from the file and used without planning
+        let mut db = Db::new(storage.clone()).unwrap();
+        db.set_plan_cache_path(&path);
+        db.initialize().unwrap();
+        let res = db.run_script(script, params.clone()).unwrap();
+        assert_eq!(res.into_json()["rows"], json!([[1, 2]]));
+        assert_eq!(db.plans_count.load(Ordering::Acquire), 0);
+
+        // different parameters are planned separately
+        let other_params = BTreeMap::from([("start".to_string(), DataValue::from(2))]);
+        let res = db.run_script(script, other_params).unwrap();
+        assert_eq!(res.into_json()["rows"], json!([]));
+        assert_eq!(db.plans_count.load(Ordering::Acquire), 1);
+
+        // schema changes invalidate the plan
+        db.run_script("::index create edge:rev {b, a}", Default::default())
+            .unwrap();
+        let res = db.run_script(script, params.clone()).unwrap();
+        assert_eq!(res.into_json()["rows"], json!([[1, 2]]));
+        assert_eq!(db.plans_count.load(Ordering::Acquire), 2);
+        let res = db.run_script(script, params).unwrap();
+        assert_eq!(res.into_json()["rows"], json!([[1, 2]]));
+        assert_eq!(db.plans_count.load(Ordering::Acquire), 2);
+
+        // mutations and time-dependent scripts are never cached
+        db.run_script("?[a, b] <- [[3, 4]] :put edge {a, b}", Default::default())
+            .unwrap();
+        db.run_script("?[a, b] <- [[3, 4]] :put edge {a, b}", Default::default())
+            .unwrap();
+        db.run_script("?[x] := x = now()", Default::default())
+            .unwrap();
+        db.run_script("?[x] := x = now()", Default::default())
+            .unwrap();
+        assert_eq!(db.plans_count.load(Ordering::Acquire), 6);
+
+        // nor are those applying registered functions, or with validity specifications
+        db.register_pure_function("twice".to_string(), 1, |args| {
+            Ok(DataValue::from(args[0].get_int().unwrap() * 2))
+        })
+        .unwrap();
+        for _ in 0..2 {
+            let res = db
+                .run_script("?[x] := x = twice(2)", Default::default())
+                .unwrap();
+            assert_eq!(res.into_json()["rows"], json!([[4]]));
+        }
+        db.run_script(
+            ":create events {k: Int, at: Validity => v: Int}",
+            Default::default(),
+        )
+        .unwrap();
+        for _ in 0..2 {
+            db.run_script("?[k] := *events{k @ 'NOW'}", Default::default())
+                .unwrap();
+        }
+        assert_eq!(db.plans_count.load(Ordering::Acquire), 11);
+
+        // names of functions in strings do not matter
+        for _ in 0..2 {
+            db.run_script("?[x] := x = 'now() or rand_int()'", Default::default())
+                .unwrap();
+        }
+        assert_eq!(db.plans_count.load(Ordering::Acquire), 12);
+
+        drop(db);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
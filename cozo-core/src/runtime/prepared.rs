@@ -0,0 +1,258 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+use miette::{ensure, Diagnostic, IntoDiagnostic, Result};
+use thiserror::Error;
+
+use crate::data::expr::ParamNotFoundError;
+use crate::data::program::InputProgram;
+use crate::parse::{parse_prepared_query, parse_script, CozoScript, SourceSpan};
+use crate::runtime::db::RunningScript;
+use crate::runtime::error::CozoError;
+use crate::runtime::plan_cache::{rebind, CompiledQuery};
+use crate::runtime::transact::SessionTx;
+use crate::{DataValue, Db, NamedRows, Storage};
+
+/// A query parsed and planned once, to be run many times with different parameters,
+/// see [Db::prepare].
+///
+/// The parameters stay unbound in the plan and are only replaced by their values when the
+/// query runs. If the schema of the database changed since the query was planned, it is
+/// planned again. Queries whose plans depend on when they are made, i.e. those with
+/// validity specifications or applying `now()`, `rand_*`, regexes or functions registered
+/// with the database, are parsed and planned again on each run.
+///
+/// Prepared queries can be shared between threads, and must only be run on the database
+/// that prepared them.
+pub struct PreparedQuery {
+    script: String,
+    program: InputProgram,
+    /// the parameters used, with the spans of their first uses
+    params: BTreeMap<String, SourceSpan>,
+    replan_every_run: bool,
+    plan: RwLock<Option<PreparedPlan>>,
+}
+
+struct PreparedPlan {
+    schema_generation: u64,
+    /// the serialized [CompiledQuery], as evaluation consumes the plan
+    bytes: Vec<u8>,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Only read-only queries can be prepared")]
+#[diagnostic(code(eval::prepared_mutation))]
+#[diagnostic(help("Queries writing to stored relations must be run as scripts"))]
+struct PreparedMutationError;
+
+impl PreparedQuery {
+    /// Runs the query on the database that prepared it.
+    /// All parameters used by the query must be given.
+    pub fn run<'s, S: Storage<'s>>(
+        &self,
+        db: &'s Db<S>,
+        params: BTreeMap<String, DataValue>,
+    ) -> Result<NamedRows> {
+        db.run_prepared(self, params)
+    }
+    /// The names of the parameters used by the query
+    pub fn param_names(&self) -> impl Iterator<Item = &str> {
+        self.params.keys().map(|name| name.as_str())
+    }
+    /// The plan of the query, made again if the schema changed since it was made
+    fn compiled<'s, S: Storage<'s>>(
+        &self,
+        db: &'s Db<S>,
+        tx: &mut SessionTx<'_>,
+    ) -> Result<CompiledQuery> {
+        let schema_generation = tx.schema_generation()?;
+        if let Some(plan) = &*self.plan.read().unwrap() {
+            if plan.schema_generation == schema_generation {
+                let compiled = rmp_serde::from_slice(&plan.bytes).into_diagnostic()?;
+                if let Some(compiled) = rebind(compiled, &db.fixed_rules.read().unwrap()) {
+                    return Ok(compiled);
+                }
+            }
+        }
+        let compiled = db.prepare_query(tx, self.program.clone())?;
+        if !compiled.uncacheable {
+            if let Ok(bytes) = rmp_serde::to_vec(&compiled) {
+                *self.plan.write().unwrap() = Some(PreparedPlan {
+                    schema_generation,
+                    bytes,
+                });
+            }
+        }
+        Ok(compiled)
+    }
+}
+
+impl<'s, S: Storage<'s>> Db<S> {
+    /// Parses and plans a query, to be run many times with different parameters by
+    /// [PreparedQuery::run], saving the cost of parsing and planning it each time.
+    ///
+    /// Only single read-only queries can be prepared. Parameters are written as in scripts,
+    /// e.g. `$name`, but only in rule bodies: options such as `:limit` need their values
+    /// when the query is prepared.
+    pub fn prepare(&'s self, script: &str) -> Result<PreparedQuery> {
+        self.do_prepare(script).map_err(CozoError::wrap)
+    }
+    fn do_prepare(&'s self, script: &str) -> Result<PreparedQuery> {
+        let (program, params) = parse_prepared_query(
+            script,
+            &self.fixed_rules.read().unwrap(),
+            self.clock.current_validity(),
+        )?;
+        ensure!(
+            program.out_opts.store_relation.is_none(),
+            PreparedMutationError
+        );
+        let mut ret = PreparedQuery {
+            script: script.to_string(),
+            program,
+            params,
+            replan_every_run: false,
+            plan: Default::default(),
+        };
+        // planned right away so that errors are reported early
+        let mut tx = self.transact()?;
+        ret.replan_every_run = ret.compiled(self, &mut tx)?.uncacheable;
+        tx.commit_tx()?;
+        Ok(ret)
+    }
+    pub(crate) fn run_prepared(
+        &'s self,
+        query: &PreparedQuery,
+        params: BTreeMap<String, DataValue>,
+    ) -> Result<NamedRows> {
+        let mut ret = self
+            .do_run_prepared(query, &params)
+            .map_err(CozoError::wrap)?;
+        ret.fill_output_options(self.output_options);
+        Ok(ret)
+    }
+    fn do_run_prepared(
+        &'s self,
+        query: &PreparedQuery,
+        params: &BTreeMap<String, DataValue>,
+    ) -> Result<NamedRows> {
+        for (name, span) in &query.params {
+            ensure!(
+                params.contains_key(name),
+                ParamNotFoundError(name.to_string(), *span)
+            );
+        }
+        let cur_vld = self.clock.current_validity();
+        if query.replan_every_run {
+            return match parse_script(
+                &query.script,
+                params,
+                &self.fixed_rules.read().unwrap(),
+                cur_vld,
+            )? {
+                CozoScript::Single(p) => {
+                    let script = RunningScript::new(&query.script, Default::default());
+                    self.execute_single(cur_vld, p, None, &script)
+                }
+                _ => unreachable!(),
+            };
+        }
+        let mut tx = self.transact()?;
+        tx.script = RunningScript::new(&query.script, Default::default());
+        let mut compiled = query.compiled(self, &mut tx)?;
+        compiled.bind_params(params)?;
+        let (res, cleanups) = self.run_prepared_query(
+            &mut tx,
+            compiled,
+            cur_vld,
+            &Default::default(),
+            &mut Default::default(),
+            true,
+        )?;
+        self.commit_audited(tx, None)?;
+        assert!(cleanups.is_empty(), "non-empty cleanups on read-only tx");
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::atomic::Ordering;
+
+    use itertools::Itertools;
+    use serde_json::json;
+
+    use crate::data::value::DataValue;
+    use crate::new_cozo_mem;
+
+    #[test]
+    fn test_prepared_query() {
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+        let db = new_cozo_mem().unwrap();
+        db.run_script(
+            "?[k, v] := k in int_range(10), v = k * 10 :create a {k => v}",
+            Default::default(),
+        )
+        .unwrap();
+        let params = |k: i64| BTreeMap::from([("k".to_string(), DataValue::from(k))]);
+
+        let query = db
+            .prepare("?[k, v] := *a{k, v}, k >= $k, v < $k * 10 + 30")
+            .unwrap();
+        assert_send_sync(&query);
+        assert_eq!(query.param_names().collect_vec(), vec!["k"]);
+        let planned = db.plans_count.load(Ordering::Acquire);
+        let res = query.run(&db, params(3)).unwrap();
+        assert_eq!(res.into_json()["rows"], json!([[3, 30], [4, 40], [5, 50]]));
+        let res = query.run(&db, params(8)).unwrap();
+        assert_eq!(res.into_json()["rows"], json!([[8, 80], [9, 90]]));
+        assert_eq!(db.plans_count.load(Ordering::Acquire), planned);
+
+        std::thread::scope(|s| {
+            for k in 0..4 {
+                let query = &query;
+                let db = &db;
+                s.spawn(move || {
+                    let res = query.run(db, params(k)).unwrap();
+                    assert_eq!(res.rows.len(), 3);
+                });
+            }
+        });
+
+        let err = query.run(&db, Default::default()).unwrap_err();
+        assert_eq!(err.code().unwrap().to_string(), "parser::param_not_found");
+
+        // planned again after the schema changes
+        db.run_script("::remove a", Default::default()).unwrap();
+        db.run_script(
+            "?[k, w, v] := k in int_range(10), w = -k, v = k * 10 :create a {k => w, v}",
+            Default::default(),
+        )
+        .unwrap();
+        let planned = db.plans_count.load(Ordering::Acquire);
+        let res = query.run(&db, params(3)).unwrap();
+        assert_eq!(res.into_json()["rows"], json!([[3, 30], [4, 40], [5, 50]]));
+        assert_eq!(db.plans_count.load(Ordering::Acquire), planned + 1);
+        db.run_script("::remove a", Default::default()).unwrap();
+        assert!(query.run(&db, params(3)).is_err());
+
+        assert!(db.prepare("?[k] := k = $k :create b {k}").is_err());
+        assert!(db.prepare("?[k] := k = 1 :limit $n").is_err());
+        assert!(db.prepare("{?[k] := k = $k} {?[k] := k = $k}").is_err());
+
+        // planned on each run, as the plan depends on the time
+        let query = db.prepare("?[x] := x = $k, now() > 0").unwrap();
+        let res = query.run(&db, params(1)).unwrap();
+        assert_eq!(res.into_json()["rows"], json!([[1]]));
+    }
+}
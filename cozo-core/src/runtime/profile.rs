@@ -0,0 +1,202 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+
+use crate::data::tuple::{Tuple, TupleIter};
+use crate::query::ra::RelAlgebra;
+use crate::runtime::transact::SessionTx;
+
+/// The rows produced by a node of a plan profiled by `::profile`, and the time spent
+/// producing them, including the time spent in its inputs
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct NodeStats {
+    pub(crate) rows: usize,
+    pub(crate) time: Duration,
+}
+
+impl NodeStats {
+    fn add(&mut self, other: NodeStats) {
+        self.rows += other.rows;
+        self.time += other.time;
+    }
+}
+
+/// The statistics of the nodes of the plans being profiled, by the address of the node
+pub(crate) type PlanStats = BTreeMap<usize, NodeStats>;
+
+pub(crate) fn node_key(node: &RelAlgebra) -> usize {
+    node as *const RelAlgebra as usize
+}
+
+/// Counts the rows produced by a node and the time taken, adding them to the
+/// statistics of the plan when done
+struct ProfiledRows<'a> {
+    inner: TupleIter<'a>,
+    node: usize,
+    stats: NodeStats,
+    plan_stats: &'a Mutex<PlanStats>,
+}
+
+impl Iterator for ProfiledRows<'_> {
+    type Item = miette::Result<Tuple>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        #[cfg(not(target_arch = "wasm32"))]
+        let started = Instant::now();
+        let ret = self.inner.next();
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            self.stats.time += started.elapsed();
+        }
+        if let Some(Ok(_)) = ret {
+            self.stats.rows += 1;
+        }
+        ret
+    }
+}
+
+impl Drop for ProfiledRows<'_> {
+    fn drop(&mut self) {
+        self.plan_stats
+            .lock()
+            .unwrap()
+            .entry(self.node)
+            .or_default()
+            .add(self.stats);
+    }
+}
+
+impl<'a> SessionTx<'a> {
+    /// Counts the rows produced by `node` with the iterator made by `make_iter`
+    /// and the time taken, if profiling
+    pub(crate) fn profile_iter<'r>(
+        &'r self,
+        node: &RelAlgebra,
+        make_iter: impl FnOnce() -> miette::Result<TupleIter<'r>>,
+    ) -> miette::Result<TupleIter<'r>> {
+        let plan_stats = match &self.plan_stats {
+            None => return make_iter(),
+            Some(plan_stats) => plan_stats,
+        };
+        #[cfg(not(target_arch = "wasm32"))]
+        let started = Instant::now();
+        let inner = make_iter()?;
+        #[cfg(not(target_arch = "wasm32"))]
+        let setup = started.elapsed();
+        #[cfg(target_arch = "wasm32")]
+        let setup = Duration::ZERO;
+        Ok(Box::new(ProfiledRows {
+            inner,
+            node: node_key(node),
+            stats: NodeStats {
+                rows: 0,
+                time: setup,
+            },
+            plan_stats,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+
+    use crate::data::value::DataValue;
+    use crate::new_cozo_mem;
+
+    #[test]
+    fn test_profile() {
+        let db = new_cozo_mem().unwrap();
+        db.run_script(
+            r"
+        {
+            ?[x, y] := x in int_range(10), y = x % 3
+            :create prof_a {x => y}
+        }
+        {
+            ?[y, z] <- [[0, 'zero'], [1, 'one']]
+            :create prof_b {y => z}
+        }
+        ",
+            Default::default(),
+        )
+        .unwrap();
+        let profile = |script: &str| {
+            let res = db
+                .run_script(&format!("::profile {{ {script} }}"), Default::default())
+                .unwrap();
+            let col = |name: &str| res.headers.iter().position(|h| h == name).unwrap();
+            let (op, ref_name, rows, time_ms) =
+                (col("op"), col("ref"), col("rows"), col("time_ms"));
+            let nodes = res
+                .rows
+                .iter()
+                .map(|row| {
+                    assert_eq!(
+                        row[rows] == DataValue::Null,
+                        row[time_ms] == DataValue::Null
+                    );
+                    (
+                        row[op].get_str().unwrap().to_string(),
+                        row[ref_name].get_str().unwrap_or_default().to_string(),
+                        row[rows].get_int().map(|n| n as usize),
+                    )
+                })
+                .collect_vec();
+            // the node before the output is the root of the plan
+            let out = nodes.iter().position(|(op, _, _)| op == "out").unwrap();
+            assert_eq!(nodes[out].2, None);
+            (nodes[out - 1].2.unwrap(), nodes)
+        };
+        let rows_of = |nodes: &[(String, String, Option<usize>)], name: &str| {
+            nodes
+                .iter()
+                .find(|(op, ref_name, _)| op == name || ref_name == name)
+                .unwrap_or_else(|| panic!("{name} not in {nodes:?}"))
+                .2
+        };
+
+        // the scan produces every row, the join only those with a match
+        let (root, nodes) = profile("?[x, z] := *prof_a{x, y}, *prof_b{y, z}");
+        assert_eq!(rows_of(&nodes, ":prof_a"), Some(10));
+        assert_eq!(root, 7);
+
+        let (root, nodes) = profile("?[x] := *prof_a{x}, not *prof_b{y: x}");
+        assert_eq!(rows_of(&nodes, ":prof_a"), Some(10));
+        assert_eq!(root, 8);
+
+        let (root, nodes) = profile("?[x, w] := *prof_a{x}, w = x * 2");
+        assert_eq!(rows_of(&nodes, "unify"), Some(10));
+        assert_eq!(root, 10);
+
+        let (root, _) = profile("?[x] := *prof_a{x}, x > 3");
+        assert_eq!(root, 6);
+
+        // the evaluation stops early with a limit
+        let (root, _) = profile("?[x] := *prof_a{x} :limit 3");
+        assert!(root <= 4);
+
+        assert!(db
+            .run_script(
+                "::profile { ?[x, y] <- [[1, 2]] :put prof_a {x => y} }",
+                Default::default()
+            )
+            .is_err());
+        assert_eq!(
+            db.run_script("?[count(x)] := *prof_a{x}", Default::default())
+                .unwrap()
+                .rows[0][0],
+            DataValue::from(10)
+        );
+    }
+}
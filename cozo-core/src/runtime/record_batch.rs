@@ -0,0 +1,465 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use arrow::array::{
+    Array, ArrayRef, AsArray, BinaryBuilder, BooleanBuilder, Float64Builder, Int64Builder,
+    ListArray, StringBuilder,
+};
+use arrow::buffer::{NullBuffer, OffsetBuffer};
+use arrow::datatypes::{DataType, Field, Float64Type, Int64Type, Schema};
+use arrow::record_batch::RecordBatch;
+use itertools::Itertools;
+use miette::{bail, Diagnostic, Result};
+use thiserror::Error;
+
+use crate::data::expr::Expr;
+use crate::data::json::JsonValue;
+use crate::data::program::{InputAtom, InputInlineRulesOrFixed};
+use crate::data::relation::{ColType, NullableColType};
+use crate::data::symb::{Symbol, PROG_ENTRY};
+use crate::data::value::{DataValue, Num};
+use crate::parse::{parse_script, CozoScript, SourceSpan};
+use crate::runtime::db::{Db, NamedRows};
+use crate::runtime::error::CozoError;
+use crate::storage::Storage;
+
+/// Set in the metadata of fields holding values written as JSON strings
+const JSON_FIELD_METADATA: &str = "cozo:json";
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Column '{0}' is converted to an Arrow array of type {1}, but contains the value {2:?}")]
+#[diagnostic(code(eval::arrow_type_mismatch))]
+struct ArrowTypeMismatch(String, DataType, DataValue);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Column '{0}' is of the Arrow type {1}, which cannot be converted")]
+#[diagnostic(code(eval::arrow_unsupported_type))]
+struct ArrowUnsupportedType(String, DataType);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Cannot convert between rows and Arrow record batches: {0}")]
+#[diagnostic(code(eval::arrow_conversion))]
+struct ArrowConversionError(String);
+
+/// The Arrow type a column is converted to
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum ArrowColumn {
+    Int64,
+    Float64,
+    Utf8,
+    Binary,
+    Boolean,
+    List(Box<ArrowColumn>),
+    /// Values of types Arrow has no counterpart for are written as JSON strings
+    Json,
+}
+
+impl ArrowColumn {
+    fn from_col_type(coltype: &ColType) -> Self {
+        match coltype {
+            ColType::Int => ArrowColumn::Int64,
+            ColType::Float => ArrowColumn::Float64,
+            ColType::String => ArrowColumn::Utf8,
+            ColType::Bytes => ArrowColumn::Binary,
+            ColType::Bool => ArrowColumn::Boolean,
+            ColType::List { eltype, .. } => match Self::from_col_type(&eltype.coltype) {
+                ArrowColumn::List(_) | ArrowColumn::Json => ArrowColumn::Json,
+                el => ArrowColumn::List(Box::new(el)),
+            },
+            ColType::Any | ColType::Uuid | ColType::Validity | ColType::Tuple(_) => {
+                ArrowColumn::Json
+            }
+        }
+    }
+    fn data_type(&self) -> DataType {
+        match self {
+            ArrowColumn::Int64 => DataType::Int64,
+            ArrowColumn::Float64 => DataType::Float64,
+            ArrowColumn::Utf8 | ArrowColumn::Json => DataType::Utf8,
+            ArrowColumn::Binary => DataType::Binary,
+            ArrowColumn::Boolean => DataType::Boolean,
+            ArrowColumn::List(el) => DataType::List(Arc::new(el.field("item"))),
+        }
+    }
+    fn field(&self, name: &str) -> Field {
+        let field = Field::new(name, self.data_type(), true);
+        if *self == ArrowColumn::Json {
+            field.with_metadata(HashMap::from([(
+                JSON_FIELD_METADATA.to_string(),
+                "true".to_string(),
+            )]))
+        } else {
+            field
+        }
+    }
+    fn build(&self, name: &str, values: &[&DataValue]) -> Result<ArrayRef> {
+        let mismatch =
+            |v: &DataValue| ArrowTypeMismatch(name.to_string(), self.data_type(), v.clone());
+        Ok(match self {
+            ArrowColumn::Int64 => {
+                let mut builder = Int64Builder::with_capacity(values.len());
+                for v in values {
+                    match v {
+                        DataValue::Null => builder.append_null(),
+                        DataValue::Num(Num::Int(i)) => builder.append_value(*i),
+                        v => bail!(mismatch(v)),
+                    }
+                }
+                Arc::new(builder.finish())
+            }
+            ArrowColumn::Float64 => {
+                let mut builder = Float64Builder::with_capacity(values.len());
+                for v in values {
+                    match v {
+                        DataValue::Null => builder.append_null(),
+                        DataValue::Num(Num::Int(i)) => builder.append_value(*i as f64),
+                        DataValue::Num(Num::Float(f)) => builder.append_value(*f),
+                        v => bail!(mismatch(v)),
+                    }
+                }
+                Arc::new(builder.finish())
+            }
+            ArrowColumn::Utf8 => {
+                let mut builder = StringBuilder::with_capacity(values.len(), 0);
+                for v in values {
+                    match v {
+                        DataValue::Null => builder.append_null(),
+                        DataValue::Str(s) => builder.append_value(s.as_str()),
+                        v => bail!(mismatch(v)),
+                    }
+                }
+                Arc::new(builder.finish())
+            }
+            ArrowColumn::Binary => {
+                let mut builder = BinaryBuilder::with_capacity(values.len(), 0);
+                for v in values {
+                    match v {
+                        DataValue::Null => builder.append_null(),
+                        DataValue::Bytes(b) => builder.append_value(b),
+                        v => bail!(mismatch(v)),
+                    }
+                }
+                Arc::new(builder.finish())
+            }
+            ArrowColumn::Boolean => {
+                let mut builder = BooleanBuilder::with_capacity(values.len());
+                for v in values {
+                    match v {
+                        DataValue::Null => builder.append_null(),
+                        DataValue::Bool(b) => builder.append_value(*b),
+                        v => bail!(mismatch(v)),
+                    }
+                }
+                Arc::new(builder.finish())
+            }
+            ArrowColumn::Json => {
+                let mut builder = StringBuilder::with_capacity(values.len(), 0);
+                for v in values {
+                    match v {
+                        DataValue::Null => builder.append_null(),
+                        v => builder.append_value(JsonValue::from((*v).clone()).to_string()),
+                    }
+                }
+                Arc::new(builder.finish())
+            }
+            ArrowColumn::List(el) => {
+                let mut offsets = Vec::with_capacity(values.len() + 1);
+                let mut validity = Vec::with_capacity(values.len());
+                let mut elements = vec![];
+                offsets.push(0i32);
+                for v in values {
+                    match v {
+                        DataValue::Null => validity.push(false),
+                        DataValue::List(l) => {
+                            elements.extend(l.iter());
+                            validity.push(true);
+                        }
+                        v => bail!(mismatch(v)),
+                    }
+                    offsets.push(elements.len() as i32);
+                }
+                let elements = el.build(name, &elements)?;
+                let array = ListArray::try_new(
+                    Arc::new(el.field("item")),
+                    OffsetBuffer::new(offsets.into()),
+                    elements,
+                    Some(NullBuffer::from(validity)),
+                )
+                .map_err(|err| ArrowConversionError(err.to_string()))?;
+                Arc::new(array)
+            }
+        })
+    }
+}
+
+/// The values in the array, with those in fields marked as JSON parsed
+fn array_values(name: &str, array: &dyn Array, json: bool) -> Result<Vec<DataValue>> {
+    let mut ret = Vec::with_capacity(array.len());
+    for i in 0..array.len() {
+        if array.is_null(i) {
+            ret.push(DataValue::Null);
+            continue;
+        }
+        let val = match array.data_type() {
+            DataType::Int64 => DataValue::from(array.as_primitive::<Int64Type>().value(i)),
+            DataType::Float64 => DataValue::from(array.as_primitive::<Float64Type>().value(i)),
+            DataType::Boolean => DataValue::Bool(array.as_boolean().value(i)),
+            DataType::Binary => DataValue::Bytes(array.as_binary::<i32>().value(i).to_vec()),
+            DataType::Utf8 => {
+                let s = array.as_string::<i32>().value(i);
+                if json {
+                    let parsed: JsonValue = serde_json::from_str(s)
+                        .map_err(|err| ArrowConversionError(err.to_string()))?;
+                    DataValue::from(parsed)
+                } else {
+                    DataValue::from(s)
+                }
+            }
+            DataType::List(_) => {
+                let elements = array.as_list::<i32>().value(i);
+                DataValue::List(array_values(name, elements.as_ref(), false)?)
+            }
+            t => bail!(ArrowUnsupportedType(name.to_string(), t.clone())),
+        };
+        ret.push(val);
+    }
+    Ok(ret)
+}
+
+impl NamedRows {
+    /// Convert to an [Arrow](https://arrow.apache.org/) record batch, the type of each column
+    /// inferred from its values. Integers, floats, strings, bytes and booleans become arrays
+    /// of the corresponding Arrow types, and integers mixed with floats become floats.
+    /// Lists of these become list arrays. Other values, e.g. nested lists or columns with values
+    /// of different types, are written as JSON strings, and their fields have `cozo:json` set in
+    /// their metadata. Only the current named rows are converted, not those in [NamedRows::next].
+    pub fn into_record_batch(self) -> Result<RecordBatch> {
+        let types = (0..self.headers.len())
+            .map(|i| NullableColType::infer(self.rows.iter().map(|row| &row[i])))
+            .collect_vec();
+        self.into_typed_record_batch(&types)
+    }
+
+    pub(crate) fn into_typed_record_batch(self, types: &[NullableColType]) -> Result<RecordBatch> {
+        let mut fields = Vec::with_capacity(self.headers.len());
+        let mut columns = Vec::with_capacity(self.headers.len());
+        for (i, (name, typing)) in self.headers.iter().zip(types).enumerate() {
+            let col = ArrowColumn::from_col_type(&typing.coltype);
+            let values = self.rows.iter().map(|row| &row[i]).collect_vec();
+            columns.push(col.build(name, &values)?);
+            fields.push(col.field(name));
+        }
+        RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)
+            .map_err(|err| ArrowConversionError(err.to_string()).into())
+    }
+
+    /// Convert an [Arrow](https://arrow.apache.org/) record batch made by
+    /// [NamedRows::into_record_batch] back into named rows. Fields with `cozo:json` set in
+    /// their metadata are parsed as JSON.
+    pub fn from_record_batch(batch: &RecordBatch) -> Result<Self> {
+        let schema = batch.schema();
+        let mut rows = vec![vec![]; batch.num_rows()];
+        for (field, column) in schema.fields().iter().zip(batch.columns()) {
+            let json = field.metadata().contains_key(JSON_FIELD_METADATA);
+            let values = array_values(field.name(), column.as_ref(), json)?;
+            for (row, val) in rows.iter_mut().zip(values) {
+                row.push(val);
+            }
+        }
+        let headers = schema.fields().iter().map(|f| f.name().clone()).collect();
+        Ok(NamedRows::new(headers, rows))
+    }
+}
+
+impl<'s, S: Storage<'s>> Db<S> {
+    /// Run the CozoScript passed in, with the results converted to an
+    /// [Arrow](https://arrow.apache.org/) record batch. If the query only reads the columns of
+    /// a stored relation, as in `?[a, b] := *rel{a, b}`, the types of the columns are those
+    /// declared by the relation. Otherwise they are inferred from the values,
+    /// as by [NamedRows::into_record_batch].
+    pub fn run_script_arrow(
+        &'s self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+    ) -> Result<RecordBatch> {
+        let types = self.scan_column_types(payload, &params);
+        let rows = self.run_script(payload, params)?;
+        let types = (0..rows.headers.len())
+            .map(|i| match types.as_ref().and_then(|types| types.get(i)) {
+                Some(t) if t.coltype != ColType::Any => t.clone(),
+                _ => NullableColType::infer(rows.rows.iter().map(|row| &row[i])),
+            })
+            .collect_vec();
+        rows.into_typed_record_batch(&types)
+            .map_err(CozoError::wrap)
+    }
+
+    /// The declared types of the columns returned by the script, if it only reads the columns
+    /// of a stored relation.
+    fn scan_column_types(
+        &'s self,
+        payload: &str,
+        params: &BTreeMap<String, DataValue>,
+    ) -> Option<Vec<NullableColType>> {
+        let cur_vld = self.clock.current_validity();
+        let fixed_rules = self.fixed_rules.read().unwrap();
+        let program = match parse_script(payload, params, &fixed_rules, cur_vld).ok()? {
+            CozoScript::Single(p) if p.out_opts.store_relation.is_none() => p,
+            _ => return None,
+        };
+        if program.prog.len() != 1 {
+            return None;
+        }
+        let rule = match program
+            .prog
+            .get(&Symbol::new(PROG_ENTRY, SourceSpan(0, 0)))?
+        {
+            InputInlineRulesOrFixed::Rules { rules } if rules.len() == 1 => &rules[0],
+            _ => return None,
+        };
+        if rule.aggr.iter().any(Option::is_some) || rule.body.len() != 1 {
+            return None;
+        }
+        let name = match &rule.body[0] {
+            InputAtom::Relation { inner } => &inner.name,
+            InputAtom::NamedFieldRelation { inner } => &inner.name,
+            _ => return None,
+        };
+        let tx = self.transact().ok()?;
+        let handle = tx.get_relation(name, false).ok()?;
+        let columns = handle
+            .metadata
+            .keys
+            .iter()
+            .chain(handle.metadata.non_keys.iter())
+            .collect_vec();
+        let bound: Vec<(&Expr, &NullableColType)> = match &rule.body[0] {
+            InputAtom::Relation { inner } => {
+                if inner.args.len() != columns.len() {
+                    return None;
+                }
+                inner
+                    .args
+                    .iter()
+                    .zip(columns.iter().map(|col| &col.typing))
+                    .collect()
+            }
+            InputAtom::NamedFieldRelation { inner } => inner
+                .args
+                .iter()
+                .map(|(field, arg)| {
+                    let col = columns.iter().find(|col| col.name == *field)?;
+                    Some((arg, &col.typing))
+                })
+                .collect::<Option<_>>()?,
+            _ => unreachable!(),
+        };
+        rule.head
+            .iter()
+            .map(|var| {
+                bound.iter().find_map(|(arg, typing)| match arg {
+                    Expr::Binding { var: bound_var, .. } if bound_var == var => {
+                        Some((*typing).clone())
+                    }
+                    _ => None,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+
+    use crate::data::value::DataValue;
+    use crate::{new_cozo_mem, NamedRows};
+
+    #[test]
+    fn test_arrow_record_batches() {
+        use arrow::array::AsArray;
+        use arrow::datatypes::{DataType, Float64Type, Int64Type};
+
+        let db = new_cozo_mem().unwrap();
+        let res = db
+            .run_script(
+                r"
+            ?[i, x, s, l, nested, mixed] <- [
+                [1, 1, 'a', [1, 2], [[1]], 1],
+                [2, 2.5, null, null, [], 'b'],
+                [3, null, 'c', [3, null], null, [true]]
+            ]
+            ",
+                Default::default(),
+            )
+            .unwrap();
+        let batch = res.clone().into_record_batch().unwrap();
+        let schema = batch.schema();
+        let types = schema
+            .fields()
+            .iter()
+            .map(|f| f.data_type().clone())
+            .collect_vec();
+        assert_eq!(types[0], DataType::Int64);
+        assert_eq!(types[1], DataType::Float64);
+        assert_eq!(types[2], DataType::Utf8);
+        assert!(matches!(&types[3], DataType::List(f) if *f.data_type() == DataType::Int64));
+        assert_eq!(types[4], DataType::Utf8);
+        assert_eq!(types[5], DataType::Utf8);
+        assert!(schema.field(4).metadata().contains_key("cozo:json"));
+
+        let xs = batch.column(1).as_primitive::<Float64Type>();
+        assert_eq!(xs.value(0), 1.0);
+        assert_eq!(xs.value(1), 2.5);
+        assert!(xs.is_null(2));
+        assert!(batch.column(3).is_null(1));
+        let elements = batch.column(3).as_list::<i32>().value(2);
+        assert_eq!(elements.as_primitive::<Int64Type>().value(0), 3);
+        assert!(elements.is_null(1));
+
+        let back = NamedRows::from_record_batch(&batch).unwrap();
+        assert_eq!(back.headers, res.headers);
+        // integers in the float column come back as floats
+        assert_eq!(back.rows[0][1], DataValue::from(1.0));
+        assert_eq!(back.rows[0][3], res.rows[0][3]);
+        for (got, expected) in back.rows.iter().zip(res.rows.iter()) {
+            for i in [0, 2, 3, 4, 5] {
+                assert_eq!(got[i], expected[i]);
+            }
+        }
+
+        // plain scans of stored relations use the types of the relation
+        db.run_script(
+            r"
+        ?[k, v] <- [[1, 1], [2, null]]
+        :create floats {k: Int => v: Float?}
+        ",
+            Default::default(),
+        )
+        .unwrap();
+        let batch = db
+            .run_script_arrow("?[v, k] := *floats{k, v}", Default::default())
+            .unwrap();
+        assert_eq!(*batch.schema().field(0).data_type(), DataType::Float64);
+        assert_eq!(*batch.schema().field(1).data_type(), DataType::Int64);
+        assert_eq!(batch.column(0).as_primitive::<Float64Type>().value(0), 1.0);
+        assert!(batch.column(0).is_null(1));
+        // other queries inspect the values
+        let batch = db
+            .run_script_arrow("?[v] := *floats{v}, v > 0", Default::default())
+            .unwrap();
+        assert_eq!(*batch.schema().field(0).data_type(), DataType::Float64);
+        let batch = db
+            .run_script_arrow("?[k] := *floats{k}, k > 1", Default::default())
+            .unwrap();
+        assert_eq!(*batch.schema().field(0).data_type(), DataType::Int64);
+    }
+}
@@ -6,7 +6,7 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{Debug, Display, Formatter};
 use std::sync::atomic::Ordering;
 
@@ -18,13 +18,23 @@ use serde::Serialize;
 use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
+use crate::data::expr::{Expr, PredicateTypeError};
+use crate::data::functions::OP_EQ;
 use crate::data::memcmp::MemCmpEncoder;
-use crate::data::relation::StoredRelationMetadata;
+use crate::data::relation::{ColType, StoredRelationMetadata};
 use crate::data::symb::Symbol;
-use crate::data::tuple::{decode_tuple_from_key, Tuple, TupleT, ENCODED_KEY_MIN_LEN};
+use crate::data::tuple::{
+    decode_tuple_from_key, MalformedData, Tuple, TupleT, ENCODED_KEY_MIN_LEN,
+};
 use crate::data::value::{DataValue, ValidityTs};
 use crate::parse::SourceSpan;
 use crate::query::compile::IndexPositionUse;
+use crate::runtime::audit::AuditFlags;
+use crate::runtime::constraint::{ForeignKey, ForeignKeys};
+use crate::runtime::fts::FtsIndexManifest;
+use crate::runtime::hnsw::HnswIndexManifest;
+use crate::runtime::lsh::LshIndexManifest;
+use crate::runtime::retention::HistoryRetention;
 use crate::runtime::transact::SessionTx;
 use crate::{NamedRows, StoreTx};
 
@@ -77,6 +87,68 @@ pub(crate) struct RelationHandle {
     pub(crate) is_temp: bool,
     #[serde(default)]
     pub(crate) indices: BTreeMap<SmartString<LazyCompact>, (RelationHandle, Vec<usize>)>,
+    /// predicates of partial indices, keyed by index name, with bindings referring to the
+    /// columns of the relation
+    #[serde(default)]
+    pub(crate) index_predicates: BTreeMap<SmartString<LazyCompact>, Expr>,
+    #[serde(default)]
+    pub(crate) audit: AuditFlags,
+    /// the constraints declared by the relation, and those referring to it
+    #[serde(default)]
+    pub(crate) foreign_keys: Vec<ForeignKey>,
+    /// the column holding the time, in seconds since the epoch, after which a row expires,
+    /// set by `::set_ttl`
+    #[serde(default)]
+    pub(crate) ttl: Option<SmartString<LazyCompact>>,
+    /// how much of the history of the keys is kept, set by `::set_history_retention`
+    #[serde(default)]
+    pub(crate) history_retention: Option<HistoryRetention>,
+    /// the HNSW indices of the vector columns, by index name, with their graphs
+    #[serde(default)]
+    pub(crate) hnsw_indices:
+        BTreeMap<SmartString<LazyCompact>, (RelationHandle, HnswIndexManifest)>,
+    /// the full-text indices of the string columns, by index name, with their postings
+    #[serde(default)]
+    pub(crate) fts_indices: BTreeMap<SmartString<LazyCompact>, (RelationHandle, FtsIndexManifest)>,
+    /// the MinHash LSH indices of the string columns, by index name, with their buckets
+    #[serde(default)]
+    pub(crate) lsh_indices: BTreeMap<SmartString<LazyCompact>, (RelationHandle, LshIndexManifest)>,
+}
+
+/// Which rows of a relation are visible at the time of a transaction: rows whose TTL column
+/// holds a time that is not after it have expired, and are filtered out by queries
+/// until `::ttl_sweep` removes them.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Expiry {
+    /// the position of the TTL column, if the relation has one
+    ttl_pos: Option<usize>,
+    /// the time of the transaction, in seconds since the epoch
+    now: f64,
+}
+
+impl Expiry {
+    pub(crate) fn has_ttl(&self) -> bool {
+        self.ttl_pos.is_some()
+    }
+    /// Whether the row, given with keys and values, has not expired. Rows without
+    /// a number in the TTL column never expire.
+    pub(crate) fn is_live(&self, row: &[DataValue]) -> bool {
+        match self.ttl_pos.and_then(|pos| row[pos].get_float()) {
+            None => true,
+            Some(expires_at) => expires_at > self.now,
+        }
+    }
+}
+
+/// The result of checking an index against the rows of its relation
+pub(crate) struct IndexIntegrity {
+    pub(crate) index: SmartString<LazyCompact>,
+    /// number of entries in the index
+    pub(crate) entries: usize,
+    /// number of rows whose entries are not found in the index
+    pub(crate) missing: usize,
+    /// number of entries not corresponding to any row
+    pub(crate) extra: usize,
 }
 
 #[derive(
@@ -121,10 +193,102 @@ struct StoredRelArityMismatch {
     span: SourceSpan,
 }
 
+/// The name of the hidden index enforcing a unique column. It is not an identifier, so it
+/// cannot clash with the indices made by `::index create`, nor be removed by `::index drop`.
+pub(crate) fn unique_index_name(col: &str) -> SmartString<LazyCompact> {
+    SmartString::from(format!("{col}.unique"))
+}
+
+/// The column enforced unique by the index, if it is one made by [unique_index_name]
+pub(crate) fn unique_index_col(idx_name: &str) -> Option<&str> {
+    idx_name.strip_suffix(".unique")
+}
+
+/// The name of the hidden index of a column referring to another relation,
+/// made by `::constraint create`
+pub(crate) fn foreign_key_index_name(col: &str) -> SmartString<LazyCompact> {
+    SmartString::from(format!("{col}.ref"))
+}
+
+/// The column referring to another relation, if the index is one made by [foreign_key_index_name]
+pub(crate) fn foreign_key_index_col(idx_name: &str) -> Option<&str> {
+    idx_name.strip_suffix(".ref")
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Unique constraint on column '{column}' of relation '{relation}' violated: the value {value:?} is already in the row with keys {other:?}")]
+#[diagnostic(code(eval::unique_violation))]
+#[diagnostic(help("Rows cannot share a value of a unique column, unless it is null"))]
+pub(crate) struct UniqueViolation {
+    pub(crate) relation: String,
+    pub(crate) column: String,
+    pub(crate) value: DataValue,
+    pub(crate) other: Vec<DataValue>,
+}
+
+/// The key by which a conjunct of the predicate of a partial index is matched against
+/// the filters of queries. Arguments of equalities are ordered, otherwise the match is
+/// purely syntactic.
+pub(crate) fn index_filter_key(expr: &Expr) -> String {
+    match expr {
+        Expr::Apply { op, args, .. } if **op == OP_EQ && args.len() == 2 => {
+            let mut args = args.iter().map(|arg| arg.to_string()).collect_vec();
+            args.sort();
+            format!("eq({}, {})", args[0], args[1])
+        }
+        expr => expr.to_string(),
+    }
+}
+
+fn eval_index_predicate(pred: &Expr, row: &[DataValue]) -> Result<bool> {
+    match pred.eval(row)? {
+        DataValue::Bool(b) => Ok(b),
+        v => bail!(PredicateTypeError(pred.span(), v)),
+    }
+}
+
 impl RelationHandle {
     pub(crate) fn has_triggers(&self) -> bool {
         !self.put_triggers.is_empty() || !self.rm_triggers.is_empty()
     }
+    /// The rows of the relation visible in the transaction
+    pub(crate) fn expiry(&self, tx: &SessionTx<'_>) -> Expiry {
+        let ttl_pos = self.ttl.as_ref().and_then(|ttl| {
+            self.metadata
+                .keys
+                .iter()
+                .chain(self.metadata.non_keys.iter())
+                .position(|col| col.name == *ttl)
+        });
+        Expiry {
+            ttl_pos,
+            now: tx.now,
+        }
+    }
+    /// Whether the relation has indices besides the hidden ones of its unique columns
+    /// and constraints
+    pub(crate) fn has_user_indices(&self) -> bool {
+        !self.hnsw_indices.is_empty()
+            || !self.fts_indices.is_empty()
+            || !self.lsh_indices.is_empty()
+            || self.indices.keys().any(|name| {
+                unique_index_col(name).is_none() && foreign_key_index_col(name).is_none()
+            })
+    }
+    /// Whether writes to the relation have indices to update
+    pub(crate) fn has_indices(&self) -> bool {
+        !self.indices.is_empty()
+            || !self.hnsw_indices.is_empty()
+            || !self.fts_indices.is_empty()
+            || !self.lsh_indices.is_empty()
+    }
+    /// Whether the relation has an index of any kind with the name
+    pub(crate) fn has_index(&self, name: &str) -> bool {
+        self.indices.contains_key(name)
+            || self.hnsw_indices.contains_key(name)
+            || self.fts_indices.contains_key(name)
+            || self.lsh_indices.contains_key(name)
+    }
     fn encode_key_prefix(&self, len: usize) -> Vec<u8> {
         let mut ret = Vec::with_capacity(4 + 4 * len + 10 * len);
         let prefix_bytes = self.id.0.to_be_bytes();
@@ -152,10 +316,22 @@ impl RelationHandle {
         let prefix_bytes = self.id.0.to_be_bytes();
         data[0..8].copy_from_slice(&prefix_bytes);
     }
+    /// Whether the row, given as keys followed by values, has an entry in the index.
+    /// This is only false for rows not satisfying the predicate of a partial index.
+    pub(crate) fn index_includes(&self, idx_name: &str, row: &[DataValue]) -> Result<bool> {
+        match self.index_predicates.get(idx_name) {
+            None => Ok(true),
+            Some(pred) => eval_index_predicate(pred, row),
+        }
+    }
+    /// Chooses an index for the given uses of the columns. A partial index is only chosen
+    /// if every conjunct of its predicate is among `implied_filters`, which are the filters
+    /// of the query keyed by [index_filter_key], with variables renamed to the columns.
     pub(crate) fn choose_index(
         &self,
         arg_uses: &[IndexPositionUse],
         validity_query: bool,
+        implied_filters: &BTreeSet<String>,
     ) -> Option<(RelationHandle, Vec<usize>, bool)> {
         if self.indices.is_empty() {
             return None;
@@ -176,10 +352,19 @@ impl RelationHandle {
             })
             .collect_vec();
         let mut chosen = None;
-        for (manifest, mapper) in self.indices.values() {
+        for (name, (manifest, mapper)) in self.indices.iter() {
             if validity_query && *mapper.last().unwrap() != self.metadata.keys.len() - 1 {
                 continue;
             }
+            if let Some(pred) = self.index_predicates.get(name) {
+                if !pred
+                    .to_conjunction()
+                    .iter()
+                    .all(|conj| implied_filters.contains(&index_filter_key(conj)))
+                {
+                    continue;
+                }
+            }
 
             let mut cur_prefix_len = 0;
             for i in mapper {
@@ -191,7 +376,8 @@ impl RelationHandle {
             }
             if cur_prefix_len > max_prefix_len {
                 max_prefix_len = cur_prefix_len;
-                let mut need_join = false;
+                // the expiry of the rows is only known from the relation itself
+                let mut need_join = self.ttl.is_some();
                 for need_pos in required_positions.iter() {
                     if !mapper.contains(need_pos) {
                         need_join = true;
@@ -238,11 +424,8 @@ impl RelationHandle {
         tuple.serialize(&mut Serializer::new(&mut ret)).unwrap();
         Ok(ret)
     }
-    pub(crate) fn ensure_compatible(
-        &self,
-        inp: &InputRelationHandle,
-        is_remove: bool,
-    ) -> Result<()> {
+    /// With `partial`, for removals and updates, the input need not give the non-key columns
+    pub(crate) fn ensure_compatible(&self, inp: &InputRelationHandle, partial: bool) -> Result<()> {
         let InputRelationHandle { metadata, .. } = inp;
         // check that every given key is found and compatible
         for col in &metadata.keys {
@@ -255,7 +438,7 @@ impl RelationHandle {
         for col in &self.metadata.keys {
             metadata.satisfied_by_required_col(col, true)?;
         }
-        if !is_remove {
+        if !partial {
             for col in &self.metadata.non_keys {
                 metadata.satisfied_by_required_col(col, false)?;
             }
@@ -271,6 +454,9 @@ pub(crate) struct InputRelationHandle {
     pub(crate) key_bindings: Vec<Symbol>,
     pub(crate) dep_bindings: Vec<Symbol>,
     pub(crate) span: SourceSpan,
+    /// The parameters of the writing query, for the expressions of auto-updated columns
+    #[serde(default)]
+    pub(crate) params: BTreeMap<String, DataValue>,
 }
 
 impl Debug for RelationHandle {
@@ -329,18 +515,35 @@ impl RelationHandle {
         }
     }
 
+    pub(crate) fn skip_scan_window_all<'a>(
+        &self,
+        tx: &'a SessionTx<'_>,
+        valid_from: ValidityTs,
+        valid_to: ValidityTs,
+    ) -> impl Iterator<Item = Result<Tuple>> + 'a {
+        let lower = Tuple::default().encode_as_key(self.id);
+        let upper = Tuple::default().encode_as_key(self.id.next());
+        if self.is_temp {
+            tx.temp_store_tx
+                .range_skip_scan_tuple_window(&lower, &upper, valid_from, valid_to)
+        } else {
+            tx.store_tx
+                .range_skip_scan_tuple_window(&lower, &upper, valid_from, valid_to)
+        }
+    }
+
     pub(crate) fn get(&self, tx: &SessionTx<'_>, key: &[DataValue]) -> Result<Option<Tuple>> {
         let key_data = key.encode_as_key(self.id);
         if self.is_temp {
-            Ok(tx
-                .temp_store_tx
+            tx.temp_store_tx
                 .get(&key_data, false)?
-                .map(|val_data| decode_tuple_from_kv(&key_data, &val_data)))
+                .map(|val_data| decode_tuple_from_kv(&key_data, &val_data))
+                .transpose()
         } else {
-            Ok(tx
-                .store_tx
+            tx.store_tx
                 .get(&key_data, false)?
-                .map(|val_data| decode_tuple_from_kv(&key_data, &val_data)))
+                .map(|val_data| decode_tuple_from_kv(&key_data, &val_data))
+                .transpose()
         }
     }
 
@@ -394,6 +597,36 @@ impl RelationHandle {
         }
     }
 
+    pub(crate) fn skip_scan_window_prefix<'a>(
+        &self,
+        tx: &'a SessionTx<'_>,
+        prefix: &Tuple,
+        valid_from: ValidityTs,
+        valid_to: ValidityTs,
+    ) -> impl Iterator<Item = Result<Tuple>> + 'a {
+        let mut lower = prefix.clone();
+        lower.truncate(self.metadata.keys.len());
+        let mut upper = lower.clone();
+        upper.push(DataValue::Bot);
+        let prefix_encoded = lower.encode_as_key(self.id);
+        let upper_encoded = upper.encode_as_key(self.id);
+        if self.is_temp {
+            tx.temp_store_tx.range_skip_scan_tuple_window(
+                &prefix_encoded,
+                &upper_encoded,
+                valid_from,
+                valid_to,
+            )
+        } else {
+            tx.store_tx.range_skip_scan_tuple_window(
+                &prefix_encoded,
+                &upper_encoded,
+                valid_from,
+                valid_to,
+            )
+        }
+    }
+
     pub(crate) fn scan_bounded_prefix<'a>(
         &self,
         tx: &'a SessionTx<'_>,
@@ -401,10 +634,13 @@ impl RelationHandle {
         lower: &[DataValue],
         upper: &[DataValue],
     ) -> impl Iterator<Item = Result<Tuple>> + 'a {
+        // the bounds of non-key columns cannot bound the keys
         let mut lower_t = prefix.clone();
         lower_t.extend_from_slice(lower);
+        lower_t.truncate(self.metadata.keys.len());
         let mut upper_t = prefix.clone();
         upper_t.extend_from_slice(upper);
+        upper_t.truncate(self.metadata.keys.len());
         upper_t.push(DataValue::Bot);
         let lower_encoded = lower_t.encode_as_key(self.id);
         let upper_encoded = upper_t.encode_as_key(self.id);
@@ -423,10 +659,13 @@ impl RelationHandle {
         upper: &[DataValue],
         valid_at: ValidityTs,
     ) -> impl Iterator<Item = Result<Tuple>> + 'a {
+        // the bounds of non-key columns cannot bound the keys
         let mut lower_t = prefix.clone();
         lower_t.extend_from_slice(lower);
+        lower_t.truncate(self.metadata.keys.len());
         let mut upper_t = prefix.clone();
         upper_t.extend_from_slice(upper);
+        upper_t.truncate(self.metadata.keys.len());
         upper_t.push(DataValue::Bot);
         let lower_encoded = lower_t.encode_as_key(self.id);
         let upper_encoded = upper_t.encode_as_key(self.id);
@@ -443,19 +682,31 @@ impl RelationHandle {
 /// Decode tuple from key-value pairs. Used for customizing storage
 /// in trait [`StoreTx`](crate::StoreTx).
 #[inline]
-pub fn decode_tuple_from_kv(key: &[u8], val: &[u8]) -> Tuple {
-    let mut tup = decode_tuple_from_key(key);
-    extend_tuple_from_v(&mut tup, val);
-    tup
+pub fn decode_tuple_from_kv(key: &[u8], val: &[u8]) -> Result<Tuple> {
+    let mut tup = decode_tuple_from_key(key)?;
+    extend_tuple_from_v(&mut tup, val)?;
+    Ok(tup)
 }
 
-pub fn extend_tuple_from_v(key: &mut Tuple, val: &[u8]) {
+/// Decode the non-key columns from a value and append them to the key columns,
+/// for the skip scans of [`StoreTx`](crate::StoreTx).
+pub fn extend_tuple_from_v(key: &mut Tuple, val: &[u8]) -> Result<()> {
     if !val.is_empty() {
-        let vals: Vec<DataValue> = rmp_serde::from_slice(&val[ENCODED_KEY_MIN_LEN..]).unwrap();
+        let vals: Vec<DataValue> = val
+            .get(ENCODED_KEY_MIN_LEN..)
+            .and_then(|data| rmp_serde::from_slice(data).ok())
+            .ok_or_else(|| MalformedData("value", val.to_vec()))?;
         key.extend(vals);
     }
+    Ok(())
 }
 
+#[derive(Debug, Diagnostic, Error)]
+#[error("Cannot remove or rename relation {0} as it is involved in constraints")]
+#[diagnostic(code(eval::relation_with_constraints))]
+#[diagnostic(help("Drop the constraints with `::constraint drop` first"))]
+struct RelationWithConstraints(String);
+
 #[derive(Debug, Diagnostic, Error)]
 #[error("Cannot create relation {0} as one with the same name already exists")]
 #[diagnostic(code(eval::rel_name_conflict))]
@@ -501,6 +752,7 @@ impl<'a> SessionTx<'a> {
             .serialize(&mut Serializer::new(&mut meta_val).with_struct_map())
             .unwrap();
         self.store_tx.put(&name_key, &meta_val)?;
+        self.bump_schema_generation()?;
 
         Ok(())
     }
@@ -512,6 +764,7 @@ impl<'a> SessionTx<'a> {
         let encoded = vec![key].encode_as_key(RelationId::SYSTEM);
 
         let is_temp = input_meta.name.is_temp_store_name();
+        let rel_name = input_meta.name.clone();
 
         if is_temp {
             if self.store_tx.exists(&encoded, true)? {
@@ -537,6 +790,14 @@ impl<'a> SessionTx<'a> {
             access_level: AccessLevel::Normal,
             is_temp,
             indices: Default::default(),
+            index_predicates: Default::default(),
+            audit: Default::default(),
+            foreign_keys: vec![],
+            ttl: None,
+            history_retention: None,
+            hnsw_indices: Default::default(),
+            fts_indices: Default::default(),
+            lsh_indices: Default::default(),
         };
 
         let name_key = vec![DataValue::Str(meta.name.clone())].encode_as_key(RelationId::SYSTEM);
@@ -554,9 +815,32 @@ impl<'a> SessionTx<'a> {
             self.store_tx.put(&encoded, &meta.id.raw_encode())?;
             self.store_tx.put(&name_key, &meta_val)?;
             self.store_tx.put(&t_encoded, &meta.id.raw_encode())?;
+            self.bump_schema_generation()?;
+        }
+
+        let unique_cols = meta
+            .metadata
+            .non_keys
+            .iter()
+            .filter(|col| col.unique)
+            .map(|col| Symbol::new(col.name.clone(), Default::default()))
+            .collect_vec();
+        if unique_cols.is_empty() {
+            return Ok(meta);
         }
+        if is_temp {
+            #[derive(Debug, Error, Diagnostic)]
+            #[error("Temp relation {0} cannot have unique columns")]
+            #[diagnostic(code(eval::unique_in_temp_relation))]
+            struct UniqueInTempRelation(String);
 
-        Ok(meta)
+            bail!(UniqueInTempRelation(meta.name.to_string()))
+        }
+        for col in unique_cols {
+            let idx_name = Symbol::new(unique_index_name(&col.name), Default::default());
+            self.create_index(&rel_name, &idx_name, vec![col], None)?;
+        }
+        self.get_relation(&meta.name, false)
     }
     pub(crate) fn get_relation(&self, name: &str, lock: bool) -> Result<RelationHandle> {
         #[derive(Error, Diagnostic, Debug)]
@@ -579,14 +863,138 @@ impl<'a> SessionTx<'a> {
         let metadata = RelationHandle::decode(&found)?;
         Ok(metadata)
     }
-    pub(crate) fn destroy_relation(&mut self, name: &str) -> Result<(Vec<u8>, Vec<u8>)> {
+    /// Fails if another row of the relation has the value of a unique column of the row,
+    /// given with keys and values. The entries of the row itself are not in conflict,
+    /// so its old image need not be removed from the indices first.
+    /// The versions of a row of a relation with time travel, which only differ in the validity
+    /// ending the keys, are not in conflict with each other either.
+    pub(crate) fn check_unique(&self, handle: &RelationHandle, row: &[DataValue]) -> Result<()> {
+        let has_validity = matches!(
+            handle.metadata.keys.last(),
+            Some(col) if col.typing.coltype == ColType::Validity
+        );
+        for (idx_name, (idx_rel, extractor)) in handle.indices.iter() {
+            let col = match unique_index_col(idx_name) {
+                None => continue,
+                Some(col) => col,
+            };
+            let value = &row[extractor[0]];
+            if *value == DataValue::Null {
+                continue;
+            }
+            // the validity is the last of the keys following the column in the index
+            let compared = if has_validity {
+                &extractor[..extractor.len() - 1]
+            } else {
+                &extractor[..]
+            };
+            for entry in idx_rel.scan_prefix(self, &vec![value.clone()]) {
+                let entry = entry?;
+                if compared.iter().zip(&entry).any(|(i, v)| row[*i] != *v) {
+                    bail!(UniqueViolation {
+                        relation: handle.name.to_string(),
+                        column: col.to_string(),
+                        value: value.clone(),
+                        other: entry[1..].to_vec(),
+                    })
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Adds the entries of a row, given with keys and values, to the indices of the relation.
+    pub(crate) fn put_into_indices(
+        &mut self,
+        handle: &RelationHandle,
+        row: &[DataValue],
+    ) -> Result<()> {
+        for (idx_name, (idx_rel, extractor)) in handle.indices.iter() {
+            if !handle.index_includes(idx_name, row)? {
+                continue;
+            }
+            let idx_tup = extractor.iter().map(|i| row[*i].clone()).collect_vec();
+            let encoded = idx_rel.encode_key_for_store(&idx_tup, Default::default())?;
+            self.store_tx.put(&encoded, &[])?;
+        }
+        self.put_into_hnsw_indices(handle, row)?;
+        self.put_into_fts_indices(handle, row)?;
+        self.put_into_lsh_indices(handle, row)
+    }
+    /// Removes the entries of a row, given with keys and values, from the indices of the relation.
+    pub(crate) fn delete_from_indices(
+        &mut self,
+        handle: &RelationHandle,
+        row: &[DataValue],
+    ) -> Result<()> {
+        for (idx_name, (idx_rel, extractor)) in handle.indices.iter() {
+            if !handle.index_includes(idx_name, row)? {
+                continue;
+            }
+            let idx_tup = extractor.iter().map(|i| row[*i].clone()).collect_vec();
+            let encoded = idx_rel.encode_key_for_store(&idx_tup, Default::default())?;
+            self.store_tx.del(&encoded)?;
+        }
+        self.delete_from_hnsw_indices(handle, row)?;
+        self.delete_from_fts_indices(handle, row)?;
+        self.delete_from_lsh_indices(handle, row)
+    }
+    /// Writes a row, given with keys and values, replacing the stored row with the same keys,
+    /// for imports reading rows one at a time, which do not run triggers. The rows referred
+    /// to by the row must be stored already.
+    pub(crate) fn put_imported_row(
+        &mut self,
+        handle: &RelationHandle,
+        foreign_keys: &ForeignKeys,
+        row: &Tuple,
+    ) -> Result<()> {
+        let k_store = handle.encode_key_for_store(row, Default::default())?;
+        let has_indices = handle.has_indices();
+        if has_indices {
+            if let Some(existing) = self.store_tx.get(&k_store, false)? {
+                let mut old = row[..handle.metadata.keys.len()].to_vec();
+                extend_tuple_from_v(&mut old, &existing)?;
+                self.delete_from_indices(handle, &old)?;
+            }
+        }
+        let v_store = handle.encode_val_for_store(row, Default::default())?;
+        self.store_tx.put(&k_store, &v_store)?;
+        if has_indices {
+            self.check_unique(handle, row)?;
+            self.put_into_indices(handle, row)?;
+            // relations with constraints always have indices
+            if foreign_keys.has_outgoing() {
+                self.check_references(foreign_keys, row)?;
+            }
+        }
+        Ok(())
+    }
+    /// Removes the rows of the temp relation with the id, which is replaced in place
+    pub(crate) fn clear_temp_relation(&mut self, id: RelationId) -> Result<()> {
+        let lower = Tuple::default().encode_as_key(id);
+        let upper = Tuple::default().encode_as_key(id.next());
+        let keys: Vec<_> = self
+            .temp_store_tx
+            .range_scan(&lower, &upper)
+            .map_ok(|(k, _)| k)
+            .try_collect()?;
+        for key in keys {
+            self.temp_store_tx.del(&key)?;
+        }
+        Ok(())
+    }
+    /// Removes the relation together with the hidden indices of its unique columns.
+    /// Returns the ranges of their rows, to be cleared at the end of the transaction.
+    pub(crate) fn destroy_relation(&mut self, name: &str) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
         if name.starts_with('_') {
             bail!("Cannot destroy temp relation");
         }
         let store = self.get_relation(name, true)?;
-        if !store.indices.is_empty() {
+        if store.has_user_indices() {
             bail!("Cannot remove stored relation `{}` with indices attached.", name);
         }
+        if !store.foreign_keys.is_empty() {
+            bail!(RelationWithConstraints(store.name.to_string()))
+        }
         if store.access_level < AccessLevel::Normal {
             bail!(InsufficientAccessLevel(
                 store.name.to_string(),
@@ -595,16 +1003,25 @@ impl<'a> SessionTx<'a> {
             ))
         }
 
-        for k in store.indices.keys() {
-            self.destroy_relation(&format!("{name}:{k}"))?;
+        let mut bounds = vec![];
+        for k in store
+            .indices
+            .keys()
+            .chain(store.hnsw_indices.keys())
+            .chain(store.fts_indices.keys())
+            .chain(store.lsh_indices.keys())
+        {
+            bounds.extend(self.destroy_relation(&format!("{name}:{k}"))?);
         }
 
         let key = DataValue::from(name);
         let encoded = vec![key].encode_as_key(RelationId::SYSTEM);
         self.store_tx.del(&encoded)?;
+        self.bump_schema_generation()?;
         let lower_bound = Tuple::default().encode_as_key(store.id);
         let upper_bound = Tuple::default().encode_as_key(store.id.next());
-        Ok((lower_bound, upper_bound))
+        bounds.push((lower_bound, upper_bound));
+        Ok(bounds)
     }
     pub(crate) fn set_access_level(&mut self, rel: Symbol, level: AccessLevel) -> Result<()> {
         let mut meta = self.get_relation(&rel, true)?;
@@ -616,6 +1033,7 @@ impl<'a> SessionTx<'a> {
         meta.serialize(&mut Serializer::new(&mut meta_val).with_struct_map())
             .unwrap();
         self.store_tx.put(&name_key, &meta_val)?;
+        self.bump_schema_generation()?;
 
         Ok(())
     }
@@ -625,9 +1043,10 @@ impl<'a> SessionTx<'a> {
         rel_name: &Symbol,
         idx_name: &Symbol,
         cols: Vec<Symbol>,
+        predicate: Option<Expr>,
     ) -> Result<()> {
         let mut rel_handle = self.get_relation(rel_name, true)?;
-        if rel_handle.indices.contains_key(&idx_name.name) {
+        if rel_handle.has_index(&idx_name.name) {
             #[derive(Debug, Error, Diagnostic)]
             #[error("index {0} for relation {1} already exists")]
             #[diagnostic(code(tx::index_already_exists))]
@@ -639,6 +1058,11 @@ impl<'a> SessionTx<'a> {
             ));
         }
 
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("column {0} in index {1} for relation {2} not found")]
+        #[diagnostic(code(tx::col_in_idx_not_found))]
+        pub(crate) struct ColInIndexNotFound(String, String, String);
+
         let mut col_defs = vec![];
         'outer: for col in cols.iter() {
             for orig_col in rel_handle
@@ -653,11 +1077,6 @@ impl<'a> SessionTx<'a> {
                 }
             }
 
-            #[derive(Debug, Error, Diagnostic)]
-            #[error("column {0} in index {1} for relation {2} not found")]
-            #[diagnostic(code(tx::col_in_idx_not_found))]
-            pub(crate) struct ColInIndexNotFound(String, String, String);
-
             bail!(ColInIndexNotFound(
                 col.name.to_string(),
                 idx_name.name.to_string(),
@@ -674,6 +1093,31 @@ impl<'a> SessionTx<'a> {
             col_defs.push(key.clone());
         }
 
+        let predicate = match predicate {
+            None => None,
+            Some(mut pred) => {
+                let col_positions: BTreeMap<_, _> = rel_handle
+                    .metadata
+                    .keys
+                    .iter()
+                    .chain(rel_handle.metadata.non_keys.iter())
+                    .enumerate()
+                    .map(|(i, col)| (Symbol::new(col.name.clone(), Default::default()), i))
+                    .collect();
+                for binding in pred.bindings() {
+                    if !col_positions.contains_key(&binding) {
+                        bail!(ColInIndexNotFound(
+                            binding.name.to_string(),
+                            idx_name.name.to_string(),
+                            rel_name.name.to_string()
+                        ));
+                    }
+                }
+                pred.fill_binding_indices(&col_positions)?;
+                Some(pred)
+            }
+        };
+
         let key_bindings = col_defs
             .iter()
             .map(|col| Symbol::new(col.name.clone(), Default::default()))
@@ -692,6 +1136,7 @@ impl<'a> SessionTx<'a> {
             key_bindings,
             dep_bindings: vec![],
             span: Default::default(),
+            params: Default::default(),
         };
 
         let idx_handle = self.create_relation(idx_handle)?;
@@ -716,9 +1161,19 @@ impl<'a> SessionTx<'a> {
             })
             .collect_vec();
 
+        let in_index = |tuple: &Tuple| -> Result<bool> {
+            match &predicate {
+                None => Ok(true),
+                Some(pred) => eval_index_predicate(pred, tuple),
+            }
+        };
+
         if self.store_tx.supports_par_put() {
             for tuple in rel_handle.scan_all(self) {
                 let tuple = tuple?;
+                if !in_index(&tuple)? {
+                    continue;
+                }
                 let extracted = extraction_indices
                     .iter()
                     .map(|idx| tuple[*idx].clone())
@@ -729,6 +1184,9 @@ impl<'a> SessionTx<'a> {
         } else {
             for tuple in rel_handle.scan_all(self).collect_vec() {
                 let tuple = tuple?;
+                if !in_index(&tuple)? {
+                    continue;
+                }
                 let extracted = extraction_indices
                     .iter()
                     .map(|idx| tuple[*idx].clone())
@@ -741,6 +1199,11 @@ impl<'a> SessionTx<'a> {
         rel_handle
             .indices
             .insert(idx_name.name.clone(), (idx_handle, extraction_indices));
+        if let Some(pred) = predicate {
+            rel_handle
+                .index_predicates
+                .insert(idx_name.name.clone(), pred);
+        }
 
         let new_encoded =
             vec![DataValue::from(&rel_name.name as &str)].encode_as_key(RelationId::SYSTEM);
@@ -749,12 +1212,20 @@ impl<'a> SessionTx<'a> {
             .serialize(&mut Serializer::new(&mut meta_val))
             .unwrap();
         self.store_tx.put(&new_encoded, &meta_val)?;
+        self.bump_schema_generation()?;
 
         Ok(())
     }
 
-    pub(crate) fn remove_index(&mut self, rel_name: &Symbol, idx_name: &Symbol) -> Result<()> {
+    /// Removes the index, returning the ranges of its entries, to be cleared at the end
+    /// of the transaction.
+    pub(crate) fn remove_index(
+        &mut self,
+        rel_name: &Symbol,
+        idx_name: &Symbol,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
         let mut rel = self.get_relation(rel_name, true)?;
+        rel.index_predicates.remove(&idx_name.name);
         if rel.indices.remove(&idx_name.name).is_none() {
             #[derive(Debug, Error, Diagnostic)]
             #[error("index {0} for relation {1} not found")]
@@ -764,15 +1235,69 @@ impl<'a> SessionTx<'a> {
             bail!(IndexNotFound(idx_name.to_string(), rel_name.to_string()));
         }
 
-        self.destroy_relation(&format!("{}:{}", rel_name.name, idx_name.name))?;
+        let cleared = self.destroy_relation(&format!("{}:{}", rel_name.name, idx_name.name))?;
 
         let new_encoded =
             vec![DataValue::from(&rel_name.name as &str)].encode_as_key(RelationId::SYSTEM);
         let mut meta_val = vec![];
         rel.serialize(&mut Serializer::new(&mut meta_val)).unwrap();
         self.store_tx.put(&new_encoded, &meta_val)?;
+        self.bump_schema_generation()?;
 
-        Ok(())
+        Ok(cleared)
+    }
+
+    /// Checks the indices of the relation against its rows, scanning both.
+    pub(crate) fn check_index_integrity(
+        &self,
+        handle: &RelationHandle,
+    ) -> Result<Vec<IndexIntegrity>> {
+        let mut ret = vec![];
+        for (idx_name, (idx_rel, extractor)) in handle.indices.iter() {
+            let mut missing = 0;
+            for row in handle.scan_all(self) {
+                let row = row?;
+                if !handle.index_includes(idx_name, &row)? {
+                    continue;
+                }
+                let idx_tup = extractor.iter().map(|i| row[*i].clone()).collect_vec();
+                if !idx_rel.exists(self, &idx_tup)? {
+                    missing += 1;
+                }
+            }
+
+            // every key of the relation is a column of the index
+            let key_positions = (0..handle.metadata.keys.len())
+                .map(|k| extractor.iter().position(|i| *i == k).unwrap())
+                .collect_vec();
+            let mut entries = 0;
+            let mut extra = 0;
+            for entry in idx_rel.scan_all(self) {
+                let entry = entry?;
+                entries += 1;
+                let key = key_positions
+                    .iter()
+                    .map(|i| entry[*i].clone())
+                    .collect_vec();
+                let expected = match handle.get(self, &key)? {
+                    None => false,
+                    Some(row) => {
+                        handle.index_includes(idx_name, &row)?
+                            && extractor.iter().map(|i| &row[*i]).eq(entry.iter())
+                    }
+                };
+                if !expected {
+                    extra += 1;
+                }
+            }
+            ret.push(IndexIntegrity {
+                index: idx_name.clone(),
+                entries,
+                missing,
+                extra,
+            });
+        }
+        Ok(ret)
     }
 
     pub(crate) fn rename_relation(&mut self, old: Symbol, new: Symbol) -> Result<()> {
@@ -790,6 +1315,9 @@ impl<'a> SessionTx<'a> {
         let old_encoded = vec![old_key].encode_as_key(RelationId::SYSTEM);
 
         let mut rel = self.get_relation(&old, true)?;
+        if !rel.foreign_keys.is_empty() {
+            bail!(RelationWithConstraints(rel.name.to_string()))
+        }
         if rel.access_level < AccessLevel::Normal {
             bail!(InsufficientAccessLevel(
                 rel.name.to_string(),
@@ -803,9 +1331,90 @@ impl<'a> SessionTx<'a> {
         rel.serialize(&mut Serializer::new(&mut meta_val)).unwrap();
         self.store_tx.del(&old_encoded)?;
         self.store_tx.put(&new_encoded, &meta_val)?;
+        self.bump_schema_generation()?;
 
         Ok(())
     }
+    /// Moves the rows of a relation and of its indices to freshly allocated key prefixes,
+    /// and points the catalog at them. Returns the old key ranges, to be deleted after
+    /// the transaction is committed.
+    pub(crate) fn rebuild_relation(&mut self, name: &Symbol) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        if name.name.starts_with('_') {
+            bail!("Cannot rebuild temp relation");
+        }
+        let mut rel = self.get_relation(name, true)?;
+        if rel.access_level < AccessLevel::Normal {
+            bail!(InsufficientAccessLevel(
+                rel.name.to_string(),
+                "rebuilding relation".to_string(),
+                rel.access_level
+            ));
+        }
+
+        let mut old_ranges = vec![self.move_relation_rows(&mut rel)?];
+        for (idx_rel, _) in rel.indices.values_mut() {
+            old_ranges.push(self.move_relation_rows(idx_rel)?);
+            self.put_relation_meta(idx_rel)?;
+        }
+        for (idx_rel, _) in rel.hnsw_indices.values_mut() {
+            old_ranges.push(self.move_relation_rows(idx_rel)?);
+            self.put_relation_meta(idx_rel)?;
+        }
+        for (idx_rel, _) in rel.fts_indices.values_mut() {
+            old_ranges.push(self.move_relation_rows(idx_rel)?);
+            self.put_relation_meta(idx_rel)?;
+        }
+        for (idx_rel, _) in rel.lsh_indices.values_mut() {
+            old_ranges.push(self.move_relation_rows(idx_rel)?);
+            self.put_relation_meta(idx_rel)?;
+        }
+        self.put_relation_meta(&rel)?;
+        self.bump_schema_generation()?;
+
+        Ok(old_ranges)
+    }
+    fn move_relation_rows(&mut self, handle: &mut RelationHandle) -> Result<(Vec<u8>, Vec<u8>)> {
+        let new_id = RelationId::new(self.relation_store_id.fetch_add(1, Ordering::SeqCst) + 1);
+        let t_encoded = vec![DataValue::Null].encode_as_key(RelationId::SYSTEM);
+        self.store_tx.put(&t_encoded, &new_id.raw_encode())?;
+
+        let lower_bound = Tuple::default().encode_as_key(handle.id);
+        let upper_bound = Tuple::default().encode_as_key(handle.id.next());
+        let prefix = new_id.raw_encode();
+        let moved = |(mut k, mut v): (Vec<u8>, Vec<u8>)| {
+            k[..ENCODED_KEY_MIN_LEN].copy_from_slice(&prefix);
+            if !v.is_empty() {
+                v[..ENCODED_KEY_MIN_LEN].copy_from_slice(&prefix);
+            }
+            (k, v)
+        };
+        if self.store_tx.supports_par_put() {
+            for kv in self.store_tx.range_scan(&lower_bound, &upper_bound) {
+                let (k, v) = moved(kv?);
+                self.store_tx.par_put(&k, &v)?;
+            }
+        } else {
+            for kv in self
+                .store_tx
+                .range_scan(&lower_bound, &upper_bound)
+                .collect_vec()
+            {
+                let (k, v) = moved(kv?);
+                self.store_tx.put(&k, &v)?;
+            }
+        }
+
+        handle.id = new_id;
+        Ok((lower_bound, upper_bound))
+    }
+    pub(crate) fn put_relation_meta(&mut self, handle: &RelationHandle) -> Result<()> {
+        let name_key = vec![DataValue::Str(handle.name.clone())].encode_as_key(RelationId::SYSTEM);
+        let mut meta_val = vec![];
+        handle
+            .serialize(&mut Serializer::new(&mut meta_val).with_struct_map())
+            .unwrap();
+        self.store_tx.put(&name_key, &meta_val)
+    }
     pub(crate) fn rename_temp_relation(&mut self, old: Symbol, new: Symbol) -> Result<()> {
         let new_key = DataValue::Str(new.name.clone());
         let new_encoded = vec![new_key].encode_as_key(RelationId::SYSTEM);
@@ -0,0 +1,335 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::BTreeSet;
+
+use miette::{bail, Diagnostic, Result};
+use thiserror::Error;
+
+use crate::data::relation::{ColType, NullableColType};
+use crate::data::symb::Symbol;
+use crate::data::tuple::{Tuple, TupleT};
+use crate::data::value::{DataValue, ValidityTs};
+use crate::parse::SourceSpan;
+use crate::runtime::relation::{
+    decode_tuple_from_kv, AccessLevel, InsufficientAccessLevel, RelationHandle,
+};
+use crate::runtime::transact::SessionTx;
+
+/// How much of the history of each key of a relation with validity is kept, set by
+/// `::set_history_retention rel {max_versions: 10, max_age_seconds: 2592000}`.
+/// The versions of a key beyond the policy are removed whenever the key is written to,
+/// except for the current version and those valid in the future, which are always kept.
+#[derive(Debug, Clone, Eq, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) struct HistoryRetention {
+    /// the number of versions kept, counting the current one
+    pub(crate) max_versions: Option<usize>,
+    /// how long versions are kept after they stop being valid
+    pub(crate) max_age_seconds: Option<i64>,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Bad option '{0}' for history retention")]
+#[diagnostic(code(parser::bad_history_retention))]
+#[diagnostic(help("{1}"))]
+struct BadHistoryRetention(String, String, #[label] SourceSpan);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("No history retention policy given")]
+#[diagnostic(code(parser::bad_history_retention))]
+#[diagnostic(help("Give 'max_versions' or 'max_age_seconds', or null to keep all history"))]
+struct EmptyHistoryRetention(#[label] SourceSpan);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Relation '{0}' does not keep history")]
+#[diagnostic(code(eval::history_retention_without_validity))]
+#[diagnostic(help(
+    "Only the relations whose last key column is of type 'Validity' keep the history of their rows"
+))]
+struct NoHistoryKept(String);
+
+impl HistoryRetention {
+    /// The policy with the given options, of which at least one is required
+    pub(crate) fn new(
+        given: Vec<(String, DataValue, SourceSpan)>,
+        span: SourceSpan,
+    ) -> Result<Self> {
+        let mut ret = Self {
+            max_versions: None,
+            max_age_seconds: None,
+        };
+        for (name, value, span) in given {
+            let bad = |help: &str| BadHistoryRetention(name.clone(), help.to_string(), span);
+            match &name as &str {
+                "max_versions" => match value.get_int() {
+                    Some(n) if n >= 1 => ret.max_versions = Some(n as usize),
+                    _ => bail!(bad(
+                        "'max_versions' must be a positive integer, as the current version is always kept"
+                    )),
+                },
+                "max_age_seconds" => match value.get_int() {
+                    Some(n) if n >= 0 => ret.max_age_seconds = Some(n),
+                    _ => bail!(bad("'max_age_seconds' must be a non-negative integer")),
+                },
+                _ => bail!(bad("The options are 'max_versions' and 'max_age_seconds'")),
+            }
+        }
+        if ret.max_versions.is_none() && ret.max_age_seconds.is_none() {
+            bail!(EmptyHistoryRetention(span))
+        }
+        Ok(ret)
+    }
+}
+
+impl<'a> SessionTx<'a> {
+    /// Sets the policy bounding the history kept of the keys of the relation,
+    /// or keeps all history when no policy is given
+    pub(crate) fn set_history_retention(
+        &mut self,
+        rel: &Symbol,
+        retention: Option<HistoryRetention>,
+    ) -> Result<()> {
+        if rel.name.starts_with('_') {
+            bail!("Cannot set history retention of temp relation");
+        }
+        let mut handle = self.get_relation(rel, true)?;
+        if handle.access_level < AccessLevel::Normal {
+            bail!(InsufficientAccessLevel(
+                handle.name.to_string(),
+                "setting history retention".to_string(),
+                handle.access_level
+            ));
+        }
+        if retention.is_some()
+            && handle.metadata.keys.last().unwrap().typing
+                != (NullableColType {
+                    coltype: ColType::Validity,
+                    nullable: false,
+                })
+        {
+            bail!(NoHistoryKept(handle.name.to_string()))
+        }
+        handle.history_retention = retention;
+        self.put_relation_meta(&handle)?;
+        self.bump_schema_generation()
+    }
+    /// Removes the versions beyond the retention policy of the relation, if it has one,
+    /// of the keys written to, given without their validity. Versions valid after
+    /// `cur_vld` are not counted. Returns the number of versions removed.
+    // keys are only mutable to clippy for the regex a `DataValue` may hold
+    #[allow(clippy::mutable_key_type)]
+    pub(crate) fn trim_history(
+        &mut self,
+        handle: &RelationHandle,
+        keys: &BTreeSet<Tuple>,
+        cur_vld: ValidityTs,
+    ) -> Result<usize> {
+        let retention = match &handle.history_retention {
+            None => return Ok(0),
+            Some(retention) => retention,
+        };
+        let now = cur_vld.0 .0;
+        let cutoff = retention
+            .max_age_seconds
+            .map(|secs| now.saturating_sub(secs.saturating_mul(1_000_000)));
+        let has_indices = handle.has_indices();
+        let mut removed = 0;
+        for key in keys {
+            let lower = key.encode_as_key(handle.id);
+            let mut upper = key.clone();
+            upper.push(DataValue::Bot);
+            let upper = upper.encode_as_key(handle.id);
+
+            // the versions are stored newest first
+            let mut n_past = 0;
+            let mut newer = None;
+            let mut expired = vec![];
+            for kv in self.store_tx.range_scan(&lower, &upper) {
+                let (k, v) = kv?;
+                let row = decode_tuple_from_kv(&k, &v)?;
+                let ts = match &row[key.len()] {
+                    DataValue::Validity(vld) => vld.timestamp.0 .0,
+                    _ => unreachable!(),
+                };
+                // a version stops being valid when the next newer one starts
+                let valid_until = newer.replace(ts);
+                if ts > now {
+                    continue;
+                }
+                n_past += 1;
+                if n_past == 1 {
+                    // the current version
+                    continue;
+                }
+                let too_many = retention.max_versions.is_some_and(|n| n_past > n);
+                let too_old = match (cutoff, valid_until) {
+                    (Some(cutoff), Some(until)) => until <= cutoff,
+                    _ => false,
+                };
+                if too_many || too_old {
+                    expired.push((k, row));
+                }
+            }
+            for (k, row) in &expired {
+                if has_indices {
+                    self.delete_from_indices(handle, row)?;
+                }
+                self.store_tx.del(k)?;
+            }
+            removed += expired.len();
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde_json::json;
+
+    use crate::data::value::DataValue;
+    use crate::{new_cozo_mem, VirtualClock};
+
+    #[test]
+    fn test_history_retention() {
+        let db = new_cozo_mem().unwrap();
+        let clock = VirtualClock::new(100_000_000_000);
+        db.set_clock(clock.as_clock_fn());
+        db.run_script(":create vld {k, at: Validity => v}", Default::default())
+            .unwrap();
+        db.run_script(
+            "::set_history_retention vld {max_versions: 3}",
+            Default::default(),
+        )
+        .unwrap();
+        let count = |k: i64| {
+            db.run_script(
+                "?[count(at)] := *vld{k: $k, at}",
+                BTreeMap::from([("k".to_string(), DataValue::from(k))]),
+            )
+            .unwrap()
+            .rows[0][0]
+                .get_int()
+                .unwrap()
+        };
+        for i in 1..=10 {
+            db.run_script(
+                "?[k, at, v] <- [[1, [$ts, true], $i]] :put vld {k, at => v}",
+                BTreeMap::from([
+                    ("ts".to_string(), DataValue::from(i * 1_000_000)),
+                    ("i".to_string(), DataValue::from(i)),
+                ]),
+            )
+            .unwrap();
+            assert!(count(1) <= 3);
+        }
+        let res = db
+            .run_script("?[at, v] := *vld{k: 1, at, v}", Default::default())
+            .unwrap();
+        assert_eq!(
+            res.into_json()["rows"],
+            json!([
+                [[10000000, true], 10],
+                [[9000000, true], 9],
+                [[8000000, true], 8]
+            ])
+        );
+        let res = db
+            .run_script("?[v] := *vld{k: 1, v @ 9500000}", Default::default())
+            .unwrap();
+        assert_eq!(res.into_json()["rows"], json!([[9]]));
+
+        // versions valid in the future are not counted, and the current one is always kept
+        db.run_script(
+            "?[k, at, v] <- [[1, [200000000000, true], 99]] :put vld {k, at => v}",
+            Default::default(),
+        )
+        .unwrap();
+        assert_eq!(count(1), 4);
+        let res = db
+            .run_script("?[v] := *vld{k: 1, v @ 'NOW'}", Default::default())
+            .unwrap();
+        assert_eq!(res.into_json()["rows"], json!([[10]]));
+
+        let res = db.run_script("::relations", Default::default()).unwrap();
+        assert_eq!(res.headers[8], "history_max_versions");
+        assert_eq!(res.rows[0][8], DataValue::from(3));
+        assert_eq!(res.rows[0][9], DataValue::Null);
+
+        // versions are kept for a while after they stop being valid
+        db.run_script(
+            "::set_history_retention vld {max_age_seconds: 5}",
+            Default::default(),
+        )
+        .unwrap();
+        for i in 1..=20 {
+            clock.advance(1_000_000);
+            db.run_script(
+                "?[k, at, v] <- [[2, 'ASSERT', $i]] :put vld {k, at => v}",
+                BTreeMap::from([("i".to_string(), DataValue::from(i))]),
+            )
+            .unwrap();
+            assert!(count(2) <= 6);
+        }
+        assert_eq!(count(2), 6);
+        let res = db
+            .run_script("?[v] := *vld{k: 2, v @ 100017500000}", Default::default())
+            .unwrap();
+        assert_eq!(res.into_json()["rows"], json!([[17]]));
+        let res = db
+            .run_script("?[v] := *vld{k: 2, v @ 100010500000}", Default::default())
+            .unwrap();
+        assert_eq!(res.into_json()["rows"], json!([]));
+
+        // without a policy, all history is kept again
+        db.run_script("::set_history_retention vld null", Default::default())
+            .unwrap();
+        for i in 21..=30 {
+            clock.advance(1_000_000);
+            db.run_script(
+                "?[k, at, v] <- [[2, 'ASSERT', $i]] :put vld {k, at => v}",
+                BTreeMap::from([("i".to_string(), DataValue::from(i))]),
+            )
+            .unwrap();
+        }
+        assert_eq!(count(2), 16);
+        let res = db.run_script("::relations", Default::default()).unwrap();
+        assert_eq!(res.rows[0][8], DataValue::Null);
+
+        for bad in [
+            "{}",
+            "{max_versions: 0}",
+            "{max_age_seconds: -1}",
+            "{keep: 1}",
+        ] {
+            let err = db
+                .run_script(
+                    &format!("::set_history_retention vld {bad}"),
+                    Default::default(),
+                )
+                .unwrap_err();
+            assert_eq!(
+                err.code().unwrap().to_string(),
+                "parser::bad_history_retention"
+            );
+        }
+        db.run_script(":create plain {k => v}", Default::default())
+            .unwrap();
+        let err = db
+            .run_script(
+                "::set_history_retention plain {max_versions: 1}",
+                Default::default(),
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.code().unwrap().to_string(),
+            "eval::history_retention_without_validity"
+        );
+    }
+}
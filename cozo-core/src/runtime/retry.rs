@@ -0,0 +1,86 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use miette::Result;
+
+use crate::data::value::DataValue;
+use crate::runtime::db::{Db, NamedRows};
+use crate::runtime::error::CozoError;
+use crate::storage::Storage;
+
+/// How [Db::run_script_with_retry] runs scripts again
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// The most times the script is run, the first time included. Defaults to 5.
+    pub max_attempts: usize,
+    /// The wait before the second attempt, doubled before each attempt after it.
+    /// Defaults to 10 milliseconds.
+    pub base_backoff: Duration,
+    /// The fraction of each wait that is random, between 0 and 1, so that conflicting writers
+    /// do not retry in lockstep: a wait `w` becomes a random duration between `w * (1 - jitter)`
+    /// and `w`. Defaults to 0.5.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_backoff: Duration::from_millis(10),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The wait after the failed attempt, counted from 1
+    fn backoff(&self, attempt: usize) -> Duration {
+        let doublings = (attempt - 1).min(16) as u32;
+        let backoff = self.base_backoff.saturating_mul(1 << doublings);
+        let jitter = self.jitter.clamp(0., 1.);
+        backoff.mul_f64(1. - jitter * rand::random::<f64>())
+    }
+}
+
+impl<'s, S: Storage<'s>> Db<S> {
+    /// Run the script as [Self::run_script] does, running it again in a new transaction
+    /// when it fails with a transient error, such as a conflict with a transaction committed
+    /// concurrently (see [CozoError::is_transient]). Before each attempt after the first,
+    /// the thread sleeps for a backoff given by the `policy`. If the last attempt allowed fails,
+    /// its error is returned. The attempts made are given by [NamedRows::attempts].
+    ///
+    /// Failed attempts commit nothing, so the writes of their triggers are undone and
+    /// callbacks are only sent for the attempt that succeeds. The script is run again as it is,
+    /// so anything else it does, such as fetching data from the web, is done again.
+    pub fn run_script_with_retry(
+        &'s self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+        policy: RetryPolicy,
+    ) -> Result<NamedRows> {
+        let mut attempt = 1;
+        loop {
+            match self.run_script(payload, params.clone()) {
+                Ok(mut res) => {
+                    res.attempts = Some(attempt);
+                    return Ok(res);
+                }
+                Err(err)
+                    if attempt < policy.max_attempts && CozoError::is_transient_report(&err) =>
+                {
+                    std::thread::sleep(policy.backoff(attempt));
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
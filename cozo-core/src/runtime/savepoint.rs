@@ -0,0 +1,382 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use miette::Result;
+use smartstring::{LazyCompact, SmartString};
+
+use crate::data::tuple::Tuple;
+use crate::data::value::ValidityTs;
+use crate::runtime::callback::CallbackCollector;
+use crate::runtime::transact::SessionTx;
+use crate::storage::StoreTx;
+
+/// The previous values of the keys written while savepoints are set, in the order written.
+/// Rolling back writes them back in the reverse order.
+#[derive(Default)]
+pub(crate) struct UndoLog {
+    recording: AtomicBool,
+    entries: Mutex<Vec<(Vec<u8>, Option<Vec<u8>>)>>,
+}
+
+impl UndoLog {
+    fn record(&self, tx: &dyn StoreTx<'_>, key: &[u8]) -> Result<()> {
+        if self.recording.load(Ordering::Acquire) {
+            let prev = tx.get(key, false)?;
+            self.entries.lock().unwrap().push((key.to_vec(), prev));
+        }
+        Ok(())
+    }
+    fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+}
+
+/// Wraps the storage transaction of a write transaction, recording the previous values
+/// of the keys written in its [UndoLog]
+pub(crate) struct UndoLogTx<'s> {
+    inner: Box<dyn StoreTx<'s> + 's>,
+    log: Arc<UndoLog>,
+}
+
+impl<'s> UndoLogTx<'s> {
+    pub(crate) fn new(inner: Box<dyn StoreTx<'s> + 's>, log: Arc<UndoLog>) -> Self {
+        Self { inner, log }
+    }
+}
+
+impl<'s> StoreTx<'s> for UndoLogTx<'s> {
+    fn get(&self, key: &[u8], for_update: bool) -> Result<Option<Vec<u8>>> {
+        self.inner.get(key, for_update)
+    }
+
+    fn multi_get(&self, keys: &[&[u8]], for_update: bool) -> Result<Vec<Option<Vec<u8>>>> {
+        self.inner.multi_get(keys, for_update)
+    }
+
+    fn put(&mut self, key: &[u8], val: &[u8]) -> Result<()> {
+        self.log.record(&*self.inner, key)?;
+        self.inner.put(key, val)
+    }
+
+    fn supports_par_put(&self) -> bool {
+        self.inner.supports_par_put()
+    }
+
+    fn par_put(&self, key: &[u8], val: &[u8]) -> Result<()> {
+        self.log.record(&*self.inner, key)?;
+        self.inner.par_put(key, val)
+    }
+
+    fn del(&mut self, key: &[u8]) -> Result<()> {
+        self.log.record(&*self.inner, key)?;
+        self.inner.del(key)
+    }
+
+    fn exists(&self, key: &[u8], for_update: bool) -> Result<bool> {
+        self.inner.exists(key, for_update)
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        self.inner.commit()
+    }
+
+    fn range_scan_tuple<'a>(
+        &'a self,
+        lower: &[u8],
+        upper: &[u8],
+    ) -> Box<dyn Iterator<Item = Result<Tuple>> + 'a>
+    where
+        's: 'a,
+    {
+        self.inner.range_scan_tuple(lower, upper)
+    }
+
+    fn range_skip_scan_tuple<'a>(
+        &'a self,
+        lower: &[u8],
+        upper: &[u8],
+        valid_at: ValidityTs,
+    ) -> Box<dyn Iterator<Item = Result<Tuple>> + 'a> {
+        self.inner.range_skip_scan_tuple(lower, upper, valid_at)
+    }
+
+    fn range_skip_scan_tuple_window<'a>(
+        &'a self,
+        lower: &[u8],
+        upper: &[u8],
+        valid_from: ValidityTs,
+        valid_to: ValidityTs,
+    ) -> Box<dyn Iterator<Item = Result<Tuple>> + 'a>
+    where
+        's: 'a,
+    {
+        self.inner
+            .range_skip_scan_tuple_window(lower, upper, valid_from, valid_to)
+    }
+
+    fn range_scan<'a>(
+        &'a self,
+        lower: &[u8],
+        upper: &[u8],
+    ) -> Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>
+    where
+        's: 'a,
+    {
+        self.inner.range_scan(lower, upper)
+    }
+
+    fn total_scan<'a>(&'a self) -> Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>
+    where
+        's: 'a,
+    {
+        self.inner.total_scan()
+    }
+}
+
+/// A point of a transaction that its writes can be rolled back to
+struct Savepoint {
+    /// `None` for the savepoints set around each `%ignore_error` statement
+    name: Option<SmartString<LazyCompact>>,
+    undo_len: usize,
+    temp_undo_len: usize,
+    cleanups_len: usize,
+    callbacks_len: BTreeMap<SmartString<LazyCompact>, usize>,
+}
+
+/// The savepoints set in a transaction, innermost last
+#[derive(Default)]
+pub(crate) struct Savepoints {
+    log: Arc<UndoLog>,
+    stack: Vec<Savepoint>,
+}
+
+impl Savepoints {
+    /// The log of the writes to roll back, to be kept by the storage transaction
+    pub(crate) fn undo_log(&self) -> Arc<UndoLog> {
+        self.log.clone()
+    }
+}
+
+impl<'a> SessionTx<'a> {
+    /// Sets a savepoint, returning its position. A savepoint with the same name is released
+    /// first, together with the savepoints set after it.
+    pub(crate) fn set_savepoint(
+        &mut self,
+        name: Option<SmartString<LazyCompact>>,
+        cleanups: &[(Vec<u8>, Vec<u8>)],
+        callback_collector: &CallbackCollector,
+    ) -> usize {
+        if let Some(name) = &name {
+            if let Some(i) = self.find_savepoint(Some(name.as_str())) {
+                self.release_savepoints(i);
+            }
+        }
+        if self.savepoints.stack.is_empty() {
+            self.savepoints.log.recording.store(true, Ordering::Release);
+            self.temp_store_tx.set_recording(true);
+        }
+        let savepoint = Savepoint {
+            name,
+            undo_len: self.savepoints.log.len(),
+            temp_undo_len: self.temp_store_tx.undo_len(),
+            cleanups_len: cleanups.len(),
+            callbacks_len: callback_collector
+                .iter()
+                .map(|(rel, events)| (rel.clone(), events.len()))
+                .collect(),
+        };
+        self.savepoints.stack.push(savepoint);
+        self.savepoints.stack.len() - 1
+    }
+    /// Rolls back the writes done since the savepoint with the given name, or the last one
+    /// if `None`, which is kept, together with the callbacks and cleanups they collected.
+    /// The savepoints set after it are released. Returns `false` if there is no such savepoint.
+    pub(crate) fn rollback_to_savepoint(
+        &mut self,
+        name: Option<&str>,
+        cleanups: &mut Vec<(Vec<u8>, Vec<u8>)>,
+        callback_collector: &mut CallbackCollector,
+    ) -> Result<bool> {
+        match self.find_savepoint(name) {
+            None => Ok(false),
+            Some(i) => self.rollback_to_savepoint_at(i, cleanups, callback_collector),
+        }
+    }
+    /// Rolls back to the savepoint at the position given, as [SessionTx::rollback_to_savepoint].
+    /// Returns `false` if it has been released.
+    pub(crate) fn rollback_to_savepoint_at(
+        &mut self,
+        i: usize,
+        cleanups: &mut Vec<(Vec<u8>, Vec<u8>)>,
+        callback_collector: &mut CallbackCollector,
+    ) -> Result<bool> {
+        if i >= self.savepoints.stack.len() {
+            return Ok(false);
+        }
+        self.savepoints.stack.truncate(i + 1);
+        let savepoint = &self.savepoints.stack[i];
+        let undone = self
+            .savepoints
+            .log
+            .entries
+            .lock()
+            .unwrap()
+            .split_off(savepoint.undo_len);
+        for (key, prev) in undone.into_iter().rev() {
+            match prev {
+                Some(val) => self.store_tx.put(&key, &val)?,
+                None => self.store_tx.del(&key)?,
+            }
+        }
+        // writing back the previous values recorded them again
+        self.savepoints
+            .log
+            .entries
+            .lock()
+            .unwrap()
+            .truncate(savepoint.undo_len);
+        self.temp_store_tx.undo_to(savepoint.temp_undo_len);
+        cleanups.truncate(savepoint.cleanups_len);
+        callback_collector.retain(|rel, events| match savepoint.callbacks_len.get(rel) {
+            None => false,
+            Some(len) => {
+                events.truncate(*len);
+                true
+            }
+        });
+        Ok(true)
+    }
+    /// Releases the last savepoint set, keeping the writes done since
+    pub(crate) fn release_last_savepoint(&mut self) {
+        if let Some(i) = self.savepoints.stack.len().checked_sub(1) {
+            self.release_savepoints(i);
+        }
+    }
+    /// Releases the savepoint at the position given and those set after it
+    pub(crate) fn release_savepoints(&mut self, from: usize) {
+        self.savepoints.stack.truncate(from);
+        if self.savepoints.stack.is_empty() {
+            self.savepoints
+                .log
+                .recording
+                .store(false, Ordering::Release);
+            self.savepoints.log.entries.lock().unwrap().clear();
+            self.temp_store_tx.set_recording(false);
+        }
+    }
+    fn find_savepoint(&self, name: Option<&str>) -> Option<usize> {
+        match name {
+            None => self.savepoints.stack.len().checked_sub(1),
+            Some(name) => self
+                .savepoints
+                .stack
+                .iter()
+                .rposition(|sp| sp.name.as_deref() == Some(name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+    use serde_json::json;
+
+    use crate::data::value::DataValue;
+    use crate::{new_cozo_mem, CozoError};
+
+    #[test]
+    fn test_savepoints() {
+        let db = new_cozo_mem().unwrap();
+        db.run_script(
+            r"
+        {:create a {k => v}}
+        {:create b {k}}
+        {:create log {k}}
+        ",
+            Default::default(),
+        )
+        .unwrap();
+        db.run_script(
+            "::set_triggers a on put { ?[k] := _new[k, _] :put log {k} }",
+            Default::default(),
+        )
+        .unwrap();
+        db.run_script(
+            "::set_triggers b on put { ?[k] := _new[k] :ensure_not log {k} }",
+            Default::default(),
+        )
+        .unwrap();
+        let (_, receiver) = db.register_callback("a", None);
+        let rows = |query: &str| {
+            db.run_script(query, Default::default())
+                .unwrap()
+                .into_json()["rows"]
+                .clone()
+        };
+
+        // the writes after the savepoint are rolled back, with those of the triggers they ran
+        let res = rows(
+            r"
+        {?[k, v] <- [[1, 'kept']] :put a {k => v}}
+        %savepoint s
+        {?[k, v] <- [[2, 'undone']] :put a {k => v}}
+        {?[k, v] <- [[1, 'overwritten']] :put a {k => v}}
+        {:create _t {x}}
+        %rollback_to s
+        {?[k, v] <- [[3, 'after']] :put a {k => v}}
+        {?[k, v] := *a{k, v}}
+        ",
+        );
+        assert_eq!(res, json!([[1, "kept"], [3, "after"]]));
+        assert_eq!(rows("?[k] := *log{k}"), json!([[1], [3]]));
+        // callbacks are only sent for the writes kept
+        let events = receiver.try_iter().collect_vec();
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            events[0].1.rows,
+            vec![vec![DataValue::from(1), DataValue::from("kept")]]
+        );
+        assert_eq!(
+            events[1].1.rows,
+            vec![vec![DataValue::from(3), DataValue::from("after")]]
+        );
+
+        // temp relations are rolled back too
+        let res = rows(
+            r"
+        {?[x] <- [[1]] :create _t {x}}
+        %savepoint s
+        {?[x] <- [[2]] :put _t {x}}
+        %rollback_to s
+        {?[x] := *_t{x}}
+        ",
+        );
+        assert_eq!(res, json!([[1]]));
+
+        // the partial writes of a program failing in `%ignore_error` are rolled back:
+        // here the row of `b` is written before its trigger fails
+        let res = rows(
+            r"
+        %ignore_error {?[k] <- [[3]] :put b {k}}
+        {?[k] <- [[4]] :put b {k}}
+        {?[k] := *b{k}}
+        ",
+        );
+        assert_eq!(res, json!([[4]]));
+        assert_eq!(rows("?[k] := *log{k}"), json!([[1], [3]]));
+
+        let err = db
+            .run_script("%rollback_to nowhere", Default::default())
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<CozoError>().unwrap().kind(), "not_found");
+    }
+}
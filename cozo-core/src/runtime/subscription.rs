@@ -0,0 +1,435 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::{bounded, unbounded, Receiver, RecvTimeoutError, Sender};
+use itertools::Itertools;
+use log::error;
+use miette::{bail, Diagnostic, Result};
+use smartstring::{LazyCompact, SmartString};
+use thiserror::Error;
+
+use crate::data::program::{InputProgram, MagicFixedRuleRuleArg, MagicSymbol};
+use crate::data::tuple::Tuple;
+use crate::parse::parse_script;
+use crate::query::compile::{stored_relations_read, CompiledProgram, CompiledRuleSet};
+use crate::runtime::error::CozoError;
+use crate::{DataValue, Db, NamedRows, Storage};
+
+/// Options for [Db::subscribe_query]
+#[derive(Clone, Debug)]
+pub struct SubscriptionOptions {
+    /// After a change to a relation read by the query, further changes are waited for
+    /// during this interval before the query is evaluated again
+    pub debounce: Duration,
+    /// Capacity of the channel receiving the changes, unbounded if `None`
+    pub capacity: Option<usize>,
+}
+
+impl Default for SubscriptionOptions {
+    fn default() -> Self {
+        Self {
+            debounce: Duration::from_millis(100),
+            capacity: None,
+        }
+    }
+}
+
+/// Changes to the results of a standing query
+#[derive(Clone, Debug)]
+pub struct QueryDiff {
+    /// Rows in the new results but not in the previous ones
+    pub added: NamedRows,
+    /// Rows in the previous results but not in the new ones
+    pub removed: NamedRows,
+}
+
+/// A subscription to a standing query, see [Db::subscribe_query]
+pub struct SubscriptionHandle {
+    /// The ID of the subscription, to be passed to [Db::unsubscribe_query]
+    pub id: u32,
+    /// Receives the changes to the results of the query
+    pub receiver: Receiver<QueryDiff>,
+}
+
+struct Subscription<S> {
+    /// stored relations read by the query
+    read_set: BTreeSet<SmartString<LazyCompact>>,
+    /// receives the database each time a relation of the read set changes
+    notifier: Sender<Db<S>>,
+}
+
+pub(crate) struct SubscriptionRegistry<S> {
+    next_id: u32,
+    subscriptions: BTreeMap<u32, Subscription<S>>,
+}
+
+impl<S> Default for SubscriptionRegistry<S> {
+    fn default() -> Self {
+        Self {
+            next_id: 0,
+            subscriptions: Default::default(),
+        }
+    }
+}
+
+impl<S> SubscriptionRegistry<S> {
+    pub(crate) fn relations_read(&self) -> impl Iterator<Item = &SmartString<LazyCompact>> {
+        self.subscriptions
+            .values()
+            .flat_map(|sub| sub.read_set.iter())
+    }
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Only read-only, non-recursive queries can be subscribed to")]
+#[diagnostic(code(eval::bad_standing_query))]
+struct BadStandingQuery;
+
+impl<'s, S: Storage<'s>> Db<S> {
+    /// Stop a subscription to a standing query. Dropping the [SubscriptionHandle]
+    /// also stops it, once the results change next.
+    pub fn unsubscribe_query(&self, id: u32) -> bool {
+        self.subscriptions
+            .write()
+            .unwrap()
+            .subscriptions
+            .remove(&id)
+            .is_some()
+    }
+    /// Wakes the subscriptions reading any of the relations changed by a committed transaction.
+    pub(crate) fn notify_subscriptions<'a>(
+        &self,
+        changed: impl Iterator<Item = &'a SmartString<LazyCompact>>,
+    ) {
+        let changed: BTreeSet<_> = changed.collect();
+        let registry = self.subscriptions.read().unwrap();
+        for sub in registry.subscriptions.values() {
+            if sub.read_set.iter().any(|rel| changed.contains(rel)) {
+                // a closed channel means the worker is gone, it cleans up after itself
+                let _ = sub.notifier.send(self.clone());
+            }
+        }
+    }
+}
+
+impl<S> Db<S>
+where
+    S: for<'s> Storage<'s> + 'static,
+{
+    /// Subscribe to the results of a standing query. The query is evaluated now, and again
+    /// whenever a stored relation it reads is changed by a committed query, after waiting
+    /// for `options.debounce` so that rapid writes are coalesced. Rows added to or removed
+    /// from the results are then sent to the returned receiver.
+    ///
+    /// The query must be a single read-only query without recursion. Changes made by
+    /// [Db::import_relations] and similar methods, which do not run callbacks, are not seen.
+    pub fn subscribe_query(
+        &self,
+        script: &str,
+        params: BTreeMap<String, DataValue>,
+        options: SubscriptionOptions,
+    ) -> Result<SubscriptionHandle> {
+        self.do_subscribe_query(script, &params, options)
+            .map_err(CozoError::wrap)
+    }
+
+    fn do_subscribe_query(
+        &self,
+        script: &str,
+        params: &BTreeMap<String, DataValue>,
+        options: SubscriptionOptions,
+    ) -> Result<SubscriptionHandle> {
+        let program = parse_script(
+            script,
+            params,
+            &self.fixed_rules.read().unwrap(),
+            self.clock.current_validity(),
+        )?
+        .get_single_program()?;
+        if program.out_opts.store_relation.is_some() {
+            bail!(BadStandingQuery)
+        }
+
+        let read_set = {
+            let mut tx = self.transact()?;
+            let prepared = self.prepare_query(&mut tx, program.clone())?;
+            tx.commit_tx()?;
+            if is_recursive(&prepared.strata) {
+                bail!(BadStandingQuery)
+            }
+            // indices are changed together with their relations
+            stored_relations_read(&prepared.strata)
+                .iter()
+                .map(|name| SmartString::from(name.split(':').next().unwrap()))
+                .collect()
+        };
+
+        // registered before the first evaluation, so that no change is missed
+        let (notifier, notifications) = unbounded();
+        let id = {
+            let mut registry = self.subscriptions.write().unwrap();
+            let id = registry.next_id;
+            registry.next_id += 1;
+            registry
+                .subscriptions
+                .insert(id, Subscription { read_set, notifier });
+            id
+        };
+        let current = match self.evaluate_standing_query(&program) {
+            Ok(res) => res,
+            Err(err) => {
+                self.unsubscribe_query(id);
+                return Err(err);
+            }
+        };
+
+        let (sender, receiver) = match options.capacity {
+            Some(c) => bounded(c),
+            None => unbounded(),
+        };
+        thread::spawn(move || {
+            run_subscription(
+                id,
+                program,
+                current,
+                notifications,
+                sender,
+                options.debounce,
+            )
+        });
+        Ok(SubscriptionHandle { id, receiver })
+    }
+
+    fn evaluate_standing_query(&self, program: &InputProgram) -> Result<NamedRows> {
+        let mut tx = self.transact()?;
+        let (res, cleanups) = self.run_query(
+            &mut tx,
+            program.clone(),
+            self.clock.current_validity(),
+            &Default::default(),
+            &mut Default::default(),
+            true,
+        )?;
+        tx.commit_tx()?;
+        assert!(cleanups.is_empty(), "non-empty cleanups on read-only tx");
+        Ok(res)
+    }
+}
+
+fn run_subscription<S>(
+    id: u32,
+    program: InputProgram,
+    mut current: NamedRows,
+    notifications: Receiver<Db<S>>,
+    sender: Sender<QueryDiff>,
+    debounce: Duration,
+) where
+    S: for<'s> Storage<'s> + 'static,
+{
+    // ends when the subscription is removed from the registry, or the database is dropped
+    while let Ok(mut db) = notifications.recv() {
+        let deadline = Instant::now() + debounce;
+        loop {
+            match notifications.recv_deadline(deadline) {
+                Ok(newer) => db = newer,
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+        let new = match db.evaluate_standing_query(&program) {
+            Ok(res) => res,
+            Err(err) => {
+                error!("evaluation of standing query {} failed: {:?}", id, err);
+                continue;
+            }
+        };
+        let diff = diff_rows(&current, &new);
+        current = new;
+        if diff.added.rows.is_empty() && diff.removed.rows.is_empty() {
+            continue;
+        }
+        if sender.send(diff).is_err() {
+            db.unsubscribe_query(id);
+            return;
+        }
+    }
+}
+
+// keys are only mutable to clippy for the regex a `DataValue` may hold
+#[allow(clippy::mutable_key_type)]
+fn diff_rows(old: &NamedRows, new: &NamedRows) -> QueryDiff {
+    let old_rows: BTreeSet<&Tuple> = old.rows.iter().collect();
+    let new_rows: BTreeSet<&Tuple> = new.rows.iter().collect();
+    let added = new
+        .rows
+        .iter()
+        .filter(|row| !old_rows.contains(row))
+        .cloned()
+        .collect_vec();
+    let removed = old
+        .rows
+        .iter()
+        .filter(|row| !new_rows.contains(row))
+        .cloned()
+        .collect_vec();
+    QueryDiff {
+        added: NamedRows::new(new.headers.clone(), added),
+        removed: NamedRows::new(old.headers.clone(), removed),
+    }
+}
+
+/// Whether some rule of the program depends on itself, directly or not
+fn is_recursive(strata: &[CompiledProgram]) -> bool {
+    let mut deps: BTreeMap<&MagicSymbol, BTreeSet<&MagicSymbol>> = BTreeMap::new();
+    for (name, ruleset) in strata.iter().flat_map(|stratum| stratum.iter()) {
+        let rule_deps = deps.entry(name).or_default();
+        match ruleset {
+            CompiledRuleSet::Rules(rules) => {
+                for rule in rules {
+                    rule_deps.extend(rule.contained_rules.iter());
+                }
+            }
+            CompiledRuleSet::Fixed(fixed) => {
+                for arg in &fixed.rule_args {
+                    if let MagicFixedRuleRuleArg::InMem { name, .. } = arg {
+                        rule_deps.insert(name);
+                    }
+                }
+            }
+        }
+    }
+    // depth-first search, a rule on the stack seen again closes a cycle
+    fn visit<'a>(
+        rule: &'a MagicSymbol,
+        deps: &BTreeMap<&'a MagicSymbol, BTreeSet<&'a MagicSymbol>>,
+        on_stack: &mut BTreeSet<&'a MagicSymbol>,
+        done: &mut BTreeSet<&'a MagicSymbol>,
+    ) -> bool {
+        if done.contains(rule) {
+            return false;
+        }
+        if !on_stack.insert(rule) {
+            return true;
+        }
+        if let Some(rule_deps) = deps.get(rule) {
+            for dep in rule_deps {
+                if visit(dep, deps, on_stack, done) {
+                    return true;
+                }
+            }
+        }
+        on_stack.remove(rule);
+        done.insert(rule);
+        false
+    }
+    let mut on_stack = BTreeSet::new();
+    let mut done = BTreeSet::new();
+    deps.keys()
+        .any(|rule| visit(rule, &deps, &mut on_stack, &mut done))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::data::value::DataValue;
+    use crate::{new_cozo_mem, SubscriptionOptions};
+
+    #[test]
+    fn test_query_subscription() {
+        let db = new_cozo_mem().unwrap();
+        db.run_script(
+            r"?[name, score] <- [['a', 1], ['b', 2]] :create scores {name => score}",
+            Default::default(),
+        )
+        .unwrap();
+        db.run_script(":create other {k}", Default::default())
+            .unwrap();
+
+        assert!(db
+            .subscribe_query(
+                "?[k] <- [[1]] :put other {k}",
+                Default::default(),
+                Default::default()
+            )
+            .is_err());
+        assert!(db
+            .subscribe_query(
+                r"r[a] := a = 1
+              r[b] := r[a], b = a + 1, b < 3
+              ?[a] := r[a]",
+                Default::default(),
+                Default::default()
+            )
+            .is_err());
+
+        let sub = db
+            .subscribe_query(
+                "?[name] := *scores{name, score}, score > 1",
+                Default::default(),
+                SubscriptionOptions {
+                    debounce: Duration::from_millis(200),
+                    capacity: None,
+                },
+            )
+            .unwrap();
+        let no_more = || {
+            sub.receiver
+                .recv_timeout(Duration::from_millis(500))
+                .is_err()
+        };
+
+        db.run_script("?[k] <- [[1]] :put other {k}", Default::default())
+            .unwrap();
+        assert!(no_more());
+
+        db.run_script(
+            r"?[name, score] <- [['a', 5], ['b', 0]] :put scores {name => score}",
+            Default::default(),
+        )
+        .unwrap();
+        let diff = sub.receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(diff.added.rows, vec![vec![DataValue::from("a")]]);
+        assert_eq!(diff.removed.rows, vec![vec![DataValue::from("b")]]);
+        assert!(no_more());
+
+        // rapid writes are coalesced into a single change
+        db.run_script(
+            r"?[name, score] <- [['c', 3]] :put scores {name => score}",
+            Default::default(),
+        )
+        .unwrap();
+        db.run_script(
+            r"?[name, score] <- [['d', 4]] :put scores {name => score}",
+            Default::default(),
+        )
+        .unwrap();
+        db.run_script(r"?[name] <- [['a']] :rm scores {name}", Default::default())
+            .unwrap();
+        let diff = sub.receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(
+            diff.added.rows,
+            vec![vec![DataValue::from("c")], vec![DataValue::from("d")]]
+        );
+        assert_eq!(diff.removed.rows, vec![vec![DataValue::from("a")]]);
+        assert!(no_more());
+
+        assert!(db.unsubscribe_query(sub.id));
+        assert!(!db.unsubscribe_query(sub.id));
+        db.run_script(
+            r"?[name, score] <- [['e', 5]] :put scores {name => score}",
+            Default::default(),
+        )
+        .unwrap();
+        assert!(sub.receiver.recv().is_err());
+    }
+}
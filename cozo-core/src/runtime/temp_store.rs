@@ -13,9 +13,11 @@ use std::collections::Bound::Included;
 use std::mem;
 use std::ops::Bound::Excluded;
 
+use crossbeam::channel::Sender;
 use either::{Left, Right};
 use itertools::Itertools;
-use miette::Result;
+use miette::{bail, Diagnostic, Result};
+use thiserror::Error;
 
 use crate::data::aggr::Aggregation;
 use crate::data::tuple::Tuple;
@@ -26,14 +28,39 @@ use crate::data::value::DataValue;
 #[derive(Default, Debug)]
 pub struct RegularTempStore {
     inner: BTreeMap<Tuple, bool>,
+    /// When set, tuples are sent here instead of being kept in the store.
+    sink: Option<Sender<Tuple>>,
+    /// Set once a tuple could not be sent to the sink, see [Self::check_sink]
+    sink_closed: bool,
 }
 
+#[derive(Debug, Error, Diagnostic)]
+#[error("The rows of the rule could not all be passed on, as their receiver has stopped")]
+#[diagnostic(code(eval::sink_closed))]
+struct SinkClosedError;
+
 const EMPTY_TUPLE_REF: &Tuple = &vec![];
 
 impl RegularTempStore {
     pub(crate) fn wrap(self) -> TempStore {
         TempStore::Normal(self)
     }
+    /// A store that passes all tuples put into it on to `sink` and stays empty.
+    pub(crate) fn streaming(sink: Sender<Tuple>) -> Self {
+        Self {
+            inner: Default::default(),
+            sink: Some(sink),
+            sink_closed: false,
+        }
+    }
+    /// Fails if tuples put into the store were lost as the receiving end of its sink
+    /// had stopped, see [Self::streaming]
+    pub(crate) fn check_sink(&self) -> Result<()> {
+        if self.sink_closed {
+            bail!(SinkClosedError)
+        }
+        Ok(())
+    }
     /// Tests if a key already exists in the store.
     pub fn exists(&self, key: &Tuple) -> bool {
         self.inner.contains_key(key)
@@ -57,10 +84,24 @@ impl RegularTempStore {
     }
     /// Add a tuple to the store
     pub fn put(&mut self, tuple: Tuple) {
-        self.inner.insert(tuple, false);
+        match &self.sink {
+            Some(sink) => {
+                self.sink_closed |= sink.send(tuple).is_err();
+            }
+            None => {
+                self.inner.insert(tuple, false);
+            }
+        }
     }
     pub(crate) fn put_with_skip(&mut self, tuple: Tuple) {
-        self.inner.insert(tuple, true);
+        match &self.sink {
+            Some(sink) => {
+                self.sink_closed |= sink.send(tuple).is_err();
+            }
+            None => {
+                self.inner.insert(tuple, true);
+            }
+        }
     }
     // returns true if prev is guaranteed to be the same as self after this function call,
     // false if we are not sure.
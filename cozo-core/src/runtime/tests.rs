@@ -7,7 +7,10 @@
  *
  */
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use itertools::Itertools;
@@ -16,13 +19,20 @@ use serde_json::json;
 use smartstring::{LazyCompact, SmartString};
 
 use crate::data::expr::Expr;
+use crate::data::functions::current_validity;
 use crate::data::symb::Symbol;
-use crate::data::value::DataValue;
+use crate::data::tuple::{Tuple, TupleT};
+use crate::data::value::{DataValue, ValidityTs};
 use crate::fixed_rule::FixedRulePayload;
-use crate::parse::SourceSpan;
+use crate::parse::{parse_script, SourceSpan};
 use crate::runtime::callback::CallbackOp;
-use crate::runtime::db::Poison;
-use crate::{new_cozo_mem, DbInstance, FixedRule, RegularTempStore};
+use crate::runtime::db::{Poison, RunningScript};
+use crate::runtime::transact::SessionTx;
+use crate::storage::mem::MemTx;
+use crate::{
+    new_cozo_mem, CozoError, Db, DbInstance, FixedRule, ImportOptions, ImportReport, MemStorage,
+    NamedRows, OnConflict, RegularTempStore, Storage, StoreTx, TransientStorageError,
+};
 
 #[test]
 fn test_limit_offset() {
@@ -738,3 +748,3956 @@ fn test_multi_tx() {
     tx.abort().unwrap();
     assert!(db.run_script("?[a] := *a[a]", Default::default()).is_err());
 }
+
+#[test]
+fn test_try_expr() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r#"
+        ?[k, v] <- [[1, '2022-01-01T00:00:00Z'], [2, 'garbage'], [3, '2022-01-03T00:00:00Z'], [4, '']]
+        :create events {k => v}
+        "#,
+        Default::default(),
+    )
+    .unwrap();
+    let res = db
+        .run_script(
+            "?[k, ok] := *events{k, v}, ok = try(parse_timestamp(v) > 0, false, true)",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(
+        res["rows"],
+        json!([[1, true], [2, false], [3, true], [4, false]])
+    );
+    assert_eq!(res["warnings"].as_array().unwrap().len(), 2);
+
+    let res = db
+        .run_script(
+            "?[a, b] := a in ['1', 'x'], b = try(try(to_int(a), to_int('y')), -1)",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([["1", 1], ["x", -1]]));
+    assert!(res.get("warnings").is_none());
+
+    assert!(db
+        .run_script(
+            "?[b] := a in ['x'], b = try(to_int(a), to_int('y'))",
+            Default::default(),
+        )
+        .is_err());
+
+    let res = db
+        .run_script("?[a] := a = try(to_int('z'), 0)", Default::default())
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[0]]));
+}
+
+#[test]
+fn test_any_all_short_circuit() {
+    let db = new_cozo_mem().unwrap();
+    let res = db
+        .run_script(
+            "?[any(c), all(c)] := a in [], c = a > 0",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[false, true]]));
+
+    db.run_script(
+        r"
+        ?[id, amount] := id in int_range(1000), amount = if(id == 10, 2000, 100)
+        :create orders {id => amount}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    // the rows produced by the root of the plan of the profiled query, and the result
+    let profile = |script: &str| {
+        let res = db
+            .run_script(&format!("::profile {{ {script} }}"), Default::default())
+            .unwrap();
+        let op = res.headers.iter().position(|h| h == "op").unwrap();
+        let rows = res.headers.iter().position(|h| h == "rows").unwrap();
+        let out = res
+            .rows
+            .iter()
+            .position(|row| row[op] == DataValue::from("aggr_out"))
+            .unwrap();
+        let res_rows = db.run_script(script, Default::default()).unwrap().rows;
+        (res.rows[out - 1][rows].get_int().unwrap(), res_rows)
+    };
+
+    // the scan stops at the first witness or counterexample
+    let (n, rows) = profile("?[any(big)] := *orders{id, amount}, big = amount > 1000");
+    assert_eq!(rows, vec![vec![DataValue::from(true)]]);
+    assert_eq!(n, 11);
+    let (n, rows) = profile("?[all(small)] := *orders{id, amount}, small = amount < 1000");
+    assert_eq!(rows, vec![vec![DataValue::from(false)]]);
+    assert_eq!(n, 11);
+    // including through joins feeding the rule
+    let (n, rows) =
+        profile("?[any(big)] := *orders{id, amount: a}, *orders{id: a, amount: b}, big = id > 2");
+    assert_eq!(rows, vec![vec![DataValue::from(true)]]);
+    assert!(n <= 4);
+    // without a decision, every row is consumed
+    let (n, rows) = profile("?[all(small)] := *orders{id, amount}, small = amount < 5000");
+    assert_eq!(rows, vec![vec![DataValue::from(true)]]);
+    assert_eq!(n, 1000);
+
+    let (n, rows) = profile("?[any(w, true)] := *orders{id, amount}, w = [amount > 1000, id]");
+    assert_eq!(
+        rows,
+        vec![vec![DataValue::List(vec![
+            DataValue::from(true),
+            DataValue::from(10)
+        ])]]
+    );
+    assert_eq!(n, 11);
+    let (_, rows) = profile("?[all(w, true)] := *orders{id, amount}, w = [amount < 5000, id]");
+    assert_eq!(
+        rows,
+        vec![vec![DataValue::List(vec![
+            DataValue::from(true),
+            DataValue::Null
+        ])]]
+    );
+
+    let res = db
+        .run_script(
+            "?[k, all(big)] := *orders{id, amount}, k = id % 2, big = amount > 60",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"], json!([[0, true], [1, true]]));
+}
+
+#[test]
+fn test_storage_info() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(":create a {a => b}", Default::default())
+        .unwrap();
+    let res = db.run_script("::storage_info", Default::default()).unwrap();
+    assert_eq!(res.headers, vec!["key", "value"]);
+    let info: BTreeMap<_, _> = res
+        .rows
+        .into_iter()
+        .map(|row| (row[0].get_str().unwrap().to_string(), row[1].clone()))
+        .collect();
+    assert_eq!(info["storage_kind"], DataValue::from("mem"));
+    assert!(info["n_keys"].get_int().unwrap() > 0);
+    assert!(info["n_bytes"].get_int().unwrap() > 0);
+}
+
+#[cfg(feature = "storage-sqlite")]
+#[test]
+fn test_sqlite_storage_info() {
+    let path = std::env::temp_dir().join(format!("cozo-test-{}.db", rand::random::<u64>()));
+    let db = crate::new_cozo_sqlite(&path).unwrap();
+    let read_info = |db: &crate::Db<crate::SqliteStorage>| -> BTreeMap<String, DataValue> {
+        db.db
+            .storage_info()
+            .unwrap()
+            .rows
+            .into_iter()
+            .map(|row| (row[0].get_str().unwrap().to_string(), row[1].clone()))
+            .collect()
+    };
+    let info = read_info(&db);
+    assert_eq!(info["storage_kind"], DataValue::from("sqlite"));
+    for key in [
+        "page_count",
+        "page_size",
+        "freelist_count",
+        "wal_size_bytes",
+        "pool_idle",
+        "pool_open",
+        "pool_max_size",
+        "pool_active",
+    ] {
+        assert!(info[key].get_int().is_some(), "{key}");
+    }
+    assert_eq!(info["pool_active"], DataValue::from(0));
+    {
+        let _tx = db.transact().unwrap();
+        let info = read_info(&db);
+        assert_eq!(info["pool_active"], DataValue::from(1));
+    }
+    let info = read_info(&db);
+    assert_eq!(info["pool_active"], DataValue::from(0));
+    db.run_script(":create a {a => b}", Default::default())
+        .unwrap();
+    let info = read_info(&db);
+    assert!(info["wal_size_bytes"].get_int().unwrap() > 0);
+    drop(db);
+
+    // with every connection in use, the statistics of the pool are still reported
+    let options = crate::SqliteOptions {
+        max_connections: 1,
+        ..Default::default()
+    };
+    let db = crate::new_cozo_sqlite_with_options(&path, options).unwrap();
+    {
+        let _tx = db.transact().unwrap();
+        let info = read_info(&db);
+        assert!(!info.contains_key("page_count"));
+        assert_eq!(info["pool_open"], DataValue::from(1));
+        assert_eq!(info["pool_idle"], DataValue::from(0));
+    }
+    let info = read_info(&db);
+    assert!(info["page_count"].get_int().unwrap() > 0);
+    drop(db);
+    let _ = std::fs::remove_file(path);
+}
+
+#[cfg(feature = "storage-rocksdb")]
+#[test]
+fn test_rocksdb_storage_info() {
+    let read_info = |db: &crate::Db<crate::RocksDbStorage>| -> BTreeMap<String, DataValue> {
+        db.run_script("::storage_info", Default::default())
+            .unwrap()
+            .rows
+            .into_iter()
+            .map(|row| (row[0].get_str().unwrap().to_string(), row[1].clone()))
+            .collect()
+    };
+    let path = std::env::temp_dir().join(format!("cozo-test-{}", rand::random::<u64>()));
+    let db = crate::new_cozo_rocksdb(&path).unwrap();
+    let info = read_info(&db);
+    assert_eq!(info["storage_kind"], DataValue::from("rocksdb"));
+    assert!(!info.contains_key("block_cache_hits"));
+    drop(db);
+
+    let options = crate::RocksDbOptions { statistics: true };
+    let db = crate::new_cozo_rocksdb_with_options(&path, options).unwrap();
+    db.run_script(
+        "?[a, b] := a in int_range(1000), b = a :create a {a => b}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script("?[count(a)] := *a{a}", Default::default())
+        .unwrap();
+    let info = read_info(&db);
+    assert_eq!(info["storage_kind"], DataValue::from("rocksdb"));
+    for key in [
+        "estimate_num_keys",
+        "compaction_pending",
+        "block_cache_hits",
+        "block_cache_misses",
+    ] {
+        assert!(info[key].get_int().is_some(), "{key}");
+    }
+    if let Some(rate) = info.get("block_cache_hit_rate") {
+        let rate = rate.get_float().unwrap();
+        assert!((0. ..=1.).contains(&rate));
+    }
+    drop(db);
+    let _ = std::fs::remove_dir_all(path);
+}
+
+#[test]
+fn test_shared_stored_scans() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"?[a, b] <- [[1, 2], [2, 3], [3, 4], [4, 1], [5, 5]] :create edge {a, b}",
+        Default::default(),
+    )
+    .unwrap();
+    let rules = [
+        "r1[a] := *edge[a, b], b > 1",
+        "r2[b] := *edge[a, b], b > 1",
+        "r3[c] := *edge[a, b], b > 1, c = a + b",
+    ];
+    let script = format!(
+        "{}\n?[k, v] := r1[v], k = 'r1'\n?[k, v] := r2[v], k = 'r2'\n?[k, v] := r3[v], k = 'r3'",
+        rules.join("\n")
+    );
+
+    let cur_vld = current_validity();
+    let program = parse_script(
+        &script,
+        &Default::default(),
+        &db.fixed_rules.read().unwrap(),
+        cur_vld,
+    )
+    .unwrap()
+    .get_single_program()
+    .unwrap();
+    let mut tx = db.transact().unwrap();
+    let (shared_res, _) = db
+        .run_query(
+            &mut tx,
+            program,
+            cur_vld,
+            &Default::default(),
+            &mut Default::default(),
+            true,
+        )
+        .unwrap();
+    assert_eq!(tx.stored_scans.load(Ordering::Relaxed), 1);
+    drop(tx);
+
+    // each rule on its own cannot share its scan
+    let mut unshared_rows = vec![];
+    for (i, rule) in rules.iter().enumerate() {
+        let head = &rule[..2];
+        let res = db
+            .run_script(
+                &format!("{rule}\n?[k, v] := {head}[v], k = 'r{}'", i + 1),
+                Default::default(),
+            )
+            .unwrap();
+        unshared_rows.extend(res.rows);
+    }
+    unshared_rows.sort();
+    assert_eq!(shared_res.rows, unshared_rows);
+
+    let explained = db
+        .run_script(&format!("::explain {{ {script} }}"), Default::default())
+        .unwrap();
+    let op_idx = explained.headers.iter().position(|h| h == "op").unwrap();
+    let ref_idx = explained.headers.iter().position(|h| h == "ref").unwrap();
+    let shared_refs = explained
+        .rows
+        .iter()
+        .filter(|row| row[op_idx] == DataValue::from("load_stored_shared"))
+        .map(|row| row[ref_idx].clone())
+        .collect_vec();
+    assert_eq!(shared_refs.len(), 3);
+    assert!(shared_refs
+        .iter()
+        .all(|r| *r == DataValue::from(":edge (shared scan #0, 3 consumers)")));
+}
+
+#[test]
+fn test_shared_stored_scan_too_large() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        &format!(
+            "?[a] := a in int_range({}) :create nums {{a}}",
+            crate::query::ra::SHARED_SCAN_MAX_ROWS + 1
+        ),
+        Default::default(),
+    )
+    .unwrap();
+    let script = "r1[count(a)] := *nums[a]\nr2[count(a)] := *nums[a]\n?[x, y] := r1[x], r2[y]";
+    let cur_vld = current_validity();
+    let program = parse_script(
+        script,
+        &Default::default(),
+        &db.fixed_rules.read().unwrap(),
+        cur_vld,
+    )
+    .unwrap()
+    .get_single_program()
+    .unwrap();
+    let mut tx = db.transact().unwrap();
+    let (res, _) = db
+        .run_query(
+            &mut tx,
+            program,
+            cur_vld,
+            &Default::default(),
+            &mut Default::default(),
+            true,
+        )
+        .unwrap();
+    // the first consumer goes on from the rows it buffered, only the second one scans again
+    assert_eq!(tx.stored_scans.load(Ordering::Relaxed), 2);
+    let n = crate::query::ra::SHARED_SCAN_MAX_ROWS as i64 + 1;
+    assert_eq!(res.rows, vec![vec![DataValue::from(n), DataValue::from(n)]]);
+}
+
+#[cfg(feature = "storage-sqlite")]
+#[test]
+fn test_stream_fixed_rule_into_relation() {
+    let path = std::env::temp_dir().join(format!("cozo-test-{}.db", rand::random::<u64>()));
+    let db = crate::new_cozo_sqlite(&path).unwrap();
+    let n = 30;
+    let mut edges = vec![];
+    for i in 0..n {
+        for j in 0..n {
+            let node = i * n + j;
+            if j + 1 < n {
+                edges.push(DataValue::List(vec![
+                    DataValue::from(node),
+                    DataValue::from(node + 1),
+                    DataValue::from(1.),
+                ]));
+            }
+            if i + 1 < n {
+                edges.push(DataValue::List(vec![
+                    DataValue::from(node),
+                    DataValue::from(node + n),
+                    DataValue::from(2.),
+                ]));
+            }
+        }
+    }
+    db.run_script(
+        "?[a, b, w] <- $edges :create edge {a, b => w}",
+        BTreeMap::from([("edges".to_string(), DataValue::List(edges))]),
+    )
+    .unwrap();
+
+    let algo = "starts[s] <- [[0], [7], [31], [450], [899]]
+                ?[start, goal, cost, path] <~ ShortestPathDijkstra(*edge[], starts[])";
+    let expected = db
+        .run_script(algo, Default::default())
+        .unwrap()
+        .rows
+        .into_iter()
+        .sorted()
+        .collect_vec();
+    assert_eq!(expected.len(), 5 * n as usize * n as usize);
+
+    let run_streamed = |script: &str| -> usize {
+        let cur_vld = current_validity();
+        let program = parse_script(
+            script,
+            &Default::default(),
+            &db.fixed_rules.read().unwrap(),
+            cur_vld,
+        )
+        .unwrap()
+        .get_single_program()
+        .unwrap();
+        let mut tx = db.transact_write().unwrap();
+        db.run_query(
+            &mut tx,
+            program,
+            cur_vld,
+            &Default::default(),
+            &mut Default::default(),
+            true,
+        )
+        .unwrap();
+        let streamed = tx.streamed_rows.load(Ordering::Relaxed);
+        tx.commit_tx().unwrap();
+        streamed
+    };
+    let read_paths = || {
+        db.run_script(
+            "?[start, goal, cost, path] := *paths[start, goal, cost, path]",
+            Default::default(),
+        )
+        .unwrap()
+        .rows
+        .into_iter()
+        .sorted()
+        .collect_vec()
+    };
+
+    let streamed = run_streamed(&format!(
+        "{algo}\n:replace paths {{start, goal => cost, path}}"
+    ));
+    assert_eq!(streamed, expected.len());
+    assert_eq!(read_paths(), expected);
+
+    // written twice, the rows are simply overwritten
+    let streamed = run_streamed(&format!("{algo}\n:put paths {{start, goal => cost, path}}"));
+    assert_eq!(streamed, expected.len());
+    assert_eq!(read_paths(), expected);
+
+    // the target is read by the program itself: must go through the usual path
+    let streamed = run_streamed(
+        "starts[s] := *paths[s, _, _, _]
+         ?[start, goal, cost, path] <~ ShortestPathDijkstra(*edge[], starts[])
+         :put paths {start, goal => cost, path}",
+    );
+    assert_eq!(streamed, 0);
+    assert_eq!(read_paths(), expected);
+
+    // rows sharing a key, also across chunks: the same row is kept as on the usual path
+    let streamed = run_streamed(&format!(
+        "{algo}\n:replace ends {{goal => start, cost, path}}"
+    ));
+    assert_eq!(streamed, n as usize * n as usize);
+    db.run_script(
+        "?[start, goal, cost, path] := *paths[start, goal, cost, path]
+         :replace usual_ends {goal => start, cost, path}",
+        Default::default(),
+    )
+    .unwrap();
+    let read_ends = |rel: &str| {
+        db.run_script(
+            &format!("?[goal, start, cost] := *{rel}{{goal, start, cost}}"),
+            Default::default(),
+        )
+        .unwrap()
+        .rows
+    };
+    let ends = read_ends("ends");
+    assert_eq!(ends.len(), n as usize * n as usize);
+    assert_eq!(ends, read_ends("usual_ends"));
+    // with `:put`, rows sharing a key in chunks next to each other
+    db.run_script(
+        ":create put_ends {goal => start, cost, path}",
+        Default::default(),
+    )
+    .unwrap();
+    let streamed = run_streamed(&format!(
+        "{algo}\n:put put_ends {{goal => start, cost, path}}"
+    ));
+    assert_eq!(streamed, n as usize * n as usize);
+    assert_eq!(read_ends("put_ends"), read_ends("usual_ends"));
+
+    // with an index on the target, too
+    db.run_script(
+        "::index create paths:by_goal {goal, start}",
+        Default::default(),
+    )
+    .unwrap();
+    let streamed = run_streamed(&format!("{algo}\n:put paths {{start, goal => cost, path}}"));
+    assert_eq!(streamed, 0);
+    assert_eq!(read_paths(), expected);
+
+    drop(db);
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn test_import_conflict_policies() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"?[k, counts, label] <- [['a', 1, 'x'], ['b', 2, 'y']]
+          :create stats {k => counts: Int, label: String}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        "::index create stats:by_label {label, k}",
+        Default::default(),
+    )
+    .unwrap();
+    let incoming = || {
+        BTreeMap::from([(
+            "stats".to_string(),
+            NamedRows::new(
+                vec!["k".to_string(), "counts".to_string(), "label".to_string()],
+                vec![
+                    vec![
+                        DataValue::from("b"),
+                        DataValue::from(10),
+                        DataValue::from("z"),
+                    ],
+                    vec![
+                        DataValue::from("c"),
+                        DataValue::from(3),
+                        DataValue::from("w"),
+                    ],
+                ],
+            ),
+        )])
+    };
+    let read = || {
+        let rows = db
+            .run_script(
+                "?[k, counts, label] := *stats[k, counts, label]",
+                Default::default(),
+            )
+            .unwrap()
+            .into_json();
+        let by_label = db
+            .run_script(
+                "?[label, k] := *stats:by_label[label, k]",
+                Default::default(),
+            )
+            .unwrap()
+            .into_json();
+        (rows["rows"].clone(), by_label["rows"].clone())
+    };
+    let import = |on_conflict: OnConflict| {
+        db.import_relations_with_options(
+            incoming(),
+            ImportOptions {
+                on_conflict,
+                ..Default::default()
+            },
+        )
+    };
+
+    // a conflict aborts the whole import, including the rows without conflicts
+    let err = import(OnConflict::Error).unwrap_err();
+    assert_eq!(err.code().unwrap().to_string(), "import::conflict");
+    let (rows, by_label) = read();
+    assert_eq!(rows, json!([["a", 1, "x"], ["b", 2, "y"]]));
+    assert_eq!(by_label, json!([["x", "a"], ["y", "b"]]));
+
+    let report = import(OnConflict::Keep).unwrap();
+    assert_eq!(
+        report,
+        ImportReport {
+            inserted: 1,
+            kept: 1,
+            ..Default::default()
+        }
+    );
+    let (rows, by_label) = read();
+    assert_eq!(rows, json!([["a", 1, "x"], ["b", 2, "y"], ["c", 3, "w"]]));
+    assert_eq!(by_label, json!([["w", "c"], ["x", "a"], ["y", "b"]]));
+
+    let report = import(OnConflict::Merge(BTreeMap::from([(
+        "counts".to_string(),
+        "old.counts + new.counts".to_string(),
+    )])))
+    .unwrap();
+    assert_eq!(
+        report,
+        ImportReport {
+            merged: 2,
+            ..Default::default()
+        }
+    );
+    let (rows, by_label) = read();
+    assert_eq!(rows, json!([["a", 1, "x"], ["b", 12, "z"], ["c", 6, "w"]]));
+    assert_eq!(by_label, json!([["w", "c"], ["x", "a"], ["z", "b"]]));
+
+    let report = import(OnConflict::Overwrite).unwrap();
+    assert_eq!(
+        report,
+        ImportReport {
+            overwritten: 2,
+            ..Default::default()
+        }
+    );
+    let (rows, _) = read();
+    assert_eq!(rows, json!([["a", 1, "x"], ["b", 10, "z"], ["c", 3, "w"]]));
+
+    // merge expressions may only refer to the columns, and only set non-key columns
+    for merge in [
+        ("counts", "old.counts + other.counts"),
+        ("counts", "counts + 1"),
+        ("k", "new.k"),
+        ("counts", "old.counts +"),
+    ] {
+        let on_conflict =
+            OnConflict::Merge(BTreeMap::from([(merge.0.to_string(), merge.1.to_string())]));
+        assert!(import(on_conflict).is_err(), "{merge:?}");
+    }
+    // qualified names are only part of merge expressions
+    let err = db
+        .run_script("?[x] := old = 1, x = old.n", Default::default())
+        .unwrap_err();
+    assert_eq!(CozoError::from_report(err).kind(), "parse");
+
+    // through the string API
+    let instance = DbInstance::Mem(db.clone());
+    let res = instance.import_relations_with_options_str(
+        &json!({"stats": {"headers": ["k", "counts", "label"], "rows": [["d", 1, "v"], ["a", 5, "x"]]}})
+            .to_string(),
+        r#"{"on_conflict": {"merge": {"label": "new.label ++ old.label"}}}"#,
+    );
+    let res: serde_json::Value = serde_json::from_str(&res).unwrap();
+    assert_eq!(res["ok"], json!(true));
+    assert_eq!(res["report"]["inserted"], json!(1));
+    assert_eq!(res["report"]["merged"], json!(1));
+    let (rows, _) = read();
+    assert_eq!(
+        rows,
+        json!([["a", 5, "xx"], ["b", 10, "z"], ["c", 3, "w"], ["d", 1, "v"]])
+    );
+    let res = instance.import_relations_with_options_str("{}", r#"{"on_conflict": "sometimes"}"#);
+    let res: serde_json::Value = serde_json::from_str(&res).unwrap();
+    assert_eq!(res["ok"], json!(false));
+}
+
+#[cfg(feature = "graph-algo")]
+#[test]
+fn test_fixed_rule_arg_filters() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"?[f, t, w] := f in int_range(200), t = (f + 1) % 200, w = f % 10
+          ?[f, t, w] := f in int_range(200), t = (f * 7) % 200, w = 10 + f % 10
+          :create road {f, t => w}",
+        Default::default(),
+    )
+    .unwrap();
+
+    let run_counting_input = |script: &str| -> (Vec<Vec<DataValue>>, usize) {
+        let cur_vld = current_validity();
+        let program = parse_script(
+            script,
+            &Default::default(),
+            &db.fixed_rules.read().unwrap(),
+            cur_vld,
+        )
+        .unwrap()
+        .get_single_program()
+        .unwrap();
+        let mut tx = db.transact_write().unwrap();
+        let (res, _) = db
+            .run_query(
+                &mut tx,
+                program,
+                cur_vld,
+                &Default::default(),
+                &mut Default::default(),
+                true,
+            )
+            .unwrap();
+        let consumed = tx.fixed_rule_input_rows.load(Ordering::Relaxed);
+        tx.commit_tx().unwrap();
+        (res.rows.into_iter().sorted().collect_vec(), consumed)
+    };
+
+    let starts = "starts[s] <- [[0], [42], [137]]";
+    let (inline, inline_consumed) = run_counting_input(&format!(
+        r"{starts}
+          edges[f, t, w] := *road{{f, t, w}}, w < 5
+          ?[start, goal, cost, path] <~ ShortestPathDijkstra(edges[], starts[])"
+    ));
+    assert!(!inline.is_empty());
+    let (named, named_consumed) = run_counting_input(&format!(
+        r"{starts}
+          ?[start, goal, cost, path] <~ ShortestPathDijkstra(*road{{f, t, w | w < 5}}, starts[])"
+    ));
+    assert_eq!(named, inline);
+    assert_eq!(named_consumed, inline_consumed);
+    let (positional, positional_consumed) = run_counting_input(&format!(
+        r"{starts}
+          ?[start, goal, cost, path] <~ ShortestPathDijkstra(*road[a, b, c | c >= 0, c < 5], starts[])"
+    ));
+    assert_eq!(positional, inline);
+    assert_eq!(positional_consumed, inline_consumed);
+
+    let (_, unfiltered_consumed) = run_counting_input(&format!(
+        r"{starts}
+          ?[start, goal, cost, path] <~ ShortestPathDijkstra(*road[], starts[])"
+    ));
+    assert!(unfiltered_consumed > inline_consumed);
+
+    let res = db.run_script(
+        r"starts[s] <- [[0]]
+          ?[start, goal, cost, path] <~ ShortestPathDijkstra(*road{f, t | w < 5}, starts[])",
+        Default::default(),
+    );
+    assert!(res.is_err());
+}
+
+#[test]
+fn test_replace_many() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"{:create countries {code: String => name: String}}
+          {:create regions {code: String => country: String}}
+          {:create cities {name: String => region: String}}
+          {?[code, name] <- [['fr', 'France']] :put countries {code => name}}
+          {?[code, country] <- [['idf', 'fr']] :put regions {code => country}}
+          {?[name, region] <- [['Paris', 'idf']] :put cities {name => region}}",
+        Default::default(),
+    )
+    .unwrap();
+    let (_id, receiver) = db.register_callback("countries", None);
+    let dump = |rel: &str| {
+        db.run_script(&format!("?[a, b] := *{rel}[a, b]"), Default::default())
+            .unwrap()
+            .rows
+    };
+    let before = ["countries", "regions", "cities"].map(dump);
+
+    let script = r"
+        :replace_many {
+            countries <- $countries,
+            regions <- {?[code, country] <- $regions},
+            cities <- {?[name, region] := *regions[region, _], name = $city},
+        }";
+    let params = |city: DataValue| {
+        BTreeMap::from([
+            (
+                "countries".to_string(),
+                DataValue::List(vec![
+                    DataValue::List(vec![DataValue::from("de"), DataValue::from("Germany")]),
+                    DataValue::List(vec![DataValue::from("it"), DataValue::from("Italy")]),
+                ]),
+            ),
+            (
+                "regions".to_string(),
+                DataValue::List(vec![DataValue::List(vec![
+                    DataValue::from("by"),
+                    DataValue::from("de"),
+                ])]),
+            ),
+            ("city".to_string(), city),
+        ])
+    };
+
+    // the third relation rejects its rows, so none of the relations change
+    assert!(db.run_script(script, params(DataValue::from(1))).is_err());
+    assert_eq!(["countries", "regions", "cities"].map(dump), before);
+    std::thread::sleep(Duration::from_secs_f64(0.01));
+    assert!(receiver.try_recv().is_err());
+
+    db.run_script(script, params(DataValue::from("Munich")))
+        .unwrap();
+    assert_eq!(
+        dump("countries"),
+        vec![
+            vec![DataValue::from("de"), DataValue::from("Germany")],
+            vec![DataValue::from("it"), DataValue::from("Italy")],
+        ]
+    );
+    assert_eq!(
+        dump("regions"),
+        vec![vec![DataValue::from("by"), DataValue::from("de")]]
+    );
+    assert_eq!(
+        dump("cities"),
+        vec![vec![DataValue::from("Munich"), DataValue::from("by")]]
+    );
+    std::thread::sleep(Duration::from_secs_f64(0.01));
+    let (op, new_rows, _) = receiver.try_recv().unwrap();
+    assert_eq!(op, CallbackOp::Put);
+    assert_eq!(new_rows.rows, dump("countries"));
+    assert!(receiver.try_recv().is_err());
+
+    assert!(db
+        .run_script(":replace_many {countries <- [['fr']]}", Default::default())
+        .is_err());
+    assert!(db
+        .run_script(
+            ":replace_many {countries <- [], countries <- []}",
+            Default::default()
+        )
+        .is_err());
+}
+
+#[test]
+fn test_partial_index() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        ":create events {id: Int => assignee: String, status: String}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r"?[id, assignee, status] <- [[1, 'bob', 'open'], [2, 'bob', 'closed'], [3, 'eve', 'open']]
+          :put events {id => assignee, status}",
+        Default::default(),
+    )
+    .unwrap();
+    assert!(db
+        .run_script(
+            "::index create events:bad_idx {assignee} where owner == 'bob'",
+            Default::default()
+        )
+        .is_err());
+    db.run_script(
+        "::index create events:open_idx {assignee} where status == 'open'",
+        Default::default(),
+    )
+    .unwrap();
+    let index_rows = || {
+        db.export_relations(["events:open_idx"].into_iter())
+            .unwrap()["events:open_idx"]
+            .clone()
+            .into_json()["rows"]
+            .clone()
+    };
+    assert_eq!(index_rows(), json!([["bob", 1], ["eve", 3]]));
+
+    // 1 leaves the index, 2 enters it, 3 is removed
+    db.run_script(
+        r"?[id, assignee, status] <- [[1, 'bob', 'closed'], [2, 'bob', 'open']]
+          :put events {id => assignee, status}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script("?[id] <- [[3]] :rm events {id}", Default::default())
+        .unwrap();
+    assert_eq!(index_rows(), json!([["bob", 2]]));
+
+    let uses_index = |script: &str| {
+        let expl = db
+            .run_script(&format!("::explain {{ {script} }}"), Default::default())
+            .unwrap();
+        expl.into_json()["rows"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|row| row.as_array().unwrap()[5] == json!(":events:open_idx"))
+    };
+    let implied = "?[id] := *events{id, assignee: 'bob', status: 'open'}";
+    assert!(uses_index(implied));
+    assert!(uses_index(
+        "?[id] := *events{id, assignee: 'bob', status: s}, s == 'open'"
+    ));
+    assert!(!uses_index("?[id] := *events{id, assignee: 'bob'}"));
+    assert!(!uses_index(
+        "?[id] := *events{id, assignee: 'bob', status: s}, s != 'open'"
+    ));
+    let res = db.run_script(implied, Default::default()).unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[2]]));
+
+    let res = db
+        .run_script("::check_integrity", Default::default())
+        .unwrap();
+    assert_eq!(
+        res.into_json()["rows"],
+        json!([["events", "open_idx", 1, 0, 0, true]])
+    );
+}
+/// Storage failing the first point lookup of each key of stored relations, once armed
+#[derive(Clone, Default)]
+struct FlakyStorage {
+    inner: MemStorage,
+    armed: Arc<AtomicBool>,
+    failed: Arc<Mutex<BTreeSet<Vec<u8>>>>,
+}
+
+struct FlakyTx<'s> {
+    inner: MemTx<'s>,
+    storage: &'s FlakyStorage,
+}
+
+impl FlakyTx<'_> {
+    fn maybe_fail(&self, key: &[u8]) -> miette::Result<()> {
+        let is_system = key[..8].iter().all(|b| *b == 0);
+        if self.storage.armed.load(Ordering::Relaxed)
+            && !is_system
+            && self.storage.failed.lock().unwrap().insert(key.to_vec())
+        {
+            miette::bail!(TransientStorageError("locked".to_string()))
+        }
+        Ok(())
+    }
+}
+
+impl<'s> Storage<'s> for FlakyStorage {
+    type Tx = FlakyTx<'s>;
+
+    fn storage_kind(&self) -> &'static str {
+        "flaky"
+    }
+
+    fn transact(&'s self, write: bool) -> miette::Result<Self::Tx> {
+        Ok(FlakyTx {
+            inner: self.inner.transact(write)?,
+            storage: self,
+        })
+    }
+
+    fn del_range(&'s self, lower: &[u8], upper: &[u8]) -> miette::Result<()> {
+        self.inner.del_range(lower, upper)
+    }
+
+    fn range_compact(&'s self, lower: &[u8], upper: &[u8]) -> miette::Result<()> {
+        self.inner.range_compact(lower, upper)
+    }
+
+    fn batch_put<'a>(
+        &'a self,
+        data: Box<dyn Iterator<Item = miette::Result<(Vec<u8>, Vec<u8>)>> + 'a>,
+    ) -> miette::Result<()> {
+        self.inner.batch_put(data)
+    }
+}
+
+impl<'s> StoreTx<'s> for FlakyTx<'s> {
+    fn get(&self, key: &[u8], for_update: bool) -> miette::Result<Option<Vec<u8>>> {
+        self.maybe_fail(key)?;
+        self.inner.get(key, for_update)
+    }
+
+    fn put(&mut self, key: &[u8], val: &[u8]) -> miette::Result<()> {
+        self.inner.put(key, val)
+    }
+
+    fn supports_par_put(&self) -> bool {
+        self.inner.supports_par_put()
+    }
+
+    fn par_put(&self, key: &[u8], val: &[u8]) -> miette::Result<()> {
+        self.inner.par_put(key, val)
+    }
+
+    fn del(&mut self, key: &[u8]) -> miette::Result<()> {
+        self.inner.del(key)
+    }
+
+    fn exists(&self, key: &[u8], for_update: bool) -> miette::Result<bool> {
+        self.maybe_fail(key)?;
+        self.inner.exists(key, for_update)
+    }
+
+    fn commit(&mut self) -> miette::Result<()> {
+        self.inner.commit()
+    }
+
+    fn range_skip_scan_tuple<'a>(
+        &'a self,
+        lower: &[u8],
+        upper: &[u8],
+        valid_at: ValidityTs,
+    ) -> Box<dyn Iterator<Item = miette::Result<Tuple>> + 'a> {
+        self.inner.range_skip_scan_tuple(lower, upper, valid_at)
+    }
+
+    fn range_scan<'a>(
+        &'a self,
+        lower: &[u8],
+        upper: &[u8],
+    ) -> Box<dyn Iterator<Item = miette::Result<(Vec<u8>, Vec<u8>)>> + 'a>
+    where
+        's: 'a,
+    {
+        self.inner.range_scan(lower, upper)
+    }
+
+    fn total_scan<'a>(&'a self) -> Box<dyn Iterator<Item = miette::Result<(Vec<u8>, Vec<u8>)>> + 'a>
+    where
+        's: 'a,
+    {
+        self.inner.total_scan()
+    }
+}
+
+#[test]
+fn test_lookup_retries() {
+    let mut db = Db::new(FlakyStorage::default()).unwrap();
+    db.initialize().unwrap();
+    db.run_script(
+        r"?[k, v] <- [[1, 'a'], [2, 'b'], [3, 'c']] :create a {k => v}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r"?[k, w] <- [[1, 10], [3, 30]] :create b {k => w}",
+        Default::default(),
+    )
+    .unwrap();
+    db.db.armed.store(true, Ordering::Relaxed);
+
+    let run_counting_retries = |db: &Db<FlakyStorage>, script: &str| {
+        let cur_vld = current_validity();
+        let program = parse_script(
+            script,
+            &Default::default(),
+            &db.fixed_rules.read().unwrap(),
+            cur_vld,
+        )
+        .unwrap()
+        .get_single_program()
+        .unwrap();
+        let mut tx = db.transact().unwrap();
+        let res = db
+            .run_query(
+                &mut tx,
+                program,
+                cur_vld,
+                &Default::default(),
+                &mut Default::default(),
+                true,
+            )
+            .map(|(rows, _)| rows);
+        let retried = tx.lookup_retry_count.load(Ordering::Relaxed);
+        (res, retried)
+    };
+
+    // every key of `b` looked up fails once, `k` being bound by unification so that
+    // the join is not made by merging the ordered relations
+    let (res, retried) =
+        run_counting_retries(&db, "?[k, v, w] := *a{k: j, v}, k = j, *b{k, w} :order k");
+    assert_eq!(
+        res.unwrap().into_json()["rows"],
+        json!([[1, "a", 10], [3, "c", 30]])
+    );
+    assert_eq!(retried, 3);
+    let (res, retried) = run_counting_retries(&db, "?[k, v, w] := *a{k: j, v}, k = j, *b{k, w}");
+    assert_eq!(res.unwrap().rows.len(), 2);
+    assert_eq!(retried, 0);
+
+    // existence checks are retried as well
+    db.db.failed.lock().unwrap().clear();
+    let (res, retried) = run_counting_retries(&db, "?[k, v] := *a{k: j, v}, k = j, *b{k} :order k");
+    assert_eq!(
+        res.unwrap().into_json()["rows"],
+        json!([[1, "a"], [3, "c"]])
+    );
+    assert_eq!(retried, 3);
+
+    db.db.failed.lock().unwrap().clear();
+    db.set_lookup_retries(0);
+    let (res, retried) = run_counting_retries(&db, "?[k, v, w] := *a{k: j, v}, k = j, *b{k, w}");
+    let err = CozoError::from_report(res.unwrap_err());
+    assert_eq!(err.kind(), "storage_io");
+    assert_eq!(retried, 0);
+}
+#[test]
+fn test_mutation_batches() {
+    let mut db = new_cozo_mem().unwrap();
+    db.run_script(":create nums {i => v}", Default::default())
+        .unwrap();
+    db.run_script(
+        "?[k, n, rows] <- [[0, 0, 0]] :create fired {k => n, rows}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r"
+        ::set_triggers nums
+        on put {
+            batch[count(i)] := _new[i, v]
+            ?[k, n, rows] := *fired{k, n: prev_n, rows: prev_rows}, batch[cnt],
+                             n = prev_n + 1, rows = prev_rows + cnt
+            :put fired {k => n, rows}
+        }
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    let (_id, receiver) = db.register_callback("nums", None);
+    let fired = |db: &Db<MemStorage>| {
+        db.run_script("?[n, rows] := *fired{n, rows}", Default::default())
+            .unwrap()
+            .into_json()["rows"][0]
+            .clone()
+    };
+
+    // the trigger runs once per statement, and sees all its rows
+    db.run_script(
+        "?[i, v] := i in int_range(1000), v = i * 2 :put nums {i => v}",
+        Default::default(),
+    )
+    .unwrap();
+    assert_eq!(fired(&db), json!([1, 1000]));
+    let (op, new, old) = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(op, CallbackOp::Put);
+    assert_eq!(new.rows.len(), 1000);
+    assert!(old.rows.is_empty());
+    assert!(receiver.try_recv().is_err());
+
+    // the old rows are all found as well
+    db.run_script(
+        "?[i, v] := i in int_range(500, 1500), v = i :put nums {i => v}",
+        Default::default(),
+    )
+    .unwrap();
+    assert_eq!(fired(&db), json!([2, 2000]));
+    let (_, new, old) = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(new.rows.len(), 1000);
+    assert_eq!(old.rows.len(), 500);
+    assert_eq!(
+        old.rows[0],
+        vec![DataValue::from(500), DataValue::from(1000)]
+    );
+
+    // batches are split only when larger than the maximum size
+    db.set_mutation_batch_size(300);
+    db.run_script(
+        "?[i, v] := i in int_range(1000), v = 0 :put nums {i => v}",
+        Default::default(),
+    )
+    .unwrap();
+    assert_eq!(fired(&db), json!([6, 3000]));
+    let sizes = (0..4)
+        .map(|_| {
+            let (_, new, _) = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+            new.rows.len()
+        })
+        .collect_vec();
+    assert_eq!(sizes, vec![300, 300, 300, 100]);
+    assert!(receiver.try_recv().is_err());
+
+    db.run_script(
+        "?[i] := i in int_range(200) :rm nums {i}",
+        Default::default(),
+    )
+    .unwrap();
+    let (op, new, old) = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(op, CallbackOp::Rm);
+    assert_eq!(new.rows.len(), 200);
+    assert_eq!(old.rows.len(), 200);
+}
+#[test]
+fn test_rebuild_relation() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"
+        {:create stock {item => qty}}
+        {:create log {item => qty}}
+        {?[item, qty] <- [['apple', 3], ['pear', 5], ['plum', 3]] :put stock {item => qty}}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script("::index create stock:by_qty {qty}", Default::default())
+        .unwrap();
+    db.run_script(
+        r"::set_triggers stock on put { ?[item, qty] := _new[item, qty] :put log {item => qty} }",
+        Default::default(),
+    )
+    .unwrap();
+
+    let ranges = |tx: &SessionTx<'_>| {
+        let rel = tx.get_relation("stock", false).unwrap();
+        let idx_id = rel.indices["by_qty"].0.id;
+        [rel.id, idx_id].map(|id| {
+            (
+                Tuple::default().encode_as_key(id),
+                Tuple::default().encode_as_key(id.next()),
+            )
+        })
+    };
+    let old_ranges = ranges(&db.transact().unwrap());
+
+    db.run_script("::rebuild stock", Default::default())
+        .unwrap();
+    // the old ranges are deleted in the background
+    db.db.wait_for_deletions().unwrap();
+
+    let tx = db.transact().unwrap();
+    let new_ranges = ranges(&tx);
+    assert_ne!(old_ranges, new_ranges);
+    for (lower, upper) in old_ranges {
+        assert_eq!(tx.store_tx.range_scan(&lower, &upper).count(), 0);
+    }
+    for (lower, upper) in new_ranges {
+        assert_eq!(tx.store_tx.range_scan(&lower, &upper).count(), 3);
+    }
+    drop(tx);
+
+    let res = db
+        .run_script(
+            "?[item, qty] := *stock{item, qty} :order item",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(
+        res.into_json()["rows"],
+        json!([["apple", 3], ["pear", 5], ["plum", 3]])
+    );
+    let res = db
+        .run_script(
+            "?[item] := *stock:by_qty{qty: 3, item} :order item",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([["apple"], ["plum"]]));
+
+    db.run_script(
+        "?[item, qty] <- [['fig', 3]] :put stock {item => qty}",
+        Default::default(),
+    )
+    .unwrap();
+    let res = db
+        .run_script("?[item, qty] := *log{item, qty}", Default::default())
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([["fig", 3]]));
+    let res = db
+        .run_script(
+            "?[item] := *stock{item, qty: 3} :order item",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(
+        res.into_json()["rows"],
+        json!([["apple"], ["fig"], ["plum"]])
+    );
+    let res = db
+        .run_script("::check_integrity", Default::default())
+        .unwrap();
+    assert_eq!(
+        res.into_json()["rows"],
+        json!([["stock", "by_qty", 4, 0, 0, true]])
+    );
+
+    db.run_script(":create other {k}", Default::default())
+        .unwrap();
+    let res = db
+        .run_script("::relations", Default::default())
+        .unwrap()
+        .into_json();
+    assert_eq!(res["rows"].as_array().unwrap().len(), 4);
+}
+#[cfg(feature = "graph-algo")]
+#[test]
+fn test_fixed_rule_options_from_params() {
+    let db = new_cozo_mem().unwrap();
+    let run = |k_expr: &str, params: &[(&str, DataValue)]| {
+        let params = params
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect();
+        db.run_script(
+            &format!(
+                r"edges[f, t, w] <- [['a', 'b', 1], ['b', 'c', 2], ['c', 'd', 4]]
+                  starts[s] <- [['c']]
+                  r[start, goal, cost, path] <~ ShortestPathDijkstra(edges[], starts[],
+                                                                     undirected: $undirected,
+                                                                     k: {k_expr})
+                  ?[goal, cost] := r[_, goal, cost, _]"
+            ),
+            params,
+        )
+        .map(|res| res.into_json()["rows"].clone())
+    };
+
+    let res = run(
+        "$k",
+        &[
+            ("undirected", DataValue::from(true)),
+            ("k", DataValue::from(2)),
+        ],
+    )
+    .unwrap();
+    assert_eq!(res, json!([["b", 2.0], ["c", 0.0]]));
+    let res = run(
+        "$k",
+        &[
+            ("undirected", DataValue::from(false)),
+            ("k", DataValue::from(2)),
+        ],
+    )
+    .unwrap();
+    assert_eq!(res, json!([["c", 0.0], ["d", 4.0]]));
+
+    let res = run(
+        "$base * 2",
+        &[
+            ("undirected", DataValue::from(true)),
+            ("base", DataValue::from(1)),
+        ],
+    )
+    .unwrap();
+    assert_eq!(res, json!([["b", 2.0], ["c", 0.0]]));
+    let res = run(
+        "$base * 2",
+        &[
+            ("undirected", DataValue::from(true)),
+            ("base", DataValue::from(2)),
+        ],
+    )
+    .unwrap();
+    assert_eq!(res, json!([["a", 3.0], ["b", 2.0], ["c", 0.0], ["d", 4.0]]));
+
+    let err = run(
+        "$k",
+        &[
+            ("undirected", DataValue::from("yes")),
+            ("k", DataValue::from(2)),
+        ],
+    )
+    .unwrap_err();
+    assert_eq!(err.code().unwrap().to_string(), "fixed_rule::arg_wrong");
+    assert_eq!(
+        err.to_string(),
+        "Wrong value for option 'undirected' of 'ShortestPathDijkstra'"
+    );
+    let err = run(
+        "$base / 2",
+        &[
+            ("undirected", DataValue::from(true)),
+            ("base", DataValue::from(1)),
+        ],
+    )
+    .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Wrong value for option 'k' of 'ShortestPathDijkstra'"
+    );
+}
+
+#[test]
+fn test_output_float_format_and_big_ints() {
+    use rand::Rng;
+
+    use crate::data::json::JsonValue;
+    use crate::{json_to_string, FloatFormat};
+
+    let db = DbInstance::new("mem", "", "").unwrap();
+
+    // the default writes the shortest text reading back to the same float
+    let mut rng = rand::thread_rng();
+    for _ in 0..1000 {
+        let f = f64::from_bits(rng.gen::<u64>());
+        if !f.is_finite() {
+            continue;
+        }
+        let rows = NamedRows::new(vec!["f".to_string()], vec![vec![DataValue::from(f)]]);
+        let text = json_to_string(&rows.into_json(), FloatFormat::Shortest);
+        // read back exactly, which `serde_json` does not do by default
+        let written = text.split("[[").nth(1).unwrap().split("]]").next().unwrap();
+        assert_eq!(written.parse::<f64>().unwrap().to_bits(), f.to_bits());
+    }
+    let res: JsonValue =
+        serde_json::from_str(&db.run_script_str("?[x] <- [[0.1 + 0.2]]", "")).unwrap();
+    assert_eq!(res["rows"], json!([[0.30000000000000004]]));
+
+    let text = db.run_script_str(
+        "?[x, y, z] <- [[0.1 + 0.2, 2 / 3, [-1.0]]] :float_format 'fixed(2)'",
+        "",
+    );
+    assert!(text.contains(r#""rows":[[0.30,0.67,[-1.00]]]"#), "{text}");
+    let res = db.run_script_fold_err(
+        "?[x, y, z] <- [[0.1 + 0.2, 2 / 3, [-1.0]]] :float_format 'fixed(2)'",
+        Default::default(),
+    );
+    assert_eq!(res["rows"], json!([[0.3, 0.67, [-1.0]]]));
+    let text = db.run_script_str("?[x] <- [[1234.5]] :float_format 'scientific'", "");
+    assert!(text.contains(r#""rows":[[1.2345e3]]"#), "{text}");
+    let res = db.run_script_fold_err("?[x] <- [[1]] :float_format 'fixed'", Default::default());
+    assert_eq!(res["ok"], json!(false));
+
+    let res = db.run_script_fold_err(
+        "?[a, b, c] <- [[9007199254740993, [1, -9007199254740993], 9007199254740991]] :big_int_as_string",
+        Default::default(),
+    );
+    assert_eq!(
+        res["rows"],
+        json!([[
+            "9007199254740993",
+            [1, "-9007199254740993"],
+            9007199254740991i64
+        ]])
+    );
+    assert_eq!(res["types"], json!({"int": [[0, 0], [0, 1, 1]]}));
+    let res = db.run_script_fold_err("?[a] <- [[9007199254740993]]", Default::default());
+    assert_eq!(res["rows"], json!([[9007199254740993i64]]));
+    assert_eq!(res["types"], json!(null));
+
+    // only the output is affected
+    db.run_script(
+        "?[k, v] <- [[1, 0.1 + 0.2], [2, 9007199254740993]] :create nums {k => v} :float_format 'fixed(1)' :big_int_as_string",
+        Default::default(),
+    )
+    .unwrap();
+    let res = db
+        .run_script("?[v] := *nums{v} :order v", Default::default())
+        .unwrap();
+    assert_eq!(
+        res.rows,
+        vec![
+            vec![DataValue::from(0.1 + 0.2)],
+            vec![DataValue::from(9007199254740993i64)]
+        ]
+    );
+
+    let db = DbInstance::new(
+        "mem",
+        "",
+        r#"{"float_format": "fixed(3)", "big_int_as_string": true}"#,
+    )
+    .unwrap();
+    let text = db.run_script_str("?[x, y] <- [[1 / 3, 9007199254740993]]", "");
+    assert!(
+        text.contains(r#""rows":[[0.333,"9007199254740993"]]"#),
+        "{text}"
+    );
+    let text = db.run_script_str("?[x] <- [[1 / 3]] :float_format 'shortest'", "");
+    assert!(text.contains(r#""rows":[[0.3333333333333333]]"#), "{text}");
+    assert!(DbInstance::new("mem", "", r#"{"float_format": "exact"}"#).is_err());
+}
+
+#[test]
+fn test_auto_update_columns() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r":create docs {id => body, updated_at: Float auto_update now(),
+                          updated_by: String default 'server' auto_update $client_id,
+                          rev: Int default 0 auto_update 0 allow_override}",
+        Default::default(),
+    )
+    .unwrap();
+    let client = |id: &str| BTreeMap::from([("client_id".to_string(), DataValue::from(id))]);
+    let get = |id: i64| {
+        db.run_script(
+            "?[updated_at, updated_by, rev] := *docs{id: $id, updated_at, updated_by, rev}",
+            BTreeMap::from([("id".to_string(), DataValue::from(id))]),
+        )
+        .unwrap()
+        .rows
+        .remove(0)
+    };
+
+    db.run_script(
+        "?[id, body] <- [[1, 'a'], [2, 'b']] :put docs {id => body}",
+        client("phone"),
+    )
+    .unwrap();
+    let inserted = get(1);
+    assert!(inserted[0].get_float().unwrap() > 0.);
+    assert_eq!(inserted[1], DataValue::from("phone"));
+    assert_eq!(inserted[2], DataValue::from(0));
+
+    std::thread::sleep(Duration::from_millis(10));
+    db.run_script(
+        "?[id, body] <- [[1, 'c']] :put docs {id => body}",
+        client("laptop"),
+    )
+    .unwrap();
+    let updated = get(1);
+    assert!(updated[0].get_float().unwrap() > inserted[0].get_float().unwrap());
+    assert_eq!(updated[1], DataValue::from("laptop"));
+    assert_eq!(get(2)[1], DataValue::from("phone"));
+
+    // the parameter is required by the writing query
+    assert!(db
+        .run_script(
+            "?[id, body] <- [[1, 'd']] :put docs {id => body}",
+            Default::default()
+        )
+        .is_err());
+
+    // explicit values are rejected unless overriding is allowed
+    let err = db
+        .run_script(
+            "?[id, body, updated_at] <- [[1, 'd', 0.0]] :put docs {id => body, updated_at}",
+            client("phone"),
+        )
+        .unwrap_err();
+    assert_eq!(
+        err.code().unwrap().to_string(),
+        "eval::auto_update_override"
+    );
+    db.run_script(
+        "?[id, body, rev] <- [[1, 'd', 5]] :put docs {id => body, rev}",
+        client("phone"),
+    )
+    .unwrap();
+    assert_eq!(get(1)[2], DataValue::from(5));
+    db.run_script(
+        "?[id, body] <- [[1, 'e']] :put docs {id => body}",
+        client("phone"),
+    )
+    .unwrap();
+    assert_eq!(get(1)[2], DataValue::from(0));
+
+    // writes by triggers are updated too
+    db.run_script(":create edits {id => body}", Default::default())
+        .unwrap();
+    db.run_script(
+        r"::set_triggers edits on put {
+              ?[id, body, rev] := _new[id, body], rev = 1
+              :put docs {id => body, rev}
+          }",
+        Default::default(),
+    )
+    .unwrap();
+    let before = get(2);
+    std::thread::sleep(Duration::from_millis(10));
+    db.run_script(
+        "?[id, body] <- [[2, 'f']] :put edits {id => body}",
+        client("tablet"),
+    )
+    .unwrap();
+    let after = get(2);
+    assert!(after[0].get_float().unwrap() > before[0].get_float().unwrap());
+    assert_eq!(after[1], DataValue::from("tablet"));
+    assert_eq!(after[2], DataValue::from(1));
+
+    let cols = db.run_script("::columns docs", Default::default()).unwrap();
+    assert_eq!(cols.headers[5], "auto_update");
+    assert_eq!(cols.rows[2][5], DataValue::from("now()"));
+    assert_eq!(cols.rows[3][5], DataValue::from("$client_id"));
+    assert_eq!(cols.rows[4][6], DataValue::from(true));
+    assert_eq!(cols.rows[1][5], DataValue::Null);
+
+    // key columns cannot be updated automatically
+    assert!(db
+        .run_script(":create bad {k auto_update now()}", Default::default())
+        .is_err());
+}
+#[test]
+fn test_multiple_parse_errors() {
+    let db = new_cozo_mem().unwrap();
+    let script =
+        "r1[x] := x = 1 +* 2\nr2[x] := x = 3 3\nr3[x] := x = ]\n?[x] := r1[x], r2[x], r3[x]";
+    let err = db.run_script(script, Default::default()).unwrap_err();
+    let related = err.related().unwrap().collect_vec();
+    assert_eq!(related.len(), 3);
+    let lines = script.lines().collect_vec();
+    let mut line_start = 0;
+    for (err, line) in related.iter().zip(lines) {
+        let label = err.labels().unwrap().next().unwrap();
+        assert!(label.offset() >= line_start);
+        assert!(label.offset() < line_start + line.len());
+        line_start += line.len() + 1;
+    }
+    let json = crate::format_error_as_json(err, Some(script));
+    assert_eq!(json["related"].as_array().unwrap().len(), 3);
+
+    // the statement after an unclosed block is not reported separately
+    let script = "{?[x] := x = 1}\n{?[y] := y = 2\n{?[z] := z = 3}";
+    let err = db.run_script(script, Default::default()).unwrap_err();
+    assert!(err.related().is_none());
+    assert_eq!(err.code().unwrap().to_string(), "parser::pest");
+}
+
+/// Checks that queries with `:limit` stop reading the relation `big` of `n` rows early
+fn check_limit_stops_scans<'s, S: Storage<'s>>(db: &'s Db<S>, n: usize) {
+    db.run_script(
+        &format!("?[a, b] := a in int_range({n}), b = a % 7 :create big {{a => b}}"),
+        Default::default(),
+    )
+    .unwrap();
+    let scanned = |script: &str, n_rows: usize| -> usize {
+        let res = db
+            .run_script(&format!("{script} :report_usage"), Default::default())
+            .unwrap();
+        assert_eq!(res.rows.len(), n_rows, "{script}");
+        res.usage().unwrap().rows_scanned["big"]
+    };
+
+    assert!(scanned("?[a, b] := *big[a, b] :limit 10", 10) <= 11);
+    // through filters and projections, counting distinct rows
+    assert!(scanned("?[a] := *big[a, b], b == 3 :limit 10 :offset 5", 10) <= 7 * 16);
+    assert!(scanned("?[b] := *big[a, b] :limit 5", 5) <= 6);
+    // sorting needs all rows
+    assert_eq!(scanned("?[a, b] := *big[a, b] :order -a :limit 10", 10), n);
+
+    let explained = db
+        .run_script(
+            "::explain { ?[a] := *big[a, b], b == 3 :limit 10 :offset 5 }",
+            Default::default(),
+        )
+        .unwrap();
+    let op_idx = explained.headers.iter().position(|h| h == "op").unwrap();
+    let expr_idx = explained
+        .headers
+        .iter()
+        .position(|h| h == "filters/expr")
+        .unwrap();
+    let top = explained.rows.last().unwrap();
+    assert_eq!(top[op_idx], DataValue::from("limit"));
+    assert_eq!(top[expr_idx], DataValue::from("skip 5, take 10"));
+    for script in [
+        "::explain { ?[a] := *big[a, b] :order a :limit 10 }",
+        "::explain { ?[b, count(a)] := *big[a, b] :limit 2 }",
+    ] {
+        let explained = db.run_script(script, Default::default()).unwrap();
+        assert!(explained
+            .rows
+            .iter()
+            .all(|row| row[op_idx] != DataValue::from("limit")));
+    }
+    // aggregations read everything before the limit applies
+    assert_eq!(scanned("?[b, count(a)] := *big[a, b] :limit 2", 2), n);
+}
+
+#[test]
+fn test_limit_stops_scans() {
+    let db = new_cozo_mem().unwrap();
+    check_limit_stops_scans(&db, 100_000);
+}
+
+#[cfg(feature = "storage-sqlite")]
+#[test]
+fn test_limit_stops_sqlite_scans() {
+    let path = std::env::temp_dir().join(format!("cozo-test-{}.db", rand::random::<u64>()));
+    let db = crate::new_cozo_sqlite(&path).unwrap();
+    check_limit_stops_scans(&db, 200_000);
+    drop(db);
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn test_explain_script() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"
+        {:create r {a => b}}
+        {:create v {k, at: Validity => x}}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    let res = db
+        .explain_script(
+            "?[c, a] := *r{a, b}, c = a + 1, c > $min, *v{k: a, x @ 'NOW'}, not *r{a: c}",
+            BTreeMap::from([("min".to_string(), DataValue::from(1))]),
+        )
+        .unwrap();
+    assert_eq!(res.headers, ["stratum", "rule", "rule_idx", "plan"]);
+    assert_eq!(res.rows.len(), 1);
+    assert_eq!(res.rows[0][1], DataValue::from("?"));
+    let plan: serde_json::Value = serde_json::from_str(res.rows[0][3].get_str().unwrap()).unwrap();
+    assert_eq!(plan["kind"], json!("rule"));
+    assert_eq!(plan["aggregations"], json!([null, null]));
+
+    fn collect_kinds(node: &serde_json::Value, kinds: &mut BTreeSet<String>) {
+        kinds.insert(node["kind"].as_str().unwrap().to_string());
+        assert!(node["bindings"].is_array());
+        assert!(node["span"].is_array());
+        for child in ["input", "left", "right"] {
+            if !node[child].is_null() {
+                collect_kinds(&node[child], kinds);
+            }
+        }
+    }
+    let mut kinds = BTreeSet::new();
+    collect_kinds(&plan["body"], &mut kinds);
+    for kind in [
+        "reorder",
+        "join",
+        "neg_join",
+        "stored",
+        "stored_with_validity",
+        "filter",
+        "unify",
+    ] {
+        assert!(kinds.contains(kind), "{kind} not in {kinds:?}");
+    }
+    assert_eq!(plan["body"]["kind"], json!("reorder"));
+    assert_eq!(plan["body"]["bindings"], json!(["c", "a"]));
+
+    let res = db
+        .explain_script("?[a] <~ Constant(data: [[1]])", Default::default())
+        .unwrap();
+    assert!(res.rows.iter().any(|row| {
+        let plan: serde_json::Value = serde_json::from_str(row[3].get_str().unwrap()).unwrap();
+        plan == json!({"kind": "fixed_rule", "name": "Constant"})
+    }));
+
+    assert!(db
+        .explain_script("{?[a] <- [[1]]} {?[a] <- [[2]]}", Default::default())
+        .is_err());
+}
+
+#[test]
+fn test_run_script_streaming() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"
+        ?[x, y] := x in int_range(1000), y = x % 7
+        :create stream_rel {x => y}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    let stream = |script: &str, stop_after: usize| {
+        let mut rows = vec![];
+        let headers = db
+            .run_script_streaming(script, Default::default(), |row| {
+                rows.push(row);
+                Ok(if rows.len() == stop_after {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                })
+            })
+            .unwrap();
+        (headers, rows)
+    };
+
+    let (headers, rows) = stream("?[x, y] := *stream_rel{x, y}", usize::MAX);
+    assert_eq!(headers, ["x", "y"]);
+    assert_eq!(rows.len(), 1000);
+    let (_, rows) = stream(
+        "?[x] := *stream_rel{x, y: 3} :order -x :limit 2",
+        usize::MAX,
+    );
+    assert_eq!(rows, [[DataValue::from(997)], [DataValue::from(990)]]);
+    let (_, rows) = stream("?[x] := *stream_rel{x} :offset 10 :limit 3", usize::MAX);
+    assert_eq!(rows.len(), 3);
+    let (_, rows) = stream("?[x] := *stream_rel{x}", 5);
+    assert_eq!(rows.len(), 5);
+
+    let failed = db.run_script_streaming("?[x] := *stream_rel{x}", Default::default(), |_| {
+        Err(miette::miette!("cannot write row"))
+    });
+    assert!(failed.unwrap_err().to_string().contains("cannot write row"));
+    assert!(db
+        .run_script_streaming(
+            "?[x, y] <- [[1, 1]] :put stream_rel {x => y}",
+            Default::default(),
+            |_| Ok(ControlFlow::Continue(()))
+        )
+        .is_err());
+
+    // the transaction is released when the callback panics
+    let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        db.run_script_streaming("?[x] := *stream_rel{x}", Default::default(), |_| {
+            panic!("callback panicked")
+        })
+    }));
+    assert!(panicked.is_err());
+    db.run_script(
+        "?[x, y] <- [[1000, 0]] :put stream_rel {x => y}",
+        Default::default(),
+    )
+    .unwrap();
+    let (_, rows) = stream("?[x] := *stream_rel{x}", usize::MAX);
+    assert_eq!(rows.len(), 1001);
+
+    let db = DbInstance::new("mem", "", "").unwrap();
+    let mut rows = vec![];
+    let res = db.run_script_streaming_str("?[a, b] <- [[1, 'x'], [2, null]]", "", |row| {
+        rows.push(row.to_string());
+        true
+    });
+    let res: serde_json::Value = serde_json::from_str(&res).unwrap();
+    assert_eq!(res["ok"], json!(true));
+    assert_eq!(res["headers"], json!(["a", "b"]));
+    assert_eq!(rows, [r#"[1,"x"]"#, "[2,null]"]);
+    let res = db.run_script_streaming_str("?[a] <- [[$a]]", "[1]", |_| true);
+    assert!(res.contains(r#""ok":false"#));
+}
+
+#[test]
+fn test_script_timeout() {
+    let db = DbInstance::new("mem", "", "").unwrap();
+    let timeout = Duration::from_millis(100);
+    let endless = "r[x] := x = 0
+                   r[y] := r[x], y = x + 1
+                   ?[x] := r[x]";
+    let err = db
+        .run_script_with_timeout(endless, Default::default(), timeout)
+        .unwrap_err();
+    assert_eq!(err.downcast_ref::<CozoError>().unwrap().kind(), "timeout");
+    assert!(err.to_string().contains("timed out after running for"));
+
+    // the relation created before the endless loop is rolled back with the writes in it
+    let err = db
+        .run_script_with_timeout(
+            r#"
+            {:create _counter {n}}
+            %loop
+                { ?[n] := n = rand_uuid_v1(); :put _counter {n} }
+            %end
+            "#,
+            Default::default(),
+            timeout,
+        )
+        .unwrap_err();
+    assert_eq!(err.downcast_ref::<CozoError>().unwrap().kind(), "timeout");
+    assert!(db
+        .run_script("?[n] := *_counter[n]", Default::default())
+        .is_err());
+
+    // the timeout of a query only stops that query
+    let res = db
+        .run_script_with_timeout(
+            "?[x] <- [[1]] :timeout 10",
+            Default::default(),
+            Duration::from_secs(10),
+        )
+        .unwrap();
+    assert_eq!(res.rows, vec![vec![DataValue::from(1)]]);
+    let err = db
+        .run_script_with_timeout(
+            &format!("{endless}\n:timeout 0.05"),
+            Default::default(),
+            Duration::from_secs(10),
+        )
+        .unwrap_err();
+    assert!(!err.to_string().contains("timed out after running for"));
+
+    let res: serde_json::Value =
+        serde_json::from_str(&db.run_script_str(endless, r#"{"timeout": 0.1}"#)).unwrap();
+    assert_eq!(res["ok"], json!(false));
+    assert_eq!(res["kind"], json!("timeout"));
+    let res: serde_json::Value =
+        serde_json::from_str(&db.run_script_str("?[x] <- [[$x]]", r#"{"timeout": 5, "x": 1}"#))
+            .unwrap();
+    assert_eq!(res["rows"], json!([[1]]));
+    let res: serde_json::Value =
+        serde_json::from_str(&db.run_script_str("?[x] <- [[1]]", r#"{"timeout": "soon"}"#))
+            .unwrap();
+    assert_eq!(res["ok"], json!(false));
+}
+
+#[test]
+fn test_list_and_kill_running() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(":create counter {x}", Default::default())
+        .unwrap();
+    let endless = "r[x] := x = 0
+                   r[y] := r[x], y = x + 1
+                   ?[x] := r[x]
+                   :put counter {x}";
+    assert!(db.list_running().unwrap().is_empty());
+    let res = db.run_script("::kill 12345", Default::default()).unwrap();
+    assert_eq!(
+        res.rows,
+        vec![vec![DataValue::from("NOT_FOUND"), DataValue::from(false)]]
+    );
+    assert!(!db.kill_query(12345));
+
+    let err = std::thread::scope(|s| {
+        let running = s.spawn(|| db.run_script(endless, Default::default()).unwrap_err());
+        let listed = loop {
+            let listed = db.run_script("::running", Default::default()).unwrap();
+            if !listed.rows.is_empty() {
+                break listed;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(
+            listed.headers,
+            ["id", "started_at", "elapsed", "script", "is_write"]
+        );
+        let queries = db.list_running().unwrap();
+        assert_eq!(queries.len(), 1);
+        assert!(queries[0].elapsed >= 0.);
+        assert!(queries[0].script.starts_with("r[x] := x = 0"));
+        assert!(queries[0].is_write);
+        assert_eq!(
+            listed.rows[0][3],
+            DataValue::from(queries[0].script.clone())
+        );
+
+        let res = db
+            .run_script(
+                "::kill $id",
+                BTreeMap::from([("id".to_string(), listed.rows[0][0].clone())]),
+            )
+            .unwrap();
+        assert_eq!(res.rows[0][1], DataValue::from(true));
+        running.join().unwrap()
+    });
+    assert_eq!(err.downcast_ref::<CozoError>().unwrap().kind(), "killed");
+    assert!(db.list_running().unwrap().is_empty());
+    // the writes of the killed query are rolled back
+    let res = db
+        .run_script("?[count(x)] := *counter[x]", Default::default())
+        .unwrap();
+    assert_eq!(res.rows, vec![vec![DataValue::from(0)]]);
+
+    let long_script = format!("?[x] <- [[{}]]", "1, ".repeat(100));
+    let script = RunningScript::new(&long_script, Default::default());
+    assert_eq!(script.snippet.chars().count(), 200);
+}
+
+#[test]
+fn test_partial_update() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r#"
+        {:create counters {k: String => hits: Int, misses: Int default 0, label: String}}
+        {?[k, hits, label] <- [['a', 1, 'first'], ['b', 10, 'second']]
+         :put counters {k => hits, label}}
+        "#,
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script("::index create counters:by_hits {hits}", Default::default())
+        .unwrap();
+    let (_id, receiver) = db.register_callback("counters", None);
+    let rows = |script: &str| {
+        db.run_script(script, Default::default())
+            .unwrap()
+            .into_json()["rows"]
+            .clone()
+    };
+
+    // only the columns given are written, also when the same key is updated several times
+    for _ in 0..3 {
+        db.run_script(
+            "?[k, hits] := *counters{k, hits: old}, k = 'a', hits = old + 1
+             :update counters {k => hits}",
+            Default::default(),
+        )
+        .unwrap();
+    }
+    db.run_script(
+        "?[k, misses] <- [['b', 1], ['b', 2]] :update counters {k => misses}",
+        Default::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        rows("?[k, hits, misses, label] := *counters{k, hits, misses, label}"),
+        json!([["a", 4, 0, "first"], ["b", 10, 2, "second"]])
+    );
+    // the index follows the updated column
+    assert_eq!(
+        rows("?[hits, k] := *counters:by_hits{hits, k}"),
+        json!([[4, "a"], [10, "b"]])
+    );
+
+    // callbacks see the whole rows
+    let (op, new, old) = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(op, CallbackOp::Put);
+    assert_eq!(new.headers, ["k", "hits", "misses", "label"]);
+    assert_eq!(new.into_json()["rows"], json!([["a", 2, 0, "first"]]));
+    assert_eq!(old.into_json()["rows"], json!([["a", 1, 0, "first"]]));
+
+    // the rows updated must exist, and nothing is written if one does not
+    let err = db
+        .run_script(
+            "?[k, hits] <- [['a', 100], ['c', 1]] :update counters {k => hits}",
+            Default::default(),
+        )
+        .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<CozoError>().unwrap().kind(),
+        "constraint_violation"
+    );
+    assert_eq!(
+        rows("?[k, hits] := *counters{k, hits}"),
+        json!([["a", 4], ["b", 10]])
+    );
+
+    // upserted rows not found take the defaults of the columns not given
+    db.run_script(
+        "?[k, hits, label] <- [['b', 11, 'again'], ['c', 1, 'third']]
+         :upsert counters {k => hits, label}",
+        Default::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        rows("?[k, hits, misses, label] := *counters{k, hits, misses, label}"),
+        json!([
+            ["a", 4, 0, "first"],
+            ["b", 11, 2, "again"],
+            ["c", 1, 0, "third"]
+        ])
+    );
+    assert_eq!(
+        rows("?[hits, k] := *counters:by_hits{hits, k}"),
+        json!([[1, "c"], [4, "a"], [11, "b"]])
+    );
+    let err = db
+        .run_script(
+            "?[k, hits] <- [['d', 1]] :upsert counters {k => hits}",
+            Default::default(),
+        )
+        .unwrap_err();
+    assert!(err.root_cause().to_string().contains("label"));
+}
+
+#[test]
+fn test_mutation_counts() {
+    let db = DbInstance::new("mem", "", "").unwrap();
+    let run = |script: &str| {
+        db.run_script(script, Default::default())
+            .unwrap()
+            .into_json()
+    };
+    let res = run("?[k, v] <- [[1, 'a'], [2, 'b']] :create kv {k => v}");
+    assert_eq!(
+        res["headers"],
+        json!(["status", "op", "rows_affected", "inserted", "overwritten"])
+    );
+    assert_eq!(res["rows"], json!([["OK", "create", 2, 2, 0]]));
+    let res = run("?[k, v] <- [[2, 'x'], [3, 'c'], [4, 'd']] :put kv {k => v}");
+    assert_eq!(res["rows"], json!([["OK", "put", 3, 2, 1]]));
+    // keys not found are not counted
+    let res = run("?[k] <- [[1], [5]] :rm kv {k}");
+    assert_eq!(res["rows"], json!([["OK", "rm", 1, 0, 0]]));
+    let res = run("?[k, v] <- [[3, 'y']] :update kv {k => v}");
+    assert_eq!(res["rows"], json!([["OK", "update", 1, 0, 1]]));
+    let res = run("?[k, v] <- [[3, 'y']] :ensure kv {k => v}");
+    assert_eq!(res["rows"], json!([["OK", "ensure", 0, 0, 0]]));
+
+    // the last statement of an imperative program gives the result
+    let res = run("{?[k] <- [[2], [3]] :rm kv {k}}
+         {?[k, v] <- [[2, 'b'], [4, 'z']] :put kv {k => v}}");
+    assert_eq!(res["rows"], json!([["OK", "put", 2, 1, 1]]));
+    let res: serde_json::Value =
+        serde_json::from_str(&db.run_script_str("?[k] <- [[2], [4], [6]] :rm kv {k}", "")).unwrap();
+    assert_eq!(res["rows"], json!([["OK", "rm", 2, 0, 0]]));
+
+    // rows written earlier in the same transaction are seen
+    let tx = db.multi_transaction(true);
+    let res = tx
+        .run_script("?[k, v] <- [[7, 'g']] :put kv {k => v}", Default::default())
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([["OK", "put", 1, 1, 0]]));
+    let res = tx
+        .run_script("?[k, v] <- [[7, 'h']] :put kv {k => v}", Default::default())
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([["OK", "put", 1, 0, 1]]));
+    tx.commit().unwrap();
+}
+
+#[test]
+fn test_list_indices() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(":create friends {fr, to => data}", Default::default())
+        .unwrap();
+    let list = || {
+        db.run_script("::indices friends", Default::default())
+            .unwrap()
+            .into_json()
+    };
+    assert_eq!(list()["rows"], json!([]));
+
+    db.run_script("::index create friends:rev {to}", Default::default())
+        .unwrap();
+    db.run_script(
+        "::index create friends:by_data {data} where data > 0",
+        Default::default(),
+    )
+    .unwrap();
+    let listed = list();
+    assert_eq!(listed["headers"], json!(["name", "columns", "predicate"]));
+    let rows = listed["rows"].as_array().unwrap();
+    assert_eq!(rows.len(), 2);
+    // the key columns of the relation not indexed follow the indexed ones
+    assert_eq!(rows[0][0], json!("by_data"));
+    assert_eq!(rows[0][1], json!(["data", "fr", "to"]));
+    assert!(rows[0][2].as_str().unwrap().contains("data"));
+    assert_eq!(rows[1], json!(["rev", ["to", "fr"], null]));
+
+    db.run_script("::index drop friends:by_data", Default::default())
+        .unwrap();
+    assert_eq!(list()["rows"], json!([["rev", ["to", "fr"], null]]));
+    assert!(db
+        .run_script("::indices nowhere", Default::default())
+        .is_err());
+
+    // the hidden indices of unique columns and constraints are not listed
+    for script in [
+        ":create users {id: Int => email: String unique}",
+        ":create orders {id: Int => user_id: Int}",
+        "::constraint create orders.user_id references users.id",
+        "::index create orders:by_user {user_id}",
+    ] {
+        db.run_script(script, Default::default()).unwrap();
+    }
+    let list = |rel: &str| {
+        db.run_script(&format!("::indices {rel}"), Default::default())
+            .unwrap()
+            .into_json()["rows"]
+            .clone()
+    };
+    assert_eq!(list("users"), json!([]));
+    assert_eq!(
+        list("orders"),
+        json!([["by_user", ["user_id", "id"], null]])
+    );
+}
+
+#[test]
+fn test_unique_columns() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r#"
+        {:create users {id: Int => name: String, email: String? unique}}
+        {?[id, name, email] <- [[1, 'a', 'a@x.org'], [2, 'b', 'b@x.org'], [3, 'c', null]]
+         :put users {id => name, email}}
+        "#,
+        Default::default(),
+    )
+    .unwrap();
+    let rows = |script: &str| {
+        db.run_script(script, Default::default())
+            .unwrap()
+            .into_json()["rows"]
+            .clone()
+    };
+    let users = || rows("?[id, email] := *users{id, email}");
+
+    // the whole mutation is rolled back, including the rows written before the violation
+    let err = db
+        .run_script(
+            "?[id, name, email] <- [[4, 'd', 'd@x.org'], [5, 'e', 'b@x.org']]
+             :put users {id => name, email}",
+            Default::default(),
+        )
+        .unwrap_err();
+    assert_eq!(err.code().unwrap().to_string(), "eval::unique_violation");
+    let message = err.root_cause().to_string();
+    assert!(message.contains("'email'") && message.contains("'users'"));
+    assert!(message.contains("b@x.org"));
+    assert_eq!(
+        err.downcast_ref::<CozoError>().unwrap().kind(),
+        "constraint_violation"
+    );
+    assert_eq!(users(), json!([[1, "a@x.org"], [2, "b@x.org"], [3, null]]));
+    // rows in the same mutation conflict with each other
+    assert!(db
+        .run_script(
+            "?[id, name, email] <- [[4, 'd', 'd@x.org'], [5, 'e', 'd@x.org']]
+             :put users {id => name, email}",
+            Default::default(),
+        )
+        .is_err());
+
+    // a row may keep its own value, nulls never conflict, and freed values can be taken
+    db.run_script(
+        r#"
+        {?[id, name, email] <- [[1, 'aa', 'a@x.org'], [4, 'd', null]]
+         :put users {id => name, email}}
+        {?[id, email] <- [[2, 'bb@x.org']] :update users {id => email}}
+        {?[id, email] <- [[3, 'b@x.org']] :update users {id => email}}
+        "#,
+        Default::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        users(),
+        json!([[1, "a@x.org"], [2, "bb@x.org"], [3, "b@x.org"], [4, null]])
+    );
+    assert!(db
+        .run_script(
+            "?[id, email] <- [[4, 'a@x.org']] :update users {id => email}",
+            Default::default(),
+        )
+        .is_err());
+
+    // imports are checked unless told otherwise
+    let import = |options: ImportOptions| {
+        let data = NamedRows::new(
+            vec!["id".to_string(), "name".to_string(), "email".to_string()],
+            vec![vec![
+                DataValue::from(5),
+                DataValue::from("e"),
+                DataValue::from("a@x.org"),
+            ]],
+        );
+        db.import_relations_with_options(BTreeMap::from([("users".to_string(), data)]), options)
+    };
+    let err = import(Default::default()).unwrap_err();
+    assert_eq!(err.code().unwrap().to_string(), "eval::unique_violation");
+    import(ImportOptions {
+        skip_constraint_checks: true,
+        ..Default::default()
+    })
+    .unwrap();
+    assert_eq!(
+        rows("?[email, id] := *users{id, email}, email = 'a@x.org'"),
+        json!([["a@x.org", 1], ["a@x.org", 5]])
+    );
+
+    // the hidden index is neither listed for removal nor in the way of replacing the relation
+    assert!(db
+        .run_script("::index drop users:email.unique", Default::default())
+        .is_err());
+    db.run_script(
+        "?[id, email] <- [[1, 'x'], [2, 'x']] :replace users {id => email}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script("::remove users", Default::default()).unwrap();
+    assert!(db
+        .run_script(":create bad {k unique => v}", Default::default())
+        .is_err());
+}
+#[cfg(feature = "storage-sqlite")]
+#[test]
+fn test_unique_columns_from_backup() {
+    let path = std::env::temp_dir().join(format!("cozo-test-{}.db", rand::random::<u64>()));
+    let schema = ":create users {id: Int => email: String unique}";
+    let backup = new_cozo_mem().unwrap();
+    backup.run_script(schema, Default::default()).unwrap();
+    backup
+        .run_script(
+            "?[id, email] <- [[1, 'a'], [2, 'b']] :put users {id => email}",
+            Default::default(),
+        )
+        .unwrap();
+    backup.backup_db(&path).unwrap();
+
+    let db = new_cozo_mem().unwrap();
+    db.run_script(schema, Default::default()).unwrap();
+    db.run_script(
+        "?[id, email] <- [[1, 'x'], [3, 'b']] :put users {id => email}",
+        Default::default(),
+    )
+    .unwrap();
+    let relations = ["users".to_string()];
+    let err = db.import_from_backup(&path, &relations).unwrap_err();
+    assert_eq!(err.code().unwrap().to_string(), "eval::unique_violation");
+
+    db.run_script("?[id] <- [[3]] :rm users {id}", Default::default())
+        .unwrap();
+    db.import_from_backup(&path, &relations).unwrap();
+    // the entry of the overwritten row is gone from the index
+    let by_email = db
+        .run_script("?[email, id] := *users{id, email}", Default::default())
+        .unwrap()
+        .into_json()["rows"]
+        .clone();
+    assert_eq!(by_email, json!([["a", 1], ["b", 2]]));
+    db.run_script(
+        "?[id, email] <- [[4, 'x']] :put users {id => email}",
+        Default::default(),
+    )
+    .unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_unique_columns_with_time_travel() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r#"
+        {:create users {id: Int, at: Validity => email: String unique}}
+        {?[id, at, email] <- [[1, [10, true], 'a'], [2, [10, true], 'b']]
+         :put users {id, at => email}}
+        "#,
+        Default::default(),
+    )
+    .unwrap();
+    // new versions and retractions of the same user keep its email
+    db.run_script(
+        "?[id, at, email] <- [[1, [20, true], 'a'], [1, [30, false], 'a']]
+         :put users {id, at => email}",
+        Default::default(),
+    )
+    .unwrap();
+    let versions = db
+        .run_script("?[count(at)] := *users{id: 1, at}", Default::default())
+        .unwrap();
+    assert_eq!(versions.rows, vec![vec![DataValue::from(3)]]);
+    // but the email of another user is still taken
+    let err = db
+        .run_script(
+            "?[id, at, email] <- [[2, [20, true], 'a']] :put users {id, at => email}",
+            Default::default(),
+        )
+        .unwrap_err();
+    assert_eq!(err.code().unwrap().to_string(), "eval::unique_violation");
+}
+
+#[test]
+fn test_column_defaults() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"
+        {:create tickets {id: Uuid default rand_uuid_v4(), at: Validity default 'ASSERT'
+                          => title: String, status: String default 'new'}}
+        {:create log {id: Uuid => status: String}}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r"::set_triggers tickets on put { ?[id, status] := _new[id, _, _, status] :put log {id => status} }",
+        Default::default(),
+    )
+    .unwrap();
+    let rows = |script: &str| db.run_script(script, Default::default()).unwrap().rows;
+
+    let cols = rows("::columns tickets");
+    assert_eq!(cols[0][7], DataValue::from("rand_uuid_v4()"));
+    assert_eq!(cols[2][7], DataValue::Null);
+    assert_eq!(cols[3][7], DataValue::from("\"new\""));
+
+    // the defaults are evaluated anew for each row, of key and value columns alike
+    rows("?[title] <- [['a'], ['b']] :put tickets {=> title}");
+    rows("?[title, status] <- [['c', 'done']] :put tickets {=> title, status}");
+    let tickets = rows("?[title, status, id, at] := *tickets{id, at, title, status}");
+    assert_eq!(tickets.len(), 3);
+    assert_ne!(tickets[0][2], tickets[1][2]);
+    assert!(tickets.iter().all(|row| row[2].get_uuid().is_some()));
+    assert!(tickets
+        .iter()
+        .all(|row| matches!(&row[3], DataValue::Validity(vld) if vld.is_assert.0)));
+    assert_eq!(
+        tickets.iter().map(|row| row[1].clone()).collect_vec(),
+        ["new", "new", "done"].map(DataValue::from)
+    );
+
+    // triggers see the rows with the defaults filled in
+    let log = rows("?[id, status] := *log{id, status}");
+    let mut expected = tickets
+        .iter()
+        .map(|row| vec![row[2].clone(), row[1].clone()])
+        .collect_vec();
+    expected.sort();
+    assert_eq!(log, expected);
+
+    // a default added by `:replace` applies to the rows written by it
+    rows("::set_triggers tickets");
+    rows(
+        "?[id, title] := *tickets{id, title}
+         :replace tickets {id: Uuid => title: String, status: String default 'open'}",
+    );
+    assert_eq!(
+        rows("?[status] := *tickets{status}"),
+        vec![vec![DataValue::from("open")]]
+    );
+    assert_eq!(rows("::columns tickets")[2][7], DataValue::from("\"open\""));
+}
+
+#[cfg(feature = "storage-sqlite")]
+#[test]
+fn test_read_only() {
+    let path = std::env::temp_dir().join(format!("cozo-test-{}.db", rand::random::<u64>()));
+    let backup = std::env::temp_dir().join(format!("cozo-test-{}.db", rand::random::<u64>()));
+    {
+        let db = crate::new_cozo_sqlite(&path).unwrap();
+        db.run_script(
+            "?[k, v] <- [[1, 'a']] :create kv {k => v}",
+            Default::default(),
+        )
+        .unwrap();
+        db.backup_db(&backup).unwrap();
+    }
+    let db = DbInstance::new("sqlite", &path, r#"{"read_only": true}"#).unwrap();
+    let assert_read_only = |res: miette::Result<()>| {
+        let err = CozoError::from_report(res.unwrap_err());
+        assert!(err.to_string().contains("read-only"), "{err}");
+        assert_eq!(err.kind(), "constraint_violation");
+    };
+    let run = |script: &str| db.run_script(script, Default::default()).map(|_| ());
+
+    // every way of writing fails
+    assert_read_only(run("?[k, v] <- [[2, 'b']] :put kv {k => v}"));
+    assert_read_only(run("?[k] <- [[1]] :create other {k}"));
+    assert_read_only(run(
+        "{?[k, v] <- [[2, 'b']] :put kv {k => v}} {?[k] := *kv{k}}",
+    ));
+    assert_read_only(run("::remove kv"));
+    assert_read_only(run("::index create kv:v {v}"));
+    assert_read_only(db.import_relations(BTreeMap::from([(
+        "kv".to_string(),
+        NamedRows::new(
+            vec!["k".to_string(), "v".to_string()],
+            vec![vec![DataValue::from(3), DataValue::from("c")]],
+        ),
+    )])));
+    assert_read_only(db.import_from_backup(&backup, &["kv".to_string()]));
+    assert_read_only(db.restore_backup(&backup));
+
+    // even bypassing the checks, the storage cannot be written to
+    if let DbInstance::Sqlite(db) = &db {
+        assert_read_only(db.db.transact(true).map(|_| ()));
+    }
+
+    // reading, backups and registering callbacks are allowed
+    let res = db
+        .run_script("?[k, v] := *kv{k, v}", Default::default())
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[1, "a"]]));
+    let copy = std::env::temp_dir().join(format!("cozo-test-{}.db", rand::random::<u64>()));
+    db.backup_db(&copy).unwrap();
+    let (id, _receiver) = db.register_callback("kv", None);
+    assert!(db.unregister_callback(id));
+
+    // the flag can be set on other engines too
+    let mut mem = new_cozo_mem().unwrap();
+    mem.set_read_only(true);
+    assert!(mem
+        .run_script("?[k] <- [[1]] :create other {k}", Default::default())
+        .is_err());
+    mem.set_read_only(false);
+    mem.run_script("?[k] <- [[1]] :create other {k}", Default::default())
+        .unwrap();
+}
+
+#[test]
+fn test_multi_tx_locks_and_callbacks() {
+    let db = DbInstance::new("mem", "", "").unwrap();
+    db.run_script(":create a {a}", Default::default()).unwrap();
+    let (_, receiver) = db.register_callback("a", None);
+    let rows = |script: &str| {
+        db.run_script(script, Default::default())
+            .unwrap()
+            .into_json()["rows"]
+            .clone()
+    };
+
+    let tx = db.multi_transaction(true);
+    tx.run_script("?[a] <- [[1]] :put a {a}", Default::default())
+        .unwrap();
+    tx.run_script("?[a] <- [[2]] :put a {a}", Default::default())
+        .unwrap();
+    // intermediate results are seen by the transaction, the mem engine blocks other reads
+    let res = tx
+        .run_script_read_only("?[a] := *a[a]", Default::default())
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[1], [2]]));
+    let err = tx
+        .run_script_read_only("?[a] <- [[3]] :put a {a}", Default::default())
+        .unwrap_err();
+    assert_eq!(err.downcast_ref::<CozoError>().unwrap().kind(), "plan");
+
+    // the relation written cannot be removed until the transaction ends
+    let remover = {
+        let db = db.clone();
+        std::thread::spawn(move || db.run_script("::remove a", Default::default()))
+    };
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(!remover.is_finished());
+    assert!(receiver.try_recv().is_err());
+    tx.commit().unwrap();
+    // callbacks are sent once committed, for all the queries of the transaction
+    let events = receiver.try_iter().collect_vec();
+    assert_eq!(events.len(), 2);
+    assert!(events.iter().all(|(op, _, _)| *op == CallbackOp::Put));
+    remover.join().unwrap().unwrap();
+
+    // dropping a transaction rolls it back
+    db.run_script(":create b {b}", Default::default()).unwrap();
+    let tx = db.multi_transaction(true);
+    tx.run_script("?[b] <- [[1]] :put b {b}", Default::default())
+        .unwrap();
+    drop(tx);
+    assert_eq!(rows("?[b] := *b[b]"), json!([]));
+    // and releases its locks
+    db.run_script("::remove b", Default::default()).unwrap();
+
+    // the same through the string API
+    db.run_script(":create c {c}", Default::default()).unwrap();
+    let begun: serde_json::Value = serde_json::from_str(&db.begin_transaction_str(true)).unwrap();
+    let id = begun["id"].as_u64().unwrap() as u32;
+    let res: serde_json::Value = serde_json::from_str(&db.run_script_in_transaction_str(
+        id,
+        "?[c] <- [[1]] :put c {c}",
+        "",
+        false,
+    ))
+    .unwrap();
+    assert_eq!(res["ok"], json!(true));
+    let res: serde_json::Value = serde_json::from_str(&db.run_script_in_transaction_str(
+        id,
+        "?[c] <- [[2]] :put c {c}",
+        "",
+        true,
+    ))
+    .unwrap();
+    assert_eq!(res["ok"], json!(false));
+    assert_eq!(
+        db.commit_transaction_str(id),
+        json!({"ok": true}).to_string()
+    );
+    assert_eq!(rows("?[c] := *c[c]"), json!([[1]]));
+    let res: serde_json::Value = serde_json::from_str(&db.abort_transaction_str(id)).unwrap();
+    assert_eq!(res["ok"], json!(false));
+
+    // ids belong to the database that began the transaction
+    let other = DbInstance::new("mem", "", "").unwrap();
+    let begun: serde_json::Value = serde_json::from_str(&db.begin_transaction_str(false)).unwrap();
+    let id = begun["id"].as_u64().unwrap() as u32;
+    let res: serde_json::Value =
+        serde_json::from_str(&other.run_script_in_transaction_str(id, "?[x] <- [[1]]", "", true))
+            .unwrap();
+    assert_eq!(res["ok"], json!(false));
+    let res: serde_json::Value = serde_json::from_str(&other.commit_transaction_str(id)).unwrap();
+    assert_eq!(res["ok"], json!(false));
+
+    // concurrent scripts in the same transaction each get their own result
+    std::thread::scope(|s| {
+        for i in 0..8 {
+            let db = &db;
+            s.spawn(move || {
+                let res: serde_json::Value = serde_json::from_str(
+                    &db.run_script_in_transaction_str(id, &format!("?[x] <- [[{i}]]"), "", true),
+                )
+                .unwrap();
+                assert_eq!(res["rows"], json!([[i]]));
+            });
+        }
+    });
+    assert_eq!(
+        db.abort_transaction_str(id),
+        json!({"ok": true}).to_string()
+    );
+}
+
+#[test]
+fn test_while_and_counted_loops() {
+    let db = new_cozo_mem().unwrap();
+    let run = |query: &str, params: BTreeMap<String, DataValue>| {
+        db.run_script(query, params).unwrap().into_json()["rows"].clone()
+    };
+
+    // the condition sees the temp relation as mutated by the previous iteration
+    let res = run(
+        r"
+        {?[n] <- [[0]] :create _c {n}}
+        {:create _log {n}}
+        %while {?[c] := *_c[n], c = n < 3}
+            {?[n] := *_c[n] :put _log {n}}
+            {?[n] := *_c[m], n = m + 1 :replace _c {n}}
+        %end
+        {?[n] := *_log[n]}
+        ",
+        Default::default(),
+    );
+    assert_eq!(res, json!([[0], [1], [2]]));
+
+    let res = run(
+        r"
+        {?[n] <- [[0]] :create _c {n}}
+        %until {?[c] := *_c[n], c = n >= 4}
+            {?[n] := *_c[m], n = m + 1 :replace _c {n}}
+        %end
+        {?[n] := *_c[n]}
+        ",
+        Default::default(),
+    );
+    assert_eq!(res, json!([[4]]));
+
+    // the number of iterations can be given by a parameter
+    let res = run(
+        r"
+        {?[n] <- [[0]] :create _c {n}}
+        %loop $times
+            {?[n] := *_c[m], n = m + 1 :replace _c {n}}
+        %end
+        %loop 2
+            {?[n] := *_c[m], n = m + 10 :replace _c {n}}
+        %end
+        {?[n] := *_c[n]}
+        ",
+        BTreeMap::from([("times".to_string(), DataValue::from(5))]),
+    );
+    assert_eq!(res, json!([[25]]));
+
+    // labelled break and continue reach the enclosing loops
+    let res = run(
+        r"
+        {?[n] <- [[0]] :create _c {n}}
+        {:create _log {n}}
+        %mark outer
+        %while {?[c] := *_c[n], c = n < 100}
+            {?[n] := *_c[m], n = m + 1 :replace _c {n}}
+            %loop 3
+                %if {?[c] := *_c[n], c = n == 2}
+                    %continue outer
+                %end
+                %if {?[c] := *_c[n], c = n == 4}
+                    %break outer
+                %end
+                {?[n] := *_c[n] :put _log {n}}
+                %break
+            %end
+        %end
+        {?[n] := *_log[n]}
+        ",
+        Default::default(),
+    );
+    assert_eq!(res, json!([[1], [3]]));
+
+    let err = db
+        .run_script(
+            "%loop $times {?[] <- [[]]} %end",
+            BTreeMap::from([("times".to_string(), DataValue::from(-1))]),
+        )
+        .unwrap_err();
+    assert_eq!(
+        err.code().unwrap().to_string(),
+        "parser::bad_imperative_int"
+    );
+}
+
+#[test]
+fn test_ignore_error_outcome() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(":create a {k: Int}", Default::default())
+        .unwrap();
+    let rows = |query: &str| {
+        db.run_script(query, Default::default())
+            .unwrap()
+            .into_json()
+    };
+
+    let res = rows("%ignore_error {?[k] <- [['not an int']] :put a {k}}");
+    assert_eq!(
+        res["headers"],
+        json!(["status", "error_code", "error_message", "span"])
+    );
+    let type_error = res["rows"][0].clone();
+    assert_eq!(type_error[0], json!("FAILED"));
+    assert!(type_error[2].is_string());
+
+    let res = rows("%ignore_error {?[k] := *nowhere[k]}");
+    let missing_error = res["rows"][0].clone();
+    assert_eq!(missing_error[0], json!("FAILED"));
+    assert!(type_error[1].is_string());
+    assert!(missing_error[1].is_string());
+    assert_ne!(type_error[1], missing_error[1]);
+    assert_eq!(missing_error[1], json!("query::relation_not_found"));
+
+    // the outcome bound to a temp relation can be branched on
+    let res = rows(
+        r"
+        {?[k] <- [[0]] :create _tries {k}}
+        %loop
+            %ignore_error _err {?[k] := *nowhere[k]}
+            %if {?[c] := *_err[_, code, _, _], c = code == 'query::relation_not_found'}
+                {?[k] := *_tries[j], k = j + 1 :replace _tries {k}}
+                %if {?[c] := *_tries[k], c = k >= 3}
+                    %break
+                %end
+            %else
+                %return {?[k] <- [['unexpected']]}
+            %end
+        %end
+        {?[k] := *_tries[k]}
+        ",
+    );
+    assert_eq!(res["rows"], json!([[3]]));
+
+    // on success the program gives its own rows, and the bound relation an OK row
+    let res = rows(
+        r"
+        %ignore_error _err {?[k] <- [[1]] :put a {k}}
+        {?[s, c] := *_err[s, c, _, _]}
+        ",
+    );
+    assert_eq!(res["rows"], json!([["OK", null]]));
+}
+
+#[test]
+fn test_retry_and_sleep() {
+    let db = new_cozo_mem().unwrap();
+    let calls = Arc::new(AtomicUsize::new(0));
+    let flaky_calls = calls.clone();
+    db.register_fixed_rule(
+        "Flaky".to_string(),
+        crate::SimpleFixedRule::new(1, move |_, _| {
+            if flaky_calls.fetch_add(1, Ordering::SeqCst) < 2 {
+                miette::bail!("not yet")
+            }
+            Ok(NamedRows::new(
+                vec!["x".to_string()],
+                vec![vec![DataValue::from(1)]],
+            ))
+        }),
+    )
+    .unwrap();
+
+    // the counter is bumped by each attempt, and the bumps of the failed ones are rolled back
+    let script = r"
+        {?[n] <- [[0]] :create _c {n}}
+        %retry $retries :backoff 1 :factor 1.5
+            {?[n] := *_c[m], n = m + 1 :replace _c {n}}
+            {?[x] <~ Flaky()}
+        %end
+        {?[n] := *_c[n]}
+    ";
+    let res = db
+        .run_script(
+            script,
+            BTreeMap::from([("retries".to_string(), DataValue::from(2))]),
+        )
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[1]]));
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+    // the error of the last attempt is returned once the retries are used up
+    calls.store(0, Ordering::SeqCst);
+    let err = db
+        .run_script(
+            script,
+            BTreeMap::from([("retries".to_string(), DataValue::from(1))]),
+        )
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("not yet"));
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+    // a sleeping script can be killed
+    let err = std::thread::scope(|s| {
+        let running = s.spawn(|| {
+            db.run_script("%sleep 100000", Default::default())
+                .unwrap_err()
+        });
+        let id = loop {
+            let queries = db.list_running().unwrap();
+            if let Some(query) = queries.first() {
+                break query.id;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        assert!(db.kill_query(id));
+        running.join().unwrap()
+    });
+    assert_eq!(err.downcast_ref::<CozoError>().unwrap().kind(), "killed");
+}
+
+#[test]
+fn test_run_script_with_relations() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"
+        ?[id, name] := id in int_range(100), name = concat('user', to_string(id))
+        :create users {id: Int => name: String}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+
+    let rows = (0..10000)
+        .map(|i| vec![DataValue::from(i), DataValue::from(i % 100)])
+        .collect_vec();
+    let relations = BTreeMap::from([(
+        "_orders".to_string(),
+        NamedRows::new(vec!["order".to_string(), "user".to_string()], rows),
+    )]);
+    let res = db
+        .run_script_with_relations(
+            r"
+            ?[name, count(order)] := *_orders{order, user}, *users{id: user, name}, user < $n
+            :order name
+            ",
+            BTreeMap::from([("n".to_string(), DataValue::from(2))]),
+            relations.clone(),
+        )
+        .unwrap();
+    assert_eq!(
+        res.into_json()["rows"],
+        json!([["user0", 100], ["user1", 100]])
+    );
+
+    // the relations are also there in imperative scripts
+    let res = db
+        .run_script_with_relations(
+            r"
+            {counted[count(order)] := *_orders[order, _]; ?[n] := counted[n] :create _counted {n}}
+            {?[n] := *_counted[n]}
+            ",
+            Default::default(),
+            relations,
+        )
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[10000]]));
+
+    // integers and floats make a float column
+    let numbers = NamedRows::new(
+        vec!["x".to_string()],
+        vec![vec![DataValue::from(1)], vec![DataValue::from(2.5)]],
+    );
+    let res = db
+        .run_script_with_relations(
+            "?[x] := *_numbers[x]",
+            Default::default(),
+            BTreeMap::from([("_numbers".to_string(), numbers.clone())]),
+        )
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[1.0], [2.5]]));
+
+    let err = db
+        .run_script_with_relations(
+            "?[x] := *numbers[x]",
+            Default::default(),
+            BTreeMap::from([("numbers".to_string(), numbers)]),
+        )
+        .unwrap_err();
+    assert_eq!(err.downcast_ref::<CozoError>().unwrap().kind(), "plan");
+
+    // relations can be given with the parameters in JSON
+    let db = DbInstance::new("mem", "", "").unwrap();
+    let res = db.run_script_str(
+        "?[x, y] := *_pairs[x, y], x > $min",
+        r#"{"min": 1, "_pairs": {"headers": ["x", "y"], "rows": [[1, "a"], [2, "b"]]}}"#,
+    );
+    let res: serde_json::Value = serde_json::from_str(&res).unwrap();
+    assert_eq!(res["rows"], json!([[2, "b"]]));
+}
+
+#[test]
+fn test_uuid_v7_order() {
+    // version 7 UUIDs with the given milliseconds
+    let v7 = |millis: u128| uuid::Uuid::from_u128((millis << 80) | (0x7000 << 64) | (0x8000 << 48));
+    let millis = [
+        1_700_000_000_123u128,
+        1_600_000_000_000,
+        1_700_000_000_122,
+        1_650_000_000_000,
+    ];
+    let db = new_cozo_mem().unwrap();
+    db.run_script(":create events {id: Uuid => n: Int}", Default::default())
+        .unwrap();
+    for (n, ms) in millis.iter().enumerate() {
+        db.run_script(
+            "?[id, n] := id = to_uuid($id), n = $n :put events {id => n}",
+            BTreeMap::from([
+                ("id".to_string(), DataValue::from(v7(*ms).to_string())),
+                ("n".to_string(), DataValue::from(n as i64)),
+            ]),
+        )
+        .unwrap();
+    }
+    // the stored rows are scanned in the order of the times of the ids
+    let scanned = db
+        .export_relations(["events"].into_iter())
+        .unwrap()
+        .remove("events")
+        .unwrap();
+    let mut by_time = millis.iter().enumerate().collect_vec();
+    by_time.sort_by_key(|(_, ms)| **ms);
+    assert_eq!(
+        scanned.rows.iter().map(|row| row[1].clone()).collect_vec(),
+        by_time
+            .iter()
+            .map(|(n, _)| DataValue::from(*n as i64))
+            .collect_vec()
+    );
+    let res = db
+        .run_script(
+            "?[n, ts] := *events{id, n}, ts = uuid_timestamp(id)",
+            Default::default(),
+        )
+        .unwrap();
+    for row in res.rows {
+        let n = row[0].get_int().unwrap() as usize;
+        assert_eq!(row[1], DataValue::from(millis[n] as f64 / 1000.));
+    }
+
+    let res = db
+        .run_script(
+            "?[ts, now] := id = rand_uuid_v7(), ts = uuid_timestamp(id), now = now()",
+            Default::default(),
+        )
+        .unwrap();
+    let ts = res.rows[0][0].get_float().unwrap();
+    let now = res.rows[0][1].get_float().unwrap();
+    assert!(ts <= now && now - ts < 10.);
+}
+
+/// Rewrites the keys of `storage` as versions of Cozo before UUIDs were ordered by their bytes
+/// wrote them, with UUIDs in the order of their fields
+fn write_uuid_field_order<'s, S: Storage<'s>>(storage: &'s S) {
+    use crate::data::memcmp::MemCmpEncoder;
+    use crate::data::tuple::{decode_tuple_from_key, ENCODED_KEY_MIN_LEN};
+    use crate::runtime::relation::RelationId;
+
+    fn to_field_order(val: &mut DataValue) {
+        match val {
+            DataValue::Uuid(u) => {
+                let (l, m, h, rest) = u.0.as_fields();
+                let mut bytes = vec![];
+                bytes.extend(h.to_be_bytes());
+                bytes.extend(m.to_be_bytes());
+                bytes.extend(l.to_be_bytes());
+                bytes.extend(rest);
+                u.0 = uuid::Uuid::from_slice(&bytes).unwrap();
+            }
+            DataValue::List(l) => l.iter_mut().for_each(to_field_order),
+            DataValue::Set(s) => {
+                *s = std::mem::take(s)
+                    .into_iter()
+                    .map(|mut el| {
+                        to_field_order(&mut el);
+                        el
+                    })
+                    .collect()
+            }
+            _ => {}
+        }
+    }
+    let mut tx = storage.transact(true).unwrap();
+    let kvs: Vec<_> = tx.total_scan().try_collect().unwrap();
+    for (k, v) in kvs {
+        if k.len() <= ENCODED_KEY_MIN_LEN {
+            continue;
+        }
+        let mut tuple = decode_tuple_from_key(&k).unwrap();
+        tuple.iter_mut().for_each(to_field_order);
+        let mut old_key = k[..ENCODED_KEY_MIN_LEN].to_vec();
+        for val in &tuple {
+            old_key.encode_datavalue(val);
+        }
+        if old_key != k {
+            tx.del(&k).unwrap();
+            tx.put(&old_key, &v).unwrap();
+        }
+    }
+    let version_key =
+        vec![DataValue::Null, DataValue::from("STORAGE_VERSION")].encode_as_key(RelationId::SYSTEM);
+    tx.put(&version_key, &[0x00]).unwrap();
+    tx.commit().unwrap();
+}
+
+#[test]
+fn test_upgrade_uuid_keys() {
+    use crate::data::memcmp::MemCmpEncoder;
+    use crate::data::tuple::ENCODED_KEY_MIN_LEN;
+    use crate::runtime::relation::RelationId;
+    use crate::runtime::transact::{upgrade_uuid_keys, upgraded_uuid_pairs};
+
+    let storage = MemStorage::default();
+    let db = Db::new(storage.clone()).unwrap();
+    db.initialize().unwrap();
+    db.run_script(
+        r"
+        ?[id, tags, n] := id = to_uuid('dd85b19a-5fde-11ed-a88e-1774a7698039'),
+                          tags = [to_uuid('018b0000-0000-7000-8000-000000000000')], n = 1
+        ?[id, tags, n] := id = to_uuid('dd85b19a-5fdf-11ec-a88e-1774a7698039'), tags = [], n = 2
+        :create tagged {id, tags => n}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    // more keys than are upgraded at a time
+    db.run_script(
+        r"
+        ?[id, tags, n] := n in int_range(3, 2003), id = rand_uuid_v1(), tags = []
+        :put tagged {id, tags => n}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script("?[tags] <- [[[]]] :create sets {tags}", Default::default())
+        .unwrap();
+    let expected = db
+        .run_script("?[id, tags, n] := *tagged{id, tags, n}", Default::default())
+        .unwrap();
+    drop(db);
+
+    // sets are stored as lists, but any left in keys have their elements reordered
+    let mut tx = storage.transact(true).unwrap();
+    let (sets_key, sets_val) = tx.total_scan().last().unwrap().unwrap();
+    let tags = [
+        "dd85b19a-5fde-11ed-a88e-1774a7698039",
+        "dd85b19b-5fdd-11ed-a88e-1774a7698039",
+    ]
+    .into_iter()
+    .map(|s| DataValue::uuid(uuid::Uuid::parse_str(s).unwrap()))
+    .collect();
+    let mut set_key = sets_key[..ENCODED_KEY_MIN_LEN].to_vec();
+    set_key.encode_datavalue(&DataValue::Set(tags));
+    tx.put(&set_key, &sets_val).unwrap();
+    tx.commit().unwrap();
+    let kvs: Vec<_> = tx.total_scan().try_collect().unwrap();
+    drop(tx);
+    write_uuid_field_order(&storage);
+
+    let check_read_only = |storage: &MemStorage| {
+        let tx = storage.transact(false).unwrap();
+        let before: Vec<_> = tx.total_scan().try_collect().unwrap();
+        // the pairs as they are once upgraded, as restored from a backup
+        let copy = MemStorage::default();
+        copy.batch_put(Box::new(upgraded_uuid_pairs(&tx).unwrap()))
+            .unwrap();
+        drop(tx);
+        let db = Db::new(copy).unwrap();
+        db.initialize().unwrap();
+        let res = db
+            .run_script("?[id, tags, n] := *tagged{id, tags, n}", Default::default())
+            .unwrap();
+        assert_eq!(res.rows, expected.rows);
+        // the storage itself cannot be opened read-only before it is upgraded
+        let mut db = Db::new(storage.clone()).unwrap();
+        db.set_read_only(true);
+        assert!(db.initialize().is_err());
+        let after: Vec<_> = storage
+            .transact(false)
+            .unwrap()
+            .total_scan()
+            .try_collect()
+            .unwrap();
+        assert!(before == after);
+    };
+    // read without being upgraded, before and in the middle of an interrupted upgrade
+    check_read_only(&storage);
+    let mut n_batches = 0;
+    loop {
+        let done = {
+            let mut tx = storage.transact(true).unwrap();
+            let done = upgrade_uuid_keys(&mut tx).unwrap();
+            tx.commit().unwrap();
+            done
+        };
+        if done {
+            break;
+        }
+        n_batches += 1;
+        check_read_only(&storage);
+        if n_batches == 5 {
+            break;
+        }
+    }
+
+    // and upgraded the rest of the way when opened for writing
+    let db = Db::new(storage.clone()).unwrap();
+    db.initialize().unwrap();
+    let res = db
+        .run_script("?[id, tags, n] := *tagged{id, tags, n}", Default::default())
+        .unwrap();
+    assert_eq!(res.rows, expected.rows);
+    let res = db
+        .run_script(
+            "?[n] := *tagged{id: to_uuid('dd85b19a-5fde-11ed-a88e-1774a7698039'), n}",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.rows, vec![vec![DataValue::from(1)]]);
+    // the keys are back in their current form, with the elements of sets reordered
+    let tx = storage.transact(false).unwrap();
+    let upgraded: Vec<_> = tx.total_scan().try_collect().unwrap();
+    let relation_rows = |kvs: &[(Vec<u8>, Vec<u8>)]| {
+        kvs.iter()
+            .filter(|(k, _)| k[..ENCODED_KEY_MIN_LEN] != RelationId::SYSTEM.raw_encode())
+            .cloned()
+            .collect_vec()
+    };
+    assert_eq!(
+        relation_rows(&upgraded),
+        relation_rows(&kvs).into_iter().sorted().collect_vec()
+    );
+    assert!(n_batches > 0);
+}
+
+#[cfg(feature = "storage-sqlite")]
+#[test]
+fn test_read_uuid_field_order_sqlite() {
+    let path = std::env::temp_dir().join(format!("cozo-test-{}.db", rand::random::<u64>()));
+    let query = "?[id, n] := *ids{id, n}";
+    let expected = {
+        let db = crate::new_cozo_sqlite(&path).unwrap();
+        db.run_script(
+            r"
+            ?[id, n] := n in int_range(100), id = rand_uuid_v1()
+            ?[id, n] := id = to_uuid('dd85b19a-5fde-11ed-a88e-1774a7698039'), n = 100
+            :create ids {id => n}
+            ",
+            Default::default(),
+        )
+        .unwrap();
+        let expected = db.run_script(query, Default::default()).unwrap();
+        write_uuid_field_order(&db.db);
+        expected
+    };
+    let raw_pairs = || -> Vec<(Vec<u8>, Vec<u8>)> {
+        let options = crate::SqliteOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        let storage = crate::storage::sqlite::open_sqlite_storage(&path, options).unwrap();
+        let tx = storage.transact(false).unwrap();
+        let pairs = tx.total_scan().try_collect().unwrap();
+        pairs
+    };
+    let stored = raw_pairs();
+    let lookup = "?[n] := *ids{id: to_uuid('dd85b19a-5fde-11ed-a88e-1774a7698039'), n}";
+
+    // not opened read-only before it is upgraded
+    assert!(crate::new_cozo_sqlite_read_only(&path).is_err());
+
+    // restored as a backup
+    let db = new_cozo_mem().unwrap();
+    db.restore_backup(&path).unwrap();
+    assert_eq!(
+        db.run_script(query, Default::default()).unwrap().rows,
+        expected.rows
+    );
+    assert_eq!(
+        db.run_script(lookup, Default::default()).unwrap().rows,
+        vec![vec![DataValue::from(100)]]
+    );
+    db.run_script(
+        "?[id, n] <- [[rand_uuid_v7(), 101]] :put ids {id => n}",
+        Default::default(),
+    )
+    .unwrap();
+    // other reads of the backup need it upgraded
+    let db = new_cozo_mem().unwrap();
+    db.run_script(":create ids {id => n}", Default::default())
+        .unwrap();
+    assert!(db.import_from_backup(&path, &["ids".to_string()]).is_err());
+
+    // all of which left it as it was
+    assert!(raw_pairs() == stored);
+
+    // and once upgraded it is read as it is
+    crate::new_cozo_sqlite(&path).unwrap();
+    let reader = crate::new_cozo_sqlite_read_only(&path).unwrap();
+    assert_eq!(
+        reader.run_script(lookup, Default::default()).unwrap().rows,
+        vec![vec![DataValue::from(100)]]
+    );
+    db.import_from_backup(&path, &["ids".to_string()]).unwrap();
+    assert_eq!(
+        db.run_script(query, Default::default()).unwrap().rows,
+        expected.rows
+    );
+}
+
+#[test]
+fn test_datetime_functions() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        ":create logs {ts: Float => day: Float, msg: String}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r#"
+        raw[t, msg] <- [['2024-02-28 23:30', 'a'], ['2024-02-29 00:00', 'b'],
+                        ['2024-02-29 23:59', 'c'], ['2024-03-01 00:00', 'd']]
+        ?[ts, day, msg] := raw[t, msg],
+            ts = parse_ts(t, '%Y-%m-%d %H:%M', 'Europe/Berlin'),
+            day = date_trunc('day', ts, 'Europe/Berlin')
+        :put logs {ts => day, msg}
+        "#,
+        Default::default(),
+    )
+    .unwrap();
+
+    // bounds of the keys are computed from the truncation of times in the filter
+    let res = db
+        .run_script(
+            r#"
+            ?[msg, day, t] := *logs{ts, day, msg},
+                date_trunc('day', ts, 'Europe/Berlin') >= parse_ts('2024-02-29', '%Y-%m-%d', 'Europe/Berlin'),
+                date_trunc('day', ts, 'Europe/Berlin') <= parse_ts('2024-02-29', '%Y-%m-%d', 'Europe/Berlin'),
+                t = format_ts(day, '%Y-%m-%d %H:%M %z', 'Europe/Berlin')
+            "#,
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(
+        res.into_json()["rows"],
+        json!([
+            ["b", 1709161200.0, "2024-02-29 00:00 +0100"],
+            ["c", 1709161200.0, "2024-02-29 00:00 +0100"]
+        ])
+    );
+    let res = db
+        .run_script(
+            r#"
+            ?[msg] := *logs{ts, msg}, ts_add(ts, 1, 'hour') > parse_ts('2024-02-29 23:00', '%Y-%m-%d %H:%M')
+            "#,
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([["c"], ["d"]]));
+
+    // bad formats and timezones are reported at the expressions using them
+    let script = "?[x] := x = format_ts(0, '%Y-%Q', 'UTC')";
+    let err = db.run_script(script, Default::default()).unwrap_err();
+    let json = crate::format_error_as_json(err, Some(script));
+    assert_eq!(
+        json["labels"][0]["span"]["offset"],
+        json!(script.find("format_ts").unwrap())
+    );
+    assert!(json["help"]
+        .as_str()
+        .unwrap()
+        .contains("invalid format string"));
+    let script = "?[x] := *logs{ts}, x = date_part('hour', ts, 'Europe/Atlantis')";
+    let err = db.run_script(script, Default::default()).unwrap_err();
+    let json = crate::format_error_as_json(err, Some(script));
+    assert_eq!(
+        json["labels"][0]["span"]["offset"],
+        json!(script.find("date_part").unwrap())
+    );
+}
+
+#[test]
+fn test_regex_groups_in_queries() {
+    let db = new_cozo_mem().unwrap();
+    let res = db
+        .run_script(
+            r#"
+            lines[l] <- [['GET /a 200'], ['POST /b/c 404'], ['bad line']]
+            ?[method, path, status] := lines[l],
+                groups = regex_extract_groups(l, $pattern), groups != null,
+                method = get(groups, 0), path = get(groups, 1), status = to_int(get(groups, 2))
+            "#,
+            BTreeMap::from([(
+                "pattern".to_string(),
+                DataValue::from(r"^(\w+) (\S+) (\d+)$"),
+            )]),
+        )
+        .unwrap();
+    assert_eq!(
+        res.into_json()["rows"],
+        json!([["GET", "/a", 200], ["POST", "/b/c", 404]])
+    );
+
+    // bad patterns are reported at the pattern
+    let script = "?[x] := x = regex_extract_all('abc', '(unclosed')";
+    let err = db.run_script(script, Default::default()).unwrap_err();
+    let json = crate::format_error_as_json(err, Some(script));
+    assert_eq!(
+        json["labels"][0]["span"]["offset"],
+        json!(script.find("'(unclosed'").unwrap())
+    );
+    let script = "?[x] := p in ['a', '(unclosed'], x = regex_replace_all('abc', p, 'x')";
+    let err = db.run_script(script, Default::default()).unwrap_err();
+    let json = crate::format_error_as_json(err, Some(script));
+    assert_eq!(
+        json["labels"][0]["span"]["offset"],
+        json!(script.find("p, 'x'").unwrap())
+    );
+}
+
+#[test]
+fn test_custom_degree_count_rule() {
+    struct DegreeCount;
+
+    impl FixedRule for DegreeCount {
+        fn arity(
+            &self,
+            _options: &BTreeMap<SmartString<LazyCompact>, Expr>,
+            _rule_head: &[Symbol],
+            _span: SourceSpan,
+        ) -> miette::Result<usize> {
+            Ok(2)
+        }
+
+        fn run(
+            &self,
+            payload: FixedRulePayload<'_, '_>,
+            out: &'_ mut RegularTempStore,
+            poison: Poison,
+        ) -> miette::Result<()> {
+            let edges = payload.get_input(0)?.ensure_min_len(2)?;
+            let undirected = payload.bool_option("undirected", Some(false))?;
+            let mut degrees: BTreeMap<DataValue, i64> = BTreeMap::new();
+            for edge in edges.iter()? {
+                let edge = edge?;
+                *degrees.entry(edge[0].clone()).or_default() += 1;
+                if undirected {
+                    *degrees.entry(edge[1].clone()).or_default() += 1;
+                }
+                poison.check()?;
+            }
+            for (node, degree) in degrees {
+                out.put(vec![node, DataValue::from(degree)]);
+            }
+            Ok(())
+        }
+    }
+
+    let db = new_cozo_mem().unwrap();
+    db.register_fixed_rule("DegreeCount".to_string(), DegreeCount)
+        .unwrap();
+    let res = db
+        .run_script(
+            r"
+            edges[a, b] <- [[1, 2], [1, 3], [2, 3]]
+            ?[node, degree] <~ DegreeCount(edges[], undirected: true)
+            ",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[1, 2], [2, 2], [3, 2]]));
+
+    // names taken by builtins or other rules, and names unusable in scripts, are rejected
+    for name in ["DegreeCount", "Constant", "Degree Count", "_Degree", ""] {
+        assert!(db
+            .register_fixed_rule(name.to_string(), DegreeCount)
+            .is_err());
+    }
+
+    // the arity and the options are checked with the spans of the application
+    for (script, code) in [
+        (
+            "edges[a, b] <- [[1, 2]] ?[node] <~ DegreeCount(edges[])",
+            "parser::fixed_rule_head_arity_mismatch",
+        ),
+        (
+            "edges[a, b] <- [[1, 2]] ?[n, d] <~ DegreeCount(edges[], undirected: 1)",
+            "fixed_rule::arg_wrong",
+        ),
+        (
+            "edges[a] <- [[1]] ?[n, d] <~ DegreeCount(edges[])",
+            "algo::input_relation_bad_arity",
+        ),
+    ] {
+        let err = db.run_script(script, Default::default()).unwrap_err();
+        assert_eq!(err.code().unwrap().to_string(), code, "{script}");
+        assert!(err.labels().unwrap().next().is_some(), "{script}");
+    }
+
+    assert!(db.unregister_fixed_rule("DegreeCount").unwrap());
+    assert!(db
+        .run_script(
+            "edges[a, b] <- [[1, 2]] ?[n, d] <~ DegreeCount(edges[])",
+            Default::default()
+        )
+        .is_err());
+}
+
+#[test]
+fn test_user_functions() {
+    let db = new_cozo_mem().unwrap();
+    db.register_function("score".to_string(), 2, |args| {
+        match (args[0].get_float(), args[1].get_float()) {
+            (Some(w), Some(bonus)) => Ok(DataValue::from(w * 10. + bonus)),
+            _ => miette::bail!("score requires numbers"),
+        }
+    })
+    .unwrap();
+    let calls = Arc::new(AtomicUsize::new(0));
+    let double_calls = calls.clone();
+    db.register_pure_function("double".to_string(), 1, move |args| {
+        double_calls.fetch_add(1, Ordering::SeqCst);
+        Ok(DataValue::from(args[0].get_float().unwrap_or(0.) * 2.))
+    })
+    .unwrap();
+    db.run_script(
+        r"
+        ?[id, region, w] <- [[1, 'a', 1.5], [2, 'a', 3.0], [3, 'b', 0.5]]
+        :create items {id: Int => region: String, w: Float}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    let rows = |script: &str| {
+        db.run_script(script, Default::default())
+            .unwrap()
+            .into_json()["rows"]
+            .clone()
+    };
+
+    // in a filter of the scan of the stored relation
+    assert_eq!(
+        rows("?[id] := *items{id, w}, score(w, 1) > 10"),
+        json!([[1], [2]])
+    );
+    // in a unification
+    assert_eq!(
+        rows("?[id, s] := *items{id, w}, s = score(w, id)"),
+        json!([[1, 16.0], [2, 32.0], [3, 8.0]])
+    );
+    // in the argument of an aggregation
+    assert_eq!(
+        rows("?[region, sum(s)] := *items{region, w}, s = score(w, 0)"),
+        json!([["a", 45.0], ["b", 5.0]])
+    );
+
+    // applications of pure functions to constants are evaluated once, when compiling
+    assert_eq!(
+        rows("?[id] := *items{id, w}, w < double(1)"),
+        json!([[1], [3]])
+    );
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    // errors are reported at the application
+    let script = "?[s] := *items{id}, s = score(id, 'x')";
+    let err = db.run_script(script, Default::default()).unwrap_err();
+    assert_eq!(err.code().unwrap().to_string(), "eval::throw");
+    let json = crate::format_error_as_json(err, Some(script));
+    assert_eq!(
+        json["labels"][0]["span"]["offset"],
+        json!(script.find("score(id, 'x')").unwrap())
+    );
+    for (script, code) in [
+        ("?[s] := s = scores(1, 2)", "parser::func_not_function"),
+        ("?[s] := s = score(1)", "parser::func_wrong_num_args"),
+    ] {
+        let err = db.run_script(script, Default::default()).unwrap_err();
+        assert_eq!(err.code().unwrap().to_string(), code, "{script}");
+    }
+
+    // builtins cannot be replaced, and names must be usable in scripts
+    for name in ["score", "length", "if", "my score"] {
+        assert!(db
+            .register_function(name.to_string(), 1, |args| Ok(args[0].clone()))
+            .is_err());
+    }
+    assert!(db.unregister_function("score"));
+    assert!(db
+        .run_script("?[s] := s = score(1, 2)", Default::default())
+        .is_err());
+}
+
+#[test]
+fn test_validity_window() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"
+        {:create hist {k, at: Validity => v}}
+        {
+            ?[k, at, v] <- [
+                [1, [10, true], 'a'], [1, [20, true], 'b'], [1, [30, false], null],
+                [1, [40, true], 'c'], [2, [25, true], 'x']
+            ]
+            :put hist {k, at => v}
+        }
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    let window = |from: i64, to: i64| {
+        db.run_script(
+            &format!("?[k, v, f, t] := *hist{{k, v, valid_from: f, valid_to: t @ [{from}, {to}]}}"),
+            Default::default(),
+        )
+        .unwrap()
+        .into_json()["rows"]
+            .clone()
+    };
+
+    // each assertion is valid until the next version of the row, if any
+    assert_eq!(
+        window(0, 100),
+        json!([
+            [1, "a", 10, 20],
+            [1, "b", 20, 30],
+            [1, "c", 40, null],
+            [2, "x", 25, null]
+        ])
+    );
+    // the version valid at the start of the window is included
+    assert_eq!(window(15, 22), json!([[1, "a", 10, 20], [1, "b", 20, 30]]));
+    // retractions end the intervals, and reassertions start new ones
+    assert_eq!(
+        window(32, 45),
+        json!([[1, "c", 40, null], [2, "x", 25, null]])
+    );
+    assert_eq!(window(32, 35), json!([[2, "x", 25, null]]));
+    // windows before the first version find nothing
+    assert_eq!(window(0, 5), json!([]));
+    // point-in-time queries are unaffected
+    assert_eq!(
+        db.run_script("?[k, v] := *hist{k, v @ 22}", Default::default())
+            .unwrap()
+            .into_json()["rows"],
+        json!([[1, "b"]])
+    );
+
+    // positional applications take the interval after the columns
+    assert_eq!(
+        db.run_script(
+            "?[k, at, v, f, t] := *hist[k, at, v, f, t @ [20, 20]]",
+            Default::default()
+        )
+        .unwrap()
+        .into_json()["rows"],
+        json!([[1, [20, true], "b", 20, 30]])
+    );
+    // joined by prefix
+    assert_eq!(
+        db.run_script(
+            "?[k, v, valid_to] := k = 1, *hist{k, v, valid_to @ [0, 100]}, v != 'c'",
+            Default::default()
+        )
+        .unwrap()
+        .into_json()["rows"],
+        json!([[1, "a", 20], [1, "b", 30]])
+    );
+
+    // changes within the transaction are seen by the scan
+    let res = db
+        .run_script(
+            r"
+            {?[k, at] <- [[1, [40, true]]] :rm hist {k, at}}
+            {?[k, at, v] <- [[2, [50, false], null]] :put hist {k, at => v}}
+            {?[k, v, valid_from, valid_to] := *hist{k, v, valid_from, valid_to @ [0, 100]}}
+            ",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(
+        res.into_json()["rows"],
+        json!([[1, "a", 10, 20], [1, "b", 20, 30], [2, "x", 25, 50]])
+    );
+
+    for (script, code) in [
+        (
+            "?[k, v] := *hist{k, v @ [100, 0]}",
+            "parser::bad_validity_window",
+        ),
+        (
+            "?[k, v] := *hist{k, v @ [0, 1, 2]}",
+            "parser::bad_validity_window",
+        ),
+        (
+            "?[k, f] := *hist{k, valid_from: f}",
+            "eval::named_field_not_found",
+        ),
+        (
+            "?[k, v] := *hist[k, _, v @ [0, 100]]",
+            "eval::rule_arity_mismatch",
+        ),
+    ] {
+        let err = db.run_script(script, Default::default()).unwrap_err();
+        assert_eq!(err.code().unwrap().to_string(), code, "{script}");
+    }
+}
+
+#[test]
+fn test_cascading_triggers() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"
+        {:create a {k => v}}
+        {:create b {k => v}}
+        {:create log {k, op}}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        "::set_triggers a on put { ?[k, v] := _new[k, x], v = x * 10 :put b {k => v} }",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r"
+        ::set_triggers b on put { ?[k, op] := _new[k, _], op = 'put' :put log {k, op} }
+                         on rm { ?[k, op] := _old[k, _], op = 'rm' :put log {k, op} }
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    let rows = |query: &str| {
+        db.run_script(query, Default::default())
+            .unwrap()
+            .into_json()["rows"]
+            .clone()
+    };
+
+    // the writes of the trigger of `a` set off the triggers of `b`
+    rows("?[k, v] <- [[1, 1], [2, 2]] :put a {k => v}");
+    assert_eq!(rows("?[k, v] := *b{k, v}"), json!([[1, 10], [2, 20]]));
+    assert_eq!(
+        rows("?[k, op] := *log{k, op}"),
+        json!([[1, "put"], [2, "put"]])
+    );
+    rows("?[k] <- [[1]] :rm b {k}");
+    assert_eq!(
+        rows("?[k, op] := *log{k, op}"),
+        json!([[1, "put"], [1, "rm"], [2, "put"]])
+    );
+
+    // a trigger writing to its own relation runs again on its own writes
+    for script in [
+        ":create chain {k}",
+        "::set_triggers chain on put { ?[k] := _new[j], j < 5, k = j + 1 :put chain {k} }",
+        ":create endless {k}",
+        "::set_triggers endless on put { ?[k] := _new[j], k = j + 1 :put endless {k} }",
+    ] {
+        db.run_script(script, Default::default()).unwrap();
+    }
+    db.run_script("?[k] <- [[0]] :put chain {k}", Default::default())
+        .unwrap();
+    assert_eq!(rows("?[count(k)] := *chain{k}"), json!([[6]]));
+
+    // but not without end: the whole transaction fails
+    let err = db
+        .run_script("?[k] <- [[0]] :put endless {k}", Default::default())
+        .unwrap_err();
+    assert_eq!(
+        err.code().unwrap().to_string(),
+        "eval::trigger_depth_exceeded"
+    );
+    assert_eq!(rows("?[k] := *endless{k}"), json!([]));
+
+    // as does the whole transaction when a trigger fails
+    db.run_script(
+        "::set_triggers log on put { ?[k] := _new[k, _], k > 100 :ensure_not a {k} }",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script("?[k, v] <- [[101, 1]] :put a {k => v}", Default::default())
+        .unwrap_err();
+    assert_eq!(rows("?[k] := *a{k}, k > 100"), json!([]));
+    assert_eq!(rows("?[k] := *b{k}, k > 100"), json!([]));
+}
+
+#[test]
+fn test_concurrent_increments() {
+    const THREADS: usize = 16;
+    const INCREMENTS: usize = 20;
+
+    let mut dbs: Vec<(DbInstance, Option<std::path::PathBuf>)> =
+        vec![(DbInstance::new("mem", "", "").unwrap(), None)];
+    #[cfg(feature = "storage-sqlite")]
+    {
+        let path = std::env::temp_dir().join(format!("cozo-test-{}.db", rand::random::<u64>()));
+        dbs.push((DbInstance::new("sqlite", &path, "").unwrap(), Some(path)));
+    }
+    for (db, path) in dbs {
+        db.run_script(
+            "?[k, v] <- [[0, 0]] :create counter {k => v}",
+            Default::default(),
+        )
+        .unwrap();
+        // each increment reads the counter and writes it back in the same transaction
+        let committed = std::thread::scope(|s| {
+            let threads = (0..THREADS)
+                .map(|_| {
+                    s.spawn(|| {
+                        (0..INCREMENTS)
+                            .filter(|_| {
+                                db.run_script(
+                                    "?[k, v] := *counter{k, v: old}, v = old + 1
+                                     :put counter {k => v}",
+                                    Default::default(),
+                                )
+                                .is_ok()
+                            })
+                            .count()
+                    })
+                })
+                .collect_vec();
+            threads
+                .into_iter()
+                .map(|t| t.join().unwrap())
+                .sum::<usize>()
+        });
+        // the writers are serialized, so none of them fails
+        assert_eq!(committed, THREADS * INCREMENTS);
+        let res = db
+            .run_script("?[v] := *counter{v}", Default::default())
+            .unwrap();
+        assert_eq!(res.rows, vec![vec![DataValue::from(committed as i64)]]);
+        drop(db);
+        if let Some(path) = path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Storage whose write transactions fail to commit with a write conflict
+/// if another one committed since they started
+#[derive(Clone, Default)]
+struct OptimisticStorage {
+    /// the committed data, and the number of commits
+    data: Arc<Mutex<(Arc<BTreeMap<Vec<u8>, Vec<u8>>>, u64)>>,
+    /// the number of the next commits failing with a conflict regardless
+    forced_conflicts: Arc<AtomicUsize>,
+}
+
+struct OptimisticTx<'s> {
+    storage: &'s OptimisticStorage,
+    snapshot: Arc<BTreeMap<Vec<u8>, Vec<u8>>>,
+    version: u64,
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<'s> Storage<'s> for OptimisticStorage {
+    type Tx = OptimisticTx<'s>;
+
+    fn storage_kind(&self) -> &'static str {
+        "optimistic"
+    }
+
+    fn transact(&'s self, _write: bool) -> miette::Result<Self::Tx> {
+        let (snapshot, version) = self.data.lock().unwrap().clone();
+        Ok(OptimisticTx {
+            storage: self,
+            snapshot,
+            version,
+            writes: Default::default(),
+        })
+    }
+
+    fn del_range(&'s self, lower: &[u8], upper: &[u8]) -> miette::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        let mut updated = (*data.0).clone();
+        updated.retain(|k, _| !(lower <= k.as_slice() && k.as_slice() < upper));
+        data.0 = Arc::new(updated);
+        Ok(())
+    }
+
+    fn range_compact(&'s self, _lower: &[u8], _upper: &[u8]) -> miette::Result<()> {
+        Ok(())
+    }
+
+    fn batch_put<'a>(
+        &'a self,
+        data: Box<dyn Iterator<Item = miette::Result<(Vec<u8>, Vec<u8>)>> + 'a>,
+    ) -> miette::Result<()> {
+        let mut committed = self.data.lock().unwrap();
+        let mut updated = (*committed.0).clone();
+        for pair in data {
+            let (k, v) = pair?;
+            updated.insert(k, v);
+        }
+        committed.0 = Arc::new(updated);
+        committed.1 += 1;
+        Ok(())
+    }
+}
+
+impl OptimisticTx<'_> {
+    fn merged(
+        &self,
+        range: impl std::ops::RangeBounds<Vec<u8>> + Clone,
+    ) -> BTreeMap<Vec<u8>, Vec<u8>> {
+        let mut ret: BTreeMap<_, _> = self
+            .snapshot
+            .range(range.clone())
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        for (k, v) in self.writes.range(range) {
+            match v {
+                Some(v) => ret.insert(k.clone(), v.clone()),
+                None => ret.remove(k),
+            };
+        }
+        ret
+    }
+    fn merged_between(&self, lower: &[u8], upper: &[u8]) -> BTreeMap<Vec<u8>, Vec<u8>> {
+        if lower >= upper {
+            return Default::default();
+        }
+        self.merged(lower.to_vec()..upper.to_vec())
+    }
+}
+
+impl<'s> StoreTx<'s> for OptimisticTx<'s> {
+    fn get(&self, key: &[u8], _for_update: bool) -> miette::Result<Option<Vec<u8>>> {
+        Ok(match self.writes.get(key) {
+            Some(v) => v.clone(),
+            None => self.snapshot.get(key).cloned(),
+        })
+    }
+
+    fn put(&mut self, key: &[u8], val: &[u8]) -> miette::Result<()> {
+        self.writes.insert(key.to_vec(), Some(val.to_vec()));
+        Ok(())
+    }
+
+    fn supports_par_put(&self) -> bool {
+        false
+    }
+
+    fn par_put(&self, _key: &[u8], _val: &[u8]) -> miette::Result<()> {
+        unreachable!()
+    }
+
+    fn del(&mut self, key: &[u8]) -> miette::Result<()> {
+        self.writes.insert(key.to_vec(), None);
+        Ok(())
+    }
+
+    fn exists(&self, key: &[u8], for_update: bool) -> miette::Result<bool> {
+        Ok(self.get(key, for_update)?.is_some())
+    }
+
+    fn commit(&mut self) -> miette::Result<()> {
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+        // widens the window for other transactions to start before this one commits
+        std::thread::sleep(Duration::from_millis(1));
+        let forced =
+            self.storage
+                .forced_conflicts
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+        let mut data = self.storage.data.lock().unwrap();
+        if data.1 != self.version || forced.is_ok() {
+            miette::bail!(crate::WriteConflict("committed concurrently".to_string()))
+        }
+        let mut updated = (*data.0).clone();
+        for (k, v) in std::mem::take(&mut self.writes) {
+            match v {
+                Some(v) => updated.insert(k, v),
+                None => updated.remove(&k),
+            };
+        }
+        data.0 = Arc::new(updated);
+        data.1 += 1;
+        Ok(())
+    }
+
+    fn range_skip_scan_tuple<'a>(
+        &'a self,
+        lower: &[u8],
+        upper: &[u8],
+        valid_at: ValidityTs,
+    ) -> Box<dyn Iterator<Item = miette::Result<Tuple>> + 'a> {
+        let data = self.merged_between(lower, upper);
+        let mut ret = vec![];
+        let mut seek = lower.to_vec();
+        while let Some((k, v)) = data.range(seek.clone()..).next() {
+            let (found, next) = match crate::format::check_key_for_validity(k, valid_at) {
+                Ok(res) => res,
+                Err(err) => {
+                    ret.push(Err(err));
+                    break;
+                }
+            };
+            if let Some(mut tup) = found {
+                ret.push(crate::format::extend_tuple_from_v(&mut tup, v).map(|_| tup));
+            }
+            seek = next;
+        }
+        Box::new(ret.into_iter())
+    }
+
+    fn range_scan<'a>(
+        &'a self,
+        lower: &[u8],
+        upper: &[u8],
+    ) -> Box<dyn Iterator<Item = miette::Result<(Vec<u8>, Vec<u8>)>> + 'a>
+    where
+        's: 'a,
+    {
+        Box::new(self.merged_between(lower, upper).into_iter().map(Ok))
+    }
+
+    fn total_scan<'a>(&'a self) -> Box<dyn Iterator<Item = miette::Result<(Vec<u8>, Vec<u8>)>> + 'a>
+    where
+        's: 'a,
+    {
+        Box::new(self.merged(..).into_iter().map(Ok))
+    }
+}
+
+#[test]
+fn test_run_script_with_retry() {
+    const THREADS: usize = 2;
+    const INCREMENTS: usize = 50;
+
+    let db = Db::new(OptimisticStorage::default()).unwrap();
+    db.initialize().unwrap();
+    db.run_script(
+        "?[k, v] <- [[0, 0]] :create counter {k => v}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(":create increments {v}", Default::default())
+        .unwrap();
+    db.run_script(
+        "::set_triggers counter on put { ?[v] := _new[_, v] :put increments {v} }",
+        Default::default(),
+    )
+    .unwrap();
+    let increment = "?[k, v] := *counter{k, v: old}, v = old + 1 :put counter {k => v}";
+
+    // a transaction started before a commit conflicts with it
+    let mut tx = db.db.transact(true).unwrap();
+    db.run_script(increment, Default::default()).unwrap();
+    tx.put(&[0; 9], &[]).unwrap();
+    let err = tx.commit().unwrap_err();
+    assert_eq!(err.code().unwrap().to_string(), "storage::write_conflict");
+    assert!(CozoError::from_report(err).is_transient());
+
+    let (_, events) = db.register_callback("counter", None);
+    let policy = crate::RetryPolicy {
+        max_attempts: 1000,
+        base_backoff: Duration::from_micros(100),
+        ..Default::default()
+    };
+    let run_with_retry = || {
+        let res = db.run_script_with_retry(increment, Default::default(), policy.clone());
+        res.unwrap().attempts().unwrap()
+    };
+    let attempts = std::thread::scope(|s| {
+        let threads = (0..THREADS)
+            .map(|_| s.spawn(|| (0..INCREMENTS).map(|_| run_with_retry()).sum::<usize>()))
+            .collect_vec();
+        threads
+            .into_iter()
+            .map(|t| t.join().unwrap())
+            .sum::<usize>()
+    });
+    assert!(
+        attempts > THREADS * INCREMENTS,
+        "no conflicts were provoked"
+    );
+    // every increment landed, with its trigger and callback fired once
+    let n = 1 + THREADS * INCREMENTS;
+    let res = db
+        .run_script("?[v] := *counter{v}", Default::default())
+        .unwrap();
+    assert_eq!(res.rows, vec![vec![DataValue::from(n as i64)]]);
+    let res = db
+        .run_script("?[count(v)] := *increments{v}", Default::default())
+        .unwrap();
+    assert_eq!(res.rows, vec![vec![DataValue::from(n as i64)]]);
+    assert_eq!(events.try_iter().count(), THREADS * INCREMENTS);
+
+    // the error of the last attempt is returned if all of them conflict
+    let policy = crate::RetryPolicy {
+        max_attempts: 3,
+        ..policy
+    };
+    db.db.forced_conflicts.store(3, Ordering::Relaxed);
+    let err = db
+        .run_script_with_retry(increment, Default::default(), policy.clone())
+        .unwrap_err();
+    assert!(CozoError::from_report(err).is_transient());
+    assert_eq!(events.try_iter().count(), 0);
+    db.db.forced_conflicts.store(2, Ordering::Relaxed);
+    let res = db
+        .run_script_with_retry(increment, Default::default(), policy)
+        .unwrap();
+    assert_eq!(res.attempts(), Some(3));
+    assert_eq!(events.try_iter().count(), 1);
+    let res = db
+        .run_script("?[v] := *counter{v}", Default::default())
+        .unwrap();
+    assert_eq!(res.rows, vec![vec![DataValue::from(n as i64 + 1)]]);
+}
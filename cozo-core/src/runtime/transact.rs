@@ -6,14 +6,29 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use std::sync::atomic::{AtomicU32, AtomicU64};
-use std::sync::Arc;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
-use miette::{bail, Result};
+use crossbeam::channel::Sender;
+use crossbeam::sync::ShardedLock;
+use itertools::Itertools;
+use miette::{bail, Diagnostic, Result};
+use thiserror::Error;
+use uuid::Uuid;
 
-use crate::data::tuple::TupleT;
-use crate::data::value::DataValue;
+use crate::data::expr::UserFunction;
+use crate::data::memcmp::MemCmpEncoder;
+use crate::data::tuple::{decode_tuple_from_key, Tuple, TupleT, ENCODED_KEY_MIN_LEN};
+use crate::data::value::{DataValue, UuidWrapper};
+use crate::runtime::audit::AuditCounts;
+use crate::runtime::clock::Clock;
+use crate::runtime::db::RunningScript;
+use crate::runtime::error::CozoError;
+use crate::runtime::profile::PlanStats;
 use crate::runtime::relation::RelationId;
+use crate::runtime::savepoint::Savepoints;
+use crate::runtime::usage::StorageCounters;
 use crate::storage::temp::TempTx;
 use crate::storage::StoreTx;
 
@@ -22,49 +37,317 @@ pub struct SessionTx<'a> {
     pub(crate) temp_store_tx: TempTx,
     pub(crate) relation_store_id: Arc<AtomicU64>,
     pub(crate) temp_store_id: AtomicU32,
+    /// number of full scans of stored relations performed in this transaction, kept for tests
+    #[cfg(test)]
+    pub(crate) stored_scans: AtomicUsize,
+    /// when set, scans of stored relations that drive a rule stop after this many rows
+    pub(crate) scan_sample: Option<usize>,
+    /// when set, the rows derived for the entry rule are sent here instead of being stored
+    pub(crate) entry_sink: Mutex<Option<Sender<Tuple>>>,
+    /// number of rows passed on from the entry sink as they were derived, kept for tests
+    #[cfg(test)]
+    pub(crate) streamed_rows: AtomicUsize,
+    /// number of sorted runs spilled to disk when sorting outputs
+    pub(crate) spilled_sort_runs: AtomicUsize,
+    /// number of rows of input relations handed to fixed rules, kept for tests
+    #[cfg(test)]
+    pub(crate) fixed_rule_input_rows: AtomicUsize,
+    /// how many times a point lookup failing with a transient error is retried
+    pub(crate) lookup_retries: usize,
+    /// joins whose right side has more rows than this do not use a hash table
+    pub(crate) hash_join_max_rows: usize,
+    /// number of point lookups retried after transient errors
+    pub(crate) lookup_retry_count: AtomicUsize,
+    /// counts of the operations on the storage, once a query reporting its usage has run
+    pub(crate) storage_counters: Option<Arc<StorageCounters>>,
+    /// approximate peak number of bytes of rows held in memory, see [Self::track_memory]
+    pub(crate) peak_memory_bytes: AtomicUsize,
+    /// rows of audited relations read or written, to be recorded in the audit log
+    pub(crate) audit_counts: AuditCounts,
+    /// statistics of the nodes of plans evaluated, only kept by `::profile`
+    pub(crate) plan_stats: Option<Mutex<PlanStats>>,
+    /// the script the queries are run for, killed with all its queries
+    pub(crate) script: RunningScript,
+    /// the time the transaction started at, in seconds since the epoch, at which the expiry
+    /// of rows is judged for all its queries
+    pub(crate) now: f64,
+    /// the clock of the database, read by the functions of expressions evaluated for the
+    /// transaction, see [Clock::scoped]
+    pub(crate) clock: Clock,
+    /// the savepoints set by `%savepoint` and `%ignore_error` in imperative scripts
+    pub(crate) savepoints: Savepoints,
+    /// the functions registered with the database, callable in expressions
+    pub(crate) user_functions: Arc<ShardedLock<BTreeMap<String, Arc<UserFunction>>>>,
+    /// the number of triggers running within each other, up to [crate::query::stored::MAX_TRIGGER_DEPTH]
+    pub(crate) trigger_depth: usize,
 }
 
-pub const CURRENT_STORAGE_VERSION: [u8; 1] = [0x00];
+pub const CURRENT_STORAGE_VERSION: [u8; 1] = [0x01];
+/// The version of storages with UUIDs in keys encoded in the order of their fields,
+/// see [crate::Db::upgrade_storage]
+const UUID_FIELD_ORDER_STORAGE_VERSION: [u8; 1] = [0x00];
+/// Keys rewritten by the upgrade of UUIDs are kept under this prefix, after the keys of all
+/// relations, until all keys have been read
+const UUID_UPGRADE_STAGING_PREFIX: [u8; 8] = [0xff; 8];
+/// Where the upgrade of UUIDs stopped, `0` followed by the next key to read or `1` once
+/// the staged keys are being moved back
+const UUID_UPGRADE_READING: u8 = 0;
+const UUID_UPGRADE_MOVING: u8 = 1;
+/// Number of keys read at a time by the upgrade of UUIDs
+const UUID_UPGRADE_BATCH_SIZE: usize = 1024;
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Point lookup still failing after {0} retries")]
+#[diagnostic(code(tx::lookup_retries_exhausted))]
+struct LookupRetriesExhausted(usize, #[help] String);
 
 fn storage_version_key() -> Vec<u8> {
     let storage_version_tuple = vec![DataValue::Null, DataValue::from("STORAGE_VERSION")];
     storage_version_tuple.encode_as_key(RelationId::SYSTEM)
 }
 
+fn uuid_upgrade_cursor_key() -> Vec<u8> {
+    let cursor_tuple = vec![DataValue::Null, DataValue::from("UUID_UPGRADE_CURSOR")];
+    cursor_tuple.encode_as_key(RelationId::SYSTEM)
+}
+
+/// Whether the storage has UUIDs in keys encoded in the order of their fields, and must be
+/// upgraded with [upgrade_uuid_keys] or read through [upgraded_uuid_pairs]
+pub(crate) fn has_uuid_field_order(tx: &dyn StoreTx<'_>) -> Result<bool> {
+    Ok(tx.get(&storage_version_key(), false)?.as_deref()
+        == Some(&UUID_FIELD_ORDER_STORAGE_VERSION[..]))
+}
+
+/// The key with its UUIDs in the order of their bytes, or `None` if it has no UUIDs
+fn upgrade_uuid_key(key: &[u8]) -> Result<Option<Vec<u8>>> {
+    if key.len() <= ENCODED_KEY_MIN_LEN {
+        return Ok(None);
+    }
+    let mut tuple = decode_tuple_from_key(key)?;
+    let mut found = false;
+    for val in &mut tuple {
+        found |= reorder_uuid_fields(val);
+    }
+    if !found {
+        return Ok(None);
+    }
+    let mut upgraded = key[..ENCODED_KEY_MIN_LEN].to_vec();
+    for val in &tuple {
+        upgraded.encode_datavalue(val);
+    }
+    Ok(Some(upgraded))
+}
+
+/// Upgrades a batch of the keys of a storage with UUIDs encoded in the order of their fields,
+/// time high first as for version 1 UUIDs, to UUIDs in the order of their bytes. Where it
+/// stopped is kept in the storage, so that each batch can be committed on its own, and the
+/// storage is only marked as upgraded by the last batch. Returns whether the upgrade is done.
+pub(crate) fn upgrade_uuid_keys(tx: &mut dyn StoreTx<'_>) -> Result<bool> {
+    if !has_uuid_field_order(tx)? {
+        return Ok(true);
+    }
+    let cursor_key = uuid_upgrade_cursor_key();
+    let cursor = tx
+        .get(&cursor_key, true)?
+        .unwrap_or_else(|| vec![UUID_UPGRADE_READING]);
+    if cursor.first() == Some(&UUID_UPGRADE_READING) {
+        // keys rewritten may be the old forms of other keys, so the rewritten keys are staged
+        // after all relations until every old key has been read
+        let batch: Vec<_> = tx
+            .range_scan(&cursor[1..], &UUID_UPGRADE_STAGING_PREFIX)
+            .take(UUID_UPGRADE_BATCH_SIZE)
+            .try_collect()?;
+        let next = match batch.last() {
+            None => vec![UUID_UPGRADE_MOVING],
+            Some((last_key, _)) => {
+                let mut next = vec![UUID_UPGRADE_READING];
+                next.extend_from_slice(last_key);
+                next.push(0);
+                next
+            }
+        };
+        for (k, v) in batch {
+            if let Some(upgraded) = upgrade_uuid_key(&k)? {
+                let mut staged = UUID_UPGRADE_STAGING_PREFIX.to_vec();
+                staged.extend(upgraded);
+                tx.del(&k)?;
+                tx.put(&staged, &v)?;
+            }
+        }
+        tx.put(&cursor_key, &next)?;
+        return Ok(false);
+    }
+    let mut staging_upper = UUID_UPGRADE_STAGING_PREFIX.to_vec();
+    staging_upper.push(0xff);
+    let batch: Vec<_> = tx
+        .range_scan(&UUID_UPGRADE_STAGING_PREFIX, &staging_upper)
+        .take(UUID_UPGRADE_BATCH_SIZE)
+        .try_collect()?;
+    if batch.is_empty() {
+        tx.del(&cursor_key)?;
+        tx.put(&storage_version_key(), &CURRENT_STORAGE_VERSION)?;
+        return Ok(true);
+    }
+    for (staged, v) in batch {
+        tx.del(&staged)?;
+        tx.put(&staged[UUID_UPGRADE_STAGING_PREFIX.len()..], &v)?;
+    }
+    Ok(false)
+}
+
+/// The pairs of a storage with UUIDs encoded in the order of their fields, possibly partly
+/// upgraded by [upgrade_uuid_keys], as they are once it is upgraded, read without writing
+/// to the storage
+#[cfg_attr(not(feature = "storage-sqlite"), allow(dead_code))]
+pub(crate) fn upgraded_uuid_pairs<'a>(
+    tx: &'a dyn StoreTx<'_>,
+) -> Result<impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a> {
+    let cursor_key = uuid_upgrade_cursor_key();
+    let version_key = storage_version_key();
+    // once the staged keys are being moved back, every key out of the staging area has
+    // its UUIDs in order already
+    let reading = tx
+        .get(&cursor_key, false)?
+        .is_none_or(|cursor| cursor.first() == Some(&UUID_UPGRADE_READING));
+    Ok(tx.total_scan().filter_map(move |pair| {
+        let (k, v) = match pair {
+            Ok(pair) => pair,
+            Err(err) => return Some(Err(err)),
+        };
+        if k == cursor_key {
+            return None;
+        }
+        if k == version_key {
+            return Some(Ok((k, CURRENT_STORAGE_VERSION.to_vec())));
+        }
+        if let Some(staged) = k.strip_prefix(&UUID_UPGRADE_STAGING_PREFIX[..]) {
+            return Some(Ok((staged.to_vec(), v)));
+        }
+        if !reading {
+            return Some(Ok((k, v)));
+        }
+        Some(upgrade_uuid_key(&k).map(|upgraded| (upgraded.unwrap_or(k), v)))
+    }))
+}
+
+/// UUIDs read from keys of older storages have their bytes in the order of their fields,
+/// time high, time mid, time low, then the rest: puts them back in place, returning whether
+/// there were any
+fn reorder_uuid_fields(val: &mut DataValue) -> bool {
+    match val {
+        DataValue::Uuid(UuidWrapper(u)) => {
+            let b = u.as_bytes();
+            let mut reordered = [0u8; 16];
+            reordered[0..4].copy_from_slice(&b[4..8]);
+            reordered[4..6].copy_from_slice(&b[2..4]);
+            reordered[6..8].copy_from_slice(&b[0..2]);
+            reordered[8..].copy_from_slice(&b[8..]);
+            *u = Uuid::from_bytes(reordered);
+            true
+        }
+        DataValue::List(l) => {
+            // every element is visited, not only up to the first UUID
+            let mut found = false;
+            for el in l {
+                found |= reorder_uuid_fields(el);
+            }
+            found
+        }
+        DataValue::Set(s) => {
+            // the order of the elements changes with their UUIDs, so the set is rebuilt
+            let mut found = false;
+            *s = std::mem::take(s)
+                .into_iter()
+                .map(|mut el| {
+                    found = reorder_uuid_fields(&mut el) || found;
+                    el
+                })
+                .collect();
+            found
+        }
+        _ => false,
+    }
+}
+
+fn schema_generation_key() -> Vec<u8> {
+    let schema_generation_tuple = vec![DataValue::Null, DataValue::from("SCHEMA_GENERATION")];
+    schema_generation_tuple.encode_as_key(RelationId::SYSTEM)
+}
+
 impl<'a> SessionTx<'a> {
-    pub(crate) fn init_storage(&mut self) -> Result<RelationId> {
+    /// Runs a point lookup, retrying it immediately when it fails with a transient error,
+    /// at most `lookup_retries` times.
+    pub(crate) fn retry_lookup<T>(&self, mut lookup: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut retries = 0;
+        loop {
+            match lookup() {
+                Err(err) if CozoError::is_transient_report(&err) => {
+                    if retries >= self.lookup_retries {
+                        bail!(LookupRetriesExhausted(retries, err.to_string()))
+                    }
+                    retries += 1;
+                    self.lookup_retry_count.fetch_add(1, Ordering::Relaxed);
+                }
+                res => return res,
+            }
+        }
+    }
+    /// The generation of the schema of stored relations, changes every time
+    /// the metadata of any stored relation changes.
+    pub(crate) fn schema_generation(&self) -> Result<u64> {
+        Ok(match self.store_tx.get(&schema_generation_key(), false)? {
+            Some(v) if v.len() == 8 => u64::from_be_bytes(v[..].try_into().unwrap()),
+            _ => 0,
+        })
+    }
+    pub(crate) fn bump_schema_generation(&mut self) -> Result<()> {
+        let key = schema_generation_key();
+        let current = match self.store_tx.get(&key, true)? {
+            Some(v) if v.len() == 8 => u64::from_be_bytes(v[..].try_into().unwrap()),
+            _ => 0,
+        };
+        self.store_tx.put(&key, &(current + 1).to_be_bytes())
+    }
+    /// The id of the last stored relation created, after checking the version of the storage,
+    /// or `None` if the storage is not initialized yet
+    pub(crate) fn last_relation_id(&self) -> Result<Option<RelationId>> {
         let tuple = vec![DataValue::Null];
         let t_encoded = tuple.encode_as_key(RelationId::SYSTEM);
-        let found = self.store_tx.get(&t_encoded, false)?;
-        let storage_version_key = storage_version_key();
-        let ret = match found {
+        let found = match self.store_tx.get(&t_encoded, false)? {
+            None => return Ok(None),
+            Some(slice) => slice,
+        };
+        let version_found = self.store_tx.get(&storage_version_key(), false)?;
+        match version_found {
             None => {
-                self.store_tx
-                    .put(&storage_version_key, &CURRENT_STORAGE_VERSION)?;
-                self.store_tx
-                    .put(&t_encoded, &RelationId::new(0).raw_encode())?;
-                RelationId::SYSTEM
+                bail!("Storage is used but un-versioned, probably created by an ancient version of Cozo.")
             }
-            Some(slice) => {
-                let version_found = self.store_tx.get(&storage_version_key, false)?;
-                match version_found {
-                    None => {
-                        bail!("Storage is used but un-versioned, probably created by an ancient version of Cozo.")
-                    }
-                    Some(v) => {
-                        if &v != &CURRENT_STORAGE_VERSION {
-                            bail!(
-                                "Version mismatch: expect storage version {:?}, got {:?}",
-                                CURRENT_STORAGE_VERSION,
-                                v
-                            )
-                        }
-                    }
+            Some(v) => {
+                if v == UUID_FIELD_ORDER_STORAGE_VERSION {
+                    bail!("Storage created by an older version of Cozo must be upgraded, by opening it for writing or with `Db::upgrade_storage`, before it can be opened read-only.")
+                }
+                if v != CURRENT_STORAGE_VERSION {
+                    bail!(
+                        "Version mismatch: expect storage version {:?}, got {:?}",
+                        CURRENT_STORAGE_VERSION,
+                        v
+                    )
                 }
-                RelationId::raw_decode(&slice)
             }
-        };
-        Ok(ret)
+        }
+        Ok(Some(RelationId::raw_decode(&found)))
+    }
+    pub(crate) fn init_storage(&mut self) -> Result<RelationId> {
+        if let Some(id) = self.last_relation_id()? {
+            return Ok(id);
+        }
+        let tuple = vec![DataValue::Null];
+        let t_encoded = tuple.encode_as_key(RelationId::SYSTEM);
+        self.store_tx
+            .put(&storage_version_key(), &CURRENT_STORAGE_VERSION)?;
+        self.store_tx
+            .put(&t_encoded, &RelationId::new(0).raw_encode())?;
+        Ok(RelationId::SYSTEM)
     }
 
     pub fn commit_tx(&mut self) -> Result<()> {
@@ -0,0 +1,348 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use miette::{bail, Diagnostic, Result};
+use thiserror::Error;
+
+use crate::data::relation::ColType;
+use crate::data::symb::Symbol;
+use crate::data::tuple::{Tuple, TupleT};
+use crate::runtime::relation::{decode_tuple_from_kv, AccessLevel, InsufficientAccessLevel};
+use crate::runtime::transact::SessionTx;
+
+/// The number of expired rows removed by each transaction of `::ttl_sweep`
+pub(crate) const SWEEP_BATCH_ROWS: usize = 10_000;
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Column '{1}' not found in relation '{0}'")]
+#[diagnostic(code(eval::ttl_column_not_found))]
+struct TtlColumnNotFound(String, String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Column '{1}' of relation '{0}' has the type {2}, which cannot hold expiry times")]
+#[diagnostic(code(eval::bad_ttl_column))]
+#[diagnostic(help(
+    "The TTL column holds times in seconds since the epoch, as returned by `now()`, \
+    so it must be of type Int, Float or Any"
+))]
+struct BadTtlColumn(String, String, String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Relation '{0}' has no TTL column")]
+#[diagnostic(code(eval::no_ttl_column))]
+#[diagnostic(help("Set one with `::set_ttl`"))]
+struct NoTtlColumn(String);
+
+impl<'a> SessionTx<'a> {
+    /// Sets the column holding the expiry times of the rows of the relation,
+    /// or makes the rows never expire when no column is given
+    pub(crate) fn set_ttl(&mut self, rel: &Symbol, column: Option<Symbol>) -> Result<()> {
+        if rel.name.starts_with('_') {
+            bail!("Cannot set TTL column of temp relation");
+        }
+        let mut handle = self.get_relation(rel, true)?;
+        if handle.access_level < AccessLevel::Normal {
+            bail!(InsufficientAccessLevel(
+                handle.name.to_string(),
+                "setting TTL column".to_string(),
+                handle.access_level
+            ));
+        }
+        if let Some(column) = &column {
+            let col = handle
+                .metadata
+                .keys
+                .iter()
+                .chain(handle.metadata.non_keys.iter())
+                .find(|col| col.name == column.name)
+                .ok_or_else(|| {
+                    TtlColumnNotFound(handle.name.to_string(), column.name.to_string())
+                })?;
+            if !matches!(
+                col.typing.coltype,
+                ColType::Int | ColType::Float | ColType::Any
+            ) {
+                bail!(BadTtlColumn(
+                    handle.name.to_string(),
+                    column.name.to_string(),
+                    col.typing.to_string()
+                ))
+            }
+        }
+        handle.ttl = column.map(|col| col.name);
+        self.put_relation_meta(&handle)?;
+        self.bump_schema_generation()
+    }
+    /// Removes at most `limit` expired rows of the relation, starting from the row with
+    /// the given key, together with their index entries and, by the constraints referring
+    /// to the relation, the rows referring to them. Single expired rows are removed in the
+    /// transaction, the runs of consecutive ones are left to the caller to delete by range
+    /// once it is committed.
+    pub(crate) fn sweep_expired(
+        &mut self,
+        rel: &Symbol,
+        from: Option<Vec<u8>>,
+        limit: usize,
+    ) -> Result<SweptBatch> {
+        let handle = self.get_relation(rel, true)?;
+        if handle.access_level < AccessLevel::Protected {
+            bail!(InsufficientAccessLevel(
+                handle.name.to_string(),
+                "removing expired rows".to_string(),
+                handle.access_level
+            ));
+        }
+        let expiry = handle.expiry(self);
+        if !expiry.has_ttl() {
+            bail!(NoTtlColumn(handle.name.to_string()))
+        }
+        let lower = from.unwrap_or_else(|| Tuple::default().encode_as_key(handle.id));
+        let upper = Tuple::default().encode_as_key(handle.id.next());
+        let mut expired = vec![];
+        // the runs of consecutive expired rows, as ranges of `expired`
+        let mut runs = vec![];
+        let mut run_start = 0;
+        let mut resume = None;
+        for kv in self.store_tx.range_scan(&lower, &upper) {
+            let (k, v) = kv?;
+            if expired.len() == limit {
+                resume = Some(k);
+                break;
+            }
+            let row = decode_tuple_from_kv(&k, &v)?;
+            if expiry.is_live(&row) {
+                if run_start < expired.len() {
+                    runs.push((run_start..expired.len(), k));
+                    run_start = expired.len();
+                }
+            } else {
+                expired.push((k, row));
+            }
+        }
+        if run_start < expired.len() {
+            let end = resume.clone().unwrap_or(upper);
+            runs.push((run_start..expired.len(), end));
+        }
+
+        let fks = self.foreign_keys(&handle)?;
+        let n_keys = handle.metadata.keys.len();
+        let mut ranges = vec![];
+        for (run, end) in runs {
+            let rows = &expired[run];
+            for (_, row) in rows {
+                self.delete_from_indices(&handle, row)?;
+                if fks.has_incoming() {
+                    self.remove_references(&fks, &row[..n_keys])?;
+                }
+            }
+            match rows {
+                [(key, _)] => self.store_tx.del(key)?,
+                [(first, _), ..] => ranges.push((first.clone(), end)),
+                [] => unreachable!(),
+            }
+        }
+        Ok(SweptBatch {
+            removed: expired.len(),
+            resume,
+            ranges,
+        })
+    }
+}
+
+/// The result of [SessionTx::sweep_expired]
+pub(crate) struct SweptBatch {
+    /// the number of rows removed
+    pub(crate) removed: usize,
+    /// the key to continue from, if the rows have not all been looked at
+    pub(crate) resume: Option<Vec<u8>>,
+    /// the ranges of keys holding only expired rows, to be deleted after the commit
+    pub(crate) ranges: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::data::tuple::{Tuple, TupleT};
+    use crate::data::value::DataValue;
+    use crate::{new_cozo_mem, Db, Storage, VirtualClock};
+
+    /// Checks that the expired rows of a relation with a TTL column are hidden from queries
+    /// and removed by `::ttl_sweep`
+    fn check_ttl<'s, S: Storage<'s>>(db: &'s Db<S>) {
+        let clock = VirtualClock::new(1_000_000_000_000_000);
+        db.set_clock(clock.as_clock_fn());
+        db.run_script(
+            r"
+        {:create sessions {id: Int => user: String, expires_at: Float?}}
+        {?[id, user, expires_at] <- [[1, 'a', 1000000010.], [2, 'a', 1000000020.],
+                                     [3, 'b', 1000000010.], [4, 'b', null]]
+         :put sessions {id => user, expires_at}}
+        ",
+            Default::default(),
+        )
+        .unwrap();
+        for script in [
+            "::index create sessions:by_user {user, id}",
+            "::set_ttl sessions expires_at",
+        ] {
+            db.run_script(script, Default::default()).unwrap();
+        }
+        let run = |query: &str| {
+            db.run_script(query, Default::default())
+                .unwrap()
+                .into_json()["rows"]
+                .clone()
+        };
+        let count_entries = || {
+            let tx = db.transact().unwrap();
+            let handle = tx.get_relation("sessions", false).unwrap();
+            let lower = Tuple::default().encode_as_key(handle.id);
+            let upper = Tuple::default().encode_as_key(handle.id.next());
+            tx.store_tx.range_scan(&lower, &upper).count()
+        };
+        let queries = [
+            "?[id] := *sessions{id}",
+            "?[id] := id in [1, 2, 3, 4], *sessions{id, user: _}",
+            "?[id] := *sessions{id, user: 'a'}",
+            "?[id] := id in [1, 2, 3, 4], not *sessions{id}",
+            "?[user] := *sessions{id: 3, user}",
+        ];
+        let expected_before = [
+            json!([[1], [2], [3], [4]]),
+            json!([[1], [2], [3], [4]]),
+            json!([[1], [2]]),
+            json!([]),
+            json!([["b"]]),
+        ];
+        for (query, expected) in queries.iter().zip(&expected_before) {
+            assert_eq!(run(query), *expected, "{query}");
+        }
+
+        // rows are gone the moment they expire, though still stored
+        clock.advance(10_000_000);
+        let expected_after = [
+            json!([[2], [4]]),
+            json!([[2], [4]]),
+            json!([[2]]),
+            json!([[1], [3]]),
+            json!([]),
+        ];
+        for (query, expected) in queries.iter().zip(&expected_after) {
+            assert_eq!(run(query), *expected, "{query}");
+        }
+        assert_eq!(count_entries(), 4);
+
+        let res = db
+            .run_script("::ttl_sweep sessions", Default::default())
+            .unwrap();
+        assert_eq!(res.rows, vec![vec![DataValue::from(2)]]);
+        assert_eq!(count_entries(), 2);
+        assert_eq!(
+            run("?[user, id] := *sessions:by_user{user, id}"),
+            json!([["a", 2], ["b", 4]])
+        );
+        for (query, expected) in queries.iter().zip(&expected_after) {
+            assert_eq!(run(query), *expected, "{query}");
+        }
+
+        // runs of consecutive expired rows are deleted by range, and their keys can be reused
+        db.run_script(
+            r"
+        ?[id, user, expires_at] := id in int_range(10, 20), user = 'c',
+                                    expires_at = if(id == 15, null, 0.)
+        :put sessions {id => user, expires_at}
+        ",
+            Default::default(),
+        )
+        .unwrap();
+        assert_eq!(count_entries(), 12);
+        let res = db
+            .run_script("::ttl_sweep sessions", Default::default())
+            .unwrap();
+        assert_eq!(res.rows, vec![vec![DataValue::from(9)]]);
+        assert_eq!(count_entries(), 3);
+        assert_eq!(
+            run("?[user, id] := *sessions:by_user{user, id}"),
+            json!([["a", 2], ["b", 4], ["c", 15]])
+        );
+        db.run_script(
+            "?[id, user, expires_at] <- [[11, 'c', null]] :put sessions {id => user, expires_at}",
+            Default::default(),
+        )
+        .unwrap();
+        assert_eq!(run("?[id] := *sessions{id}"), json!([[2], [4], [11], [15]]));
+        db.run_script(
+            "?[id] <- [[11], [15]] :rm sessions {id}",
+            Default::default(),
+        )
+        .unwrap();
+
+        // without the TTL column, rows never expire
+        clock.advance(100_000_000);
+        db.run_script("::set_ttl sessions", Default::default())
+            .unwrap();
+        assert_eq!(run("?[id] := *sessions{id}"), json!([[2], [4]]));
+        assert!(db
+            .run_script("::ttl_sweep sessions", Default::default())
+            .is_err());
+        assert!(db
+            .run_script("::set_ttl sessions user", Default::default())
+            .is_err());
+        assert!(db
+            .run_script("::set_ttl sessions missing", Default::default())
+            .is_err());
+    }
+
+    #[test]
+    fn test_ttl() {
+        let db = new_cozo_mem().unwrap();
+        check_ttl(&db);
+
+        // the TTL column follows renames and cannot be dropped
+        db.run_script("::set_ttl sessions expires_at", Default::default())
+            .unwrap();
+        db.run_script(
+            "::alter sessions rename column expires_at to deadline",
+            Default::default(),
+        )
+        .unwrap();
+        assert!(db
+            .run_script("::alter sessions drop column deadline", Default::default())
+            .is_err());
+        db.run_script(
+            "?[id, user, deadline] <- [[5, 'c', 0.]] :put sessions {id => user, deadline}",
+            Default::default(),
+        )
+        .unwrap();
+        let res = db
+            .run_script("?[id] := *sessions{id}", Default::default())
+            .unwrap();
+        assert_eq!(res.into_json()["rows"], json!([[4]]));
+    }
+
+    #[cfg(feature = "storage-sqlite")]
+    #[test]
+    fn test_ttl_sqlite() {
+        let path = std::env::temp_dir().join(format!("cozo-test-{}.db", rand::random::<u64>()));
+        let db = crate::new_cozo_sqlite(&path).unwrap();
+        check_ttl(&db);
+        drop(db);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[cfg(feature = "storage-rocksdb")]
+    #[test]
+    fn test_ttl_rocksdb() {
+        let path = std::env::temp_dir().join(format!("cozo-test-{}", rand::random::<u64>()));
+        let db = crate::new_cozo_rocksdb(&path).unwrap();
+        check_ttl(&db);
+        drop(db);
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
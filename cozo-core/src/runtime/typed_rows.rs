@@ -0,0 +1,1236 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+use itertools::Itertools;
+use miette::{bail, Diagnostic, Result, WrapErr};
+use serde::de::value::BorrowedStrDeserializer;
+use serde::de::{
+    DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess, SeqAccess, Unexpected, Visitor,
+};
+use serde::ser::{
+    Impossible, SerializeMap, SerializeSeq, SerializeStruct, SerializeStructVariant,
+    SerializeTuple, SerializeTupleStruct, SerializeTupleVariant,
+};
+use serde::{forward_to_deserialize_any, Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+use crate::data::relation::ColumnDef;
+use crate::data::value::{DataValue, Num, Validity};
+use crate::runtime::db::{Db, NamedRows};
+use crate::runtime::error::CozoError;
+use crate::storage::Storage;
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Cannot deserialize row {0}: {1}")]
+#[diagnostic(code(eval::row_deserialization))]
+struct RowDeserializationError(usize, String);
+
+/// The error raised while deserializing a row, naming the column it was raised for
+#[derive(Debug, Error)]
+#[error("{message}")]
+struct DeError {
+    column: Option<String>,
+    message: String,
+}
+
+impl DeError {
+    fn in_column(mut self, column: &str) -> Self {
+        if self.column.is_none() {
+            self.column = Some(column.to_string());
+        }
+        self
+    }
+    fn in_row(self, row: usize) -> RowDeserializationError {
+        match self.column {
+            None => RowDeserializationError(row, self.message),
+            Some(col) => RowDeserializationError(row, format!("column '{col}': {}", self.message)),
+        }
+    }
+}
+
+impl serde::de::Error for DeError {
+    fn custom<T: Display>(msg: T) -> Self {
+        DeError {
+            column: None,
+            message: msg.to_string(),
+        }
+    }
+    fn missing_field(field: &'static str) -> Self {
+        DeError {
+            column: Some(field.to_string()),
+            message: "there is no such column in the results".to_string(),
+        }
+    }
+}
+
+/// Deserializes a row as a map from the headers to the values, or as a sequence of the values
+struct RowDeserializer<'a> {
+    headers: &'a [String],
+    row: &'a [DataValue],
+}
+
+impl<'de> Deserializer<'de> for RowDeserializer<'de> {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_map(RowAccess {
+            columns: self.headers.iter().zip(self.row.iter()),
+            current: None,
+        })
+    }
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_seq(ListAccess(self.row.iter()))
+    }
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.deserialize_seq(visitor)
+    }
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.deserialize_seq(visitor)
+    }
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct map struct enum identifier ignored_any
+    }
+}
+
+struct RowAccess<'a, I> {
+    columns: I,
+    current: Option<(&'a str, &'a DataValue)>,
+}
+
+impl<'de, I: Iterator<Item = (&'de String, &'de DataValue)>> MapAccess<'de> for RowAccess<'de, I> {
+    type Error = DeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, DeError> {
+        match self.columns.next() {
+            None => Ok(None),
+            Some((header, value)) => {
+                self.current = Some((header, value));
+                seed.deserialize(BorrowedStrDeserializer::new(header))
+                    .map(Some)
+            }
+        }
+    }
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, DeError> {
+        let (header, value) = self
+            .current
+            .take()
+            .expect("value of a column asked for before its header");
+        seed.deserialize(ValueDeserializer(value))
+            .map_err(|err| err.in_column(header))
+    }
+}
+
+struct ListAccess<I>(I);
+
+impl<'de, I: Iterator<Item = &'de DataValue>> SeqAccess<'de> for ListAccess<I> {
+    type Error = DeError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, DeError> {
+        match self.0.next() {
+            None => Ok(None),
+            Some(value) => seed.deserialize(ValueDeserializer(value)).map(Some),
+        }
+    }
+}
+
+/// Validities are deserialized as the pair `[timestamp, is_assert]`, as in JSON
+struct ValidityAccess(Validity, usize);
+
+impl<'de> SeqAccess<'de> for ValidityAccess {
+    type Error = DeError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, DeError> {
+        self.1 += 1;
+        match self.1 {
+            1 => seed
+                .deserialize(self.0.timestamp.0 .0.into_deserializer())
+                .map(Some),
+            2 => seed
+                .deserialize(self.0.is_assert.0.into_deserializer())
+                .map(Some),
+            _ => Ok(None),
+        }
+    }
+}
+
+struct ValueDeserializer<'a>(&'a DataValue);
+
+impl<'a> ValueDeserializer<'a> {
+    fn unexpected(&self) -> Unexpected<'a> {
+        match self.0 {
+            DataValue::Null => Unexpected::Unit,
+            DataValue::Bool(b) => Unexpected::Bool(*b),
+            DataValue::Num(Num::Int(i)) => Unexpected::Signed(*i),
+            DataValue::Num(Num::Float(f)) => Unexpected::Float(*f),
+            DataValue::Str(s) => Unexpected::Str(s),
+            DataValue::Bytes(b) => Unexpected::Bytes(b),
+            DataValue::Uuid(_) => Unexpected::Other("UUID"),
+            DataValue::Regex(_) => Unexpected::Other("regex"),
+            DataValue::List(_) | DataValue::Set(_) => Unexpected::Seq,
+            DataValue::Validity(_) => Unexpected::Other("validity"),
+            DataValue::Bot => Unexpected::Other("bottom"),
+        }
+    }
+}
+
+impl<'de> Deserializer<'de> for ValueDeserializer<'de> {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match self.0 {
+            DataValue::Null => visitor.visit_unit(),
+            DataValue::Bool(b) => visitor.visit_bool(*b),
+            DataValue::Num(Num::Int(i)) => visitor.visit_i64(*i),
+            DataValue::Num(Num::Float(f)) => visitor.visit_f64(*f),
+            DataValue::Str(s) => visitor.visit_borrowed_str(s),
+            DataValue::Bytes(b) => visitor.visit_borrowed_bytes(b),
+            DataValue::Uuid(u) => visitor.visit_string(u.0.to_string()),
+            DataValue::Regex(r) => visitor.visit_borrowed_str(r.0.as_str()),
+            DataValue::List(l) => visitor.visit_seq(ListAccess(l.iter())),
+            DataValue::Set(s) => visitor.visit_seq(ListAccess(s.iter())),
+            DataValue::Validity(v) => visitor.visit_seq(ValidityAccess(*v, 0)),
+            DataValue::Bot => Err(serde::de::Error::invalid_type(self.unexpected(), &visitor)),
+        }
+    }
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match self.0 {
+            DataValue::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match self.0 {
+            // so that bytes can be deserialized into `Vec<u8>`
+            DataValue::Bytes(b) => {
+                visitor.visit_seq(serde::de::value::SeqDeserializer::new(b.iter().copied()))
+            }
+            _ => self.deserialize_any(visitor),
+        }
+    }
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.deserialize_seq(visitor)
+    }
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        visitor.visit_newtype_struct(self)
+    }
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        match self.0 {
+            // values deserialized as they are, e.g. in `Vec<DataValue>`, go through their
+            // own serialized form
+            v if name == "DataValue" => {
+                let bytes = rmp_serde::to_vec(v).map_err(serde::de::Error::custom)?;
+                let mut de = rmp_serde::Deserializer::new(&bytes[..]);
+                (&mut de)
+                    .deserialize_enum(name, variants, visitor)
+                    .map_err(serde::de::Error::custom)
+            }
+            // unit variants are given by their names
+            DataValue::Str(s) => visitor.visit_enum(BorrowedStrDeserializer::new(s)),
+            _ => Err(serde::de::Error::invalid_type(self.unexpected(), &visitor)),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl NamedRows {
+    /// Deserialize each row into a `T`, e.g. a struct whose fields are named as the headers.
+    /// Columns without fields are ignored, unless `T` denies unknown fields, and fields without
+    /// columns are errors, unless they have defaults. Nullable columns go into `Option`s,
+    /// lists into `Vec`s, and strings can be deserialized into enums with unit variants.
+    /// `DataValue` and `serde_json::Value` take any value. Rows can also be deserialized
+    /// into tuples, taking the columns in order.
+    pub fn deserialize_rows<T: DeserializeOwned>(&self) -> Result<Vec<T>> {
+        self.deserialize_rows_ref()
+    }
+
+    /// As [NamedRows::deserialize_rows], but `T` may borrow from the rows,
+    /// e.g. with `&str` fields for string columns.
+    pub fn deserialize_rows_ref<'a, T: Deserialize<'a>>(&'a self) -> Result<Vec<T>> {
+        let mut ret = Vec::with_capacity(self.rows.len());
+        for (i, row) in self.rows.iter().enumerate() {
+            let de = RowDeserializer {
+                headers: &self.headers,
+                row,
+            };
+            ret.push(T::deserialize(de).map_err(|err| err.in_row(i))?);
+        }
+        Ok(ret)
+    }
+}
+
+impl<'s, S: Storage<'s>> Db<S> {
+    /// Run the CozoScript passed in, with the rows of the result deserialized as by
+    /// [NamedRows::deserialize_rows].
+    pub fn run_typed<T: DeserializeOwned>(
+        &'s self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+    ) -> Result<Vec<T>> {
+        self.run_script(payload, params)?
+            .deserialize_rows()
+            .map_err(CozoError::wrap)
+    }
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Cannot serialize row {0} for relation '{1}': {2}")]
+#[diagnostic(code(import::bad_row))]
+struct BadRow(usize, String, String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Row {0} for relation '{1}' has no field for the column '{2}'")]
+#[diagnostic(code(import::missing_field))]
+#[diagnostic(help("Only columns with defaults may be left out, and only from every row"))]
+struct MissingField(usize, String, String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Row {0} for relation '{1}' has the field '{2}', which is not a column of the relation")]
+#[diagnostic(code(import::extra_field))]
+#[diagnostic(help("Set `ignore_extra_fields` in the options to leave out such fields"))]
+struct ExtraField(usize, String, String);
+
+#[derive(Debug, Error)]
+#[error("{0}")]
+struct SerError(String);
+
+impl serde::ser::Error for SerError {
+    fn custom<T: Display>(msg: T) -> Self {
+        SerError(msg.to_string())
+    }
+}
+
+/// An enum variant holding data, which becomes `[[variant, data]]` as it does in JSON
+fn variant_value(variant: &str, data: DataValue) -> DataValue {
+    DataValue::List(vec![DataValue::List(vec![DataValue::from(variant), data])])
+}
+
+/// Serializes values as they would be converted from JSON, except that bytes become bytes,
+/// and values of [DataValue] and [Validity] are kept as they are.
+struct ValueSerializer;
+
+impl Serializer for ValueSerializer {
+    type Ok = DataValue;
+    type Error = SerError;
+    type SerializeSeq = ListSerializer;
+    type SerializeTuple = ListSerializer;
+    type SerializeTupleStruct = ListSerializer;
+    type SerializeTupleVariant = ListSerializer;
+    type SerializeMap = MapSerializer;
+    type SerializeStruct = MapSerializer;
+    type SerializeStructVariant = MapSerializer;
+
+    fn serialize_bool(self, v: bool) -> Result<DataValue, SerError> {
+        Ok(DataValue::Bool(v))
+    }
+    fn serialize_i8(self, v: i8) -> Result<DataValue, SerError> {
+        Ok(DataValue::from(v as i64))
+    }
+    fn serialize_i16(self, v: i16) -> Result<DataValue, SerError> {
+        Ok(DataValue::from(v as i64))
+    }
+    fn serialize_i32(self, v: i32) -> Result<DataValue, SerError> {
+        Ok(DataValue::from(v as i64))
+    }
+    fn serialize_i64(self, v: i64) -> Result<DataValue, SerError> {
+        Ok(DataValue::from(v))
+    }
+    fn serialize_u8(self, v: u8) -> Result<DataValue, SerError> {
+        Ok(DataValue::from(v as i64))
+    }
+    fn serialize_u16(self, v: u16) -> Result<DataValue, SerError> {
+        Ok(DataValue::from(v as i64))
+    }
+    fn serialize_u32(self, v: u32) -> Result<DataValue, SerError> {
+        Ok(DataValue::from(v as i64))
+    }
+    fn serialize_u64(self, v: u64) -> Result<DataValue, SerError> {
+        i64::try_from(v)
+            .map(DataValue::from)
+            .map_err(|_| SerError(format!("the integer {v} is too large")))
+    }
+    fn serialize_f32(self, v: f32) -> Result<DataValue, SerError> {
+        Ok(DataValue::from(v as f64))
+    }
+    fn serialize_f64(self, v: f64) -> Result<DataValue, SerError> {
+        Ok(DataValue::from(v))
+    }
+    fn serialize_char(self, v: char) -> Result<DataValue, SerError> {
+        Ok(DataValue::from(v.to_string()))
+    }
+    fn serialize_str(self, v: &str) -> Result<DataValue, SerError> {
+        Ok(DataValue::from(v))
+    }
+    fn serialize_bytes(self, v: &[u8]) -> Result<DataValue, SerError> {
+        Ok(DataValue::Bytes(v.to_vec()))
+    }
+    fn serialize_none(self) -> Result<DataValue, SerError> {
+        Ok(DataValue::Null)
+    }
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<DataValue, SerError> {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<DataValue, SerError> {
+        Ok(DataValue::Null)
+    }
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<DataValue, SerError> {
+        Ok(DataValue::Null)
+    }
+    fn serialize_unit_variant(
+        self,
+        name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<DataValue, SerError> {
+        Ok(match (name, variant) {
+            ("DataValue", "Null") => DataValue::Null,
+            _ => DataValue::from(variant),
+        })
+    }
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<DataValue, SerError> {
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<DataValue, SerError> {
+        match name {
+            // the variants of data values hold the values as they are
+            "DataValue" | "Num" => value.serialize(self),
+            _ => Ok(variant_value(variant, value.serialize(self)?)),
+        }
+    }
+    fn serialize_seq(self, len: Option<usize>) -> Result<ListSerializer, SerError> {
+        Ok(ListSerializer::new(len.unwrap_or_default(), None))
+    }
+    fn serialize_tuple(self, len: usize) -> Result<ListSerializer, SerError> {
+        Ok(ListSerializer::new(len, None))
+    }
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<ListSerializer, SerError> {
+        Ok(ListSerializer::new(len, None))
+    }
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<ListSerializer, SerError> {
+        Ok(ListSerializer::new(len, Some(variant)))
+    }
+    fn serialize_map(self, len: Option<usize>) -> Result<MapSerializer, SerError> {
+        Ok(MapSerializer::new(len.unwrap_or_default(), None, false))
+    }
+    fn serialize_struct(self, name: &'static str, len: usize) -> Result<MapSerializer, SerError> {
+        Ok(MapSerializer::new(len, None, name == "Validity"))
+    }
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<MapSerializer, SerError> {
+        Ok(MapSerializer::new(len, Some(variant), false))
+    }
+}
+
+struct ListSerializer {
+    items: Vec<DataValue>,
+    variant: Option<&'static str>,
+}
+
+impl ListSerializer {
+    fn new(len: usize, variant: Option<&'static str>) -> Self {
+        Self {
+            items: Vec::with_capacity(len),
+            variant,
+        }
+    }
+    fn push<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), SerError> {
+        self.items.push(value.serialize(ValueSerializer)?);
+        Ok(())
+    }
+    fn finish(self) -> Result<DataValue, SerError> {
+        let list = DataValue::List(self.items);
+        Ok(match self.variant {
+            None => list,
+            Some(variant) => variant_value(variant, list),
+        })
+    }
+}
+
+impl SerializeSeq for ListSerializer {
+    type Ok = DataValue;
+    type Error = SerError;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), SerError> {
+        self.push(value)
+    }
+    fn end(self) -> Result<DataValue, SerError> {
+        self.finish()
+    }
+}
+
+impl SerializeTuple for ListSerializer {
+    type Ok = DataValue;
+    type Error = SerError;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), SerError> {
+        self.push(value)
+    }
+    fn end(self) -> Result<DataValue, SerError> {
+        self.finish()
+    }
+}
+
+impl SerializeTupleStruct for ListSerializer {
+    type Ok = DataValue;
+    type Error = SerError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), SerError> {
+        self.push(value)
+    }
+    fn end(self) -> Result<DataValue, SerError> {
+        self.finish()
+    }
+}
+
+impl SerializeTupleVariant for ListSerializer {
+    type Ok = DataValue;
+    type Error = SerError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), SerError> {
+        self.push(value)
+    }
+    fn end(self) -> Result<DataValue, SerError> {
+        self.finish()
+    }
+}
+
+struct MapSerializer {
+    entries: Vec<(DataValue, DataValue)>,
+    key: Option<DataValue>,
+    variant: Option<&'static str>,
+    is_validity: bool,
+}
+
+impl MapSerializer {
+    fn new(len: usize, variant: Option<&'static str>, is_validity: bool) -> Self {
+        Self {
+            entries: Vec::with_capacity(len),
+            key: None,
+            variant,
+            is_validity,
+        }
+    }
+    fn push<T: ?Sized + Serialize>(&mut self, key: DataValue, value: &T) -> Result<(), SerError> {
+        self.entries.push((key, value.serialize(ValueSerializer)?));
+        Ok(())
+    }
+    fn finish(self) -> Result<DataValue, SerError> {
+        if self.is_validity {
+            // `[timestamp, is_assert]`, as columns of validities take them
+            return Ok(DataValue::List(
+                self.entries.into_iter().map(|(_, v)| v).collect(),
+            ));
+        }
+        let map = DataValue::List(
+            self.entries
+                .into_iter()
+                .map(|(k, v)| DataValue::List(vec![k, v]))
+                .collect(),
+        );
+        Ok(match self.variant {
+            None => map,
+            Some(variant) => variant_value(variant, map),
+        })
+    }
+}
+
+impl SerializeMap for MapSerializer {
+    type Ok = DataValue;
+    type Error = SerError;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), SerError> {
+        self.key = Some(key.serialize(ValueSerializer)?);
+        Ok(())
+    }
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), SerError> {
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| SerError("value serialized before its key".to_string()))?;
+        self.push(key, value)
+    }
+    fn end(self) -> Result<DataValue, SerError> {
+        self.finish()
+    }
+}
+
+impl SerializeStruct for MapSerializer {
+    type Ok = DataValue;
+    type Error = SerError;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerError> {
+        self.push(DataValue::from(key), value)
+    }
+    fn end(self) -> Result<DataValue, SerError> {
+        self.finish()
+    }
+}
+
+impl SerializeStructVariant for MapSerializer {
+    type Ok = DataValue;
+    type Error = SerError;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerError> {
+        self.push(DataValue::from(key), value)
+    }
+    fn end(self) -> Result<DataValue, SerError> {
+        self.finish()
+    }
+}
+
+/// Serializes structs and maps into their fields, by name
+struct RowSerializer;
+
+fn not_a_row() -> SerError {
+    SerError("rows must be structs or maps".to_string())
+}
+
+impl Serializer for RowSerializer {
+    type Ok = Vec<(String, DataValue)>;
+    type Error = SerError;
+    type SerializeSeq = Impossible<Self::Ok, SerError>;
+    type SerializeTuple = Impossible<Self::Ok, SerError>;
+    type SerializeTupleStruct = Impossible<Self::Ok, SerError>;
+    type SerializeTupleVariant = Impossible<Self::Ok, SerError>;
+    type SerializeMap = RowFieldsSerializer;
+    type SerializeStruct = RowFieldsSerializer;
+    type SerializeStructVariant = Impossible<Self::Ok, SerError>;
+
+    fn serialize_bool(self, _v: bool) -> Result<Self::Ok, SerError> {
+        Err(not_a_row())
+    }
+    fn serialize_i8(self, _v: i8) -> Result<Self::Ok, SerError> {
+        Err(not_a_row())
+    }
+    fn serialize_i16(self, _v: i16) -> Result<Self::Ok, SerError> {
+        Err(not_a_row())
+    }
+    fn serialize_i32(self, _v: i32) -> Result<Self::Ok, SerError> {
+        Err(not_a_row())
+    }
+    fn serialize_i64(self, _v: i64) -> Result<Self::Ok, SerError> {
+        Err(not_a_row())
+    }
+    fn serialize_u8(self, _v: u8) -> Result<Self::Ok, SerError> {
+        Err(not_a_row())
+    }
+    fn serialize_u16(self, _v: u16) -> Result<Self::Ok, SerError> {
+        Err(not_a_row())
+    }
+    fn serialize_u32(self, _v: u32) -> Result<Self::Ok, SerError> {
+        Err(not_a_row())
+    }
+    fn serialize_u64(self, _v: u64) -> Result<Self::Ok, SerError> {
+        Err(not_a_row())
+    }
+    fn serialize_f32(self, _v: f32) -> Result<Self::Ok, SerError> {
+        Err(not_a_row())
+    }
+    fn serialize_f64(self, _v: f64) -> Result<Self::Ok, SerError> {
+        Err(not_a_row())
+    }
+    fn serialize_char(self, _v: char) -> Result<Self::Ok, SerError> {
+        Err(not_a_row())
+    }
+    fn serialize_str(self, _v: &str) -> Result<Self::Ok, SerError> {
+        Err(not_a_row())
+    }
+    fn serialize_bytes(self, _v: &[u8]) -> Result<Self::Ok, SerError> {
+        Err(not_a_row())
+    }
+    fn serialize_none(self) -> Result<Self::Ok, SerError> {
+        Err(not_a_row())
+    }
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Self::Ok, SerError> {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<Self::Ok, SerError> {
+        Err(not_a_row())
+    }
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, SerError> {
+        Err(not_a_row())
+    }
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+    ) -> Result<Self::Ok, SerError> {
+        Err(not_a_row())
+    }
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, SerError> {
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<Self::Ok, SerError> {
+        Err(not_a_row())
+    }
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, SerError> {
+        Err(not_a_row())
+    }
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, SerError> {
+        Err(not_a_row())
+    }
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, SerError> {
+        Err(not_a_row())
+    }
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, SerError> {
+        Err(not_a_row())
+    }
+    fn serialize_map(self, len: Option<usize>) -> Result<RowFieldsSerializer, SerError> {
+        Ok(RowFieldsSerializer(MapSerializer::new(
+            len.unwrap_or_default(),
+            None,
+            false,
+        )))
+    }
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<RowFieldsSerializer, SerError> {
+        Ok(RowFieldsSerializer(MapSerializer::new(len, None, false)))
+    }
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, SerError> {
+        Err(not_a_row())
+    }
+}
+
+struct RowFieldsSerializer(MapSerializer);
+
+impl RowFieldsSerializer {
+    fn finish(self) -> Result<Vec<(String, DataValue)>, SerError> {
+        self.0
+            .entries
+            .into_iter()
+            .map(|(k, v)| match k {
+                DataValue::Str(name) => Ok((name.to_string(), v)),
+                k => Err(SerError(format!(
+                    "the field {k:?} is not named by a string"
+                ))),
+            })
+            .collect()
+    }
+}
+
+impl SerializeMap for RowFieldsSerializer {
+    type Ok = Vec<(String, DataValue)>;
+    type Error = SerError;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), SerError> {
+        self.0.serialize_key(key)
+    }
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), SerError> {
+        self.0.serialize_value(value)
+    }
+    fn end(self) -> Result<Self::Ok, SerError> {
+        self.finish()
+    }
+}
+
+impl SerializeStruct for RowFieldsSerializer {
+    type Ok = Vec<(String, DataValue)>;
+    type Error = SerError;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerError> {
+        self.0.push(DataValue::from(key), value)
+    }
+    fn end(self) -> Result<Self::Ok, SerError> {
+        self.finish()
+    }
+}
+
+/// Options for [Db::put_rows_with_options]
+#[derive(Clone, Debug, Default)]
+pub struct PutRowsOptions {
+    /// Leave out fields that are not columns of the relation, instead of raising an error
+    pub ignore_extra_fields: bool,
+}
+
+impl<'s, S: Storage<'s>> Db<S> {
+    /// Put the `rows` into the stored `relation` in a single transaction, as `:put` does.
+    /// Each row must serialize into a struct or a map, whose fields are put into the columns
+    /// of the same names, coerced into the types of the columns. Columns with defaults may be
+    /// left out, but only from every row. Values are serialized as they would be converted
+    /// from JSON, except that bytes, e.g. with `serde_bytes`, stay bytes, and [DataValue]s and
+    /// [Validity]s are kept as they are.
+    pub fn put_rows<T: Serialize>(
+        &'s self,
+        relation: &str,
+        rows: impl IntoIterator<Item = T>,
+    ) -> Result<()> {
+        self.put_rows_with_options(relation, rows, Default::default())
+    }
+    /// Put the `rows` into the stored `relation` as [Self::put_rows] does,
+    /// dealing with fields that are not columns as specified by `options`.
+    pub fn put_rows_with_options<T: Serialize>(
+        &'s self,
+        relation: &str,
+        rows: impl IntoIterator<Item = T>,
+        options: PutRowsOptions,
+    ) -> Result<()> {
+        let (rows, n_keys) = self
+            .serialize_rows(relation, rows, &options)
+            .map_err(CozoError::wrap)?;
+        if rows.rows.is_empty() {
+            return Ok(());
+        }
+        let cols = rows.headers.join(", ");
+        let bindings = if n_keys == rows.headers.len() {
+            cols.clone()
+        } else {
+            let keys = rows.headers[..n_keys].join(", ");
+            let non_keys = rows.headers[n_keys..].join(", ");
+            format!("{keys} => {non_keys}")
+        };
+        let script = format!("?[{cols}] := *_put_rows[{cols}] :put {relation} {{{bindings}}}");
+        let relations = BTreeMap::from([("_put_rows".to_string(), rows)]);
+        self.run_script_with_relations(&script, Default::default(), relations)?;
+        Ok(())
+    }
+
+    /// The rows serialized, with the columns of the relation they give,
+    /// coerced into the types of the columns, and how many of these columns are keys
+    fn serialize_rows<T: Serialize>(
+        &'s self,
+        relation: &str,
+        rows: impl IntoIterator<Item = T>,
+        options: &PutRowsOptions,
+    ) -> Result<(NamedRows, usize)> {
+        let cur_vld = self.clock.current_validity();
+        let (columns, n_keys) = {
+            let tx = self.transact()?;
+            let handle = tx.get_relation(relation, false)?;
+            let metadata = handle.metadata;
+            let n_keys = metadata.keys.len();
+            let columns = metadata
+                .keys
+                .into_iter()
+                .chain(metadata.non_keys)
+                .collect_vec();
+            (columns, n_keys)
+        };
+        let mut serialized = vec![];
+        for (i, row) in rows.into_iter().enumerate() {
+            let fields = row
+                .serialize(RowSerializer)
+                .map_err(|err| BadRow(i, relation.to_string(), err.0))?;
+            let mut by_name = BTreeMap::new();
+            for (name, val) in fields {
+                if columns.iter().any(|col| col.name.as_str() == name) {
+                    by_name.insert(name, val);
+                } else if !options.ignore_extra_fields {
+                    bail!(ExtraField(i, relation.to_string(), name))
+                }
+            }
+            serialized.push(by_name);
+        }
+        let is_given = |col: &&ColumnDef| {
+            col.default_gen.is_none()
+                || serialized
+                    .iter()
+                    .any(|row| row.contains_key(col.name.as_str()))
+        };
+        let n_keys = columns[..n_keys].iter().filter(is_given).count();
+        let given = columns.iter().filter(is_given).collect_vec();
+        let mut tuples = Vec::with_capacity(serialized.len());
+        for (i, mut fields) in serialized.into_iter().enumerate() {
+            let tuple: Vec<_> = given
+                .iter()
+                .map(|col| -> Result<DataValue> {
+                    let val = fields.remove(col.name.as_str()).ok_or_else(|| {
+                        MissingField(i, relation.to_string(), col.name.to_string())
+                    })?;
+                    col.typing.coerce(val, cur_vld).wrap_err_with(|| {
+                        format!("when putting the field '{}' of row {i}", col.name)
+                    })
+                })
+                .try_collect()?;
+            tuples.push(tuple);
+        }
+        let headers = given.iter().map(|col| col.name.to_string()).collect();
+        Ok((NamedRows::new(headers, tuples), n_keys))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+    use serde_json::json;
+
+    use crate::data::value::{DataValue, ValidityTs};
+    use crate::new_cozo_mem;
+
+    #[test]
+    fn test_deserialize_rows() {
+        #[derive(Debug, PartialEq, serde_derive::Deserialize)]
+        #[serde(rename_all = "snake_case")]
+        enum Role {
+            Admin,
+            Guest,
+        }
+
+        #[derive(Debug, PartialEq, serde_derive::Deserialize)]
+        struct User {
+            id: u32,
+            name: String,
+            role: Role,
+            score: Option<f64>,
+            tags: Vec<DataValue>,
+            extra: serde_json::Value,
+        }
+
+        let db = new_cozo_mem().unwrap();
+        let script = r"
+        ?[id, name, role, score, tags, extra, ignored] <- [
+            [1, 'alice', 'admin', 1, ['a', 1], [1, [2, 3]], true],
+            [2, 'bob', 'guest', null, [], null, false]
+        ]
+        ";
+        let users: Vec<User> = db.run_typed(script, Default::default()).unwrap();
+        assert_eq!(
+            users,
+            vec![
+                User {
+                    id: 1,
+                    name: "alice".to_string(),
+                    role: Role::Admin,
+                    score: Some(1.0),
+                    tags: vec![DataValue::from("a"), DataValue::from(1)],
+                    extra: json!([1, [2, 3]]),
+                },
+                User {
+                    id: 2,
+                    name: "bob".to_string(),
+                    role: Role::Guest,
+                    score: None,
+                    tags: vec![],
+                    extra: json!(null),
+                }
+            ]
+        );
+
+        // borrowing from the rows, and rows as tuples
+        let res = db.run_script(script, Default::default()).unwrap();
+        #[derive(serde_derive::Deserialize)]
+        struct Name<'a> {
+            name: &'a str,
+        }
+        let names: Vec<Name<'_>> = res.deserialize_rows_ref().unwrap();
+        assert_eq!(names.iter().map(|n| n.name).collect_vec(), ["alice", "bob"]);
+        let ids: Vec<(i64, String)> = res.deserialize_rows().unwrap();
+        assert_eq!(ids[1], (2, "bob".to_string()));
+
+        // missing columns
+        #[derive(Debug, serde_derive::Deserialize)]
+        struct WithAge {
+            #[allow(dead_code)]
+            age: i64,
+        }
+        let err = res.deserialize_rows::<WithAge>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot deserialize row 0: column 'age': there is no such column in the results"
+        );
+
+        // extra columns are errors only if denied
+        #[derive(Debug, serde_derive::Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct OnlyId {
+            #[allow(dead_code)]
+            id: i64,
+        }
+        let err = res.deserialize_rows::<OnlyId>().unwrap_err();
+        assert!(err.to_string().contains("unknown field `name`"), "{err}");
+
+        // wrong types name the column, the expected and the actual type
+        #[derive(Debug, serde_derive::Deserialize)]
+        struct BadName {
+            #[allow(dead_code)]
+            name: i64,
+        }
+        let err = res.deserialize_rows::<BadName>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot deserialize row 0: column 'name': invalid type: string \"alice\", expected i64"
+        );
+        let err = db
+            .run_typed::<User>(
+                "?[id, name, role, score, tags, extra] <- [[1, 'a', 'root', null, [], null]]",
+                Default::default(),
+            )
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("column 'role': unknown variant `root`"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_put_rows() {
+        use crate::data::value::Validity;
+        use crate::PutRowsOptions;
+        use std::cmp::Reverse;
+
+        #[derive(serde_derive::Serialize)]
+        struct Doc {
+            id: uuid::Uuid,
+            at: Validity,
+            #[serde(with = "serde_bytes")]
+            body: Vec<u8>,
+            matrix: Vec<Vec<i64>>,
+            note: Option<String>,
+        }
+
+        let db = new_cozo_mem().unwrap();
+        db.run_script(
+            r"
+        :create docs {
+            id: Uuid, at: Validity =>
+            body: Bytes, matrix: [[Int]], note: String?, version: Int default 1
+        }
+        ",
+            Default::default(),
+        )
+        .unwrap();
+        let at = |ts| Validity {
+            timestamp: ValidityTs(Reverse(ts)),
+            is_assert: Reverse(true),
+        };
+        let docs = vec![
+            Doc {
+                id: uuid::Uuid::from_u128(1),
+                at: at(1000),
+                body: vec![1, 2, 3],
+                matrix: vec![vec![1, 2], vec![]],
+                note: None,
+            },
+            Doc {
+                id: uuid::Uuid::from_u128(2),
+                at: at(2000),
+                body: vec![],
+                matrix: vec![vec![3]],
+                note: Some("second".to_string()),
+            },
+        ];
+        db.put_rows("docs", &docs).unwrap();
+        let res = db
+        .run_script(
+            "?[id, at, body, matrix, note, version] := *docs{id, at, body, matrix, note, version}",
+            Default::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            res.into_json()["rows"],
+            json!([
+                [
+                    "00000000-0000-0000-0000-000000000001",
+                    [1000, true],
+                    "AQID",
+                    [[1, 2], []],
+                    null,
+                    1
+                ],
+                [
+                    "00000000-0000-0000-0000-000000000002",
+                    [2000, true],
+                    "",
+                    [[3]],
+                    "second",
+                    1
+                ]
+            ])
+        );
+
+        // columns without defaults must be given
+        #[derive(serde_derive::Serialize)]
+        struct NoBody {
+            id: uuid::Uuid,
+            at: Validity,
+        }
+        let err = db
+            .put_rows(
+                "docs",
+                [NoBody {
+                    id: uuid::Uuid::from_u128(3),
+                    at: at(3000),
+                }],
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Row 0 for relation 'docs' has no field for the column 'body'"
+        );
+
+        // fields that are not columns are errors, unless ignored
+        #[derive(serde_derive::Serialize)]
+        struct WithExtra {
+            id: String,
+            at: String,
+            body: String,
+            matrix: Vec<DataValue>,
+            note: (),
+            extra: bool,
+        }
+        let row = WithExtra {
+            id: uuid::Uuid::from_u128(3).to_string(),
+            at: "ASSERT".to_string(),
+            body: "AQID".to_string(),
+            matrix: vec![],
+            note: (),
+            extra: true,
+        };
+        let err = db.put_rows("docs", [&row]).unwrap_err();
+        assert_eq!(
+        err.to_string(),
+        "Row 0 for relation 'docs' has the field 'extra', which is not a column of the relation"
+    );
+        db.put_rows_with_options(
+            "docs",
+            [&row],
+            PutRowsOptions {
+                ignore_extra_fields: true,
+            },
+        )
+        .unwrap();
+        let res = db
+            .run_script("?[count(id)] := *docs{id}", Default::default())
+            .unwrap();
+        assert_eq!(res.rows[0][0], DataValue::from(3));
+
+        // values not coercing into the types of the columns are errors naming the field
+        #[derive(serde_derive::Serialize)]
+        struct BadMatrix {
+            id: uuid::Uuid,
+            at: Validity,
+            body: serde_bytes::ByteBuf,
+            matrix: Vec<Vec<String>>,
+        }
+        let err = db
+            .put_rows(
+                "docs",
+                [BadMatrix {
+                    id: uuid::Uuid::from_u128(4),
+                    at: at(4000),
+                    body: serde_bytes::ByteBuf::new(),
+                    matrix: vec![vec!["x".to_string()]],
+                }],
+            )
+            .unwrap_err();
+        assert_eq!(err.to_string(), "when putting the field 'matrix' of row 0");
+    }
+}
@@ -0,0 +1,356 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use miette::Result;
+
+use crate::data::tuple::Tuple;
+use crate::data::value::ValidityTs;
+use crate::runtime::transact::SessionTx;
+use crate::storage::temp::TempStorage;
+use crate::storage::{Storage, StoreTx};
+
+/// Resources used by a query, reported for queries with the `:report_usage` option,
+/// see [NamedRows::usage](crate::NamedRows::usage).
+#[derive(Clone, Debug, Default, Eq, PartialEq, serde_derive::Serialize)]
+pub struct QueryUsage {
+    /// The numbers of rows read by scans of stored relations and indices, by relation
+    pub rows_scanned: BTreeMap<String, usize>,
+    /// The approximate peak number of bytes of rows held in memory for sorting
+    /// or as the result
+    pub peak_memory_bytes: usize,
+    /// The number of keys looked up in the storage
+    pub storage_reads: usize,
+    /// The number of range scans of the storage
+    pub storage_scans: usize,
+    /// The number of keys written into or deleted from the storage
+    pub storage_writes: usize,
+    /// The number of point lookups retried after transient errors
+    pub retries: usize,
+    /// The number of sorted runs spilled to disk
+    pub spills: usize,
+}
+
+/// Counts the operations on the storage of a transaction, see [CountingTx]
+#[derive(Default)]
+pub(crate) struct StorageCounters {
+    reads: AtomicUsize,
+    scans: AtomicUsize,
+    writes: AtomicUsize,
+    /// rows read by scans, by the id of the relation scanned
+    rows_scanned: Mutex<BTreeMap<u64, usize>>,
+}
+
+/// Wraps the storage transaction to count the operations on it. Only installed for
+/// transactions running queries that report their usage, so that others pay nothing.
+struct CountingTx<'s> {
+    inner: Box<dyn StoreTx<'s> + 's>,
+    counters: Arc<StorageCounters>,
+}
+
+/// Counts the rows of a scan, adding them to the counters when done
+struct CountedRows<'a, I> {
+    inner: I,
+    relation: Option<u64>,
+    n_rows: usize,
+    counters: &'a StorageCounters,
+}
+
+impl<I: Iterator> Iterator for CountedRows<'_, I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let ret = self.inner.next();
+        if ret.is_some() {
+            self.n_rows += 1;
+        }
+        ret
+    }
+}
+
+impl<I> Drop for CountedRows<'_, I> {
+    fn drop(&mut self) {
+        if let Some(relation) = self.relation {
+            *self
+                .counters
+                .rows_scanned
+                .lock()
+                .unwrap()
+                .entry(relation)
+                .or_default() += self.n_rows;
+        }
+    }
+}
+
+impl<'s> CountingTx<'s> {
+    fn count_scan<'a, I: Iterator + 'a>(
+        &'a self,
+        lower: &[u8],
+        inner: I,
+    ) -> Box<dyn Iterator<Item = I::Item> + 'a> {
+        self.counters.scans.fetch_add(1, Ordering::Relaxed);
+        // keys start with the id of the relation
+        let relation = lower
+            .get(..8)
+            .map(|prefix| u64::from_be_bytes(prefix.try_into().unwrap()));
+        Box::new(CountedRows {
+            inner,
+            relation,
+            n_rows: 0,
+            counters: &self.counters,
+        })
+    }
+}
+
+impl<'s> StoreTx<'s> for CountingTx<'s> {
+    fn get(&self, key: &[u8], for_update: bool) -> Result<Option<Vec<u8>>> {
+        self.counters.reads.fetch_add(1, Ordering::Relaxed);
+        self.inner.get(key, for_update)
+    }
+
+    fn multi_get(&self, keys: &[&[u8]], for_update: bool) -> Result<Vec<Option<Vec<u8>>>> {
+        self.counters.reads.fetch_add(keys.len(), Ordering::Relaxed);
+        self.inner.multi_get(keys, for_update)
+    }
+
+    fn put(&mut self, key: &[u8], val: &[u8]) -> Result<()> {
+        self.counters.writes.fetch_add(1, Ordering::Relaxed);
+        self.inner.put(key, val)
+    }
+
+    fn supports_par_put(&self) -> bool {
+        self.inner.supports_par_put()
+    }
+
+    fn par_put(&self, key: &[u8], val: &[u8]) -> Result<()> {
+        self.counters.writes.fetch_add(1, Ordering::Relaxed);
+        self.inner.par_put(key, val)
+    }
+
+    fn del(&mut self, key: &[u8]) -> Result<()> {
+        self.counters.writes.fetch_add(1, Ordering::Relaxed);
+        self.inner.del(key)
+    }
+
+    fn exists(&self, key: &[u8], for_update: bool) -> Result<bool> {
+        self.counters.reads.fetch_add(1, Ordering::Relaxed);
+        self.inner.exists(key, for_update)
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        self.inner.commit()
+    }
+
+    fn range_scan_tuple<'a>(
+        &'a self,
+        lower: &[u8],
+        upper: &[u8],
+    ) -> Box<dyn Iterator<Item = Result<Tuple>> + 'a>
+    where
+        's: 'a,
+    {
+        self.count_scan(lower, self.inner.range_scan_tuple(lower, upper))
+    }
+
+    fn range_skip_scan_tuple<'a>(
+        &'a self,
+        lower: &[u8],
+        upper: &[u8],
+        valid_at: ValidityTs,
+    ) -> Box<dyn Iterator<Item = Result<Tuple>> + 'a> {
+        self.count_scan(
+            lower,
+            self.inner.range_skip_scan_tuple(lower, upper, valid_at),
+        )
+    }
+
+    fn range_skip_scan_tuple_window<'a>(
+        &'a self,
+        lower: &[u8],
+        upper: &[u8],
+        valid_from: ValidityTs,
+        valid_to: ValidityTs,
+    ) -> Box<dyn Iterator<Item = Result<Tuple>> + 'a>
+    where
+        's: 'a,
+    {
+        self.count_scan(
+            lower,
+            self.inner
+                .range_skip_scan_tuple_window(lower, upper, valid_from, valid_to),
+        )
+    }
+
+    fn range_scan<'a>(
+        &'a self,
+        lower: &[u8],
+        upper: &[u8],
+    ) -> Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>
+    where
+        's: 'a,
+    {
+        self.count_scan(lower, self.inner.range_scan(lower, upper))
+    }
+
+    fn total_scan<'a>(&'a self) -> Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>
+    where
+        's: 'a,
+    {
+        self.count_scan(&[], self.inner.total_scan())
+    }
+}
+
+/// The counters when a query reporting its usage started
+pub(crate) struct UsageStart {
+    reads: usize,
+    scans: usize,
+    writes: usize,
+    rows_scanned: BTreeMap<u64, usize>,
+    retries: usize,
+    spills: usize,
+    peak_memory_bytes: usize,
+}
+
+impl<'a> SessionTx<'a> {
+    /// Starts counting the usage of resources by the transaction, if not counting already,
+    /// and returns the current counts.
+    pub(crate) fn start_usage_report(&mut self) -> Result<UsageStart> {
+        let counters = match &self.storage_counters {
+            Some(counters) => counters.clone(),
+            None => {
+                let counters = Arc::new(StorageCounters::default());
+                let placeholder = Box::new(TempStorage.transact(false)?);
+                let inner = std::mem::replace(&mut self.store_tx, placeholder);
+                self.store_tx = Box::new(CountingTx {
+                    inner,
+                    counters: counters.clone(),
+                });
+                self.storage_counters = Some(counters.clone());
+                counters
+            }
+        };
+        let ret = UsageStart {
+            reads: counters.reads.load(Ordering::Relaxed),
+            scans: counters.scans.load(Ordering::Relaxed),
+            writes: counters.writes.load(Ordering::Relaxed),
+            rows_scanned: counters.rows_scanned.lock().unwrap().clone(),
+            retries: self.lookup_retry_count.load(Ordering::Relaxed),
+            spills: self.spilled_sort_runs.load(Ordering::Relaxed),
+            peak_memory_bytes: self.peak_memory_bytes.swap(0, Ordering::Relaxed),
+        };
+        Ok(ret)
+    }
+    /// The usage of resources since [Self::start_usage_report] returned `start`
+    pub(crate) fn finish_usage_report(&self, start: UsageStart) -> Result<QueryUsage> {
+        let counters = self
+            .storage_counters
+            .as_ref()
+            .expect("usage report not started");
+        let peak_memory_bytes = self.peak_memory_bytes.load(Ordering::Relaxed);
+        self.peak_memory_bytes
+            .fetch_max(start.peak_memory_bytes, Ordering::Relaxed);
+        let mut ret = QueryUsage {
+            rows_scanned: Default::default(),
+            peak_memory_bytes,
+            storage_reads: counters.reads.load(Ordering::Relaxed) - start.reads,
+            storage_scans: counters.scans.load(Ordering::Relaxed) - start.scans,
+            storage_writes: counters.writes.load(Ordering::Relaxed) - start.writes,
+            retries: self.lookup_retry_count.load(Ordering::Relaxed) - start.retries,
+            spills: self.spilled_sort_runs.load(Ordering::Relaxed) - start.spills,
+        };
+        let rows_scanned = counters.rows_scanned.lock().unwrap().clone();
+        let catalog = self.relation_catalog()?;
+        for handle in catalog.values() {
+            let n_rows = rows_scanned.get(&handle.id.0).copied().unwrap_or(0)
+                - start.rows_scanned.get(&handle.id.0).copied().unwrap_or(0);
+            if n_rows > 0 {
+                ret.rows_scanned.insert(handle.name.to_string(), n_rows);
+            }
+        }
+        Ok(ret)
+    }
+    /// Records that about `bytes` of rows are held in memory
+    pub(crate) fn track_memory(&self, bytes: usize) {
+        self.peak_memory_bytes.fetch_max(bytes, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::new_cozo_mem;
+
+    #[test]
+    fn test_report_usage() {
+        let db = new_cozo_mem().unwrap();
+        db.run_script(
+            r"
+        {?[k, v] := k in int_range(10), v = k * 2 :create a {k => v}}
+        {?[k, w] := k in int_range(5), w = k * 3 :create b {k => w}}
+        ",
+            Default::default(),
+        )
+        .unwrap();
+
+        let join = "?[k, v, w] := *a{k, v}, *b{k, w}";
+        let res = db.run_script(join, Default::default()).unwrap();
+        assert!(res.usage().is_none());
+        assert!(res.into_json().get("usage").is_none());
+
+        let res = db
+            .run_script(
+                &format!("{join} :order -v :report_usage"),
+                Default::default(),
+            )
+            .unwrap();
+        assert_eq!(res.rows.len(), 5);
+        let usage = res.usage().unwrap().clone();
+        let scanned = |rel: &str| usage.rows_scanned.get(rel).copied().unwrap_or(0);
+        // whichever relation drives the join, the other one is only read where keys match
+        assert!(scanned("a") <= 10);
+        assert!(scanned("b") <= 5);
+        assert!(scanned("a") + scanned("b") >= 10);
+        assert!(usage.storage_scans > 0);
+        assert_eq!(usage.storage_writes, 0);
+        assert_eq!(usage.retries, 0);
+        assert_eq!(usage.spills, 0);
+        assert!(usage.peak_memory_bytes > 0);
+        let json = res.into_json();
+        assert!(json["usage"]["rows_scanned"].is_object());
+        assert_eq!(json["usage"]["storage_writes"], json!(0));
+
+        let res = db
+            .run_script(
+                "?[k, v] := k in int_range(20, 23), v = 0 :put a {k => v} :report_usage",
+                Default::default(),
+            )
+            .unwrap();
+        let usage = res.usage().unwrap();
+        assert!(usage.storage_writes >= 3);
+        assert_eq!(usage.spills, 0);
+
+        // the counts are for each query only
+        let alone = db
+            .run_script(&format!("{join} :report_usage"), Default::default())
+            .unwrap();
+        let res = db
+            .run_script(
+                &format!("{{?[k] := *b{{k}} :report_usage}} {{{join} :report_usage}}"),
+                Default::default(),
+            )
+            .unwrap();
+        assert_eq!(
+            res.usage().unwrap().rows_scanned,
+            alone.usage().unwrap().rows_scanned
+        );
+    }
+}
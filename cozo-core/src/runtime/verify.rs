@@ -0,0 +1,284 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// only backups in Sqlite files are verified
+#![cfg_attr(not(feature = "storage-sqlite"), allow(dead_code))]
+
+use std::cmp::Reverse;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+
+use miette::Result;
+use rand::prelude::*;
+use smartstring::{LazyCompact, SmartString};
+
+use crate::data::relation::ColType;
+use crate::data::tuple::{Tuple, TupleT};
+use crate::data::value::{DataValue, ValidityTs, LARGEST_UTF_CHAR};
+use crate::runtime::relation::{RelationHandle, RelationId};
+use crate::runtime::transact::SessionTx;
+use crate::NamedRows;
+
+/// Options for [crate::Db::verify_backup]
+#[derive(Clone, Debug, Default, serde_derive::Deserialize)]
+pub struct VerifyBackupOptions {
+    /// If given, relations whose last key column is a validity are compared as of this
+    /// timestamp, in microseconds since the epoch. Otherwise their whole histories are compared.
+    #[serde(default)]
+    pub valid_at: Option<i64>,
+    /// The number of random rows of each relation that are also looked up in the backup
+    /// and compared in full
+    #[serde(default)]
+    pub sample: usize,
+}
+
+/// The digest of a row. The `xor` of the digests of the rows of a relation
+/// does not depend on the order in which they are read.
+fn row_digest(row: &[DataValue]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    row.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The rows of a relation, summarized
+struct RelationSummary {
+    n_rows: usize,
+    /// the `xor` of the [row_digest] of all rows
+    digest: u64,
+    /// random rows, to be compared in full
+    sample: Vec<Tuple>,
+}
+
+impl<'a> SessionTx<'a> {
+    /// All stored relations and indices, by name
+    pub(crate) fn relation_catalog(
+        &self,
+    ) -> Result<BTreeMap<SmartString<LazyCompact>, RelationHandle>> {
+        let lower = vec![DataValue::from("")].encode_as_key(RelationId::SYSTEM);
+        let upper =
+            vec![DataValue::from(String::from(LARGEST_UTF_CHAR))].encode_as_key(RelationId::SYSTEM);
+        let mut ret = BTreeMap::new();
+        for kv_res in self.store_tx.range_scan(&lower, &upper) {
+            let (k_slice, v_slice) = kv_res?;
+            if upper <= k_slice {
+                break;
+            }
+            let handle = RelationHandle::decode(&v_slice)?;
+            ret.insert(handle.name.clone(), handle);
+        }
+        Ok(ret)
+    }
+    /// Reads through the relation, keeping only the count, the digest and a sample of its rows.
+    fn summarize_relation(
+        &self,
+        handle: &RelationHandle,
+        valid_at: Option<ValidityTs>,
+        sample_size: usize,
+        rng: &mut impl Rng,
+    ) -> Result<RelationSummary> {
+        let has_validity = matches!(
+            handle.metadata.keys.last(),
+            Some(col) if col.typing.coltype == ColType::Validity
+        );
+        let rows: Box<dyn Iterator<Item = Result<Tuple>> + '_> = match valid_at {
+            Some(valid_at) if has_validity => Box::new(handle.skip_scan_all(self, valid_at)),
+            _ => Box::new(handle.scan_all(self)),
+        };
+        let mut ret = RelationSummary {
+            n_rows: 0,
+            digest: 0,
+            sample: vec![],
+        };
+        for row in rows {
+            let row = row?;
+            ret.digest ^= row_digest(&row);
+            ret.n_rows += 1;
+            // reservoir sampling
+            if ret.sample.len() < sample_size {
+                ret.sample.push(row);
+            } else {
+                let i = rng.gen_range(0..ret.n_rows);
+                if i < sample_size {
+                    ret.sample[i] = row;
+                }
+            }
+        }
+        Ok(ret)
+    }
+}
+
+/// Compares the relations of the live database with those of the backup.
+/// Returns a row for each relation in either of them.
+pub(crate) fn compare_with_backup(
+    tx: &SessionTx<'_>,
+    backup_tx: &SessionTx<'_>,
+    options: &VerifyBackupOptions,
+) -> Result<NamedRows> {
+    let valid_at = options.valid_at.map(|ts| ValidityTs(Reverse(ts)));
+    let mut rng = thread_rng();
+    let live = tx.relation_catalog()?;
+    let mut backup = backup_tx.relation_catalog()?;
+    let mut rows = vec![];
+    for (name, handle) in live {
+        let summary = tx.summarize_relation(&handle, valid_at, options.sample, &mut rng)?;
+        let backup_handle = match backup.remove(&name) {
+            None => {
+                rows.push(vec![
+                    DataValue::from(&name as &str),
+                    DataValue::from(false),
+                    DataValue::from(summary.n_rows as i64),
+                    DataValue::Null,
+                    DataValue::from("not in backup"),
+                ]);
+                continue;
+            }
+            Some(h) => h,
+        };
+        let backup_summary = backup_tx.summarize_relation(&backup_handle, valid_at, 0, &mut rng)?;
+        let notice = if handle.metadata != backup_handle.metadata {
+            Some("columns differ")
+        } else if summary.n_rows != backup_summary.n_rows {
+            Some("row counts differ")
+        } else if summary.digest != backup_summary.digest {
+            Some("rows differ")
+        } else {
+            let n_keys = backup_handle.metadata.keys.len();
+            let mut sample_differs = false;
+            for row in &summary.sample {
+                if backup_handle.get(backup_tx, &row[..n_keys])?.as_ref() != Some(row) {
+                    sample_differs = true;
+                    break;
+                }
+            }
+            sample_differs.then_some("sampled rows differ")
+        };
+        rows.push(vec![
+            DataValue::from(&name as &str),
+            DataValue::from(notice.is_none()),
+            DataValue::from(summary.n_rows as i64),
+            DataValue::from(backup_summary.n_rows as i64),
+            notice.map_or(DataValue::Null, DataValue::from),
+        ]);
+    }
+    for (name, handle) in backup {
+        let summary = backup_tx.summarize_relation(&handle, valid_at, 0, &mut rng)?;
+        rows.push(vec![
+            DataValue::from(&name as &str),
+            DataValue::from(false),
+            DataValue::Null,
+            DataValue::from(summary.n_rows as i64),
+            DataValue::from("not in database"),
+        ]);
+    }
+    rows.sort();
+    Ok(NamedRows::new(
+        vec![
+            "relation".to_string(),
+            "matches".to_string(),
+            "rows".to_string(),
+            "backup_rows".to_string(),
+            "notice".to_string(),
+        ],
+        rows,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::data::value::DataValue;
+    use crate::{new_cozo_mem, DbInstance};
+
+    #[cfg(feature = "storage-sqlite")]
+    #[test]
+    fn test_verify_backup() {
+        use crate::VerifyBackupOptions;
+
+        let path = std::env::temp_dir().join(format!("cozo-test-{}.db", rand::random::<u64>()));
+        let db = new_cozo_mem().unwrap();
+        db.run_script(
+        r"
+        {?[k, v] := k in int_range(100), v = k * 2 :create nums {k => v}}
+        {?[k, at, v] <- [[1, [1000, true], 'x'], [2, [1000, true], 'y']] :create hist {k, at: Validity => v}}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+        db.run_script("::index create nums:by_v {v}", Default::default())
+            .unwrap();
+        db.backup_db(&path).unwrap();
+
+        let verify = |options: VerifyBackupOptions| -> Vec<Vec<DataValue>> {
+            db.verify_backup(&path, options).unwrap().rows
+        };
+        let row = |name: &str, matches: bool, rows: i64, notice: DataValue| {
+            vec![
+                DataValue::from(name),
+                DataValue::from(matches),
+                DataValue::from(rows),
+                DataValue::from(rows),
+                notice,
+            ]
+        };
+        assert_eq!(
+            verify(VerifyBackupOptions {
+                sample: 10,
+                ..Default::default()
+            }),
+            vec![
+                row("hist", true, 2, DataValue::Null),
+                row("nums", true, 100, DataValue::Null),
+                row("nums:by_v", true, 100, DataValue::Null),
+            ]
+        );
+
+        db.run_script("?[k, v] <- [[7, 0]] :put nums {k => v}", Default::default())
+            .unwrap();
+        db.run_script(
+            "?[k, at, v] <- [[1, [2000, true], 'z']] :put hist {k, at => v}",
+            Default::default(),
+        )
+        .unwrap();
+        let rows = verify(Default::default());
+        assert_eq!(rows[0][4], DataValue::from("row counts differ"));
+        assert_eq!(
+            rows[1],
+            row("nums", false, 100, DataValue::from("rows differ"))
+        );
+        assert_eq!(
+            rows[2],
+            row("nums:by_v", false, 100, DataValue::from("rows differ"))
+        );
+        // as of before the change, the history is the same
+        let rows = verify(VerifyBackupOptions {
+            valid_at: Some(1500),
+            ..Default::default()
+        });
+        assert_eq!(rows[0], row("hist", true, 2, DataValue::Null));
+
+        db.run_script(":create extra {k}", Default::default())
+            .unwrap();
+        let res: serde_json::Value =
+            serde_json::from_str(&DbInstance::Mem(db.clone()).verify_backup_str(
+                &json!({"path": path.to_str().unwrap(), "sample": 5}).to_string(),
+            ))
+            .unwrap();
+        assert_eq!(res["ok"], json!(true));
+        assert_eq!(
+            res["rows"][0],
+            json!(["extra", false, 0, null, "not in backup"])
+        );
+
+        let missing = std::env::temp_dir().join(format!("cozo-test-{}.db", rand::random::<u64>()));
+        assert!(db.verify_backup(&missing, Default::default()).is_err());
+        assert!(!missing.exists());
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,441 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Building CozoScript from values that cannot be passed as parameters, e.g. relation names.
+//!
+//! Values are written as CozoScript expressions evaluating to them, and identifiers are checked
+//! to be valid, so that nothing embedded this way can change the structure of the script:
+//!
+//! ```
+//! use cozo::script::Ident;
+//! use cozo::{format_script, DbInstance};
+//!
+//! let db = DbInstance::new("mem", "", "").unwrap();
+//! let name = "Robert'); :rm students {name}";
+//! let script = format_script!(
+//!     "?[name] <- [[{}]] :create {} {{name}}",
+//!     name,
+//!     Ident("students")
+//! )
+//! .unwrap();
+//! db.run_script(&script, Default::default()).unwrap();
+//! ```
+//!
+//! Prefer parameters where possible: they are never parsed as part of the script.
+
+use std::fmt::Write;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use miette::{bail, Diagnostic, Result};
+use pest::Parser;
+use thiserror::Error;
+
+use crate::data::value::{DataValue, Num};
+use crate::parse::{CozoScriptParser, Rule};
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("'{0}' cannot be used as an identifier in CozoScript")]
+#[diagnostic(code(script::bad_ident))]
+#[diagnostic(help(
+    "Identifiers are names of relations, indices or columns, made of letters, digits and underscores"
+))]
+struct BadIdentError(String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("The number of placeholders in the script template does not match the {0} arguments")]
+#[diagnostic(code(script::placeholder_mismatch))]
+struct PlaceholderMismatchError(usize);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Invalid placeholder in the script template at byte {0}")]
+#[diagnostic(code(script::bad_placeholder))]
+#[diagnostic(help("Only `{{}}` is allowed, write `{{{{` and `}}}}` for literal braces"))]
+struct BadPlaceholderError(usize);
+
+/// Checks that the name can be used as an identifier, e.g. the name of a stored relation,
+/// possibly followed by `:` and the name of an index, and returns it.
+pub fn quote_ident(name: &str) -> Result<String> {
+    let parses_as = |entry: Rule| {
+        CozoScriptParser::parse(entry, name)
+            .ok()
+            .and_then(|mut pairs| pairs.next())
+            .is_some_and(|pair| pair.as_str().len() == name.len())
+    };
+    let is_literal = matches!(name, "null" | "true" | "false");
+    if is_literal
+        || !(parses_as(Rule::compound_or_index_ident) || parses_as(Rule::underscore_ident))
+    {
+        bail!(BadIdentError(name.to_string()))
+    }
+    Ok(name.to_string())
+}
+
+/// Writes the value as a CozoScript expression evaluating to it.
+///
+/// Sets and the bottom value, which are only used internally, are written as lists and `null`.
+pub fn quote_value(val: &DataValue) -> String {
+    let mut ret = String::new();
+    write_value(&mut ret, val);
+    ret
+}
+
+fn write_value(out: &mut String, val: &DataValue) {
+    match val {
+        DataValue::Null | DataValue::Bot => out.push_str("null"),
+        DataValue::Bool(b) => write!(out, "{b}").unwrap(),
+        DataValue::Num(Num::Int(i)) => {
+            if *i == i64::MIN {
+                write!(out, "({} - 1)", i64::MIN + 1).unwrap()
+            } else if *i < 0 {
+                write!(out, "({i})").unwrap()
+            } else {
+                write!(out, "{i}").unwrap()
+            }
+        }
+        DataValue::Num(Num::Float(f)) => {
+            if f.is_nan() {
+                out.push_str("to_float('NAN')")
+            } else if f.is_infinite() {
+                if f.is_sign_negative() {
+                    out.push_str("to_float('NEG_INF')")
+                } else {
+                    out.push_str("to_float('INF')")
+                }
+            } else if f.is_sign_negative() {
+                // the debug format is the shortest one reading back to the same float,
+                // and always has a decimal point or an exponent
+                write!(out, "({f:?})").unwrap()
+            } else {
+                write!(out, "{f:?}").unwrap()
+            }
+        }
+        DataValue::Str(s) => write_str(out, s),
+        DataValue::Bytes(b) => {
+            out.push_str("decode_base64(");
+            write_str(out, &STANDARD.encode(b));
+            out.push(')')
+        }
+        DataValue::Uuid(u) => {
+            out.push_str("to_uuid(");
+            write_str(out, &u.0.to_string());
+            out.push(')')
+        }
+        DataValue::Regex(rx) => {
+            out.push_str("regex(");
+            write_str(out, rx.0.as_str());
+            out.push(')')
+        }
+        DataValue::Validity(vld) => {
+            write!(out, "validity({}, {})", vld.timestamp.0 .0, vld.is_assert.0).unwrap()
+        }
+        DataValue::List(l) => write_list(out, l.iter()),
+        DataValue::Set(s) => write_list(out, s.iter()),
+    }
+}
+
+fn write_list<'a>(out: &mut String, vals: impl Iterator<Item = &'a DataValue>) {
+    out.push('[');
+    for (i, val) in vals.enumerate() {
+        if i > 0 {
+            out.push_str(", ");
+        }
+        write_value(out, val);
+    }
+    out.push(']')
+}
+
+/// Writes the string single-quoted, as double quotes start raw strings without escapes
+fn write_str(out: &mut String, s: &str) {
+    out.push('\'');
+    for c in s.chars() {
+        match c {
+            '\'' => out.push_str(r"\'"),
+            '\\' => out.push_str(r"\\"),
+            '\n' => out.push_str(r"\n"),
+            '\r' => out.push_str(r"\r"),
+            '\t' => out.push_str(r"\t"),
+            c if c.is_control() => write!(out, r"\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('\'')
+}
+
+/// An identifier to be embedded by [format_script!] after checking it with [quote_ident]
+#[derive(Debug, Copy, Clone)]
+pub struct Ident<'a>(pub &'a str);
+
+/// Arguments of [format_script!]: identifiers wrapped in [Ident], and values,
+/// which are written with [quote_value].
+pub trait ScriptArg {
+    /// The CozoScript text for the argument
+    fn to_script(&self) -> Result<String>;
+}
+
+impl ScriptArg for Ident<'_> {
+    fn to_script(&self) -> Result<String> {
+        quote_ident(self.0)
+    }
+}
+
+impl ScriptArg for DataValue {
+    fn to_script(&self) -> Result<String> {
+        Ok(quote_value(self))
+    }
+}
+
+impl ScriptArg for str {
+    fn to_script(&self) -> Result<String> {
+        Ok(quote_value(&DataValue::from(self)))
+    }
+}
+
+impl ScriptArg for String {
+    fn to_script(&self) -> Result<String> {
+        self.as_str().to_script()
+    }
+}
+
+impl ScriptArg for i64 {
+    fn to_script(&self) -> Result<String> {
+        Ok(quote_value(&DataValue::from(*self)))
+    }
+}
+
+impl ScriptArg for i32 {
+    fn to_script(&self) -> Result<String> {
+        (*self as i64).to_script()
+    }
+}
+
+impl ScriptArg for f64 {
+    fn to_script(&self) -> Result<String> {
+        Ok(quote_value(&DataValue::from(*self)))
+    }
+}
+
+impl ScriptArg for bool {
+    fn to_script(&self) -> Result<String> {
+        Ok(quote_value(&DataValue::from(*self)))
+    }
+}
+
+impl ScriptArg for [DataValue] {
+    fn to_script(&self) -> Result<String> {
+        let mut ret = String::new();
+        write_list(&mut ret, self.iter());
+        Ok(ret)
+    }
+}
+
+impl ScriptArg for Vec<DataValue> {
+    fn to_script(&self) -> Result<String> {
+        self.as_slice().to_script()
+    }
+}
+
+impl<T: ScriptArg + ?Sized> ScriptArg for &T {
+    fn to_script(&self) -> Result<String> {
+        (**self).to_script()
+    }
+}
+
+/// Replaces each `{}` in the template by the next argument. `{{` and `}}` stand for
+/// literal braces, and any other use of braces is an error. See [format_script!].
+pub fn format_script(template: &str, args: &[&dyn ScriptArg]) -> Result<String> {
+    let mut ret = String::with_capacity(template.len());
+    let mut args = args.iter();
+    let n_args = args.len();
+    let mut chars = template.char_indices();
+    while let Some((pos, c)) = chars.next() {
+        match c {
+            '{' => match chars.next() {
+                Some((_, '{')) => ret.push('{'),
+                Some((_, '}')) => match args.next() {
+                    Some(arg) => ret.push_str(&arg.to_script()?),
+                    None => bail!(PlaceholderMismatchError(n_args)),
+                },
+                _ => bail!(BadPlaceholderError(pos)),
+            },
+            '}' => match chars.next() {
+                Some((_, '}')) => ret.push('}'),
+                _ => bail!(BadPlaceholderError(pos)),
+            },
+            c => ret.push(c),
+        }
+    }
+    if args.next().is_some() {
+        bail!(PlaceholderMismatchError(n_args))
+    }
+    Ok(ret)
+}
+
+/// Builds a script from a template like [format!], except that only `{}` placeholders are
+/// allowed, and the arguments must implement [ScriptArg](crate::script::ScriptArg):
+/// values are written as CozoScript literals and identifiers must be wrapped in
+/// [Ident](crate::script::Ident).
+///
+/// ```
+/// use cozo::format_script;
+/// use cozo::script::Ident;
+///
+/// let script = format_script!("?[x] := *{}{{x}}, x > {}", Ident("nums"), 10).unwrap();
+/// assert_eq!(script, "?[x] := *nums{x}, x > 10");
+/// ```
+#[macro_export]
+macro_rules! format_script {
+    ($template:expr $(, $arg:expr)* $(,)?) => {
+        $crate::script::format_script(
+            $template,
+            &[$(&$arg as &dyn $crate::script::ScriptArg),*],
+        )
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::value::{DataValue, ValidityTs};
+    use crate::new_cozo_mem;
+
+    #[test]
+    fn test_script_quoting_round_trip() {
+        use rand::seq::SliceRandom;
+        use rand::Rng;
+
+        use crate::data::value::{RegexWrapper, UuidWrapper, Validity};
+
+        use crate::script::{quote_value, Ident};
+
+        let db = new_cozo_mem().unwrap();
+        let mut rng = rand::thread_rng();
+        let fragments = [
+            "\"",
+            "'",
+            "\\",
+            "\\\"",
+            "\n",
+            "\r",
+            "\t",
+            "\u{0}",
+            "\u{1b}",
+            "\u{7f}",
+            "\u{85}",
+            "\"}]",
+            "]]",
+            "#",
+            "//",
+            "/*",
+            "{",
+            "}",
+            "$x",
+            "é",
+            "日本",
+            "🦀",
+            "\u{2028}",
+            "\u{feff}",
+            "null",
+            "\"; :rm x",
+            "]] :put users {name} #",
+            "r###\"",
+            "\\u0041",
+            "_",
+        ];
+        let mut corpus = vec![
+            DataValue::Null,
+            DataValue::from(true),
+            DataValue::from(false),
+            DataValue::from(0),
+            DataValue::from(-1),
+            DataValue::from(i64::MAX),
+            DataValue::from(i64::MIN),
+            DataValue::from(0.0),
+            DataValue::from(-0.0),
+            DataValue::from(1e300),
+            DataValue::from(-1.5e-300),
+            DataValue::from(f64::MIN_POSITIVE),
+            DataValue::from(f64::NAN),
+            DataValue::from(f64::INFINITY),
+            DataValue::from(f64::NEG_INFINITY),
+            DataValue::from(""),
+            DataValue::Bytes(vec![]),
+            DataValue::Bytes((0..=255).collect()),
+            DataValue::Uuid(UuidWrapper(uuid::Uuid::new_v4())),
+            DataValue::Regex(RegexWrapper(regex::Regex::new(r#"^"\\d+\n"$"#).unwrap())),
+            DataValue::Validity(Validity {
+                timestamp: ValidityTs(std::cmp::Reverse(-1000)),
+                is_assert: std::cmp::Reverse(false),
+            }),
+            DataValue::List(vec![]),
+        ];
+        for _ in 0..200 {
+            let s: String = (0..rng.gen_range(1..8))
+                .map(|_| {
+                    if rng.gen_bool(0.5) {
+                        fragments.choose(&mut rng).unwrap().to_string()
+                    } else {
+                        rng.gen::<char>().to_string()
+                    }
+                })
+                .collect();
+            corpus.push(DataValue::from(s));
+            corpus.push(DataValue::from(rng.gen::<i64>()));
+            // NaNs with other payloads read back as the canonical one
+            let f = f64::from_bits(rng.gen::<u64>());
+            if !f.is_nan() {
+                corpus.push(DataValue::from(f));
+            }
+            corpus.push(DataValue::Bytes(
+                (0..rng.gen_range(0..20)).map(|_| rng.gen()).collect(),
+            ));
+        }
+        for _ in 0..50 {
+            let nested = (0..rng.gen_range(1..5))
+                .map(|_| {
+                    let val = corpus.choose(&mut rng).unwrap().clone();
+                    if rng.gen_bool(0.3) {
+                        DataValue::List(vec![val])
+                    } else {
+                        val
+                    }
+                })
+                .collect();
+            corpus.push(DataValue::List(nested));
+        }
+
+        for val in corpus {
+            let script = format!("?[v] <- [[{}]]", quote_value(&val));
+            let res = db
+                .run_script(&script, Default::default())
+                .unwrap_or_else(|err| panic!("{script}: {err:?}"));
+            assert_eq!(res.rows, vec![vec![val]], "{script}");
+        }
+
+        let name = "x\"]] :rm stuff {}";
+        let script = format_script!(
+            "?[name] <- [[{}]] :create {} {{name}}",
+            name,
+            Ident("stuff")
+        )
+        .unwrap();
+        db.run_script(&script, Default::default()).unwrap();
+        let res = db
+            .run_script("?[name] := *stuff{name}", Default::default())
+            .unwrap();
+        assert_eq!(res.rows, vec![vec![DataValue::from(name)]]);
+
+        assert!(format_script!("?[x] := *{}[x]", Ident("stuff:idx")).is_ok());
+        assert!(format_script!("?[x] := *{}[x]", Ident("stuff[x]")).is_err());
+        assert!(format_script!("?[x] := *{}[x]", Ident("null")).is_err());
+        assert!(format_script!("?[x] := *{}[x]", Ident("")).is_err());
+        assert!(format_script!("?[x] <- [[{}]]").is_err());
+        assert!(format_script!("?[x] <- [[{}]]", 1, 2).is_err());
+        assert!(format_script!("?[x] <- [[{x}]]", 1).is_err());
+        assert!(format_script!("?[x] <- [[1]] }", 1).is_err());
+    }
+}
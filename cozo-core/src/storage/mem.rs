@@ -15,19 +15,24 @@ use std::iter::Fuse;
 use std::mem;
 use std::ops::Bound;
 use std::sync::Arc;
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::Mutex;
+#[cfg(not(target_arch = "wasm32"))]
+use std::thread::JoinHandle;
 
 use itertools::Itertools;
 use miette::{bail, Result};
 
-use crate::data::tuple::{check_key_for_validity, Tuple};
-use crate::data::value::ValidityTs;
+use crate::data::tuple::{check_key_for_validity, Tuple, ValidityWindow};
+use crate::data::value::{DataValue, ValidityTs};
+use crate::runtime::db::NamedRows;
 use crate::runtime::relation::{decode_tuple_from_kv, extend_tuple_from_v};
-use crate::storage::{Storage, StoreTx};
+use crate::storage::{storage_info_rows, Storage, StoreTx};
 use crate::utils::swap_option_result;
 
 /// Create a database backed by memory.
 /// This is the fastest storage, but non-persistent.
-/// Supports concurrent readers but only a single writer.
+/// Supports concurrent readers but only a single writer, see [MemStorage] for the isolation.
 pub fn new_cozo_mem() -> Result<crate::Db<MemStorage>> {
     let ret = crate::Db::new(MemStorage::default())?;
 
@@ -36,9 +41,16 @@ pub fn new_cozo_mem() -> Result<crate::Db<MemStorage>> {
 }
 
 /// The non-persistent storage
+///
+/// A write transaction holds the lock on the whole store from its start until it is
+/// committed or dropped, and read transactions hold it for reading. Transactions are
+/// therefore serialized: they never conflict, and no update is lost to a concurrent writer.
 #[derive(Default, Clone)]
 pub struct MemStorage {
     store: Arc<ShardedLock<BTreeMap<Vec<u8>, Vec<u8>>>>,
+    /// the threads deleting ranges in the background
+    #[cfg(not(target_arch = "wasm32"))]
+    deletions: Arc<Mutex<Vec<JoinHandle<()>>>>,
 }
 
 impl<'s> Storage<'s> for MemStorage {
@@ -50,6 +62,7 @@ impl<'s> Storage<'s> for MemStorage {
 
     fn transact(&'s self, write: bool) -> Result<Self::Tx> {
         Ok(if write {
+            // held until the transaction ends, so that writers wait for each other
             let wtr = self.store.write().unwrap();
             MemTx::Writer(wtr, Default::default())
         } else {
@@ -77,7 +90,20 @@ impl<'s> Storage<'s> for MemStorage {
         #[cfg(target_arch = "wasm32")]
         closure();
         #[cfg(not(target_arch = "wasm32"))]
-        std::thread::spawn(closure);
+        {
+            let mut deletions = self.deletions.lock().unwrap();
+            deletions.retain(|handle| !handle.is_finished());
+            deletions.push(std::thread::spawn(closure));
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn wait_for_deletions(&'s self) -> Result<()> {
+        let deletions = mem::take(&mut *self.deletions.lock().unwrap());
+        for handle in deletions {
+            handle.join().unwrap();
+        }
         Ok(())
     }
 
@@ -96,6 +122,18 @@ impl<'s> Storage<'s> for MemStorage {
         }
         Ok(())
     }
+
+    fn storage_info(&'s self) -> Result<NamedRows> {
+        let store = self.store.read().unwrap();
+        let n_bytes: usize = store.iter().map(|(k, v)| k.len() + v.len()).sum();
+        Ok(storage_info_rows(
+            self.storage_kind(),
+            vec![
+                ("n_keys", DataValue::from(store.len() as i64)),
+                ("n_bytes", DataValue::from(n_bytes as i64)),
+            ],
+        ))
+    }
 }
 
 pub enum MemTx<'s> {
@@ -191,7 +229,7 @@ impl<'s> StoreTx<'s> for MemTx<'s> {
         match self {
             MemTx::Reader(rdr) => Box::new(
                 rdr.range(lower.to_vec()..upper.to_vec())
-                    .map(|(k, v)| Ok(decode_tuple_from_kv(k, v))),
+                    .map(|(k, v)| decode_tuple_from_kv(k, v)),
             ),
             MemTx::Writer(wtr, cache) => Box::new(CacheIter {
                 change_iter: cache.range(lower.to_vec()..upper.to_vec()).fuse(),
@@ -209,25 +247,46 @@ impl<'s> StoreTx<'s> for MemTx<'s> {
         valid_at: ValidityTs,
     ) -> Box<dyn Iterator<Item = Result<Tuple>> + 'a> {
         match self {
-            MemTx::Reader(stored) => Box::new(
-                SkipIterator {
-                    inner: stored,
-                    upper: upper.to_vec(),
-                    valid_at,
-                    next_bound: lower.to_vec(),
-                }
-                .map(Ok),
-            ),
-            MemTx::Writer(stored, delta) => Box::new(
-                SkipDualIterator {
-                    stored,
-                    delta,
-                    upper: upper.to_vec(),
-                    valid_at,
-                    next_bound: lower.to_vec(),
-                }
-                .map(Ok),
-            ),
+            MemTx::Reader(stored) => Box::new(SkipIterator {
+                inner: stored,
+                upper: upper.to_vec(),
+                valid_at,
+                next_bound: lower.to_vec(),
+            }),
+            MemTx::Writer(stored, delta) => Box::new(SkipDualIterator {
+                stored,
+                delta,
+                upper: upper.to_vec(),
+                valid_at,
+                next_bound: lower.to_vec(),
+            }),
+        }
+    }
+
+    fn range_skip_scan_tuple_window<'a>(
+        &'a self,
+        lower: &[u8],
+        upper: &[u8],
+        valid_from: ValidityTs,
+        valid_to: ValidityTs,
+    ) -> Box<dyn Iterator<Item = Result<Tuple>> + 'a>
+    where
+        's: 'a,
+    {
+        match self {
+            MemTx::Reader(stored) => Box::new(WindowIterator {
+                inner: stored,
+                upper: upper.to_vec(),
+                window: ValidityWindow::new(valid_from, valid_to),
+                next_bound: lower.to_vec(),
+            }),
+            MemTx::Writer(stored, delta) => Box::new(WindowDualIterator {
+                stored,
+                delta,
+                upper: upper.to_vec(),
+                window: ValidityWindow::new(valid_from, valid_to),
+                next_bound: lower.to_vec(),
+            }),
         }
     }
 
@@ -389,24 +448,24 @@ impl CacheIter<'_> {
                     let (k, cv) = self.change_cache.take().unwrap();
                     match cv {
                         None => continue,
-                        Some(v) => return Ok(Some(decode_tuple_from_kv(k, v))),
+                        Some(v) => return decode_tuple_from_kv(k, v).map(Some),
                     }
                 }
                 (None, Some(_)) => {
                     let (k, v) = self.db_cache.take().unwrap();
-                    return Ok(Some(decode_tuple_from_kv(k, v)));
+                    return decode_tuple_from_kv(k, v).map(Some);
                 }
                 (Some((ck, _)), Some((dk, _))) => match ck.cmp(dk) {
                     Ordering::Less => {
                         let (k, sv) = self.change_cache.take().unwrap();
                         match sv {
                             None => continue,
-                            Some(v) => return Ok(Some(decode_tuple_from_kv(k, v))),
+                            Some(v) => return decode_tuple_from_kv(k, v).map(Some),
                         }
                     }
                     Ordering::Greater => {
                         let (k, v) = self.db_cache.take().unwrap();
-                        return Ok(Some(decode_tuple_from_kv(k, v)));
+                        return decode_tuple_from_kv(k, v).map(Some);
                     }
                     Ordering::Equal => {
                         self.db_cache.take();
@@ -436,9 +495,15 @@ pub(crate) struct SkipIterator<'a> {
 }
 
 impl<'a> Iterator for SkipIterator<'a> {
-    type Item = Tuple;
+    type Item = Result<Tuple>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        swap_option_result(self.next_inner())
+    }
+}
+
+impl<'a> SkipIterator<'a> {
+    fn next_inner(&mut self) -> Result<Option<Tuple>> {
         loop {
             let nxt = self
                 .inner
@@ -448,13 +513,13 @@ impl<'a> Iterator for SkipIterator<'a> {
                 ))
                 .next();
             match nxt {
-                None => return None,
+                None => return Ok(None),
                 Some((candidate_key, candidate_val)) => {
-                    let (ret, nxt_bound) = check_key_for_validity(candidate_key, self.valid_at);
+                    let (ret, nxt_bound) = check_key_for_validity(candidate_key, self.valid_at)?;
                     self.next_bound = nxt_bound;
                     if let Some(mut nk) = ret {
-                        extend_tuple_from_v(&mut nk, candidate_val);
-                        return Some(nk);
+                        extend_tuple_from_v(&mut nk, candidate_val)?;
+                        return Ok(Some(nk));
                     }
                 }
             }
@@ -471,9 +536,15 @@ struct SkipDualIterator<'a> {
 }
 
 impl<'a> Iterator for SkipDualIterator<'a> {
-    type Item = Tuple;
+    type Item = Result<Tuple>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        swap_option_result(self.next_inner())
+    }
+}
+
+impl<'a> SkipDualIterator<'a> {
+    fn next_inner(&mut self) -> Result<Option<Tuple>> {
         loop {
             let stored_nxt = self
                 .stored
@@ -490,10 +561,10 @@ impl<'a> Iterator for SkipDualIterator<'a> {
                 ))
                 .next();
             let (candidate_key, candidate_val) = match (stored_nxt, delta_nxt) {
-                (None, None) => return None,
+                (None, None) => return Ok(None),
                 (None, Some((delta_key, maybe_delta_val))) => match maybe_delta_val {
                     None => {
-                        let (_, nxt_seek) = check_key_for_validity(delta_key, self.valid_at);
+                        let (_, nxt_seek) = check_key_for_validity(delta_key, self.valid_at)?;
                         self.next_bound = nxt_seek;
                         continue;
                     }
@@ -507,7 +578,7 @@ impl<'a> Iterator for SkipDualIterator<'a> {
                         match maybe_delta_val {
                             None => {
                                 let (_, nxt_seek) =
-                                    check_key_for_validity(delta_key, self.valid_at);
+                                    check_key_for_validity(delta_key, self.valid_at)?;
                                 self.next_bound = nxt_seek;
                                 continue;
                             }
@@ -516,11 +587,117 @@ impl<'a> Iterator for SkipDualIterator<'a> {
                     }
                 }
             };
-            let (ret, nxt_bound) = check_key_for_validity(candidate_key, self.valid_at);
+            let (ret, nxt_bound) = check_key_for_validity(candidate_key, self.valid_at)?;
             self.next_bound = nxt_bound;
             if let Some(mut nk) = ret {
-                extend_tuple_from_v(&mut nk, candidate_val);
-                return Some(nk);
+                extend_tuple_from_v(&mut nk, candidate_val)?;
+                return Ok(Some(nk));
+            }
+        }
+    }
+}
+
+struct WindowIterator<'a> {
+    inner: &'a BTreeMap<Vec<u8>, Vec<u8>>,
+    upper: Vec<u8>,
+    window: ValidityWindow,
+    next_bound: Vec<u8>,
+}
+
+impl<'a> Iterator for WindowIterator<'a> {
+    type Item = Result<Tuple>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        swap_option_result(self.next_inner())
+    }
+}
+
+impl<'a> WindowIterator<'a> {
+    fn next_inner(&mut self) -> Result<Option<Tuple>> {
+        loop {
+            let (candidate_key, candidate_val) = match self
+                .inner
+                .range::<Vec<u8>, (Bound<&Vec<u8>>, Bound<&Vec<u8>>)>((
+                    Bound::Included(&self.next_bound),
+                    Bound::Excluded(&self.upper),
+                ))
+                .next()
+            {
+                None => return Ok(None),
+                Some(kv) => kv,
+            };
+            let (ret, nxt_bound) = self.window.check_key(candidate_key)?;
+            self.next_bound = nxt_bound;
+            if let Some((mut nk, interval)) = ret {
+                extend_tuple_from_v(&mut nk, candidate_val)?;
+                nk.extend(interval);
+                return Ok(Some(nk));
+            }
+        }
+    }
+}
+
+struct WindowDualIterator<'a> {
+    stored: &'a BTreeMap<Vec<u8>, Vec<u8>>,
+    delta: &'a BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    upper: Vec<u8>,
+    window: ValidityWindow,
+    next_bound: Vec<u8>,
+}
+
+impl<'a> Iterator for WindowDualIterator<'a> {
+    type Item = Result<Tuple>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        swap_option_result(self.next_inner())
+    }
+}
+
+impl<'a> WindowDualIterator<'a> {
+    fn next_inner(&mut self) -> Result<Option<Tuple>> {
+        loop {
+            let stored_nxt = self
+                .stored
+                .range::<Vec<u8>, (Bound<&Vec<u8>>, Bound<&Vec<u8>>)>((
+                    Bound::Included(&self.next_bound),
+                    Bound::Excluded(&self.upper),
+                ))
+                .next();
+            let delta_nxt = self
+                .delta
+                .range::<Vec<u8>, (Bound<&Vec<u8>>, Bound<&Vec<u8>>)>((
+                    Bound::Included(&self.next_bound),
+                    Bound::Excluded(&self.upper),
+                ))
+                .next();
+            let (candidate_key, maybe_candidate_val) = match (stored_nxt, delta_nxt) {
+                (None, None) => return Ok(None),
+                (None, Some((delta_key, maybe_delta_val))) => (delta_key, maybe_delta_val.as_ref()),
+                (Some((stored_key, stored_val)), None) => (stored_key, Some(stored_val)),
+                (Some((stored_key, stored_val)), Some((delta_key, maybe_delta_val))) => {
+                    if stored_key < delta_key {
+                        (stored_key, Some(stored_val))
+                    } else {
+                        (delta_key, maybe_delta_val.as_ref())
+                    }
+                }
+            };
+            let candidate_val = match maybe_candidate_val {
+                Some(v) => v,
+                None => {
+                    // deleted within the transaction, the version does not exist
+                    let mut successor = candidate_key.clone();
+                    successor.push(0);
+                    self.next_bound = successor;
+                    continue;
+                }
+            };
+            let (ret, nxt_bound) = self.window.check_key(candidate_key)?;
+            self.next_bound = nxt_bound;
+            if let Some((mut nk, interval)) = ret {
+                extend_tuple_from_v(&mut nk, candidate_val)?;
+                nk.extend(interval);
+                return Ok(Some(nk));
             }
         }
     }
@@ -6,12 +6,14 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use itertools::Itertools;
-use miette::Result;
+use miette::{Diagnostic, Result};
+use thiserror::Error;
 
-use crate::data::tuple::Tuple;
-use crate::data::value::ValidityTs;
+use crate::data::tuple::{Tuple, ValidityWindow};
+use crate::data::value::{DataValue, ValidityTs};
 use crate::decode_tuple_from_kv;
+use crate::runtime::db::NamedRows;
+use crate::runtime::relation::extend_tuple_from_v;
 
 pub(crate) mod mem;
 #[cfg(feature = "storage-rocksdb")]
@@ -21,11 +23,20 @@ pub(crate) mod sled;
 #[cfg(feature = "storage-sqlite")]
 pub(crate) mod sqlite;
 pub(crate) mod temp;
+#[cfg(any(test, feature = "storage-dev"))]
+pub(crate) mod test_suite;
 #[cfg(feature = "storage-tikv")]
 pub(crate) mod tikv;
 // pub(crate) mod re;
 
 /// Swappable storage trait for Cozo's storage engine
+///
+/// Storage engines may be implemented outside of this crate and used with
+/// [`Db::new`](crate::Db::new). This trait, [StoreTx] and the encodings of
+/// [`format`](crate::format) are stable: they only change with the minor version before 1.0,
+/// and the major version after, and methods added come with default implementations.
+/// Engines should be checked with the conformance suite, exposed by the `storage-dev` feature
+/// as `storage_test_suite`.
 pub trait Storage<'s>: Send + Sync + Clone {
     /// The associated transaction type used by this engine
     type Tx: StoreTx<'s>;
@@ -37,10 +48,20 @@ pub trait Storage<'s>: Send + Sync + Clone {
     fn transact(&'s self, write: bool) -> Result<Self::Tx>;
 
     /// Delete a range. It is ok to return immediately and do the deletion in
-    /// the background. It is guaranteed that no keys within the deleted range
-    /// will be accessed in any way by any transaction again.
+    /// the background, if [`wait_for_deletions`](Self::wait_for_deletions) waits for it
+    /// and the deletion is finished before the storage is dropped. It is guaranteed
+    /// that no keys within the deleted range will be accessed in any way by any
+    /// transaction again, except by scans of the whole storage such as backups.
     fn del_range(&'s self, lower: &[u8], upper: &[u8]) -> Result<()>;
 
+    /// Wait until the deletions of ranges done in the background are finished, returning
+    /// the first error raised by them. Called before the whole storage is scanned, and must
+    /// not be called while holding a transaction. The default implementation returns
+    /// immediately, for engines deleting ranges before returning from `del_range`.
+    fn wait_for_deletions(&'s self) -> Result<()> {
+        Ok(())
+    }
+
     /// Compact the key range. Can be a no-op if the storage engine does not
     /// have the concept of compaction.
     fn range_compact(&'s self, lower: &[u8], upper: &[u8]) -> Result<()>;
@@ -52,16 +73,57 @@ pub trait Storage<'s>: Send + Sync + Clone {
         &'a self,
         data: Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>,
     ) -> Result<()>;
+
+    /// Engine-level statistics, as rows of `key` and `value`.
+    /// Values should be numeric where possible so that they can be scraped.
+    /// The default implementation only reports the storage kind.
+    fn storage_info(&'s self) -> Result<NamedRows> {
+        Ok(storage_info_rows(self.storage_kind(), vec![]))
+    }
+}
+
+/// Build the result of [Storage::storage_info] from the given key-value entries.
+pub(crate) fn storage_info_rows(kind: &str, entries: Vec<(&str, DataValue)>) -> NamedRows {
+    let mut rows = vec![vec![DataValue::from("storage_kind"), DataValue::from(kind)]];
+    for (k, v) in entries {
+        rows.push(vec![DataValue::from(k), v]);
+    }
+    NamedRows::new(vec!["key".to_string(), "value".to_string()], rows)
 }
 
+/// Error for storage engines to return for failures that may go away if the operation
+/// is retried, e.g. when another process holds a lock on the storage.
+/// Point lookups in joins are retried on such errors, see [crate::Db::set_lookup_retries].
+#[derive(Debug, Error, Diagnostic)]
+#[error("Transient storage error: {0}")]
+#[diagnostic(code(storage::transient))]
+pub struct TransientStorageError(pub String);
+
+/// Error for storage engines to return from [StoreTx::commit] when the transaction conflicts
+/// with another one committed concurrently, so that it may succeed if run again.
+/// Scripts failing with it are run again by `Db::run_script_with_retry`.
+#[derive(Debug, Error, Diagnostic)]
+#[error("Write conflict: {0}")]
+#[diagnostic(code(storage::write_conflict))]
+#[diagnostic(help("The transaction may succeed if run again"))]
+pub struct WriteConflict(pub String);
+
 /// Trait for the associated transaction type of a storage engine.
 /// A transaction needs to guarantee MVCC semantics for all operations.
+/// Its stability is as for [Storage].
 pub trait StoreTx<'s>: Sync {
     /// Get a key. If `for_update` is `true` (only possible in a write transaction),
     /// then the database needs to guarantee that `commit()` can only succeed if
     /// the key has not been modified outside the transaction.
     fn get(&self, key: &[u8], for_update: bool) -> Result<Option<Vec<u8>>>;
 
+    /// Get several keys at once, returning the values in the same order as the keys.
+    /// The keys are usually sorted, so that engines can look them up in a single pass.
+    /// The default implementation calls [`get`](Self::get) for each key.
+    fn multi_get(&self, keys: &[&[u8]], for_update: bool) -> Result<Vec<Option<Vec<u8>>>> {
+        keys.iter().map(|key| self.get(key, for_update)).collect()
+    }
+
     /// Put a key-value pair into the storage. In case of existing key,
     /// the storage engine needs to overwrite the old value.
     fn put(&mut self, key: &[u8], val: &[u8]) -> Result<()>;
@@ -101,7 +163,7 @@ pub trait StoreTx<'s>: Sync {
         's: 'a,
     {
         let it = self.range_scan(lower, upper);
-        Box::new(it.map_ok(|(k, v)| decode_tuple_from_kv(&k, &v)))
+        Box::new(it.map(|res| res.and_then(|(k, v)| decode_tuple_from_kv(&k, &v))))
     }
 
     /// Scan on a range with a certain validity.
@@ -127,6 +189,47 @@ pub trait StoreTx<'s>: Sync {
         valid_at: ValidityTs,
     ) -> Box<dyn Iterator<Item = Result<Tuple>> + 'a>;
 
+    /// Scan on a range for every version valid within a window of validity.
+    ///
+    /// `lower` is inclusive whereas `upper` is exclusive, and the window includes
+    /// both `valid_from` and `valid_to`.
+    /// For tuples that differ only with respect to their validity, which must be at
+    /// the last slot of the key, each assertive tuple is valid from its validity until
+    /// the validity of the next newer tuple, or indefinitely if there is none.
+    /// The tuples whose validity overlaps the window should be returned, with the
+    /// timestamps at which they start and stop to be valid appended as two integers,
+    /// the latter being null for the tuple that is still valid.
+    ///
+    /// The default implementation looks at every tuple within the `lower` and `upper` range.
+    /// Ideally, implementations should take advantage of seeking capabilities of the
+    /// underlying storage to skip the tuples older than the one valid at `valid_from`.
+    fn range_skip_scan_tuple_window<'a>(
+        &'a self,
+        lower: &[u8],
+        upper: &[u8],
+        valid_from: ValidityTs,
+        valid_to: ValidityTs,
+    ) -> Box<dyn Iterator<Item = Result<Tuple>> + 'a>
+    where
+        's: 'a,
+    {
+        let mut window = ValidityWindow::new(valid_from, valid_to);
+        Box::new(self.range_scan(lower, upper).filter_map(move |res| {
+            let (k, v) = match res {
+                Ok(kv) => kv,
+                Err(err) => return Some(Err(err)),
+            };
+            let (mut tup, interval) = match window.check_key(&k) {
+                Ok((found, _)) => found?,
+                Err(err) => return Some(Err(err)),
+            };
+            Some(extend_tuple_from_v(&mut tup, &v).map(|_| {
+                tup.extend(interval);
+                tup
+            }))
+        }))
+    }
+
     /// Scan on a range and return the raw results.
     /// `lower` is inclusive whereas `upper` is exclusive.
     fn range_scan<'a>(
@@ -12,25 +12,46 @@ use std::path::{Path, PathBuf};
 use log::info;
 use miette::{miette, IntoDiagnostic, Result, WrapErr};
 
-use cozorocks::{DbBuilder, DbIter, RocksDb, Tx};
+use cozorocks::{DbBuilder, DbIter, RocksDb, StatusCode, Tx};
 
-use crate::data::tuple::{check_key_for_validity, Tuple};
-use crate::data::value::ValidityTs;
-use crate::runtime::db::{BadDbInit, DbManifest};
+use crate::data::tuple::{check_key_for_validity, Tuple, ValidityWindow};
+use crate::data::value::{DataValue, ValidityTs};
+use crate::runtime::db::{BadDbInit, DbManifest, NamedRows};
 use crate::runtime::relation::{decode_tuple_from_kv, extend_tuple_from_v};
-use crate::storage::{Storage, StoreTx};
+use crate::storage::{storage_info_rows, Storage, StoreTx, WriteConflict};
 use crate::utils::swap_option_result;
 use crate::Db;
 
 const KEY_PREFIX_LEN: usize = 9;
 const CURRENT_STORAGE_VERSION: u64 = 1;
 
+/// Options for [new_cozo_rocksdb_with_options]. In [crate::DbInstance::new] they are given
+/// in the options JSON of the `rocksdb` engine.
+#[derive(Clone, Debug, Default, serde_derive::Deserialize)]
+#[serde(default)]
+pub struct RocksDbOptions {
+    /// Collect the statistics of RocksDB, such as the hits of the block cache
+    /// reported by `::storage_info`. This costs a little on every operation, so it is off
+    /// by default.
+    pub statistics: bool,
+}
+
 /// Creates a RocksDB database object.
 /// This is currently the fastest persistent storage and it can
 /// sustain huge concurrency.
 /// Supports concurrent readers and writers.
 pub fn new_cozo_rocksdb(path: impl AsRef<Path>) -> Result<Db<RocksDbStorage>> {
-    let builder = DbBuilder::default().path(path.as_ref());
+    new_cozo_rocksdb_with_options(path, Default::default())
+}
+
+/// Creates a RocksDB database object, configured by `options`.
+pub fn new_cozo_rocksdb_with_options(
+    path: impl AsRef<Path>,
+    options: RocksDbOptions,
+) -> Result<Db<RocksDbStorage>> {
+    let builder = DbBuilder::default()
+        .path(path.as_ref())
+        .enable_statistics(options.statistics);
     fs::create_dir_all(path.as_ref()).map_err(|err| {
         BadDbInit(format!(
             "cannot create directory {}: {}",
@@ -152,6 +173,45 @@ impl Storage<'_> for RocksDbStorage {
         }
         Ok(())
     }
+
+    fn storage_info(&self) -> Result<NamedRows> {
+        const PROPERTIES: [(&str, &str); 10] = [
+            ("estimate_num_keys", "rocksdb.estimate-num-keys"),
+            ("total_sst_files_size", "rocksdb.total-sst-files-size"),
+            ("live_sst_files_size", "rocksdb.live-sst-files-size"),
+            ("cur_size_all_mem_tables", "rocksdb.cur-size-all-mem-tables"),
+            ("block_cache_capacity", "rocksdb.block-cache-capacity"),
+            ("block_cache_usage", "rocksdb.block-cache-usage"),
+            (
+                "block_cache_pinned_usage",
+                "rocksdb.block-cache-pinned-usage",
+            ),
+            ("compaction_pending", "rocksdb.compaction-pending"),
+            ("num_running_compactions", "rocksdb.num-running-compactions"),
+            (
+                "estimate_pending_compaction_bytes",
+                "rocksdb.estimate-pending-compaction-bytes",
+            ),
+        ];
+        let mut entries: Vec<_> = PROPERTIES
+            .iter()
+            .filter_map(|(key, prop)| {
+                self.db
+                    .get_int_property(prop)
+                    .map(|v| (*key, DataValue::from(v as i64)))
+            })
+            .collect();
+        if let Some((hits, misses)) = self.db.block_cache_hits_and_misses() {
+            entries.push(("block_cache_hits", DataValue::from(hits as i64)));
+            entries.push(("block_cache_misses", DataValue::from(misses as i64)));
+            // the rate is left out until the cache is first used
+            if hits + misses > 0 {
+                let rate = hits as f64 / (hits + misses) as f64;
+                entries.push(("block_cache_hit_rate", DataValue::from(rate)));
+            }
+        }
+        Ok(storage_info_rows(self.storage_kind(), entries))
+    }
 }
 
 pub struct RocksDbTx {
@@ -190,7 +250,11 @@ impl<'s> StoreTx<'s> for RocksDbTx {
     }
 
     fn commit(&mut self) -> Result<()> {
-        Ok(self.db_tx.commit()?)
+        // optimistic transactions fail to commit with these when they conflict
+        self.db_tx.commit().map_err(|err| match err.code {
+            StatusCode::kBusy | StatusCode::kTryAgain => WriteConflict(err.message).into(),
+            _ => err.into(),
+        })
     }
 
     fn range_scan_tuple<'a>(
@@ -225,6 +289,25 @@ impl<'s> StoreTx<'s> for RocksDbTx {
         })
     }
 
+    fn range_skip_scan_tuple_window<'a>(
+        &'a self,
+        lower: &[u8],
+        upper: &[u8],
+        valid_from: ValidityTs,
+        valid_to: ValidityTs,
+    ) -> Box<dyn Iterator<Item = Result<Tuple>> + 'a>
+    where
+        's: 'a,
+    {
+        let inner = self.db_tx.iterator().upper_bound(upper).start();
+        Box::new(RocksDbWindowIterator {
+            inner,
+            upper_bound: upper.to_vec(),
+            next_bound: lower.to_owned(),
+            window: ValidityWindow::new(valid_from, valid_to),
+        })
+    }
+
     fn range_scan<'a>(
         &'a self,
         lower: &[u8],
@@ -271,7 +354,7 @@ impl RocksDbIterator {
                     None
                 } else {
                     // upper bound is exclusive
-                    Some(decode_tuple_from_kv(k_slice, v_slice))
+                    Some(decode_tuple_from_kv(k_slice, v_slice)?)
                 }
             }
         })
@@ -305,10 +388,10 @@ impl RocksDbSkipIterator {
                         return Ok(None);
                     }
 
-                    let (ret, nxt_bound) = check_key_for_validity(k_slice, self.valid_at);
+                    let (ret, nxt_bound) = check_key_for_validity(k_slice, self.valid_at)?;
                     self.next_bound = nxt_bound;
                     if let Some(mut tup) = ret {
-                        extend_tuple_from_v(&mut tup, v_slice);
+                        extend_tuple_from_v(&mut tup, v_slice)?;
                         return Ok(Some(tup));
                     }
                 }
@@ -325,6 +408,46 @@ impl Iterator for RocksDbSkipIterator {
     }
 }
 
+pub(crate) struct RocksDbWindowIterator {
+    inner: DbIter,
+    upper_bound: Vec<u8>,
+    next_bound: Vec<u8>,
+    window: ValidityWindow,
+}
+
+impl RocksDbWindowIterator {
+    #[inline]
+    fn next_inner(&mut self) -> Result<Option<Tuple>> {
+        loop {
+            self.inner.seek(&self.next_bound);
+            match self.inner.pair()? {
+                None => return Ok(None),
+                Some((k_slice, v_slice)) => {
+                    if self.upper_bound.as_slice() <= k_slice {
+                        return Ok(None);
+                    }
+
+                    let (ret, nxt_bound) = self.window.check_key(k_slice)?;
+                    self.next_bound = nxt_bound;
+                    if let Some((mut tup, interval)) = ret {
+                        extend_tuple_from_v(&mut tup, v_slice)?;
+                        tup.extend(interval);
+                        return Ok(Some(tup));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for RocksDbWindowIterator {
+    type Item = Result<Tuple>;
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        swap_option_result(self.next_inner())
+    }
+}
+
 pub(crate) struct RocksDbIteratorRaw {
     inner: DbIter,
     started: bool,
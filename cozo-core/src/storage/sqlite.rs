@@ -7,81 +7,407 @@
  */
 
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
 
-use ::sqlite::Connection;
 use crossbeam::sync::{ShardedLock, ShardedLockReadGuard, ShardedLockWriteGuard};
 use either::{Either, Left, Right};
-use miette::{bail, miette, IntoDiagnostic, Result};
-use sqlite::{ConnectionWithFullMutex, State, Statement};
+use log::error;
+use miette::{bail, miette, Diagnostic, Result};
+use sqlite::{Connection, OpenFlags, State, Statement};
 
-use crate::data::tuple::{check_key_for_validity, Tuple};
-use crate::data::value::ValidityTs;
+use thiserror::Error;
+
+use crate::data::tuple::{check_key_for_validity, Tuple, ValidityWindow};
+use crate::data::value::{DataValue, ValidityTs};
+use crate::runtime::db::{NamedRows, ReadOnlyDatabase};
 use crate::runtime::relation::{decode_tuple_from_kv, extend_tuple_from_v};
-use crate::storage::{Storage, StoreTx};
+use crate::storage::{storage_info_rows, Storage, StoreTx};
 use crate::utils::swap_option_result;
 
 /// The Sqlite storage engine
 #[derive(Clone)]
 pub struct SqliteStorage {
     lock: Arc<ShardedLock<()>>,
+    pool: Arc<ConnectionPool>,
+    active_txs: Arc<AtomicUsize>,
+    pending_deletions: Arc<PendingDeletions>,
+    /// whether the database is in WAL mode, in which readers take snapshots instead
+    /// of the read lock, as they do not block the writer
+    wal: bool,
+}
+
+/// Options for [new_cozo_sqlite_with_options]. In [crate::DbInstance::new] they are given
+/// in the options JSON of the `sqlite` engine.
+#[derive(Clone, Debug, serde_derive::Deserialize)]
+#[serde(default)]
+pub struct SqliteOptions {
+    /// The most connections to the file open at once. Transactions wait for a connection
+    /// to be given back when all are in use. Defaults to 8.
+    pub max_connections: usize,
+    /// Set by `PRAGMA journal_mode` when connections are opened, defaults to `WAL`,
+    /// in which readers do not block the writer. Not set for read-only connections.
+    pub journal_mode: String,
+    /// Set by `PRAGMA synchronous` when connections are opened, defaults to `NORMAL`
+    pub synchronous: String,
+    /// Open the connections with read-only flags, see [new_cozo_sqlite_read_only]
+    pub read_only: bool,
+    /// Create the file if it does not exist, defaults to `true`.
+    /// Otherwise opening a missing file fails.
+    pub create_if_missing: bool,
+}
+
+impl Default for SqliteOptions {
+    fn default() -> Self {
+        Self {
+            max_connections: 8,
+            journal_mode: "WAL".to_string(),
+            synchronous: "NORMAL".to_string(),
+            read_only: false,
+            create_if_missing: true,
+        }
+    }
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Bad value '{1}' for the sqlite option '{0}'")]
+#[diagnostic(code(db::bad_sqlite_option))]
+struct BadSqliteOption(&'static str, String);
+
+/// The connections to the file, of which at most `max_size` are open at once
+struct ConnectionPool {
+    state: Mutex<PoolState>,
+    /// notified when a connection is given back or closed
+    released: Condvar,
+    max_size: usize,
     name: PathBuf,
-    pool: Arc<Mutex<Vec<ConnectionWithFullMutex>>>,
+    options: SqliteOptions,
+    /// An in-memory database lives as long as one of its connections is open,
+    /// so one is kept open besides those of the pool. Behind a mutex, as connections
+    /// cannot be shared between threads.
+    keep_alive: Mutex<Option<Connection>>,
+}
+
+#[derive(Default)]
+struct PoolState {
+    idle: Vec<Connection>,
+    /// the number of connections open, idle or in use
+    open: usize,
+}
+
+impl ConnectionPool {
+    /// Takes an idle connection, or opens a new one if fewer than the most allowed are open,
+    /// or else waits for a connection to be given back
+    fn connect(&self) -> Result<Connection> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(conn) = state.idle.pop() {
+                return Ok(conn);
+            }
+            if state.open < self.max_size {
+                break;
+            }
+            state = self.released.wait(state).unwrap();
+        }
+        state.open += 1;
+        drop(state);
+        self.open_connection().inspect_err(|_| self.forget())
+    }
+    /// Like [Self::connect], but returns `None` instead of waiting if all the connections
+    /// allowed are in use
+    fn try_connect(&self) -> Result<Option<Connection>> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(conn) = state.idle.pop() {
+            return Ok(Some(conn));
+        }
+        if state.open >= self.max_size {
+            return Ok(None);
+        }
+        state.open += 1;
+        drop(state);
+        self.open_connection()
+            .inspect_err(|_| self.forget())
+            .map(Some)
+    }
+    fn open_connection(&self) -> Result<Connection> {
+        // names starting with `file:` are taken as URIs
+        let flags = OpenFlags::new().with_full_mutex().with_uri();
+        let flags = if self.options.read_only {
+            flags.with_read_only()
+        } else if self.options.create_if_missing {
+            flags.with_read_write().with_create()
+        } else {
+            flags.with_read_write()
+        };
+        let conn = Connection::open_with_flags(&self.name, flags).map_err(SqliteError)?;
+        if !self.options.read_only {
+            conn.execute(format!(
+                "pragma journal_mode={};",
+                self.options.journal_mode
+            ))
+            .map_err(SqliteError)?;
+        }
+        conn.execute(format!("pragma synchronous={};", self.options.synchronous))
+            .map_err(SqliteError)?;
+        Ok(conn)
+    }
+    fn give_back(&self, conn: Connection) {
+        self.state.lock().unwrap().idle.push(conn);
+        self.released.notify_one();
+    }
+    /// Forgets a connection that could not be opened
+    fn forget(&self) {
+        self.state.lock().unwrap().open -= 1;
+        self.released.notify_one();
+    }
+    /// Closes the idle connections, so that the connections opened afterwards start afresh
+    fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        let idle = std::mem::take(&mut state.idle);
+        state.open -= idle.len();
+        drop(state);
+        drop(idle);
+        self.released.notify_all();
+    }
+}
+
+/// The deletions of ranges done in the background by [SqliteStorage::del_range],
+/// waited for when the storage is dropped
+#[derive(Default)]
+struct PendingDeletions(Mutex<DeletionsState>);
+
+#[derive(Default)]
+struct DeletionsState {
+    running: Vec<JoinHandle<Result<()>>>,
+    /// the first error of the deletions found finished, not yet returned by
+    /// [PendingDeletions::wait]
+    failed: Option<miette::Report>,
+}
+
+impl PendingDeletions {
+    fn push(&self, handle: JoinHandle<Result<()>>) {
+        let mut state = self.0.lock().unwrap();
+        let (finished, running) = std::mem::take(&mut state.running)
+            .into_iter()
+            .partition(|h: &JoinHandle<_>| h.is_finished());
+        state.running = running;
+        state.running.push(handle);
+        // the error is kept for the next wait
+        if let Err(err) = join_deletions(finished) {
+            state.failed.get_or_insert(err);
+        }
+    }
+    fn wait(&self) -> Result<()> {
+        let (handles, failed) = {
+            let mut state = self.0.lock().unwrap();
+            (std::mem::take(&mut state.running), state.failed.take())
+        };
+        let res = join_deletions(handles);
+        match failed {
+            Some(err) => Err(err),
+            None => res,
+        }
+    }
+}
+
+/// Joins all the threads, returning the first error
+fn join_deletions(handles: Vec<JoinHandle<Result<()>>>) -> Result<()> {
+    let mut ret = Ok(());
+    for handle in handles {
+        let res = handle
+            .join()
+            .unwrap_or_else(|_| Err(miette!("deletion of a range panicked")));
+        if ret.is_ok() {
+            ret = res;
+        }
+    }
+    ret
+}
+
+impl Drop for PendingDeletions {
+    fn drop(&mut self) {
+        // no one is left to return the error to
+        if let Err(err) = self.wait() {
+            error!("deletion of a range failed: {:?}", err)
+        }
+    }
+}
+
+/// An error raised by Sqlite, with a diagnostic code such as `sqlite::busy` derived from
+/// the primary result code.
+#[derive(Debug, Error)]
+#[error("Sqlite error: {}", .0.message.as_deref().unwrap_or("unknown error"))]
+pub(crate) struct SqliteError(pub(crate) sqlite::Error);
+
+impl Diagnostic for SqliteError {
+    fn code<'a>(&'a self) -> Option<Box<dyn std::fmt::Display + 'a>> {
+        // see https://www.sqlite.org/rescode.html
+        let name = match self.0.code.map(|c| c & 0xff) {
+            Some(5) => "busy",
+            Some(6) => "locked",
+            Some(11) => "corrupt",
+            Some(13) => "full",
+            Some(26) => "notadb",
+            _ => "error",
+        };
+        Some(Box::new(format!("sqlite::{name}")))
+    }
 }
 
 /// Create a sqlite backed database.
 /// Supports concurrent readers but only a single writer.
 ///
-/// You must provide a disk-based path: `:memory:` is not OK.
+/// The path may also be a [sqlite URI](https://www.sqlite.org/uri.html) starting with `file:`.
+/// You must provide a disk-based path or a shared in-memory database such as
+/// `file::memory:?cache=shared`: `:memory:` is not OK, as each connection would have
+/// a database of its own.
 /// If you want a pure memory storage, use [`new_cozo_mem`](crate::new_cozo_mem).
 pub fn new_cozo_sqlite(path: impl AsRef<Path>) -> Result<crate::Db<SqliteStorage>> {
-    if path.as_ref().to_str() == Some("") {
+    new_cozo_sqlite_with_options(path, Default::default())
+}
+
+/// Open an existing sqlite backed database for reading only.
+/// The connections to the file are opened with read-only flags, so that nothing can be written
+/// to it, and every write to the database fails, see [`Db::set_read_only`](crate::Db::set_read_only).
+pub fn new_cozo_sqlite_read_only(path: impl AsRef<Path>) -> Result<crate::Db<SqliteStorage>> {
+    let options = SqliteOptions {
+        read_only: true,
+        ..Default::default()
+    };
+    new_cozo_sqlite_with_options(path, options)
+}
+
+/// Create or open a sqlite backed database, with the connections to it configured by `options`.
+pub fn new_cozo_sqlite_with_options(
+    path: impl AsRef<Path>,
+    options: SqliteOptions,
+) -> Result<crate::Db<SqliteStorage>> {
+    let read_only = options.read_only;
+    let mut ret = crate::Db::new(open_sqlite_storage(path, options)?)?;
+    ret.set_read_only(read_only);
+
+    ret.initialize()?;
+    Ok(ret)
+}
+
+/// Open the storage of a sqlite backed database, without initializing it
+pub(crate) fn open_sqlite_storage(
+    path: impl AsRef<Path>,
+    options: SqliteOptions,
+) -> Result<SqliteStorage> {
+    let path = path.as_ref();
+    if path.to_str() == Some("") {
         bail!("empty path for sqlite storage")
     }
-    let conn = Connection::open_with_full_mutex(&path).into_diagnostic()?;
-    let query = r#"
+    if options.max_connections == 0 {
+        bail!(BadSqliteOption("max_connections", "0".to_string()))
+    }
+    // the values are put into the pragmas as they are
+    for (name, value) in [
+        ("journal_mode", &options.journal_mode),
+        ("synchronous", &options.synchronous),
+    ] {
+        if value.is_empty() || !value.chars().all(|c| c.is_ascii_alphanumeric()) {
+            bail!(BadSqliteOption(name, value.to_string()))
+        }
+    }
+    let in_memory = is_in_memory(path)?;
+    let read_only = options.read_only;
+    let mut pool = ConnectionPool {
+        state: Default::default(),
+        released: Default::default(),
+        max_size: options.max_connections,
+        name: PathBuf::from(path),
+        options,
+        keep_alive: Default::default(),
+    };
+    if in_memory {
+        pool.keep_alive = Mutex::new(Some(pool.open_connection()?));
+    }
+    let pool = Arc::new(pool);
+    let conn = pool.connect()?;
+    let wal = init_connection(&conn, read_only);
+    pool.give_back(conn);
+    Ok(SqliteStorage {
+        lock: Default::default(),
+        pool,
+        active_txs: Default::default(),
+        pending_deletions: Default::default(),
+        wal: wal?,
+    })
+}
+
+/// Whether the path is a URI of an in-memory database, which must be shared by the connections
+fn is_in_memory(path: &Path) -> Result<bool> {
+    #[derive(Debug, Error, Diagnostic)]
+    #[error("The sqlite database '{0}' is in memory but not shared between connections")]
+    #[diagnostic(code(db::unshared_sqlite_memory))]
+    #[diagnostic(help("Use a URI with shared cache, e.g. `file::memory:?cache=shared`"))]
+    struct UnsharedMemory(String);
+
+    let name = path.to_string_lossy();
+    let in_memory = name == ":memory:"
+        || (name.starts_with("file:")
+            && (name.contains(":memory:") || name.contains("mode=memory")));
+    if in_memory && !name.contains("cache=shared") {
+        bail!(UnsharedMemory(name.to_string()))
+    }
+    Ok(in_memory)
+}
+
+/// Creates the table holding the data if it does not exist and the database is writable,
+/// returning whether the database is in WAL mode
+fn init_connection(conn: &Connection, read_only: bool) -> Result<bool> {
+    if !read_only {
+        let query = r#"
         create table if not exists cozo
         (
             k BLOB primary key,
             v BLOB
         );
     "#;
-    let mut statement = conn.prepare(query).unwrap();
-    while statement.next().into_diagnostic()? != State::Done {}
-
-    let ret = crate::Db::new(SqliteStorage {
-        lock: Default::default(),
-        name: PathBuf::from(path.as_ref()),
-        pool: Default::default(),
-    })?;
-
-    ret.initialize()?;
-    Ok(ret)
+        conn.execute(query).map_err(SqliteError)?;
+    }
+    let mut statement = conn.prepare("pragma journal_mode;").map_err(SqliteError)?;
+    Ok(match statement.next().map_err(SqliteError)? {
+        State::Row => statement
+            .read::<String, _>(0)
+            .map_err(SqliteError)?
+            .eq_ignore_ascii_case("wal"),
+        State::Done => false,
+    })
 }
 
 impl<'s> Storage<'s> for SqliteStorage {
     type Tx = SqliteTx<'s>;
 
     fn transact(&'s self, write: bool) -> Result<Self::Tx> {
-        let conn = {
-            match self.pool.lock().unwrap().pop() {
-                None => Connection::open_with_full_mutex(&self.name).into_diagnostic()?,
-                Some(conn) => conn,
-            }
-        };
+        if write && self.pool.options.read_only {
+            bail!(ReadOnlyDatabase)
+        }
+        let conn = self.pool.connect()?;
         let lock = if write {
             Right(self.lock.write().unwrap())
+        } else if self.wal {
+            Left(None)
         } else {
-            Left(self.lock.read().unwrap())
+            Left(Some(self.lock.read().unwrap()))
         };
-        if write {
-            let mut stmt = conn.prepare("begin;").into_diagnostic()?;
-            while stmt.next().into_diagnostic()? != State::Done {}
+        // in WAL mode, readers see the snapshot taken when their transaction starts
+        let in_tx = write || self.wal;
+        if in_tx {
+            if let Err(err) = conn.execute("begin;") {
+                self.pool.give_back(conn);
+                return Err(SqliteError(err).into());
+            }
         }
+        self.active_txs.fetch_add(1, Ordering::AcqRel);
         Ok(SqliteTx {
             lock,
             storage: self,
             conn: Some(conn),
+            in_tx,
             stmts: [
                 Mutex::new(None),
                 Mutex::new(None),
@@ -98,20 +424,28 @@ impl<'s> Storage<'s> for SqliteStorage {
         let query = r#"
                 delete from cozo where k >= ? and k < ?;
             "#;
+        // the caller may still hold the write lock, so the deletion waits for it
+        // in the background, see [Self::wait_for_deletions]
         let lock = self.lock.clone();
-        let name = self.name.clone();
-        let closure = move || {
-            let _locked = lock.write().unwrap();
-            let conn = sqlite::open(&name).unwrap();
-            let mut statement = conn.prepare(query).unwrap();
-            statement.bind((1, &lower_b as &[u8])).unwrap();
-            statement.bind((2, &upper_b as &[u8])).unwrap();
-            while statement.next().unwrap() != State::Done {}
+        let pool = self.pool.clone();
+        let closure = move || -> Result<()> {
+            // connections are taken before the lock, as by transactions
+            let conn = pool.connect()?;
+            let res = {
+                let _locked = lock.write().unwrap();
+                delete_range(&conn, query, &lower_b, &upper_b)
+            };
+            pool.give_back(conn);
+            res
         };
-        std::thread::spawn(closure);
+        self.pending_deletions.push(std::thread::spawn(closure));
         Ok(())
     }
 
+    fn wait_for_deletions(&'s self) -> Result<()> {
+        self.pending_deletions.wait()
+    }
+
     fn batch_put<'a>(
         &'a self,
         data: Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>,
@@ -126,20 +460,107 @@ impl<'s> Storage<'s> for SqliteStorage {
     }
 
     fn range_compact(&'_ self, _lower: &[u8], _upper: &[u8]) -> Result<()> {
-        let mut pool = self.pool.lock().unwrap();
-        while pool.pop().is_some() {}
+        self.pending_deletions.wait()?;
+        self.pool.reset();
         Ok(())
     }
 
     fn storage_kind(&self) -> &'static str {
         "sqlite"
     }
+
+    fn storage_info(&'s self) -> Result<NamedRows> {
+        let mut entries = vec![];
+        // the sizes are only reported if a connection is free, so as not to wait for one
+        if let Some(conn) = self.pool.try_connect()? {
+            let read = || -> Result<_> {
+                Ok((
+                    read_int_pragma(&conn, "page_count")?[0],
+                    read_int_pragma(&conn, "page_size")?[0],
+                    read_int_pragma(&conn, "freelist_count")?[0],
+                    wal_file_size(&conn)?,
+                ))
+            };
+            let read = read();
+            self.pool.give_back(conn);
+            let (page_count, page_size, freelist_count, wal_size) = read?;
+            entries.extend([
+                ("page_count", DataValue::from(page_count)),
+                ("page_size", DataValue::from(page_size)),
+                ("db_size_bytes", DataValue::from(page_count * page_size)),
+                ("freelist_count", DataValue::from(freelist_count)),
+                ("wal_size_bytes", DataValue::from(wal_size)),
+            ]);
+        }
+        let (pool_idle, pool_open) = {
+            let state = self.pool.state.lock().unwrap();
+            (state.idle.len(), state.open)
+        };
+        entries.extend([
+            ("pool_idle", DataValue::from(pool_idle as i64)),
+            ("pool_open", DataValue::from(pool_open as i64)),
+            ("pool_max_size", DataValue::from(self.pool.max_size as i64)),
+            (
+                "pool_active",
+                DataValue::from(self.active_txs.load(Ordering::Acquire) as i64),
+            ),
+        ]);
+        Ok(storage_info_rows(self.storage_kind(), entries))
+    }
+}
+
+fn delete_range(conn: &Connection, query: &str, lower: &[u8], upper: &[u8]) -> Result<()> {
+    let mut statement = conn.prepare(query).map_err(SqliteError)?;
+    statement.bind((1, lower)).map_err(SqliteError)?;
+    statement.bind((2, upper)).map_err(SqliteError)?;
+    while statement.next().map_err(SqliteError)? != State::Done {}
+    Ok(())
+}
+
+/// The size of the `-wal` file next to the database, zero if there is none, as for databases
+/// in memory or not in WAL mode. Read from the file system, as checkpointing the WAL to learn
+/// its size would write to the database.
+fn wal_file_size(conn: &Connection) -> Result<i64> {
+    // columns: seq, name, file; the file is empty for databases in memory
+    let mut statement = conn.prepare("pragma database_list;").map_err(SqliteError)?;
+    while statement.next().map_err(SqliteError)? == State::Row {
+        if statement.read::<String, _>(1).map_err(SqliteError)? != "main" {
+            continue;
+        }
+        let file = statement.read::<String, _>(2).map_err(SqliteError)?;
+        if file.is_empty() {
+            break;
+        }
+        return Ok(match std::fs::metadata(format!("{file}-wal")) {
+            Ok(meta) => meta.len() as i64,
+            Err(_) => 0,
+        });
+    }
+    Ok(0)
+}
+
+/// Reads the columns of the row returned by the pragma, zeros if it returns no row
+fn read_int_pragma(conn: &Connection, pragma: &str) -> Result<Vec<i64>> {
+    let mut statement = conn
+        .prepare(format!("pragma {pragma};"))
+        .map_err(SqliteError)?;
+    let n_cols = statement.column_count();
+    Ok(match statement.next().map_err(SqliteError)? {
+        State::Row => (0..n_cols)
+            .map(|col| statement.read::<i64, _>(col))
+            .collect::<std::result::Result<_, _>>()
+            .map_err(SqliteError)?,
+        State::Done => vec![0; n_cols],
+    })
 }
 
 pub struct SqliteTx<'a> {
-    lock: Either<ShardedLockReadGuard<'a, ()>, ShardedLockWriteGuard<'a, ()>>,
+    /// no read lock is taken in WAL mode
+    lock: Either<Option<ShardedLockReadGuard<'a, ()>>, ShardedLockWriteGuard<'a, ()>>,
     storage: &'a SqliteStorage,
-    conn: Option<ConnectionWithFullMutex>,
+    conn: Option<Connection>,
+    /// whether `begin` has been executed
+    in_tx: bool,
     stmts: [Mutex<Option<Statement<'a>>>; N_CACHED_QUERIES],
     committed: bool,
 }
@@ -166,15 +587,17 @@ const SKIP_RANGE_QUERY: usize = 5;
 
 impl Drop for SqliteTx<'_> {
     fn drop(&mut self) {
-        if let Right(ShardedLockWriteGuard { .. }) = self.lock {
-            if !self.committed {
-                let query = r#"rollback;"#;
-                let _ = self.conn.as_ref().unwrap().execute(query);
-            }
+        // the cached statements refer to the connection
+        for stmt in &self.stmts {
+            stmt.lock().unwrap().take();
+        }
+        if self.in_tx && !self.committed {
+            let query = r#"rollback;"#;
+            let _ = self.conn.as_ref().unwrap().execute(query);
         }
-        let mut pool = self.storage.pool.lock().unwrap();
         let conn = self.conn.take().unwrap();
-        pool.push(conn)
+        self.storage.pool.give_back(conn);
+        self.storage.active_txs.fetch_sub(1, Ordering::AcqRel);
     }
 }
 
@@ -203,9 +626,9 @@ impl<'s> StoreTx<'s> for SqliteTx<'s> {
         statement.reset().unwrap();
 
         statement.bind((1, key)).unwrap();
-        Ok(match statement.next().into_diagnostic()? {
+        Ok(match statement.next().map_err(SqliteError)? {
             State::Row => {
-                let res = statement.read::<Vec<u8>, _>(0).into_diagnostic()?;
+                let res = statement.read::<Vec<u8>, _>(0).map_err(SqliteError)?;
                 Some(res)
             }
             State::Done => None,
@@ -228,7 +651,7 @@ impl<'s> StoreTx<'s> for SqliteTx<'s> {
 
         statement.bind((1, key)).unwrap();
         statement.bind((2, val)).unwrap();
-        while statement.next().into_diagnostic()? != State::Done {}
+        while statement.next().map_err(SqliteError)? != State::Done {}
         Ok(())
     }
 
@@ -239,7 +662,7 @@ impl<'s> StoreTx<'s> for SqliteTx<'s> {
         statement.reset().unwrap();
 
         statement.bind((1, key)).unwrap();
-        while statement.next().into_diagnostic()? != State::Done {}
+        while statement.next().map_err(SqliteError)? != State::Done {}
 
         Ok(())
     }
@@ -251,7 +674,7 @@ impl<'s> StoreTx<'s> for SqliteTx<'s> {
         statement.reset().unwrap();
 
         statement.bind((1, key)).unwrap();
-        Ok(match statement.next().into_diagnostic()? {
+        Ok(match statement.next().map_err(SqliteError)? {
             State::Row => true,
             State::Done => false,
         })
@@ -262,7 +685,7 @@ impl<'s> StoreTx<'s> for SqliteTx<'s> {
             if !self.committed {
                 let query = r#"commit;"#;
                 let mut statement = self.conn.as_ref().unwrap().prepare(query).unwrap();
-                while statement.next().into_diagnostic()? != State::Done {}
+                while statement.next().map_err(SqliteError)? != State::Done {}
                 self.committed = true;
             } else {
                 bail!("multiple commits")
@@ -304,6 +727,26 @@ impl<'s> StoreTx<'s> for SqliteTx<'s> {
         })
     }
 
+    fn range_skip_scan_tuple_window<'a>(
+        &'a self,
+        lower: &[u8],
+        upper: &[u8],
+        valid_from: ValidityTs,
+        valid_to: ValidityTs,
+    ) -> Box<dyn Iterator<Item = Result<Tuple>> + 'a>
+    where
+        's: 'a,
+    {
+        let query = QUERIES[SKIP_RANGE_QUERY];
+        let statement = self.conn.as_ref().unwrap().prepare(query).unwrap();
+        Box::new(WindowIter {
+            stmt: statement,
+            window: ValidityWindow::new(valid_from, valid_to),
+            next_bound: lower.to_vec(),
+            upper_bound: upper.to_vec(),
+        })
+    }
+
     fn range_scan<'a>(
         &'a self,
         lower: &[u8],
@@ -344,10 +787,9 @@ impl<'l> Iterator for TupleIter<'l> {
             Ok(State::Row) => {
                 let k = self.0.read::<Vec<u8>, _>(0).unwrap();
                 let v = self.0.read::<Vec<u8>, _>(1).unwrap();
-                let tuple = decode_tuple_from_kv(&k, &v);
-                Some(Ok(tuple))
+                Some(decode_tuple_from_kv(&k, &v))
             }
-            Err(err) => Some(Err(miette!(err))),
+            Err(err) => Some(Err(SqliteError(err).into())),
         }
     }
 }
@@ -365,7 +807,7 @@ impl<'l> Iterator for RawIter<'l> {
                 let v = self.0.read::<Vec<u8>, _>(1).unwrap();
                 Some(Ok((k, v)))
             }
-            Err(err) => Some(Err(miette!(err))),
+            Err(err) => Some(Err(SqliteError(err).into())),
         }
     }
 }
@@ -380,19 +822,19 @@ struct SkipIter<'l> {
 impl<'l> SkipIter<'l> {
     fn next_inner(&mut self) -> Result<Option<Tuple>> {
         loop {
-            self.stmt.reset().into_diagnostic()?;
+            self.stmt.reset().map_err(SqliteError)?;
             self.stmt.bind((1, &self.next_bound as &[u8])).unwrap();
             self.stmt.bind((2, &self.upper_bound as &[u8])).unwrap();
 
-            match self.stmt.next().into_diagnostic()? {
+            match self.stmt.next().map_err(SqliteError)? {
                 State::Done => return Ok(None),
                 State::Row => {
                     let k = self.stmt.read::<Vec<u8>, _>(0).unwrap();
-                    let (ret, nxt_bound) = check_key_for_validity(&k, self.valid_at);
+                    let (ret, nxt_bound) = check_key_for_validity(&k, self.valid_at)?;
                     self.next_bound = nxt_bound;
                     if let Some(mut tup) = ret {
                         let v = self.stmt.read::<Vec<u8>, _>(1).unwrap();
-                        extend_tuple_from_v(&mut tup, &v);
+                        extend_tuple_from_v(&mut tup, &v)?;
                         return Ok(Some(tup));
                     }
                 }
@@ -408,3 +850,242 @@ impl<'l> Iterator for SkipIter<'l> {
         swap_option_result(self.next_inner())
     }
 }
+
+struct WindowIter<'l> {
+    stmt: Statement<'l>,
+    window: ValidityWindow,
+    next_bound: Vec<u8>,
+    upper_bound: Vec<u8>,
+}
+
+impl<'l> WindowIter<'l> {
+    fn next_inner(&mut self) -> Result<Option<Tuple>> {
+        loop {
+            self.stmt.reset().map_err(SqliteError)?;
+            self.stmt.bind((1, &self.next_bound as &[u8])).unwrap();
+            self.stmt.bind((2, &self.upper_bound as &[u8])).unwrap();
+
+            match self.stmt.next().map_err(SqliteError)? {
+                State::Done => return Ok(None),
+                State::Row => {
+                    let k = self.stmt.read::<Vec<u8>, _>(0).unwrap();
+                    let (ret, nxt_bound) = self.window.check_key(&k)?;
+                    self.next_bound = nxt_bound;
+                    if let Some((mut tup, interval)) = ret {
+                        let v = self.stmt.read::<Vec<u8>, _>(1).unwrap();
+                        extend_tuple_from_v(&mut tup, &v)?;
+                        tup.extend(interval);
+                        return Ok(Some(tup));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<'l> Iterator for WindowIter<'l> {
+    type Item = Result<Tuple>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        swap_option_result(self.next_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::data::tuple::{Tuple, TupleT};
+    use crate::data::value::DataValue;
+    use crate::{Db, Storage};
+
+    #[test]
+    fn test_sqlite_pool_bounded() {
+        let path = std::env::temp_dir().join(format!("cozo-test-{}.db", rand::random::<u64>()));
+        let options = crate::SqliteOptions {
+            max_connections: 4,
+            ..Default::default()
+        };
+        let db = crate::new_cozo_sqlite_with_options(&path, options).unwrap();
+        db.run_script(
+            "?[k, v] := k in int_range(1000), v = k * 2 :create nums {k => v}",
+            Default::default(),
+        )
+        .unwrap();
+        let file = std::fs::canonicalize(&path).unwrap();
+        // the handles to the file open in the process, where the OS tells
+        let open_handles = || -> usize {
+            match std::fs::read_dir("/proc/self/fd") {
+                Ok(fds) => fds
+                    .filter_map(|fd| std::fs::read_link(fd.ok()?.path()).ok())
+                    .filter(|target| *target == file)
+                    .count(),
+                Err(_) => 0,
+            }
+        };
+        let pool_open = || {
+            db.db
+                .storage_info()
+                .unwrap()
+                .rows
+                .into_iter()
+                .find(|row| row[0] == DataValue::from("pool_open"))
+                .unwrap()[1]
+                .get_int()
+                .unwrap() as usize
+        };
+
+        let most_handles = AtomicUsize::new(0);
+        let most_open = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..64 {
+                s.spawn(|| {
+                    for _ in 0..10 {
+                        let res = db
+                            .run_script("?[v] := *nums{k: 10, v}", Default::default())
+                            .unwrap();
+                        assert_eq!(res.rows, vec![vec![DataValue::from(20)]]);
+                        most_handles.fetch_max(open_handles(), Ordering::Relaxed);
+                        most_open.fetch_max(pool_open(), Ordering::Relaxed);
+                    }
+                });
+            }
+            // with a writer among the readers
+            s.spawn(|| {
+                for i in 0..10 {
+                    db.run_script(
+                        "?[k, v] <- [[$k, 0]] :put nums {k => v}",
+                        BTreeMap::from([("k".to_string(), DataValue::from(1000 + i))]),
+                    )
+                    .unwrap();
+                }
+            });
+        });
+        assert!(most_handles.load(Ordering::Relaxed) <= 4);
+        assert!(most_open.load(Ordering::Relaxed) <= 4);
+        let res = db
+            .run_script("?[count(k)] := *nums{k}", Default::default())
+            .unwrap();
+        assert_eq!(res.rows[0][0], DataValue::from(1010));
+        drop(db);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_sqlite_backup_after_removal() {
+        let path = std::env::temp_dir().join(format!("cozo-test-{}.db", rand::random::<u64>()));
+        let backup = std::env::temp_dir().join(format!("cozo-test-{}.db", rand::random::<u64>()));
+        let db = crate::new_cozo_sqlite(&path).unwrap();
+        db.run_script(
+            "?[k, v] := k in int_range(100000), v = k + 1 :create big {k => v}",
+            Default::default(),
+        )
+        .unwrap();
+        let id = db
+            .transact()
+            .unwrap()
+            .get_relation("big", false)
+            .unwrap()
+            .id;
+        db.run_script("::remove big", Default::default()).unwrap();
+        // the keys of the relation are deleted in the background, but backups wait for them
+        db.backup_db(&backup).unwrap();
+
+        let backed_up = crate::new_cozo_sqlite(&backup).unwrap();
+        let tx = backed_up.transact().unwrap();
+        let lower = Tuple::default().encode_as_key(id);
+        let upper = Tuple::default().encode_as_key(id.next());
+        assert_eq!(tx.store_tx.range_scan(&lower, &upper).count(), 0);
+        drop(tx);
+        drop(backed_up);
+        drop(db);
+        let _ = std::fs::remove_file(path);
+        let _ = std::fs::remove_file(backup);
+    }
+
+    #[test]
+    fn test_failed_deletion_reported() {
+        let pending = super::PendingDeletions::default();
+        let failing = std::thread::spawn(|| -> miette::Result<()> { miette::bail!("failed") });
+        while !failing.is_finished() {
+            std::thread::yield_now();
+        }
+        pending.push(failing);
+        // the finished deletion is joined by the next one, its error kept for the wait
+        pending.push(std::thread::spawn(|| Ok(())));
+        assert_eq!(pending.0.lock().unwrap().running.len(), 1);
+        assert!(pending.wait().is_err());
+        assert!(pending.wait().is_ok());
+    }
+
+    #[test]
+    fn test_sqlite_uri() {
+        use crate::SqliteOptions;
+
+        // URIs are taken as such even if sqlite is initialized by a connection of its own
+        drop(sqlite::Connection::open(":memory:").unwrap());
+        let path = std::env::temp_dir().join(format!("cozo-test-{}.db", rand::random::<u64>()));
+        let read = |db: &Db<crate::SqliteStorage>| {
+            db.run_script("?[k, v] := *kv{k, v}", Default::default())
+                .unwrap()
+                .rows
+        };
+        let missing = SqliteOptions {
+            create_if_missing: false,
+            ..Default::default()
+        };
+        assert!(crate::new_cozo_sqlite_with_options(&path, missing.clone()).is_err());
+        {
+            let db = crate::new_cozo_sqlite(&path).unwrap();
+            db.run_script(
+                "?[k, v] <- [[1, 'a']] :create kv {k => v}",
+                Default::default(),
+            )
+            .unwrap();
+        }
+        let db = crate::new_cozo_sqlite_with_options(&path, missing).unwrap();
+        assert_eq!(read(&db).len(), 1);
+        drop(db);
+
+        // a read-only copy opened by a URI, while another instance writes
+        let writer = crate::new_cozo_sqlite(&path).unwrap();
+        let uri = format!("file:{}?mode=ro", path.display());
+        let options = SqliteOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        let reader = crate::new_cozo_sqlite_with_options(&uri, options).unwrap();
+        assert_eq!(read(&reader).len(), 1);
+        writer
+            .run_script("?[k, v] <- [[2, 'b']] :put kv {k => v}", Default::default())
+            .unwrap();
+        assert_eq!(read(&reader).len(), 2);
+        assert!(reader.db.transact(true).is_err());
+        assert!(reader
+            .run_script("?[k, v] <- [[3, 'c']] :put kv {k => v}", Default::default())
+            .is_err());
+        drop(reader);
+        drop(writer);
+        let _ = std::fs::remove_file(path);
+
+        // a shared in-memory database is seen by all the connections of the pool
+        let uri = format!(
+            "file:cozo-test-{}?mode=memory&cache=shared",
+            rand::random::<u64>()
+        );
+        let db = crate::new_cozo_sqlite(&uri).unwrap();
+        db.run_script(
+            "?[k, v] <- [[1, 'a']] :create kv {k => v}",
+            Default::default(),
+        )
+        .unwrap();
+        {
+            let _held = db.transact().unwrap();
+            assert_eq!(read(&db).len(), 1);
+        }
+        db.run_script("::compact", Default::default()).unwrap();
+        assert_eq!(read(&db).len(), 1);
+        assert!(crate::new_cozo_sqlite(":memory:").is_err());
+    }
+}
@@ -30,6 +30,7 @@ impl<'s> Storage<'s> for TempStorage {
     fn transact(&'s self, _write: bool) -> Result<Self::Tx> {
         Ok(TempTx {
             store: Default::default(),
+            undo: None,
         })
     }
 
@@ -51,6 +52,34 @@ impl<'s> Storage<'s> for TempStorage {
 
 pub(crate) struct TempTx {
     store: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// the previous values of the keys written while savepoints are set,
+    /// see [crate::runtime::savepoint]
+    undo: Option<Vec<(Vec<u8>, Option<Vec<u8>>)>>,
+}
+
+impl TempTx {
+    pub(crate) fn set_recording(&mut self, recording: bool) {
+        self.undo = if recording { Some(vec![]) } else { None };
+    }
+    pub(crate) fn undo_len(&self) -> usize {
+        self.undo.as_ref().map_or(0, |undo| undo.len())
+    }
+    /// Writes back the previous values of the keys written after the first `len` recorded
+    pub(crate) fn undo_to(&mut self, len: usize) {
+        if let Some(undo) = &mut self.undo {
+            for (key, prev) in undo.split_off(len).into_iter().rev() {
+                match prev {
+                    Some(val) => self.store.insert(key, val),
+                    None => self.store.remove(&key),
+                };
+            }
+        }
+    }
+    fn record(&mut self, key: &[u8]) {
+        if let Some(undo) = &mut self.undo {
+            undo.push((key.to_vec(), self.store.get(key).cloned()));
+        }
+    }
 }
 
 impl<'s> StoreTx<'s> for TempTx {
@@ -59,6 +88,7 @@ impl<'s> StoreTx<'s> for TempTx {
     }
 
     fn put(&mut self, key: &[u8], val: &[u8]) -> Result<()> {
+        self.record(key);
         self.store.insert(key.to_vec(), val.to_vec());
         Ok(())
     }
@@ -72,6 +102,7 @@ impl<'s> StoreTx<'s> for TempTx {
     }
 
     fn del(&mut self, key: &[u8]) -> Result<()> {
+        self.record(key);
         self.store.remove(key);
         Ok(())
     }
@@ -95,7 +126,7 @@ impl<'s> StoreTx<'s> for TempTx {
         Box::new(
             self.store
                 .range(lower.to_vec()..upper.to_vec())
-                .map(|(k, v)| Ok(decode_tuple_from_kv(k, v))),
+                .map(|(k, v)| decode_tuple_from_kv(k, v)),
         )
     }
 
@@ -105,15 +136,12 @@ impl<'s> StoreTx<'s> for TempTx {
         upper: &[u8],
         valid_at: ValidityTs,
     ) -> Box<dyn Iterator<Item = Result<Tuple>> + 'a> {
-        Box::new(
-            SkipIterator {
-                inner: &self.store,
-                upper: upper.to_vec(),
-                valid_at,
-                next_bound: lower.to_vec(),
-            }
-            .map(Ok),
-        )
+        Box::new(SkipIterator {
+            inner: &self.store,
+            upper: upper.to_vec(),
+            valid_at,
+            next_bound: lower.to_vec(),
+        })
     }
 
     fn range_scan<'a>(
@@ -0,0 +1,427 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use itertools::Itertools;
+use miette::Result;
+
+use crate::data::tuple::{Tuple, ValidityWindow};
+use crate::data::value::{DataValue, Validity, ValidityTs};
+use crate::format::{
+    decode_tuple_from_value, encode_tuple_key, encode_tuple_value, relation_prefix,
+};
+use crate::runtime::relation::extend_tuple_from_v;
+use crate::storage::{Storage, StoreTx};
+
+/// Checks that a storage engine behaves as Cozo requires of implementations of [Storage]
+/// and [StoreTx], panicking with the failed check otherwise. Meant to be called from the tests
+/// of engines implemented outside of this crate.
+///
+/// `open` must open the same database each time it is called, which must have no keys
+/// with the relation prefixes 1000 to 1011, used by the suite, the first time. The data
+/// committed through a storage must be seen by the storages opened after it is dropped.
+///
+/// Time travel is only checked if [StoreTx::range_skip_scan_tuple] does not return errors,
+/// as engines need not support it.
+pub fn storage_test_suite<S>(open: impl Fn() -> S)
+where
+    S: for<'s> Storage<'s>,
+{
+    let storage = open();
+    check_point_ops(&storage);
+    check_uncommitted(&storage);
+    check_range_scans(&storage);
+    check_time_travel(&storage);
+    check_del_range(&storage);
+    check_batch_put(&storage);
+    check_concurrency(&storage);
+    commit_rows(&storage, DURABLE_REL, 0..10);
+    drop(storage);
+    let reopened = open();
+    assert_eq!(
+        read_rows(&reopened, DURABLE_REL),
+        (0..10).map(|i| row(i, i)).collect_vec(),
+        "committed data must persist after the storage is dropped"
+    );
+    // the uncommitted rows of `check_uncommitted` are still not there
+    assert!(read_rows(&reopened, UNCOMMITTED_REL).is_empty());
+}
+
+// each check writes to relations of its own, the range and deletion checks also
+// to the relations around theirs
+const POINT_REL: u64 = 1000;
+const UNCOMMITTED_REL: u64 = 1001;
+const RANGE_REL: u64 = 1003;
+const TIME_TRAVEL_REL: u64 = 1005;
+const DEL_RANGE_REL: u64 = 1006;
+const BATCH_REL: u64 = 1009;
+const COUNTER_REL: u64 = 1010;
+const DURABLE_REL: u64 = 1011;
+
+fn key(rel: u64, k: i64) -> Vec<u8> {
+    encode_tuple_key(rel, &[DataValue::from(k)])
+}
+
+fn val(rel: u64, v: i64) -> Vec<u8> {
+    encode_tuple_value(rel, &[DataValue::from(v)])
+}
+
+fn row(k: i64, v: i64) -> Tuple {
+    vec![DataValue::from(k), DataValue::from(v)]
+}
+
+/// The bounds of the keys of the relation
+fn rel_range(rel: u64) -> (Vec<u8>, Vec<u8>) {
+    (
+        relation_prefix(rel).to_vec(),
+        relation_prefix(rel + 1).to_vec(),
+    )
+}
+
+fn commit_rows<S: for<'s> Storage<'s>>(storage: &S, rel: u64, ks: impl Iterator<Item = i64>) {
+    let mut tx = storage.transact(true).unwrap();
+    for k in ks {
+        tx.put(&key(rel, k), &val(rel, k)).unwrap();
+    }
+    tx.commit().unwrap();
+}
+
+fn read_rows<S: for<'s> Storage<'s>>(storage: &S, rel: u64) -> Vec<Tuple> {
+    let tx = storage.transact(false).unwrap();
+    let (lower, upper) = rel_range(rel);
+    let rows = tx.range_scan_tuple(&lower, &upper).try_collect().unwrap();
+    rows
+}
+
+fn read_val(res: Option<Vec<u8>>) -> Option<i64> {
+    res.map(|v| decode_tuple_from_value(&v).unwrap()[0].get_int().unwrap())
+}
+
+fn check_point_ops<S: for<'s> Storage<'s>>(storage: &S) {
+    let rel = POINT_REL;
+    let mut tx = storage.transact(true).unwrap();
+    for k in 0..3 {
+        tx.put(&key(rel, k), &val(rel, k)).unwrap();
+    }
+    tx.del(&key(rel, 1)).unwrap();
+    assert_eq!(
+        read_val(tx.get(&key(rel, 0), false).unwrap()),
+        Some(0),
+        "a transaction must see its own writes"
+    );
+    assert!(!tx.exists(&key(rel, 1), false).unwrap());
+    tx.commit().unwrap();
+    // engines may hold locks until transactions are dropped
+    drop(tx);
+
+    let mut tx = storage.transact(true).unwrap();
+    tx.put(&key(rel, 2), &val(rel, 20)).unwrap();
+    if tx.supports_par_put() {
+        tx.par_put(&key(rel, 3), &val(rel, 3)).unwrap();
+    } else {
+        tx.put(&key(rel, 3), &val(rel, 3)).unwrap();
+    }
+    tx.commit().unwrap();
+    drop(tx);
+
+    let tx = storage.transact(false).unwrap();
+    assert_eq!(read_val(tx.get(&key(rel, 0), false).unwrap()), Some(0));
+    assert_eq!(tx.get(&key(rel, 1), false).unwrap(), None);
+    assert_eq!(
+        read_val(tx.get(&key(rel, 2), false).unwrap()),
+        Some(20),
+        "puts must overwrite existing keys"
+    );
+    assert!(tx.exists(&key(rel, 3), false).unwrap());
+    assert!(!tx.exists(&key(rel, 4), false).unwrap());
+    let keys = [key(rel, 3), key(rel, 1), key(rel, 0)];
+    let keys = keys.iter().map(|k| k as &[u8]).collect_vec();
+    let found = tx
+        .multi_get(&keys, false)
+        .unwrap()
+        .into_iter()
+        .map(read_val)
+        .collect_vec();
+    assert_eq!(
+        found,
+        vec![Some(3), None, Some(0)],
+        "multi_get must return the values in the order of the keys"
+    );
+}
+
+fn check_uncommitted<S: for<'s> Storage<'s>>(storage: &S) {
+    let rel = UNCOMMITTED_REL;
+    {
+        let mut tx = storage.transact(true).unwrap();
+        tx.put(&key(rel, 0), &val(rel, 0)).unwrap();
+    }
+    assert!(
+        read_rows(storage, rel).is_empty(),
+        "transactions dropped without committing must leave nothing"
+    );
+}
+
+fn check_range_scans<S: for<'s> Storage<'s>>(storage: &S) {
+    let rel = RANGE_REL;
+    // the neighbouring relations must not be seen by scans of the relation
+    commit_rows(storage, rel - 1, 0..3);
+    commit_rows(storage, rel + 1, 0..3);
+    // out of order
+    commit_rows(storage, rel, (0..100).map(|i| (i * 37) % 100));
+
+    let tx = storage.transact(false).unwrap();
+    let scanned: Vec<_> = tx
+        .range_scan(&key(rel, 10), &key(rel, 20))
+        .try_collect()
+        .unwrap();
+    let expected = (10..20).map(|i| (key(rel, i), val(rel, i))).collect_vec();
+    assert_eq!(
+        scanned, expected,
+        "range scans must return the keys from the lower bound inclusive to the upper \
+         bound exclusive, in ascending order"
+    );
+    let tuples: Vec<_> = tx
+        .range_scan_tuple(&key(rel, 10), &key(rel, 20))
+        .try_collect()
+        .unwrap();
+    assert_eq!(tuples, (10..20).map(|i| row(i, i)).collect_vec());
+    let (lower, upper) = rel_range(rel);
+    assert_eq!(tx.range_scan(&lower, &upper).count(), 100);
+
+    let all: Vec<_> = tx.total_scan().try_collect().unwrap();
+    assert!(
+        all.windows(2).all(|w| w[0].0 < w[1].0),
+        "total scans must return the keys in ascending order"
+    );
+    for k in [key(rel - 1, 0), key(rel, 99), key(rel + 1, 2)] {
+        assert!(all.iter().any(|(found, _)| *found == k));
+    }
+    drop(tx);
+
+    // scans in a write transaction see its writes
+    let mut tx = storage.transact(true).unwrap();
+    tx.del(&key(rel, 11)).unwrap();
+    tx.put(&key(rel, 100), &val(rel, 100)).unwrap();
+    let keys = tx
+        .range_scan(&key(rel, 10), &key(rel, 101))
+        .map_ok(|(k, _)| k)
+        .try_collect::<_, Vec<_>, _>()
+        .unwrap();
+    assert!(!keys.contains(&key(rel, 11)));
+    assert!(keys.contains(&key(rel, 100)));
+}
+
+fn validity(ts: i64, is_assert: bool) -> DataValue {
+    DataValue::Validity(Validity {
+        timestamp: ValidityTs(Reverse(ts)),
+        is_assert: Reverse(is_assert),
+    })
+}
+
+fn check_time_travel<S: for<'s> Storage<'s>>(storage: &S) {
+    let rel = TIME_TRAVEL_REL;
+    // for each key, its versions as timestamps and whether they are assertions
+    let histories: Vec<Vec<(i64, bool)>> = vec![
+        vec![(10, true)],
+        vec![(10, true), (20, true), (30, true)],
+        vec![(10, true), (20, false), (30, true)],
+        vec![(20, true), (30, false)],
+        vec![(10, false)],
+    ];
+    let mut versions = BTreeMap::new();
+    for (k, history) in histories.iter().enumerate() {
+        for &(ts, is_assert) in history {
+            let tuple_key =
+                encode_tuple_key(rel, &[DataValue::from(k as i64), validity(ts, is_assert)]);
+            versions.insert(tuple_key, val(rel, ts));
+        }
+    }
+    let mut tx = storage.transact(true).unwrap();
+    for (k, v) in &versions {
+        tx.put(k, v).unwrap();
+    }
+    tx.commit().unwrap();
+    drop(tx);
+
+    let (lower, upper) = rel_range(rel);
+    let tx = storage.transact(false).unwrap();
+    let skip_scan = |valid_at: i64| -> Result<Vec<Tuple>> {
+        tx.range_skip_scan_tuple(&lower, &upper, ValidityTs(Reverse(valid_at)))
+            .try_collect()
+    };
+    if skip_scan(0).is_err() {
+        // the engine does not support time travel
+        return;
+    }
+    for valid_at in [5, 10, 15, 20, 25, 30, 35] {
+        let expected = histories
+            .iter()
+            .enumerate()
+            .filter_map(|(k, history)| {
+                let &(ts, is_assert) = history.iter().rev().find(|(ts, _)| *ts <= valid_at)?;
+                is_assert.then(|| {
+                    vec![
+                        DataValue::from(k as i64),
+                        validity(ts, true),
+                        DataValue::from(ts),
+                    ]
+                })
+            })
+            .collect_vec();
+        assert_eq!(
+            skip_scan(valid_at).unwrap(),
+            expected,
+            "skip scans must return the assertions valid at {valid_at}"
+        );
+    }
+    for (from, to) in [(0, 100), (15, 25), (20, 20), (35, 40)] {
+        let mut window = ValidityWindow::new(ValidityTs(Reverse(from)), ValidityTs(Reverse(to)));
+        let expected = versions
+            .iter()
+            .filter_map(|(k, v)| {
+                window.check_key(k).unwrap().0.map(|(mut tup, interval)| {
+                    extend_tuple_from_v(&mut tup, v).unwrap();
+                    tup.extend(interval);
+                    tup
+                })
+            })
+            .collect_vec();
+        let scanned: Vec<_> = tx
+            .range_skip_scan_tuple_window(
+                &lower,
+                &upper,
+                ValidityTs(Reverse(from)),
+                ValidityTs(Reverse(to)),
+            )
+            .try_collect()
+            .unwrap();
+        assert_eq!(
+            scanned, expected,
+            "window scans must return the versions valid from {from} to {to}"
+        );
+    }
+}
+
+fn check_del_range<S: for<'s> Storage<'s>>(storage: &S) {
+    let rel = DEL_RANGE_REL;
+    for r in rel..rel + 3 {
+        commit_rows(storage, r, 0..100);
+    }
+    let (lower, upper) = rel_range(rel + 1);
+    storage.del_range(&lower, &upper).unwrap();
+    storage.wait_for_deletions().unwrap();
+    assert_eq!(read_rows(storage, rel).len(), 100);
+    assert!(
+        read_rows(storage, rel + 1).is_empty(),
+        "the deleted range must be gone once the deletions are waited for"
+    );
+    assert_eq!(read_rows(storage, rel + 2).len(), 100);
+    let tx = storage.transact(false).unwrap();
+    assert_eq!(
+        tx.total_scan()
+            .filter(|kv| {
+                let (k, _) = kv.as_ref().unwrap();
+                lower <= *k && *k < upper
+            })
+            .count(),
+        0
+    );
+}
+
+fn check_batch_put<S: for<'s> Storage<'s>>(storage: &S) {
+    let rel = BATCH_REL;
+    let data = (0..1000).map(|i| Ok((key(rel, i), val(rel, i))));
+    storage.batch_put(Box::new(data)).unwrap();
+    assert_eq!(
+        read_rows(storage, rel),
+        (0..1000).map(|i| row(i, i)).collect_vec()
+    );
+}
+
+/// Writers increment two counters together, retrying on errors, while readers check
+/// that they always see the counters equal.
+fn check_concurrency<S: for<'s> Storage<'s>>(storage: &S) {
+    const WRITERS: i64 = 8;
+    const INCREMENTS: i64 = 20;
+
+    let rel = COUNTER_REL;
+    let (a, b) = (key(rel, 0), key(rel, 1));
+    let mut tx = storage.transact(true).unwrap();
+    tx.put(&a, &val(rel, 0)).unwrap();
+    tx.put(&b, &val(rel, 0)).unwrap();
+    tx.commit().unwrap();
+    drop(tx);
+
+    let increment = || -> Result<()> {
+        let mut tx = storage.transact(true)?;
+        let n = read_val(tx.get(&a, true)?).unwrap();
+        let m = read_val(tx.get(&b, true)?).unwrap();
+        tx.put(&a, &val(rel, n + 1))?;
+        tx.put(&b, &val(rel, m + 1))?;
+        tx.commit()
+    };
+    let writing = AtomicBool::new(true);
+    std::thread::scope(|s| {
+        let writers = (0..WRITERS)
+            .map(|_| {
+                s.spawn(|| {
+                    for _ in 0..INCREMENTS {
+                        while increment().is_err() {}
+                    }
+                })
+            })
+            .collect_vec();
+        for _ in 0..4 {
+            s.spawn(|| {
+                while writing.load(Ordering::Acquire) {
+                    let tx = storage.transact(false).unwrap();
+                    let n = read_val(tx.get(&a, false).unwrap());
+                    let m = read_val(tx.get(&b, false).unwrap());
+                    assert_eq!(
+                        n, m,
+                        "transactions must not see the writes of others partially"
+                    );
+                }
+            });
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        writing.store(false, Ordering::Release);
+    });
+    let tx = storage.transact(false).unwrap();
+    assert_eq!(
+        read_val(tx.get(&a, false).unwrap()),
+        Some(WRITERS * INCREMENTS),
+        "concurrent writes must not be lost"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::MemStorage;
+
+    #[test]
+    fn test_storage_suite_mem() {
+        let storage = MemStorage::default();
+        crate::storage::test_suite::storage_test_suite(|| storage.clone());
+    }
+
+    #[cfg(feature = "storage-sqlite")]
+    #[test]
+    fn test_storage_suite_sqlite() {
+        let path = std::env::temp_dir().join(format!("cozo-test-{}.db", rand::random::<u64>()));
+        crate::storage::test_suite::storage_test_suite(|| {
+            crate::new_cozo_sqlite(&path).unwrap().db
+        });
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,89 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+#![cfg(feature = "async")]
+
+use std::time::Duration;
+
+use futures::StreamExt;
+use serde_json::json;
+use tokio::time::timeout;
+
+use cozo::{new_cozo_mem, CallbackOp, DataValue};
+
+#[tokio::test]
+async fn run_script_async() {
+    let db = new_cozo_mem().unwrap();
+    let res = db
+        .run_script_async("?[a] <- [[1], [2]]", Default::default())
+        .await
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[1], [2]]));
+
+    let err = db
+        .run_script_async("?[a] <- [[1]] :put nothing {a}", Default::default())
+        .await
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("nothing"));
+
+    // many scripts at once, more than there are threads to run them
+    let mut db = db;
+    db.set_async_threads(2);
+    let futures = (0..10)
+        .map(|i| {
+            db.run_script_async(
+                "?[x] := x = $i * 2",
+                [("i".to_string(), DataValue::from(i))].into(),
+            )
+        })
+        .collect::<Vec<_>>();
+    for (i, fut) in futures.into_iter().enumerate() {
+        let res = tokio::spawn(fut).await.unwrap().unwrap();
+        assert_eq!(res.rows[0][0], DataValue::from(i as i64 * 2));
+    }
+}
+
+#[tokio::test]
+async fn dropping_the_future_kills_the_script() {
+    let db = new_cozo_mem().unwrap();
+    let sleeping = db.run_script_async("%sleep 100000", Default::default());
+    assert!(timeout(Duration::from_millis(200), sleeping).await.is_err());
+    // the script is killed rather than left running
+    for _ in 0..250 {
+        if db.list_running().unwrap().is_empty() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("the script of the dropped future is still running");
+}
+
+#[tokio::test]
+async fn subscribe() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(":create kv {k => v}", Default::default())
+        .unwrap();
+    let mut changes = db.subscribe("kv");
+    db.run_script_async("?[k, v] <- [[1, 'a']] :put kv {k => v}", Default::default())
+        .await
+        .unwrap();
+    db.run_script("?[k] <- [[1]] :rm kv {k}", Default::default())
+        .unwrap();
+
+    let next = timeout(Duration::from_secs(5), changes.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(next.op, CallbackOp::Put);
+    assert_eq!(next.new_rows.into_json()["rows"], json!([[1, "a"]]));
+    let next = timeout(Duration::from_secs(5), changes.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(next.op, CallbackOp::Rm);
+    assert_eq!(next.old_rows.into_json()["rows"], json!([[1, "a"]]));
+}
@@ -61,6 +61,30 @@ bool cozo_close_db(int32_t id);
  */
 char *cozo_run_query(int32_t db_id, const char *script_raw, const char *params_raw);
 
+/**
+ * Run a read-only query against a database, passing each row of the result to a callback
+ * instead of collecting the rows into a single JSON string.
+ * The result is sorted on disk as it is derived rather than held in memory, but the
+ * first row is only passed on once the query is evaluated.
+ *
+ * `db_id`:      the ID representing the database to run the query.
+ * `script_raw`: a UTF-8 encoded C-string for the CozoScript to execute.
+ * `params_raw`: a UTF-8 encoded C-string for the params of the query,
+ *               in JSON format, as for `cozo_run_query`.
+ * `on_row`:     called with each row, as a UTF-8 encoded C-string containing a JSON array
+ *               that is only valid during the call, and `user_data`.
+ *               Returns `false` to stop the query.
+ * `user_data`:  passed to `on_row` unchanged.
+ *
+ * Returns a UTF-8-encoded C-string that **must** be freed with `cozo_free_str`.
+ * The string contains the JSON return value of the query, with the headers but no rows.
+ */
+char *cozo_run_query_streaming(int32_t db_id,
+                               const char *script_raw,
+                               const char *params_raw,
+                               bool (*on_row)(const char*, void*),
+                               void *user_data);
+
 /**
  * Import data into relations
  *
@@ -97,6 +121,26 @@ char *cozo_export_relations(int32_t db_id,
 char *cozo_backup(int32_t db_id,
                   const char *out_path);
 
+/**
+ * Backup the database in chunks, reporting the progress to a callback.
+ *
+ * `db_id`:       the ID representing the database.
+ * `out_path`:    path of the output file.
+ * `resume`:      whether to continue a cancelled or interrupted backup into the file.
+ * `on_progress`: called after each chunk is written, with a UTF-8 encoded C-string containing
+ *                the progress as a JSON object that is only valid during the call, and
+ *                `user_data`. Returns `false` to cancel the backup.
+ * `user_data`:   passed to `on_progress` unchanged.
+ *
+ * Returns a UTF-8-encoded C-string indicating the result that **must** be freed with `cozo_free_str`.
+ * The field `complete` of the result is `false` if the backup was cancelled.
+ */
+char *cozo_backup_with_progress(int32_t db_id,
+                                const char *out_path,
+                                bool resume,
+                                bool (*on_progress)(const char*, void*),
+                                void *user_data);
+
 /**
  * Restore the database from a backup.
  *
@@ -122,6 +166,95 @@ char *cozo_restore(int32_t db_id,
 char *cozo_import_from_backup(int32_t db_id,
                               const char *json_payload);
 
+/**
+ * Verify that a backup has the same relations and rows as the database
+ *
+ * `db_id`:        the ID representing the database.
+ * `json_payload`: a UTF-8 encoded JSON payload: `{"path": ..., "sample": ..., "valid_at": ...}`,
+ *                 where `sample` and `valid_at` are optional
+ *
+ * Returns a UTF-8-encoded C-string indicating the result that **must** be freed with `cozo_free_str`.
+ */
+char *cozo_verify_backup(int32_t db_id,
+                         const char *json_payload);
+
+/**
+ * Export a relation into a JSON Lines file, a JSON object keyed by column names per row
+ *
+ * `db_id`:        the ID representing the database.
+ * `json_payload`: a UTF-8 encoded JSON payload: `{"relation": ..., "path": ...}`
+ *
+ * Returns a UTF-8-encoded C-string indicating the result that **must** be freed with `cozo_free_str`.
+ */
+char *cozo_export_relations_jsonl(int32_t db_id,
+                                  const char *json_payload);
+
+/**
+ * Import data into a relation from a JSON Lines file, as written by `cozo_export_relations_jsonl`
+ *
+ * Note that triggers are _not_ run for the relation, if any exists.
+ *
+ * `db_id`:        the ID representing the database.
+ * `json_payload`: a UTF-8 encoded JSON payload: `{"relation": ..., "path": ...}`
+ *
+ * Returns a UTF-8-encoded C-string indicating the result that **must** be freed with `cozo_free_str`.
+ */
+char *cozo_import_relations_jsonl(int32_t db_id,
+                                  const char *json_payload);
+
+/**
+ * Begin a transaction spanning several queries, which sees none of the writes done by
+ * others after it began, and whose writes are only seen by others once it is committed.
+ *
+ * `db_id`: the ID representing the database.
+ * `write`: whether queries of the transaction may write to the database.
+ *
+ * Returns a UTF-8-encoded C-string indicating the result that **must** be freed with `cozo_free_str`.
+ * On success, it contains the ID of the transaction: `{"ok":true,"id":...}`. The transaction
+ * must be ended with `cozo_commit_transaction` or `cozo_abort_transaction`.
+ */
+char *cozo_begin_transaction(int32_t db_id, bool write);
+
+/**
+ * Run a query in a transaction begun by `cozo_begin_transaction`.
+ *
+ * `db_id`:      the ID representing the database.
+ * `tx_id`:      the ID representing the transaction.
+ * `script_raw`: a UTF-8 encoded C-string for the CozoScript to execute.
+ * `params_raw`: a UTF-8 encoded C-string for the params of the query,
+ *               in JSON format, as for `cozo_run_query`.
+ * `read_only`:  if `true`, the query fails if it writes to a stored relation.
+ *
+ * Returns a UTF-8-encoded C-string that **must** be freed with `cozo_free_str`.
+ * The string contains the JSON return value of the query.
+ */
+char *cozo_run_query_in_transaction(int32_t db_id,
+                                    uint32_t tx_id,
+                                    const char *script_raw,
+                                    const char *params_raw,
+                                    bool read_only);
+
+/**
+ * Commit a transaction begun by `cozo_begin_transaction`, which then ends.
+ *
+ * `db_id`: the ID representing the database.
+ * `tx_id`: the ID representing the transaction.
+ *
+ * Returns a UTF-8-encoded C-string indicating the result that **must** be freed with `cozo_free_str`.
+ */
+char *cozo_commit_transaction(int32_t db_id, uint32_t tx_id);
+
+/**
+ * Abort a transaction begun by `cozo_begin_transaction`, which then ends,
+ * rolling back its writes.
+ *
+ * `db_id`: the ID representing the database.
+ * `tx_id`: the ID representing the transaction.
+ *
+ * Returns a UTF-8-encoded C-string indicating the result that **must** be freed with `cozo_free_str`.
+ */
+char *cozo_abort_transaction(int32_t db_id, uint32_t tx_id);
+
 /**
  * Free any C-string returned from the Cozo C API.
  * Must be called exactly once for each returned C-string.
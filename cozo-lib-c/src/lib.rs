@@ -9,7 +9,7 @@
 #![allow(clippy::missing_safety_doc)]
 
 use std::collections::BTreeMap;
-use std::ffi::{c_char, CStr, CString};
+use std::ffi::{c_char, c_void, CStr, CString};
 use std::ptr::null_mut;
 use std::sync::atomic::{AtomicI32, Ordering};
 use std::sync::Mutex;
@@ -145,6 +145,71 @@ pub unsafe extern "C" fn cozo_run_query(
     CString::new(result).unwrap().into_raw()
 }
 
+/// Run a read-only query against a database, passing each row of the result to a callback
+/// instead of collecting the rows into a single JSON string.
+/// The result is sorted on disk as it is derived rather than held in memory, but the
+/// first row is only passed on once the query is evaluated.
+///
+/// `db_id`:      the ID representing the database to run the query.
+/// `script_raw`: a UTF-8 encoded C-string for the CozoScript to execute.
+/// `params_raw`: a UTF-8 encoded C-string for the params of the query,
+///               in JSON format, as for `cozo_run_query`.
+/// `on_row`:     called with each row, as a UTF-8 encoded C-string containing a JSON array
+///               that is only valid during the call, and `user_data`.
+///               Returns `false` to stop the query.
+/// `user_data`:  passed to `on_row` unchanged.
+///
+/// Returns a UTF-8-encoded C-string that **must** be freed with `cozo_free_str`.
+/// The string contains the JSON return value of the query, with the headers but no rows.
+#[no_mangle]
+pub unsafe extern "C" fn cozo_run_query_streaming(
+    db_id: i32,
+    script_raw: *const c_char,
+    params_raw: *const c_char,
+    on_row: extern "C" fn(*const c_char, *mut c_void) -> bool,
+    user_data: *mut c_void,
+) -> *mut c_char {
+    let script = match CStr::from_ptr(script_raw).to_str() {
+        Ok(p) => p,
+        Err(_) => {
+            return CString::new(r##"{"ok":false,"message":"script is not UTF-8 encoded"}"##)
+                .unwrap()
+                .into_raw();
+        }
+    };
+    let db = {
+        let db_ref = {
+            let dbs = HANDLES.dbs.lock().unwrap();
+            dbs.get(&db_id).cloned()
+        };
+        match db_ref {
+            None => {
+                return CString::new(r##"{"ok":false,"message":"database closed"}"##)
+                    .unwrap()
+                    .into_raw();
+            }
+            Some(db) => db,
+        }
+    };
+    let params_str = match CStr::from_ptr(params_raw).to_str() {
+        Ok(p) => p,
+        Err(_) => {
+            return CString::new(
+                r##"{"ok":false,"message":"params argument is not UTF-8 encoded"}"##,
+            )
+            .unwrap()
+            .into_raw();
+        }
+    };
+
+    let result = db.run_script_streaming_str(script, params_str, |row| {
+        // JSON text never contains NUL
+        let row = CString::new(row).unwrap();
+        on_row(row.as_ptr(), user_data)
+    });
+    CString::new(result).unwrap().into_raw()
+}
+
 #[no_mangle]
 /// Import data into relations
 ///
@@ -245,6 +310,52 @@ pub unsafe extern "C" fn cozo_backup(db_id: i32, out_path: *const c_char) -> *mu
     CString::new(db.backup_db_str(data)).unwrap().into_raw()
 }
 
+#[no_mangle]
+/// Backup the database in chunks, reporting the progress to a callback.
+///
+/// `db_id`:       the ID representing the database.
+/// `out_path`:    path of the output file.
+/// `resume`:      whether to continue a cancelled or interrupted backup into the file.
+/// `on_progress`: called after each chunk is written, with a UTF-8 encoded C-string containing
+///                the progress as a JSON object that is only valid during the call, and
+///                `user_data`. Returns `false` to cancel the backup.
+/// `user_data`:   passed to `on_progress` unchanged.
+///
+/// Returns a UTF-8-encoded C-string indicating the result that **must** be freed with `cozo_free_str`.
+/// The field `complete` of the result is `false` if the backup was cancelled.
+pub unsafe extern "C" fn cozo_backup_with_progress(
+    db_id: i32,
+    out_path: *const c_char,
+    resume: bool,
+    on_progress: extern "C" fn(*const c_char, *mut c_void) -> bool,
+    user_data: *mut c_void,
+) -> *mut c_char {
+    let db = {
+        let db_ref = {
+            let dbs = HANDLES.dbs.lock().unwrap();
+            dbs.get(&db_id).cloned()
+        };
+        match db_ref {
+            None => {
+                return CString::new(r##"{"ok":false,"message":"database closed"}"##)
+                    .unwrap()
+                    .into_raw();
+            }
+            Some(db) => db,
+        }
+    };
+    let data = match CStr::from_ptr(out_path).to_str() {
+        Ok(p) => p,
+        Err(err) => return CString::new(format!("{err}")).unwrap().into_raw(),
+    };
+    let result = db.backup_db_with_progress_str(data, resume, |progress| {
+        // JSON text never contains NUL
+        let progress = CString::new(progress).unwrap();
+        on_progress(progress.as_ptr(), user_data)
+    });
+    CString::new(result).unwrap().into_raw()
+}
+
 #[no_mangle]
 /// Restore the database from a backup.
 ///
@@ -315,6 +426,260 @@ pub unsafe extern "C" fn cozo_import_from_backup(
         .into_raw()
 }
 
+#[no_mangle]
+/// Verify that a backup has the same relations and rows as the database
+///
+/// `db_id`:        the ID representing the database.
+/// `json_payload`: a UTF-8 encoded JSON payload: `{"path": ..., "sample": ..., "valid_at": ...}`,
+///                 where `sample` and `valid_at` are optional
+///
+/// Returns a UTF-8-encoded C-string indicating the result that **must** be freed with `cozo_free_str`.
+pub unsafe extern "C" fn cozo_verify_backup(
+    db_id: i32,
+    json_payload: *const c_char,
+) -> *mut c_char {
+    let db = {
+        let db_ref = {
+            let dbs = HANDLES.dbs.lock().unwrap();
+            dbs.get(&db_id).cloned()
+        };
+        match db_ref {
+            None => {
+                return CString::new(r##"{"ok":false,"message":"database closed"}"##)
+                    .unwrap()
+                    .into_raw();
+            }
+            Some(db) => db,
+        }
+    };
+
+    let data = match CStr::from_ptr(json_payload).to_str() {
+        Ok(p) => p,
+        Err(err) => return CString::new(format!("{err}")).unwrap().into_raw(),
+    };
+
+    CString::new(db.verify_backup_str(data)).unwrap().into_raw()
+}
+
+#[no_mangle]
+/// Export a relation into a JSON Lines file, a JSON object keyed by column names per row
+///
+/// `db_id`:        the ID representing the database.
+/// `json_payload`: a UTF-8 encoded JSON payload: `{"relation": ..., "path": ...}`
+///
+/// Returns a UTF-8-encoded C-string indicating the result that **must** be freed with `cozo_free_str`.
+pub unsafe extern "C" fn cozo_export_relations_jsonl(
+    db_id: i32,
+    json_payload: *const c_char,
+) -> *mut c_char {
+    let db = {
+        let db_ref = {
+            let dbs = HANDLES.dbs.lock().unwrap();
+            dbs.get(&db_id).cloned()
+        };
+        match db_ref {
+            None => {
+                return CString::new(r##"{"ok":false,"message":"database closed"}"##)
+                    .unwrap()
+                    .into_raw();
+            }
+            Some(db) => db,
+        }
+    };
+
+    let data = match CStr::from_ptr(json_payload).to_str() {
+        Ok(p) => p,
+        Err(err) => return CString::new(format!("{err}")).unwrap().into_raw(),
+    };
+
+    CString::new(db.export_relations_jsonl_str(data))
+        .unwrap()
+        .into_raw()
+}
+
+#[no_mangle]
+/// Import data into a relation from a JSON Lines file, as written by `cozo_export_relations_jsonl`
+///
+/// Note that triggers are _not_ run for the relation, if any exists.
+///
+/// `db_id`:        the ID representing the database.
+/// `json_payload`: a UTF-8 encoded JSON payload: `{"relation": ..., "path": ...}`
+///
+/// Returns a UTF-8-encoded C-string indicating the result that **must** be freed with `cozo_free_str`.
+pub unsafe extern "C" fn cozo_import_relations_jsonl(
+    db_id: i32,
+    json_payload: *const c_char,
+) -> *mut c_char {
+    let db = {
+        let db_ref = {
+            let dbs = HANDLES.dbs.lock().unwrap();
+            dbs.get(&db_id).cloned()
+        };
+        match db_ref {
+            None => {
+                return CString::new(r##"{"ok":false,"message":"database closed"}"##)
+                    .unwrap()
+                    .into_raw();
+            }
+            Some(db) => db,
+        }
+    };
+
+    let data = match CStr::from_ptr(json_payload).to_str() {
+        Ok(p) => p,
+        Err(err) => return CString::new(format!("{err}")).unwrap().into_raw(),
+    };
+
+    CString::new(db.import_relations_jsonl_str(data))
+        .unwrap()
+        .into_raw()
+}
+
+/// Begin a transaction spanning several queries, which sees none of the writes done by
+/// others after it began, and whose writes are only seen by others once it is committed.
+///
+/// `db_id`: the ID representing the database.
+/// `write`: whether queries of the transaction may write to the database.
+///
+/// Returns a UTF-8-encoded C-string indicating the result that **must** be freed with `cozo_free_str`.
+/// On success, it contains the ID of the transaction: `{"ok":true,"id":...}`. The transaction
+/// must be ended with `cozo_commit_transaction` or `cozo_abort_transaction`, called with
+/// the same `db_id`; transactions still open when the database is closed are rolled back.
+#[no_mangle]
+pub unsafe extern "C" fn cozo_begin_transaction(db_id: i32, write: bool) -> *mut c_char {
+    let db = {
+        let db_ref = {
+            let dbs = HANDLES.dbs.lock().unwrap();
+            dbs.get(&db_id).cloned()
+        };
+        match db_ref {
+            None => {
+                return CString::new(r##"{"ok":false,"message":"database closed"}"##)
+                    .unwrap()
+                    .into_raw();
+            }
+            Some(db) => db,
+        }
+    };
+
+    CString::new(db.begin_transaction_str(write))
+        .unwrap()
+        .into_raw()
+}
+
+/// Run a query in a transaction begun by `cozo_begin_transaction`.
+///
+/// `db_id`:      the ID representing the database.
+/// `tx_id`:      the ID representing the transaction.
+/// `script_raw`: a UTF-8 encoded C-string for the CozoScript to execute.
+/// `params_raw`: a UTF-8 encoded C-string for the params of the query,
+///               in JSON format, as for `cozo_run_query`.
+/// `read_only`:  if `true`, the query fails if it writes to a stored relation.
+///
+/// Returns a UTF-8-encoded C-string that **must** be freed with `cozo_free_str`.
+/// The string contains the JSON return value of the query.
+#[no_mangle]
+pub unsafe extern "C" fn cozo_run_query_in_transaction(
+    db_id: i32,
+    tx_id: u32,
+    script_raw: *const c_char,
+    params_raw: *const c_char,
+    read_only: bool,
+) -> *mut c_char {
+    let script = match CStr::from_ptr(script_raw).to_str() {
+        Ok(p) => p,
+        Err(_) => {
+            return CString::new(r##"{"ok":false,"message":"script is not UTF-8 encoded"}"##)
+                .unwrap()
+                .into_raw();
+        }
+    };
+    let db = {
+        let db_ref = {
+            let dbs = HANDLES.dbs.lock().unwrap();
+            dbs.get(&db_id).cloned()
+        };
+        match db_ref {
+            None => {
+                return CString::new(r##"{"ok":false,"message":"database closed"}"##)
+                    .unwrap()
+                    .into_raw();
+            }
+            Some(db) => db,
+        }
+    };
+    let params_str = match CStr::from_ptr(params_raw).to_str() {
+        Ok(p) => p,
+        Err(_) => {
+            return CString::new(
+                r##"{"ok":false,"message":"params argument is not UTF-8 encoded"}"##,
+            )
+            .unwrap()
+            .into_raw();
+        }
+    };
+
+    let result = db.run_script_in_transaction_str(tx_id, script, params_str, read_only);
+    CString::new(result).unwrap().into_raw()
+}
+
+/// Commit a transaction begun by `cozo_begin_transaction`, which then ends.
+///
+/// `db_id`: the ID representing the database.
+/// `tx_id`: the ID representing the transaction.
+///
+/// Returns a UTF-8-encoded C-string indicating the result that **must** be freed with `cozo_free_str`.
+#[no_mangle]
+pub unsafe extern "C" fn cozo_commit_transaction(db_id: i32, tx_id: u32) -> *mut c_char {
+    let db = {
+        let db_ref = {
+            let dbs = HANDLES.dbs.lock().unwrap();
+            dbs.get(&db_id).cloned()
+        };
+        match db_ref {
+            None => {
+                return CString::new(r##"{"ok":false,"message":"database closed"}"##)
+                    .unwrap()
+                    .into_raw();
+            }
+            Some(db) => db,
+        }
+    };
+
+    CString::new(db.commit_transaction_str(tx_id))
+        .unwrap()
+        .into_raw()
+}
+
+/// Abort a transaction begun by `cozo_begin_transaction`, which then ends,
+/// rolling back its writes.
+///
+/// `db_id`: the ID representing the database.
+/// `tx_id`: the ID representing the transaction.
+///
+/// Returns a UTF-8-encoded C-string indicating the result that **must** be freed with `cozo_free_str`.
+#[no_mangle]
+pub unsafe extern "C" fn cozo_abort_transaction(db_id: i32, tx_id: u32) -> *mut c_char {
+    let db = {
+        let db_ref = {
+            let dbs = HANDLES.dbs.lock().unwrap();
+            dbs.get(&db_id).cloned()
+        };
+        match db_ref {
+            None => {
+                return CString::new(r##"{"ok":false,"message":"database closed"}"##)
+                    .unwrap()
+                    .into_raw();
+            }
+            Some(db) => db,
+        }
+    };
+
+    CString::new(db.abort_transaction_str(tx_id))
+        .unwrap()
+        .into_raw()
+}
+
 /// Free any C-string returned from the Cozo C API.
 /// Must be called exactly once for each returned C-string.
 ///
@@ -9,4 +9,6 @@ public class CozoJavaBridge {
     private static native String backup(int id, String file);
     private static native String restore(int id, String file);
     private static native String importFromBackup(int id, String data);
+    private static native String exportRelationsJsonl(int id, String data);
+    private static native String importRelationsJsonl(int id, String data);
 }
\ No newline at end of file
@@ -71,6 +71,22 @@ JNIEXPORT jstring JNICALL Java_org_cozodb_CozoJavaBridge_restore
 JNIEXPORT jstring JNICALL Java_org_cozodb_CozoJavaBridge_importFromBackup
   (JNIEnv *, jclass, jint, jstring);
 
+/*
+ * Class:     org_cozodb_CozoJavaBridge
+ * Method:    exportRelationsJsonl
+ * Signature: (ILjava/lang/String;)Ljava/lang/String;
+ */
+JNIEXPORT jstring JNICALL Java_org_cozodb_CozoJavaBridge_exportRelationsJsonl
+  (JNIEnv *, jclass, jint, jstring);
+
+/*
+ * Class:     org_cozodb_CozoJavaBridge
+ * Method:    importRelationsJsonl
+ * Signature: (ILjava/lang/String;)Ljava/lang/String;
+ */
+JNIEXPORT jstring JNICALL Java_org_cozodb_CozoJavaBridge_importRelationsJsonl
+  (JNIEnv *, jclass, jint, jstring);
+
 #ifdef __cplusplus
 }
 #endif
@@ -178,3 +178,37 @@ pub extern "system" fn Java_org_cozodb_CozoJavaBridge_importFromBackup(
         }
     }
 }
+
+#[no_mangle]
+pub extern "system" fn Java_org_cozodb_CozoJavaBridge_exportRelationsJsonl(
+    env: JNIEnv,
+    _class: JClass,
+    id: jint,
+    data: JString,
+) -> jstring {
+    let data: String = env.get_string(data).unwrap().into();
+    match get_db(id) {
+        None => env.new_string(DB_NOT_FOUND).unwrap().into_raw(),
+        Some(db) => {
+            let res = db.export_relations_jsonl_str(&data);
+            env.new_string(res).unwrap().into_raw()
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "system" fn Java_org_cozodb_CozoJavaBridge_importRelationsJsonl(
+    env: JNIEnv,
+    _class: JClass,
+    id: jint,
+    data: JString,
+) -> jstring {
+    let data: String = env.get_string(data).unwrap().into();
+    match get_db(id) {
+        None => env.new_string(DB_NOT_FOUND).unwrap().into_raw(),
+        Some(db) => {
+            let res = db.import_relations_jsonl_str(&data);
+            env.new_string(res).unwrap().into_raw()
+        }
+    }
+}
@@ -77,6 +77,23 @@ extension NamedRow {
     }
 }
 
+func namedRows(_ resStr: String) throws -> [NamedRow] {
+    let dataFromString = resStr.data(using: .utf8, allowLossyConversion: false)!
+    let json = JSON(dataFromString);
+    if json["ok"].boolValue {
+        let jHeaders = json["headers"].arrayValue.map{(j) -> String in
+            return j.stringValue
+        }
+        let headers = RowHeaders(headers: jHeaders)
+        return json["rows"].arrayValue.map{(j) -> NamedRow in
+            let fields = j.arrayValue
+            return NamedRow(headers: headers, fields: fields)
+        }
+    } else {
+        throw CozoError.query(json)
+    }
+}
+
 public class CozoDB {
     public let db: DbInstance
     
@@ -100,17 +117,15 @@ public class CozoDB {
     }
     func run(_ query: String, stringParams: String) throws -> [NamedRow] {
         let resStr = self.db.run_script_str(query, stringParams).toString()
+        return try namedRows(resStr)
+    }
+    /// Begin a transaction spanning several queries, ended by `commit` or `abort`
+    public func transaction(write: Bool) throws -> CozoTransaction {
+        let resStr = self.db.begin_transaction_str(write).toString()
         let dataFromString = resStr.data(using: .utf8, allowLossyConversion: false)!
         let json = JSON(dataFromString);
         if json["ok"].boolValue {
-            let jHeaders = json["headers"].arrayValue.map{(j) -> String in
-                return j.stringValue
-            }
-            let headers = RowHeaders(headers: jHeaders)
-            return json["rows"].arrayValue.map{(j) -> NamedRow in
-                let fields = j.arrayValue
-                return NamedRow(headers: headers, fields: fields)
-            }
+            return CozoTransaction(db: self.db, id: json["id"].uInt32Value)
         } else {
             throw CozoError.query(json)
         }
@@ -135,6 +150,28 @@ public class CozoDB {
             throw CozoError.query(json)
         }
     }
+    public func exportRelationJsonl(relation: String, path: String) throws -> Int {
+        let payload = JSON(["relation": relation, "path": path]).rawString(.utf8, options: .init(rawValue: 0))!
+        let resStr = self.db.export_relations_jsonl_str(payload).toString()
+        let dataFromString = resStr.data(using: .utf8, allowLossyConversion: false)!
+        let json = JSON(dataFromString);
+        if json["ok"].boolValue {
+            return json["exported"].intValue
+        } else {
+            throw CozoError.query(json)
+        }
+    }
+    public func importRelationJsonl(relation: String, path: String) throws -> Int {
+        let payload = JSON(["relation": relation, "path": path]).rawString(.utf8, options: .init(rawValue: 0))!
+        let resStr = self.db.import_relations_jsonl_str(payload).toString()
+        let dataFromString = resStr.data(using: .utf8, allowLossyConversion: false)!
+        let json = JSON(dataFromString);
+        if json["ok"].boolValue {
+            return json["imported"].intValue
+        } else {
+            throw CozoError.query(json)
+        }
+    }
     public func backup(path: String) throws {
         let resStr = self.db.backup_db_str(path).toString()
         let dataFromString = resStr.data(using: .utf8, allowLossyConversion: false)!
@@ -163,3 +200,48 @@ public class CozoDB {
         }
     }
 }
+
+/// A transaction spanning several queries, begun by `CozoDB.transaction`.
+/// It is rolled back if neither committed nor aborted before it is released.
+public class CozoTransaction {
+    let db: DbInstance
+    let id: UInt32
+    var ended = false
+
+    init(db: DbInstance, id: UInt32) {
+        self.db = db
+        self.id = id
+    }
+    deinit {
+        if !self.ended {
+            _ = self.db.abort_transaction_str(self.id)
+        }
+    }
+    public func run(_ query: String, params: JSON) throws -> [NamedRow] {
+        let payload = params.rawString(.utf8, options: .init(rawValue: 0))!
+        return try namedRows(self.db.run_script_in_transaction_str(self.id, query, payload, false).toString())
+    }
+    public func run(_ query: String) throws -> [NamedRow] {
+        return try namedRows(self.db.run_script_in_transaction_str(self.id, query, "", false).toString())
+    }
+    /// Run a query that fails if it writes to a stored relation
+    public func runReadOnly(_ query: String, params: JSON) throws -> [NamedRow] {
+        let payload = params.rawString(.utf8, options: .init(rawValue: 0))!
+        return try namedRows(self.db.run_script_in_transaction_str(self.id, query, payload, true).toString())
+    }
+    public func commit() throws {
+        self.ended = true
+        try self.checkOk(self.db.commit_transaction_str(self.id).toString())
+    }
+    public func abort() throws {
+        self.ended = true
+        try self.checkOk(self.db.abort_transaction_str(self.id).toString())
+    }
+    func checkOk(_ resStr: String) throws {
+        let dataFromString = resStr.data(using: .utf8, allowLossyConversion: false)!
+        let json = JSON(dataFromString);
+        if !json["ok"].boolValue {
+            throw CozoError.query(json)
+        }
+    }
+}
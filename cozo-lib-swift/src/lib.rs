@@ -22,6 +22,18 @@ mod ffi {
         fn backup_db_str(&self, out_file: &str) -> String;
         fn restore_backup_str(&self, in_file: &str) -> String;
         fn import_from_backup_str(&self, data: &str) -> String;
+        fn export_relations_jsonl_str(&self, payload: &str) -> String;
+        fn import_relations_jsonl_str(&self, payload: &str) -> String;
+        fn begin_transaction_str(&self, write: bool) -> String;
+        fn run_script_in_transaction_str(
+            &self,
+            id: u32,
+            payload: &str,
+            params: &str,
+            read_only: bool,
+        ) -> String;
+        fn commit_transaction_str(&self, id: u32) -> String;
+        fn abort_transaction_str(&self, id: u32) -> String;
     }
 }
 
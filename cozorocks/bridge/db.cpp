@@ -119,6 +119,11 @@ shared_ptr <RocksDbBridge> open_db(const DbOpts &opts, RocksDbStatus &status) {
         options.prefix_extractor.reset(NewFixedPrefixTransform(opts.fixed_prefix_extractor_len));
     }
     options.create_missing_column_families = true;
+    if (opts.enable_statistics) {
+        // only the counters are kept, such as the hits of the block cache reported by `::storage_info`
+        options.statistics = CreateDBStatistics();
+        options.statistics->set_stats_level(kExceptHistogramOrTimers);
+    }
 
     shared_ptr <RocksDbBridge> db = make_shared<RocksDbBridge>();
 
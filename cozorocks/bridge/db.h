@@ -110,6 +110,24 @@ struct RocksDbBridge {
         write_status(s, status);
     }
 
+    [[nodiscard]] inline int64_t get_int_property(rust::Str name) const {
+        uint64_t value;
+        string name_(name);
+        if (db->GetIntProperty(name_, &value)) {
+            return static_cast<int64_t>(value);
+        }
+        return -1;
+    }
+
+    [[nodiscard]] inline int64_t get_block_cache_ticker(bool miss) const {
+        auto stats = db->GetOptions().statistics;
+        if (stats == nullptr) {
+            return -1;
+        }
+        auto count = stats->getTickerCount(miss ? BLOCK_CACHE_MISS : BLOCK_CACHE_HIT);
+        return static_cast<int64_t>(count);
+    }
+
     DB *get_base_db() const {
         return db->GetBaseDB();
     }
@@ -54,6 +54,7 @@ impl Default for DbOpts {
             fixed_prefix_extractor_len: 0,
             destroy_on_exit: false,
             block_cache_size: 0,
+            enable_statistics: false,
         }
     }
 }
@@ -121,6 +122,12 @@ impl DbBuilder {
         self.opts.fixed_prefix_extractor_len = len;
         self
     }
+    /// Collect the counters of the database, such as the hits of the block cache,
+    /// at a small cost to every operation
+    pub fn enable_statistics(mut self, val: bool) -> Self {
+        self.opts.enable_statistics = val;
+        self
+    }
     pub fn build(self) -> Result<RocksDb, RocksDbStatus> {
         let mut status = RocksDbStatus::default();
 
@@ -186,6 +193,27 @@ impl RocksDb {
             Err(status)
         }
     }
+    /// Get an integer-valued property of the database, such as `rocksdb.estimate-num-keys`.
+    /// Returns `None` if the property is not available.
+    pub fn get_int_property(&self, name: &str) -> Option<u64> {
+        let ret = self.inner.get_int_property(name);
+        if ret < 0 {
+            None
+        } else {
+            Some(ret as u64)
+        }
+    }
+    /// Get the hits and misses of the block cache since the database was opened.
+    /// Returns `None` if statistics are not collected.
+    pub fn block_cache_hits_and_misses(&self) -> Option<(u64, u64)> {
+        let hits = self.inner.get_block_cache_ticker(false);
+        let misses = self.inner.get_block_cache_ticker(true);
+        if hits < 0 || misses < 0 {
+            None
+        } else {
+            Some((hits as u64, misses as u64))
+        }
+    }
     pub fn ingest_sst_file(&self, path: &str) -> Result<(), RocksDbStatus> {
         let mut status = RocksDbStatus::default();
         self.inner.ingest_sst(path, &mut status);
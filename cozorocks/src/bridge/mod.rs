@@ -41,6 +41,7 @@ pub(crate) mod ffi {
         pub fixed_prefix_extractor_len: usize,
         pub destroy_on_exit: bool,
         pub block_cache_size: usize,
+        pub enable_statistics: bool,
     }
 
     #[derive(Clone, Debug, Eq, PartialEq)]
@@ -138,6 +139,8 @@ pub(crate) mod ffi {
             status: &mut RocksDbStatus,
         ) -> UniquePtr<SstFileWriterBridge>;
         fn ingest_sst(self: &RocksDbBridge, path: &str, status: &mut RocksDbStatus);
+        fn get_int_property(self: &RocksDbBridge, name: &str) -> i64;
+        fn get_block_cache_ticker(self: &RocksDbBridge, miss: bool) -> i64;
 
         type SstFileWriterBridge;
         fn put(